@@ -15,6 +15,7 @@ PROTO_FILES = [
     "bastionlab_polars.proto",
     "bastionlab_torch.proto",
     "bastionlab_conversion.proto",
+    "bastionlab_self_test.proto",
 ]
 PROTO_PATH = os.path.join(os.path.dirname(DIR), "protos")
 LONG_DESCRIPTION = read("README.md")
@@ -1,9 +1,9 @@
 import ssl
-from typing import Any, TYPE_CHECKING, Optional
+from typing import Any, Dict, List, TYPE_CHECKING, Optional, Union
 from hashlib import sha256
 import grpc
-from .keys import SigningKey
-from .pb.bastionlab_pb2 import Empty
+from .keys import PublicKey, SigningKey
+from .pb.bastionlab_pb2 import ChallengeRequest, Empty
 from .pb.bastionlab_pb2 import ClientInfo
 from .version import __version__ as app_version
 from .pb.bastionlab_pb2_grpc import SessionServiceStub
@@ -49,6 +49,47 @@ CLIENT_INFO = ClientInfo(
 )
 
 
+_last_nonce = 0
+
+
+def _signed_session_metadata(
+    session_stub: SessionServiceStub, signing_key: SigningKey, data: bytes
+) -> tuple:
+    """Signs a session request, timestamped with the server clock and a nonce.
+
+    The server rejects timestamps too far from its clock and nonces not greater than the last
+    one it accepted from the key: nonces follow the clock so they keep increasing.
+    """
+    global _last_nonce
+
+    sent = time.time()
+    server_ms = session_stub.GetServerTime(Empty()).unix_ms
+    offset_ms = server_ms - int((sent + time.time()) / 2 * 1000)
+    timestamp = int(time.time() * 1000) + offset_ms
+    _last_nonce = max(_last_nonce + 1, timestamp * 1000)
+    timestamp_bytes = timestamp.to_bytes(8, "big")
+    nonce_bytes = _last_nonce.to_bytes(8, "big")
+
+    pubkey_hex = signing_key.pubkey.hash.hex()
+    # Bound to the key: only requests it signs spend the challenge.
+    challenge = session_stub.GetChallenge(
+        ChallengeRequest(public_key_hash=pubkey_hex)
+    ).value
+    to_sign = b"create-session" + challenge + timestamp_bytes + nonce_bytes + data
+    return (
+        ("challenge-bin", challenge),
+        ("timestamp-bin", timestamp_bytes),
+        ("nonce-bin", nonce_bytes),
+        (f"signature-{pubkey_hex}-bin", signing_key.sign(to_sign)),
+    )
+
+
+def _public_key_dict(key) -> Dict[str, str]:
+    from .pb.bastionlab_pb2 import KeyRole
+
+    return {"hash": key.hash, "role": KeyRole.Name(key.role).lower()}
+
+
 class Client:
     """
     The Client class provides access to the BastionLab machine learning platform through several attributes.
@@ -96,14 +137,9 @@ class Client:
         metadata = ()
         if self.signing_key is not None:
             data: bytes = CLIENT_INFO.SerializeToString()
-            challenge = self.__session_stub.GetChallenge(Empty()).value
-
-            metadata += (("challenge-bin", challenge),)
-            to_sign = b"create-session" + challenge + data
-
-            pubkey_hex = self.signing_key.pubkey.hash.hex()
-            signed = self.signing_key.sign(to_sign)
-            metadata += ((f"signature-{pubkey_hex}-bin", signed),)
+            metadata = _signed_session_metadata(
+                self.__session_stub, self.signing_key, data
+            )
 
         res = self.__session_stub.CreateSession(CLIENT_INFO, metadata=metadata)
 
@@ -115,6 +151,167 @@ class Client:
         )
         self._token = res.token
 
+    def run_self_test(self, skip_persistence: bool = False) -> List[Dict[str, Any]]:
+        """
+        Runs the server's self-test, which exercises the whole pipeline on a synthetic dataset
+        in an isolated in-process server. Only data owners can do this.
+
+        Args:
+            skip_persistence (bool): Do not check that persisted DataFrames are reloaded.
+
+        Returns:
+            List[Dict[str, Any]]: The name, outcome, duration in milliseconds and detail of each step.
+
+        Raises:
+            RuntimeError: If any step failed, with the detail of the failed steps.
+        """
+        from .pb.bastionlab_self_test_pb2 import SelfTestRequest
+        from .pb.bastionlab_self_test_pb2_grpc import SelfTestServiceStub
+        from .errors import GRPCException
+
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: SelfTestServiceStub(self._channel).RunSelfTest(
+                SelfTestRequest(skip_persistence=skip_persistence)
+            )
+        )
+        steps = [
+            {
+                "name": step.name,
+                "passed": step.passed,
+                "duration_ms": step.duration_ms,
+                "detail": step.detail,
+            }
+            for step in res.steps
+        ]
+        if not res.passed:
+            failed = "; ".join(
+                f"{step['name']}: {step['detail']}" for step in steps if not step["passed"]
+            )
+            raise RuntimeError(f"Self-test failed: {failed}")
+        return steps
+
+    def list_connections(self) -> List[Dict[str, Any]]:
+        """
+        Lists the connections open on the server. Only data owners can do this.
+
+        Returns:
+            List[Dict[str, Any]]: The id, peer address, age and idle time in milliseconds, number of
+            requests in flight and draining state of each connection.
+        """
+        from .pb.bastionlab_pb2_grpc import ConnectionServiceStub
+        from .errors import GRPCException
+
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: ConnectionServiceStub(self._channel).ListConnections(Empty())
+        )
+        return [
+            {
+                "id": c.id,
+                "peer": c.peer,
+                "age_ms": c.age_ms,
+                "idle_ms": c.idle_ms,
+                "active_streams": c.active_streams,
+                "draining": c.draining,
+            }
+            for c in res.connections
+        ]
+
+    def drain_connections(self) -> int:
+        """
+        Asks every connection open on the server to reconnect once it has no request in flight.
+        Only data owners can do this.
+
+        Sessions are bound to their connection: clients authenticate again after reconnecting.
+
+        Returns:
+            int: The number of connections asked to reconnect.
+        """
+        from .pb.bastionlab_pb2_grpc import ConnectionServiceStub
+        from .errors import GRPCException
+
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: ConnectionServiceStub(self._channel).DrainConnections(Empty())
+        )
+        return res.draining
+
+    def list_public_keys(self) -> List[Dict[str, str]]:
+        """
+        Lists the public keys the server authenticates, sorted by hash. Only data owners
+        can do this.
+
+        Returns:
+            List[Dict[str, str]]: The hash and role, `"user"` or `"owner"`, of each key.
+        """
+        from .pb.bastionlab_pb2_grpc import KeyServiceStub
+        from .errors import GRPCException
+
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: KeyServiceStub(self._channel).ListPublicKeys(Empty())
+        )
+        return [_public_key_dict(key) for key in res.keys]
+
+    def add_public_key(
+        self, key: Union[PublicKey, str], role: str = "user"
+    ) -> Dict[str, str]:
+        """
+        Adds a public key, which authenticates at once and is kept across restarts. Only
+        data owners can do this.
+
+        Args:
+            key (Union[PublicKey, str]): The key, or its PEM encoding.
+            role (str): `"user"` or `"owner"`.
+
+        Returns:
+            Dict[str, str]: The hash and role of the key.
+        """
+        from .pb.bastionlab_pb2 import AddPublicKeyRequest, KeyRole
+        from .pb.bastionlab_pb2_grpc import KeyServiceStub
+        from .errors import GRPCException
+
+        if role not in ("user", "owner"):
+            raise ValueError(f'role must be "user" or "owner", not {role!r}')
+        pem = key.pem if isinstance(key, PublicKey) else key
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: KeyServiceStub(self._channel).AddPublicKey(
+                AddPublicKeyRequest(role=KeyRole.Value(role.upper()), pem=pem)
+            )
+        )
+        return _public_key_dict(res)
+
+    def remove_public_key(self, hash: str) -> Dict[str, str]:
+        """
+        Removes a public key: its sessions end at once. The last data owner key cannot
+        be removed. Only data owners can do this.
+
+        Args:
+            hash (str): The hash of the key, as listed by `list_public_keys`.
+
+        Returns:
+            Dict[str, str]: The hash and role of the removed key.
+        """
+        from .pb.bastionlab_pb2 import RemovePublicKeyRequest
+        from .pb.bastionlab_pb2_grpc import KeyServiceStub
+        from .errors import GRPCException
+
+        self._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: KeyServiceStub(self._channel).RemovePublicKey(
+                RemovePublicKeyRequest(hash=hash)
+            )
+        )
+        return _public_key_dict(res)
+
     @property
     def torch(self) -> "bastionlab.torch.BastionLabTorch":
         """
@@ -214,14 +411,7 @@ class Connection:
         data: bytes = CLIENT_INFO.SerializeToString()
 
         if signing_key is not None:
-            challenge = session_stub.GetChallenge(Empty()).value
-
-            metadata += (("challenge-bin", challenge),)
-            to_sign = b"create-session" + challenge + data
-
-            pubkey_hex = signing_key.pubkey.hash.hex()
-            signed = signing_key.sign(to_sign)
-            metadata += ((f"signature-{(pubkey_hex)}-bin", signed),)
+            metadata = _signed_session_metadata(session_stub, signing_key, data)
 
             token = session_stub.CreateSession(CLIENT_INFO, metadata=metadata).token
 
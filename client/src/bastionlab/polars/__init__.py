@@ -14,6 +14,8 @@ from .frame import (
 )
 
 from . import policy
+from . import temporal
+from .utils import is_nan, is_finite
 from .frame import train_test_split
 
 __pdoc__["RemoteLazyFrame.__init__"] = False
@@ -28,8 +30,11 @@ __all__ = [
     "RemoteLazyGroupBy",
     "train_test_split",
     "policy",
+    "temporal",
     "FetchableLazyFrame",
     "train_test_split",
     "Facet",
     "RemoteArray",
+    "is_nan",
+    "is_finite",
 ]
@@ -1,4 +1,4 @@
-from typing import Iterator, List, Union, TYPE_CHECKING
+from typing import Any, Dict, Iterator, List, Optional, Union, TYPE_CHECKING
 import polars as pl
 import io
 from ..pb.bastionlab_polars_pb2 import SendChunk
@@ -15,6 +15,13 @@ if TYPE_CHECKING:
     from .client import BastionLabPolars
 
 CHUNK_SIZE = 32 * 1024
+# API version and optional features this client speaks, see `BastionLabPolars.handshake`.
+API_VERSION = 2
+FEATURES = ["ipc_upload", "delta_fetch"]
+# Semantics version of the plans built by this client, see `BastionLabPolars.migrate_semantics`.
+SEMANTICS_VERSION = 1
+# Version of the format of the plans built by this client, which servers check.
+PLAN_FORMAT_VERSION = 1
 
 # TODO PERF: Do a PR on polars/pypolars to add the streaming IPC (apache flight) format to the python interface
 # right now, there is only the file format which requires random access
@@ -37,6 +44,29 @@ class EntryPointPlanSegment(CompositePlanSegment):
     identifier: str
 
 
+@dataclass
+@serde
+class FamilyEntryPointSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for reading the members of a dataset family
+    """
+
+    family: str
+    predicate: Dict[str, Any]
+
+
+@dataclass
+@serde
+class ResourceHints:
+    """
+    Memory limit of a Polars segment, and whether its inputs may be spilled to disk above it.
+    Unset fields take the server defaults, within the caps of the data owners' policies.
+    """
+
+    allow_spill: Optional[bool] = None
+    max_memory_mb: Optional[int] = None
+
+
 @dataclass
 @serde
 class PolarsPlanSegment(CompositePlanSegment):
@@ -52,6 +82,9 @@ class PolarsPlanSegment(CompositePlanSegment):
         serializer=lambda val: val.schema and json.loads(val.write_json()),
         deserializer=lambda _: None,
     )
+    # Whether the aggregations of the plan skip NaN like they skip nulls.
+    skip_nan: bool = False
+    resources: Optional[ResourceHints] = None
 
 
 @dataclass
@@ -86,6 +119,201 @@ class RowCountSegment(CompositePlanSegment):
     row: str
 
 
+@dataclass
+@serde
+class TemporalPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for temporal expressions, see `temporal`
+    """
+
+    columns: List[Dict[str, Any]]
+
+
+@dataclass
+@serde
+class LiteralFramePlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for small dataframes written inline in the plan
+    """
+
+    columns: List[Dict[str, Any]]
+
+
+@dataclass
+@serde
+class JoinPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for joining the two previous inputs, the first
+    one on the left
+    """
+
+    left_on: List[str]
+    right_on: List[str]
+    # One of "Inner", "Left", "Outer" or "Cross", which takes no keys.
+    how: str = "Inner"
+
+
+@dataclass
+@serde
+class GroupByPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for aggregating the previous input by group
+    """
+
+    by: List[str]
+    # e.g. `{"column": "age", "agg": "Mean"}`, with "agg" one of "Sum", "Mean", "Min", "Max",
+    # "Count", "Median" or "Std". The result column is named `age_mean`.
+    aggs: List[Dict[str, str]]
+
+
+@dataclass
+@serde
+class FilterPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for filtering the rows of the previous input
+    """
+
+    # An expression tree, e.g. `age > 65` is `{"op": "Gt", "left": {"op": "Column",
+    # "name": "age"}, "right": {"op": "Literal", "value": 65}}`. Comparisons are "Eq",
+    # "NotEq", "Lt", "LtEq", "Gt" and "GtEq", combined with "And", "Or" and "Not".
+    # Operands can be computed with "Add", "Sub", "Mul", "Div" and "Concat".
+    predicate: Dict[str, Any]
+
+
+@dataclass
+@serde
+class WithColumnPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class adding a computed column to the previous input
+    """
+
+    name: str
+    # An expression tree, as the predicates of `FilterPlanSegment`, e.g. `price * 2` is
+    # `{"op": "Mul", "left": {"op": "Column", "name": "price"},
+    # "right": {"op": "Literal", "value": 2}}`. Divisions by zero are null.
+    expr: Dict[str, Any]
+    # Replaces a column of that name, which is an error otherwise.
+    replace: bool = False
+
+
+@dataclass
+@serde
+class SortPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for sorting the rows of the previous input
+    """
+
+    # e.g. `{"column": "age", "descending": True, "nulls_last": True}`. Both flags
+    # default to False: ascending, with nulls first.
+    by: List[Dict[str, Any]]
+    # Keeps rows of equal keys in their input order.
+    stable: bool = False
+
+
+@dataclass
+@serde
+class SelectPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for selecting columns of the previous input
+    """
+
+    # e.g. `{"name": "age", "alias": "years"}`, or `{"name": "age"}` to keep its name.
+    columns: List[Dict[str, str]] = field(default_factory=list)
+    # Adds the other columns of these dtype groups: "Numeric", "Integer", "Float",
+    # "String", "Boolean" or "Temporal".
+    dtypes: List[str] = field(default_factory=list)
+
+
+@dataclass
+@serde
+class SamplePlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class responsible for sampling rows of the previous input
+    """
+
+    # Exactly one of `fraction` and `n` is set. Fractions above 1 need replacement.
+    fraction: Optional[float] = None
+    n: Optional[int] = None
+    with_replacement: bool = False
+    # The server draws from its own entropy if unset.
+    seed: Optional[int] = None
+
+
+@dataclass
+@serde
+class HeadPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class keeping the first rows of the previous input
+    """
+
+    # Capped by the `slice_max_rows` of the server.
+    n: int
+
+
+@dataclass
+@serde
+class SlicePlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class keeping a slice of the rows of the previous input
+    """
+
+    # Capped by the `slice_max_rows` of the server.
+    length: int
+    # Counted from the end if negative.
+    offset: int = 0
+
+
+@dataclass
+@serde
+class VStackPlanSegment(CompositePlanSegment):
+    """
+    Composite plan segment class stacking the rows of stored dataframes, in order
+    """
+
+    inputs: List[str]
+    # Whether compatible dtypes are cast to a common one instead of rejected.
+    relaxed: bool = False
+
+
+# Dtypes literal frames can hold, by their name on the server.
+_LITERAL_DTYPES = {
+    pl.Boolean: "Boolean",
+    pl.Int32: "Int32",
+    pl.Int64: "Int64",
+    pl.UInt32: "UInt32",
+    pl.UInt64: "UInt64",
+    pl.Float32: "Float32",
+    pl.Float64: "Float64",
+    pl.Utf8: "Utf8",
+    pl.Date: "Date",
+}
+
+
+def _literal_value(value: Any) -> Any:
+    if isinstance(value, float) and value != value:
+        return "NaN"
+    if isinstance(value, float) and value in (float("inf"), float("-inf")):
+        return "inf" if value > 0 else "-inf"
+    if hasattr(value, "isoformat"):
+        return value.isoformat()
+    return value
+
+
+def literal_columns(df: pl.DataFrame) -> List[Dict[str, Any]]:
+    """Converts the columns of `df` to those of a `LiteralFramePlanSegment`."""
+    columns = []
+    for series in df.get_columns():
+        dtype = _LITERAL_DTYPES.get(series.dtype)
+        if dtype is None:
+            raise TypeError(
+                f"Column {series.name} of dtype {series.dtype} cannot be sent inline, "
+                f"supported dtypes: {', '.join(_LITERAL_DTYPES.values())}"
+            )
+        values = [_literal_value(value) for value in series.to_list()]
+        columns.append({"name": series.name, "dtype": dtype, "values": values})
+    return columns
+
+
 @dataclass
 @serde(tagging=InternalTagging("type"))
 class PlanSegments:
@@ -94,10 +322,27 @@ class PlanSegments:
             PolarsPlanSegment,
             UdfPlanSegment,
             EntryPointPlanSegment,
+            FamilyEntryPointSegment,
             StackPlanSegment,
             RowCountSegment,
+            TemporalPlanSegment,
+            LiteralFramePlanSegment,
+            JoinPlanSegment,
+            GroupByPlanSegment,
+            FilterPlanSegment,
+            SortPlanSegment,
+            SelectPlanSegment,
+            SamplePlanSegment,
+            WithColumnPlanSegment,
+            HeadPlanSegment,
+            SlicePlanSegment,
+            VStackPlanSegment,
         ]
     ]
+    semantics_version: int = SEMANTICS_VERSION
+    format_version: int = PLAN_FORMAT_VERSION
+    # Whether NaN is converted to null throughout the plan.
+    nan_as_null: bool = False
 
 
 @dataclass
@@ -114,7 +359,16 @@ class UdfTransformerPlanSegment(CompositePlanSegment):
 
 
 def serialize_dataframe(
-    df: pl.DataFrame, policy: Policy, sanitized_columns: List[str]
+    df: pl.DataFrame,
+    policy: Policy,
+    sanitized_columns: List[str],
+    optimize_storage: bool = False,
+    allow_lossy_floats: bool = False,
+    append_to: str = "",
+    key_columns: List[str] = [],
+    name: str = "",
+    tags: List[str] = [],
+    chunk_size: int = CHUNK_SIZE,
 ) -> Iterator[SendChunk]:
     """Converts Polars `DataFrame` to BastionLab `SendChunk` protobuf message.
     This currently uses the Apache IPC format.
@@ -127,6 +381,21 @@ def serialize_dataframe(
         sanitized_columns : List[str]
             This field contains the sensitive columns in the DataFrame that will be removed when a Data Scientist
             wishes to fetch a query performed on the DataFrame.
+        optimize_storage : bool
+            Whether the server should shrink the dtypes the DataFrame is stored with.
+        allow_lossy_floats : bool
+            Allow Float64 columns to be stored as Float32 even if this loses precision.
+        append_to : str
+            Identifier of the DataFrame the rows are appended to, when calling `AppendDataFrame`
+            or `UpsertRows`.
+        key_columns : List[str]
+            Columns identifying rows, when calling `UpsertRows`.
+        name : str
+            Name the DataFrame can be listed by.
+        tags : List[str]
+            Tags the DataFrame can be listed by.
+        chunk_size : int
+            Bytes of data per chunk, as negotiated by the handshake.
     Returns:
         Iterator[SendChunk]
     """
@@ -138,13 +407,19 @@ def serialize_dataframe(
     max = len(buf.getvalue())
     first = True
     while buf.tell() < max:
-        data = buf.read(CHUNK_SIZE)
+        data = buf.read(chunk_size)
 
         if first:
             chunk = SendChunk(
                 data=data,
                 policy=to_json(policy),
                 sanitized_columns=sanitized_columns,
+                optimize_storage=optimize_storage,
+                allow_lossy_floats=allow_lossy_floats,
+                append_to=append_to,
+                key_columns=key_columns,
+                name=name,
+                tags=tags,
             )
             first = False
         else:
@@ -173,6 +448,41 @@ def deserialize_dataframe(chunks: Iterator[bytes]) -> pl.DataFrame:
     return df
 
 
+# Columns of the deltas sent by delta fetches, see `FetchableLazyFrame.fetch_since`.
+DELTA_OPERATION = "__bastionlab_delta_op"
+DELTA_POSITION = "__bastionlab_delta_position"
+
+
+def apply_delta(
+    previous: pl.DataFrame, delta: pl.DataFrame, keys: List[str]
+) -> pl.DataFrame:
+    """Rebuilds the new version of a result from the previous one and a delta.
+
+    Inserted and updated rows come with their position in the new version. The rows of the
+    previous version that were neither updated nor deleted fill the other positions, in order.
+    Args:
+        previous : polars.internals.dataframe.frame.DataFrame
+            The version the delta was computed against.
+        delta : polars.internals.dataframe.frame.DataFrame
+            The delta sent by the server.
+        keys : List[str]
+            The columns the rows were matched on.
+    Returns:
+        polars.internals.dataframe.frame.DataFrame
+    """
+    operation = pl.col(DELTA_OPERATION)
+    removed = delta.filter(operation != "insert").select(keys)
+    kept = previous.join(removed, on=keys, how="anti")
+    changed = delta.filter(operation != "delete").drop(DELTA_OPERATION)
+
+    positions = set(changed[DELTA_POSITION].to_list())
+    free = [p for p in range(kept.height + changed.height) if p not in positions]
+    kept = kept.with_column(
+        pl.Series(DELTA_POSITION, free, dtype=changed[DELTA_POSITION].dtype)
+    )
+    return pl.concat([kept, changed]).sort(DELTA_POSITION).drop(DELTA_POSITION)
+
+
 @dataclass
 class Metadata:
     """
@@ -1,18 +1,83 @@
-from typing import List, TYPE_CHECKING, Optional, Iterator
+import json
+from typing import Any, Dict, List, TYPE_CHECKING, Optional, Iterator, Tuple, Union
 from grpc import StatusCode
 import polars as pl
 from colorama import Fore
-from ..pb.bastionlab_polars_pb2 import ReferenceRequest, Empty, Query
+from serde.json import to_json
+from ..pb.bastionlab_polars_pb2 import (
+    ReferenceRequest,
+    Empty,
+    ListDataFramesRequest,
+    DataFrameKind,
+    Query,
+    QueryBatch,
+    OptimizeStorageRequest,
+    QualityConstraintsRequest,
+    RecompressRequest,
+    SemanticsMigrationRequest,
+    FamilyMember,
+    RegisterFamilyRequest,
+    FamilyMembersRequest,
+    RemoveFamilyMembersRequest,
+    FamilyRequest,
+    RegisterPipelineRequest,
+    PipelineRequest,
+    ReferenceResponse,
+    SyntheticRequest,
+    RegisterViewRequest,
+    ViewRequest,
+    StringList,
+    UpdateDraftRequest,
+    ReviewRequest,
+    DeduplicateRequest,
+    AliasRequest,
+    ReproducibilityBundle,
+    Purpose,
+    UsageReportRequest,
+    ExportWaiverRequest,
+    WorkspaceRequest,
+    WorkspaceMembersRequest,
+    ShareWorkspaceRequest,
+    DeleteWorkspaceRequest,
+    RetentionRequest,
+    StorageClassRequest,
+    StorageClassJobRequest,
+    PolicySelector,
+    PolicyRolloutRequest,
+    RolloutRequest,
+    TransferRequest,
+    HandshakeRequest,
+    KillQueryRequest,
+    ApproveRequestRequest,
+    RejectRequestRequest,
+    AuditLogRequest,
+)
 from ..pb.bastionlab_polars_pb2_grpc import PolarsServiceStub
 from ..pb.bastionlab_pb2 import Reference
 from ..errors import GRPCException
-from ._utils import deserialize_dataframe, serialize_dataframe
+from ..version import __version__ as app_version
+from ._utils import (
+    API_VERSION,
+    CHUNK_SIZE,
+    FEATURES,
+    apply_delta,
+    deserialize_dataframe,
+    serialize_dataframe,
+    FamilyEntryPointSegment,
+    LiteralFramePlanSegment,
+    Metadata,
+    VStackPlanSegment,
+    literal_columns,
+)
 from .policy import Policy, DEFAULT_POLICY
 
+# Rows polars indexes without the `bigidx` feature, see `server_capabilities`.
+_U32_ROWS = 2**32 - 1
+
 
 if TYPE_CHECKING:
     import bastionlab.polars.frame
-    from .frame import FetchableLazyFrame, RemoteArray
+    from .frame import FetchableLazyFrame, RemoteArray, RemoteLazyFrame
     from ..client import Client
 
 
@@ -29,12 +94,27 @@ class BastionLabPolars:
     def __init__(self, client: "Client"):
         self.stub = PolarsServiceStub(client._channel)
         self.client = client
+        # The delta header of the last fetch, if any.
+        self._last_delta = None
+        # The row range of the last fetch, if any.
+        self._last_row_range = None
+        # The workspace references are scoped to, see `use_workspace`.
+        self._workspace = None
+        # The answer to the handshake, once made, see `handshake`.
+        self._handshake = None
+        self._handshake_done = False
+        # Kilobytes of data per fetched chunk, see `set_fetch_chunk_kb`.
+        self._fetch_chunk_kb = 0
 
     def send_df(
         self,
         df: pl.DataFrame,
         policy: Policy = DEFAULT_POLICY,
         sanitized_columns: List[str] = [],
+        optimize_storage: bool = False,
+        allow_lossy_floats: bool = False,
+        name: str = "",
+        tags: List[str] = [],
     ) -> "FetchableLazyFrame":
         """
         This method is used to send `pl.DataFrame` to the BastionLab server.
@@ -50,6 +130,13 @@ class BastionLabPolars:
             sanitized_columns (List[str], optional): This field contains (sensitive) columns in the
                 DataFrame that are to be removed when a Data Scientist wishes to fetch a
                 query performed on the DataFrame.
+            optimize_storage (bool, optional): Whether the server should store columns with the
+                smallest dtype that holds their values. Queries and fetches still see the
+                declared dtypes.
+            allow_lossy_floats (bool, optional): Allow Float64 columns to be stored as Float32
+                even if this loses precision. Only used with `optimize_storage`.
+            name (str, optional): Name the DataFrame can be listed by, see `list_dfs`.
+            tags (List[str], optional): Tags the DataFrame can be listed by, see `list_dfs`.
 
         Returns:
             FetchableLazyFrame
@@ -58,14 +145,38 @@ class BastionLabPolars:
 
         self.client._refresh_session_if_needed()
 
+        if df.height > _U32_ROWS:
+            max_rows = self.server_capabilities()["max_rows"]
+            if df.height > max_rows:
+                print(
+                    f"""{Fore.YELLOW}Warning: the DataFrame has {df.height} rows but the server indexes at most {max_rows}.
+It rejects the upload unless it was built with the `bigidx` cargo feature.{Fore.WHITE}"""
+                )
+
         res = GRPCException._map_error(
             lambda: self.stub.SendDataFrame(
-                serialize_dataframe(df, policy, sanitized_columns)
+                serialize_dataframe(
+                    df,
+                    policy,
+                    sanitized_columns,
+                    optimize_storage,
+                    allow_lossy_floats,
+                    name=name,
+                    tags=tags,
+                    chunk_size=self._upload_chunk_size(),
+                )
             )
         )
+        self._attach_to_scope(res.identifier)
         return FetchableLazyFrame._from_reference(self, res)
 
-    def _fetch_df(self, ref: str) -> Optional[pl.DataFrame]:
+    def _fetch_df(
+        self,
+        ref: str,
+        request: Optional[ReferenceRequest] = None,
+        purpose: Optional[Purpose] = None,
+        columns: Optional[List[str]] = None,
+    ) -> Optional[pl.DataFrame]:
         """
         Fetches the specified `pl.DataFrame` from the BastionLab server
         with the provided reference identifier.
@@ -73,15 +184,27 @@ class BastionLabPolars:
         Args:
             ref : strget_df
                 A unique identifier for the Remote DataFrame.
+            request : Optional[ReferenceRequest]
+                The request to send instead of a plain fetch of `ref`.
+            purpose : Optional[Purpose]
+                Why the result is fetched, see `_purpose`.
+            columns : Optional[List[str]]
+                The columns to fetch, in this order, every column if None or empty.
 
         Returns:
             Optional[pl.DataFrame]
         """
+        if request is None:
+            request = ReferenceRequest(
+                identifier=ref, restore_dtypes=True, purpose=purpose, columns=columns
+            )
+        if request.chunk_kb == 0:
+            request.chunk_kb = self._fetch_chunk_kb
 
         def make_chunks_iter() -> Iterator[bytes]:
             blocked = False
 
-            for b in self.stub.FetchDataFrame(ReferenceRequest(identifier=ref)):
+            for b in self.stub.FetchDataFrame(request):
                 if blocked:
                     blocked = False
                     print(
@@ -105,6 +228,12 @@ Reason: {b.warning}
 This incident will be reported to the data owner.{Fore.WHITE}"""
                     )
 
+                if b.HasField("delta"):
+                    self._last_delta = b.delta
+
+                if b.HasField("row_range"):
+                    self._last_row_range = b.row_range
+
                 yield b.data
 
         self.client._refresh_session_if_needed()
@@ -123,9 +252,127 @@ This incident will be reported to the data owner.{Fore.WHITE}"""
             else:
                 raise e
 
+    def _fetch_scalar(self, ref: str, purpose: Optional[Purpose] = None) -> Any:
+        """
+        Fetches a result with one row and one column as a Python value.
+
+        Args:
+            ref : str
+                A unique identifier for the Remote DataFrame.
+            purpose : Optional[Purpose]
+                Why the result is fetched, see `_purpose`.
+
+        Returns:
+            Any: An `int`, `float`, `str`, `bool` or `None`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.FetchScalar(
+                ReferenceRequest(identifier=ref, purpose=purpose)
+            )
+        )
+        if res.warning != "":
+            print(
+                f"""{Fore.YELLOW}Warning: non privacy-preserving query.
+Reason: {res.warning}
+
+This incident will be reported to the data owner.{Fore.WHITE}"""
+            )
+        kind = res.WhichOneof("value")
+        return None if kind in (None, "null") else getattr(res, kind)
+
+    def _fetch_rows(
+        self,
+        ref: str,
+        offset: int,
+        limit: Optional[int] = None,
+        purpose: Optional[Purpose] = None,
+    ) -> Tuple[Optional[pl.DataFrame], int]:
+        """
+        Fetches the `limit` rows of a DataFrame from `offset`, the rest of them if
+        `limit` is None. Offsets past the end fetch no rows.
+
+        Args:
+            ref : str
+                A unique identifier for the Remote DataFrame.
+            offset : int
+                The first row to fetch.
+            limit : Optional[int]
+                The most rows to fetch.
+            purpose : Optional[Purpose]
+                Why the result is fetched, see `_purpose`.
+
+        Returns:
+            Tuple[Optional[pl.DataFrame], int]: The rows, and the number of rows of
+                the whole DataFrame.
+        """
+        self._last_row_range = None
+        df = self._fetch_df(
+            ref,
+            ReferenceRequest(
+                identifier=ref,
+                restore_dtypes=True,
+                offset=offset,
+                limit=limit,
+                purpose=purpose,
+            ),
+        )
+        total_rows = self._last_row_range.total_rows if self._last_row_range else 0
+        return df, total_rows
+
+    def _fetch_delta(
+        self,
+        ref: str,
+        since: str,
+        previous: pl.DataFrame,
+        keys: List[str],
+        purpose: Optional[Purpose] = None,
+    ) -> Optional[pl.DataFrame]:
+        """
+        Fetches a new version of a result, receiving only the rows changed since `since`, a
+        version fetched before as `previous`.
+
+        Args:
+            ref : str
+                A unique identifier for the Remote DataFrame.
+            since : str
+                The identifier of the version fetched before.
+            previous : pl.DataFrame
+                The version fetched before.
+            keys : List[str]
+                The columns identifying the rows across versions.
+            purpose : Optional[Purpose]
+                Why the result is fetched, see `_purpose`.
+
+        Returns:
+            Optional[pl.DataFrame]
+        """
+        self._last_delta = None
+        df = self._fetch_df(
+            ref,
+            ReferenceRequest(
+                identifier=ref,
+                restore_dtypes=True,
+                delta_since=since,
+                delta_keys=keys,
+                purpose=purpose,
+            ),
+        )
+        header = self._last_delta
+        if df is None or header is None:
+            return df
+        if header.full:
+            print(
+                f"{Fore.YELLOW}The full result was sent instead of a delta: {header.reason}{Fore.WHITE}"
+            )
+            return df
+        return apply_delta(previous, df, keys)
+
     def _run_query(
         self,
         composite_plan: str,
+        purpose: Optional[Purpose] = None,
     ) -> "FetchableLazyFrame":
         """
         Executes a Composite Plan on the BastionLab server.
@@ -134,6 +381,8 @@ This incident will be reported to the data owner.{Fore.WHITE}"""
         Args:
             composite_plan : str
                 Serialized instructions to be executed on BastionLab server.
+            purpose : Optional[Purpose]
+                Why the query is run, see `_purpose`.
 
         Returns:
             FetchableLazyFrame
@@ -144,22 +393,176 @@ This incident will be reported to the data owner.{Fore.WHITE}"""
         self.client._refresh_session_if_needed()
 
         res = GRPCException._map_error(
-            lambda: self.stub.RunQuery(Query(composite_plan=composite_plan))
+            lambda: self.stub.RunQuery(
+                Query(composite_plan=composite_plan, purpose=purpose)
+            )
         )
+        # Plans with named output slots store a dataframe per slot, see `run_query_outputs`.
+        for output in res.outputs or [res]:
+            self._attach_to_scope(output.identifier)
         return FetchableLazyFrame._from_reference(self, res)
 
-    def list_dfs(self) -> List["FetchableLazyFrame"]:
+    def run_query_outputs(
+        self,
+        composite_plan: str,
+        purpose: Optional[Purpose] = None,
+    ) -> Dict[str, "FetchableLazyFrame"]:
         """
-        Enlists all the DataFrames available on the BastionLab server.
+        Executes a Composite Plan whose segments store outputs in named slots, such as splits
+        and label encodings, on the BastionLab server.
+
+        Args:
+            composite_plan : str
+                Serialized instructions to be executed on BastionLab server.
+            purpose : Optional[Purpose]
+                Why the query is run, see `_purpose`.
 
         Returns:
-            List[FetchableLazyFrame]
+            Dict[str, FetchableLazyFrame]: The outputs by slot, `main` being the dataframe left
+                on the stack if any. Each one has its own policy.
+        """
+
+        from .frame import FetchableLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RunQuery(
+                Query(composite_plan=composite_plan, purpose=purpose)
+            )
+        )
+        outputs = {}
+        for output in res.outputs:
+            self._attach_to_scope(output.identifier)
+            outputs[output.slot] = FetchableLazyFrame._from_reference(self, output)
+        return outputs
+
+    def run_query_batch(
+        self,
+        composite_plans: List[str],
+        fail_fast: bool = False,
+        inline_results: bool = False,
+    ) -> List[
+        Tuple[Optional["FetchableLazyFrame"], Optional[pl.DataFrame], Optional[str]]
+    ]:
+        """
+        Executes several Composite Plans in one call, such as the queries of a dashboard.
+        A failing plan does not fail the others.
+
+        Args:
+            composite_plans : List[str]
+                Serialized instructions to be executed on BastionLab server.
+            fail_fast : bool
+                Skip the plans that have not started once one fails.
+            inline_results : bool
+                Receive the results small enough, and fetchable without approval, along with
+                their reference.
+
+        Returns:
+            List[Tuple[Optional[FetchableLazyFrame], Optional[polars.DataFrame], Optional[str]]]:
+                For each plan, in order: its result, its data if it was inlined, and why it
+                failed or was skipped, if it did not run.
         """
+
         from .frame import FetchableLazyFrame
 
         self.client._refresh_session_if_needed()
 
-        res = GRPCException._map_error(lambda: self.stub.ListDataFrames(Empty()).list)
+        queries = [Query(composite_plan=plan) for plan in composite_plans]
+        res = GRPCException._map_error(
+            lambda: self.stub.RunQueryBatch(
+                QueryBatch(
+                    queries=queries,
+                    fail_fast=fail_fast,
+                    inline_results=inline_results,
+                )
+            )
+        )
+        results = []
+        for batched in res.results:
+            if batched.skipped:
+                results.append((None, None, "Skipped after a failing query"))
+            elif not batched.HasField("result"):
+                results.append((None, None, batched.error))
+            else:
+                self._attach_to_scope(batched.result.identifier)
+                frame = FetchableLazyFrame._from_reference(self, batched.result)
+                data = None
+                if batched.inline_data:
+                    data = deserialize_dataframe(iter([batched.inline_data]))
+                if batched.inline_warning:
+                    print(f"{Fore.YELLOW}Warning: {batched.inline_warning}{Fore.WHITE}")
+                results.append((frame, data, None))
+        return results
+
+    def list_dfs(
+        self,
+        owner: str = "",
+        tag: str = "",
+        name_prefix: str = "",
+        created_after: Optional[int] = None,
+        kind: Optional[str] = None,
+        min_size: Optional[int] = None,
+        max_size: Optional[int] = None,
+        page_size: int = 0,
+        workspace: Optional[str] = None,
+    ) -> List["FetchableLazyFrame"]:
+        """
+        Enlists the DataFrames available on the BastionLab server matching all the given filters,
+        in creation order. The listing is fetched page by page.
+
+        Args:
+            owner (str, optional): Hash of the public key of the user who uploaded or produced
+                the DataFrames.
+            tag (str, optional): A tag given on upload.
+            name_prefix (str, optional): Prefix of the name given on upload.
+            created_after (int, optional): Milliseconds since the Unix epoch.
+            kind (str, optional): `"upload"`, `"result"` or `"synthetic"`.
+            min_size (int, optional): Minimum estimated size in memory, in bytes.
+            max_size (int, optional): Maximum estimated size in memory, in bytes.
+            page_size (int, optional): DataFrames listed per request, 100 if unset and at
+                most 1000.
+            workspace (str, optional): Name of a workspace the DataFrames are members of.
+                Defaults to the workspace set by `use_workspace`, if any.
+
+        Returns:
+            List[FetchableLazyFrame]
+        """
+        from .frame import FetchableLazyFrame
+
+        kinds = {
+            None: DataFrameKind.ANY_KIND,
+            "upload": DataFrameKind.UPLOAD,
+            "result": DataFrameKind.RESULT,
+            "synthetic": DataFrameKind.SYNTHETIC,
+        }
+        if kind not in kinds:
+            raise ValueError(f"Unknown DataFrame kind: {kind}")
+
+        res = []
+        page_token = ""
+        while True:
+            self.client._refresh_session_if_needed()
+            page = GRPCException._map_error(
+                lambda: self.stub.ListDataFrames(
+                    ListDataFramesRequest(
+                        page_size=page_size,
+                        page_token=page_token,
+                        owner=owner,
+                        tag=tag,
+                        name_prefix=name_prefix,
+                        created_after=created_after,
+                        kind=kinds[kind],
+                        min_size=min_size,
+                        max_size=max_size,
+                        workspace=workspace or self._workspace or "",
+                    )
+                )
+            )
+            res.extend(page.list)
+            if page.next_page_token == "":
+                break
+            page_token = page.next_page_token
         return [FetchableLazyFrame._from_reference(self, ref) for ref in res]
 
     def get_df(self, identifier: str) -> "FetchableLazyFrame":
@@ -183,6 +586,57 @@ This incident will be reported to the data owner.{Fore.WHITE}"""
         )
         return FetchableLazyFrame._from_reference(self, res)
 
+    def literal_frame(self, df: pl.DataFrame) -> "RemoteLazyFrame":
+        """
+        Returns a `RemoteLazyFrame` of a small local DataFrame, such as a lookup table, sent inline
+        in the plans using it instead of being uploaded.
+
+        The server caps the cells (rows times columns) of such frames, and data owners may forbid
+        joining them on some key columns of their data.
+
+        Args:
+            df (pl.DataFrame): The DataFrame, of Boolean, integer, float, Utf8 or Date columns.
+
+        Returns:
+            RemoteLazyFrame
+        """
+        from .frame import RemoteLazyFrame
+
+        return RemoteLazyFrame(
+            df.head(0).lazy(),
+            Metadata(self, [LiteralFramePlanSegment(literal_columns(df))]),
+        )
+
+    def vstack(
+        self, frames: List["FetchableLazyFrame"], relaxed: bool = False
+    ) -> "RemoteLazyFrame":
+        """
+        Returns the rows of stored DataFrames, such as the monthly parts of a dataset,
+        stacked in order as a `RemoteLazyFrame`.
+
+        The DataFrames must have the columns of the first one, as the result does.
+        The policy of the result combines those of all the DataFrames.
+
+        Args:
+            frames (List[FetchableLazyFrame]): The DataFrames to stack.
+            relaxed (bool): Whether columns of different but compatible dtypes, such as
+                integers and floats, are cast to a common dtype instead of rejected.
+
+        Returns:
+            RemoteLazyFrame
+        """
+        from .frame import RemoteLazyFrame
+
+        if not frames:
+            raise ValueError("At least one DataFrame is needed")
+        return RemoteLazyFrame(
+            frames[0]._inner,
+            Metadata(
+                self,
+                [VStackPlanSegment([frame.identifier for frame in frames], relaxed)],
+            ),
+        )
+
     def _persist_df(self, identifier: str):
         """
         Saves a Dataframe on the server from a BastionLab DataFrame identifier.
@@ -221,16 +675,1596 @@ This incident will be reported to the data owner.{Fore.WHITE}"""
             lambda: self.stub.DeleteDataFrame(ReferenceRequest(identifier=identifier))
         )
 
-    def RemoteArray(
-        self, identifier: Optional[str] = None, reference: Optional[Reference] = None
-    ) -> "bastionlab.polars.frame.RemoteArray":
-        if not identifier and not reference:
-            raise Exception("Please pass an identifier [str, RemoteArray]")
-        if reference:
-            if reference:
-                identifier = reference.identifier
+    def optimize_storage(
+        self, identifier: str, allow_lossy_floats: bool = False
+    ) -> int:
+        """
+        Shrinks the dtypes a DataFrame is stored with on the server. Only data owners can do this.
 
-        return RemoteArray(self, identifier)
+        Args:
+            identifier (str): A unique identifier for the Remote DataFrame.
+            allow_lossy_floats (bool, optional): Allow Float64 columns to be stored as Float32
+                even if this loses precision.
+
+        Returns:
+            int: The number of bytes saved.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.OptimizeStorage(
+                OptimizeStorageRequest(
+                    identifier=identifier, allow_lossy_floats=allow_lossy_floats
+                )
+            )
+        )
+        return max(res.bytes_before - res.bytes_after, 0)
+
+    def append_df(self, identifier: str, df: pl.DataFrame) -> None:
+        """
+        Appends rows to a DataFrame on the server. Only data owners can do this.
+
+        The rows must have the schema of the DataFrame. They are checked against its data-quality
+        constraints first, and rejected if a constraint with the `Block` severity is breached.
+
+        Args:
+            identifier (str): A unique identifier for the Remote DataFrame.
+            df (pl.DataFrame): The rows to append.
+        """
+        self.client._refresh_session_if_needed()
+
+        GRPCException._map_error(
+            lambda: self.stub.AppendDataFrame(
+                serialize_dataframe(
+                    df,
+                    DEFAULT_POLICY,
+                    [],
+                    append_to=identifier,
+                    chunk_size=self._upload_chunk_size(),
+                )
+            )
+        )
+
+    def upsert_rows(
+        self, identifier: str, df: pl.DataFrame, key_columns: List[str]
+    ) -> Dict[str, int]:
+        """
+        Updates the rows of a DataFrame on the server whose keys match rows of `df`, and inserts
+        the other rows. Only data owners can do this.
+
+        `df` must contain the key columns and may only contain some of the other columns: updated
+        rows keep their values in the missing columns, and inserted rows get nulls. Duplicated
+        keys in `df` are rejected.
+
+        Args:
+            identifier (str): A unique identifier for the Remote DataFrame.
+            df (pl.DataFrame): The rows to merge.
+            key_columns (List[str]): Columns identifying rows.
+
+        Returns:
+            Dict[str, int]: The numbers of `inserted`, `updated` and `unchanged` rows, and the new
+                `version` of the DataFrame.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.UpsertRows(
+                serialize_dataframe(
+                    df,
+                    DEFAULT_POLICY,
+                    [],
+                    append_to=identifier,
+                    key_columns=key_columns,
+                    chunk_size=self._upload_chunk_size(),
+                )
+            )
+        )
+        return {
+            "inserted": res.inserted,
+            "updated": res.updated,
+            "unchanged": res.unchanged,
+            "version": res.version,
+        }
+
+    def set_quality_constraints(
+        self, identifier: str, constraints: List[Dict[str, Any]]
+    ) -> Dict[str, Any]:
+        """
+        Sets the data-quality constraints monitored on every append to a DataFrame.
+        Only data owners can do this.
+
+        Each constraint is a dict with a `name`, a `constraint` (e.g.
+        `{"type": "NullRate", "column": "age", "max": 0.1}`) and a `severity`
+        (`{"type": "Warn"}` or `{"type": "Block"}`).
+
+        Args:
+            identifier (str): A unique identifier for the Remote DataFrame.
+            constraints (List[Dict[str, Any]]): The constraints, replacing the previous ones.
+
+        Returns:
+            Dict[str, Any]: The quality status, see `quality_status`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.SetQualityConstraints(
+                QualityConstraintsRequest(
+                    identifier=identifier, constraints=json.dumps(constraints)
+                )
+            )
+        )
+        return json.loads(res.status)
+
+    def quality_status(self, identifier: str) -> Dict[str, Any]:
+        """
+        Returns the current state of the data-quality constraints of a DataFrame, along with its
+        recent breaches. Only data owners can do this.
+
+        Args:
+            identifier (str): A unique identifier for the Remote DataFrame.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetQualityStatus(ReferenceRequest(identifier=identifier))
+        )
+        return json.loads(res.status)
+
+    def trace_watermark(self, leaked: pl.DataFrame) -> List[Dict[str, Any]]:
+        """
+        Scores every watermarked fetch against a suspected leak of one of your DataFrames.
+        Only data owners can do this. The leaked data is not stored on the server.
+
+        Args:
+            leaked (pl.DataFrame): The leaked data, possibly a subset of the rows and columns of
+                a fetched DataFrame.
+
+        Returns:
+            List[Dict[str, Any]]: The fetches with their recipient and score, best matches first.
+                Scores close to 1 identify the fetch, while unrelated data scores about 0.5.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.TraceWatermark(
+                serialize_dataframe(
+                    leaked, DEFAULT_POLICY, [], chunk_size=self._upload_chunk_size()
+                )
+            )
+        )
+        return [
+            {
+                "fetch_id": m.fetch_id,
+                "recipient": m.recipient,
+                "identifier": m.identifier,
+                "fetch_time": m.fetch_time,
+                "score": m.score,
+                "values": m.values,
+            }
+            for m in res.matches
+        ]
+
+    def recompress(
+        self,
+        zstd_level: Optional[int] = None,
+        dictionary_ratio: Optional[float] = None,
+    ) -> int:
+        """
+        Rewrites every persisted DataFrame on the server with new compression settings.
+        Only data owners can do this.
+
+        Args:
+            zstd_level (Optional[int]): zstd level of compressed columns, 0 disables compression.
+                Defaults to the server's configuration.
+            dictionary_ratio (Optional[float]): String columns whose distinct/total ratio is at
+                most this value are dictionary-encoded. Defaults to the server's configuration.
+
+        Returns:
+            int: The number of bytes saved, negative if the new settings use more space.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.Recompress(
+                RecompressRequest(
+                    zstd_level=zstd_level, dictionary_ratio=dictionary_ratio
+                )
+            )
+        )
+        return res.bytes_before - res.bytes_after
+
+    def migrate_semantics(
+        self, identifiers: Optional[List[str]] = None, confirm: bool = False
+    ) -> List[Dict[str, Any]]:
+        """
+        Re-tags DataFrames produced under an older query semantics version with the current one.
+        Only data owners can do this.
+
+        Without `confirm`, this only lists the DataFrames that would be migrated, so that their
+        owner can check the behaviors that change before confirming.
+
+        Args:
+            identifiers (Optional[List[str]]): DataFrames to migrate, all of them by default.
+            confirm (bool): Apply the migration.
+
+        Returns:
+            List[Dict[str, Any]]: The identifier, old and new versions, and the shims of the old
+                version that no longer apply, for each migrated DataFrame.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.MigrateSemantics(
+                SemanticsMigrationRequest(
+                    identifiers=identifiers or [], confirm=confirm
+                )
+            )
+        )
+        return [
+            {
+                "identifier": m.identifier,
+                "from_version": m.from_version,
+                "to_version": m.to_version,
+                "shims": list(m.shims),
+            }
+            for m in res.migrations
+        ]
+
+    def register_family(
+        self,
+        name: str,
+        column: str,
+        transform: str = "Identity",
+        members: Dict[str, Union[int, str]] = {},
+    ) -> Dict[str, Any]:
+        """
+        Registers a family of DataFrames partitioned by hand, e.g. one DataFrame per month.
+        Only data owners can do this.
+
+        Every row of a member must belong to its partition, and all members must have the same
+        schema.
+
+        Args:
+            name (str): Name of the family.
+            column (str): Column the partitions are computed from.
+            transform (str, optional): How partitions are derived from `column`: `Identity` for
+                the value itself, or `Year`, `Month` or `Day` for date columns. Months and days are
+                strings such as "2023-01" and "2023-01-31", years are integers.
+            members (Dict[str, Union[int, str]], optional): Identifier and partition value of the
+                initial members.
+
+        Returns:
+            Dict[str, Any]: The partition key and members of the family.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RegisterFamily(
+                RegisterFamilyRequest(
+                    name=name,
+                    partition_key=json.dumps({"column": column, "transform": transform}),
+                    members=_family_members(members),
+                )
+            )
+        )
+        return _family_dict(res)
+
+    def add_family_members(
+        self, name: str, members: Dict[str, Union[int, str]]
+    ) -> Dict[str, Any]:
+        """
+        Adds members to a family, all of them or none. Only data owners can do this.
+
+        Args:
+            name (str): Name of the family.
+            members (Dict[str, Union[int, str]]): Identifier and partition value of the new members.
+
+        Returns:
+            Dict[str, Any]: The partition key and members of the family.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.AddFamilyMembers(
+                FamilyMembersRequest(name=name, members=_family_members(members))
+            )
+        )
+        return _family_dict(res)
+
+    def remove_family_members(self, name: str, identifiers: List[str]) -> Dict[str, Any]:
+        """
+        Removes members from a family, all of them or none. Only data owners can do this.
+
+        Members must be removed from their family before they can be deleted.
+
+        Args:
+            name (str): Name of the family.
+            identifiers (List[str]): Identifiers of the members to remove.
+
+        Returns:
+            Dict[str, Any]: The partition key and members of the family.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RemoveFamilyMembers(
+                RemoveFamilyMembersRequest(name=name, identifiers=identifiers)
+            )
+        )
+        return _family_dict(res)
+
+    def get_family(
+        self,
+        name: str,
+        partitions: Optional[List[Union[int, str]]] = None,
+        start: Optional[Union[int, str]] = None,
+        end: Optional[Union[int, str]] = None,
+    ) -> "RemoteLazyFrame":
+        """
+        Returns the members of a family whose partition matches, concatenated, as a
+        `RemoteLazyFrame`. The other members are not read by the query.
+
+        Args:
+            name (str): Name of the family.
+            partitions (Optional[List[Union[int, str]]]): Partitions to read. If unset, the
+                partitions between `start` and `end` are read instead.
+            start (Optional[Union[int, str]]): First partition to read, included.
+                Defaults to the first partition of the family.
+            end (Optional[Union[int, str]]): Last partition to read, included.
+                Defaults to the last partition of the family.
+
+        Returns:
+            RemoteLazyFrame
+        """
+        from .frame import FetchableLazyFrame, RemoteLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetFamily(FamilyRequest(name=name))
+        )
+        if partitions is not None:
+            predicate = {"type": "In", "values": partitions}
+        else:
+            predicate = {"type": "Range", "from": start, "to": end}
+
+        schema = FetchableLazyFrame._from_reference(
+            self, ReferenceResponse(identifier=name, header=res.header)
+        )
+        return RemoteLazyFrame(
+            schema._inner,
+            Metadata(self, [FamilyEntryPointSegment(name, predicate)]),
+        )
+
+    def register_pipeline(
+        self,
+        name: str,
+        plan: Union[str, "RemoteLazyFrame"],
+        parameters: Optional[Dict[str, str]] = None,
+        visibility: str = "private",
+        members: Optional[List[str]] = None,
+    ) -> Dict[str, Any]:
+        """
+        Registers a named pipeline, or a new version of it if `name` is already registered.
+        Only the author of a pipeline can add versions.
+
+        Args:
+            name (str): Name of the pipeline.
+            plan (Union[str, RemoteLazyFrame]): The composite plan of the pipeline. Values can be
+                replaced by placeholders `{"$param": "<name>"}` in its serialized form, which is
+                the `composite_plan` of a `RemoteLazyFrame`.
+            parameters (Optional[Dict[str, str]]): Type of each parameter, by name: `"Int"`,
+                `"Float"`, `"Str"`, `"Bool"`, or `"Identifier"` for the identifier of a
+                DataFrame read by the pipeline.
+            visibility (str): `"private"`, `"group"` or `"public"`.
+            members (Optional[List[str]]): User identifiers that can run a `"group"` pipeline.
+
+        Returns:
+            Dict[str, Any]: The registered pipeline, with its version.
+        """
+        from .frame import RemoteLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        if isinstance(plan, RemoteLazyFrame):
+            plan = plan.composite_plan
+        visibility = {"type": visibility.capitalize()}
+        if visibility["type"] == "Group":
+            visibility["members"] = members or []
+        res = GRPCException._map_error(
+            lambda: self.stub.RegisterPipeline(
+                RegisterPipelineRequest(
+                    name=name,
+                    plan=plan,
+                    parameters=json.dumps(
+                        [{"name": k, "type": v} for k, v in (parameters or {}).items()]
+                    ),
+                    visibility=json.dumps(visibility),
+                )
+            )
+        )
+        return _pipeline_dict(res)
+
+    def list_pipelines(self) -> List[Dict[str, Any]]:
+        """
+        Lists the latest version of the pipelines you can run.
+
+        Returns:
+            List[Dict[str, Any]]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.ListPipelines(Empty()).list)
+        return [_pipeline_dict(p) for p in res]
+
+    def get_pipeline(self, name: str, version: Optional[int] = None) -> Dict[str, Any]:
+        """
+        Returns a pipeline, at its latest version unless `version` is set.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetPipeline(
+                PipelineRequest(name=name, version=version or 0)
+            )
+        )
+        return _pipeline_dict(res)
+
+    def run_pipeline(
+        self,
+        name: str,
+        parameters: Optional[Dict[str, Any]] = None,
+        version: Optional[int] = None,
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+    ) -> "FetchableLazyFrame":
+        """
+        Runs a pipeline, at its latest version unless `version` is set. The version that ran is
+        recorded in the lineage of the result.
+
+        Args:
+            name (str): Name of the pipeline.
+            parameters (Optional[Dict[str, Any]]): The value of each parameter, by name.
+            version (Optional[int]): The version to run.
+            purpose (Optional[str]): Code of the purpose of the run, from the list defined by the
+                data owner, required by some policies.
+            purpose_text (str): Free text detailing the purpose.
+
+        Returns:
+            FetchableLazyFrame
+        """
+        from .frame import FetchableLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        reference = f"pipeline:{name}" if version is None else f"pipeline:{name}@{version}"
+        res = GRPCException._map_error(
+            lambda: self.stub.RunQuery(
+                Query(
+                    composite_plan=reference,
+                    parameters={
+                        k: json.dumps(v) for k, v in (parameters or {}).items()
+                    },
+                    purpose=_purpose(purpose, purpose_text),
+                )
+            )
+        )
+        return FetchableLazyFrame._from_reference(self, res)
+
+    def transfer_dataframe(
+        self,
+        identifier: str,
+        address: str,
+        credential: str,
+        policy: Policy = DEFAULT_POLICY,
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+    ) -> Dict[str, Any]:
+        """
+        Uploads a result to another BastionLab server, without downloading it: the server
+        checks the transfer as a fetch, waiting for the approval of the data owner if needed,
+        and uploads the rows itself. The transfer is recorded by both servers under the same
+        correlation id.
+
+        Args:
+            identifier (str): The result to transfer.
+            address (str): Address of the destination server, e.g. `https://partner:50056`.
+            credential (str): PEM-encoded PKCS#8 key of an identity of the destination server,
+                which the result is uploaded as.
+            policy (Policy, optional): Policy of the dataframe on the destination server.
+            purpose (Optional[str]): Code of the purpose of the transfer, required by some
+                policies.
+            purpose_text (str): Free text detailing the purpose.
+
+        Returns:
+            Dict[str, Any]: The `identifier` of the dataframe on the destination server and the
+                `receipt` of the transfer, with its `correlation_id`, `rows` and
+                `transferred_at` time.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.TransferDataFrame(
+                TransferRequest(
+                    identifier=identifier,
+                    address=address,
+                    credential=credential,
+                    policy=to_json(policy),
+                    purpose=_purpose(purpose, purpose_text),
+                )
+            )
+        )
+        return {
+            "identifier": res.reference.identifier,
+            "receipt": {
+                "correlation_id": res.receipt.correlation_id,
+                "identifier": res.receipt.identifier,
+                "address": res.receipt.address,
+                "destination_identifier": res.receipt.destination_identifier,
+                "rows": res.receipt.rows,
+                "transferred_at": res.receipt.transferred_at,
+            },
+        }
+
+    def generate_synthetic(
+        self,
+        identifier: str,
+        rows: Optional[int] = None,
+        seed: Optional[int] = None,
+    ) -> "FetchableLazyFrame":
+        """
+        Generates a synthetic RDF matching the per-column marginals of an RDF whose policy enables
+        it. The result records its source and seed in its header, and can be fetched freely.
+
+        Args:
+            identifier (str): Identifier of the source RDF.
+            rows (Optional[int]): Number of rows to generate. Defaults to the row count of the source.
+            seed (Optional[int]): Seed making the generation reproducible. Defaults to a random seed.
+
+        Returns:
+            FetchableLazyFrame
+        """
+        from .frame import FetchableLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GenerateSynthetic(
+                SyntheticRequest(identifier=identifier, rows=rows, seed=seed)
+            )
+        )
+        return FetchableLazyFrame._from_reference(self, res)
+
+    def register_view(
+        self,
+        name: str,
+        plan: Union[str, "RemoteLazyFrame"],
+        max_staleness_secs: int = 0,
+    ) -> Dict[str, Any]:
+        """
+        Registers a materialized view: a group-by over an RDF whose result the server keeps up to
+        date as rows are appended or upserted. Queries running the same group-by are answered from
+        the view. Only data owners can do this.
+
+        Args:
+            name (str): Name of the view.
+            plan (Union[str, RemoteLazyFrame]): The group-by, e.g.
+                `rdf.groupby("city").agg(pl.col("amount").sum())`, or its `composite_plan`.
+            max_staleness_secs (int, optional): How long the view keeps answering queries once it
+                fell behind its RDF, e.g. because it could not be updated. Defaults to 0.
+
+        Returns:
+            Dict[str, Any]: The view, with the version of the RDF it was computed on and its state.
+        """
+        from .frame import RemoteLazyFrame
+
+        self.client._refresh_session_if_needed()
+
+        if isinstance(plan, RemoteLazyFrame):
+            plan = plan.composite_plan
+        res = GRPCException._map_error(
+            lambda: self.stub.RegisterView(
+                RegisterViewRequest(
+                    name=name,
+                    composite_plan=plan,
+                    max_staleness_secs=max_staleness_secs,
+                )
+            )
+        )
+        return _view_dict(res)
+
+    def refresh_view(self, name: str) -> Dict[str, Any]:
+        """
+        Recomputes a view from its RDF, e.g. once it is stale or invalid. Only data owners can do
+        this.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RefreshView(ViewRequest(name=name))
+        )
+        return _view_dict(res)
+
+    def get_view(self, name: str) -> Dict[str, Any]:
+        """
+        Returns a view, with its `state`: `fresh`, `stale` or `invalid`, and the `reason` of the
+        last two.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.GetView(ViewRequest(name=name)))
+        return _view_dict(res)
+
+    def update_draft(
+        self,
+        identifier: str,
+        policy: Optional[Policy] = None,
+        sanitized_columns: Optional[List[str]] = None,
+        name: Optional[str] = None,
+        tags: Optional[List[str]] = None,
+    ) -> Dict[str, Any]:
+        """
+        Edits an RDF still in the `draft` state. Only its owner can do this. Arguments left to
+        `None` are kept.
+
+        Returns:
+            Dict[str, Any]: The onboarding state of the RDF.
+        """
+        self.client._refresh_session_if_needed()
+
+        request = UpdateDraftRequest(identifier=identifier)
+        if policy is not None:
+            request.policy = to_json(policy)
+        if sanitized_columns is not None:
+            request.sanitized_columns.CopyFrom(StringList(values=sanitized_columns))
+        if name is not None:
+            request.name = name
+        if tags is not None:
+            request.tags.CopyFrom(StringList(values=tags))
+        res = GRPCException._map_error(lambda: self.stub.UpdateDraft(request))
+        return _lifecycle_dict(res)
+
+    def submit_for_review(self, identifier: str) -> Dict[str, Any]:
+        """
+        Submits a draft RDF to the reviewers, who publish it or send it back as a draft.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.SubmitForReview(ReferenceRequest(identifier=identifier))
+        )
+        return _lifecycle_dict(res)
+
+    def review(self, identifier: str, approve: bool, reason: str = "") -> Dict[str, Any]:
+        """
+        Publishes an RDF in review, or rejects it back to the `draft` state with a `reason` shown
+        to its owner. Only reviewers can do this.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.ReviewDataFrame(
+                ReviewRequest(identifier=identifier, approve=approve, reason=reason)
+            )
+        )
+        return _lifecycle_dict(res)
+
+    def unpublish(self, identifier: str) -> Dict[str, Any]:
+        """
+        Makes a published RDF a draft again. This fails like a deletion would, e.g. while it is
+        exported, and drops its materialized views.
+
+        Returns:
+            Dict[str, Any]: The onboarding state, with the `dropped_views`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.UnpublishDataFrame(ReferenceRequest(identifier=identifier))
+        )
+        return _lifecycle_dict(res)
+
+    def get_lifecycle(self, identifier: str) -> Dict[str, Any]:
+        """
+        Returns the onboarding state of an RDF: `draft`, `in_review` or `published`. Its owner and
+        reviewers also get a `preview` of its first rows, with the sanitized columns nulled out.
+
+        Returns:
+            Dict[str, Any]
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetLifecycle(ReferenceRequest(identifier=identifier))
+        )
+        return _lifecycle_dict(res)
+
+    def deduplicate(self, identifier: str, canonical: str) -> Dict[str, Any]:
+        """
+        Deletes an RDF holding the same data as `canonical`. Its identifier keeps resolving to
+        `canonical`, whose policy applies, until the alias expires. Only data owners can do this.
+
+        Returns:
+            Dict[str, Any]: The `alias`, its `canonical` identifier, `reason` and `created_at`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.DeduplicateDataFrame(
+                DeduplicateRequest(identifier=identifier, canonical=canonical)
+            )
+        )
+        return _alias_dict(res)
+
+    def create_alias(self, alias: str, canonical: str, reason: str = "") -> Dict[str, Any]:
+        """
+        Makes the identifier `alias` resolve to `canonical`. Only data owners can do this.
+
+        Returns:
+            Dict[str, Any]: The `alias`, its `canonical` identifier, `reason` and `created_at`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.CreateAlias(
+                AliasRequest(alias=alias, canonical=canonical, reason=reason)
+            )
+        )
+        return _alias_dict(res)
+
+    def create_reproducibility_bundle(self, identifier: str) -> bytes:
+        """
+        Creates a signed bundle of how a result was produced: its plan, the versions, policies and
+        content hashes of its inputs, the engine versions, its trace, and the schema and content
+        hash of the result. It holds no data. Only the owner of the result and data owners can do
+        this.
+
+        The bundle can be checked offline against the `bundle_signing_key` of the server's
+        capabilities.
+
+        Returns:
+            bytes: The signed JSON bundle.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.CreateReproducibilityBundle(
+                ReferenceRequest(identifier=identifier)
+            )
+        )
+        return res.bundle
+
+    def verify_reproducibility_bundle(self, bundle: bytes) -> Dict[str, Any]:
+        """
+        Checks the inputs of a bundle created by this server against the current RDFs, and runs
+        its plan again.
+
+        Returns:
+            Dict[str, Any]: Whether the `inputs_match` and the `result_matches`, the
+                `expected_hash` and `recomputed_hash` of the result, and the `mismatches` found.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.VerifyReproducibilityBundle(
+                ReproducibilityBundle(bundle=bundle)
+            )
+        )
+        return {
+            "inputs_match": res.inputs_match,
+            "result_matches": res.result_matches,
+            "expected_hash": res.expected_hash,
+            "recomputed_hash": res.recomputed_hash,
+            "mismatches": list(res.mismatches),
+        }
+
+    def usage_report(
+        self, identifier: Optional[str] = None, since: int = 0
+    ) -> Dict[str, Dict[str, Any]]:
+        """
+        Reports the accesses to your RDFs by purpose code, from the access log of the server.
+        Only data owners can do this.
+
+        Args:
+            identifier (Optional[str]): Only report the accesses touching this RDF.
+            since (int): Only report the accesses since then, in milliseconds since the Unix
+                epoch.
+
+        Returns:
+            Dict[str, Dict[str, Any]]: For each purpose code, the number of `queries`,
+                `fetches` and `transfers` and the `users` behind them. Accesses without a
+                purpose are reported under `""`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetUsageReport(
+                UsageReportRequest(identifier=identifier or "", since=since)
+            )
+        )
+        return {
+            usage.code: {
+                "queries": usage.queries,
+                "fetches": usage.fetches,
+                "transfers": usage.transfers,
+                "users": list(usage.users),
+            }
+            for usage in res.purposes
+        }
+
+    def used_export_waivers(
+        self, identifier: Optional[str] = None, since: int = 0
+    ) -> List[Dict[str, Any]]:
+        """
+        Lists the export waivers you issued that were used, see `create_export_waiver`.
+
+        Args:
+            identifier (Optional[str]): Only list the waivers covering this RDF.
+            since (int): Only list the waivers used since then, in milliseconds since the Unix
+                epoch.
+
+        Returns:
+            List[Dict[str, Any]]: The waivers, oldest use first, see `create_export_waiver`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetUsageReport(
+                UsageReportRequest(identifier=identifier or "", since=since)
+            )
+        )
+        return [_export_waiver_dict(waiver) for waiver in res.waivers]
+
+    def create_export_waiver(
+        self, identifier: str, recipient: str, expires_at: int, justification: str
+    ) -> Dict[str, Any]:
+        """
+        Issues a one-time waiver of the masks and watermarks of an RDF, for `recipient` to fetch
+        it pristine with `fetch_with_waiver`. Only the owners of the data the RDF was computed
+        from can do this, and each use is logged with its justification.
+
+        Args:
+            identifier (str): The RDF the waiver covers.
+            recipient (str): The identity allowed to use the waiver.
+            expires_at (int): When the waiver expires, in milliseconds since the Unix epoch.
+            justification (str): Why the RDF must be fetched pristine.
+
+        Returns:
+            Dict[str, Any]: The waiver, with its `id` and, once used, its `used_at` time and the
+                `content_hash` of the dataframe it released.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.CreateExportWaiver(
+                ExportWaiverRequest(
+                    identifier=identifier,
+                    recipient=recipient,
+                    expires_at=expires_at,
+                    justification=justification,
+                )
+            )
+        )
+        return _export_waiver_dict(res)
+
+    def fetch_with_waiver(
+        self, identifier: str, waiver: str
+    ) -> Optional[pl.DataFrame]:
+        """
+        Fetches an RDF without masks nor watermarks, under an export waiver a data owner issued
+        to you with `create_export_waiver`. Waivers can only be used once.
+
+        Args:
+            identifier (str): The RDF the waiver covers.
+            waiver (str): The id of the waiver.
+
+        Returns:
+            Optional[pl.DataFrame]
+        """
+        return self._fetch_df(
+            identifier,
+            ReferenceRequest(
+                identifier=identifier, restore_dtypes=True, waiver=waiver
+            ),
+        )
+
+    def list_running_queries(self) -> Dict[str, Any]:
+        """
+        Lists the queries in flight: every query for data owners, your own for others.
+
+        Returns:
+            Dict[str, Any]: The `queries`, oldest first, with their `id`, `identity`,
+                `submitted_at`, `started_at`, `queue_wait_ms`, `segment` out of `segments`,
+                `inputs`, estimated `progress` and `state` (`queued`, `running` or
+                `finishing`), and for data owners the last `kills`, see `kill_query`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.ListRunningQueries(Empty()))
+        return {
+            "queries": [_running_query_dict(query) for query in res.queries],
+            "kills": [_query_kill_dict(kill) for kill in res.kills],
+        }
+
+    def kill_query(self, id: str, reason: str) -> Dict[str, Any]:
+        """
+        Kills a query in flight, one of yours unless you are a data owner. Running queries stop
+        at their next segment boundary, and store nothing.
+
+        Args:
+            id (str): The id of the query, see `list_running_queries`.
+            reason (str): Why it is killed, recorded with the kill.
+
+        Returns:
+            Dict[str, Any]: The kill, with the `state` of the query when killed: `finishing`
+                queries complete anyway.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.KillQuery(KillQueryRequest(id=id, reason=reason))
+        )
+        return _query_kill_dict(res)
+
+    def list_pending_requests(self) -> List[Dict[str, Any]]:
+        """
+        Lists the fetches pending the approval of a data owner. Only data owners can.
+
+        Returns:
+            List[Dict[str, Any]]: The requests, oldest first, with their `id`, the
+                `identifier` of the RDF to fetch, the `identity` that requested it, the
+                `plan` that produced it, the `reason` it needs approval, and when it was
+                `requested_at` and `expires_at`, in milliseconds since the Unix epoch.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.ListPendingRequests(Empty()))
+        return [_pending_request_dict(request) for request in res.requests]
+
+    def approve_request(self, id: str) -> Dict[str, Any]:
+        """
+        Approves a pending fetch, which then receives the RDF. Only data owners can.
+
+        Args:
+            id (str): The id of the request, see `list_pending_requests`.
+
+        Returns:
+            Dict[str, Any]: The approved request.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.ApproveRequest(ApproveRequestRequest(id=id))
+        )
+        return _pending_request_dict(res)
+
+    def reject_request(self, id: str, reason: str = "") -> Dict[str, Any]:
+        """
+        Rejects a pending fetch, which then fails. Only data owners can.
+
+        Args:
+            id (str): The id of the request, see `list_pending_requests`.
+            reason (str): Why it is rejected, sent to whoever requested the fetch.
+
+        Returns:
+            Dict[str, Any]: The rejected request.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RejectRequest(RejectRequestRequest(id=id, reason=reason))
+        )
+        return _pending_request_dict(res)
+
+    def get_audit_log(
+        self, since: int = 0, until: int = 0, identifier: str = ""
+    ) -> List[Dict[str, Any]]:
+        """
+        Reads the audit log: who ran, fetched, transferred and uploaded which RDFs, and
+        how the policy decided. Only data owners can.
+
+        Args:
+            since (int): Earliest entry, in milliseconds since the Unix epoch.
+            until (int): Entries before it only, in milliseconds since the Unix epoch,
+                unbounded if 0.
+            identifier (str): Only entries about this RDF, or computed from it, if set.
+
+        Returns:
+            List[Dict[str, Any]]: The entries, oldest first, with their `at`,
+                `identity`, `action`, `identifier`, `inputs`, `plan_hash` and
+                `segments` for queries, and `outcome` (`Allowed`, `Pending`, `Denied`
+                or `Failed`).
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetAuditLog(
+                AuditLogRequest(since=since, until=until, identifier=identifier)
+            )
+        )
+        return [_audit_entry_dict(entry) for entry in res.entries]
+
+    def create_workspace(self, name: str) -> Dict[str, Any]:
+        """
+        Creates a workspace: a named set of RDFs handled together, see `attach_to_workspace`.
+
+        Args:
+            name (str): Name of the workspace, unique on the server.
+
+        Returns:
+            Dict[str, Any]: The workspace, see `get_workspace`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.CreateWorkspace(WorkspaceRequest(name=name))
+        )
+        return _workspace_dict(res)
+
+    def get_workspace(self, name: str) -> Dict[str, Any]:
+        """
+        Returns a workspace you own or that is shared with you.
+
+        Returns:
+            Dict[str, Any]: Its `name`, `owner`, `created_at` (milliseconds since the Unix
+                epoch), `members` and the users it is `shared_with`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetWorkspace(WorkspaceRequest(name=name))
+        )
+        return _workspace_dict(res)
+
+    def list_workspaces(self) -> List[Dict[str, Any]]:
+        """
+        Lists the workspaces you own or that are shared with you, by name.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.ListWorkspaces(Empty()))
+        return [_workspace_dict(workspace) for workspace in res.workspaces]
+
+    def delete_workspace(self, name: str, cascade: bool = False) -> Dict[str, Any]:
+        """
+        Deletes one of your workspaces. Its members survive, unless `cascade` is set.
+
+        Returns:
+            Dict[str, Any]: When cascading, the members that were deleted and those that could
+                not be, see `delete_workspace_members`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.DeleteWorkspace(
+                DeleteWorkspaceRequest(name=name, cascade=cascade)
+            )
+        )
+        if self._workspace == name:
+            self._workspace = None
+        return _bulk_dict(res)
+
+    def attach_to_workspace(self, name: str, identifiers: List[str]) -> Dict[str, Any]:
+        """
+        Attaches RDFs to one of your workspaces. You must be able to read all of them.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.AttachToWorkspace(
+                WorkspaceMembersRequest(name=name, identifiers=identifiers)
+            )
+        )
+        return _workspace_dict(res)
+
+    def detach_from_workspace(
+        self, name: str, identifiers: List[str]
+    ) -> Dict[str, Any]:
+        """
+        Detaches RDFs from one of your workspaces, revoking the access its sharing gave to them.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.DetachFromWorkspace(
+                WorkspaceMembersRequest(name=name, identifiers=identifiers)
+            )
+        )
+        return _workspace_dict(res)
+
+    def share_workspace(self, name: str, users: List[str]) -> Dict[str, Any]:
+        """
+        Lets a group of users list, read and query the members of one of your workspaces that you
+        own, for as long as they are members. Replaces the previous group.
+
+        Args:
+            name (str): Name of the workspace.
+            users (List[str]): Hashes of the public keys of the users, empty to unshare.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.ShareWorkspace(
+                ShareWorkspaceRequest(name=name, users=users)
+            )
+        )
+        return _workspace_dict(res)
+
+    def delete_workspace_members(self, name: str) -> Dict[str, Any]:
+        """
+        Deletes the members of one of your workspaces. Data owners can delete any member, other
+        users the results they produced.
+
+        Returns:
+            Dict[str, Any]: The `succeeded` identifiers, and the `failures` of the others with
+                their reason.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.DeleteWorkspaceMembers(WorkspaceRequest(name=name))
+        )
+        return _bulk_dict(res)
+
+    def extend_workspace_retention(self, name: str, seconds: int) -> Dict[str, Any]:
+        """
+        Keeps the members of one of your workspaces for at least `seconds` from now when the
+        server deletes idle results under memory pressure.
+
+        Returns:
+            Dict[str, Any]: See `delete_workspace_members`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.ExtendWorkspaceRetention(
+                RetentionRequest(name=name, seconds=seconds)
+            )
+        )
+        return _bulk_dict(res)
+
+    def export_workspace_manifest(self, name: str) -> Dict[str, Any]:
+        """
+        Describes the members of a workspace you can read, as an export of the workspace holds
+        them: name, owner, kind, rows, schema and content hash.
+
+        Returns:
+            Dict[str, Any]: The `manifest`, and the `failures` of the members left out.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.ExportWorkspaceManifest(WorkspaceRequest(name=name))
+        )
+        return {
+            "manifest": json.loads(res.manifest),
+            "failures": {f.identifier: f.error for f in res.failures},
+        }
+
+    def use_workspace(self, name: Optional[str]) -> None:
+        """
+        Scopes the references of this client to a workspace: uploads and query results are
+        attached to it, and `list_dfs` lists its members. `None` removes the scope.
+        """
+        if name is not None:
+            self.get_workspace(name)
+        self._workspace = name
+
+    def _attach_to_scope(self, identifier: str) -> None:
+        if self._workspace is not None:
+            self.attach_to_workspace(self._workspace, [identifier])
+
+    def server_capabilities(self) -> Dict[str, Any]:
+        """
+        Lists the composite plan segments, formats and optional operations the server was built
+        with. Slim builds may leave operations such as asof joins out: plans using them are
+        rejected before running.
+
+        Returns:
+            Dict[str, Any]: The supported `segments` and `formats`, and for each optional
+                operation, whether it is supported and the cargo feature providing it. The
+                `memory_pressure` level tells whether uploads (from `soft` on) or queries (at
+                `hard`) are currently rejected. `storage_classes` gives the `dataframes`, and
+                their `memory_bytes` and `disk_bytes`, of each storage class. `max_rows` is the
+                largest number of rows of a DataFrame, above 2^32 - 1 only if `big_index`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(lambda: self.stub.GetServerCapabilities(Empty()))
+        return {
+            "segments": list(res.segments),
+            "formats": list(res.formats),
+            "operations": {
+                op.name: {"supported": op.supported, "cargo_feature": op.cargo_feature}
+                for op in res.operations
+            },
+            "memory_pressure": res.memory_pressure,
+            "memory_usage_bytes": res.memory_usage_bytes,
+            "bundle_signing_key": res.bundle_signing_key,
+            "storage_classes": {
+                usage.storage_class: {
+                    "dataframes": usage.dataframes,
+                    "memory_bytes": usage.memory_bytes,
+                    "disk_bytes": usage.disk_bytes,
+                }
+                for usage in res.storage_classes
+            },
+            "big_index": res.big_index,
+            "max_rows": res.max_rows,
+        }
+
+    def handshake(self) -> Optional[Dict[str, Any]]:
+        """
+        Negotiates the API version, features and limits with the server, once per client. Uploads
+        are chunked as the server asks from then on. This is done before the first upload anyway.
+        Deprecated features this client uses are printed along with their sunset dates.
+
+        Returns:
+            Optional[Dict[str, Any]]: The `server_version`, negotiated `api_version`,
+                `enabled_features`, `auth_mode` (`open` or `keys`), `limits` and `deprecations`.
+                None if the server predates handshakes.
+        """
+        if not self._handshake_done:
+            self.client._refresh_session_if_needed()
+            request = HandshakeRequest(
+                client_name="bastionlab_python",
+                client_version=app_version,
+                api_version=API_VERSION,
+                features=FEATURES,
+            )
+            try:
+                self._handshake = GRPCException._map_error(
+                    lambda: self.stub.Handshake(request)
+                )
+            except GRPCException as e:
+                # Servers predating handshakes apply their defaults.
+                if e.code != StatusCode.UNIMPLEMENTED:
+                    raise
+            self._handshake_done = True
+            for deprecation in self._handshake.deprecations if self._handshake else []:
+                print(
+                    f"""{Fore.YELLOW}Warning: {deprecation.feature} is deprecated and may be removed after {deprecation.sunset}.{Fore.WHITE}"""
+                )
+
+        res = self._handshake
+        if res is None:
+            return None
+        return {
+            "server_version": res.server_version,
+            "api_version": res.api_version,
+            "enabled_features": list(res.enabled_features),
+            "auth_mode": res.auth_mode,
+            "limits": {
+                "max_upload_bytes": res.limits.max_upload_bytes,
+                "upload_chunk_bytes": res.limits.upload_chunk_bytes,
+                "max_rows": res.limits.max_rows,
+                "max_literal_frame_cells": res.limits.max_literal_frame_cells,
+                "max_slice_rows": res.limits.max_slice_rows,
+                "max_page_size": res.limits.max_page_size,
+            },
+            "deprecations": {d.feature: d.sunset for d in res.deprecations},
+        }
+
+    def set_fetch_chunk_kb(self, kb: int) -> None:
+        """
+        Asks for fetched dataframes in chunks of `kb` kilobytes of data, or in chunks of the
+        size the server is configured with if 0. The server caps it so that chunks fit in gRPC
+        messages.
+        """
+        self._fetch_chunk_kb = kb
+
+    def _upload_chunk_size(self) -> int:
+        handshake = self.handshake()
+        if handshake is None or handshake["limits"]["upload_chunk_bytes"] == 0:
+            return CHUNK_SIZE
+        return handshake["limits"]["upload_chunk_bytes"]
+
+    def set_storage_class(self, identifier: str, storage_class: str) -> Dict[str, Any]:
+        """
+        Sets the storage class of a dataframe you own, or of any dataframe as a data owner:
+        `hot` dataframes always stay in memory, `warm` ones may be spilled to disk under memory
+        pressure and `cold` ones are read from disk. `auto` lets the server classify the
+        dataframe by its size and how often it is read.
+
+        The change is applied in the background: poll it with `storage_class_job`.
+
+        Returns:
+            Dict[str, Any]: The `job`, with its `identifier`, `storage_class`, `status`
+                (`pending`, `done` or `failed`) and `error`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.SetStorageClass(
+                StorageClassRequest(identifier=identifier, storage_class=storage_class)
+            )
+        )
+        return _storage_class_job_dict(res)
+
+    def storage_class_job(self, job: int) -> Dict[str, Any]:
+        """
+        Returns a storage class change you requested, see `set_storage_class`.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetStorageClassJob(StorageClassJobRequest(job=job))
+        )
+        return _storage_class_job_dict(res)
+
+    def roll_out_policy(
+        self,
+        patch: Dict[str, Any],
+        tags: Optional[List[str]] = None,
+        owner: Optional[str] = None,
+        name_pattern: Optional[str] = None,
+        dry_run: bool = True,
+    ) -> Dict[str, Any]:
+        """
+        Changes the policy of every dataset carrying all of `tags`, uploaded by `owner` and
+        whose name matches the glob `name_pattern`, all at once or not at all. Only data
+        owners can do this.
+
+        `patch` either tightens the policies, `{"op": "Tighten", "policy": {...}}`, or sets
+        some of their fields, `{"op": "Set", "fields": {...}}`. By default, only reports what
+        the rollout would break: set `dry_run=False` to apply it.
+
+        Returns:
+            Dict[str, Any]: The `rollout_id` (empty for dry runs) and the `impacts` on each
+                dataset: results that could be fetched and no longer can
+                (`blocked_results`), results no longer pending approval
+                (`changed_approvals`) and pipelines that would be denied
+                (`blocked_pipelines`).
+        """
+        self.client._refresh_session_if_needed()
+
+        selector = PolicySelector(
+            tags=tags or [], owner=owner or "", name_pattern=name_pattern or ""
+        )
+        res = GRPCException._map_error(
+            lambda: self.stub.RollOutPolicy(
+                PolicyRolloutRequest(
+                    selector=selector, patch=json.dumps(patch), dry_run=dry_run
+                )
+            )
+        )
+        return _rollout_report_dict(res)
+
+    def rollback_policy_rollout(self, rollout_id: str) -> Dict[str, Any]:
+        """
+        Restores the policies a rollout changed, if no later rollout changed them since.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.RollBackPolicyRollout(RolloutRequest(rollout_id=rollout_id))
+        )
+        return _rollout_report_dict(res)
+
+    def policy_history(self, identifier: str) -> List[Dict[str, Any]]:
+        """
+        Returns the changes of the policy of a dataset since its upload, oldest first.
+        """
+        self.client._refresh_session_if_needed()
+
+        res = GRPCException._map_error(
+            lambda: self.stub.GetPolicyHistory(ReferenceRequest(identifier=identifier))
+        )
+        return [
+            {
+                "version": change.version,
+                "rollout_id": change.rollout_id,
+                "changed_at": change.changed_at,
+                "author": change.author,
+                "rollback": change.rollback,
+                "policy": json.loads(change.policy),
+            }
+            for change in res.changes
+        ]
+
+    def RemoteArray(
+        self, identifier: Optional[str] = None, reference: Optional[Reference] = None
+    ) -> "bastionlab.polars.frame.RemoteArray":
+        if not identifier and not reference:
+            raise Exception("Please pass an identifier [str, RemoteArray]")
+        if reference:
+            if reference:
+                identifier = reference.identifier
+
+        return RemoteArray(self, identifier)
+
+
+def _purpose(code: Optional[str], text: str = "") -> Optional[Purpose]:
+    """The purpose of a request, `None` if `code` is unset."""
+    if code is None:
+        if text:
+            raise ValueError("A purpose needs a code")
+        return None
+    return Purpose(code=code, text=text)
+
+
+def _family_members(members: Dict[str, Union[int, str]]) -> List[FamilyMember]:
+    return [
+        FamilyMember(identifier=identifier, partition_value=json.dumps(value))
+        for identifier, value in members.items()
+    ]
+
+
+def _family_dict(res) -> Dict[str, Any]:
+    return {
+        "name": res.name,
+        "partition_key": json.loads(res.partition_key),
+        "members": {m.identifier: json.loads(m.partition_value) for m in res.members},
+    }
+
+
+def _pipeline_dict(res) -> Dict[str, Any]:
+    return {
+        "name": res.name,
+        "version": res.version,
+        "author": res.author,
+        "visibility": json.loads(res.visibility),
+        "parameters": {p["name"]: p["type"] for p in json.loads(res.parameters)},
+        "plan": res.plan,
+    }
+
+
+def _view_dict(res) -> Dict[str, Any]:
+    return {
+        "name": res.name,
+        "base": res.base,
+        "base_version": res.base_version,
+        "groups": res.groups,
+        "incremental": res.incremental,
+        "state": res.state,
+        "reason": res.reason,
+    }
+
+
+def _lifecycle_dict(res) -> Dict[str, Any]:
+    return {
+        "identifier": res.identifier,
+        "state": res.state,
+        "owner": res.owner,
+        "rejection": res.rejection,
+        "preview": res.preview,
+        "dropped_views": list(res.dropped_views),
+    }
+
+
+def _workspace_dict(res) -> Dict[str, Any]:
+    return {
+        "name": res.name,
+        "owner": res.owner,
+        "created_at": res.created_at,
+        "members": list(res.members),
+        "shared_with": list(res.shared_with),
+    }
+
+
+def _export_waiver_dict(res) -> Dict[str, Any]:
+    return {
+        "id": res.id,
+        "identifier": res.identifier,
+        "recipient": res.recipient,
+        "issuer": res.issuer,
+        "justification": res.justification,
+        "created_at": res.created_at,
+        "expires_at": res.expires_at,
+        "used_at": res.used_at,
+        "content_hash": res.content_hash,
+    }
+
+
+def _running_query_dict(res) -> Dict[str, Any]:
+    return {
+        "id": res.id,
+        "identity": res.identity,
+        "submitted_at": res.submitted_at,
+        "started_at": res.started_at,
+        "queue_wait_ms": res.queue_wait_ms,
+        "segment": res.segment,
+        "segments": res.segments,
+        "inputs": list(res.inputs),
+        "progress": res.progress,
+        "state": res.state,
+    }
+
+
+def _query_kill_dict(res) -> Dict[str, Any]:
+    return {
+        "id": res.id,
+        "identity": res.identity,
+        "killed_by": res.killed_by,
+        "reason": res.reason,
+        "at": res.at,
+        "state": res.state,
+    }
+
+
+def _pending_request_dict(res) -> Dict[str, Any]:
+    return {
+        "id": res.id,
+        "identifier": res.identifier,
+        "identity": res.identity,
+        "plan": res.plan,
+        "reason": res.reason,
+        "requested_at": res.requested_at,
+        "expires_at": res.expires_at,
+    }
+
+
+def _audit_entry_dict(res) -> Dict[str, Any]:
+    return {
+        "at": res.at,
+        "identity": res.identity,
+        "action": res.action,
+        "identifier": res.identifier,
+        "inputs": list(res.inputs),
+        "plan_hash": res.plan_hash,
+        "segments": list(res.segments),
+        "outcome": res.outcome,
+    }
+
+
+def _bulk_dict(res) -> Dict[str, Any]:
+    return {
+        "succeeded": list(res.succeeded),
+        "failures": {f.identifier: f.error for f in res.failures},
+    }
+
+
+def _storage_class_job_dict(res) -> Dict[str, Any]:
+    return {
+        "job": res.job,
+        "identifier": res.identifier,
+        "storage_class": res.storage_class,
+        "status": res.status,
+        "error": res.error,
+    }
+
+
+def _rollout_report_dict(res) -> Dict[str, Any]:
+    return {
+        "rollout_id": res.rollout_id,
+        "rolled_back": res.rolled_back,
+        "impacts": [
+            {
+                "identifier": impact.identifier,
+                "blocked_results": list(impact.blocked_results),
+                "changed_approvals": list(impact.changed_approvals),
+                "blocked_pipelines": list(impact.blocked_pipelines),
+            }
+            for impact in res.impacts
+        ],
+    }
+
+
+def _alias_dict(res) -> Dict[str, Any]:
+    return {
+        "alias": res.alias,
+        "canonical": res.canonical,
+        "reason": res.reason,
+        "created_at": res.created_at,
+    }
 
 
 __pdoc__["BastionLabPolars.__init__"] = False
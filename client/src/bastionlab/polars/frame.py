@@ -1,6 +1,17 @@
 from __future__ import annotations
-from dataclasses import dataclass
-from typing import Callable, Generic, List, Optional, TypeVar, Sequence, Union, Dict
+from dataclasses import dataclass, field
+from typing import (
+    Any,
+    Callable,
+    Generic,
+    List,
+    Optional,
+    TypeVar,
+    Sequence,
+    Tuple,
+    Union,
+    Dict,
+)
 import polars as pl
 from polars.internals.sql.context import SQLContext
 import json
@@ -10,7 +21,7 @@ from ..pb.bastionlab_conversion_pb2 import (
     RemoteDataFrame as PbRemoteDataFrame,
 )
 from ..pb.bastionlab_polars_pb2 import ReferenceResponse, SplitRequest, ReferenceRequest
-from .client import BastionLabPolars
+from .client import BastionLabPolars, _purpose
 from .utils import ApplyBins, Palettes, ApplyAbs, VisTools
 import matplotlib.pyplot as plt
 import seaborn as sns
@@ -21,12 +32,15 @@ from serde.json import to_json
 from ._utils import (
     Metadata,
     PolarsPlanSegment,
+    ResourceHints,
     EntryPointPlanSegment,
     UdfPlanSegment,
     StackPlanSegment,
     PlanSegments,
     RowCountSegment,
+    TemporalPlanSegment,
 )
+from .temporal import TemporalExpr
 from .._utils import delegate, delegate_properties
 
 
@@ -156,12 +170,49 @@ class RemoteLazyFrame:
             )
         )
 
-    def collect(self: LDF) -> LDF:
+    def collect(
+        self: LDF,
+        nan_as_null: bool = False,
+        skip_nan: bool = False,
+        allow_spill: Optional[bool] = None,
+        max_memory_mb: Optional[int] = None,
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+    ) -> LDF:
         """runs any pending queries/actions on RemoteLazyFrame that have not yet been performed.
+        Args:
+            nan_as_null (bool): Whether NaN is converted to null throughout the query, so that
+                filters, aggregations, sorts and joins treat it as null. Defaults to False.
+            skip_nan (bool): Whether the aggregations of the pending queries skip NaN like they
+                skip nulls, instead of propagating it. Defaults to False.
+            allow_spill (Optional[bool]): Whether the inputs of the pending queries may be spilled
+                to disk when they exceed `max_memory_mb`. Defaults to the server setting.
+            max_memory_mb (Optional[int]): Memory limit of the pending queries, which fail above it
+                unless spilling is allowed. Defaults to the server setting.
+            purpose (Optional[str]): Code of the purpose of the queries, from the list defined by
+                the data owner, required by some policies.
+            purpose_text (str): Free text detailing the purpose.
         Returns:
             FetchableLazyFrame: FetchableLazyFrame of datarame after any queries have been performed
         """
-        return self._meta._polars_client._run_query(self.composite_plan)
+        plan = to_json(
+            PlanSegments(
+                segments=[
+                    *self._meta._prev_segments,
+                    PolarsPlanSegment(
+                        self._inner,
+                        skip_nan=skip_nan,
+                        resources=ResourceHints(allow_spill, max_memory_mb)
+                        if allow_spill is not None or max_memory_mb is not None
+                        else None,
+                    ),
+                ],
+                nan_as_null=nan_as_null,
+            )
+        )
+        return self._meta._polars_client._run_query(
+            plan, _purpose(purpose, purpose_text)
+        )
 
     @staticmethod
     def sql(query: str, *rdfs: LDF) -> LDF:
@@ -285,6 +336,35 @@ class RemoteLazyFrame:
         # because if not this leads to panics etc. when we follow this with other operations that use the new column before next using collect()
         return ret.collect()
 
+    def with_temporal_columns(self: LDF, **columns: TemporalExpr) -> LDF:
+        """adds columns computed by temporal expressions, in order: later ones can use earlier ones
+        Args:
+            **columns (TemporalExpr): The expressions of the new columns, by name. See `temporal`.
+        Returns:
+            RemoteLazyFrame: The RemoteLazyFrame with the new columns
+        """
+        df = pl.DataFrame(
+            [pl.Series(k, dtype=v) for k, v in self._inner.schema.items()]
+        )
+        ret = RemoteLazyFrame(
+            df.lazy(),
+            Metadata(
+                self._meta._polars_client,
+                [
+                    *self._meta._prev_segments,
+                    PolarsPlanSegment(self._inner),
+                    TemporalPlanSegment(
+                        [
+                            {"name": name, "expr": expr.to_dict()}
+                            for name, expr in columns.items()
+                        ]
+                    ),
+                ],
+            ),
+        )
+        # The server computes the dtypes of the new columns.
+        return ret.collect()
+
     def describe(self: LDF) -> pl.DataFrame:
         """
         Provides the following summary statistics for our RemoteLazyFrame:
@@ -1270,6 +1350,12 @@ class FetchableLazyFrame(RemoteLazyFrame):
     """
 
     _identifier: str
+    # See `fingerprint`.
+    _fingerprint: str = ""
+    _input_fingerprints: Dict[str, str] = field(default_factory=dict)
+    # See `queue_wait_ms` and `queue_position`.
+    _queue_wait_ms: int = 0
+    _queue_position: int = 0
 
     def to_array(self: "FetchableLazyFrame") -> "RemoteArray":
         """
@@ -1294,6 +1380,40 @@ class FetchableLazyFrame(RemoteLazyFrame):
         """
         return self._identifier
 
+    @property
+    def fingerprint(self) -> str:
+        """
+        The fingerprint of the dataframe on the server when this reference was obtained:
+        it changes with every modification of the dataframe, so that fetched results can
+        be cached until it does. Empty for federated dataframes.
+        """
+        return self._fingerprint
+
+    @property
+    def input_fingerprints(self) -> Dict[str, str]:
+        """
+        For query results, the fingerprints of the dataframes they were computed from,
+        by `identifier@version`: a cached result is current while these dataframes have
+        the same fingerprints.
+        """
+        return dict(self._input_fingerprints)
+
+    @property
+    def queue_wait_ms(self) -> int:
+        """
+        For query results, how long the query waited for an execution slot on the server,
+        in milliseconds. 0 if it ran right away.
+        """
+        return self._queue_wait_ms
+
+    @property
+    def queue_position(self) -> int:
+        """
+        For query results, how many queries were to be served before this one when it was
+        queued. 0 if it ran right away.
+        """
+        return self._queue_position
+
     @staticmethod
     def _from_reference(client: BastionLabPolars, ref: ReferenceResponse) -> LDF:
         header = json.loads(ref.header)["inner"]
@@ -1316,6 +1436,10 @@ class FetchableLazyFrame(RemoteLazyFrame):
             _identifier=ref.identifier,
             _inner=df.lazy(),
             _meta=Metadata(client, [EntryPointPlanSegment(ref.identifier)]),
+            _fingerprint=ref.fingerprint,
+            _input_fingerprints=dict(ref.input_fingerprints),
+            _queue_wait_ms=ref.queue_wait_ms,
+            _queue_position=ref.queue_position,
         )
 
     def __str__(self) -> str:
@@ -1324,12 +1448,97 @@ class FetchableLazyFrame(RemoteLazyFrame):
     def __repr__(self) -> str:
         return str(self)
 
-    def fetch(self) -> pl.DataFrame:
+    def fetch(
+        self,
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+        columns: Optional[List[str]] = None,
+    ) -> pl.DataFrame:
         """Fetches your FetchableLazyFrame and returns it as a Polars DataFrame
+        Args:
+            purpose (Optional[str]): Code of the purpose of the fetch, from the list defined by
+                the data owner, required by some policies.
+            purpose_text (str): Free text detailing the purpose.
+            columns (Optional[List[str]]): The columns to fetch, in this order, every
+                column if None. The others stay on the server. Blacklisted columns are
+                refused.
+        Returns:
+            Polars.DataFrame: returns a Polars DataFrame instance of your FetchableLazyFrame
+        """
+        return self._meta._polars_client._fetch_df(
+            self._identifier,
+            purpose=_purpose(purpose, purpose_text),
+            columns=columns,
+        )
+
+    def fetch_scalar(
+        self, purpose: Optional[str] = None, purpose_text: str = ""
+    ) -> Any:
+        """Fetches your FetchableLazyFrame as a value, if it has one row and one column, such as
+        the result of a count.
+        Args:
+            purpose (Optional[str]): Code of the purpose of the fetch, as for `fetch`.
+            purpose_text (str): Free text detailing the purpose.
+        Returns:
+            Any: an `int`, `float`, `str`, `bool` or `None`
+        """
+        return self._meta._polars_client._fetch_scalar(
+            self._identifier, _purpose(purpose, purpose_text)
+        )
+
+    def fetch_rows(
+        self,
+        offset: int,
+        limit: Optional[int] = None,
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+    ) -> Tuple[pl.DataFrame, int]:
+        """Fetches a page of your FetchableLazyFrame: the `limit` rows from `offset`, the
+        rest of them if `limit` is None. Offsets past the end fetch no rows.
+
+        The policy applies to the whole FetchableLazyFrame, whatever the page.
+        Args:
+            offset (int): The first row to fetch.
+            limit (Optional[int]): The most rows to fetch.
+            purpose (Optional[str]): Code of the purpose of the fetch, as for `fetch`.
+            purpose_text (str): Free text detailing the purpose.
+        Returns:
+            Tuple[Polars.DataFrame, int]: the rows, and the number of rows of the whole
+                FetchableLazyFrame, to paginate
+        """
+        return self._meta._polars_client._fetch_rows(
+            self._identifier, offset, limit, _purpose(purpose, purpose_text)
+        )
+
+    def fetch_since(
+        self,
+        previous: "FetchableLazyFrame",
+        previous_df: pl.DataFrame,
+        keys: List[str],
+        purpose: Optional[str] = None,
+        purpose_text: str = "",
+    ) -> pl.DataFrame:
+        """Fetches your FetchableLazyFrame, receiving only the rows changed since a version
+        fetched before.
+
+        The server sends the full result instead when the previous version is gone or when the
+        keys do not identify the rows.
+        Args:
+            previous (FetchableLazyFrame): The version fetched before.
+            previous_df (Polars.DataFrame): The result of fetching `previous`.
+            keys (List[str]): The columns identifying the rows across versions.
+            purpose (Optional[str]): Code of the purpose of the fetch, as for `fetch`.
+            purpose_text (str): Free text detailing the purpose.
         Returns:
             Polars.DataFrame: returns a Polars DataFrame instance of your FetchableLazyFrame
         """
-        return self._meta._polars_client._fetch_df(self._identifier)
+        return self._meta._polars_client._fetch_delta(
+            self._identifier,
+            previous._identifier,
+            previous_df,
+            keys,
+            _purpose(purpose, purpose_text),
+        )
 
     def save(self):
         return self._meta._polars_client._persist_df(self._identifier)
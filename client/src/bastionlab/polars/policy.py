@@ -1,5 +1,5 @@
-from dataclasses import dataclass
-from typing import List, Union
+from dataclasses import dataclass, field
+from typing import Any, Dict, List, Optional, Union
 from serde import serde, InternalTagging
 
 
@@ -96,6 +96,338 @@ class Reject:
     """
 
 
+ProbingResponse = Union["Alert", "Jitter", "ForcePending", "Suspend"]
+"""Defines how queries that look like probing (differencing) attacks should be handled."""
+
+
+@dataclass
+@serde
+class Alert:
+    """
+    Instructs the BastionLab server to only log queries that look like a probing attack.
+    """
+
+
+@dataclass
+@serde
+class Jitter:
+    """
+    Instructs the BastionLab server to delay the response by a random amount of time.
+
+    Args:
+        max_delay_ms : int
+            Maximum delay in milliseconds.
+    """
+
+    max_delay_ms: int
+
+
+@dataclass
+@serde
+class ForcePending:
+    """
+    Instructs the BastionLab server to require the data owner's approval to fetch the result.
+    """
+
+
+@dataclass
+@serde
+class Suspend:
+    """
+    Instructs the BastionLab server to reject the identity's queries on the dataset for some time.
+
+    Args:
+        duration_secs : int
+            Duration of the suspension in seconds.
+    """
+
+    duration_secs: int
+
+
+@dataclass
+@serde
+class Watermark:
+    """
+    Instructs the BastionLab server to embed a recipient-specific watermark in every fetch, so
+    that leaked copies can be traced back to the fetch they came from.
+
+    Args:
+        columns : List[str]
+            Float64 columns that may be perturbed.
+        epsilon : float
+            Marked values are moved by less than this amount.
+    """
+
+    columns: List[str]
+    epsilon: float
+
+
+@dataclass
+@serde
+class MaxOutputRows:
+    """
+    Caps the number of rows of every result computed from the RDF.
+
+    Args:
+        limit : int
+            Maximum number of rows of a result.
+        mode : str
+            `"truncate"` keeps the first `limit` rows of larger results, after any final sort, and
+            warns about the dropped ones. `"reject"` makes larger results non-fetchable.
+            When several RDFs have a cap, the lowest limit applies, rejecting if any cap does.
+    """
+
+    limit: int
+    mode: str = "truncate"
+
+
+@dataclass
+@serde
+class Synthetic:
+    """
+    Lets data scientists generate synthetic look-alikes of the RDF. Synthetic RDFs match the
+    per-column marginals of the original and can be fetched freely.
+
+    Args:
+        identifier_columns : List[str]
+            Columns replaced by format-preserving fakes that never equal a real value.
+            Blacklisted columns are always treated as identifiers.
+        min_category_count : int
+            Categories seen fewer times are never reproduced.
+        max_rows : Optional[int]
+            Maximum number of rows of a synthetic RDF. Defaults to no limit.
+    """
+
+    identifier_columns: List[str] = field(default_factory=list)
+    min_category_count: int = 5
+    max_rows: Optional[int] = None
+
+
+@dataclass
+@serde
+class ResourceCaps:
+    """
+    Caps the resource hints of the queries reading the RDF.
+
+    Args:
+        max_memory_mb : Optional[int]
+            Highest memory limit a query segment may ask for. Defaults to no cap.
+        allow_spill : bool
+            Whether query segments may spill the RDF to disk. Defaults to True.
+    """
+
+    max_memory_mb: Optional[int] = None
+    allow_spill: bool = True
+
+
+@dataclass
+@serde
+class RequirePurpose:
+    """
+    Requires the queries and fetches of the RDF, and of the results computed from it, to state a
+    purpose with one of `allowed_codes`. Other requests are rejected.
+
+    Args:
+        allowed_codes : List[str]
+            The purpose codes requesters may use.
+    """
+
+    allowed_codes: List[str] = field(default_factory=list)
+
+
+Masker = Union["Bucketize", "TopBottomCode", "Redact", "GeneralizeDate", "Replace"]
+"""Masks the values of a column. Column masks apply an ordered list of maskers."""
+
+
+@dataclass
+@serde
+class Bucketize:
+    """
+    Replaces numeric values by the greatest of `edges` they are not below, as floats. Values
+    below the first edge, and NaNs, become null.
+
+    Args:
+        edges : List[float]
+            Increasing bucket edges.
+    """
+
+    edges: List[float]
+
+
+@dataclass
+@serde
+class TopBottomCode:
+    """
+    Clamps numeric values between two percentiles of the column, as floats. Percentiles are read
+    from the statistics the server caches: fetches are refused when they are more than
+    `max_stale_mutations` mutations behind the data, until the owner requests the header of the
+    RDF again.
+
+    Args:
+        bottom : int
+            Percentile values below are raised to.
+        top : int
+            Percentile values above are lowered to, at most 100.
+        max_stale_mutations : int
+            How many mutations the percentiles may lag behind the data. Defaults to 0.
+    """
+
+    bottom: int
+    top: int
+    max_stale_mutations: int = 0
+
+
+@dataclass
+@serde
+class Redact:
+    """
+    Replaces every character of strings with `*` but the first `keep_first` and last `keep_last`
+    ones, and all of them in shorter strings.
+    """
+
+    keep_first: int = 0
+    keep_last: int = 0
+
+
+@dataclass
+@serde
+class GeneralizeDate:
+    """
+    Replaces dates and datetimes by the first day of their period, as dates.
+
+    Args:
+        period : str
+            `"Week"` (starting on Mondays), `"Month"` or `"Quarter"`.
+    """
+
+    period: str
+
+
+@dataclass
+@serde
+class Replace:
+    """
+    Replaces every value with `value`: None, a boolean, a number within the range of the column
+    dtype, a string, or a `"YYYY-MM-DD"` date.
+    """
+
+    value: Any = None
+
+
+Exposure = Union["Full", "Rounded", "CountsOnly", "Hidden"]
+"""What the statistics of a column disclose in metadata responses, such as headers, to requesters
+other than the owner of the RDF."""
+
+
+@dataclass
+@serde
+class Full:
+    """Exact statistics, as columns without an exposure."""
+
+
+@dataclass
+@serde
+class Rounded:
+    """
+    Bounds rounded outwards to multiples of `precision`.
+
+    Args:
+        precision : float
+            Positive step the bounds are rounded to.
+        distinct_floor : int
+            Distinct counts below it are reported as below it. Defaults to 0.
+    """
+
+    precision: float
+    distinct_floor: int = 0
+
+
+@dataclass
+@serde
+class CountsOnly:
+    """
+    Null and distinct counts only, without bounds.
+
+    Args:
+        distinct_floor : int
+            Distinct counts below it are reported as below it. Defaults to 0.
+    """
+
+    distinct_floor: int = 0
+
+
+@dataclass
+@serde
+class Hidden:
+    """No statistics at all."""
+
+
+Mechanism = Union["Laplace", "Gaussian"]
+"""The noise differential privacy adds to aggregates."""
+
+
+@dataclass
+@serde
+class Laplace:
+    """Laplace noise, for pure epsilon differential privacy."""
+
+
+@dataclass
+@serde
+class Gaussian:
+    """
+    Gaussian noise, for (epsilon, delta) differential privacy with epsilon below 1.
+
+    Args:
+        delta : float
+            Probability that the privacy loss exceeds epsilon, between 0 and 1.
+    """
+
+    delta: float
+
+
+@dataclass
+@serde
+class Bounds:
+    """
+    The range the values of a column are clamped to before they are aggregated.
+
+    Args:
+        min : float
+            Lowest value.
+        max : float
+            Highest value.
+    """
+
+    min: float
+    max: float
+
+
+@dataclass
+@serde
+class DifferentialPrivacy:
+    """
+    Releases the counts, sums and means of group-bys the safe zone rejects with calibrated noise,
+    instead of handling them as unsafe. Other unsafe results are rejected, and group-bys with
+    other aggregations fail.
+
+    Args:
+        mechanism : Mechanism
+            The noise added to the aggregates.
+        epsilon : float
+            Privacy spent by each fetch of a noisy result, shared by its aggregates.
+        budget : float
+            Privacy each user may spend on the RDF. Fetches past it are rejected.
+        bounds : Dict[str, Bounds]
+            Bounds of the columns that may be summed and averaged.
+    """
+
+    mechanism: Mechanism
+    epsilon: float
+    budget: float
+    bounds: Dict[str, Bounds] = field(default_factory=dict)
+
+
 serde(AtLeastNOf)
 
 
@@ -112,11 +444,46 @@ class Policy:
             Describes what operations are considered _safe_ on the RDF.
         unsafe_handling : UnsafeAction
             Describes what should happen if a user violates the `safe_zone`. For example (logging operations)
+        probing_response : ProbingResponse
+            Describes what should happen when a query looks like a probing attack. Defaults to `Alert()`.
+        watermark : Optional[Watermark]
+            Watermarking applied to every fetch of the RDF. Defaults to no watermarking.
+        exact_columns : List[str]
+            Columns that must never be distorted, even by watermarking.
+        max_output_rows : Optional[MaxOutputRows]
+            Cap on the rows of every result computed from the RDF. Defaults to no cap.
+        synthetic : Optional[Synthetic]
+            Enables synthetic data generation from the RDF. Defaults to disabled.
+        resource_caps : Optional[ResourceCaps]
+            Caps the resource hints of the queries reading the RDF. Defaults to no cap.
+        require_purpose : Optional[RequirePurpose]
+            Purposes requesters must state to access the RDF. Defaults to none required.
+        literal_join_keys : List[str]
+            Key columns of the RDF that frames sent inline in queries must not be joined on.
+        column_masks : Dict[str, List[Masker]]
+            Maskers applied in order to the values of columns whenever they are fetched,
+            exported, previewed or described. Checked against the column dtypes on upload.
+        metadata_exposure : Dict[str, Exposure]
+            What the statistics of columns disclose in headers to requesters other than the
+            owner. Results computed from the RDF take them too. Defaults to full statistics.
+        differential_privacy : Optional[DifferentialPrivacy]
+            Noise releasing the aggregates the safe zone rejects. Defaults to none.
     """
 
     safe_zone: Rule
     unsafe_handling: UnsafeAction
     savable: bool
+    probing_response: ProbingResponse = field(default_factory=Alert)
+    watermark: Optional[Watermark] = None
+    exact_columns: List[str] = field(default_factory=list)
+    max_output_rows: Optional[MaxOutputRows] = None
+    synthetic: Optional[Synthetic] = None
+    resource_caps: Optional[ResourceCaps] = None
+    require_purpose: Optional[RequirePurpose] = None
+    literal_join_keys: List[str] = field(default_factory=list)
+    column_masks: Dict[str, List[Masker]] = field(default_factory=dict)
+    metadata_exposure: Dict[str, Exposure] = field(default_factory=dict)
+    differential_privacy: Optional[DifferentialPrivacy] = None
 
 
 DEFAULT_POLICY = Policy(
@@ -137,6 +504,32 @@ __all__ = [
     "Log",
     "Review",
     "Reject",
+    "ProbingResponse",
+    "Alert",
+    "Jitter",
+    "ForcePending",
+    "Suspend",
+    "Watermark",
+    "MaxOutputRows",
+    "Synthetic",
+    "ResourceCaps",
+    "RequirePurpose",
+    "Masker",
+    "Bucketize",
+    "TopBottomCode",
+    "Redact",
+    "GeneralizeDate",
+    "Replace",
+    "Exposure",
+    "Full",
+    "Rounded",
+    "CountsOnly",
+    "Hidden",
+    "Mechanism",
+    "Laplace",
+    "Gaussian",
+    "Bounds",
+    "DifferentialPrivacy",
     "Policy",
     "DEFAULT_POLICY",
 ]
@@ -0,0 +1,74 @@
+"""
+Temporal expressions, computed by the server where Polars expressions cannot: ISO-8601 durations
+and calendar offsets, datetime arithmetic, truncation to period boundaries and business-day
+differences. See `RemoteLazyFrame.with_temporal_columns`.
+
+Days, weeks, months and years shift the wall clock of timezone-aware datetimes, hours, minutes and
+seconds are exact. Mixing timezone-naive and timezone-aware datetimes is an error.
+"""
+
+from typing import Any, Dict, List, Optional, Union
+
+WEEKEND = [False, False, False, False, False, True, True]
+
+
+class TemporalExpr:
+    """A typed temporal expression. Supports `+` and `-`."""
+
+    def __init__(self, node: Dict[str, Any]) -> None:
+        self._node = node
+
+    def __add__(self, other: "TemporalExpr") -> "TemporalExpr":
+        return TemporalExpr({"op": "Add", "left": self._node, "right": other._node})
+
+    def __sub__(self, other: "TemporalExpr") -> "TemporalExpr":
+        return TemporalExpr({"op": "Sub", "left": self._node, "right": other._node})
+
+    def truncate(self, every: str) -> "TemporalExpr":
+        """Truncates datetimes or dates to the start of their period, an ISO-8601 duration of a
+        single unit such as `P1M`, `P1W` (weeks start on Mondays), `P1D` or `PT1H`.
+        """
+        return TemporalExpr({"op": "Truncate", "expr": self._node, "every": every})
+
+    def to_dict(self) -> Dict[str, Any]:
+        return self._node
+
+
+def col(name: str) -> TemporalExpr:
+    """A datetime, date or duration column."""
+    return TemporalExpr({"op": "Column", "name": name})
+
+
+def duration(iso: str) -> TemporalExpr:
+    """An ISO-8601 duration such as `P30D`, `P1Y2M` or `-PT15M`."""
+    return TemporalExpr({"op": "Duration", "iso": iso})
+
+
+def business_days(
+    start: TemporalExpr,
+    end: TemporalExpr,
+    weekend: Optional[List[bool]] = None,
+    holidays: Optional[Union[str, Any]] = None,
+    holidays_column: str = "date",
+) -> TemporalExpr:
+    """Business days from `start` to `end`, excluding `end`, negative if `end` is before `start`.
+
+    Args:
+        weekend: Which days of the week, from Monday, are not business days. Saturdays and
+            Sundays by default.
+        holidays: An uploaded RDF, or its identifier, listing holidays in `holidays_column`.
+            Its policy applies to the result.
+    """
+    node = {
+        "op": "BusinessDays",
+        "start": start._node,
+        "end": end._node,
+        "weekend": list(weekend if weekend is not None else WEEKEND),
+    }
+    if holidays is not None:
+        identifier = holidays if isinstance(holidays, str) else holidays.identifier
+        node["holidays"] = {"identifier": identifier, "column": holidays_column}
+    return TemporalExpr(node)
+
+
+__all__ = ["TemporalExpr", "col", "duration", "business_days"]
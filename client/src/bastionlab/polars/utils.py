@@ -3,6 +3,22 @@ import polars as pl
 from ..errors import RequestRejected
 
 
+def is_nan(expr: pl.Expr) -> pl.Expr:
+    """Whether the values of `expr` are NaN. Nulls are not.
+
+    Unlike `pl.Expr.is_nan`, this can be sent to the server, and also accepts integer columns.
+    """
+    return expr != expr
+
+
+def is_finite(expr: pl.Expr) -> pl.Expr:
+    """Whether the values of `expr` are neither NaN, infinite nor null.
+
+    Unlike `pl.Expr.is_finite`, this can be sent to the server.
+    """
+    return (expr - expr) == 0.0
+
+
 class ApplyBins(torch.nn.Module):
     """BastionLab internal class used to serialize user-defined functions (UDF) in TorchScript.
     It uses `torch.nn.Module` and stores the `bin_size`, which is the aggregation count of the query.
@@ -0,0 +1,9 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../../protos/bastionlab_self_test.proto");
+    tonic_build::compile_protos("../../protos/bastionlab_self_test.proto")?;
+
+    Ok(())
+}
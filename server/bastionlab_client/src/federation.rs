@@ -0,0 +1,60 @@
+//! The connector servers read federated dataframes and transfer results with, see
+//! [`bastionlab_polars::federation`].
+
+use bastionlab_polars::access_control::Policy;
+use bastionlab_polars::composite_plan::CompositePlan;
+use bastionlab_polars::federation::{RemoteConnector, RemoteResult, RemoteSource};
+use bastionlab_polars::polars_proto::{self, ReferenceResponse};
+use bastionlab_polars::purpose::Purpose;
+use bastionlab_polars::FetchStatus;
+use polars::prelude::DataFrame;
+use tonic::Status;
+
+use crate::{Client, SigningKey};
+
+/// Runs sub-plans on and uploads results to remote servers with this client, opening a session
+/// per request.
+pub struct ClientConnector;
+
+#[tonic::async_trait]
+impl RemoteConnector for ClientConnector {
+    async fn run(
+        &self,
+        source: &RemoteSource,
+        plan: &CompositePlan,
+        correlation_id: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<RemoteResult, Status> {
+        let purpose = purpose.map(|purpose| polars_proto::Purpose {
+            code: purpose.code.clone(),
+            text: purpose.text.clone(),
+        });
+        let key = SigningKey::from_pkcs8_pem(source.credential.as_bytes())?;
+        let mut client = Client::connect(source.address.clone(), Some(key)).await?;
+        client.set_correlation_id(Some(correlation_id));
+        let reference = client.run_plan_for(plan, purpose.clone()).await?;
+        let fetched = client.fetch_for(&reference, purpose).await?;
+        let warning = match fetched.status {
+            FetchStatus::Warning(reason) => Some(reason),
+            _ => None,
+        };
+        Ok(RemoteResult {
+            dataframe: fetched.dataframe,
+            warning,
+        })
+    }
+
+    async fn upload(
+        &self,
+        address: &str,
+        credential: &str,
+        df: &DataFrame,
+        policy: &Policy,
+        correlation_id: &str,
+    ) -> Result<ReferenceResponse, Status> {
+        let key = SigningKey::from_pkcs8_pem(credential.as_bytes())?;
+        let mut client = Client::connect(address.to_string(), Some(key)).await?;
+        client.set_correlation_id(Some(correlation_id));
+        client.upload_dataframe(df, policy, &[]).await
+    }
+}
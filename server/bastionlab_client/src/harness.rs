@@ -0,0 +1,328 @@
+//! An in-process BastionLab server, isolated from the datasets of any other instance.
+//!
+//! Each server gets its own temporary directory holding its keys and persisted dataframes, which
+//! is removed when the server is dropped.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bastionlab_common::auth::{KeyGrpcService, KeyManagement, KeyRole};
+use bastionlab_common::config::BastionLabConfig;
+use bastionlab_common::connections::{self, ConnectionGrpcService, ConnectionManager};
+use bastionlab_common::health;
+use bastionlab_common::metrics;
+use bastionlab_common::replay::ReplayGuard;
+use bastionlab_common::session::{SessionGrpcService, SessionManager, TokenValidator};
+use bastionlab_common::session_proto::connection_service_server::ConnectionServiceServer;
+use bastionlab_common::session_proto::key_service_server::KeyServiceServer;
+use bastionlab_common::session_proto::session_service_server::SessionServiceServer;
+use bastionlab_polars::polars_proto::polars_service_server::PolarsServiceServer;
+use bastionlab_polars::watermark::Watermarker;
+use bastionlab_polars::BastionLabPolars;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use tonic::Status;
+
+use crate::federation::ClientConnector;
+use crate::{Client, SigningKey};
+
+pub struct InProcessServer {
+    root: PathBuf,
+    owner_key: Vec<u8>,
+    config: BastionLabConfig,
+    sess_manager: Arc<SessionManager>,
+    polars: BastionLabPolars,
+    serving: Serving,
+}
+
+/// The task serving a [`BastionLabPolars`], until shut down or aborted.
+struct Serving {
+    addr: String,
+    metrics_addr: SocketAddr,
+    connections: Arc<ConnectionManager>,
+    task: JoinHandle<std::io::Result<()>>,
+    /// Taken once the server is shut down.
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+fn io_err(e: std::io::Error) -> Status {
+    Status::internal(format!("Could not set up the in-process server: {e}"))
+}
+
+/// Sets up `polars` with the configured watermarking secret, as the server does, then loads its
+/// persisted tables.
+fn open_storage(
+    mut polars: BastionLabPolars,
+    config: &BastionLabConfig,
+) -> Result<BastionLabPolars, Status> {
+    if !config.watermark_secret_file.is_empty() {
+        polars = polars.with_watermarker(Watermarker::from_secret_file(
+            Path::new(&config.watermark_secret_file),
+            config.watermark_record_capacity,
+        )?);
+    }
+    polars
+        .open_storage()
+        .map_err(|e| Status::internal(format!("Could not load the persisted tables: {e}")))?;
+    Ok(polars)
+}
+
+impl InProcessServer {
+    /// Starts a server on a free local port, with authentication enabled and a freshly generated
+    /// owner key.
+    ///
+    /// The server is served over TLS, as `localhost`, when the TLS files of `config` exist.
+    pub async fn start(config: &BastionLabConfig) -> Result<Self, Status> {
+        let root = std::env::temp_dir().join(format!("bastionlab-{}", uuid::Uuid::new_v4()));
+        match Self::start_in(root.clone(), config).await {
+            Ok(server) => Ok(server),
+            Err(e) => {
+                fs::remove_dir_all(&root).unwrap_or(());
+                Err(e)
+            }
+        }
+    }
+
+    async fn start_in(root: PathBuf, config: &BastionLabConfig) -> Result<Self, Status> {
+        let (key, owner_key) = SigningKey::generate()?;
+        let keys = root.join("keys");
+        fs::create_dir_all(keys.join("owners")).map_err(io_err)?;
+        fs::create_dir_all(keys.join("users")).map_err(io_err)?;
+        fs::write(keys.join("owners").join("owner.pem"), key.public_key_pem()).map_err(io_err)?;
+
+        let sess_manager = Arc::new(
+            SessionManager::new(
+                Some(KeyManagement::load_from_dir(&keys)?),
+                config.session_expiry_in_secs,
+            )
+            .with_replay_guard(ReplayGuard::new(config.signature_max_skew_secs))
+            .with_challenges(
+                Duration::from_secs(config.challenge_ttl_secs),
+                config.max_outstanding_challenges,
+                config.max_challenges_per_holder,
+            )
+            .with_keys_dir(keys.clone()),
+        );
+        let polars = Self::polars(&root, sess_manager.clone(), config)?;
+        polars.watch_expiry(Duration::from_secs(config.dataframe_ttl_sweep_secs));
+        let serving = Self::serve(sess_manager.clone(), polars.clone(), config).await?;
+
+        Ok(InProcessServer {
+            root,
+            owner_key,
+            config: config.clone(),
+            sess_manager,
+            polars,
+            serving,
+        })
+    }
+
+    /// The service over the data directory under `root`, with its persisted tables loaded.
+    fn polars(
+        root: &Path,
+        sess_manager: Arc<SessionManager>,
+        config: &BastionLabConfig,
+    ) -> Result<BastionLabPolars, Status> {
+        let polars = BastionLabPolars::new(sess_manager, config)
+            .with_data_dir(root.join("data_frames"))
+            .with_remote_connector(Arc::new(ClientConnector));
+        open_storage(polars, config)
+    }
+
+    async fn serve(
+        sess_manager: Arc<SessionManager>,
+        polars: BastionLabPolars,
+        config: &BastionLabConfig,
+    ) -> Result<Serving, Status> {
+        let connections = Arc::new(ConnectionManager::new(config).with_metrics(polars.metrics()));
+        {
+            let sess_manager = sess_manager.clone();
+            connections.on_close(move |connection| sess_manager.close_connection(connection));
+        }
+        let token_validator = TokenValidator::new(sess_manager.clone());
+        // The keys are loaded and the dataframes reloaded by now.
+        let (mut health_reporter, health_service) = health::health_service().await;
+        health::ready(&mut health_reporter).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.map_err(io_err)?;
+        let addr = listener.local_addr().map_err(io_err)?;
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.map_err(io_err)?;
+        let metrics_addr = metrics_listener.local_addr().map_err(io_err)?;
+        let metrics = metrics::serve(metrics_listener, polars.metrics());
+        let (shutdown, signal) = oneshot::channel();
+        let signal = {
+            let polars = polars.clone();
+            async move {
+                match signal.await {
+                    Ok(()) => {
+                        polars.reject_pending_approvals();
+                    }
+                    // Dropped along with the server, whose task is aborted.
+                    Err(_) => std::future::pending().await,
+                }
+            }
+        };
+        let service = Server::builder()
+            .add_service(SessionServiceServer::new(SessionGrpcService::new(
+                sess_manager.clone(),
+            )))
+            .add_service(health_service)
+            .add_service(ConnectionServiceServer::with_interceptor(
+                ConnectionGrpcService::new(sess_manager.clone(), connections.clone()),
+                token_validator.clone(),
+            ))
+            .add_service(KeyServiceServer::with_interceptor(
+                KeyGrpcService::new(sess_manager),
+                token_validator.clone(),
+            ))
+            .add_service(PolarsServiceServer::with_interceptor(
+                polars,
+                token_validator,
+            ))
+            .into_service();
+        let (tls, addr) = match connections::load_tls(&config.tls_files())
+            .map_err(|e| Status::invalid_argument(format!("{e:#}")))?
+        {
+            Some((_, tls)) => (Some(tls), format!("https://localhost:{}", addr.port())),
+            None => (None, format!("http://{addr}")),
+        };
+        let grace = Duration::from_secs(config.shutdown_grace_secs);
+        let server = connections
+            .clone()
+            .serve_with_shutdown(listener, tls, service, signal, grace);
+        let server = async move {
+            tokio::select! {
+                served = server => served,
+                served = metrics => served.map_err(std::io::Error::other),
+            }
+        };
+        Ok(Serving {
+            addr,
+            metrics_addr,
+            connections,
+            task: tokio::spawn(server),
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.serving.addr
+    }
+
+    /// Where the Prometheus metrics are served over HTTP, at `/metrics`.
+    pub fn metrics_addr(&self) -> SocketAddr {
+        self.serving.metrics_addr
+    }
+
+    /// The key of the only data owner of the server.
+    pub fn owner_key(&self) -> SigningKey {
+        SigningKey::from_pkcs8_der(&self.owner_key).expect("The owner key was generated")
+    }
+
+    /// Connects a new client authenticated as the data owner, servers using TLS are connected to
+    /// with [`Client::connect_tls`] instead.
+    pub async fn client(&self) -> Result<Client, Status> {
+        Client::connect(self.serving.addr.clone(), Some(self.owner_key())).await
+    }
+
+    /// The public keys directory of the server.
+    pub fn keys_dir(&self) -> PathBuf {
+        self.root.join("keys")
+    }
+
+    /// Adds a key and reloads the keys.
+    pub fn add_key(&self, role: KeyRole, key: &SigningKey) -> Result<(), Status> {
+        let keys = self.keys_dir();
+        KeyManagement::add_key(&keys, role, key.public_key_pem().as_bytes())
+            .map_err(|e| Status::invalid_argument(format!("Could not add the key: {e}")))?;
+        self.sess_manager
+            .reload_keys(KeyManagement::load_from_dir(&keys)?);
+        Ok(())
+    }
+
+    /// Revokes a key and reloads the keys, as the server does when its key files change.
+    pub fn revoke_key(&self, hash: &str) -> Result<(), Status> {
+        let keys = self.keys_dir();
+        KeyManagement::revoke_key(&keys, hash)
+            .map_err(|e| Status::not_found(format!("Could not revoke key {hash}: {e}")))?;
+        self.sess_manager
+            .reload_keys(KeyManagement::load_from_dir(&keys)?);
+        Ok(())
+    }
+
+    pub fn polars(&self) -> &BastionLabPolars {
+        &self.polars
+    }
+
+    pub fn connections(&self) -> &ConnectionManager {
+        &self.serving.connections
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.polars.data_dir()
+    }
+
+    /// Stops the server as a crash would, its plan jobs at their next segment boundary without
+    /// saving it, and starts a new one over the same keys and data directory. The new one loads
+    /// the persisted dataframes and resumes the plan jobs, on a new address.
+    pub async fn restart(&mut self) -> Result<(), Status> {
+        self.polars.halt_plan_jobs();
+        self.serving.task.abort();
+        let polars = Self::polars(&self.root, self.sess_manager.clone(), &self.config)?;
+        polars.load_dfs().map_err(|e| {
+            Status::internal(format!("Could not load the persisted dataframes: {e}"))
+        })?;
+        polars.resume_plan_jobs();
+        polars.watch_expiry(Duration::from_secs(self.config.dataframe_ttl_sweep_secs));
+        self.serving = Self::serve(self.sess_manager.clone(), polars.clone(), &self.config).await?;
+        self.polars = polars;
+        Ok(())
+    }
+
+    /// Shuts the server down as SIGTERM does: fetches pending approval are rejected, connections
+    /// stop taking requests and those in flight get `shutdown_grace_secs` to finish, then the
+    /// plan jobs are stopped and the audit log flushed.
+    pub async fn shut_down(&mut self) -> Result<(), Status> {
+        let shutdown = match self.serving.shutdown.take() {
+            Some(shutdown) => shutdown,
+            None => return Ok(()),
+        };
+        let _ignored = shutdown.send(());
+        (&mut self.serving.task)
+            .await
+            .map_err(|e| Status::internal(format!("The in-process server failed: {e}")))?
+            .map_err(|e| Status::internal(format!("The in-process server failed: {e}")))?;
+        let grace = Duration::from_secs(self.config.shutdown_grace_secs);
+        self.polars.stop_plan_jobs(grace).await;
+        self.polars.flush_audit_log().await;
+        Ok(())
+    }
+
+    /// Creates a new instance over the same data directory and loads its persisted dataframes,
+    /// as a restarted server would.
+    pub fn reload(&self) -> Result<BastionLabPolars, Status> {
+        let sess_manager = Arc::new(SessionManager::new(
+            None,
+            self.config.session_expiry_in_secs,
+        ));
+        let polars = BastionLabPolars::new(sess_manager, &self.config)
+            .with_data_dir(self.data_dir().to_path_buf());
+        let polars = open_storage(polars, &self.config)?;
+        polars.load_dfs().map_err(|e| {
+            Status::internal(format!("Could not load the persisted dataframes: {e}"))
+        })?;
+        Ok(polars)
+    }
+}
+
+impl Drop for InProcessServer {
+    fn drop(&mut self) {
+        self.serving.task.abort();
+        fs::remove_dir_all(&self.root).unwrap_or(());
+    }
+}
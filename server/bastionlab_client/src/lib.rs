@@ -0,0 +1,1765 @@
+//! Rust client for the BastionLab server.
+//!
+//! This wraps the generated tonic clients and reuses the server's serialization module, so the
+//! chunk framing used here is always the one the server expects.
+
+use std::time::{Duration, Instant};
+
+use bastionlab_common::replay::now_ms;
+use bastionlab_common::session_proto::{
+    connection_service_client::ConnectionServiceClient, key_service_client::KeyServiceClient,
+    session_service_client::SessionServiceClient, AddPublicKeyRequest, ChallengeRequest,
+    ClientInfo, ConnectionInfo, Empty, KeyRole, PublicKey, RemovePublicKeyRequest,
+};
+use bastionlab_polars::delta;
+use bastionlab_polars::faults::FAULTS_METADATA;
+use bastionlab_polars::federation::CORRELATION_METADATA;
+use bastionlab_polars::handshake;
+use bastionlab_polars::pipelines::PIPELINE_PREFIX;
+use bastionlab_polars::polars_proto::{
+    self, polars_service_client::PolarsServiceClient, AliasRequest, AliasResponse,
+    ApproveRequestRequest, AuditLog, AuditLogRequest, BatchedQueryResult, BulkResponse,
+    ClientVersionCount, DeduplicateRequest, DefaultPolicyQuery, DefaultPolicyRequest,
+    DefaultPolicyResponse, DeleteWorkspaceRequest, ExportWaiverRequest, FetchChunk,
+    HandshakeRequest, HandshakeResponse, KillQueryRequest, LifecycleResponse,
+    ListDataFramesRequest, PendingRequest, PendingRequests, PipelineResponse, PlanJob,
+    PlanJobQuery, PlanJobRequest, PolicyHistory, PolicyRolloutReport, PolicyRolloutRequest, Query,
+    QueryBatch, QueryBatchResponse, ReferenceList, ReferenceRequest, ReferenceResponse,
+    RegisterPipelineRequest, RegisterViewRequest, RejectRequestRequest, RemoteDataFrameRequest,
+    ReproducibilityBundle, ReproducibilityReport, ResultShape, ReviewRequest, RolloutRequest,
+    RowRange, SendChunk, ServerCapabilities, ShareWorkspaceRequest, StorageClassJob,
+    StorageClassJobRequest, StorageClassRequest, SyntheticRequest, TransferRequest,
+    TransferResponse, UpdateDraftRequest, UpsertResponse, UsageReportRequest, VersionList,
+    VersionRetentionRequest, ViewRequest, ViewResponse, WatermarkTrace, WorkspaceMembersRequest,
+    WorkspaceRequest, WorkspaceResponse,
+};
+use bastionlab_polars::serialization::{
+    checksum, dataframe_ser_helper, ipc_to_dataframe, sized_column_upload_chunks,
+    sized_stream_upload_chunks, sized_upload_chunks, FetchAssembler, CHUNK_SIZE,
+};
+use polars::prelude::{DataFrame, Schema};
+use prost::Message;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use tokio_stream::StreamExt;
+use tonic::metadata::{MetadataKey, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::{Request, Status, Streaming};
+
+pub use bastionlab_polars::access_control::Policy;
+pub use bastionlab_polars::composite_plan::{CompositePlan, CompositePlanSegment};
+pub use bastionlab_polars::faults::FaultSchedule;
+pub use bastionlab_polars::masking::{ColumnMasks, DatePeriod, Masker};
+pub use bastionlab_polars::pipelines::{Parameter, ParameterType, Visibility};
+pub use bastionlab_polars::polars_proto::{
+    column_order, ActivityEntry, ColumnOrder, DataFormat, ExportWaiver, FetchOutcome,
+    PolicySelector, Purpose, PurposeUsage, QueryKill, RunningQueries, RunningQuery,
+};
+pub use bastionlab_polars::reproducibility::{open_bundle, Bundle};
+pub use bastionlab_polars::resources::ResourceHints;
+pub use bastionlab_polars::rollouts::PolicyPatch;
+pub use bastionlab_polars::shape::Scalar;
+pub use bastionlab_polars::FetchStatus;
+
+pub mod federation;
+pub mod harness;
+pub mod self_test;
+
+pub mod self_test_proto {
+    tonic::include_proto!("bastionlab_self_test");
+}
+
+/// DER prefix of a P-256 SubjectPublicKeyInfo, followed by the uncompressed public point.
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// Sessions are refreshed this long before the server expires them.
+const SESSION_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let body = base64::encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in body.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// The PKCS#8 PEM encoding of a key returned by [`SigningKey::generate`], as read by
+/// [`SigningKey::from_pkcs8_pem`].
+pub fn pkcs8_pem(der: &[u8]) -> String {
+    pem("PRIVATE KEY", der)
+}
+
+/// An ECDSA P-256 identity, compatible with the keys of the Python client.
+pub struct SigningKey {
+    pair: EcdsaKeyPair,
+    pubkey_hash: String,
+}
+
+impl SigningKey {
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self, Status> {
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, der)
+            .map_err(|e| Status::invalid_argument(format!("Invalid signing key: {e}")))?;
+        let mut key = SigningKey {
+            pair,
+            pubkey_hash: String::new(),
+        };
+        key.pubkey_hash = checksum(&key.public_key_der());
+        Ok(key)
+    }
+
+    /// Loads an unencrypted PKCS#8 PEM private key.
+    pub fn from_pkcs8_pem(pem: &[u8]) -> Result<Self, Status> {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem)
+            .map_err(|e| Status::invalid_argument(format!("Invalid PEM file: {e}")))?;
+        Self::from_pkcs8_der(&pem.contents)
+    }
+
+    /// Generates a new key, returned along with its PKCS#8 DER encoding so it can be saved.
+    pub fn generate() -> Result<(Self, Vec<u8>), Status> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+                .map_err(|_| Status::internal("Could not generate a signing key"))?;
+        let der = pkcs8.as_ref().to_vec();
+        Ok((Self::from_pkcs8_der(&der)?, der))
+    }
+
+    pub fn public_key_der(&self) -> Vec<u8> {
+        let mut der = P256_SPKI_PREFIX.to_vec();
+        der.extend_from_slice(self.pair.public_key().as_ref());
+        der
+    }
+
+    /// The public key in the PEM format expected in the server's keys directory.
+    pub fn public_key_pem(&self) -> String {
+        pem("PUBLIC KEY", &self.public_key_der())
+    }
+
+    /// Hex-encoded SHA256 of the public key, which is how the server identifies users.
+    pub fn pubkey_hash(&self) -> &str {
+        &self.pubkey_hash
+    }
+
+    /// Signs `message`, as the client signs its session requests.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Status> {
+        Ok(self
+            .pair
+            .sign(&SystemRandom::new(), message)
+            .map_err(|_| Status::internal("Could not sign the request"))?
+            .as_ref()
+            .to_vec())
+    }
+}
+
+/// A dataframe fetched from the server, along with the state the server reported for it.
+///
+/// `Pending` means the data owner had to approve the query before the data was sent.
+#[derive(Debug)]
+pub struct FetchedDataFrame {
+    pub status: FetchStatus,
+    pub dataframe: DataFrame,
+    /// The shape announced by the server, see [`bastionlab_polars::shape`].
+    pub shape: Option<ResultShape>,
+    /// The fingerprint of the dataframe on the server, see [`bastionlab_polars::fingerprints`].
+    pub fingerprint: Option<String>,
+    /// Set on fetches of a range of rows, with the rows of the whole dataframe, see
+    /// [`Client::fetch_rows`].
+    pub row_range: Option<RowRange>,
+}
+
+/// A scalar result fetched from the server, see [`Client::fetch_scalar`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchedScalar {
+    pub value: Scalar,
+    pub dtype: String,
+    /// Set when the policy let the value through with a warning.
+    pub warning: Option<String>,
+}
+
+/// A dataframe rebuilt from the rows changed since a version fetched before, see
+/// [`Client::fetch_delta`].
+#[derive(Debug)]
+pub struct DeltaFetchedDataFrame {
+    pub status: FetchStatus,
+    pub dataframe: DataFrame,
+    /// Why the server sent the full result instead of a delta, if it did.
+    pub full_reason: Option<String>,
+}
+
+pub struct Client {
+    session: SessionServiceClient<Channel>,
+    connections: ConnectionServiceClient<Channel>,
+    keys: KeyServiceClient<Channel>,
+    polars: PolarsServiceClient<Channel>,
+    key: Option<SigningKey>,
+    token: Option<Vec<u8>>,
+    expiry: Instant,
+    client_info: ClientInfo,
+    /// Sent with every request, see [`Client::inject_faults`].
+    faults: Option<String>,
+    /// Sent with every request, see [`Client::set_correlation_id`].
+    correlation_id: Option<String>,
+    /// How far the server clock is ahead of ours in milliseconds, once measured.
+    clock_offset_ms: Option<i64>,
+    /// The nonce of the last signed request, see [`bastionlab_common::replay`].
+    last_nonce: u64,
+    /// What the server answered the handshake, see [`Client::handshake`].
+    handshake: Option<HandshakeResponse>,
+    /// Whether to handshake when the first session opens.
+    auto_handshake: bool,
+    /// Sent with every fetch, see [`Client::set_fetch_chunk_kb`].
+    fetch_chunk_kb: u32,
+    /// See [`Client::set_data_format`].
+    data_format: DataFormat,
+}
+
+fn client_info() -> ClientInfo {
+    ClientInfo {
+        uid: checksum(format!("{}-{}", whoami::devicename(), whoami::username()).as_bytes()),
+        platform_name: whoami::platform().to_string(),
+        platform_arch: std::env::consts::ARCH.to_string(),
+        platform_version: whoami::distro(),
+        platform_release: std::env::consts::OS.to_string(),
+        user_agent: String::from("bastionlab_rust"),
+        user_agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        is_colab: false,
+    }
+}
+
+fn workspace_members(name: &str, identifiers: &[&str]) -> WorkspaceMembersRequest {
+    WorkspaceMembersRequest {
+        name: name.to_string(),
+        identifiers: identifiers.iter().map(|id| id.to_string()).collect(),
+    }
+}
+
+fn reference_request(identifier: &str) -> ReferenceRequest {
+    ReferenceRequest {
+        identifier: identifier.to_string(),
+        ..Default::default()
+    }
+}
+
+/// The handshake this client sends: it speaks every feature of the server crate it is built
+/// with.
+pub fn handshake_request() -> HandshakeRequest {
+    HandshakeRequest {
+        client_name: String::from("bastionlab_rust"),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        api_version: handshake::API_VERSION,
+        features: handshake::FEATURES.iter().map(|f| f.to_string()).collect(),
+    }
+}
+
+/// The result inlined in `result`, if it was, see [`Client::run_plan_batch`].
+pub fn inlined_dataframe(result: &BatchedQueryResult) -> Result<Option<DataFrame>, Status> {
+    if result.inline_data.is_empty() {
+        return Ok(None);
+    }
+    ipc_to_dataframe(&result.inline_data).map(Some)
+}
+
+impl Client {
+    /// Connects to the server at `dst` (e.g. `https://localhost:50056`).
+    ///
+    /// Requests are signed with `key` when one is given, otherwise the server must have
+    /// authentication disabled.
+    pub async fn connect(dst: String, key: Option<SigningKey>) -> Result<Self, Status> {
+        let channel = Channel::from_shared(dst)
+            .map_err(|e| Status::invalid_argument(format!("Invalid server address: {e}")))?
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(format!("Could not connect to the server: {e}")))?;
+        Ok(Self::new(channel, key))
+    }
+
+    /// Connects to the server at `dst` over TLS, trusting the certificates of `tls` and
+    /// presenting its identity, for servers requiring client certificates.
+    pub async fn connect_tls(
+        dst: String,
+        key: Option<SigningKey>,
+        tls: ClientTlsConfig,
+    ) -> Result<Self, Status> {
+        let channel = Channel::from_shared(dst)
+            .map_err(|e| Status::invalid_argument(format!("Invalid server address: {e}")))?
+            .tls_config(tls)
+            .map_err(|e| Status::invalid_argument(format!("Invalid TLS configuration: {e}")))?
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(format!("Could not connect to the server: {e}")))?;
+        Ok(Self::new(channel, key))
+    }
+
+    pub fn new(channel: Channel, key: Option<SigningKey>) -> Self {
+        Client {
+            session: SessionServiceClient::new(channel.clone()),
+            connections: ConnectionServiceClient::new(channel.clone()),
+            keys: KeyServiceClient::new(channel.clone()),
+            polars: PolarsServiceClient::new(channel),
+            key,
+            token: None,
+            expiry: Instant::now(),
+            client_info: client_info(),
+            faults: None,
+            correlation_id: None,
+            clock_offset_ms: None,
+            last_nonce: 0,
+            handshake: None,
+            auto_handshake: true,
+            fetch_chunk_kb: 0,
+            data_format: DataFormat::IpcStream,
+        }
+    }
+
+    /// Does not handshake when the first session opens, as clients predating handshakes. The
+    /// server defaults apply until [`Client::handshake`] is called.
+    pub fn skip_handshake(&mut self) {
+        self.auto_handshake = false;
+    }
+
+    /// Negotiates the API version, features and limits with the server, which the uploads of this
+    /// client follow from then on, see [`bastionlab_polars::handshake`]. Clients handshake with
+    /// [`handshake_request`] when their first session opens, unless told not to.
+    pub async fn handshake(
+        &mut self,
+        handshake: HandshakeRequest,
+    ) -> Result<HandshakeResponse, Status> {
+        self.auto_handshake = false;
+        let request = self.request(handshake).await?;
+        let response = self.polars.handshake(request).await?.into_inner();
+        self.handshake = Some(response.clone());
+        Ok(response)
+    }
+
+    /// What the server answered the last handshake, if any.
+    pub fn negotiated(&self) -> Option<&HandshakeResponse> {
+        self.handshake.as_ref()
+    }
+
+    /// Bytes of data per upload chunk, as negotiated.
+    pub fn upload_chunk_size(&self) -> usize {
+        match self.limits().upload_chunk_bytes {
+            0 => CHUNK_SIZE,
+            bytes => bytes as usize,
+        }
+    }
+
+    fn limits(&self) -> polars_proto::ClientLimits {
+        self.handshake
+            .as_ref()
+            .and_then(|handshake| handshake.limits.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether `feature` was enabled by the handshake. Every feature is assumed to be without one.
+    fn feature_enabled(&self, feature: &str) -> bool {
+        self.handshake.as_ref().map_or(true, |handshake| {
+            handshake.enabled_features.iter().any(|f| f == feature)
+        })
+    }
+
+    /// Sends `df` in the negotiated format. Canonical uploads go column by column so that the
+    /// server can decode them as they arrive, or as IPC if a column type has no canonical encoding
+    /// or the server did not enable column uploads. Uploads larger than the server accepts are
+    /// rejected before anything is sent.
+    async fn dataframe_chunks(
+        &mut self,
+        df: &DataFrame,
+        policy: &Policy,
+        sanitized_columns: Vec<String>,
+    ) -> Result<Vec<SendChunk>, Status> {
+        // Opens the session first, and handshakes with it.
+        self.refresh_session_if_needed().await?;
+        let chunk_size = self.upload_chunk_size();
+        let format = self.data_format();
+        let mut chunks = None;
+        if format == DataFormat::IpcStream {
+            chunks = Some(sized_stream_upload_chunks(
+                &mut df.clone(),
+                policy,
+                sanitized_columns.clone(),
+                None,
+                chunk_size,
+            )?);
+        } else if format == DataFormat::Canonical
+            && df.width() > 0
+            && self.feature_enabled("canonical_columns_upload")
+        {
+            match sized_column_upload_chunks(
+                df,
+                policy,
+                sanitized_columns.clone(),
+                None,
+                chunk_size,
+            ) {
+                Err(e) if e.code() == tonic::Code::Unimplemented => (),
+                res => chunks = Some(res?),
+            }
+        }
+        let chunks = match chunks {
+            Some(chunks) => chunks,
+            None => {
+                let buf = dataframe_ser_helper(&mut df.clone())
+                    .map_err(|e| Status::invalid_argument(format!("Polars error: {e}")))?;
+                sized_upload_chunks(&buf, policy, sanitized_columns, None, chunk_size)?
+            }
+        };
+
+        let max_bytes = self.limits().max_upload_bytes;
+        let bytes: u64 = chunks.iter().map(|chunk| chunk.data.len() as u64).sum();
+        if max_bytes > 0 && bytes > max_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "The upload is {bytes} bytes, larger than the {max_bytes} bytes the server accepts"
+            )));
+        }
+        Ok(chunks)
+    }
+
+    /// Asks the server to inject `schedule` in the chunk streams of the next requests, until it is
+    /// unset. Only dev servers with fault injection enabled honor it, see
+    /// [`bastionlab_polars::faults`].
+    pub fn inject_faults(&mut self, schedule: Option<&FaultSchedule>) -> Result<(), Status> {
+        self.faults = schedule
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| {
+                Status::invalid_argument(format!("Could not serialize the schedule: {e}"))
+            })?;
+        Ok(())
+    }
+
+    /// Sends `id` with the next requests, until it is unset, so that the server records them under
+    /// it, see [`bastionlab_polars::federation`].
+    pub fn set_correlation_id(&mut self, id: Option<&str>) {
+        self.correlation_id = id.map(str::to_string);
+    }
+
+    /// Asks for fetched dataframes in chunks of `kb` kilobytes of data, or in chunks of the size
+    /// the server is configured with if 0. The server caps it so that chunks fit in gRPC messages.
+    pub fn set_fetch_chunk_kb(&mut self, kb: u32) {
+        self.fetch_chunk_kb = kb;
+    }
+
+    /// Uploads and fetches dataframes in `format`, an Arrow IPC stream by default. Canonical
+    /// uploads are sent column by column, or as an IPC file if a column type has no canonical
+    /// encoding.
+    pub fn set_data_format(&mut self, format: DataFormat) {
+        self.data_format = format;
+    }
+
+    /// The format dataframes are transferred in: the canonical one if the server did not enable
+    /// IPC streams, as servers predating the `format` fields.
+    fn data_format(&self) -> DataFormat {
+        match self.data_format {
+            DataFormat::IpcStream if self.feature_enabled("ipc_stream") => DataFormat::IpcStream,
+            DataFormat::IpcFile => DataFormat::IpcFile,
+            _ => DataFormat::Canonical,
+        }
+    }
+
+    /// Fetches `identifier` in the negotiated format.
+    fn fetch_reference(&self, identifier: &str) -> ReferenceRequest {
+        let format = self.data_format();
+        ReferenceRequest {
+            identifier: identifier.to_string(),
+            restore_dtypes: true,
+            canonical_format: format == DataFormat::Canonical,
+            format: format as i32,
+            chunk_kb: self.fetch_chunk_kb,
+            ..Default::default()
+        }
+    }
+
+    /// Checks that the server answers, returning its version. This needs no session.
+    pub async fn ping(&mut self) -> Result<String, Status> {
+        Ok(self.session.ping(Empty {}).await?.into_inner().version)
+    }
+
+    /// Measures how far the server clock is ahead of ours, in milliseconds. Signed requests are
+    /// timestamped with the server clock from then on, which the first one measures anyway.
+    pub async fn sync_clock(&mut self) -> Result<i64, Status> {
+        let sent = now_ms();
+        let server = self.session.get_server_time(Empty {}).await?.into_inner();
+        let received = now_ms();
+        // The server read its clock about halfway through the round trip.
+        let offset = server.unix_ms as i64 - (sent / 2 + received / 2) as i64;
+        self.clock_offset_ms = Some(offset);
+        Ok(offset)
+    }
+
+    async fn refresh_session_if_needed(&mut self) -> Result<(), Status> {
+        if self.token.is_some() && Instant::now() < self.expiry {
+            return Ok(());
+        }
+
+        let offset = match (self.key.is_some(), self.clock_offset_ms) {
+            (true, None) => self.sync_clock().await?,
+            (_, offset) => offset.unwrap_or(0),
+        };
+        let timestamp = (now_ms() as i64 + offset) as u64;
+        // Nonces follow the clock, so that they keep increasing across clients of the same key.
+        let nonce = (self.last_nonce + 1).max(timestamp.saturating_mul(1000));
+        let mut request = Request::new(self.client_info.clone());
+        if let Some(key) = &self.key {
+            self.last_nonce = nonce;
+            let challenge = self
+                .session
+                .get_challenge(ChallengeRequest {
+                    public_key_hash: key.pubkey_hash().to_string(),
+                })
+                .await?
+                .into_inner()
+                .value;
+
+            let mut message = b"create-session".to_vec();
+            message.extend_from_slice(&challenge);
+            message.extend_from_slice(&timestamp.to_be_bytes());
+            message.extend_from_slice(&nonce.to_be_bytes());
+            self.client_info
+                .encode(&mut message)
+                .map_err(|e| Status::internal(format!("Could not encode client info: {e}")))?;
+            let signature = key.sign(&message)?;
+
+            let signature_key =
+                MetadataKey::from_bytes(format!("signature-{}-bin", key.pubkey_hash()).as_bytes())
+                    .map_err(|e| Status::internal(format!("Invalid metadata key: {e}")))?;
+            let metadata = request.metadata_mut();
+            metadata.insert_bin("challenge-bin", MetadataValue::from_bytes(&challenge));
+            metadata.insert_bin(
+                "timestamp-bin",
+                MetadataValue::from_bytes(&timestamp.to_be_bytes()),
+            );
+            metadata.insert_bin("nonce-bin", MetadataValue::from_bytes(&nonce.to_be_bytes()));
+            metadata.insert_bin(signature_key, MetadataValue::from_bytes(&signature));
+        }
+
+        let session = self.session.create_session(request).await?.into_inner();
+        let lifetime = Duration::from_millis(session.expiry_time);
+        self.expiry = Instant::now() + lifetime.saturating_sub(SESSION_EXPIRY_MARGIN);
+        self.token = Some(session.token);
+
+        if self.auto_handshake {
+            self.auto_handshake = false;
+            let request = self.with_metadata(handshake_request())?;
+            match self.polars.handshake(request).await {
+                Ok(response) => self.handshake = Some(response.into_inner()),
+                // Servers predating handshakes apply their defaults.
+                Err(e) if e.code() == tonic::Code::Unimplemented => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens a new session, even if the current one is still valid.
+    pub async fn authenticate(&mut self) -> Result<(), Status> {
+        self.token = None;
+        self.refresh_session_if_needed().await
+    }
+
+    async fn request<T>(&mut self, message: T) -> Result<Request<T>, Status> {
+        self.refresh_session_if_needed().await?;
+        self.with_metadata(message)
+    }
+
+    /// `message` with the session token and the other metadata sent with every request.
+    fn with_metadata<T>(&self, message: T) -> Result<Request<T>, Status> {
+        let mut request = Request::new(message);
+        if let (Some(_), Some(token)) = (&self.key, &self.token) {
+            request
+                .metadata_mut()
+                .insert_bin("accesstoken-bin", MetadataValue::from_bytes(token));
+        }
+        if let Some(faults) = &self.faults {
+            let faults = MetadataValue::from_str(faults)
+                .map_err(|_| Status::invalid_argument("Invalid fault schedule"))?;
+            request.metadata_mut().insert(FAULTS_METADATA, faults);
+        }
+        if let Some(id) = &self.correlation_id {
+            let id = MetadataValue::from_str(id)
+                .map_err(|_| Status::invalid_argument("Invalid correlation id"))?;
+            request.metadata_mut().insert(CORRELATION_METADATA, id);
+        }
+        Ok(request)
+    }
+
+    /// Uploads `df` with the given policy. `sanitized_columns` are nulled out on fetch.
+    pub async fn upload_dataframe(
+        &mut self,
+        df: &DataFrame,
+        policy: &Policy,
+        sanitized_columns: &[String],
+    ) -> Result<ReferenceResponse, Status> {
+        let chunks = self
+            .dataframe_chunks(df, policy, sanitized_columns.to_vec())
+            .await?;
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.send_data_frame(request).await?.into_inner())
+    }
+
+    /// Uploads `df` with the policy fields of `policy`, the default policy of the requester
+    /// completing them, see [`bastionlab_polars::default_policies`]. Takes the whole default
+    /// policy if `policy` is `None`.
+    pub async fn upload_with_defaults(
+        &mut self,
+        df: &DataFrame,
+        policy: Option<&serde_json::Value>,
+        sanitized_columns: &[String],
+    ) -> Result<ReferenceResponse, Status> {
+        let mut chunks = self
+            .dataframe_chunks(df, &Policy::allow_by_default(), sanitized_columns.to_vec())
+            .await?;
+        chunks[0].policy = match policy {
+            Some(policy) => serde_json::to_string(policy)
+                .map_err(|e| Status::invalid_argument(format!("Could not serialize: {e}")))?,
+            None => String::new(),
+        };
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.send_data_frame(request).await?.into_inner())
+    }
+
+    /// Uploads `df` with a name and tags it can be listed by.
+    pub async fn upload_tagged_dataframe(
+        &mut self,
+        df: &DataFrame,
+        policy: &Policy,
+        name: &str,
+        tags: &[String],
+    ) -> Result<ReferenceResponse, Status> {
+        let mut chunks = self.dataframe_chunks(df, policy, Vec::new()).await?;
+        chunks[0].name = name.to_string();
+        chunks[0].tags = tags.to_vec();
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.send_data_frame(request).await?.into_inner())
+    }
+
+    /// The chunks [`Self::upload_dataframe`] sends for `df`, to be sent with
+    /// [`Self::upload_stream`].
+    pub async fn upload_chunks(
+        &mut self,
+        df: &DataFrame,
+        policy: &Policy,
+        sanitized_columns: &[String],
+    ) -> Result<Vec<SendChunk>, Status> {
+        self.dataframe_chunks(df, policy, sanitized_columns.to_vec())
+            .await
+    }
+
+    /// Uploads the chunks of `chunks`, as prepared by [`Self::upload_chunks`], as they come: the
+    /// upload lasts until the stream ends.
+    pub async fn upload_stream(
+        &mut self,
+        chunks: impl tokio_stream::Stream<Item = SendChunk> + Send + Sync + 'static,
+    ) -> Result<ReferenceResponse, Status> {
+        let request = self.request(chunks).await?;
+        Ok(self.polars.send_data_frame(request).await?.into_inner())
+    }
+
+    /// Scores the watermarked fetches of the server against `leaked`, a suspected leak, best
+    /// matches first. Data owners only.
+    pub async fn trace_watermark(&mut self, leaked: &DataFrame) -> Result<WatermarkTrace, Status> {
+        let chunks = self
+            .dataframe_chunks(leaked, &Policy::allow_by_default(), Vec::new())
+            .await?;
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.trace_watermark(request).await?.into_inner())
+    }
+
+    /// Uploads result `identifier` to the server at `address` with `policy`, as the identity of
+    /// that server whose PEM-encoded key is `credential`. The result is checked as a fetch, and
+    /// the call waits for the approval of its owner if needed.
+    pub async fn transfer_dataframe(
+        &mut self,
+        identifier: &str,
+        address: &str,
+        credential: &str,
+        policy: &Policy,
+    ) -> Result<TransferResponse, Status> {
+        let policy = serde_json::to_string(policy)
+            .map_err(|e| Status::invalid_argument(format!("Could not serialize policy: {e}")))?;
+        let request = self
+            .request(TransferRequest {
+                identifier: identifier.to_string(),
+                address: address.to_string(),
+                credential: credential.to_string(),
+                policy,
+                purpose: None,
+            })
+            .await?;
+        Ok(self.polars.transfer_data_frame(request).await?.into_inner())
+    }
+
+    /// Registers dataframe `identifier` of the server at `address` with its schema and policy
+    /// only, its rows being read from there with `credential`, the PEM-encoded key of an identity
+    /// of that server, see [`bastionlab_polars::federation`].
+    pub async fn register_remote_dataframe(
+        &mut self,
+        address: &str,
+        identifier: &str,
+        credential: &str,
+        schema: &Schema,
+        policy: &Policy,
+        sanitized_columns: &[String],
+    ) -> Result<ReferenceResponse, Status> {
+        let serialize_err =
+            |e: serde_json::Error| Status::invalid_argument(format!("Could not serialize: {e}"));
+        let request = self
+            .request(RemoteDataFrameRequest {
+                address: address.to_string(),
+                identifier: identifier.to_string(),
+                credential: credential.to_string(),
+                schema: serde_json::to_string(schema).map_err(serialize_err)?,
+                policy: serde_json::to_string(policy).map_err(serialize_err)?,
+                sanitized_columns: sanitized_columns.to_vec(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(self
+            .polars
+            .register_remote_data_frame(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Updates the rows of a dataframe whose `keys` match rows of `df` and inserts the others.
+    ///
+    /// `df` may only contain some of the columns of the dataframe, the other ones are left as is.
+    /// Appends the rows of `df` to dataframe `identifier`. Only data owners can do this.
+    pub async fn append_rows(
+        &mut self,
+        identifier: &str,
+        df: &DataFrame,
+    ) -> Result<ReferenceResponse, Status> {
+        let mut chunks = self
+            .dataframe_chunks(df, &Policy::allow_by_default(), Vec::new())
+            .await?;
+        chunks[0].append_to = identifier.to_string();
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.append_data_frame(request).await?.into_inner())
+    }
+
+    pub async fn upsert_rows(
+        &mut self,
+        identifier: &str,
+        df: &DataFrame,
+        keys: &[String],
+    ) -> Result<UpsertResponse, Status> {
+        let mut chunks = self
+            .dataframe_chunks(df, &Policy::allow_by_default(), Vec::new())
+            .await?;
+        chunks[0].append_to = identifier.to_string();
+        chunks[0].key_columns = keys.to_vec();
+        let request = self.request(tokio_stream::iter(chunks)).await?;
+        Ok(self.polars.upsert_rows(request).await?.into_inner())
+    }
+
+    pub async fn run_plan(&mut self, plan: &CompositePlan) -> Result<ReferenceResponse, Status> {
+        self.run_plan_for(plan, None).await
+    }
+
+    /// Runs `plan` stating why, as required by some policies.
+    pub async fn run_plan_with_purpose(
+        &mut self,
+        plan: &CompositePlan,
+        purpose: &Purpose,
+    ) -> Result<ReferenceResponse, Status> {
+        self.run_plan_for(plan, Some(purpose.clone())).await
+    }
+
+    pub(crate) async fn run_plan_for(
+        &mut self,
+        plan: &CompositePlan,
+        purpose: Option<Purpose>,
+    ) -> Result<ReferenceResponse, Status> {
+        let composite_plan = serde_json::to_string(plan)
+            .map_err(|e| Status::invalid_argument(format!("Could not serialize the plan: {e}")))?;
+        let request = self
+            .request(Query {
+                composite_plan,
+                purpose,
+                ..Default::default()
+            })
+            .await?;
+        Ok(self.polars.run_query(request).await?.into_inner())
+    }
+
+    /// Runs `plans` in one call, see [`bastionlab_polars::batches`]. The response holds the result
+    /// of each plan, in order, with its data if `inline_results` and the server inlined it, see
+    /// [`inlined_dataframe`].
+    pub async fn run_plan_batch(
+        &mut self,
+        plans: &[CompositePlan],
+        fail_fast: bool,
+        inline_results: bool,
+    ) -> Result<QueryBatchResponse, Status> {
+        let queries = plans
+            .iter()
+            .map(|plan| {
+                let composite_plan = serde_json::to_string(plan).map_err(|e| {
+                    Status::invalid_argument(format!("Could not serialize the plan: {e}"))
+                })?;
+                Ok(Query {
+                    composite_plan,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+        let request = self
+            .request(QueryBatch {
+                queries,
+                fail_fast,
+                inline_results,
+            })
+            .await?;
+        Ok(self.polars.run_query_batch(request).await?.into_inner())
+    }
+
+    /// Runs `plan` in the background, saving its segment boundaries if `checkpoint` for it to
+    /// survive a restart, see [`bastionlab_polars::plan_jobs`]. The job is polled with
+    /// [`Client::plan_job`].
+    pub async fn submit_plan_job(
+        &mut self,
+        plan: &CompositePlan,
+        checkpoint: bool,
+    ) -> Result<PlanJob, Status> {
+        let composite_plan = serde_json::to_string(plan)
+            .map_err(|e| Status::invalid_argument(format!("Could not serialize the plan: {e}")))?;
+        let request = self
+            .request(PlanJobRequest {
+                query: Some(Query {
+                    composite_plan,
+                    ..Default::default()
+                }),
+                checkpoint,
+            })
+            .await?;
+        Ok(self.polars.submit_plan_job(request).await?.into_inner())
+    }
+
+    pub async fn plan_job(&mut self, job: &str) -> Result<PlanJob, Status> {
+        let request = self
+            .request(PlanJobQuery {
+                job: job.to_string(),
+            })
+            .await?;
+        Ok(self.polars.get_plan_job(request).await?.into_inner())
+    }
+
+    /// Sets the default policy of the requester, or of `group` with its members, see
+    /// [`bastionlab_polars::default_policies`].
+    pub async fn set_default_policy(
+        &mut self,
+        policy: &serde_json::Value,
+        blacklist_patterns: &[&str],
+        group: Option<(&str, &[String])>,
+    ) -> Result<DefaultPolicyResponse, Status> {
+        let (group, members) = group.unwrap_or_default();
+        let request = self
+            .request(DefaultPolicyRequest {
+                policy: policy.to_string(),
+                blacklist_patterns: blacklist_patterns.iter().map(|p| p.to_string()).collect(),
+                group: group.to_string(),
+                members: members.to_vec(),
+            })
+            .await?;
+        Ok(self.polars.set_default_policy(request).await?.into_inner())
+    }
+
+    /// The latest default policy of the requester, or of `group`, or `version` if set.
+    pub async fn default_policy(
+        &mut self,
+        group: Option<&str>,
+        version: Option<u32>,
+    ) -> Result<DefaultPolicyResponse, Status> {
+        let request = self
+            .request(DefaultPolicyQuery {
+                group: group.unwrap_or_default().to_string(),
+                version: version.unwrap_or(0),
+            })
+            .await?;
+        Ok(self.polars.get_default_policy(request).await?.into_inner())
+    }
+
+    /// Registers a pipeline, or a new version of it, see [`bastionlab_polars::pipelines`].
+    pub async fn register_pipeline(
+        &mut self,
+        name: &str,
+        plan: &serde_json::Value,
+        parameters: &[Parameter],
+        visibility: &Visibility,
+    ) -> Result<PipelineResponse, Status> {
+        let serialize_err =
+            |e: serde_json::Error| Status::invalid_argument(format!("Could not serialize: {e}"));
+        let request = self
+            .request(RegisterPipelineRequest {
+                name: name.to_string(),
+                plan: serde_json::to_string(plan).map_err(serialize_err)?,
+                parameters: serde_json::to_string(parameters).map_err(serialize_err)?,
+                visibility: serde_json::to_string(visibility).map_err(serialize_err)?,
+            })
+            .await?;
+        Ok(self.polars.register_pipeline(request).await?.into_inner())
+    }
+
+    /// Runs the latest version of pipeline `name`, or `version` if set.
+    pub async fn run_pipeline(
+        &mut self,
+        name: &str,
+        version: Option<u32>,
+        parameters: &[(&str, serde_json::Value)],
+    ) -> Result<ReferenceResponse, Status> {
+        let composite_plan = match version {
+            Some(version) => format!("{PIPELINE_PREFIX}{name}@{version}"),
+            None => format!("{PIPELINE_PREFIX}{name}"),
+        };
+        let request = self
+            .request(Query {
+                composite_plan,
+                parameters: parameters
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(self.polars.run_query(request).await?.into_inner())
+    }
+
+    /// Generates a synthetic version of a dataframe whose policy allows it, see
+    /// [`bastionlab_polars::synthetic`].
+    ///
+    /// `rows` defaults to the rows of the source and `seed` to a random one, recorded in the header
+    /// of the result.
+    pub async fn generate_synthetic(
+        &mut self,
+        identifier: &str,
+        rows: Option<u64>,
+        seed: Option<u64>,
+    ) -> Result<ReferenceResponse, Status> {
+        let request = self
+            .request(SyntheticRequest {
+                identifier: identifier.to_string(),
+                rows,
+                seed,
+            })
+            .await?;
+        Ok(self.polars.generate_synthetic(request).await?.into_inner())
+    }
+
+    /// Registers a materialized view maintained by the server, see [`bastionlab_polars::views`].
+    ///
+    /// `plan` is a group-by over a dataframe: running the same plan is then answered from the
+    /// view.
+    pub async fn register_view(
+        &mut self,
+        name: &str,
+        plan: &CompositePlan,
+        max_staleness: Duration,
+    ) -> Result<ViewResponse, Status> {
+        let composite_plan = serde_json::to_string(plan)
+            .map_err(|e| Status::invalid_argument(format!("Could not serialize the plan: {e}")))?;
+        let request = self
+            .request(RegisterViewRequest {
+                name: name.to_string(),
+                composite_plan,
+                max_staleness_secs: max_staleness.as_secs(),
+            })
+            .await?;
+        Ok(self.polars.register_view(request).await?.into_inner())
+    }
+
+    /// Recomputes a view from its base, e.g. once it is stale or invalid.
+    pub async fn refresh_view(&mut self, name: &str) -> Result<ViewResponse, Status> {
+        let request = self
+            .request(ViewRequest {
+                name: name.to_string(),
+            })
+            .await?;
+        Ok(self.polars.refresh_view(request).await?.into_inner())
+    }
+
+    /// Edits a draft dataframe, see [`bastionlab_polars::lifecycle`].
+    pub async fn update_draft(
+        &mut self,
+        request: UpdateDraftRequest,
+    ) -> Result<LifecycleResponse, Status> {
+        let request = self.request(request).await?;
+        Ok(self.polars.update_draft(request).await?.into_inner())
+    }
+
+    /// Submits a draft dataframe to the reviewers.
+    pub async fn submit_for_review(
+        &mut self,
+        identifier: &str,
+    ) -> Result<LifecycleResponse, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self.polars.submit_for_review(request).await?.into_inner())
+    }
+
+    /// Publishes a dataframe in review, or makes it a draft again with the reason of the
+    /// rejection. Only reviewers can do this.
+    pub async fn review_dataframe(
+        &mut self,
+        identifier: &str,
+        approve: bool,
+        reason: &str,
+    ) -> Result<LifecycleResponse, Status> {
+        let request = self
+            .request(ReviewRequest {
+                identifier: identifier.to_string(),
+                approve,
+                reason: reason.to_string(),
+            })
+            .await?;
+        Ok(self.polars.review_data_frame(request).await?.into_inner())
+    }
+
+    /// Makes a published dataframe a draft again, dropping its views.
+    pub async fn unpublish_dataframe(
+        &mut self,
+        identifier: &str,
+    ) -> Result<LifecycleResponse, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self
+            .polars
+            .unpublish_data_frame(request)
+            .await?
+            .into_inner())
+    }
+
+    pub async fn lifecycle(&mut self, identifier: &str) -> Result<LifecycleResponse, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self.polars.get_lifecycle(request).await?.into_inner())
+    }
+
+    /// Deletes a dataframe holding the same data as `canonical`, whose identifier then resolves
+    /// to `canonical`. Only data owners can do this.
+    pub async fn deduplicate_dataframe(
+        &mut self,
+        identifier: &str,
+        canonical: &str,
+    ) -> Result<AliasResponse, Status> {
+        let request = self
+            .request(DeduplicateRequest {
+                identifier: identifier.to_string(),
+                canonical: canonical.to_string(),
+            })
+            .await?;
+        Ok(self
+            .polars
+            .deduplicate_data_frame(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Makes `alias` resolve to `canonical`. Only data owners can do this.
+    pub async fn create_alias(
+        &mut self,
+        alias: &str,
+        canonical: &str,
+        reason: &str,
+    ) -> Result<AliasResponse, Status> {
+        let request = self
+            .request(AliasRequest {
+                alias: alias.to_string(),
+                canonical: canonical.to_string(),
+                reason: reason.to_string(),
+            })
+            .await?;
+        Ok(self.polars.create_alias(request).await?.into_inner())
+    }
+
+    /// The identifier and header of a dataframe, with a redirect note if `identifier` is an alias.
+    pub async fn header(&mut self, identifier: &str) -> Result<ReferenceResponse, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self
+            .polars
+            .get_data_frame_header(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Lists all the dataframes, going through every page.
+    pub async fn list_dataframes(&mut self) -> Result<Vec<ReferenceResponse>, Status> {
+        let mut list = Vec::new();
+        let mut page_token = String::new();
+        loop {
+            let page = self
+                .list_dataframes_page(ListDataFramesRequest {
+                    page_token,
+                    ..Default::default()
+                })
+                .await?;
+            list.extend(page.list);
+            if page.next_page_token.is_empty() {
+                return Ok(list);
+            }
+            page_token = page.next_page_token;
+        }
+    }
+
+    /// Lists one page of the dataframes matching the filters of `request`, see
+    /// [`bastionlab_polars::catalog`].
+    pub async fn list_dataframes_page(
+        &mut self,
+        request: ListDataFramesRequest,
+    ) -> Result<ReferenceList, Status> {
+        let request = self.request(request).await?;
+        Ok(self.polars.list_data_frames(request).await?.into_inner())
+    }
+
+    /// Saves a dataframe on the server's disk, which its policy must allow.
+    pub async fn persist_dataframe(&mut self, identifier: &str) -> Result<(), Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        self.polars.persist_data_frame(request).await?;
+        Ok(())
+    }
+
+    /// Deletes a dataframe and its persisted copy. Data owners can delete any dataframe, other
+    /// users only the results they computed.
+    pub async fn delete_dataframe(&mut self, identifier: &str) -> Result<(), Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        self.polars.delete_data_frame(request).await?;
+        Ok(())
+    }
+
+    /// Fetches a dataframe with its declared dtypes in the canonical format, verifying its checksum.
+    ///
+    /// This waits for the data owner's decision when the query needs approval; a rejection is
+    /// returned as an error.
+    pub async fn fetch(
+        &mut self,
+        reference: &ReferenceResponse,
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_for(reference, None).await
+    }
+
+    /// Fetches a dataframe like [`Client::fetch`], stating why, as required by some policies.
+    pub async fn fetch_with_purpose(
+        &mut self,
+        reference: &ReferenceResponse,
+        purpose: &Purpose,
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_for(reference, Some(purpose.clone())).await
+    }
+
+    /// Fetches a dataframe like [`Client::fetch`], without masks nor watermarks under export
+    /// waiver `waiver`, which a data owner issued to this client with
+    /// [`Client::create_export_waiver`]. Waivers can only be used once.
+    pub async fn fetch_with_waiver(
+        &mut self,
+        reference: &ReferenceResponse,
+        waiver: &str,
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_request(ReferenceRequest {
+            waiver: waiver.to_string(),
+            ..self.fetch_reference(&reference.identifier)
+        })
+        .await
+    }
+
+    /// Fetches `columns` of a dataframe, in this order, like [`Client::fetch`]. The other
+    /// columns stay on the server; requesting a blacklisted column is refused.
+    pub async fn fetch_columns(
+        &mut self,
+        reference: &ReferenceResponse,
+        columns: &[&str],
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_request(ReferenceRequest {
+            columns: columns.iter().map(|name| name.to_string()).collect(),
+            ..self.fetch_reference(&reference.identifier)
+        })
+        .await
+    }
+
+    /// Fetches the `limit` rows of a dataframe from `offset`, the rest of them if `None`, like
+    /// [`Client::fetch`], for pagination. Offsets past the end fetch no rows. The policy applies to
+    /// the whole dataframe, whose number of rows comes in [`FetchedDataFrame::row_range`].
+    pub async fn fetch_rows(
+        &mut self,
+        reference: &ReferenceResponse,
+        offset: u64,
+        limit: Option<u64>,
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_request(ReferenceRequest {
+            offset: Some(offset),
+            limit,
+            ..self.fetch_reference(&reference.identifier)
+        })
+        .await
+    }
+
+    pub(crate) async fn fetch_for(
+        &mut self,
+        reference: &ReferenceResponse,
+        purpose: Option<Purpose>,
+    ) -> Result<FetchedDataFrame, Status> {
+        self.fetch_request(ReferenceRequest {
+            purpose,
+            ..self.fetch_reference(&reference.identifier)
+        })
+        .await
+    }
+
+    async fn fetch_request(
+        &mut self,
+        request: ReferenceRequest,
+    ) -> Result<FetchedDataFrame, Status> {
+        let format = request.format();
+        let request = self.request(request).await?;
+        let mut stream = self.polars.fetch_data_frame(request).await?.into_inner();
+        let mut assembler = FetchAssembler::with_format(format);
+        while let Some(chunk) = stream.next().await {
+            assembler.push(chunk?)?;
+        }
+        let shape = assembler.shape().cloned();
+        let fingerprint = assembler.fingerprint().map(String::from);
+        let row_range = assembler.row_range().cloned();
+        let (status, dataframe) = assembler.finish()?;
+        Ok(FetchedDataFrame {
+            status,
+            dataframe,
+            shape,
+            fingerprint,
+            row_range,
+        })
+    }
+
+    /// Fetches a new version of a result, receiving only the rows changed since `previous`, a
+    /// version fetched before as `previous_df`. Rows are matched across versions on `keys`.
+    ///
+    /// The server sends the full result instead when the previous version is gone or the keys
+    /// do not identify the rows.
+    pub async fn fetch_delta(
+        &mut self,
+        reference: &ReferenceResponse,
+        previous: &ReferenceResponse,
+        previous_df: &DataFrame,
+        keys: &[String],
+    ) -> Result<DeltaFetchedDataFrame, Status> {
+        let request = ReferenceRequest {
+            delta_since: previous.identifier.clone(),
+            delta_keys: keys.to_vec(),
+            ..self.fetch_reference(&reference.identifier)
+        };
+        let format = request.format();
+        let request = self.request(request).await?;
+        let mut stream = self.polars.fetch_data_frame(request).await?.into_inner();
+        let mut assembler = FetchAssembler::with_format(format);
+        while let Some(chunk) = stream.next().await {
+            assembler.push(chunk?)?;
+        }
+        let header = assembler.delta().cloned();
+        let (status, dataframe) = assembler.finish()?;
+        let (dataframe, full_reason) = match header {
+            Some(header) if !header.full => (delta::apply(previous_df, &dataframe, keys)?, None),
+            Some(header) => (dataframe, Some(header.reason)),
+            None => (
+                dataframe,
+                Some("The server does not support delta fetches".to_string()),
+            ),
+        };
+        Ok(DeltaFetchedDataFrame {
+            status,
+            dataframe,
+            full_reason,
+        })
+    }
+
+    /// Starts fetching a dataframe like [`Client::fetch`], returning the raw chunks.
+    pub async fn fetch_stream(
+        &mut self,
+        reference: &ReferenceResponse,
+    ) -> Result<Streaming<FetchChunk>, Status> {
+        let request = self
+            .request(ReferenceRequest {
+                identifier: reference.identifier.clone(),
+                restore_dtypes: true,
+                canonical_format: true,
+                chunk_kb: self.fetch_chunk_kb,
+                ..Default::default()
+            })
+            .await?;
+        Ok(self.polars.fetch_data_frame(request).await?.into_inner())
+    }
+
+    /// Starts fetching a dataframe column by column in `order`, returning the raw chunks. Each
+    /// column follows a `ColumnStart` chunk, and [`FetchAssembler::column`] decodes it as soon as
+    /// all of its data is in, before the next columns arrive.
+    pub async fn fetch_columns_stream(
+        &mut self,
+        reference: &ReferenceResponse,
+        order: ColumnOrder,
+    ) -> Result<Streaming<FetchChunk>, Status> {
+        let request = self
+            .request(ReferenceRequest {
+                identifier: reference.identifier.clone(),
+                restore_dtypes: true,
+                canonical_format: true,
+                column_order: Some(order),
+                chunk_kb: self.fetch_chunk_kb,
+                ..Default::default()
+            })
+            .await?;
+        Ok(self.polars.fetch_data_frame(request).await?.into_inner())
+    }
+
+    /// Fetches a result with one row and one column as a value. Other results are rejected.
+    pub async fn fetch_scalar(
+        &mut self,
+        reference: &ReferenceResponse,
+    ) -> Result<FetchedScalar, Status> {
+        let request = self
+            .request(reference_request(&reference.identifier))
+            .await?;
+        let scalar = self.polars.fetch_scalar(request).await?.into_inner();
+        Ok(FetchedScalar {
+            value: Scalar::from(scalar.value),
+            dtype: scalar.dtype,
+            warning: (!scalar.warning.is_empty()).then_some(scalar.warning),
+        })
+    }
+
+    /// Creates the signed reproducibility bundle of a result. Only the owner of the result and
+    /// data owners can do this.
+    pub async fn create_reproducibility_bundle(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Vec<u8>, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self
+            .polars
+            .create_reproducibility_bundle(request)
+            .await?
+            .into_inner()
+            .bundle)
+    }
+
+    /// Checks a bundle against the current inputs and runs its plan again.
+    pub async fn verify_reproducibility_bundle(
+        &mut self,
+        bundle: &[u8],
+    ) -> Result<ReproducibilityReport, Status> {
+        let request = self
+            .request(ReproducibilityBundle {
+                bundle: bundle.to_vec(),
+            })
+            .await?;
+        Ok(self
+            .polars
+            .verify_reproducibility_bundle(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Accesses to the dataframes of the data owner by purpose code, restricted to those touching
+    /// `identifier` if set, since `since` in milliseconds since the Unix epoch.
+    pub async fn usage_report(
+        &mut self,
+        identifier: Option<&str>,
+        since: u64,
+    ) -> Result<Vec<PurposeUsage>, Status> {
+        let request = self
+            .request(UsageReportRequest {
+                identifier: identifier.unwrap_or_default().to_string(),
+                since,
+            })
+            .await?;
+        Ok(self
+            .polars
+            .get_usage_report(request)
+            .await?
+            .into_inner()
+            .purposes)
+    }
+
+    /// The export waivers the data owner issued that were used since `since`, in milliseconds
+    /// since the Unix epoch, restricted to those covering `identifier` if set.
+    pub async fn used_export_waivers(
+        &mut self,
+        identifier: Option<&str>,
+        since: u64,
+    ) -> Result<Vec<ExportWaiver>, Status> {
+        let request = self
+            .request(UsageReportRequest {
+                identifier: identifier.unwrap_or_default().to_string(),
+                since,
+            })
+            .await?;
+        Ok(self
+            .polars
+            .get_usage_report(request)
+            .await?
+            .into_inner()
+            .waivers)
+    }
+
+    /// Issues a one-time waiver of the masks and watermarks of result `identifier`, for
+    /// `recipient` to fetch it before `expires_at`, in milliseconds since the Unix epoch. Only
+    /// the owners of the data it was computed from can issue waivers.
+    pub async fn create_export_waiver(
+        &mut self,
+        identifier: &str,
+        recipient: &str,
+        expires_at: u64,
+        justification: &str,
+    ) -> Result<ExportWaiver, Status> {
+        let request = self
+            .request(ExportWaiverRequest {
+                identifier: identifier.to_string(),
+                recipient: recipient.to_string(),
+                expires_at,
+                justification: justification.to_string(),
+            })
+            .await?;
+        Ok(self
+            .polars
+            .create_export_waiver(request)
+            .await?
+            .into_inner())
+    }
+
+    /// The recent queries on dataframe `identifier`, oldest first. Only its owner can see them.
+    pub async fn recent_activity(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Vec<ActivityEntry>, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self
+            .polars
+            .get_recent_activity(request)
+            .await?
+            .into_inner()
+            .entries)
+    }
+
+    pub async fn create_workspace(&mut self, name: &str) -> Result<WorkspaceResponse, Status> {
+        let request = self
+            .request(WorkspaceRequest {
+                name: name.to_string(),
+            })
+            .await?;
+        Ok(self.polars.create_workspace(request).await?.into_inner())
+    }
+
+    /// Attaches dataframes the user can read to their workspace `name`.
+    pub async fn attach_to_workspace(
+        &mut self,
+        name: &str,
+        identifiers: &[&str],
+    ) -> Result<WorkspaceResponse, Status> {
+        let request = self.request(workspace_members(name, identifiers)).await?;
+        Ok(self.polars.attach_to_workspace(request).await?.into_inner())
+    }
+
+    pub async fn detach_from_workspace(
+        &mut self,
+        name: &str,
+        identifiers: &[&str],
+    ) -> Result<WorkspaceResponse, Status> {
+        let request = self.request(workspace_members(name, identifiers)).await?;
+        Ok(self
+            .polars
+            .detach_from_workspace(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Lets `users`, and only them, read and query the members of workspace `name` the user owns.
+    pub async fn share_workspace(
+        &mut self,
+        name: &str,
+        users: &[&str],
+    ) -> Result<WorkspaceResponse, Status> {
+        let request = self
+            .request(ShareWorkspaceRequest {
+                name: name.to_string(),
+                users: users.iter().map(|user| user.to_string()).collect(),
+            })
+            .await?;
+        Ok(self.polars.share_workspace(request).await?.into_inner())
+    }
+
+    /// Deletes the members of workspace `name`, reporting those that could not be.
+    pub async fn delete_workspace_members(&mut self, name: &str) -> Result<BulkResponse, Status> {
+        let request = self
+            .request(WorkspaceRequest {
+                name: name.to_string(),
+            })
+            .await?;
+        Ok(self
+            .polars
+            .delete_workspace_members(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Deletes workspace `name`, detaching its members, or deleting them too if `cascade`.
+    pub async fn delete_workspace(
+        &mut self,
+        name: &str,
+        cascade: bool,
+    ) -> Result<BulkResponse, Status> {
+        let request = self
+            .request(DeleteWorkspaceRequest {
+                name: name.to_string(),
+                cascade,
+            })
+            .await?;
+        Ok(self.polars.delete_workspace(request).await?.into_inner())
+    }
+
+    /// Lists the plan segments, formats and optional operations the server was built with.
+    pub async fn server_capabilities(&mut self) -> Result<ServerCapabilities, Status> {
+        let request = self.request(polars_proto::Empty {}).await?;
+        Ok(self
+            .polars
+            .get_server_capabilities(request)
+            .await?
+            .into_inner())
+    }
+
+    /// How many handshakes each client name, version and API version made, most first. Only
+    /// data owners can list them.
+    pub async fn client_versions(&mut self) -> Result<Vec<ClientVersionCount>, Status> {
+        let request = self.request(polars_proto::Empty {}).await?;
+        Ok(self
+            .polars
+            .get_client_versions(request)
+            .await?
+            .into_inner()
+            .versions)
+    }
+
+    /// The queries in flight, every one for data owners who also get the last kills, their own
+    /// for others, see [`bastionlab_polars::running`].
+    pub async fn list_running_queries(&mut self) -> Result<RunningQueries, Status> {
+        let request = self.request(polars_proto::Empty {}).await?;
+        Ok(self
+            .polars
+            .list_running_queries(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Kills running query `id` for `reason`. Queries stop at their next segment boundary, unless
+    /// already finishing, as the `state` of the kill tells.
+    pub async fn kill_query(&mut self, id: &str, reason: &str) -> Result<QueryKill, Status> {
+        let request = self
+            .request(KillQueryRequest {
+                id: id.to_string(),
+                reason: reason.to_string(),
+            })
+            .await?;
+        Ok(self.polars.kill_query(request).await?.into_inner())
+    }
+
+    /// The fetches pending the approval of a data owner, which only data owners list, see
+    /// [`bastionlab_polars::approvals`].
+    pub async fn list_pending_requests(&mut self) -> Result<PendingRequests, Status> {
+        let request = self.request(polars_proto::Empty {}).await?;
+        Ok(self
+            .polars
+            .list_pending_requests(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Approves pending fetch `id`: the fetch that waits for it receives the dataframe.
+    pub async fn approve_request(&mut self, id: &str) -> Result<PendingRequest, Status> {
+        let request = self
+            .request(ApproveRequestRequest { id: id.to_string() })
+            .await?;
+        Ok(self.polars.approve_request(request).await?.into_inner())
+    }
+
+    /// Rejects pending fetch `id`, with `reason` sent to the client that requested it if not
+    /// empty.
+    pub async fn reject_request(
+        &mut self,
+        id: &str,
+        reason: &str,
+    ) -> Result<PendingRequest, Status> {
+        let request = self
+            .request(RejectRequestRequest {
+                id: id.to_string(),
+                reason: reason.to_string(),
+            })
+            .await?;
+        Ok(self.polars.reject_request(request).await?.into_inner())
+    }
+
+    /// The entries of the audit log from `since` to `until` (milliseconds since the Unix epoch,
+    /// unbounded if 0) touching `identifier` if not empty, which only data owners read, see
+    /// [`bastionlab_polars::audit`].
+    pub async fn audit_log(
+        &mut self,
+        since: u64,
+        until: u64,
+        identifier: &str,
+    ) -> Result<AuditLog, Status> {
+        let request = self
+            .request(AuditLogRequest {
+                since,
+                until,
+                identifier: identifier.to_string(),
+            })
+            .await?;
+        Ok(self.polars.get_audit_log(request).await?.into_inner())
+    }
+
+    /// Sets the storage class of dataframe `identifier`: `hot`, `warm`, `cold`, or `auto` to let
+    /// the server classify it. Returns the job applying the change, polled with
+    /// [`Client::storage_class_job`].
+    pub async fn set_storage_class(
+        &mut self,
+        identifier: &str,
+        storage_class: &str,
+    ) -> Result<StorageClassJob, Status> {
+        let request = self
+            .request(StorageClassRequest {
+                identifier: identifier.to_string(),
+                storage_class: storage_class.to_string(),
+            })
+            .await?;
+        Ok(self.polars.set_storage_class(request).await?.into_inner())
+    }
+
+    pub async fn storage_class_job(&mut self, job: u64) -> Result<StorageClassJob, Status> {
+        let request = self.request(StorageClassJobRequest { job }).await?;
+        Ok(self
+            .polars
+            .get_storage_class_job(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Rolls `patch` out on the datasets `selector` matches, or only reports its impact if
+    /// `dry_run`. Only data owners can do this.
+    pub async fn roll_out_policy(
+        &mut self,
+        selector: PolicySelector,
+        patch: &PolicyPatch,
+        dry_run: bool,
+    ) -> Result<PolicyRolloutReport, Status> {
+        let patch = serde_json::to_string(patch)
+            .map_err(|e| Status::invalid_argument(format!("Invalid policy patch: {e}")))?;
+        let request = self
+            .request(PolicyRolloutRequest {
+                selector: Some(selector),
+                patch,
+                dry_run,
+            })
+            .await?;
+        Ok(self.polars.roll_out_policy(request).await?.into_inner())
+    }
+
+    pub async fn roll_back_policy_rollout(
+        &mut self,
+        rollout_id: &str,
+    ) -> Result<PolicyRolloutReport, Status> {
+        let request = self
+            .request(RolloutRequest {
+                rollout_id: rollout_id.to_string(),
+            })
+            .await?;
+        Ok(self
+            .polars
+            .roll_back_policy_rollout(request)
+            .await?
+            .into_inner())
+    }
+
+    pub async fn policy_history(&mut self, identifier: &str) -> Result<PolicyHistory, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self.polars.get_policy_history(request).await?.into_inner())
+    }
+
+    /// The retained versions of `identifier`, the current one last, see
+    /// [`bastionlab_polars::versions`].
+    pub async fn list_versions(&mut self, identifier: &str) -> Result<VersionList, Status> {
+        let request = self.request(reference_request(identifier)).await?;
+        Ok(self.polars.list_versions(request).await?.into_inner())
+    }
+
+    /// Retains the `max_versions` most recent superseded versions of `identifier`, for
+    /// `max_age_secs` after they were superseded (without limit if 0).
+    pub async fn set_version_retention(
+        &mut self,
+        identifier: &str,
+        max_versions: u32,
+        max_age_secs: u64,
+    ) -> Result<VersionList, Status> {
+        let request = self
+            .request(VersionRetentionRequest {
+                identifier: identifier.to_string(),
+                max_versions,
+                max_age_secs,
+            })
+            .await?;
+        Ok(self
+            .polars
+            .set_version_retention(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Lists the connections open on the server. Only data owners can do this.
+    pub async fn list_connections(&mut self) -> Result<Vec<ConnectionInfo>, Status> {
+        let request = self.request(Empty {}).await?;
+        Ok(self
+            .connections
+            .list_connections(request)
+            .await?
+            .into_inner()
+            .connections)
+    }
+
+    /// Asks every connection open on the server to reconnect once it has no request in flight,
+    /// and returns their number. Only data owners can do this.
+    ///
+    /// Sessions are bound to their connection, so clients authenticate again after reconnecting.
+    pub async fn drain_connections(&mut self) -> Result<u32, Status> {
+        let request = self.request(Empty {}).await?;
+        Ok(self
+            .connections
+            .drain_connections(request)
+            .await?
+            .into_inner()
+            .draining)
+    }
+
+    /// Lists the public keys the server authenticates, sorted by hash. Only data owners can do
+    /// this.
+    pub async fn list_public_keys(&mut self) -> Result<Vec<PublicKey>, Status> {
+        let request = self.request(Empty {}).await?;
+        Ok(self.keys.list_public_keys(request).await?.into_inner().keys)
+    }
+
+    /// Adds PEM public key `pem` with `role`, which authenticates at once. Only data owners can
+    /// do this.
+    pub async fn add_public_key(&mut self, role: KeyRole, pem: &str) -> Result<PublicKey, Status> {
+        let request = self
+            .request(AddPublicKeyRequest {
+                role: role as i32,
+                pem: pem.to_string(),
+            })
+            .await?;
+        Ok(self.keys.add_public_key(request).await?.into_inner())
+    }
+
+    /// Removes the public key with `hash`, ending its sessions. Only data owners can do this.
+    pub async fn remove_public_key(&mut self, hash: &str) -> Result<PublicKey, Status> {
+        let request = self
+            .request(RemovePublicKeyRequest {
+                hash: hash.to_string(),
+            })
+            .await?;
+        Ok(self.keys.remove_public_key(request).await?.into_inner())
+    }
+}
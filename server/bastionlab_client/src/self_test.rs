@@ -0,0 +1,619 @@
+//! End-to-end self-test of the server against a synthetic dataset.
+//!
+//! The test only touches the dataframes it creates, so it can also run against a live server, and
+//! it deletes all of them whatever the outcome. Features add their own checks by implementing
+//! [`SelfTestStep`] and registering it with [`SelfTest::register`].
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bastionlab_common::config::BastionLabConfig;
+use bastionlab_common::prelude::*;
+use bastionlab_common::session::SessionManager;
+use bastionlab_polars::polars_proto::ReferenceResponse;
+use polars::prelude::*;
+use tonic::{Code, Request, Response, Status};
+
+use crate::harness::InProcessServer;
+use crate::self_test_proto::{self, self_test_service_server::SelfTestService, SelfTestRequest};
+use crate::{Client, CompositePlan, CompositePlanSegment, FetchStatus, Policy, SigningKey};
+
+const ROWS: usize = 240;
+const CATEGORIES: [&str; 6] = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta"];
+
+/// Rows are only fetchable in groups of at least 10, every category has 40.
+const POLICY: &str = r#"{
+    "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+    "unsafe_handling": {"type": "Reject"},
+    "savable": true
+}"#;
+
+/// A deterministic dataframe with a column of each common dtype, some of them with nulls.
+pub fn synthetic_dataframe() -> DataFrame {
+    let day = Series::new(
+        "day",
+        (0..ROWS).map(|i| 19_000 + i as i32).collect::<Vec<_>>(),
+    )
+    .cast(&DataType::Date)
+    .expect("Dates are stored as Int32");
+    let mut df = df! {
+        "id" => (0..ROWS as i64).collect::<Vec<_>>(),
+        "category" => (0..ROWS).map(|i| CATEGORIES[i % CATEGORIES.len()]).collect::<Vec<_>>(),
+        "value" => (0..ROWS)
+            .map(|i| (i % 17 != 0).then(|| (i * 37 % 101) as f64 / 4.0))
+            .collect::<Vec<_>>(),
+        "count" => (0..ROWS).map(|i| (i % 9) as i32).collect::<Vec<_>>(),
+        "flag" => (0..ROWS).map(|i| i % 3 == 0).collect::<Vec<_>>(),
+    }
+    .expect("The synthetic columns have the same length");
+    df.with_column(day)
+        .expect("The synthetic columns have the same length");
+    df
+}
+
+fn aggregate(lf: LazyFrame) -> LazyFrame {
+    lf.groupby([col("category")])
+        .agg([
+            col("value").sum().alias("total"),
+            col("id").count().alias("rows"),
+        ])
+        .sort("category", Default::default())
+}
+
+fn entry_point(identifier: &str) -> CompositePlanSegment {
+    CompositePlanSegment::EntryPointPlanSegment {
+        identifier: identifier.to_string(),
+    }
+}
+
+/// A Polars segment aggregating the dataframe on top of the stack.
+fn aggregation_segment(df: &DataFrame) -> CompositePlanSegment {
+    CompositePlanSegment::PolarsPlanSegment {
+        plan: aggregate(df.head(Some(0)).lazy()).logical_plan,
+        skip_nan: false,
+        resources: None,
+    }
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error: {e}"))
+}
+
+fn expect_header(reference: &ReferenceResponse, column: &str) -> Result<(), Status> {
+    if reference.header.contains(&format!("\"{column}\"")) {
+        Ok(())
+    } else {
+        Err(Status::internal(format!(
+            "Column {column} is missing from the result header: {}",
+            reference.header
+        )))
+    }
+}
+
+/// Files of the data directory that belong to `identifier`.
+fn persisted_files(dir: &Path, identifier: &str) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with(identifier))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SelfTestOptions {
+    /// Check that persisted dataframes are reloaded. Only done against an in-process server.
+    pub persistence: bool,
+}
+
+impl Default for SelfTestOptions {
+    fn default() -> Self {
+        SelfTestOptions { persistence: true }
+    }
+}
+
+/// The state shared by the steps of a self-test.
+pub struct SelfTestContext<'a> {
+    /// A client authenticated as a data owner.
+    pub client: Client,
+    pub addr: String,
+    /// The in-process server the test runs against, `None` for a live server.
+    pub server: Option<&'a InProcessServer>,
+    pub options: SelfTestOptions,
+    dataset: Option<(ReferenceResponse, DataFrame)>,
+    created: Vec<String>,
+}
+
+impl<'a> SelfTestContext<'a> {
+    /// The synthetic dataframe uploaded by the upload step, and its reference on the server.
+    pub fn dataset(&self) -> Result<(&ReferenceResponse, &DataFrame), Status> {
+        self.dataset
+            .as_ref()
+            .map(|(reference, df)| (reference, df))
+            .ok_or_else(|| Status::failed_precondition("The synthetic dataset was not uploaded"))
+    }
+
+    /// Registers a dataframe created by a step, so that it is deleted at the end of the test.
+    pub fn track(&mut self, reference: &ReferenceResponse) {
+        self.created.push(reference.identifier.clone());
+    }
+
+    /// Runs a plan and tracks its result.
+    pub async fn run_plan(
+        &mut self,
+        segments: Vec<CompositePlanSegment>,
+    ) -> Result<ReferenceResponse, Status> {
+        let reference = self.client.run_plan(&CompositePlan::new(segments)).await?;
+        self.track(&reference);
+        Ok(reference)
+    }
+}
+
+/// A check of the self-test.
+#[tonic::async_trait]
+pub trait SelfTestStep: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Runs the check, returning a short description of what was verified.
+    ///
+    /// Dataframes created by the step must be registered with [`SelfTestContext::track`].
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status>;
+}
+
+#[derive(Debug, Clone)]
+pub struct StepReport {
+    pub name: String,
+    pub passed: bool,
+    pub duration: Duration,
+    pub detail: String,
+}
+
+impl StepReport {
+    fn new(name: &str, start: Instant, result: Result<String, Status>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, format!("{:?}: {}", e.code(), e.message())),
+        };
+        StepReport {
+            name: name.to_string(),
+            passed,
+            duration: start.elapsed(),
+            detail,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SelfTestReport {
+    pub steps: Vec<StepReport>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.passed)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in self.steps.iter() {
+            writeln!(
+                f,
+                "[{}] {} ({} ms): {}",
+                if step.passed { "PASS" } else { "FAIL" },
+                step.name,
+                step.duration.as_millis(),
+                step.detail
+            )?;
+        }
+        let failed = self.steps.iter().filter(|step| !step.passed).count();
+        if failed == 0 {
+            write!(f, "Self-test passed ({} steps)", self.steps.len())
+        } else {
+            write!(f, "Self-test failed ({failed}/{} steps)", self.steps.len())
+        }
+    }
+}
+
+impl From<SelfTestReport> for self_test_proto::SelfTestReport {
+    fn from(report: SelfTestReport) -> Self {
+        self_test_proto::SelfTestReport {
+            passed: report.passed(),
+            steps: report
+                .steps
+                .into_iter()
+                .map(|step| self_test_proto::SelfTestStep {
+                    name: step.name,
+                    passed: step.passed,
+                    duration_ms: step.duration.as_millis() as u64,
+                    detail: step.detail,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// An ordered list of steps, followed by the deletion of everything they created.
+pub struct SelfTest {
+    steps: Vec<Box<dyn SelfTestStep>>,
+}
+
+impl Default for SelfTest {
+    /// The pipeline checks: authentication, upload, queries, policy enforcement and persistence.
+    fn default() -> Self {
+        let mut test = SelfTest::empty();
+        test.register(ChallengeAuth)
+            .register(Upload)
+            .register(SegmentFamilies)
+            .register(BlockedFetch)
+            .register(AllowedFetch)
+            .register(PersistenceRoundTrip);
+        test
+    }
+}
+
+impl SelfTest {
+    pub fn empty() -> Self {
+        SelfTest { steps: Vec::new() }
+    }
+
+    /// Adds a step, run after the already registered ones.
+    pub fn register(&mut self, step: impl SelfTestStep + 'static) -> &mut Self {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// Runs the self-test against a fresh in-process server, which is discarded afterwards.
+    pub async fn run(&self, config: &BastionLabConfig, options: SelfTestOptions) -> SelfTestReport {
+        let start = Instant::now();
+        let server = match InProcessServer::start(config).await {
+            Ok(server) => server,
+            Err(e) => {
+                return SelfTestReport {
+                    steps: vec![StepReport::new("server start", start, Err(e))],
+                }
+            }
+        };
+        let client = match server.client().await {
+            Ok(client) => client,
+            Err(e) => {
+                return SelfTestReport {
+                    steps: vec![StepReport::new("server start", start, Err(e))],
+                }
+            }
+        };
+        let addr = server.addr().to_string();
+        self.run_with(client, addr, Some(&server), options).await
+    }
+
+    /// Runs the self-test against a live server, with a client authenticated as a data owner.
+    pub async fn run_live(&self, client: Client, addr: String) -> SelfTestReport {
+        let options = SelfTestOptions { persistence: false };
+        self.run_with(client, addr, None, options).await
+    }
+
+    async fn run_with(
+        &self,
+        client: Client,
+        addr: String,
+        server: Option<&InProcessServer>,
+        options: SelfTestOptions,
+    ) -> SelfTestReport {
+        let mut ctx = SelfTestContext {
+            client,
+            addr,
+            server,
+            options,
+            dataset: None,
+            created: Vec::new(),
+        };
+        let mut report = SelfTestReport::default();
+        for step in self.steps.iter() {
+            let start = Instant::now();
+            let result = step.run(&mut ctx).await;
+            if let Err(e) = &result {
+                warn!("Self-test step {} failed: {}", step.name(), e.message());
+            }
+            report
+                .steps
+                .push(StepReport::new(step.name(), start, result));
+        }
+
+        let start = Instant::now();
+        let result = delete_created(&mut ctx).await;
+        report
+            .steps
+            .push(StepReport::new("deletion", start, result));
+        report
+    }
+}
+
+/// Deletes every dataframe created by the test and checks that they are gone, even from disk.
+async fn delete_created(ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+    let created = std::mem::take(&mut ctx.created);
+    let mut errors = Vec::new();
+    for identifier in created.iter() {
+        if let Err(e) = ctx.client.delete_dataframe(identifier).await {
+            errors.push(format!("{identifier}: {}", e.message()));
+        }
+    }
+
+    let listed = ctx.client.list_dataframes().await?;
+    for identifier in created.iter() {
+        if listed.iter().any(|r| &r.identifier == identifier) {
+            errors.push(format!("{identifier} is still listed"));
+        }
+        if let Some(server) = ctx.server {
+            if !persisted_files(server.data_dir(), identifier).is_empty() {
+                errors.push(format!("{identifier} is still persisted"));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(format!("deleted {} dataframes", created.len()))
+    } else {
+        Err(Status::internal(format!(
+            "Could not delete every dataframe: {}",
+            errors.join(", ")
+        )))
+    }
+}
+
+struct ChallengeAuth;
+
+#[tonic::async_trait]
+impl SelfTestStep for ChallengeAuth {
+    fn name(&self) -> &str {
+        "challenge auth"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        ctx.client.authenticate().await?;
+
+        let (stranger, _) = SigningKey::generate()?;
+        let mut stranger = Client::connect(ctx.addr.clone(), Some(stranger)).await?;
+        match stranger.authenticate().await {
+            Ok(()) => Err(Status::internal("A session was opened with an unknown key")),
+            Err(_) => Ok(String::from(
+                "the owner signed a challenge and an unknown key was rejected",
+            )),
+        }
+    }
+}
+
+struct Upload;
+
+#[tonic::async_trait]
+impl SelfTestStep for Upload {
+    fn name(&self) -> &str {
+        "upload"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        let df = synthetic_dataframe();
+        let policy: Policy = serde_json::from_str(POLICY)
+            .map_err(|e| Status::internal(format!("Invalid self-test policy: {e}")))?;
+        let reference = ctx.client.upload_dataframe(&df, &policy, &[]).await?;
+        ctx.track(&reference);
+        for name in df.get_column_names() {
+            expect_header(&reference, name)?;
+        }
+        let detail = format!(
+            "{} rows of {} columns ({})",
+            df.height(),
+            df.width(),
+            df.dtypes()
+                .iter()
+                .map(|dtype| dtype.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        ctx.dataset = Some((reference, df));
+        Ok(detail)
+    }
+}
+
+/// Runs a plan with each family of segments but UDFs, which need a TorchScript module.
+struct SegmentFamilies;
+
+#[tonic::async_trait]
+impl SelfTestStep for SegmentFamilies {
+    fn name(&self) -> &str {
+        "composite plans"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        let (reference, df) = ctx.dataset()?;
+        let (identifier, df) = (reference.identifier.clone(), df.clone());
+
+        let result = ctx
+            .run_plan(vec![entry_point(&identifier), aggregation_segment(&df)])
+            .await?;
+        expect_header(&result, "total")?;
+
+        let result = ctx
+            .run_plan(vec![
+                entry_point(&identifier),
+                CompositePlanSegment::RowCountSegment {
+                    row: String::from("row"),
+                },
+            ])
+            .await?;
+        expect_header(&result, "row")?;
+
+        let result = ctx
+            .run_plan(vec![
+                entry_point(&identifier),
+                entry_point(&identifier),
+                CompositePlanSegment::StackPlanSegment,
+                aggregation_segment(&df),
+            ])
+            .await?;
+        let stacked = ctx.client.fetch(&result).await?.dataframe;
+        let rows = stacked
+            .column("rows")
+            .and_then(|s| s.cast(&DataType::UInt64))
+            .map_err(polars_err)?;
+        let expected = (2 * ROWS / CATEGORIES.len()) as u64;
+        if rows
+            .u64()
+            .map_err(polars_err)?
+            .into_iter()
+            .any(|n| n != Some(expected))
+        {
+            return Err(Status::internal(format!(
+                "Stacking the dataset twice should give {expected} rows per category"
+            )));
+        }
+
+        Ok(String::from(
+            "entry point, polars, row count and stack segments",
+        ))
+    }
+}
+
+struct BlockedFetch;
+
+#[tonic::async_trait]
+impl SelfTestStep for BlockedFetch {
+    fn name(&self) -> &str {
+        "blocked fetch"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        let identifier = ctx.dataset()?.0.identifier.clone();
+        let result = ctx.run_plan(vec![entry_point(&identifier)]).await?;
+        match ctx.client.fetch(&result).await {
+            Err(e) if e.code() == Code::PermissionDenied => {
+                Ok(String::from("row-level data was refused by the policy"))
+            }
+            Err(e) => Err(e),
+            Ok(_) => Err(Status::internal(
+                "Row-level data was fetched despite the aggregation policy",
+            )),
+        }
+    }
+}
+
+struct AllowedFetch;
+
+#[tonic::async_trait]
+impl SelfTestStep for AllowedFetch {
+    fn name(&self) -> &str {
+        "allowed fetch"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        let (reference, df) = ctx.dataset()?;
+        let (identifier, df) = (reference.identifier.clone(), df.clone());
+        let result = ctx
+            .run_plan(vec![entry_point(&identifier), aggregation_segment(&df)])
+            .await?;
+        // The checksum of the canonical stream is verified by the client.
+        let fetched = ctx.client.fetch(&result).await?;
+        if fetched.status != FetchStatus::Ok {
+            return Err(Status::internal(format!(
+                "The aggregate was fetched with status {:?}",
+                fetched.status
+            )));
+        }
+
+        let expected = aggregate(df.lazy()).collect().map_err(polars_err)?;
+        if !fetched.dataframe.frame_equal_missing(&expected) {
+            return Err(Status::internal(format!(
+                "The fetched aggregate differs from the local one:\n{}\n{}",
+                fetched.dataframe, expected
+            )));
+        }
+        Ok(format!(
+            "{} aggregated rows matched, checksum verified",
+            expected.height()
+        ))
+    }
+}
+
+struct PersistenceRoundTrip;
+
+#[tonic::async_trait]
+impl SelfTestStep for PersistenceRoundTrip {
+    fn name(&self) -> &str {
+        "persistence round trip"
+    }
+
+    async fn run(&self, ctx: &mut SelfTestContext<'_>) -> Result<String, Status> {
+        let server = match ctx.server {
+            Some(server) if ctx.options.persistence => server,
+            Some(_) => return Ok(String::from("skipped: disabled")),
+            None => return Ok(String::from("skipped: only checked in-process")),
+        };
+        let (reference, df) = ctx.dataset()?;
+        let (identifier, df) = (reference.identifier.clone(), df.clone());
+
+        ctx.client.persist_dataframe(&identifier).await?;
+        if persisted_files(server.data_dir(), &identifier).is_empty() {
+            return Err(Status::internal("No file was written for the dataset"));
+        }
+        let reloaded = server.reload()?.get_df_unchecked(&identifier)?;
+        if !reloaded.frame_equal_missing(&df) {
+            return Err(Status::internal(
+                "The reloaded dataset differs from the uploaded one",
+            ));
+        }
+        Ok(String::from("the dataset was persisted and reloaded"))
+    }
+}
+
+/// The `RunSelfTest` RPC, which runs the self-test against an in-process server with the
+/// configuration of the live one.
+pub struct SelfTestGrpcService {
+    sess_manager: Arc<SessionManager>,
+    config: BastionLabConfig,
+    self_test: Arc<SelfTest>,
+}
+
+impl SelfTestGrpcService {
+    pub fn new(
+        sess_manager: Arc<SessionManager>,
+        config: BastionLabConfig,
+        self_test: SelfTest,
+    ) -> Self {
+        SelfTestGrpcService {
+            sess_manager,
+            config,
+            self_test: Arc::new(self_test),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SelfTestService for SelfTestGrpcService {
+    async fn run_self_test(
+        &self,
+        request: Request<SelfTestRequest>,
+    ) -> Result<Response<self_test_proto::SelfTestReport>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can run the self-test.",
+            ));
+        }
+
+        let options = SelfTestOptions {
+            persistence: !request.get_ref().skip_persistence,
+        };
+        let report = self.self_test.run(&self.config, options).await;
+        if report.passed() {
+            info!("Self-test passed");
+        } else {
+            warn!("Self-test failed:\n{}", report);
+        }
+        Ok(Response::new(report.into()))
+    }
+}
@@ -0,0 +1,279 @@
+//! Upload, query and fetch round trips under randomized fault schedules, see
+//! `bastionlab_polars::faults`.
+//!
+//! Uploads and fetches cannot resume, so every round must either complete with the right result,
+//! or fail with a typed error, leaving no artifact, spilled file or panicked task behind. Rounds
+//! print their seed when they fail, to be replayed with `CHAOS_SEED`.
+
+use bastionlab_client::harness::InProcessServer;
+use bastionlab_client::{
+    CompositePlan, CompositePlanSegment, FaultSchedule, Policy, ResourceHints,
+};
+use bastionlab_common::config::BastionLabConfig;
+use polars::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+const ROUNDS: u64 = 24;
+
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+static COUNT_PANICS: Once = Once::new();
+
+/// Counts panics, those of server tasks included.
+fn count_panics() {
+    COUNT_PANICS.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::SeqCst);
+            default(info)
+        }));
+    });
+}
+
+fn config(spill_dir: &Path) -> BastionLabConfig {
+    toml::from_str(&format!(
+        r#"
+        client_to_enclave_untrusted_url = "https://0.0.0.0:50056"
+        public_keys_directory = "keys/"
+        session_expiry_in_secs = 3600
+        fault_injection = true
+        fetch_chunk_kb = 32
+        spill_dir = {:?}
+        "#,
+        spill_dir.to_str().unwrap()
+    ))
+    .unwrap()
+}
+
+/// SplitMix64, enough to draw schedules from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn schedule(rng: &mut Rng) -> FaultSchedule {
+    FaultSchedule {
+        seed: rng.next(),
+        delay: rng.unit() / 2.0,
+        max_delay_ms: rng.below(10),
+        duplicate: if rng.chance(0.3) {
+            rng.unit() / 4.0
+        } else {
+            0.0
+        },
+        truncate_final: rng.chance(0.2),
+        disconnect_after: rng.chance(0.3).then(|| rng.below(24) as usize),
+        ..Default::default()
+    }
+}
+
+/// Faults are injected on data chunks only, so streams only delayed complete.
+fn delays_only(schedule: &FaultSchedule) -> bool {
+    schedule.duplicate == 0.0 && !schedule.truncate_final && schedule.disconnect_after.is_none()
+}
+
+/// Interrupted or corrupted streams are reported as such, never as internal errors.
+fn assert_typed(err: &Status, seed: u64) {
+    assert!(
+        matches!(err.code(), Code::DataLoss | Code::InvalidArgument),
+        "seed {seed}: {err:?}"
+    );
+}
+
+/// Rows above `threshold`, sorted, with a memory limit of zero so that the input is spilled.
+fn query(identifier: &str, threshold: i64) -> CompositePlan {
+    let plan = DataFrame::new(vec![
+        Series::new_empty("id", &DataType::Int64),
+        Series::new_empty("value", &DataType::Int64),
+    ])
+    .unwrap()
+    .lazy()
+    .filter(col("value").gt(lit(threshold)))
+    .sort("id", Default::default())
+    .logical_plan;
+    CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.to_string(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan,
+            skip_nan: false,
+            resources: Some(ResourceHints {
+                allow_spill: Some(true),
+                max_memory_mb: Some(0),
+            }),
+        },
+    ])
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn round_trips_complete_or_fail_cleanly_under_faults() {
+    count_panics();
+    let spill_dir = std::env::temp_dir().join(format!("bastionlab-chaos-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&spill_dir).unwrap();
+    let server = InProcessServer::start(&config(&spill_dir)).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // About 640 KB, some twenty chunks each way.
+    let n = 40_000i64;
+    let df = df! {
+        "id" => (0..n).collect::<Vec<_>>(),
+        "value" => (0..n).map(|i| (i * 7919) % 1000).collect::<Vec<_>>(),
+    }
+    .unwrap();
+
+    let replay = std::env::var("CHAOS_SEED").ok();
+    let seeds: Vec<u64> = match &replay {
+        Some(seed) => vec![seed.parse().unwrap()],
+        None => (0..ROUNDS).collect(),
+    };
+    let mut completed = 0;
+    for seed in seeds {
+        let mut rng = Rng(seed);
+        let upload_faults = schedule(&mut rng);
+        let fetch_faults = schedule(&mut rng);
+        let threshold = rng.below(1000) as i64;
+        let before = client.list_dataframes().await.unwrap().len();
+
+        client.inject_faults(Some(&upload_faults)).unwrap();
+        let upload = client
+            .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+            .await;
+        client.inject_faults(None).unwrap();
+        let reference = match upload {
+            Ok(reference) => reference,
+            Err(err) => {
+                assert!(!delays_only(&upload_faults), "seed {seed}: {err:?}");
+                assert_typed(&err, seed);
+                let after = client.list_dataframes().await.unwrap().len();
+                assert_eq!(after, before, "seed {seed}: orphan upload");
+                continue;
+            }
+        };
+
+        // Checksums catch corrupted uploads, so an accepted upload holds the data sent.
+        let result = client
+            .run_plan(&query(&reference.identifier, threshold))
+            .await
+            .unwrap_or_else(|err| panic!("seed {seed}: {err:?}"));
+        let after = client.list_dataframes().await.unwrap().len();
+        assert_eq!(after, before + 2, "seed {seed}");
+        let expected = df
+            .clone()
+            .lazy()
+            .filter(col("value").gt(lit(threshold)))
+            .sort("id", Default::default())
+            .collect()
+            .unwrap();
+
+        client.inject_faults(Some(&fetch_faults)).unwrap();
+        let fetched = client.fetch(&result).await;
+        client.inject_faults(None).unwrap();
+        match fetched {
+            Ok(fetched) => {
+                assert!(fetched.dataframe.frame_equal(&expected), "seed {seed}");
+                completed += 1;
+            }
+            Err(err) => {
+                assert!(!delays_only(&fetch_faults), "seed {seed}: {err:?}");
+                assert_typed(&err, seed);
+                // The result is still there, and fetches again without faults.
+                let fetched = client
+                    .fetch(&result)
+                    .await
+                    .unwrap_or_else(|err| panic!("seed {seed}: {err:?}"));
+                assert!(fetched.dataframe.frame_equal(&expected), "seed {seed}");
+            }
+        }
+        assert_eq!(
+            std::fs::read_dir(&spill_dir).unwrap().count(),
+            0,
+            "seed {seed}: spilled files left behind"
+        );
+    }
+
+    assert!(replay.is_some() || completed > 0, "no round completed");
+    assert_eq!(PANICS.load(Ordering::SeqCst), 0);
+    std::fs::remove_dir_all(spill_dir).unwrap();
+}
+
+/// Waits for the server to end `fetches` fetch streams in all, once their sends failed for
+/// dropped streams: each stream is timed until its task ends.
+async fn fetch_streams_ended(server: &InProcessServer, fetches: u64) {
+    let ended = format!("bastionlab_fetch_stream_seconds_count {fetches}");
+    for _ in 0..100 {
+        let metrics = server.polars().metrics().render();
+        if metrics.lines().any(|line| line == ended) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The fetch streams did not all end");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_fetch_streams_leave_the_server_serving() {
+    count_panics();
+    let spill_dir = std::env::temp_dir().join(format!("bastionlab-chaos-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&spill_dir).unwrap();
+    let server = InProcessServer::start(&config(&spill_dir)).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // Far more chunks than the stream buffers.
+    let n = 200_000i64;
+    let df = df! {
+        "id" => (0..n).collect::<Vec<_>>(),
+        "value" => (0..n).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&query(&reference.identifier, -1))
+        .await
+        .unwrap();
+
+    for _ in 0..3 {
+        let mut stream = client.fetch_stream(&result).await.unwrap();
+        stream.message().await.unwrap().unwrap();
+        drop(stream);
+    }
+    fetch_streams_ended(&server, 3).await;
+
+    let fetched = client.fetch(&result).await.unwrap();
+    assert!(fetched.dataframe.frame_equal(&df));
+    let result = client
+        .run_plan(&query(&reference.identifier, n / 2))
+        .await
+        .unwrap();
+    assert_eq!(
+        client.fetch(&result).await.unwrap().dataframe.height() as i64,
+        n / 2 - 1
+    );
+    assert_eq!(PANICS.load(Ordering::SeqCst), 0);
+    std::fs::remove_dir_all(spill_dir).unwrap();
+}
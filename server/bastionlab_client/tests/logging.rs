@@ -0,0 +1,101 @@
+//! The JSON logs of a server, captured through the global subscriber: this file holds a single test
+//! for no other to log into the capture.
+
+use bastionlab_client::harness::InProcessServer;
+use bastionlab_client::{CompositePlan, CompositePlanSegment, Policy};
+use bastionlab_common::config::BastionLabConfig;
+use bastionlab_common::logging::{self, LogFormat};
+use polars::prelude::*;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn lines(&self) -> Vec<Value> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+fn config() -> BastionLabConfig {
+    toml::from_str(
+        r#"
+        client_to_enclave_untrusted_url = "https://0.0.0.0:50056"
+        public_keys_directory = "keys/"
+        session_expiry_in_secs = 3600
+        "#,
+    )
+    .unwrap()
+}
+
+/// The span of the line whose message starts with `message`.
+fn span_of<'a>(lines: &'a [Value], message: &str) -> &'a Value {
+    let line = lines
+        .iter()
+        .find(|line| {
+            line["fields"]["message"]
+                .as_str()
+                .is_some_and(|logged| logged.starts_with(message))
+        })
+        .unwrap_or_else(|| panic!("Nothing logged starting with {message:?}"));
+    &line["span"]
+}
+
+#[tokio::test]
+async fn requests_are_logged_in_their_span_without_their_contents() {
+    std::env::set_var("RUST_LOG", "info");
+    let captured = Captured::default();
+    let writer = captured.clone();
+    logging::init(LogFormat::Json, None, move || writer.clone()).unwrap();
+
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "secret_column" => [1i64, 2, 3] }.unwrap();
+    let upload = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let plan = CompositePlan::new(vec![CompositePlanSegment::EntryPointPlanSegment {
+        identifier: upload.identifier.clone(),
+    }]);
+    let result = client.run_plan(&plan).await.unwrap();
+
+    let lines = captured.lines();
+    let uploaded = span_of(&lines, "Succesfully sent dataframe");
+    assert_eq!(uploaded["name"], "rpc");
+    assert!(uploaded["method"]
+        .as_str()
+        .unwrap()
+        .ends_with("/SendDataFrame"));
+    assert_eq!(uploaded["identifier"], upload.identifier.as_str());
+    let queried = span_of(
+        &lines,
+        &format!("Succesfully ran query on {}", result.identifier),
+    );
+    assert!(queried["method"].as_str().unwrap().ends_with("/RunQuery"));
+    let request_id = queried["request_id"].as_str().unwrap();
+    assert!(!request_id.is_empty());
+    assert_ne!(uploaded["request_id"].as_str(), Some(request_id));
+    // Plans are only logged at debug level.
+    for line in &lines {
+        let line = line.to_string();
+        assert!(!line.contains("EntryPointPlanSegment"), "{line}");
+    }
+}
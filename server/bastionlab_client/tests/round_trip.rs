@@ -0,0 +1,5593 @@
+use bastionlab_client::harness::InProcessServer;
+use bastionlab_client::self_test::{SelfTest, SelfTestOptions};
+use bastionlab_client::{
+    column_order, open_bundle, pkcs8_pem, Client, ColumnOrder, CompositePlan, CompositePlanSegment,
+    DataFormat, FaultSchedule, FetchOutcome, FetchStatus, Parameter, ParameterType, Policy,
+    PolicyPatch, PolicySelector, Purpose, ResourceHints, Scalar, SigningKey, Visibility,
+};
+use bastionlab_common::auth::KeyRole;
+use bastionlab_common::config::{BastionLabConfig, MAX_CHUNK_BYTES};
+use bastionlab_common::session_proto::{
+    self, session_service_client::SessionServiceClient, ClientInfo,
+};
+use bastionlab_polars::aggregations::{AggKind, Aggregation};
+use bastionlab_polars::catalog::{self, Cursor, ListingFilter};
+use bastionlab_polars::exposure::{ColumnExposures, Exposure};
+use bastionlab_polars::handshake;
+use bastionlab_polars::joins::JoinKind;
+use bastionlab_polars::literal_frames::{LiteralColumn, LiteralDtype};
+use bastionlab_polars::output_rows::{MaxOutputRows, OutputRowsMode};
+use bastionlab_polars::persistence::{ARTIFACT_EXTENSION, BUDGETS_FILE, WATERMARKS_FILE};
+use bastionlab_polars::polars_proto::{
+    fetch_chunk, result_shape, DataFrameKind, Empty, FetchChunk, ListDataFramesRequest,
+    PendingRequest, PlanJob, ReferenceResponse, ResultShape, StringList, TableShape,
+    UpdateDraftRequest,
+};
+use bastionlab_polars::purpose::AccessKind;
+use bastionlab_polars::serialization::FetchAssembler;
+use bastionlab_polars::statistics::Tolerance;
+use bastionlab_polars::temporal::{Holidays, TemporalColumn, TemporalExpr};
+use bastionlab_polars::versions;
+use polars::prelude::*;
+use prost::Message;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tonic::metadata::{MetadataKey, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+
+fn config() -> BastionLabConfig {
+    config_with("")
+}
+
+fn config_with(extra: &str) -> BastionLabConfig {
+    toml::from_str(&format!(
+        r#"
+        client_to_enclave_untrusted_url = "https://0.0.0.0:50056"
+        public_keys_directory = "keys/"
+        session_expiry_in_secs = 3600
+        {extra}
+        "#,
+    ))
+    .unwrap()
+}
+
+/// Waits for the server to close all its connections.
+async fn connections_closed(server: &InProcessServer) {
+    for _ in 0..100 {
+        if server.connections().count() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!(
+        "Connections still open: {:?}",
+        server.connections().connections()
+    );
+}
+
+fn entry_point(identifier: &str) -> CompositePlan {
+    CompositePlan::new(vec![CompositePlanSegment::EntryPointPlanSegment {
+        identifier: identifier.to_string(),
+    }])
+}
+
+#[tokio::test]
+async fn upload_query_and_fetch() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! {
+        "age" => [Some(31i64), None, Some(54)],
+        "name" => ["alice", "bob", "carol"],
+    }
+    .unwrap();
+
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    // Uploaded dataframes themselves are never fetchable.
+    let err = client.fetch(&reference).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap();
+    assert_eq!(fetched.status, FetchStatus::Ok);
+    assert!(fetched.dataframe.frame_equal_missing(&df));
+}
+
+#[tokio::test]
+async fn unsafe_fetch_reports_a_warning() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let policy: Policy = serde_json::from_str(
+        r#"{"safe_zone": {"type": "FalseRule"}, "unsafe_handling": {"type": "Log"}, "savable": false}"#,
+    )
+    .unwrap();
+    let df = df! { "x" => [1i32, 2, 3] }.unwrap();
+    let reference = client.upload_dataframe(&df, &policy, &[]).await.unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    let fetched = client.fetch(&result).await.unwrap();
+    assert!(matches!(fetched.status, FetchStatus::Warning(_)));
+    assert!(fetched.dataframe.frame_equal(&df));
+}
+
+#[tokio::test]
+async fn unknown_keys_are_rejected() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+
+    let (stranger, _) = SigningKey::generate().unwrap();
+    let mut client = Client::connect(server.addr().to_string(), Some(stranger))
+        .await
+        .unwrap();
+    let df = df! { "x" => [1i32] }.unwrap();
+    let err = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn data_owners_add_and_remove_keys_at_runtime() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let hash = analyst_key.pubkey_hash().to_string();
+    let pem = analyst_key.public_key_pem();
+
+    let added = owner
+        .add_public_key(session_proto::KeyRole::User, &pem)
+        .await
+        .unwrap();
+    assert_eq!(added.hash, hash);
+    assert_eq!(added.role(), session_proto::KeyRole::User);
+    let err = owner
+        .add_public_key(session_proto::KeyRole::User, "not a key")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    let listed = owner.list_public_keys().await.unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed.contains(&added));
+    // Persisted, for the next start.
+    assert!(server
+        .keys_dir()
+        .join("users")
+        .join(format!("{hash}.pem"))
+        .exists());
+
+    // No reload needed.
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    assert!(analyst.list_dataframes().await.unwrap().is_empty());
+    let err = analyst.list_public_keys().await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = analyst.remove_public_key(&hash).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+
+    let removed = owner.remove_public_key(&hash).await.unwrap();
+    assert_eq!(removed, added);
+    assert!(analyst.list_dataframes().await.is_err());
+    let err = analyst.authenticate().await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = owner.remove_public_key(&hash).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let owner_hash = server.owner_key().pubkey_hash().to_string();
+    let err = owner.remove_public_key(&owner_hash).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+    assert_eq!(owner.list_public_keys().await.unwrap().len(), 1);
+}
+
+fn tls_fixture(name: &str) -> String {
+    format!(
+        "{}/../bastionlab_common/tests/fixtures/tls/{name}",
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+fn tls_config(client_ca: &str) -> BastionLabConfig {
+    config_with(&format!(
+        r#"
+        tls_cert_file = "{}"
+        tls_key_file = "{}"
+        tls_client_ca_file = "{client_ca}"
+        "#,
+        tls_fixture("first.pem"),
+        tls_fixture("first.key"),
+    ))
+}
+
+fn client_tls() -> ClientTlsConfig {
+    let ca = std::fs::read(tls_fixture("ca.pem")).unwrap();
+    ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(ca))
+        .domain_name("localhost")
+}
+
+async fn round_trip_small_dataframe(client: &mut Client) -> Result<(), tonic::Status> {
+    let df = df! { "x" => [1i32, 2, 3] }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await?;
+    let result = client.run_plan(&entry_point(&reference.identifier)).await?;
+    let fetched = client.fetch(&result).await?;
+    assert!(fetched.dataframe.frame_equal(&df));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dataframes_round_trip_over_tls() {
+    let server = InProcessServer::start(&tls_config("")).await.unwrap();
+    assert!(server.addr().starts_with("https://"));
+    let mut client = Client::connect_tls(
+        server.addr().to_string(),
+        Some(server.owner_key()),
+        client_tls(),
+    )
+    .await
+    .unwrap();
+    round_trip_small_dataframe(&mut client).await.unwrap();
+}
+
+#[tokio::test]
+async fn client_certificates_can_be_required() {
+    let server = InProcessServer::start(&tls_config(&tls_fixture("client_ca.pem")))
+        .await
+        .unwrap();
+
+    // The handshake may only fail on the first request.
+    let rejected = match Client::connect_tls(
+        server.addr().to_string(),
+        Some(server.owner_key()),
+        client_tls(),
+    )
+    .await
+    {
+        Ok(mut client) => round_trip_small_dataframe(&mut client).await,
+        Err(e) => Err(e),
+    };
+    assert!(rejected.is_err());
+
+    let identity = Identity::from_pem(
+        std::fs::read(tls_fixture("client.pem")).unwrap(),
+        std::fs::read(tls_fixture("client.key")).unwrap(),
+    );
+    let mut client = Client::connect_tls(
+        server.addr().to_string(),
+        Some(server.owner_key()),
+        client_tls().identity(identity),
+    )
+    .await
+    .unwrap();
+    round_trip_small_dataframe(&mut client).await.unwrap();
+}
+
+/// Polls plan job `job` until it is no longer running.
+async fn finished_job(client: &mut Client, job: &str) -> PlanJob {
+    for _ in 0..400 {
+        let job = client.plan_job(job).await.unwrap();
+        if job.status != "running" {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Plan job {job} is still running");
+}
+
+#[tokio::test]
+async fn checkpointed_jobs_resume_after_a_restart() {
+    let mut server = InProcessServer::start(&config_with("fault_injection = true"))
+        .await
+        .unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "x" => (0..1000i64).collect::<Vec<_>>(),
+        "name" => (0..1000).map(|i| format!("row {i}")).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    client
+        .persist_dataframe(&reference.identifier)
+        .await
+        .unwrap();
+
+    let empty = || df.head(Some(0)).lazy();
+    let polars = |lf: LazyFrame| CompositePlanSegment::PolarsPlanSegment {
+        plan: lf.logical_plan,
+        skip_nan: false,
+        resources: None,
+    };
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: reference.identifier.clone(),
+        },
+        polars(empty().filter(col("x").gt(lit(10i64)))),
+        CompositePlanSegment::OutputSegment {
+            slot: String::from("kept"),
+        },
+        CompositePlanSegment::SlotEntryPointSegment {
+            slot: String::from("kept"),
+        },
+        polars(empty().sort("x", true)),
+        CompositePlanSegment::RowCountSegment {
+            row: String::from("rank"),
+        },
+    ]);
+
+    let uninterrupted = client.submit_plan_job(&plan, true).await.unwrap();
+    let uninterrupted = finished_job(&mut client, &uninterrupted.job).await;
+    assert_eq!(uninterrupted.status, "succeeded", "{}", uninterrupted.error);
+    let expected = client
+        .fetch(uninterrupted.result.as_ref().unwrap())
+        .await
+        .unwrap()
+        .dataframe;
+    assert_eq!(expected.height(), 989);
+
+    client
+        .inject_faults(Some(&FaultSchedule {
+            segment_delay_ms: 200,
+            ..Default::default()
+        }))
+        .unwrap();
+    let job = client.submit_plan_job(&plan, true).await.unwrap().job;
+    client.inject_faults(None).unwrap();
+    for _ in 0..100 {
+        if client.plan_job(&job).await.unwrap().completed_segments >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.restart().await.unwrap();
+
+    let mut client = server.client().await.unwrap();
+    let resumed = finished_job(&mut client, &job).await;
+    assert_eq!(resumed.status, "succeeded", "{}", resumed.error);
+    assert_eq!(resumed.attempts, 2);
+    assert!(resumed.resumed_after.unwrap() >= 3, "{resumed:?}");
+    let fetched = client
+        .fetch(resumed.result.as_ref().unwrap())
+        .await
+        .unwrap()
+        .dataframe;
+    assert!(fetched.frame_equal(&expected));
+    // Checkpoints go away with their job.
+    let checkpoint = std::env::temp_dir()
+        .join("bastionlab-checkpoints")
+        .join(&job);
+    assert!(!checkpoint.exists());
+}
+
+#[tokio::test]
+async fn running_queries_are_listed_and_killed() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let (other_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config_with("fault_injection = true"))
+        .await
+        .unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    server.add_key(KeyRole::User, &other_key).unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let mut other = Client::connect(server.addr().to_string(), Some(other_key))
+        .await
+        .unwrap();
+
+    let df = df! { "x" => (0..100i64).collect::<Vec<_>>() }.unwrap();
+    let reference = analyst
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let filter = || CompositePlanSegment::PolarsPlanSegment {
+        plan: df
+            .head(Some(0))
+            .lazy()
+            .filter(col("x").gt(lit(1i64)))
+            .logical_plan,
+        skip_nan: false,
+        resources: None,
+    };
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: reference.identifier.clone(),
+        },
+        filter(),
+        filter(),
+        filter(),
+        filter(),
+        filter(),
+    ]);
+    let before = owner.list_dataframes().await.unwrap().len();
+
+    // Slow enough to be seen running.
+    analyst
+        .inject_faults(Some(&FaultSchedule {
+            segment_delay_ms: 300,
+            ..Default::default()
+        }))
+        .unwrap();
+    let job = analyst.submit_plan_job(&plan, true).await.unwrap().job;
+    analyst.inject_faults(None).unwrap();
+    let mut running = None;
+    for _ in 0..100 {
+        let listed = analyst.list_running_queries().await.unwrap();
+        if let Some(query) = listed.queries.into_iter().find(|query| query.segment >= 2) {
+            running = Some(query);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let query = running.expect("The job never showed up running");
+    assert_eq!(query.identity, analyst_id);
+    assert_eq!(query.state, "running");
+    assert_eq!(query.segments, 6);
+    assert!(query.progress > 0.0 && query.progress < 1.0, "{query:?}");
+    assert!(query.started_at >= query.submitted_at);
+    assert_eq!(query.inputs, [reference.identifier.clone()]);
+    // Others see their own queries only, data owners every query.
+    assert_eq!(other.list_running_queries().await.unwrap().queries, []);
+    let listed = owner.list_running_queries().await.unwrap();
+    assert_eq!(listed.queries.len(), 1);
+    assert_eq!(listed.kills, []);
+
+    let err = other.kill_query(&query.id, "Mine now").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let kill = owner.kill_query(&query.id, "Runaway plan").await.unwrap();
+    assert_eq!(kill.state, "running");
+    assert_eq!(kill.identity, analyst_id);
+
+    let killed = finished_job(&mut analyst, &job).await;
+    assert_eq!(killed.status, "failed");
+    assert!(
+        killed.error.contains("killed by") && killed.error.contains("Runaway plan"),
+        "{}",
+        killed.error
+    );
+    let listed = owner.list_running_queries().await.unwrap();
+    assert_eq!(listed.queries, []);
+    assert_eq!(listed.kills.len(), 1);
+    assert_eq!(listed.kills[0].killed_by, server.owner_key().pubkey_hash());
+    assert_eq!(listed.kills[0].reason, "Runaway plan");
+    // Nothing of the killed query was stored.
+    assert_eq!(owner.list_dataframes().await.unwrap().len(), before);
+    let err = owner.kill_query(&query.id, "Again").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+}
+
+#[tokio::test]
+async fn upserts_are_atomic_under_concurrent_queries() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+
+    let ids: Vec<i64> = (0..2000).collect();
+    let df = df! { "id" => &ids, "version" => vec![0i64; ids.len()] }.unwrap();
+    let reference = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+
+    let mut readers = Vec::new();
+    for _ in 0..3 {
+        let mut client = server.client().await.unwrap();
+        let identifier = reference.identifier.clone();
+        readers.push(tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for _ in 0..10 {
+                let result = client.run_plan(&entry_point(&identifier)).await.unwrap();
+                let fetched = client.fetch(&result).await.unwrap().dataframe;
+                // Every query sees a single version of the whole dataframe.
+                let versions = fetched.column("version").unwrap().unique().unwrap();
+                assert_eq!(versions.len(), 1);
+                assert_eq!(
+                    fetched.height(),
+                    2000 + versions.i64().unwrap().get(0).unwrap() as usize
+                );
+                seen.push(versions.i64().unwrap().get(0).unwrap());
+            }
+            seen
+        }));
+    }
+
+    for version in 1..=5i64 {
+        // Bump the version of every row and add one row per upsert.
+        let mut ids = ids.clone();
+        ids.extend(2000..2000 + version);
+        let batch = df! { "id" => &ids, "version" => vec![version; ids.len()] }.unwrap();
+        let res = owner
+            .upsert_rows(&reference.identifier, &batch, &[String::from("id")])
+            .await
+            .unwrap();
+        assert_eq!(res.inserted, 1);
+        assert_eq!(res.updated as usize, ids.len() - 1);
+        assert_eq!(res.version, version as u64);
+    }
+
+    for reader in readers {
+        let seen = reader.await.unwrap();
+        assert!(seen.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
+
+/// The dataframes are stored behind a single lock: uploads must be received and decoded before
+/// taking it, so that they neither wait for each other nor hold queries back.
+#[tokio::test]
+async fn uploads_in_progress_do_not_block_queries_or_other_uploads() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let small = df! { "id" => [1i64, 2, 3] }.unwrap();
+    let reference = owner
+        .upload_dataframe(&small, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+
+    // The upload only gets its next chunk when the test sends it.
+    let mut uploader = server.client().await.unwrap();
+    let large = df! { "id" => (0..100_000i64).collect::<Vec<_>>() }.unwrap();
+    let mut chunks = uploader
+        .upload_chunks(&large, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .into_iter();
+    assert!(chunks.len() > 2);
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let upload = tokio::spawn(async move {
+        uploader
+            .upload_stream(tokio_stream::wrappers::ReceiverStream::new(rx))
+            .await
+    });
+    tx.send(chunks.next().unwrap()).await.unwrap();
+    tx.send(chunks.next().unwrap()).await.unwrap();
+
+    for _ in 0..3 {
+        let result = owner
+            .run_plan(&entry_point(&reference.identifier))
+            .await
+            .unwrap();
+        let fetched = owner.fetch(&result).await.unwrap().dataframe;
+        assert!(fetched.frame_equal(&small));
+    }
+    let other = owner
+        .upload_dataframe(&small, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    owner.header(&other.identifier).await.unwrap();
+
+    for chunk in chunks {
+        tx.send(chunk).await.unwrap();
+    }
+    drop(tx);
+    let uploaded = upload.await.unwrap().unwrap();
+    let result = owner
+        .run_plan(&entry_point(&uploaded.identifier))
+        .await
+        .unwrap();
+    let fetched = owner.fetch(&result).await.unwrap().dataframe;
+    assert!(fetched.frame_equal(&large));
+}
+
+#[tokio::test]
+async fn column_statistics_follow_appends_and_upserts() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let df = df! {
+        "id" => [1i64, 2, 3],
+        "score" => [Some(10i64), None, Some(30)],
+    }
+    .unwrap();
+    let identifier = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let score = |header: &ReferenceResponse| {
+        header
+            .statistics
+            .iter()
+            .find(|statistics| statistics.column == "score")
+            .cloned()
+            .unwrap()
+    };
+    let header = owner.header(&identifier).await.unwrap();
+    assert_eq!(header.statistics.len(), 2);
+    let before = score(&header);
+    assert_eq!(
+        (before.nulls, before.min, before.max),
+        (1, Some(10.0), Some(30.0))
+    );
+    assert!(!before.stale);
+
+    // Appends update the statistics from the appended rows.
+    for (i, value) in [None, Some(-5i64)].into_iter().enumerate() {
+        let delta = df! { "id" => [4 + i as i64], "score" => [value] }.unwrap();
+        owner.append_rows(&identifier, &delta).await.unwrap();
+        let fresh = server
+            .polars()
+            .column_statistics(&identifier, &["score".to_string()], Tolerance::Fresh)
+            .unwrap();
+        assert_eq!(fresh[0].1.nulls, 2);
+        assert!(!fresh[0].2);
+    }
+    let appended = score(&owner.header(&identifier).await.unwrap());
+    assert_eq!((appended.min, appended.distinct), (Some(-5.0), 3));
+    assert!(!appended.stale);
+
+    // Upserts leave them stale: headers report the previous values, policy checks refresh them.
+    let upserted = df! { "id" => [2i64], "score" => [Some(100i64)] }.unwrap();
+    owner
+        .upsert_rows(&identifier, &upserted, &["id".to_string()])
+        .await
+        .unwrap();
+    let stale = score(&owner.header(&identifier).await.unwrap());
+    assert!(stale.stale);
+    assert_eq!((stale.nulls, stale.max), (2, Some(30.0)));
+    let fresh = server
+        .polars()
+        .column_statistics(&identifier, &["score".to_string()], Tolerance::Fresh)
+        .unwrap();
+    assert_eq!((fresh[0].1.nulls, fresh[0].1.max), (1, Some(100.0)));
+    let refreshed = score(&owner.header(&identifier).await.unwrap());
+    assert!(!refreshed.stale);
+    assert_eq!(refreshed.max, Some(100.0));
+}
+
+#[tokio::test]
+async fn internal_columns_never_leak() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let reserved = df! { "__bastionlab_row" => [1i64] }.unwrap();
+    let err = client
+        .upload_dataframe(&reserved, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    let blank = df! { " " => [1i64] }.unwrap();
+    assert!(client
+        .upload_dataframe(&blank, &Policy::allow_by_default(), &[])
+        .await
+        .is_err());
+
+    let df = df! {
+        "id" => [1i64, 2, 3, 3],
+        "group" => ["a", "b", "a", "b"],
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let id = || CompositePlanSegment::EntryPointPlanSegment {
+        identifier: reference.identifier.clone(),
+    };
+    let empty = || df.head(Some(0)).lazy();
+    let polars = |lf: LazyFrame| CompositePlanSegment::PolarsPlanSegment {
+        plan: lf.logical_plan,
+        skip_nan: false,
+        resources: None,
+    };
+
+    let plans = vec![
+        vec![id()],
+        vec![
+            id(),
+            id(),
+            polars(empty().join(empty(), [col("id")], [col("id")], JoinType::Inner)),
+        ],
+        vec![
+            id(),
+            polars(empty().groupby([col("group")]).agg([col("id").sum()])),
+        ],
+        vec![
+            id(),
+            CompositePlanSegment::RowCountSegment {
+                row: String::from("__bastionlab_row"),
+            },
+        ],
+        vec![id(), id(), CompositePlanSegment::StackPlanSegment],
+        vec![
+            id(),
+            polars(empty().with_column(col("id").alias("__bastionlab_alias"))),
+        ],
+    ];
+    for segments in plans {
+        let result = client
+            .run_plan(&CompositePlan::new(segments))
+            .await
+            .unwrap();
+        assert!(
+            !result.header.contains("__bastionlab_"),
+            "{}",
+            result.header
+        );
+        let fetched = client.fetch(&result).await.unwrap().dataframe;
+        assert!(fetched
+            .get_column_names()
+            .iter()
+            .all(|name| !name.starts_with("__bastionlab_")));
+    }
+}
+
+#[tokio::test]
+async fn self_test_passes_and_cleans_up() {
+    let report = SelfTest::default()
+        .run(&config(), SelfTestOptions::default())
+        .await;
+    assert!(report.passed(), "{report}");
+    let names: Vec<_> = report.steps.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names.first(), Some(&"challenge auth"));
+    assert!(names.contains(&"persistence round trip"));
+    assert_eq!(names.last(), Some(&"deletion"));
+}
+
+#[tokio::test]
+async fn self_test_against_a_live_server_leaves_its_datasets_alone() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let df = df! { "x" => [1i32, 2, 3] }.unwrap();
+    let reference = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+
+    let report = SelfTest::default()
+        .run_live(server.client().await.unwrap(), server.addr().to_string())
+        .await;
+    assert!(report.passed(), "{report}");
+    let listed = owner.list_dataframes().await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].identifier, reference.identifier);
+}
+
+#[tokio::test]
+async fn drained_connections_finish_their_streams_and_reauthenticate() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let ids: Vec<i64> = (0..300_000).collect();
+    let df =
+        df! { "id" => &ids, "value" => ids.iter().map(|&i| i as f64).collect::<Vec<_>>() }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    let mut stream = client.fetch_stream(&result).await.unwrap();
+    let mut assembler = FetchAssembler::new(true);
+    assembler
+        .push(stream.next().await.unwrap().unwrap())
+        .unwrap();
+
+    assert_eq!(client.drain_connections().await.unwrap(), 1);
+    // The connection is kept until the fetch completes.
+    let connections = client.list_connections().await.unwrap();
+    assert_eq!(connections.len(), 1);
+    assert!(connections[0].draining);
+    assert!(connections[0].active_streams >= 1);
+
+    while let Some(chunk) = stream.next().await {
+        assembler.push(chunk.unwrap()).unwrap();
+    }
+    let (status, fetched) = assembler.finish().unwrap();
+    assert_eq!(status, FetchStatus::Ok);
+    assert!(fetched.frame_equal(&df));
+    connections_closed(&server).await;
+
+    // The session was bound to the recycled connection.
+    let err = client.list_dataframes().await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Aborted, "{err:?}");
+    client.authenticate().await.unwrap();
+    assert_eq!(client.list_dataframes().await.unwrap().len(), 2);
+    let connections = client.list_connections().await.unwrap();
+    assert_eq!(connections.len(), 1);
+    assert!(!connections[0].draining);
+
+    let stats = server.connections().connections();
+    assert_eq!(stats.len(), 1);
+    assert!(stats[0].age < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn idle_connections_are_recycled() {
+    let server = InProcessServer::start(&config_with("connection_max_idle_secs = 1"))
+        .await
+        .unwrap();
+    let mut client = server.client().await.unwrap();
+    assert!(client.list_dataframes().await.unwrap().is_empty());
+    assert_eq!(server.connections().count(), 1);
+
+    connections_closed(&server).await;
+    let err = client.list_dataframes().await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Aborted);
+    client.authenticate().await.unwrap();
+    assert!(client.list_dataframes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn categorical_and_datetime_columns_survive_every_data_format() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let mut df = df! {
+        "city" => [Some("Paris"), Some("Lyon"), None, Some("Paris")],
+        "at" => [Some(0i64), Some(86_400_000), Some(1_000), None],
+    }
+    .unwrap();
+    let city = df
+        .column("city")
+        .unwrap()
+        .cast(&DataType::Categorical(None));
+    df.with_column(city.unwrap()).unwrap();
+    let at = df.column("at").unwrap();
+    let at = at.cast(&DataType::Datetime(TimeUnit::Milliseconds, None));
+    df.with_column(at.unwrap()).unwrap();
+
+    // Arrow IPC streams by default, the other formats when asked for.
+    for format in [
+        DataFormat::IpcStream,
+        DataFormat::IpcFile,
+        DataFormat::Canonical,
+    ] {
+        client.set_data_format(format);
+        let upload = client
+            .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+            .await
+            .unwrap();
+        let result = client
+            .run_plan(&entry_point(&upload.identifier))
+            .await
+            .unwrap();
+        let fetched = client.fetch(&result).await.unwrap().dataframe;
+        assert_eq!(fetched.schema(), df.schema(), "{format:?}");
+        let values = |df: &DataFrame| df.column("city").unwrap().cast(&DataType::Utf8).unwrap();
+        assert!(values(&fetched).series_equal_missing(&values(&df)));
+        assert!(fetched
+            .column("at")
+            .unwrap()
+            .series_equal_missing(df.column("at").unwrap()));
+    }
+}
+
+#[tokio::test]
+async fn capabilities_match_the_server_build() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let capabilities = client.server_capabilities().await.unwrap();
+    assert!(capabilities.supports("FamilyEntryPointSegment"));
+    assert!(capabilities.supports("canonical"));
+    assert!(capabilities.supports("canonical_columns"));
+    assert!(capabilities.supports("ipc_stream"));
+    let supported: Vec<_> = capabilities
+        .operations
+        .iter()
+        .filter(|op| op.supported)
+        .map(|op| op.name.as_str())
+        .collect();
+    let expected: Vec<_> = bastionlab_polars::capabilities::supported()
+        .into_iter()
+        .map(|c| c.name())
+        .collect();
+    assert_eq!(supported, expected);
+    assert!(capabilities
+        .operations
+        .iter()
+        .all(|op| !op.cargo_feature.is_empty()));
+    assert_eq!(capabilities.memory_pressure, "normal");
+    assert_eq!(
+        capabilities.big_index,
+        bastionlab_polars::capabilities::big_index()
+    );
+    let max_rows = if capabilities.big_index {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    assert_eq!(capabilities.max_rows, max_rows);
+}
+
+/// A pipeline reading `input` and adding a column `name` with `x` scaled by `factor`.
+fn scaling_pipeline(name: &str) -> (serde_json::Value, Vec<Parameter>) {
+    let scale = df! { "x" => [0i64] }
+        .unwrap()
+        .lazy()
+        .with_column((col("x") * lit(1234.5f64)).alias(name))
+        .logical_plan;
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: String::from("INPUT"),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: scale,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+    // Substituted on the serialized plan, as a client would write it.
+    let plan = serde_json::to_string(&plan)
+        .unwrap()
+        .replace(r#""INPUT""#, r#"{"$param": "input"}"#)
+        .replace(
+            r#"{"Literal":{"Float64":1234.5}}"#,
+            r#"{"$param": "factor"}"#,
+        );
+    let parameters = vec![
+        Parameter {
+            name: String::from("input"),
+            ty: ParameterType::Identifier,
+        },
+        Parameter {
+            name: String::from("factor"),
+            ty: ParameterType::Float,
+        },
+    ];
+    (serde_json::from_str(&plan).unwrap(), parameters)
+}
+
+#[tokio::test]
+async fn pipelines_run_the_pinned_version() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i64, 2] }.unwrap();
+    let input = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    let (plan, parameters) = scaling_pipeline("scaled");
+    let v1 = client
+        .register_pipeline("scale", &plan, &parameters, &Visibility::Private)
+        .await
+        .unwrap();
+    assert_eq!(v1.version, 1);
+    let (plan, parameters) = scaling_pipeline("rescaled");
+    let v2 = client
+        .register_pipeline("scale", &plan, &parameters, &Visibility::Private)
+        .await
+        .unwrap();
+    assert_eq!(v2.version, 2);
+
+    let params = [
+        ("input", serde_json::json!(input)),
+        ("factor", serde_json::json!(0.5)),
+    ];
+    let latest = client.run_pipeline("scale", None, &params).await.unwrap();
+    let latest = client.fetch(&latest).await.unwrap().dataframe;
+    assert!(latest.column("rescaled").is_ok());
+
+    let pinned = client
+        .run_pipeline("scale", Some(1), &params)
+        .await
+        .unwrap();
+    let pinned = client.fetch(&pinned).await.unwrap().dataframe;
+    assert_eq!(
+        pinned.column("scaled").unwrap().f64().unwrap().get(1),
+        Some(1.0)
+    );
+
+    let err = client
+        .run_pipeline("scale", None, &params[..1])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(err.message().contains("Missing parameter factor"));
+    let err = client
+        .run_pipeline("scale", Some(3), &params)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+}
+
+#[tokio::test]
+async fn delta_fetches_rebuild_the_new_version() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let policy = Policy::allow_by_default();
+    let keys = [String::from("id")];
+
+    let ids: Vec<i64> = (0..1000).collect();
+    let v1 = df! { "id" => &ids, "value" => vec![Some(0i64); ids.len()] }.unwrap();
+    let v1 = client.upload_dataframe(&v1, &policy, &[]).await.unwrap();
+    let previous = client.run_plan(&entry_point(&v1.identifier)).await.unwrap();
+    let previous_df = client.fetch(&previous).await.unwrap().dataframe;
+
+    // Deletes, in-place changes, and inserts in the middle and at the end.
+    let mut ids: Vec<i64> = (0..1000).filter(|id| !(10..20).contains(id)).collect();
+    ids.splice(300..300, 2000..2005);
+    ids.extend(1000..1010);
+    let values: Vec<_> = ids
+        .iter()
+        .map(|id| match id {
+            500..=504 => Some(1i64),
+            505 => None,
+            _ => Some(0),
+        })
+        .collect();
+    let v2 = df! { "id" => &ids, "value" => values }.unwrap();
+    let v2 = client.upload_dataframe(&v2, &policy, &[]).await.unwrap();
+    let current = client.run_plan(&entry_point(&v2.identifier)).await.unwrap();
+    let full = client.fetch(&current).await.unwrap().dataframe;
+
+    let fetched = client
+        .fetch_delta(&current, &previous, &previous_df, &keys)
+        .await
+        .unwrap();
+    assert_eq!(fetched.full_reason, None);
+    assert_eq!(fetched.status, FetchStatus::Ok);
+    assert!(fetched.dataframe.frame_equal_missing(&full));
+
+    // The full result is sent when no delta can be computed.
+    let mut gone = previous.clone();
+    gone.identifier = String::from("gone");
+    let fetched = client
+        .fetch_delta(&current, &gone, &previous_df, &keys)
+        .await
+        .unwrap();
+    assert!(fetched.full_reason.unwrap().contains("is gone"));
+    assert!(fetched.dataframe.frame_equal_missing(&full));
+    // Uploaded dataframes cannot be fetched: they cannot be diffed against either.
+    let fetched = client
+        .fetch_delta(&current, &v1, &previous_df, &keys)
+        .await
+        .unwrap();
+    assert!(fetched.full_reason.unwrap().contains("without approval"));
+
+    let ambiguous = client
+        .fetch_delta(&current, &previous, &previous_df, &[String::from("value")])
+        .await
+        .unwrap();
+    assert!(ambiguous.full_reason.unwrap().contains("duplicated keys"));
+    assert!(ambiguous.dataframe.frame_equal_missing(&full));
+}
+
+#[tokio::test]
+async fn synthetic_dataframes_are_seeded_and_freely_fetchable() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let n = 200;
+    let df = df! {
+        "id" => (0..n).map(|i| format!("P-{i:04}")).collect::<Vec<_>>(),
+        "age" => (0..n).map(|i| 20 + i % 40).collect::<Vec<i64>>(),
+        "city" => (0..n).map(|i| if i % 2 == 0 { "Paris" } else { "Lyon" }).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let closed = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let err = client
+        .generate_synthetic(&closed.identifier, None, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "FalseRule"},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+        "synthetic": {"identifier_columns": ["id"], "max_rows": 1000},
+    }))
+    .unwrap();
+    let reference = client.upload_dataframe(&df, &policy, &[]).await.unwrap();
+    let first = client
+        .generate_synthetic(&reference.identifier, Some(500), Some(7))
+        .await
+        .unwrap();
+    let header: serde_json::Value = serde_json::from_str(&first.header).unwrap();
+    assert_eq!(header["synthetic"]["seed"], 7);
+    assert_eq!(header["synthetic"]["rows"], 500);
+
+    // Fetchable although the real data is not, and reproducible under the same seed.
+    let fetched = client.fetch(&first).await.unwrap();
+    assert_eq!(fetched.status, FetchStatus::Ok);
+    assert_eq!(fetched.dataframe.schema(), df.schema());
+    assert_eq!(fetched.dataframe.height(), 500);
+    let second = client
+        .generate_synthetic(&reference.identifier, Some(500), Some(7))
+        .await
+        .unwrap();
+    let refetched = client.fetch(&second).await.unwrap();
+    assert!(refetched.dataframe.frame_equal_missing(&fetched.dataframe));
+
+    let ids: Vec<_> = df
+        .column("id")
+        .unwrap()
+        .utf8()
+        .unwrap()
+        .into_iter()
+        .collect();
+    let fake_ids = fetched.dataframe.column("id").unwrap().utf8().unwrap();
+    assert!(fake_ids.into_iter().all(|id| !ids.contains(&id)));
+
+    let err = client
+        .generate_synthetic(&reference.identifier, Some(5000), None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn listings_are_paginated_and_filtered() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let mut uploads = Vec::new();
+    for i in 0..5 {
+        let tags = vec![if i % 2 == 0 { "even" } else { "odd" }.to_string()];
+        let reference = client
+            .upload_tagged_dataframe(
+                &df,
+                &Policy::allow_by_default(),
+                &format!("sales-{i}"),
+                &tags,
+            )
+            .await
+            .unwrap();
+        uploads.push(reference.identifier);
+    }
+    let result = client.run_plan(&entry_point(&uploads[0])).await.unwrap();
+    let all = client.list_dataframes().await.unwrap();
+    assert_eq!(all.len(), 6);
+    // Listed headers are the ones returned when the dataframe was created.
+    let listed = all
+        .iter()
+        .find(|r| r.identifier == result.identifier)
+        .unwrap();
+    assert_eq!(listed.header, result.header);
+
+    let mut listed = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let page = client
+            .list_dataframes_page(ListDataFramesRequest {
+                page_size: 2,
+                page_token,
+                kind: DataFrameKind::Upload.into(),
+                owner: server.owner_key().pubkey_hash().to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(page.list.len() <= 2);
+        assert_eq!(page.total_count_estimate, 5);
+        listed.extend(page.list.into_iter().map(|r| r.identifier));
+        if page.next_page_token.is_empty() {
+            break;
+        }
+        page_token = page.next_page_token;
+    }
+    listed.sort();
+    let mut sorted = uploads.clone();
+    sorted.sort();
+    assert_eq!(listed, sorted);
+
+    let page = client
+        .list_dataframes_page(ListDataFramesRequest {
+            tag: "even".to_string(),
+            name_prefix: "sales-".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.list.len(), 3);
+    let page = client
+        .list_dataframes_page(ListDataFramesRequest {
+            kind: DataFrameKind::Result.into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.list.len(), 1);
+    let err = client
+        .list_dataframes_page(ListDataFramesRequest {
+            page_token: "garbage".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    // Page tokens are still valid after a restart.
+    let list = |page_size| ListDataFramesRequest {
+        page_size,
+        name_prefix: "sales-".to_string(),
+        ..Default::default()
+    };
+    let all = client.list_dataframes_page(list(10)).await.unwrap().list;
+    let first = client.list_dataframes_page(list(2)).await.unwrap();
+    for identifier in uploads.iter() {
+        client.persist_dataframe(identifier).await.unwrap();
+    }
+    let restarted = server.reload().unwrap();
+    let filter = ListingFilter {
+        name_prefix: Some("sales-".to_string()),
+        ..Default::default()
+    };
+    let cursor = Cursor::decode(&first.next_page_token).unwrap();
+    let (rest, page) = restarted
+        .list_dfs(server.owner_key().pubkey_hash(), &filter, Some(&cursor), 10)
+        .unwrap();
+    let rest: Vec<_> = rest.into_iter().map(|(identifier, _)| identifier).collect();
+    let expected: Vec<_> = all[2..].iter().map(|r| r.identifier.clone()).collect();
+    assert_eq!(rest, expected);
+    assert_eq!(page.total, 5);
+}
+
+#[tokio::test]
+async fn views_follow_upserts_and_refreshes() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! {
+        "id" => [1i64, 2, 3],
+        "city" => ["Paris", "Lyon", "Paris"],
+        "amount" => [10i64, 20, 30],
+    }
+    .unwrap();
+    let base = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: base.identifier.clone(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: df
+                .head(Some(0))
+                .lazy()
+                .groupby([col("city")])
+                .agg([col("amount").sum()])
+                .logical_plan,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+    let view = client
+        .register_view("totals", &plan, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(view.state, "fresh");
+    assert_eq!(view.groups, 2);
+    assert!(view.incremental);
+
+    // Row 3 moves from Paris to Lyon, and a row of a new city is inserted.
+    let incoming = df! {
+        "id" => [3i64, 4],
+        "city" => ["Lyon", "Nice"],
+        "amount" => [5i64, 7],
+    }
+    .unwrap();
+    client
+        .upsert_rows(&base.identifier, &incoming, &[String::from("id")])
+        .await
+        .unwrap();
+    let result = client.run_plan(&plan).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let expected = df! {
+        "city" => ["Lyon", "Nice", "Paris"],
+        "amount" => [25i64, 7, 10],
+    }
+    .unwrap();
+    assert!(fetched
+        .sort(["city"], false)
+        .unwrap()
+        .frame_equal(&expected));
+
+    let refreshed = client.refresh_view("totals").await.unwrap();
+    assert_eq!(refreshed.base_version, 1);
+    assert_eq!(refreshed.groups, 3);
+    assert_eq!(
+        client.refresh_view("unknown").await.unwrap_err().code(),
+        tonic::Code::NotFound
+    );
+}
+
+/// Reads `stream` to its end, returning the number of chunks received and the error it ended with.
+async fn chunks_until_error(stream: &mut tonic::Streaming<FetchChunk>) -> (usize, tonic::Status) {
+    let mut received = 0;
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(_) => received += 1,
+            Err(err) => return (received, err),
+        }
+    }
+    panic!("The fetch completed after {received} chunks");
+}
+
+#[tokio::test]
+async fn long_fetches_stop_when_access_is_revoked() {
+    let every = 4;
+    let server = InProcessServer::start(&config_with(&format!(
+        "fetch_checkpoint_chunks = {every}\nfetch_chunk_kb = 32"
+    )))
+    .await
+    .unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let ids: Vec<i64> = (0..300_000).collect();
+    let df =
+        df! { "id" => &ids, "value" => ids.iter().map(|&i| i as f64).collect::<Vec<_>>() }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    // Chunks already queued in the stream are still delivered.
+    let bound = every + 4 + 1;
+
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+    let mut stream = client.fetch_stream(&result).await.unwrap();
+    stream.next().await.unwrap().unwrap();
+    server.polars().delete_dfs(&result.identifier).unwrap();
+    let (received, err) = chunks_until_error(&mut stream).await;
+    assert_eq!(err.code(), tonic::Code::Aborted, "{err:?}");
+    assert!(received <= bound, "{received} chunks after the deletion");
+
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+    let mut stream = client.fetch_stream(&result).await.unwrap();
+    stream.next().await.unwrap().unwrap();
+    server.revoke_key(server.owner_key().pubkey_hash()).unwrap();
+    let (received, err) = chunks_until_error(&mut stream).await;
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(received <= bound, "{received} chunks after the revocation");
+}
+
+/// The sizes of the data chunks of a fetch of `result`, and the dataframe they hold.
+async fn data_chunk_sizes(
+    client: &mut Client,
+    result: &ReferenceResponse,
+) -> (Vec<usize>, DataFrame) {
+    let mut stream = client.fetch_stream(result).await.unwrap();
+    let mut assembler = FetchAssembler::new(true);
+    let mut sizes = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.unwrap();
+        if let Some(fetch_chunk::Body::Data(data)) = &chunk.body {
+            sizes.push(data.len());
+        }
+        assembler.push(chunk).unwrap();
+    }
+    (sizes, assembler.finish().unwrap().1)
+}
+
+#[tokio::test]
+async fn large_fetches_take_a_handful_of_chunks() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // About 9.6 MB.
+    let ids: Vec<i64> = (0..600_000).collect();
+    let df =
+        df! { "id" => &ids, "value" => ids.iter().map(|&i| i as f64).collect::<Vec<_>>() }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    // The server default, a requested size, and a requested size above the cap.
+    for (kb, chunk_size) in [(0, 4095 << 10), (1024, 1 << 20), (1 << 20, MAX_CHUNK_BYTES)] {
+        client.set_fetch_chunk_kb(kb);
+        let (sizes, fetched) = data_chunk_sizes(&mut client, &result).await;
+        assert!(fetched.frame_equal(&df));
+        let total: usize = sizes.iter().sum();
+        assert!(total > 8 << 20, "{total} bytes");
+        assert_eq!(
+            sizes.len(),
+            total.div_ceil(chunk_size),
+            "{kb} KB: {sizes:?}"
+        );
+        let (last, full) = sizes.split_last().unwrap();
+        assert!(
+            full.iter().all(|size| *size == chunk_size),
+            "{kb} KB: {sizes:?}"
+        );
+        assert!(*last > 0 && *last <= chunk_size, "{kb} KB: {sizes:?}");
+        if kb == 0 {
+            assert_eq!(sizes.len(), 3);
+        }
+    }
+}
+
+#[tokio::test]
+async fn users_delete_their_results_but_not_uploads() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let upload = analyst
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = analyst
+        .run_plan(&entry_point(&upload))
+        .await
+        .unwrap()
+        .identifier;
+    let err = analyst.delete_dataframe(&upload).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    analyst.delete_dataframe(&result).await.unwrap();
+    let err = analyst.delete_dataframe(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let err = owner.delete_dataframe("missing").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    owner.delete_dataframe(&upload).await.unwrap();
+    assert!(owner.list_dataframes().await.unwrap().is_empty());
+}
+
+/// A result deleted while it is fetched is either sent whole, or the fetch fails: it never ends
+/// with part of the rows.
+#[tokio::test]
+async fn deleting_a_result_during_its_fetch_ends_the_fetch_cleanly() {
+    let server = InProcessServer::start(&config_with(
+        "fetch_checkpoint_chunks = 4\nfetch_chunk_kb = 32",
+    ))
+    .await
+    .unwrap();
+    let mut owner = server.client().await.unwrap();
+    let df = df! { "id" => (0..300_000i64).collect::<Vec<_>>() }.unwrap();
+    let upload = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    // Deleted between two chunks.
+    let result = owner.run_plan(&entry_point(&upload)).await.unwrap();
+    let mut stream = owner.fetch_stream(&result).await.unwrap();
+    stream.next().await.unwrap().unwrap();
+    owner.delete_dataframe(&result.identifier).await.unwrap();
+    let (_, err) = chunks_until_error(&mut stream).await;
+    assert_eq!(err.code(), tonic::Code::Aborted, "{err:?}");
+
+    // Deleted at any point of the fetch.
+    for _ in 0..10 {
+        let result = owner.run_plan(&entry_point(&upload)).await.unwrap();
+        let identifier = result.identifier.clone();
+        let mut fetcher = server.client().await.unwrap();
+        let fetch = tokio::spawn(async move { fetcher.fetch(&result).await });
+        owner.delete_dataframe(&identifier).await.unwrap();
+        match fetch.await.unwrap() {
+            Ok(fetched) => assert!(fetched.dataframe.frame_equal(&df)),
+            Err(err) => assert!(
+                matches!(err.code(), tonic::Code::NotFound | tonic::Code::Aborted),
+                "{err:?}"
+            ),
+        }
+    }
+}
+
+/// A session request signed as clients sign them.
+fn signed_session(
+    key: &SigningKey,
+    challenge: &[u8],
+    timestamp: u64,
+    nonce: u64,
+) -> tonic::Request<ClientInfo> {
+    let info = ClientInfo::default();
+    let mut message = b"create-session".to_vec();
+    message.extend_from_slice(challenge);
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&nonce.to_be_bytes());
+    info.encode(&mut message).unwrap();
+    let signature = key.sign(&message).unwrap();
+
+    let mut request = tonic::Request::new(info);
+    let metadata = request.metadata_mut();
+    metadata.insert_bin("challenge-bin", MetadataValue::from_bytes(challenge));
+    metadata.insert_bin(
+        "timestamp-bin",
+        MetadataValue::from_bytes(&timestamp.to_be_bytes()),
+    );
+    metadata.insert_bin("nonce-bin", MetadataValue::from_bytes(&nonce.to_be_bytes()));
+    let signature_key =
+        MetadataKey::from_bytes(format!("signature-{}-bin", key.pubkey_hash()).as_bytes()).unwrap();
+    metadata.insert_bin(signature_key, MetadataValue::from_bytes(&signature));
+    request
+}
+
+async fn challenge(session: &mut SessionServiceClient<Channel>) -> Vec<u8> {
+    challenge_for(session, "").await
+}
+
+/// A challenge bound to the key of hash `key_hash`, or to the connection of `session` if empty.
+async fn challenge_for(session: &mut SessionServiceClient<Channel>, key_hash: &str) -> Vec<u8> {
+    let request = session_proto::ChallengeRequest {
+        public_key_hash: key_hash.to_string(),
+    };
+    session
+        .get_challenge(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .value
+}
+
+#[tokio::test]
+async fn replayed_and_skewed_session_requests_are_rejected() {
+    let server = InProcessServer::start(&config_with("signature_max_skew_secs = 10"))
+        .await
+        .unwrap();
+    let key = server.owner_key();
+    let channel = Channel::from_shared(server.addr().to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut session = SessionServiceClient::new(channel);
+    let time = session
+        .get_server_time(session_proto::Empty {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(time.max_skew_ms, 10_000);
+    let now = time.unix_ms;
+
+    let captured = challenge(&mut session).await;
+    session
+        .create_session(signed_session(&key, &captured, now, 100))
+        .await
+        .unwrap();
+    let err = session
+        .create_session(signed_session(&key, &captured, now, 100))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    // Even with a challenge of its own, a request reusing the nonce is a replay.
+    let fresh = challenge(&mut session).await;
+    let err = session
+        .create_session(signed_session(&key, &fresh, now, 100))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("Replay suspected"), "{err:?}");
+
+    // Clocks ahead or behind are tolerated up to the skew.
+    let fresh = challenge(&mut session).await;
+    session
+        .create_session(signed_session(&key, &fresh, now + 9_000, 101))
+        .await
+        .unwrap();
+    let fresh = challenge(&mut session).await;
+    let err = session
+        .create_session(signed_session(&key, &fresh, now - 11_000, 102))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("GetServerTime"), "{err:?}");
+
+    // Clients measure the skew before signing.
+    let mut client = server.client().await.unwrap();
+    assert!(client.sync_clock().await.unwrap().abs() < 1_000);
+    assert!(client.list_dataframes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn forged_and_unsigned_session_requests_are_rejected() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let key = server.owner_key();
+    let (forger, _) = SigningKey::generate().unwrap();
+    let channel = Channel::from_shared(server.addr().to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut session = SessionServiceClient::new(channel);
+    let now = session
+        .get_server_time(session_proto::Empty {})
+        .await
+        .unwrap()
+        .into_inner()
+        .unix_ms;
+    let owner_signature =
+        MetadataKey::from_bytes(format!("signature-{}-bin", key.pubkey_hash()).as_bytes()).unwrap();
+    let forger_signature =
+        MetadataKey::from_bytes(format!("signature-{}-bin", forger.pubkey_hash()).as_bytes())
+            .unwrap();
+
+    // Knowing the hash of a key is not enough, requests must be signed with it.
+    let fresh = challenge(&mut session).await;
+    let mut request = signed_session(&forger, &fresh, now, 1);
+    let signature = request.metadata_mut().remove_bin(forger_signature).unwrap();
+    request
+        .metadata_mut()
+        .insert_bin(owner_signature.clone(), signature);
+    let err = session.create_session(request).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("Invalid signature"), "{err:?}");
+
+    let fresh = challenge(&mut session).await;
+    let mut request = signed_session(&key, &fresh, now, 2);
+    request.metadata_mut().remove_bin(owner_signature.clone());
+    let err = session.create_session(request).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated, "{err:?}");
+
+    // Signatures cover the challenge, so they cannot be moved to another one.
+    let signed = challenge(&mut session).await;
+    let fresh = challenge(&mut session).await;
+    let mut request = signed_session(&key, &signed, now, 3);
+    request
+        .metadata_mut()
+        .insert_bin("challenge-bin", MetadataValue::from_bytes(&fresh));
+    let err = session.create_session(request).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("Invalid signature"), "{err:?}");
+
+    // Nor to another request body.
+    let fresh = challenge(&mut session).await;
+    let request = signed_session(&key, &fresh, now, 4);
+    let signature = request
+        .metadata()
+        .get_bin(&owner_signature)
+        .unwrap()
+        .clone();
+    let mut request = tonic::Request::new(ClientInfo {
+        uid: String::from("someone else"),
+        ..Default::default()
+    });
+    let metadata = request.metadata_mut();
+    metadata.insert_bin("challenge-bin", MetadataValue::from_bytes(&fresh));
+    metadata.insert_bin(
+        "timestamp-bin",
+        MetadataValue::from_bytes(&now.to_be_bytes()),
+    );
+    metadata.insert_bin("nonce-bin", MetadataValue::from_bytes(&4u64.to_be_bytes()));
+    metadata.insert_bin(owner_signature, signature);
+    let err = session.create_session(request).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+
+    let fresh = challenge(&mut session).await;
+    session
+        .create_session(signed_session(&key, &fresh, now, 5))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn challenges_are_spent_by_the_identity_they_were_issued_to() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let owner = server.owner_key();
+    let (user, _) = SigningKey::generate().unwrap();
+    server.add_key(KeyRole::User, &user).unwrap();
+    let connect = || async {
+        let channel = Channel::from_shared(server.addr().to_string())
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        SessionServiceClient::new(channel)
+    };
+    let (mut first, mut second) = (connect().await, connect().await);
+    let now = first
+        .get_server_time(session_proto::Empty {})
+        .await
+        .unwrap()
+        .into_inner()
+        .unix_ms;
+
+    // Challenges requested for a key are only spent by requests it signs, on any connection.
+    let bound = challenge_for(&mut first, user.pubkey_hash()).await;
+    let err = first
+        .create_session(signed_session(&owner, &bound, now, 1))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("another identity"), "{err:?}");
+    second
+        .create_session(signed_session(&user, &bound, now, 2))
+        .await
+        .unwrap();
+
+    // Others are only spent on the connection they were requested on.
+    let anonymous = challenge(&mut first).await;
+    let err = second
+        .create_session(signed_session(&owner, &anonymous, now, 3))
+        .await
+        .unwrap_err();
+    assert!(err.message().contains("another identity"), "{err:?}");
+    first
+        .create_session(signed_session(&owner, &anonymous, now, 4))
+        .await
+        .unwrap();
+
+    let err = first
+        .get_challenge(session_proto::ChallengeRequest {
+            public_key_hash: String::from("not a hash"),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+}
+
+#[tokio::test]
+async fn corrupted_files_are_skipped_on_restart() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let mut identifiers = Vec::new();
+    for _ in 0..2 {
+        let reference = client
+            .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+            .await
+            .unwrap();
+        client
+            .persist_dataframe(&reference.identifier)
+            .await
+            .unwrap();
+        identifiers.push(reference.identifier);
+    }
+    let path = server
+        .data_dir()
+        .join(format!("{}.{ARTIFACT_EXTENSION}", identifiers[0]));
+    std::fs::write(&path, b"not an artifact").unwrap();
+
+    let restarted = server.reload().unwrap();
+    assert!(restarted.get_df_unchecked(&identifiers[0]).is_err());
+    // Identifiers survive the restart, so references held by clients keep working.
+    let reloaded = restarted.get_df_unchecked(&identifiers[1]).unwrap();
+    assert_eq!(reloaded.height(), 3);
+}
+
+#[tokio::test]
+async fn uploads_are_reviewed_before_publication() {
+    let (reviewer_key, _) = SigningKey::generate().unwrap();
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config_with(&format!(
+        "publish_uploads = false\ndataset_reviewers = [{:?}]",
+        reviewer_key.pubkey_hash()
+    )))
+    .await
+    .unwrap();
+    server.add_key(KeyRole::User, &reviewer_key).unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut reviewer = Client::connect(server.addr().to_string(), Some(reviewer_key))
+        .await
+        .unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! {
+        "city" => ["Paris", "Lyon", "Paris"],
+        "name" => ["alice", "bob", "carol"],
+        "amount" => [10i64, 20, 30],
+    }
+    .unwrap();
+    let reference = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let identifier = reference.identifier.as_str();
+    let totals = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.to_string(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: df
+                .head(Some(0))
+                .lazy()
+                .groupby([col("city")])
+                .agg([col("amount").sum()])
+                .logical_plan,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+
+    // Drafts are only seen and queried by their owner.
+    assert_eq!(owner.lifecycle(identifier).await.unwrap().state, "draft");
+    owner.run_plan(&totals).await.unwrap();
+    assert!(analyst.list_dataframes().await.unwrap().is_empty());
+    let err = analyst.run_plan(&totals).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    let err = reviewer.lifecycle(identifier).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    let err = owner
+        .register_view("totals", &totals, Duration::ZERO)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+
+    let submitted = owner.submit_for_review(identifier).await.unwrap();
+    assert_eq!(submitted.state, "in_review");
+    let edit = UpdateDraftRequest {
+        identifier: identifier.to_string(),
+        sanitized_columns: Some(StringList {
+            values: vec![String::from("name")],
+        }),
+        ..Default::default()
+    };
+    let err = owner.update_draft(edit.clone()).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+
+    // Reviewers see the metadata and a preview, but cannot query the dataframe yet.
+    let in_review = reviewer.lifecycle(identifier).await.unwrap();
+    assert_eq!(in_review.state, "in_review");
+    assert!(in_review.preview.contains("alice"), "{}", in_review.preview);
+    assert_eq!(reviewer.list_dataframes().await.unwrap().len(), 1);
+    let err = reviewer.run_plan(&totals).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    assert!(analyst
+        .review_dataframe(identifier, true, "")
+        .await
+        .is_err());
+    let err = owner
+        .review_dataframe(identifier, true, "")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+
+    let rejected = reviewer
+        .review_dataframe(identifier, false, "Names must be sanitized")
+        .await
+        .unwrap();
+    assert_eq!(rejected.state, "draft");
+    assert_eq!(
+        owner.lifecycle(identifier).await.unwrap().rejection,
+        "Names must be sanitized"
+    );
+    owner.update_draft(edit).await.unwrap();
+    owner.submit_for_review(identifier).await.unwrap();
+    let in_review = reviewer.lifecycle(identifier).await.unwrap();
+    assert!(
+        !in_review.preview.contains("alice"),
+        "{}",
+        in_review.preview
+    );
+    let published = reviewer
+        .review_dataframe(identifier, true, "")
+        .await
+        .unwrap();
+    assert_eq!(published.state, "published");
+    assert_eq!(published.rejection, "");
+
+    assert_eq!(analyst.list_dataframes().await.unwrap().len(), 1);
+    analyst.run_plan(&totals).await.unwrap();
+    owner
+        .register_view("totals", &totals, Duration::ZERO)
+        .await
+        .unwrap();
+
+    let unpublished = owner.unpublish_dataframe(identifier).await.unwrap();
+    assert_eq!(unpublished.state, "draft");
+    assert_eq!(unpublished.dropped_views, ["totals"]);
+    let listed = analyst.list_dataframes().await.unwrap();
+    assert!(listed.iter().all(|r| r.identifier != identifier));
+    let err = analyst.run_plan(&totals).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+}
+
+#[tokio::test]
+async fn deduplicated_identifiers_resolve_to_the_canonical_dataframe() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let closed: Policy = serde_json::from_str(
+        r#"{"safe_zone": {"type": "FalseRule"}, "unsafe_handling": {"type": "Reject"}, "savable": false}"#,
+    )
+    .unwrap();
+    let mut identifiers = Vec::new();
+    for policy in [
+        Policy::allow_by_default(),
+        Policy::allow_by_default(),
+        closed,
+    ] {
+        let reference = client.upload_dataframe(&df, &policy, &[]).await.unwrap();
+        identifiers.push(reference.identifier);
+    }
+    let [a, b, c]: [String; 3] = identifiers.try_into().unwrap();
+
+    // a -> b, then b -> c: a resolves straight to c.
+    client.deduplicate_dataframe(&a, &b).await.unwrap();
+    let alias = client.deduplicate_dataframe(&b, &c).await.unwrap();
+    assert_eq!(alias.canonical, c);
+    let header = client.header(&a).await.unwrap();
+    assert_eq!(header.identifier, c);
+    assert!(header.redirect.contains(&c), "{}", header.redirect);
+
+    // The canonical dataframe's policy applies, not the one a was uploaded with.
+    let result = client.run_plan(&entry_point(&a)).await.unwrap();
+    assert!(result.redirect.contains(&c), "{}", result.redirect);
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+
+    let other = df! { "x" => [4i64, 5, 6] }.unwrap();
+    let d = client
+        .upload_dataframe(&other, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let err = client.deduplicate_dataframe(&d, &c).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+    let err = client.create_alias(&c, &a, "cycle").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+
+    // Aliases of a deleted dataframe name it in their error.
+    client.create_alias("renamed", &d, "renamed").await.unwrap();
+    let reference = client.header("renamed").await.unwrap();
+    assert_eq!(reference.identifier, d);
+    client.delete_dataframe(&d).await.unwrap();
+    let err = client.header("renamed").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    assert!(err.message().contains(&d), "{err:?}");
+}
+
+#[tokio::test]
+async fn empty_and_scalar_results_are_shaped() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = DataFrame::new(vec![
+        Series::new("id", [1i64, 2, 3]),
+        Series::new("amount", [Some(1.5f64), None, Some(-2.0)]),
+        Series::new("name", ["alice", "bob", "carol"]),
+        Series::new("flag", [true, false, true]),
+        Series::new("day", [1i32, 2, 3])
+            .cast(&DataType::Date)
+            .unwrap(),
+        Series::new("at", [1i64, 2, 3])
+            .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))
+            .unwrap(),
+    ])
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let plan = |identifier: &str, lazy: LazyFrame| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.to_string(),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: lazy.logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    };
+    let template = || df.head(Some(0)).lazy();
+    let empty_shape = Some(ResultShape {
+        shape: Some(result_shape::Shape::Empty(Empty {})),
+    });
+
+    // Empty results keep their schema and come as a single data chunk.
+    let empty = client
+        .run_plan(&plan(
+            &reference.identifier,
+            template().filter(col("id").gt(lit(10i64))),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(empty.shape, empty_shape);
+    let mut stream = client.fetch_stream(&empty).await.unwrap();
+    let mut data_chunks = 0;
+    while let Some(chunk) = stream.next().await {
+        if let Some(fetch_chunk::Body::Data(_)) = chunk.unwrap().body {
+            data_chunks += 1;
+        }
+    }
+    assert_eq!(data_chunks, 1);
+    let fetched = client.fetch(&empty).await.unwrap();
+    assert_eq!(fetched.dataframe.height(), 0);
+    assert_eq!(fetched.dataframe.schema(), df.schema());
+    assert_eq!(fetched.shape, empty_shape);
+
+    // Counts are scalars.
+    let count = client
+        .run_plan(&plan(
+            &reference.identifier,
+            template().select([col("id").count()]),
+        ))
+        .await
+        .unwrap();
+    let Some(result_shape::Shape::Scalar(scalar)) = count.shape.and_then(|s| s.shape) else {
+        panic!("not a scalar: {:?}", count.shape);
+    };
+    let fetched = client.fetch_scalar(&count).await.unwrap();
+    assert_eq!(fetched.value, Scalar::Int(3));
+    assert_eq!(fetched.dtype, scalar.dtype);
+    assert_eq!(fetched.warning, None);
+
+    let err = client.fetch_scalar(&reference).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+    let header = client.header(&reference.identifier).await.unwrap();
+    assert_eq!(
+        header.shape.and_then(|s| s.shape),
+        Some(result_shape::Shape::Table(TableShape { rows: 3, cols: 6 }))
+    );
+
+    // Scalars go through the policy like any fetch.
+    let closed: Policy = serde_json::from_str(
+        r#"{"safe_zone": {"type": "FalseRule"}, "unsafe_handling": {"type": "Reject"}, "savable": false}"#,
+    )
+    .unwrap();
+    let reference = client.upload_dataframe(&df, &closed, &[]).await.unwrap();
+    let count = client
+        .run_plan(&plan(
+            &reference.identifier,
+            template().select([col("id").count()]),
+        ))
+        .await
+        .unwrap();
+    let err = client.fetch_scalar(&count).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+}
+
+#[tokio::test]
+async fn joins_above_their_memory_limit_spill_to_disk() {
+    let spill_dir = std::env::temp_dir().join(format!("bastionlab-spill-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&spill_dir).unwrap();
+    let server = InProcessServer::start(&config_with(&format!(
+        "spill_dir = {:?}",
+        spill_dir.to_str().unwrap()
+    )))
+    .await
+    .unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // About 1.6 MB of inputs, above a limit of 1 MB.
+    let n = 100_000i64;
+    let left = df! {
+        "id" => (0..n).collect::<Vec<_>>(),
+        "amount" => (0..n).map(|i| i * 2).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let right = df! {
+        "id" => (0..n).rev().collect::<Vec<_>>(),
+        "score" => (0..n).rev().map(|i| i % 7).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let mut identifiers = Vec::new();
+    for df in [&left, &right] {
+        let reference = client
+            .upload_dataframe(df, &Policy::allow_by_default(), &[])
+            .await
+            .unwrap();
+        identifiers.push(reference.identifier);
+    }
+    let join = |allow_spill| {
+        let lazy = left.head(Some(0)).lazy().join(
+            right.head(Some(0)).lazy(),
+            [col("id")],
+            [col("id")],
+            JoinType::Inner,
+        );
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifiers[0].clone(),
+            },
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifiers[1].clone(),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: lazy.logical_plan,
+                skip_nan: false,
+                resources: Some(ResourceHints {
+                    allow_spill: Some(allow_spill),
+                    max_memory_mb: Some(1),
+                }),
+            },
+        ])
+    };
+    let spill_files = || std::fs::read_dir(&spill_dir).unwrap().count();
+
+    let err = client.run_plan(&join(false)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::ResourceExhausted, "{err:?}");
+    assert!(err.message().contains("segment 2 (join)"), "{err:?}");
+    assert_eq!(spill_files(), 0);
+
+    let result = client.run_plan(&join(true)).await.unwrap();
+    assert_eq!(spill_files(), 0);
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let expected = left
+        .join(&right, ["id"], ["id"], JoinType::Inner, None)
+        .unwrap();
+    let sorted = |df: &DataFrame| {
+        df.select(["id", "amount", "score"])
+            .and_then(|df| df.sort(["id"], false))
+            .unwrap()
+    };
+    assert!(sorted(&fetched).frame_equal(&sorted(&expected)));
+
+    std::fs::remove_dir_all(spill_dir).unwrap();
+}
+
+#[tokio::test]
+async fn reproducibility_bundles_detect_modified_inputs() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! {
+        "id" => [1i64, 2, 3, 4],
+        "city" => ["Paris", "Lyon", "Paris", "Nice"],
+        "amount" => [10i64, 20, 30, 40],
+    }
+    .unwrap();
+    let input = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: input.clone(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: df
+                .head(Some(0))
+                .lazy()
+                .groupby([col("city")])
+                .agg([col("amount").sum()])
+                .logical_plan,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+    let result = client.run_plan(&plan).await.unwrap();
+
+    // The bundle checks out offline against the key the server lists, and holds no data.
+    let archive = client
+        .create_reproducibility_bundle(&result.identifier)
+        .await
+        .unwrap();
+    let key = client
+        .server_capabilities()
+        .await
+        .unwrap()
+        .bundle_signing_key;
+    let bundle = open_bundle(&archive, &key).unwrap();
+    assert_eq!(bundle.result, result.identifier);
+    assert_eq!(bundle.inputs.len(), 1);
+    assert_eq!(bundle.inputs[0].input.identifier, input);
+    assert_eq!(bundle.inputs[0].input.version, 0);
+    assert_eq!(
+        bundle.schema,
+        [
+            (String::from("city"), String::from("str")),
+            (String::from("amount"), String::from("i64"))
+        ]
+    );
+    let text = String::from_utf8(archive.clone()).unwrap();
+    assert!(!text.contains("Lyon"), "{text}");
+    let forged = text.replacen(&bundle.result_hash, &"0".repeat(64), 1);
+    let err = client
+        .verify_reproducibility_bundle(forged.as_bytes())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+
+    let report = client
+        .verify_reproducibility_bundle(&archive)
+        .await
+        .unwrap();
+    assert!(report.inputs_match && report.result_matches, "{report:?}");
+    assert_eq!(report.recomputed_hash, bundle.result_hash);
+
+    // Modifying the input is reported, and the content it was computed from is gone.
+    let batch = df! { "id" => [2i64], "city" => ["Lyon"], "amount" => [25i64] }.unwrap();
+    client
+        .upsert_rows(&input, &batch, &[String::from("id")])
+        .await
+        .unwrap();
+    let report = client
+        .verify_reproducibility_bundle(&archive)
+        .await
+        .unwrap();
+    assert!(!report.inputs_match && !report.result_matches, "{report:?}");
+    assert_ne!(report.recomputed_hash, bundle.result_hash);
+    assert!(
+        report
+            .mismatches
+            .iter()
+            .any(|m| m.contains(&input) && m.contains("version 1")),
+        "{report:?}"
+    );
+    let err = client
+        .create_reproducibility_bundle(&result.identifier)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+}
+
+#[tokio::test]
+async fn business_days_skip_the_holidays_of_an_uploaded_frame() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // Days since the epoch: 2024-12-23 is a Monday, 2024-12-25 and 2025-01-01 are holidays.
+    let dates = |name: &str, days: &[i32]| {
+        Int32Chunked::from_slice(name, days)
+            .into_date()
+            .into_series()
+    };
+    let orders = DataFrame::new(vec![
+        dates("ordered", &[20080, 20084]),
+        dates("delivered", &[20087, 20091]),
+    ])
+    .unwrap();
+    let calendar = DataFrame::new(vec![dates("day", &[20082, 20089])]).unwrap();
+    let mut identifiers = Vec::new();
+    for df in [&orders, &calendar] {
+        let reference = client
+            .upload_dataframe(df, &Policy::allow_by_default(), &[])
+            .await
+            .unwrap();
+        identifiers.push(reference.identifier);
+    }
+
+    let col = |name: &str| {
+        Box::new(TemporalExpr::Column {
+            name: name.to_string(),
+        })
+    };
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifiers[0].clone(),
+        },
+        CompositePlanSegment::TemporalPlanSegment {
+            columns: vec![
+                TemporalColumn {
+                    name: String::from("business_days"),
+                    expr: TemporalExpr::BusinessDays {
+                        start: col("ordered"),
+                        end: col("delivered"),
+                        weekend: [false, false, false, false, false, true, true],
+                        holidays: Some(Holidays {
+                            identifier: identifiers[1].clone(),
+                            column: String::from("day"),
+                        }),
+                    },
+                },
+                TemporalColumn {
+                    name: String::from("due"),
+                    expr: TemporalExpr::Add {
+                        left: col("ordered"),
+                        right: Box::new(TemporalExpr::Duration {
+                            iso: String::from("P1M"),
+                        }),
+                    },
+                },
+            ],
+        },
+    ]);
+    let result = client.run_plan(&plan).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let business_days: Vec<_> = fetched
+        .column("business_days")
+        .unwrap()
+        .i64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(business_days, [4, 4]);
+    let due = fetched.column("due").unwrap();
+    assert_eq!(due.dtype(), &DataType::Date);
+    assert!(due.equals(&dates("due", &[20111, 20115])));
+
+    // The holidays are an input of the result.
+    let bundle = client
+        .create_reproducibility_bundle(&result.identifier)
+        .await
+        .unwrap();
+    let key = client
+        .server_capabilities()
+        .await
+        .unwrap()
+        .bundle_signing_key;
+    let inputs: Vec<_> = open_bundle(&bundle, &key)
+        .unwrap()
+        .inputs
+        .into_iter()
+        .map(|input| input.input.identifier)
+        .collect();
+    assert_eq!(inputs, identifiers);
+}
+
+#[tokio::test]
+async fn purposes_are_enforced_and_reported_by_code() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "TrueRule"},
+        "unsafe_handling": {"type": "Log"},
+        "savable": true,
+        "require_purpose": {"allowed_codes": ["research", "billing"]},
+    }))
+    .unwrap();
+    let input = client
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: input.clone(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: df
+                .head(Some(0))
+                .lazy()
+                .select([col("x").sum()])
+                .logical_plan,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+    let purpose = |code: &str, text: &str| Purpose {
+        code: code.to_string(),
+        text: text.to_string(),
+    };
+
+    // Missing and disallowed codes are rejected.
+    let err = client.run_plan(&plan).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = client
+        .run_plan_with_purpose(&plan, &purpose("marketing", ""))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = client
+        .run_plan_with_purpose(&plan, &purpose("research\nforged", ""))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+
+    let result = client
+        .run_plan_with_purpose(&plan, &purpose("research", "yearly totals"))
+        .await
+        .unwrap();
+    client
+        .run_plan_with_purpose(&plan, &purpose("research", ""))
+        .await
+        .unwrap();
+    // The result requires a purpose too.
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    client
+        .fetch_with_purpose(&result, &purpose("billing", "invoice"))
+        .await
+        .unwrap();
+
+    let usage = client.usage_report(Some(&input), 0).await.unwrap();
+    let codes: Vec<_> = usage
+        .iter()
+        .map(|usage| (usage.code.as_str(), usage.queries, usage.fetches))
+        .collect();
+    assert_eq!(codes, [("billing", 0, 1), ("research", 2, 0)]);
+    assert!(client
+        .usage_report(None, u64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+/// Fetches `reference` column by column in `order`, returning the columns as announced and the
+/// reassembled dataframe.
+async fn fetch_in_order(
+    client: &mut Client,
+    reference: &bastionlab_polars::polars_proto::ReferenceResponse,
+    order: ColumnOrder,
+) -> Result<(Vec<String>, DataFrame), tonic::Status> {
+    let mut stream = client.fetch_columns_stream(reference, order).await?;
+    let mut assembler = FetchAssembler::new(true);
+    while let Some(chunk) = stream.next().await {
+        assembler.push(chunk?)?;
+    }
+    let names = assembler.columns().map(|c| c.name.clone()).collect();
+    Ok((names, assembler.finish()?.1))
+}
+
+#[tokio::test]
+async fn columns_are_fetched_in_the_requested_order() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let n = 2_000i64;
+    let df = df! {
+        "id" => (0..n).collect::<Vec<_>>(),
+        "text" => (0..n).map(|i| format!("row {i} {}", "x".repeat(40))).collect::<Vec<_>>(),
+        "flag" => (0..n).map(|i| i % 2 == 0).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    // Markers announce every column before its data.
+    let mut stream = client
+        .fetch_columns_stream(
+            &result,
+            ColumnOrder {
+                strategy: column_order::Strategy::SmallestFirst as i32,
+                columns: Vec::new(),
+            },
+        )
+        .await
+        .unwrap();
+    let mut assembler = FetchAssembler::new(true);
+    let mut announced = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.unwrap();
+        if let Some(fetch_chunk::Body::ColumnStart(_)) = &chunk.body {
+            // The previous column is complete when the next one starts.
+            if let Some(previous) = assembler.columns().last() {
+                assert!(assembler.column(&previous.name).unwrap().is_some());
+            }
+            announced += 1;
+        }
+        assembler.push(chunk).unwrap();
+    }
+    assert_eq!(announced, 3);
+    let starts: Vec<_> = assembler.columns().cloned().collect();
+    let names: Vec<&str> = starts.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["flag", "id", "text"]);
+    for start in starts.iter() {
+        let series = df.column(&start.name).unwrap();
+        assert_eq!(start.dtype, series.dtype().to_string());
+        assert_eq!(
+            start.index as usize,
+            df.find_idx_by_name(&start.name).unwrap()
+        );
+        assert!(assembler
+            .column(&start.name)
+            .unwrap()
+            .unwrap()
+            .series_equal(series));
+    }
+    let (_, fetched) = assembler.finish().unwrap();
+    assert!(fetched.frame_equal(&df));
+
+    // Whatever the order, the frame comes back in schema order.
+    for (order, expected) in [
+        (vec!["text"], vec!["text", "id", "flag"]),
+        (vec!["flag", "text", "id"], vec!["flag", "text", "id"]),
+        (vec![], vec!["id", "text", "flag"]),
+    ] {
+        let (names, fetched) = fetch_in_order(
+            &mut client,
+            &result,
+            ColumnOrder {
+                strategy: column_order::Strategy::SchemaOrder as i32,
+                columns: order.iter().map(|c| c.to_string()).collect(),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(names, expected);
+        assert!(fetched.frame_equal(&df));
+    }
+
+    for order in [vec!["missing"], vec!["id", "id"]] {
+        let err = fetch_in_order(
+            &mut client,
+            &result,
+            ColumnOrder {
+                strategy: 0,
+                columns: order.iter().map(|c| c.to_string()).collect(),
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    }
+}
+
+#[tokio::test]
+async fn column_data_cannot_be_mistaken_for_framing() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // Columns are delimited by their announced lengths, whatever bytes they contain.
+    let n = 5_000i64;
+    let df = df! {
+        "text" => (0..n).map(|i| format!("[end]{i}[end]")).collect::<Vec<_>>(),
+        "id" => (0..n).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    assert!(client
+        .fetch(&result)
+        .await
+        .unwrap()
+        .dataframe
+        .frame_equal(&df));
+    let (names, fetched) = fetch_in_order(
+        &mut client,
+        &result,
+        ColumnOrder {
+            strategy: column_order::Strategy::SmallestFirst as i32,
+            columns: Vec::new(),
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(names, vec!["id", "text"]);
+    assert!(fetched.frame_equal(&df));
+}
+
+#[tokio::test]
+async fn the_first_column_decodes_before_the_last_is_sent() {
+    let server = InProcessServer::start(&config_with("fetch_chunk_kb = 32"))
+        .await
+        .unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // About 10 MB of text, far more than the stream buffers.
+    let n = 100_000i64;
+    let df = df! {
+        "text" => (0..n).map(|i| format!("{i:0>100}")).collect::<Vec<_>>(),
+        "id" => (0..n).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    let mut stream = client
+        .fetch_columns_stream(
+            &result,
+            ColumnOrder {
+                strategy: column_order::Strategy::SmallestFirst as i32,
+                columns: Vec::new(),
+            },
+        )
+        .await
+        .unwrap();
+    let mut assembler = FetchAssembler::new(true);
+    let id = loop {
+        assembler
+            .push(stream.next().await.unwrap().unwrap())
+            .unwrap();
+        if let Some(id) = assembler.column("id").unwrap() {
+            break id;
+        }
+    };
+    assert!(id.series_equal(df.column("id").unwrap()));
+
+    // While the consumer works on the first column, the server waits for it.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(assembler.column("text").unwrap().is_none());
+    let mut remaining = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.unwrap();
+        if let Some(fetch_chunk::Body::Data(_)) = &chunk.body {
+            remaining += 1;
+        }
+        assembler.push(chunk).unwrap();
+    }
+    assert!(remaining > 100, "{remaining}");
+    let (_, fetched) = assembler.finish().unwrap();
+    assert!(fetched.frame_equal(&df));
+}
+
+#[tokio::test]
+async fn joins_read_federated_dataframes_from_their_server() {
+    let local = InProcessServer::start(&config()).await.unwrap();
+    let remote = InProcessServer::start(&config()).await.unwrap();
+    let mut client = local.client().await.unwrap();
+    let mut remote_client = remote.client().await.unwrap();
+
+    let people = df! {
+        "id" => [1i64, 2, 3, 4],
+        "name" => ["ada", "bob", "cyd", "dan"],
+    }
+    .unwrap();
+    let salaries = df! {
+        "id" => [2i64, 3, 4, 5],
+        "salary" => [1000i64, 2000, 3000, 4000],
+    }
+    .unwrap();
+    let people_ref = client
+        .upload_dataframe(&people, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let salaries_ref = remote_client
+        .upload_dataframe(&salaries, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+
+    // The local server reads the remote dataframe as a user of the remote server.
+    let (delegate, der) = SigningKey::generate().unwrap();
+    remote.add_key(KeyRole::User, &delegate).unwrap();
+    let federated = client
+        .register_remote_dataframe(
+            remote.addr(),
+            &salaries_ref.identifier,
+            &pkcs8_pem(&der),
+            &salaries.schema(),
+            &Policy::allow_by_default(),
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        client.header(&federated.identifier).await.unwrap().header,
+        salaries_ref.header
+    );
+
+    // The filter only reads the federated dataframe and runs remotely, the join runs locally.
+    let join = |identifier: &str| {
+        let filter = salaries
+            .head(Some(0))
+            .lazy()
+            .filter(col("salary").gt(lit(1500i64)));
+        let join = people.head(Some(0)).lazy().join(
+            salaries.head(Some(0)).lazy(),
+            [col("id")],
+            [col("id")],
+            JoinType::Inner,
+        );
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: people_ref.identifier.clone(),
+            },
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.to_string(),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: filter.logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: join.logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    };
+    let correlation_id = "federated-join";
+    client.set_correlation_id(Some(correlation_id));
+    let result = client.run_plan(&join(&federated.identifier)).await.unwrap();
+    client.set_correlation_id(None);
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let expected = df! {
+        "id" => [3i64, 4],
+        "name" => ["cyd", "dan"],
+        "salary" => [2000i64, 3000],
+    }
+    .unwrap();
+    assert!(fetched.sort(["id"], false).unwrap().frame_equal(&expected));
+
+    let local_records = local.polars().correlated_accesses(correlation_id);
+    assert_eq!(local_records.len(), 1);
+    assert!(local_records[0].inputs.contains(&federated.identifier));
+    let remote_records = remote.polars().correlated_accesses(correlation_id);
+    assert_eq!(remote_records.len(), 2, "{remote_records:?}");
+    assert!(remote_records
+        .iter()
+        .all(|record| record.user_id == delegate.pubkey_hash()));
+    assert!(remote_records[0].inputs.contains(&salaries_ref.identifier));
+
+    // Rows stay on the remote server.
+    let err = client.fetch(&federated).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+
+    let missing = client
+        .register_remote_dataframe(
+            remote.addr(),
+            "missing",
+            &pkcs8_pem(&der),
+            &salaries.schema(),
+            &Policy::allow_by_default(),
+            &[],
+        )
+        .await
+        .unwrap();
+    let err = client
+        .run_plan(&join(&missing.identifier))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unavailable, "{err:?}");
+    assert!(err.message().contains("identifier=missing"), "{err:?}");
+}
+
+#[tokio::test]
+async fn transfers_upload_released_results_to_another_server() {
+    let source = InProcessServer::start(&config()).await.unwrap();
+    let destination = InProcessServer::start(&config()).await.unwrap();
+    let mut client = source.client().await.unwrap();
+    let df = df! {
+        "id" => [1i64, 2, 3],
+        "name" => ["ada", "bob", "cyd"],
+    }
+    .unwrap();
+    let input = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&input.identifier))
+        .await
+        .unwrap();
+
+    // The source server uploads the result as a user of the destination server.
+    let (delegate, der) = SigningKey::generate().unwrap();
+    destination.add_key(KeyRole::User, &delegate).unwrap();
+    let credential = pkcs8_pem(&der);
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "TrueRule"},
+        "unsafe_handling": {"type": "Log"},
+        "savable": true,
+        "require_purpose": {"allowed_codes": ["research"]},
+    }))
+    .unwrap();
+    client.set_correlation_id(Some("partner-transfer"));
+    let transfer = client
+        .transfer_dataframe(&result.identifier, destination.addr(), &credential, &policy)
+        .await
+        .unwrap();
+    client.set_correlation_id(None);
+    let reference = transfer.reference.unwrap();
+    let receipt = transfer.receipt.unwrap();
+    assert_eq!(receipt.correlation_id, "partner-transfer");
+    assert_eq!(receipt.identifier, result.identifier);
+    assert_eq!(receipt.destination_identifier, reference.identifier);
+    assert_eq!(receipt.rows, 3);
+    assert_eq!(reference.header, result.header);
+
+    let source_records = source.polars().correlated_accesses("partner-transfer");
+    assert_eq!(source_records.len(), 1);
+    assert_eq!(source_records[0].kind, AccessKind::Transfer);
+    assert!(source_records[0].inputs.contains(&input.identifier));
+    let destination_records = destination.polars().correlated_accesses("partner-transfer");
+    assert_eq!(destination_records.len(), 1);
+    assert_eq!(destination_records[0].kind, AccessKind::Upload);
+    assert_eq!(destination_records[0].identifier, reference.identifier);
+    assert_eq!(destination_records[0].user_id, delegate.pubkey_hash());
+
+    // The rows and the destination policy are those of the new upload.
+    let key = SigningKey::from_pkcs8_pem(credential.as_bytes()).unwrap();
+    let mut partner = Client::connect(destination.addr().to_string(), Some(key))
+        .await
+        .unwrap();
+    let err = partner
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let research = Purpose {
+        code: String::from("research"),
+        text: String::new(),
+    };
+    let rows = partner
+        .run_plan_with_purpose(&entry_point(&reference.identifier), &research)
+        .await
+        .unwrap();
+    let fetched = partner
+        .fetch_with_purpose(&rows, &research)
+        .await
+        .unwrap()
+        .dataframe;
+    assert!(fetched.frame_equal(&df));
+
+    // Failed transfers leave nothing on the destination.
+    let mut owner = destination.client().await.unwrap();
+    let listed = owner.list_dataframes().await.unwrap().len();
+    let (_, der) = SigningKey::generate().unwrap();
+    let err = client
+        .transfer_dataframe(
+            &result.identifier,
+            destination.addr(),
+            &pkcs8_pem(&der),
+            &policy,
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unavailable, "{err:?}");
+    assert_eq!(owner.list_dataframes().await.unwrap().len(), listed);
+}
+
+#[tokio::test]
+async fn owners_see_the_recent_queries_on_their_dataframes() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! {
+        "city" => ["Paris", "Lyon", "Paris"],
+        "name" => ["alice", "bob", "carol"],
+        "amount" => [10i64, 20, 30],
+    }
+    .unwrap();
+    let identifier = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let plan = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.clone(),
+        },
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: df
+                .head(Some(0))
+                .lazy()
+                .filter(col("name").eq(lit("alice")).or(col("city").eq(lit("Lyon"))))
+                .groupby([col("city")])
+                .agg([col("amount").sum()])
+                .logical_plan,
+            skip_nan: false,
+            resources: None,
+        },
+    ]);
+    let result = analyst.run_plan(&plan).await.unwrap();
+    analyst.fetch(&result).await.unwrap();
+
+    let err = analyst.recent_activity(&identifier).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let activity = owner.recent_activity(&identifier).await.unwrap();
+    assert_eq!(activity.len(), 1);
+    let entry = &activity[0];
+    assert_eq!(entry.result, result.identifier);
+    assert_eq!(
+        entry.synopsis,
+        r#"filter(name == ? or city == "Lyon"), groupby(city), sum(amount)"#
+    );
+    assert_eq!(entry.output_rows, 2);
+    assert_eq!(entry.fetch_outcome(), FetchOutcome::Fetched);
+}
+
+#[tokio::test]
+async fn workspace_shares_are_revoked_with_membership() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    let server = InProcessServer::start(&config_with("publish_uploads = false"))
+        .await
+        .unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let draft = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let plan = entry_point(&draft);
+    let err = analyst.run_plan(&plan).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    owner.create_workspace("study").await.unwrap();
+    owner.attach_to_workspace("study", &[&draft]).await.unwrap();
+    owner
+        .share_workspace("study", &[&analyst_id])
+        .await
+        .unwrap();
+    let result = analyst.run_plan(&plan).await.unwrap();
+    assert!(analyst
+        .fetch(&result)
+        .await
+        .unwrap()
+        .dataframe
+        .frame_equal(&df));
+
+    // Detaching the member revokes the grant at once.
+    owner
+        .detach_from_workspace("study", &[&draft])
+        .await
+        .unwrap();
+    let err = analyst.run_plan(&plan).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    owner.attach_to_workspace("study", &[&draft]).await.unwrap();
+    let second = analyst.run_plan(&plan).await.unwrap();
+
+    // Analysts attach what they can read, and delete what they produced.
+    let err = analyst
+        .attach_to_workspace("study", &[&result.identifier])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    analyst.create_workspace("scratch").await.unwrap();
+    analyst
+        .attach_to_workspace("scratch", &[&draft, &result.identifier, &second.identifier])
+        .await
+        .unwrap();
+    let outcome = analyst.delete_workspace_members("scratch").await.unwrap();
+    let mut succeeded = outcome.succeeded.clone();
+    succeeded.sort();
+    let mut expected = vec![result.identifier.clone(), second.identifier.clone()];
+    expected.sort();
+    assert_eq!(succeeded, expected);
+    let failed: Vec<_> = outcome
+        .failures
+        .iter()
+        .map(|failure| failure.identifier.as_str())
+        .collect();
+    assert_eq!(failed, [draft.as_str()]);
+    assert!(analyst.header(&result.identifier).await.is_err());
+
+    // Deleting a workspace detaches its members, which survive.
+    owner.delete_workspace("study", false).await.unwrap();
+    let err = analyst.run_plan(&plan).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+    assert!(owner.header(&draft).await.is_ok());
+}
+
+#[tokio::test]
+async fn storage_class_changes_are_polled_jobs() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! { "amount" => [10i64, 20, 30] }.unwrap();
+    let identifier = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let err = analyst
+        .set_storage_class(&identifier, "cold")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = owner
+        .set_storage_class(&identifier, "frozen")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+
+    let job = owner.set_storage_class(&identifier, "cold").await.unwrap();
+    assert!(analyst.storage_class_job(job.job).await.is_err());
+    let mut status = job.status;
+    for _ in 0..100 {
+        if status != "pending" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        status = owner.storage_class_job(job.job).await.unwrap().status;
+    }
+    assert_eq!(status, "done");
+
+    let capabilities = owner.server_capabilities().await.unwrap();
+    let cold = capabilities
+        .storage_classes
+        .iter()
+        .find(|usage| usage.storage_class == "cold")
+        .unwrap();
+    assert_eq!((cold.dataframes, cold.memory_bytes), (1, 0));
+    assert!(cold.disk_bytes > 0);
+
+    // Cold dataframes are loaded when queried.
+    let result = owner.run_plan(&entry_point(&identifier)).await.unwrap();
+    let fetched = owner.fetch(&result).await.unwrap();
+    assert!(fetched.dataframe.frame_equal_missing(&df));
+}
+
+#[tokio::test]
+async fn splits_produce_an_output_per_slot() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => (0..10i64).collect::<Vec<_>>() }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let split = |then: Option<&str>| {
+        let mut segments = vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::SplitSegment {
+                train_size: 0.8,
+                seed: Some(7),
+                slots: ["train".to_string(), "test".to_string()],
+            },
+        ];
+        segments.extend(
+            then.map(|slot| CompositePlanSegment::SlotEntryPointSegment {
+                slot: slot.to_string(),
+            }),
+        );
+        CompositePlan::new(segments)
+    };
+
+    let result = client.run_plan(&split(None)).await.unwrap();
+    let slots: Vec<_> = result.outputs.iter().map(|o| o.slot.as_str()).collect();
+    assert_eq!(slots, ["train", "test"]);
+    assert_eq!(result.identifier, result.outputs[0].identifier);
+    let mut rows = 0;
+    for (output, height) in result.outputs.iter().zip([8, 2]) {
+        let fetched = client
+            .fetch(&ReferenceResponse {
+                identifier: output.identifier.clone(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(fetched.dataframe.height(), height);
+        rows += fetched.dataframe.column("x").unwrap().sum::<i64>().unwrap();
+    }
+    assert_eq!(rows, 45);
+
+    // Later segments read slots back, the one left on the stack is the main output.
+    let result = client.run_plan(&split(Some("test"))).await.unwrap();
+    let slots: Vec<_> = result.outputs.iter().map(|o| o.slot.as_str()).collect();
+    assert_eq!(slots, ["main", "train", "test"]);
+    assert_eq!(client.fetch(&result).await.unwrap().dataframe.height(), 2);
+
+    let err = client
+        .run_plan(&split(Some("validation")))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(err.message().contains("train, test"), "{err:?}");
+}
+
+#[tokio::test]
+async fn encoder_mappings_inherit_the_restrictions_of_their_column() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "name" => ["alice", "bob", "alice"],
+        "city" => ["Paris", "Lyon", "Paris"],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+
+    let encode = |column: &str| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::LabelEncodeSegment {
+                column: column.to_string(),
+                mapping: "mapping".to_string(),
+            },
+        ])
+    };
+    let fetch_slot = |result: &ReferenceResponse, slot: &str| ReferenceResponse {
+        identifier: result
+            .outputs
+            .iter()
+            .find(|output| output.slot == slot)
+            .unwrap()
+            .identifier
+            .clone(),
+        ..Default::default()
+    };
+
+    let result = client.run_plan(&encode("city")).await.unwrap();
+    let mapping = client
+        .fetch(&fetch_slot(&result, "mapping"))
+        .await
+        .unwrap()
+        .dataframe;
+    assert!(mapping
+        .frame_equal(&df! { "city" => ["Lyon", "Paris"], "code" => [0 as IdxSize, 1] }.unwrap()));
+
+    // The mapping of a blacklisted column holds its values: they stay masked.
+    let result = client.run_plan(&encode("name")).await.unwrap();
+    let mapping = client
+        .fetch(&fetch_slot(&result, "mapping"))
+        .await
+        .unwrap()
+        .dataframe;
+    assert_eq!(mapping.height(), 2);
+    assert_eq!(mapping.column("name").unwrap().null_count(), 2);
+    let encoded = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(encoded.column("name").unwrap().null_count(), 3);
+}
+
+#[tokio::test]
+async fn literal_frames_join_as_lookup_tables() {
+    let server = InProcessServer::start(&config_with("literal_frame_max_cells = 6"))
+        .await
+        .unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "id" => [1i64, 2, 3],
+        "country" => ["FR", "DE", "FR"],
+    }
+    .unwrap();
+    let policy = Policy::allow_by_default().with_literal_join_keys(vec!["id".to_string()]);
+    let identifier = client
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    let lookup = |on: &str, columns: serde_json::Value| {
+        let columns: Vec<LiteralColumn> = serde_json::from_value(columns).unwrap();
+        let literal = DataFrame::new(
+            columns
+                .iter()
+                .map(|column| match column.dtype {
+                    LiteralDtype::Int64 => Series::new_empty(&column.name, &DataType::Int64),
+                    _ => Series::new_empty(&column.name, &DataType::Utf8),
+                })
+                .collect(),
+        )
+        .unwrap();
+        let join =
+            df.head(Some(0))
+                .lazy()
+                .join(literal.lazy(), [col(on)], [col(on)], JoinType::Inner);
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::LiteralFramePlanSegment { columns },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: join.logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    };
+
+    let result = client
+        .run_plan(&lookup(
+            "country",
+            serde_json::json!([
+                {"name": "country", "dtype": "Utf8", "values": ["FR", "DE"]},
+                {"name": "label", "dtype": "Utf8", "values": ["France", null]},
+            ]),
+        ))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let expected = df! {
+        "id" => [1i64, 2, 3],
+        "country" => ["FR", "DE", "FR"],
+        "label" => [Some("France"), None, Some("France")],
+    }
+    .unwrap();
+    assert!(fetched
+        .sort(["id"], false)
+        .unwrap()
+        .frame_equal_missing(&expected));
+
+    // Values are checked against their dtype before the plan runs.
+    let err = client
+        .run_plan(&lookup(
+            "country",
+            serde_json::json!([{"name": "country", "dtype": "Utf8", "values": ["FR", 2]}]),
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(
+        err.message()
+            .contains("column `country`, row 1: expected a string"),
+        "{err:?}"
+    );
+
+    let err = client
+        .run_plan(&lookup(
+            "country",
+            serde_json::json!([{"name": "country", "dtype": "Utf8", "values": vec!["FR"; 7]}]),
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(err.message().contains("at most 6 cells"), "{err:?}");
+
+    // Joining on a sensitive key would tell which guessed values are in the data.
+    let err = client
+        .run_plan(&lookup(
+            "id",
+            serde_json::json!([{"name": "id", "dtype": "Int64", "values": [2]}]),
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    assert!(err.message().contains("on column id."), "{err:?}");
+}
+
+/// Runs `plan` and fetches its result, sorted by patient and lab result.
+async fn joined(client: &mut Client, plan: CompositePlan) -> Result<DataFrame, tonic::Status> {
+    let result = client.run_plan(&plan).await?;
+    let fetched = client.fetch(&result).await?.dataframe;
+    Ok(fetched.sort(["id", "result"], false).unwrap())
+}
+
+#[tokio::test]
+async fn join_segments_combine_both_inputs() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let patients = df! {
+        "id" => [1i64, 2, 3],
+        "name" => ["alice", "bob", "carol"],
+    }
+    .unwrap();
+    let labs = df! {
+        "patient" => [1i64, 1, 3],
+        "code" => ["1", "1", "3"],
+        "result" => [4.2f64, 5.1, 3.9],
+        "tech" => ["dan", "erin", "dan"],
+    }
+    .unwrap();
+    let patients_id = client
+        .upload_dataframe(
+            &patients,
+            &Policy::allow_by_default(),
+            &["name".to_string()],
+        )
+        .await
+        .unwrap()
+        .identifier;
+    let labs_id = client
+        .upload_dataframe(&labs, &Policy::allow_by_default(), &["tech".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let capped = Policy::allow_by_default().with_max_output_rows(Some(MaxOutputRows {
+        limit: 2,
+        mode: OutputRowsMode::Truncate,
+    }));
+    let capped_labs_id = client
+        .upload_dataframe(&labs, &capped, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let empty_labs_id = client
+        .upload_dataframe(&labs.head(Some(0)), &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    let join = |right: &str, left_on: &str, right_on: &str, how| {
+        let keys = |key: &str| match how {
+            JoinKind::Cross => Vec::new(),
+            _ => vec![key.to_string()],
+        };
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: patients_id.clone(),
+            },
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: right.to_string(),
+            },
+            CompositePlanSegment::JoinPlanSegment {
+                left_on: keys(left_on),
+                right_on: keys(right_on),
+                how,
+            },
+        ])
+    };
+    // Both blacklists apply, each to the columns of its side.
+    let fetched = joined(
+        &mut client,
+        join(&labs_id, "id", "patient", JoinKind::Inner),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fetched.get_column_names(),
+        ["id", "name", "code", "result", "tech"]
+    );
+    assert_eq!(fetched.height(), 3);
+    assert_eq!(fetched.column("name").unwrap().null_count(), 3);
+    assert_eq!(fetched.column("tech").unwrap().null_count(), 3);
+    assert!(fetched
+        .column("result")
+        .unwrap()
+        .series_equal(&Series::new("result", [3.9f64, 4.2, 5.1])));
+
+    let fetched = joined(&mut client, join(&labs_id, "id", "patient", JoinKind::Left))
+        .await
+        .unwrap();
+    assert_eq!(fetched.height(), 4);
+    assert_eq!(fetched.column("result").unwrap().null_count(), 1);
+
+    // So does the policy of either side.
+    let fetched = joined(
+        &mut client,
+        join(&capped_labs_id, "id", "patient", JoinKind::Inner),
+    )
+    .await
+    .unwrap();
+    assert_eq!(fetched.height(), 2);
+
+    // Joins with an empty side.
+    for (how, rows) in [
+        (JoinKind::Inner, 0),
+        (JoinKind::Left, 3),
+        (JoinKind::Outer, 3),
+        (JoinKind::Cross, 0),
+    ] {
+        let fetched = joined(&mut client, join(&empty_labs_id, "id", "patient", how))
+            .await
+            .unwrap();
+        assert_eq!(fetched.height(), rows, "{how:?}");
+    }
+
+    // Keys of different dtypes are rejected before anything runs.
+    let err = joined(&mut client, join(&labs_id, "id", "code", JoinKind::Inner))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(
+        err.message().contains("`id` (i64) with `code` (str)"),
+        "{err:?}"
+    );
+    let err = joined(&mut client, join(&labs_id, "id", "missing", JoinKind::Left))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+}
+
+#[tokio::test]
+async fn group_by_segments_aggregate_all_but_blacklisted_values() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "code" => ["a", "b", "a", "b", "a"],
+        "age" => [30i64, 40, 50, 60, 70],
+        "cost" => [1.0f64, 2.0, 3.0, 4.0, 5.0],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["age".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let agg = |column: &str, agg| Aggregation {
+        column: column.to_string(),
+        agg,
+    };
+    let group_by = |before: Vec<CompositePlanSegment>, aggs| {
+        let mut segments = vec![CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.clone(),
+        }];
+        segments.extend(before);
+        segments.push(CompositePlanSegment::GroupByPlanSegment {
+            by: vec![String::from("code")],
+            aggs,
+        });
+        CompositePlan::new(segments)
+    };
+
+    // Counts are allowed on blacklisted columns, which only disclose the size of each group.
+    let kinds = [
+        AggKind::Sum,
+        AggKind::Mean,
+        AggKind::Min,
+        AggKind::Max,
+        AggKind::Median,
+        AggKind::Std,
+    ];
+    let mut aggs: Vec<_> = kinds.iter().map(|&kind| agg("cost", kind)).collect();
+    aggs.push(agg("age", AggKind::Count));
+    let result = client.run_plan(&group_by(Vec::new(), aggs)).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    let fetched = fetched.sort(["code"], false).unwrap();
+    assert_eq!(
+        fetched.get_column_names(),
+        [
+            "code",
+            "cost_sum",
+            "cost_mean",
+            "cost_min",
+            "cost_max",
+            "cost_median",
+            "cost_std",
+            "age_count"
+        ]
+    );
+    assert!(fetched
+        .column("cost_mean")
+        .unwrap()
+        .series_equal(&Series::new("cost_mean", [3.0f64, 3.0])));
+    let counts = fetched
+        .column("age_count")
+        .unwrap()
+        .cast(&DataType::Int64)
+        .unwrap();
+    assert!(counts.series_equal(&Series::new("age_count", [3i64, 2])));
+
+    // Other aggregations of blacklisted columns are rejected, under an alias too.
+    let err = client
+        .run_plan(&group_by(Vec::new(), vec![agg("age", AggKind::Mean)]))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(
+        err.message()
+            .contains("Could not aggregate `age` with mean: the column is blacklisted"),
+        "{err:?}"
+    );
+    let rename = CompositePlanSegment::PolarsPlanSegment {
+        plan: df
+            .head(Some(0))
+            .lazy()
+            .select([col("code"), col("age").alias("years")])
+            .logical_plan,
+        skip_nan: false,
+        resources: None,
+    };
+    let err = client
+        .run_plan(&group_by(vec![rename], vec![agg("years", AggKind::Max)]))
+        .await
+        .unwrap_err();
+    assert!(err.message().contains("`years` with max"), "{err:?}");
+}
+
+#[tokio::test]
+async fn results_aggregating_too_few_rows_are_not_fetched() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "site" => ["a", "a", "a", "b", "b", "c"],
+        "cost" => [1.0f64, 2.0, 3.0, 4.0, 5.0, 6.0],
+    }
+    .unwrap();
+    let policy = |unsafe_handling: &str| -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "Aggregation", "min_agg_size": 2},
+            "unsafe_handling": {"type": unsafe_handling},
+            "savable": false,
+        }))
+        .unwrap()
+    };
+    let identifier = client
+        .upload_dataframe(&df, &policy("Reject"), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let group_by = |identifier: &str, without: Option<&str>| {
+        let mut segments = vec![CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.to_string(),
+        }];
+        if let Some(site) = without {
+            segments.push(CompositePlanSegment::FilterPlanSegment {
+                predicate: serde_json::from_value(serde_json::json!({
+                    "op": "NotEq",
+                    "left": {"op": "Column", "name": "site"},
+                    "right": {"op": "Literal", "value": site},
+                }))
+                .unwrap(),
+            });
+        }
+        segments.push(CompositePlanSegment::GroupByPlanSegment {
+            by: vec![String::from("site")],
+            aggs: vec![Aggregation {
+                column: String::from("cost"),
+                agg: AggKind::Sum,
+            }],
+        });
+        CompositePlan::new(segments)
+    };
+
+    // Site c has a single row, which its sum discloses.
+    let result = client.run_plan(&group_by(&identifier, None)).await.unwrap();
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(
+        err.message()
+            .contains("its smallest aggregate covers 1 rows"),
+        "{err:?}"
+    );
+    let result = client.run_plan(&entry_point(&identifier)).await.unwrap();
+    assert!(client.fetch(&result).await.is_err());
+
+    // Without it, every sum aggregates at least 2 rows.
+    let result = client
+        .run_plan(&group_by(&identifier, Some("c")))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap();
+    assert_eq!(fetched.status, FetchStatus::Ok);
+    assert_eq!(fetched.dataframe.height(), 2);
+
+    // Policies logging violations release the result with the reason.
+    let logged = client
+        .upload_dataframe(&df, &policy("Log"), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client.run_plan(&group_by(&logged, None)).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap();
+    match fetched.status {
+        FetchStatus::Warning(reason) => assert!(reason.contains("at least 2 rows"), "{reason}"),
+        status => panic!("unexpected status {status:?}"),
+    }
+}
+
+#[tokio::test]
+async fn policy_rollouts_report_what_they_break_and_roll_back() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let clinical = [String::from("clinical")];
+    let visits = client
+        .upload_tagged_dataframe(&df, &Policy::allow_by_default(), "visits", &clinical)
+        .await
+        .unwrap()
+        .identifier;
+    client
+        .upload_tagged_dataframe(&df, &Policy::allow_by_default(), "labs", &clinical)
+        .await
+        .unwrap();
+    let other = client
+        .upload_tagged_dataframe(&df, &Policy::allow_by_default(), "weather", &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client.run_plan(&entry_point(&visits)).await.unwrap();
+    let unaffected = client.run_plan(&entry_point(&other)).await.unwrap();
+    let plan = serde_json::to_value(entry_point(&visits)).unwrap();
+    client
+        .register_pipeline("visits", &plan, &[], &Visibility::Private)
+        .await
+        .unwrap();
+
+    let patch = PolicyPatch::Tighten {
+        policy: serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+            "unsafe_handling": {"type": "Reject"},
+            "savable": true,
+            "require_purpose": {"allowed_codes": ["research"]},
+        }))
+        .unwrap(),
+    };
+    let selector = PolicySelector {
+        tags: clinical.to_vec(),
+        ..Default::default()
+    };
+    let report = client
+        .roll_out_policy(selector.clone(), &patch, true)
+        .await
+        .unwrap();
+    assert!(report.rollout_id.is_empty());
+    assert_eq!(report.impacts.len(), 2);
+    let impact = report
+        .impacts
+        .iter()
+        .find(|impact| impact.identifier == visits)
+        .unwrap();
+    assert_eq!(impact.blocked_results, [result.identifier.clone()]);
+    assert_eq!(impact.blocked_pipelines, ["visits@1"]);
+    // Dry runs change nothing.
+    assert!(client.fetch(&result).await.is_ok());
+
+    let report = client
+        .roll_out_policy(selector, &patch, false)
+        .await
+        .unwrap();
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let err = client.run_pipeline("visits", None, &[]).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    assert!(client.fetch(&unaffected).await.is_ok());
+    assert!(client
+        .policy_history(&other)
+        .await
+        .unwrap()
+        .changes
+        .is_empty());
+    let history = client.policy_history(&visits).await.unwrap().changes;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].rollout_id, report.rollout_id);
+
+    let rolled_back = client
+        .roll_back_policy_rollout(&report.rollout_id)
+        .await
+        .unwrap();
+    assert!(rolled_back.rolled_back);
+    assert!(client.fetch(&result).await.is_ok());
+    assert!(client.run_pipeline("visits", None, &[]).await.is_ok());
+    let history = client.policy_history(&visits).await.unwrap().changes;
+    assert!(history[1].rollback);
+    let err = client
+        .roll_back_policy_rollout(&report.rollout_id)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn masked_columns_are_fetched_and_described_masked() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! {
+        "score" => (0..=100i64).collect::<Vec<_>>(),
+        "email" => (0..=100).map(|i| format!("user{i}@example.com")).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let masks = |masks: serde_json::Value| {
+        Policy::allow_by_default().with_column_masks(serde_json::from_value(masks).unwrap())
+    };
+    // Compositions are checked against the dtypes on upload.
+    let err = owner
+        .upload_dataframe(
+            &df,
+            &masks(serde_json::json!({"email": [{"type": "Bucketize", "edges": [0]}]})),
+            &[],
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    let policy = masks(serde_json::json!({
+        "score": [{"type": "TopBottomCode", "bottom": 10, "top": 90, "max_stale_mutations": 1}],
+        "email": [{"type": "Redact", "keep_first": 0, "keep_last": 12}],
+    }));
+    let identifier = owner
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    // Results keep the masks of the columns they are computed from.
+    let result = owner.run_plan(&entry_point(&identifier)).await.unwrap();
+    let fetched = owner.fetch(&result).await.unwrap().dataframe;
+    let score = fetched.column("score").unwrap().f64().unwrap();
+    assert_eq!((score.min(), score.max()), (Some(10.0), Some(90.0)));
+    let email = fetched.column("email").unwrap().utf8().unwrap();
+    assert_eq!(email.get(7), Some("*****@example.com"));
+
+    let described = |header: ReferenceResponse| {
+        header
+            .statistics
+            .into_iter()
+            .find(|statistics| statistics.column == "score")
+            .unwrap()
+    };
+    let score = described(analyst.header(&identifier).await.unwrap());
+    assert_eq!((score.min, score.max), (Some(10.0), Some(90.0)));
+
+    // Percentiles lag behind appends, up to the bound of the mask.
+    let delta = df! { "score" => [1000i64], "email" => ["late@example.com"] }.unwrap();
+    owner.append_rows(&identifier, &delta).await.unwrap();
+    let score = described(analyst.header(&identifier).await.unwrap());
+    assert_eq!(score.max, Some(90.0));
+    owner.append_rows(&identifier, &delta).await.unwrap();
+    let err = analyst.header(&identifier).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+    assert!(err.message().contains("2 mutations behind"), "{err:?}");
+
+    // The owner refreshes them by requesting the header.
+    let score = described(owner.header(&identifier).await.unwrap());
+    assert_eq!((score.min, score.max), (Some(10.0), Some(92.0)));
+    let score = described(analyst.header(&identifier).await.unwrap());
+    assert_eq!(score.max, Some(92.0));
+}
+
+#[tokio::test]
+async fn export_waivers_release_one_pristine_fetch() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut owner = server.client().await.unwrap();
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! {
+        "site" => ["a", "b", "c"],
+        "email" => ["ann@example.com", "bob@example.com", "cid@example.com"],
+    }
+    .unwrap();
+    let policy = Policy::allow_by_default().with_column_masks(
+        serde_json::from_value(serde_json::json!({
+            "email": [{"type": "Redact", "keep_first": 0, "keep_last": 12}],
+        }))
+        .unwrap(),
+    );
+    let other = owner.upload_dataframe(&df, &policy, &[]).await.unwrap();
+    let result = analyst
+        .run_plan(&entry_point(&other.identifier))
+        .await
+        .unwrap();
+    let email = |df: DataFrame| {
+        let email = df.column("email").unwrap().utf8().unwrap().get(0);
+        email.unwrap().to_string()
+    };
+    assert_eq!(
+        email(analyst.fetch(&result).await.unwrap().dataframe),
+        "***@example.com"
+    );
+
+    // Only the owners of the data of a result can issue waivers for it.
+    let expires_at = catalog::now_ms() + 60_000;
+    let err = analyst
+        .create_export_waiver(&result.identifier, &analyst_id, expires_at, "Submission")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let justification = "Regulatory submission 2026-114";
+    let waiver = owner
+        .create_export_waiver(&result.identifier, &analyst_id, expires_at, justification)
+        .await
+        .unwrap();
+
+    let err = analyst
+        .fetch_with_waiver(&other, &waiver.id)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("covers"), "{err:?}");
+    let err = owner
+        .fetch_with_waiver(&result, &waiver.id)
+        .await
+        .unwrap_err();
+    assert!(err.message().contains("was issued to"), "{err:?}");
+    let err = analyst
+        .fetch_with_waiver(&result, "no-such-waiver")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let fetched = analyst
+        .fetch_with_waiver(&result, &waiver.id)
+        .await
+        .unwrap();
+    assert_eq!(email(fetched.dataframe), "ann@example.com");
+    let err = analyst
+        .fetch_with_waiver(&result, &waiver.id)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{err:?}");
+    assert!(err.message().contains("already used"), "{err:?}");
+    // Other fetches are masked as usual.
+    assert_eq!(
+        email(analyst.fetch(&result).await.unwrap().dataframe),
+        "***@example.com"
+    );
+
+    let short = owner
+        .create_export_waiver(
+            &other.identifier,
+            &analyst_id,
+            catalog::now_ms() + 200,
+            justification,
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let err = analyst
+        .fetch_with_waiver(&other, &short.id)
+        .await
+        .unwrap_err();
+    assert!(err.message().contains("expired"), "{err:?}");
+
+    let used = owner.used_export_waivers(None, 0).await.unwrap();
+    assert_eq!(used.len(), 1);
+    assert_eq!(used[0].id, waiver.id);
+    assert_eq!(used[0].justification, justification);
+    assert_eq!(used[0].recipient, analyst_id);
+    assert!(used[0].used_at > 0 && !used[0].content_hash.is_empty());
+    assert!(analyst
+        .used_export_waivers(None, 0)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn uploads_without_a_policy_take_default_policies() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let df = df! {
+        "patient_id" => [1i64, 2, 3],
+        "email" => ["a@x.org", "b@x.org", "c@x.org"],
+        "age" => [31i64, 42, 54],
+    }
+    .unwrap();
+
+    let err = analyst
+        .upload_with_defaults(&df, None, &[])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+
+    let template = serde_json::json!({
+        "safe_zone": {"type": "TrueRule"},
+        "unsafe_handling": {"type": "Log"},
+        "savable": false,
+    });
+    owner
+        .set_default_policy(&template, &["email"], None)
+        .await
+        .unwrap();
+    let latest = owner
+        .set_default_policy(&template, &["email|.*_id"], None)
+        .await
+        .unwrap();
+    assert_eq!(latest.version, 2);
+    let first = owner.default_policy(None, Some(1)).await.unwrap();
+    assert_eq!(first.blacklist_patterns, ["email"]);
+
+    // The whole template applies, and the patterns blacklist on top of the listed columns.
+    let upload = owner
+        .upload_with_defaults(&df, None, &["age".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(upload.warnings.len(), 3, "{:?}", upload.warnings);
+    assert!(upload.warnings[0].contains("`safe_zone`, `unsafe_handling`, `savable`"));
+    assert!(upload.warnings[1].contains("Column `patient_id` was blacklisted by pattern"));
+    let applied = server
+        .polars()
+        .upload_defaults(&upload.identifier)
+        .unwrap()
+        .unwrap();
+    assert_eq!(applied.version, 2);
+    assert_eq!(applied.scope, server.owner_key().pubkey_hash());
+    let result = owner
+        .run_plan(&entry_point(&upload.identifier))
+        .await
+        .unwrap();
+    let fetched = owner.fetch(&result).await.unwrap().dataframe;
+    for column in ["patient_id", "email", "age"] {
+        assert_eq!(fetched.column(column).unwrap().null_count(), 3, "{column}");
+    }
+    // Defaults do not get around governance: the template leaves the upload unsavable.
+    assert!(owner.persist_dataframe(&upload.identifier).await.is_err());
+
+    // Explicit fields win over the template.
+    let upload = owner
+        .upload_with_defaults(&df, Some(&serde_json::json!({"savable": true})), &[])
+        .await
+        .unwrap();
+    assert!(upload.warnings[0].starts_with("Policy fields `safe_zone`, `unsafe_handling` were"));
+    owner.persist_dataframe(&upload.identifier).await.unwrap();
+
+    // Group defaults apply to members without defaults of their own.
+    let members = [analyst_id.clone()];
+    let err = analyst
+        .set_default_policy(&template, &[], Some(("analysts", &members)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    owner
+        .set_default_policy(&template, &[], Some(("analysts", &members)))
+        .await
+        .unwrap();
+    let group = analyst
+        .default_policy(Some("analysts"), None)
+        .await
+        .unwrap();
+    assert_eq!(group.members, [analyst_id]);
+    let upload = analyst.upload_with_defaults(&df, None, &[]).await.unwrap();
+    assert_eq!(upload.warnings.len(), 1);
+    assert!(upload.warnings[0].ends_with("the default policy of group analysts version 1"));
+}
+
+#[tokio::test]
+async fn queries_read_retained_versions() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i64, 2] }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let retention = client
+        .set_version_retention(&identifier, 2, 0)
+        .await
+        .unwrap();
+    assert_eq!(retention.versions.len(), 1);
+    for rows in [[3i64], [4]] {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let delta = df! { "x" => rows }.unwrap();
+        client.append_rows(&identifier, &delta).await.unwrap();
+    }
+
+    let sum = |version: &str| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: format!("{identifier}@{version}"),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: df
+                    .head(Some(0))
+                    .lazy()
+                    .select([col("x").sum()])
+                    .logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    };
+    let mut sums = Vec::new();
+    for version in ["0", "1", "2"] {
+        let result = client.run_plan(&sum(version)).await.unwrap();
+        assert_eq!(result.input_versions, [format!("{identifier}@{version}")]);
+        let fetched = client.fetch(&result).await.unwrap().dataframe;
+        sums.push(fetched.column("x").unwrap().i64().unwrap().get(0).unwrap());
+    }
+    assert_eq!(sums, [3, 6, 10]);
+
+    // Timestamps select the latest version created at or before them.
+    let list = client.list_versions(&identifier).await.unwrap();
+    let created: Vec<_> = list.versions.iter().map(|v| v.created_at).collect();
+    let rows: Vec<_> = list.versions.iter().map(|v| v.rows).collect();
+    assert_eq!(rows, [2, 3, 4]);
+    assert!(list.versions[2].current);
+    assert_eq!(
+        list.retained_bytes, 0,
+        "appends share the rows of previous versions"
+    );
+    let result = client
+        .run_plan(&sum(&versions::timestamp(created[1])))
+        .await
+        .unwrap();
+    assert_eq!(result.input_versions, [format!("{identifier}@1")]);
+
+    // A third append prunes version 0.
+    client
+        .append_rows(&identifier, &df! { "x" => [5i64] }.unwrap())
+        .await
+        .unwrap();
+    let err = client.run_plan(&sum("0")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::OutOfRange, "{err:?}");
+    assert!(
+        err.message().contains(&format!(
+            "Version 0 of {identifier} is not retained: versions 1 to 3 are available, created \
+             since {}",
+            versions::timestamp(created[1])
+        )),
+        "{err:?}"
+    );
+    let err = client
+        .run_plan(&sum(&versions::timestamp(created[0])))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::OutOfRange, "{err:?}");
+
+    // Results record the version they read in their headers and lineage.
+    let result = client.run_plan(&sum("1")).await.unwrap();
+    let header = client.header(&result.identifier).await.unwrap();
+    assert_eq!(header.input_versions, [format!("{identifier}@1")]);
+    let archive = client
+        .create_reproducibility_bundle(&result.identifier)
+        .await
+        .unwrap();
+    let key = client
+        .server_capabilities()
+        .await
+        .unwrap()
+        .bundle_signing_key;
+    let bundle = open_bundle(&archive, &key).unwrap();
+    assert_eq!(bundle.inputs[0].input.identifier, identifier);
+    assert_eq!(bundle.inputs[0].input.version, 1);
+
+    // Only owners set retention.
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let err = analyst
+        .set_version_retention(&identifier, 0, 0)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+}
+
+#[tokio::test]
+async fn filter_segments_type_their_predicates() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "age" => [50i64, 70, 80],
+        "site" => ["A", "B", "A"],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let filter = |predicate: serde_json::Value| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::FilterPlanSegment {
+                predicate: serde_json::from_value(predicate).unwrap(),
+            },
+        ])
+    };
+    let compare = |op: &str, column: &str, value: serde_json::Value| {
+        serde_json::json!({
+            "op": op,
+            "left": {"op": "Column", "name": column},
+            "right": {"op": "Literal", "value": value},
+        })
+    };
+
+    let old_in_a = serde_json::json!({
+        "op": "And",
+        "left": compare("Gt", "age", serde_json::json!(65)),
+        "right": compare("Eq", "site", serde_json::json!("A")),
+    });
+    let result = client.run_plan(&filter(old_in_a)).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert!(fetched.frame_equal(&df.slice(2, 1)));
+
+    // Filters that match no row give an empty dataframe with the schema of the input.
+    let result = client
+        .run_plan(&filter(compare("Gt", "age", serde_json::json!(100))))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(fetched.height(), 0);
+    assert_eq!(fetched.schema(), df.schema());
+
+    let err = client
+        .run_plan(&filter(compare("Eq", "site", serde_json::json!(1))))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert_eq!(
+        err.message(),
+        "Could not filter: cannot compare `site` (str) with 1 (a number)"
+    );
+    let err = client
+        .run_plan(&filter(compare("Eq", "name", serde_json::json!("A"))))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert_eq!(
+        err.message(),
+        "Could not filter: no column `name` in the input"
+    );
+}
+
+#[tokio::test]
+async fn sort_segments_place_nulls_per_key_and_keep_the_blacklist() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "age" => [Some(50i64), None, Some(30), Some(50)],
+        "name" => ["a", "b", "c", "d"],
+        "row" => [0i64, 1, 2, 3],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let sort = |by: serde_json::Value| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::SortPlanSegment {
+                by: serde_json::from_value(by).unwrap(),
+                stable: true,
+            },
+        ])
+    };
+    let rows = |df: &DataFrame| -> Vec<Option<i64>> {
+        df.column("row")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect()
+    };
+
+    let result = client
+        .run_plan(&sort(serde_json::json!([{"column": "age"}])))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(rows(&fetched), [Some(1), Some(2), Some(0), Some(3)]);
+    // Sorting discloses nothing new: the blacklisted column stays masked.
+    assert_eq!(fetched.column("name").unwrap().null_count(), 4);
+
+    let by = serde_json::json!([{"column": "age", "descending": true, "nulls_last": true}]);
+    let result = client.run_plan(&sort(by)).await.unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(rows(&fetched), [Some(0), Some(3), Some(2), Some(1)]);
+
+    let err = client
+        .run_plan(&sort(serde_json::json!([{"column": "height"}])))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert_eq!(
+        err.message(),
+        "Could not sort: no column `height` in the input"
+    );
+}
+
+#[tokio::test]
+async fn select_segments_keep_blacklisted_columns_masked_under_their_alias() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "name" => ["alice", "bob"],
+        "age" => [31i64, 42],
+        "score" => [1.5f64, 2.5],
+        "site" => ["A", "B"],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let select = |columns: serde_json::Value, dtypes: serde_json::Value| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::SelectPlanSegment {
+                columns: serde_json::from_value(columns).unwrap(),
+                dtypes: serde_json::from_value(dtypes).unwrap(),
+            },
+        ])
+    };
+
+    let columns = serde_json::json!([{"name": "name", "alias": "who"}, {"name": "site"}]);
+    let result = client
+        .run_plan(&select(columns, serde_json::json!(["Numeric"])))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(fetched.get_column_names(), ["who", "site", "age", "score"]);
+    // Blacklisted columns can be selected, and are masked at fetch time under their new name.
+    assert_eq!(fetched.column("who").unwrap().null_count(), 2);
+    assert!(fetched
+        .column("age")
+        .unwrap()
+        .series_equal(df.column("age").unwrap()));
+
+    let err = client
+        .run_plan(&select(
+            serde_json::json!([{"name": "height"}]),
+            serde_json::json!([]),
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert_eq!(
+        err.message(),
+        "Could not select: no column `height` in the input"
+    );
+}
+
+#[tokio::test]
+async fn seeded_samples_are_reproducible_and_stay_unaggregated() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => (0..100i64).collect::<Vec<_>>() }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let sample = |identifier: &str, fraction: f64, with_replacement: bool, seed: Option<u64>| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.to_string(),
+            },
+            CompositePlanSegment::SamplePlanSegment {
+                fraction: Some(fraction),
+                n: None,
+                with_replacement,
+                seed,
+            },
+        ])
+    };
+
+    let mut samples = Vec::new();
+    for _ in 0..2 {
+        let result = client
+            .run_plan(&sample(&identifier, 0.2, false, Some(42)))
+            .await
+            .unwrap();
+        samples.push(client.fetch(&result).await.unwrap().dataframe);
+    }
+    assert_eq!(samples[0].height(), 20);
+    assert!(samples[0].frame_equal(&samples[1]));
+    let result = client
+        .run_plan(&sample(&identifier, 1.5, true, None))
+        .await
+        .unwrap();
+    assert_eq!(client.fetch(&result).await.unwrap().dataframe.height(), 150);
+
+    let err = client
+        .run_plan(&sample(&identifier, 1.5, false, Some(42)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(
+        err.message().contains("needs sampling with replacement"),
+        "{err:?}"
+    );
+
+    // Samples hold rows of their input, not aggregates: they are as unfetchable.
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 2},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+    }))
+    .unwrap();
+    let protected = client
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client
+        .run_plan(&sample(&protected, 0.1, false, None))
+        .await
+        .unwrap();
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+}
+
+#[tokio::test]
+async fn slices_count_from_the_end_and_are_capped() {
+    let server = InProcessServer::start(&config_with("slice_max_rows = 3"))
+        .await
+        .unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [0i64, 1, 2, 3, 4] }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let slice = |segment: CompositePlanSegment| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            segment,
+        ])
+    };
+    let values = |df: DataFrame| -> Vec<i64> {
+        let x = df.column("x").unwrap().i64().unwrap();
+        x.into_no_null_iter().collect()
+    };
+
+    let result = client
+        .run_plan(&slice(CompositePlanSegment::HeadPlanSegment { n: 2 }))
+        .await
+        .unwrap();
+    assert_eq!(
+        values(client.fetch(&result).await.unwrap().dataframe),
+        [0, 1]
+    );
+    let result = client
+        .run_plan(&slice(CompositePlanSegment::SlicePlanSegment {
+            offset: -2,
+            length: 3,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(
+        values(client.fetch(&result).await.unwrap().dataframe),
+        [3, 4]
+    );
+    // Past the end of the frame, slices are empty.
+    let result = client
+        .run_plan(&slice(CompositePlanSegment::SlicePlanSegment {
+            offset: 7,
+            length: 2,
+        }))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    assert_eq!(fetched.height(), 0);
+    assert_eq!(fetched.get_column_names(), ["x"]);
+
+    let err = client
+        .run_plan(&slice(CompositePlanSegment::HeadPlanSegment {
+            n: 10_000_000,
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(
+        err.message().contains("slices keep at most 3 rows"),
+        "{err:?}"
+    );
+    let limits = client.negotiated().unwrap().limits.clone().unwrap();
+    assert_eq!(limits.max_slice_rows, 3);
+}
+
+#[tokio::test]
+async fn computed_columns_are_as_protected_as_their_sources() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "salary" => [3000i64, 4000, 5000],
+        "sold" => [10i64, 5, 7],
+        "stock" => [2i64, 0, 1],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["salary".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let compute = |columns: &[(&str, serde_json::Value)]| {
+        let mut segments = vec![CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.clone(),
+        }];
+        for (name, expr) in columns {
+            segments.push(CompositePlanSegment::WithColumnPlanSegment {
+                name: name.to_string(),
+                expr: serde_json::from_value(expr.clone()).unwrap(),
+                replace: false,
+            });
+        }
+        CompositePlan::new(segments)
+    };
+    let column = |name: &str| serde_json::json!({"op": "Column", "name": name});
+
+    let laundered = serde_json::json!({
+        "op": "Add", "left": column("salary"), "right": {"op": "Literal", "value": 0},
+    });
+    let twice = serde_json::json!({"op": "Mul", "left": column("pay"), "right": column("sold")});
+    let ratio = serde_json::json!({"op": "Div", "left": column("sold"), "right": column("stock")});
+    let result = client
+        .run_plan(&compute(&[
+            ("pay", laundered),
+            ("bonus", twice),
+            ("ratio", ratio),
+        ]))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap().dataframe;
+    // Computed from a blacklisted column, even through another computed column.
+    for masked in ["salary", "pay", "bonus"] {
+        assert_eq!(fetched.column(masked).unwrap().null_count(), 3, "{masked}");
+    }
+    // Divisions by zero are null.
+    let ratio = fetched.column("ratio").unwrap().f64().unwrap();
+    assert_eq!(Vec::from(ratio), [Some(5.0), None, Some(7.0)]);
+
+    let err = client
+        .run_plan(&compute(&[("sold", column("stock"))]))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(err.message().contains("set `replace`"), "{err:?}");
+}
+
+#[tokio::test]
+async fn clients_skipping_the_handshake_get_the_defaults() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    client.skip_handshake();
+
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let fetched = client.fetch(&reference).await.unwrap().dataframe;
+    assert!(fetched.frame_equal(&df));
+    assert!(client.negotiated().is_none());
+    assert_eq!(client.upload_chunk_size(), 32 * 1024);
+    assert!(client.client_versions().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn newer_clients_negotiate_down() {
+    let config = config_with(r#"deprecated_features = { ipc_upload = "2027-06-30" }"#);
+    let server = InProcessServer::start(&config).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    // A newer client: a later API version, and a feature this server does not know.
+    let mut request = bastionlab_client::handshake_request();
+    request.client_version = String::from("9.0.0");
+    request.api_version = handshake::API_VERSION + 1;
+    request.features = vec![String::from("ipc_upload"), String::from("quantum_fetch")];
+    let response = client.handshake(request).await.unwrap();
+    assert_eq!(response.api_version, handshake::API_VERSION);
+    assert_eq!(response.enabled_features, ["ipc_upload"]);
+    assert_eq!(response.auth_mode, "keys");
+    assert_eq!(response.deprecations.len(), 1);
+    assert_eq!(response.deprecations[0].sunset, "2027-06-30");
+    let limits = response.limits.unwrap();
+    assert_eq!(limits.max_page_size, 1000);
+    assert_eq!(limits.max_upload_bytes, 0);
+    assert!(response.capabilities.unwrap().supports("FilterPlanSegment"));
+
+    // Column uploads were not enabled: the client uploads IPC files.
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    assert!(client
+        .fetch(&reference)
+        .await
+        .unwrap()
+        .dataframe
+        .frame_equal(&df));
+
+    let versions = client.client_versions().await.unwrap();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].client_version, "9.0.0");
+    assert_eq!(versions[0].api_version, handshake::API_VERSION + 1);
+    assert_eq!(versions[0].handshakes, 1);
+}
+
+#[tokio::test]
+async fn handshake_limits_apply_to_uploads() {
+    let config = config_with("max_upload_mb = 1\nupload_chunk_kb = 4");
+    let server = InProcessServer::start(&config).await.unwrap();
+    let mut client = server.client().await.unwrap();
+
+    let small = df! { "x" => [1i64, 2, 3] }.unwrap();
+    client
+        .upload_dataframe(&small, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let limits = client.negotiated().unwrap().limits.clone().unwrap();
+    assert_eq!(limits.max_upload_bytes, 1 << 20);
+    assert_eq!(client.upload_chunk_size(), 4096);
+
+    // Rejected by the client, before anything is sent.
+    let large = df! { "x" => (0..300_000i64).collect::<Vec<_>>() }.unwrap();
+    let err = client
+        .upload_dataframe(&large, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::ResourceExhausted, "{err:?}");
+    assert!(
+        err.message()
+            .ends_with("larger than the 1048576 bytes the server accepts"),
+        "{err:?}"
+    );
+
+    // Clients skipping the handshake are rejected by the server.
+    let mut old = server.client().await.unwrap();
+    old.skip_handshake();
+    let err = old
+        .upload_dataframe(&large, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::ResourceExhausted, "{err:?}");
+    assert_eq!(
+        err.message(),
+        "The upload is larger than the 1048576 bytes this server accepts"
+    );
+}
+
+#[tokio::test]
+async fn metadata_exposures_redact_header_statistics() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+
+    let df = df! {
+        "full" => [41_250.0f64, 41_250.0, 97_830.5],
+        "rounded" => [41_250.0f64, 41_250.0, 97_830.5],
+        "counts" => [41_250.0f64, 41_250.0, 97_830.5],
+        "hidden" => [41_250.0f64, 41_250.0, 97_830.5],
+    }
+    .unwrap();
+    let exposures = ColumnExposures::from([
+        (String::from("full"), Exposure::Full),
+        (
+            String::from("rounded"),
+            Exposure::Rounded {
+                precision: 1000.0,
+                distinct_floor: 5,
+            },
+        ),
+        (
+            String::from("counts"),
+            Exposure::CountsOnly { distinct_floor: 0 },
+        ),
+        (String::from("hidden"), Exposure::Hidden),
+    ]);
+    let policy = Policy::allow_by_default().with_metadata_exposure(exposures);
+    let upload = owner
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = analyst
+        .run_plan(&entry_point(&upload))
+        .await
+        .unwrap()
+        .identifier;
+
+    let exposed = |header: ReferenceResponse| {
+        header
+            .statistics
+            .into_iter()
+            .map(|s| {
+                let bounds = (s.min, s.max);
+                (s.column, (s.exposure, bounds, s.distinct, s.distinct_below))
+            })
+            .collect::<std::collections::BTreeMap<_, _>>()
+    };
+    let exact = (Some(41_250.0), Some(97_830.5));
+    let full = (String::from("full"), exact, 2, false);
+    // Results take the exposures of their inputs, whoever computed them.
+    for identifier in [&upload, &result] {
+        let statistics = exposed(analyst.header(identifier).await.unwrap());
+        assert_eq!(statistics.len(), 3, "{statistics:?}");
+        assert_eq!(statistics["full"], full);
+        assert_eq!(
+            statistics["rounded"],
+            (
+                String::from("rounded"),
+                (Some(41_000.0), Some(98_000.0)),
+                5,
+                true
+            )
+        );
+        assert_eq!(
+            statistics["counts"],
+            (String::from("counts_only"), (None, None), 2, false)
+        );
+        assert!(!statistics.contains_key("hidden"));
+    }
+
+    // Owners see the full statistics of their uploads.
+    let statistics = exposed(owner.header(&upload).await.unwrap());
+    assert_eq!(statistics.len(), 4);
+    assert!(statistics.values().all(|exposed| *exposed == full));
+
+    // Listings carry no statistics.
+    let listed = analyst.list_dataframes().await.unwrap();
+    assert!(listed.iter().all(|entry| entry.statistics.is_empty()));
+}
+
+#[tokio::test]
+async fn query_batches_answer_each_query() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "site" => ["a", "a", "b"], "cost" => [1.0f64, 2.0, 3.0] }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let reject: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+    }))
+    .unwrap();
+    let protected = client
+        .upload_dataframe(&df, &reject, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let ids: Vec<i64> = (0..100_000).collect();
+    let large = client
+        .upload_dataframe(
+            &df! { "id" => &ids }.unwrap(),
+            &Policy::allow_by_default(),
+            &[],
+        )
+        .await
+        .unwrap()
+        .identifier;
+
+    let plans = [
+        entry_point(&identifier),
+        entry_point("missing"),
+        entry_point(&protected),
+        entry_point(&large),
+    ];
+    let response = client.run_plan_batch(&plans, false, true).await.unwrap();
+    let results = response.results;
+    assert_eq!(results.len(), 4);
+
+    let inlined = bastionlab_client::inlined_dataframe(&results[0])
+        .unwrap()
+        .unwrap();
+    assert!(inlined.frame_equal(&df));
+    assert_ne!(results[1].error_code, tonic::Code::Ok as i32);
+    assert!(results[1].error.contains("missing"), "{:?}", results[1]);
+    assert!(results[1].result.is_none() && !results[1].skipped);
+    // Results that need approval or are too large are fetched as usual.
+    let protected_result = results[2].result.clone().unwrap();
+    assert!(results[2].inline_data.is_empty());
+    let err = client.fetch(&protected_result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let large_result = results[3].result.clone().unwrap();
+    assert!(results[3].inline_data.is_empty());
+    let fetched = client.fetch(&large_result).await.unwrap().dataframe;
+    assert_eq!(fetched.height(), ids.len());
+
+    // Each query, and the inlined fetch, is recorded under the batch.
+    let records = server.polars().correlated_accesses(&response.batch_id);
+    let kinds: Vec<_> = records
+        .iter()
+        .map(|record| (record.kind, record.identifier.clone()))
+        .collect();
+    let first = results[0].result.as_ref().unwrap().identifier.clone();
+    assert_eq!(kinds.len(), 4, "{kinds:?}");
+    assert!(kinds.contains(&(AccessKind::Fetch, first.clone())));
+    assert!(kinds.contains(&(AccessKind::Query, first)));
+    assert!(kinds.contains(&(AccessKind::Query, protected_result.identifier)));
+    assert!(kinds.contains(&(AccessKind::Query, large_result.identifier)));
+}
+
+#[tokio::test]
+async fn fail_fast_batches_skip_the_queries_after_a_failure() {
+    let server = InProcessServer::start(&config_with(
+        "query_batch_parallelism = 1\nmax_queries_per_batch = 4",
+    ))
+    .await
+    .unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let plans = [
+        entry_point(&identifier),
+        entry_point("missing"),
+        entry_point(&identifier),
+        entry_point(&identifier),
+    ];
+
+    let results = client
+        .run_plan_batch(&plans, true, false)
+        .await
+        .unwrap()
+        .results;
+    assert!(results[0].result.is_some());
+    assert!(results[1].result.is_none() && !results[1].skipped);
+    assert!(results[2].skipped && results[3].skipped);
+    assert!(results[2].result.is_none());
+
+    // Without fail-fast, every query runs.
+    let results = client
+        .run_plan_batch(&plans, false, false)
+        .await
+        .unwrap()
+        .results;
+    let ran: Vec<_> = results.iter().map(|r| r.result.is_some()).collect();
+    assert_eq!(ran, [true, false, true, true]);
+    assert!(results.iter().all(|r| !r.skipped));
+
+    let mut too_many = plans.to_vec();
+    too_many.push(entry_point(&identifier));
+    let err = client
+        .run_plan_batch(&too_many, false, false)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(err.message().contains("more than the 4"), "{err:?}");
+}
+
+#[tokio::test]
+async fn batched_queries_are_faster_than_sequential_ones() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "site" => (0..1_000).map(|i| format!("s{}", i % 7)).collect::<Vec<_>>(),
+        "cost" => (0..1_000).map(|i| i as f64).collect::<Vec<_>>(),
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let plans: Vec<_> = (0..30)
+        .map(|_| {
+            CompositePlan::new(vec![
+                CompositePlanSegment::EntryPointPlanSegment {
+                    identifier: identifier.clone(),
+                },
+                CompositePlanSegment::GroupByPlanSegment {
+                    by: vec![String::from("site")],
+                    aggs: vec![Aggregation {
+                        column: String::from("cost"),
+                        agg: AggKind::Mean,
+                    }],
+                },
+            ])
+        })
+        .collect();
+    // Warms up the session and the connection.
+    client.run_plan(&plans[0]).await.unwrap();
+
+    let start = std::time::Instant::now();
+    for plan in plans.iter() {
+        client.run_plan(plan).await.unwrap();
+    }
+    let sequential = start.elapsed();
+    let start = std::time::Instant::now();
+    let response = client.run_plan_batch(&plans, false, false).await.unwrap();
+    let batched = start.elapsed();
+
+    assert!(response.results.iter().all(|r| r.result.is_some()));
+    assert!(
+        batched < sequential,
+        "{} queries took {batched:?} batched and {sequential:?} one by one",
+        plans.len()
+    );
+}
+
+#[tokio::test]
+async fn fingerprints_are_stable_and_follow_mutations_and_lineage() {
+    let mut server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "id" => [1i64, 2, 3],
+        "score" => [Some(10i64), None, Some(30)],
+    }
+    .unwrap();
+    let upload = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let identifier = upload.identifier.clone();
+    let uploaded = upload.fingerprint.clone();
+    assert_eq!(uploaded.len(), 64);
+    assert_eq!(
+        client.header(&identifier).await.unwrap().fingerprint,
+        uploaded
+    );
+
+    // Results hash the fingerprints of their inputs, which their headers list.
+    let result = client.run_plan(&entry_point(&identifier)).await.unwrap();
+    assert_eq!(
+        result.input_fingerprints[&format!("{identifier}@0")],
+        uploaded
+    );
+    assert_ne!(result.fingerprint, uploaded);
+    let header = client.header(&result.identifier).await.unwrap();
+    assert_eq!(header.fingerprint, result.fingerprint);
+    assert_eq!(header.input_fingerprints, result.input_fingerprints);
+    let fetched = client.fetch(&result).await.unwrap();
+    assert_eq!(fetched.fingerprint.as_ref(), Some(&result.fingerprint));
+    let derived = client
+        .run_plan(&entry_point(&result.identifier))
+        .await
+        .unwrap();
+    assert_eq!(
+        derived.input_fingerprints[&format!("{}@0", result.identifier)],
+        result.fingerprint
+    );
+    let listed = client.list_dataframes().await.unwrap();
+    let listed = listed.iter().find(|r| r.identifier == identifier).unwrap();
+    assert_eq!(listed.fingerprint, uploaded);
+
+    // Same content, same fingerprints after a restart.
+    client.persist_dataframe(&identifier).await.unwrap();
+    client.persist_dataframe(&result.identifier).await.unwrap();
+    server.restart().await.unwrap();
+    let mut client = server.client().await.unwrap();
+    assert_eq!(
+        client.header(&identifier).await.unwrap().fingerprint,
+        uploaded
+    );
+    let header = client.header(&result.identifier).await.unwrap();
+    assert_eq!(header.fingerprint, result.fingerprint);
+
+    // Every mutation changes them, and cached results can tell from the headers of their inputs.
+    let delta = df! { "id" => [4i64], "score" => [Some(40i64)] }.unwrap();
+    let appended = client.append_rows(&identifier, &delta).await.unwrap();
+    assert_ne!(appended.fingerprint, uploaded);
+    assert_eq!(
+        client.header(&identifier).await.unwrap().fingerprint,
+        appended.fingerprint
+    );
+    let upserted = df! { "id" => [2i64], "score" => [Some(20i64)] }.unwrap();
+    client
+        .upsert_rows(&identifier, &upserted, &[String::from("id")])
+        .await
+        .unwrap();
+    let current = client.header(&identifier).await.unwrap().fingerprint;
+    assert_ne!(current, appended.fingerprint);
+    assert_ne!(
+        current,
+        header.input_fingerprints[&format!("{identifier}@0")]
+    );
+    let rerun = client.run_plan(&entry_point(&identifier)).await.unwrap();
+    assert_eq!(
+        rerun.input_fingerprints[&format!("{identifier}@2")],
+        current
+    );
+    assert_ne!(rerun.fingerprint, result.fingerprint);
+}
+
+#[tokio::test]
+async fn unused_results_expire_unless_persisted_or_uploaded() {
+    let config = config_with("dataframe_ttl_secs = 2\ndataframe_ttl_sweep_secs = 3600");
+    let server = InProcessServer::start(&config).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "id" => [1i64, 2, 3] }.unwrap();
+    let upload = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&upload.identifier))
+        .await
+        .unwrap();
+    let persisted = client
+        .run_plan(&entry_point(&upload.identifier))
+        .await
+        .unwrap();
+    client
+        .persist_dataframe(&persisted.identifier)
+        .await
+        .unwrap();
+
+    // Fetches refresh the TTL.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    client.fetch(&result).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    assert_eq!(server.polars().sweep_expired(), Vec::<String>::new());
+    client.fetch(&result).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(2_500)).await;
+    assert_eq!(server.polars().sweep_expired(), [result.identifier.clone()]);
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+    assert!(
+        err.message()
+            .contains(&format!("Dataframe {} expired at", result.identifier)),
+        "{}",
+        err.message()
+    );
+    let err = client
+        .run_plan(&entry_point(&result.identifier))
+        .await
+        .unwrap_err();
+    assert!(err.message().contains("expired"), "{}", err.message());
+    client.header(&upload.identifier).await.unwrap();
+    client.fetch(&persisted).await.unwrap();
+}
+
+#[tokio::test]
+async fn data_owners_approve_and_reject_pending_fetches() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 2},
+        "unsafe_handling": {"type": "Review"},
+        "savable": false,
+    }))
+    .unwrap();
+    let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let identifier = owner
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = analyst.run_plan(&entry_point(&identifier)).await.unwrap();
+    let err = analyst.list_pending_requests().await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+
+    async fn pending(owner: &mut Client) -> PendingRequest {
+        for _ in 0..100 {
+            let listed = owner.list_pending_requests().await.unwrap();
+            if let Some(request) = listed.requests.into_iter().next() {
+                return request;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("No fetch request pending");
+    }
+
+    let fetch = |mut analyst: Client, result: ReferenceResponse| {
+        tokio::spawn(async move {
+            let fetched = analyst.fetch(&result).await;
+            (analyst, result, fetched)
+        })
+    };
+    let fetching = fetch(analyst, result);
+    let request = pending(&mut owner).await;
+    assert_eq!(request.identity, analyst_id);
+    assert!(!request.plan.is_empty());
+    assert!(request.expires_at > request.requested_at);
+    owner.approve_request(&request.id).await.unwrap();
+    let (analyst, result, fetched) = fetching.await.unwrap();
+    let fetched = fetched.unwrap();
+    assert!(matches!(fetched.status, FetchStatus::Pending(_)));
+    assert!(fetched.dataframe.frame_equal(&df));
+    let err = owner.approve_request(&request.id).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let fetching = fetch(analyst, result);
+    let request = pending(&mut owner).await;
+    owner
+        .reject_request(&request.id, "Aggregate it first")
+        .await
+        .unwrap();
+    let (_, _, fetched) = fetching.await.unwrap();
+    let err = fetched.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("Aggregate it first"), "{err:?}");
+    assert!(owner
+        .list_pending_requests()
+        .await
+        .unwrap()
+        .requests
+        .is_empty());
+
+    // Requests nobody decided on are rejected.
+    let server = InProcessServer::start(&config_with("approval_timeout_secs = 1"))
+        .await
+        .unwrap();
+    let mut owner = server.client().await.unwrap();
+    let identifier = owner
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let result = analyst.run_plan(&entry_point(&identifier)).await.unwrap();
+    let err = analyst.fetch(&result).await.unwrap_err();
+    assert!(err.message().contains("not approved within 1s"), "{err:?}");
+}
+
+#[tokio::test]
+async fn the_audit_log_records_decisions_without_data() {
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    let analyst_id = analyst_key.pubkey_hash().to_string();
+    let server = InProcessServer::start(&config()).await.unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut owner = server.client().await.unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let df = df! { "site" => ["north", "south"], "cost" => [1i64, 2] }.unwrap();
+    let open = owner
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let closed: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "FalseRule"},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+    }))
+    .unwrap();
+    let closed = owner
+        .upload_dataframe(&df, &closed, &[])
+        .await
+        .unwrap()
+        .identifier;
+
+    let filtered = CompositePlan::new(vec![
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: open.clone(),
+        },
+        CompositePlanSegment::FilterPlanSegment {
+            predicate: serde_json::from_value(serde_json::json!({
+                "op": "Eq",
+                "left": {"op": "Column", "name": "site"},
+                "right": {"op": "Literal", "value": "south"},
+            }))
+            .unwrap(),
+        },
+    ]);
+    let result = analyst.run_plan(&filtered).await.unwrap();
+    analyst.fetch(&result).await.unwrap();
+    let denied = analyst.run_plan(&entry_point(&closed)).await.unwrap();
+    let err = analyst.fetch(&denied).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let err = analyst.run_plan(&entry_point("missing")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let err = analyst.audit_log(0, 0, "").await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let entries = owner.audit_log(0, 0, "").await.unwrap().entries;
+    let summary: Vec<(&str, &str, &str)> = entries
+        .iter()
+        .map(|e| (e.action.as_str(), e.identifier.as_str(), e.outcome.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("Upload", open.as_str(), "Allowed"),
+            ("Upload", closed.as_str(), "Allowed"),
+            ("Query", result.identifier.as_str(), "Allowed"),
+            ("Fetch", result.identifier.as_str(), "Allowed"),
+            ("Query", denied.identifier.as_str(), "Allowed"),
+            ("Fetch", denied.identifier.as_str(), "Denied"),
+            ("Query", "", "Failed"),
+        ]
+    );
+    let query = &entries[2];
+    assert_eq!(query.identity, analyst_id);
+    assert_eq!(query.inputs, [open.clone()]);
+    assert_eq!(query.plan_hash.len(), 64);
+    assert_eq!(
+        query.segments,
+        ["EntryPointPlanSegment", "FilterPlanSegment"]
+    );
+    assert_eq!(entries[6].inputs, ["missing"]);
+    // Plans are recorded by hash: the literal of the filter is nowhere.
+    assert!(!format!("{entries:?}").contains("south"));
+
+    let about_closed = owner.audit_log(0, 0, &closed).await.unwrap().entries;
+    assert_eq!(about_closed.len(), 3);
+    let before = owner.audit_log(0, entries[0].at, "").await.unwrap();
+    assert!(before.entries.is_empty());
+    let after = owner.audit_log(entries[6].at + 1, 0, "").await.unwrap();
+    assert!(after.entries.is_empty());
+}
+
+#[tokio::test]
+async fn small_aggregates_are_released_with_noise_within_the_budget() {
+    let mut server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "site" => ["a", "a", "a", "b"],
+        "age" => [30i64, 40, 500, 60],
+    }
+    .unwrap();
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": true,
+        "differential_privacy": {
+            "mechanism": {"type": "Laplace"},
+            "epsilon": 0.5,
+            "budget": 1.0,
+            "bounds": {"age": {"min": 0, "max": 100}},
+        },
+    }))
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &policy, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let group_by = |aggs: &[AggKind]| {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.clone(),
+            },
+            CompositePlanSegment::GroupByPlanSegment {
+                by: vec![String::from("site")],
+                aggs: aggs
+                    .iter()
+                    .map(|&agg| Aggregation {
+                        column: String::from("age"),
+                        agg,
+                    })
+                    .collect(),
+            },
+        ])
+    };
+
+    // Groups of fewer than 10 rows are released, noised.
+    let result = client
+        .run_plan(&group_by(&[AggKind::Count, AggKind::Sum, AggKind::Mean]))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&result).await.unwrap();
+    match &fetched.status {
+        FetchStatus::Warning(notice) => {
+            assert!(notice.contains("differential privacy noise"), "{notice}")
+        }
+        status => panic!("unexpected status {status:?}"),
+    }
+    let fetched = fetched.dataframe.sort(["site"], false).unwrap();
+    assert_eq!(
+        fetched.get_column_names(),
+        ["site", "age_count", "age_sum", "age_mean"]
+    );
+    let means = fetched.column("age_mean").unwrap().f64().unwrap();
+    assert!(means
+        .into_iter()
+        .flatten()
+        .all(|mean| (0.0..=100.0).contains(&mean)));
+
+    // Other aggregations cannot be noised, nor can other unsafe results be released.
+    let err = client
+        .run_plan(&group_by(&[AggKind::Min]))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(
+        err.message().contains("only count, sum and mean"),
+        "{err:?}"
+    );
+    let rows = client.run_plan(&entry_point(&identifier)).await.unwrap();
+    let err = client.fetch(&rows).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("differential privacy"), "{err:?}");
+
+    // Each fetch spends epsilon 0.5 of the budget of 1.
+    client.fetch(&result).await.unwrap();
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("epsilon 0 of 1 remains"), "{err:?}");
+
+    // Spent budgets are persisted as they are spent, and survive restarts.
+    assert!(server.data_dir().join(BUDGETS_FILE).exists());
+    client.persist_dataframe(&identifier).await.unwrap();
+    server.restart().await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let result = client.run_plan(&group_by(&[AggKind::Count])).await.unwrap();
+    let err = client.fetch(&result).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(err.message().contains("epsilon 0 of 1 remains"), "{err:?}");
+}
+
+#[tokio::test]
+async fn leaks_are_traced_after_a_restart() {
+    let secret_file =
+        std::env::temp_dir().join(format!("bastionlab-watermark-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&secret_file, "07".repeat(32)).unwrap();
+    let mut server = InProcessServer::start(&config_with(&format!(
+        "watermark_secret_file = {:?}",
+        secret_file.to_str().unwrap()
+    )))
+    .await
+    .unwrap();
+    let mut client = server.client().await.unwrap();
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "TrueRule"},
+        "unsafe_handling": {"type": "Log"},
+        "savable": true,
+        "watermark": {"columns": ["income"], "epsilon": 0.01},
+    }))
+    .unwrap();
+    let df = df! {
+        "id" => (0..200i64).collect::<Vec<_>>(),
+        "income" => (0..200).map(|i| 20_000.0 + i as f64 * 37.3).collect::<Vec<f64>>(),
+    }
+    .unwrap();
+    let upload = client.upload_dataframe(&df, &policy, &[]).await.unwrap();
+    let result = client
+        .run_plan(&entry_point(&upload.identifier))
+        .await
+        .unwrap();
+    let leaked = client.fetch(&result).await.unwrap().dataframe;
+    client.fetch(&result).await.unwrap();
+    assert!(!leaked.frame_equal(&df));
+
+    // With a configured secret, the watermarked fetches survive restarts.
+    server.restart().await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let trace = client.trace_watermark(&leaked).await.unwrap();
+    assert_eq!(trace.matches.len(), 2);
+    assert_eq!(trace.matches[0].score, 1.0);
+    assert_eq!(trace.matches[0].values, 200);
+    assert!(trace.matches[1].score < 0.8, "{:?}", trace.matches);
+    // The secret is not persisted with them.
+    let persisted = std::fs::read_to_string(server.data_dir().join(WATERMARKS_FILE)).unwrap();
+    assert!(!persisted.contains(&"07".repeat(32)));
+    std::fs::remove_file(secret_file).unwrap();
+}
+
+#[tokio::test]
+async fn vstack_segments_stack_inputs_under_all_their_policies() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let january = df! { "name" => ["ann", "bob"], "visits" => [1i64, 2] }.unwrap();
+    let january = client
+        .upload_dataframe(&january, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let february = df! { "visits" => [3i32], "name" => ["cid"] }.unwrap();
+    let reviewed: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+    }))
+    .unwrap();
+    let february = client
+        .upload_dataframe(&february, &reviewed, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let vstack = |inputs: &[&String], relaxed| {
+        CompositePlan::new(vec![CompositePlanSegment::VStackPlanSegment {
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            relaxed,
+        }])
+    };
+
+    let err = client.run_plan(&vstack(&[], false)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    let err = client
+        .run_plan(&vstack(&[&january, &february], false))
+        .await
+        .unwrap_err();
+    assert!(
+        err.message()
+            .contains(&format!("`{february}`: `visits` is i32, not i64")),
+        "{err:?}"
+    );
+
+    // The rows of February are under its stricter policy, and the names of January blacklisted.
+    let stacked = client
+        .run_plan(&vstack(&[&january, &february], true))
+        .await
+        .unwrap();
+    let err = client.fetch(&stacked).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    let single = client.run_plan(&vstack(&[&january], false)).await.unwrap();
+    let fetched = client.fetch(&single).await.unwrap().dataframe;
+    assert_eq!(fetched.column("name").unwrap().null_count(), 2);
+
+    let march = df! { "name" => ["dan"], "visits" => [4i64] }.unwrap();
+    let march = client
+        .upload_dataframe(&march, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let stacked = client
+        .run_plan(&vstack(&[&march, &january], false))
+        .await
+        .unwrap();
+    let fetched = client.fetch(&stacked).await.unwrap().dataframe;
+    assert_eq!(fetched.get_column_names(), ["name", "visits"]);
+    let visits = fetched.column("visits").unwrap().i64().unwrap();
+    assert_eq!(Vec::from(visits), [Some(4), Some(1), Some(2)]);
+    assert_eq!(fetched.column("name").unwrap().null_count(), 3);
+}
+
+#[tokio::test]
+async fn fetches_are_restricted_to_the_requested_columns() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! {
+        "name" => ["ann", "bob"],
+        "age" => [30i64, 40],
+        "city" => ["paris", "lyon"],
+    }
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &["name".to_string()])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client
+        .run_plan(&CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment { identifier },
+        ]))
+        .await
+        .unwrap();
+
+    // An empty list means every column.
+    let fetched = client.fetch_columns(&result, &[]).await.unwrap().dataframe;
+    assert_eq!(fetched.get_column_names(), ["name", "age", "city"]);
+    let fetched = client
+        .fetch_columns(&result, &["city", "age"])
+        .await
+        .unwrap()
+        .dataframe;
+    assert_eq!(fetched.get_column_names(), ["city", "age"]);
+    assert_eq!(
+        Vec::from(fetched.column("age").unwrap().i64().unwrap()),
+        [Some(30), Some(40)]
+    );
+
+    let err = client
+        .fetch_columns(&result, &["age", "zip"])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    assert!(err.message().contains("it has no column `zip`"), "{err:?}");
+    let err = client
+        .fetch_columns(&result, &["age", "age"])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{err:?}");
+    // Blacklisted columns are refused, not sent empty.
+    let err = client
+        .fetch_columns(&result, &["name", "age"])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+    assert!(
+        err.message()
+            .contains("the policy blacklists column `name`"),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn fetches_of_row_ranges_paginate_under_the_whole_policy() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "n" => [1i64, 2, 3, 4, 5] }.unwrap();
+    let entry = |identifier: String| {
+        CompositePlan::new(vec![CompositePlanSegment::EntryPointPlanSegment {
+            identifier,
+        }])
+    };
+    let identifier = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client.run_plan(&entry(identifier)).await.unwrap();
+
+    let page = client.fetch_rows(&result, 1, Some(2)).await.unwrap();
+    assert_eq!(
+        Vec::from(page.dataframe.column("n").unwrap().i64().unwrap()),
+        [Some(2), Some(3)]
+    );
+    let range = page.row_range.unwrap();
+    assert_eq!((range.offset, range.total_rows), (1, 5));
+    let rest = client.fetch_rows(&result, 3, None).await.unwrap();
+    assert_eq!(rest.dataframe.height(), 2);
+    // Offsets past the end fetch no rows, but still the columns and the total.
+    let past = client.fetch_rows(&result, 10, Some(2)).await.unwrap();
+    assert_eq!(past.dataframe.height(), 0);
+    assert_eq!(past.dataframe.get_column_names(), ["n"]);
+    assert_eq!(past.row_range.unwrap().total_rows, 5);
+    assert!(client.fetch(&result).await.unwrap().row_range.is_none());
+
+    // A page of a single row is no aggregate: the policy applies to the whole result.
+    let aggregated: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+        "unsafe_handling": {"type": "Reject"},
+        "savable": false,
+    }))
+    .unwrap();
+    let identifier = client
+        .upload_dataframe(&df, &aggregated, &[])
+        .await
+        .unwrap()
+        .identifier;
+    let result = client.run_plan(&entry(identifier)).await.unwrap();
+    let err = client.fetch_rows(&result, 0, Some(1)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied, "{err:?}");
+}
+
+#[tokio::test]
+async fn the_health_service_and_ping_answer_without_a_session() {
+    use tonic_health::proto::{
+        health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+    };
+
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let channel = Channel::from_shared(server.addr().to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut health = HealthClient::new(channel.clone());
+    let check = |service: &str| HealthCheckRequest {
+        service: service.to_string(),
+    };
+    let status = health.check(check("")).await.unwrap().into_inner().status;
+    assert_eq!(status, ServingStatus::Serving as i32);
+    let err = health.check(check("unknown.Service")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound, "{err:?}");
+
+    let mut session = SessionServiceClient::new(channel);
+    let ping = session.ping(session_proto::Empty {}).await.unwrap();
+    assert_eq!(ping.into_inner().version, env!("CARGO_PKG_VERSION"));
+    let mut client = server.client().await.unwrap();
+    assert_eq!(client.ping().await.unwrap(), env!("CARGO_PKG_VERSION"));
+}
+
+/// Scrapes the metrics of `server` over plain HTTP/1.1.
+async fn scrape(server: &InProcessServer) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(server.metrics_addr())
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    response
+}
+
+#[tokio::test]
+async fn metrics_are_scraped_over_http() {
+    let server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let df = df! { "x" => [1i32, 2, 3] }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+    client.fetch(&result).await.unwrap();
+
+    // Fetches are timed until their stream task ends, just after the client has the last chunk.
+    let mut response = scrape(&server).await;
+    for _ in 0..100 {
+        if response.contains("bastionlab_fetch_stream_seconds_count 1") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        response = scrape(&server).await;
+    }
+    for method in ["SendDataFrame", "RunQuery", "FetchDataFrame"] {
+        let path = format!("/bastionlab_polars.PolarsService/{method}");
+        let line = format!("bastionlab_rpc_requests_total{{method=\"{path}\"}} 1");
+        assert!(response.contains(&line), "{line} in {response}");
+    }
+    for histogram in ["plan_run", "fetch_stream", "upload"] {
+        let count = format!("bastionlab_{histogram}_seconds_count 1");
+        assert!(response.contains(&count), "{count} in {response}");
+    }
+    // The result of the query is stored too.
+    assert!(response.contains("bastionlab_dataframes 2"), "{response}");
+}
+
+#[tokio::test]
+async fn shutting_down_finishes_fetches_in_flight_and_rejects_pending_ones() {
+    let mut server = InProcessServer::start(&config()).await.unwrap();
+    let mut client = server.client().await.unwrap();
+    let ids: Vec<i64> = (0..300_000).collect();
+    let df =
+        df! { "id" => &ids, "value" => ids.iter().map(|&i| i as f64).collect::<Vec<_>>() }.unwrap();
+    let reference = client
+        .upload_dataframe(&df, &Policy::allow_by_default(), &[])
+        .await
+        .unwrap();
+    let result = client
+        .run_plan(&entry_point(&reference.identifier))
+        .await
+        .unwrap();
+
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "safe_zone": {"type": "Aggregation", "min_agg_size": 2},
+        "unsafe_handling": {"type": "Review"},
+        "savable": false,
+    }))
+    .unwrap();
+    let small = df! { "x" => [1i64, 2, 3] }.unwrap();
+    let reviewed = client.upload_dataframe(&small, &policy, &[]).await.unwrap();
+    let (analyst_key, _) = SigningKey::generate().unwrap();
+    server.add_key(KeyRole::User, &analyst_key).unwrap();
+    let mut analyst = Client::connect(server.addr().to_string(), Some(analyst_key))
+        .await
+        .unwrap();
+    let pending = analyst
+        .run_plan(&entry_point(&reviewed.identifier))
+        .await
+        .unwrap();
+    let pending_fetch = tokio::spawn(async move { analyst.fetch(&pending).await });
+    for _ in 0..100 {
+        if !client
+            .list_pending_requests()
+            .await
+            .unwrap()
+            .requests
+            .is_empty()
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // A slow reader: the rest of the fetch is only read once the shutdown started.
+    let mut stream = client.fetch_stream(&result).await.unwrap();
+    let mut assembler = FetchAssembler::new(true);
+    assembler
+        .push(stream.next().await.unwrap().unwrap())
+        .unwrap();
+    let addr = server.addr().to_string();
+    let fetched = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while let Some(chunk) = stream.next().await {
+            assembler.push(chunk.unwrap()).unwrap();
+        }
+        assembler.finish().unwrap()
+    };
+    let (shut_down, (status, fetched)) = tokio::join!(server.shut_down(), fetched);
+    shut_down.unwrap();
+    assert_eq!(status, FetchStatus::Ok);
+    assert!(fetched.frame_equal(&df));
+
+    let err = pending_fetch.await.unwrap().unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unavailable, "{err:?}");
+    assert!(err.message().contains("shutting down"), "{err:?}");
+    // No longer accepting connections.
+    assert!(Client::connect(addr, None).await.is_err());
+}
@@ -0,0 +1,30 @@
+//! Writes files so that readers, including a server restarting after a crash, see either the
+//! previous contents or the new ones.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The temporary file `path` is written to before being renamed.
+///
+/// Its extension ends in `.tmp`, so directory listings looking for a given extension skip it.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Replaces the contents of `path` atomically, syncing them to disk before the rename.
+pub fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_through(path, &temp_path(path), contents)
+}
+
+/// Like [`write`], with the temporary file at `tmp`, which must be on the same filesystem.
+///
+/// For directories whose every file is read, where a temporary file left by a crash would be
+/// taken for a real one.
+pub fn write_through(path: &Path, tmp: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(tmp, contents)?;
+    fs::File::open(tmp).and_then(|f| f.sync_all())?;
+    fs::rename(tmp, path)
+}
@@ -1,15 +1,61 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
+use crate::atomic_file;
 use crate::prelude::*;
+use crate::session::SessionManager;
+use crate::session_proto::{self, AddPublicKeyRequest, PublicKeyList, RemovePublicKeyRequest};
 use ring::{
     digest::{digest, SHA256},
     signature,
 };
-use tonic::{metadata::MetadataMap, Status};
+use tonic::{metadata::MetadataMap, Request, Response, Status};
 use x509_parser::prelude::Pem;
 
 pub type PubKey = Vec<u8>;
 
+/// The kinds of keys, each stored in its own subdirectory of the public keys directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    Owner,
+    User,
+}
+
+impl KeyRole {
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            KeyRole::Owner => "owners",
+            KeyRole::User => "users",
+        }
+    }
+
+    fn from_proto(role: session_proto::KeyRole) -> Self {
+        match role {
+            session_proto::KeyRole::Owner => KeyRole::Owner,
+            session_proto::KeyRole::User => KeyRole::User,
+        }
+    }
+
+    fn to_proto(self) -> session_proto::KeyRole {
+        match self {
+            KeyRole::Owner => session_proto::KeyRole::Owner,
+            KeyRole::User => session_proto::KeyRole::User,
+        }
+    }
+}
+
+/// A key file of a public keys directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEntry {
+    pub role: KeyRole,
+    /// The hash clients identify with.
+    pub hash: String,
+    pub path: PathBuf,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct KeyManagement {
     owners: HashMap<String, PubKey>,
@@ -17,15 +63,17 @@ pub struct KeyManagement {
 }
 
 impl KeyManagement {
-    fn read_from_file(path: &Path) -> Result<(String, PubKey)> {
-        let file = &fs::read(path).with_context(|| anyhow!("Reading file: {path:?}"))?;
-        let (_, Pem { contents, .. }) = x509_parser::pem::parse_x509_pem(&file[..])
-            .with_context(|| anyhow!("Parsing PEM file: {path:?}"))?;
-
+    fn parse_pem(file: &[u8]) -> Result<(String, PubKey)> {
+        let (_, Pem { contents, .. }) = x509_parser::pem::parse_x509_pem(file)?;
         let hash = hex::encode(digest(&SHA256, &contents[..]));
         Ok((hash, contents))
     }
 
+    fn read_from_file(path: &Path) -> Result<(String, PubKey)> {
+        let file = &fs::read(path).with_context(|| anyhow!("Reading file: {path:?}"))?;
+        Self::parse_pem(file).with_context(|| anyhow!("Parsing PEM file: {path:?}"))
+    }
+
     pub fn get_hash_and_keys(dir: fs::ReadDir) -> Result<HashMap<String, PubKey>> {
         let mut res = HashMap::new();
         for entry in dir {
@@ -60,6 +108,92 @@ impl KeyManagement {
         Ok(Self { owners, users })
     }
 
+    /// Lists the keys of a public keys directory, read as [`KeyManagement::load_from_dir`] does.
+    pub fn list_entries(path: &Path) -> Result<Vec<KeyEntry>> {
+        let mut res = Vec::new();
+        for role in [KeyRole::Owner, KeyRole::User] {
+            let dir = path.join(role.dir_name());
+            for entry in fs::read_dir(&dir).with_context(|| anyhow!("Listing {dir:?}"))? {
+                let path = entry.context("Listing files in directory")?.path();
+                let (hash, _) = Self::read_from_file(&path)?;
+                res.push(KeyEntry { role, hash, path });
+            }
+        }
+        res.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(res)
+    }
+
+    /// Adds a PEM public key to a public keys directory, in a file named after its hash.
+    ///
+    /// The file is written atomically, so that a server reloading the directory never reads it
+    /// half-written.
+    pub fn add_key(path: &Path, role: KeyRole, pem: &[u8]) -> Result<KeyEntry> {
+        let (hash, raw) = Self::parse_pem(pem).context("Parsing the PEM key")?;
+        spki::SubjectPublicKeyInfo::try_from(raw.as_ref())
+            .map_err(|e| anyhow!("Invalid SubjectPublicKeyInfo: {e}"))?;
+        if let Some(existing) = Self::list_entries(path)?
+            .into_iter()
+            .find(|entry| entry.hash == hash)
+        {
+            bail!("Key {hash} is already registered at {:?}", existing.path);
+        }
+
+        let dest = path.join(role.dir_name()).join(format!("{hash}.pem"));
+        // The temporary file is kept out of the key directories, where every file is loaded.
+        let tmp = path.join(format!(".{hash}.pem.tmp"));
+        atomic_file::write_through(&dest, &tmp, pem)
+            .with_context(|| anyhow!("Writing {dest:?}"))?;
+        Ok(KeyEntry {
+            role,
+            hash,
+            path: dest,
+        })
+    }
+
+    /// Removes the key with `hash` from a public keys directory.
+    pub fn revoke_key(path: &Path, hash: &str) -> Result<KeyEntry> {
+        let entry = Self::list_entries(path)?
+            .into_iter()
+            .find(|entry| entry.hash == hash)
+            .ok_or_else(|| anyhow!("No key {hash} in {path:?}"))?;
+        fs::remove_file(&entry.path).with_context(|| anyhow!("Removing {:?}", entry.path))?;
+        Ok(entry)
+    }
+
+    /// Adds PEM public key `pem` to the keys in use, returning its hash.
+    pub fn insert(&mut self, role: KeyRole, pem: &[u8]) -> Result<String> {
+        let (hash, raw) = Self::parse_pem(pem).context("Parsing the PEM key")?;
+        match role {
+            KeyRole::Owner => self.owners.insert(hash.clone(), raw),
+            KeyRole::User => self.users.insert(hash.clone(), raw),
+        };
+        Ok(hash)
+    }
+
+    /// Removes the key with `hash` from the keys in use, returning its role if it was one.
+    pub fn remove(&mut self, hash: &str) -> Option<KeyRole> {
+        if self.owners.remove(hash).is_some() {
+            return Some(KeyRole::Owner);
+        }
+        self.users.remove(hash).map(|_| KeyRole::User)
+    }
+
+    /// The hashes of the keys in use with their roles, sorted by hash.
+    pub fn keys(&self) -> Vec<(String, KeyRole)> {
+        let mut keys: Vec<_> = self
+            .owners
+            .keys()
+            .map(|hash| (hash.clone(), KeyRole::Owner))
+            .chain(self.users.keys().map(|hash| (hash.clone(), KeyRole::User)))
+            .collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        keys
+    }
+
+    pub fn owner_count(&self) -> usize {
+        self.owners.len()
+    }
+
     pub fn verify_signature(
         &self,
         public_key_hash: &str,
@@ -118,6 +252,11 @@ impl KeyManagement {
         }
     }
 
+    /// Whether the key is one of the owner or user keys.
+    pub fn contains(&self, public_key_hash: &str) -> bool {
+        self.owners.contains_key(public_key_hash) || self.users.contains_key(public_key_hash)
+    }
+
     pub fn verify_owner(&self, public_key_hash: &str) -> bool {
         /*
             For authentication, we check if the provided public key exists in the list of owner public keys provided at start-up.
@@ -128,3 +267,79 @@ impl KeyManagement {
         return false;
     }
 }
+
+/// Manages the public keys at runtime, see [`SessionManager::add_key`].
+pub struct KeyGrpcService {
+    sess_manager: Arc<SessionManager>,
+}
+
+impl KeyGrpcService {
+    pub fn new(sess_manager: Arc<SessionManager>) -> Self {
+        Self { sess_manager }
+    }
+
+    fn verify_owner<T>(&self, request: &Request<T>) -> Result<String, Status> {
+        let token = self.sess_manager.get_token(request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can manage public keys.",
+            ));
+        }
+        Ok(user_id)
+    }
+}
+
+fn public_key(hash: String, role: KeyRole) -> session_proto::PublicKey {
+    session_proto::PublicKey {
+        hash,
+        role: role.to_proto() as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl session_proto::key_service_server::KeyService for KeyGrpcService {
+    async fn list_public_keys(
+        &self,
+        request: Request<session_proto::Empty>,
+    ) -> Result<Response<PublicKeyList>, Status> {
+        self.verify_owner(&request)?;
+        let keys = self
+            .sess_manager
+            .public_keys()
+            .into_iter()
+            .map(|(hash, role)| public_key(hash, role))
+            .collect();
+        Ok(Response::new(PublicKeyList { keys }))
+    }
+
+    async fn add_public_key(
+        &self,
+        request: Request<AddPublicKeyRequest>,
+    ) -> Result<Response<session_proto::PublicKey>, Status> {
+        let user_id = self.verify_owner(&request)?;
+        let request = request.into_inner();
+        let role = KeyRole::from_proto(request.role());
+        let entry = self.sess_manager.add_key(role, request.pem.as_bytes())?;
+        info!(
+            "Key {} added to the {} by {user_id}",
+            entry.hash,
+            role.dir_name()
+        );
+        Ok(Response::new(public_key(entry.hash, entry.role)))
+    }
+
+    async fn remove_public_key(
+        &self,
+        request: Request<RemovePublicKeyRequest>,
+    ) -> Result<Response<session_proto::PublicKey>, Status> {
+        let user_id = self.verify_owner(&request)?;
+        let entry = self.sess_manager.remove_key(&request.into_inner().hash)?;
+        info!(
+            "Key {} removed from the {} by {user_id}",
+            entry.hash,
+            entry.role.dir_name()
+        );
+        Ok(Response::new(public_key(entry.hash, entry.role)))
+    }
+}
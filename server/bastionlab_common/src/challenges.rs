@@ -0,0 +1,623 @@
+//! Single-use authentication challenges, handed out by `GetChallenge` and spent by the requests
+//! they sign.
+//!
+//! Every authentication handshake goes through the store twice, so it must not serialize them:
+//! issued challenges are spread over [`SHARDS`] shards by their first byte, which is uniformly
+//! random, and each shard has its own lock. Challenges are drawn from per-shard batches of
+//! [`BATCH`] challenges, filled by a single call to the system RNG, and handed out round-robin.
+//! Entries and batches are fixed-size arrays: the hot path only allocates when a shard grows.
+//!
+//! Challenges are bound to who requested them, see [`Holder`]: the key hash the request states,
+//! or else its connection. Only a request signed by that key, or received on that connection,
+//! spends the challenge, so that challenges requested by one client cannot be used to sign as
+//! another. Requests of another identity leave the challenge outstanding for its holder.
+//!
+//! Challenges that are never spent expire after a TTL, so that clients cannot grow the store
+//! without limit. Expired challenges are evicted when a spend locks their shard, at most once per
+//! TTL and shard, and by [`ChallengeStore::evict_expired`], which servers call periodically.
+//!
+//! Since challenges are requested before any authentication, each holder has at most
+//! `max_per_holder` challenges outstanding, so that a client requesting challenges it never spends
+//! only exhausts its own quota. At most `max_outstanding` challenges are outstanding in all, as a
+//! backstop against clients that open many connections. Past either limit, expired challenges are
+//! evicted and, if that does not free any for the holder, no challenge is issued to it until some
+//! are spent or expire.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tonic::Status;
+
+use crate::connections::ConnectionId;
+
+pub const CHALLENGE_LEN: usize = 32;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+pub const DEFAULT_MAX_OUTSTANDING: usize = 100_000;
+
+pub const DEFAULT_MAX_PER_HOLDER: usize = 100;
+
+/// Number of shards, a power of two.
+pub const SHARDS: usize = 16;
+
+/// Number of challenges generated at once.
+pub const BATCH: usize = 64;
+
+pub type Challenge = [u8; CHALLENGE_LEN];
+
+/// Who a challenge was issued to, and who alone spends it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Holder {
+    /// The SHA-256 hash of the public key that signs the request spending the challenge.
+    Key([u8; 32]),
+    /// The connection the challenge was requested on, when the request stated no key.
+    Connection(ConnectionId),
+    /// Requests that stated no key and were not received through a
+    /// [`crate::connections::ConnectionManager`].
+    Anyone,
+}
+
+impl Holder {
+    /// The holder of challenges requested for the hex-encoded key hash `key_hash`, if not
+    /// empty, on `connection`.
+    pub fn new(key_hash: &str, connection: Option<ConnectionId>) -> Result<Self, Status> {
+        if key_hash.is_empty() {
+            return Ok(connection.map_or(Holder::Anyone, Holder::Connection));
+        }
+        let mut key = [0; 32];
+        hex::decode_to_slice(key_hash, &mut key).map_err(|_| {
+            Status::invalid_argument(format!(
+                "Invalid public key hash {key_hash:?}: expected 64 hexadecimal digits"
+            ))
+        })?;
+        Ok(Holder::Key(key))
+    }
+
+    /// Whether a request signed by the key of hex-encoded hash `key_hash`, received on
+    /// `connection`, is from the holder.
+    fn holds(&self, key_hash: &str, connection: Option<ConnectionId>) -> bool {
+        match self {
+            Holder::Key(key) => {
+                let mut signer = [0; 32];
+                hex::decode_to_slice(key_hash, &mut signer).is_ok() && signer == *key
+            }
+            Holder::Connection(id) => connection == Some(*id),
+            Holder::Anyone => true,
+        }
+    }
+
+    /// The shard counting the challenges of the holder.
+    fn shard(&self) -> usize {
+        let index = match self {
+            Holder::Key(key) => key[0] as usize,
+            Holder::Connection(id) => id.0 as usize,
+            Holder::Anyone => 0,
+        };
+        index & (SHARDS - 1)
+    }
+}
+
+struct Batch {
+    bytes: [u8; CHALLENGE_LEN * BATCH],
+    /// Index of the next unused challenge, [`BATCH`] once all were used.
+    next: usize,
+}
+
+#[derive(Default)]
+struct Shard {
+    /// Outstanding challenges, with when and to whom they were issued.
+    issued: HashMap<Challenge, (Instant, Holder)>,
+    /// When expired challenges were last evicted from the shard.
+    evicted_at: Option<Instant>,
+}
+
+impl Shard {
+    /// Evicts the challenges issued before `expired`, handing their holders to `evicted`, and
+    /// returns how many.
+    fn evict(&mut self, expired: Instant, now: Instant, mut evicted: impl FnMut(Holder)) -> usize {
+        let before = self.issued.len();
+        self.issued.retain(|_, (issued_at, holder)| {
+            let kept = *issued_at > expired;
+            if !kept {
+                evicted(*holder);
+            }
+            kept
+        });
+        self.evicted_at = Some(now);
+        before - self.issued.len()
+    }
+}
+
+/// What spending a challenge found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spend {
+    Spent,
+    /// Issued longer than the TTL ago.
+    Expired,
+    /// Never issued, already spent, or evicted once expired.
+    Unknown,
+    /// Issued to another identity, for which it stays outstanding.
+    Mismatch,
+}
+
+pub struct ChallengeStore {
+    rng: SystemRandom,
+    issued: [Mutex<Shard>; SHARDS],
+    batches: [Mutex<Batch>; SHARDS],
+    next_batch: AtomicUsize,
+    ttl: Duration,
+    max_outstanding: usize,
+    /// Challenges in all shards, kept apart so that issuing does not lock every shard.
+    outstanding: AtomicUsize,
+    max_per_holder: usize,
+    /// Outstanding challenges of each holder, sharded by holder. Only locked on its own, or
+    /// under the lock of a shard of `issued`.
+    holders: [Mutex<HashMap<Holder, usize>>; SHARDS],
+}
+
+impl Default for ChallengeStore {
+    fn default() -> Self {
+        ChallengeStore::new(DEFAULT_TTL, DEFAULT_MAX_OUTSTANDING, DEFAULT_MAX_PER_HOLDER)
+    }
+}
+
+impl fmt::Debug for ChallengeStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChallengeStore")
+            .field("outstanding", &self.outstanding())
+            .finish()
+    }
+}
+
+fn shard(challenge: &Challenge) -> usize {
+    challenge[0] as usize & (SHARDS - 1)
+}
+
+impl ChallengeStore {
+    pub fn new(ttl: Duration, max_outstanding: usize, max_per_holder: usize) -> Self {
+        ChallengeStore {
+            rng: SystemRandom::new(),
+            issued: Default::default(),
+            batches: std::array::from_fn(|_| {
+                Mutex::new(Batch {
+                    bytes: [0; CHALLENGE_LEN * BATCH],
+                    next: BATCH,
+                })
+            }),
+            next_batch: AtomicUsize::new(0),
+            ttl,
+            max_outstanding: max_outstanding.max(1),
+            outstanding: AtomicUsize::new(0),
+            max_per_holder: max_per_holder.max(1),
+            holders: Default::default(),
+        }
+    }
+
+    /// Issues a new challenge to `holder`, distinct from every outstanding one.
+    pub fn issue(&self, holder: Holder) -> Result<Challenge, Status> {
+        self.issue_at(holder, Instant::now())
+    }
+
+    fn issue_at(&self, holder: Holder, now: Instant) -> Result<Challenge, Status> {
+        if self.outstanding.load(Ordering::Relaxed) >= self.max_outstanding
+            && self.evict_expired_at(now) == 0
+        {
+            return Err(Status::resource_exhausted(format!(
+                "Too many outstanding challenges ({}), try again later",
+                self.max_outstanding
+            )));
+        }
+        if !self.reserve(holder) && (self.evict_expired_at(now) == 0 || !self.reserve(holder)) {
+            return Err(Status::resource_exhausted(format!(
+                "Too many outstanding challenges for this key or connection ({}), spend them or \
+                 try again later",
+                self.max_per_holder
+            )));
+        }
+        loop {
+            let challenge = self.generate();
+            let mut shard = self.issued[shard(&challenge)]
+                .lock()
+                .expect("Poisoned lock");
+            if let std::collections::hash_map::Entry::Vacant(entry) = shard.issued.entry(challenge)
+            {
+                entry.insert((now, holder));
+                self.outstanding.fetch_add(1, Ordering::Relaxed);
+                return Ok(challenge);
+            }
+        }
+    }
+
+    /// Counts a new challenge of `holder`, unless it already has `max_per_holder`.
+    fn reserve(&self, holder: Holder) -> bool {
+        let mut holders = self.holders[holder.shard()].lock().expect("Poisoned lock");
+        let count = holders.entry(holder).or_default();
+        if *count >= self.max_per_holder {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Uncounts `n` challenges of `holder`, that were spent or evicted.
+    fn release(&self, holder: Holder, n: usize) {
+        let mut holders = self.holders[holder.shard()].lock().expect("Poisoned lock");
+        if let Some(count) = holders.get_mut(&holder) {
+            *count = count.saturating_sub(n);
+            if *count == 0 {
+                holders.remove(&holder);
+            }
+        }
+    }
+
+    /// Random bytes from the challenge batches, that are not issued as a challenge, such as
+    /// session tokens.
+    pub fn generate(&self) -> Challenge {
+        let index = self.next_batch.fetch_add(1, Ordering::Relaxed) & (SHARDS - 1);
+        let mut batch = self.batches[index].lock().expect("Poisoned lock");
+        if batch.next == BATCH {
+            // The RNG only fails if the OS cannot provide randomness yet, retry until it can.
+            while self.rng.fill(&mut batch.bytes).is_err() {}
+            batch.next = 0;
+        }
+        let start = batch.next * CHALLENGE_LEN;
+        let mut challenge = [0; CHALLENGE_LEN];
+        challenge.copy_from_slice(&batch.bytes[start..start + CHALLENGE_LEN]);
+        // Handed out challenges are not kept around in the batch.
+        batch.bytes[start..start + CHALLENGE_LEN].fill(0);
+        batch.next += 1;
+        challenge
+    }
+
+    /// Spends `challenge` for a request signed by the key of hex-encoded hash `key_hash`,
+    /// received on `connection`: only one caller spends an outstanding challenge, and only if
+    /// it is its holder.
+    pub fn spend(
+        &self,
+        challenge: &[u8],
+        key_hash: &str,
+        connection: Option<ConnectionId>,
+    ) -> Spend {
+        self.spend_at(challenge, key_hash, connection, Instant::now())
+    }
+
+    fn spend_at(
+        &self,
+        challenge: &[u8],
+        key_hash: &str,
+        connection: Option<ConnectionId>,
+        now: Instant,
+    ) -> Spend {
+        let challenge: &Challenge = match challenge.try_into() {
+            Ok(challenge) => challenge,
+            Err(_) => return Spend::Unknown,
+        };
+        let mut shard = self.issued[shard(challenge)].lock().expect("Poisoned lock");
+        let spend = match shard.issued.get(challenge) {
+            Some((issued_at, _)) if now.saturating_duration_since(*issued_at) > self.ttl => {
+                Spend::Expired
+            }
+            Some((_, holder)) if !holder.holds(key_hash, connection) => Spend::Mismatch,
+            Some(_) => Spend::Spent,
+            None => Spend::Unknown,
+        };
+        let removed_spent = matches!(spend, Spend::Expired | Spend::Spent);
+        if removed_spent {
+            if let Some((_, holder)) = shard.issued.remove(challenge) {
+                self.release(holder, 1);
+            }
+        }
+        let mut removed = usize::from(removed_spent);
+        let expired = now.checked_sub(self.ttl);
+        let due = shard.evicted_at.map_or(true, |evicted_at| {
+            now.saturating_duration_since(evicted_at) > self.ttl
+        });
+        if let (Some(expired), true) = (expired, due) {
+            removed += shard.evict(expired, now, |holder| self.release(holder, 1));
+        }
+        self.outstanding.fetch_sub(removed, Ordering::Relaxed);
+        spend
+    }
+
+    /// Evicts the expired challenges, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        self.evict_expired_at(Instant::now())
+    }
+
+    fn evict_expired_at(&self, now: Instant) -> usize {
+        let expired = match now.checked_sub(self.ttl) {
+            Some(expired) => expired,
+            None => return 0,
+        };
+        let evicted = self
+            .issued
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .expect("Poisoned lock")
+                    .evict(expired, now, |holder| self.release(holder, 1))
+            })
+            .sum();
+        self.outstanding.fetch_sub(evicted, Ordering::Relaxed);
+        evicted
+    }
+
+    /// Revokes the outstanding challenges issued to the key of hex-encoded hash `key_hash`, such as
+    /// a removed key, returning how many.
+    pub fn revoke(&self, key_hash: &str) -> usize {
+        let holder = match Holder::new(key_hash, None) {
+            Ok(holder @ Holder::Key(_)) => holder,
+            _ => return 0,
+        };
+        let revoked = self
+            .issued
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock().expect("Poisoned lock");
+                let before = shard.issued.len();
+                shard
+                    .issued
+                    .retain(|_, (_, issued_to)| *issued_to != holder);
+                before - shard.issued.len()
+            })
+            .sum();
+        self.outstanding.fetch_sub(revoked, Ordering::Relaxed);
+        self.release(holder, revoked);
+        revoked
+    }
+
+    /// Number of challenges issued and not spent yet, expired ones that were not evicted yet
+    /// included.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Instant;
+
+    const THREADS: usize = 8;
+
+    fn in_parallel<T: Send + 'static>(
+        threads: usize,
+        f: impl Fn(usize) -> T + Send + Sync + 'static,
+    ) -> Vec<T> {
+        let f = Arc::new(f);
+        let barrier = Arc::new(Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let (f, barrier) = (Arc::clone(&f), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    f(i)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn challenges_are_single_use() {
+        let store = ChallengeStore::default();
+        let challenge = store.issue(Holder::Anyone).unwrap();
+        assert_eq!(store.outstanding(), 1);
+        assert_eq!(store.spend(&[0; CHALLENGE_LEN], "", None), Spend::Unknown);
+        assert_eq!(store.spend(&challenge[1..], "", None), Spend::Unknown);
+        assert_eq!(store.spend(&challenge, "", None), Spend::Spent);
+        assert_eq!(store.spend(&challenge, "", None), Spend::Unknown);
+        assert_eq!(store.outstanding(), 0);
+    }
+
+    #[test]
+    fn challenges_are_spent_by_their_holder_only() {
+        let store = ChallengeStore::default();
+        let (alice, mallory) = ("a1".repeat(32), "b2".repeat(32));
+        assert!(Holder::new("a1", None).is_err());
+        let holder = Holder::new(&alice.to_uppercase(), Some(ConnectionId(1))).unwrap();
+        let challenge = store.issue(holder).unwrap();
+        assert_eq!(
+            store.spend(&challenge, &mallory, Some(ConnectionId(1))),
+            Spend::Mismatch
+        );
+        assert_eq!(store.spend(&challenge, "", None), Spend::Mismatch);
+        // The challenge stays outstanding for its holder, on any connection.
+        assert_eq!(store.outstanding(), 1);
+        assert_eq!(
+            store.spend(&challenge, &alice, Some(ConnectionId(2))),
+            Spend::Spent
+        );
+
+        // Challenges of revoked keys cannot be spent anymore.
+        let revoked = store.issue(Holder::new(&alice, None).unwrap()).unwrap();
+        let kept = store.issue(Holder::new(&mallory, None).unwrap()).unwrap();
+        assert_eq!(store.revoke(&alice.to_uppercase()), 1);
+        assert_eq!(store.spend(&revoked, &alice, None), Spend::Unknown);
+        assert_eq!(store.spend(&kept, &mallory, None), Spend::Spent);
+
+        // Without a key, challenges are bound to their connection.
+        let holder = Holder::new("", Some(ConnectionId(1))).unwrap();
+        let challenge = store.issue(holder).unwrap();
+        assert_eq!(
+            store.spend(&challenge, &alice, Some(ConnectionId(2))),
+            Spend::Mismatch
+        );
+        assert_eq!(
+            store.spend(&challenge, &mallory, Some(ConnectionId(1))),
+            Spend::Spent
+        );
+        assert_eq!(store.outstanding(), 0);
+    }
+
+    #[test]
+    fn challenges_expire_after_the_ttl() {
+        let ttl = Duration::from_secs(60);
+        let store = ChallengeStore::new(ttl, 3, 3);
+        let start = Instant::now();
+        let old = store.issue_at(Holder::Anyone, start).unwrap();
+        let recent = store.issue_at(Holder::Anyone, start + ttl / 2).unwrap();
+        let later = start + ttl + Duration::from_secs(1);
+        assert_eq!(store.spend_at(&old, "", None, later), Spend::Expired);
+        assert_eq!(store.spend_at(&old, "", None, later), Spend::Unknown);
+        assert_eq!(store.spend_at(&recent, "", None, later), Spend::Spent);
+
+        // Full stores evict expired challenges to issue new ones, and refuse to otherwise.
+        let unspent: Vec<_> = (0..3)
+            .map(|_| store.issue_at(Holder::Anyone, start).unwrap())
+            .collect();
+        assert_eq!(store.outstanding(), 3);
+        let err = store.issue_at(Holder::Anyone, start).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert!(store.issue_at(Holder::Anyone, later).is_ok());
+        assert_eq!(store.outstanding(), 1);
+        assert_eq!(store.spend_at(&unspent[0], "", None, later), Spend::Unknown);
+        assert_eq!(store.evict_expired_at(later + ttl * 2), 1);
+        assert_eq!(store.outstanding(), 0);
+    }
+
+    /// A store in which a single holder gets as many challenges as the store holds.
+    fn uncapped() -> ChallengeStore {
+        ChallengeStore::new(
+            DEFAULT_TTL,
+            DEFAULT_MAX_OUTSTANDING,
+            DEFAULT_MAX_OUTSTANDING,
+        )
+    }
+
+    #[test]
+    fn holders_have_their_own_quota() {
+        let store = ChallengeStore::new(DEFAULT_TTL, 5, 2);
+        let (alice, bob) = (
+            Holder::Connection(ConnectionId(1)),
+            Holder::Connection(ConnectionId(2)),
+        );
+        let issued: Vec<_> = (0..2).map(|_| store.issue(alice).unwrap()).collect();
+        let err = store.issue(alice).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert!(err.message().contains("this key or connection"), "{err:?}");
+
+        // Alice exhausting her quota leaves Bob's untouched.
+        for _ in 0..2 {
+            store.issue(bob).unwrap();
+        }
+        assert!(store.issue(bob).is_err());
+
+        // Spent and expired challenges give their holder its quota back.
+        assert_eq!(
+            store.spend(&issued[0], "", Some(ConnectionId(1))),
+            Spend::Spent
+        );
+        store.issue(alice).unwrap();
+        let later = Instant::now() + DEFAULT_TTL * 2;
+        store.issue_at(bob, later).unwrap();
+        assert_eq!(store.outstanding(), 1);
+
+        // Past the global backstop, no holder gets challenges.
+        for id in 3..7 {
+            store
+                .issue_at(Holder::Connection(ConnectionId(id)), later)
+                .unwrap();
+        }
+        let err = store
+            .issue_at(Holder::Connection(ConnectionId(7)), later)
+            .unwrap_err();
+        assert!(err.message().contains("(5)"), "{err:?}");
+    }
+
+    #[test]
+    fn parallel_challenges_are_unique() {
+        let store = Arc::new(uncapped());
+        let per_thread = BATCH * SHARDS;
+        let issued = {
+            let store = Arc::clone(&store);
+            in_parallel(THREADS, move |_| {
+                (0..per_thread)
+                    .map(|_| store.issue(Holder::Anyone).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let unique: HashSet<_> = issued.into_iter().flatten().collect();
+        assert_eq!(unique.len(), THREADS * per_thread);
+        assert_eq!(store.outstanding(), THREADS * per_thread);
+    }
+
+    #[test]
+    fn racing_spends_succeed_once() {
+        let store = Arc::new(uncapped());
+        let challenges: Arc<Vec<_>> = Arc::new(
+            (0..1000)
+                .map(|_| store.issue(Holder::Anyone).unwrap())
+                .collect(),
+        );
+        let spent = {
+            let (store, challenges) = (Arc::clone(&store), Arc::clone(&challenges));
+            in_parallel(THREADS, move |_| {
+                challenges
+                    .iter()
+                    .filter(|c| store.spend(&c[..], "", None) == Spend::Spent)
+                    .count()
+            })
+        };
+        assert_eq!(spent.iter().sum::<usize>(), challenges.len());
+        assert_eq!(store.outstanding(), 0);
+    }
+
+    /// The store this one replaced: a single lock, and a call to the RNG per challenge.
+    #[derive(Default)]
+    struct SingleLock(Mutex<HashSet<Challenge>>);
+
+    impl SingleLock {
+        fn issue(&self) -> Challenge {
+            let challenge: Challenge = ring::rand::generate(&SystemRandom::new()).unwrap().expose();
+            self.0.lock().unwrap().insert(challenge);
+            challenge
+        }
+
+        fn spend(&self, challenge: &[u8]) -> bool {
+            self.0.lock().unwrap().remove(challenge)
+        }
+    }
+
+    /// Runs handshakes on every thread, passing `issue_and_spend` the index of the thread.
+    fn handshakes_per_sec(issue_and_spend: impl Fn(usize) + Send + Sync + 'static) -> f64 {
+        const HANDSHAKES: usize = 100_000;
+        let start = Instant::now();
+        in_parallel(THREADS, move |i| {
+            (0..HANDSHAKES).for_each(|_| issue_and_spend(i))
+        });
+        (THREADS * HANDSHAKES) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    /// Run with `cargo test --release -p bastionlab_common -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_handshakes() {
+        let single = Arc::new(SingleLock::default());
+        let baseline = handshakes_per_sec(move |_| {
+            let challenge = single.issue();
+            assert!(single.spend(&challenge));
+        });
+        let store = Arc::new(ChallengeStore::default());
+        let sharded = handshakes_per_sec(move |i| {
+            let connection = Some(ConnectionId(i as u64));
+            let challenge = store
+                .issue(Holder::Connection(connection.unwrap()))
+                .unwrap();
+            assert_eq!(store.spend(&challenge, "", connection), Spend::Spent);
+        });
+        println!(
+            "{THREADS} threads: single lock {baseline:.0}/s, sharded {sharded:.0}/s ({:.1}x)",
+            sharded / baseline
+        );
+    }
+}
@@ -12,20 +12,514 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use http::Uri;
-use serde::{de::Error, Deserialize, Deserializer};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Deserialize, Clone, Debug)]
+/// Most bytes of data per streamed chunk: with their framing, chunks fit in the 4 MiB messages
+/// gRPC clients accept by default.
+pub const MAX_CHUNK_BYTES: usize = 4_194_285;
+
+/// The configuration of the server, read from config.toml by [`crate::config_check::load_file`],
+/// which checks it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct BastionLabConfig {
     //  Connection for Client -> Enclave communication
-    #[serde(deserialize_with = "deserialize_uri")]
+    #[serde(deserialize_with = "deserialize_uri", serialize_with = "serialize_uri")]
     pub client_to_enclave_untrusted_url: Uri,
 
     pub public_keys_directory: String,
     pub session_expiry_in_secs: u64,
+
+    /// Maximum number of queries executing at the same time.
+    #[serde(default = "default_query_concurrency")]
+    pub query_concurrency: usize,
+    /// Maximum number of queries waiting for an execution slot.
+    #[serde(default = "default_query_queue_depth")]
+    pub query_queue_depth: usize,
+    /// Share of the execution slots batch queries may use.
+    #[serde(default = "default_batch_query_share")]
+    pub batch_query_share: f64,
+
+    /// How long queries are remembered by the probing detector.
+    #[serde(default = "default_probing_window_secs")]
+    pub probing_window_secs: u64,
+    /// Number of queries differing only in one literal that are flagged as probing.
+    #[serde(default = "default_probing_literal_variants")]
+    pub probing_literal_variants: usize,
+    /// Number of pairs of counts differing by exactly 1 that are flagged as probing (0 disables this check).
+    #[serde(default = "default_probing_count_pairs")]
+    pub probing_count_pairs: usize,
+    /// Maximum number of queries remembered per identity and dataset.
+    #[serde(default = "default_probing_history")]
+    pub probing_history: usize,
+
+    /// zstd level of persisted dataframes (0 disables compression).
+    #[serde(default = "default_persistence_zstd_level")]
+    pub persistence_zstd_level: i32,
+    /// Persisted Utf8 columns whose distinct/total ratio is at most this value are dictionary-encoded.
+    #[serde(default = "default_persistence_dictionary_ratio")]
+    pub persistence_dictionary_ratio: f64,
+
+    /// Whether uploaded columns with an empty or whitespace-only name are rejected or renamed.
+    #[serde(default)]
+    pub blank_column_names: BlankColumnNames,
+
+    /// Connections older than this are asked to reconnect (0 disables this limit).
+    #[serde(default)]
+    pub connection_max_age_secs: u64,
+    /// Connections without any request in flight for this long are asked to reconnect (0 disables
+    /// this limit).
+    #[serde(default)]
+    pub connection_max_idle_secs: u64,
+
+    /// Port Prometheus metrics are served on over HTTP, at `/metrics`, on the host of
+    /// `client_to_enclave_untrusted_url` (0 disables them).
+    #[serde(default)]
+    pub metrics_port: u16,
+
+    /// Certificate served over TLS, overridden by the `BASTIONLAB_TLS_CERT` environment variable.
+    /// The server falls back to plaintext when it or its key is missing.
+    #[serde(default = "default_tls_cert_file")]
+    pub tls_cert_file: String,
+
+    /// Private key of the TLS certificate, overridden by `BASTIONLAB_TLS_KEY`.
+    #[serde(default = "default_tls_key_file")]
+    pub tls_key_file: String,
+
+    /// CA certificates clients must present a certificate issued by, overridden by
+    /// `BASTIONLAB_TLS_CLIENT_CA` (empty does not require client certificates).
+    #[serde(default)]
+    pub tls_client_ca_file: String,
+
+    /// How often the TLS certificate and the public keys are checked for changes (0 disables
+    /// reloading them).
+    #[serde(default = "default_credentials_reload_secs")]
+    pub credentials_reload_secs: u64,
+
+    /// Fetch streams re-check that the recipient may still receive the data every this many
+    /// chunks (0 disables these checks).
+    #[serde(default = "default_fetch_checkpoint_chunks")]
+    pub fetch_checkpoint_chunks: usize,
+
+    /// Whether uploaded dataframes can be queried right away, as before onboarding states, instead
+    /// of starting as drafts that must be reviewed.
+    #[serde(default = "default_publish_uploads")]
+    pub publish_uploads: bool,
+    /// Hashes of the public keys that review drafts submitted for publication. Data owners review
+    /// them if empty.
+    #[serde(default)]
+    pub dataset_reviewers: Vec<String>,
+
+    /// How long the identifiers of replaced dataframes keep resolving to the dataframe that
+    /// replaced them (0 keeps them forever).
+    #[serde(default = "default_alias_retention_secs")]
+    pub alias_retention_secs: u64,
+
+    /// Memory usage, in megabytes, above which idle results are deleted and uploads are rejected
+    /// (0 disables the memory watchdog).
+    #[serde(default)]
+    pub memory_soft_watermark_mb: u64,
+    /// Memory usage, in megabytes, above which queries are rejected too and savable dataframes
+    /// are persisted (0 disables these responses). It is raised to the soft watermark if below.
+    #[serde(default)]
+    pub memory_hard_watermark_mb: u64,
+    /// How far below a watermark memory usage must go, in megabytes, before its responses stop.
+    #[serde(default = "default_memory_recovery_mb")]
+    pub memory_recovery_mb: u64,
+    /// How often memory usage is sampled.
+    #[serde(default = "default_memory_sample_secs")]
+    pub memory_sample_secs: u64,
+    /// Results created longer ago than this are deleted under memory pressure.
+    #[serde(default = "default_memory_idle_result_secs")]
+    pub memory_idle_result_secs: u64,
+    /// Results unused for longer than this are deleted, unless something depends on them (0 keeps
+    /// them), see `bastionlab_polars::expiry`.
+    #[serde(default)]
+    pub dataframe_ttl_secs: u64,
+    /// How often results are checked for expiry.
+    #[serde(default = "default_dataframe_ttl_sweep_secs")]
+    pub dataframe_ttl_sweep_secs: u64,
+
+    /// Total size, in megabytes, of the dataframes kept in memory as hot (0 for no limit), see
+    /// `bastionlab_polars::storage_classes`.
+    #[serde(default)]
+    pub storage_hot_budget_mb: u64,
+    /// Accesses per window from which dataframes whose class was not set are hot (0 disables
+    /// automatic promotion).
+    #[serde(default)]
+    pub storage_hot_accesses: u32,
+    /// Size, in megabytes, from which dataframes whose class was not set are cold when accessed
+    /// at most `storage_cold_accesses` times per window (0 disables automatic demotion).
+    #[serde(default)]
+    pub storage_cold_min_mb: u64,
+    #[serde(default)]
+    pub storage_cold_accesses: u32,
+    /// Length of the windows accesses are counted over.
+    #[serde(default = "default_storage_access_window_secs")]
+    pub storage_access_window_secs: u64,
+    /// How long cold dataframes stay in memory after they were last read.
+    #[serde(default = "default_storage_cold_residency_secs")]
+    pub storage_cold_residency_secs: u64,
+
+    /// Whether persisted dataframes are encrypted under a master key per tenant, wrapped by the
+    /// private key of the server unless provided by the KMS.
+    #[serde(default)]
+    pub tenant_encryption: bool,
+    /// Files of 32 hex-encoded bytes holding the master keys the KMS provides, by tenant.
+    #[serde(default)]
+    pub tenant_key_files: HashMap<String, String>,
+
+    /// Memory limit, in megabytes, of the plan segments that do not set one (0 for none).
+    #[serde(default)]
+    pub segment_max_memory_mb: u64,
+    /// Whether the inputs of plan segments above their memory limit are spilled to disk, unless
+    /// the segments say otherwise.
+    #[serde(default)]
+    pub segment_allow_spill: bool,
+    /// Where queries spill segment inputs, the system temporary directory if empty.
+    #[serde(default)]
+    pub spill_dir: String,
+    /// Megabytes each query may spill to disk.
+    #[serde(default = "default_spill_quota_mb")]
+    pub spill_quota_mb: u64,
+
+    /// Runs a checkpointed plan job may start, restarts included, before it is given up.
+    #[serde(default = "default_plan_job_max_attempts")]
+    pub plan_job_max_attempts: u32,
+    /// Checkpoints of plan jobs older than this are not resumed on restart, and removed.
+    #[serde(default = "default_plan_checkpoint_expiry_secs")]
+    pub plan_checkpoint_expiry_secs: u64,
+    /// On shutdown, how long requests in flight, such as fetch streams, are waited for to finish,
+    /// then how long checkpointed plan jobs are waited for to reach a segment boundary.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+
+    /// PEM-encoded PKCS#8 ECDSA P-256 key reproducibility bundles are signed with. A key is
+    /// generated on startup if empty, and bundles cannot be verified after a restart.
+    #[serde(default)]
+    pub bundle_signing_key_file: String,
+
+    /// File of 32 hex-encoded bytes holding the secret fetches are watermarked with. Only with it
+    /// are watermarked fetches persisted, and traced after a restart: a secret is generated on
+    /// startup otherwise, and never persisted.
+    #[serde(default)]
+    pub watermark_secret_file: String,
+    /// Number of watermarked fetches kept to trace leaks back to, oldest first to go.
+    #[serde(default = "default_watermark_record_capacity")]
+    pub watermark_record_capacity: usize,
+
+    /// Number of accesses kept in the access log, and of decisions kept in the decision log of
+    /// the policy engine, oldest first to go.
+    #[serde(default = "default_access_log_capacity")]
+    pub access_log_capacity: usize,
+
+    /// File the audit log is appended to, as JSON lines. The last `access_log_capacity` entries are
+    /// kept in memory instead if empty.
+    #[serde(default)]
+    pub audit_log_file: String,
+    /// Deny whatever no rule of the policies allows: mismatches of safe zones are denied whatever
+    /// the unsafe handling of the policies says.
+    #[serde(default)]
+    pub strict_governance: bool,
+
+    /// Number of recent queries each dataframe keeps for its owner, newest last, see
+    /// `bastionlab_polars::activity`.
+    #[serde(default = "default_recent_activity_entries")]
+    pub recent_activity_entries: usize,
+    /// Age after which recent queries are forgotten.
+    #[serde(default = "default_recent_activity_max_age_secs")]
+    pub recent_activity_max_age_secs: u64,
+
+    /// Honor the fault schedules of chaos tests on upload and fetch streams. Dev builds only:
+    /// release builds ignore it.
+    #[serde(default)]
+    pub fault_injection: bool,
+
+    /// In embedded mode, the share of the store file that deleted and replaced dataframes may
+    /// take before it is compacted.
+    #[serde(default = "default_embedded_compaction_ratio")]
+    pub embedded_compaction_ratio: f64,
+
+    /// How long a query waits for the remote server of a federated dataframe to answer its
+    /// sub-plan, fetch included, before failing as unavailable.
+    #[serde(default = "default_federation_timeout_secs")]
+    pub federation_timeout_secs: u64,
+
+    /// Whether composite plans may hold unknown hints and traces, as written by newer SDKs, which
+    /// are then ignored. Unknown segments and other unknown fields are rejected either way.
+    #[serde(default)]
+    pub plan_compatibility_mode: bool,
+
+    /// The largest number of cells (rows times columns) of the literal frames composite plans
+    /// may hold inline.
+    #[serde(default = "default_literal_frame_max_cells")]
+    pub literal_frame_max_cells: usize,
+
+    /// How long fetches pending the data owner's approval wait for a decision before they are
+    /// rejected.
+    #[serde(default = "default_approval_timeout_secs")]
+    pub approval_timeout_secs: u64,
+
+    /// The largest number of rows head and slice segments of composite plans may keep.
+    #[serde(default = "default_slice_max_rows")]
+    pub slice_max_rows: usize,
+
+    /// How long policy rollouts can be rolled back after they were applied.
+    #[serde(default = "default_policy_rollout_retention_secs")]
+    pub policy_rollout_retention_secs: u64,
+
+    /// How far from the server clock the timestamps of signed requests may be, see
+    /// `bastionlab_common::replay`.
+    #[serde(default = "default_signature_max_skew_secs")]
+    pub signature_max_skew_secs: u64,
+    /// How often the last nonces of signed requests are persisted at most.
+    #[serde(default = "default_replay_persist_interval_secs")]
+    pub replay_persist_interval_secs: u64,
+    /// Where the last nonces of signed requests are persisted.
+    #[serde(default = "default_replay_state_file")]
+    pub replay_state_file: String,
+    /// How long authentication challenges can be spent after they were issued, see
+    /// `bastionlab_common::challenges`.
+    #[serde(default = "default_challenge_ttl_secs")]
+    pub challenge_ttl_secs: u64,
+    /// Most challenges issued and not spent yet at once, in all.
+    #[serde(default = "default_max_outstanding_challenges")]
+    pub max_outstanding_challenges: usize,
+    /// Most challenges issued and not spent yet at once to the same key, or connection for
+    /// requests that state no key.
+    #[serde(default = "default_max_challenges_per_holder")]
+    pub max_challenges_per_holder: usize,
+
+    /// Largest upload, in megabytes of chunk data, larger ones are rejected (0 for no limit).
+    /// Clients learn it from the handshake, see `bastionlab_polars::handshake`.
+    #[serde(default)]
+    pub max_upload_mb: u64,
+    /// Kilobytes of data clients are asked to send per upload chunk.
+    #[serde(default = "default_upload_chunk_kb")]
+    pub upload_chunk_kb: u64,
+    /// Kilobytes of data per chunk of fetched dataframes, unless fetches ask for another size.
+    /// At most [`MAX_CHUNK_BYTES`].
+    #[serde(default = "default_fetch_chunk_kb")]
+    pub fetch_chunk_kb: u64,
+    /// Sunset dates, e.g. `2027-06-30`, of the deprecated client features, by feature. Clients
+    /// asking for these features are told in the handshake.
+    #[serde(default)]
+    pub deprecated_features: HashMap<String, String>,
+
+    /// Largest number of queries of a query batch, see `bastionlab_polars::batches`.
+    #[serde(default = "default_max_queries_per_batch")]
+    pub max_queries_per_batch: usize,
+    /// Queries of a batch run at most this many at a time.
+    #[serde(default = "default_query_batch_parallelism")]
+    pub query_batch_parallelism: usize,
+    /// Results of batched queries up to this many kilobytes are sent with their reference, when
+    /// asked for (0 to never send them).
+    #[serde(default = "default_inline_result_max_kb")]
+    pub inline_result_max_kb: u64,
+
+    /// Settings of extensions, which the server does not read. Other unknown keys are errors.
+    #[serde(default)]
+    pub extensions: toml::value::Table,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlankColumnNames {
+    #[default]
+    Reject,
+    /// Renamed to `column_<index>`.
+    Rename,
+}
+
+fn default_query_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
+fn default_query_queue_depth() -> usize {
+    256
+}
+
+fn default_batch_query_share() -> f64 {
+    0.5
+}
+
+fn default_probing_window_secs() -> u64 {
+    600
+}
+
+fn default_probing_literal_variants() -> usize {
+    10
+}
+
+fn default_probing_count_pairs() -> usize {
+    2
+}
+
+fn default_probing_history() -> usize {
+    128
+}
+
+fn default_access_log_capacity() -> usize {
+    100_000
+}
+
+fn default_watermark_record_capacity() -> usize {
+    10_000
+}
+
+fn default_recent_activity_entries() -> usize {
+    50
+}
+
+fn default_recent_activity_max_age_secs() -> u64 {
+    30 * 24 * 3600
+}
+
+fn default_embedded_compaction_ratio() -> f64 {
+    0.5
+}
+
+fn default_federation_timeout_secs() -> u64 {
+    30
+}
+
+fn default_literal_frame_max_cells() -> usize {
+    10_000
+}
+
+fn default_approval_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_slice_max_rows() -> usize {
+    10_000
+}
+
+fn default_policy_rollout_retention_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_signature_max_skew_secs() -> u64 {
+    30
+}
+
+fn default_replay_persist_interval_secs() -> u64 {
+    5
+}
+
+fn default_upload_chunk_kb() -> u64 {
+    32
+}
+
+fn default_fetch_chunk_kb() -> u64 {
+    (MAX_CHUNK_BYTES >> 10) as u64
+}
+
+fn default_max_queries_per_batch() -> usize {
+    64
+}
+
+fn default_query_batch_parallelism() -> usize {
+    4
+}
+
+fn default_inline_result_max_kb() -> u64 {
+    64
+}
+
+fn default_challenge_ttl_secs() -> u64 {
+    300
+}
+
+fn default_max_outstanding_challenges() -> usize {
+    100_000
+}
+
+fn default_max_challenges_per_holder() -> usize {
+    100
+}
+
+fn default_replay_state_file() -> String {
+    String::from("replay_nonces.json")
+}
+
+fn default_persistence_zstd_level() -> i32 {
+    3
+}
+
+fn default_persistence_dictionary_ratio() -> f64 {
+    0.1
+}
+
+fn default_tls_cert_file() -> String {
+    String::from("tls/host_server.pem")
+}
+
+fn default_tls_key_file() -> String {
+    String::from("tls/host_server.key")
+}
+
+fn default_credentials_reload_secs() -> u64 {
+    5
+}
+
+fn default_fetch_checkpoint_chunks() -> usize {
+    32
+}
+
+fn default_publish_uploads() -> bool {
+    true
+}
+
+fn default_alias_retention_secs() -> u64 {
+    90 * 24 * 3600
+}
+
+fn default_memory_recovery_mb() -> u64 {
+    256
+}
+
+fn default_memory_sample_secs() -> u64 {
+    5
+}
+
+fn default_memory_idle_result_secs() -> u64 {
+    600
+}
+
+fn default_dataframe_ttl_sweep_secs() -> u64 {
+    60
+}
+
+fn default_storage_access_window_secs() -> u64 {
+    3600
+}
+
+fn default_storage_cold_residency_secs() -> u64 {
+    60
+}
+
+fn default_plan_job_max_attempts() -> u32 {
+    3
+}
+
+fn default_plan_checkpoint_expiry_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    60
+}
+
+fn default_spill_quota_mb() -> u64 {
+    1024
 }
 
 fn uri_to_socket(uri: &Uri) -> Result<SocketAddr> {
@@ -49,12 +543,45 @@ impl BastionLabConfig {
     pub fn session_expiry(&self) -> Result<u64> {
         Ok(self.session_expiry_in_secs)
     }
+
+    /// The TLS files, after the environment overrides.
+    pub fn tls_files(&self) -> TlsFiles {
+        let file = |var, configured: &String| std::env::var(var).unwrap_or(configured.clone());
+        let client_ca = file("BASTIONLAB_TLS_CLIENT_CA", &self.tls_client_ca_file);
+        TlsFiles {
+            cert: PathBuf::from(file("BASTIONLAB_TLS_CERT", &self.tls_cert_file)),
+            key: PathBuf::from(file("BASTIONLAB_TLS_KEY", &self.tls_key_file)),
+            client_ca: (!client_ca.is_empty()).then(|| PathBuf::from(client_ca)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Client certificates are only required when it is set.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Whether both the certificate and its key exist, TLS is served only then.
+    pub fn present(&self) -> bool {
+        self.cert.exists() && self.key.exists()
+    }
 }
 
 fn deserialize_uri<'de, D>(deserializer: D) -> Result<Uri, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s: &str = Deserialize::deserialize(deserializer)?;
+    let s: String = Deserialize::deserialize(deserializer)?;
     s.parse::<Uri>().map_err(D::Error::custom)
 }
+
+fn serialize_uri<S>(uri: &Uri, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&uri.to_string())
+}
@@ -0,0 +1,806 @@
+//! Loading config.toml with its environment overrides, and checking it before the server starts.
+//!
+//! Every key of [`BastionLabConfig`] can be overridden by an environment variable named after it,
+//! e.g. `BASTIONLAB_QUERY_CONCURRENCY` for `query_concurrency`. Values are read as TOML, except for
+//! string keys whose values are taken as is. The TLS files are also overridden by
+//! `BASTIONLAB_TLS_CERT`, `BASTIONLAB_TLS_KEY` and `BASTIONLAB_TLS_CLIENT_CA`, which win over the
+//! generic variables. Keys can then be overridden on the command line, for which the server has a
+//! few flags. So, by increasing precedence: defaults, config.toml, the environment, the command
+//! line.
+//!
+//! The file is `config.toml` in the working directory, unless another one is named by `--config`
+//! or the `BASTIONLAB_CONFIG` environment variable, see [`config_path`].
+//!
+//! Unknown keys are errors, unless under `[extensions]`, which the server does not read. The
+//! loaded configuration is then checked as a whole, see [`violations`]: every problem is reported
+//! at once, so that a deployment can be fixed in one go rather than one failed start at a time.
+//! `bastionlab config check` runs the same checks without starting the server, and prints the
+//! effective configuration along with where each value came from.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use toml::value::{Table, Value};
+
+use crate::config::{BastionLabConfig, MAX_CHUNK_BYTES};
+
+pub const ENV_PREFIX: &str = "BASTIONLAB_";
+pub const EXTENSIONS: &str = "extensions";
+/// The environment variable naming the configuration file.
+pub const CONFIG_VAR: &str = "BASTIONLAB_CONFIG";
+/// The configuration file when none is named.
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// The environment variables overriding TLS files before the generic ones existed.
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("tls_cert_file", "BASTIONLAB_TLS_CERT"),
+    ("tls_key_file", "BASTIONLAB_TLS_KEY"),
+    ("tls_client_ca_file", "BASTIONLAB_TLS_CLIENT_CA"),
+];
+
+/// The keys without a default, and the placeholder values they are given to learn the defaults
+/// of the others.
+const REQUIRED: &[(&str, &str)] = &[
+    (
+        "client_to_enclave_untrusted_url",
+        "\"https://0.0.0.0:50056\"",
+    ),
+    ("public_keys_directory", "\"keys/\""),
+    ("session_expiry_in_secs", "3600"),
+];
+
+/// Keys whose values are not printed, at any depth, including under `[extensions]`.
+const SECRET_WORDS: &[&str] = &["secret", "token", "password", "credential", "api_key"];
+
+/// Largest zstd compression level.
+const MAX_ZSTD_LEVEL: i32 = 22;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    /// The environment variable it was read from.
+    Env(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "file"),
+            Source::Env(var) => write!(f, "env {var}"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Everything wrong with a configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration, {} problem(s):", self.0.len())?;
+        for error in self.0.iter() {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// A configuration that passed every check, with where each of its values came from.
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: BastionLabConfig,
+    pub sources: BTreeMap<String, Source>,
+}
+
+impl LoadedConfig {
+    /// The effective configuration as TOML, one key per line followed by its source, secrets
+    /// redacted.
+    pub fn render(&self) -> String {
+        let values = match Value::try_from(&self.config) {
+            Ok(Value::Table(values)) => values,
+            _ => return String::new(),
+        };
+        let mut lines = String::new();
+        for (key, value) in values.iter() {
+            let source = self.sources.get(key).unwrap_or(&Source::Default);
+            lines.push_str(&format!(
+                "{} = {}  # {source}\n",
+                render_key(key),
+                render_value(key, value)
+            ));
+        }
+        lines
+    }
+}
+
+/// The keys of the configuration with their default values, required keys having placeholders.
+fn defaults() -> Table {
+    let minimal = minimal();
+    let config: BastionLabConfig = Value::Table(minimal)
+        .try_into()
+        .expect("The required keys are enough to configure the server");
+    match Value::try_from(&config) {
+        Ok(Value::Table(defaults)) => defaults,
+        _ => unreachable!("Configurations serialize to tables"),
+    }
+}
+
+/// A table with only the required keys, set to placeholders.
+fn minimal() -> Table {
+    REQUIRED
+        .iter()
+        .map(|(key, placeholder)| (key.to_string(), parse_toml(placeholder).unwrap()))
+        .collect()
+}
+
+fn parse_toml(raw: &str) -> Result<Value, toml::de::Error> {
+    let mut table: Table = toml::from_str(&format!("value = {raw}"))?;
+    Ok(table.remove("value").unwrap_or(Value::Table(Table::new())))
+}
+
+/// The value of environment variable `var` overriding a key whose default is `default`.
+fn env_value(var: &str, raw: String, default: &Value) -> Result<Value, String> {
+    match default {
+        Value::String(_) => Ok(Value::String(raw)),
+        _ => {
+            parse_toml(&raw).map_err(|e| format!("Invalid {var}: {raw:?} is not a TOML value: {e}"))
+        }
+    }
+}
+
+/// The configuration file: `flag` if given, else the one named by [`CONFIG_VAR`] in `env`, else
+/// [`DEFAULT_CONFIG_FILE`].
+pub fn config_path(flag: Option<&Path>, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+    match (flag, env(CONFIG_VAR)) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(path)) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(DEFAULT_CONFIG_FILE),
+    }
+}
+
+/// Loads `config.toml` from `path`, overridden by the environment of the process and then by
+/// `overrides`, values of keys given on the command line.
+pub fn load_file(path: &Path, overrides: Table) -> Result<LoadedConfig> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Reading {path:?}"))?;
+    Ok(load_overridden(
+        &text,
+        |var| std::env::var(var).ok(),
+        overrides,
+    )?)
+}
+
+/// Loads the configuration `text`, overridden by the variables of `env`, and checks it.
+pub fn load(
+    text: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<LoadedConfig, ConfigErrors> {
+    load_overridden(text, env, Table::new())
+}
+
+/// Like [`load`], the variables of `env` being overridden in turn by `overrides`.
+pub fn load_overridden(
+    text: &str,
+    env: impl Fn(&str) -> Option<String>,
+    overrides: Table,
+) -> Result<LoadedConfig, ConfigErrors> {
+    let mut table: Table = toml::from_str(text)
+        .map_err(|e| ConfigErrors(vec![format!("config.toml is not valid TOML: {e}")]))?;
+    let defaults = defaults();
+    let mut errors = Vec::new();
+    let mut sources: BTreeMap<String, Source> = table
+        .keys()
+        .map(|key| (key.clone(), Source::File))
+        .collect();
+
+    for (key, default) in defaults.iter() {
+        let legacy = LEGACY_ENV
+            .iter()
+            .filter(|(legacy, _)| legacy == key)
+            .map(|(_, var)| var.to_string());
+        // Later variables win.
+        let vars = std::iter::once(format!("{ENV_PREFIX}{}", key.to_uppercase())).chain(legacy);
+        for var in vars {
+            let raw = match env(&var) {
+                Some(raw) => raw,
+                None => continue,
+            };
+            match env_value(&var, raw, default) {
+                Ok(value) => {
+                    table.insert(key.clone(), value);
+                    sources.insert(key.clone(), Source::Env(var));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+
+    for (key, value) in overrides {
+        sources.insert(key.clone(), Source::CommandLine);
+        table.insert(key, value);
+    }
+
+    for key in table.keys() {
+        if !defaults.contains_key(key) {
+            errors.push(format!(
+                "Unknown key `{key}`: the settings of extensions go under [{EXTENSIONS}]"
+            ));
+        }
+    }
+    for (key, _) in REQUIRED {
+        if !table.contains_key(*key) {
+            errors.push(format!("Missing key `{key}`"));
+        }
+    }
+    // Each key alone, so that every invalid value is reported.
+    for (key, value) in table.iter().filter(|(key, _)| defaults.contains_key(*key)) {
+        let mut probe = minimal();
+        probe.insert(key.clone(), value.clone());
+        if let Err(e) = Value::Table(probe).try_into::<BastionLabConfig>() {
+            errors.push(format!("Invalid `{key}`: {e}"));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+
+    let config: BastionLabConfig = Value::Table(table)
+        .try_into()
+        .map_err(|e| ConfigErrors(vec![e.to_string()]))?;
+    let errors = violations(&config);
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+    Ok(LoadedConfig { config, sources })
+}
+
+/// What is wrong with `config` as a whole: settings that contradict each other, values out of
+/// bounds and missing files.
+pub fn violations(config: &BastionLabConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut check = |ok: bool, error: String| {
+        if !ok {
+            errors.push(error);
+        }
+    };
+    let exists = |path: &str| Path::new(path).exists();
+
+    let url = &config.client_to_enclave_untrusted_url;
+    check(
+        url.authority()
+            .is_some_and(|authority| authority.port().is_some()),
+        format!("`client_to_enclave_untrusted_url` {url} must have a host and a port"),
+    );
+    check(
+        config.metrics_port == 0 || url.port_u16() != Some(config.metrics_port),
+        format!(
+            "`metrics_port` {} is the port of `client_to_enclave_untrusted_url`",
+            config.metrics_port
+        ),
+    );
+    check(
+        config.session_expiry_in_secs > 0,
+        String::from("`session_expiry_in_secs` must be positive"),
+    );
+    check(
+        config.challenge_ttl_secs > 0,
+        String::from("`challenge_ttl_secs` must be positive"),
+    );
+
+    // TLS
+    if !config.tls_client_ca_file.is_empty() {
+        check(
+            exists(&config.tls_client_ca_file),
+            format!(
+                "`tls_client_ca_file` {} does not exist",
+                config.tls_client_ca_file
+            ),
+        );
+        check(
+            exists(&config.tls_cert_file) && exists(&config.tls_key_file),
+            format!(
+                "`tls_client_ca_file` requires TLS, but the certificate {} or its key {} does not \
+                 exist",
+                config.tls_cert_file, config.tls_key_file
+            ),
+        );
+    }
+
+    // Persistence
+    check(
+        (0..=MAX_ZSTD_LEVEL).contains(&config.persistence_zstd_level),
+        format!(
+            "`persistence_zstd_level` must be between 0 and {MAX_ZSTD_LEVEL}, not {}",
+            config.persistence_zstd_level
+        ),
+    );
+    check(
+        (0.0..=1.0).contains(&config.persistence_dictionary_ratio),
+        format!(
+            "`persistence_dictionary_ratio` must be between 0 and 1, not {}",
+            config.persistence_dictionary_ratio
+        ),
+    );
+    check(
+        config.embedded_compaction_ratio > 0.0 && config.embedded_compaction_ratio <= 1.0,
+        format!(
+            "`embedded_compaction_ratio` must be above 0 and at most 1, not {}",
+            config.embedded_compaction_ratio
+        ),
+    );
+    if config.tenant_encryption {
+        check(
+            exists(&config.tls_key_file),
+            format!(
+                "`tenant_encryption` requires the server key {} to wrap the tenant keys",
+                config.tls_key_file
+            ),
+        );
+    } else {
+        check(
+            config.tenant_key_files.is_empty(),
+            String::from("`tenant_key_files` are only used with `tenant_encryption`"),
+        );
+    }
+    for (tenant, path) in config.tenant_key_files.iter() {
+        check(
+            exists(path),
+            format!("The key file {path} of tenant {tenant} does not exist"),
+        );
+    }
+    if !config.audit_log_file.is_empty() {
+        let directory = Path::new(&config.audit_log_file)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        check(
+            directory.is_dir(),
+            format!(
+                "The directory of `audit_log_file` {} does not exist",
+                config.audit_log_file
+            ),
+        );
+    }
+    if !config.bundle_signing_key_file.is_empty() {
+        check(
+            exists(&config.bundle_signing_key_file),
+            format!(
+                "`bundle_signing_key_file` {} does not exist",
+                config.bundle_signing_key_file
+            ),
+        );
+    }
+
+    if !config.watermark_secret_file.is_empty() {
+        check(
+            exists(&config.watermark_secret_file),
+            format!(
+                "`watermark_secret_file` {} does not exist",
+                config.watermark_secret_file
+            ),
+        );
+    }
+
+    // Limits
+    check(
+        config.query_concurrency > 0,
+        String::from("`query_concurrency` must be positive"),
+    );
+    check(
+        config.batch_query_share > 0.0 && config.batch_query_share <= 1.0,
+        format!(
+            "`batch_query_share` must be above 0 and at most 1, not {}",
+            config.batch_query_share
+        ),
+    );
+    check(
+        config.max_queries_per_batch > 0 && config.query_batch_parallelism > 0,
+        String::from("`max_queries_per_batch` and `query_batch_parallelism` must be positive"),
+    );
+    check(
+        (1..=(MAX_CHUNK_BYTES >> 10) as u64).contains(&config.fetch_chunk_kb),
+        format!(
+            "`fetch_chunk_kb` must be between 1 and {}, not {}",
+            MAX_CHUNK_BYTES >> 10,
+            config.fetch_chunk_kb
+        ),
+    );
+    check(
+        config.watermark_record_capacity > 0,
+        String::from("`watermark_record_capacity` must be positive"),
+    );
+    check(
+        config.upload_chunk_kb > 0,
+        String::from("`upload_chunk_kb` must be positive"),
+    );
+    check(
+        config.max_upload_mb == 0 || config.upload_chunk_kb <= config.max_upload_mb * 1024,
+        format!(
+            "`upload_chunk_kb` ({} KB) is larger than `max_upload_mb` ({} MB)",
+            config.upload_chunk_kb, config.max_upload_mb
+        ),
+    );
+    check(
+        !config.segment_allow_spill || config.spill_quota_mb > 0,
+        String::from("`segment_allow_spill` requires a positive `spill_quota_mb`"),
+    );
+    check(
+        config.dataframe_ttl_secs == 0 || config.dataframe_ttl_sweep_secs > 0,
+        String::from("`dataframe_ttl_secs` requires a positive `dataframe_ttl_sweep_secs`"),
+    );
+
+    // Storage classes
+    check(
+        config.storage_hot_budget_mb == 0
+            || config.memory_soft_watermark_mb == 0
+            || config.storage_hot_budget_mb <= config.memory_soft_watermark_mb,
+        format!(
+            "`storage_hot_budget_mb` ({} MB) exceeds `memory_soft_watermark_mb` ({} MB): hot \
+             dataframes alone would put the server under memory pressure",
+            config.storage_hot_budget_mb, config.memory_soft_watermark_mb
+        ),
+    );
+    check(
+        config.storage_hot_accesses == 0
+            || config.storage_cold_min_mb == 0
+            || config.storage_cold_accesses < config.storage_hot_accesses,
+        format!(
+            "`storage_cold_accesses` ({}) must be below `storage_hot_accesses` ({}), or dataframes \
+             could be both hot and cold",
+            config.storage_cold_accesses, config.storage_hot_accesses
+        ),
+    );
+
+    // Governance
+    check(
+        config.dataset_reviewers.is_empty() || !config.publish_uploads,
+        String::from("`dataset_reviewers` are only used when `publish_uploads` is false"),
+    );
+    for (feature, sunset) in config.deprecated_features.iter() {
+        check(
+            is_date(sunset),
+            format!("The sunset date {sunset:?} of deprecated feature {feature} is not YYYY-MM-DD"),
+        );
+    }
+    errors
+}
+
+fn is_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    match parts[..] {
+        [year, month, day] => {
+            let number = |part: &str, len: usize, max: u32| {
+                part.len() == len && part.parse::<u32>().is_ok_and(|n| n >= 1 && n <= max)
+            };
+            number(year, 4, 9999) && number(month, 2, 12) && number(day, 2, 31)
+        }
+        _ => false,
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_WORDS.iter().any(|word| key.contains(word))
+}
+
+fn render_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        format!("{key:?}")
+    }
+}
+
+/// `value` of `key` as inline TOML, secrets redacted.
+fn render_value(key: &str, value: &Value) -> String {
+    if is_secret(key) {
+        return String::from("\"<redacted>\"");
+    }
+    match value {
+        Value::String(s) => format!("{s:?}"),
+        Value::Float(f) => format!("{f:?}"),
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(|v| render_value("", v)).collect();
+            format!("[{}]", values.join(", "))
+        }
+        Value::Integer(i) => i.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => d.to_string(),
+        Value::Table(table) if table.is_empty() => String::from("{}"),
+        Value::Table(table) => {
+            let entries: Vec<String> = table
+                .iter()
+                .map(|(key, value)| format!("{} = {}", render_key(key), render_value(key, value)))
+                .collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const BASE: &str = r#"
+        client_to_enclave_untrusted_url = "https://0.0.0.0:50056"
+        public_keys_directory = "keys/"
+        session_expiry_in_secs = 3600
+    "#;
+
+    fn load_with(extra: &str, env: &[(&str, &str)]) -> Result<LoadedConfig, ConfigErrors> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect();
+        load(&format!("{BASE}\n{extra}"), |var| env.get(var).cloned())
+    }
+
+    fn errors(extra: &str) -> Vec<String> {
+        load_with(extra, &[]).unwrap_err().0
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file_which_overrides_defaults() {
+        let loaded = load_with(
+            "query_concurrency = 3\nmax_upload_mb = 10\ntls_cert_file = \"file.pem\"",
+            &[
+                ("BASTIONLAB_MAX_UPLOAD_MB", "20"),
+                ("BASTIONLAB_TLS_CERT_FILE", "generic.pem"),
+                ("BASTIONLAB_TLS_CERT", "legacy.pem"),
+                ("BASTIONLAB_SPILL_DIR", "2027-06-30"),
+            ],
+        )
+        .unwrap();
+        let config = &loaded.config;
+        assert_eq!(config.query_concurrency, 3);
+        assert_eq!(config.max_upload_mb, 20);
+        assert_eq!(config.tls_cert_file, "legacy.pem");
+        // String keys are taken as is, even when they read as other TOML values.
+        assert_eq!(config.spill_dir, "2027-06-30");
+        assert_eq!(config.upload_chunk_kb, 32);
+
+        let source = |key: &str| loaded.sources.get(key).cloned();
+        assert_eq!(source("query_concurrency"), Some(Source::File));
+        assert_eq!(
+            source("max_upload_mb"),
+            Some(Source::Env(String::from("BASTIONLAB_MAX_UPLOAD_MB")))
+        );
+        assert_eq!(
+            source("tls_cert_file"),
+            Some(Source::Env(String::from("BASTIONLAB_TLS_CERT")))
+        );
+        assert_eq!(source("upload_chunk_kb"), None);
+
+        let rendered = loaded.render();
+        assert!(
+            rendered.contains("query_concurrency = 3  # file\n"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("upload_chunk_kb = 32  # default\n"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("max_upload_mb = 20  # env BASTIONLAB_MAX_UPLOAD_MB\n"),
+            "{rendered}"
+        );
+
+        let err = load_with("", &[("BASTIONLAB_MAX_UPLOAD_MB", "lots")]).unwrap_err();
+        assert!(
+            err.0[0].starts_with("Invalid BASTIONLAB_MAX_UPLOAD_MB"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_environment() {
+        let sample = r#"
+            client_to_enclave_untrusted_url = "https://[::1]:50056"
+            public_keys_directory = "/etc/bastionlab/keys"
+            session_expiry_in_secs = 600
+            tls_cert_file = "/etc/bastionlab/cert.pem"
+            fetch_chunk_kb = 64
+        "#;
+        let env: HashMap<_, _> = [("BASTIONLAB_MAX_UPLOAD_MB", "20"), (CONFIG_VAR, "env.toml")]
+            .into_iter()
+            .collect();
+        let overrides: Table = [
+            (String::from("max_upload_mb"), Value::Integer(30)),
+            (String::from("dataframe_ttl_secs"), Value::Integer(3600)),
+        ]
+        .into_iter()
+        .collect();
+        let loaded =
+            load_overridden(sample, |var| env.get(var).map(|v| v.to_string()), overrides).unwrap();
+        let config = &loaded.config;
+        assert_eq!(config.public_keys_directory, "/etc/bastionlab/keys");
+        assert_eq!(config.fetch_chunk_kb, 64);
+        assert_eq!(config.max_upload_mb, 30);
+        assert_eq!(config.dataframe_ttl_secs, 3600);
+        // Omitted keys get their defaults.
+        assert_eq!(config.tls_key_file, "tls/host_server.key");
+        assert_eq!(config.upload_chunk_kb, 32);
+        assert_eq!(config.metrics_port, 0);
+        assert_eq!(
+            loaded.sources.get("max_upload_mb"),
+            Some(&Source::CommandLine)
+        );
+        assert!(loaded
+            .render()
+            .contains("dataframe_ttl_secs = 3600  # command line\n"));
+
+        let overrides: Table = [(String::from("fetch_chunk_kb"), Value::Integer(0))]
+            .into_iter()
+            .collect();
+        let err = load_overridden(sample, |_| None, overrides).unwrap_err();
+        assert_eq!(
+            err.0,
+            ["`fetch_chunk_kb` must be between 1 and 4095, not 0"]
+        );
+
+        let env = |var: &str| env.get(var).map(|v| v.to_string());
+        assert_eq!(config_path(None, |_| None), Path::new(DEFAULT_CONFIG_FILE));
+        assert_eq!(config_path(None, env), Path::new("env.toml"));
+        assert_eq!(
+            config_path(Some(Path::new("flag.toml")), env),
+            Path::new("flag.toml")
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let errors = errors(
+            "query_concurency = 3\nmax_upload_mb = \"ten\"\nupload_chunk_kb = -1\n\
+             [extensions.kms]\napi_token = \"abc\"",
+        );
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].starts_with("Unknown key `query_concurency`"));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("Invalid `max_upload_mb`")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("Invalid `upload_chunk_kb`")));
+
+        let err = load("public_keys_directory = \"keys/\"", |_| None).unwrap_err();
+        assert_eq!(err.0.len(), 2, "{err}");
+
+        // Extensions are not checked, and their secrets are not printed.
+        let loaded = load_with(
+            "[extensions.kms]\napi_token = \"abc\"\nregion = \"eu\"",
+            &[],
+        )
+        .unwrap();
+        let rendered = loaded.render();
+        assert!(
+            rendered.contains(
+                "extensions = { kms = { api_token = \"<redacted>\", region = \"eu\" } }  # file"
+            ),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn settings_are_checked_against_each_other() {
+        let dir = std::env::temp_dir().join(format!("bastionlab-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, b"").unwrap();
+            path.display().to_string()
+        };
+        let (ca, cert, key) = (file("ca.pem"), file("cert.pem"), file("key.pem"));
+        let missing = dir.join("missing.pem").display().to_string();
+
+        let cases = [
+            (
+                format!("tls_client_ca_file = {ca:?}\ntls_cert_file = {missing:?}"),
+                String::from("`tls_client_ca_file` requires TLS"),
+            ),
+            (
+                format!(
+                    "tls_client_ca_file = {missing:?}\ntls_cert_file = {cert:?}\n\
+                     tls_key_file = {key:?}"
+                ),
+                format!("`tls_client_ca_file` {missing} does not exist"),
+            ),
+            (
+                format!("tenant_encryption = true\ntls_key_file = {missing:?}"),
+                String::from("`tenant_encryption` requires the server key"),
+            ),
+            (
+                format!("[tenant_key_files]\nacme = {key:?}"),
+                String::from("`tenant_key_files` are only used with `tenant_encryption`"),
+            ),
+            (
+                String::from("metrics_port = 50056"),
+                String::from(
+                    "`metrics_port` 50056 is the port of `client_to_enclave_untrusted_url`",
+                ),
+            ),
+            (
+                String::from("persistence_zstd_level = 23"),
+                String::from("`persistence_zstd_level` must be between 0 and 22"),
+            ),
+            (
+                String::from("persistence_dictionary_ratio = 1.5"),
+                String::from("`persistence_dictionary_ratio` must be between 0 and 1"),
+            ),
+            (
+                String::from("batch_query_share = 0.0"),
+                String::from("`batch_query_share` must be above 0"),
+            ),
+            (
+                String::from("query_batch_parallelism = 0"),
+                String::from("`query_batch_parallelism` must be positive"),
+            ),
+            (
+                String::from("fetch_chunk_kb = 8192"),
+                String::from("`fetch_chunk_kb` must be between 1 and 4095, not 8192"),
+            ),
+            (
+                String::from("max_upload_mb = 1\nupload_chunk_kb = 2048"),
+                String::from("`upload_chunk_kb` (2048 KB) is larger than `max_upload_mb` (1 MB)"),
+            ),
+            (
+                String::from("segment_allow_spill = true\nspill_quota_mb = 0"),
+                String::from("`segment_allow_spill` requires a positive `spill_quota_mb`"),
+            ),
+            (
+                String::from("dataframe_ttl_secs = 60\ndataframe_ttl_sweep_secs = 0"),
+                String::from("`dataframe_ttl_secs` requires a positive `dataframe_ttl_sweep_secs`"),
+            ),
+            (
+                String::from("storage_hot_budget_mb = 2048\nmemory_soft_watermark_mb = 1024"),
+                String::from("`storage_hot_budget_mb` (2048 MB) exceeds"),
+            ),
+            (
+                String::from(
+                    "storage_hot_accesses = 5\nstorage_cold_accesses = 5\nstorage_cold_min_mb = 1",
+                ),
+                String::from("`storage_cold_accesses` (5) must be below"),
+            ),
+            (
+                String::from("dataset_reviewers = [\"abc\"]"),
+                String::from("`dataset_reviewers` are only used when `publish_uploads` is false"),
+            ),
+            (
+                String::from("[deprecated_features]\nipc_upload = \"June 2027\""),
+                String::from("\"June 2027\" of deprecated feature ipc_upload is not YYYY-MM-DD"),
+            ),
+        ];
+        for (extra, expected) in cases.iter() {
+            let errors = errors(extra);
+            assert_eq!(errors.len(), 1, "{extra}: {errors:?}");
+            assert!(errors[0].contains(expected.as_str()), "{extra}: {errors:?}");
+        }
+        let err = load(&BASE.replace("3600", "0"), |_| None).unwrap_err();
+        assert_eq!(
+            err.0,
+            [String::from("`session_expiry_in_secs` must be positive")]
+        );
+
+        let valid = format!(
+            "tls_client_ca_file = {ca:?}\ntls_cert_file = {cert:?}\ntls_key_file = {key:?}\n\
+             tenant_encryption = true\npublish_uploads = false\ndataset_reviewers = [\"abc\"]\n\
+             [tenant_key_files]\nacme = {key:?}\n[deprecated_features]\nipc_upload = \"2027-06-30\""
+        );
+        load_with(&valid, &[]).unwrap();
+        // Every violation is listed, not only the first.
+        let errors = errors("persistence_zstd_level = 23\nbatch_query_share = 2.0");
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,798 @@
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+
+use http_body::{Body, SizeHint};
+use hyper::server::conn::Http;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey, SigningKey};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerifier, ClientHello, NoClientAuth,
+    ResolvesServerCert, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{webpki, TlsAcceptor};
+use tonic::transport::server::Connected;
+use tonic::{Request, Response, Status};
+use tower::Service;
+use tracing::Instrument;
+
+use crate::config::{BastionLabConfig, TlsFiles};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::prelude::*;
+use crate::session::SessionManager;
+use crate::session_proto::{self, ConnectionInfo, ConnectionList, DrainResponse};
+
+/// Identifies the connection a request was received on, it is set in the extensions of every
+/// request served by [`ConnectionManager::serve`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    max_age: Option<Duration>,
+    max_idle: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recycle {
+    MaxAge,
+    MaxIdle,
+    Drain,
+    Shutdown,
+}
+
+impl Recycle {
+    fn reason(&self) -> &'static str {
+        match self {
+            Recycle::MaxAge => "it reached its maximum age",
+            Recycle::MaxIdle => "it was idle for too long",
+            Recycle::Drain => "connections are being drained",
+            Recycle::Shutdown => "the server is shutting down",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Activity {
+    streams: usize,
+    last_active: Instant,
+}
+
+#[derive(Debug)]
+struct Connection {
+    id: ConnectionId,
+    peer: Option<SocketAddr>,
+    opened: Instant,
+    activity: Mutex<Activity>,
+    draining: AtomicBool,
+    /// Notified when the connection becomes idle or must be drained.
+    wake: Notify,
+}
+
+impl Connection {
+    fn new(id: ConnectionId, peer: Option<SocketAddr>) -> Self {
+        let now = Instant::now();
+        Connection {
+            id,
+            peer,
+            opened: now,
+            activity: Mutex::new(Activity {
+                streams: 0,
+                last_active: now,
+            }),
+            draining: AtomicBool::new(false),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Returns why the connection must be recycled at `now`, if it must.
+    ///
+    /// Only the maximum age applies to connections with requests in flight, which these requests
+    /// outlive: recycling only stops new requests from being sent on the connection.
+    fn recycle_reason(&self, limits: &Limits, now: Instant) -> Option<Recycle> {
+        if limits
+            .max_age
+            .is_some_and(|max_age| now >= self.opened + max_age)
+        {
+            return Some(Recycle::MaxAge);
+        }
+        let activity = self.activity.lock().expect("Poisoned lock");
+        if activity.streams > 0 {
+            return None;
+        }
+        if self.draining.load(Ordering::SeqCst) {
+            return Some(Recycle::Drain);
+        }
+        limits
+            .max_idle
+            .filter(|max_idle| now >= activity.last_active + *max_idle)
+            .map(|_| Recycle::MaxIdle)
+    }
+
+    /// The next time a limit may be reached, if any.
+    fn next_check(&self, limits: &Limits) -> Option<Instant> {
+        let max_age = limits.max_age.map(|max_age| self.opened + max_age);
+        let activity = self.activity.lock().expect("Poisoned lock");
+        let max_idle = limits
+            .max_idle
+            .filter(|_| activity.streams == 0)
+            .map(|max_idle| activity.last_active + max_idle);
+        max_age.into_iter().chain(max_idle).min()
+    }
+
+    fn stats(&self, now: Instant) -> ConnectionStats {
+        let activity = self.activity.lock().expect("Poisoned lock");
+        ConnectionStats {
+            id: self.id,
+            peer: self.peer,
+            age: now.saturating_duration_since(self.opened),
+            idle: (activity.streams == 0)
+                .then(|| now.saturating_duration_since(activity.last_active)),
+            active_streams: activity.streams,
+            draining: self.draining.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A snapshot of a connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    pub peer: Option<SocketAddr>,
+    pub age: Duration,
+    /// Time since the last request ended, `None` while requests are in flight.
+    pub idle: Option<Duration>,
+    pub active_streams: usize,
+    pub draining: bool,
+}
+
+/// Counts a request as in flight on its connection until the response has been sent.
+struct StreamGuard(Arc<Connection>);
+
+impl StreamGuard {
+    fn new(connection: Arc<Connection>) -> Self {
+        {
+            let mut activity = connection.activity.lock().expect("Poisoned lock");
+            activity.streams += 1;
+            activity.last_active = Instant::now();
+        }
+        StreamGuard(connection)
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut activity = self.0.activity.lock().expect("Poisoned lock");
+        activity.streams -= 1;
+        activity.last_active = Instant::now();
+        if activity.streams == 0 {
+            self.0.wake.notify_one();
+        }
+    }
+}
+
+/// A response body that keeps its request in flight until it is dropped.
+///
+/// Responses to failed requests have no body, their status is in their headers.
+pub struct TrackedBody<B> {
+    inner: Option<B>,
+    _guard: StreamGuard,
+}
+
+impl<B: Body + Unpin> Body for TrackedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        match &mut self.inner {
+            Some(inner) => Pin::new(inner).poll_data(cx),
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        match &mut self.inner {
+            Some(inner) => Pin::new(inner).poll_trailers(cx),
+            None => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or(SizeHint::with_exact(0), |inner| inner.size_hint())
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Tags the requests of a connection with its [`ConnectionId`] and connect info, tracks them, and
+/// serves them in their [`logging::rpc_span`].
+///
+/// Like tonic's own server does, errors carrying a [`Status`], such as the ones of interceptors,
+/// are sent as responses.
+#[derive(Clone)]
+struct Tracked<S, I> {
+    inner: S,
+    connection: Arc<Connection>,
+    connect_info: I,
+    metrics: Option<Arc<Metrics>>,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, I, B> Service<http::Request<hyper::Body>> for Tracked<S, I>
+where
+    S: Service<http::Request<hyper::Body>, Response = http::Response<B>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    I: Clone + Send + Sync + 'static,
+    B: 'static,
+{
+    type Response = http::Response<TrackedBody<B>>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: http::Request<hyper::Body>) -> Self::Future {
+        req.extensions_mut().insert(self.connection.id);
+        req.extensions_mut().insert(self.connect_info.clone());
+        if let Some(metrics) = &self.metrics {
+            metrics.rpc(req.uri().path());
+        }
+        let guard = StreamGuard::new(self.connection.clone());
+        let span = logging::rpc_span(&req, self.connection.id.0);
+        let response = span.in_scope(|| self.inner.call(req));
+        let served = async move {
+            match response.await.map_err(Into::into) {
+                Ok(response) => Ok(response.map(|inner| TrackedBody {
+                    inner: Some(inner),
+                    _guard: guard,
+                })),
+                Err(e) => {
+                    let status = e.downcast::<Status>()?;
+                    let (parts, _) = status.to_http().into_parts();
+                    Ok(http::Response::from_parts(
+                        parts,
+                        TrackedBody {
+                            inner: None,
+                            _guard: guard,
+                        },
+                    ))
+                }
+            }
+        };
+        Box::pin(served.instrument(span))
+    }
+}
+
+type CloseHook = Box<dyn Fn(ConnectionId) + Send + Sync>;
+
+/// Serves gRPC over connections that are recycled once they reach the configured maximum age or
+/// idle time, or when asked to by [`ConnectionManager::drain`].
+///
+/// Recycling sends a GOAWAY to the client: requests in flight complete on the old connection and
+/// the next ones are sent on a new one.
+pub struct ConnectionManager {
+    limits: Limits,
+    next_id: AtomicU64,
+    connections: RwLock<HashMap<ConnectionId, Arc<Connection>>>,
+    close_hooks: RwLock<Vec<CloseHook>>,
+    metrics: Option<Arc<Metrics>>,
+    shutting_down: AtomicBool,
+}
+
+fn limit(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl ConnectionManager {
+    pub fn new(config: &BastionLabConfig) -> Self {
+        ConnectionManager {
+            limits: Limits {
+                max_age: limit(config.connection_max_age_secs),
+                max_idle: limit(config.connection_max_idle_secs),
+            },
+            next_id: AtomicU64::new(1),
+            connections: Default::default(),
+            close_hooks: Default::default(),
+            metrics: None,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts the requests of every connection in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Registers `hook` to be called with the id of every connection that closes.
+    pub fn on_close(&self, hook: impl Fn(ConnectionId) + Send + Sync + 'static) {
+        self.close_hooks
+            .write()
+            .expect("Poisoned lock")
+            .push(Box::new(hook));
+    }
+
+    pub fn count(&self) -> usize {
+        self.connections.read().expect("Poisoned lock").len()
+    }
+
+    /// Returns the open connections, by order of opening.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        let now = Instant::now();
+        let mut stats: Vec<_> = self
+            .connections
+            .read()
+            .expect("Poisoned lock")
+            .values()
+            .map(|connection| connection.stats(now))
+            .collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+
+    /// Asks every open connection to reconnect once it has no request in flight, and returns their
+    /// number.
+    ///
+    /// Connections opened afterwards are not affected.
+    pub fn drain(&self) -> usize {
+        let connections = self.connections.read().expect("Poisoned lock");
+        for connection in connections.values() {
+            connection.draining.store(true, Ordering::SeqCst);
+            connection.wake.notify_one();
+        }
+        connections.len()
+    }
+
+    fn open(&self, peer: Option<SocketAddr>) -> Arc<Connection> {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::SeqCst));
+        let connection = Arc::new(Connection::new(id, peer));
+        self.connections
+            .write()
+            .expect("Poisoned lock")
+            .insert(id, connection.clone());
+        connection
+    }
+
+    fn close(&self, connection: &Connection) {
+        self.connections
+            .write()
+            .expect("Poisoned lock")
+            .remove(&connection.id);
+        for hook in self.close_hooks.read().expect("Poisoned lock").iter() {
+            hook(connection.id);
+        }
+    }
+
+    /// Accepts connections on `listener`, over TLS if `tls` is given, and serves `service` on them.
+    pub async fn serve<S, B>(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        service: S,
+    ) -> std::io::Result<()>
+    where
+        S: Service<http::Request<hyper::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Unpin + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Could not accept a connection: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let manager = self.clone();
+            let tls = tls.clone();
+            let service = service.clone();
+            tokio::spawn(async move {
+                match tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => manager.serve_connection(stream, peer, service).await,
+                        Err(e) => warn!("TLS handshake with {peer} failed: {e}"),
+                    },
+                    None => manager.serve_connection(stream, peer, service).await,
+                }
+            });
+        }
+    }
+
+    /// Like [`ConnectionManager::serve`], until `signal` completes: connections are no longer
+    /// accepted then, and the open ones are drained, see [`ConnectionManager::shut_down`].
+    pub async fn serve_with_shutdown<S, B>(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        service: S,
+        signal: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> std::io::Result<()>
+    where
+        S: Service<http::Request<hyper::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Unpin + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        tokio::select! {
+            served = self.clone().serve(listener, tls, service) => served,
+            _ = signal => {
+                self.shut_down(grace).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Asks every connection to stop sending requests, right away unlike
+    /// [`ConnectionManager::drain`], and waits up to `grace` for their requests in flight, such as
+    /// fetch streams, to finish. Returns whether they all did.
+    pub async fn shut_down(&self, grace: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + grace;
+        loop {
+            let open = {
+                let connections = self.connections.read().expect("Poisoned lock");
+                // Every round, for the connections still in their TLS handshake at first.
+                for connection in connections.values() {
+                    connection.wake.notify_one();
+                }
+                connections.len()
+            };
+            if open == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!("{open} connections still had requests in flight after {grace:?}");
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn serve_connection<IO, S, B>(&self, io: IO, peer: SocketAddr, service: S)
+    where
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+        S: Service<http::Request<hyper::Body>, Response = http::Response<B>> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Unpin + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let connection = self.open(Some(peer));
+        let service = Tracked {
+            inner: service,
+            connection: connection.clone(),
+            connect_info: io.connect_info(),
+            metrics: self.metrics.clone(),
+        };
+        let conn = Http::new().http2_only(true).serve_connection(io, service);
+        tokio::pin!(conn);
+
+        let res = loop {
+            let next_check = connection.next_check(&self.limits);
+            let deadline = async move {
+                match next_check {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                res = conn.as_mut() => break res,
+                _ = connection.wake.notified() => (),
+                _ = deadline => (),
+            }
+            let recycle = if self.shutting_down.load(Ordering::SeqCst) {
+                Some(Recycle::Shutdown)
+            } else {
+                connection.recycle_reason(&self.limits, Instant::now())
+            };
+            if let Some(recycle) = recycle {
+                info!(
+                    "Recycling connection {} from {peer} as {}",
+                    connection.id.0,
+                    recycle.reason()
+                );
+                conn.as_mut().graceful_shutdown();
+                break conn.as_mut().await;
+            }
+        };
+        if let Err(e) = res {
+            debug!("Connection {} from {peer} failed: {e}", connection.id.0);
+        }
+        self.close(&connection);
+    }
+}
+
+/// Loads a TLS configuration for [`ConnectionManager::serve`] from PEM files.
+pub fn tls_acceptor(cert: &[u8], key: &[u8]) -> Result<TlsAcceptor> {
+    Ok(Arc::new(CertResolver::new(cert, key)?).acceptor())
+}
+
+/// Loads the TLS configuration from `files`, `None` when the certificate or its key is missing.
+///
+/// The resolver is returned for the certificate to be reloaded.
+pub fn load_tls(files: &TlsFiles) -> Result<Option<(Arc<CertResolver>, TlsAcceptor)>> {
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("Reading the {} file", path.display()))
+    };
+    if !files.present() {
+        ensure!(
+            files.client_ca.is_none(),
+            "Client certificates are required, but TLS is not set up"
+        );
+        return Ok(None);
+    }
+    let resolver = Arc::new(
+        CertResolver::new(&read(&files.cert)?, &read(&files.key)?).context("Setting up TLS")?,
+    );
+    let acceptor = match &files.client_ca {
+        Some(client_ca) => resolver
+            .mutual_acceptor(&read(client_ca)?)
+            .context("Setting up TLS")?,
+        None => resolver.acceptor(),
+    };
+    Ok(Some((resolver, acceptor)))
+}
+
+/// Serves the latest certificate loaded with [`CertResolver::reload`] on every new handshake.
+///
+/// Established connections keep the certificate they were opened with.
+pub struct CertResolver {
+    current: RwLock<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(cert: &[u8], key: &[u8]) -> Result<Self> {
+        Ok(CertResolver {
+            current: RwLock::new(certified_key(cert, key)?),
+        })
+    }
+
+    /// Replaces the certificate, the previous one is kept if the new one is invalid.
+    pub fn reload(&self, cert: &[u8], key: &[u8]) -> Result<()> {
+        let certified = certified_key(cert, key)?;
+        *self.current.write().expect("Poisoned lock") = certified;
+        Ok(())
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        self.acceptor_verifying(NoClientAuth::new())
+    }
+
+    /// An acceptor rejecting clients without a certificate issued by one of the PEM
+    /// certificates of `client_ca`.
+    pub fn mutual_acceptor(self: &Arc<Self>, client_ca: &[u8]) -> Result<TlsAcceptor> {
+        let mut roots = RootCertStore::empty();
+        match roots.add_pem_file(&mut BufReader::new(client_ca)) {
+            Ok((added, _)) if added > 0 => (),
+            _ => bail!("Could not parse the client CA certificates"),
+        }
+        Ok(self.acceptor_verifying(AllowAnyAuthenticatedClient::new(roots)))
+    }
+
+    fn acceptor_verifying(self: &Arc<Self>, verifier: Arc<dyn ClientCertVerifier>) -> TlsAcceptor {
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = self.clone();
+        config.set_protocols(&[b"h2".to_vec()]);
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().expect("Poisoned lock").clone())
+    }
+}
+
+fn certified_key(cert: &[u8], key: &[u8]) -> Result<CertifiedKey> {
+    let certs = pemfile::certs(&mut BufReader::new(cert))
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .context("Could not parse the server certificate")?;
+    let key = match pemfile::pkcs8_private_keys(&mut BufReader::new(key)) {
+        Ok(mut keys) if !keys.is_empty() => keys.remove(0),
+        _ => pemfile::rsa_private_keys(&mut BufReader::new(key))
+            .ok()
+            .and_then(|mut keys| (!keys.is_empty()).then(|| keys.remove(0)))
+            .context("Could not parse the server key")?,
+    };
+    let key = sign::any_supported_type(&key).map_err(|_| anyhow!("Unsupported server key"))?;
+    check_key_matches(&certs[0], key.as_ref())?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Signs a message with the key and verifies it with the certificate.
+///
+/// Certificates and keys are written to disk one after the other: this rejects the key of a
+/// previous certificate, which would fail every handshake.
+fn check_key_matches(cert: &Certificate, key: &dyn SigningKey) -> Result<()> {
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = key
+        .choose_scheme(&offered)
+        .context("Unsupported server key")?;
+    let (_, algorithm) = schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .context("Unsupported server key")?;
+
+    let message = b"bastionlab-certificate-check";
+    let signature = signer
+        .sign(message)
+        .map_err(|e| anyhow!("Could not sign with the server key: {e}"))?;
+    webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| anyhow!("Invalid server certificate: {e:?}"))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow!("The server key does not match the certificate"))
+}
+
+pub struct ConnectionGrpcService {
+    sess_manager: Arc<SessionManager>,
+    connections: Arc<ConnectionManager>,
+}
+
+impl ConnectionGrpcService {
+    pub fn new(sess_manager: Arc<SessionManager>, connections: Arc<ConnectionManager>) -> Self {
+        Self {
+            sess_manager,
+            connections,
+        }
+    }
+
+    fn verify_owner<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let token = self.sess_manager.get_token(request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can manage connections.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl session_proto::connection_service_server::ConnectionService for ConnectionGrpcService {
+    async fn list_connections(
+        &self,
+        request: Request<session_proto::Empty>,
+    ) -> Result<Response<ConnectionList>, Status> {
+        self.verify_owner(&request)?;
+        let connections = self
+            .connections
+            .connections()
+            .into_iter()
+            .map(|stats| ConnectionInfo {
+                id: stats.id.0,
+                peer: stats.peer.map(|peer| peer.to_string()).unwrap_or_default(),
+                age_ms: stats.age.as_millis() as u64,
+                idle_ms: stats.idle.map_or(0, |idle| idle.as_millis() as u64),
+                active_streams: stats.active_streams as u32,
+                draining: stats.draining,
+            })
+            .collect();
+        Ok(Response::new(ConnectionList { connections }))
+    }
+
+    async fn drain_connections(
+        &self,
+        request: Request<session_proto::Empty>,
+    ) -> Result<Response<DrainResponse>, Status> {
+        self.verify_owner(&request)?;
+        let draining = self.connections.drain();
+        info!("Draining {draining} connections");
+        Ok(Response::new(DrainResponse {
+            draining: draining as u32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_age: Some(Duration::from_secs(60)),
+        max_idle: Some(Duration::from_secs(10)),
+    };
+
+    #[test]
+    fn idle_connections_are_recycled() {
+        let connection = Connection::new(ConnectionId(1), None);
+        let opened = connection.opened;
+        assert_eq!(connection.recycle_reason(&LIMITS, opened), None);
+        assert_eq!(
+            connection.next_check(&LIMITS),
+            Some(opened + Duration::from_secs(10))
+        );
+        assert_eq!(
+            connection.recycle_reason(&LIMITS, opened + Duration::from_secs(10)),
+            Some(Recycle::MaxIdle)
+        );
+        assert_eq!(
+            connection.recycle_reason(&Limits::default(), opened + Duration::from_secs(3600)),
+            None
+        );
+    }
+
+    #[test]
+    fn requests_in_flight_only_delay_idle_recycling() {
+        let connection = Arc::new(Connection::new(ConnectionId(1), None));
+        let opened = connection.opened;
+        let guard = StreamGuard::new(connection.clone());
+        connection.draining.store(true, Ordering::SeqCst);
+        assert_eq!(
+            connection.recycle_reason(&LIMITS, opened + Duration::from_secs(30)),
+            None
+        );
+        assert_eq!(
+            connection.next_check(&LIMITS),
+            Some(opened + Duration::from_secs(60))
+        );
+        assert_eq!(
+            connection.recycle_reason(&LIMITS, opened + Duration::from_secs(60)),
+            Some(Recycle::MaxAge)
+        );
+
+        drop(guard);
+        assert_eq!(connection.stats(Instant::now()).active_streams, 0);
+        assert_eq!(
+            connection.recycle_reason(&LIMITS, Instant::now()),
+            Some(Recycle::Drain)
+        );
+    }
+}
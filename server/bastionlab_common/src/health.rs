@@ -0,0 +1,26 @@
+//! The standard gRPC health service, `grpc.health.v1.Health`, for the liveness and readiness
+//! probes of deployments.
+//!
+//! The server as a whole, the service named `""`, is reported `NOT_SERVING` until its keys are
+//! loaded and its persisted dataframes reloaded, then `SERVING`. Clients can also call `Ping`
+//! on the session service, which answers with the version of the server.
+
+use tonic_health::proto::health_server::{Health, HealthServer};
+pub use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+/// The health service, reporting the server as not serving until [`ready`].
+pub async fn health_service() -> (HealthReporter, HealthServer<impl Health>) {
+    let (mut reporter, service) = tonic_health::server::health_reporter();
+    reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    (reporter, service)
+}
+
+/// Reports the server as serving.
+pub async fn ready(reporter: &mut HealthReporter) {
+    reporter
+        .set_service_status("", ServingStatus::Serving)
+        .await;
+}
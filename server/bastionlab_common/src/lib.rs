@@ -1,8 +1,17 @@
 pub mod array_store;
+pub mod atomic_file;
 pub mod auth;
+pub mod challenges;
 pub mod common_conversions;
 pub mod config;
+pub mod config_check;
+pub mod connections;
+pub mod health;
+pub mod logging;
+pub mod metrics;
 pub mod prelude;
+pub mod reload;
+pub mod replay;
 pub mod session;
 pub mod telemetry;
 
@@ -0,0 +1,135 @@
+//! Structured logging, with a span per RPC.
+//!
+//! Logs are filtered by the `--log-level` of the server, else `RUST_LOG`, `info` by default, and
+//! written human-readable or as one JSON object per line, depending on `BASTIONLAB_LOG_FORMAT`
+//! (`pretty` or `json`). Every request gets an `rpc` span carrying its method, the id of its
+//! connection, its request id and, once the handler knows them, the identifier of the dataframe it
+//! is about and how long a query waited for an execution slot: every line logged while serving it
+//! carries them. The request id is taken from the `x-request-id` header when clients set it, so
+//! that their logs and the server's can be joined, and generated otherwise.
+//!
+//! Request contents, such as plans or rows, are only logged at `debug` level.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::{field, info_span, Span};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::prelude::*;
+
+/// The environment variable selecting the format of the logs.
+pub const FORMAT_VAR: &str = "BASTIONLAB_LOG_FORMAT";
+
+/// The header clients set their request ids in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id kept from clients, longer ones are replaced.
+const MAX_REQUEST_ID: usize = 128;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    /// The format set in `BASTIONLAB_LOG_FORMAT`, pretty if unset.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(FORMAT_VAR) {
+            Ok(format) => format.parse(),
+            Err(_) => Ok(LogFormat::Pretty),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => bail!("Invalid {FORMAT_VAR} {other:?}: expected pretty or json"),
+        }
+    }
+}
+
+/// Installs the global subscriber, writing to `writer` in `format` the records `level` lets
+/// through, a filter such as `debug` or `bastionlab_polars=trace,info` that overrides `RUST_LOG`.
+/// Records of crates still using `log` are forwarded to it.
+pub fn init<W>(format: LogFormat, level: Option<&str>, writer: W) -> Result<()>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let filter = match level {
+        Some(level) => {
+            EnvFilter::try_new(level).map_err(|e| anyhow!("Invalid log level {level:?}: {e}"))?
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    let installed = match format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    installed.map_err(|e| anyhow!("Could not install the logger: {e}"))
+}
+
+/// The span of request `req`, with an empty `identifier` for [`record_identifier`] and an empty
+/// `queue_wait_ms` for [`record_queue_wait`].
+pub fn rpc_span<B>(req: &http::Request<B>, connection: u64) -> Span {
+    info_span!(
+        "rpc",
+        method = %req.uri().path(),
+        connection,
+        request_id = %request_id(req.headers()),
+        identifier = field::Empty,
+        queue_wait_ms = field::Empty,
+    )
+}
+
+/// The request id set by the client, or a new one if it set none or an invalid one.
+fn request_id(headers: &http::HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID)
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Records the dataframe the current request is about in its span.
+pub fn record_identifier(identifier: &str) {
+    Span::current().record("identifier", &identifier);
+}
+
+/// Records how long the current request waited for an execution slot in its span.
+pub fn record_queue_wait(elapsed: Duration) {
+    Span::current().record("queue_wait_ms", &(elapsed.as_millis() as u64));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_parse_and_request_ids_are_kept_when_valid() {
+        assert_eq!("".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert_eq!(" JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, "client-42".parse().unwrap());
+        assert_eq!(request_id(&headers), "client-42");
+        headers.insert(REQUEST_ID_HEADER, "x".repeat(200).parse().unwrap());
+        let generated = request_id(&headers);
+        assert!(Uuid::parse_str(&generated).is_ok());
+        assert_ne!(request_id(&http::HeaderMap::new()), generated);
+    }
+}
@@ -0,0 +1,214 @@
+//! Prometheus metrics of the server, served as text at `/metrics` on their own HTTP port,
+//! `metrics_port`:
+//! - `bastionlab_rpc_requests_total`, the requests received, by `method`, the gRPC path of the RPC,
+//! - `bastionlab_plan_run_seconds`, how long composite plans took to run, queueing excluded,
+//! - `bastionlab_query_queue_seconds`, how long queries waited for an execution slot,
+//! - `bastionlab_fetch_stream_seconds`, how long fetches took to stream, approvals included,
+//! - `bastionlab_upload_seconds`, how long uploads took to receive and store,
+//! - `bastionlab_dataframes` and `bastionlab_dataframes_bytes`, the dataframes stored and their
+//!   estimated size, sampled when scraped.
+//!
+//! Clients choose the paths of their requests: past [`MAX_METHODS`] distinct ones, requests are
+//! counted as `other`.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+
+pub use prometheus::HistogramTimer;
+
+/// Most distinct RPC methods counted.
+pub const MAX_METHODS: usize = 256;
+
+/// The dataframes stored, and their estimated size in bytes.
+type Sampler = Box<dyn Fn() -> (u64, u64) + Send + Sync>;
+
+pub struct Metrics {
+    registry: Registry,
+    rpc_requests: IntCounterVec,
+    methods: Mutex<HashSet<String>>,
+    plan_run: Histogram,
+    queue_wait: Histogram,
+    fetch_stream: Histogram,
+    upload: Histogram,
+    dataframes: IntGauge,
+    dataframes_bytes: IntGauge,
+    sampler: Mutex<Option<Sampler>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // From 5ms to about 20 minutes.
+        let buckets = exponential_buckets(0.005, 4.0, 10).expect("Valid buckets");
+        let histogram = |name: &str, help: &str| {
+            Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets.clone()))
+                .expect("Valid histogram")
+        };
+        let metrics = Metrics {
+            registry: Registry::new(),
+            rpc_requests: IntCounterVec::new(
+                Opts::new("bastionlab_rpc_requests_total", "Requests received, by RPC"),
+                &["method"],
+            )
+            .expect("Valid counter"),
+            methods: Default::default(),
+            plan_run: histogram(
+                "bastionlab_plan_run_seconds",
+                "Time composite plans took to run",
+            ),
+            queue_wait: histogram(
+                "bastionlab_query_queue_seconds",
+                "Time queries waited for an execution slot",
+            ),
+            fetch_stream: histogram(
+                "bastionlab_fetch_stream_seconds",
+                "Time fetches took to stream",
+            ),
+            upload: histogram(
+                "bastionlab_upload_seconds",
+                "Time uploads took to receive and store",
+            ),
+            dataframes: IntGauge::new("bastionlab_dataframes", "Dataframes stored")
+                .expect("Valid gauge"),
+            dataframes_bytes: IntGauge::new(
+                "bastionlab_dataframes_bytes",
+                "Estimated size of the dataframes stored",
+            )
+            .expect("Valid gauge"),
+            sampler: Default::default(),
+        };
+        let collectors: [Box<dyn Collector>; 7] = [
+            Box::new(metrics.rpc_requests.clone()),
+            Box::new(metrics.plan_run.clone()),
+            Box::new(metrics.queue_wait.clone()),
+            Box::new(metrics.fetch_stream.clone()),
+            Box::new(metrics.upload.clone()),
+            Box::new(metrics.dataframes.clone()),
+            Box::new(metrics.dataframes_bytes.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Distinct metric names");
+        }
+        metrics
+    }
+
+    /// Counts a request to `method`, the path of a gRPC request.
+    pub fn rpc(&self, method: &str) {
+        let known = {
+            let mut methods = self.methods.lock().expect("Poisoned lock");
+            methods.contains(method)
+                || (methods.len() < MAX_METHODS && methods.insert(method.to_string()))
+        };
+        let label = if known { method } else { "other" };
+        self.rpc_requests.with_label_values(&[label]).inc();
+    }
+
+    pub fn plan_run(&self, elapsed: Duration) {
+        self.plan_run.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn queue_wait(&self, elapsed: Duration) {
+        self.queue_wait.observe(elapsed.as_secs_f64());
+    }
+
+    /// Times a fetch stream until the timer is dropped.
+    pub fn fetch_stream_timer(&self) -> HistogramTimer {
+        self.fetch_stream.start_timer()
+    }
+
+    pub fn upload(&self, elapsed: Duration) {
+        self.upload.observe(elapsed.as_secs_f64());
+    }
+
+    /// Sets the dataframe gauges from `sampler` on every scrape, replacing the previous sampler.
+    pub fn sample_dataframes(&self, sampler: impl Fn() -> (u64, u64) + Send + Sync + 'static) {
+        *self.sampler.lock().expect("Poisoned lock") = Some(Box::new(sampler));
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        if let Some(sampler) = self.sampler.lock().expect("Poisoned lock").as_ref() {
+            let (dataframes, bytes) = sampler();
+            self.dataframes.set(dataframes as i64);
+            self.dataframes_bytes.set(bytes as i64);
+        }
+        let mut buf = Vec::new();
+        // Only fails on invalid metrics, which the registry rejects.
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap_or(());
+        String::from_utf8(buf).unwrap_or_default()
+    }
+
+    fn respond(&self, request: &hyper::Request<Body>) -> hyper::Response<Body> {
+        let mut response = hyper::Response::new(Body::empty());
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(prometheus::TEXT_FORMAT),
+                );
+                *response.body_mut() = Body::from(self.render());
+            }
+            _ => *response.status_mut() = StatusCode::NOT_FOUND,
+        }
+        response
+    }
+}
+
+/// Serves `metrics` over HTTP on `listener`, at `/metrics`.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> hyper::Result<()> {
+    let incoming = AddrIncoming::from_listener(listener)?;
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = metrics.respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    hyper::Server::builder(incoming).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_past_the_limit_are_counted_as_other() {
+        let metrics = Metrics::new();
+        for i in 0..MAX_METHODS + 2 {
+            metrics.rpc(&format!("/bastionlab.Service/Method{i}"));
+        }
+        metrics.rpc("/bastionlab.Service/Method0");
+        metrics.sample_dataframes(|| (3, 1024));
+        let rendered = metrics.render();
+        assert!(rendered
+            .contains("bastionlab_rpc_requests_total{method=\"/bastionlab.Service/Method0\"} 2"));
+        assert!(rendered.contains("bastionlab_rpc_requests_total{method=\"other\"} 2"));
+        assert!(rendered.contains("bastionlab_dataframes 3"));
+        assert!(rendered.contains("bastionlab_dataframes_bytes 1024"));
+    }
+}
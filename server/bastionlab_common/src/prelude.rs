@@ -1,5 +1,5 @@
 pub use anyhow::{anyhow, bail, ensure, Context, Result};
-pub use log::{debug, error, info, trace, warn};
+pub use tracing::{debug, error, info, trace, warn};
 pub use std::{
     collections::HashMap,
     hash::{Hash, Hasher},
@@ -0,0 +1,340 @@
+//! Reloads the TLS certificate and the public keys of the server when their files change, without
+//! restarting it.
+//!
+//! The files are polled: a change is picked up within one interval. A reload that fails, on a
+//! half-written file or a key not matching its certificate, keeps the previous material in use
+//! and logs an error. It is retried on the next change of the files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ring::digest::{Context as Digest, SHA256};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::auth::KeyManagement;
+use crate::connections::CertResolver;
+use crate::prelude::*;
+use crate::session::SessionManager;
+
+/// A digest of the contents of `paths`, directories included recursively.
+///
+/// Unreadable files are part of the digest as such, so that a file appearing again is reloaded.
+fn fingerprint(paths: &[PathBuf]) -> Vec<u8> {
+    fn visit(path: &Path, digest: &mut Digest) {
+        digest.update(path.as_os_str().to_string_lossy().as_bytes());
+        if path.is_dir() {
+            let mut entries: Vec<_> = fs::read_dir(path)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect();
+            entries.sort();
+            for entry in entries {
+                visit(&entry, digest);
+            }
+        } else {
+            match fs::read(path) {
+                Ok(contents) => {
+                    digest.update(&(contents.len() as u64).to_le_bytes());
+                    digest.update(&contents);
+                }
+                Err(_) => digest.update(b"unreadable"),
+            }
+        }
+    }
+
+    let mut digest = Digest::new(&SHA256);
+    for path in paths {
+        visit(path, &mut digest);
+    }
+    digest.finish().as_ref().to_vec()
+}
+
+/// Calls `reload` whenever the contents of `paths` change.
+fn watch(
+    what: &'static str,
+    paths: Vec<PathBuf>,
+    interval: Duration,
+    reload: impl Fn() -> Result<()> + Send + 'static,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last = fingerprint(&paths);
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let current = fingerprint(&paths);
+            if current == last {
+                continue;
+            }
+            last = current;
+            match reload() {
+                Ok(()) => info!("Reloaded the {what}"),
+                Err(e) => {
+                    error!("Could not reload the {what}, the previous ones are still in use: {e:#}")
+                }
+            }
+        }
+    })
+}
+
+/// Reloads the TLS certificate served by `resolver` when its files change.
+pub fn watch_tls(
+    resolver: Arc<CertResolver>,
+    cert: PathBuf,
+    key: PathBuf,
+    interval: Duration,
+) -> JoinHandle<()> {
+    watch(
+        "TLS certificate and key",
+        vec![cert.clone(), key.clone()],
+        interval,
+        move || {
+            let cert = fs::read(&cert).with_context(|| anyhow!("Reading {cert:?}"))?;
+            let key = fs::read(&key).with_context(|| anyhow!("Reading {key:?}"))?;
+            resolver.reload(&cert, &key)
+        },
+    )
+}
+
+/// Reloads the public keys of the owners and users when the files of `dir` change.
+pub fn watch_public_keys(
+    sess_manager: Arc<SessionManager>,
+    dir: PathBuf,
+    interval: Duration,
+) -> JoinHandle<()> {
+    watch("public keys", vec![dir.clone()], interval, move || {
+        let keys = KeyManagement::load_from_dir(&dir).map_err(|e| anyhow!("{}", e.message()))?;
+        sess_manager.reload_keys(keys);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::SocketAddr;
+    use std::time::{Instant, SystemTime};
+
+    use hyper::client::conn::{ResponseFuture, SendRequest};
+    use hyper::Body;
+    use ring::digest::digest;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Semaphore;
+    use tokio_rustls::rustls::{ClientConfig, Session as _};
+    use tokio_rustls::{webpki::DNSNameRef, TlsConnector};
+
+    use crate::config::BastionLabConfig;
+    use crate::connections::ConnectionManager;
+    use crate::session::Session;
+    use crate::session_proto::ClientInfo;
+
+    const INTERVAL: Duration = Duration::from_millis(20);
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/");
+        fs::read(Path::new(path).join(name)).unwrap()
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("bastionlab-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            tokio::time::sleep(INTERVAL).await;
+        }
+    }
+
+    /// Serves requests, those to `/slow` only completing once `gate` has a permit.
+    async fn serve(acceptor: tokio_rustls::TlsAcceptor, gate: Arc<Semaphore>) -> SocketAddr {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = tower::service_fn(move |req: http::Request<Body>| {
+            let gate = gate.clone();
+            async move {
+                if req.uri().path() == "/slow" {
+                    gate.acquire().await.unwrap().forget();
+                }
+                Ok::<_, std::convert::Infallible>(http::Response::new(Body::from("ok")))
+            }
+        });
+        let manager = Arc::new(ConnectionManager::new(&config));
+        tokio::spawn(manager.serve(listener, Some(acceptor), service));
+        addr
+    }
+
+    /// Opens a connection, returning the certificate the server presented.
+    async fn connect(addr: SocketAddr) -> (Vec<u8>, SendRequest<Body>) {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut BufReader::new(&fixture("ca.pem")[..]))
+            .unwrap();
+        config.set_protocols(&[b"h2".to_vec()]);
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let cert = tls.get_ref().1.get_peer_certificates().unwrap()[0]
+            .0
+            .clone();
+        let (sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(tls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        (cert, sender)
+    }
+
+    fn get(sender: &mut SendRequest<Body>, path: &str) -> ResponseFuture {
+        let req = http::Request::get(format!("https://localhost{path}"))
+            .body(Body::empty())
+            .unwrap();
+        sender.send_request(req)
+    }
+
+    fn leaf(name: &str) -> Vec<u8> {
+        let pem = fixture(name);
+        tokio_rustls::rustls::internal::pemfile::certs(&mut BufReader::new(&pem[..])).unwrap()[0]
+            .0
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn new_connections_use_the_reloaded_certificate() {
+        let dir = TempDir::new();
+        let (cert, key) = (dir.0.join("server.pem"), dir.0.join("server.key"));
+        fs::write(&cert, fixture("first.pem")).unwrap();
+        fs::write(&key, fixture("first.key")).unwrap();
+
+        let resolver =
+            Arc::new(CertResolver::new(&fixture("first.pem"), &fixture("first.key")).unwrap());
+        let watcher = watch_tls(resolver.clone(), cert.clone(), key.clone(), INTERVAL);
+        let gate = Arc::new(Semaphore::new(0));
+        let addr = serve(resolver.acceptor(), gate.clone()).await;
+
+        let (presented, mut first) = connect(addr).await;
+        assert_eq!(presented, leaf("first.pem"));
+        let in_flight = tokio::spawn(get(&mut first, "/slow"));
+
+        // Swapped on disk one file after the other, as certificate renewals do.
+        fs::write(&cert, fixture("second.pem")).unwrap();
+        tokio::time::sleep(INTERVAL * 3).await;
+        fs::write(&key, fixture("second.key")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if connect(addr).await.0 == leaf("second.pem") {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "The certificate was not reloaded"
+            );
+            tokio::time::sleep(INTERVAL).await;
+        }
+
+        // The connection opened before the reload keeps working, including its streams in flight.
+        gate.add_permits(1);
+        in_flight.await.unwrap().unwrap();
+        get(&mut first, "/").await.unwrap();
+
+        // A broken certificate is not picked up.
+        fs::write(&cert, b"not a certificate").unwrap();
+        tokio::time::sleep(INTERVAL * 5).await;
+        assert_eq!(connect(addr).await.0, leaf("second.pem"));
+        watcher.abort();
+    }
+
+    #[test]
+    fn keys_must_match_their_certificate() {
+        let err = CertResolver::new(&fixture("first.pem"), &fixture("second.key"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("does not match"), "{err}");
+
+        let resolver = CertResolver::new(&fixture("first.pem"), &fixture("first.key")).unwrap();
+        assert!(resolver
+            .reload(&fixture("second.pem"), &fixture("first.key"))
+            .is_err());
+        assert!(resolver.reload(b"", &fixture("second.key")).is_err());
+        resolver
+            .reload(&fixture("second.pem"), &fixture("second.key"))
+            .unwrap();
+    }
+
+    fn write_key(path: PathBuf, contents: &[u8]) -> String {
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode(contents)
+        );
+        fs::write(path, pem).unwrap();
+        hex::encode(digest(&SHA256, contents))
+    }
+
+    #[tokio::test]
+    async fn public_keys_are_reloaded() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.0.join("owners")).unwrap();
+        fs::create_dir_all(dir.0.join("users")).unwrap();
+        let owner = write_key(dir.0.join("owners/owner.pem"), b"owner");
+        let user = write_key(dir.0.join("users/user.pem"), b"user");
+
+        let keys = KeyManagement::load_from_dir(&dir.0).unwrap();
+        let sess_manager = Arc::new(SessionManager::new(Some(keys), 3600));
+        sess_manager.sessions.write().unwrap().insert(
+            [1; 32],
+            Session {
+                pubkey: user.clone(),
+                user_ip: "127.0.0.1:1".parse().unwrap(),
+                expiry: SystemTime::now() + Duration::from_secs(3600),
+                client_info: ClientInfo::default(),
+                connection: None,
+            },
+        );
+        let watcher = watch_public_keys(sess_manager.clone(), dir.0.clone(), INTERVAL);
+        tokio::time::sleep(INTERVAL).await;
+
+        let new_owner = write_key(dir.0.join("owners/new_owner.pem"), b"new owner");
+        eventually(|| sess_manager.verify_if_owner(&new_owner).unwrap()).await;
+        assert!(sess_manager.verify_if_owner(&owner).unwrap());
+        assert_eq!(sess_manager.sessions.read().unwrap().len(), 1);
+
+        // Revoked keys lose their sessions.
+        fs::remove_file(dir.0.join("users/user.pem")).unwrap();
+        eventually(|| sess_manager.sessions.read().unwrap().is_empty()).await;
+
+        // An unreadable directory keeps the previous keys.
+        fs::remove_dir_all(dir.0.join("users")).unwrap();
+        tokio::time::sleep(INTERVAL * 5).await;
+        assert!(sess_manager.verify_if_owner(&new_owner).unwrap());
+        watcher.abort();
+    }
+}
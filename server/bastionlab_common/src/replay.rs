@@ -0,0 +1,260 @@
+//! Protection of signed requests against replays and against skewed client clocks.
+//!
+//! Signed requests carry the time they were signed at and a nonce, both covered by the signature.
+//! The server accepts timestamps at most `signature_max_skew_secs` away from its clock and, per
+//! identity, only nonces greater than the last one it accepted. Clients derive nonces from their
+//! clock, so that they keep increasing across client restarts, and measure how far their clock is
+//! from the server's with `GetServerTime`.
+//!
+//! Only the identities that signed a request within the skew window are remembered: older
+//! requests are rejected by their timestamp anyway, which bounds memory.
+//!
+//! The nonces are persisted lazily, at most every `replay_persist_interval_secs`, so the last ones
+//! accepted before a crash may be lost. A restarted server rejects the requests signed before the
+//! persisted state could have fallen behind, plus the skew: legitimate clients retry for a few
+//! seconds after a restart, rather than captured requests being accepted. A missing state file is
+//! a first start, an unreadable one rejects the requests signed before the restart.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::atomic_file;
+use crate::prelude::*;
+
+/// The current time, in milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// What is persisted of the guard.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// When the state was taken, in milliseconds since the Unix epoch.
+    saved_at: u64,
+    /// By identity, the last nonce accepted and the timestamp of its request.
+    nonces: HashMap<String, (u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct ReplayGuard {
+    max_skew_ms: u64,
+    persist_interval_ms: u64,
+    /// Where the state is persisted, if anywhere.
+    path: Option<PathBuf>,
+    /// Requests signed before this are rejected, see the module documentation.
+    restart_floor: u64,
+    state: Mutex<State>,
+    /// Held while the state is written, so that an older state never replaces a newer one.
+    saving: Mutex<()>,
+}
+
+impl ReplayGuard {
+    /// A guard whose state only lives in memory.
+    pub fn new(max_skew_secs: u64) -> Self {
+        ReplayGuard {
+            max_skew_ms: max_skew_secs * 1000,
+            persist_interval_ms: 0,
+            path: None,
+            restart_floor: 0,
+            state: Default::default(),
+            saving: Default::default(),
+        }
+    }
+
+    /// A guard persisting its state to `path`, recovering the state a previous server left there.
+    pub fn open(path: &Path, max_skew_secs: u64, persist_interval_secs: u64) -> Self {
+        Self::open_at(path, max_skew_secs, persist_interval_secs, now_ms())
+    }
+
+    fn open_at(path: &Path, max_skew_secs: u64, persist_interval_secs: u64, now: u64) -> Self {
+        let mut guard = ReplayGuard {
+            path: Some(path.to_path_buf()),
+            persist_interval_ms: persist_interval_secs * 1000,
+            ..Self::new(max_skew_secs)
+        };
+        let state = match std::fs::read(path) {
+            Ok(buf) => match serde_json::from_slice::<State>(&buf) {
+                Ok(state) => {
+                    guard.restart_floor =
+                        state.saved_at + guard.persist_interval_ms + guard.max_skew_ms;
+                    state
+                }
+                Err(e) => {
+                    warn!("Unreadable replay protection state {}: {e}", path.display());
+                    guard.restart_floor = now + guard.max_skew_ms;
+                    State::default()
+                }
+            },
+            Err(_) => State::default(),
+        };
+        guard.state = Mutex::new(state);
+        guard.save(now);
+        guard
+    }
+
+    /// How far from the server clock timestamps may be.
+    pub fn max_skew_ms(&self) -> u64 {
+        self.max_skew_ms
+    }
+
+    /// Accepts a request of `identity` signed at `timestamp` with `nonce`, or tells why it is
+    /// rejected.
+    pub fn check(&self, identity: &str, timestamp: u64, nonce: u64) -> Result<(), Status> {
+        self.check_at(identity, timestamp, nonce, now_ms())
+    }
+
+    fn check_at(&self, identity: &str, timestamp: u64, nonce: u64, now: u64) -> Result<(), Status> {
+        let skew = timestamp.abs_diff(now);
+        if skew > self.max_skew_ms {
+            return Err(Status::permission_denied(format!(
+                "The request was signed {skew} ms away from the server clock, more than the {} \
+                 ms tolerated: adjust the client clock with GetServerTime",
+                self.max_skew_ms
+            )));
+        }
+        if timestamp < self.restart_floor {
+            return Err(Status::permission_denied(format!(
+                "Replay suspected: the request was signed before the server restarted, retry \
+                 in {} ms",
+                self.restart_floor - timestamp
+            )));
+        }
+
+        let save = {
+            let mut state = self.state.lock().expect("Poisoned lock");
+            match state.nonces.get(identity) {
+                Some(&(last, _)) if nonce <= last => {
+                    return Err(Status::permission_denied(format!(
+                        "Replay suspected: nonce {nonce} is not greater than the last one \
+                         accepted from this identity, {last}"
+                    )))
+                }
+                Some(_) => (),
+                // Only identities that signed a request within the skew window are kept.
+                None => {
+                    let oldest = now.saturating_sub(self.max_skew_ms);
+                    state
+                        .nonces
+                        .retain(|_, &mut (_, timestamp)| timestamp >= oldest);
+                }
+            }
+            state
+                .nonces
+                .insert(identity.to_string(), (nonce, timestamp));
+            now >= state.saved_at + self.persist_interval_ms
+        };
+        if save {
+            self.save(now);
+        }
+        Ok(())
+    }
+
+    /// Persists the state, if the guard has a file.
+    fn save(&self, now: u64) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let _saving = match self.saving.try_lock() {
+            Ok(saving) => saving,
+            // Another request is saving the state, less than an interval after this one was
+            // accepted.
+            Err(_) => return,
+        };
+        let buf = {
+            let mut state = self.state.lock().expect("Poisoned lock");
+            state.saved_at = now;
+            serde_json::to_vec(&*state)
+        };
+        let written = buf
+            .map_err(|e| e.to_string())
+            .and_then(|buf| atomic_file::write(path, &buf).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            error!("Could not persist the replay protection state: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn message(result: Result<(), Status>) -> String {
+        let err = result.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        err.message().to_string()
+    }
+
+    #[test]
+    fn nonces_must_increase_per_identity() {
+        let guard = ReplayGuard::new(30);
+        guard.check_at("alice", NOW, 10, NOW).unwrap();
+        // A captured request, replayed.
+        assert!(message(guard.check_at("alice", NOW, 10, NOW + 5)).contains("Replay suspected"));
+        assert!(message(guard.check_at("alice", NOW, 9, NOW + 5)).contains("Replay suspected"));
+        guard.check_at("bob", NOW, 1, NOW).unwrap();
+        guard.check_at("alice", NOW + 5, 11, NOW + 5).unwrap();
+    }
+
+    #[test]
+    fn timestamps_are_accepted_up_to_the_skew() {
+        let guard = ReplayGuard::new(30);
+        guard.check_at("ahead", NOW + 30_000, 1, NOW).unwrap();
+        guard.check_at("behind", NOW - 30_000, 1, NOW).unwrap();
+        assert!(message(guard.check_at("ahead", NOW + 30_001, 2, NOW)).contains("GetServerTime"));
+        assert!(message(guard.check_at("behind", NOW - 30_001, 2, NOW)).contains("30001 ms"));
+    }
+
+    #[test]
+    fn only_identities_within_the_skew_window_are_kept() {
+        let guard = ReplayGuard::new(30);
+        for i in 0..100 {
+            guard.check_at(&format!("key-{i}"), NOW, 1, NOW).unwrap();
+        }
+        guard
+            .check_at("late", NOW + 30_001, 1, NOW + 30_001)
+            .unwrap();
+        assert_eq!(guard.state.lock().unwrap().nonces.len(), 1);
+    }
+
+    #[test]
+    fn restarts_reject_requests_the_lost_state_may_have_accepted() {
+        let dir = std::env::temp_dir().join(format!("replay-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nonces.json");
+
+        // A missing file is a first start.
+        let guard = ReplayGuard::open_at(&path, 30, 5, NOW);
+        guard
+            .check_at("alice", NOW + 5_000, 10, NOW + 5_000)
+            .unwrap();
+        guard
+            .check_at("alice", NOW + 6_000, 11, NOW + 6_000)
+            .unwrap();
+        // Only the state with nonce 10 was saved: nonce 11 is lost on a crash.
+        let restarted = ReplayGuard::open_at(&path, 30, 5, NOW + 7_000);
+        let err = message(restarted.check_at("alice", NOW + 6_000, 11, NOW + 7_000));
+        assert!(err.contains("restarted"), "{err}");
+        // Requests signed once the lost nonces can no longer be replayed are accepted.
+        let floor = NOW + 5_000 + 5_000 + 30_000;
+        assert!(restarted.check_at("alice", floor - 1, 12, floor).is_err());
+        let err = message(restarted.check_at("alice", floor, 10, floor));
+        assert!(err.contains("Replay suspected"), "{err}");
+        restarted.check_at("alice", floor, 12, floor).unwrap();
+
+        std::fs::write(&path, b"garbage").unwrap();
+        let restarted = ReplayGuard::open_at(&path, 30, 5, floor);
+        let (late, later) = (floor + 29_999, floor + 30_000);
+        assert!(restarted.check_at("alice", late, 13, late).is_err());
+        restarted.check_at("alice", later, 13, later).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
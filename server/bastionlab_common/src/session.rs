@@ -1,5 +1,6 @@
-use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
@@ -7,48 +8,139 @@ use prost::Message;
 use tonic::metadata::KeyRef;
 use tonic::{Request, Response, Status};
 
-use crate::auth::KeyManagement;
+use crate::auth::{KeyEntry, KeyManagement, KeyRole};
+use crate::challenges::{ChallengeStore, Holder, Spend};
+use crate::connections::ConnectionId;
+use crate::replay::{now_ms, ReplayGuard};
 use crate::session_proto::{ClientInfo, SessionInfo};
 use crate::{prelude::*, session_proto};
 
+/// The signed message: the method, the challenge, the timestamp and nonce of the request, see
+/// [`crate::replay`], then the request itself.
 fn get_message<T: Message>(
     method: &[u8],
     req: &Request<T>,
     challenge: Bytes,
+    timestamp: u64,
+    nonce: u64,
 ) -> Result<Vec<u8>, Status> {
-    let mut res =
-        Vec::with_capacity(method.len() + challenge.as_ref().len() + req.get_ref().encoded_len());
+    let mut res = Vec::with_capacity(
+        method.len() + challenge.as_ref().len() + 16 + req.get_ref().encoded_len(),
+    );
     res.extend_from_slice(method);
     res.extend_from_slice(challenge.as_ref());
+    res.extend_from_slice(&timestamp.to_be_bytes());
+    res.extend_from_slice(&nonce.to_be_bytes());
     req.get_ref()
         .encode(&mut res)
         .map_err(|e| Status::internal(format!("error while encoding the request: {:?}", e)))?;
     Ok(res)
 }
 
+/// Reads the big-endian u64 of binary metadata `key`.
+fn get_u64<T>(req: &Request<T>, key: &str) -> Result<u64, Status> {
+    let value = req
+        .metadata()
+        .get_bin(key)
+        .ok_or_else(|| Status::invalid_argument(format!("No {key} in request metadata")))?
+        .to_bytes()
+        .map_err(|_| Status::invalid_argument(format!("Could not decode {key}")))?;
+    let bytes: [u8; 8] = value[..]
+        .try_into()
+        .map_err(|_| Status::invalid_argument(format!("{key} must be 8 bytes long")))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
 #[derive(Debug)]
 pub struct Session {
     pub pubkey: String,
     pub user_ip: SocketAddr,
     pub expiry: SystemTime,
     pub client_info: ClientInfo,
+    /// The connection the session was opened on, the only one it can be used on.
+    pub connection: Option<ConnectionId>,
 }
 
 #[derive(Debug)]
 pub struct SessionManager {
     keys: Option<Mutex<KeyManagement>>,
+    /// Where keys added and removed at runtime are persisted, see [`SessionManager::add_key`].
+    keys_dir: Option<PathBuf>,
     pub sessions: Arc<RwLock<HashMap<[u8; 32], Session>>>,
     session_expiry: u64,
-    challenges: Mutex<HashSet<[u8; 32]>>,
+    challenges: ChallengeStore,
+    replay: ReplayGuard,
+    /// Incremented on every key reload, so that long-running operations can tell cheaply whether
+    /// the keys they checked may have changed.
+    key_generation: AtomicU64,
 }
 
 impl SessionManager {
     pub fn new(keys: Option<KeyManagement>, session_expiry: u64) -> Self {
         Self {
             keys: keys.map(Mutex::new),
+            keys_dir: None,
             sessions: Default::default(),
             session_expiry,
             challenges: Default::default(),
+            replay: ReplayGuard::new(30),
+            key_generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Replaces the in-memory replay protection, tolerating 30 seconds of skew.
+    pub fn with_replay_guard(mut self, replay: ReplayGuard) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    /// Lets data owners add and remove keys at runtime, persisting them to the public keys
+    /// directory `dir` the keys were loaded from.
+    pub fn with_keys_dir(mut self, dir: PathBuf) -> Self {
+        self.keys_dir = Some(dir);
+        self
+    }
+
+    /// Replaces the challenge store, whose challenges expire after 5 minutes and of which at most
+    /// 100000 are outstanding, 100 per holder, see [`crate::challenges`].
+    pub fn with_challenges(
+        mut self,
+        ttl: Duration,
+        max_outstanding: usize,
+        max_per_holder: usize,
+    ) -> Self {
+        self.challenges = ChallengeStore::new(ttl, max_outstanding, max_per_holder);
+        self
+    }
+
+    /// Evicts the expired challenges, returning how many.
+    pub fn evict_expired_challenges(&self) -> usize {
+        self.challenges.evict_expired()
+    }
+
+    /// Evicts the expired challenges every `interval`, in the background.
+    pub fn evict_challenges_every(
+        self: &Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let sess_manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let evicted = sess_manager.evict_expired_challenges();
+                if evicted > 0 {
+                    debug!("Evicted {evicted} expired challenges");
+                }
+            }
+        })
+    }
+
+    /// The server clock signed requests are checked against.
+    pub fn server_time(&self) -> session_proto::ServerTime {
+        session_proto::ServerTime {
+            unix_ms: now_ms(),
+            max_skew_ms: self.replay.max_skew_ms(),
         }
     }
 
@@ -103,36 +195,156 @@ impl SessionManager {
         Ok(session.client_info.clone())
     }
 
-    fn new_challenge(&self) -> [u8; 32] {
-        let rng = ring::rand::SystemRandom::new();
-        loop {
-            if let Ok(challenge) = ring::rand::generate(&rng) {
-                let challenge: [u8; 32] = challenge.expose();
-                let mut lock = self.challenges.lock().expect("Poisoned lock");
-                lock.insert(challenge);
-                return challenge;
-            }
-        }
+    /// Issues a challenge to the key the request states, or else to its connection, see
+    /// [`crate::challenges`].
+    fn new_challenge(
+        &self,
+        request: &Request<session_proto::ChallengeRequest>,
+    ) -> Result<[u8; 32], Status> {
+        let connection = request.extensions().get::<ConnectionId>().copied();
+        let holder = Holder::new(&request.get_ref().public_key_hash, connection)?;
+        self.challenges.issue(holder)
     }
 
-    fn check_challenge<T: Message>(&self, request: &Request<T>) -> Result<Bytes, Status> {
+    /// Spends the challenge of a request signed by `pubkey_hash`.
+    fn check_challenge<T: Message>(
+        &self,
+        request: &Request<T>,
+        pubkey_hash: &str,
+    ) -> Result<Bytes, Status> {
         let challenge = request.metadata()
             .get_bin("challenge-bin")
             .ok_or_else(|| Status::unauthenticated("You must be authenticated to perform this action. Please reconnect with an identity."))?;
         let challenge_bytes = challenge.to_bytes().map_err(|_| {
             Status::invalid_argument(format!("Could not decode challenge {:?}", challenge))
         })?;
-        let challenge = challenge_bytes.as_ref();
+        let connection = request.extensions().get::<ConnectionId>().copied();
+        match self
+            .challenges
+            .spend(&challenge_bytes, pubkey_hash, connection)
         {
-            let mut lock = self.challenges.lock().expect("Poisoned lock");
-            if !lock.remove(challenge) {
-                return Err(Status::permission_denied("Challenge not found!"));
+            Spend::Spent => (),
+            Spend::Expired => {
+                return Err(Status::permission_denied(
+                    "Challenge expired! Request a new one.",
+                ))
+            }
+            Spend::Unknown => {
+                return Err(Status::permission_denied(
+                    "Challenge not found! It was never issued or was already used.",
+                ))
+            }
+            Spend::Mismatch => {
+                return Err(Status::permission_denied(
+                    "Challenge issued to another identity! Request a new one.",
+                ))
             }
         }
 
         Ok(challenge_bytes)
     }
 
+    /// Ends the sessions opened on a connection that closed, so that the client authenticates
+    /// again on its next connection.
+    pub fn close_connection(&self, connection: ConnectionId) {
+        self.sessions
+            .write()
+            .expect("Poisoned lock")
+            .retain(|_, session| session.connection != Some(connection));
+    }
+
+    /// Replaces the public keys of the owners and users.
+    ///
+    /// The sessions of removed keys are ended and their outstanding challenges revoked, other
+    /// sessions are untouched. This does nothing when authentication is disabled.
+    pub fn reload_keys(&self, keys: KeyManagement) {
+        if let Some(lock) = &self.keys {
+            let mut current = lock.lock().expect("Poisoned lock");
+            self.sessions
+                .write()
+                .expect("Poisoned lock")
+                .retain(|_, session| keys.contains(&session.pubkey));
+            for (hash, _) in current.keys() {
+                if !keys.contains(&hash) {
+                    self.challenges.revoke(&hash);
+                }
+            }
+            *current = keys;
+            self.key_generation.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// The keys in use and the directory they are persisted to, failing unless keys are managed
+    /// at runtime.
+    fn managed_keys(&self) -> Result<(&Mutex<KeyManagement>, &Path), Status> {
+        match (&self.keys, &self.keys_dir) {
+            (Some(keys), Some(dir)) => Ok((keys, dir)),
+            _ => Err(Status::failed_precondition(
+                "The public keys of this server are not managed at runtime",
+            )),
+        }
+    }
+
+    /// The hashes of the keys in use with their roles, sorted by hash.
+    pub fn public_keys(&self) -> Vec<(String, KeyRole)> {
+        match &self.keys {
+            Some(lock) => lock.lock().expect("Poisoned lock").keys(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Adds PEM public key `pem` to the public keys directory and to the keys in use: it
+    /// authenticates at once, without waiting for the directory to be reloaded.
+    pub fn add_key(&self, role: KeyRole, pem: &[u8]) -> Result<KeyEntry, Status> {
+        let (lock, dir) = self.managed_keys()?;
+        let mut keys = lock.lock().expect("Poisoned lock");
+        let entry = KeyManagement::add_key(dir, role, pem)
+            .map_err(|e| Status::invalid_argument(format!("Could not add the key: {e:#}")))?;
+        keys.insert(role, pem)
+            .map_err(|e| Status::internal(format!("Could not add key {}: {e:#}", entry.hash)))?;
+        self.key_generation.fetch_add(1, Ordering::Release);
+        Ok(entry)
+    }
+
+    /// Removes the key with `hash` from the public keys directory and from the keys in use: its
+    /// sessions end and its outstanding challenges are revoked at once. The last owner key is
+    /// kept, so that the server stays manageable.
+    pub fn remove_key(&self, hash: &str) -> Result<KeyEntry, Status> {
+        let (lock, dir) = self.managed_keys()?;
+        let mut keys = lock.lock().expect("Poisoned lock");
+        if keys.verify_owner(hash) && keys.owner_count() == 1 {
+            return Err(Status::failed_precondition(format!(
+                "Could not remove key {hash}: it is the last owner key"
+            )));
+        }
+        let entry = KeyManagement::revoke_key(dir, hash)
+            .map_err(|e| Status::not_found(format!("Could not remove key {hash}: {e:#}")))?;
+        keys.remove(&entry.hash);
+        drop(keys);
+        // Once the key is out, so that no session of it can be opened meanwhile.
+        self.sessions
+            .write()
+            .expect("Poisoned lock")
+            .retain(|_, session| session.pubkey != entry.hash);
+        self.challenges.revoke(&entry.hash);
+        self.key_generation.fetch_add(1, Ordering::Release);
+        Ok(entry)
+    }
+
+    /// Number of key reloads so far.
+    pub fn key_generation(&self) -> u64 {
+        self.key_generation.load(Ordering::Acquire)
+    }
+
+    /// Whether `public_hash` is still one of the owner or user keys. Always true when
+    /// authentication is disabled.
+    pub fn is_active(&self, public_hash: &str) -> bool {
+        match &self.keys {
+            Some(lock) => lock.lock().expect("Poisoned lock").contains(public_hash),
+            None => true,
+        }
+    }
+
     pub fn verify_if_owner(&self, public_hash: &str) -> Result<bool, Status> {
         if self.auth_enabled() == false {
             return Err(Status::permission_denied(
@@ -163,6 +375,7 @@ impl SessionManager {
                     user_ip,
                     expiry,
                     client_info: request.into_inner(),
+                    connection: None,
                 },
             );
             return Ok(SessionInfo {
@@ -175,8 +388,6 @@ impl SessionManager {
         // unwrap: self.keys is not None since auth is enabled
         let keys_lock = self.keys.as_ref().unwrap().lock().expect("Poisoned lock");
 
-        let challenge = self.check_challenge(&request)?;
-
         // stripped key hash from the request metadata
         let pubkey_hash = request
             .metadata()
@@ -194,18 +405,30 @@ impl SessionManager {
             .ok_or_else(|| {
                 Status::unauthenticated("You are not authenticated. Please provide an identity.")
             })?;
+        let challenge = self.check_challenge(&request, pubkey_hash)?;
 
         // verify signature
-        let message = get_message(b"create-session", &request, challenge.clone())?;
+        let timestamp = get_u64(&request, "timestamp-bin")?;
+        let nonce = get_u64(&request, "nonce-bin")?;
+        let message = get_message(
+            b"create-session",
+            &request,
+            challenge.clone(),
+            timestamp,
+            nonce,
+        )?;
         keys_lock.verify_signature(pubkey_hash, &message[..], request.metadata())?;
+        // Only once the signature is verified, so that unknown identities take no memory.
+        self.replay.check(pubkey_hash, timestamp, nonce)?;
 
         let (token, expiry) = {
             let time = SystemTime::now();
             let expiry = time
                 .checked_add(Duration::from_secs(self.session_expiry))
                 .unwrap_or(time);
-            (self.new_challenge(), expiry)
+            (self.challenges.generate(), expiry)
         };
+        let connection = request.extensions().get::<ConnectionId>().copied();
         sessions.insert(
             token.clone(),
             Session {
@@ -213,6 +436,7 @@ impl SessionManager {
                 user_ip,
                 expiry,
                 client_info: request.into_inner(),
+                connection,
             },
         );
         Ok(SessionInfo {
@@ -222,6 +446,66 @@ impl SessionManager {
     }
 }
 
+/// Checks the access token of the requests to authenticated services.
+#[derive(Clone)]
+pub struct TokenValidator {
+    sess_manager: Arc<SessionManager>,
+}
+
+impl TokenValidator {
+    pub fn new(sess_manager: Arc<SessionManager>) -> Self {
+        Self { sess_manager }
+    }
+}
+
+impl tonic::service::Interceptor for TokenValidator {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        if !self.sess_manager.auth_enabled() {
+            return Ok(req);
+        }
+        let meta = req
+            .metadata()
+            .get_bin("accesstoken-bin")
+            .ok_or_else(|| Status::invalid_argument("No access token in request metadata"))?;
+
+        let access_token = meta
+            .to_bytes()
+            .map_err(|_| Status::invalid_argument("Could not decode accesstoken"))?;
+
+        let mut tokens = self.sess_manager.sessions.write().expect("Poisoned lock");
+
+        let session = tokens
+            .get(access_token.as_ref())
+            .ok_or_else(|| Status::aborted("Session not found!"))?;
+
+        let recv_ip = &req
+            .remote_addr()
+            .ok_or_else(|| Status::aborted("User IP unavailable"))?;
+
+        // ip verification
+        if session.user_ip.ip() != recv_ip.ip() {
+            return Err(Status::aborted("Unknown IP Address!"));
+        }
+
+        // connection verification
+        let connection = req.extensions().get::<ConnectionId>();
+        if session.connection.is_some() && session.connection.as_ref() != connection {
+            return Err(Status::unauthenticated(
+                "This session was opened on another connection, please authenticate again",
+            ));
+        }
+
+        // expiry verification
+        let curr_time = SystemTime::now();
+        if curr_time > session.expiry {
+            tokens.remove(access_token.as_ref());
+            return Err(Status::aborted("Session Expired"));
+        }
+
+        Ok(req)
+    }
+}
+
 pub struct SessionGrpcService {
     sess_manager: Arc<SessionManager>,
 }
@@ -236,9 +520,9 @@ impl SessionGrpcService {
 impl session_proto::session_service_server::SessionService for SessionGrpcService {
     async fn get_challenge(
         &self,
-        _request: Request<session_proto::Empty>,
+        request: Request<session_proto::ChallengeRequest>,
     ) -> Result<Response<session_proto::ChallengeResponse>, Status> {
-        let challenge = self.sess_manager.new_challenge();
+        let challenge = self.sess_manager.new_challenge(&request)?;
         Ok(Response::new(session_proto::ChallengeResponse {
             value: challenge.into(),
         }))
@@ -251,4 +535,20 @@ impl session_proto::session_service_server::SessionService for SessionGrpcServic
         let session = self.sess_manager.create_session(request)?;
         Ok(Response::new(session))
     }
+
+    async fn get_server_time(
+        &self,
+        _request: Request<session_proto::Empty>,
+    ) -> Result<Response<session_proto::ServerTime>, Status> {
+        Ok(Response::new(self.sess_manager.server_time()))
+    }
+
+    async fn ping(
+        &self,
+        _request: Request<session_proto::Empty>,
+    ) -> Result<Response<session_proto::PingResponse>, Status> {
+        Ok(Response::new(session_proto::PingResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
 }
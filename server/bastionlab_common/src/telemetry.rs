@@ -34,6 +34,16 @@ pub enum TelemetryEventProps {
     DeleteDataframe {
         dataset_name: Option<String>,
     },
+    MemoryPressure {
+        level: String,
+        usage_bytes: u64,
+    },
+    StorageClasses {
+        hot_bytes: u64,
+        warm_bytes: u64,
+        cold_bytes: u64,
+        disk_bytes: u64,
+    },
     // Torch
     SendModel {
         model_name: Option<String>,
@@ -74,6 +84,8 @@ impl TelemetryEventProps {
             TelemetryEventProps::GetDataFrameHeader { .. } => "get_data_frame_header",
             TelemetryEventProps::SaveDataframe { .. } => "save_data_frame",
             TelemetryEventProps::DeleteDataframe { .. } => "delete_data_frame",
+            TelemetryEventProps::MemoryPressure { .. } => "memory_pressure",
+            TelemetryEventProps::StorageClasses { .. } => "storage_classes",
             // torch
             TelemetryEventProps::SendModel { .. } => "send_model",
             TelemetryEventProps::SendDataset { .. } => "send_dataset",
@@ -0,0 +1,74 @@
+//! An in-process engine reading an approved query result over the Arrow C stream interface.
+//!
+//! The consumer side only relies on the C interface: arrow-rs consumers would use
+//! `arrow::ffi_stream::ArrowArrayStreamReader::from_raw` on the same pointer.
+
+use std::sync::Arc;
+
+use bastionlab_common::config::BastionLabConfig;
+use bastionlab_common::session::SessionManager;
+use bastionlab_polars::access_control::Policy;
+use bastionlab_polars::composite_plan::{CompositePlan, CompositePlanSegment};
+use bastionlab_polars::{BastionLabPolars, DataFrameArtifact};
+use polars::export::arrow::array::StructArray;
+use polars::export::arrow::datatypes::DataType;
+use polars::export::arrow::ffi;
+use polars::prelude::*;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config: BastionLabConfig = serde_json::from_str(
+        r#"{
+            "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+            "public_keys_directory": "keys/",
+            "session_expiry_in_secs": 3600
+        }"#,
+    )?;
+    let polars = BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config);
+
+    let df = df! {
+        "ward" => ["a", "b", "a", "c"],
+        "weight" => [70.5, 62.0, 80.0, 75.5],
+    }?;
+    let uploaded = polars.insert_df(DataFrameArtifact::new(
+        df,
+        Policy::allow_by_default(),
+        Vec::new(),
+    ));
+    let result = CompositePlan::new(vec![CompositePlanSegment::EntryPointPlanSegment {
+        identifier: uploaded,
+    }])
+    .run(&polars, "engine")?;
+    let result = polars.insert_df(result);
+
+    // The engine provides the struct the stream is moved into, as a C consumer would.
+    let mut stream = Box::new(ffi::ArrowArrayStream::empty());
+    unsafe {
+        polars
+            .export_arrow(&result, "engine")
+            .await?
+            .move_into(&mut *stream as *mut ffi::ArrowArrayStream as *mut _);
+    }
+    println!("Pinned exports: {:?}", polars.arrow_exports());
+
+    let mut reader = unsafe { ffi::ArrowArrayStreamReader::try_new(stream)? };
+    let fields = match reader.field().data_type() {
+        DataType::Struct(fields) => fields.clone(),
+        dtype => return Err(format!("Unexpected dtype {dtype:?}").into()),
+    };
+    while let Some(batch) = unsafe { reader.next() } {
+        let batch = batch?;
+        let batch = batch
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .ok_or("Batches are struct arrays")?;
+        for (field, values) in fields.iter().zip(batch.values()) {
+            println!("{}: {} values", field.name, values.len());
+        }
+    }
+
+    // Dropping the reader releases the stream, which unpins the result.
+    drop(reader);
+    assert!(polars.arrow_exports().is_empty());
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Compares the buffered IPC and the per-column upload paths.
+//!
+//! Run with `cargo run --release --example ingest_bench -- [size in MiB]` (256 by default). For
+//! each path, prints the ingest time and the memory used at the peak on top of the dataframe.
+
+#[path = "../tests/common/peak_alloc.rs"]
+mod peak_alloc;
+
+use std::time::Instant;
+
+use bastionlab_polars::access_control::Policy;
+use bastionlab_polars::polars_proto::SendChunk;
+use bastionlab_polars::serialization::{
+    column_upload_chunks, dataframe_ser_helper, upload_chunks, UploadAssembler,
+};
+use peak_alloc::PeakAlloc;
+use polars::prelude::*;
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+fn ingest(name: &str, chunks: &[SendChunk]) -> Result<(), Box<dyn std::error::Error>> {
+    peak_alloc::reset_peak();
+    let base = peak_alloc::current();
+    let start = Instant::now();
+
+    let mut assembler = UploadAssembler::new();
+    for chunk in chunks {
+        assembler.push(chunk.clone())?;
+    }
+    let upload = assembler.finish()?;
+
+    let elapsed = start.elapsed();
+    let decoded = peak_alloc::current() - base;
+    let overhead = peak_alloc::peak() - base - decoded;
+    println!(
+        "{name}: {} rows in {elapsed:.2?}, {} MiB on top of the {} MiB dataframe",
+        upload.dataframe.height(),
+        overhead >> 20,
+        decoded >> 20,
+    );
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let size: usize = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => 256,
+    };
+    let rows = (size << 20) / 32;
+    let df = df! {
+        "id" => (0..rows as i64).collect::<Vec<_>>(),
+        "value" => (0..rows).map(|i| i as f64 * 0.5).collect::<Vec<_>>(),
+        "label" => (0..rows).map(|i| format!("{:03}", i % 1000)).collect::<Vec<_>>(),
+        "flag" => (0..rows).map(|i| i % 3 == 0).collect::<Vec<_>>(),
+    }?;
+    let policy = Policy::allow_by_default();
+
+    let chunks = column_upload_chunks(&df, &policy, Vec::new(), None)?;
+    let largest = chunks[0].column_lengths.iter().max().copied().unwrap_or(0);
+    println!("Largest column frame: {} MiB", largest >> 20);
+    ingest("columns", &chunks)?;
+    drop(chunks);
+
+    let buf = dataframe_ser_helper(&mut df.clone())?;
+    let chunks = upload_chunks(&buf, &policy, Vec::new(), None)?;
+    drop(buf);
+    ingest("ipc", &chunks)?;
+    Ok(())
+}
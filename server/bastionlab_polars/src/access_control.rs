@@ -1,13 +1,55 @@
+use polars::prelude::Schema;
 use serde::{Deserialize, Serialize};
 use tonic::Status;
 
 use crate::composite_plan::StatsEntry;
+use crate::differential_privacy::DifferentialPrivacy;
+use crate::exposure::{self, ColumnExposures};
+use crate::masking::{self, ColumnMasks};
+use crate::output_rows::MaxOutputRows;
+use crate::purpose::{merge_require_purpose, Purpose, RequirePurpose};
+use crate::resources::{merge_resource_caps, ResourceCaps};
+use crate::synthetic::SyntheticPolicy;
+use crate::watermark::Watermark;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Policy {
     safe_zone: Rule,
     unsafe_handling: UnsafeAction,
     savable: bool,
+    #[serde(default)]
+    probing_response: ProbingResponse,
+    /// Watermarking applied to every fetch of the data.
+    #[serde(default)]
+    watermark: Option<Watermark>,
+    /// Columns that must never be distorted, even by watermarking.
+    #[serde(default)]
+    exact_columns: Vec<String>,
+    /// Cap on the rows of every result computed from the data.
+    #[serde(default)]
+    max_output_rows: Option<MaxOutputRows>,
+    /// Synthetic data generation from the data, disabled if unset.
+    #[serde(default)]
+    synthetic: Option<SyntheticPolicy>,
+    /// Caps on the resource hints of the plans reading the data.
+    #[serde(default)]
+    resource_caps: Option<ResourceCaps>,
+    /// Purposes requesters must state to access the data, see [`crate::purpose`].
+    #[serde(default)]
+    require_purpose: Option<RequirePurpose>,
+    /// Key columns literal frames must not be joined on, see [`crate::literal_frames`].
+    #[serde(default)]
+    literal_join_keys: Vec<String>,
+    /// Maskers applied to columns whenever their values are released, see [`crate::masking`].
+    #[serde(default)]
+    column_masks: ColumnMasks,
+    /// What the statistics of columns disclose in metadata responses, see [`crate::exposure`].
+    #[serde(default)]
+    metadata_exposure: ColumnExposures,
+    /// Noise releasing the aggregates the safe zone rejects, see
+    /// [`crate::differential_privacy`].
+    #[serde(default)]
+    differential_privacy: Option<DifferentialPrivacy>,
 }
 
 impl Policy {
@@ -29,6 +71,52 @@ impl Policy {
             },
             unsafe_handling: self.unsafe_handling.merge(other.unsafe_handling),
             savable: self.savable && other.savable,
+            probing_response: self.probing_response.merge(other.probing_response),
+            watermark: match (&self.watermark, &other.watermark) {
+                (Some(a), Some(b)) => Some(a.merge(b)),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            },
+            exact_columns: {
+                let mut columns = self.exact_columns.clone();
+                columns.extend(
+                    other
+                        .exact_columns
+                        .iter()
+                        .filter(|c| !self.exact_columns.contains(c))
+                        .cloned(),
+                );
+                columns
+            },
+            max_output_rows: merge_max_output_rows(self.max_output_rows, other.max_output_rows),
+            synthetic: match (&self.synthetic, &other.synthetic) {
+                (Some(a), Some(b)) => Some(a.merge(b)),
+                _ => None,
+            },
+            resource_caps: merge_resource_caps(self.resource_caps, other.resource_caps),
+            require_purpose: merge_require_purpose(
+                self.require_purpose.as_ref(),
+                other.require_purpose.as_ref(),
+            ),
+            literal_join_keys: {
+                let mut columns = self.literal_join_keys.clone();
+                columns.extend(
+                    other
+                        .literal_join_keys
+                        .iter()
+                        .filter(|c| !self.literal_join_keys.contains(c))
+                        .cloned(),
+                );
+                columns
+            },
+            column_masks: masking::merge_masks(&self.column_masks, &other.column_masks),
+            metadata_exposure: exposure::merge_exposures(
+                &self.metadata_exposure,
+                &other.metadata_exposure,
+            ),
+            differential_privacy: match (&self.differential_privacy, &other.differential_privacy) {
+                (Some(a), Some(b)) => Some(a.merge(b)),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            },
         }
     }
 
@@ -37,12 +125,144 @@ impl Policy {
             safe_zone: Rule::TrueRule,
             unsafe_handling: UnsafeAction::Log,
             savable: true,
+            probing_response: ProbingResponse::Alert,
+            watermark: None,
+            exact_columns: Vec::new(),
+            max_output_rows: None,
+            synthetic: None,
+            resource_caps: None,
+            require_purpose: None,
+            literal_join_keys: Vec::new(),
+            column_masks: ColumnMasks::new(),
+            metadata_exposure: ColumnExposures::new(),
+            differential_privacy: None,
         }
     }
 
     pub fn check_savable(&self) -> bool {
         return self.savable;
     }
+
+    pub fn probing_response(&self) -> ProbingResponse {
+        self.probing_response
+    }
+
+    pub fn watermark(&self) -> Option<&Watermark> {
+        self.watermark.as_ref()
+    }
+
+    pub fn exact_columns(&self) -> &[String] {
+        &self.exact_columns
+    }
+
+    pub fn max_output_rows(&self) -> Option<MaxOutputRows> {
+        self.max_output_rows
+    }
+
+    pub fn synthetic(&self) -> Option<&SyntheticPolicy> {
+        self.synthetic.as_ref()
+    }
+
+    pub fn resource_caps(&self) -> Option<ResourceCaps> {
+        self.resource_caps
+    }
+
+    pub fn with_resource_caps(mut self, resource_caps: Option<ResourceCaps>) -> Self {
+        self.resource_caps = resource_caps;
+        self
+    }
+
+    pub fn require_purpose(&self) -> Option<&RequirePurpose> {
+        self.require_purpose.as_ref()
+    }
+
+    pub fn with_require_purpose(mut self, require_purpose: Option<RequirePurpose>) -> Self {
+        self.require_purpose = require_purpose;
+        self
+    }
+
+    pub fn literal_join_keys(&self) -> &[String] {
+        &self.literal_join_keys
+    }
+
+    pub fn with_literal_join_keys(mut self, literal_join_keys: Vec<String>) -> Self {
+        self.literal_join_keys = literal_join_keys;
+        self
+    }
+
+    pub fn column_masks(&self) -> &ColumnMasks {
+        &self.column_masks
+    }
+
+    pub fn with_column_masks(mut self, column_masks: ColumnMasks) -> Self {
+        self.column_masks = column_masks;
+        self
+    }
+
+    /// Checks the column masks of the policy against `schema`, that of the data it is attached to,
+    /// its metadata exposures and its differential privacy rule.
+    pub fn check_masks(&self, schema: &Schema) -> Result<(), Status> {
+        masking::check(&self.column_masks, schema)?;
+        exposure::check(&self.metadata_exposure)?;
+        match &self.differential_privacy {
+            Some(privacy) => privacy.check(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn metadata_exposure(&self) -> &ColumnExposures {
+        &self.metadata_exposure
+    }
+
+    pub fn with_metadata_exposure(mut self, metadata_exposure: ColumnExposures) -> Self {
+        self.metadata_exposure = metadata_exposure;
+        self
+    }
+
+    pub fn differential_privacy(&self) -> Option<&DifferentialPrivacy> {
+        self.differential_privacy.as_ref()
+    }
+
+    pub fn with_differential_privacy(
+        mut self,
+        differential_privacy: Option<DifferentialPrivacy>,
+    ) -> Self {
+        self.differential_privacy = differential_privacy;
+        self
+    }
+
+    /// Checks the purpose of a request on `identifier` against the policy.
+    pub fn check_purpose(&self, purpose: Option<&Purpose>, identifier: &str) -> Result<(), Status> {
+        match &self.require_purpose {
+            Some(rule) => rule.check(purpose, identifier),
+            None => Ok(()),
+        }
+    }
+
+    pub fn with_watermark(
+        mut self,
+        watermark: Option<Watermark>,
+        exact_columns: Vec<String>,
+    ) -> Self {
+        self.watermark = watermark;
+        self.exact_columns = exact_columns;
+        self
+    }
+
+    pub fn with_max_output_rows(mut self, max_output_rows: Option<MaxOutputRows>) -> Self {
+        self.max_output_rows = max_output_rows;
+        self
+    }
+}
+
+pub fn merge_max_output_rows(
+    a: Option<MaxOutputRows>,
+    b: Option<MaxOutputRows>,
+) -> Option<MaxOutputRows> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(b)),
+        (a, b) => a.or(b),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -50,7 +270,7 @@ impl Policy {
 pub enum Rule {
     AtLeastNOf { n: usize, of: Vec<Rule> },
     UserId { id: String },
-    Aggregation { min_agg_size: usize },
+    Aggregation { min_agg_size: u64 },
     TrueRule,
     FalseRule,
 }
@@ -94,14 +314,16 @@ impl Rule {
             Rule::Aggregation {
                 min_agg_size: min_allowed_agg_size,
             } => {
-                let min_allowed_agg_size = *min_allowed_agg_size * ctx.stats.join_scaling;
+                let min_allowed_agg_size =
+                    min_allowed_agg_size.saturating_mul(ctx.stats.join_scaling);
                 Ok(if ctx.stats.agg_size >= min_allowed_agg_size {
                     RuleMatch::Match
                 } else {
                     RuleMatch::Mismatch(format!(
-                        "Cannot fetch a result DataFrame that does not aggregate at least {} rows of DataFrame {}.",
+                        "Cannot fetch a result DataFrame that does not aggregate at least {} rows of DataFrame {}: its smallest aggregate covers {} rows.",
                         min_allowed_agg_size,
                         ctx.df_identifier,
+                        ctx.stats.agg_size,
                     ))
                 })
             }
@@ -138,6 +360,51 @@ impl UnsafeAction {
     }
 }
 
+/// What the server does when a query looks like part of a probing attack.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(tag = "type")]
+pub enum ProbingResponse {
+    /// Only log the incident.
+    #[default]
+    Alert,
+    /// Delay the response by a random amount of time.
+    Jitter { max_delay_ms: u64 },
+    /// Require the data owner's approval to fetch the result.
+    ForcePending,
+    /// Reject the queries of this identity on the dataset for some time.
+    Suspend { duration_secs: u64 },
+}
+
+impl ProbingResponse {
+    fn strictness(&self) -> u8 {
+        match self {
+            ProbingResponse::Alert => 0,
+            ProbingResponse::Jitter { .. } => 1,
+            ProbingResponse::ForcePending => 2,
+            ProbingResponse::Suspend { .. } => 3,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (
+                ProbingResponse::Jitter { max_delay_ms: a },
+                ProbingResponse::Jitter { max_delay_ms: b },
+            ) => ProbingResponse::Jitter {
+                max_delay_ms: a.max(b),
+            },
+            (
+                ProbingResponse::Suspend { duration_secs: a },
+                ProbingResponse::Suspend { duration_secs: b },
+            ) => ProbingResponse::Suspend {
+                duration_secs: a.max(b),
+            },
+            (a, b) if b.strictness() > a.strictness() => b,
+            (a, _) => a,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum VerificationResult {
     Safe,
@@ -0,0 +1,601 @@
+//! Recent queries on each dataframe, for its owner to review approval requests in context.
+//!
+//! Every dataframe keeps the last queries that read it, and persists them with its artifact: who
+//! ran them and when, the hash of their plan and a synopsis of it, the number of rows of their
+//! result and how fetching it went. Entries are evicted past `recent_activity_entries` newer ones
+//! or after `recent_activity_max_age_secs`, which leaves the access log of [`crate::purpose`]
+//! untouched.
+//!
+//! Synopses list the operations of the plan with the columns they read, in order, e.g.
+//! `filter(region == "EU"), groupby(site), mean(cost)`. The literals of a filter comparison are
+//! only shown when it reads columns of the inputs that no input blacklists: the others read as
+//! `?`, so that synopses never reveal the values a blacklisted column was filtered on.
+
+use std::collections::{HashSet, VecDeque};
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::composite_plan::{CompositePlan, CompositePlanSegment};
+use crate::polars_proto;
+use crate::visitable::Visitable;
+use crate::{DataFrameArtifact, FetchStatus};
+
+/// Longest literal shown in synopses, in characters.
+const MAX_LITERAL: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FetchOutcome {
+    NotFetched,
+    Fetched,
+    /// Fetched, with a warning of the policy.
+    Warned,
+    /// Waiting for the approval of the data owner.
+    Pending,
+    Denied,
+}
+
+impl FetchOutcome {
+    pub fn of(status: &FetchStatus) -> Self {
+        match status {
+            FetchStatus::Ok => FetchOutcome::Fetched,
+            FetchStatus::Warning(_) => FetchOutcome::Warned,
+            FetchStatus::Pending(_) => FetchOutcome::Pending,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            FetchOutcome::NotFetched => "not fetched",
+            FetchOutcome::Fetched => "fetched",
+            FetchOutcome::Warned => "fetched with a warning",
+            FetchOutcome::Pending => "pending approval",
+            FetchOutcome::Denied => "denied",
+        }
+    }
+
+    fn to_proto(self) -> polars_proto::FetchOutcome {
+        match self {
+            FetchOutcome::NotFetched => polars_proto::FetchOutcome::NotFetched,
+            FetchOutcome::Fetched => polars_proto::FetchOutcome::Fetched,
+            FetchOutcome::Warned => polars_proto::FetchOutcome::Warned,
+            FetchOutcome::Pending => polars_proto::FetchOutcome::Pending,
+            FetchOutcome::Denied => polars_proto::FetchOutcome::Denied,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActivityEntry {
+    /// Milliseconds since the Unix epoch.
+    pub at: u64,
+    pub requester: String,
+    pub plan_hash: String,
+    pub synopsis: String,
+    /// Identifier of the result.
+    pub result: String,
+    pub output_rows: u64,
+    pub fetch: FetchOutcome,
+}
+
+impl ActivityEntry {
+    pub fn to_proto(&self) -> polars_proto::ActivityEntry {
+        polars_proto::ActivityEntry {
+            at: self.at,
+            requester: self.requester.clone(),
+            plan_hash: self.plan_hash.clone(),
+            synopsis: self.synopsis.clone(),
+            result: self.result.clone(),
+            output_rows: self.output_rows,
+            fetch_outcome: self.fetch.to_proto() as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ActivityLimits {
+    pub entries: usize,
+    pub max_age_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecentActivity {
+    entries: VecDeque<ActivityEntry>,
+}
+
+impl RecentActivity {
+    pub fn record(&mut self, entry: ActivityEntry, limits: ActivityLimits) {
+        let now = entry.at;
+        self.entries.push_back(entry);
+        while self.entries.len() > limits.entries {
+            self.entries.pop_front();
+        }
+        self.evict_older(limits, now);
+    }
+
+    fn evict_older(&mut self, limits: ActivityLimits, now: u64) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.at.saturating_add(limits.max_age_ms) < now)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Records how the last fetch of `result` went.
+    pub fn set_fetch(&mut self, result: &str, outcome: FetchOutcome) {
+        for entry in self
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.result == result)
+        {
+            entry.fetch = outcome;
+        }
+    }
+
+    /// The entries still within the limits at `now`, oldest first.
+    pub fn recent(&self, limits: ActivityLimits, now: u64) -> Vec<&ActivityEntry> {
+        let skip = self.entries.len().saturating_sub(limits.entries);
+        self.entries
+            .iter()
+            .skip(skip)
+            .filter(|entry| entry.at.saturating_add(limits.max_age_ms) >= now)
+            .collect()
+    }
+}
+
+/// The recent queries of `requester` on `inputs`, as shown in approval prompts.
+pub fn approval_details(
+    inputs: &[(&str, &RecentActivity)],
+    requester: &str,
+    limits: ActivityLimits,
+    now: u64,
+) -> String {
+    let mut lines = String::new();
+    for (identifier, activity) in inputs {
+        let entries: Vec<_> = activity
+            .recent(limits, now)
+            .into_iter()
+            .filter(|entry| entry.requester == requester)
+            .collect();
+        if entries.is_empty() {
+            continue;
+        }
+        lines.push_str(&format!(
+            "Recent queries of this user on DataFrame {identifier}:\n"
+        ));
+        for entry in entries {
+            let at = chrono::DateTime::from_timestamp_millis(entry.at as i64)
+                .map_or(String::new(), |at| {
+                    at.format("%Y-%m-%d %H:%M UTC").to_string()
+                });
+            lines.push_str(&format!(
+                "  {at}: {} ({} rows, {})\n",
+                entry.synopsis,
+                entry.output_rows,
+                entry.fetch.describe()
+            ));
+        }
+    }
+    lines
+}
+
+/// A one-line synopsis of `plan`, whose `inputs` decide which filter literals are shown.
+pub fn synopsis(plan: &CompositePlan, inputs: &[&DataFrameArtifact]) -> String {
+    let mut hidden = HashSet::new();
+    let mut known = HashSet::new();
+    for artifact in inputs {
+        hidden.extend(artifact.blacklist.iter().cloned());
+        known.extend(
+            artifact
+                .dataframe
+                .get_column_names()
+                .into_iter()
+                .map(String::from),
+        );
+    }
+    let visible = |column: &str| known.contains(column) && !hidden.contains(column);
+
+    let mut steps = Vec::new();
+    for seg in plan.segments() {
+        match seg {
+            CompositePlanSegment::PolarsPlanSegment { plan, .. } => {
+                if plan_steps(plan, &visible, &mut steps).is_err() {
+                    steps.push(String::from("unsupported plan"));
+                }
+            }
+            CompositePlanSegment::UdfPlanSegment { columns, .. } => {
+                steps.push(format!("udf({})", columns.join(", ")))
+            }
+            CompositePlanSegment::FamilyEntryPointSegment { family, .. } => {
+                steps.push(format!("family({family})"))
+            }
+            CompositePlanSegment::StackPlanSegment => steps.push(String::from("stack")),
+            CompositePlanSegment::VStackPlanSegment { inputs, .. } => {
+                steps.push(format!("vstack({} inputs)", inputs.len()))
+            }
+            CompositePlanSegment::RowCountSegment { .. } => steps.push(String::from("row_count")),
+            CompositePlanSegment::TemporalPlanSegment { columns } => steps.push(format!(
+                "temporal({})",
+                columns
+                    .iter()
+                    .map(|column| column.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            CompositePlanSegment::OutputSegment { slot } => steps.push(format!("output({slot})")),
+            CompositePlanSegment::SplitSegment { slots, .. } => {
+                steps.push(format!("split({})", slots.join(", ")))
+            }
+            CompositePlanSegment::LabelEncodeSegment { column, .. } => {
+                steps.push(format!("label_encode({column})"))
+            }
+            // Only the column names: the values may be guesses about the data.
+            CompositePlanSegment::LiteralFramePlanSegment { columns } => steps.push(format!(
+                "literal({})",
+                columns
+                    .iter()
+                    .map(|column| column.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            CompositePlanSegment::JoinPlanSegment { how, .. } => {
+                steps.push(format!("join({})", how.name()))
+            }
+            CompositePlanSegment::GroupByPlanSegment { by, aggs } => {
+                steps.push(format!("groupby({})", by.join(", ")));
+                for agg in aggs {
+                    steps.push(format!("{}({})", agg.agg.name(), agg.column));
+                }
+            }
+            // Only the columns, as for literal frames.
+            CompositePlanSegment::FilterPlanSegment { predicate } => {
+                steps.push(format!("filter({})", predicate.columns().join(", ")))
+            }
+            CompositePlanSegment::SelectPlanSegment { columns, dtypes } => {
+                let mut selected: Vec<String> = columns
+                    .iter()
+                    .map(|column| match &column.alias {
+                        Some(alias) => format!("{} as {alias}", column.name),
+                        None => column.name.clone(),
+                    })
+                    .collect();
+                selected.extend(
+                    dtypes
+                        .iter()
+                        .map(|group| format!("{} columns", group.name())),
+                );
+                steps.push(format!("select({})", selected.join(", ")))
+            }
+            CompositePlanSegment::SamplePlanSegment { fraction, n, .. } => match (fraction, n) {
+                (Some(fraction), _) => steps.push(format!("sample({fraction})")),
+                (_, Some(n)) => steps.push(format!("sample({n} rows)")),
+                _ => steps.push(String::from("sample")),
+            },
+            // Only the columns read, as for filters.
+            CompositePlanSegment::WithColumnPlanSegment { name, expr, .. } => steps.push(format!(
+                "with_column({name}: {})",
+                expr.columns().join(", ")
+            )),
+            CompositePlanSegment::HeadPlanSegment { n } => steps.push(format!("head({n})")),
+            CompositePlanSegment::SlicePlanSegment { offset, length } => {
+                steps.push(format!("slice({offset}, {length})"))
+            }
+            CompositePlanSegment::SortPlanSegment { by, .. } => steps.push(format!(
+                "sort({})",
+                by.iter()
+                    .map(|key| key.column.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            CompositePlanSegment::EntryPointPlanSegment { .. }
+            | CompositePlanSegment::SlotEntryPointSegment { .. } => (),
+        }
+    }
+    steps.join(", ")
+}
+
+fn plan_steps(
+    plan: &LogicalPlan,
+    visible: &impl Fn(&str) -> bool,
+    steps: &mut Vec<String>,
+) -> Result<(), tonic::Status> {
+    // Inputs are visited first, so steps come in the order they run.
+    plan.visit(steps, |plan, steps| {
+        match plan {
+            LogicalPlan::Selection { predicate, .. } => {
+                steps.push(format!("filter({})", condition(predicate, visible)?))
+            }
+            LogicalPlan::Aggregate { keys, aggs, .. } => {
+                steps.push(format!("groupby({})", columns_of(keys)?));
+                for agg in aggs.iter() {
+                    steps.push(expression(agg)?);
+                }
+            }
+            LogicalPlan::Join { left_on, .. } => {
+                steps.push(format!("join({})", columns_of(left_on)?))
+            }
+            LogicalPlan::Sort { by_column, .. } => {
+                steps.push(format!("sort({})", columns_of(by_column)?))
+            }
+            LogicalPlan::Projection { expr, .. } | LogicalPlan::LocalProjection { expr, .. } => {
+                let exprs = expr.iter().map(expression).collect::<Result<Vec<_>, _>>()?;
+                steps.push(format!("select({})", exprs.join(", ")));
+            }
+            LogicalPlan::HStack { exprs, .. } => {
+                let exprs = exprs
+                    .iter()
+                    .map(expression)
+                    .collect::<Result<Vec<_>, _>>()?;
+                steps.push(format!("with_columns({})", exprs.join(", ")));
+            }
+            LogicalPlan::Distinct { .. } => steps.push(String::from("distinct")),
+            LogicalPlan::Slice { .. } => steps.push(String::from("slice")),
+            LogicalPlan::Explode { .. } => steps.push(String::from("explode")),
+            LogicalPlan::Melt { .. } => steps.push(String::from("melt")),
+            _ => (),
+        }
+        Ok(())
+    })
+}
+
+/// The columns `expr` reads, aggregations included.
+fn columns(expr: &Expr) -> Result<Vec<String>, tonic::Status> {
+    let mut columns = Vec::new();
+    expr.visit(&mut columns, |expr, columns| {
+        match expr {
+            Expr::Column(name) => columns.push(name.to_string()),
+            Expr::Agg(agg) => columns.extend(self::columns(aggregation(agg).1)?),
+            _ => (),
+        }
+        Ok(())
+    })?;
+    columns.dedup();
+    Ok(columns)
+}
+
+fn columns_of(exprs: &[Expr]) -> Result<String, tonic::Status> {
+    let mut all = Vec::new();
+    for expr in exprs {
+        for column in columns(expr)? {
+            if !all.contains(&column) {
+                all.push(column);
+            }
+        }
+    }
+    Ok(all.join(", "))
+}
+
+/// An expression of a selection or an aggregation: its aggregation if any, and its columns.
+fn expression(expr: &Expr) -> Result<String, tonic::Status> {
+    match expr {
+        Expr::Alias(inner, _) | Expr::KeepName(inner) => expression(inner),
+        Expr::Agg(agg) => {
+            let (name, input) = aggregation(agg);
+            Ok(format!("{name}({})", columns(input)?.join(", ")))
+        }
+        Expr::Count => Ok(String::from("count()")),
+        expr => Ok(columns(expr)?.join(", ")),
+    }
+}
+
+fn aggregation(agg: &AggExpr) -> (&'static str, &Expr) {
+    match agg {
+        AggExpr::Min { input, .. } => ("min", input),
+        AggExpr::Max { input, .. } => ("max", input),
+        AggExpr::Median(input) => ("median", input),
+        AggExpr::NUnique(input) => ("n_unique", input),
+        AggExpr::First(input) => ("first", input),
+        AggExpr::Last(input) => ("last", input),
+        AggExpr::Mean(input) => ("mean", input),
+        AggExpr::List(input) => ("list", input),
+        AggExpr::Count(input) => ("count", input),
+        AggExpr::Quantile { expr, .. } => ("quantile", expr),
+        AggExpr::Sum(input) => ("sum", input),
+        AggExpr::AggGroups(input) => ("agg_groups", input),
+        AggExpr::Std(input, _) => ("std", input),
+        AggExpr::Var(input, _) => ("var", input),
+    }
+}
+
+/// A filter condition, whose comparisons only show their literals if they read visible columns.
+fn condition(expr: &Expr, visible: &impl Fn(&str) -> bool) -> Result<String, tonic::Status> {
+    match expr {
+        Expr::BinaryExpr { left, op, right } => {
+            if let Operator::And | Operator::Or = op {
+                return Ok(format!(
+                    "{} {} {}",
+                    condition(left, visible)?,
+                    operator(op),
+                    condition(right, visible)?
+                ));
+            }
+            let read = columns(expr)?;
+            let shown = !read.is_empty() && read.iter().all(|column| visible(column));
+            Ok(format!(
+                "{} {} {}",
+                operand(left, shown)?,
+                operator(op),
+                operand(right, shown)?
+            ))
+        }
+        expr => Ok(columns(expr)?.join(", ")),
+    }
+}
+
+fn operand(expr: &Expr, shown: bool) -> Result<String, tonic::Status> {
+    match expr {
+        Expr::Alias(inner, _) | Expr::Cast { expr: inner, .. } => operand(inner, shown),
+        Expr::Column(name) => Ok(name.to_string()),
+        Expr::Literal(value) if shown => Ok(literal(value)),
+        Expr::Literal(_) => Ok(String::from("?")),
+        expr => {
+            let read = columns(expr)?;
+            Ok(if read.is_empty() {
+                String::from("?")
+            } else {
+                read.join(", ")
+            })
+        }
+    }
+}
+
+fn literal(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Null => String::from("null"),
+        LiteralValue::Boolean(v) => v.to_string(),
+        LiteralValue::Int32(v) => v.to_string(),
+        LiteralValue::Int64(v) => v.to_string(),
+        LiteralValue::UInt32(v) => v.to_string(),
+        LiteralValue::UInt64(v) => v.to_string(),
+        LiteralValue::Float32(v) => v.to_string(),
+        LiteralValue::Float64(v) => v.to_string(),
+        // Quoted and escaped, so that values cannot forge lines of the prompt.
+        LiteralValue::Utf8(v) if v.chars().count() > MAX_LITERAL => {
+            format!("{:?}…", v.chars().take(MAX_LITERAL).collect::<String>())
+        }
+        LiteralValue::Utf8(v) => format!("{v:?}"),
+        _ => String::from("?"),
+    }
+}
+
+fn operator(op: &Operator) -> &'static str {
+    match op {
+        Operator::Eq => "==",
+        Operator::NotEq => "!=",
+        Operator::Lt => "<",
+        Operator::LtEq => "<=",
+        Operator::Gt => ">",
+        Operator::GtEq => ">=",
+        Operator::Plus => "+",
+        Operator::Minus => "-",
+        Operator::Multiply => "*",
+        Operator::Divide => "/",
+        Operator::And => "and",
+        Operator::Or => "or",
+        _ => "op",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+
+    fn input() -> DataFrame {
+        df! {
+            "region" => ["EU", "US"],
+            "site" => ["a", "b"],
+            "ssn" => ["123-45-6789", "987-65-4321"],
+            "cost" => [1.0, 2.0],
+        }
+        .unwrap()
+    }
+
+    fn plan(lf: LazyFrame) -> CompositePlan {
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: String::from("df"),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: lf.logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    }
+
+    #[test]
+    fn synopses_redact_the_literals_of_blacklisted_columns() {
+        let artifact = DataFrameArtifact::new(
+            input(),
+            Policy::allow_by_default(),
+            vec![String::from("ssn")],
+        );
+        let lf = input()
+            .lazy()
+            .filter(
+                col("region")
+                    .eq(lit("EU"))
+                    .and(col("ssn").eq(lit("123-45-6789"))),
+            )
+            .groupby([col("site")])
+            .agg([col("cost").mean()]);
+        assert_eq!(
+            synopsis(&plan(lf), &[&artifact]),
+            r#"filter(region == "EU" and ssn == ?), groupby(site), mean(cost)"#
+        );
+
+        // Comparisons mixing columns, or reading renamed ones, are redacted too.
+        let lf = input()
+            .lazy()
+            .with_columns([col("ssn").alias("id")])
+            .filter(col("id").eq(lit("123-45-6789")))
+            .filter(col("region").eq(col("ssn")).or(col("cost").gt(lit(1.5))));
+        assert_eq!(
+            synopsis(&plan(lf), &[&artifact]),
+            "with_columns(ssn), filter(id == ?), filter(region == ssn or cost > 1.5)"
+        );
+    }
+
+    #[test]
+    fn long_literals_are_escaped_and_truncated() {
+        let artifact = DataFrameArtifact::new(input(), Policy::allow_by_default(), Vec::new());
+        let forged = format!("EU\nReason: none{}", "x".repeat(64));
+        let lf = input()
+            .lazy()
+            .filter(col("region").eq(lit(forged.as_str())));
+        let synopsis = synopsis(&plan(lf), &[&artifact]);
+        assert!(!synopsis.contains('\n'), "{synopsis}");
+        assert!(synopsis.ends_with("…)"), "{synopsis}");
+    }
+
+    fn entry(at: u64, result: &str) -> ActivityEntry {
+        ActivityEntry {
+            at,
+            requester: String::from("analyst"),
+            plan_hash: String::new(),
+            synopsis: String::from("filter(region)"),
+            result: result.to_string(),
+            output_rows: 1,
+            fetch: FetchOutcome::NotFetched,
+        }
+    }
+
+    #[test]
+    fn the_history_is_bounded_in_size_and_age() {
+        let limits = ActivityLimits {
+            entries: 3,
+            max_age_ms: 1_000,
+        };
+        let mut activity = RecentActivity::default();
+        for at in 0..5 {
+            activity.record(entry(at * 100, &format!("r{at}")), limits);
+        }
+        let results = |activity: &RecentActivity, now| {
+            activity
+                .recent(limits, now)
+                .into_iter()
+                .map(|entry| entry.result.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(results(&activity, 400), vec!["r2", "r3", "r4"]);
+        // Older entries are hidden on reads, and evicted on the next record.
+        assert_eq!(results(&activity, 1_350), vec!["r4"]);
+        activity.record(entry(1_350, "r5"), limits);
+        assert_eq!(activity.entries.len(), 2);
+
+        activity.set_fetch("r5", FetchOutcome::Denied);
+        assert_eq!(
+            activity.recent(limits, 1_350)[1].fetch,
+            FetchOutcome::Denied
+        );
+        let details = approval_details(&[("df", &activity)], "analyst", limits, 1_350);
+        assert!(
+            details.contains("filter(region) (1 rows, denied)"),
+            "{details}"
+        );
+        assert!(approval_details(&[("df", &activity)], "other", limits, 1_350).is_empty());
+    }
+}
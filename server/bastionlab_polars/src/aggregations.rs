@@ -0,0 +1,190 @@
+//! Group-by aggregations of a composite plan, without writing a polars plan.
+//!
+//! A `GroupByPlanSegment` groups the dataframe on top of the stack by columns, and aggregates
+//! other columns within each group. Aggregations are named after their column and function, e.g.
+//! `age_mean`. The segment runs as a polars segment would, so that aggregation sizes are tracked
+//! and the policy of the input applies to the result.
+//!
+//! Aggregations other than counts disclose the values of their column: they are rejected on
+//! blacklisted columns before anything runs. Counts only disclose how many rows each group holds,
+//! and are allowed on any column.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AggKind {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    Median,
+    /// Sample standard deviation.
+    Std,
+}
+
+impl AggKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AggKind::Sum => "sum",
+            AggKind::Mean => "mean",
+            AggKind::Min => "min",
+            AggKind::Max => "max",
+            AggKind::Count => "count",
+            AggKind::Median => "median",
+            AggKind::Std => "std",
+        }
+    }
+
+    /// Whether the aggregation only applies to numeric columns.
+    fn numeric(self) -> bool {
+        matches!(
+            self,
+            AggKind::Sum | AggKind::Mean | AggKind::Median | AggKind::Std
+        )
+    }
+
+    fn expr(self, column: &str) -> Expr {
+        let column = col(column);
+        match self {
+            AggKind::Sum => column.sum(),
+            AggKind::Mean => column.mean(),
+            AggKind::Min => column.min(),
+            AggKind::Max => column.max(),
+            AggKind::Count => column.count(),
+            AggKind::Median => column.median(),
+            AggKind::Std => column.std(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Aggregation {
+    pub column: String,
+    pub agg: AggKind,
+}
+
+impl Aggregation {
+    /// The name of the column of the result.
+    pub fn output(&self) -> String {
+        format!("{}_{}", self.column, self.agg.name())
+    }
+}
+
+/// Checks that `aggs` of `schema` grouped by `by` can run, none of them disclosing the values of
+/// the `blacklisted` columns.
+pub fn check(
+    schema: &Schema,
+    by: &[String],
+    aggs: &[Aggregation],
+    blacklisted: &[String],
+) -> Result<(), Status> {
+    if by.is_empty() || aggs.is_empty() {
+        return Err(Status::invalid_argument(
+            "Could not group: at least one grouping column and one aggregation are needed",
+        ));
+    }
+    let column_dtype = |column: &str| {
+        schema.get(column).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Could not group: no column `{column}` in the input"
+            ))
+        })
+    };
+    for column in by {
+        column_dtype(column)?;
+    }
+    let mut outputs: Vec<String> = by.to_vec();
+    for agg in aggs {
+        let dtype = column_dtype(&agg.column)?;
+        let name = agg.agg.name();
+        if agg.agg != AggKind::Count && blacklisted.contains(&agg.column) {
+            return Err(Status::invalid_argument(format!(
+                "Could not aggregate `{}` with {name}: the column is blacklisted, only count can \
+                 aggregate it",
+                agg.column
+            )));
+        }
+        if agg.agg.numeric() && !dtype.is_numeric() {
+            return Err(Status::invalid_argument(format!(
+                "Could not aggregate `{}` ({dtype}) with {name}: {name} needs a numeric column",
+                agg.column
+            )));
+        }
+        let output = agg.output();
+        if outputs.contains(&output) {
+            return Err(Status::invalid_argument(format!(
+                "Could not group: the result would have two columns named `{output}`"
+            )));
+        }
+        outputs.push(output);
+    }
+    Ok(())
+}
+
+/// The polars plan aggregating `df`, whose dataframe scan stands for the input, see
+/// [`crate::composite_plan`].
+pub fn plan(
+    df: &DataFrame,
+    by: &[String],
+    aggs: &[Aggregation],
+    blacklisted: &[String],
+) -> Result<LogicalPlan, Status> {
+    check(&df.schema(), by, aggs, blacklisted)?;
+    Ok(df
+        .head(Some(0))
+        .lazy()
+        .groupby(by.iter().map(|column| col(column)).collect::<Vec<_>>())
+        .agg(
+            aggs.iter()
+                .map(|agg| agg.agg.expr(&agg.column).alias(&agg.output()))
+                .collect::<Vec<_>>(),
+        )
+        .logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bastionlab_common::common_conversions::lazy_frame_from_logical_plan;
+
+    fn agg(column: &str, agg: AggKind) -> Aggregation {
+        Aggregation {
+            column: column.to_string(),
+            agg,
+        }
+    }
+
+    #[test]
+    fn only_counts_aggregate_blacklisted_columns() {
+        let df = df! {
+            "code" => ["a", "b", "a"],
+            "age" => [31i64, 42, 54],
+            "name" => ["x", "y", "z"],
+        }
+        .unwrap();
+        let by = [String::from("code")];
+        let blacklisted = [String::from("age")];
+        let check = |aggs: &[Aggregation]| check(&df.schema(), &by, aggs, &blacklisted);
+
+        assert!(check(&[agg("age", AggKind::Count), agg("name", AggKind::Max)]).is_ok());
+        let err = check(&[agg("age", AggKind::Mean)]).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not aggregate `age` with mean: the column is blacklisted, only count can \
+             aggregate it"
+        );
+        let err = check(&[agg("name", AggKind::Sum)]).unwrap_err();
+        assert!(err.message().contains("`name` (str) with sum"), "{err:?}");
+        assert!(check(&[agg("missing", AggKind::Count)]).is_err());
+        assert!(check(&[agg("age", AggKind::Count), agg("age", AggKind::Count)]).is_err());
+        assert!(check(&[]).is_err());
+
+        let plan = plan(&df, &by, &[agg("name", AggKind::Count)], &blacklisted).unwrap();
+        let schema = lazy_frame_from_logical_plan(plan).schema().unwrap();
+        let columns: Vec<_> = schema.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(columns, ["code", "name_count"]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Identifiers that keep resolving to the dataframe that replaced them.
+//!
+//! When a dataframe is deduplicated into another one, its identifier becomes an alias of the
+//! remaining one, so that saved notebooks keep working. Data owners can also alias identifiers by
+//! hand. Requests on an alias are served from the canonical dataframe, under its policy, and their
+//! responses note the redirect so that clients update their references.
+//!
+//! The table is kept flat: an alias always points to a canonical identifier, never to another
+//! alias. Aliases expire after a retention period, after which they only resolve to an error
+//! naming the canonical identifier.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alias {
+    pub canonical: String,
+    pub reason: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl Alias {
+    /// The note sent along with responses to requests on the alias.
+    pub fn redirect(&self, alias: &str) -> String {
+        format!(
+            "Dataframe {alias} is now {} ({}), please update your references",
+            self.canonical, self.reason
+        )
+    }
+}
+
+/// Callers that also lock the dataframes must lock them first.
+#[derive(Debug, Default)]
+pub struct AliasRegistry {
+    aliases: RwLock<HashMap<String, Alias>>,
+    /// Aliases are kept forever if unset.
+    retention: Option<Duration>,
+}
+
+impl AliasRegistry {
+    pub fn new(retention: Option<Duration>) -> Self {
+        AliasRegistry {
+            retention,
+            ..Default::default()
+        }
+    }
+
+    /// Makes `alias` resolve to `canonical`, or to what `canonical` resolves to if it is an
+    /// alias itself. The aliases of `alias` are moved to the new canonical identifier.
+    pub fn insert(
+        &self,
+        alias: &str,
+        canonical: &str,
+        reason: &str,
+        now: u64,
+    ) -> Result<Alias, Status> {
+        let mut aliases = self.aliases.write().unwrap();
+        let canonical = aliases
+            .get(canonical)
+            .map_or(canonical, |target| target.canonical.as_str())
+            .to_string();
+        if canonical == alias {
+            return Err(Status::invalid_argument(format!(
+                "Aliasing {alias} to itself would create a cycle"
+            )));
+        }
+        for target in aliases.values_mut() {
+            if target.canonical == alias {
+                target.canonical = canonical.clone();
+            }
+        }
+        let entry = Alias {
+            canonical,
+            reason: reason.to_string(),
+            created_at: now,
+        };
+        aliases.insert(alias.to_string(), entry.clone());
+        Ok(entry)
+    }
+
+    /// The alias `identifier` is, if any. Fails if it expired.
+    pub fn resolve(&self, identifier: &str, now: u64) -> Result<Option<Alias>, Status> {
+        let aliases = self.aliases.read().unwrap();
+        let Some(alias) = aliases.get(identifier) else {
+            return Ok(None);
+        };
+        if let Some(retention) = self.retention {
+            if alias
+                .created_at
+                .saturating_add(retention.as_millis() as u64)
+                < now
+            {
+                return Err(Status::not_found(format!(
+                    "Dataframe {identifier} was replaced by {} ({}) and its alias expired: use {} instead",
+                    alias.canonical, alias.reason, alias.canonical
+                )));
+            }
+        }
+        Ok(Some(alias.clone()))
+    }
+
+    /// Whether some alias resolves to `identifier`.
+    pub fn is_canonical(&self, identifier: &str) -> bool {
+        let aliases = self.aliases.read().unwrap();
+        aliases.values().any(|alias| alias.canonical == identifier)
+    }
+
+    pub fn aliases(&self) -> HashMap<String, Alias> {
+        self.aliases.read().unwrap().clone()
+    }
+
+    /// Restores persisted aliases.
+    pub fn load(&self, aliases: HashMap<String, Alias>) {
+        self.aliases.write().unwrap().extend(aliases);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_and_cycles_are_collapsed() {
+        let registry = AliasRegistry::default();
+        registry.insert("a", "b", "dedup", 0).unwrap();
+        // a -> b, then b -> c: a goes straight to c.
+        registry.insert("b", "c", "dedup", 0).unwrap();
+        assert_eq!(registry.resolve("a", 0).unwrap().unwrap().canonical, "c");
+        assert_eq!(registry.resolve("b", 0).unwrap().unwrap().canonical, "c");
+        // d -> a resolves through a to c.
+        registry.insert("d", "a", "manual", 0).unwrap();
+        assert_eq!(registry.resolve("d", 0).unwrap().unwrap().canonical, "c");
+        assert!(registry.resolve("c", 0).unwrap().is_none());
+
+        // c -> a would close the cycle a -> c -> a.
+        let err = registry.insert("c", "a", "manual", 0).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(registry
+            .aliases()
+            .values()
+            .all(|alias| alias.canonical == "c"));
+    }
+
+    #[test]
+    fn aliases_expire_into_tombstones() {
+        let registry = AliasRegistry::new(Some(Duration::from_secs(60)));
+        registry.insert("old", "new", "dedup", 1_000).unwrap();
+        assert!(registry.resolve("old", 61_000).unwrap().is_some());
+        let err = registry.resolve("old", 61_001).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        assert!(err.message().contains("use new instead"), "{err:?}");
+    }
+}
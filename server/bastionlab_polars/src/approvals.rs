@@ -0,0 +1,268 @@
+//! Fetches pending the data owner's approval, which data owners list, approve and reject.
+//!
+//! Fetching a result whose policy requires a review registers a request, with its own id, the
+//! identity that requested it, the plan that produced the result and when. The fetch streams a
+//! `Pending` status naming the request, then waits on the same stream: approving the request
+//! releases the result, rejecting it ends the stream with a `permission_denied` carrying the reason
+//! of the owner. Requests nobody decided on within `approval_timeout_secs` are rejected.
+//!
+//! Requests only live while their fetch waits: they are not persisted. Shutting the server down
+//! rejects them with `unavailable`, for clients to fetch again once it is back, see
+//! [`Approvals::shut_down`].
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::polars_proto;
+
+/// Longest rejection reason, in characters.
+const MAX_REASON: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRequest {
+    pub id: String,
+    /// The dataframe to fetch.
+    pub identifier: String,
+    /// The identity that requested the fetch.
+    pub identity: String,
+    /// The plan that produced the dataframe.
+    pub plan: String,
+    /// Why the fetch needs approval.
+    pub reason: String,
+    /// Milliseconds since the Unix epoch.
+    pub requested_at: u64,
+    /// Milliseconds since the Unix epoch the request is rejected at, unless decided on before.
+    pub expires_at: u64,
+}
+
+impl PendingRequest {
+    pub fn to_proto(&self) -> polars_proto::PendingRequest {
+        polars_proto::PendingRequest {
+            id: self.id.clone(),
+            identifier: self.identifier.clone(),
+            identity: self.identity.clone(),
+            plan: self.plan.clone(),
+            reason: self.reason.clone(),
+            requested_at: self.requested_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Decision {
+    Approved,
+    /// With the reason of the owner.
+    Rejected(String),
+    ShutDown,
+}
+
+/// The requests pending approval, by id.
+#[derive(Debug, Default)]
+pub struct Approvals {
+    timeout: Duration,
+    pending: Mutex<HashMap<String, (PendingRequest, oneshot::Sender<Decision>)>>,
+    shut_down: AtomicBool,
+}
+
+impl Approvals {
+    pub fn new(timeout: Duration) -> Self {
+        Approvals {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Registers a request of `identity` to fetch `identifier`, produced by `plan`, which needs
+    /// approval for `reason`.
+    pub fn submit(
+        self: &Arc<Self>,
+        identifier: &str,
+        identity: &str,
+        plan: &str,
+        reason: &str,
+        now: u64,
+    ) -> Waiter {
+        let request = PendingRequest {
+            id: Uuid::new_v4().to_string(),
+            identifier: identifier.to_string(),
+            identity: identity.to_string(),
+            plan: plan.to_string(),
+            reason: reason.to_string(),
+            requested_at: now,
+            expires_at: now.saturating_add(self.timeout.as_millis() as u64),
+        };
+        let (tx, decision) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        // Checked under the lock, so that no request is registered once they are all rejected.
+        if self.shut_down.load(Ordering::SeqCst) {
+            let _ignored = tx.send(Decision::ShutDown);
+        } else {
+            pending.insert(request.id.clone(), (request.clone(), tx));
+        }
+        drop(pending);
+        Waiter {
+            approvals: self.clone(),
+            request,
+            decision,
+        }
+    }
+
+    /// The requests pending, oldest first.
+    pub fn list(&self) -> Vec<PendingRequest> {
+        let mut listed: Vec<PendingRequest> = self
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(request, _)| request.clone())
+            .collect();
+        listed.sort_by(|a, b| (a.requested_at, &a.id).cmp(&(b.requested_at, &b.id)));
+        listed
+    }
+
+    /// Approves request `id`, which releases the dataframe to its fetch.
+    pub fn approve(&self, id: &str) -> Result<PendingRequest, Status> {
+        self.decide(id, Decision::Approved)
+    }
+
+    /// Rejects request `id`, for `reason` if not empty.
+    pub fn reject(&self, id: &str, reason: &str) -> Result<PendingRequest, Status> {
+        let reason: String = reason
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_REASON)
+            .collect();
+        self.decide(id, Decision::Rejected(reason))
+    }
+
+    /// Rejects the requests pending, and those submitted afterwards, with `unavailable`: their
+    /// fetches end instead of waiting on a server that is going away. Returns how many were.
+    pub fn shut_down(&self) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        self.shut_down.store(true, Ordering::SeqCst);
+        let rejected = pending.len();
+        for (_, (_, tx)) in pending.drain() {
+            let _ignored = tx.send(Decision::ShutDown);
+        }
+        rejected
+    }
+
+    fn decide(&self, id: &str, decision: Decision) -> Result<PendingRequest, Status> {
+        let (request, tx) = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| Status::not_found(format!("No fetch request {id} pending")))?;
+        // The fetch may have ended meanwhile: nothing waits for the decision then.
+        let _ignored = tx.send(decision);
+        Ok(request)
+    }
+}
+
+/// A fetch waiting for the decision on its request, which is withdrawn when dropped.
+pub struct Waiter {
+    approvals: Arc<Approvals>,
+    request: PendingRequest,
+    decision: oneshot::Receiver<Decision>,
+}
+
+impl Waiter {
+    pub fn request(&self) -> &PendingRequest {
+        &self.request
+    }
+
+    /// Waits for the decision on the request, failing unless it is approved in time.
+    pub async fn decision(mut self) -> Result<(), Status> {
+        let id = &self.request.id;
+        match tokio::time::timeout(self.approvals.timeout, &mut self.decision).await {
+            Ok(Ok(Decision::Approved)) => Ok(()),
+            Ok(Ok(Decision::Rejected(reason))) => Err(Status::permission_denied(format!(
+                "The data owner rejected fetch request {id}: {}\nFetching a dataframe obtained \
+                 with a non privacy-preserving query requires the approval of the data owner.\n\
+                 Reason the fetch needed approval: {}",
+                match reason.trim() {
+                    "" => "no reason given",
+                    reason => reason,
+                },
+                self.request.reason
+            ))),
+            Ok(Ok(Decision::ShutDown)) => Err(Status::unavailable(format!(
+                "The server is shutting down before fetch request {id} was decided on, fetch \
+                 again once it is back"
+            ))),
+            Ok(Err(_)) => Err(Status::permission_denied(format!(
+                "Fetch request {id} was withdrawn"
+            ))),
+            Err(_) => Err(Status::permission_denied(format!(
+                "Fetch request {id} was not approved within {}s, and was rejected",
+                self.approvals.timeout.as_secs()
+            ))),
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.approvals
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.request.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn decisions_reach_the_waiting_fetch() {
+        let approvals = Arc::new(Approvals::new(Duration::from_millis(50)));
+        let approved = approvals.submit("df", "analyst", "plan", "unsafe", 1_000);
+        let rejected = approvals.submit("df", "analyst", "plan", "unsafe", 2_000);
+        let expired = approvals.submit("df", "analyst", "plan", "unsafe", 3_000);
+        let listed = approvals.list();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[0], *approved.request());
+        assert_eq!(listed[0].expires_at, 1_050);
+
+        approvals.approve(&approved.request().id).unwrap();
+        approved.decision().await.unwrap();
+        approvals
+            .reject(&rejected.request().id, "Too detailed")
+            .unwrap();
+        let err = rejected.decision().await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert!(err.message().contains("Too detailed"), "{err:?}");
+
+        let id = expired.request().id.clone();
+        let err = expired.decision().await.unwrap_err();
+        assert!(err.message().contains("not approved within"), "{err:?}");
+        // Withdrawn along with the fetch.
+        assert!(approvals.list().is_empty());
+        let err = approvals.approve(&id).unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let pending = approvals.submit("df", "analyst", "plan", "unsafe", 4_000);
+        assert_eq!(approvals.shut_down(), 1);
+        let late = approvals.submit("df", "analyst", "plan", "unsafe", 5_000);
+        assert!(approvals.list().is_empty());
+        for waiter in [pending, late] {
+            let err = waiter.decision().await.unwrap_err();
+            assert_eq!(err.code(), Code::Unavailable, "{err:?}");
+        }
+    }
+}
@@ -0,0 +1,536 @@
+//! In-process export of dataframes over the [Arrow C stream interface].
+//!
+//! [`BastionLabPolars::export_arrow`](crate::BastionLabPolars::export_arrow) runs the checks of a
+//! fetch and hands out an [`ArrowArrayStream`] of struct arrays, one per chunk of the dataframe.
+//! The arrays share the buffers of the stored columns, unless the policy requires a masked or
+//! watermarked copy.
+//!
+//! Lifetime rules:
+//! - the stream pins the exported dataframe until it is released: the dataframe cannot be deleted
+//!   meanwhile, and [`ExportRegistry::exports`] lists it,
+//! - the stream and the arrays it produced own the data they point to, they stay valid after the
+//!   stream is released, the dataframe is deleted or replaced, and the server is dropped,
+//! - releasing a released stream does nothing, and reading from one fails with `EINVAL`.
+//!
+//! [Arrow C stream interface]: https://arrow.apache.org/docs/format/CStreamInterface.html
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Weak;
+use std::time::SystemTime;
+
+use polars::export::arrow::array::{Array, StructArray};
+use polars::export::arrow::datatypes::{DataType, Field};
+use polars::export::arrow::ffi;
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::prelude::*;
+
+const EINVAL: c_int = 22;
+const RELEASED: &CStr = c"The stream was released";
+
+/// An exported dataframe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportInfo {
+    pub id: u64,
+    pub identifier: String,
+    /// Version of the dataframe when it was exported.
+    pub version: u64,
+    pub identity: String,
+    pub exported_at: SystemTime,
+}
+
+/// The dataframes exported by streams that have not been released yet.
+#[derive(Debug, Default)]
+pub struct ExportRegistry {
+    next_id: AtomicU64,
+    exports: Mutex<HashMap<u64, ExportInfo>>,
+}
+
+impl ExportRegistry {
+    pub(crate) fn pin(
+        self: &Arc<Self>,
+        identifier: &str,
+        version: u64,
+        identity: &str,
+    ) -> ExportPin {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.exports.lock().expect("Poisoned lock").insert(
+            id,
+            ExportInfo {
+                id,
+                identifier: identifier.to_string(),
+                version,
+                identity: identity.to_string(),
+                exported_at: SystemTime::now(),
+            },
+        );
+        ExportPin {
+            id,
+            registry: Arc::downgrade(self),
+        }
+    }
+
+    pub fn is_exported(&self, identifier: &str) -> bool {
+        self.exports
+            .lock()
+            .expect("Poisoned lock")
+            .values()
+            .any(|export| export.identifier == identifier)
+    }
+
+    /// Returns the exports that have not been released, by order of export.
+    pub fn exports(&self) -> Vec<ExportInfo> {
+        let mut exports: Vec<_> = self
+            .exports
+            .lock()
+            .expect("Poisoned lock")
+            .values()
+            .cloned()
+            .collect();
+        exports.sort_by_key(|export| export.id);
+        exports
+    }
+}
+
+/// Keeps an export in its registry until it is dropped. Registries that were dropped are ignored.
+#[derive(Debug)]
+pub(crate) struct ExportPin {
+    id: u64,
+    registry: Weak<ExportRegistry>,
+}
+
+impl Drop for ExportPin {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry
+                .exports
+                .lock()
+                .expect("Poisoned lock")
+                .remove(&self.id);
+        }
+    }
+}
+
+struct StreamState {
+    field: Field,
+    batches: std::vec::IntoIter<Box<dyn Array>>,
+    _pin: ExportPin,
+}
+
+/// ABI-compatible struct for the [Arrow C stream interface], see the [module docs](self) for
+/// its lifetime rules.
+///
+/// Dropping the stream releases it if it was not moved out with [`ArrowArrayStream::move_into`]
+/// or [`ArrowArrayStream::into_arrow2`].
+///
+/// [Arrow C stream interface]: https://arrow.apache.org/docs/format/CStreamInterface.html
+#[repr(C)]
+#[derive(Debug)]
+pub struct ArrowArrayStream {
+    get_schema: Option<
+        unsafe extern "C" fn(stream: *mut ArrowArrayStream, out: *mut ffi::ArrowSchema) -> c_int,
+    >,
+    get_next: Option<
+        unsafe extern "C" fn(stream: *mut ArrowArrayStream, out: *mut ffi::ArrowArray) -> c_int,
+    >,
+    get_last_error: Option<unsafe extern "C" fn(stream: *mut ArrowArrayStream) -> *const c_char>,
+    release: Option<unsafe extern "C" fn(stream: *mut ArrowArrayStream)>,
+    private_data: *mut c_void,
+}
+
+const _: () = assert!(
+    std::mem::size_of::<ArrowArrayStream>() == std::mem::size_of::<ffi::ArrowArrayStream>()
+);
+
+// The state of the stream is only reachable through the stream, which the C interface does not
+// allow to use from several threads at once.
+unsafe impl Send for ArrowArrayStream {}
+
+unsafe fn state<'a>(stream: *mut ArrowArrayStream) -> Option<&'a mut StreamState> {
+    if stream.is_null() {
+        return None;
+    }
+    ((*stream).private_data as *mut StreamState).as_mut()
+}
+
+unsafe extern "C" fn get_schema(
+    stream: *mut ArrowArrayStream,
+    out: *mut ffi::ArrowSchema,
+) -> c_int {
+    match state(stream) {
+        Some(state) if !out.is_null() => {
+            std::ptr::write(out, ffi::export_field_to_c(&state.field));
+            0
+        }
+        _ => EINVAL,
+    }
+}
+
+unsafe extern "C" fn get_next(stream: *mut ArrowArrayStream, out: *mut ffi::ArrowArray) -> c_int {
+    match state(stream) {
+        Some(state) if !out.is_null() => {
+            let array = match state.batches.next() {
+                Some(batch) => ffi::export_array_to_c(batch),
+                // A released array marks the end of the stream.
+                None => ffi::ArrowArray::empty(),
+            };
+            std::ptr::write(out, array);
+            0
+        }
+        _ => EINVAL,
+    }
+}
+
+unsafe extern "C" fn get_last_error(stream: *mut ArrowArrayStream) -> *const c_char {
+    match state(stream) {
+        Some(_) => std::ptr::null(),
+        None => RELEASED.as_ptr(),
+    }
+}
+
+unsafe extern "C" fn release(stream: *mut ArrowArrayStream) {
+    if stream.is_null() || (*stream).private_data.is_null() {
+        return;
+    }
+    drop(Box::from_raw((*stream).private_data as *mut StreamState));
+    (*stream).private_data = std::ptr::null_mut();
+    (*stream).release = None;
+}
+
+impl ArrowArrayStream {
+    pub(crate) fn new(mut df: DataFrame, pin: ExportPin) -> Result<Self, Status> {
+        if df.should_rechunk() {
+            df.rechunk();
+        }
+        let data_type = DataType::Struct(df.schema().to_arrow().fields);
+        let batches = df
+            .iter_chunks()
+            .map(|chunk| {
+                StructArray::try_new(data_type.clone(), chunk.into_arrays(), None)
+                    .map(|array| array.boxed())
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::internal(format!("Could not export the dataframe: {e}")))?;
+        let state = Box::new(StreamState {
+            field: Field::new("", data_type, false),
+            batches: batches.into_iter(),
+            _pin: pin,
+        });
+        Ok(ArrowArrayStream {
+            get_schema: Some(get_schema),
+            get_next: Some(get_next),
+            get_last_error: Some(get_last_error),
+            release: Some(release),
+            private_data: Box::into_raw(state) as *mut c_void,
+        })
+    }
+
+    /// Moves the stream into `out`, which is then responsible for releasing it.
+    ///
+    /// # Safety
+    /// `out` must be valid for writes, and whatever it holds is overwritten without being released.
+    pub unsafe fn move_into(self, out: *mut ArrowArrayStream) {
+        std::ptr::write(out, self);
+    }
+
+    /// Converts the stream for consumers using the FFI of arrow2, such as
+    /// [`ffi::ArrowArrayStreamReader`].
+    pub fn into_arrow2(self) -> Box<ffi::ArrowArrayStream> {
+        let stream = Box::into_raw(Box::new(self));
+        // Safety: both are the `repr(C)` struct of the C stream interface, whose drop releases
+        // the stream.
+        unsafe { Box::from_raw(stream as *mut ffi::ArrowArrayStream) }
+    }
+}
+
+impl Drop for ArrowArrayStream {
+    fn drop(&mut self) {
+        if let Some(release) = self.release {
+            unsafe { release(self) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::{Policy, VerificationResult};
+    use crate::policy_engine::Released;
+    use crate::{BastionLabPolars, DataFrameArtifact};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+
+    fn polars() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn patients() -> DataFrame {
+        let mut df = df! {
+            "id" => [1i64, 2, 3],
+            "name" => ["alice", "bob", "carol"],
+            "weight" => [Some(70.5), None, Some(80.0)],
+        }
+        .unwrap();
+        let more = df! {
+            "id" => [4i64],
+            "name" => ["dave"],
+            "weight" => [Some(66.0)],
+        }
+        .unwrap();
+        // Two chunks, exported as two batches.
+        df.vstack_mut(&more).unwrap();
+        df
+    }
+
+    fn insert(polars: &BastionLabPolars, fetchable: VerificationResult) -> String {
+        polars.insert_df(
+            DataFrameArtifact::new(
+                patients(),
+                Policy::allow_by_default(),
+                vec![String::from("name")],
+            )
+            .with_fetchable(fetchable),
+        )
+    }
+
+    /// Reads a stream as an arrow2 consumer would.
+    fn import(stream: ArrowArrayStream) -> DataFrame {
+        let mut reader =
+            unsafe { ffi::ArrowArrayStreamReader::try_new(stream.into_arrow2()) }.unwrap();
+        let fields = match reader.field().data_type() {
+            DataType::Struct(fields) => fields.clone(),
+            dtype => panic!("Unexpected dtype {dtype:?}"),
+        };
+        let mut df: Option<DataFrame> = None;
+        while let Some(array) = unsafe { reader.next() } {
+            let array = array.unwrap();
+            let array = array.as_any().downcast_ref::<StructArray>().unwrap();
+            let batch = DataFrame::new(
+                fields
+                    .iter()
+                    .zip(array.values())
+                    .map(|(field, values)| {
+                        Series::try_from((field.name.as_str(), values.clone())).unwrap()
+                    })
+                    .collect(),
+            )
+            .unwrap();
+            match &mut df {
+                Some(df) => {
+                    df.vstack_mut(&batch).unwrap();
+                }
+                None => df = Some(batch),
+            }
+        }
+        df.unwrap()
+    }
+
+    async fn fetch(polars: &BastionLabPolars, identifier: &str) -> Result<DataFrame, Status> {
+        polars
+            .get_df(identifier, true, "reader", None, None)?
+            .future
+            .await
+            .map(Released::into_dataframe)
+    }
+
+    #[tokio::test]
+    async fn exports_match_fetches() {
+        let polars = polars();
+        let identifier = insert(&polars, VerificationResult::Safe);
+
+        let stream = polars.export_arrow(&identifier, "reader").await.unwrap();
+        let exported = import(stream);
+        let fetched = fetch(&polars, &identifier).await.unwrap();
+        assert!(exported.frame_equal_missing(&fetched));
+        // Blacklisted columns are masked in both.
+        assert_eq!(exported.column("name").unwrap().null_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn masks_apply_alike_to_exports_fetches_and_previews() {
+        let polars = polars();
+        let mut df = patients();
+        let days = Series::new("day", [19_405i32, 19_406, 19_450, 19_500]);
+        df.with_column(days.cast(&DataType::Date).unwrap()).unwrap();
+        let masks = serde_json::from_value(serde_json::json!({
+            "id": [
+                {"type": "TopBottomCode", "bottom": 25, "top": 75},
+                {"type": "Bucketize", "edges": [0, 3]},
+            ],
+            "name": [{"type": "Redact", "keep_first": 1, "keep_last": 1}],
+            "weight": [{"type": "Replace", "value": 0}],
+            "day": [{"type": "GeneralizeDate", "period": "Month"}],
+        }))
+        .unwrap();
+        let identifier = polars.insert_df(
+            DataFrameArtifact::new(
+                df,
+                Policy::allow_by_default().with_column_masks(masks),
+                Vec::new(),
+            )
+            .with_owner("owner")
+            .with_fetchable(VerificationResult::Safe),
+        );
+
+        let exported = import(polars.export_arrow(&identifier, "reader").await.unwrap());
+        let fetched = fetch(&polars, &identifier).await.unwrap();
+        assert!(exported.frame_equal_missing(&fetched));
+        let preview = polars
+            .lifecycle_response(&identifier, "owner", Vec::new())
+            .unwrap()
+            .preview;
+        assert_eq!(preview, format!("{fetched}"));
+
+        let ids: Vec<_> = fetched
+            .column("id")
+            .unwrap()
+            .f64()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(ids, [Some(0.0), Some(0.0), Some(3.0), Some(3.0)]);
+        let names: Vec<_> = fetched
+            .column("name")
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            names,
+            [Some("a***e"), Some("b*b"), Some("c***l"), Some("d**e")]
+        );
+        let weights = fetched.column("weight").unwrap();
+        assert_eq!(weights.f64().unwrap().sum(), Some(0.0));
+        assert_eq!(weights.null_count(), 0);
+        let days = fetched
+            .column("day")
+            .unwrap()
+            .cast(&DataType::Int32)
+            .unwrap();
+        // 2023-02-01, 2023-04-01 and 2023-05-01.
+        let days: Vec<_> = days.i32().unwrap().into_iter().collect();
+        assert_eq!(
+            days,
+            [Some(19_389), Some(19_389), Some(19_448), Some(19_478)]
+        );
+    }
+
+    /// The first chunk of the values of column `id`.
+    fn id_values(df: &DataFrame) -> *const i64 {
+        df.column("id")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .downcast_iter()
+            .next()
+            .unwrap()
+            .values()
+            .as_ptr()
+    }
+
+    #[tokio::test]
+    async fn exports_and_fetches_share_the_stored_buffers() {
+        let polars = polars();
+        let identifier = polars.insert_df(
+            DataFrameArtifact::new(patients(), Policy::allow_by_default(), Vec::new())
+                .with_fetchable(VerificationResult::Safe),
+        );
+        let stored = polars
+            .with_df_artifact_ref(&identifier, |artifact| id_values(&artifact.dataframe))
+            .unwrap();
+
+        let exported = import(polars.export_arrow(&identifier, "reader").await.unwrap());
+        assert_eq!(id_values(&exported), stored);
+        // Fetches release the stored columns as they are to the serializer.
+        let fetched = fetch(&polars, &identifier).await.unwrap();
+        assert_eq!(id_values(&fetched), stored);
+    }
+
+    #[tokio::test]
+    async fn policies_are_enforced_like_fetches() {
+        let polars = polars();
+        let rejected = polars.insert_df(DataFrameArtifact::new(
+            patients(),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        let export_err = polars.export_arrow(&rejected, "reader").await.unwrap_err();
+        let fetch_err = fetch(&polars, &rejected).await.unwrap_err();
+        assert_eq!(export_err.code(), tonic::Code::PermissionDenied);
+        assert_eq!(export_err.code(), fetch_err.code());
+        assert!(polars.exports.exports().is_empty());
+
+        let logged = insert(
+            &polars,
+            VerificationResult::Unsafe {
+                action: crate::access_control::UnsafeAction::Log,
+                reason: String::from("not aggregated"),
+            },
+        );
+        assert!(polars.export_arrow(&logged, "reader").await.is_ok());
+
+        let missing = polars.export_arrow("missing", "reader").await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn streams_pin_their_dataframe_until_released() {
+        let polars = polars();
+        let identifier = insert(&polars, VerificationResult::Safe);
+        let mut stream = polars.export_arrow(&identifier, "reader").await.unwrap();
+
+        let exports = polars.exports.exports();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].identifier, identifier);
+        assert_eq!(exports[0].identity, "reader");
+        assert!(polars.delete_dfs(&identifier).is_err());
+
+        let mut batch = ffi::ArrowArray::empty();
+        unsafe {
+            assert_eq!(get_next(&mut stream, &mut batch), 0);
+            release(&mut stream);
+            // Releasing twice or reading after release is harmless.
+            release(&mut stream);
+            assert_eq!(get_next(&mut stream, &mut ffi::ArrowArray::empty()), EINVAL);
+            assert!(!get_last_error(&mut stream).is_null());
+        }
+        assert!(polars.exports.exports().is_empty());
+        polars.delete_dfs(&identifier).unwrap();
+
+        // The batch read before the release is still valid.
+        let field = Field::new(
+            "",
+            DataType::Struct(patients().schema().to_arrow().fields),
+            false,
+        );
+        let batch = unsafe { ffi::import_array_from_c(batch, field.data_type).unwrap() };
+        let ids = batch
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .unwrap()
+            .values()[0]
+            .clone();
+        let ids = Series::try_from(("id", ids)).unwrap();
+        assert_eq!(ids.i64().unwrap().get(0), Some(1));
+    }
+
+    #[tokio::test]
+    async fn streams_outlive_the_server() {
+        let polars = polars();
+        let identifier = insert(&polars, VerificationResult::Safe);
+        let stream = polars.export_arrow(&identifier, "reader").await.unwrap();
+        drop(polars);
+        assert_eq!(import(stream).height(), 4);
+    }
+}
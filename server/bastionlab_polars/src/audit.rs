@@ -0,0 +1,390 @@
+//! The audit log: an append-only record of who queried, fetched and uploaded what, and when.
+//!
+//! Every query records the identity that ran it, the hash and segment types of its composite plan,
+//! the dataframes it read and the result it produced; every fetch and transfer whether the policy
+//! allowed it, held it pending the data owner's approval or denied it; every upload the dataframe
+//! it stored. Identities are the hashes of the verified keys of the sessions. Entries never hold
+//! data values: plans are recorded by hash, as their filters and literal frames may contain some,
+//! and errors by outcome only.
+//!
+//! Entries are written by [`AuditSink`]s: as JSON lines appended to `audit_log_file` if set, in
+//! memory otherwise, up to `access_log_capacity` entries. Writes go through a channel to a thread
+//! of their own, so that requests never wait on the disk. Data owners read the log back with
+//! `GetAuditLog`, filtered by time range and dataframe.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tonic::Status;
+
+use crate::polars_proto;
+use crate::prelude::*;
+use crate::purpose::AccessKind;
+
+/// Longest identifier or segment type recorded from a plan that could not run, in characters.
+const MAX_PLAN_STRING: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOutcome {
+    Allowed,
+    /// Held for the approval of the data owner, see [`crate::approvals`].
+    Pending,
+    Denied,
+    /// Failed for another reason than the policy, such as an invalid plan.
+    Failed,
+}
+
+impl AuditOutcome {
+    /// The outcome of a request that returned `code`.
+    pub fn of_error(code: tonic::Code) -> Self {
+        match code {
+            tonic::Code::PermissionDenied => AuditOutcome::Denied,
+            _ => AuditOutcome::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch.
+    pub at: u64,
+    pub identity: String,
+    pub action: AccessKind,
+    /// The result of a query, the dataframe fetched or uploaded. Empty for queries that failed.
+    pub identifier: String,
+    /// The dataframes read to produce `identifier`.
+    pub inputs: Vec<String>,
+    /// SHA-256 of the plan as submitted, for queries.
+    #[serde(default)]
+    pub plan_hash: String,
+    /// The types of the segments of the plan, for queries.
+    #[serde(default)]
+    pub segments: Vec<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEntry {
+    fn touches(&self, identifier: &str) -> bool {
+        self.identifier == identifier || self.inputs.iter().any(|input| input == identifier)
+    }
+
+    pub fn to_proto(&self) -> polars_proto::AuditEntry {
+        polars_proto::AuditEntry {
+            at: self.at,
+            identity: self.identity.clone(),
+            action: format!("{:?}", self.action),
+            identifier: self.identifier.clone(),
+            inputs: self.inputs.clone(),
+            plan_hash: self.plan_hash.clone(),
+            segments: self.segments.clone(),
+            outcome: format!("{:?}", self.outcome),
+        }
+    }
+}
+
+/// Entries from `since` to `until` (both in milliseconds since the Unix epoch, `until` excluded
+/// and unbounded if 0), touching `identifier` if set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub since: u64,
+    pub until: u64,
+    pub identifier: Option<String>,
+}
+
+impl AuditFilter {
+    pub fn from_proto(request: polars_proto::AuditLogRequest) -> Self {
+        AuditFilter {
+            since: request.since,
+            until: request.until,
+            identifier: (!request.identifier.is_empty()).then_some(request.identifier),
+        }
+    }
+
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        entry.at >= self.since
+            && (self.until == 0 || entry.at < self.until)
+            && self
+                .identifier
+                .as_ref()
+                .map_or(true, |identifier| entry.touches(identifier))
+    }
+}
+
+/// Where entries are written.
+pub trait AuditSink: Send {
+    fn append(&mut self, entry: &AuditEntry) -> io::Result<()>;
+
+    /// The entries matching `filter`, oldest first, `None` for sinks that cannot be read back.
+    fn read(&mut self, _filter: &AuditFilter) -> io::Result<Option<Vec<AuditEntry>>> {
+        Ok(None)
+    }
+
+    /// Makes the entries appended so far durable.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends entries to a file, one JSON object per line.
+pub struct JsonLinesSink {
+    path: PathBuf,
+    file: File,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(JsonLinesSink { path, file })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn append(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // A single write, so that lines are not interleaved with those of another process.
+        self.file.write_all(&line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn read(&mut self, filter: &AuditFilter) -> io::Result<Option<Vec<AuditEntry>>> {
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(File::open(&self.path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                Ok(_) => (),
+                Err(e) => warn!(
+                    "Skipping line {} of audit log {}: {e}",
+                    number + 1,
+                    self.path.display()
+                ),
+            }
+        }
+        Ok(Some(entries))
+    }
+}
+
+/// Keeps the last `capacity` entries in memory.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+}
+
+impl MemorySink {
+    pub fn new(capacity: usize) -> Self {
+        MemorySink {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+}
+
+impl AuditSink for MemorySink {
+    fn append(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        if self.capacity > 0 {
+            self.entries.push_back(entry.clone());
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, filter: &AuditFilter) -> io::Result<Option<Vec<AuditEntry>>> {
+        let entries = self.entries.iter().filter(|e| filter.matches(e)).cloned();
+        Ok(Some(entries.collect()))
+    }
+}
+
+enum Message {
+    Append(AuditEntry),
+    Read(AuditFilter, oneshot::Sender<io::Result<Vec<AuditEntry>>>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands entries over to the thread writing them to the sinks.
+#[derive(Debug)]
+pub struct AuditLog {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl AuditLog {
+    /// Starts the thread writing to `sinks`, which stops with the last handle on the log. Reads
+    /// are served by the first sink that can be read back.
+    pub fn new(mut sinks: Vec<Box<dyn AuditSink>>) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let spawned = std::thread::Builder::new()
+            .name(String::from("audit-log"))
+            .spawn(move || {
+                while let Some(message) = receiver.blocking_recv() {
+                    match message {
+                        Message::Append(entry) => {
+                            for sink in sinks.iter_mut() {
+                                if let Err(e) = sink.append(&entry) {
+                                    error!("Could not write audit entry {entry:?}: {e}");
+                                }
+                            }
+                        }
+                        Message::Read(filter, reply) => {
+                            let _ignored = reply.send(read(&mut sinks, &filter));
+                        }
+                        Message::Flush(reply) => {
+                            for sink in sinks.iter_mut() {
+                                if let Err(e) = sink.flush() {
+                                    error!("Could not flush the audit log: {e}");
+                                }
+                            }
+                            let _ignored = reply.send(());
+                        }
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Could not start the audit log writer: {e}");
+        }
+        AuditLog { sender }
+    }
+
+    /// Queues `entry` for writing, without waiting for it to be written.
+    pub fn record(&self, entry: AuditEntry) {
+        if self.sender.send(Message::Append(entry)).is_err() {
+            error!("Could not record an audit entry: the audit log writer stopped");
+        }
+    }
+
+    /// The entries matching `filter`, oldest first, including all those recorded before.
+    pub async fn entries(&self, filter: AuditFilter) -> Result<Vec<AuditEntry>, Status> {
+        let stopped = || Status::unavailable("The audit log writer stopped");
+        let (reply, entries) = oneshot::channel();
+        self.sender
+            .send(Message::Read(filter, reply))
+            .map_err(|_| stopped())?;
+        entries
+            .await
+            .map_err(|_| stopped())?
+            .map_err(|e| Status::internal(format!("Could not read the audit log: {e}")))
+    }
+
+    /// Waits for the entries recorded so far to be written, and flushes the sinks.
+    pub async fn flush(&self) {
+        let (reply, flushed) = oneshot::channel();
+        if self.sender.send(Message::Flush(reply)).is_err() || flushed.await.is_err() {
+            error!("Could not flush the audit log: the audit log writer stopped");
+        }
+    }
+}
+
+fn read(sinks: &mut [Box<dyn AuditSink>], filter: &AuditFilter) -> io::Result<Vec<AuditEntry>> {
+    for sink in sinks.iter_mut() {
+        if let Some(entries) = sink.read(filter)? {
+            return Ok(entries);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no audit sink can be read back",
+    ))
+}
+
+/// The entry point identifiers and segment types of composite plan `plan` as submitted, which may
+/// not be valid: strings are stripped of control characters and truncated.
+pub fn plan_summary(plan: &str) -> (Vec<String>, Vec<String>) {
+    let segments = match serde_json::from_str::<Value>(plan) {
+        Ok(Value::Object(mut plan)) => match plan.remove("segments") {
+            Some(Value::Array(segments)) => segments,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+    let field = |segment: &Value, name: &str| -> Option<String> {
+        let value = segment.get(name)?.as_str()?;
+        Some(
+            value
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_PLAN_STRING)
+                .collect(),
+        )
+    };
+    let mut inputs = Vec::new();
+    let mut types = Vec::new();
+    for segment in segments.iter() {
+        let kind = field(segment, "type").unwrap_or_default();
+        if kind == "EntryPointPlanSegment" {
+            inputs.extend(field(segment, "identifier"));
+        }
+        types.push(kind);
+    }
+    (inputs, types)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(at: u64, identifier: &str, outcome: AuditOutcome) -> AuditEntry {
+        AuditEntry {
+            at,
+            identity: String::from("analyst"),
+            action: AccessKind::Fetch,
+            identifier: identifier.to_string(),
+            inputs: vec![String::from("input")],
+            plan_hash: String::new(),
+            segments: Vec::new(),
+            outcome,
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_and_filtered() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = AuditLog::new(vec![Box::new(JsonLinesSink::open(&path).unwrap())]);
+        log.record(entry(1, "a", AuditOutcome::Allowed));
+        log.record(entry(2, "b", AuditOutcome::Pending));
+        log.record(entry(3, "a", AuditOutcome::Denied));
+
+        let all = log.entries(AuditFilter::default()).await.unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1].outcome, AuditOutcome::Pending);
+        let filter = AuditFilter {
+            since: 2,
+            until: 0,
+            identifier: Some(String::from("a")),
+        };
+        assert_eq!(log.entries(filter).await.unwrap(), [all[2].clone()]);
+        let filter = AuditFilter {
+            since: 0,
+            until: 3,
+            identifier: Some(String::from("input")),
+        };
+        assert_eq!(log.entries(filter).await.unwrap().len(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        // Flushing waits for the entries recorded before.
+        log.record(entry(4, "c", AuditOutcome::Allowed));
+        log.flush().await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+        std::fs::remove_file(path).unwrap();
+
+        let (inputs, segments) = plan_summary(
+            r#"{"segments": [{"type": "EntryPointPlanSegment", "identifier": "x\ny"},
+                             {"type": "FilterPlanSegment", "predicate": {"value": 42}}]}"#,
+        );
+        assert_eq!(inputs, ["xy"]);
+        assert_eq!(segments, ["EntryPointPlanSegment", "FilterPlanSegment"]);
+        assert_eq!(plan_summary("pipeline:x"), (Vec::new(), Vec::new()));
+    }
+}
@@ -0,0 +1,119 @@
+//! Queries run in one call, such as the many small aggregates of a dashboard.
+//!
+//! `RunQueryBatch` takes up to `max_queries_per_batch` queries and answers with one result per
+//! query, in order: its reference, or the error it failed with. A failing query does not fail the
+//! batch, unless it is run in fail-fast mode: queries that have not started by then are skipped.
+//! Queries run `query_batch_parallelism` at a time, each in its own execution slot, so that the
+//! scheduler and the probing detector count a batch as the queries it holds.
+//!
+//! Entry points are resolved once per batch: queries naming the same alias or the same
+//! `identifier@timestamp` read the same dataframe. Every access of the batch is recorded under the
+//! id of the batch, as a correlation id.
+//!
+//! Batches may ask for results to be inlined: results up to `inline_result_max_kb` that their
+//! requester may fetch without approval are sent along with their reference, as IPC files, and
+//! recorded as fetched. Other results are fetched as usual.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tonic::{Code, Status};
+
+use crate::polars_proto::BatchedQueryResult;
+
+/// The identifier an entry point resolved to, and the redirect of the alias it named if any.
+type Resolution = (String, Option<String>);
+
+/// How entry points resolved within a batch, by entry point, or the code and message of the error
+/// they failed with.
+#[derive(Debug, Default)]
+pub struct EntryPoints(Mutex<HashMap<String, Result<Resolution, (Code, String)>>>);
+
+impl EntryPoints {
+    /// Resolves entry point `identifier` with `resolve`, unless it was already.
+    pub fn resolve(
+        &self,
+        identifier: &str,
+        resolve: impl FnOnce(&str) -> Result<Resolution, Status>,
+    ) -> Result<Resolution, Status> {
+        if let Some(resolved) = self.0.lock().unwrap().get(identifier) {
+            return resolved
+                .clone()
+                .map_err(|(code, message)| Status::new(code, message));
+        }
+        let resolved = resolve(identifier);
+        let cached = match &resolved {
+            Ok(resolved) => Ok(resolved.clone()),
+            Err(e) => Err((e.code(), e.message().to_string())),
+        };
+        self.0
+            .lock()
+            .unwrap()
+            .insert(identifier.to_string(), cached);
+        resolved
+    }
+}
+
+pub fn check_size(queries: usize, max_queries: usize) -> Result<(), Status> {
+    if queries == 0 {
+        return Err(Status::invalid_argument(
+            "Could not run batch: it holds no query",
+        ));
+    }
+    if queries > max_queries {
+        return Err(Status::invalid_argument(format!(
+            "Could not run batch: it holds {queries} queries, more than the {max_queries} this \
+             server runs per batch"
+        )));
+    }
+    Ok(())
+}
+
+pub fn failed(e: Status) -> BatchedQueryResult {
+    BatchedQueryResult {
+        error_code: e.code() as i32,
+        error: e.message().to_string(),
+        ..Default::default()
+    }
+}
+
+pub fn skipped() -> BatchedQueryResult {
+    BatchedQueryResult {
+        skipped: true,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_points_resolve_once_per_batch() {
+        let entry_points = EntryPoints::default();
+        let mut calls = 0;
+        for _ in 0..3 {
+            let resolved = entry_points.resolve("visits", |identifier| {
+                calls += 1;
+                Ok((format!("{identifier}-canonical"), None))
+            });
+            assert_eq!(resolved.unwrap().0, "visits-canonical");
+        }
+        for _ in 0..2 {
+            let err = entry_points
+                .resolve("gone", |identifier| {
+                    calls += 1;
+                    Err(Status::not_found(format!("No dataframe {identifier}")))
+                })
+                .unwrap_err();
+            assert_eq!(err.code(), Code::NotFound);
+            assert_eq!(err.message(), "No dataframe gone");
+        }
+        assert_eq!(calls, 2);
+
+        assert!(check_size(0, 4).is_err());
+        assert!(check_size(4, 4).is_ok());
+        let err = check_size(5, 4).unwrap_err();
+        assert!(err.message().contains("holds 5 queries, more than the 4"));
+    }
+}
@@ -0,0 +1,587 @@
+//! Canonical dataframe serialization.
+//!
+//! The IPC bytes of a dataframe depend on how it is chunked and on the polars version, so the
+//! same logical frame can hash differently. This format only depends on the logical content of
+//! the frame and is the stable contract for hashing and for clients that are not written in Rust.
+//!
+//! All integers are little-endian. A frame is laid out as:
+//!
+//! ```text
+//! magic      b"BLCF"
+//! version    u8 (currently 1)
+//! n_columns  u32
+//! n_rows     u64
+//! columns    n_columns times:
+//!     name       u32 byte length, then UTF-8 bytes
+//!     dtype      u8 tag, then its parameters (see below)
+//!     validity   u8: 0 if the column has no null, 1 if it is followed by a bitmap
+//!     values     depends on the dtype (see below)
+//! ```
+//!
+//! Bitmaps are `ceil(n_rows / 8)` bytes, bit `i % 8` of byte `i / 8` (least significant first)
+//! stands for row `i`. In the validity bitmap, a set bit means the row is not null.
+//!
+//! | tag | dtype        | parameters                                | values                          |
+//! |-----|--------------|-------------------------------------------|---------------------------------|
+//! | 1   | Boolean      |                                           | bitmap                          |
+//! | 2-5 | UInt8..64    |                                           | `n_rows` fixed-width integers   |
+//! | 6-9 | Int8..64     |                                           | `n_rows` fixed-width integers   |
+//! | 10  | Float32      |                                           | `n_rows` IEEE 754 floats        |
+//! | 11  | Float64      |                                           | `n_rows` IEEE 754 floats        |
+//! | 12  | Utf8         |                                           | per row: u32 length, UTF-8 bytes|
+//! | 13  | Categorical  |                                           | same as Utf8 (category values)  |
+//! | 14  | Date         |                                           | `n_rows` i32 days since epoch   |
+//! | 15  | Datetime     | u8 time unit, u32 length + UTF-8 timezone | `n_rows` i64                    |
+//! | 16  | Duration     | u8 time unit                              | `n_rows` i64                    |
+//! | 17  | Time         |                                           | `n_rows` i64 ns since midnight  |
+//!
+//! Time units are 0 for nanoseconds, 1 for microseconds and 2 for milliseconds; an empty
+//! timezone means none. Null rows are written as zero (or as an empty string), and every NaN is
+//! written as the quiet NaN `0x7fc00000` / `0x7ff8000000000000`.
+
+use polars::prelude::*;
+use tonic::Status;
+
+const MAGIC: &[u8; 4] = b"BLCF";
+const VERSION: u8 = 1;
+
+const F32_NAN: u32 = 0x7fc0_0000;
+const F64_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error during canonical serialization: {e}"))
+}
+
+fn corrupt(what: &str) -> Status {
+    Status::invalid_argument(format!("Invalid canonical dataframe: {what}"))
+}
+
+fn time_unit_tag(tu: &TimeUnit) -> u8 {
+    match tu {
+        TimeUnit::Nanoseconds => 0,
+        TimeUnit::Microseconds => 1,
+        TimeUnit::Milliseconds => 2,
+    }
+}
+
+fn time_unit_from_tag(tag: u8) -> Result<TimeUnit, Status> {
+    Ok(match tag {
+        0 => TimeUnit::Nanoseconds,
+        1 => TimeUnit::Microseconds,
+        2 => TimeUnit::Milliseconds,
+        _ => return Err(corrupt("unknown time unit")),
+    })
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn bitmap(&mut self, bits: impl Iterator<Item = bool>) {
+        let mut byte = 0u8;
+        let mut n = 0;
+        for bit in bits {
+            byte |= (bit as u8) << (n % 8);
+            n += 1;
+            if n % 8 == 0 {
+                self.buf.push(byte);
+                byte = 0;
+            }
+        }
+        if n % 8 != 0 {
+            self.buf.push(byte);
+        }
+    }
+}
+
+macro_rules! write_values {
+    ($w:expr, $ca:expr) => {
+        for v in $ca.into_iter() {
+            $w.buf
+                .extend_from_slice(&v.unwrap_or_default().to_le_bytes());
+        }
+    };
+}
+
+fn write_column(w: &mut Writer, series: &Series) -> Result<(), Status> {
+    w.str(series.name());
+
+    let dtype = series.dtype();
+    let tag = match dtype {
+        DataType::Boolean => 1,
+        DataType::UInt8 => 2,
+        DataType::UInt16 => 3,
+        DataType::UInt32 => 4,
+        DataType::UInt64 => 5,
+        DataType::Int8 => 6,
+        DataType::Int16 => 7,
+        DataType::Int32 => 8,
+        DataType::Int64 => 9,
+        DataType::Float32 => 10,
+        DataType::Float64 => 11,
+        DataType::Utf8 => 12,
+        DataType::Categorical(_) => 13,
+        DataType::Date => 14,
+        DataType::Datetime(_, _) => 15,
+        DataType::Duration(_) => 16,
+        DataType::Time => 17,
+        dtype => {
+            return Err(Status::unimplemented(format!(
+                "Canonical serialization does not support columns of type {dtype}"
+            )))
+        }
+    };
+    w.u8(tag);
+    match dtype {
+        DataType::Datetime(tu, tz) => {
+            w.u8(time_unit_tag(tu));
+            w.str(tz.as_deref().unwrap_or(""));
+        }
+        DataType::Duration(tu) => w.u8(time_unit_tag(tu)),
+        _ => (),
+    }
+
+    if series.null_count() > 0 {
+        w.u8(1);
+        w.bitmap(series.is_not_null().into_iter().map(|v| v.unwrap_or(false)));
+    } else {
+        w.u8(0);
+    }
+
+    match dtype {
+        DataType::Boolean => {
+            w.bitmap(
+                series
+                    .bool()
+                    .map_err(polars_err)?
+                    .into_iter()
+                    .map(|v| v.unwrap_or(false)),
+            );
+        }
+        DataType::UInt8 => write_values!(w, series.u8().map_err(polars_err)?),
+        DataType::UInt16 => write_values!(w, series.u16().map_err(polars_err)?),
+        DataType::UInt32 => write_values!(w, series.u32().map_err(polars_err)?),
+        DataType::UInt64 => write_values!(w, series.u64().map_err(polars_err)?),
+        DataType::Int8 => write_values!(w, series.i8().map_err(polars_err)?),
+        DataType::Int16 => write_values!(w, series.i16().map_err(polars_err)?),
+        DataType::Int32 | DataType::Date => {
+            let physical = series.to_physical_repr();
+            write_values!(w, physical.i32().map_err(polars_err)?)
+        }
+        DataType::Int64 | DataType::Datetime(_, _) | DataType::Duration(_) | DataType::Time => {
+            let physical = series.to_physical_repr();
+            write_values!(w, physical.i64().map_err(polars_err)?)
+        }
+        DataType::Float32 => {
+            for v in series.f32().map_err(polars_err)?.into_iter() {
+                let bits = match v {
+                    Some(x) if x.is_nan() => F32_NAN,
+                    Some(x) => x.to_bits(),
+                    None => 0,
+                };
+                w.u32(bits);
+            }
+        }
+        DataType::Float64 => {
+            for v in series.f64().map_err(polars_err)?.into_iter() {
+                let bits = match v {
+                    Some(x) if x.is_nan() => F64_NAN,
+                    Some(x) => x.to_bits(),
+                    None => 0,
+                };
+                w.buf.extend_from_slice(&bits.to_le_bytes());
+            }
+        }
+        _ => {
+            let values = series.cast(&DataType::Utf8).map_err(polars_err)?;
+            for v in values.utf8().map_err(polars_err)?.into_iter() {
+                w.str(v.unwrap_or(""));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serializes `df` to the canonical format described in the module documentation.
+pub fn to_canonical_bytes(df: &DataFrame) -> Result<Vec<u8>, Status> {
+    let mut w = Writer { buf: Vec::new() };
+    w.buf.extend_from_slice(MAGIC);
+    w.u8(VERSION);
+    w.u32(df.width() as u32);
+    w.buf.extend_from_slice(&(df.height() as u64).to_le_bytes());
+    for series in df.get_columns() {
+        write_column(&mut w, series)?;
+    }
+    Ok(w.buf)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Status> {
+        if self.buf.len() < n {
+            return Err(corrupt("unexpected end of data"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Status> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Status> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Status> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Status> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| corrupt("invalid UTF-8"))
+    }
+
+    fn bitmap(&mut self, n: usize) -> Result<Vec<bool>, Status> {
+        let bytes = self.bytes(n.div_ceil(8))?;
+        Ok((0..n).map(|i| bytes[i / 8] & (1 << (i % 8)) != 0).collect())
+    }
+}
+
+macro_rules! read_values {
+    ($r:expr, $name:expr, $n_rows:expr, $validity:expr, $ty:ty) => {{
+        let size = std::mem::size_of::<$ty>();
+        let bytes = $r.bytes(size * $n_rows)?;
+        let values = bytes
+            .chunks_exact(size)
+            .map(|b| <$ty>::from_le_bytes(b.try_into().unwrap()));
+        match &$validity {
+            // Without nulls, the values are moved into the series instead of being copied.
+            None => Series::new($name, values.collect::<Vec<$ty>>()),
+            Some(validity) => {
+                let values: Vec<Option<$ty>> = values
+                    .zip(validity.iter())
+                    .map(|(v, valid)| valid.then_some(v))
+                    .collect();
+                Series::new($name, values)
+            }
+        }
+    }};
+}
+
+fn read_column(r: &mut Reader, n_rows: usize) -> Result<Series, Status> {
+    let name = r.str()?;
+    let tag = r.u8()?;
+    let dtype = match tag {
+        1 => DataType::Boolean,
+        2 => DataType::UInt8,
+        3 => DataType::UInt16,
+        4 => DataType::UInt32,
+        5 => DataType::UInt64,
+        6 => DataType::Int8,
+        7 => DataType::Int16,
+        8 => DataType::Int32,
+        9 => DataType::Int64,
+        10 => DataType::Float32,
+        11 => DataType::Float64,
+        12 => DataType::Utf8,
+        13 => DataType::Categorical(None),
+        14 => DataType::Date,
+        15 => {
+            let tu = time_unit_from_tag(r.u8()?)?;
+            let tz = r.str()?;
+            DataType::Datetime(tu, (!tz.is_empty()).then(|| tz.to_string()))
+        }
+        16 => DataType::Duration(time_unit_from_tag(r.u8()?)?),
+        17 => DataType::Time,
+        _ => return Err(corrupt("unknown dtype")),
+    };
+
+    let validity = match r.u8()? {
+        0 => None,
+        1 => Some(r.bitmap(n_rows)?),
+        _ => return Err(corrupt("invalid validity flag")),
+    };
+    let is_valid = |i: usize| validity.as_ref().is_none_or(|v| v[i]);
+
+    let series = match &dtype {
+        DataType::Boolean => {
+            let values: Vec<Option<bool>> = r
+                .bitmap(n_rows)?
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| is_valid(i).then_some(v))
+                .collect();
+            Series::new(name, values)
+        }
+        DataType::UInt8 => read_values!(r, name, n_rows, validity, u8),
+        DataType::UInt16 => read_values!(r, name, n_rows, validity, u16),
+        DataType::UInt32 => read_values!(r, name, n_rows, validity, u32),
+        DataType::UInt64 => read_values!(r, name, n_rows, validity, u64),
+        DataType::Int8 => read_values!(r, name, n_rows, validity, i8),
+        DataType::Int16 => read_values!(r, name, n_rows, validity, i16),
+        DataType::Int32 | DataType::Date => read_values!(r, name, n_rows, validity, i32),
+        DataType::Int64 | DataType::Datetime(_, _) | DataType::Duration(_) | DataType::Time => {
+            read_values!(r, name, n_rows, validity, i64)
+        }
+        DataType::Float32 => read_values!(r, name, n_rows, validity, f32),
+        DataType::Float64 => read_values!(r, name, n_rows, validity, f64),
+        _ => {
+            // The strings are measured first so that the builder is never reallocated.
+            let mut scan = Reader { buf: r.buf };
+            let mut bytes_capacity = 0;
+            for _ in 0..n_rows {
+                bytes_capacity += scan.str()?.len();
+            }
+            let mut builder = Utf8ChunkedBuilder::new(name, n_rows, bytes_capacity);
+            for i in 0..n_rows {
+                let v = r.str()?;
+                if is_valid(i) {
+                    builder.append_value(v);
+                } else {
+                    builder.append_null();
+                }
+            }
+            builder.finish().into_series()
+        }
+    };
+
+    if series.dtype() == &dtype {
+        Ok(series)
+    } else {
+        series.cast(&dtype).map_err(polars_err)
+    }
+}
+
+/// Reads a dataframe serialized with [`to_canonical_bytes`].
+pub fn from_canonical_bytes(buf: &[u8]) -> Result<DataFrame, Status> {
+    let mut r = Reader { buf };
+    if r.bytes(4)? != MAGIC {
+        return Err(corrupt("bad magic"));
+    }
+    if r.u8()? != VERSION {
+        return Err(corrupt("unsupported version"));
+    }
+    let n_columns = r.u32()? as usize;
+    let n_rows = usize::try_from(r.u64()?).map_err(|_| corrupt("too many rows"))?;
+
+    let mut columns = Vec::with_capacity(n_columns);
+    for _ in 0..n_columns {
+        columns.push(read_column(&mut r, n_rows)?);
+    }
+    if !r.buf.is_empty() {
+        return Err(corrupt("trailing data"));
+    }
+    DataFrame::new(columns).map_err(polars_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(n_columns: u32, n_rows: u64) -> Vec<u8> {
+        let mut buf = b"BLCF\x01".to_vec();
+        buf.extend_from_slice(&n_columns.to_le_bytes());
+        buf.extend_from_slice(&n_rows.to_le_bytes());
+        buf
+    }
+
+    fn column(name: &str, dtype: &[u8], validity: &[u8], values: &[u8]) -> Vec<u8> {
+        let mut buf = (name.len() as u32).to_le_bytes().to_vec();
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(dtype);
+        buf.extend_from_slice(validity);
+        buf.extend_from_slice(values);
+        buf
+    }
+
+    /// Checks the golden bytes of a single-column frame, and that they read back to the same frame.
+    fn check(series: Series, dtype: &[u8], validity: &[u8], values: &[u8]) {
+        let df = DataFrame::new(vec![series]).unwrap();
+        let mut expected = header(1, df.height() as u64);
+        expected.extend(column(df.get_column_names()[0], dtype, validity, values));
+
+        let bytes = to_canonical_bytes(&df).unwrap();
+        assert_eq!(bytes, expected, "golden bytes of {:?}", df.dtypes());
+
+        let back = from_canonical_bytes(&bytes).unwrap();
+        assert_eq!(back.dtypes(), df.dtypes());
+        // Categories are only comparable through their values.
+        let values = |df: &DataFrame| match df.dtypes()[0] {
+            DataType::Categorical(_) => df.get_columns()[0].cast(&DataType::Utf8).unwrap(),
+            _ => df.get_columns()[0].clone(),
+        };
+        assert!(values(&back).series_equal_missing(&values(&df)));
+    }
+
+    #[test]
+    fn golden_integers() {
+        check(Series::new("a", [1u8, 255]), &[2], &[0], &[1, 255]);
+        check(Series::new("a", [258u16]), &[3], &[0], &[2, 1]);
+        check(Series::new("a", [1u32]), &[4], &[0], &[1, 0, 0, 0]);
+        check(
+            Series::new("a", [1u64]),
+            &[5],
+            &[0],
+            &[1, 0, 0, 0, 0, 0, 0, 0],
+        );
+        check(Series::new("a", [-1i8]), &[6], &[0], &[0xff]);
+        check(Series::new("a", [-2i16]), &[7], &[0], &[0xfe, 0xff]);
+        check(
+            Series::new("a", [Some(-1i32), None, Some(2)]),
+            &[8],
+            &[1, 0b101],
+            &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 2, 0, 0, 0],
+        );
+        check(
+            Series::new("a", [i64::MIN]),
+            &[9],
+            &[0],
+            &[0, 0, 0, 0, 0, 0, 0, 0x80],
+        );
+    }
+
+    #[test]
+    fn golden_floats() {
+        check(
+            Series::new("f", [Some(1.0f32), None]),
+            &[10],
+            &[1, 0b01],
+            &[0, 0, 0x80, 0x3f, 0, 0, 0, 0],
+        );
+        check(
+            Series::new("f", [-0.0f64, 1.5]),
+            &[11],
+            &[0],
+            &[0, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f],
+        );
+
+        // Every NaN payload is written as the same quiet NaN.
+        let odd_nan = f64::from_bits(0x7ff0_0000_0000_0001);
+        let df = DataFrame::new(vec![Series::new("f", [odd_nan])]).unwrap();
+        let bytes = to_canonical_bytes(&df).unwrap();
+        assert_eq!(&bytes[bytes.len() - 8..], &F64_NAN.to_le_bytes());
+    }
+
+    #[test]
+    fn golden_booleans() {
+        let values: Vec<Option<bool>> = (0..10)
+            .map(|i| if i == 3 { None } else { Some(i % 2 == 0) })
+            .collect();
+        check(
+            Series::new("b", values),
+            &[1],
+            &[1, 0b1111_0111, 0b11],
+            &[0b0101_0101, 0b01],
+        );
+    }
+
+    #[test]
+    fn golden_strings() {
+        let utf8 = Series::new("s", [Some("ab"), None, Some("é")]);
+        let values = [2, 0, 0, 0, b'a', b'b', 0, 0, 0, 0, 2, 0, 0, 0, 0xc3, 0xa9];
+        check(utf8.clone(), &[12], &[1, 0b101], &values);
+        check(
+            utf8.cast(&DataType::Categorical(None)).unwrap(),
+            &[13],
+            &[1, 0b101],
+            &values,
+        );
+    }
+
+    #[test]
+    fn golden_temporal() {
+        let days = Series::new("t", [1i32]);
+        check(
+            days.cast(&DataType::Date).unwrap(),
+            &[14],
+            &[0],
+            &[1, 0, 0, 0],
+        );
+
+        let ticks = Series::new("t", [1i64]);
+        let one = [1, 0, 0, 0, 0, 0, 0, 0];
+        check(
+            ticks
+                .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))
+                .unwrap(),
+            &[15, 2, 0, 0, 0, 0],
+            &[0],
+            &one,
+        );
+        check(
+            ticks
+                .cast(&DataType::Datetime(
+                    TimeUnit::Microseconds,
+                    Some("UTC".to_string()),
+                ))
+                .unwrap(),
+            &[15, 1, 3, 0, 0, 0, b'U', b'T', b'C'],
+            &[0],
+            &one,
+        );
+        check(
+            ticks
+                .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+                .unwrap(),
+            &[16, 0],
+            &[0],
+            &one,
+        );
+        check(ticks.cast(&DataType::Time).unwrap(), &[17], &[0], &one);
+    }
+
+    #[test]
+    fn bytes_do_not_depend_on_chunking() {
+        let whole = df! {
+            "x" => [Some(1i64), None, Some(3), Some(4)],
+            "s" => ["a", "b", "c", "d"],
+        }
+        .unwrap();
+        let mut chunked = whole.slice(0, 2);
+        chunked.vstack_mut(&whole.slice(2, 2)).unwrap();
+        assert!(chunked.n_chunks().unwrap() > 1);
+
+        assert_eq!(
+            to_canonical_bytes(&whole).unwrap(),
+            to_canonical_bytes(&chunked).unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_frames_round_trip() {
+        let df = df! { "x" => Vec::<i32>::new() }.unwrap();
+        let bytes = to_canonical_bytes(&df).unwrap();
+        assert_eq!(bytes, [header(1, 0), column("x", &[8], &[0], &[])].concat());
+        assert!(from_canonical_bytes(&bytes)
+            .unwrap()
+            .frame_equal_missing(&df));
+    }
+
+    #[test]
+    fn corrupt_input_is_rejected() {
+        let df = df! { "x" => [1i32, 2] }.unwrap();
+        let bytes = to_canonical_bytes(&df).unwrap();
+        assert!(from_canonical_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_canonical_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(from_canonical_bytes(b"IPC!").is_err());
+    }
+}
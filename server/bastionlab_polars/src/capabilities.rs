@@ -0,0 +1,361 @@
+//! The plan operations this binary supports.
+//!
+//! Slim builds compile some polars features out. Plans using them would otherwise fail to
+//! deserialize with obscure errors, or worse, have the unsupported options silently dropped: they
+//! are detected on the serialized plan and rejected before running.
+//!
+//! Polars indexes rows with u32 unless the `bigidx` cargo feature is enabled: dataframes of more
+//! than [`max_rows`] rows are rejected rather than silently mis-indexed.
+
+use polars::prelude::IdxSize;
+use serde_json::Value;
+use tonic::Status;
+
+use crate::polars_proto::ServerCapabilities;
+
+/// Composite plan segments, all of which are always supported.
+pub const SEGMENTS: &[&str] = &[
+    "PolarsPlanSegment",
+    "UdfPlanSegment",
+    "EntryPointPlanSegment",
+    "FamilyEntryPointSegment",
+    "StackPlanSegment",
+    "RowCountSegment",
+    "TemporalPlanSegment",
+    "OutputSegment",
+    "SlotEntryPointSegment",
+    "SplitSegment",
+    "LabelEncodeSegment",
+    "LiteralFramePlanSegment",
+    "JoinPlanSegment",
+    "GroupByPlanSegment",
+    "FilterPlanSegment",
+    "SortPlanSegment",
+    "SelectPlanSegment",
+    "SamplePlanSegment",
+    "WithColumnPlanSegment",
+    "HeadPlanSegment",
+    "SlicePlanSegment",
+    "VStackPlanSegment",
+];
+
+/// Dataframe formats accepted on upload (IPC file or stream, or canonical columns) and available
+/// on fetch (IPC file or stream, or canonical), see `DataFormat`.
+pub const FORMATS: &[&str] = &["ipc", "ipc_stream", "canonical", "canonical_columns"];
+
+/// A plan operation that depends on an optional cargo feature of this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    GroupbyDynamic,
+    GroupbyRolling,
+    AsofJoin,
+    CrossJoin,
+    SemiJoin,
+    AntiJoin,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::GroupbyDynamic,
+        Capability::GroupbyRolling,
+        Capability::AsofJoin,
+        Capability::CrossJoin,
+        Capability::SemiJoin,
+        Capability::AntiJoin,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::GroupbyDynamic => "groupby_dynamic",
+            Capability::GroupbyRolling => "groupby_rolling",
+            Capability::AsofJoin => "asof_join",
+            Capability::CrossJoin => "cross_join",
+            Capability::SemiJoin => "semi_join",
+            Capability::AntiJoin => "anti_join",
+        }
+    }
+
+    /// The cargo feature providing the capability, named after the polars feature it enables.
+    pub fn cargo_feature(self) -> &'static str {
+        match self {
+            Capability::GroupbyDynamic | Capability::GroupbyRolling => "dynamic_groupby",
+            Capability::AsofJoin => "asof_join",
+            Capability::CrossJoin => "cross_join",
+            Capability::SemiJoin | Capability::AntiJoin => "semi_anti_join",
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Capability::GroupbyDynamic | Capability::GroupbyRolling => {
+                cfg!(feature = "dynamic_groupby")
+            }
+            Capability::AsofJoin => cfg!(feature = "asof_join"),
+            Capability::CrossJoin => cfg!(feature = "cross_join"),
+            Capability::SemiJoin | Capability::AntiJoin => cfg!(feature = "semi_anti_join"),
+        }
+    }
+}
+
+pub fn supported() -> Vec<Capability> {
+    Capability::ALL
+        .into_iter()
+        .filter(|c| c.is_supported())
+        .collect()
+}
+
+/// Whether this build indexes rows with u64, see the module documentation.
+pub fn big_index() -> bool {
+    cfg!(feature = "bigidx")
+}
+
+/// The largest number of rows of a dataframe this build can index.
+pub fn max_rows() -> u64 {
+    IdxSize::MAX as u64
+}
+
+/// Checks that a dataframe of `rows` rows can be indexed by this build.
+pub fn check_rows(rows: u64) -> Result<(), Status> {
+    if rows > max_rows() {
+        return Err(Status::failed_precondition(format!(
+            "{rows} rows exceed the {} rows this server can index, it must be built with the \
+             `bigidx` cargo feature",
+            max_rows()
+        )));
+    }
+    Ok(())
+}
+
+impl ServerCapabilities {
+    /// Whether the server supports the segment, format or operation `name`.
+    pub fn supports(&self, name: &str) -> bool {
+        self.segments.iter().any(|s| s == name)
+            || self.formats.iter().any(|f| f == name)
+            || self
+                .operations
+                .iter()
+                .any(|op| op.name == name && op.supported)
+    }
+}
+
+/// Capabilities used by the polars segments of a serialized composite plan.
+pub fn required(plan: &Value) -> Vec<Capability> {
+    let mut required = Vec::new();
+    visit(plan, &mut required);
+    required.dedup();
+    required
+}
+
+fn visit(value: &Value, required: &mut Vec<Capability>) {
+    match value {
+        Value::Object(map) => {
+            if let Some(options) = map.get("Join").and_then(|join| join.get("options")) {
+                match options.get("how") {
+                    Some(Value::String(how)) if how == "Cross" => {
+                        required.push(Capability::CrossJoin)
+                    }
+                    Some(Value::String(how)) if how == "Semi" => {
+                        required.push(Capability::SemiJoin)
+                    }
+                    Some(Value::String(how)) if how == "Anti" => {
+                        required.push(Capability::AntiJoin)
+                    }
+                    Some(Value::Object(how)) if how.contains_key("AsOf") => {
+                        required.push(Capability::AsofJoin)
+                    }
+                    _ => (),
+                }
+            }
+            if map.get("type") == Some(&Value::from("JoinPlanSegment"))
+                && map.get("how") == Some(&Value::from("Cross"))
+            {
+                required.push(Capability::CrossJoin);
+            }
+            if let Some(options) = map.get("Aggregate").and_then(|agg| agg.get("options")) {
+                if options.get("dynamic").is_some_and(|v| !v.is_null()) {
+                    required.push(Capability::GroupbyDynamic);
+                }
+                if options.get("rolling").is_some_and(|v| !v.is_null()) {
+                    required.push(Capability::GroupbyRolling);
+                }
+            }
+            for value in map.values() {
+                visit(value, required);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                visit(value, required);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Rejects plans using capabilities compiled out of this binary.
+pub fn check_plan(plan: &Value) -> Result<(), Status> {
+    let mut missing: Vec<_> = required(plan)
+        .into_iter()
+        .filter(|c| !c.is_supported())
+        .map(|c| format!("{} (cargo feature `{}`)", c.name(), c.cargo_feature()))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    if !missing.is_empty() {
+        return Err(Status::unimplemented(format!(
+            "This server was built without support for {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scan() -> Value {
+        json!({"DataFrameScan": {"df": {"columns": []}, "schema": {"inner": {}}}})
+    }
+
+    fn join(how: Value) -> Value {
+        json!({
+            "segments": [{
+                "type": "PolarsPlanSegment",
+                "plan": {"Join": {
+                    "input_left": scan(),
+                    "input_right": scan(),
+                    "left_on": [{"Column": "k"}],
+                    "right_on": [{"Column": "k"}],
+                    "options": {"how": how, "suffix": "_right"},
+                }},
+            }],
+        })
+    }
+
+    fn aggregate(dynamic: Value, rolling: Value) -> Value {
+        json!({"Aggregate": {
+            "input": scan(),
+            "keys": [],
+            "aggs": [{"Agg": {"Sum": {"Column": "k"}}}],
+            "options": {"dynamic": dynamic, "rolling": rolling, "slice": null},
+        }})
+    }
+
+    #[test]
+    fn plans_are_scanned_for_required_capabilities() {
+        assert_eq!(required(&join(json!("Inner"))), []);
+        assert_eq!(required(&join(json!("Cross"))), [Capability::CrossJoin]);
+        let segment = |how| json!({"segments": [{"type": "JoinPlanSegment", "how": how}]});
+        assert_eq!(required(&segment("Cross")), [Capability::CrossJoin]);
+        assert_eq!(required(&segment("Left")), []);
+        assert_eq!(required(&join(json!("Anti"))), [Capability::AntiJoin]);
+        assert_eq!(
+            required(&join(json!({"AsOf": {"strategy": "Backward"}}))),
+            [Capability::AsofJoin]
+        );
+
+        let options = json!({"index_column": "t", "closed_window": "Left"});
+        assert_eq!(required(&aggregate(json!(null), json!(null))), []);
+        assert_eq!(
+            required(&aggregate(options.clone(), json!(null))),
+            [Capability::GroupbyDynamic]
+        );
+        // Nested plans are scanned too.
+        let nested = json!({"Join": {
+            "input_left": aggregate(json!(null), options),
+            "input_right": scan(),
+            "options": {"how": "Left"},
+        }});
+        assert_eq!(required(&nested), [Capability::GroupbyRolling]);
+    }
+
+    /// Run with `--no-default-features` as well: the supported capabilities, and which plans are
+    /// rejected, follow the enabled features.
+    #[test]
+    fn capabilities_follow_the_enabled_features() {
+        let names: Vec<_> = supported().into_iter().map(|c| c.name()).collect();
+        assert_eq!(names.contains(&"asof_join"), cfg!(feature = "asof_join"));
+        assert_eq!(
+            names.contains(&"groupby_dynamic"),
+            cfg!(feature = "dynamic_groupby")
+        );
+        assert_eq!(
+            names.contains(&"semi_join") && names.contains(&"anti_join"),
+            cfg!(feature = "semi_anti_join")
+        );
+        assert_eq!(names.contains(&"cross_join"), cfg!(feature = "cross_join"));
+
+        let asof = check_plan(&join(json!({"AsOf": {}})));
+        if cfg!(feature = "asof_join") {
+            assert!(asof.is_ok());
+        } else {
+            let err = asof.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::Unimplemented);
+            assert!(err
+                .message()
+                .contains("asof_join (cargo feature `asof_join`)"));
+        }
+        assert!(check_plan(&join(json!("Inner"))).is_ok());
+    }
+
+    #[cfg(not(feature = "default"))]
+    #[test]
+    fn slim_builds_support_no_optional_capability() {
+        assert!(supported().is_empty());
+    }
+
+    #[cfg(all(
+        feature = "dynamic_groupby",
+        feature = "asof_join",
+        feature = "cross_join",
+        feature = "semi_anti_join"
+    ))]
+    #[test]
+    fn full_builds_support_every_capability() {
+        assert_eq!(supported(), Capability::ALL);
+    }
+
+    #[test]
+    fn row_limits_follow_the_index_width() {
+        assert!(check_rows(u32::MAX as u64).is_ok());
+        match check_rows(u32::MAX as u64 + 1) {
+            Ok(()) => assert!(big_index()),
+            Err(err) => {
+                assert!(!big_index());
+                assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+                assert!(err.message().contains("bigidx"), "{err:?}");
+            }
+        }
+    }
+
+    #[cfg(feature = "bigidx")]
+    #[test]
+    fn big_indexes_address_rows_past_u32() {
+        use polars::prelude::*;
+
+        let offset = u32::MAX as IdxSize + 1;
+        let df = df! { "x" => [1i64, 2, 3] }
+            .unwrap()
+            .with_row_count("row", Some(offset))
+            .unwrap();
+        let rows: Vec<_> = df
+            .column("row")
+            .unwrap()
+            .idx()
+            .unwrap()
+            .into_no_null_iter()
+            .collect();
+        assert_eq!(rows, [offset, offset + 1, offset + 2]);
+
+        // Plans compare such row numbers without truncating them.
+        let sliced = df
+            .lazy()
+            .filter(col("row").gt_eq(lit(offset + 1)))
+            .collect()
+            .unwrap();
+        assert_eq!(sliced.height(), 2);
+        assert_eq!(max_rows(), u64::MAX);
+    }
+}
@@ -0,0 +1,353 @@
+//! What dataframes are listed by, and the paginated listing itself.
+//!
+//! Dataframes are listed in a stable order, by creation time then identifier. Page tokens encode
+//! the position of the last listed dataframe in this order rather than an offset, so that:
+//! - dataframes inserted or deleted between two pages do not shift the others: a dataframe that
+//!   exists during the whole scan is listed exactly once,
+//! - tokens stay valid across restarts, as both parts of the key are persisted.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+/// Page size when the request does not set one.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Larger page sizes are lowered to this.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Creation times are in milliseconds since the Unix epoch.
+pub use bastionlab_common::replay::now_ms;
+
+/// Listing metadata of a dataframe.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Milliseconds since the Unix epoch, 0 for dataframes persisted before it was recorded.
+    #[serde(default)]
+    pub created_at: u64,
+    /// User who uploaded or produced the dataframe.
+    #[serde(default)]
+    pub owner: String,
+    /// Name given on upload, if any.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Milliseconds since the Unix epoch until which idle results are kept under memory pressure,
+    /// see [`crate::workspaces`].
+    #[serde(default)]
+    pub retained_until: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFrameKind {
+    Upload,
+    Result,
+    Synthetic,
+}
+
+impl DataFrameKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataFrameKind::Upload => "upload",
+            DataFrameKind::Result => "result",
+            DataFrameKind::Synthetic => "synthetic",
+        }
+    }
+}
+
+/// A dataframe, as seen by the listing.
+#[derive(Debug, Clone, Copy)]
+pub struct Listed<'a> {
+    pub identifier: &'a str,
+    pub entry: &'a CatalogEntry,
+    pub kind: DataFrameKind,
+    /// Estimated size in memory, in bytes.
+    pub size: u64,
+}
+
+impl Listed<'_> {
+    fn key(&self) -> (u64, &str) {
+        (self.entry.created_at, self.identifier)
+    }
+}
+
+/// Filters of a listing, all of which must match. Unset ones match everything.
+#[derive(Debug, Clone, Default)]
+pub struct ListingFilter {
+    pub owner: Option<String>,
+    pub tag: Option<String>,
+    pub name_prefix: Option<String>,
+    /// Milliseconds since the Unix epoch, excluded.
+    pub created_after: Option<u64>,
+    pub kind: Option<DataFrameKind>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Identifiers the listing is restricted to, e.g. the members of a workspace.
+    pub within: Option<HashSet<String>>,
+}
+
+impl ListingFilter {
+    pub fn matches(&self, item: &Listed) -> bool {
+        self.owner.as_ref().map_or(true, |o| &item.entry.owner == o)
+            && self
+                .tag
+                .as_ref()
+                .map_or(true, |t| item.entry.tags.contains(t))
+            && self
+                .name_prefix
+                .as_ref()
+                .map_or(true, |p| item.entry.name.starts_with(p.as_str()))
+            && self
+                .created_after
+                .map_or(true, |t| item.entry.created_at > t)
+            && self.kind.map_or(true, |k| item.kind == k)
+            && self.min_size.map_or(true, |s| item.size >= s)
+            && self.max_size.map_or(true, |s| item.size <= s)
+            && self
+                .within
+                .as_ref()
+                .map_or(true, |within| within.contains(item.identifier))
+    }
+}
+
+/// Position of the last dataframe of a page in the listing order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    created_at: u64,
+    identifier: String,
+}
+
+impl Cursor {
+    /// The opaque page token sent to clients.
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursors always serialize");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(token: &str) -> Result<Self, Status> {
+        base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| Status::invalid_argument("Invalid page token"))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Page {
+    pub identifiers: Vec<String>,
+    /// Set if more dataframes match after this page.
+    pub next: Option<Cursor>,
+    /// Number of dataframes matching the filters, over all pages.
+    pub total: u64,
+}
+
+/// Lists the page of at most `page_size` dataframes of `items` matching `filter` that follows
+/// `after`, or the first page.
+///
+/// `page_size` 0 means [`DEFAULT_PAGE_SIZE`], and is capped to [`MAX_PAGE_SIZE`].
+pub fn page<'a>(
+    items: impl IntoIterator<Item = Listed<'a>>,
+    filter: &ListingFilter,
+    after: Option<&Cursor>,
+    page_size: usize,
+) -> Page {
+    let page_size = match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    };
+    let after = after.map(|c| (c.created_at, c.identifier.as_str()));
+
+    let mut total = 0;
+    let mut rest = Vec::new();
+    for item in items {
+        if !filter.matches(&item) {
+            continue;
+        }
+        total += 1;
+        if after.map_or(true, |after| item.key() > after) {
+            rest.push(item.key());
+        }
+    }
+    let more = rest.len() > page_size;
+    if more {
+        rest.select_nth_unstable(page_size);
+        rest.truncate(page_size);
+    }
+    rest.sort_unstable();
+
+    Page {
+        next: match rest.last() {
+            Some(&(created_at, identifier)) if more => Some(Cursor {
+                created_at,
+                identifier: identifier.to_string(),
+            }),
+            _ => None,
+        },
+        identifiers: rest.into_iter().map(|(_, id)| id.to_string()).collect(),
+        total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Default)]
+    struct Registry(BTreeMap<String, (CatalogEntry, DataFrameKind, u64)>);
+
+    impl Registry {
+        /// Inserts a dataframe with a random identifier, which may sort before older ones.
+        fn insert_random(&mut self, rng: &mut StdRng, created_at: u64) {
+            let identifier = format!("{:016x}", rng.gen::<u64>());
+            self.insert(identifier, created_at);
+        }
+
+        fn insert(&mut self, identifier: String, created_at: u64) {
+            let entry = CatalogEntry {
+                created_at,
+                owner: format!("user{}", created_at % 3),
+                name: format!("dataset-{identifier}"),
+                tags: vec![format!("tag{}", created_at % 2)],
+                ..Default::default()
+            };
+            let kind = [
+                DataFrameKind::Upload,
+                DataFrameKind::Result,
+                DataFrameKind::Synthetic,
+            ][created_at as usize % 3];
+            self.0.insert(identifier, (entry, kind, created_at * 10));
+        }
+
+        fn page(&self, filter: &ListingFilter, after: Option<&Cursor>, size: usize) -> Page {
+            let items = self
+                .0
+                .iter()
+                .map(|(identifier, (entry, kind, size))| Listed {
+                    identifier,
+                    entry,
+                    kind: *kind,
+                    size: *size,
+                });
+            page(items, filter, after, size)
+        }
+    }
+
+    #[test]
+    fn pages_are_stable_under_concurrent_changes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut registry = Registry::default();
+        // Few distinct timestamps, so that the identifier tiebreak matters.
+        for i in 0..5000 {
+            registry.insert_random(&mut rng, i / 50);
+        }
+
+        for filter in [
+            ListingFilter::default(),
+            ListingFilter {
+                tag: Some("tag1".into()),
+                kind: Some(DataFrameKind::Result),
+                ..Default::default()
+            },
+        ] {
+            let mut registry = registry.clone();
+            let initial: HashSet<String> = registry.0.keys().cloned().collect();
+            let mut deleted = HashSet::new();
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            let mut now = 100;
+            loop {
+                let page = registry.page(&filter, cursor.as_ref(), 37);
+                assert!(page.identifiers.len() <= 37);
+                seen.extend(page.identifiers);
+                cursor = match page.next {
+                    // Tokens are only kept as strings by clients.
+                    Some(next) => Some(Cursor::decode(&next.encode()).unwrap()),
+                    None => break,
+                };
+
+                // Inserts, some with the timestamp of the cursor, and deletions between pages.
+                for _ in 0..5 {
+                    registry.insert_random(&mut rng, now);
+                    registry.insert_random(&mut rng, cursor.as_ref().unwrap().created_at);
+                }
+                now += 1;
+                let victims: Vec<_> = registry.0.keys().cloned().choose_multiple(&mut rng, 10);
+                for victim in victims {
+                    registry.0.remove(&victim);
+                    deleted.insert(victim);
+                }
+            }
+
+            let unique: HashSet<_> = seen.iter().cloned().collect();
+            assert_eq!(unique.len(), seen.len(), "duplicates in the listing");
+            for identifier in initial.difference(&deleted) {
+                let (entry, kind, size) = &registry.0[identifier];
+                let listed = Listed {
+                    identifier,
+                    entry,
+                    kind: *kind,
+                    size: *size,
+                };
+                assert_eq!(
+                    unique.contains(identifier),
+                    filter.matches(&listed),
+                    "{identifier} listed wrongly"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn filters_and_counts() {
+        let mut registry = Registry::default();
+        for i in 0..30 {
+            registry.insert(format!("id{i:02}"), i);
+        }
+        let filter = ListingFilter {
+            owner: Some("user1".into()),
+            created_after: Some(10),
+            max_size: Some(250),
+            ..Default::default()
+        };
+        // 13, 16, 19, 22, 25
+        let page = registry.page(&filter, None, 2);
+        assert_eq!(page.identifiers, ["id13", "id16"]);
+        assert_eq!(page.total, 5);
+        let page = registry.page(&filter, page.next.as_ref(), 2);
+        assert_eq!(page.identifiers, ["id19", "id22"]);
+        let page = registry.page(&filter, page.next.as_ref(), 2);
+        assert_eq!(page.identifiers, ["id25"]);
+        assert!(page.next.is_none());
+
+        let prefix = ListingFilter {
+            name_prefix: Some("dataset-id2".into()),
+            min_size: Some(250),
+            ..Default::default()
+        };
+        assert_eq!(registry.page(&prefix, None, 0).identifiers.len(), 5);
+        assert_eq!(
+            registry
+                .page(&ListingFilter::default(), None, 0)
+                .identifiers
+                .len(),
+            30
+        );
+        assert!(Cursor::decode("not a token").is_err());
+    }
+
+    #[test]
+    fn page_sizes_are_capped() {
+        let mut registry = Registry::default();
+        for i in 0..(MAX_PAGE_SIZE as u64 + 5) {
+            registry.insert(format!("{i:08}"), i);
+        }
+        let page = registry.page(&ListingFilter::default(), None, usize::MAX);
+        assert_eq!(page.identifiers.len(), MAX_PAGE_SIZE);
+        assert!(page.next.is_some());
+        let page = registry.page(&ListingFilter::default(), None, 0);
+        assert_eq!(page.identifiers.len(), DEFAULT_PAGE_SIZE);
+    }
+}
@@ -0,0 +1,360 @@
+//! Checkpoints of the plans run by checkpointed jobs, see [`crate::plan_jobs`].
+//!
+//! At every segment boundary, the dataframes on the stack and in the output slots of the plan
+//! are written as IPC files to a new directory of the checkpoint, in the spill area. A manifest
+//! listing them, along with the rest of the state of the run, then replaces the previous one
+//! atomically, and the files of the previous boundary are removed: a crash leaves one boundary or
+//! the other, never a mix of both.
+//!
+//! A checkpoint is only resumed if the dataframes the plan read before it are still at the
+//! versions it read, see [`Restored::changed_input`].
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use bastionlab_common::atomic_file;
+use bastionlab_common::replay::now_ms;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::prelude::*;
+use crate::reproducibility::Provenance;
+use crate::resources::ResourceCaps;
+
+/// Directory of the checkpoints of all jobs, in the spill area.
+pub const CHECKPOINTS_DIR: &str = "bastionlab-checkpoints";
+
+const MANIFEST: &str = "manifest.json";
+
+/// A dataframe of the stack or of an output slot, without its data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedFrame {
+    /// Aggregation size and join scaling by dataframe read, see
+    /// [`crate::composite_plan::StatsEntry`].
+    pub stats: Vec<(String, u64, u64)>,
+    pub literal: bool,
+}
+
+/// What a run has computed at a segment boundary, besides its dataframes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunState {
+    pub stack: Vec<SavedFrame>,
+    pub slots: Vec<(String, SavedFrame)>,
+    pub blacklist: HashMap<String, String>,
+    /// Columns computed from blacklisted columns.
+    #[serde(default)]
+    pub derived_blacklist: Vec<String>,
+    pub trace: Vec<String>,
+    pub warnings: Vec<String>,
+    pub resource_caps: Option<ResourceCaps>,
+    pub provenance: Provenance,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// Segments completed.
+    completed: usize,
+    /// In milliseconds since the Unix epoch.
+    saved_at: u64,
+    /// Directory of the dataframe files, in the checkpoint directory.
+    files: String,
+    state: RunState,
+}
+
+/// A checkpoint read back.
+#[derive(Debug)]
+pub struct Restored {
+    pub completed: usize,
+    pub saved_at: u64,
+    pub state: RunState,
+    /// The dataframes of the stack, then those of the slots.
+    pub frames: Vec<DataFrame>,
+    files: String,
+}
+
+impl Restored {
+    /// Why the checkpoint cannot be resumed, if a dataframe it read changed since, given the
+    /// current versions of the dataframes.
+    pub fn changed_input(&self, version_of: impl Fn(&str) -> Option<u64>) -> Option<String> {
+        self.state
+            .provenance
+            .inputs
+            .iter()
+            .find_map(|input| match version_of(&input.identifier) {
+                Some(version) if version == input.version => None,
+                Some(version) => Some(format!(
+                    "{} changed from version {} to {version}",
+                    input.identifier, input.version
+                )),
+                None => Some(format!("{} was deleted", input.identifier)),
+            })
+    }
+}
+
+/// Shared between a running job and the registry of jobs.
+#[derive(Debug, Default)]
+pub struct JobControl {
+    /// Segments completed by the current run.
+    completed: AtomicUsize,
+    /// Stop at the next boundary, once it is saved.
+    stopping: AtomicBool,
+    /// Stop at the next boundary without saving it, as a crash would.
+    halted: AtomicBool,
+    /// Whether the run stopped at a boundary rather than completed or failed.
+    stopped: AtomicBool,
+}
+
+impl JobControl {
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn halt(&self) {
+        self.halted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// Saves the segment boundaries of a run, and stops it at one when asked to.
+#[derive(Debug)]
+pub struct Checkpointer {
+    dir: PathBuf,
+    control: Arc<JobControl>,
+    /// Waited before each segment, injected by chaos tests, see [`crate::faults`].
+    delay: Duration,
+    /// Directory of the files of the last boundary saved.
+    files: Option<String>,
+    resumed: Option<Restored>,
+}
+
+impl Checkpointer {
+    /// Checkpoints to `dir`, resuming from `resumed`, read from the same directory, if given.
+    pub fn new(dir: PathBuf, control: Arc<JobControl>, resumed: Option<Restored>) -> Self {
+        Checkpointer {
+            dir,
+            control,
+            delay: Duration::ZERO,
+            files: resumed.as_ref().map(|resumed| resumed.files.clone()),
+            resumed,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// The checkpoint the run resumes from, taken once.
+    pub fn take_resumed(&mut self) -> Option<Restored> {
+        let resumed = self.resumed.take()?;
+        self.control
+            .completed
+            .store(resumed.completed, Ordering::Relaxed);
+        Some(resumed)
+    }
+
+    /// Called before every segment.
+    pub fn before_segment(&self) {
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+    }
+
+    /// Saves the boundary after `completed` segments, `frames` being those of the stack then
+    /// those of the slots of `state`. Fails with `Aborted` when the run must stop there.
+    pub fn boundary(
+        &mut self,
+        completed: usize,
+        state: RunState,
+        frames: Vec<DataFrame>,
+    ) -> Result<(), Status> {
+        if self.control.halted.load(Ordering::Relaxed) {
+            self.control.stopped.store(true, Ordering::Relaxed);
+            return Err(Status::aborted(format!("Halted after segment {completed}")));
+        }
+        self.save(completed, state, frames)?;
+        self.control.completed.store(completed, Ordering::Relaxed);
+        if self.control.stopping.load(Ordering::Relaxed) {
+            self.control.stopped.store(true, Ordering::Relaxed);
+            return Err(Status::aborted(format!(
+                "Stopped after segment {completed} for a shutdown, the job resumes on restart"
+            )));
+        }
+        Ok(())
+    }
+
+    fn save(
+        &mut self,
+        completed: usize,
+        state: RunState,
+        frames: Vec<DataFrame>,
+    ) -> Result<(), Status> {
+        let io_err = |e: std::io::Error| {
+            Status::internal(format!("Could not checkpoint segment {completed}: {e}"))
+        };
+        let files = format!("{completed}-{}", uuid::Uuid::new_v4());
+        let files_dir = self.dir.join(&files);
+        fs::create_dir_all(&files_dir).map_err(io_err)?;
+        for (index, mut df) in frames.into_iter().enumerate() {
+            let file = File::create(files_dir.join(format!("{index}.arrow"))).map_err(io_err)?;
+            IpcWriter::new(file).finish(&mut df).map_err(|e| {
+                Status::internal(format!("Could not checkpoint segment {completed}: {e}"))
+            })?;
+        }
+        let manifest = Manifest {
+            completed,
+            saved_at: now_ms(),
+            files: files.clone(),
+            state,
+        };
+        let buf = serde_json::to_vec(&manifest).map_err(|e| {
+            Status::internal(format!("Could not checkpoint segment {completed}: {e}"))
+        })?;
+        atomic_file::write(&self.dir.join(MANIFEST), &buf).map_err(io_err)?;
+        if let Some(previous) = self.files.replace(files) {
+            fs::remove_dir_all(self.dir.join(previous)).unwrap_or(());
+        }
+        Ok(())
+    }
+}
+
+/// Reads the checkpoint of `dir`, `None` if none was saved.
+pub fn load(dir: &Path) -> Result<Option<Restored>, Status> {
+    let err = |e: &dyn std::fmt::Display| {
+        Status::data_loss(format!(
+            "Could not read the checkpoint in {}: {e}",
+            dir.display()
+        ))
+    };
+    let buf = match fs::read(dir.join(MANIFEST)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(err(&e)),
+    };
+    let manifest: Manifest = serde_json::from_slice(&buf).map_err(|e| err(&e))?;
+    let count = manifest.state.stack.len() + manifest.state.slots.len();
+    let frames = (0..count)
+        .map(|index| {
+            let path = dir.join(&manifest.files).join(format!("{index}.arrow"));
+            let file = File::open(path).map_err(|e| err(&e))?;
+            IpcReader::new(file).finish().map_err(|e| err(&e))
+        })
+        .collect::<Result<Vec<_>, Status>>()?;
+    Ok(Some(Restored {
+        completed: manifest.completed,
+        saved_at: manifest.saved_at,
+        state: manifest.state,
+        frames,
+        files: manifest.files,
+    }))
+}
+
+/// Removes the checkpoint of `dir`, if any.
+pub fn remove(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Could not remove the checkpoint in {}: {e}", dir.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reproducibility::InputVersion;
+
+    fn state(inputs: &[(&str, u64)]) -> RunState {
+        RunState {
+            stack: vec![SavedFrame {
+                stats: vec![(String::from("a"), 1, 2)],
+                literal: false,
+            }],
+            slots: vec![(
+                String::from("test"),
+                SavedFrame {
+                    stats: Vec::new(),
+                    literal: true,
+                },
+            )],
+            blacklist: HashMap::new(),
+            derived_blacklist: Vec::new(),
+            trace: vec![String::from("segment 0")],
+            warnings: Vec::new(),
+            resource_caps: None,
+            provenance: Provenance {
+                plan: serde_json::Value::Null,
+                inputs: inputs
+                    .iter()
+                    .map(|&(identifier, version)| InputVersion {
+                        identifier: identifier.to_string(),
+                        version,
+                        policy_hash: String::new(),
+                    })
+                    .collect(),
+                slot: String::new(),
+                input_stats: Vec::new(),
+                fingerprints: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn boundaries_replace_each_other_and_check_their_inputs() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", uuid::Uuid::new_v4()));
+        let control = Arc::new(JobControl::default());
+        assert!(load(&dir).unwrap().is_none());
+
+        let mut checkpointer = Checkpointer::new(dir.clone(), control.clone(), None);
+        let first = df! { "x" => [1i64, 2, 3] }.unwrap();
+        let second = df! { "y" => ["a", "b"] }.unwrap();
+        checkpointer
+            .boundary(1, state(&[("a", 1)]), vec![first.clone(), second.clone()])
+            .unwrap();
+        checkpointer
+            .boundary(2, state(&[("a", 1)]), vec![second.clone(), first.clone()])
+            .unwrap();
+        assert_eq!(control.completed(), 2);
+        // Only the files of the last boundary are kept, next to the manifest.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let restored = load(&dir).unwrap().unwrap();
+        assert_eq!(restored.completed, 2);
+        assert_eq!(restored.state.stack, state(&[]).stack);
+        assert!(restored.frames[0].frame_equal(&second));
+        assert!(restored.frames[1].frame_equal(&first));
+        assert_eq!(restored.changed_input(|_| Some(1)), None);
+        assert_eq!(
+            restored.changed_input(|_| Some(2)).unwrap(),
+            "a changed from version 1 to 2"
+        );
+        assert_eq!(restored.changed_input(|_| None).unwrap(), "a was deleted");
+
+        // Stopping saves the boundary first, halting does not.
+        let mut checkpointer = Checkpointer::new(dir.clone(), control.clone(), Some(restored));
+        control.stop();
+        let err = checkpointer
+            .boundary(3, state(&[]), vec![first.clone(), second.clone()])
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Aborted);
+        assert!(control.stopped());
+        assert_eq!(load(&dir).unwrap().unwrap().completed, 3);
+        control.halt();
+        assert!(checkpointer
+            .boundary(4, state(&[]), vec![first, second])
+            .is_err());
+        assert_eq!(load(&dir).unwrap().unwrap().completed, 3);
+
+        remove(&dir);
+        assert!(!dir.exists());
+    }
+}
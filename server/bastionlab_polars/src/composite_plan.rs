@@ -10,67 +10,809 @@ use tch::CModule;
 use tonic::Status;
 
 use crate::{
-    access_control::{Context, Policy, VerificationResult},
+    access_control::Policy,
+    activity::RecentActivity,
+    aggregations::{self, Aggregation},
+    capabilities,
+    catalog::{self, CatalogEntry},
+    checkpoints::{Checkpointer, RunState, SavedFrame},
+    computed,
+    differential_privacy::{self, DifferentialPrivacy, NoisyColumn},
+    families::PartitionPredicate,
+    federation::RemoteSource,
+    filters::{self, FilterExpr},
+    joins::{self, JoinKind},
+    lifecycle::Onboarding,
+    literal_frames::{self, LiteralColumn},
+    masking::{merge_masks, ColumnMasks},
+    nan,
+    outputs::{self, Slots, MAIN_SLOT},
+    plan_format::{self, PLAN_FORMAT_VERSION},
+    policy_engine::{Action, EvaluationContext, Subject, Verdict},
+    prelude::*,
+    projections::{self, DtypeGroup, SelectedColumn},
+    purpose::merge_require_purpose,
+    reproducibility::{InputStats, Provenance},
+    reserved::{strip_internal_columns, GROUP_ROW, JOIN_LEFT_ROW, JOIN_RIGHT_ROW},
+    resources::{
+        mb, merge_resource_caps, over_limit, segment_inputs, segment_kind, ResourceHints, SpillDir,
+    },
+    running::QueryControl,
+    sampling,
+    semantics::{self, legacy_semantics, CURRENT_SEMANTICS},
+    slices,
+    sorts::{self, SortKey},
+    stacks,
+    storage_classes::StorageState,
+    temporal::{self, TemporalColumn},
+    versions,
     visitable::{Visitable, VisitableMut},
-    BastionLabPolars, DataFrameArtifact,
+    BastionLabPolars, DataFrameArtifact, QualityMonitor,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CompositePlan {
     segments: Vec<CompositePlanSegment>,
+    /// Semantics version the plan was written for, see [`crate::semantics`].
+    #[serde(default = "legacy_semantics")]
+    semantics_version: u32,
+    /// Whether NaN is treated as null, see [`crate::nan`].
+    #[serde(default)]
+    nan_as_null: bool,
+    /// Format the plan is written in, see [`crate::plan_format`].
+    #[serde(default = "plan_format::default_format_version")]
+    format_version: u32,
+    /// Results of the sub-plans run remotely, by the index of their entry point, see
+    /// [`crate::federation`].
+    #[serde(skip)]
+    remote_inputs: HashMap<usize, DataFrame>,
+    /// Saves the segment boundaries of checkpointed jobs, see [`crate::checkpoints`].
+    #[serde(skip)]
+    checkpointer: Option<Checkpointer>,
+    /// Follows the run and stops it when killed, see [`crate::running`].
+    #[serde(skip)]
+    control: Option<Arc<QueryControl>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum CompositePlanSegment {
-    PolarsPlanSegment { plan: LogicalPlan },
-    UdfPlanSegment { columns: Vec<String>, udf: String },
-    EntryPointPlanSegment { identifier: String },
+    PolarsPlanSegment {
+        plan: LogicalPlan,
+        /// Whether the aggregations of the plan skip NaN, see [`crate::nan`].
+        #[serde(default)]
+        skip_nan: bool,
+        /// Memory limit and spilling of the plan, see [`crate::resources`].
+        #[serde(default)]
+        resources: Option<ResourceHints>,
+    },
+    UdfPlanSegment {
+        columns: Vec<String>,
+        udf: String,
+    },
+    EntryPointPlanSegment {
+        identifier: String,
+    },
+    /// Reads the members of a dataset family whose partition matches `predicate`.
+    FamilyEntryPointSegment {
+        family: String,
+        predicate: PartitionPredicate,
+    },
     StackPlanSegment,
-    RowCountSegment { row: String },
+    RowCountSegment {
+        row: String,
+    },
+    /// Adds columns computed by temporal expressions, see [`crate::temporal`].
+    TemporalPlanSegment {
+        columns: Vec<TemporalColumn>,
+    },
+    /// Stores its input in output slot `slot`, see [`crate::outputs`].
+    OutputSegment {
+        slot: String,
+    },
+    /// Reads the output a previous segment stored in slot `slot`.
+    SlotEntryPointSegment {
+        slot: String,
+    },
+    /// Stores the first `train_size` share of the rows of its input in the first of `slots`, and
+    /// the rest in the second one. Rows are shuffled with `seed` first, if given.
+    SplitSegment {
+        train_size: f64,
+        #[serde(default)]
+        seed: Option<u64>,
+        #[serde(default = "outputs::default_split_slots")]
+        slots: [String; 2],
+    },
+    /// Replaces the values of `column` by codes, and stores the mapping from values to codes in
+    /// slot `mapping`.
+    LabelEncodeSegment {
+        column: String,
+        #[serde(default = "outputs::default_mapping_slot")]
+        mapping: String,
+    },
+    /// Pushes the small dataframe written inline in `columns`, see [`crate::literal_frames`].
+    LiteralFramePlanSegment {
+        columns: Vec<LiteralColumn>,
+    },
+    /// Joins the two dataframes on top of the stack, the one pushed first on the left, see
+    /// [`crate::joins`].
+    JoinPlanSegment {
+        #[serde(default)]
+        left_on: Vec<String>,
+        #[serde(default)]
+        right_on: Vec<String>,
+        how: JoinKind,
+    },
+    /// Groups the dataframe on top of the stack by the `by` columns and aggregates others within
+    /// each group, see [`crate::aggregations`].
+    GroupByPlanSegment {
+        by: Vec<String>,
+        aggs: Vec<Aggregation>,
+    },
+    /// Keeps the rows of the dataframe on top of the stack `predicate` holds for, see
+    /// [`crate::filters`].
+    FilterPlanSegment {
+        predicate: FilterExpr,
+    },
+    /// Sorts the dataframe on top of the stack by `by`, keeping rows of equal keys in order if
+    /// `stable`, see [`crate::sorts`].
+    SortPlanSegment {
+        by: Vec<SortKey>,
+        #[serde(default)]
+        stable: bool,
+    },
+    /// Keeps the `columns` of the dataframe on top of the stack, then those of the `dtypes`, see
+    /// [`crate::projections`].
+    SelectPlanSegment {
+        #[serde(default)]
+        columns: Vec<SelectedColumn>,
+        #[serde(default)]
+        dtypes: Vec<DtypeGroup>,
+    },
+    /// Replaces the dataframe on top of the stack by a random sample of its rows, a `fraction` of
+    /// them or `n`, see [`crate::sampling`].
+    SamplePlanSegment {
+        #[serde(default)]
+        fraction: Option<f64>,
+        #[serde(default)]
+        n: Option<u64>,
+        #[serde(default)]
+        with_replacement: bool,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    /// Adds column `name` computed by `expr` to the dataframe on top of the stack, replacing a
+    /// column of that name if `replace`, see [`crate::computed`].
+    WithColumnPlanSegment {
+        name: String,
+        expr: FilterExpr,
+        #[serde(default)]
+        replace: bool,
+    },
+    /// Keeps the first `n` rows of the dataframe on top of the stack, see [`crate::slices`].
+    HeadPlanSegment {
+        n: u64,
+    },
+    /// Keeps the `length` rows of the dataframe on top of the stack from `offset`, counted from
+    /// the end if negative, see [`crate::slices`].
+    SlicePlanSegment {
+        #[serde(default)]
+        offset: i64,
+        length: u64,
+    },
+    /// Reads the dataframes `inputs` and stacks their rows in order, casting their columns to
+    /// common dtypes if `relaxed`, see [`crate::stacks`].
+    VStackPlanSegment {
+        inputs: Vec<String>,
+        #[serde(default)]
+        relaxed: bool,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct StatsEntry {
-    pub agg_size: usize,
-    pub join_scaling: usize,
+    pub agg_size: u64,
+    pub join_scaling: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct DataFrameStats(HashMap<String, StatsEntry>);
 
+#[derive(Clone)]
 struct StackFrame {
     df: DataFrame,
     stats: DataFrameStats,
+    /// Plan reading `df` back from disk, if it was spilled.
+    spilled: Option<LogicalPlan>,
+    /// Whether `df` derives from a literal frame, see [`crate::literal_frames`].
+    literal: bool,
+}
+
+impl StackFrame {
+    fn new(df: DataFrame, stats: DataFrameStats) -> Self {
+        StackFrame {
+            df,
+            stats,
+            spilled: None,
+            literal: false,
+        }
+    }
+
+    fn with_literal(mut self, literal: bool) -> Self {
+        self.literal = literal;
+        self
+    }
+
+    fn saved(&self) -> SavedFrame {
+        SavedFrame {
+            stats: self
+                .stats
+                .0
+                .iter()
+                .map(|(identifier, entry)| (identifier.clone(), entry.agg_size, entry.join_scaling))
+                .collect(),
+            literal: self.literal,
+        }
+    }
+
+    fn restore(saved: SavedFrame, df: DataFrame) -> Self {
+        let stats = saved
+            .stats
+            .into_iter()
+            .map(|(identifier, agg_size, join_scaling)| {
+                let entry = StatsEntry {
+                    agg_size,
+                    join_scaling,
+                };
+                (identifier, entry)
+            })
+            .collect();
+        StackFrame::new(df, DataFrameStats(stats)).with_literal(saved.literal)
+    }
 }
 
 impl CompositePlan {
+    pub fn new(segments: Vec<CompositePlanSegment>) -> Self {
+        CompositePlan {
+            segments,
+            semantics_version: CURRENT_SEMANTICS,
+            nan_as_null: false,
+            format_version: PLAN_FORMAT_VERSION,
+            remote_inputs: HashMap::new(),
+            checkpointer: None,
+            control: None,
+        }
+    }
+
+    pub fn with_nan_as_null(mut self, nan_as_null: bool) -> Self {
+        self.nan_as_null = nan_as_null;
+        self
+    }
+
+    pub fn segments(&self) -> &[CompositePlanSegment] {
+        &self.segments
+    }
+
+    pub fn semantics_version(&self) -> u32 {
+        self.semantics_version
+    }
+
+    pub fn nan_as_null(&self) -> bool {
+        self.nan_as_null
+    }
+
+    /// The dataframe read and the polars plan run on it, with its `skip_nan` flag, if the plan is
+    /// made of these two segments only, see [`crate::views`].
+    pub fn single_plan(&self) -> Option<(&str, &LogicalPlan, bool)> {
+        let [entry, polars] = &self.segments[..] else {
+            return None;
+        };
+        match (entry, polars) {
+            (
+                CompositePlanSegment::EntryPointPlanSegment { identifier },
+                CompositePlanSegment::PolarsPlanSegment { plan, skip_nan, .. },
+            ) => Some((identifier, plan, *skip_nan)),
+            _ => None,
+        }
+    }
+
+    /// Identifiers of the dataframes this plan reads from, without their version selectors,
+    /// holiday dataframes of temporal segments included.
+    pub fn entry_points(&self) -> Vec<String> {
+        self.segments
+            .iter()
+            .flat_map(|seg| match seg {
+                CompositePlanSegment::EntryPointPlanSegment { identifier } => {
+                    vec![versions::base(identifier).to_string()]
+                }
+                CompositePlanSegment::VStackPlanSegment { inputs, .. } => inputs
+                    .iter()
+                    .map(|identifier| versions::base(identifier).to_string())
+                    .collect(),
+                CompositePlanSegment::TemporalPlanSegment { columns } => {
+                    temporal::holiday_frames(columns)
+                }
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Replaces the identifier of every entry point with the one `resolve` returns for it.
+    pub fn resolve_entry_points(
+        &mut self,
+        mut resolve: impl FnMut(&str) -> Result<String, Status>,
+    ) -> Result<(), Status> {
+        for seg in self.segments.iter_mut() {
+            match seg {
+                CompositePlanSegment::EntryPointPlanSegment { identifier } => {
+                    *identifier = resolve(identifier)?;
+                }
+                CompositePlanSegment::VStackPlanSegment { inputs, .. } => {
+                    for identifier in inputs.iter_mut() {
+                        *identifier = resolve(identifier)?;
+                    }
+                }
+                CompositePlanSegment::TemporalPlanSegment { columns } => {
+                    temporal::resolve_holidays(columns, &mut resolve)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the sub-plans reading the federated dataframes `remote` returns a source for out of
+    /// this plan: each entry point, with the polars segment right after it if that one reads it
+    /// only. Returns the index of the entry points, the dataframes and the sub-plans, to be run on
+    /// the remote servers and given back with [`CompositePlan::set_remote_input`].
+    pub fn split_remote(
+        &mut self,
+        remote: impl Fn(&str) -> Option<RemoteSource>,
+    ) -> Result<Vec<(usize, String, RemoteSource, CompositePlan)>, Status> {
+        let mut split = Vec::new();
+        let mut segments = Vec::with_capacity(self.segments.len());
+        let mut rest = std::mem::take(&mut self.segments).into_iter().peekable();
+        while let Some(seg) = rest.next() {
+            let (identifier, source) = match &seg {
+                CompositePlanSegment::EntryPointPlanSegment { identifier } => {
+                    match remote(versions::base(identifier)) {
+                        Some(source) => (identifier.clone(), source),
+                        None => {
+                            segments.push(seg);
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    segments.push(seg);
+                    continue;
+                }
+            };
+            let mut sub_plan = vec![CompositePlanSegment::EntryPointPlanSegment {
+                identifier: source.identifier.clone(),
+            }];
+            let reads_it_only = match rest.peek() {
+                Some(CompositePlanSegment::PolarsPlanSegment { plan, .. }) => {
+                    segment_inputs(plan)? == 1
+                }
+                _ => false,
+            };
+            if reads_it_only {
+                sub_plan.extend(rest.next());
+            }
+            split.push((
+                segments.len(),
+                identifier,
+                source,
+                CompositePlan {
+                    segments: sub_plan,
+                    semantics_version: self.semantics_version,
+                    nan_as_null: self.nan_as_null,
+                    format_version: self.format_version,
+                    remote_inputs: HashMap::new(),
+                    checkpointer: None,
+                    control: None,
+                },
+            ));
+            segments.push(seg);
+        }
+        self.segments = segments;
+        Ok(split)
+    }
+
+    /// Reads `df` instead of the dataframe of the entry point at `index`.
+    pub fn set_remote_input(&mut self, index: usize, df: DataFrame) {
+        self.remote_inputs.insert(index, df);
+    }
+
+    /// Saves the segment boundaries of the run with `checkpointer`, resuming from its checkpoint
+    /// if it has one, see [`crate::checkpoints`].
+    pub fn set_checkpointer(&mut self, checkpointer: Checkpointer) {
+        self.checkpointer = Some(checkpointer);
+    }
+
+    /// Reports the progress of the run to `control`, and stops it at the next segment boundary
+    /// once killed, see [`crate::running`].
+    pub fn set_control(&mut self, control: Arc<QueryControl>) {
+        self.control = Some(control);
+    }
+
+    /// Checks the values of the literal frames of this plan against their dtypes, and their size
+    /// against `max_cells`, before anything runs.
+    pub fn check_literal_frames(&self, max_cells: usize) -> Result<(), Status> {
+        for (index, seg) in self.segments.iter().enumerate() {
+            if let CompositePlanSegment::LiteralFramePlanSegment { columns } = seg {
+                literal_frames::build(&format!("segment {index}"), columns, max_cells)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the slices of this plan keep at most `max_rows` rows.
+    pub fn check_slices(&self, max_rows: usize) -> Result<(), Status> {
+        for (index, seg) in self.segments.iter().enumerate() {
+            let length = match seg {
+                CompositePlanSegment::HeadPlanSegment { n } => *n,
+                CompositePlanSegment::SlicePlanSegment { length, .. } => *length,
+                _ => continue,
+            };
+            slices::check(&format!("segment {index}"), length, max_rows)?;
+        }
+        Ok(())
+    }
+
+    /// Dataset families this plan reads from, with their partition predicates.
+    pub fn family_entry_points(&self) -> Vec<(&str, &PartitionPredicate)> {
+        self.segments
+            .iter()
+            .filter_map(|seg| match seg {
+                CompositePlanSegment::FamilyEntryPointSegment { family, predicate } => {
+                    Some((family.as_str(), predicate))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Runs this plan, returning its [`MAIN_SLOT`] output.
     pub fn run(self, state: &BastionLabPolars, user_id: &str) -> Result<DataFrameArtifact, Status> {
+        let outputs = self.run_outputs(state, user_id)?;
+        let slots = outputs
+            .iter()
+            .map(|(slot, _)| slot.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        outputs
+            .into_iter()
+            .find(|(slot, _)| slot == MAIN_SLOT)
+            .map(|(_, artifact)| artifact)
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "This plan leaves no dataframe on the stack, its outputs are in slots: {slots}"
+                ))
+            })
+    }
+
+    /// Runs this plan, returning its outputs by slot, the [`MAIN_SLOT`] one first if any, see
+    /// [`crate::outputs`].
+    pub fn run_outputs(
+        mut self,
+        state: &BastionLabPolars,
+        user_id: &str,
+    ) -> Result<Vec<(String, DataFrameArtifact)>, Status> {
         let mut stack = Vec::new();
+        let mut slots = Slots::default();
         let plan_str = serde_json::to_string(&self.segments).map_err(|e| {
             Status::invalid_argument(format!("Could not parse composite plan: {e}"))
         })?;
         let mut blacklist_hashmap = HashMap::new();
+        // Columns computed from blacklisted columns, see [`crate::computed`].
+        let mut derived_blacklist: Vec<String> = Vec::new();
+        // The aggregates of the last segment, if noised, see [`crate::differential_privacy`].
+        let mut noisy_aggregates = None;
+        let mut trace = Vec::new();
+        let mut warnings = Vec::new();
+        let shims = semantics::shims(self.semantics_version)?;
+        let nan_as_null = self.nan_as_null;
+        // Caps of the policies of the dataframes read so far.
+        let mut resource_caps = None;
+        let mut spill = SpillDir::new(&state.resources);
+        let mut provenance = Provenance {
+            plan: serde_json::to_value(&self).map_err(|e| {
+                Status::internal(format!("Could not serialize composite plan: {e}"))
+            })?,
+            inputs: Vec::new(),
+            slot: MAIN_SLOT.to_string(),
+            input_stats: Vec::new(),
+            fingerprints: HashMap::new(),
+        };
+
+        // Views are computed from local data only.
+        let mut remote_inputs = std::mem::take(&mut self.remote_inputs);
+        let mut checkpointer = self.checkpointer.take();
+        if checkpointer.is_some() && !remote_inputs.is_empty() {
+            return Err(Status::failed_precondition(
+                "Checkpointed jobs cannot read remote dataframes: their versions could not be \
+                 checked on resume",
+            ));
+        }
+        let resumed = checkpointer.as_mut().and_then(Checkpointer::take_resumed);
+        let view = if remote_inputs.is_empty() && resumed.is_none() {
+            state.answer_from_view(&self)?
+        } else {
+            None
+        };
+        let segments = match view {
+            Some(answer) => {
+                info!("{answer}");
+                trace.push(answer.to_string());
+                // The version the view was computed from, which lags behind the base when stale.
+                state.with_df_artifact_ref(&answer.base, |artifact| {
+                    provenance.read(&answer.base, answer.base_version, &artifact.policy)
+                })??;
+                record_aliases(&answer.plan, &mut blacklist_hashmap);
+                let mut stats = DataFrameStats::new(answer.base);
+                if let Some(agg_size) = answer.agg_size {
+                    stats.update_agg_size(agg_size);
+                }
+                stack.push(StackFrame::new(answer.dataframe, stats));
+                Vec::new()
+            }
+            None => self.segments,
+        };
+        let segment_count = segments.len();
+        let mut resume_from = 0;
+        if let Some(resumed) = resumed {
+            resume_from = resumed.completed;
+            let saved = resumed.state;
+            let mut frames = resumed.frames.into_iter();
+            for (frame, df) in saved.stack.into_iter().zip(frames.by_ref()) {
+                stack.push(StackFrame::restore(frame, df));
+            }
+            for ((slot, frame), df) in saved.slots.into_iter().zip(frames) {
+                slots.store(&slot, StackFrame::restore(frame, df))?;
+            }
+            blacklist_hashmap = saved.blacklist;
+            derived_blacklist = saved.derived_blacklist;
+            trace = saved.trace;
+            trace.push(format!("Resumed after segment {}", resume_from - 1));
+            warnings = saved.warnings;
+            resource_caps = saved.resource_caps;
+            provenance = saved.provenance;
+        }
 
-        for seg in self.segments {
+        for (index, seg) in segments.into_iter().enumerate().skip(resume_from) {
+            if let Some(control) = &self.control {
+                control.enter(index, segment_count)?;
+            }
+            if let Some(checkpointer) = &checkpointer {
+                checkpointer.before_segment();
+            }
+            noisy_aggregates = None;
+            // Heads are slices from the first row.
+            let seg = match seg {
+                CompositePlanSegment::HeadPlanSegment { n } => {
+                    CompositePlanSegment::SlicePlanSegment {
+                        offset: 0,
+                        length: n,
+                    }
+                }
+                seg => seg,
+            };
+            // Joins, aggregations, filters, sorts, selections and slices run as the polars segment
+            // they stand for.
+            let seg = match seg {
+                CompositePlanSegment::JoinPlanSegment {
+                    left_on,
+                    right_on,
+                    how,
+                } => {
+                    let plan = match &stack[..] {
+                        [.., left, right] => {
+                            joins::plan(&left.df, &right.df, &left_on, &right_on, how)?
+                        }
+                        _ => {
+                            return Err(Status::invalid_argument(
+                                "Could not join: fewer than two input data frames",
+                            ))
+                        }
+                    };
+                    // Polars plans read their left input from the top of the stack.
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::GroupByPlanSegment { by, aggs } => {
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not group: no input data frame")
+                    })?;
+                    let blacklisted =
+                        blacklisted_columns(state, input, &blacklist_hashmap, &derived_blacklist)?;
+                    let plan = match differential_privacy_of(state, input)? {
+                        Some(privacy) => {
+                            let (plan, columns) = differential_privacy::plan(
+                                &input.df,
+                                &by,
+                                &aggs,
+                                &blacklisted,
+                                &privacy,
+                            )?;
+                            noisy_aggregates = Some(columns);
+                            plan
+                        }
+                        None => aggregations::plan(&input.df, &by, &aggs, &blacklisted)?,
+                    };
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::FilterPlanSegment { predicate } => {
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not filter: no input data frame")
+                    })?;
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan: filters::plan(&input.df, &predicate)?,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::SortPlanSegment { by, stable } => {
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not sort: no input data frame")
+                    })?;
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan: sorts::plan(&input.df, &by, stable)?,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::SelectPlanSegment { columns, dtypes } => {
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not select: no input data frame")
+                    })?;
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan: projections::plan(&input.df, &columns, &dtypes)?,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::WithColumnPlanSegment {
+                    name,
+                    expr,
+                    replace,
+                } => {
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not compute a column: no input data frame")
+                    })?;
+                    let plan = computed::plan(&input.df, &name, &expr, replace)?;
+                    let blacklisted =
+                        blacklisted_columns(state, input, &blacklist_hashmap, &derived_blacklist)?;
+                    // Or `protected + 0` would launder a blacklisted column.
+                    let reads_blacklisted = expr
+                        .columns()
+                        .iter()
+                        .any(|column| blacklisted.iter().any(|b| b == column));
+                    if reads_blacklisted && !derived_blacklist.contains(&name) {
+                        derived_blacklist.push(name);
+                    }
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                CompositePlanSegment::SlicePlanSegment { offset, length } => {
+                    slices::check(&format!("segment {index}"), length, state.slice_max_rows)?;
+                    let input = stack.last().ok_or_else(|| {
+                        Status::invalid_argument("Could not slice: no input data frame")
+                    })?;
+                    CompositePlanSegment::PolarsPlanSegment {
+                        plan: slices::plan(&input.df, offset, length)?,
+                        skip_nan: false,
+                        resources: None,
+                    }
+                }
+                seg => seg,
+            };
             match seg {
-                CompositePlanSegment::PolarsPlanSegment { mut plan } => {
+                CompositePlanSegment::PolarsPlanSegment {
+                    mut plan,
+                    skip_nan,
+                    resources,
+                } => {
+                    semantics::apply(&mut plan, shims)?;
+                    if skip_nan {
+                        nan::skip_nan(&mut plan)?;
+                    }
+                    if nan_as_null {
+                        nan::normalize_plan(&mut plan)?;
+                    }
+
+                    let segment = format!("segment {index} ({})", segment_kind(&plan)?);
+                    let (limits, notes) = state
+                        .resources
+                        .limits(resources.unwrap_or_default(), resource_caps);
+                    for note in notes {
+                        trace.push(format!("{segment}: {note}"));
+                        warnings.push(note);
+                    }
+                    let consumed = segment_inputs(&plan)?.min(stack.len());
+                    let inputs_start = stack.len() - consumed;
+                    let input_size: u64 = stack[inputs_start..]
+                        .iter()
+                        .map(|frame| frame.df.estimated_size() as u64)
+                        .sum();
+                    let spilled_before = spill.written();
+                    if limits.max_memory.is_some_and(|limit| input_size > limit) {
+                        if !limits.allow_spill {
+                            return Err(over_limit(
+                                &segment,
+                                &format!("{} of inputs", mb(input_size)),
+                                limits.max_memory.unwrap(),
+                            ));
+                        }
+                        for frame in stack[inputs_start..].iter_mut() {
+                            frame.spilled = Some(spill.spill(&segment, &mut frame.df)?);
+                            frame.df = DataFrame::default();
+                        }
+                    }
+
+                    let literal = stack[inputs_start..].iter().any(|frame| frame.literal);
+                    if literal {
+                        let forbidden = stack[inputs_start..]
+                            .iter()
+                            .flat_map(|frame| frame.stats.0.keys())
+                            .map(|identifier| {
+                                state.with_df_artifact_ref(identifier, |artifact| {
+                                    let keys = artifact.policy.literal_join_keys().to_vec();
+                                    (identifier.clone(), keys)
+                                })
+                            })
+                            .collect::<Result<Vec<_>, Status>>()?;
+                        literal_frames::check_joins(&plan, &forbidden)?;
+                    }
+
                     let stats = initialize_plan(&mut plan, &mut stack)?;
+                    for warning in nan::float_join_keys(&plan)? {
+                        warn!("{warning}");
+                        trace.push(warning.clone());
+                        warnings.push(warning);
+                    }
                     let df = run_logical_plan(plan.clone())?;
+                    record_aliases(&plan, &mut blacklist_hashmap);
 
-                    let polars_plan_str = format!("{:?}", plan);
-                    let re =
-                        Regex::new(r#"col\("(?P<original>[^)]+)"\).alias\("(?P<alias>[^)]+)"\)"#)
-                            .unwrap();
-                    let matches = re.captures_iter(&polars_plan_str);
-                    for captures in matches {
-                        blacklist_hashmap.insert(
-                            captures["original"].to_string(),
-                            captures["alias"].to_string(),
-                        );
+                    let output_size = df.estimated_size() as u64;
+                    let spilled = spill.written() - spilled_before;
+                    let usage = if spilled > 0 {
+                        format!(
+                            "{} of inputs, {} of them spilled to disk, {} of output",
+                            mb(input_size),
+                            mb(spilled),
+                            mb(output_size)
+                        )
+                    } else {
+                        format!(
+                            "{} of inputs, {} of output",
+                            mb(input_size),
+                            mb(output_size)
+                        )
+                    };
+                    info!("{segment}: {usage}");
+                    trace.push(format!("{segment}: {usage}"));
+                    // Outputs cannot be spilled with this polars version, see [`crate::resources`].
+                    if let Some(limit) = limits.max_memory.filter(|_| spilled == 0) {
+                        if input_size + output_size > limit {
+                            return Err(over_limit(&segment, &usage, limit));
+                        }
                     }
 
-                    stack.push(StackFrame { df, stats });
+                    stack.push(StackFrame::new(df, stats).with_literal(literal));
                 }
                 CompositePlanSegment::UdfPlanSegment { columns, udf } => {
                     let module =
@@ -109,12 +851,101 @@ impl CompositePlan {
                         })?;
                         *series = tensor_to_series(series.name(), series.dtype(), tensor)?;
                     }
+                    if nan_as_null {
+                        frame.df = nan::normalize_dataframe(frame.df)?;
+                    }
                     stack.push(frame);
                 }
                 CompositePlanSegment::EntryPointPlanSegment { identifier } => {
-                    let df = state.get_df_unchecked(&identifier)?;
-                    let stats = DataFrameStats::new(identifier);
-                    stack.push(StackFrame { df, stats });
+                    let (identifier, selector) = state.split_version(&identifier)?;
+                    let (mut df, version) = match remote_inputs.remove(&index) {
+                        Some(df) => {
+                            let version = state
+                                .with_df_artifact_ref(identifier, |artifact| artifact.version)?;
+                            (df, version)
+                        }
+                        None => state.get_df_version(identifier, selector)?,
+                    };
+                    resource_caps = merge_resource_caps(
+                        resource_caps,
+                        state.with_df_artifact_ref(identifier, |artifact| {
+                            provenance.read(identifier, version, &artifact.policy)?;
+                            Ok::<_, Status>(artifact.policy.resource_caps())
+                        })??,
+                    );
+                    if nan_as_null {
+                        df = nan::normalize_dataframe(df)?;
+                    }
+                    let stats = DataFrameStats::new(identifier.to_string());
+                    stack.push(StackFrame::new(df, stats));
+                }
+                CompositePlanSegment::FamilyEntryPointSegment { family, predicate } => {
+                    let scan = state.families.prune(&family, &predicate)?;
+                    let mut stats = DataFrameStats(HashMap::new());
+                    let mut members = Vec::with_capacity(scan.scanned.len());
+                    for (identifier, _) in scan.scanned.iter() {
+                        resource_caps = merge_resource_caps(
+                            resource_caps,
+                            state.with_df_artifact_ref(identifier, |artifact| {
+                                provenance.read(identifier, artifact.version, &artifact.policy)?;
+                                Ok::<_, Status>(artifact.policy.resource_caps())
+                            })??,
+                        );
+                        members.push(state.get_df_unchecked(identifier)?.lazy());
+                        stats.merge(DataFrameStats::new(identifier.clone()));
+                    }
+                    let df = if members.is_empty() {
+                        DataFrame::new_no_checks(
+                            scan.schema
+                                .iter()
+                                .map(|(name, dtype)| Series::new_empty(name, dtype))
+                                .collect(),
+                        )
+                    } else {
+                        concat(&members, false, true)
+                            .and_then(|ldf| ldf.collect())
+                            .map_err(|e| {
+                                Status::internal(format!(
+                                    "Could not concatenate the members of family {family}: {e}"
+                                ))
+                            })?
+                    };
+                    let df = if nan_as_null {
+                        nan::normalize_dataframe(df)?
+                    } else {
+                        df
+                    };
+                    info!("{scan}");
+                    trace.push(scan.to_string());
+                    stack.push(StackFrame::new(df, stats));
+                }
+                CompositePlanSegment::VStackPlanSegment { inputs, relaxed } => {
+                    let mut stats = DataFrameStats(HashMap::new());
+                    let mut dfs = Vec::with_capacity(inputs.len());
+                    for input in inputs {
+                        let (identifier, selector) = state.split_version(&input)?;
+                        // Federated rows are only read by the entry points sent to their server.
+                        if state.with_df_artifact_ref(identifier, |a| a.remote.is_some())? {
+                            return Err(Status::invalid_argument(format!(
+                                "Could not stack `{input}`: its rows are held by another server"
+                            )));
+                        }
+                        let (df, version) = state.get_df_version(identifier, selector)?;
+                        resource_caps = merge_resource_caps(
+                            resource_caps,
+                            state.with_df_artifact_ref(identifier, |artifact| {
+                                provenance.read(identifier, version, &artifact.policy)?;
+                                Ok::<_, Status>(artifact.policy.resource_caps())
+                            })??,
+                        );
+                        stats.merge(DataFrameStats::new(identifier.to_string()));
+                        dfs.push((input, df));
+                    }
+                    let mut df = stacks::stack(dfs, relaxed)?;
+                    if nan_as_null {
+                        df = nan::normalize_dataframe(df)?;
+                    }
+                    stack.push(StackFrame::new(df, stats));
                 }
                 CompositePlanSegment::StackPlanSegment => {
                     let frame1 = stack.pop().ok_or_else(|| {
@@ -128,9 +959,10 @@ impl CompositePlan {
                     let df = frame1.df.vstack(&frame2.df).map_err(|e| {
                         Status::invalid_argument(format!("Error while running vstack: {}", e))
                     })?;
+                    let literal = frame1.literal || frame2.literal;
                     let mut stats = frame1.stats;
                     stats.merge(frame2.stats);
-                    stack.push(StackFrame { df, stats });
+                    stack.push(StackFrame::new(df, stats).with_literal(literal));
                 }
                 CompositePlanSegment::RowCountSegment { row: name } => {
                     let frame = stack.pop().ok_or(Status::invalid_argument(
@@ -143,53 +975,347 @@ impl CompositePlan {
                         ))
                     })?;
                     let stats = frame.stats;
-                    stack.push(StackFrame { df, stats });
+                    stack.push(StackFrame::new(df, stats).with_literal(frame.literal));
+                }
+                CompositePlanSegment::TemporalPlanSegment { columns } => {
+                    let mut frame = stack.pop().ok_or_else(|| {
+                        Status::invalid_argument(
+                            "Could not apply temporal expressions: no input data frame",
+                        )
+                    })?;
+                    // Holiday dataframes are inputs of the query: their policies apply too.
+                    let mut holidays = HashMap::new();
+                    for identifier in temporal::holiday_frames(&columns) {
+                        resource_caps = merge_resource_caps(
+                            resource_caps,
+                            state.with_df_artifact_ref(&identifier, |artifact| {
+                                provenance.read(&identifier, artifact.version, &artifact.policy)?;
+                                Ok::<_, Status>(artifact.policy.resource_caps())
+                            })??,
+                        );
+                        holidays.insert(identifier.clone(), state.get_df_unchecked(&identifier)?);
+                        frame.stats.merge(DataFrameStats::new(identifier));
+                    }
+                    frame.df = temporal::apply(frame.df, &columns, &holidays)?;
+                    stack.push(frame);
+                }
+                CompositePlanSegment::OutputSegment { slot } => {
+                    let frame = stack.pop().ok_or_else(|| {
+                        Status::invalid_argument(format!(
+                            "Could not store output slot `{slot}`: no input data frame"
+                        ))
+                    })?;
+                    slots.store(&slot, frame)?;
+                }
+                CompositePlanSegment::SlotEntryPointSegment { slot } => {
+                    stack.push(slots.get(&slot)?.clone());
+                }
+                CompositePlanSegment::SplitSegment {
+                    train_size,
+                    seed,
+                    slots: [train_slot, test_slot],
+                } => {
+                    let frame = stack.pop().ok_or_else(|| {
+                        Status::invalid_argument("Could not apply split: no input data frame")
+                    })?;
+                    let (train, test) = outputs::split(frame.df, train_size, seed)?;
+                    let train = StackFrame::new(train, frame.stats.clone());
+                    slots.store(&train_slot, train.with_literal(frame.literal))?;
+                    let test = StackFrame::new(test, frame.stats);
+                    slots.store(&test_slot, test.with_literal(frame.literal))?;
+                }
+                CompositePlanSegment::SamplePlanSegment {
+                    fraction,
+                    n,
+                    with_replacement,
+                    seed,
+                } => {
+                    let frame = stack.pop().ok_or_else(|| {
+                        Status::invalid_argument("Could not sample: no input data frame")
+                    })?;
+                    let df = sampling::sample(&frame.df, fraction, n, with_replacement, seed)?;
+                    trace.push(format!(
+                        "segment {index}: sampled {} of {} rows",
+                        df.height(),
+                        frame.df.height()
+                    ));
+                    // Sampled rows are not aggregates: the aggregation sizes of the input apply.
+                    stack.push(StackFrame::new(df, frame.stats).with_literal(frame.literal));
+                }
+                CompositePlanSegment::LabelEncodeSegment { column, mapping } => {
+                    let frame = stack.pop().ok_or_else(|| {
+                        Status::invalid_argument("Could not label encode: no input data frame")
+                    })?;
+                    let (encoded, values) = outputs::label_encode(frame.df, &column)?;
+                    // The mapping holds the values of the column: it derives from the same inputs.
+                    let values = StackFrame::new(values, frame.stats.clone());
+                    slots.store(&mapping, values.with_literal(frame.literal))?;
+                    stack.push(StackFrame::new(encoded, frame.stats).with_literal(frame.literal));
+                }
+                CompositePlanSegment::LiteralFramePlanSegment { columns } => {
+                    let segment = format!("segment {index}");
+                    let df =
+                        literal_frames::build(&segment, &columns, state.literal_frame_max_cells)?;
+                    trace.push(format!(
+                        "{segment}: literal frame of {} rows and {} columns",
+                        df.height(),
+                        df.width()
+                    ));
+                    // Literal frames derive from no stored dataframe: no policy of theirs applies.
+                    let stats = DataFrameStats(HashMap::new());
+                    stack.push(StackFrame::new(df, stats).with_literal(true));
                 }
+                CompositePlanSegment::JoinPlanSegment { .. }
+                | CompositePlanSegment::GroupByPlanSegment { .. }
+                | CompositePlanSegment::FilterPlanSegment { .. }
+                | CompositePlanSegment::SortPlanSegment { .. }
+                | CompositePlanSegment::SelectPlanSegment { .. }
+                | CompositePlanSegment::WithColumnPlanSegment { .. }
+                | CompositePlanSegment::HeadPlanSegment { .. }
+                | CompositePlanSegment::SlicePlanSegment { .. } => unreachable!(),
+            }
+            if let Some(checkpointer) = checkpointer.as_mut().filter(|_| index + 1 < segment_count)
+            {
+                let run_state = RunState {
+                    stack: stack.iter().map(StackFrame::saved).collect(),
+                    slots: slots
+                        .iter()
+                        .map(|(slot, frame)| (slot.clone(), frame.saved()))
+                        .collect(),
+                    blacklist: blacklist_hashmap.clone(),
+                    derived_blacklist: derived_blacklist.clone(),
+                    trace: trace.clone(),
+                    warnings: warnings.clone(),
+                    resource_caps,
+                    provenance: provenance.clone(),
+                };
+                let frames = stack
+                    .iter()
+                    .chain(slots.iter().map(|(_, frame)| frame))
+                    .map(|frame| frame.df.clone())
+                    .collect();
+                checkpointer.boundary(index + 1, run_state, frames)?;
             }
         }
 
-        if stack.len() != 1 {
-            return Err(Status::invalid_argument(
-                "Wrong number of input data frames",
-            ));
+        let mut frames = Vec::new();
+        match stack.len() {
+            1 => frames.push((MAIN_SLOT.to_string(), stack.pop().unwrap())),
+            0 if !slots.is_empty() => (),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Wrong number of input data frames",
+                ))
+            }
         }
+        frames.extend(slots.into_vec());
 
-        let StackFrame { df, stats } = stack.pop().unwrap();
+        let record = RunRecord {
+            plan_str,
+            trace,
+            warnings,
+            blacklist_hashmap,
+            derived_blacklist,
+            provenance,
+            semantics_version: self.semantics_version,
+            noisy_aggregates,
+        };
+        frames
+            .into_iter()
+            .map(|(slot, frame)| {
+                let artifact = record.output(state, user_id, &slot, frame)?;
+                Ok((slot, artifact))
+            })
+            .collect()
+    }
+}
 
-        let mut policy = Policy::allow_by_default();
-        let mut blacklist = Vec::new();
-        let mut fetchable = VerificationResult::Safe;
-
-        for (identifier, stats) in stats.0.into_iter() {
-            state.with_df_artifact_ref(&identifier, |artifact| -> Result<(), Status> {
-                let check = artifact.policy.verify(&Context {
-                    stats,
-                    user_id: String::from(user_id),
-                    df_identifier: identifier.clone(),
+/// What every output of a run shares, see [`RunRecord::output`].
+struct RunRecord {
+    plan_str: String,
+    trace: Vec<String>,
+    warnings: Vec<String>,
+    blacklist_hashmap: HashMap<String, String>,
+    derived_blacklist: Vec<String>,
+    provenance: Provenance,
+    semantics_version: u32,
+    /// The aggregates of the main output, if the plan ends with a noised group-by.
+    noisy_aggregates: Option<Vec<NoisyColumn>>,
+}
+
+impl RunRecord {
+    /// The dataframe stored for output `slot`, whose policy and lineage derive from the inputs it
+    /// was computed from only.
+    fn output(
+        &self,
+        state: &BastionLabPolars,
+        user_id: &str,
+        slot: &str,
+        frame: StackFrame,
+    ) -> Result<DataFrameArtifact, Status> {
+        let StackFrame { mut df, stats, .. } = frame;
+        // Joins can multiply rows past what this build indexes.
+        capabilities::check_rows(df.height() as u64)?;
+        let mut noisy_aggregates = match (slot, &self.noisy_aggregates) {
+            (MAIN_SLOT, Some(columns)) => Some(columns.clone()),
+            _ => None,
+        };
+        if let Some(columns) = noisy_aggregates.as_mut() {
+            differential_privacy::take_counts(&mut df, columns)?;
+        }
+        strip_internal_columns(&mut df);
+        let mut trace = self.trace.clone();
+        if slot != MAIN_SLOT {
+            trace.push(format!("Output slot {slot}"));
+        }
+        let mut provenance = self.provenance.clone();
+        provenance
+            .inputs
+            .retain(|input| stats.0.contains_key(&input.identifier));
+        provenance.slot = slot.to_string();
+        provenance.input_stats = stats
+            .0
+            .iter()
+            .map(|(identifier, stats)| InputStats {
+                identifier: identifier.clone(),
+                agg_size: stats.agg_size,
+                join_scaling: stats.join_scaling,
+            })
+            .collect();
+
+        let dfs = state.dataframes.read().unwrap();
+        let inputs = stats
+            .0
+            .into_iter()
+            .map(|(identifier, stats)| {
+                let artifact = dfs.get(&identifier).ok_or_else(|| {
+                    Status::not_found(format!(
+                        "Could not find dataframe: identifier={}",
+                        identifier
+                    ))
                 })?;
+                Ok((identifier, artifact, stats))
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        // Whether the result can be fetched, and what must be done to it, see
+        // [`crate::policy_engine`].
+        let decision = state.policy_engine.evaluate(
+            Action::Derive,
+            user_id,
+            &inputs
+                .iter()
+                .map(|(identifier, artifact, stats)| {
+                    let subject = Subject::input(identifier, artifact, *stats);
+                    if noisy_aggregates.is_some() {
+                        subject.noisable()
+                    } else {
+                        subject
+                    }
+                })
+                .collect::<Vec<_>>(),
+            &EvaluationContext::default(),
+        )?;
+
+        let mut policy = Policy::allow_by_default();
+        let mut blacklist = decision.sanitized_columns().to_vec();
+        let mut require_purpose = None;
+        let mut exact_columns: Vec<String> = Vec::new();
+        let mut column_masks = ColumnMasks::new();
+        // Results of unpublished dataframes are drafts of the user too.
+        let mut onboarding = Onboarding::default();
 
-                if let VerificationResult::Unsafe { .. } = check {
-                    policy = policy.merge(&artifact.policy);
+        for (identifier, artifact, _) in inputs.iter() {
+            if !artifact.onboarding.is_published() {
+                onboarding = Onboarding::draft();
+            }
+            // Noised results are only allowed by the differential privacy rule of the policy.
+            if decision.verdict_of(identifier) != Some(&Verdict::Allow)
+                || decision.noised(identifier)
+            {
+                policy = policy.merge(&artifact.policy);
+            }
+            // Purpose requirements apply to every result, whether the query is safe or not.
+            require_purpose =
+                merge_require_purpose(require_purpose.as_ref(), artifact.policy.require_purpose());
+            // So do exact columns, which later watermarks must spare too.
+            for column in artifact.policy.exact_columns() {
+                if !exact_columns.contains(column) {
+                    exact_columns.push(column.clone());
                 }
-                fetchable.merge(check);
+            }
+            // And masks, to the columns of the result of the same name.
+            column_masks = merge_masks(&column_masks, artifact.policy.column_masks());
 
-                for (key, val) in blacklist_hashmap.iter() {
-                    if artifact.blacklist[..].contains(&key.to_string()) {
-                        blacklist.push(val.to_string());
-                    }
+            for (key, val) in self.blacklist_hashmap.iter() {
+                if artifact.blacklist[..].contains(&key.to_string()) {
+                    blacklist.push(val.to_string());
                 }
-                blacklist.extend_from_slice(&artifact.blacklist[..]);
+            }
+        }
+        drop(dfs);
+        for column in self.derived_blacklist.iter() {
+            if let Some(alias) = self.blacklist_hashmap.get(column) {
+                blacklist.push(alias.clone());
+            }
+            blacklist.push(column.clone());
+        }
 
-                Ok(())
-            })??;
+        // The cap is applied to the final result, after any sort of the plan.
+        let max_output_rows = decision.row_cap();
+        let (df, capped_output) = match max_output_rows {
+            Some(cap) => cap.apply(df),
+            None => (df, None),
+        };
+        if let Some(capped) = &capped_output {
+            info!(
+                "Row cap of the data owner's policy applied: {}",
+                capped.message()
+            );
+            trace.push(capped.message());
         }
+        let noise = decision.noise().map(|noise| {
+            let mut noise = noise.clone();
+            noise.columns = noisy_aggregates.unwrap_or_default();
+            noise.truncate(df.height());
+            noise
+        });
+        let watermark = decision.watermark().map(|(watermark, _)| watermark.clone());
+        let policy = policy
+            .with_max_output_rows(max_output_rows)
+            .with_require_purpose(require_purpose)
+            .with_watermark(watermark, exact_columns)
+            .with_column_masks(column_masks);
 
         Ok(DataFrameArtifact {
             dataframe: df,
-            fetchable,
+            fetchable: decision.verdict().to_fetchable(),
             policy,
             blacklist,
-            query_details: plan_str,
+            query_details: trace
+                .into_iter()
+                .fold(self.plan_str.clone(), |details, line| {
+                    format!("{details}\n{line}")
+                }),
+            dtype_changes: Vec::new(),
+            quality: QualityMonitor::default(),
+            version: 0,
+            semantics_version: self.semantics_version,
+            capped_output,
+            warnings: self.warnings.clone(),
+            synthetic: None,
+            catalog: CatalogEntry::default(),
+            onboarding,
+            provenance: Some(provenance),
+            purpose: None,
+            remote: None,
+            activity: RecentActivity::default(),
+            storage: StorageState::default(),
+            policy_history: Vec::new(),
+            statistics: Default::default(),
+            defaults: None,
+            history: versions::VersionHistory::new(catalog::now_ms()),
+            fingerprints: Default::default(),
+            noise,
         })
     }
 }
@@ -239,7 +1365,7 @@ fn expr_agg_check(expr: &Expr) -> Result<bool, Status> {
     Ok(state.pop().unwrap())
 }
 
-fn exprs_agg_check(exprs: &[Expr]) -> Result<bool, Status> {
+pub(crate) fn exprs_agg_check(exprs: &[Expr]) -> Result<bool, Status> {
     for e in exprs.iter() {
         let x = expr_agg_check(e)?;
         if !x {
@@ -249,6 +1375,66 @@ fn exprs_agg_check(exprs: &[Expr]) -> Result<bool, Status> {
     Ok(true)
 }
 
+/// The columns of `frame` blacklisted by the dataframes it derives from, or computed from
+/// blacklisted columns, under their alias too.
+fn blacklisted_columns(
+    state: &BastionLabPolars,
+    frame: &StackFrame,
+    aliases: &HashMap<String, String>,
+    derived: &[String],
+) -> Result<Vec<String>, Status> {
+    let mut blacklisted = Vec::new();
+    for column in derived {
+        if let Some(alias) = aliases.get(column) {
+            blacklisted.push(alias.clone());
+        }
+        blacklisted.push(column.clone());
+    }
+    for identifier in frame.stats.0.keys() {
+        let blacklist =
+            state.with_df_artifact_ref(identifier, |artifact| artifact.blacklist.clone())?;
+        for column in blacklist {
+            if let Some(alias) = aliases.get(&column) {
+                blacklisted.push(alias.clone());
+            }
+            blacklisted.push(column);
+        }
+    }
+    Ok(blacklisted)
+}
+
+/// The differential privacy rule of the dataframes `frame` derives from, the stricter of all.
+fn differential_privacy_of(
+    state: &BastionLabPolars,
+    frame: &StackFrame,
+) -> Result<Option<DifferentialPrivacy>, Status> {
+    let mut merged: Option<DifferentialPrivacy> = None;
+    for identifier in frame.stats.0.keys() {
+        let privacy = state.with_df_artifact_ref(identifier, |artifact| {
+            artifact.policy.differential_privacy().cloned()
+        })?;
+        if let Some(privacy) = privacy {
+            merged = Some(match merged {
+                Some(merged) => merged.merge(&privacy),
+                None => privacy,
+            });
+        }
+    }
+    Ok(merged)
+}
+
+/// Records the columns `plan` renames, so that blacklisted columns stay so under their alias.
+fn record_aliases(plan: &LogicalPlan, aliases: &mut HashMap<String, String>) {
+    let polars_plan_str = format!("{:?}", plan);
+    let re = Regex::new(r#"col\("(?P<original>[^)]+)"\).alias\("(?P<alias>[^)]+)"\)"#).unwrap();
+    for captures in re.captures_iter(&polars_plan_str) {
+        aliases.insert(
+            captures["original"].to_string(),
+            captures["alias"].to_string(),
+        );
+    }
+}
+
 fn run_logical_plan(plan: LogicalPlan) -> Result<DataFrame, Status> {
     let ldf = lazy_frame_from_logical_plan(plan);
     ldf.collect()
@@ -268,15 +1454,16 @@ impl DataFrameStats {
         DataFrameStats(stats)
     }
 
-    fn update_agg_size(&mut self, agg_size: usize) {
+    // Sizes saturate: `u64::MAX` stands for aggregations of every row.
+    fn update_agg_size(&mut self, agg_size: u64) {
         for stats in self.0.values_mut() {
-            stats.agg_size *= agg_size;
+            stats.agg_size = stats.agg_size.saturating_mul(agg_size);
         }
     }
 
-    fn update_join_scaling(&mut self, join_scaling: usize) {
+    fn update_join_scaling(&mut self, join_scaling: u64) {
         for stats in self.0.values_mut() {
-            stats.join_scaling *= join_scaling;
+            stats.join_scaling = stats.join_scaling.saturating_mul(join_scaling);
         }
     }
 
@@ -310,7 +1497,10 @@ fn initialize_plan(
                     )
                 })?;
                 stats_stack.push(frame.stats);
-                *plan = frame.df.lazy().logical_plan;
+                *plan = match frame.spilled {
+                    Some(spilled) => spilled,
+                    None => frame.df.lazy().logical_plan,
+                };
             }
             LogicalPlan::Join {
                 input_left,
@@ -327,34 +1517,35 @@ fn initialize_plan(
                 let mut left = stats_stack.pop().unwrap();
 
                 match options.how {
+                    #[cfg(feature = "semi_anti_join")]
                     JoinType::Anti | JoinType::Semi => right.update_join_scaling(0),
                     _ => {
                         let joined_ids = left_ldf
                             .cache()
-                            .with_row_count("__left_count", None)
+                            .with_row_count(JOIN_LEFT_ROW, None)
                             .join(
-                                right_ldf.cache().with_row_count("__right_count", None),
+                                right_ldf.cache().with_row_count(JOIN_RIGHT_ROW, None),
                                 left_on,
                                 right_on,
                                 options.how.clone(),
                             )
-                            .select([col("__left_count"), col("__right_count")])
+                            .select([col(JOIN_LEFT_ROW), col(JOIN_RIGHT_ROW)])
                             .cache();
 
-                        let left_join_scaling = usize_item(
+                        let left_join_scaling = u64_item(
                             joined_ids
                                 .clone()
-                                .groupby([col("__left_count")])
-                                .agg([col("__right_count").count()])
-                                .select([col("__right_count").max()])
+                                .groupby([col(JOIN_LEFT_ROW)])
+                                .agg([col(JOIN_RIGHT_ROW).count()])
+                                .select([col(JOIN_RIGHT_ROW).max()])
                                 .collect(),
                         )?;
 
-                        let right_join_scaling = usize_item(
+                        let right_join_scaling = u64_item(
                             joined_ids
-                                .groupby([col("__right_count")])
-                                .agg([col("__left_count").count()])
-                                .select([col("__left_count").max()])
+                                .groupby([col(JOIN_RIGHT_ROW)])
+                                .agg([col(JOIN_LEFT_ROW).count()])
+                                .select([col(JOIN_LEFT_ROW).max()])
                                 .collect(),
                         )?;
 
@@ -371,12 +1562,12 @@ fn initialize_plan(
             // LogicalPlan::Union { .. } => *state = false,
             LogicalPlan::Projection { expr, .. } => {
                 if exprs_agg_check(expr)? {
-                    stats_stack.last_mut().unwrap().update_agg_size(usize::MAX);
+                    stats_stack.last_mut().unwrap().update_agg_size(u64::MAX);
                 }
             }
             LogicalPlan::LocalProjection { expr, .. } => {
                 if exprs_agg_check(expr)? {
-                    stats_stack.last_mut().unwrap().update_agg_size(usize::MAX);
+                    stats_stack.last_mut().unwrap().update_agg_size(u64::MAX);
                 }
             }
             LogicalPlan::Aggregate {
@@ -384,12 +1575,12 @@ fn initialize_plan(
             } => {
                 let keys = &(**keys)[..];
                 let ldf = lazy_frame_from_logical_plan((&**input).clone());
-                let agg_size = usize_item(
+                let agg_size = u64_item(
                     ldf.cache()
-                        .with_row_count("__count", None)
+                        .with_row_count(GROUP_ROW, None)
                         .groupby(keys)
-                        .agg([col("__count").count()])
-                        .select([col("__count").min()])
+                        .agg([col(GROUP_ROW).count()])
+                        .select([col(GROUP_ROW).min()])
                         .collect(),
                 )?;
 
@@ -405,11 +1596,14 @@ fn initialize_plan(
     Ok(state.1.pop().unwrap())
 }
 
-fn usize_item(df_res: Result<DataFrame, PolarsError>) -> Result<usize, Status> {
-    Ok(df_res
-        .map_err(|e| Status::internal(format!("Could not get usize item from DataFrame: {}", e)))?
-        .get(0)
-        .unwrap()[0]
-        .try_extract()
-        .unwrap())
+/// The first item of `df_res`, 0 when it is null: inputs without rows match and group none.
+fn u64_item(df_res: Result<DataFrame, PolarsError>) -> Result<u64, Status> {
+    let df = df_res
+        .map_err(|e| Status::internal(format!("Could not get u64 item from DataFrame: {}", e)))?;
+    match df.get(0).as_ref().map(|row| &row[0]) {
+        None | Some(AnyValue::Null) => Ok(0),
+        Some(value) => value
+            .try_extract()
+            .map_err(|e| Status::internal(format!("Could not get u64 item from DataFrame: {}", e))),
+    }
 }
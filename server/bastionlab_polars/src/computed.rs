@@ -0,0 +1,111 @@
+//! Computed columns of a composite plan, without writing a polars plan.
+//!
+//! A `WithColumnPlanSegment` adds a column to the dataframe on top of the stack, computed from its
+//! other columns with the expressions of filters, see [`crate::filters`], e.g. a BMI:
+//!
+//! ```json
+//! {"type": "WithColumnPlanSegment", "name": "bmi",
+//!  "expr": {"op": "Div", "left": {"op": "Column", "name": "weight"},
+//!           "right": {"op": "Mul", "left": {"op": "Column", "name": "height"},
+//!                     "right": {"op": "Column", "name": "height"}}}}
+//! ```
+//!
+//! Columns of the input are only replaced if `replace` is set. Computed columns are as protected
+//! as the columns they are computed from: reading a blacklisted column blacklists the result, so
+//! that `protected + 0` is masked as `protected` is.
+
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::{
+    filters::{FilterExpr, Kind},
+    reserved::is_reserved,
+};
+
+/// The polars plan adding column `name` computed by `expr` to `df`, whose dataframe scan stands
+/// for the input, see [`crate::composite_plan`].
+pub fn plan(
+    df: &DataFrame,
+    name: &str,
+    expr: &FilterExpr,
+    replace: bool,
+) -> Result<LogicalPlan, Status> {
+    if name.trim().is_empty() || is_reserved(name) {
+        return Err(Status::invalid_argument(format!(
+            "Could not compute column `{name}`: invalid name"
+        )));
+    }
+    let schema = df.schema();
+    if schema.get(name).is_some() && !replace {
+        return Err(Status::invalid_argument(format!(
+            "Could not compute column `{name}`: the input has a column of that name, set \
+             `replace` to replace it"
+        )));
+    }
+    let action = format!("compute column `{name}`");
+    let computed = expr.typed(&schema, &action)?;
+    if computed.kind == Kind::Null {
+        return Err(Status::invalid_argument(format!(
+            "Could not {action}: its values would all be null"
+        )));
+    }
+    let ldf = df
+        .head(Some(0))
+        .lazy()
+        .with_column(computed.expr.alias(name));
+    // Fails here rather than on the rows, e.g. on casts polars cannot do.
+    ldf.clone()
+        .collect()
+        .map_err(|e| Status::invalid_argument(format!("Could not {action}: {e}")))?;
+    Ok(ldf.logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn compute(name: &str, expr: serde_json::Value, replace: bool) -> Result<DataFrame, Status> {
+        let df = df! {
+            "sold" => [10i64, 5, 7],
+            "stock" => [2i64, 0, 1],
+            "site" => ["A", "B", "A"],
+        }
+        .unwrap();
+        let expr: FilterExpr = serde_json::from_value(expr).unwrap();
+        plan(&df, name, &expr, replace)?;
+        let computed = expr.typed(&df.schema(), "")?.expr.alias(name);
+        Ok(df.lazy().with_column(computed).collect().unwrap())
+    }
+
+    fn column(name: &str) -> serde_json::Value {
+        json!({"op": "Column", "name": name})
+    }
+
+    #[test]
+    fn divisions_by_zero_are_null() {
+        let ratio = json!({"op": "Div", "left": column("sold"), "right": column("stock")});
+        let df = compute("ratio", ratio, false).unwrap();
+        let ratio = df.column("ratio").unwrap().f64().unwrap();
+        assert_eq!(Vec::from(ratio), [Some(5.0), None, Some(7.0)]);
+
+        let label = json!({"op": "Concat", "left": column("site"),
+                           "right": {"op": "Literal", "value": "-east"}});
+        let df = compute("site", label, true).unwrap();
+        assert_eq!(
+            df.column("site").unwrap().utf8().unwrap().get(0),
+            Some("A-east")
+        );
+
+        let err = compute("site", column("sold"), false).unwrap_err();
+        assert!(err.message().contains("set `replace`"), "{err:?}");
+        let concat = json!({"op": "Concat", "left": column("site"), "right": column("sold")});
+        let err = compute("label", concat, false).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not compute column `label`: Concat needs strings, not `site` (str) and `sold` \
+             (i64)"
+        );
+        assert!(compute("__bastionlab_x", column("sold"), false).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Default policies that identities and groups register for the uploads that leave theirs out.
+//!
+//! A default is a policy template, a JSON object of policy fields, along with blacklist patterns.
+//! An upload without a policy takes the whole template. An upload with a JSON object of policy
+//! fields only takes the fields it leaves out: explicit fields always win, field by field, at the
+//! top level of the policy. Columns of the upload whose name matches a pattern are blacklisted on
+//! top of the ones it lists. The response to the upload warns about every default it took, and
+//! the dataframe records the scope and version of the defaults, see [`AppliedDefaults`].
+//!
+//! Uploads take the defaults of their requester if they set some, else those of the first group,
+//! by name, that lists them as a member. Identities set their own defaults, data owners those of
+//! groups. Setting defaults again adds a version, and uploads take the latest one. Defaults are
+//! kept in memory, as pipelines are.
+//!
+//! Defaults do not get around governance: the policy they complete is evaluated like any other,
+//! so an upload whose defaults leave it unsavable cannot be persisted, for instance.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tonic::Status;
+
+use crate::access_control::Policy;
+use crate::catalog::now_ms;
+
+/// Defaults of an identity or of a group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DefaultPolicy {
+    /// The identity the defaults apply to, or the name of the group if `group`.
+    pub scope: String,
+    pub group: bool,
+    /// Starts at 1.
+    pub version: u32,
+    pub author: String,
+    /// Milliseconds since the Unix epoch.
+    pub set_at: u64,
+    /// The identities of the group.
+    pub members: Vec<String>,
+    pub template: Map<String, Value>,
+    pub blacklist_patterns: Vec<String>,
+}
+
+fn origin(group: bool, scope: &str, version: u32) -> String {
+    let kind = if group { "group" } else { "identity" };
+    format!("the default policy of {kind} {scope} version {version}")
+}
+
+impl DefaultPolicy {
+    /// The policy and blacklist of an upload given the policy it sent, empty if none, the columns
+    /// it holds and the columns it blacklists.
+    pub fn apply(
+        &self,
+        policy: &str,
+        columns: &[&str],
+        mut blacklist: Vec<String>,
+    ) -> Result<(Policy, Vec<String>, AppliedDefaults), Status> {
+        let explicit = explicit_fields(policy)?;
+        let mut merged = self.template.clone();
+        let fields: Vec<String> = self
+            .template
+            .keys()
+            .filter(|field| !explicit.contains_key(*field))
+            .cloned()
+            .collect();
+        merged.extend(explicit);
+        let policy = serde_json::from_value(Value::Object(merged)).map_err(|e| {
+            Status::invalid_argument(format!(
+                "The policy of the upload, completed by {}, is invalid: {e}",
+                origin(self.group, &self.scope, self.version)
+            ))
+        })?;
+
+        let mut blacklisted = Vec::new();
+        for pattern in self.blacklist_patterns.iter() {
+            let regex = compile(pattern)?;
+            for column in columns {
+                if regex.is_match(column) && !blacklist.iter().any(|c| c == *column) {
+                    blacklist.push(column.to_string());
+                    blacklisted.push((column.to_string(), pattern.clone()));
+                }
+            }
+        }
+
+        let applied = AppliedDefaults {
+            scope: self.scope.clone(),
+            group: self.group,
+            version: self.version,
+            fields,
+            blacklisted,
+        };
+        Ok((policy, blacklist, applied))
+    }
+}
+
+/// The defaults an upload took, recorded on the dataframe.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppliedDefaults {
+    pub scope: String,
+    pub group: bool,
+    pub version: u32,
+    /// Policy fields taken from the template.
+    pub fields: Vec<String>,
+    /// Columns blacklisted by a pattern, along with the pattern.
+    pub blacklisted: Vec<(String, String)>,
+}
+
+impl AppliedDefaults {
+    /// What the upload took from the defaults, for its response.
+    pub fn warnings(&self) -> Vec<String> {
+        let origin = origin(self.group, &self.scope, self.version);
+        let mut warnings = Vec::new();
+        if !self.fields.is_empty() {
+            warnings.push(format!(
+                "Policy fields {} were taken from {origin}",
+                quoted(self.fields.iter().map(String::as_str))
+            ));
+        }
+        for (column, pattern) in self.blacklisted.iter() {
+            warnings.push(format!(
+                "Column `{column}` was blacklisted by pattern `{pattern}` of {origin}"
+            ));
+        }
+        warnings
+    }
+}
+
+fn quoted<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    items
+        .map(|item| format!("`{item}`"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn compile(pattern: &str) -> Result<Regex, Status> {
+    // Anchored, so that a pattern names whole columns.
+    Regex::new(&format!("^(?:{pattern})$")).map_err(|e| {
+        Status::invalid_argument(format!("Invalid blacklist pattern `{pattern}`: {e}"))
+    })
+}
+
+/// The fields set by `policy`, a JSON object, none if it is empty.
+fn explicit_fields(policy: &str) -> Result<Map<String, Value>, Status> {
+    if policy.trim().is_empty() {
+        return Ok(Map::new());
+    }
+    match serde_json::from_str(policy) {
+        Ok(Value::Object(fields)) => Ok(fields),
+        Ok(_) => Err(Status::invalid_argument(
+            "Error during the parsing of the policy: expected a JSON object",
+        )),
+        Err(e) => Err(Status::invalid_argument(format!(
+            "Error during the parsing of the policy: {e}"
+        ))),
+    }
+}
+
+/// Checks that `template` only sets policy fields, with values of their type.
+fn check_template(template: &Value) -> Result<Map<String, Value>, Status> {
+    let template = match template {
+        Value::Object(template) => template.clone(),
+        _ => {
+            return Err(Status::invalid_argument(
+                "A default policy must be a JSON object of policy fields",
+            ))
+        }
+    };
+    let mut full = match serde_json::to_value(Policy::allow_by_default()) {
+        Ok(Value::Object(full)) => full,
+        _ => return Err(Status::internal("Could not serialize a policy")),
+    };
+    if let Some(unknown) = template.keys().find(|field| !full.contains_key(*field)) {
+        return Err(Status::invalid_argument(format!(
+            "Unknown policy field `{unknown}` in the default policy"
+        )));
+    }
+    full.extend(template.clone());
+    serde_json::from_value::<Policy>(Value::Object(full))
+        .map_err(|e| Status::invalid_argument(format!("Invalid default policy: {e}")))?;
+    Ok(template)
+}
+
+#[derive(Debug, Default)]
+pub struct DefaultPolicies {
+    identities: RwLock<HashMap<String, Vec<DefaultPolicy>>>,
+    /// By name, so that the first group of a member is well defined.
+    groups: RwLock<BTreeMap<String, Vec<DefaultPolicy>>>,
+}
+
+impl DefaultPolicies {
+    /// Sets the defaults of `author`, or of group `group` with its members, adding a version.
+    pub fn set(
+        &self,
+        author: &str,
+        group: Option<(&str, Vec<String>)>,
+        template: &Value,
+        blacklist_patterns: Vec<String>,
+    ) -> Result<DefaultPolicy, Status> {
+        let template = check_template(template)?;
+        for pattern in blacklist_patterns.iter() {
+            compile(pattern)?;
+        }
+        let (scope, members) = match &group {
+            Some((name, _)) if name.is_empty() => {
+                return Err(Status::invalid_argument("Invalid group name: \"\""))
+            }
+            Some((name, members)) => (name.to_string(), members.clone()),
+            None => (author.to_string(), Vec::new()),
+        };
+        let mut defaults = DefaultPolicy {
+            scope: scope.clone(),
+            group: group.is_some(),
+            version: 0,
+            author: author.to_string(),
+            set_at: now_ms(),
+            members,
+            template,
+            blacklist_patterns,
+        };
+        let mut push = |versions: &mut Vec<DefaultPolicy>| {
+            defaults.version = versions.len() as u32 + 1;
+            versions.push(defaults.clone());
+        };
+        if group.is_some() {
+            push(self.groups.write().unwrap().entry(scope).or_default());
+        } else {
+            push(self.identities.write().unwrap().entry(scope).or_default());
+        }
+        Ok(defaults)
+    }
+
+    /// Version `version` of the defaults of `user_id`, or of group `group`, the latest if unset.
+    /// Groups are visible to their members and to data owners only, if `owner` is set.
+    pub fn get(
+        &self,
+        user_id: &str,
+        group: Option<&str>,
+        version: Option<u32>,
+        owner: bool,
+    ) -> Result<DefaultPolicy, Status> {
+        let found = match group {
+            Some(group) => self.groups.read().unwrap().get(group).cloned(),
+            None => self.identities.read().unwrap().get(user_id).cloned(),
+        };
+        let versions = found.unwrap_or_default();
+        let defaults = match version {
+            Some(version) => versions.iter().find(|d| d.version == version),
+            None => versions.last(),
+        };
+        match defaults {
+            Some(defaults)
+                if !defaults.group || owner || defaults.members.iter().any(|m| m == user_id) =>
+            {
+                Ok(defaults.clone())
+            }
+            _ => Err(Status::not_found(match group {
+                Some(group) => format!("Could not find the default policy of group {group}"),
+                None => format!("No default policy is set for {user_id}"),
+            })),
+        }
+    }
+
+    /// The defaults the uploads of `user_id` take, if any.
+    pub fn resolve(&self, user_id: &str) -> Option<DefaultPolicy> {
+        if let Some(defaults) = self
+            .identities
+            .read()
+            .unwrap()
+            .get(user_id)
+            .and_then(|versions| versions.last())
+        {
+            return Some(defaults.clone());
+        }
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|versions| versions.last())
+            .find(|defaults| defaults.members.iter().any(|m| m == user_id))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn explicit_fields_win_over_the_template() {
+        let defaults = DefaultPolicies::default();
+        let set = |template: Value, patterns: &[&str]| {
+            defaults.set(
+                "alice",
+                None,
+                &template,
+                patterns.iter().map(|p| p.to_string()).collect(),
+            )
+        };
+        let template = json!({
+            "safe_zone": {"type": "FalseRule"},
+            "unsafe_handling": {"type": "Review"},
+            "savable": false,
+        });
+        set(template.clone(), &["ssn|.*_id"]).unwrap();
+        let latest = set(template, &["ssn|.*_id", "email"]).unwrap();
+        assert_eq!(latest.version, 2);
+        assert_eq!(defaults.resolve("alice"), Some(latest.clone()));
+        assert_eq!(defaults.resolve("bob"), None);
+
+        let columns = ["ssn", "patient_id", "email", "age", "email_domain"];
+        let explicit = r#"{"savable": true, "unsafe_handling": {"type": "Log"}}"#;
+        let (policy, blacklist, applied) = latest
+            .apply(explicit, &columns, vec![String::from("age")])
+            .unwrap();
+        assert!(policy.check_savable());
+        assert_eq!(applied.fields, ["safe_zone"]);
+        assert_eq!(blacklist, ["age", "ssn", "patient_id", "email"]);
+        assert_eq!(applied.blacklisted.len(), 3);
+        let warnings = applied.warnings();
+        assert_eq!(
+            warnings[0],
+            "Policy fields `safe_zone` were taken from the default policy of identity alice \
+             version 2"
+        );
+        assert!(warnings[1].contains("Column `ssn` was blacklisted by pattern `ssn|.*_id`"));
+
+        // Without a policy, the upload takes the whole template.
+        let (policy, _, applied) = latest.apply("", &columns, Vec::new()).unwrap();
+        assert!(!policy.check_savable());
+        assert_eq!(applied.fields.len(), 3);
+    }
+
+    #[test]
+    fn invalid_defaults_are_rejected() {
+        let defaults = DefaultPolicies::default();
+        let err = defaults
+            .set("alice", None, &json!({"savable": "yes"}), Vec::new())
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = defaults
+            .set("alice", None, &json!({"ttl": 3}), Vec::new())
+            .unwrap_err();
+        assert!(err.message().contains("`ttl`"), "{err:?}");
+        assert!(defaults
+            .set("alice", None, &json!({}), vec![String::from("(")])
+            .is_err());
+
+        // A template that leaves required fields out only fails on uploads that do too.
+        let partial = defaults
+            .set("alice", None, &json!({"savable": false}), Vec::new())
+            .unwrap();
+        let err = partial.apply("", &[], Vec::new()).unwrap_err();
+        assert!(err.message().contains("missing field"), "{err:?}");
+        let explicit = r#"{"safe_zone": {"type": "TrueRule"}, "unsafe_handling": {"type": "Log"}}"#;
+        assert!(partial.apply(explicit, &[], Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn groups_apply_to_their_members_without_defaults_of_their_own() {
+        let defaults = DefaultPolicies::default();
+        let members = vec![String::from("bob"), String::from("carol")];
+        defaults
+            .set("owner", Some(("analysts", members)), &json!({}), Vec::new())
+            .unwrap();
+        defaults
+            .set("carol", None, &json!({"savable": false}), Vec::new())
+            .unwrap();
+        assert_eq!(defaults.resolve("bob").unwrap().scope, "analysts");
+        assert_eq!(defaults.resolve("carol").unwrap().scope, "carol");
+        assert!(defaults.get("bob", Some("analysts"), None, false).is_ok());
+        assert!(defaults.get("dave", Some("analysts"), None, false).is_err());
+        assert!(defaults.get("dave", Some("analysts"), None, true).is_ok());
+    }
+}
@@ -0,0 +1,335 @@
+//! Delta fetches: sending only the rows of a result that changed since a version the client
+//! already fetched.
+//!
+//! Rows are matched across versions on key columns. A delta holds the inserted and updated rows,
+//! each with its position in the new version, and the keys of the deleted rows. The other rows
+//! are unchanged: the client keeps them in their order and fills the remaining positions with
+//! them. This requires them to keep their relative order, a delta is refused otherwise.
+
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::reserved::DELTA_PREVIOUS_ROW as PREVIOUS_ROW;
+pub use crate::reserved::{DELTA_OPERATION as OPERATION, DELTA_POSITION as POSITION};
+use crate::upsert::same_values;
+
+pub const INSERT: &str = "insert";
+pub const UPDATE: &str = "update";
+pub const DELETE: &str = "delete";
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error while computing a delta: {e}"))
+}
+
+/// The rows that changed between two versions of a result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaRows {
+    /// Inserted and updated rows of the new version, by position.
+    changed: Vec<(IdxSize, &'static str)>,
+    /// Rows of the previous version.
+    deleted: Vec<IdxSize>,
+}
+
+/// Why a delta cannot be computed, the full result is sent instead.
+pub type Fallback = String;
+
+/// The key columns of `df`, numbered in the column `row`.
+fn numbered_keys(df: &DataFrame, keys: &[String], row: &str) -> Result<DataFrame, Status> {
+    let mut keys = df.select(keys).map_err(polars_err)?;
+    // Joins on keys made of several chunks are not reliable in this version of polars.
+    keys.as_single_chunk();
+    keys.with_row_count(row, None).map_err(polars_err)
+}
+
+/// Checks that `keys` identify the rows of `df`, returning why they don't otherwise.
+fn check_keys(df: &DataFrame, keys: &[String], version: &str) -> Result<Option<Fallback>, Status> {
+    if let Some(key) = keys.iter().find(|key| df.find_idx_by_name(key).is_none()) {
+        return Ok(Some(format!(
+            "the {version} version has no key column {key}"
+        )));
+    }
+    let keys = df.select(keys).map_err(polars_err)?;
+    if keys.get_columns().iter().any(|s| s.null_count() > 0) {
+        return Ok(Some(format!("the {version} version has null keys")));
+    }
+    if keys.is_duplicated().map_err(polars_err)?.any() {
+        return Ok(Some(format!("the {version} version has duplicated keys")));
+    }
+    Ok(None)
+}
+
+impl DeltaRows {
+    /// Computes the rows changed from `previous` to `current`, matched on `keys`.
+    pub fn diff(
+        previous: &DataFrame,
+        current: &DataFrame,
+        keys: &[String],
+    ) -> Result<Result<Self, Fallback>, Status> {
+        if keys.is_empty() {
+            return Ok(Err("no key columns were given".into()));
+        }
+        if previous.schema() != current.schema() {
+            return Ok(Err("the columns changed".into()));
+        }
+        for (df, version) in [(previous, "previous"), (current, "new")] {
+            if let Some(fallback) = check_keys(df, keys, version)? {
+                return Ok(Err(fallback));
+            }
+        }
+
+        let previous_keys = numbered_keys(previous, keys, PREVIOUS_ROW)?;
+        let current_keys = numbered_keys(current, keys, POSITION)?;
+        let joined = current_keys
+            .join(&previous_keys, keys, keys, JoinType::Left, None)
+            .and_then(|df| df.sort([POSITION], false))
+            .map_err(polars_err)?;
+        let matches = joined
+            .column(PREVIOUS_ROW)
+            .map_err(polars_err)?
+            .idx()
+            .map_err(polars_err)?;
+
+        // New rows are compared to any previous row, they are inserted whatever the result.
+        let aligned = previous
+            .take(&matches.fill_null_with_values(0).map_err(polars_err)?)
+            .map_err(polars_err)?;
+        let mut same = vec![true; current.height()];
+        for series in current.get_columns() {
+            if keys.iter().any(|key| key == series.name()) {
+                continue;
+            }
+            let previous = aligned.column(series.name()).map_err(polars_err)?;
+            for (same, eq) in same.iter_mut().zip(same_values(series, previous)?) {
+                *same &= eq;
+            }
+        }
+
+        let mut changed = Vec::new();
+        let mut matched = vec![false; previous.height()];
+        let mut last_unchanged = None;
+        for (position, previous_row) in matches.into_iter().enumerate() {
+            let position = position as IdxSize;
+            match previous_row {
+                None => changed.push((position, INSERT)),
+                Some(row) => {
+                    matched[row as usize] = true;
+                    if !same[position as usize] {
+                        changed.push((position, UPDATE));
+                    } else if last_unchanged.replace(row) > Some(row) {
+                        return Ok(Err("the rows were reordered".into()));
+                    }
+                }
+            }
+        }
+        let deleted = (0..previous.height() as IdxSize)
+            .filter(|row| !matched[*row as usize])
+            .collect();
+        Ok(Ok(DeltaRows { changed, deleted }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.changed.len() + self.deleted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The delta, with the changed rows taken from `current` and the deleted keys from `previous`.
+    ///
+    /// `current` is the new version as it is sent, which may differ from the one the delta was
+    /// computed on by its watermark.
+    pub fn to_dataframe(
+        &self,
+        previous: &DataFrame,
+        current: &DataFrame,
+        keys: &[String],
+    ) -> Result<DataFrame, Status> {
+        let positions: Vec<IdxSize> = self.changed.iter().map(|(position, _)| *position).collect();
+        let mut changed = current
+            .take(&IdxCa::from_vec("", positions.clone()))
+            .map_err(polars_err)?;
+        let operations: Vec<&str> = self.changed.iter().map(|(_, op)| *op).collect();
+        changed
+            .with_column(Series::new(OPERATION, operations))
+            .and_then(|df| df.with_column(Series::new(POSITION, positions)))
+            .map_err(polars_err)?;
+
+        let deleted = previous
+            .take(&IdxCa::from_vec("", self.deleted.clone()))
+            .map_err(polars_err)?;
+        let mut columns: Vec<_> = current
+            .get_columns()
+            .iter()
+            .map(|series| {
+                if keys.iter().any(|key| key == series.name()) {
+                    deleted.column(series.name()).cloned()
+                } else {
+                    Ok(Series::full_null(
+                        series.name(),
+                        deleted.height(),
+                        series.dtype(),
+                    ))
+                }
+            })
+            .collect::<PolarsResult<_>>()
+            .map_err(polars_err)?;
+        columns.push(Series::new(OPERATION, vec![DELETE; deleted.height()]));
+        columns.push(Series::full_null(POSITION, deleted.height(), &IDX_DTYPE));
+        let deleted = DataFrame::new(columns).map_err(polars_err)?;
+
+        changed.vstack(&deleted).map_err(polars_err)
+    }
+}
+
+fn invalid_delta(reason: &str) -> Status {
+    Status::data_loss(format!("Invalid delta: {reason}"))
+}
+
+/// Rebuilds the new version of a result from the previous one and a delta.
+pub fn apply(
+    previous: &DataFrame,
+    delta: &DataFrame,
+    keys: &[String],
+) -> Result<DataFrame, Status> {
+    let operations = delta
+        .column(OPERATION)
+        .and_then(|s| s.utf8().cloned())
+        .map_err(|_| invalid_delta("no operation column"))?;
+    let inserted = operations.equal(INSERT);
+    let deleted = operations.equal(DELETE);
+
+    // Updated and deleted rows are removed, the others are kept in their order. The operation
+    // column marks the removed rows once joined.
+    let removed = delta.filter(&!&inserted).map_err(polars_err)?;
+    let removed = numbered_keys(&removed, keys, OPERATION)?;
+    let previous_keys = numbered_keys(previous, keys, PREVIOUS_ROW)?;
+    let joined = previous_keys
+        .join(&removed, keys, keys, JoinType::Left, None)
+        .and_then(|df| df.sort([PREVIOUS_ROW], false))
+        .map_err(polars_err)?;
+    if joined.height() != previous.height() {
+        return Err(invalid_delta("the previous version has duplicated keys"));
+    }
+    let kept = joined.column(OPERATION).map_err(polars_err)?.is_null();
+    let kept = previous.filter(&kept).map_err(polars_err)?;
+
+    let mut changed = delta.filter(&!&deleted).map_err(polars_err)?;
+    let positions = changed
+        .drop_in_place(POSITION)
+        .and_then(|s| s.idx().cloned())
+        .map_err(|_| invalid_delta("no position column"))?;
+    let _ = changed.drop_in_place(OPERATION);
+
+    let height = kept.height() + changed.height();
+    let mut take: Vec<Option<IdxSize>> = vec![None; height];
+    for (idx, position) in positions.into_iter().enumerate() {
+        let slot = position
+            .and_then(|position| take.get_mut(position as usize))
+            .filter(|slot| slot.is_none())
+            .ok_or_else(|| invalid_delta("invalid positions"))?;
+        *slot = Some((kept.height() + idx) as IdxSize);
+    }
+    let mut unchanged = 0..kept.height() as IdxSize;
+    let take: Vec<IdxSize> = take
+        .into_iter()
+        .map(|slot| slot.or_else(|| unchanged.next()))
+        .collect::<Option<_>>()
+        .ok_or_else(|| invalid_delta("invalid positions"))?;
+
+    let mut rows = kept.vstack(&changed).map_err(polars_err)?;
+    rows.as_single_chunk()
+        .take(&IdxCa::from_vec("", take))
+        .map_err(polars_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<String> {
+        vec!["id".to_string()]
+    }
+
+    fn rows(ids: &[i64], values: &[Option<&str>]) -> DataFrame {
+        df! { "id" => ids, "value" => values }.unwrap()
+    }
+
+    /// Diffs and applies the delta, which must have `expected` rows.
+    fn round_trip(previous: &DataFrame, current: &DataFrame, expected: usize) {
+        let delta = DeltaRows::diff(previous, current, &keys())
+            .unwrap()
+            .unwrap();
+        assert_eq!(delta.len(), expected);
+        let delta = delta.to_dataframe(previous, current, &keys()).unwrap();
+        let rebuilt = apply(previous, &delta, &keys()).unwrap();
+        assert!(rebuilt.frame_equal_missing(current), "{rebuilt:?}");
+    }
+
+    #[test]
+    fn deltas_rebuild_the_new_version() {
+        let previous = rows(&[1, 2, 3, 4], &[Some("a"), Some("b"), None, Some("d")]);
+        round_trip(&previous, &previous, 0);
+
+        // Inserts, in the middle and at the end.
+        let current = rows(
+            &[1, 5, 2, 3, 4, 6],
+            &[Some("a"), Some("e"), Some("b"), None, Some("d"), Some("f")],
+        );
+        round_trip(&previous, &current, 2);
+
+        // Deletes.
+        round_trip(&previous, &rows(&[1, 4], &[Some("a"), Some("d")]), 2);
+
+        // In-place changes, null values included.
+        let current = rows(&[1, 2, 3, 4], &[Some("a"), None, Some("c"), Some("d")]);
+        round_trip(&previous, &current, 2);
+
+        // All of them, with a changed row moving.
+        let current = rows(&[3, 7, 2, 4], &[None, Some("g"), Some("x"), Some("d")]);
+        round_trip(&previous, &current, 3);
+
+        round_trip(&previous, &rows(&[], &[]), 4);
+    }
+
+    #[test]
+    fn deltas_only_hold_changed_rows() {
+        let previous = rows(&[1, 2, 3], &[Some("a"), Some("b"), Some("c")]);
+        let current = rows(&[1, 2, 4], &[Some("a"), Some("z"), Some("d")]);
+        let delta = DeltaRows::diff(&previous, &current, &keys())
+            .unwrap()
+            .unwrap()
+            .to_dataframe(&previous, &current, &keys())
+            .unwrap();
+        let expected = df! {
+            "id" => [2i64, 4, 3],
+            "value" => [Some("z"), Some("d"), None],
+            OPERATION => [UPDATE, INSERT, DELETE],
+            POSITION => [Some(1 as IdxSize), Some(2), None],
+        }
+        .unwrap();
+        assert!(delta.frame_equal_missing(&expected), "{delta:?}");
+    }
+
+    #[test]
+    fn ambiguous_deltas_fall_back_to_full_fetches() {
+        let previous = rows(&[1, 2, 3], &[Some("a"), Some("b"), Some("c")]);
+        let fallback = |current: &DataFrame, keys: &[String]| {
+            DeltaRows::diff(&previous, current, keys)
+                .unwrap()
+                .unwrap_err()
+        };
+
+        let duplicated = rows(&[1, 1, 3], &[Some("a"), Some("b"), Some("c")]);
+        assert!(fallback(&duplicated, &keys()).contains("duplicated keys"));
+        let null_keys = df! { "id" => [Some(1i64), None], "value" => ["a", "b"] }.unwrap();
+        assert!(fallback(&null_keys, &keys()).contains("null keys"));
+        assert!(fallback(&previous, &["value2".to_string()]).contains("no key column"));
+        assert!(fallback(&previous, &[]).contains("no key columns"));
+        let other_columns = df! { "id" => [1i64], "other" => ["a"] }.unwrap();
+        assert!(fallback(&other_columns, &keys()).contains("columns changed"));
+        // Unchanged rows moving cannot be described by positions of changed rows only.
+        let reordered = rows(&[3, 1, 2], &[Some("c"), Some("a"), Some("b")]);
+        assert!(fallback(&reordered, &keys()).contains("reordered"));
+    }
+}
@@ -0,0 +1,660 @@
+//! Differential privacy noise on the aggregates the safe zone of a policy rejects.
+//!
+//! A policy with a `differential_privacy` rule releases the results its safe zone rejects, such as
+//! aggregates of too few rows, with calibrated noise instead of blocking them:
+//!
+//! ```json
+//! {"differential_privacy": {"mechanism": {"type": "Laplace"}, "epsilon": 0.5, "budget": 5.0,
+//!                           "bounds": {"age": {"min": 0, "max": 120}}}}
+//! ```
+//!
+//! Only the counts, sums and means of a `GroupByPlanSegment` ending the plan are noised, see
+//! [`crate::aggregations`]. Whatever else the safe zone rejects is denied, whatever the unsafe
+//! handling of the policy says, and group-bys of the data with other aggregations fail. Under the
+//! rule, the values of summed and averaged columns are clamped to their `bounds`, which bound how
+//! much one row changes an aggregate: 1 for counts, the largest magnitude of the bounds for sums.
+//! Means are released as noisy sums over noisy counts. The keys of the groups are released as they
+//! are: group by columns whose values are not sensitive themselves.
+//!
+//! Every fetch of a noisy result draws fresh noise and spends `epsilon` of the budget of the
+//! recipient on each dataframe the result derives from, shared by the aggregates it releases.
+//! Fetches past the budget are denied. Results of noisy results are noised again, and spend the
+//! budgets of the original dataframes. Spent budgets are persisted next to the dataframes before
+//! the result is released, so that restarts do not reset them, and a server that cannot read them
+//! back does not start.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use polars::prelude::*;
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::aggregations::{self, AggKind, Aggregation};
+use crate::reserved::DP_VALUES_PREFIX;
+
+/// Slack of budget comparisons, so that spending a budget in equal parts uses all of it.
+const BUDGET_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Mechanism {
+    Laplace,
+    /// Gaussian noise, for (epsilon, delta) privacy with epsilon below 1.
+    Gaussian {
+        delta: f64,
+    },
+}
+
+/// The range the values of a column are clamped to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Bounds {
+    /// How much one row changes the sum of the clamped values.
+    fn sensitivity(&self) -> f64 {
+        self.min.abs().max(self.max.abs())
+    }
+}
+
+/// The differential privacy rule of a policy.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DifferentialPrivacy {
+    pub mechanism: Mechanism,
+    /// Privacy spent by each noisy fetch.
+    pub epsilon: f64,
+    /// Privacy each identity may spend on the data, over all its noisy fetches.
+    pub budget: f64,
+    /// Bounds of the summed and averaged columns.
+    #[serde(default)]
+    pub bounds: BTreeMap<String, Bounds>,
+}
+
+impl DifferentialPrivacy {
+    pub fn check(&self) -> Result<(), Status> {
+        let invalid = |reason: String| {
+            Err(Status::invalid_argument(format!(
+                "Invalid differential privacy rule: {reason}"
+            )))
+        };
+        if !(self.epsilon > 0.0 && self.epsilon.is_finite()) {
+            return invalid(format!("epsilon {} is not positive", self.epsilon));
+        }
+        if !(self.budget >= self.epsilon && self.budget.is_finite()) {
+            return invalid(format!(
+                "budget {} is less than the epsilon of a fetch, {}",
+                self.budget, self.epsilon
+            ));
+        }
+        if let Mechanism::Gaussian { delta } = self.mechanism {
+            if !(delta > 0.0 && delta < 1.0) {
+                return invalid(format!("delta {delta} is not between 0 and 1"));
+            }
+            if self.epsilon >= 1.0 {
+                return invalid(format!(
+                    "Gaussian noise needs an epsilon below 1, not {}",
+                    self.epsilon
+                ));
+            }
+        }
+        for (column, bounds) in self.bounds.iter() {
+            if !(bounds.min.is_finite() && bounds.max.is_finite() && bounds.min <= bounds.max) {
+                return invalid(format!(
+                    "the bounds of `{column}`, {} to {}, are not a range",
+                    bounds.min, bounds.max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The stricter of both rules: the smaller epsilons and budgets, Laplace noise unless both
+    /// are Gaussian, and the narrower bounds.
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut bounds = self.bounds.clone();
+        for (column, b) in other.bounds.iter() {
+            bounds
+                .entry(column.clone())
+                .and_modify(|a| {
+                    a.min = a.min.max(b.min);
+                    a.max = a.max.min(b.max).max(a.min);
+                })
+                .or_insert(*b);
+        }
+        DifferentialPrivacy {
+            mechanism: match (self.mechanism, other.mechanism) {
+                (Mechanism::Gaussian { delta: a }, Mechanism::Gaussian { delta: b }) => {
+                    Mechanism::Gaussian { delta: a.min(b) }
+                }
+                _ => Mechanism::Laplace,
+            },
+            epsilon: self.epsilon.min(other.epsilon),
+            budget: self.budget.min(other.budget),
+            bounds,
+        }
+    }
+
+    /// A draw of the noise of a statistic of `sensitivity`, one of `shares` statistics a fetch
+    /// releases.
+    fn noise(&self, sensitivity: f64, shares: f64, rng: &mut StdRng) -> f64 {
+        let epsilon = self.epsilon / shares;
+        match self.mechanism {
+            Mechanism::Laplace => {
+                // -0.5 would draw infinite noise: sample the open interval.
+                let u: f64 = loop {
+                    let u = rng.gen_range(-0.5..0.5);
+                    if u != -0.5 {
+                        break u;
+                    }
+                };
+                -(sensitivity / epsilon) * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+            }
+            Mechanism::Gaussian { delta } => {
+                let sigma = sensitivity * (2.0 * (1.25 * shares / delta).ln()).sqrt() / epsilon;
+                let (u, v): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                sigma * (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+            }
+        }
+    }
+}
+
+/// An aggregate of a noisy result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoisyColumn {
+    pub name: String,
+    pub agg: AggKind,
+    /// The bounds of the aggregated column, for sums and means.
+    pub bounds: Option<Bounds>,
+    /// For means, how many values each row averages.
+    #[serde(default)]
+    pub counts: Vec<f64>,
+}
+
+impl NoisyColumn {
+    /// The statistics the noise of the column is drawn for: means are a sum and a count.
+    fn shares(&self) -> f64 {
+        match self.agg {
+            AggKind::Mean => 2.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// How a result is released under differential privacy, see [`crate::DataFrameArtifact`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoisyRelease {
+    pub privacy: DifferentialPrivacy,
+    /// The dataframes whose budgets the fetches spend.
+    pub sources: Vec<String>,
+    pub columns: Vec<NoisyColumn>,
+}
+
+impl NoisyRelease {
+    /// A release spending the budgets of `sources` under `privacy`, whose columns are set once
+    /// the result is computed.
+    pub fn new(privacy: DifferentialPrivacy, sources: Vec<String>) -> Self {
+        NoisyRelease {
+            privacy,
+            sources,
+            columns: Vec::new(),
+        }
+    }
+
+    /// The release under both rules, spending the budgets of the sources of both.
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut sources = self.sources.clone();
+        for source in other.sources.iter() {
+            if !sources.contains(source) {
+                sources.push(source.clone());
+            }
+        }
+        NoisyRelease {
+            privacy: self.privacy.merge(&other.privacy),
+            sources,
+            columns: Vec::new(),
+        }
+    }
+
+    /// Keeps the counts of the first `rows` rows, those a row cap kept.
+    pub fn truncate(&mut self, rows: usize) {
+        for column in self.columns.iter_mut() {
+            column.counts.truncate(rows);
+        }
+    }
+
+    /// What recipients are told of the release.
+    pub fn notice(&self) -> String {
+        format!(
+            "Released with differential privacy noise: every fetch draws new noise and spends \
+             epsilon {} of your budget on {}",
+            self.privacy.epsilon,
+            self.sources.join(", ")
+        )
+    }
+
+    /// Adds noise to the aggregates of `df`, failing rather than releasing any exactly.
+    pub fn apply(&self, df: &mut DataFrame, rng: &mut StdRng) -> Result<(), Status> {
+        if self.columns.is_empty() {
+            return Err(noise_err("the result has no aggregate to noise"));
+        }
+        let shares: f64 = self.columns.iter().map(NoisyColumn::shares).sum();
+        let height = df.height();
+        for column in self.columns.iter() {
+            let name = &column.name;
+            let idx = df
+                .find_idx_by_name(name)
+                .ok_or_else(|| noise_err(&format!("the result has no column `{name}`")))?;
+            if column.agg == AggKind::Mean && column.counts.len() != height {
+                return Err(noise_err(&format!(
+                    "the counts of mean `{name}` do not match the rows of the result"
+                )));
+            }
+            let series = df.get_columns_mut().get_mut(idx).unwrap();
+            let values = series.cast(&DataType::Float64).map_err(polars_err)?;
+            let values = values.f64().map_err(polars_err)?;
+            let mut noisy = |sensitivity: f64| self.privacy.noise(sensitivity, shares, rng);
+            let mut noised: Float64Chunked = match (column.agg, column.bounds) {
+                (AggKind::Count, _) => values
+                    .into_iter()
+                    .map(|v| v.map(|count| (count + noisy(1.0)).round().max(0.0)))
+                    .collect(),
+                (AggKind::Sum, Some(bounds)) => values
+                    .into_iter()
+                    .map(|v| v.map(|sum| sum + noisy(bounds.sensitivity())))
+                    .collect(),
+                (AggKind::Mean, Some(bounds)) => values
+                    .into_iter()
+                    .zip(column.counts.iter())
+                    .map(|(v, count)| {
+                        v.map(|mean| {
+                            let sum = mean * count + noisy(bounds.sensitivity());
+                            let count = (count + noisy(1.0)).max(1.0);
+                            (sum / count).clamp(bounds.min, bounds.max)
+                        })
+                    })
+                    .collect(),
+                (agg, _) => {
+                    return Err(noise_err(&format!(
+                        "the {} of column `{name}` cannot be noised",
+                        agg.name()
+                    )))
+                }
+            };
+            noised.rename(name);
+            *series = match column.agg {
+                AggKind::Count => noised
+                    .into_series()
+                    .cast(series.dtype())
+                    .map_err(polars_err)?,
+                _ => noised.into_series(),
+            };
+        }
+        Ok(())
+    }
+}
+
+fn noise_err(reason: &str) -> Status {
+    Status::failed_precondition(format!(
+        "Could not release the result with differential privacy noise: {reason}"
+    ))
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error while adding noise: {e}"))
+}
+
+/// The name of the column counting the values of mean `output`.
+fn values_column(output: &str) -> String {
+    format!("{DP_VALUES_PREFIX}{output}")
+}
+
+/// The polars plan aggregating `df` under `privacy`, whose dataframe scan stands for the input,
+/// and the aggregates its result releases, see [`crate::aggregations::plan`].
+///
+/// Fails on the aggregations that cannot be noised, and on sums and means of unbounded columns.
+pub fn plan(
+    df: &DataFrame,
+    by: &[String],
+    aggs: &[Aggregation],
+    blacklisted: &[String],
+    privacy: &DifferentialPrivacy,
+) -> Result<(LogicalPlan, Vec<NoisyColumn>), Status> {
+    aggregations::check(&df.schema(), by, aggs, blacklisted)?;
+    let (exprs, columns) = exprs(aggs, privacy)?;
+    let plan = df
+        .head(Some(0))
+        .lazy()
+        .groupby(by.iter().map(|column| col(column)).collect::<Vec<_>>())
+        .agg(exprs)
+        .logical_plan;
+    Ok((plan, columns))
+}
+
+/// The expressions of the clamped `aggs`, and of the counts of their means.
+fn exprs(
+    aggs: &[Aggregation],
+    privacy: &DifferentialPrivacy,
+) -> Result<(Vec<Expr>, Vec<NoisyColumn>), Status> {
+    let mut exprs = Vec::new();
+    let mut columns = Vec::new();
+    for agg in aggs {
+        let (column, kind) = (&agg.column, agg.agg.name());
+        let bounds = match agg.agg {
+            AggKind::Count => None,
+            AggKind::Sum | AggKind::Mean => Some(*privacy.bounds.get(column).ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Could not aggregate `{column}` with {kind}: the differential privacy \
+                         rule of the data sets no bounds for the column"
+                ))
+            })?),
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "Could not aggregate `{column}` with {kind}: under the differential privacy \
+                     rule of the data, only count, sum and mean can aggregate it"
+                )))
+            }
+        };
+        let output = agg.output();
+        let clamped = bounds.map(|bounds| {
+            let value = col(column).cast(DataType::Float64);
+            when(value.clone().lt(lit(bounds.min)))
+                .then(lit(bounds.min))
+                .otherwise(
+                    when(value.clone().gt(lit(bounds.max)))
+                        .then(lit(bounds.max))
+                        .otherwise(value),
+                )
+        });
+        let expr = match clamped {
+            None => col(column).count(),
+            Some(clamped) if agg.agg == AggKind::Sum => clamped.sum(),
+            Some(clamped) => {
+                exprs.push(
+                    clamped
+                        .clone()
+                        .is_not_null()
+                        .cast(DataType::Float64)
+                        .sum()
+                        .alias(&values_column(&output)),
+                );
+                clamped.mean()
+            }
+        };
+        exprs.push(expr.alias(&output));
+        columns.push(NoisyColumn {
+            name: output,
+            agg: agg.agg,
+            bounds,
+            counts: Vec::new(),
+        });
+    }
+    Ok((exprs, columns))
+}
+
+/// Moves the counts of the means of `columns` out of `df`, the result of [`plan`].
+pub fn take_counts(df: &mut DataFrame, columns: &mut [NoisyColumn]) -> Result<(), Status> {
+    for column in columns.iter_mut() {
+        if column.agg != AggKind::Mean {
+            continue;
+        }
+        let counts = df
+            .drop_in_place(&values_column(&column.name))
+            .map_err(polars_err)?;
+        let counts = counts.cast(&DataType::Float64).map_err(polars_err)?;
+        column.counts = counts
+            .f64()
+            .map_err(polars_err)?
+            .into_iter()
+            .map(|count| count.unwrap_or(0.0))
+            .collect();
+    }
+    Ok(())
+}
+
+/// The privacy `identity` spent on dataframe `source`, as persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpentBudget {
+    pub source: String,
+    pub identity: String,
+    pub spent: f64,
+}
+
+/// Persists all the budgets spent, see [`PrivacyBudgets::persist_with`].
+type Persist = Box<dyn Fn(&[SpentBudget]) -> Result<(), Status> + Send + Sync>;
+
+/// The privacy each identity spent on each dataframe.
+#[derive(Default)]
+pub struct PrivacyBudgets {
+    spent: Mutex<HashMap<(String, String), f64>>,
+    persist: Mutex<Option<Persist>>,
+}
+
+impl PrivacyBudgets {
+    /// Loads `budgets`, those persisted before a restart.
+    pub fn restore(&self, budgets: Vec<SpentBudget>) {
+        let mut spent = self.spent.lock().unwrap();
+        for budget in budgets {
+            spent.insert((budget.source, budget.identity), budget.spent);
+        }
+    }
+
+    /// Persists the budgets with `persist` from now on, on every spend.
+    pub fn persist_with(
+        &self,
+        persist: impl Fn(&[SpentBudget]) -> Result<(), Status> + Send + Sync + 'static,
+    ) {
+        *self.persist.lock().unwrap() = Some(Box::new(persist));
+    }
+
+    /// Spends the epsilon of a fetch of `release` by `identity` on each of its sources, or
+    /// nothing if any budget is exhausted or the budgets could not be persisted.
+    pub fn spend(&self, release: &NoisyRelease, identity: &str) -> Result<(), Status> {
+        let privacy = &release.privacy;
+        let mut spent = self.spent.lock().unwrap();
+        for source in release.sources.iter() {
+            let key = (source.clone(), identity.to_string());
+            let remaining = (privacy.budget - spent.get(&key).copied().unwrap_or(0.0)).max(0.0);
+            if privacy.epsilon > remaining + BUDGET_TOLERANCE {
+                return Err(Status::permission_denied(format!(
+                    "The privacy budget of {identity} on dataframe {source} is exhausted: epsilon \
+                     {remaining} of {} remains, and a fetch of this result spends {}",
+                    privacy.budget, privacy.epsilon
+                )));
+            }
+        }
+        for source in release.sources.iter() {
+            *spent
+                .entry((source.clone(), identity.to_string()))
+                .or_default() += privacy.epsilon;
+        }
+        if let Some(persist) = self.persist.lock().unwrap().as_ref() {
+            let budgets: Vec<_> = spent
+                .iter()
+                .map(|((source, identity), spent)| SpentBudget {
+                    source: source.clone(),
+                    identity: identity.clone(),
+                    spent: *spent,
+                })
+                .collect();
+            if let Err(err) = persist(&budgets) {
+                for source in release.sources.iter() {
+                    let key = (source.clone(), identity.to_string());
+                    if let Some(spent) = spent.get_mut(&key) {
+                        *spent -= privacy.epsilon;
+                    }
+                }
+                return Err(Status::unavailable(format!(
+                    "Could not release the result: the privacy budgets spent could not be \
+                     persisted: {}",
+                    err.message()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The epsilon `identity` spent on `source` so far.
+    pub fn spent(&self, source: &str, identity: &str) -> f64 {
+        let spent = self.spent.lock().unwrap();
+        spent
+            .get(&(source.to_string(), identity.to_string()))
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+    use tonic::Code;
+
+    fn privacy(mechanism: Mechanism) -> DifferentialPrivacy {
+        DifferentialPrivacy {
+            mechanism,
+            epsilon: 0.5,
+            budget: 1.0,
+            bounds: BTreeMap::from([(
+                String::from("age"),
+                Bounds {
+                    min: 0.0,
+                    max: 100.0,
+                },
+            )]),
+        }
+    }
+
+    fn agg(column: &str, agg: AggKind) -> Aggregation {
+        Aggregation {
+            column: column.to_string(),
+            agg,
+        }
+    }
+
+    #[test]
+    fn aggregates_are_clamped_noised_and_budgeted() {
+        let df = df! {
+            "site" => ["a", "a", "b"],
+            "age" => [30i64, 500, 40],
+        }
+        .unwrap();
+        let by = [String::from("site")];
+        let privacy = privacy(Mechanism::Laplace);
+        let plan = |aggs: &[Aggregation]| plan(&df, &by, aggs, &[], &privacy);
+        let err = plan(&[agg("age", AggKind::Max)]).unwrap_err();
+        assert!(
+            err.message().contains("only count, sum and mean"),
+            "{err:?}"
+        );
+        let err = plan(&[agg("site", AggKind::Count), agg("site", AggKind::Sum)]).unwrap_err();
+        assert!(err.message().contains("sets no bounds"), "{err:?}");
+
+        let aggs = [
+            agg("age", AggKind::Count),
+            agg("age", AggKind::Sum),
+            agg("age", AggKind::Mean),
+        ];
+        plan(&aggs).unwrap();
+        let (exprs, mut columns) = exprs(&aggs, &privacy).unwrap();
+        let mut result = df
+            .clone()
+            .lazy()
+            .groupby_stable([col("site")])
+            .agg(exprs)
+            .collect()
+            .unwrap();
+        take_counts(&mut result, &mut columns).unwrap();
+        assert_eq!(
+            result.get_column_names(),
+            ["site", "age_count", "age_sum", "age_mean"]
+        );
+        // 500 is clamped to 100.
+        let sums = result.column("age_sum").unwrap().f64().unwrap();
+        assert_eq!(sums.get(0), Some(130.0));
+        assert_eq!(columns[2].counts, [2.0, 1.0]);
+
+        let release = NoisyRelease {
+            columns,
+            ..NoisyRelease::new(privacy, vec![String::from("people")])
+        };
+        let mut noised = result.clone();
+        release
+            .apply(&mut noised, &mut StdRng::seed_from_u64(1))
+            .unwrap();
+        assert!(!noised.frame_equal(&result));
+        assert_eq!(
+            noised.column("age_count").unwrap().dtype(),
+            result.column("age_count").unwrap().dtype()
+        );
+        let means = noised.column("age_mean").unwrap().f64().unwrap();
+        assert!(means
+            .into_iter()
+            .flatten()
+            .all(|mean| (0.0..=100.0).contains(&mean)));
+
+        let budgets = PrivacyBudgets::default();
+        budgets.spend(&release, "analyst").unwrap();
+        budgets.spend(&release, "analyst").unwrap();
+        let err = budgets.spend(&release, "analyst").unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert!(err.message().contains("epsilon 0 of 1 remains"), "{err:?}");
+        assert_eq!(budgets.spent("people", "analyst"), 1.0);
+        budgets.spend(&release, "other").unwrap();
+
+        // Budgets survive a restart, and are not spent if they cannot be persisted.
+        let persisted = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&persisted);
+        budgets.persist_with(move |budgets: &[SpentBudget]| {
+            *sink.lock().unwrap() = budgets.to_vec();
+            Ok(())
+        });
+        budgets.spend(&release, "other").unwrap();
+        let restarted = PrivacyBudgets::default();
+        restarted.restore(persisted.lock().unwrap().clone());
+        restarted.persist_with(|_: &[SpentBudget]| Err(Status::internal("Disk full")));
+        assert_eq!(restarted.spent("people", "analyst"), 1.0);
+        assert_eq!(restarted.spent("people", "other"), 1.0);
+        let other = NoisyRelease::new(release.privacy.clone(), vec![String::from("visits")]);
+        let err = restarted.spend(&other, "analyst").unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        assert_eq!(restarted.spent("visits", "analyst"), 0.0);
+    }
+
+    #[test]
+    fn rules_merge_to_the_stricter() {
+        let mut wide = privacy(Mechanism::Gaussian { delta: 1e-5 });
+        wide.bounds.insert(
+            String::from("age"),
+            Bounds {
+                min: -10.0,
+                max: 90.0,
+            },
+        );
+        wide.epsilon = 0.8;
+        let merged = privacy(Mechanism::Gaussian { delta: 1e-6 }).merge(&wide);
+        assert_eq!(merged.mechanism, Mechanism::Gaussian { delta: 1e-6 });
+        assert_eq!(merged.epsilon, 0.5);
+        assert_eq!(
+            merged.bounds["age"],
+            Bounds {
+                min: 0.0,
+                max: 90.0
+            }
+        );
+        let merged = merged.merge(&privacy(Mechanism::Laplace));
+        assert_eq!(merged.mechanism, Mechanism::Laplace);
+        merged.check().unwrap();
+        let err = DifferentialPrivacy {
+            epsilon: 2.0,
+            ..wide
+        }
+        .check()
+        .unwrap_err();
+        assert!(err.message().contains("budget 1 is less"), "{err:?}");
+    }
+}
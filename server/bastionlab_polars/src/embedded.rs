@@ -0,0 +1,723 @@
+//! Embedded mode: all the persisted state of a server in a single file, for laptops and CI.
+//!
+//! The file starts with a header and is only appended to: every persisted artifact, deletion and
+//! version of the aliases, of the privacy budgets or of the watermarks is a record, checksummed so
+//! that a record torn by a crash is detected and dropped on the next start. Replaced and deleted
+//! artifacts leave garbage behind, reclaimed by rewriting the live records to a new file once
+//! garbage makes up more than the configured ratio.
+//!
+//! A lock file next to the store holds the PID of the process that opened it, so that two servers
+//! never append to the same file. Locks left by processes that are gone are taken over.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bastionlab_common::atomic_file;
+use ring::digest::{digest, SHA256};
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::aliases::Alias;
+use crate::differential_privacy::SpentBudget;
+use crate::persistence::{decode_artifact, encode_artifact, PersistenceSettings, RecompressReport};
+use crate::tenant_keys::{tenant_of, TenantKeyring};
+use crate::watermark::FetchRecord;
+use crate::DataFrameArtifact;
+
+/// Magic bytes of embedded stores, followed by the format version.
+const MAGIC: &[u8; 4] = b"BLEM";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8;
+
+/// Extension of embedded stores.
+pub const STORE_EXTENSION: &str = "bastionlab";
+
+/// Records start with the length of their payload and its SHA-256.
+const RECORD_HEADER_LEN: u64 = 8 + 32;
+
+/// Garbage below this size is never compacted, whatever its ratio.
+const MIN_COMPACTION_GARBAGE: u64 = 64 * 1024;
+
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+const ALIASES: u8 = 3;
+const BUDGETS: u8 = 4;
+const WATERMARKS: u8 = 5;
+
+fn io_err(e: std::io::Error) -> Status {
+    Status::internal(format!("Could not access the embedded store: {e}"))
+}
+
+fn corrupted(reason: &str) -> Status {
+    Status::data_loss(format!("Corrupted embedded store: {reason}"))
+}
+
+/// Where a record is in the file.
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    offset: u64,
+    /// Length of the whole record, header included.
+    len: u64,
+    /// Offset and length of the data it stores.
+    data: u64,
+    data_len: u64,
+}
+
+/// The live records of a store.
+#[derive(Default)]
+struct Index {
+    artifacts: HashMap<String, Extent>,
+    aliases: Option<Extent>,
+    budgets: Option<Extent>,
+    watermarks: Option<Extent>,
+    /// Bytes of the records that were replaced or deleted, deletions included.
+    garbage: u64,
+}
+
+impl Index {
+    /// Replays the records of `buf`, returning the index and the length of the valid prefix.
+    ///
+    /// A last record that is incomplete or whose checksum does not match was torn by a crash, and
+    /// is left out of the valid prefix. A corrupted record followed by others is an error.
+    fn replay(buf: &[u8]) -> Result<(Self, u64), Status> {
+        if buf.len() < HEADER_LEN as usize || &buf[..4] != MAGIC {
+            return Err(corrupted("missing header"));
+        }
+        let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(corrupted(&format!("unknown format version {version}")));
+        }
+
+        let mut index = Index::default();
+        let mut offset = HEADER_LEN as usize;
+        while offset < buf.len() {
+            let record = &buf[offset..];
+            let payload_len = match record.get(..8) {
+                Some(len) => u64::from_le_bytes(len.try_into().unwrap()) as usize,
+                None => break,
+            };
+            let payload = match record
+                .get(RECORD_HEADER_LEN as usize..)
+                .and_then(|rest| rest.get(..payload_len))
+            {
+                Some(payload) => payload,
+                None => break,
+            };
+            let len = RECORD_HEADER_LEN as usize + payload_len;
+            if digest(&SHA256, payload).as_ref() != &record[8..40] {
+                if offset + len == buf.len() {
+                    break;
+                }
+                return Err(corrupted(&format!("checksum mismatch at offset {offset}")));
+            }
+            let (kind, identifier, data) = parse_payload(payload)
+                .ok_or_else(|| corrupted(&format!("invalid record at offset {offset}")))?;
+            let extent = Extent {
+                offset: offset as u64,
+                len: len as u64,
+                data: (offset + len - data.len()) as u64,
+                data_len: data.len() as u64,
+            };
+            index.apply(kind, identifier, extent)?;
+            offset += len;
+        }
+        Ok((index, offset as u64))
+    }
+
+    fn apply(&mut self, kind: u8, identifier: String, extent: Extent) -> Result<(), Status> {
+        let replaced = match kind {
+            PUT => self.artifacts.insert(identifier, extent),
+            DELETE => {
+                self.garbage += extent.len;
+                self.artifacts.remove(&identifier)
+            }
+            ALIASES => self.aliases.replace(extent),
+            BUDGETS => self.budgets.replace(extent),
+            WATERMARKS => self.watermarks.replace(extent),
+            kind => return Err(corrupted(&format!("unknown record kind {kind}"))),
+        };
+        self.garbage += replaced.map_or(0, |replaced| replaced.len);
+        Ok(())
+    }
+}
+
+fn parse_payload(payload: &[u8]) -> Option<(u8, String, &[u8])> {
+    let kind = *payload.first()?;
+    let len = u32::from_le_bytes(payload.get(1..5)?.try_into().unwrap()) as usize;
+    let identifier = std::str::from_utf8(payload.get(5..5 + len)?).ok()?;
+    Some((kind, identifier.to_string(), &payload[5 + len..]))
+}
+
+fn encode_record(kind: u8, identifier: &str, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(5 + identifier.len() + data.len());
+    payload.push(kind);
+    payload.extend_from_slice(&(identifier.len() as u32).to_le_bytes());
+    payload.extend_from_slice(identifier.as_bytes());
+    payload.extend_from_slice(data);
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    record.extend_from_slice(digest(&SHA256, &payload).as_ref());
+    record.extend_from_slice(&payload);
+    record
+}
+
+struct State {
+    file: File,
+    len: u64,
+    index: Index,
+}
+
+pub struct EmbeddedStore {
+    path: PathBuf,
+    compaction_ratio: f64,
+    state: Mutex<State>,
+    _lock: StoreLock,
+}
+
+impl EmbeddedStore {
+    /// Opens the store at `path`, creating it if needed. Writes compact it once garbage makes up
+    /// more than `compaction_ratio` of its size.
+    pub fn open(path: &Path, compaction_ratio: f64) -> Result<Self, Status> {
+        let lock = StoreLock::acquire(path)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(io_err)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).map_err(io_err)?;
+        if buf.is_empty() {
+            buf.extend_from_slice(MAGIC);
+            buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+            file.write_all(&buf).map_err(io_err)?;
+            file.sync_all().map_err(io_err)?;
+        }
+
+        let (index, len) = Index::replay(&buf)?;
+        if len < buf.len() as u64 {
+            warn!(
+                "Dropped a torn record of {} bytes at the end of {}",
+                buf.len() as u64 - len,
+                path.display()
+            );
+            file.set_len(len).map_err(io_err)?;
+            file.sync_all().map_err(io_err)?;
+        }
+        Ok(EmbeddedStore {
+            path: path.to_path_buf(),
+            compaction_ratio,
+            state: Mutex::new(State { file, len, index }),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(state: &mut State, kind: u8, identifier: &str, data: &[u8]) -> Result<(), Status> {
+        let record = encode_record(kind, identifier, data);
+        let offset = state.len;
+        let written = state
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| state.file.write_all(&record))
+            .and_then(|_| state.file.sync_data());
+        if let Err(e) = written {
+            // Leaves no partial record behind for the next append to follow.
+            state.file.set_len(offset).unwrap_or(());
+            return Err(io_err(e));
+        }
+        let len = record.len() as u64;
+        state.len += len;
+        state.index.apply(
+            kind,
+            identifier.to_string(),
+            Extent {
+                offset,
+                len,
+                data: offset + len - data.len() as u64,
+                data_len: data.len() as u64,
+            },
+        )
+    }
+
+    fn read(state: &mut State, extent: Extent) -> Result<Vec<u8>, Status> {
+        let mut buf = vec![0; extent.data_len as usize];
+        state
+            .file
+            .seek(SeekFrom::Start(extent.data))
+            .and_then(|_| state.file.read_exact(&mut buf))
+            .map_err(io_err)?;
+        Ok(buf)
+    }
+
+    pub fn put(&self, identifier: &str, data: &[u8]) -> Result<(), Status> {
+        let mut state = self.state.lock().unwrap();
+        Self::append(&mut state, PUT, identifier, data)?;
+        self.compact_if_needed(&mut state)
+    }
+
+    /// Deletes `identifier`, returning whether it was stored.
+    pub fn remove(&self, identifier: &str) -> Result<bool, Status> {
+        let mut state = self.state.lock().unwrap();
+        if !state.index.artifacts.contains_key(identifier) {
+            return Ok(false);
+        }
+        Self::append(&mut state, DELETE, identifier, &[])?;
+        self.compact_if_needed(&mut state)?;
+        Ok(true)
+    }
+
+    pub fn get(&self, identifier: &str) -> Result<Option<Vec<u8>>, Status> {
+        let mut state = self.state.lock().unwrap();
+        match state.index.artifacts.get(identifier).copied() {
+            Some(extent) => Self::read(&mut state, extent).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, identifier: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.index.artifacts.contains_key(identifier)
+    }
+
+    /// Size of the record of `identifier`, in bytes, if it is stored.
+    pub fn stored_len(&self, identifier: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .index
+            .artifacts
+            .get(identifier)
+            .map(|extent| extent.data_len)
+    }
+
+    /// Identifiers of the stored artifacts, sorted.
+    pub fn identifiers(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut identifiers: Vec<String> = state.index.artifacts.keys().cloned().collect();
+        identifiers.sort();
+        identifiers
+    }
+
+    /// Size of the file, and how much of it is garbage.
+    pub fn usage(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.len, state.index.garbage)
+    }
+
+    fn compact_if_needed(&self, state: &mut State) -> Result<(), Status> {
+        let garbage = state.index.garbage;
+        if garbage >= MIN_COMPACTION_GARBAGE
+            && garbage as f64 > state.len as f64 * self.compaction_ratio
+        {
+            let before = state.len;
+            self.compact_locked(state)?;
+            info!(
+                "Compacted {}: {before} bytes before, {} bytes after",
+                self.path.display(),
+                state.len
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites the live records to a new file, returning the bytes reclaimed.
+    pub fn compact(&self) -> Result<u64, Status> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.len;
+        self.compact_locked(&mut state)?;
+        Ok(before - state.len)
+    }
+
+    fn compact_locked(&self, state: &mut State) -> Result<(), Status> {
+        let mut live: Vec<Extent> = state.index.artifacts.values().copied().collect();
+        live.extend(state.index.aliases);
+        live.extend(state.index.budgets);
+        live.extend(state.index.watermarks);
+        live.sort_by_key(|extent| extent.offset);
+
+        let tmp = atomic_file::temp_path(&self.path);
+        let mut out = File::create(&tmp).map_err(io_err)?;
+        let mut buf = Vec::from(&MAGIC[..]);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let mut moved = HashMap::new();
+        for extent in live {
+            let mut record = vec![0; extent.len as usize];
+            state
+                .file
+                .seek(SeekFrom::Start(extent.offset))
+                .and_then(|_| state.file.read_exact(&mut record))
+                .map_err(io_err)?;
+            moved.insert(extent.offset, buf.len() as u64);
+            buf.extend_from_slice(&record);
+        }
+        out.write_all(&buf)
+            .and_then(|_| out.sync_all())
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(io_err)?;
+
+        let relocate = |extent: &mut Extent| {
+            let offset = moved[&extent.offset];
+            extent.data = extent.data - extent.offset + offset;
+            extent.offset = offset;
+        };
+        state.index.artifacts.values_mut().for_each(relocate);
+        state.index.aliases.iter_mut().for_each(relocate);
+        state.index.budgets.iter_mut().for_each(relocate);
+        state.index.watermarks.iter_mut().for_each(relocate);
+        state.index.garbage = 0;
+        state.len = buf.len() as u64;
+        state.file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(io_err)?;
+        Ok(())
+    }
+
+    /// Stores an artifact, encrypted under the key of its tenant if `keys` is enabled, like
+    /// [`crate::persistence::store_artifact`] does in a directory.
+    pub fn store_artifact(
+        &self,
+        identifier: &str,
+        artifact: &DataFrameArtifact,
+        settings: &PersistenceSettings,
+        keys: &TenantKeyring,
+    ) -> Result<u64, Status> {
+        let buf = keys.seal(tenant_of(artifact), encode_artifact(artifact, settings)?)?;
+        self.put(identifier, &buf)?;
+        Ok(buf.len() as u64)
+    }
+
+    pub fn load_artifact(
+        &self,
+        identifier: &str,
+        keys: &TenantKeyring,
+    ) -> Result<DataFrameArtifact, Status> {
+        let buf = self
+            .get(identifier)?
+            .ok_or_else(|| Status::not_found(format!("{identifier} is not stored")))?;
+        decode_artifact(&keys.open(&buf)?)
+    }
+
+    pub fn store_aliases(&self, aliases: &HashMap<String, Alias>) -> Result<(), Status> {
+        let buf = serde_json::to_vec(aliases)
+            .map_err(|e| Status::internal(format!("Could not serialize the aliases: {e}")))?;
+        let mut state = self.state.lock().unwrap();
+        Self::append(&mut state, ALIASES, "", &buf)?;
+        self.compact_if_needed(&mut state)
+    }
+
+    pub fn load_aliases(&self) -> Result<HashMap<String, Alias>, Status> {
+        let mut state = self.state.lock().unwrap();
+        match state.index.aliases {
+            Some(extent) => serde_json::from_slice(&Self::read(&mut state, extent)?)
+                .map_err(|e| corrupted(&e.to_string())),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    pub fn store_budgets(&self, budgets: &[SpentBudget]) -> Result<(), Status> {
+        let buf = serde_json::to_vec(budgets).map_err(|e| {
+            Status::internal(format!("Could not serialize the privacy budgets: {e}"))
+        })?;
+        let mut state = self.state.lock().unwrap();
+        Self::append(&mut state, BUDGETS, "", &buf)?;
+        self.compact_if_needed(&mut state)
+    }
+
+    pub fn load_budgets(&self) -> Result<Vec<SpentBudget>, Status> {
+        let mut state = self.state.lock().unwrap();
+        match state.index.budgets {
+            Some(extent) => serde_json::from_slice(&Self::read(&mut state, extent)?)
+                .map_err(|e| corrupted(&e.to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn store_watermarks(&self, records: &[FetchRecord]) -> Result<(), Status> {
+        let buf = serde_json::to_vec(records)
+            .map_err(|e| Status::internal(format!("Could not serialize the watermarks: {e}")))?;
+        let mut state = self.state.lock().unwrap();
+        Self::append(&mut state, WATERMARKS, "", &buf)?;
+        self.compact_if_needed(&mut state)
+    }
+
+    pub fn load_watermarks(&self) -> Result<Vec<FetchRecord>, Status> {
+        let mut state = self.state.lock().unwrap();
+        match state.index.watermarks {
+            Some(extent) => serde_json::from_slice(&Self::read(&mut state, extent)?)
+                .map_err(|e| corrupted(&e.to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Rewrites every stored artifact under `settings`, then compacts the store.
+    pub fn recompress(
+        &self,
+        settings: &PersistenceSettings,
+        keys: &TenantKeyring,
+    ) -> Result<RecompressReport, Status> {
+        let mut report = RecompressReport::default();
+        for identifier in self.identifiers() {
+            let before = self.get(&identifier)?.map_or(0, |buf| buf.len() as u64);
+            let artifact = self.load_artifact(&identifier, keys)?;
+            report.bytes_after += self.store_artifact(&identifier, &artifact, settings, keys)?;
+            report.bytes_before += before;
+            report.rewritten.push(identifier);
+        }
+        self.compact()?;
+        Ok(report)
+    }
+}
+
+/// The lock file of the store at `path`.
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+/// Held while a store is open, see the module documentation.
+struct StoreLock {
+    path: PathBuf,
+}
+
+impl StoreLock {
+    fn acquire(store: &Path) -> Result<Self, Status> {
+        let path = lock_path(store);
+        // A stale lock is removed once, after which another process taking it wins.
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())
+                        .and_then(|_| file.sync_all())
+                        .map_err(io_err)?;
+                    return Ok(StoreLock { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<u32>().ok());
+                    match owner {
+                        Some(pid) if process_alive(pid) => {
+                            return Err(Status::failed_precondition(format!(
+                                "{} is already open in process {pid}",
+                                store.display()
+                            )))
+                        }
+                        _ => {
+                            warn!("Taking over the stale lock {}", path.display());
+                            fs::remove_file(&path).map_err(io_err)?;
+                        }
+                    }
+                }
+                Err(e) => return Err(io_err(e)),
+            }
+        }
+        Err(Status::failed_precondition(format!(
+            "Could not lock {}",
+            store.display()
+        )))
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove lock {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Whether process `pid` still runs. Without procfs, every process is assumed to.
+fn process_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    let proc = Path::new("/proc");
+    !proc.exists() || proc.join(pid.to_string()).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use std::sync::Arc;
+
+    fn store_path() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bastionlab-embedded-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(format!("state.{STORE_EXTENSION}"))
+    }
+
+    #[test]
+    fn torn_final_records_are_dropped() {
+        let path = store_path();
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        store.put("a", b"first").unwrap();
+        store.put("b", b"second").unwrap();
+        let (len, _) = store.usage();
+        drop(store);
+
+        // A crash in the middle of the last append.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        assert_eq!(store.identifiers(), vec!["a"]);
+        assert_eq!(store.get("a").unwrap().unwrap(), b"first");
+        store.put("c", b"third").unwrap();
+        drop(store);
+
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        assert_eq!(store.identifiers(), vec!["a", "c"]);
+        assert_eq!(store.get("c").unwrap().unwrap(), b"third");
+        let (len, _) = store.usage();
+        drop(store);
+
+        // Corruption before the last record is not a crash, and is reported.
+        let mut buf = fs::read(&path).unwrap();
+        buf[HEADER_LEN as usize + RECORD_HEADER_LEN as usize + 6] ^= 1;
+        assert_eq!(buf.len() as u64, len);
+        fs::write(&path, buf).unwrap();
+        let err = EmbeddedStore::open(&path, 0.5).err().unwrap();
+        assert_eq!(err.code(), tonic::Code::DataLoss);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stores_are_locked_by_their_process() {
+        let path = store_path();
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        let err = EmbeddedStore::open(&path, 0.5).err().unwrap();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        drop(store);
+        assert!(!lock_path(&path).exists());
+
+        // Another process holds the lock while it runs.
+        let mut other = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        fs::write(lock_path(&path), other.id().to_string()).unwrap();
+        let err = EmbeddedStore::open(&path, 0.5).err().unwrap();
+        assert!(
+            err.message().contains(&other.id().to_string()),
+            "{}",
+            err.message()
+        );
+
+        other.kill().unwrap();
+        other.wait().unwrap();
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        assert_eq!(
+            fs::read_to_string(lock_path(&path)).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(store);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn compaction_reclaims_deleted_artifacts() {
+        let path = store_path();
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        let budgets = vec![SpentBudget {
+            source: String::from("d"),
+            identity: String::from("analyst"),
+            spent: 0.5,
+        }];
+        store.store_budgets(&budgets).unwrap();
+        let watermarks = vec![FetchRecord {
+            fetch_id: String::from("f"),
+            recipient: String::from("analyst"),
+            dataset: String::from("d"),
+            columns: vec![String::from("income")],
+            epsilon: 0.01,
+            time: 0,
+        }];
+        store.store_watermarks(&watermarks).unwrap();
+        let blob = vec![7u8; 100 * 1024];
+        for identifier in ["a", "b", "c", "d", "e"] {
+            store.put(identifier, &blob).unwrap();
+        }
+        store.remove("a").unwrap();
+        store.remove("b").unwrap();
+        let (len, garbage) = store.usage();
+        assert!(garbage > 0 && garbage < len / 2);
+
+        // Past half of the file, the next write compacts it.
+        store.remove("c").unwrap();
+        let (len, garbage) = store.usage();
+        assert_eq!(garbage, 0);
+        assert!(len < blob.len() as u64 * 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        drop(store);
+
+        let store = EmbeddedStore::open(&path, 0.5).unwrap();
+        assert_eq!(store.identifiers(), vec!["d", "e"]);
+        assert_eq!(store.get("d").unwrap().unwrap(), blob);
+        assert_eq!(store.load_budgets().unwrap(), budgets);
+        assert_eq!(store.load_watermarks().unwrap(), watermarks);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn servers_keep_their_state_in_the_store() {
+        let path = store_path();
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        let server = || {
+            BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+                .with_embedded_store(EmbeddedStore::open(&path, 0.5).unwrap())
+        };
+
+        let polars = server();
+        let policy: Policy = serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "TrueRule"},
+            "unsafe_handling": {"type": "Log"},
+            "savable": true,
+        }))
+        .unwrap();
+        let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+        let kept = polars.insert_df(DataFrameArtifact::new(df.clone(), policy.clone(), vec![]));
+        let deleted = polars.insert_df(DataFrameArtifact::new(df.clone(), policy, vec![]));
+        polars.persist_df(&kept).unwrap();
+        polars.persist_df(&deleted).unwrap();
+        polars.delete_dfs(&deleted).unwrap();
+        drop(polars);
+
+        let polars = server();
+        polars.load_dfs().unwrap();
+        let loaded = polars
+            .with_df_artifact_ref(&kept, |artifact| artifact.dataframe.clone())
+            .unwrap();
+        assert!(loaded.frame_equal(&df));
+        assert!(polars.with_df_artifact_ref(&deleted, |_| ()).is_err());
+        drop(polars);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
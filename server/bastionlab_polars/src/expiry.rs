@@ -0,0 +1,140 @@
+//! Time-to-live of results: results unused for longer than `dataframe_ttl_secs` are deleted.
+//!
+//! Every `run_query` leaves a result behind, which long-running servers would otherwise keep
+//! forever. A background task sweeps the dataframes every `dataframe_ttl_sweep_secs` and deletes
+//! the results last used longer than the TTL ago. Fetches and queries reading a result use it,
+//! which refreshes its TTL, so that active work is not deleted mid-session. Uploads are never
+//! deleted, nor are the results something else depends on: those persisted, exported, retained
+//! by a workspace, family members, view bases or alias targets, as under memory pressure.
+//!
+//! Uses are kept in memory: on restart, results count as used when the server started. Expired
+//! identifiers are remembered, the last [`MAX_EXPIRED`] ones, for requests on them to fail with a
+//! `not_found` telling that they expired rather than that they never existed.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use tonic::Status;
+
+/// Expired identifiers remembered, oldest first to go.
+pub const MAX_EXPIRED: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct Expired {
+    /// Milliseconds since the Unix epoch the results expired at, by identifier.
+    at: HashMap<String, u64>,
+    order: VecDeque<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Expiry {
+    /// Results are kept forever if unset.
+    ttl: Option<Duration>,
+    started_at: u64,
+    /// Milliseconds since the Unix epoch results were last used at, by identifier.
+    used: RwLock<HashMap<String, u64>>,
+    expired: Mutex<Expired>,
+}
+
+impl Expiry {
+    pub fn new(ttl: Option<Duration>, now: u64) -> Self {
+        Expiry {
+            ttl,
+            started_at: now,
+            ..Default::default()
+        }
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Records a use of `identifier`, which refreshes its TTL.
+    pub fn used(&self, identifier: &str, now: u64) {
+        if self.ttl.is_some() {
+            self.used
+                .write()
+                .unwrap()
+                .insert(identifier.to_string(), now);
+        }
+    }
+
+    /// Whether `identifier`, created at `created_at`, went unused for longer than the TTL.
+    pub fn is_expired(&self, identifier: &str, created_at: u64, now: u64) -> bool {
+        let Some(ttl) = self.ttl else {
+            return false;
+        };
+        let used = self.used.read().unwrap().get(identifier).copied();
+        let last_used = used.unwrap_or_else(|| created_at.max(self.started_at));
+        now.saturating_sub(last_used) > ttl.as_millis() as u64
+    }
+
+    /// Records that `identifier` was deleted as expired.
+    pub fn expired(&self, identifier: &str, now: u64) {
+        self.forget(identifier);
+        let mut expired = self.expired.lock().unwrap();
+        if expired.at.insert(identifier.to_string(), now).is_none() {
+            expired.order.push_back(identifier.to_string());
+        }
+        while expired.order.len() > MAX_EXPIRED {
+            if let Some(oldest) = expired.order.pop_front() {
+                expired.at.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forgets the uses of `identifier`, once deleted.
+    pub fn forget(&self, identifier: &str) {
+        self.used.write().unwrap().remove(identifier);
+    }
+
+    /// Fails with a `not_found` telling so if `identifier`, which is not a dataframe, expired.
+    pub fn check(&self, identifier: &str) -> Result<(), Status> {
+        let expired = self.expired.lock().unwrap();
+        match (expired.at.get(identifier), self.ttl) {
+            (Some(at), Some(ttl)) => Err(Status::not_found(format!(
+                "Dataframe {identifier} expired at {at}, after going unused for more than {}s: \
+                 run its query again",
+                ttl.as_secs()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn uses_refresh_the_ttl_and_expired_identifiers_are_remembered() {
+        let expiry = Expiry::new(Some(Duration::from_secs(10)), 5_000);
+        // Created before the server started: counts from the start.
+        assert!(!expiry.is_expired("restored", 0, 15_000));
+        assert!(expiry.is_expired("restored", 0, 15_001));
+
+        expiry.used("result", 20_000);
+        expiry.used("result", 28_000);
+        assert!(!expiry.is_expired("result", 20_000, 38_000));
+        assert!(expiry.is_expired("result", 20_000, 38_001));
+        expiry.check("result").unwrap();
+
+        expiry.expired("result", 38_001);
+        let err = expiry.check("result").unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        assert!(err.message().contains("expired at 38001"));
+        expiry.check("unknown").unwrap();
+
+        for i in 0..MAX_EXPIRED {
+            expiry.expired(&format!("r{i}"), 40_000);
+        }
+        expiry.check("result").unwrap();
+        assert!(expiry.check("r0").is_err());
+
+        let forever = Expiry::new(None, 0);
+        forever.used("result", 0);
+        assert!(!forever.is_expired("result", 0, u64::MAX));
+    }
+}
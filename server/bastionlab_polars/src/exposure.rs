@@ -0,0 +1,239 @@
+//! How much the statistics of each column disclose in metadata responses, such as headers.
+//!
+//! Statistics leak too: the exact bounds of a salary column, or a distinct count of 1 revealing a
+//! constant. Policies set the exposure of columns, see [`Exposure`]: columns they leave out are
+//! fully exposed. The owners of uploads always see their full statistics, while results take the
+//! exposures of the policies they inherit, whoever computed them.
+//!
+//! [`expose`] is the only way cached statistics make it into responses: the conversion to their
+//! protobuf message is private to this module, so that endpoints cannot bypass the policy.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::polars_proto;
+use crate::statistics::ColumnStatistics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Exposure {
+    Full,
+    /// Bounds rounded outwards to multiples of `precision`, and distinct counts below
+    /// `distinct_floor` reported as below it.
+    Rounded {
+        precision: f64,
+        #[serde(default)]
+        distinct_floor: u64,
+    },
+    /// Null and distinct counts only, distinct counts below `distinct_floor` reported as below
+    /// it.
+    CountsOnly {
+        #[serde(default)]
+        distinct_floor: u64,
+    },
+    /// No statistics at all.
+    Hidden,
+}
+
+impl Exposure {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exposure::Full => "full",
+            Exposure::Rounded { .. } => "rounded",
+            Exposure::CountsOnly { .. } => "counts_only",
+            Exposure::Hidden => "hidden",
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Exposure::Full => 0,
+            Exposure::Rounded { .. } => 1,
+            Exposure::CountsOnly { .. } => 2,
+            Exposure::Hidden => 3,
+        }
+    }
+
+    /// The stricter of both exposures.
+    pub fn merge(&self, other: &Exposure) -> Exposure {
+        match (self, other) {
+            (
+                Exposure::Rounded {
+                    precision: a,
+                    distinct_floor: floor_a,
+                },
+                Exposure::Rounded {
+                    precision: b,
+                    distinct_floor: floor_b,
+                },
+            ) => Exposure::Rounded {
+                precision: a.max(*b),
+                distinct_floor: *floor_a.max(floor_b),
+            },
+            (
+                Exposure::CountsOnly {
+                    distinct_floor: floor_a,
+                },
+                Exposure::CountsOnly {
+                    distinct_floor: floor_b,
+                },
+            ) => Exposure::CountsOnly {
+                distinct_floor: *floor_a.max(floor_b),
+            },
+            (a, b) if a.rank() >= b.rank() => *a,
+            (_, b) => *b,
+        }
+    }
+}
+
+/// The exposures of columns, by column.
+pub type ColumnExposures = BTreeMap<String, Exposure>;
+
+pub fn merge_exposures(a: &ColumnExposures, b: &ColumnExposures) -> ColumnExposures {
+    let mut merged = a.clone();
+    for (column, exposure) in b.iter() {
+        let exposure = match merged.get(column) {
+            Some(mine) => mine.merge(exposure),
+            None => *exposure,
+        };
+        merged.insert(column.clone(), exposure);
+    }
+    merged
+}
+
+pub fn check(exposures: &ColumnExposures) -> Result<(), Status> {
+    for (column, exposure) in exposures.iter() {
+        if let Exposure::Rounded { precision, .. } = exposure {
+            if !(precision.is_finite() && *precision > 0.0) {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid metadata exposure of column {column}: the precision must be positive, \
+                     not {precision}"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The statistics of a column as a response discloses them, `None` if hidden.
+fn to_proto(
+    column: String,
+    statistics: &ColumnStatistics,
+    stale: bool,
+    exposure: &Exposure,
+) -> Option<polars_proto::ColumnStatistics> {
+    let mut exposed = polars_proto::ColumnStatistics {
+        column,
+        nulls: statistics.nulls,
+        min: statistics.min,
+        max: statistics.max,
+        distinct: statistics.distinct.estimate(),
+        stale,
+        refreshed_at: statistics.refreshed_at,
+        distinct_below: false,
+        exposure: exposure.name().to_string(),
+    };
+    let distinct_floor = match *exposure {
+        Exposure::Full => 0,
+        Exposure::Rounded {
+            precision,
+            distinct_floor,
+        } => {
+            exposed.min = exposed.min.map(|min| (min / precision).floor() * precision);
+            exposed.max = exposed.max.map(|max| (max / precision).ceil() * precision);
+            distinct_floor
+        }
+        Exposure::CountsOnly { distinct_floor } => {
+            exposed.min = None;
+            exposed.max = None;
+            distinct_floor
+        }
+        Exposure::Hidden => return None,
+    };
+    if exposed.distinct < distinct_floor {
+        exposed.distinct = distinct_floor;
+        exposed.distinct_below = true;
+    }
+    Some(exposed)
+}
+
+/// The statistics a response discloses to a requester, given the `exposures` of the policy of
+/// the dataframe they describe. `owner` tells whether the requester uploaded it.
+pub fn expose(
+    statistics: Vec<(String, ColumnStatistics, bool)>,
+    exposures: &ColumnExposures,
+    owner: bool,
+) -> Vec<polars_proto::ColumnStatistics> {
+    statistics
+        .into_iter()
+        .filter_map(|(column, statistics, stale)| {
+            let exposure = match owner {
+                true => Exposure::Full,
+                false => exposures.get(&column).copied().unwrap_or(Exposure::Full),
+            };
+            to_proto(column, &statistics, stale, &exposure)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::prelude::*;
+
+    #[test]
+    fn exposures_generalize_statistics() {
+        let series = Series::new("salary", [41_250.0f64, 41_250.0, 97_830.5]);
+        let statistics = ColumnStatistics::compute(&series, 0).unwrap();
+        let exposed = |exposure: Exposure, owner: bool| {
+            let exposures = ColumnExposures::from([(String::from("salary"), exposure)]);
+            let statistics = vec![(String::from("salary"), statistics.clone(), false)];
+            expose(statistics, &exposures, owner).pop()
+        };
+
+        let full = exposed(Exposure::Full, false).unwrap();
+        assert_eq!(
+            (full.min, full.max, full.distinct),
+            (Some(41_250.0), Some(97_830.5), 2)
+        );
+        let rounded = Exposure::Rounded {
+            precision: 10_000.0,
+            distinct_floor: 5,
+        };
+        let generalized = exposed(rounded, false).unwrap();
+        assert_eq!(generalized.min, Some(40_000.0));
+        assert_eq!(generalized.max, Some(100_000.0));
+        assert_eq!(
+            (generalized.distinct, generalized.distinct_below),
+            (5, true)
+        );
+        assert_eq!(generalized.exposure, "rounded");
+        let counts = exposed(Exposure::CountsOnly { distinct_floor: 0 }, false).unwrap();
+        assert_eq!((counts.min, counts.max, counts.distinct), (None, None, 2));
+        assert!(exposed(Exposure::Hidden, false).is_none());
+        assert_eq!(exposed(Exposure::Hidden, true).unwrap(), full);
+
+        let merged = rounded.merge(&Exposure::Rounded {
+            precision: 100.0,
+            distinct_floor: 10,
+        });
+        assert_eq!(
+            merged,
+            Exposure::Rounded {
+                precision: 10_000.0,
+                distinct_floor: 10
+            }
+        );
+        assert_eq!(rounded.merge(&Exposure::Hidden), Exposure::Hidden);
+        assert!(check(&ColumnExposures::from([(
+            String::from("salary"),
+            Exposure::Rounded {
+                precision: 0.0,
+                distinct_floor: 0
+            }
+        )]))
+        .is_err());
+    }
+}
@@ -0,0 +1,703 @@
+//! Dataset families: dataframes partitioned by hand, e.g. one upload per month, that can be
+//! queried as a whole.
+//!
+//! The owner registers a family with a [`PartitionKey`] and the partition value of each member.
+//! Queries read a family through a [`PartitionPredicate`]: only the members whose partition
+//! matches are scanned, the others are pruned from the plan.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::RwLock;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::DataFrameArtifact;
+
+/// How the partition of a row is derived from its value in `column`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PartitionTransform {
+    /// The value itself, for integer and string columns.
+    #[default]
+    Identity,
+    /// The year of a date or datetime column, e.g. `2023`.
+    Year,
+    /// The month of a date or datetime column, e.g. `"2023-01"`.
+    Month,
+    /// The day of a date or datetime column, e.g. `"2023-01-31"`.
+    Day,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartitionKey {
+    pub column: String,
+    #[serde(default)]
+    pub transform: PartitionTransform,
+}
+
+/// The partition of a member. Months and days are zero-padded so that they sort in order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PartitionValue {
+    Int(i64),
+    Str(String),
+}
+
+impl fmt::Display for PartitionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionValue::Int(v) => write!(f, "{v}"),
+            PartitionValue::Str(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+/// Selects the members of a family by their partition value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PartitionPredicate {
+    /// Partitions between `from` and `to`, both included. An unset bound is unbounded.
+    Range {
+        #[serde(default)]
+        from: Option<PartitionValue>,
+        #[serde(default)]
+        to: Option<PartitionValue>,
+    },
+    /// Partitions equal to one of `values`.
+    In { values: Vec<PartitionValue> },
+}
+
+impl PartitionPredicate {
+    fn values(&self) -> Vec<&PartitionValue> {
+        match self {
+            PartitionPredicate::Range { from, to } => from.iter().chain(to.iter()).collect(),
+            PartitionPredicate::In { values } => values.iter().collect(),
+        }
+    }
+
+    fn matches(&self, value: &PartitionValue) -> bool {
+        match self {
+            PartitionPredicate::Range { from, to } => {
+                from.as_ref().is_none_or(|from| value >= from)
+                    && to.as_ref().is_none_or(|to| value <= to)
+            }
+            PartitionPredicate::In { values } => values.contains(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Family {
+    key: PartitionKey,
+    /// Declared schema shared by all members, set by the first one.
+    schema: Option<Schema>,
+    members: BTreeMap<String, PartitionValue>,
+}
+
+/// A family and its members, as returned to the owner.
+#[derive(Debug, Clone)]
+pub struct FamilyInfo {
+    pub name: String,
+    pub key: PartitionKey,
+    pub schema: Option<Schema>,
+    pub members: Vec<(String, PartitionValue)>,
+}
+
+/// The members a predicate selects in a family, in partition order.
+#[derive(Debug, Clone)]
+pub struct FamilyScan {
+    pub family: String,
+    pub schema: Schema,
+    pub scanned: Vec<(String, PartitionValue)>,
+    pub pruned: Vec<(String, PartitionValue)>,
+}
+
+impl fmt::Display for FamilyScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |members: &[(String, PartitionValue)]| {
+            members
+                .iter()
+                .map(|(identifier, value)| format!("{value} ({identifier})"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(
+            f,
+            "Family {}: scanned partitions [{}], pruned partitions [{}]",
+            self.family,
+            list(&self.scanned),
+            list(&self.pruned)
+        )
+    }
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::invalid_argument(format!("Could not compute partition values: {e}"))
+}
+
+fn is_integer(dtype: &DataType) -> bool {
+    matches!(
+        dtype,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+    )
+}
+
+impl PartitionKey {
+    /// Checks that the key can be computed on `schema`.
+    fn check(&self, schema: &Schema) -> Result<(), Status> {
+        let dtype = schema.get(&self.column).ok_or_else(|| {
+            Status::invalid_argument(format!("Partition column {} is missing", self.column))
+        })?;
+        let supported = match self.transform {
+            PartitionTransform::Identity => is_integer(dtype) || dtype == &DataType::Utf8,
+            _ => matches!(dtype, DataType::Date | DataType::Datetime(..)),
+        };
+        if !supported {
+            return Err(Status::invalid_argument(format!(
+                "Partition transform {:?} does not apply to column {} of dtype {dtype}",
+                self.transform, self.column
+            )));
+        }
+        Ok(())
+    }
+
+    fn kind_matches(&self, value: &PartitionValue) -> bool {
+        match value {
+            PartitionValue::Int(_) => matches!(
+                self.transform,
+                PartitionTransform::Identity | PartitionTransform::Year
+            ),
+            PartitionValue::Str(_) => self.transform != PartitionTransform::Year,
+        }
+    }
+
+    /// Distinct partition values of the rows of `df`.
+    fn values(&self, df: &DataFrame) -> Result<Vec<PartitionValue>, Status> {
+        let series = df.column(&self.column).map_err(polars_err)?;
+        if series.null_count() > 0 {
+            return Err(Status::invalid_argument(format!(
+                "Partition column {} must not contain nulls",
+                self.column
+            )));
+        }
+        let format = match self.transform {
+            PartitionTransform::Identity if is_integer(series.dtype()) => {
+                let values = series.cast(&DataType::Int64).map_err(polars_err)?;
+                let values = values.unique().map_err(polars_err)?;
+                return Ok(values
+                    .i64()
+                    .map_err(polars_err)?
+                    .into_no_null_iter()
+                    .map(PartitionValue::Int)
+                    .collect());
+            }
+            PartitionTransform::Identity => None,
+            PartitionTransform::Year => Some("%Y"),
+            PartitionTransform::Month => Some("%Y-%m"),
+            PartitionTransform::Day => Some("%Y-%m-%d"),
+        };
+        let strings = match (format, series.dtype()) {
+            (None, _) => series.clone(),
+            (Some(fmt), DataType::Date) => series
+                .date()
+                .map_err(polars_err)?
+                .strftime(fmt)
+                .into_series(),
+            (Some(fmt), _) => series
+                .datetime()
+                .map_err(polars_err)?
+                .strftime(fmt)
+                .into_series(),
+        };
+        let strings = strings.unique().map_err(polars_err)?;
+        let strings = strings.utf8().map_err(polars_err)?.into_no_null_iter();
+        if self.transform == PartitionTransform::Year {
+            strings
+                .map(|year| {
+                    year.parse()
+                        .map(PartitionValue::Int)
+                        .map_err(|e| Status::internal(format!("Could not parse year {year}: {e}")))
+                })
+                .collect()
+        } else {
+            Ok(strings
+                .map(|s| PartitionValue::Str(s.to_string()))
+                .collect())
+        }
+    }
+
+    /// Checks that all the rows of `df` belong to partition `value`.
+    fn check_rows(&self, df: &DataFrame, value: &PartitionValue) -> Result<(), Status> {
+        let outside: Vec<_> = self
+            .values(df)?
+            .into_iter()
+            .filter(|v| v != value)
+            .map(|v| v.to_string())
+            .collect();
+        if !outside.is_empty() {
+            return Err(Status::invalid_argument(format!(
+                "Rows of partition {value} have partition values {}",
+                outside.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Family {
+    fn info(&self, name: &str) -> FamilyInfo {
+        FamilyInfo {
+            name: name.to_string(),
+            key: self.key.clone(),
+            schema: self.schema.clone(),
+            members: self
+                .members
+                .iter()
+                .map(|(identifier, value)| (identifier.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Validates and adds `members`, all of them or none.
+    fn add(
+        &mut self,
+        name: &str,
+        members: Vec<(String, PartitionValue)>,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<(), Status> {
+        let mut schema = self.schema.clone();
+        for (identifier, value) in members.iter() {
+            if self.members.contains_key(identifier) {
+                return Err(Status::already_exists(format!(
+                    "Dataframe {identifier} already is a member of family {name}"
+                )));
+            }
+            if !self.key.kind_matches(value) {
+                return Err(Status::invalid_argument(format!(
+                    "Partition value {value} of {identifier} does not match the {:?} transform",
+                    self.key.transform
+                )));
+            }
+            let artifact = dfs.get(identifier).ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?;
+            let declared = artifact.declared_schema();
+            match &schema {
+                Some(schema) if schema != &declared => {
+                    return Err(Status::invalid_argument(format!(
+                        "Dataframe {identifier} does not have the schema of family {name}: expected {schema:?}, got {declared:?}"
+                    )))
+                }
+                Some(_) => (),
+                None => {
+                    self.key.check(&declared)?;
+                    schema = Some(declared);
+                }
+            }
+            self.key
+                .check_rows(&artifact.dataframe, value)
+                .map_err(|e| {
+                    Status::invalid_argument(format!("Dataframe {identifier}: {}", e.message()))
+                })?;
+        }
+        self.schema = schema;
+        self.members.extend(members);
+        Ok(())
+    }
+}
+
+/// The registered dataset families.
+///
+/// Callers that also lock the dataframes must lock them first.
+#[derive(Debug, Default)]
+pub struct FamilyRegistry {
+    families: RwLock<HashMap<String, Family>>,
+}
+
+impl FamilyRegistry {
+    pub fn register(
+        &self,
+        name: &str,
+        key: PartitionKey,
+        members: Vec<(String, PartitionValue)>,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<FamilyInfo, Status> {
+        let mut families = self.families.write().unwrap();
+        if families.contains_key(name) {
+            return Err(Status::already_exists(format!(
+                "Family {name} is already registered"
+            )));
+        }
+        let mut family = Family {
+            key,
+            schema: None,
+            members: BTreeMap::new(),
+        };
+        family.add(name, members, dfs)?;
+        let info = family.info(name);
+        families.insert(name.to_string(), family);
+        Ok(info)
+    }
+
+    pub fn add_members(
+        &self,
+        name: &str,
+        members: Vec<(String, PartitionValue)>,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<FamilyInfo, Status> {
+        let mut families = self.families.write().unwrap();
+        let family = families.get_mut(name).ok_or_else(|| not_found(name))?;
+        family.add(name, members, dfs)?;
+        Ok(family.info(name))
+    }
+
+    /// Removes `identifiers` from a family, all of them or none.
+    pub fn remove_members(&self, name: &str, identifiers: &[String]) -> Result<FamilyInfo, Status> {
+        let mut families = self.families.write().unwrap();
+        let family = families.get_mut(name).ok_or_else(|| not_found(name))?;
+        if let Some(missing) = identifiers
+            .iter()
+            .find(|identifier| !family.members.contains_key(*identifier))
+        {
+            return Err(Status::not_found(format!(
+                "Dataframe {missing} is not a member of family {name}"
+            )));
+        }
+        for identifier in identifiers {
+            family.members.remove(identifier);
+        }
+        Ok(family.info(name))
+    }
+
+    pub fn family(&self, name: &str) -> Result<FamilyInfo, Status> {
+        let families = self.families.read().unwrap();
+        Ok(families
+            .get(name)
+            .ok_or_else(|| not_found(name))?
+            .info(name))
+    }
+
+    /// Splits the members of a family between those `predicate` selects and those it prunes.
+    pub fn prune(&self, name: &str, predicate: &PartitionPredicate) -> Result<FamilyScan, Status> {
+        let families = self.families.read().unwrap();
+        let family = families.get(name).ok_or_else(|| not_found(name))?;
+        if let Some(value) = predicate
+            .values()
+            .into_iter()
+            .find(|value| !family.key.kind_matches(value))
+        {
+            return Err(Status::invalid_argument(format!(
+                "Partition value {value} does not match the {:?} transform of family {name}",
+                family.key.transform
+            )));
+        }
+        let schema = family
+            .schema
+            .clone()
+            .ok_or_else(|| Status::failed_precondition(format!("Family {name} has no members")))?;
+
+        let mut members: Vec<_> = family
+            .members
+            .iter()
+            .map(|(identifier, value)| (identifier.clone(), value.clone()))
+            .collect();
+        members.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        let (scanned, pruned) = members
+            .into_iter()
+            .partition(|(_, value)| predicate.matches(value));
+        Ok(FamilyScan {
+            family: name.to_string(),
+            schema,
+            scanned,
+            pruned,
+        })
+    }
+
+    fn membership(&self, identifier: &str) -> Option<(String, PartitionKey, PartitionValue)> {
+        let families = self.families.read().unwrap();
+        families.iter().find_map(|(name, family)| {
+            family
+                .members
+                .get(identifier)
+                .map(|value| (name.clone(), family.key.clone(), value.clone()))
+        })
+    }
+
+    /// Checks that new rows of `identifier` stay in its partition, if it is a family member.
+    pub fn check_rows(&self, identifier: &str, df: &DataFrame) -> Result<(), Status> {
+        match self.membership(identifier) {
+            Some((name, key, value)) => key.check_rows(df, &value).map_err(|e| {
+                Status::invalid_argument(format!(
+                    "Dataframe {identifier} is a member of family {name}: {}",
+                    e.message()
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_not_member(&self, identifier: &str) -> Result<(), Status> {
+        match self.membership(identifier) {
+            Some((name, ..)) => Err(Status::failed_precondition(format!(
+                "Dataframe {identifier} is a member of family {name}: it must be removed from it first"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn not_found(name: &str) -> Status {
+    Status::not_found(format!("Could not find family: name={name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::composite_plan::{CompositePlan, CompositePlanSegment};
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use std::sync::Arc;
+
+    fn polars() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn month(year: i32, month: u32) -> DataFrame {
+        let days: Utf8Chunked = [1, 15, 28]
+            .iter()
+            .map(|day| Some(format!("{year}-{month:02}-{day:02}")))
+            .collect();
+        let mut dates = days.as_date(Some("%Y-%m-%d")).unwrap().into_series();
+        dates.rename("date");
+        DataFrame::new(vec![dates, Series::new("events", [1i64, 2, 3])]).unwrap()
+    }
+
+    fn artifact(df: DataFrame) -> DataFrameArtifact {
+        DataFrameArtifact::new(df, Policy::allow_by_default(), Vec::new())
+    }
+
+    fn month_key() -> PartitionKey {
+        PartitionKey {
+            column: String::from("date"),
+            transform: PartitionTransform::Month,
+        }
+    }
+
+    fn str_value(s: &str) -> PartitionValue {
+        PartitionValue::Str(s.to_string())
+    }
+
+    /// A family of the twelve months of 2023, members being named after their month.
+    fn year_of_events() -> (FamilyRegistry, HashMap<String, DataFrameArtifact>) {
+        let mut dfs = HashMap::new();
+        let mut members = Vec::new();
+        for m in 1..=12 {
+            let identifier = format!("events_2023_{m:02}");
+            dfs.insert(identifier.clone(), artifact(month(2023, m)));
+            members.push((identifier, str_value(&format!("2023-{m:02}"))));
+        }
+        let registry = FamilyRegistry::default();
+        registry
+            .register("events", month_key(), members, &dfs)
+            .unwrap();
+        (registry, dfs)
+    }
+
+    fn scanned(scan: &FamilyScan) -> Vec<&str> {
+        scan.scanned.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn range_predicates_prune_members() {
+        let (registry, _) = year_of_events();
+        let scan = registry
+            .prune(
+                "events",
+                &PartitionPredicate::Range {
+                    from: Some(str_value("2023-10")),
+                    to: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            scanned(&scan),
+            ["events_2023_10", "events_2023_11", "events_2023_12"]
+        );
+        assert_eq!(scan.pruned.len(), 9);
+
+        let scan = registry
+            .prune(
+                "events",
+                &PartitionPredicate::Range {
+                    from: Some(str_value("2023-02")),
+                    to: Some(str_value("2023-03")),
+                },
+            )
+            .unwrap();
+        assert_eq!(scanned(&scan), ["events_2023_02", "events_2023_03"]);
+        assert!(scan
+            .to_string()
+            .contains("pruned partitions [2023-01 (events_2023_01), 2023-04"));
+
+        let scan = registry
+            .prune(
+                "events",
+                &PartitionPredicate::Range {
+                    from: Some(str_value("2024-01")),
+                    to: None,
+                },
+            )
+            .unwrap();
+        assert!(scan.scanned.is_empty());
+        assert_eq!(scan.pruned.len(), 12);
+    }
+
+    #[test]
+    fn set_predicates_prune_members() {
+        let (registry, _) = year_of_events();
+        let scan = registry
+            .prune(
+                "events",
+                &PartitionPredicate::In {
+                    values: vec![
+                        str_value("2023-12"),
+                        str_value("2023-01"),
+                        str_value("2022-12"),
+                    ],
+                },
+            )
+            .unwrap();
+        assert_eq!(scanned(&scan), ["events_2023_01", "events_2023_12"]);
+
+        let wrong_kind = PartitionPredicate::In {
+            values: vec![PartitionValue::Int(2023)],
+        };
+        assert!(registry.prune("events", &wrong_kind).is_err());
+        assert_eq!(
+            registry.prune("unknown", &wrong_kind).unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+    }
+
+    #[test]
+    fn schema_drift_is_rejected() {
+        let (registry, mut dfs) = year_of_events();
+        let drifted = month(2024, 1)
+            .lazy()
+            .with_column(col("events").cast(DataType::Float64))
+            .collect()
+            .unwrap();
+        dfs.insert(String::from("events_2024_01"), artifact(drifted));
+        dfs.insert(String::from("events_2024_02"), artifact(month(2024, 2)));
+
+        let err = registry
+            .add_members(
+                "events",
+                vec![
+                    (String::from("events_2024_02"), str_value("2024-02")),
+                    (String::from("events_2024_01"), str_value("2024-01")),
+                ],
+                &dfs,
+            )
+            .unwrap_err();
+        assert!(err.message().contains("events_2024_01"));
+        // Members are added atomically: the compatible one was not added either.
+        assert_eq!(registry.family("events").unwrap().members.len(), 12);
+    }
+
+    #[test]
+    fn members_must_hold_their_partition_only() {
+        let (registry, mut dfs) = year_of_events();
+        dfs.insert(String::from("mislabeled"), artifact(month(2024, 3)));
+        let err = registry
+            .add_members(
+                "events",
+                vec![(String::from("mislabeled"), str_value("2024-04"))],
+                &dfs,
+            )
+            .unwrap_err();
+        assert!(err.message().contains("2024-03"));
+
+        assert!(registry
+            .check_rows("events_2023_05", &month(2023, 5))
+            .is_ok());
+        assert!(registry
+            .check_rows("events_2023_05", &month(2023, 6))
+            .is_err());
+        assert!(registry.check_not_member("events_2023_05").is_err());
+
+        registry
+            .remove_members("events", &[String::from("events_2023_05")])
+            .unwrap();
+        assert!(registry.check_not_member("events_2023_05").is_ok());
+        assert!(registry
+            .remove_members("events", &[String::from("events_2023_05")])
+            .is_err());
+    }
+
+    #[test]
+    fn plans_concatenate_the_scanned_members_only() {
+        let polars = polars();
+        let members = (1..=3)
+            .map(|m| {
+                let identifier = polars.insert_df(artifact(month(2023, m)));
+                (identifier, str_value(&format!("2023-{m:02}")))
+            })
+            .collect::<Vec<_>>();
+        polars
+            .register_family("events", month_key(), members.clone())
+            .unwrap();
+        let run = |predicate| {
+            CompositePlan::new(vec![CompositePlanSegment::FamilyEntryPointSegment {
+                family: String::from("events"),
+                predicate,
+            }])
+            .run(&polars, "analyst")
+            .unwrap()
+        };
+
+        let result = run(PartitionPredicate::Range {
+            from: Some(str_value("2023-02")),
+            to: None,
+        });
+        let expected = month(2023, 2).vstack(&month(2023, 3)).unwrap();
+        assert!(result.dataframe.frame_equal(&expected));
+        assert!(result
+            .query_details
+            .contains(&format!("pruned partitions [2023-01 ({})]", members[0].0)));
+
+        let result = run(PartitionPredicate::In {
+            values: vec![str_value("2022-12")],
+        });
+        assert_eq!(result.dataframe.height(), 0);
+        assert_eq!(result.dataframe.schema(), month(2023, 1).schema());
+
+        // Members cannot be deleted while they belong to the family.
+        assert!(polars.delete_dfs(&members[0].0).is_err());
+        polars
+            .remove_family_members("events", &[members[0].0.clone()])
+            .unwrap();
+        assert!(polars.delete_dfs(&members[0].0).is_ok());
+    }
+}
@@ -0,0 +1,257 @@
+//! Faults injected in the chunk streams of uploads and fetches, for chaos tests.
+//!
+//! Dev builds started with `fault_injection` set in their config read a [`FaultSchedule`] from the
+//! [`FAULTS_METADATA`] metadata of upload and fetch requests, and apply it to the chunks they
+//! receive or send: delays, duplicated chunks, a truncated final chunk and a disconnect after some
+//! chunks. Schedules are seeded, so that a failing run can be replayed.
+//!
+//! On uploads, a disconnect ends the stream early, as a client whose connection dropped between
+//! two chunks. On fetches, the server stops sending and closes the stream.
+//!
+//! Schedules can also slow down the plan jobs submitted with them, to restart a server in the
+//! middle of one, see [`crate::plan_jobs`].
+//!
+//! Release builds compile the hooks to no-ops and ignore the metadata.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+use crate::polars_proto::{fetch_chunk, FetchChunk, SendChunk};
+
+/// Metadata key of the JSON-encoded schedule of a request.
+pub const FAULTS_METADATA: &str = "x-bastionlab-faults";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FaultSchedule {
+    #[serde(default)]
+    pub seed: u64,
+    /// Probability that a chunk is delayed, by up to `max_delay_ms`.
+    #[serde(default)]
+    pub delay: f64,
+    #[serde(default)]
+    pub max_delay_ms: u64,
+    /// Probability that a chunk is sent twice.
+    #[serde(default)]
+    pub duplicate: f64,
+    /// Cut the final data chunk in half.
+    #[serde(default)]
+    pub truncate_final: bool,
+    /// Number of chunks after which the stream is cut.
+    #[serde(default)]
+    pub disconnect_after: Option<usize>,
+    /// Milliseconds waited before each segment of a plan job.
+    #[serde(default)]
+    pub segment_delay_ms: u64,
+}
+
+/// The schedule of a request, if fault injection is `enabled` on a dev build.
+#[cfg(debug_assertions)]
+fn schedule(metadata: &MetadataMap, enabled: bool) -> Result<Option<FaultSchedule>, Status> {
+    let schedule = match metadata.get(FAULTS_METADATA) {
+        Some(schedule) if enabled => schedule,
+        _ => return Ok(None),
+    };
+    schedule
+        .to_str()
+        .ok()
+        .and_then(|schedule| serde_json::from_str(schedule).ok())
+        .map(Some)
+        .ok_or_else(|| Status::invalid_argument("Invalid fault schedule"))
+}
+
+/// The delay before each segment of the plan job submitted with `metadata`.
+#[cfg(debug_assertions)]
+pub fn segment_delay(metadata: &MetadataMap, enabled: bool) -> Result<Duration, Status> {
+    let delay = schedule(metadata, enabled)?.map_or(0, |schedule| schedule.segment_delay_ms);
+    if delay > 0 {
+        log::warn!("Delaying each segment of a plan job by {delay} ms");
+    }
+    Ok(Duration::from_millis(delay))
+}
+
+#[cfg(not(debug_assertions))]
+#[inline]
+pub fn segment_delay(_metadata: &MetadataMap, _enabled: bool) -> Result<Duration, Status> {
+    Ok(Duration::ZERO)
+}
+
+/// What is delivered in place of a chunk.
+pub enum Delivery<C> {
+    Once(C),
+    Twice(C),
+    /// The stream is cut before the chunk.
+    Cut,
+}
+
+/// Chunks whose data faults can truncate.
+pub trait Chunk: Clone {
+    /// Drops the second half of the data of the chunk.
+    fn truncate(&mut self);
+}
+
+impl Chunk for SendChunk {
+    fn truncate(&mut self) {
+        self.data.truncate(self.data.len() / 2);
+    }
+}
+
+impl Chunk for FetchChunk {
+    fn truncate(&mut self) {
+        if let Some(fetch_chunk::Body::Data(data)) = &mut self.body {
+            data.truncate(data.len() / 2);
+        }
+    }
+}
+
+/// The faults of one stream.
+#[derive(Default)]
+pub struct StreamFaults {
+    #[cfg(debug_assertions)]
+    active: Option<ActiveFaults>,
+}
+
+#[cfg(debug_assertions)]
+struct ActiveFaults {
+    schedule: FaultSchedule,
+    rng: rand::rngs::StdRng,
+    delivered: usize,
+}
+
+impl StreamFaults {
+    /// Reads the schedule of a request, if fault injection is `enabled` on a dev build.
+    #[cfg(debug_assertions)]
+    pub fn from_metadata(metadata: &MetadataMap, enabled: bool) -> Result<Self, Status> {
+        use rand::SeedableRng;
+
+        let schedule = match schedule(metadata, enabled)? {
+            Some(schedule) => schedule,
+            None => return Ok(StreamFaults::default()),
+        };
+        log::warn!("Injecting faults in a stream: {schedule:?}");
+        Ok(StreamFaults {
+            active: Some(ActiveFaults {
+                rng: rand::rngs::StdRng::seed_from_u64(schedule.seed),
+                schedule,
+                delivered: 0,
+            }),
+        })
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline]
+    pub fn from_metadata(_metadata: &MetadataMap, _enabled: bool) -> Result<Self, Status> {
+        Ok(StreamFaults::default())
+    }
+
+    /// Applies the schedule to `chunk`, the `last` one carrying data in its stream.
+    #[cfg(debug_assertions)]
+    pub async fn apply<C: Chunk>(&mut self, mut chunk: C, last: bool) -> Delivery<C> {
+        use rand::Rng;
+
+        let faults = match &mut self.active {
+            Some(faults) => faults,
+            None => return Delivery::Once(chunk),
+        };
+        let schedule = &faults.schedule;
+        if schedule.disconnect_after == Some(faults.delivered) {
+            return Delivery::Cut;
+        }
+        faults.delivered += 1;
+        if schedule.delay > 0.0 && faults.rng.gen_bool(schedule.delay.min(1.0)) {
+            let delay = faults.rng.gen_range(0..=schedule.max_delay_ms);
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        }
+        if last && schedule.truncate_final {
+            chunk.truncate();
+        }
+        if schedule.duplicate > 0.0 && faults.rng.gen_bool(schedule.duplicate.min(1.0)) {
+            return Delivery::Twice(chunk);
+        }
+        Delivery::Once(chunk)
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline]
+    pub async fn apply<C: Chunk>(&mut self, chunk: C, _last: bool) -> Delivery<C> {
+        Delivery::Once(chunk)
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use tonic::metadata::MetadataValue;
+
+    fn faults(schedule: &FaultSchedule, enabled: bool) -> StreamFaults {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            FAULTS_METADATA,
+            MetadataValue::from_str(&serde_json::to_string(schedule).unwrap()).unwrap(),
+        );
+        StreamFaults::from_metadata(&metadata, enabled).unwrap()
+    }
+
+    fn chunk(data: &[u8]) -> SendChunk {
+        SendChunk {
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn schedules_apply_only_when_enabled() {
+        let schedule = FaultSchedule {
+            truncate_final: true,
+            disconnect_after: Some(2),
+            ..Default::default()
+        };
+
+        let mut disabled = faults(&schedule, false);
+        for _ in 0..3 {
+            assert!(matches!(
+                disabled.apply(chunk(b"abcd"), true).await,
+                Delivery::Once(c) if c.data == b"abcd"
+            ));
+        }
+
+        let mut enabled = faults(&schedule, true);
+        assert!(matches!(
+            enabled.apply(chunk(b"abcd"), false).await,
+            Delivery::Once(c) if c.data == b"abcd"
+        ));
+        assert!(matches!(
+            enabled.apply(chunk(b"abcd"), true).await,
+            Delivery::Once(c) if c.data == b"ab"
+        ));
+        assert!(matches!(
+            enabled.apply(chunk(b"abcd"), true).await,
+            Delivery::Cut
+        ));
+    }
+
+    #[tokio::test]
+    async fn schedules_replay_from_their_seed() {
+        let schedule = FaultSchedule {
+            seed: 7,
+            duplicate: 0.5,
+            ..Default::default()
+        };
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let mut faults = faults(&schedule, true);
+            let mut run = Vec::new();
+            for _ in 0..32 {
+                run.push(matches!(
+                    faults.apply(chunk(b"x"), false).await,
+                    Delivery::Twice(_)
+                ));
+            }
+            runs.push(run);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].contains(&true) && runs[0].contains(&false));
+    }
+}
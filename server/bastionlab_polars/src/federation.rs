@@ -0,0 +1,321 @@
+//! Dataframes held by other servers, registered here with their schema and policy only.
+//!
+//! A federated dataframe keeps the address of its remote server, its identifier there and a
+//! delegation credential, the key of an identity of the remote server. Queries reading it send
+//! the sub-plan reading it alone, its entry point and the polars segment right after it when that
+//! one has no other input, to the remote server through a [`RemoteConnector`], fetch the result,
+//! filtered by the remote's policy, and carry on locally. The registered policy is merged into
+//! the policy of the local result as that of any other input.
+//!
+//! Failures of the remote server fail the query as `unavailable`, with the remote's error, and so
+//! do remote servers that do not answer within `federation_timeout_secs`. Both servers record the
+//! query in their access logs under the same correlation id, sent in the [`CORRELATION_METADATA`]
+//! metadata.
+//!
+//! Results can also be transferred to another server, which stores them as uploads of its own:
+//! the source server checks the transfer as a fetch, waiting for approval if needed, and uploads
+//! the released rows there through the connector, with the destination policy and a credential
+//! of the destination server. Uploads are single streams the destination only stores once they
+//! are complete, so a transfer failing midway leaves nothing there. The transfer is recorded in
+//! the access logs of both servers under the same correlation id.
+//!
+//! This crate cannot depend on the client crate, which implements the connector: servers are
+//! given one with [`crate::BastionLabPolars::with_remote_connector`].
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+use crate::access_control::Policy;
+use crate::composite_plan::CompositePlan;
+use crate::polars_proto::{ReferenceResponse, RemoteDataFrameRequest};
+use crate::purpose::Purpose;
+use crate::DataFrameArtifact;
+
+/// Metadata key of the correlation id of a request.
+pub const CORRELATION_METADATA: &str = "x-bastionlab-correlation-id";
+
+const MAX_CORRELATION_ID: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteSource {
+    /// Address of the remote server, e.g. `https://remote:50056`.
+    pub address: String,
+    /// Identifier of the dataframe on the remote server.
+    pub identifier: String,
+    /// PEM-encoded PKCS#8 key the sub-plans are run with, never sent back to clients.
+    pub credential: String,
+}
+
+/// The result of a sub-plan, as fetched from the remote server.
+pub struct RemoteResult {
+    pub dataframe: DataFrame,
+    /// The warning the remote server fetched the result with, if any.
+    pub warning: Option<String>,
+}
+
+/// Runs sub-plans on remote servers.
+#[tonic::async_trait]
+pub trait RemoteConnector: Send + Sync {
+    /// Runs `plan` on the server of `source` and fetches its result, stating `purpose` and
+    /// sending `correlation_id` with both requests.
+    async fn run(
+        &self,
+        source: &RemoteSource,
+        plan: &CompositePlan,
+        correlation_id: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<RemoteResult, Status>;
+
+    /// Uploads `df` with `policy` to the server at `address`, authenticating with `credential`,
+    /// the PEM-encoded key of an identity of that server, and sending `correlation_id`.
+    async fn upload(
+        &self,
+        _address: &str,
+        _credential: &str,
+        _df: &DataFrame,
+        _policy: &Policy,
+        _correlation_id: &str,
+    ) -> Result<ReferenceResponse, Status> {
+        Err(Status::unimplemented(
+            "This connector cannot upload dataframes to remote servers",
+        ))
+    }
+}
+
+#[derive(Clone)]
+pub struct Federation {
+    connector: Option<Arc<dyn RemoteConnector>>,
+    timeout: Duration,
+}
+
+impl Federation {
+    pub fn new(timeout: Duration) -> Self {
+        Federation {
+            connector: None,
+            timeout,
+        }
+    }
+
+    pub fn with_connector(mut self, connector: Arc<dyn RemoteConnector>) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Runs `plan`, the sub-plan reading federated dataframe `identifier`, on its remote server.
+    pub async fn forward(
+        &self,
+        identifier: &str,
+        source: &RemoteSource,
+        plan: &CompositePlan,
+        correlation_id: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<RemoteResult, Status> {
+        let connector = self.connector.as_ref().ok_or_else(|| {
+            Status::unavailable(format!(
+                "Dataframe {identifier} is federated, but this server cannot reach remote servers"
+            ))
+        })?;
+        match tokio::time::timeout(
+            self.timeout,
+            connector.run(source, plan, correlation_id, purpose),
+        )
+        .await
+        {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => Err(Status::unavailable(format!(
+                "The server of federated dataframe {identifier} at {} failed: {}",
+                source.address,
+                e.message()
+            ))),
+            Err(_) => Err(Status::unavailable(format!(
+                "The server of federated dataframe {identifier} at {} did not answer within {}s",
+                source.address,
+                self.timeout.as_secs()
+            ))),
+        }
+    }
+
+    /// Uploads `df`, the released rows of result `identifier`, to the server at `address`.
+    pub async fn transfer(
+        &self,
+        identifier: &str,
+        address: &str,
+        credential: &str,
+        df: &DataFrame,
+        policy: &Policy,
+        correlation_id: &str,
+    ) -> Result<ReferenceResponse, Status> {
+        let connector = self.connector.as_ref().ok_or_else(|| {
+            Status::unavailable("This server cannot reach remote servers to transfer dataframes")
+        })?;
+        match tokio::time::timeout(
+            self.timeout,
+            connector.upload(address, credential, df, policy, correlation_id),
+        )
+        .await
+        {
+            Ok(Ok(reference)) => Ok(reference),
+            Ok(Err(e)) => Err(Status::unavailable(format!(
+                "Could not transfer {identifier} to the server at {address}: {}",
+                e.message()
+            ))),
+            Err(_) => Err(Status::unavailable(format!(
+                "Could not transfer {identifier}: the server at {address} did not answer within \
+                 {}s",
+                self.timeout.as_secs()
+            ))),
+        }
+    }
+}
+
+/// Reads the correlation id of a request, which ends up in logs and must be short and plain.
+pub fn correlation_id(metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    let id = match metadata.get(CORRELATION_METADATA) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    match id.to_str() {
+        Ok(id)
+            if !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            Ok(Some(id.to_string()))
+        }
+        _ => Err(Status::invalid_argument(format!(
+            "Invalid correlation id: at most {MAX_CORRELATION_ID} letters, digits or dashes"
+        ))),
+    }
+}
+
+/// The artifact of a federated dataframe: an empty dataframe with the registered schema.
+pub fn remote_artifact(request: RemoteDataFrameRequest) -> Result<DataFrameArtifact, Status> {
+    if request.address.is_empty() || request.identifier.is_empty() {
+        return Err(Status::invalid_argument(
+            "Federated dataframes need the address of their server and their identifier there",
+        ));
+    }
+    if request.credential.is_empty() {
+        return Err(Status::invalid_argument(
+            "Federated dataframes need a credential of their server",
+        ));
+    }
+    let schema: Schema = serde_json::from_str(&request.schema).map_err(|e| {
+        Status::invalid_argument(format!("Error during the parsing of the schema: {e}"))
+    })?;
+    let policy: Policy = serde_json::from_str(&request.policy).map_err(|e| {
+        Status::invalid_argument(format!("Error during the parsing of the policy: {e}"))
+    })?;
+    policy.check_masks(&schema)?;
+
+    let df = DataFrame::new_no_checks(
+        schema
+            .iter()
+            .map(|(name, dtype)| Series::new_empty(name, dtype))
+            .collect(),
+    );
+    let mut artifact = DataFrameArtifact::new(df, policy, request.sanitized_columns);
+    artifact.catalog.name = request.name;
+    artifact.catalog.tags = request.tags;
+    artifact.remote = Some(RemoteSource {
+        address: request.address,
+        identifier: request.identifier,
+        credential: request.credential,
+    });
+    Ok(artifact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::metadata::MetadataValue;
+
+    fn metadata(id: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(CORRELATION_METADATA, MetadataValue::from_str(id).unwrap());
+        metadata
+    }
+
+    #[test]
+    fn correlation_ids_are_plain() {
+        assert_eq!(correlation_id(&MetadataMap::new()).unwrap(), None);
+        let id = "0b6a7c3e-5d1f-4e2a-9c8b-7f6e5d4c3b2a";
+        assert_eq!(correlation_id(&metadata(id)).unwrap().as_deref(), Some(id));
+        assert!(correlation_id(&metadata("a b")).is_err());
+        assert!(correlation_id(&metadata(&"a".repeat(MAX_CORRELATION_ID + 1))).is_err());
+    }
+
+    struct Failing;
+
+    #[tonic::async_trait]
+    impl RemoteConnector for Failing {
+        async fn run(
+            &self,
+            _source: &RemoteSource,
+            _plan: &CompositePlan,
+            _correlation_id: &str,
+            _purpose: Option<&Purpose>,
+        ) -> Result<RemoteResult, Status> {
+            Err(Status::not_found(
+                "Could not find dataframe: identifier=remote",
+            ))
+        }
+    }
+
+    struct Silent;
+
+    #[tonic::async_trait]
+    impl RemoteConnector for Silent {
+        async fn run(
+            &self,
+            _source: &RemoteSource,
+            _plan: &CompositePlan,
+            _correlation_id: &str,
+            _purpose: Option<&Purpose>,
+        ) -> Result<RemoteResult, Status> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_failures_are_unavailable() {
+        let source = RemoteSource {
+            address: String::from("http://remote:50056"),
+            identifier: String::from("remote"),
+            credential: String::from("key"),
+        };
+        let plan = CompositePlan::new(Vec::new());
+        let federation = Federation::new(Duration::from_millis(10));
+
+        let err = federation
+            .forward("local", &source, &plan, "id", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        let err = federation
+            .clone()
+            .with_connector(Arc::new(Failing))
+            .forward("local", &source, &plan, "id", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert!(err.message().contains("identifier=remote"), "{err:?}");
+
+        let err = federation
+            .with_connector(Arc::new(Silent))
+            .forward("local", &source, &plan, "id", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert!(err.message().contains("did not answer"), "{err:?}");
+    }
+}
@@ -0,0 +1,214 @@
+//! Checkpoints of long fetch streams.
+//!
+//! A fetch is authorized when it starts, but streaming a large result can take long enough for
+//! the recipient's key to be revoked or the dataframe to be deleted in the meantime. Streams
+//! therefore stop at a checkpoint every few chunks. Checkpoints only compare generation counters,
+//! which are bumped by every change that may narrow access, and re-check the fetch when one of
+//! them moved.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use bastionlab_common::metrics::HistogramTimer;
+use bastionlab_common::session::SessionManager;
+use tonic::Status;
+
+use crate::access_control::{Policy, VerificationResult};
+use crate::probing::ProbingDetector;
+use crate::DataFrameArtifact;
+
+/// Counts the changes that may narrow access to dataframes: deletions, replacements and
+/// suspensions. Anything that removes a dataframe or changes its policy must bump it.
+#[derive(Debug, Default)]
+pub struct AccessGeneration(AtomicU64);
+
+impl AccessGeneration {
+    pub fn bump(&self) {
+        self.0.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// What a fetch was authorized with, to re-check it while it streams.
+pub struct FetchGuard {
+    identifier: String,
+    recipient: String,
+    every: usize,
+    policy: Policy,
+    fetchable: VerificationResult,
+    sess_manager: Arc<SessionManager>,
+    dataframes: Arc<RwLock<HashMap<String, DataFrameArtifact>>>,
+    probing: Arc<ProbingDetector>,
+    access: Arc<AccessGeneration>,
+    /// Key and access generations the fetch was last checked at.
+    checked: (u64, u64),
+    /// Observes how long the fetch streamed when the guard is dropped, once the stream ends.
+    timer: Option<HistogramTimer>,
+}
+
+impl FetchGuard {
+    /// Snapshots the fetch of `identifier` by `recipient`, re-checked every `every` chunks (never
+    /// if 0).
+    pub fn new(
+        identifier: &str,
+        recipient: &str,
+        every: usize,
+        sess_manager: Arc<SessionManager>,
+        dataframes: Arc<RwLock<HashMap<String, DataFrameArtifact>>>,
+        probing: Arc<ProbingDetector>,
+        access: Arc<AccessGeneration>,
+    ) -> Result<Self, Status> {
+        // Generations are read first: a change racing with the snapshot is re-checked.
+        let checked = (sess_manager.key_generation(), access.get());
+        let (policy, fetchable) = {
+            let dfs = dataframes.read().unwrap();
+            let artifact = dfs.get(identifier).ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?;
+            (artifact.policy.clone(), artifact.fetchable.clone())
+        };
+        Ok(FetchGuard {
+            identifier: identifier.to_owned(),
+            recipient: recipient.to_owned(),
+            every,
+            policy,
+            fetchable,
+            sess_manager,
+            dataframes,
+            probing,
+            access,
+            checked,
+            timer: None,
+        })
+    }
+
+    pub fn with_timer(mut self, timer: HistogramTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    pub fn recipient(&self) -> &str {
+        &self.recipient
+    }
+
+    /// Called before sending the chunk `index`. Fails with `permission_denied` if the recipient
+    /// may no longer receive the dataframe, and with `aborted` if it was deleted.
+    pub fn checkpoint(&mut self, index: usize) -> Result<(), Status> {
+        if self.every == 0 || index % self.every != 0 {
+            return Ok(());
+        }
+        let generations = (self.sess_manager.key_generation(), self.access.get());
+        if generations == self.checked {
+            return Ok(());
+        }
+        self.checked = generations;
+        self.recheck()
+    }
+
+    fn recheck(&self) -> Result<(), Status> {
+        if !self.sess_manager.is_active(&self.recipient) {
+            return Err(Status::permission_denied(format!(
+                "The key of {} was revoked during the fetch",
+                self.recipient
+            )));
+        }
+        self.probing
+            .check_suspended(&self.recipient, std::slice::from_ref(&self.identifier))?;
+        let dfs = self.dataframes.read().unwrap();
+        let artifact = dfs.get(&self.identifier).ok_or_else(|| {
+            Status::aborted(format!(
+                "Dataframe {} was deleted during the fetch",
+                self.identifier
+            ))
+        })?;
+        if artifact.policy != self.policy || artifact.fetchable != self.fetchable {
+            return Err(Status::permission_denied(format!(
+                "The policy of dataframe {} changed during the fetch",
+                self.identifier
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use polars::prelude::*;
+    use std::time::Duration;
+    use tonic::Code;
+
+    fn polars() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600,
+                "fetch_checkpoint_chunks": 4
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn upload(polars: &BastionLabPolars) -> String {
+        let df = df! { "a" => [1i64, 2, 3] }.unwrap();
+        polars.insert_df(DataFrameArtifact::new(
+            df,
+            Policy::allow_by_default(),
+            vec![],
+        ))
+    }
+
+    #[test]
+    fn checkpoints_only_recheck_after_changes() {
+        let polars = polars();
+        let identifier = upload(&polars);
+        let mut guard = polars.fetch_guard(&identifier, "alice").unwrap();
+        for index in 0..16 {
+            guard.checkpoint(index).unwrap();
+        }
+
+        // Between checkpoints, changes go unnoticed.
+        polars.delete_dfs(&identifier).unwrap();
+        guard.checkpoint(17).unwrap();
+        let err = guard.checkpoint(20).unwrap_err();
+        assert_eq!(err.code(), Code::Aborted);
+    }
+
+    #[test]
+    fn suspensions_stop_fetches() {
+        let polars = polars();
+        let identifier = upload(&polars);
+        let mut guard = polars.fetch_guard(&identifier, "alice").unwrap();
+        let other = upload(&polars);
+        polars.suspend(
+            "bob",
+            std::slice::from_ref(&identifier),
+            Duration::from_secs(60),
+        );
+        polars.delete_dfs(&other).unwrap();
+        guard.checkpoint(4).unwrap();
+
+        polars.suspend(
+            "alice",
+            std::slice::from_ref(&identifier),
+            Duration::from_secs(60),
+        );
+        let err = guard.checkpoint(8).unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,447 @@
+//! Row filters of a composite plan, written as expression trees instead of polars plans.
+//!
+//! A `FilterPlanSegment` keeps the rows of the dataframe on top of the stack its predicate holds
+//! for. Predicates compare columns and literals, and combine comparisons with `And`, `Or` and
+//! `Not`, e.g. `age > 65 AND site == 'A'` is:
+//!
+//! ```json
+//! {"op": "And",
+//!  "left": {"op": "Gt", "left": {"op": "Column", "name": "age"},
+//!           "right": {"op": "Literal", "value": 65}},
+//!  "right": {"op": "Eq", "left": {"op": "Column", "name": "site"},
+//!            "right": {"op": "Literal", "value": "A"}}}
+//! ```
+//!
+//! Operands can be computed too: `Add`, `Sub`, `Mul` and `Div` of numbers, and `Concat` of
+//! strings. Divisions are of floats, and null where the divisor is zero. The same expressions
+//! compute the columns of a `WithColumnPlanSegment`, see [`crate::computed`].
+//!
+//! Expressions are typed against the schema of the input before anything runs: numbers compare
+//! with numbers, strings with strings, booleans for equality only, and temporal columns with
+//! columns of the same dtype. Comparing with a null literal tests whether values are null.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum FilterExpr {
+    Column {
+        name: String,
+    },
+    /// A JSON number, string, boolean or null.
+    Literal {
+        value: serde_json::Value,
+    },
+    Eq {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    NotEq {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Lt {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    LtEq {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Gt {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    GtEq {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    And {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Or {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Not {
+        expr: Box<FilterExpr>,
+    },
+    Add {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Sub {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Mul {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Div {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    Concat {
+        left: Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+}
+
+/// What values of an operand compare with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Number,
+    String,
+    Boolean,
+    Null,
+    /// Temporal and other dtypes, which only compare with the same dtype.
+    Other(DataType),
+}
+
+impl Kind {
+    fn of(dtype: &DataType) -> Kind {
+        match dtype {
+            DataType::Utf8 => Kind::String,
+            DataType::Boolean => Kind::Boolean,
+            DataType::Null => Kind::Null,
+            dtype if dtype.is_numeric() => Kind::Number,
+            dtype => Kind::Other(dtype.clone()),
+        }
+    }
+}
+
+/// A compiled expression, with what its values are.
+pub struct Operand {
+    pub expr: Expr,
+    pub kind: Kind,
+    /// How error messages name it.
+    pub describe: String,
+}
+
+impl FilterExpr {
+    /// Columns the filter reads, in order of appearance.
+    pub fn columns(&self) -> Vec<&str> {
+        let mut columns = Vec::new();
+        self.collect_columns(&mut columns);
+        columns
+    }
+
+    fn collect_columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            FilterExpr::Column { name } => {
+                if !columns.contains(&name.as_str()) {
+                    columns.push(name);
+                }
+            }
+            FilterExpr::Literal { .. } => (),
+            FilterExpr::Not { expr } => expr.collect_columns(columns),
+            FilterExpr::Eq { left, right }
+            | FilterExpr::NotEq { left, right }
+            | FilterExpr::Lt { left, right }
+            | FilterExpr::LtEq { left, right }
+            | FilterExpr::Gt { left, right }
+            | FilterExpr::GtEq { left, right }
+            | FilterExpr::And { left, right }
+            | FilterExpr::Or { left, right }
+            | FilterExpr::Add { left, right }
+            | FilterExpr::Sub { left, right }
+            | FilterExpr::Mul { left, right }
+            | FilterExpr::Div { left, right }
+            | FilterExpr::Concat { left, right } => {
+                left.collect_columns(columns);
+                right.collect_columns(columns);
+            }
+        }
+    }
+
+    /// Compiles the filter to a polars predicate over `schema`.
+    pub fn compile(&self, schema: &Schema) -> Result<Expr, Status> {
+        boolean(self.typed(schema, "filter")?, "filter")
+    }
+
+    /// Compiles the expression over `schema`. Errors tell that `action` could not be done.
+    pub fn typed(&self, schema: &Schema, action: &str) -> Result<Operand, Status> {
+        match self {
+            FilterExpr::Column { name } => {
+                let dtype = schema.get(name).ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "Could not {action}: no column `{name}` in the input"
+                    ))
+                })?;
+                Ok(Operand {
+                    expr: col(name),
+                    kind: Kind::of(dtype),
+                    describe: format!("`{name}` ({dtype})"),
+                })
+            }
+            FilterExpr::Literal { value } => literal(value, action),
+            FilterExpr::And { left, right } => Ok(Operand {
+                expr: boolean(left.typed(schema, action)?, action)?
+                    .and(boolean(right.typed(schema, action)?, action)?),
+                kind: Kind::Boolean,
+                describe: String::from("And (a boolean)"),
+            }),
+            FilterExpr::Or { left, right } => Ok(Operand {
+                expr: boolean(left.typed(schema, action)?, action)?
+                    .or(boolean(right.typed(schema, action)?, action)?),
+                kind: Kind::Boolean,
+                describe: String::from("Or (a boolean)"),
+            }),
+            FilterExpr::Not { expr } => Ok(Operand {
+                expr: boolean(expr.typed(schema, action)?, action)?.not(),
+                kind: Kind::Boolean,
+                describe: String::from("Not (a boolean)"),
+            }),
+            FilterExpr::Eq { left, right } => compare("Eq", left, right, schema, action),
+            FilterExpr::NotEq { left, right } => compare("NotEq", left, right, schema, action),
+            FilterExpr::Lt { left, right } => compare("Lt", left, right, schema, action),
+            FilterExpr::LtEq { left, right } => compare("LtEq", left, right, schema, action),
+            FilterExpr::Gt { left, right } => compare("Gt", left, right, schema, action),
+            FilterExpr::GtEq { left, right } => compare("GtEq", left, right, schema, action),
+            FilterExpr::Add { left, right } => compute("Add", left, right, schema, action),
+            FilterExpr::Sub { left, right } => compute("Sub", left, right, schema, action),
+            FilterExpr::Mul { left, right } => compute("Mul", left, right, schema, action),
+            FilterExpr::Div { left, right } => compute("Div", left, right, schema, action),
+            FilterExpr::Concat { left, right } => compute("Concat", left, right, schema, action),
+        }
+    }
+}
+
+fn boolean(operand: Operand, action: &str) -> Result<Expr, Status> {
+    match operand.kind {
+        Kind::Boolean => Ok(operand.expr),
+        _ => Err(Status::invalid_argument(format!(
+            "Could not {action}: {} is not a boolean",
+            operand.describe
+        ))),
+    }
+}
+
+fn literal(value: &serde_json::Value, action: &str) -> Result<Operand, Status> {
+    use serde_json::Value;
+    let (expr, kind) = match value {
+        Value::Null => (lit(Null {}), Kind::Null),
+        Value::Bool(value) => (lit(*value), Kind::Boolean),
+        Value::String(value) => (lit(value.as_str()), Kind::String),
+        Value::Number(number) => {
+            let expr = if let Some(value) = number.as_i64() {
+                lit(value)
+            } else if let Some(value) = number.as_u64() {
+                lit(value)
+            } else {
+                lit(number.as_f64().unwrap_or(f64::NAN))
+            };
+            (expr, Kind::Number)
+        }
+        Value::Array(_) | Value::Object(_) => {
+            return Err(Status::invalid_argument(format!(
+                "Could not {action}: literal {value} is not a number, a string, a boolean or null"
+            )))
+        }
+    };
+    let describe = match &kind {
+        Kind::Number => format!("{value} (a number)"),
+        Kind::String => format!("{value} (a string)"),
+        Kind::Boolean => format!("{value} (a boolean)"),
+        _ => value.to_string(),
+    };
+    Ok(Operand {
+        expr,
+        kind,
+        describe,
+    })
+}
+
+fn compare(
+    op: &str,
+    left: &FilterExpr,
+    right: &FilterExpr,
+    schema: &Schema,
+    action: &str,
+) -> Result<Operand, Status> {
+    let left = left.typed(schema, action)?;
+    let right = right.typed(schema, action)?;
+    let equality = matches!(op, "Eq" | "NotEq");
+    let expr = match (&left.kind, &right.kind) {
+        (Kind::Null, Kind::Null) => {
+            return Err(Status::invalid_argument(format!(
+                "Could not {action}: {op} compares null with null"
+            )))
+        }
+        (Kind::Null, _) | (_, Kind::Null) if !equality => {
+            return Err(Status::invalid_argument(format!(
+                "Could not {action}: null can only be compared with Eq or NotEq, not {op}"
+            )))
+        }
+        // Tests for nulls, which comparisons with null would not.
+        (Kind::Null, _) | (_, Kind::Null) => {
+            let operand = match left.kind {
+                Kind::Null => right.expr,
+                _ => left.expr,
+            };
+            match op {
+                "Eq" => operand.is_null(),
+                _ => operand.is_not_null(),
+            }
+        }
+        (left_kind, right_kind) if left_kind != right_kind => {
+            return Err(Status::invalid_argument(format!(
+                "Could not {action}: cannot compare {} with {}",
+                left.describe, right.describe
+            )))
+        }
+        (Kind::Boolean, _) if !equality => {
+            return Err(Status::invalid_argument(format!(
+                "Could not {action}: {} can only be compared with Eq or NotEq, not {op}",
+                left.describe
+            )))
+        }
+        _ => match op {
+            "Eq" => left.expr.eq(right.expr),
+            "NotEq" => left.expr.neq(right.expr),
+            "Lt" => left.expr.lt(right.expr),
+            "LtEq" => left.expr.lt_eq(right.expr),
+            "Gt" => left.expr.gt(right.expr),
+            _ => left.expr.gt_eq(right.expr),
+        },
+    };
+    Ok(Operand {
+        expr,
+        kind: Kind::Boolean,
+        describe: format!("{op} (a boolean)"),
+    })
+}
+
+/// Arithmetic of numbers, or concatenation of strings.
+fn compute(
+    op: &str,
+    left: &FilterExpr,
+    right: &FilterExpr,
+    schema: &Schema,
+    action: &str,
+) -> Result<Operand, Status> {
+    let left = left.typed(schema, action)?;
+    let right = right.typed(schema, action)?;
+    let (kind, describe) = match op {
+        "Concat" => (Kind::String, "strings"),
+        _ => (Kind::Number, "numbers"),
+    };
+    if left.kind != kind || right.kind != kind {
+        return Err(Status::invalid_argument(format!(
+            "Could not {action}: {op} needs {describe}, not {} and {}",
+            left.describe, right.describe
+        )));
+    }
+    let expr = match op {
+        "Sub" => left.expr - right.expr,
+        "Mul" => left.expr * right.expr,
+        "Div" => {
+            // Null rather than infinite, or a panic for integers.
+            let divisor = right.expr.cast(DataType::Float64);
+            when(divisor.clone().eq(lit(0.0)))
+                .then(lit(Null {}).cast(DataType::Float64))
+                .otherwise(left.expr.cast(DataType::Float64) / divisor)
+        }
+        // Adds numbers, and concatenates strings.
+        _ => left.expr + right.expr,
+    };
+    Ok(Operand {
+        expr,
+        kind,
+        describe: format!("{op} (a {})", describe.trim_end_matches('s')),
+    })
+}
+
+/// The polars plan filtering `df` with `predicate`, whose dataframe scan stands for the input,
+/// see [`crate::composite_plan`].
+pub fn plan(df: &DataFrame, predicate: &FilterExpr) -> Result<LogicalPlan, Status> {
+    let predicate = predicate.compile(&df.schema())?;
+    Ok(df.head(Some(0)).lazy().filter(predicate).logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(predicate: serde_json::Value) -> Result<DataFrame, Status> {
+        let df = df! {
+            "age" => [50i64, 70, 80],
+            "site" => ["A", "B", "A"],
+            "active" => [true, true, false],
+            "score" => [Some(1.5f64), None, Some(3.0)],
+        }
+        .unwrap();
+        let predicate: FilterExpr = serde_json::from_value(predicate).unwrap();
+        let predicate = predicate.compile(&df.schema())?;
+        Ok(df.lazy().filter(predicate).collect().unwrap())
+    }
+
+    fn column(name: &str) -> serde_json::Value {
+        json!({"op": "Column", "name": name})
+    }
+
+    fn value(value: serde_json::Value) -> serde_json::Value {
+        json!({"op": "Literal", "value": value})
+    }
+
+    fn op(op: &str, left: serde_json::Value, right: serde_json::Value) -> serde_json::Value {
+        json!({"op": op, "left": left, "right": right})
+    }
+
+    #[test]
+    fn predicates_are_typed_against_the_schema() {
+        let old_in_a = op(
+            "And",
+            op("Gt", column("age"), value(json!(65))),
+            op("Eq", column("site"), value(json!("A"))),
+        );
+        let rows = filter(old_in_a).unwrap();
+        assert_eq!(rows.height(), 1);
+        assert_eq!(rows.column("age").unwrap().i64().unwrap().get(0), Some(80));
+
+        let rows = filter(json!({"op": "Not", "expr": column("active")})).unwrap();
+        assert_eq!(rows.height(), 1);
+        let rows = filter(op("Eq", column("score"), value(json!(null)))).unwrap();
+        assert_eq!(rows.height(), 1);
+        let rows = filter(op("GtEq", column("score"), value(json!(1.5)))).unwrap();
+        assert_eq!(rows.height(), 2);
+        let older = op("Add", column("age"), value(json!(10)));
+        let rows = filter(op("Gt", older, value(json!(85)))).unwrap();
+        assert_eq!(rows.height(), 1);
+
+        let err = filter(op("Gt", column("site"), value(json!(65)))).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            err.message(),
+            "Could not filter: cannot compare `site` (str) with 65 (a number)"
+        );
+        let err = filter(op("Lt", column("active"), value(json!(true)))).unwrap_err();
+        assert!(
+            err.message().contains("only be compared with Eq"),
+            "{err:?}"
+        );
+        let err = filter(op("Eq", column("missing"), value(json!(1)))).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not filter: no column `missing` in the input"
+        );
+        let err = filter(column("age")).unwrap_err();
+        assert!(err.message().contains("`age` (i64) is not a boolean"));
+        let err = filter(op("Add", column("site"), value(json!(1)))).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not filter: Add needs numbers, not `site` (str) and 1 (a number)"
+        );
+        let nested = op(
+            "Gt",
+            op("Gt", column("age"), value(json!(1))),
+            value(json!(1)),
+        );
+        assert!(filter(nested).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Dataset fingerprints, for clients to tell whether what they cached is still current.
+//!
+//! References, headers, listings and the trailer of fetches carry the fingerprint of the
+//! dataframe as stored, masks and watermarks aside. It hashes the content of every column, the
+//! schema and the version counter, so that every mutation changes it. Results also hash the
+//! fingerprints of the dataframes they read, as they were at the version read, which their
+//! headers list by `identifier@version`: a cached result is still current while the headers of
+//! its inputs show the fingerprints it lists.
+//!
+//! The construction is stable: the same dataframe has the same fingerprint across restarts and
+//! servers, as long as [`FORMAT`] is the same. All integers are little-endian, strings are a u32
+//! byte length then UTF-8 bytes, and hashes are SHA-256:
+//!
+//! ```text
+//! block        rows [i * BLOCK_ROWS, (i + 1) * BLOCK_ROWS) of a column, cast to its declared
+//!              dtype, as a one-column canonical frame (see `canonical`); columns of no rows are
+//!              one empty block
+//! column hash  hash of the hashes of its blocks, in order
+//! dataframe    hash of b"BLFP", FORMAT u8, version u64, n_rows u64, n_columns u32, then per
+//!              column its name and its column hash
+//! result       hash of b"BLFR", FORMAT u8, the fingerprint of its content (as a dataframe),
+//!              n_inputs u32, then per input by `identifier@version`: that key and the
+//!              fingerprint of the input, empty if it was gone by the time the result was stored
+//! ```
+//!
+//! Fingerprints are hex-encoded. Blocks of dtypes the canonical format does not cover are hashed
+//! from their IPC serialization, which is only stable for a given polars version.
+//!
+//! Block hashes are cached per column: appends only hash the blocks past the last full one, and
+//! other mutations hash everything again. Column hashes are never disclosed.
+
+use std::collections::{BTreeMap, HashMap};
+
+use polars::prelude::*;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+
+use crate::canonical::to_canonical_bytes;
+use crate::serialization::dataframe_ser_helper;
+
+/// Version of the construction, hashed into every fingerprint.
+pub const FORMAT: u8 = 1;
+
+/// Rows per block.
+pub const BLOCK_ROWS: usize = 65_536;
+
+type Hash = [u8; 32];
+
+/// The block hashes of the columns of a dataframe, and its fingerprint until the next mutation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fingerprints {
+    columns: HashMap<String, Vec<Hash>>,
+    current: Option<String>,
+    /// Blocks hashed since the dataframe was loaded.
+    #[serde(skip)]
+    hashed: u64,
+}
+
+impl Fingerprints {
+    /// The fingerprint of `df` at `version`, its columns hashed with their dtype in `schema`.
+    pub fn get(&mut self, df: &DataFrame, schema: &Schema, version: u64) -> Result<String, Status> {
+        if let Some(current) = &self.current {
+            return Ok(current.clone());
+        }
+        let mut hasher = digest::Context::new(&digest::SHA256);
+        hasher.update(b"BLFP");
+        hasher.update(&[FORMAT]);
+        hasher.update(&version.to_le_bytes());
+        hasher.update(&(df.height() as u64).to_le_bytes());
+        hasher.update(&(df.width() as u32).to_le_bytes());
+        for series in df.get_columns() {
+            let dtype = schema.get(series.name()).unwrap_or(series.dtype());
+            let blocks = self.columns.entry(series.name().to_string()).or_default();
+            for index in blocks.len()..series.len().div_ceil(BLOCK_ROWS).max(1) {
+                blocks.push(hash_block(series, dtype, index)?);
+                self.hashed += 1;
+            }
+            let mut column = digest::Context::new(&digest::SHA256);
+            for block in blocks.iter() {
+                column.update(block);
+            }
+            update_str(&mut hasher, series.name());
+            hasher.update(column.finish().as_ref());
+        }
+        let fingerprint = hex::encode(hasher.finish().as_ref());
+        self.current = Some(fingerprint.clone());
+        Ok(fingerprint)
+    }
+
+    /// Records that rows were appended to the `rows` there were: full blocks keep their hashes.
+    pub fn appended(&mut self, rows: usize) {
+        for blocks in self.columns.values_mut() {
+            blocks.truncate(rows / BLOCK_ROWS);
+        }
+        self.current = None;
+    }
+
+    /// Records a mutation after which every block is hashed again.
+    pub fn replaced(&mut self) {
+        self.columns.clear();
+        self.current = None;
+    }
+
+    /// Whether the fingerprint is known without hashing anything.
+    pub fn is_cached(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn hashed(&self) -> u64 {
+        self.hashed
+    }
+}
+
+/// The fingerprint of a result of content fingerprint `content`, computed from the inputs of
+/// fingerprints `inputs`, by `identifier@version`.
+pub fn compose(content: &str, inputs: &HashMap<String, String>) -> String {
+    let mut hasher = digest::Context::new(&digest::SHA256);
+    hasher.update(b"BLFR");
+    hasher.update(&[FORMAT]);
+    update_str(&mut hasher, content);
+    hasher.update(&(inputs.len() as u32).to_le_bytes());
+    let inputs: BTreeMap<_, _> = inputs.iter().collect();
+    for (key, fingerprint) in inputs {
+        update_str(&mut hasher, key);
+        update_str(&mut hasher, fingerprint);
+    }
+    hex::encode(hasher.finish().as_ref())
+}
+
+fn update_str(hasher: &mut digest::Context, s: &str) {
+    hasher.update(&(s.len() as u32).to_le_bytes());
+    hasher.update(s.as_bytes());
+}
+
+fn hash_block(series: &Series, dtype: &DataType, index: usize) -> Result<Hash, Status> {
+    let polars_err = |e: PolarsError| {
+        Status::internal(format!(
+            "Could not fingerprint column {}: {e}",
+            series.name()
+        ))
+    };
+    let block = series
+        .slice((index * BLOCK_ROWS) as i64, BLOCK_ROWS)
+        .cast(dtype)
+        .map_err(polars_err)?;
+    let mut df = DataFrame::new_no_checks(vec![block]);
+    let bytes = match to_canonical_bytes(&df) {
+        Ok(bytes) => bytes,
+        Err(e) if e.code() == Code::Unimplemented => {
+            dataframe_ser_helper(&mut df).map_err(polars_err)?
+        }
+        Err(e) => return Err(e),
+    };
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, &bytes).as_ref());
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn df(rows: i64) -> DataFrame {
+        df! {
+            "id" => (0..rows).collect::<Vec<_>>(),
+            "name" => (0..rows).map(|i| format!("n{i}")).collect::<Vec<_>>(),
+        }
+        .unwrap()
+    }
+
+    fn fingerprint(df: &DataFrame, version: u64) -> String {
+        Fingerprints::default()
+            .get(df, &df.schema(), version)
+            .unwrap()
+    }
+
+    #[test]
+    fn appends_only_hash_the_blocks_past_the_last_full_one() {
+        let rows = BLOCK_ROWS as i64 + 10;
+        let mut stored = df(rows);
+        let mut fingerprints = Fingerprints::default();
+        let before = fingerprints.get(&stored, &stored.schema(), 0).unwrap();
+        assert_eq!(before, fingerprint(&df(rows), 0));
+        assert_ne!(before, fingerprint(&df(rows), 1));
+        assert_eq!(fingerprints.hashed(), 4);
+
+        let delta = df(rows + 5).slice(rows, 5);
+        stored.vstack_mut(&delta).unwrap();
+        fingerprints.appended(rows as usize);
+        let after = fingerprints.get(&stored, &stored.schema(), 1).unwrap();
+        // The full blocks were kept, the partial ones hashed again.
+        assert_eq!(fingerprints.hashed(), 6);
+        assert_eq!(after, fingerprint(&df(rows + 5), 1));
+        assert_ne!(after, before);
+
+        fingerprints.replaced();
+        assert_eq!(
+            fingerprints.get(&stored, &stored.schema(), 1).unwrap(),
+            after
+        );
+        assert_eq!(fingerprints.hashed(), 10);
+    }
+
+    #[test]
+    fn fingerprints_hash_declared_dtypes_and_compose_with_inputs() {
+        let declared = df(10);
+        let mut stored = declared.clone();
+        stored
+            .with_column(stored.column("id").unwrap().cast(&DataType::Int32).unwrap())
+            .unwrap();
+        let stored_fingerprint = Fingerprints::default()
+            .get(&stored, &declared.schema(), 0)
+            .unwrap();
+        assert_eq!(stored_fingerprint, fingerprint(&declared, 0));
+        assert_ne!(fingerprint(&stored, 0), fingerprint(&declared, 0));
+        let mut renamed = declared.clone();
+        renamed.rename("name", "label").unwrap();
+        assert_ne!(fingerprint(&renamed, 0), fingerprint(&declared, 0));
+
+        let content = fingerprint(&declared, 0);
+        let inputs = HashMap::from([
+            (String::from("a@0"), String::from("1")),
+            (String::from("b@2"), String::from("2")),
+        ]);
+        let composed = compose(&content, &inputs);
+        let reversed: HashMap<_, _> = inputs.clone().into_iter().rev().collect();
+        assert_eq!(compose(&content, &reversed), composed);
+        assert_ne!(composed, content);
+        let mut changed = inputs.clone();
+        changed.insert(String::from("b@2"), String::from("3"));
+        assert_ne!(compose(&content, &changed), composed);
+        assert_ne!(compose(&content, &HashMap::new()), composed);
+    }
+}
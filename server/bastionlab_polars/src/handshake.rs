@@ -0,0 +1,185 @@
+//! Version and feature negotiation between clients and the server.
+//!
+//! Clients call `Handshake` once their session is open, with their name, version, the latest API
+//! version they speak and the optional features they would like to use. The server answers with
+//! the API version both sides speak, the features it enables among those, its capabilities, the
+//! limits the client should stay within, and the deprecated features the client asked for along
+//! with their sunset dates. Clients size their upload chunks and pick their upload format from
+//! the answer.
+//!
+//! The handshake is optional: clients that skip it, such as the ones that predate it, speak API
+//! version 1 and get the server defaults. Handshakes are counted per client name and version, so
+//! that data owners can tell from `GetClientVersions` when no client relies on a legacy path
+//! anymore. Counts are kept in memory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::catalog::now_ms;
+use crate::polars_proto::{
+    ClientLimits, ClientVersionCount, Deprecation, HandshakeRequest, HandshakeResponse,
+    ServerCapabilities,
+};
+
+/// The latest API version of this server. Version 1 is the API before handshakes.
+pub const API_VERSION: u32 = 2;
+
+/// Optional features clients may ask for.
+pub const FEATURES: &[&str] = &[
+    // Uploads as IPC files.
+    "ipc_upload",
+    // Uploads as one canonical frame per column, decoded as they arrive.
+    "canonical_columns_upload",
+    "canonical_fetch",
+    // Uploads and fetches as Arrow IPC streams, selected by their `format`.
+    "ipc_stream",
+    "delta_fetch",
+    "column_order_fetch",
+    // `identifier@version` entry points, see [`crate::versions`].
+    "version_selectors",
+];
+
+/// The API version of a client that asked for `api_version`, 0 meaning it did not say.
+fn client_api_version(api_version: u32) -> u32 {
+    api_version.max(1)
+}
+
+/// Answers `request`. `deprecated` maps deprecated features to their sunset dates.
+pub fn negotiate(
+    request: &HandshakeRequest,
+    capabilities: ServerCapabilities,
+    auth_mode: &str,
+    limits: ClientLimits,
+    deprecated: &HashMap<String, String>,
+) -> HandshakeResponse {
+    let mut enabled_features = Vec::new();
+    let mut deprecations = Vec::new();
+    for feature in request.features.iter() {
+        if enabled_features.contains(feature) || !FEATURES.contains(&feature.as_str()) {
+            continue;
+        }
+        enabled_features.push(feature.clone());
+        if let Some(sunset) = deprecated.get(feature) {
+            deprecations.push(Deprecation {
+                feature: feature.clone(),
+                sunset: sunset.clone(),
+            });
+        }
+    }
+    HandshakeResponse {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        api_version: client_api_version(request.api_version).min(API_VERSION),
+        enabled_features,
+        capabilities: Some(capabilities),
+        auth_mode: auth_mode.to_string(),
+        limits: Some(limits),
+        deprecations,
+    }
+}
+
+/// Handshakes counted by client name, version and API version.
+#[derive(Debug, Default)]
+pub struct ClientVersions {
+    counts: RwLock<HashMap<(String, String, u32), (u64, u64)>>,
+}
+
+impl ClientVersions {
+    pub fn record(&self, request: &HandshakeRequest) {
+        let key = (
+            request.client_name.clone(),
+            request.client_version.clone(),
+            client_api_version(request.api_version),
+        );
+        let mut counts = self.counts.write().unwrap();
+        let (handshakes, last_seen) = counts.entry(key).or_default();
+        *handshakes += 1;
+        *last_seen = now_ms();
+    }
+
+    /// Most handshakes first, then by client name and version.
+    pub fn distribution(&self) -> Vec<ClientVersionCount> {
+        let counts = self.counts.read().unwrap();
+        let sorted: BTreeMap<_, _> = counts
+            .iter()
+            .map(|(key, counts)| ((std::cmp::Reverse(counts.0), key), counts.1))
+            .collect();
+        sorted
+            .into_iter()
+            .map(
+                |((handshakes, (client_name, client_version, api_version)), last_seen)| {
+                    ClientVersionCount {
+                        client_name: client_name.clone(),
+                        client_version: client_version.clone(),
+                        api_version: *api_version,
+                        handshakes: handshakes.0,
+                        last_seen,
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(api_version: u32, features: &[&str]) -> HandshakeRequest {
+        HandshakeRequest {
+            client_name: String::from("bastionlab_rust"),
+            client_version: String::from("0.3.7"),
+            api_version,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn newer_clients_negotiate_down() {
+        let deprecated = HashMap::from([(String::from("ipc_upload"), String::from("2027-06-30"))]);
+        let features = [
+            "ipc_upload",
+            "canonical_fetch",
+            // Uploads and fetches as Arrow IPC streams, selected by their `format`.
+            "ipc_stream",
+            "quantum_fetch",
+            "ipc_upload",
+        ];
+        let response = negotiate(
+            &request(API_VERSION + 3, &features),
+            ServerCapabilities::default(),
+            "open",
+            ClientLimits::default(),
+            &deprecated,
+        );
+        assert_eq!(response.api_version, API_VERSION);
+        assert_eq!(response.enabled_features, ["ipc_upload", "canonical_fetch"]);
+        assert_eq!(
+            response.deprecations,
+            [Deprecation {
+                feature: String::from("ipc_upload"),
+                sunset: String::from("2027-06-30"),
+            }]
+        );
+
+        let response = negotiate(
+            &request(0, &[]),
+            ServerCapabilities::default(),
+            "open",
+            ClientLimits::default(),
+            &deprecated,
+        );
+        assert_eq!(response.api_version, 1);
+        assert!(response.deprecations.is_empty());
+
+        let versions = ClientVersions::default();
+        versions.record(&request(2, &[]));
+        versions.record(&request(2, &[]));
+        versions.record(&request(0, &[]));
+        let distribution = versions.distribution();
+        let counts: Vec<_> = distribution
+            .iter()
+            .map(|v| (v.api_version, v.handshakes))
+            .collect();
+        assert_eq!(counts, [(2, 2), (1, 1)]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Joins of two inputs of a composite plan, without writing a polars plan.
+//!
+//! A `JoinPlanSegment` joins the two dataframes on top of the stack, pushed by entry points or by
+//! previous segments: the one pushed first is the left side. Keys are checked against both sides
+//! before anything runs, so that a missing key or keys of different dtypes fail with an error
+//! naming them. The join then runs as a polars segment would: the result derives from both inputs,
+//! whose policies both apply to it, and joins scale the rows of each side as polars joins do.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Outer,
+    /// Every row of the left side with every row of the right side, without keys.
+    Cross,
+}
+
+impl JoinKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            JoinKind::Inner => "inner",
+            JoinKind::Left => "left",
+            JoinKind::Outer => "outer",
+            JoinKind::Cross => "cross",
+        }
+    }
+
+    fn join_type(self) -> Result<JoinType, Status> {
+        match self {
+            JoinKind::Inner => Ok(JoinType::Inner),
+            JoinKind::Left => Ok(JoinType::Left),
+            JoinKind::Outer => Ok(JoinType::Outer),
+            #[cfg(feature = "cross_join")]
+            JoinKind::Cross => Ok(JoinType::Cross),
+            #[cfg(not(feature = "cross_join"))]
+            JoinKind::Cross => Err(Status::unimplemented(
+                "This server was built without support for cross_join (cargo feature \
+                 `cross_join`)",
+            )),
+        }
+    }
+}
+
+/// Checks that `left_on` and `right_on` can join `left` with `right` in a join of kind `how`.
+pub fn check_keys(
+    left: &Schema,
+    right: &Schema,
+    left_on: &[String],
+    right_on: &[String],
+    how: JoinKind,
+) -> Result<(), Status> {
+    if how == JoinKind::Cross {
+        if !left_on.is_empty() || !right_on.is_empty() {
+            return Err(Status::invalid_argument(
+                "Could not join: cross joins take no keys",
+            ));
+        }
+        return Ok(());
+    }
+    if left_on.is_empty() || left_on.len() != right_on.len() {
+        return Err(Status::invalid_argument(format!(
+            "Could not join: {} left keys for {} right keys, each side needs as many and at \
+             least one",
+            left_on.len(),
+            right_on.len()
+        )));
+    }
+    for (left_key, right_key) in left_on.iter().zip(right_on) {
+        let dtype = |schema: &Schema, key: &str, side: &str| {
+            schema.get(key).cloned().ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Could not join: no column `{key}` in the {side} input"
+                ))
+            })
+        };
+        let left_dtype = dtype(left, left_key, "left")?;
+        let right_dtype = dtype(right, right_key, "right")?;
+        if left_dtype != right_dtype {
+            return Err(Status::invalid_argument(format!(
+                "Could not join `{left_key}` ({left_dtype}) with `{right_key}` ({right_dtype}): \
+                 keys must have the same dtype, cast one of them first"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The polars plan joining `left` with `right`, whose dataframe scans stand for the inputs, see
+/// [`crate::composite_plan`].
+pub fn plan(
+    left: &DataFrame,
+    right: &DataFrame,
+    left_on: &[String],
+    right_on: &[String],
+    how: JoinKind,
+) -> Result<LogicalPlan, Status> {
+    check_keys(&left.schema(), &right.schema(), left_on, right_on, how)?;
+    let keys = |keys: &[String]| keys.iter().map(|key| col(key)).collect::<Vec<_>>();
+    Ok(left
+        .head(Some(0))
+        .lazy()
+        .join(
+            right.head(Some(0)).lazy(),
+            keys(left_on),
+            keys(right_on),
+            how.join_type()?,
+        )
+        .logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn keys_must_exist_and_match() {
+        let left = df! { "id" => [1i64, 2], "name" => ["a", "b"] }.unwrap();
+        let right = df! { "id" => ["1", "2"], "patient" => [1i64, 2] }.unwrap();
+        let check = |left_on: &[&str], right_on: &[&str], how| {
+            check_keys(
+                &left.schema(),
+                &right.schema(),
+                &keys(left_on),
+                &keys(right_on),
+                how,
+            )
+        };
+
+        assert!(check(&["id"], &["patient"], JoinKind::Inner).is_ok());
+        assert!(check(&[], &[], JoinKind::Cross).is_ok());
+        let err = check(&["id"], &["id"], JoinKind::Left).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(
+            err.message().contains("`id` (i64) with `id` (str)"),
+            "{err:?}"
+        );
+        let err = check(&["id"], &["missing"], JoinKind::Inner).unwrap_err();
+        assert!(err
+            .message()
+            .contains("no column `missing` in the right input"));
+        assert!(check(&["id"], &[], JoinKind::Outer).is_err());
+        assert!(check(&[], &[], JoinKind::Inner).is_err());
+        assert!(check(&["id"], &["patient"], JoinKind::Cross).is_err());
+    }
+}
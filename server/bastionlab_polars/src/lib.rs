@@ -1,20 +1,30 @@
 use bastionlab_common::prelude::*;
 use bastionlab_common::{
     array_store::ArrayStore,
+    config::{BastionLabConfig, BlankColumnNames},
+    logging,
+    metrics::Metrics,
     session::SessionManager,
     session_proto::ClientInfo,
     telemetry::{self, TelemetryEventProps},
 };
 
 use polars::prelude::*;
+use prost::Message;
 use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::{thread_rng, SeedableRng};
+use rand::{thread_rng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::fs::{create_dir, read_dir, OpenOptions};
+use std::fs::create_dir;
 use std::io::{Error, ErrorKind};
-use std::{future::Future, pin::Pin, time::Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, Instant},
+};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Streaming};
 use utils::sanitize_df;
@@ -25,14 +35,37 @@ pub mod polars_proto {
 }
 
 use polars_proto::{
-    polars_service_server::PolarsService, Empty, FetchChunk, Query, ReferenceList,
-    ReferenceRequest, ReferenceResponse, SendChunk, SplitRequest,
+    polars_service_server::PolarsService, AliasRequest, AliasResponse, ApproveRequestRequest,
+    AuditLog as AuditLogProto, AuditLogRequest, BatchedQueryResult, BulkFailure, BulkResponse,
+    Capability, ClientLimits, ClientVersions as ClientVersionsProto, DataFrameVersion,
+    DeduplicateRequest, DefaultPolicyQuery, DefaultPolicyRequest, DefaultPolicyResponse,
+    DeleteWorkspaceRequest, Empty, ExportWaiver, ExportWaiverRequest, FamilyMember,
+    FamilyMembersRequest, FamilyRequest, FamilyResponse, FetchChunk, HandshakeRequest,
+    HandshakeResponse, KillQueryRequest, LifecycleResponse, ListDataFramesRequest,
+    OptimizeStorageRequest, OptimizeStorageResponse, OutputSlot,
+    PendingRequest as PendingRequestProto, PendingRequests, PipelineList, PipelineRequest,
+    PipelineResponse, PlanJobQuery, PlanJobRequest, PolicyHistory, PolicyRolloutReport,
+    PolicyRolloutRequest, QualityConstraintsRequest, QualityStatus, Query, QueryBatch,
+    QueryBatchResponse, QueryKill, RecompressRequest, RecompressResponse, ReferenceList,
+    ReferenceRequest, ReferenceResponse, RegisterFamilyRequest, RegisterPipelineRequest,
+    RegisterViewRequest, RejectRequestRequest, RemoteDataFrameRequest, RemoveFamilyMembersRequest,
+    ReproducibilityBundle, ReproducibilityReport, ResultShape, RetentionRequest, ReviewRequest,
+    RolloutRequest, RunningQueries as RunningQueriesProto, ScalarValue, SemanticsMigration,
+    SemanticsMigrationRequest, SemanticsMigrationResponse, SendChunk, ServerCapabilities,
+    ShareWorkspaceRequest, SplitRequest, StorageClassJob, StorageClassJobRequest,
+    StorageClassRequest, StorageClassUsage, SyntheticRequest, TransferReceipt, TransferRequest,
+    TransferResponse, UpdateDraftRequest, UpsertResponse, UsageReport, UsageReportRequest,
+    VersionList, VersionRetentionRequest, ViewRequest, ViewResponse, WatermarkMatch,
+    WatermarkTrace, WorkspaceList, WorkspaceManifest, WorkspaceMembersRequest, WorkspaceRequest,
+    WorkspaceResponse,
 };
 
-mod serialization;
+pub mod serialization;
 use serialization::*;
 
-mod composite_plan;
+pub mod canonical;
+
+pub mod composite_plan;
 use composite_plan::*;
 
 mod visitable;
@@ -42,20 +75,215 @@ use access_control::*;
 
 pub mod utils;
 
+mod scheduler;
+use scheduler::*;
+
+mod storage_optimization;
+use storage_optimization::*;
+
+mod probing;
+use probing::*;
+
+mod quality;
+use quality::*;
+
+pub mod watermark;
+use watermark::*;
+
+pub mod persistence;
+use persistence::*;
+
+mod upsert;
+use upsert::*;
+
+pub mod delta;
+
+mod reserved;
+use reserved::*;
+
+pub mod semantics;
+
+pub mod nan;
+
+pub mod synthetic;
+use semantics::{legacy_semantics, CURRENT_SEMANTICS};
+use synthetic::SyntheticOrigin;
+
+pub mod arrow_export;
+use arrow_export::*;
+
+pub mod families;
+use families::*;
+
+pub mod capabilities;
+
+pub mod plan_format;
+
+pub mod outputs;
+
+pub mod literal_frames;
+
+pub mod joins;
+
+pub mod aggregations;
+
+pub mod computed;
+pub mod filters;
+
+pub mod sorts;
+
+pub mod projections;
+pub mod sampling;
+pub mod slices;
+pub mod stacks;
+
+pub mod output_rows;
+use output_rows::CappedOutput;
+
+pub mod pipelines;
+use pipelines::{PipelineRegistry, PipelineVersion};
+
+pub mod catalog;
+use catalog::{CatalogEntry, Cursor, DataFrameKind, Listed, ListingFilter};
+
+pub mod views;
+use views::{ViewAnswer, ViewInfo, ViewRegistry};
+
+mod fetch_guard;
+use fetch_guard::{AccessGeneration, FetchGuard};
+
+pub mod lifecycle;
+use lifecycle::{Onboarding, Transition};
+
+pub mod aliases;
+use aliases::{Alias, AliasRegistry};
+
+pub mod shape;
+use shape::{check_scalar, result_shape, Scalar};
+
+pub mod memory;
+use memory::{MemoryReader, MemorySample, MemoryWatchdog, Pressure, ProcessMemory, Watermarks};
+
+pub mod tenant_keys;
+use tenant_keys::TenantKeyring;
+
+pub mod resources;
+use resources::ResourceDefaults;
+
+pub mod reproducibility;
+use reproducibility::{
+    content_hash, open_bundle, Bundle, BundleInput, BundleSigner, InputVersion, Provenance,
+};
+
+pub mod purpose;
+use purpose::{AccessKind, AccessLog, AccessRecord, Purpose, PurposeUsage};
+
+pub mod faults;
+use faults::StreamFaults;
+
+pub mod embedded;
+use embedded::EmbeddedStore;
+
+pub mod temporal;
+
+pub mod policy_engine;
+use policy_engine::{
+    Action, EvaluationContext, Grant, PolicyEngine, Released, Subject, Verdict, SERVER,
+};
+
+pub mod federation;
+use federation::{Federation, RemoteConnector, RemoteSource};
+
+pub mod activity;
+use activity::{ActivityEntry, ActivityLimits, FetchOutcome, RecentActivity};
+
+pub mod workspaces;
+use workspaces::{BulkOutcome, Manifest, ManifestEntry, WorkspaceInfo, WorkspaceRegistry};
+
+pub mod storage_classes;
+use storage_classes::{
+    AccessCounter, ClassJob, ClassJobs, ClassPolicy, ClassUsage, JobStatus, StorageClass,
+    StorageState,
+};
+
+pub mod rollouts;
+use rollouts::{
+    DatasetImpact, PolicyChange, PolicyPatch, PolicySelector, Rollout, RolloutRegistry,
+};
+
+pub mod statistics;
+use statistics::{ColumnStatistics, Statistics, Tolerance};
+
+pub mod masking;
+use masking::Masking;
+
+pub mod checkpoints;
+use checkpoints::{Checkpointer, Restored, CHECKPOINTS_DIR};
+
+pub mod plan_jobs;
+use plan_jobs::{PlanJob, PlanJobStatus, PlanJobs, JOBS_DIR};
+
+pub mod default_policies;
+use default_policies::{AppliedDefaults, DefaultPolicies, DefaultPolicy};
+
+pub mod versions;
+use versions::{Found, Retention, Selector, VersionHistory};
+
+pub mod handshake;
+use handshake::ClientVersions;
+
+pub mod exposure;
+
+pub mod batches;
+use batches::EntryPoints;
+
+pub mod waivers;
+use waivers::{Waiver, Waivers};
+
+pub mod running;
+use running::RunningQueries;
+
+pub mod approvals;
+use approvals::Approvals;
+
+pub mod audit;
+use audit::{AuditEntry, AuditFilter, AuditLog, AuditOutcome, AuditSink, MemorySink};
+
+pub mod fingerprints;
+use fingerprints::Fingerprints;
+
+pub mod expiry;
+use expiry::Expiry;
+
+pub mod differential_privacy;
+use differential_privacy::{NoisyRelease, PrivacyBudgets, SpentBudget};
+
 pub mod prelude {
     pub use bastionlab_common::prelude::*;
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum FetchStatus {
     Ok,
     Pending(String),
     Warning(String),
 }
 
+impl FetchStatus {
+    /// Adds `notice` to the status, turning `Ok` into a warning.
+    fn with_notice(self, notice: String) -> Self {
+        match self {
+            FetchStatus::Ok => FetchStatus::Warning(notice),
+            FetchStatus::Warning(reason) => FetchStatus::Warning(format!("{reason}\n{notice}")),
+            FetchStatus::Pending(reason) => FetchStatus::Pending(format!("{reason}\n{notice}")),
+        }
+    }
+}
+
 /// This a DataFrame intended to be streamed to the client.
 /// It can be delayed when the data owner's approval is required.
 pub struct DelayedDataFrame {
-    future: Pin<Box<dyn Future<Output = Result<DataFrame, Status>> + Send>>,
+    future: Pin<Box<dyn Future<Output = Result<Released, Status>> + Send>>,
     fetch_status: FetchStatus,
 }
 
@@ -66,8 +294,70 @@ pub struct DataFrameArtifact {
     fetchable: VerificationResult,
     blacklist: Vec<String>,
     query_details: String,
+    /// Columns whose stored dtype differs from the declared one after storage optimization.
+    #[serde(default)]
+    dtype_changes: Vec<DtypeChange>,
+    #[serde(default)]
+    quality: QualityMonitor,
+    /// Incremented on every append.
+    #[serde(default)]
+    version: u64,
+    /// Semantics version of the plan that produced the dataframe.
+    #[serde(default = "legacy_semantics")]
+    semantics_version: u32,
+    /// What the row cap of the policy did to this result, if anything.
+    #[serde(default)]
+    capped_output: Option<CappedOutput>,
+    /// Validation warnings of the plan that produced the dataframe, reported on fetches.
+    #[serde(default)]
+    warnings: Vec<String>,
+    /// Set on synthetic dataframes, see [`synthetic`].
+    #[serde(default)]
+    synthetic: Option<SyntheticOrigin>,
+    /// What the dataframe is listed by, see [`catalog`].
+    #[serde(default)]
+    catalog: CatalogEntry,
+    /// See [`lifecycle`].
+    #[serde(default)]
+    onboarding: Onboarding,
+    /// Set on query results, see [`reproducibility`].
+    #[serde(default)]
+    provenance: Option<Provenance>,
+    /// Purpose of the query that produced the dataframe, see [`purpose`].
+    #[serde(default)]
+    purpose: Option<Purpose>,
+    /// Set on federated dataframes, whose rows are held by another server, see [`federation`].
+    #[serde(default)]
+    remote: Option<RemoteSource>,
+    /// Recent queries reading the dataframe, see [`activity`].
+    #[serde(default)]
+    activity: RecentActivity,
+    /// See [`storage_classes`].
+    #[serde(default)]
+    storage: StorageState,
+    /// Changes of the policy since upload, see [`rollouts`].
+    #[serde(default)]
+    policy_history: Vec<PolicyChange>,
+    /// See [`statistics`].
+    #[serde(default)]
+    statistics: Statistics,
+    /// The default policy the upload took, see [`default_policies`].
+    #[serde(default)]
+    defaults: Option<AppliedDefaults>,
+    /// Superseded versions, see [`versions`].
+    #[serde(default)]
+    history: VersionHistory,
+    /// See [`fingerprints`].
+    #[serde(default)]
+    fingerprints: Fingerprints,
+    /// Set on results released with noise, see [`differential_privacy`].
+    #[serde(default)]
+    noise: Option<NoisyRelease>,
 }
 
+/// The query details of uploaded dataframes.
+const UPLOADED_DATAFRAME: &str = "uploaded dataframe";
+
 impl DataFrameArtifact {
     pub fn new(df: DataFrame, policy: Policy, blacklist: Vec<String>) -> Self {
         DataFrameArtifact {
@@ -78,7 +368,27 @@ impl DataFrameArtifact {
                 reason: String::from("DataFrames uploaded by the Data Owner are protected."),
             },
             blacklist,
-            query_details: String::from("uploaded dataframe"),
+            query_details: String::from(UPLOADED_DATAFRAME),
+            dtype_changes: Vec::new(),
+            quality: QualityMonitor::default(),
+            version: 0,
+            semantics_version: CURRENT_SEMANTICS,
+            capped_output: None,
+            warnings: Vec::new(),
+            synthetic: None,
+            catalog: CatalogEntry::default(),
+            onboarding: Onboarding::default(),
+            provenance: None,
+            purpose: None,
+            remote: None,
+            activity: RecentActivity::default(),
+            storage: StorageState::default(),
+            policy_history: Vec::new(),
+            statistics: Statistics::default(),
+            defaults: None,
+            history: VersionHistory::new(catalog::now_ms()),
+            fingerprints: Fingerprints::default(),
+            noise: None,
         }
     }
 
@@ -94,486 +404,6025 @@ impl DataFrameArtifact {
             blacklist: self.blacklist.clone(),
             fetchable: self.fetchable.clone(),
             query_details: self.query_details.clone(),
+            dtype_changes: Vec::new(),
+            quality: QualityMonitor::default(),
+            version: 0,
+            semantics_version: self.semantics_version,
+            capped_output: self.capped_output.clone(),
+            warnings: self.warnings.clone(),
+            synthetic: self.synthetic.clone(),
+            catalog: CatalogEntry::default(),
+            onboarding: self.onboarding.clone(),
+            provenance: None,
+            purpose: self.purpose.clone(),
+            remote: None,
+            activity: RecentActivity::default(),
+            storage: StorageState::default(),
+            policy_history: self.policy_history.clone(),
+            statistics: Statistics::default(),
+            defaults: None,
+            history: VersionHistory::new(catalog::now_ms()),
+            fingerprints: Fingerprints::default(),
+            noise: self.noise.clone(),
         }
     }
-}
-
-#[derive(Clone)]
-pub struct BastionLabPolars {
-    dataframes: Arc<RwLock<HashMap<String, DataFrameArtifact>>>,
-    arrays: Arc<RwLock<HashMap<String, ArrayStore>>>,
-    sess_manager: Arc<SessionManager>,
-}
 
-impl BastionLabPolars {
-    pub fn new(sess_manager: Arc<SessionManager>) -> Self {
-        Self {
-            dataframes: Arc::new(RwLock::new(HashMap::new())),
-            arrays: Arc::new(RwLock::new(HashMap::new())),
-            sess_manager,
-        }
+    /// Sets the policy of the dataframe, recording the change in its history, see [`rollouts`].
+    fn change_policy(&mut self, policy: Policy, rollout_id: &str, author: &str, rollback: bool) {
+        self.policy_history.push(PolicyChange {
+            version: self.policy_history.len() as u64 + 1,
+            rollout_id: rollout_id.to_string(),
+            changed_at: catalog::now_ms(),
+            author: author.to_string(),
+            rollback,
+            policy: policy.clone(),
+        });
+        self.policy = policy;
     }
 
-    fn get_df(
-        &self,
-        identifier: &str,
-        client_info: Option<ClientInfo>,
-    ) -> Result<DelayedDataFrame, Status> {
-        let dfs = self.dataframes.read().unwrap();
-        let artifact = dfs.get(identifier).ok_or_else(|| {
-            Status::not_found(format!(
-                "Could not find dataframe: identifier={}",
-                identifier
-            ))
-        })?;
-        if let VerificationResult::Unsafe { reason, .. } = &artifact.fetchable {
-            println!(
-                "Safe zone violation: a DataFrame has been non-privately fetched.
-Reason: {}",
-                reason
-            );
-        }
-        Ok(match &artifact.fetchable {
-            VerificationResult::Safe
-            | VerificationResult::Unsafe {
-                action: UnsafeAction::Log,
-                ..
-            } => {
-                let mut df = artifact.dataframe.clone();
-                sanitize_df(&mut df, &artifact.blacklist);
-                telemetry::add_event(
-                    TelemetryEventProps::FetchDataFrame {
-                        dataset_name: Some(identifier.to_owned()),
-                        request_accepted: true,
-                    },
-                    client_info,
-                );
-                DelayedDataFrame {
-                    future: Box::pin(async { Ok(df) }),
-                    fetch_status: if let VerificationResult::Unsafe {
-                        action: UnsafeAction::Log,
-                        reason,
-                    } = &artifact.fetchable
-                    {
-                        FetchStatus::Warning(String::from(reason))
-                    } else {
-                        FetchStatus::Ok
-                    },
-                }
-            }
-            VerificationResult::Unsafe {
-                action: UnsafeAction::Reject,
-                reason,
-            } => {
-                let reason = reason.clone();
-                DelayedDataFrame {
-                    future: Box::pin(async move {
-                        Err(Status::permission_denied(format!(
-                        "Cannot fetch this DataFrame: operation denied by the data owner's policy
-Reason: {}",
-                        reason,
-                    )))
-                    }),
-                    fetch_status: FetchStatus::Ok,
-                }
-            }
-            VerificationResult::Unsafe {
-                action: UnsafeAction::Review,
-                reason,
-            } => {
-                let reason = reason.clone();
-                let identifier = String::from(identifier);
-                let query_details = artifact.query_details.clone();
-                let dfs = Arc::clone(&self.dataframes);
-                DelayedDataFrame {
-                    fetch_status: FetchStatus::Pending(reason.clone()),
-                    future: Box::pin(async move {
-                        println!(
-                            "A user requests unsafe access to one of your DataFrames
-DataFrame identifier: {}
-Reason the request is unsafe:
-{}",
-                            identifier, reason,
-                        );
+    pub fn policy_history(&self) -> &[PolicyChange] {
+        &self.policy_history
+    }
 
-                        loop {
-                            let mut ans = String::new();
-                            println!("Accept [y], Reject [n], Show query details [s]?");
-                            std::io::stdin()
-                                .read_line(&mut ans)
-                                .expect("Failed to read line");
-
-                            match ans.trim() {
-                                "y" => break,
-                                "s" => {
-                                    println!(
-                                        "Query's Logical Plan:
-{}",
-                                        query_details,
-                                    );
-                                    continue;
-                                }
-                                "n" => {
-                                    telemetry::add_event(
-                                        TelemetryEventProps::FetchDataFrame {
-                                            dataset_name: Some(identifier.to_owned()),
-                                            request_accepted: false,
-                                        },
-                                        client_info,
-                                    );
-                                    return Err(Status::permission_denied(format!(
-                                        "The data owner rejected the fetch operation.
-Fetching a dataframe obtained with a non privacy-preserving query requires the approval of the data owner.
-This dataframe was obtained in a non privacy-preserving fashion.
-Reason: {}",
-                                        reason
-                                    )));
-                                }
-                                _ => continue,
-                            }
-                        }
-                        telemetry::add_event(
-                            TelemetryEventProps::FetchDataFrame {
-                                dataset_name: Some(identifier.to_owned()),
-                                request_accepted: true,
-                            },
-                            client_info,
-                        );
-                        Ok({
-                            let guard = dfs.read().unwrap();
-                            let artifact = guard.get(&identifier).ok_or_else(|| {
-                                Status::not_found(format!(
-                                    "Could not find dataframe: identifier={}",
-                                    identifier
-                                ))
-                            })?;
-                            let mut df = artifact.dataframe.clone();
-                            sanitize_df(&mut df, &artifact.blacklist);
-                            df
-                        })
-                    }),
-                }
-            }
-        })
+    pub fn defaults(&self) -> Option<&AppliedDefaults> {
+        self.defaults.as_ref()
     }
 
-    pub fn get_df_unchecked(&self, identifier: &str) -> Result<DataFrame, Status> {
-        let dfs = self.dataframes.read().unwrap();
-        Ok(dfs
-            .get(identifier)
-            .ok_or_else(|| {
-                Status::not_found(format!(
-                    "Could not find dataframe: identifier={}",
-                    identifier
-                ))
-            })?
-            .dataframe
-            .clone())
+    pub fn kind(&self) -> DataFrameKind {
+        if self.synthetic.is_some() {
+            DataFrameKind::Synthetic
+        } else if self.query_details == UPLOADED_DATAFRAME {
+            DataFrameKind::Upload
+        } else {
+            DataFrameKind::Result
+        }
     }
 
-    fn with_df_artifact_ref<T>(
-        &self,
-        identifier: &str,
-        mut f: impl FnMut(&DataFrameArtifact) -> T,
-    ) -> Result<T, Status> {
-        let dfs = self.dataframes.read().unwrap();
-        Ok(f(dfs.get(identifier).ok_or_else(|| {
-            Status::not_found(format!(
-                "Could not find dataframe: identifier={}",
-                identifier
-            ))
-        })?))
+    /// Sets the user listed as the owner of the dataframe.
+    pub fn with_owner(mut self, owner: &str) -> Self {
+        self.catalog.owner = owner.to_string();
+        self
     }
 
-    pub fn get_header(&self, identifier: &str) -> Result<String, Status> {
-        Ok(get_df_header(
-            &self
-                .dataframes
-                .read()
-                .unwrap()
-                .get(identifier)
-                .ok_or_else(|| {
-                    Status::not_found(format!(
-                        "Could not find dataframe: identifier={}",
-                        identifier
-                    ))
-                })?
-                .dataframe,
-        )?)
+    /// Fails if the rows of the dataframe are not in memory, see [`storage_classes`].
+    fn check_resident(&self) -> Result<(), Status> {
+        if !self.storage.resident {
+            return Err(Status::unavailable(
+                "The rows of the dataframe were evicted to disk in the meantime, please retry",
+            ));
+        }
+        Ok(())
     }
 
-    fn get_headers(&self) -> Result<Vec<(String, String)>, Status> {
-        let dataframes = self.dataframes.read().unwrap();
-        let mut res = Vec::with_capacity(dataframes.len());
-        for (k, v) in dataframes.iter() {
-            let header = get_df_header(&v.dataframe)?;
-            res.push((k.clone(), header));
+    /// Estimated size of the rows, in bytes, whether they are in memory or not.
+    pub fn size(&self) -> u64 {
+        match self.storage.resident {
+            true => self.dataframe.estimated_size() as u64,
+            false => self.storage.evicted_bytes,
         }
-        Ok(res)
     }
 
-    pub fn insert_df(&self, df: DataFrameArtifact) -> String {
-        let mut dfs = self.dataframes.write().unwrap();
-        let identifier = format!("{}", Uuid::new_v4());
-        dfs.insert(identifier.clone(), df);
-        identifier
+    /// Returns a copy of the dataframe with the dtypes it was declared with. The copy shares the
+    /// buffers of the stored columns, only those with changed dtypes are converted.
+    pub fn declared_dataframe(&self) -> Result<DataFrame, Status> {
+        self.check_resident()?;
+        let mut df = self.dataframe.clone();
+        restore_dtypes(&mut df, &self.dtype_changes)?;
+        Ok(df)
     }
 
-    pub fn insert_array(&self, array: ArrayStore) -> String {
-        let mut arrays = self.arrays.write().unwrap();
-        let identifier = format!("{}", Uuid::new_v4());
-        arrays.insert(identifier.clone(), array);
-        identifier
+    /// The masks of the policy, with the percentiles they read, see [`masking`].
+    pub fn masking(&self) -> Result<Masking, Status> {
+        Masking::resolve(self.policy.column_masks(), &self.statistics)
     }
 
-    pub fn get_array(&self, identifier: &str) -> Result<ArrayStore, Status> {
-        let arrays = self.arrays.read().unwrap();
-        let arr = arrays
-            .get(identifier)
-            .cloned()
-            .ok_or(Status::invalid_argument(format!(
-                "Could not find Array: {identifier}"
-            )))?;
-        Ok(arr)
+    /// The header sent to clients: the declared schema, with the origin of synthetic dataframes.
+    /// See [`shape`].
+    pub fn shape(&self) -> ResultShape {
+        result_shape(self.dataframe.height(), &self.declared_schema())
     }
 
-    fn persist_df(&self, identifier: &str) -> Result<(), Status> {
-        let dataframes = self
-            .dataframes
-            .read()
-            .map_err(|_| Status::internal("Unable to read dataframes!"))?;
+    /// The fingerprint of the dataframe, and those of its inputs if it is a result, see
+    /// [`fingerprints`].
+    fn fingerprint(&mut self) -> Result<(String, HashMap<String, String>), Status> {
+        if !self.fingerprints.is_cached() {
+            self.check_resident()?;
+        }
+        let schema = self.declared_schema();
+        let content = self
+            .fingerprints
+            .get(&self.dataframe, &schema, self.version)?;
+        Ok(match &self.provenance {
+            Some(provenance) => (
+                fingerprints::compose(&content, &provenance.fingerprints),
+                provenance.fingerprints.clone(),
+            ),
+            None => (content, HashMap::new()),
+        })
+    }
 
-        let df_artifact = dataframes
-            .get(identifier)
-            .ok_or_else(|| Status::not_found("Unable to find dataframe!"))?;
+    pub fn header(&self) -> Result<String, Status> {
+        let header = get_schema_header(&self.declared_schema())?;
+        let origin = match &self.synthetic {
+            Some(origin) => origin,
+            None => return Ok(header),
+        };
+        let mut header: serde_json::Value = serde_json::from_str(&header)
+            .map_err(|e| Status::internal(format!("Could not tag the header: {e}")))?;
+        header["synthetic"] = serde_json::to_value(origin)
+            .map_err(|e| Status::internal(format!("Could not tag the header: {e}")))?;
+        Ok(header.to_string())
+    }
 
-        if df_artifact.policy.check_savable() != true {
-            return Err(Status::unknown("Dataframe is not savable"));
+    /// Returns the schema of the dataframe as it was declared, regardless of storage optimization.
+    pub fn declared_schema(&self) -> Schema {
+        let mut schema = self.dataframe.schema();
+        for change in self.dtype_changes.iter() {
+            schema.coerce_by_name(&change.column, change.declared.clone());
         }
+        schema
+    }
 
-        let error = create_dir("data_frames");
-        match error {
-            Ok(_) => {}
-            Err(err) => {
-                if err.kind() != ErrorKind::AlreadyExists {
-                    return Err(Status::unknown(err.kind().to_string()));
-                }
-            }
+    /// Shrinks the stored dtypes of the dataframe, see [`optimize_storage`].
+    pub fn optimize_storage(&mut self, allow_lossy_floats: bool) -> Result<StorageReport, Status> {
+        self.check_resident()?;
+        let report = optimize_storage(&mut self.dataframe, allow_lossy_floats)?;
+        if allow_lossy_floats && !report.changes.is_empty() {
+            self.statistics.replaced();
+            self.fingerprints.replaced();
         }
+        self.dtype_changes.extend(report.changes.iter().cloned());
+        Ok(report)
+    }
 
-        let path = format!("data_frames/{}.json", identifier);
-        let df_store = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path)
-            .map_err(|_| Status::internal("Unable to find or create storage file!"))?;
-
-        serde_json::to_writer(df_store, df_artifact)
-            .map_err(|_| Status::internal("Could not serialize dataframe artifact!"))?;
+    /// Checks that `delta` has the declared schema and casts it to the stored dtypes.
+    ///
+    /// Columns whose new values do not fit in their optimized dtype are restored to the declared one.
+    fn align_append(&mut self, mut delta: DataFrame) -> Result<DataFrame, Status> {
+        if delta.schema() != self.declared_schema() {
+            return Err(Status::invalid_argument(format!(
+                "Appended rows must have the schema of the dataframe: expected {:?}, got {:?}",
+                self.declared_schema(),
+                delta.schema()
+            )));
+        }
 
-        Ok(())
+        let mut restored = Vec::new();
+        for change in self.dtype_changes.iter() {
+            let idx = delta.find_idx_by_name(&change.column).unwrap();
+            let series = delta.get_columns_mut().get_mut(idx).unwrap();
+            match series.strict_cast(&change.stored) {
+                Ok(s) => *series = s,
+                Err(_) => restored.push(change.clone()),
+            }
+        }
+        if !restored.is_empty() {
+            restore_dtypes(&mut self.dataframe, &restored)?;
+            self.dtype_changes
+                .retain(|c| !restored.iter().any(|r| r.column == c.column));
+        }
+        Ok(delta)
     }
+}
 
-    pub fn load_dfs(&self) -> Result<(), Error> {
-        let files = read_dir("data_frames")?;
-
-        for file in files {
-            let file = file?;
-            let identifier = file.file_name().to_str().unwrap().replace(".json", "");
+/// Logs and records the breaches found by a quality check, failing if the mutation is blocked.
+fn record_quality_check(
+    identifier: &str,
+    artifact: &mut DataFrameArtifact,
+    check: QualityCheck,
+    operation: &str,
+) -> Result<(), Status> {
+    for event in check.events.iter() {
+        warn!(
+            "Data-quality constraint {} breached on {} (version {}): {}{}",
+            event.constraint,
+            identifier,
+            event.version,
+            event.observed,
+            if event.blocked {
+                format!(", {operation} rejected")
+            } else {
+                String::new()
+            }
+        );
+    }
+    let blocked: Vec<_> = check
+        .events
+        .iter()
+        .filter(|e| e.blocked)
+        .map(|e| e.constraint.clone())
+        .collect();
+    artifact.quality.commit(check);
+    if !blocked.is_empty() {
+        return Err(Status::failed_precondition(format!(
+            "{} rejected by data-quality constraints: {}",
+            capitalize(operation),
+            blocked.join(", ")
+        )));
+    }
+    Ok(())
+}
 
-            let file = std::fs::OpenOptions::new()
-                .read(true)
-                .open(file.path().to_str().unwrap())?;
-            let reader = std::io::BufReader::new(file);
-            let df: DataFrameArtifact = serde_json::from_reader(reader)?;
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
 
-            let mut dfs = self.dataframes.write().unwrap();
-            dfs.insert(identifier, df);
-        }
-        Ok(())
+/// Releases the data of `identifier` to `recipient` under `grant`, without masks nor watermarks
+/// under export waiver `waiver` of `waivers`, which this uses up. Noisy releases spend the privacy
+/// budgets of `recipient` first, see [`differential_privacy`].
+fn release(
+    grant: Grant,
+    df: DataFrame,
+    identifier: &str,
+    recipient: &str,
+    watermarker: &Watermarker,
+    budgets: &PrivacyBudgets,
+    waiver: Option<(&Waivers, &str)>,
+) -> Result<Released, Status> {
+    if let Some(noise) = grant.noise() {
+        budgets.spend(noise, recipient)?;
     }
+    let (waivers, id) = match waiver {
+        Some(waiver) => waiver,
+        None => return grant.release(df, identifier, recipient, watermarker),
+    };
+    let released = grant
+        .waive()
+        .release(df, identifier, recipient, watermarker)?;
+    let hash = content_hash(released.dataframe())?;
+    let waiver = waivers.consume(id, identifier, recipient, hash, catalog::now_ms())?;
+    info!(
+        "Export waiver {id} of {} released {identifier} to {recipient} without masks nor \
+         watermarks, content hash {}. Justification: {}",
+        waiver.issuer,
+        waiver.used.as_ref().map_or("", |(_, hash)| hash.as_str()),
+        waiver.justification
+    );
+    Ok(released)
+}
 
-    pub fn delete_dfs(&self, identifier: &str) -> Result<(), Error> {
-        let mut dfs = self.dataframes.write().unwrap();
-        dfs.remove(identifier);
+/// Shows an approval prompt to the data owner, on the console: prompts are not logs, and the
+/// requests are decided on with `ApproveRequest` and `RejectRequest`, see [`approvals`].
+fn prompt(line: std::fmt::Arguments) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout().lock();
+    // Requests are listed by `ListPendingRequests` too, should the console be closed.
+    let _ = writeln!(stdout, "{line}").and_then(|_| stdout.flush());
+}
 
-        let path = "data_frames/".to_owned() + identifier + ".json";
-        std::fs::remove_file(path).unwrap_or(());
-        Ok(())
+/// The lines of an approval prompt stating the purposes of the fetch and of the query that
+/// produced the dataframe.
+fn approval_purposes(fetch: Option<&Purpose>, query: Option<&Purpose>) -> String {
+    let mut lines = String::new();
+    if let Some(purpose) = fetch {
+        lines.push_str(&format!("Purpose of the fetch: {purpose}\n"));
     }
+    if let Some(purpose) = query {
+        lines.push_str(&format!("Purpose of the query: {purpose}\n"));
+    }
+    lines
 }
 
-fn get_df_header(df: &DataFrame) -> Result<String, Status> {
-    serde_json::to_string(&df.schema())
-        .map_err(|e| Status::internal(format!("Could not serialize data frame header: {}", e)))
+/// The dataframe of `artifact` as it is fetched, before the transformations of the policy.
+fn fetched_dataframe(
+    artifact: &DataFrameArtifact,
+    restore_dtypes: bool,
+) -> Result<DataFrame, Status> {
+    let mut df = if restore_dtypes {
+        artifact.declared_dataframe()?
+    } else {
+        artifact.check_resident()?;
+        artifact.dataframe.clone()
+    };
+    strip_internal_columns(&mut df);
+    Ok(df)
 }
 
-#[tonic::async_trait]
-impl PolarsService for BastionLabPolars {
-    type FetchDataFrameStream = ReceiverStream<Result<FetchChunk, Status>>;
+/// Checks the `columns` a fetch of `identifier` is restricted to: each must be a column of the
+/// dataframe, once, and blacklisted ones are refused rather than sent empty.
+fn check_fetched_columns(
+    identifier: &str,
+    artifact: &DataFrameArtifact,
+    columns: &[String],
+) -> Result<(), Status> {
+    let schema = artifact.declared_schema();
+    let unknown: Vec<_> = columns
+        .iter()
+        .filter(|name| is_reserved(name) || schema.get(name).is_none())
+        .map(|name| format!("`{name}`"))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(Status::invalid_argument(format!(
+            "Could not fetch {identifier}: it has no column {}",
+            unknown.join(", ")
+        )));
+    }
+    if let Some(name) = columns
+        .iter()
+        .enumerate()
+        .find_map(|(i, name)| columns[..i].contains(name).then_some(name))
+    {
+        return Err(Status::invalid_argument(format!(
+            "Could not fetch {identifier}: column `{name}` is requested twice"
+        )));
+    }
+    let blacklisted: Vec<_> = columns
+        .iter()
+        .filter(|name| artifact.blacklist.contains(name))
+        .map(|name| format!("`{name}`"))
+        .collect();
+    if !blacklisted.is_empty() {
+        return Err(Status::permission_denied(format!(
+            "Could not fetch {identifier}: the policy blacklists column {}",
+            blacklisted.join(", ")
+        )));
+    }
+    Ok(())
+}
 
-    async fn run_query(
-        &self,
-        request: Request<Query>,
-    ) -> Result<Response<ReferenceResponse>, Status> {
-        let token = self.sess_manager.get_token(&request)?;
+fn quality_status(artifact: &DataFrameArtifact) -> Result<String, Status> {
+    serde_json::to_string(&artifact.quality.status(artifact.version))
+        .map_err(|e| Status::internal(format!("Could not serialize the quality status: {e}")))
+}
 
-        let composite_plan: CompositePlan = serde_json::from_str(&request.get_ref().composite_plan)
-            .map_err(|e| {
+fn parse_family_members(
+    members: Vec<FamilyMember>,
+) -> Result<Vec<(String, PartitionValue)>, Status> {
+    members
+        .into_iter()
+        .map(|member| {
+            let value = serde_json::from_str(&member.partition_value).map_err(|e| {
                 Status::invalid_argument(format!(
-                    "Could not deserialize composite plan: {}{}",
-                    e,
-                    &request.get_ref().composite_plan
+                    "Could not parse the partition value of {}: {e}",
+                    member.identifier
                 ))
             })?;
-        let user_id = self.sess_manager.get_user_id(token.clone())?;
+            Ok((member.identifier, value))
+        })
+        .collect()
+}
 
-        let start_time = Instant::now();
+fn family_response(info: FamilyInfo) -> Result<FamilyResponse, Status> {
+    let serialize_err = |e: serde_json::Error| {
+        Status::internal(format!("Could not serialize family {}: {e}", info.name))
+    };
+    let members = info
+        .members
+        .iter()
+        .map(|(identifier, value)| {
+            Ok(FamilyMember {
+                identifier: identifier.clone(),
+                partition_value: serde_json::to_string(value).map_err(serialize_err)?,
+            })
+        })
+        .collect::<Result<_, Status>>()?;
+    Ok(FamilyResponse {
+        partition_key: serde_json::to_string(&info.key).map_err(serialize_err)?,
+        members,
+        header: match &info.schema {
+            Some(schema) => get_schema_header(schema)?,
+            None => String::new(),
+        },
+        name: info.name,
+    })
+}
+
+fn view_response(info: ViewInfo) -> ViewResponse {
+    ViewResponse {
+        base: info.base,
+        base_version: info.base_version,
+        groups: info.groups as u64,
+        incremental: info.incremental,
+        state: info.state.name().to_string(),
+        reason: info.state.reason().to_string(),
+        name: info.name,
+    }
+}
+
+fn alias_response(alias: String, entry: Alias) -> AliasResponse {
+    AliasResponse {
+        alias,
+        canonical: entry.canonical,
+        reason: entry.reason,
+        created_at: entry.created_at,
+    }
+}
+
+fn workspace_response(info: WorkspaceInfo) -> WorkspaceResponse {
+    WorkspaceResponse {
+        name: info.name,
+        owner: info.owner,
+        created_at: info.created_at,
+        members: info.members,
+        shared_with: info.shared_with,
+    }
+}
+
+fn class_job_response(id: u64, job: ClassJob) -> StorageClassJob {
+    StorageClassJob {
+        job: id,
+        identifier: job.identifier,
+        storage_class: job.class.map_or("auto", |class| class.name()).to_string(),
+        status: job.status.name().to_string(),
+        error: match job.status {
+            JobStatus::Failed(error) => error,
+            _ => String::new(),
+        },
+    }
+}
+
+fn rollout_report(
+    rollout_id: String,
+    impacts: Vec<DatasetImpact>,
+    rolled_back: bool,
+) -> PolicyRolloutReport {
+    PolicyRolloutReport {
+        rollout_id,
+        impacts: impacts
+            .into_iter()
+            .map(|impact| polars_proto::DatasetImpact {
+                identifier: impact.identifier,
+                blocked_results: impact.blocked_results,
+                changed_approvals: impact.changed_approvals,
+                blocked_pipelines: impact.blocked_pipelines,
+            })
+            .collect(),
+        rolled_back,
+    }
+}
+
+fn bulk_failures(failures: Vec<(String, Status)>) -> Vec<BulkFailure> {
+    failures
+        .into_iter()
+        .map(|(identifier, e)| BulkFailure {
+            identifier,
+            error: e.message().to_string(),
+        })
+        .collect()
+}
+
+fn bulk_response(outcome: BulkOutcome) -> BulkResponse {
+    BulkResponse {
+        succeeded: outcome.succeeded,
+        failures: bulk_failures(outcome.failures),
+    }
+}
+
+fn listing_filter(request: &ListDataFramesRequest) -> ListingFilter {
+    let non_empty = |s: &String| (!s.is_empty()).then(|| s.clone());
+    ListingFilter {
+        owner: non_empty(&request.owner),
+        tag: non_empty(&request.tag),
+        name_prefix: non_empty(&request.name_prefix),
+        created_after: request.created_after,
+        kind: match request.kind() {
+            polars_proto::DataFrameKind::AnyKind => None,
+            polars_proto::DataFrameKind::Upload => Some(DataFrameKind::Upload),
+            polars_proto::DataFrameKind::Result => Some(DataFrameKind::Result),
+            polars_proto::DataFrameKind::Synthetic => Some(DataFrameKind::Synthetic),
+        },
+        min_size: request.min_size,
+        max_size: request.max_size,
+    }
+}
+
+fn pipeline_response(pipeline: PipelineVersion) -> Result<PipelineResponse, Status> {
+    let serialize_err = |e: serde_json::Error| {
+        Status::internal(format!(
+            "Could not serialize pipeline {}: {e}",
+            pipeline.name
+        ))
+    };
+    Ok(PipelineResponse {
+        visibility: serde_json::to_string(&pipeline.visibility).map_err(serialize_err)?,
+        parameters: serde_json::to_string(&pipeline.parameters).map_err(serialize_err)?,
+        plan: serde_json::to_string(&pipeline.plan).map_err(serialize_err)?,
+        name: pipeline.name,
+        version: pipeline.version,
+        author: pipeline.author,
+    })
+}
+
+/// `identifier@version` of every dataframe a result read.
+fn input_versions(provenance: Option<&Provenance>) -> Vec<String> {
+    provenance.map_or(Vec::new(), |provenance| {
+        provenance.inputs.iter().map(InputVersion::key).collect()
+    })
+}
+
+fn version_list(identifier: &str, artifact: &DataFrameArtifact) -> VersionList {
+    let history = &artifact.history;
+    let mut versions: Vec<_> = history
+        .retained()
+        .map(|retained| DataFrameVersion {
+            version: retained.version,
+            created_at: retained.created_at,
+            rows: retained.rows() as u64,
+            expires_at: history.expires_at(retained).unwrap_or(0),
+            current: false,
+            unshared_bytes: retained.unshared_bytes,
+        })
+        .collect();
+    versions.push(DataFrameVersion {
+        version: artifact.version,
+        created_at: history.created_at,
+        rows: artifact.dataframe.height() as u64,
+        expires_at: 0,
+        current: true,
+        unshared_bytes: artifact.size(),
+    });
+    VersionList {
+        identifier: identifier.to_string(),
+        versions,
+        max_versions: history.retention.max_versions,
+        max_age_secs: history.retention.max_age_secs,
+        retained_bytes: history.retained_bytes(),
+        current_bytes: artifact.size(),
+    }
+}
+
+fn default_policy_response(defaults: DefaultPolicy) -> Result<DefaultPolicyResponse, Status> {
+    let policy = serde_json::to_string(&defaults.template).map_err(|e| {
+        Status::internal(format!(
+            "Could not serialize the default policy of {}: {e}",
+            defaults.scope
+        ))
+    })?;
+    Ok(DefaultPolicyResponse {
+        scope: defaults.scope,
+        group: defaults.group,
+        version: defaults.version,
+        author: defaults.author,
+        set_at: defaults.set_at,
+        members: defaults.members,
+        policy,
+        blacklist_patterns: defaults.blacklist_patterns,
+    })
+}
+
+/// The watermarks of `config` in bytes, if the memory watchdog is enabled.
+fn memory_watermarks(config: &BastionLabConfig) -> Option<Watermarks> {
+    let mb = |mb: u64| mb.saturating_mul(1 << 20);
+    (config.memory_soft_watermark_mb > 0).then(|| Watermarks {
+        soft: mb(config.memory_soft_watermark_mb),
+        hard: match config.memory_hard_watermark_mb {
+            0 => u64::MAX,
+            hard => mb(hard.max(config.memory_soft_watermark_mb)),
+        },
+        recovery: mb(config.memory_recovery_mb),
+    })
+}
+
+/// Where dataframes are persisted, relative to the working directory, unless set otherwise.
+pub const DEFAULT_DATA_DIR: &str = "data_frames";
+
+#[derive(Clone)]
+pub struct BastionLabPolars {
+    dataframes: Arc<RwLock<HashMap<String, DataFrameArtifact>>>,
+    arrays: Arc<RwLock<HashMap<String, ArrayStore>>>,
+    sess_manager: Arc<SessionManager>,
+    scheduler: Arc<QueryScheduler>,
+    probing: Arc<ProbingDetector>,
+    watermarker: Arc<Watermarker>,
+    persistence: PersistenceSettings,
+    data_dir: PathBuf,
+    blank_column_names: BlankColumnNames,
+    exports: Arc<ExportRegistry>,
+    families: Arc<FamilyRegistry>,
+    pipelines: Arc<PipelineRegistry>,
+    views: Arc<ViewRegistry>,
+    access: Arc<AccessGeneration>,
+    fetch_checkpoint_chunks: usize,
+    publish_uploads: bool,
+    dataset_reviewers: Vec<String>,
+    aliases: Arc<AliasRegistry>,
+    memory: Arc<MemoryWatchdog>,
+    idle_result_age: Duration,
+    expiry: Arc<Expiry>,
+    tenant_keys: Arc<TenantKeyring>,
+    resources: ResourceDefaults,
+    bundle_signer: Arc<BundleSigner>,
+    access_log: Arc<AccessLog>,
+    waivers: Arc<Waivers>,
+    running: Arc<RunningQueries>,
+    approvals: Arc<Approvals>,
+    privacy_budgets: Arc<PrivacyBudgets>,
+    audit: Arc<AuditLog>,
+    fault_injection: bool,
+    embedded: Option<Arc<EmbeddedStore>>,
+    policy_engine: Arc<PolicyEngine>,
+    federation: Federation,
+    activity_limits: ActivityLimits,
+    workspaces: Arc<WorkspaceRegistry>,
+    plan_compatibility_mode: bool,
+    literal_frame_max_cells: usize,
+    slice_max_rows: usize,
+    class_policy: ClassPolicy,
+    accesses: Arc<AccessCounter>,
+    class_jobs: Arc<ClassJobs>,
+    rollouts: Arc<RolloutRegistry>,
+    plan_jobs: Arc<PlanJobs>,
+    plan_job_max_attempts: u32,
+    plan_checkpoint_expiry_ms: u64,
+    default_policies: Arc<DefaultPolicies>,
+    max_upload_bytes: u64,
+    upload_chunk_bytes: u64,
+    fetch_chunk_bytes: usize,
+    deprecated_features: HashMap<String, String>,
+    client_versions: Arc<ClientVersions>,
+    max_queries_per_batch: usize,
+    query_batch_parallelism: usize,
+    inline_result_bytes: u64,
+    metrics: Arc<Metrics>,
+    /// Called with the identifier of every dataframe about to be written, to hold writes.
+    #[cfg(test)]
+    store_hook: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+}
+
+impl BastionLabPolars {
+    pub fn new(sess_manager: Arc<SessionManager>, config: &BastionLabConfig) -> Self {
+        let polars = Self {
+            dataframes: Arc::new(RwLock::new(HashMap::new())),
+            arrays: Arc::new(RwLock::new(HashMap::new())),
+            sess_manager,
+            scheduler: Arc::new(QueryScheduler::new(
+                config.query_concurrency,
+                config.batch_query_share,
+                config.query_queue_depth,
+            )),
+            probing: Arc::new(ProbingDetector::new(ProbingConfig {
+                window: Duration::from_secs(config.probing_window_secs),
+                literal_variants: config.probing_literal_variants,
+                count_pairs: config.probing_count_pairs,
+                history: config.probing_history,
+            })),
+            watermarker: Arc::new(Watermarker::new(config.watermark_record_capacity)),
+            persistence: PersistenceSettings {
+                zstd_level: config.persistence_zstd_level,
+                dictionary_ratio: config.persistence_dictionary_ratio,
+            },
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            blank_column_names: config.blank_column_names,
+            exports: Default::default(),
+            families: Default::default(),
+            pipelines: Default::default(),
+            views: Default::default(),
+            access: Default::default(),
+            fetch_checkpoint_chunks: config.fetch_checkpoint_chunks,
+            publish_uploads: config.publish_uploads,
+            dataset_reviewers: config.dataset_reviewers.clone(),
+            aliases: Arc::new(AliasRegistry::new(
+                (config.alias_retention_secs > 0)
+                    .then(|| Duration::from_secs(config.alias_retention_secs)),
+            )),
+            memory: Arc::new(MemoryWatchdog::new(
+                Box::new(ProcessMemory),
+                memory_watermarks(config),
+            )),
+            idle_result_age: Duration::from_secs(config.memory_idle_result_secs),
+            expiry: Arc::new(Expiry::new(
+                (config.dataframe_ttl_secs > 0)
+                    .then(|| Duration::from_secs(config.dataframe_ttl_secs)),
+                catalog::now_ms(),
+            )),
+            tenant_keys: Arc::new(TenantKeyring::disabled()),
+            resources: ResourceDefaults {
+                max_memory_mb: config.segment_max_memory_mb,
+                allow_spill: config.segment_allow_spill,
+                spill_dir: match config.spill_dir.as_str() {
+                    "" => std::env::temp_dir(),
+                    dir => PathBuf::from(dir),
+                },
+                spill_quota: config.spill_quota_mb << 20,
+            },
+            bundle_signer: Arc::new(BundleSigner::ephemeral()),
+            access_log: Arc::new(AccessLog::new(config.access_log_capacity)),
+            waivers: Arc::new(Waivers::default()),
+            running: Default::default(),
+            approvals: Arc::new(Approvals::new(Duration::from_secs(
+                config.approval_timeout_secs,
+            ))),
+            privacy_budgets: Default::default(),
+            audit: Arc::new(AuditLog::new(vec![Box::new(MemorySink::new(
+                config.access_log_capacity,
+            ))])),
+            fault_injection: config.fault_injection,
+            embedded: None,
+            policy_engine: Arc::new(PolicyEngine::new(
+                config.strict_governance,
+                config.access_log_capacity,
+            )),
+            federation: Federation::new(Duration::from_secs(config.federation_timeout_secs)),
+            activity_limits: ActivityLimits {
+                entries: config.recent_activity_entries,
+                max_age_ms: config.recent_activity_max_age_secs.saturating_mul(1000),
+            },
+            workspaces: Default::default(),
+            plan_compatibility_mode: config.plan_compatibility_mode,
+            literal_frame_max_cells: config.literal_frame_max_cells,
+            slice_max_rows: config.slice_max_rows,
+            class_policy: ClassPolicy {
+                hot_accesses: config.storage_hot_accesses,
+                cold_min_bytes: config.storage_cold_min_mb.saturating_mul(1 << 20),
+                cold_accesses: config.storage_cold_accesses,
+                window: Duration::from_secs(config.storage_access_window_secs),
+                hot_budget: config.storage_hot_budget_mb.saturating_mul(1 << 20),
+                cold_residency: Duration::from_secs(config.storage_cold_residency_secs),
+            },
+            accesses: Default::default(),
+            class_jobs: Default::default(),
+            rollouts: Arc::new(RolloutRegistry::new(
+                config.policy_rollout_retention_secs.saturating_mul(1000),
+            )),
+            plan_jobs: Default::default(),
+            plan_job_max_attempts: config.plan_job_max_attempts,
+            plan_checkpoint_expiry_ms: config.plan_checkpoint_expiry_secs.saturating_mul(1000),
+            default_policies: Default::default(),
+            max_upload_bytes: config.max_upload_mb.saturating_mul(1 << 20),
+            upload_chunk_bytes: config.upload_chunk_kb.max(1).saturating_mul(1 << 10),
+            fetch_chunk_bytes: (config.fetch_chunk_kb as usize).saturating_mul(1 << 10),
+            deprecated_features: config.deprecated_features.clone(),
+            client_versions: Default::default(),
+            max_queries_per_batch: config.max_queries_per_batch,
+            query_batch_parallelism: config.query_batch_parallelism.max(1),
+            inline_result_bytes: config.inline_result_max_kb.saturating_mul(1 << 10),
+            metrics: Arc::new(Metrics::new()),
+            #[cfg(test)]
+            store_hook: None,
+        };
+        polars.sample_dataframes();
+        polars
+    }
+
+    /// Persists dataframes in `dir` instead of [`DEFAULT_DATA_DIR`].
+    pub fn with_data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = dir.into();
+        self
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Persists dataframes and aliases in the single file of `store` instead of the data
+    /// directory, see [`embedded`].
+    pub fn with_embedded_store(mut self, store: EmbeddedStore) -> Self {
+        self.embedded = Some(Arc::new(store));
+        self
+    }
+
+    fn is_persisted(&self, identifier: &str) -> bool {
+        match &self.embedded {
+            Some(store) => store.contains(identifier),
+            None => {
+                artifact_path(&self.data_dir, identifier).exists()
+                    || legacy_path(&self.data_dir, identifier).exists()
+            }
+        }
+    }
+
+    /// Records its metrics in `metrics`, shared with the connections, instead of its own.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self.sample_dataframes();
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Samples the dataframes stored on every scrape. The sampler holds them weakly, as the
+    /// metrics outlive the service.
+    fn sample_dataframes(&self) {
+        let dataframes = Arc::downgrade(&self.dataframes);
+        self.metrics.sample_dataframes(move || {
+            let Some(dataframes) = dataframes.upgrade() else {
+                return (0, 0);
+            };
+            let dfs = dataframes.read().unwrap();
+            let bytes = dfs
+                .values()
+                .map(|artifact| artifact.dataframe.estimated_size() as u64)
+                .sum();
+            (dfs.len() as u64, bytes)
+        });
+    }
+
+    /// Reads federated dataframes from their servers with `connector`, see [`federation`].
+    pub fn with_remote_connector(mut self, connector: Arc<dyn RemoteConnector>) -> Self {
+        self.federation = self.federation.with_connector(connector);
+        self
+    }
+
+    /// Encrypts persisted dataframes under the keys of their tenants, see [`tenant_keys`].
+    pub fn with_tenant_keys(mut self, keys: TenantKeyring) -> Self {
+        self.tenant_keys = Arc::new(keys);
+        self
+    }
+
+    /// Signs reproducibility bundles with `signer` instead of a key generated on startup, see
+    /// [`reproducibility`].
+    pub fn with_bundle_signer(mut self, signer: BundleSigner) -> Self {
+        self.bundle_signer = Arc::new(signer);
+        self
+    }
+
+    /// Watermarks fetches with `watermarker`, configured with its secret, instead of a secret
+    /// generated on startup, see [`watermark`].
+    pub fn with_watermarker(mut self, watermarker: Watermarker) -> Self {
+        self.watermarker = Arc::new(watermarker);
+        self
+    }
+
+    /// Writes the audit log to `sinks` instead of memory, see [`audit`].
+    pub fn with_audit_sinks(mut self, sinks: Vec<Box<dyn AuditSink>>) -> Self {
+        self.audit = Arc::new(AuditLog::new(sinks));
+        self
+    }
+
+    /// Reads memory usage from `reader` instead of procfs.
+    pub fn with_memory_reader(mut self, reader: Box<dyn MemoryReader>) -> Self {
+        self.memory = Arc::new(MemoryWatchdog::new(reader, self.memory.watermarks()));
+        self
+    }
+
+    /// Samples memory usage every `interval` in the background, which also applies the storage
+    /// classes, see [`storage_classes`].
+    pub fn watch_memory(&self, interval: Duration) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let state = state.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || state.sample_memory()).await {
+                    warn!("Memory sampling failed: {e}");
+                }
+            }
+        });
+    }
+
+    /// Samples memory usage and responds to the pressure, see [`memory`].
+    pub fn sample_memory(&self) -> MemorySample {
+        let tracked = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.values()
+                .map(|artifact| artifact.dataframe.estimated_size() as u64)
+                .sum()
+        };
+        let (previous, sample) = self.memory.sample(tracked);
+        if sample.pressure != previous {
+            let change = format!(
+                "Memory pressure went from {} to {} ({} MB used)",
+                previous.name(),
+                sample.pressure.name(),
+                sample.usage / (1 << 20)
+            );
+            if sample.pressure > previous {
+                warn!("{change}");
+            } else {
+                info!("{change}");
+            }
+            telemetry::add_event(
+                TelemetryEventProps::MemoryPressure {
+                    level: sample.pressure.name().to_string(),
+                    usage_bytes: sample.usage,
+                },
+                None,
+            );
+        }
+        if sample.pressure >= Pressure::Soft {
+            let collected = self.collect_idle_results();
+            if !collected.is_empty() {
+                warn!(
+                    "Deleted {} idle results under memory pressure: {}",
+                    collected.len(),
+                    collected.join(", ")
+                );
+            }
+            if previous < Pressure::Soft {
+                let saved = self.compact_dfs();
+                warn!("Compacted dataframes under memory pressure, saving {saved} bytes");
+            }
+        }
+        if sample.pressure == Pressure::Hard && previous < Pressure::Hard {
+            let persisted = self.persist_savable_dfs();
+            warn!("Persisted {persisted} savable dataframes under memory pressure");
+            let spilled = self.spill_warm_dfs();
+            if !spilled.is_empty() {
+                warn!(
+                    "Spilled {} warm dataframes to disk under memory pressure: {}",
+                    spilled.len(),
+                    spilled.join(", ")
+                );
+            }
+        }
+        self.apply_storage_classes();
+        sample
+    }
+
+    /// Deletes the results created longer ago than the idle age that nothing else depends on.
+    fn collect_idle_results(&self) -> Vec<String> {
+        let now = catalog::now_ms();
+        let cutoff = now.saturating_sub(self.idle_result_age.as_millis() as u64);
+        let idle: Vec<String> = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.iter()
+                .filter(|(identifier, artifact)| {
+                    artifact.storage.class != StorageClass::Hot
+                        && artifact.catalog.created_at < cutoff
+                        && self.is_disposable(identifier, artifact, now)
+                })
+                .map(|(identifier, _)| identifier.clone())
+                .collect()
+        };
+        idle.into_iter()
+            .filter(|identifier| self.delete_dfs(identifier).is_ok())
+            .collect()
+    }
+
+    /// Whether `artifact` is a result nothing else depends on: it is neither persisted, exported,
+    /// a family member, a view base nor an alias target, nor retained by a workspace. Deleting the
+    /// exported ones and family members fails.
+    fn is_disposable(&self, identifier: &str, artifact: &DataFrameArtifact, now: u64) -> bool {
+        artifact.kind() == DataFrameKind::Result
+            && artifact.catalog.retained_until < now
+            && !self.views.has_views(identifier)
+            && !self.aliases.is_canonical(identifier)
+            && !self.is_persisted(identifier)
+    }
+
+    /// Sweeps the dataframes every `interval` in the background for expired results, if results
+    /// have a TTL, see [`expiry`].
+    pub fn watch_expiry(&self, interval: Duration) {
+        if self.expiry.ttl().is_none() {
+            return;
+        }
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let state = state.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || state.sweep_expired()).await {
+                    warn!("Sweeping expired results failed: {e}");
+                }
+            }
+        });
+    }
+
+    /// Deletes the results unused for longer than their TTL, returning their identifiers.
+    pub fn sweep_expired(&self) -> Vec<String> {
+        let now = catalog::now_ms();
+        let expired: Vec<String> = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.iter()
+                .filter(|(identifier, artifact)| {
+                    self.expiry
+                        .is_expired(identifier, artifact.catalog.created_at, now)
+                        && self.is_disposable(identifier, artifact, now)
+                })
+                .map(|(identifier, _)| identifier.clone())
+                .collect()
+        };
+        let expired: Vec<String> = expired
+            .into_iter()
+            .filter(|identifier| self.delete_dfs(identifier).is_ok())
+            .collect();
+        for identifier in expired.iter() {
+            self.expiry.expired(identifier, now);
+        }
+        if !expired.is_empty() {
+            info!(
+                "Deleted {} expired results: {}",
+                expired.len(),
+                expired.join(", ")
+            );
+        }
+        expired
+    }
+
+    /// Shrinks the stored dtypes of every dataframe without loss, returning the bytes saved.
+    fn compact_dfs(&self) -> usize {
+        let mut dfs = self.dataframes.write().unwrap();
+        let mut saved = 0;
+        for (identifier, artifact) in dfs.iter_mut().filter(|(_, a)| a.storage.resident) {
+            match artifact.optimize_storage(false) {
+                Ok(report) => saved += report.bytes_before.saturating_sub(report.bytes_after),
+                Err(e) => warn!("Could not compact dataframe {identifier}: {}", e.message()),
+            }
+        }
+        saved
+    }
+
+    /// Whether the policy of `artifact` lets the server persist it.
+    fn is_savable(&self, identifier: &str, artifact: &DataFrameArtifact) -> bool {
+        self.policy_engine
+            .evaluate(
+                Action::Persist,
+                SERVER,
+                &[Subject::artifact(identifier, artifact)],
+                &EvaluationContext::default(),
+            )
+            .is_ok_and(|decision| decision.verdict().permits())
+    }
+
+    /// Persists every savable dataframe in memory, returning how many were.
+    fn persist_savable_dfs(&self) -> usize {
+        let savable: Vec<String> = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.iter()
+                .filter(|(identifier, artifact)| {
+                    artifact.storage.resident && self.is_savable(identifier, artifact)
+                })
+                .map(|(identifier, _)| identifier.clone())
+                .collect()
+        };
+        savable
+            .into_iter()
+            .filter(|identifier| match self.persist_df(identifier) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Could not persist dataframe {identifier}: {}", e.message());
+                    false
+                }
+            })
+            .count()
+    }
+
+    /// Records a read of the rows of `identifier` and loads them if they were evicted, see
+    /// [`storage_classes`].
+    fn touch(&self, identifier: &str) -> Result<(), Status> {
+        if self.dataframes.read().unwrap().contains_key(identifier) {
+            let now = catalog::now_ms();
+            self.accesses
+                .record(identifier, self.class_policy.window, now);
+            self.expiry.used(identifier, now);
+        }
+        self.load_evicted(identifier)
+    }
+
+    /// Loads the rows of `identifier` back from disk if they were evicted.
+    fn load_evicted(&self, identifier: &str) -> Result<(), Status> {
+        let evicted = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.get(identifier)
+                .is_some_and(|artifact| !artifact.storage.resident)
+        };
+        if !evicted {
+            return Ok(());
+        }
+        let loaded = match &self.embedded {
+            Some(store) => store.load_artifact(identifier, &self.tenant_keys),
+            None => load_artifact(
+                &artifact_path(&self.data_dir, identifier),
+                &self.tenant_keys,
+            ),
+        }
+        .map_err(|e| {
+            Status::unavailable(format!(
+                "Could not load dataframe {identifier} from disk: {}",
+                e.message()
+            ))
+        })?;
+        let mut dfs = self.dataframes.write().unwrap();
+        if let Some(artifact) = dfs.get_mut(identifier) {
+            if !artifact.storage.resident {
+                artifact.dataframe = loaded.dataframe;
+                artifact.storage.resident = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Persists `identifier` and drops its rows from memory, returning whether it was evicted:
+    /// hot and federated dataframes and the bases of views stay in memory.
+    fn evict(&self, identifier: &str) -> Result<bool, Status> {
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = match dfs.get_mut(identifier) {
+            Some(artifact) => artifact,
+            None => return Ok(false),
+        };
+        if !artifact.storage.resident
+            || artifact.storage.class == StorageClass::Hot
+            || artifact.remote.is_some()
+            || self.views.has_views(identifier)
+        {
+            return Ok(false);
+        }
+        self.store_df(identifier, artifact)?;
+        artifact.storage.evicted_bytes = artifact.dataframe.estimated_size() as u64;
+        artifact.dataframe = artifact.dataframe.head(Some(0));
+        artifact.storage.resident = false;
+        Ok(true)
+    }
+
+    /// Evicts the savable warm dataframes, returning their identifiers.
+    fn spill_warm_dfs(&self) -> Vec<String> {
+        let warm: Vec<String> = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.iter()
+                .filter(|(identifier, artifact)| {
+                    artifact.storage.class == StorageClass::Warm
+                        && artifact.storage.resident
+                        && self.is_savable(identifier, artifact)
+                })
+                .map(|(identifier, _)| identifier.clone())
+                .collect()
+        };
+        warm.into_iter()
+            .filter(|identifier| match self.evict(identifier) {
+                Ok(evicted) => evicted,
+                Err(e) => {
+                    warn!("Could not spill dataframe {identifier}: {}", e.message());
+                    false
+                }
+            })
+            .collect()
+    }
+
+    /// Classifies the unpinned dataframes by their size and accesses, and evicts the cold
+    /// dataframes not read for the cold residency.
+    fn apply_storage_classes(&self) {
+        let now = catalog::now_ms();
+        let policy = self.class_policy;
+        let mut changed = Vec::new();
+        {
+            let mut dfs = self.dataframes.write().unwrap();
+            let mut hot: u64 = dfs
+                .values()
+                .filter(|artifact| artifact.storage.class == StorageClass::Hot)
+                .map(|artifact| artifact.size())
+                .sum();
+            let mut identifiers: Vec<String> = dfs.keys().cloned().collect();
+            identifiers.sort();
+            for identifier in identifiers {
+                let artifact = dfs.get(&identifier).unwrap();
+                if artifact.storage.pinned || artifact.remote.is_some() {
+                    continue;
+                }
+                let size = artifact.size();
+                let accesses = self.accesses.count(&identifier, policy.window, now);
+                let class = match policy.classify(size, accesses) {
+                    StorageClass::Hot if policy.check_budget(hot, size).is_err() => {
+                        StorageClass::Warm
+                    }
+                    StorageClass::Cold if !self.is_savable(&identifier, artifact) => {
+                        StorageClass::Warm
+                    }
+                    class => class,
+                };
+                let previous = artifact.storage.class;
+                if class == previous {
+                    continue;
+                }
+                if class == StorageClass::Hot {
+                    hot += size;
+                } else if previous == StorageClass::Hot {
+                    hot = hot.saturating_sub(size);
+                }
+                dfs.get_mut(&identifier).unwrap().storage.class = class;
+                changed.push((identifier, previous, class));
+            }
+        }
+        for (identifier, previous, class) in changed.iter() {
+            info!(
+                "Dataframe {identifier} went from {} to {} storage",
+                previous.name(),
+                class.name()
+            );
+            if *class == StorageClass::Hot {
+                if let Err(e) = self.load_evicted(identifier) {
+                    warn!("Could not load hot dataframe {identifier}: {}", e.message());
+                }
+            }
+        }
+
+        let residency = policy.cold_residency.as_millis() as u64;
+        let cold: Vec<String> = {
+            let dfs = self.dataframes.read().unwrap();
+            dfs.iter()
+                .filter(|(identifier, artifact)| {
+                    artifact.storage.class == StorageClass::Cold
+                        && artifact.storage.resident
+                        && self
+                            .accesses
+                            .last(identifier)
+                            .map_or(true, |last| last.saturating_add(residency) <= now)
+                })
+                .map(|(identifier, _)| identifier.clone())
+                .collect()
+        };
+        for identifier in cold {
+            if let Err(e) = self.evict(&identifier) {
+                warn!(
+                    "Could not evict cold dataframe {identifier}: {}",
+                    e.message()
+                );
+            }
+        }
+
+        if !changed.is_empty() {
+            let usage = self.storage_usage();
+            telemetry::add_event(
+                TelemetryEventProps::StorageClasses {
+                    hot_bytes: usage[0].memory_bytes,
+                    warm_bytes: usage[1].memory_bytes,
+                    cold_bytes: usage[2].memory_bytes,
+                    disk_bytes: usage.iter().map(|usage| usage.disk_bytes).sum(),
+                },
+                None,
+            );
+        }
+    }
+
+    /// The capabilities of this server, as returned by `GetServerCapabilities` and handshakes.
+    pub fn server_capabilities(&self) -> ServerCapabilities {
+        let owned = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let memory = self.memory.last();
+        ServerCapabilities {
+            segments: owned(capabilities::SEGMENTS),
+            formats: owned(capabilities::FORMATS),
+            operations: capabilities::Capability::ALL
+                .into_iter()
+                .map(|c| Capability {
+                    name: c.name().to_string(),
+                    supported: c.is_supported(),
+                    cargo_feature: c.cargo_feature().to_string(),
+                })
+                .collect(),
+            memory_pressure: memory.pressure.name().to_string(),
+            memory_usage_bytes: memory.usage,
+            bundle_signing_key: self.bundle_signer.public_key(),
+            storage_classes: StorageClass::ALL
+                .iter()
+                .zip(self.storage_usage())
+                .map(|(class, usage)| StorageClassUsage {
+                    storage_class: class.name().to_string(),
+                    dataframes: usage.dataframes,
+                    memory_bytes: usage.memory_bytes,
+                    disk_bytes: usage.disk_bytes,
+                })
+                .collect(),
+            big_index: capabilities::big_index(),
+            max_rows: capabilities::max_rows(),
+        }
+    }
+
+    /// Memory and disk usage of each class, in the order of [`StorageClass::ALL`].
+    pub fn storage_usage(&self) -> [ClassUsage; 3] {
+        let mut usage = [ClassUsage::default(); 3];
+        let dfs = self.dataframes.read().unwrap();
+        for (identifier, artifact) in dfs.iter() {
+            let class = &mut usage[artifact.storage.class as usize];
+            class.dataframes += 1;
+            if artifact.storage.resident {
+                class.memory_bytes += artifact.dataframe.estimated_size() as u64;
+            }
+            class.disk_bytes += match &self.embedded {
+                Some(store) => store.stored_len(identifier).unwrap_or(0),
+                None => std::fs::metadata(artifact_path(&self.data_dir, identifier))
+                    .map_or(0, |metadata| metadata.len()),
+            };
+        }
+        usage
+    }
+
+    /// Sets the class of `identifier`, or unpins it if `None`, in the background. Returns the job
+    /// applying the change.
+    pub fn set_storage_class(
+        &self,
+        identifier: &str,
+        class: Option<StorageClass>,
+        user_id: &str,
+    ) -> Result<u64, Status> {
+        let owner =
+            self.with_df_artifact_ref(identifier, |artifact| artifact.catalog.owner.clone())?;
+        if owner != user_id && !self.sess_manager.verify_if_owner(user_id)? {
+            return Err(Status::permission_denied(
+                "Only the owner of a dataframe and data owners can set its storage class.",
+            ));
+        }
+        let job = self.class_jobs.create(identifier, class, user_id);
+        let (state, identifier) = (self.clone(), identifier.to_string());
+        tokio::task::spawn_blocking(move || {
+            let result = state.apply_storage_class(&identifier, class);
+            if let Err(e) = &result {
+                warn!(
+                    "Could not change the storage class of {identifier}: {}",
+                    e.message()
+                );
+            }
+            state.class_jobs.finish(job, result);
+        });
+        Ok(job)
+    }
+
+    pub fn storage_class_job(&self, job: u64, user_id: &str) -> Result<ClassJob, Status> {
+        self.class_jobs.get(job, user_id)
+    }
+
+    /// Runs `query` for `user_id` with [`Self::execute_plan`], and records it in the audit log
+    /// whichever way it returns, see [`audit`].
+    async fn execute_query(
+        &self,
+        query: &Query,
+        user_id: &str,
+        client_info: Option<ClientInfo>,
+        correlation_id: String,
+        checkpointer: Option<Checkpointer>,
+        entry_points: &EntryPoints,
+    ) -> Result<ReferenceResponse, Status> {
+        let executed = self
+            .execute_plan(
+                query,
+                user_id,
+                client_info,
+                correlation_id,
+                checkpointer,
+                entry_points,
+            )
+            .await;
+        let (planned_inputs, segments) = audit::plan_summary(&query.composite_plan);
+        let (identifier, inputs, outcome) = match &executed {
+            Ok(response) => (
+                response.identifier.clone(),
+                self.provenance_inputs(&response.identifier)
+                    .unwrap_or(planned_inputs),
+                AuditOutcome::Allowed,
+            ),
+            Err(e) => (
+                String::new(),
+                planned_inputs,
+                AuditOutcome::of_error(e.code()),
+            ),
+        };
+        self.audit.record(AuditEntry {
+            at: catalog::now_ms(),
+            identity: user_id.to_string(),
+            action: AccessKind::Query,
+            identifier,
+            inputs,
+            plan_hash: checksum(query.composite_plan.as_bytes()),
+            segments,
+            outcome,
+        });
+        executed
+    }
+
+    /// Runs `query` for `user_id`, saving its segment boundaries with `checkpointer` if given.
+    /// Entry points are resolved through `entry_points`, shared by the queries of a batch.
+    async fn execute_plan(
+        &self,
+        query: &Query,
+        user_id: &str,
+        client_info: Option<ClientInfo>,
+        correlation_id: String,
+        checkpointer: Option<Checkpointer>,
+        entry_points: &EntryPoints,
+    ) -> Result<ReferenceResponse, Status> {
+        let user_id = user_id.to_string();
+        self.memory.check("queries", Pressure::Hard)?;
+
+        let deserialize_err = |e: serde_json::Error| {
+            Status::invalid_argument(format!(
+                "Could not deserialize composite plan: {}{}",
+                e, &query.composite_plan
+            ))
+        };
+        let (plan, pipeline) = match pipelines::parse_reference(&query.composite_plan) {
+            Some(reference) => {
+                let (name, version) = reference?;
+                let pipeline = self.pipelines.get(name, version, &user_id)?;
+                (pipeline.instantiate(&query.parameters)?, Some(pipeline))
+            }
+            None if !query.parameters.is_empty() => {
+                return Err(Status::invalid_argument(
+                    "Parameters can only be given to pipelines",
+                ))
+            }
+            None => (
+                serde_json::from_str(&query.composite_plan).map_err(deserialize_err)?,
+                None,
+            ),
+        };
+        // Checked before deserializing: options of compiled-out operations would be dropped.
+        plan_format::check(&plan, self.plan_compatibility_mode)?;
+        capabilities::check_plan(&plan)?;
+        let mut composite_plan: CompositePlan =
+            serde_json::from_value(plan).map_err(deserialize_err)?;
+        composite_plan.check_literal_frames(self.literal_frame_max_cells)?;
+        composite_plan.check_slices(self.slice_max_rows)?;
+        debug!("Plan of {user_id}: {}", query.composite_plan);
+        let mut redirects = Vec::new();
+        // Versions are pinned in the plan, so that they are those of its lineage.
+        composite_plan.resolve_entry_points(|identifier| {
+            let (resolved, redirect) = entry_points.resolve(identifier, |identifier| {
+                self.resolve_entry_point(identifier)
+            })?;
+            redirects.extend(redirect);
+            Ok(resolved)
+        })?;
+        let priority = QueryPriority::from(query.priority());
+
+        let mut datasets = composite_plan.entry_points();
+        for (family, predicate) in composite_plan.family_entry_points() {
+            let scan = self.families.prune(family, predicate)?;
+            datasets.extend(scan.scanned.into_iter().map(|(identifier, _)| identifier));
+        }
+        self.check_resolvable(&datasets, &user_id)?;
+        self.probing.check_suspended(&user_id, &datasets)?;
+        let purpose = Purpose::from_proto(query.purpose.clone())?;
+        self.admit_query(&datasets, &user_id, purpose.as_ref())?;
+        let plan_value = serde_json::to_value(&composite_plan)
+            .map_err(|e| Status::internal(format!("Could not serialize composite plan: {e}")))?;
+        let canonical_plan = CanonicalPlan::new(&plan_value);
+        let plan_hash = checksum(plan_value.to_string().as_bytes());
+        let synopsis = {
+            let dfs = self.dataframes.read().unwrap();
+            let inputs: Vec<_> = datasets
+                .iter()
+                .filter_map(|identifier| dfs.get(identifier))
+                .collect();
+            activity::synopsis(&composite_plan, &inputs)
+        };
+        let remote_warnings = self
+            .federate(&mut composite_plan, &correlation_id, purpose.as_ref())
+            .await?;
+
+        let start_time = Instant::now();
+
+        // Unregistered on every return.
+        let registration = self
+            .running
+            .register(&user_id, &datasets, catalog::now_ms());
+        let control = registration.control();
+        let slot = tokio::select! {
+            slot = self.scheduler.acquire(&user_id, priority) => slot?,
+            killed = control.killed() => return Err(killed),
+        };
+        registration.started(catalog::now_ms());
+        self.metrics.queue_wait(slot.queue_time);
+        logging::record_queue_wait(slot.queue_time);
+        let (queue_wait, queue_position) = (slot.queue_time, slot.queue_position);
+        if !slot.queue_time.is_zero() {
+            info!(
+                "Query waited {:?} in the execution queue ({} still waiting)",
+                slot.queue_time,
+                self.scheduler.queue_depth()
+            );
+        }
+
+        if let Some(checkpointer) = checkpointer {
+            composite_plan.set_checkpointer(checkpointer);
+        }
+        composite_plan.set_control(control.clone());
+        let state = self.clone();
+        let run_user_id = user_id.clone();
+        let run_start = Instant::now();
+        let results =
+            tokio::task::spawn_blocking(move || composite_plan.run_outputs(&state, &run_user_id))
+                .await
+                .map_err(|e| Status::internal(format!("Query execution task failed: {e}")))?;
+        self.metrics.plan_run(run_start.elapsed());
+        let results = results?;
+        drop(slot);
+        // Killed during the last segment, the results are dropped.
+        control.finish()?;
+
+        let input_versions =
+            input_versions(results.first().and_then(|(_, res)| res.provenance.as_ref()));
+        let mut outputs = Vec::with_capacity(results.len());
+        for (slot, mut res) in results {
+            res.warnings.extend(redirects.iter().cloned());
+            res.warnings.extend(remote_warnings.iter().cloned());
+            if let Some(pipeline) = &pipeline {
+                // Pins the version into the lineage of the result.
+                res.query_details = format!(
+                    "Pipeline {} version {} by {}\n{}",
+                    pipeline.name, pipeline.version, pipeline.author, res.query_details
+                );
+            }
+
+            if let Some(reason) =
+                self.probing
+                    .observe(&user_id, &datasets, &canonical_plan, &res.dataframe)
+            {
+                self.respond_to_probing(&user_id, &datasets, &mut res, reason)
+                    .await?;
+            }
+            // TODO: this isn't really great.. this does a full serialization under the hood
+            let hash = hash_dataset(&res.dataframe)?;
+
+            let header = get_df_header(&res.dataframe)?;
+            let shape = res.shape();
+            let output_rows = res.dataframe.height() as u64;
+            res.purpose = purpose.clone();
+            self.record_input_fingerprints(&mut res)?;
+            let identifier = self.insert_df(res.with_owner(&user_id));
+            self.record_activity(
+                &datasets,
+                ActivityEntry {
+                    at: catalog::now_ms(),
+                    requester: user_id.clone(),
+                    plan_hash: plan_hash.clone(),
+                    synopsis: synopsis.clone(),
+                    result: identifier.clone(),
+                    output_rows,
+                    fetch: FetchOutcome::NotFetched,
+                },
+            );
+            self.record_access(
+                AccessKind::Query,
+                &user_id,
+                &identifier,
+                datasets.clone(),
+                purpose.clone(),
+                Some(correlation_id.clone()),
+            );
+
+            telemetry::add_event(
+                TelemetryEventProps::RunQuery {
+                    dataset_name: Some(identifier.clone()),
+                    dataset_hash: Some(hash),
+                    time_taken: start_time.elapsed().as_millis() as f64,
+                },
+                client_info.clone(),
+            );
+
+            match &pipeline {
+                Some(pipeline) => info!(
+                    "Succesfully ran pipeline {} version {} for {} on {}",
+                    pipeline.name, pipeline.version, user_id, identifier
+                ),
+                None => info!("Succesfully ran query on {}", identifier.clone()),
+            }
+            outputs.push(OutputSlot {
+                slot,
+                identifier,
+                header,
+                shape: Some(shape),
+            });
+        }
+
+        let main = outputs.first().cloned().unwrap_or_default();
+        logging::record_identifier(&main.identifier);
+        let (fingerprint, input_fingerprints) = match main.identifier.as_str() {
+            "" => Default::default(),
+            identifier => self.fingerprint(identifier)?,
+        };
+        Ok(ReferenceResponse {
+            identifier: main.identifier,
+            header: main.header,
+            redirect: redirects.join("\n"),
+            shape: main.shape,
+            outputs,
+            input_versions,
+            fingerprint,
+            input_fingerprints,
+            queue_wait_ms: queue_wait.as_millis() as u64,
+            queue_position: queue_position as u64,
+            ..Default::default()
+        })
+    }
+
+    /// The canonical identifier of entry point `identifier`, with the version it selects pinned,
+    /// and the redirect of the alias it names, if any.
+    fn resolve_entry_point(&self, identifier: &str) -> Result<(String, Option<String>), Status> {
+        let (identifier, selector) = self.split_version(identifier)?;
+        let (canonical, redirect) = self.resolve(identifier)?;
+        let resolved = match selector {
+            Some(selector) => {
+                let version = self.resolve_version(&canonical, selector)?;
+                format!("{canonical}{}{version}", versions::SEPARATOR)
+            }
+            None => canonical,
+        };
+        Ok((resolved, redirect))
+    }
+
+    /// Runs the queries of `batch` for `user_id`, see [`batches`].
+    pub async fn run_query_batch(
+        &self,
+        batch: QueryBatch,
+        user_id: &str,
+        client_info: Option<ClientInfo>,
+    ) -> Result<QueryBatchResponse, Status> {
+        batches::check_size(batch.queries.len(), self.max_queries_per_batch)?;
+        let batch_id = Uuid::new_v4().to_string();
+        let entry_points = Arc::new(EntryPoints::default());
+        let permits = Arc::new(tokio::sync::Semaphore::new(self.query_batch_parallelism));
+        let failed = Arc::new(AtomicBool::new(false));
+        let mut tasks = Vec::with_capacity(batch.queries.len());
+        for query in batch.queries {
+            let state = self.clone();
+            let user_id = user_id.to_string();
+            let client_info = client_info.clone();
+            let batch_id = batch_id.clone();
+            let entry_points = Arc::clone(&entry_points);
+            let failed = Arc::clone(&failed);
+            let (fail_fast, inline) = (batch.fail_fast, batch.inline_results);
+            // Taken in order, so that fail-fast batches skip the queries after a failing one.
+            let permit = Arc::clone(&permits).acquire_owned().await;
+            tasks.push(tokio::spawn(async move {
+                let _permit = permit;
+                if failed.load(Ordering::SeqCst) {
+                    return batches::skipped();
+                }
+                let result = state
+                    .execute_query(
+                        &query,
+                        &user_id,
+                        client_info,
+                        batch_id.clone(),
+                        None,
+                        &entry_points,
+                    )
+                    .await;
+                let result = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        failed.fetch_or(fail_fast, Ordering::SeqCst);
+                        return batches::failed(e);
+                    }
+                };
+                let mut batched = BatchedQueryResult::default();
+                if inline {
+                    let purpose = Purpose::from_proto(query.purpose.clone()).ok().flatten();
+                    let inlined = state
+                        .inline_result(&result.identifier, &user_id, purpose, &batch_id)
+                        .await;
+                    match inlined {
+                        Ok(Some((data, status))) => {
+                            batched.inline_data = data;
+                            if let FetchStatus::Warning(reason) = status {
+                                batched.inline_warning = reason;
+                            }
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("Could not inline result {}: {e}", result.identifier),
+                    }
+                }
+                batched.result = Some(result);
+                batched
+            }));
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            results.push(
+                task.await
+                    .map_err(|e| Status::internal(format!("Batched query task failed: {e}")))?,
+            );
+        }
+        let failures = results.iter().filter(|r| r.result.is_none()).count();
+        info!(
+            "Succesfully ran batch {batch_id} of {} queries for {user_id} ({failures} failed or \
+             skipped)",
+            results.len()
+        );
+        Ok(QueryBatchResponse { batch_id, results })
+    }
+
+    /// Result `identifier` as an IPC file, and how it was fetched, if it is no larger than
+    /// `inline_result_bytes` and `recipient` may fetch it without approval. Inlined results are
+    /// recorded as fetched, under `correlation_id`.
+    async fn inline_result(
+        &self,
+        identifier: &str,
+        recipient: &str,
+        purpose: Option<Purpose>,
+        correlation_id: &str,
+    ) -> Result<Option<(Vec<u8>, FetchStatus)>, Status> {
+        let size = self.with_df_artifact_ref(identifier, |artifact| {
+            artifact.dataframe.estimated_size() as u64
+        })?;
+        if size > self.inline_result_bytes {
+            return Ok(None);
+        }
+        let df = match self.get_df(identifier, true, recipient, purpose.as_ref(), None) {
+            Ok(df) if !matches!(df.fetch_status, FetchStatus::Pending(_)) => df,
+            // Left to regular fetches, which ask for approval or tell why they are denied.
+            _ => return Ok(None),
+        };
+        let status = df.fetch_status;
+        let mut fetched = df.future.await?.into_dataframe();
+        let data = dataframe_ser_helper(&mut fetched)
+            .map_err(|e| Status::internal(format!("Could not serialize result: {e}")))?;
+        if data.len() as u64 > self.inline_result_bytes {
+            return Ok(None);
+        }
+        self.set_fetch_outcome(identifier, FetchOutcome::of(&status));
+        self.record_fetch(
+            AccessKind::Fetch,
+            recipient,
+            identifier,
+            purpose,
+            Some(correlation_id.to_string()),
+        )?;
+        Ok(Some((data, status)))
+    }
+
+    fn plan_jobs_dir(&self) -> PathBuf {
+        self.data_dir.join(JOBS_DIR)
+    }
+
+    fn checkpoint_dir(&self, job: &str) -> PathBuf {
+        self.resources.spill_dir.join(CHECKPOINTS_DIR).join(job)
+    }
+
+    /// Runs `query` for `user_id` in the background, checkpointed if `checkpoint`, see
+    /// [`plan_jobs`]. Segments are delayed by `delay` for chaos tests.
+    pub fn submit_plan_job(
+        &self,
+        query: Query,
+        checkpoint: bool,
+        user_id: &str,
+        client_info: Option<ClientInfo>,
+        delay: Duration,
+    ) -> Result<PlanJob, Status> {
+        let job = PlanJob {
+            id: Uuid::new_v4().to_string(),
+            requester: user_id.to_string(),
+            query: query.encode_to_vec(),
+            checkpoint,
+            attempts: 1,
+            resumed_after: None,
+            notes: Vec::new(),
+            status: PlanJobStatus::Running,
+        };
+        // Embedded servers do not resume jobs.
+        if self.embedded.is_none() {
+            plan_jobs::save(&self.plan_jobs_dir(), &job)?;
+        }
+        self.run_plan_job(job.clone(), query, None, client_info, delay);
+        Ok(job)
+    }
+
+    fn run_plan_job(
+        &self,
+        job: PlanJob,
+        query: Query,
+        resumed: Option<Restored>,
+        client_info: Option<ClientInfo>,
+        delay: Duration,
+    ) {
+        let control = self.plan_jobs.start(job.clone());
+        let checkpointer = job.checkpoint.then(|| {
+            Checkpointer::new(self.checkpoint_dir(&job.id), control.clone(), resumed)
+                .with_delay(delay)
+        });
+        let state = self.clone();
+        tokio::spawn(async move {
+            let correlation_id = Uuid::new_v4().to_string();
+            let result = state
+                .execute_query(
+                    &query,
+                    &job.requester,
+                    client_info,
+                    correlation_id,
+                    checkpointer,
+                    &EntryPoints::default(),
+                )
+                .await;
+            if control.stopped() {
+                info!(
+                    "Plan job {} stopped after segment {}",
+                    job.id,
+                    control.completed()
+                );
+                return;
+            }
+            let status = match result {
+                Ok(response) => PlanJobStatus::Succeeded {
+                    result: response.encode_to_vec(),
+                },
+                Err(e) => {
+                    warn!("Plan job {} failed: {}", job.id, e.message());
+                    PlanJobStatus::Failed {
+                        error: e.message().to_string(),
+                    }
+                }
+            };
+            state.end_plan_job(&job.id, status);
+        });
+    }
+
+    fn end_plan_job(&self, id: &str, status: PlanJobStatus) {
+        checkpoints::remove(&self.checkpoint_dir(id));
+        if self.embedded.is_none() {
+            plan_jobs::remove(&self.plan_jobs_dir(), id);
+        }
+        self.plan_jobs.finish(id, status);
+    }
+
+    /// Job `id` with the segments its current run completed, if `user_id` submitted it.
+    pub fn plan_job(&self, id: &str, user_id: &str) -> Result<(PlanJob, usize), Status> {
+        self.plan_jobs.get(id, user_id)
+    }
+
+    /// Resumes the unfinished plan jobs recorded by a previous server, see [`plan_jobs`].
+    pub fn resume_plan_jobs(&self) {
+        if self.embedded.is_some() {
+            return;
+        }
+        for mut job in plan_jobs::load(&self.plan_jobs_dir()) {
+            let query = Query::decode(&job.query[..]);
+            let dir = self.checkpoint_dir(&job.id);
+            let restored = match (&query, job.checkpoint) {
+                (Ok(_), true) if job.attempts < self.plan_job_max_attempts => {
+                    checkpoints::load(&dir)
+                }
+                _ => Ok(None),
+            };
+            let expired = |restored: &Restored| {
+                catalog::now_ms().saturating_sub(restored.saved_at) > self.plan_checkpoint_expiry_ms
+            };
+            let error = match (&query, &restored) {
+                (Err(e), _) => Some(format!("Unreadable query: {e}")),
+                _ if !job.checkpoint => Some(String::from(
+                    "Interrupted by a restart, without checkpoints to resume from",
+                )),
+                _ if job.attempts >= self.plan_job_max_attempts => {
+                    Some(format!("Gave up after {} runs", job.attempts))
+                }
+                (_, Ok(Some(restored))) if expired(restored) => Some(String::from(
+                    "Interrupted by a restart, its checkpoint expired",
+                )),
+                _ => None,
+            };
+            if let Some(error) = error {
+                warn!("Plan job {} failed: {error}", job.id);
+                self.plan_jobs.start(job.clone());
+                self.end_plan_job(&job.id, PlanJobStatus::Failed { error });
+                continue;
+            }
+
+            let restart = match restored {
+                Ok(Some(restored)) => {
+                    match restored.changed_input(|identifier| {
+                        self.with_df_artifact_ref(identifier, |artifact| artifact.version)
+                            .ok()
+                    }) {
+                        Some(reason) => Err(reason),
+                        None => Ok(Some(restored)),
+                    }
+                }
+                Ok(None) => Ok(None),
+                Err(e) => Err(e.message().to_string()),
+            };
+            let resumed = match restart {
+                Ok(resumed) => resumed,
+                Err(reason) => {
+                    let note = format!("Restarted from the beginning: {reason}");
+                    warn!("Plan job {}: {note}", job.id);
+                    job.notes.push(note);
+                    None
+                }
+            };
+            if resumed.is_none() {
+                checkpoints::remove(&dir);
+            }
+            job.attempts += 1;
+            job.resumed_after = resumed.as_ref().map(|resumed| resumed.completed);
+            if let Err(e) = plan_jobs::save(&self.plan_jobs_dir(), &job) {
+                warn!("{}", e.message());
+            }
+            match job.resumed_after {
+                Some(completed) => info!("Resuming plan job {} after {completed} segments", job.id),
+                None => info!("Running plan job {} again", job.id),
+            }
+            let query = query.expect("Unreadable queries fail");
+            self.run_plan_job(job, query, resumed, None, Duration::ZERO);
+        }
+    }
+
+    /// Stops the checkpointed plan jobs at their next segment boundary, waiting up to `grace` for
+    /// them to reach it, see [`plan_jobs`].
+    pub async fn stop_plan_jobs(&self, grace: Duration) {
+        for control in self.plan_jobs.running(true) {
+            control.stop();
+        }
+        let deadline = Instant::now() + grace;
+        loop {
+            let pending = self
+                .plan_jobs
+                .running(true)
+                .iter()
+                .filter(|control| !control.stopped())
+                .count();
+            if pending == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "{pending} checkpointed plan jobs did not reach a segment boundary in time, \
+                     they resume from their last one"
+                );
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Rejects the fetches pending approval, and those requested afterwards, with `unavailable`,
+    /// on shutdown. Returns how many were pending, see [`approvals`].
+    pub fn reject_pending_approvals(&self) -> usize {
+        self.approvals.shut_down()
+    }
+
+    /// Waits for the audit entries recorded so far to be written and flushed, see [`audit`].
+    pub async fn flush_audit_log(&self) {
+        self.audit.flush().await
+    }
+
+    /// Stops the plan jobs at their next segment boundary without saving it, as a crash would,
+    /// for tests.
+    pub fn halt_plan_jobs(&self) {
+        for control in self.plan_jobs.running(false) {
+            control.halt();
+        }
+    }
+
+    /// Rolls `patch` out on the datasets `selector` matches, all of them or none, or only reports
+    /// its impact if `dry_run`. Returns the id of the rollout if applied, see [`rollouts`].
+    pub fn roll_out_policy(
+        &self,
+        selector: &PolicySelector,
+        patch: &PolicyPatch,
+        dry_run: bool,
+        user_id: &str,
+    ) -> Result<(Option<String>, Vec<DatasetImpact>), Status> {
+        if !self.sess_manager.verify_if_owner(user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can roll out policies.",
+            ));
+        }
+        selector.check()?;
+        // Resolved before locking the dataframes, which resolving reads.
+        let pipelines: Vec<_> = self
+            .pipelines
+            .latest()
+            .into_iter()
+            .map(|pipeline| {
+                let entry_points = pipelines::fixed_entry_points(&pipeline.plan)
+                    .into_iter()
+                    .map(|identifier| {
+                        self.resolve(&identifier)
+                            .map_or(identifier, |(canonical, _)| canonical)
+                    })
+                    .collect();
+                (pipeline, entry_points)
+            })
+            .collect();
+        if dry_run {
+            let dfs = self.dataframes.read().unwrap();
+            let changes = rollouts::plan(&dfs, selector, patch, &self.policy_engine, &pipelines)?;
+            return Ok((None, changes.impacts));
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let (rollout, modified) = {
+            let mut dfs = self.dataframes.write().unwrap();
+            let changes = rollouts::plan(&dfs, selector, patch, &self.policy_engine, &pipelines)?;
+            let modified: Vec<String> = changes.modified().map(String::from).collect();
+            (
+                self.rollouts.apply(&mut dfs, changes, &id, user_id),
+                modified,
+            )
+        };
+        self.access.bump();
+        info!(
+            "{user_id} applied policy rollout {id} to {} datasets",
+            rollout.impacts.len()
+        );
+        self.persist_rollout(&id, &modified);
+        Ok((Some(id), rollout.impacts))
+    }
+
+    /// Restores the policies rollout `id` changed, all of them or none.
+    pub fn roll_back_policy_rollout(&self, id: &str, user_id: &str) -> Result<Rollout, Status> {
+        if !self.sess_manager.verify_if_owner(user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can roll back policy rollouts.",
+            ));
+        }
+        let (rollout, modified) = {
+            let mut dfs = self.dataframes.write().unwrap();
+            self.rollouts.roll_back(&mut dfs, id, user_id)?
+        };
+        // Rolling back a loosening narrows access too.
+        self.access.bump();
+        info!("{user_id} rolled back policy rollout {id}");
+        self.persist_rollout(id, &modified);
+        Ok(rollout)
+    }
+
+    /// Rewrites the persisted copies of the dataframes rollout `id` modified. The rollout stays
+    /// applied in memory if some cannot be.
+    fn persist_rollout(&self, id: &str, modified: &[String]) {
+        for identifier in modified {
+            if let Err(e) = self.persist_if_stored(identifier) {
+                warn!(
+                    "Could not persist {identifier} after policy rollout {id}: {}",
+                    e.message()
+                );
+            }
+        }
+    }
+
+    pub fn policy_history(&self, identifier: &str) -> Result<Vec<PolicyChange>, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| artifact.policy_history().to_vec())
+    }
+
+    /// The default policy `identifier` took on upload, if any, see [`default_policies`].
+    pub fn upload_defaults(&self, identifier: &str) -> Result<Option<AppliedDefaults>, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| artifact.defaults().cloned())
+    }
+
+    /// The versions of `identifier`, after pruning those no longer retained, see [`versions`].
+    pub fn df_versions(&self, identifier: &str) -> Result<VersionList, Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.history.prune(catalog::now_ms());
+        Ok(version_list(identifier, artifact))
+    }
+
+    /// Sets how many superseded versions of `identifier` are retained, and for how long. Only its
+    /// owner and data owners can.
+    pub fn retain_versions(
+        &self,
+        identifier: &str,
+        retention: Retention,
+        user_id: &str,
+    ) -> Result<VersionList, Status> {
+        let owner =
+            self.with_df_artifact_ref(identifier, |artifact| artifact.catalog.owner.clone())?;
+        if owner != user_id && !self.sess_manager.verify_if_owner(user_id)? {
+            return Err(Status::permission_denied(
+                "Only the owner of a dataframe and data owners can set how its versions are \
+                 retained",
+            ));
+        }
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.history.set_retention(retention, catalog::now_ms());
+        Ok(version_list(identifier, artifact))
+    }
+
+    /// Splits the version selector off entry point `identifier`, unless it names a dataframe or
+    /// an alias as it is, see [`versions`].
+    pub fn split_version<'a>(
+        &self,
+        identifier: &'a str,
+    ) -> Result<(&'a str, Option<Selector>), Status> {
+        if self.dataframes.read().unwrap().contains_key(identifier)
+            || self
+                .aliases
+                .resolve(identifier, catalog::now_ms())?
+                .is_some()
+        {
+            return Ok((identifier, None));
+        }
+        versions::split(identifier)
+    }
+
+    /// The version of `identifier` that `selector` selects.
+    pub fn resolve_version(&self, identifier: &str, selector: Selector) -> Result<u64, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| -> Result<u64, Status> {
+            let found =
+                artifact
+                    .history
+                    .find(identifier, artifact.version, selector, catalog::now_ms())?;
+            Ok(match found {
+                Found::Current => artifact.version,
+                Found::Retained(retained) => retained.version,
+            })
+        })?
+    }
+
+    /// `identifier` at the version `selector` selects, the current one if `None`, along with that
+    /// version.
+    pub fn get_df_version(
+        &self,
+        identifier: &str,
+        selector: Option<Selector>,
+    ) -> Result<(DataFrame, u64), Status> {
+        let read = |artifact: &DataFrameArtifact| -> Result<(Option<DataFrame>, u64), Status> {
+            let found = match selector {
+                Some(selector) => artifact.history.find(
+                    identifier,
+                    artifact.version,
+                    selector,
+                    catalog::now_ms(),
+                )?,
+                None => Found::Current,
+            };
+            match found {
+                Found::Current => Ok((None, artifact.version)),
+                Found::Retained(retained) => {
+                    Ok((Some(retained.declared_dataframe()?), retained.version))
+                }
+            }
+        };
+        let (retained, version) = self.with_df_artifact_ref(identifier, read)??;
+        match retained {
+            Some(df) => Ok((df, version)),
+            None => Ok((self.get_df_unchecked(identifier)?, version)),
+        }
+    }
+
+    /// Sets and pins the class of `identifier`, loading or evicting its rows accordingly.
+    pub fn apply_storage_class(
+        &self,
+        identifier: &str,
+        class: Option<StorageClass>,
+    ) -> Result<(), Status> {
+        if class == Some(StorageClass::Hot) {
+            self.load_evicted(identifier)?;
+        }
+        {
+            let mut dfs = self.dataframes.write().unwrap();
+            let hot: u64 = dfs
+                .iter()
+                .filter(|(id, artifact)| {
+                    id.as_str() != identifier && artifact.storage.class == StorageClass::Hot
+                })
+                .map(|(_, artifact)| artifact.size())
+                .sum();
+            let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?;
+            let class = match class {
+                Some(class) => class,
+                None => {
+                    artifact.storage.pinned = false;
+                    return Ok(());
+                }
+            };
+            match class {
+                StorageClass::Hot => self.class_policy.check_budget(hot, artifact.size())?,
+                StorageClass::Cold if !self.is_savable(identifier, artifact) => {
+                    return Err(Status::failed_precondition(format!(
+                        "Dataframe {identifier} cannot be cold: its policy does not let the \
+                         server persist it."
+                    )))
+                }
+                _ => (),
+            }
+            artifact.storage.class = class;
+            artifact.storage.pinned = true;
+        }
+        match class {
+            Some(StorageClass::Cold) => self.evict(identifier).map(|_| ()),
+            _ => self.load_evicted(identifier),
+        }
+    }
+
+    /// The versions a delta fetch of `identifier` is computed on, or why the full result is sent
+    /// instead.
+    ///
+    /// The delta discloses the keys of the previous version: `recipient` must be allowed to fetch
+    /// it without approval, like `identifier` is when the delta is released.
+    fn delta_versions(
+        &self,
+        identifier: &str,
+        since: &str,
+        restore_dtypes: bool,
+        keys: &[String],
+        recipient: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<Result<(Released, DataFrame), delta::Fallback>, Status> {
+        self.load_evicted(since)?;
+        self.load_evicted(identifier)?;
+        let dfs = self.dataframes.read().unwrap();
+        let (previous, current) = match (dfs.get(since), dfs.get(identifier)) {
+            (Some(previous), Some(current)) => (previous, current),
+            _ => return Ok(Err("the previous version is gone".into())),
+        };
+        let decision = self.policy_engine.evaluate(
+            Action::Fetch,
+            recipient,
+            &[Subject::artifact(since, previous)],
+            &EvaluationContext { purpose },
+        )?;
+        if !decision.verdict().permits() {
+            return Ok(Err(
+                "the previous version cannot be fetched without approval".into(),
+            ));
+        }
+        // Noise differs on every fetch: the versions cannot be compared.
+        if previous.noise.is_some() || current.noise.is_some() {
+            return Ok(Err("the versions are noised".into()));
+        }
+        let watermarked = |artifact: &DataFrameArtifact, key: &String| {
+            artifact.policy.watermark().is_some_and(|watermark| {
+                watermark.columns.contains(key) && !artifact.policy.exact_columns().contains(key)
+            })
+        };
+        if keys
+            .iter()
+            .any(|key| watermarked(previous, key) || watermarked(current, key))
+        {
+            return Ok(Err("key columns are watermarked".into()));
+        }
+        // Masked versions would be compared on values the recipient does not get.
+        if [previous, current]
+            .iter()
+            .any(|artifact| !artifact.policy.column_masks().is_empty())
+        {
+            return Ok(Err("columns are masked".into()));
+        }
+        // The current version is only compared to the previous one: the delta takes its rows from
+        // the fetched result.
+        let mut compared = fetched_dataframe(current, restore_dtypes)?;
+        sanitize_df(&mut compared, &current.blacklist);
+        Ok(Ok((
+            self.policy_engine
+                .grant(&decision)?
+                .release_unmarked(fetched_dataframe(previous, restore_dtypes)?)?,
+            compared,
+        )))
+    }
+
+    fn get_df(
+        &self,
+        identifier: &str,
+        restore_dtypes: bool,
+        recipient: &str,
+        purpose: Option<&Purpose>,
+        client_info: Option<ClientInfo>,
+    ) -> Result<DelayedDataFrame, Status> {
+        self.get_df_waived(
+            identifier,
+            restore_dtypes,
+            recipient,
+            purpose,
+            client_info,
+            None,
+        )
+    }
+
+    /// Like [`Self::get_df`], releasing the dataframe without masks nor watermarks under export
+    /// waiver `waiver` if set, see [`waivers`].
+    fn get_df_waived(
+        &self,
+        identifier: &str,
+        restore_dtypes: bool,
+        recipient: &str,
+        purpose: Option<&Purpose>,
+        client_info: Option<ClientInfo>,
+        waiver: Option<&str>,
+    ) -> Result<DelayedDataFrame, Status> {
+        self.touch(identifier)?;
+        self.prepare_masks(identifier, Tolerance::Stale)?;
+        let dfs = self.dataframes.read().unwrap();
+        let artifact = dfs.get(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        if artifact.remote.is_some() {
+            return Err(Status::failed_precondition(format!(
+                "Dataframe {identifier} is federated: its rows can only be read by queries"
+            )));
+        }
+        let decision = self.policy_engine.evaluate(
+            Action::Fetch,
+            recipient,
+            &[Subject::artifact(identifier, artifact)],
+            &EvaluationContext { purpose },
+        )?;
+        let mut delayed = match decision.verdict().clone() {
+            verdict @ (Verdict::Allow | Verdict::Warn(_)) => {
+                if let Verdict::Warn(reason) = &verdict {
+                    warn!(
+                        "Safe zone violation: {identifier} was non-privately fetched by \
+                         {recipient}: {reason}"
+                    );
+                }
+                let df = release(
+                    self.policy_engine.grant(&decision)?,
+                    fetched_dataframe(artifact, restore_dtypes)?,
+                    identifier,
+                    recipient,
+                    &self.watermarker,
+                    &self.privacy_budgets,
+                    waiver.map(|waiver| (&*self.waivers, waiver)),
+                )?;
+                telemetry::add_event(
+                    TelemetryEventProps::FetchDataFrame {
+                        dataset_name: Some(identifier.to_owned()),
+                        request_accepted: true,
+                    },
+                    client_info,
+                );
+                DelayedDataFrame {
+                    future: Box::pin(async { Ok(df) }),
+                    fetch_status: match verdict {
+                        Verdict::Warn(reason) => FetchStatus::Warning(reason),
+                        _ => FetchStatus::Ok,
+                    },
+                }
+            }
+            // Held back by a row cap: clients are told why nothing comes.
+            Verdict::Deny(reason) if decision.withheld() => {
+                return Ok(DelayedDataFrame {
+                    fetch_status: FetchStatus::Pending(reason.clone()),
+                    future: Box::pin(async move { Err(Status::permission_denied(reason)) }),
+                });
+            }
+            Verdict::Deny(reason) => return Err(Status::permission_denied(reason)),
+            Verdict::Pending(reason) => {
+                let identifier = String::from(identifier);
+                let waiter = self.approvals.submit(
+                    &identifier,
+                    recipient,
+                    &artifact.query_details,
+                    &reason,
+                    catalog::now_ms(),
+                );
+                let id = waiter.request().id.clone();
+                let purposes = approval_purposes(purpose, artifact.purpose.as_ref());
+                let history = self.approval_history(&dfs, artifact, recipient);
+                prompt(format_args!(
+                    "A user requests unsafe access to one of your DataFrames
+Request id: {}
+DataFrame identifier: {}
+{}{}Reason the request is unsafe:
+{}
+Approve it with ApproveRequest, or reject it with RejectRequest, within {}s",
+                    id,
+                    identifier,
+                    purposes,
+                    history,
+                    reason,
+                    self.approvals.timeout().as_secs(),
+                ));
+                let dfs = Arc::clone(&self.dataframes);
+                let watermarker = Arc::clone(&self.watermarker);
+                let policy_engine = Arc::clone(&self.policy_engine);
+                let budgets = Arc::clone(&self.privacy_budgets);
+                let recipient = recipient.to_owned();
+                let waivers = Arc::clone(&self.waivers);
+                let waiver = waiver.map(String::from);
+                DelayedDataFrame {
+                    fetch_status: FetchStatus::Pending(format!(
+                        "{reason}\nFetch request {id} is pending the approval of the data owner"
+                    )),
+                    future: Box::pin(async move {
+                        let decided = waiter.decision().await;
+                        telemetry::add_event(
+                            TelemetryEventProps::FetchDataFrame {
+                                dataset_name: Some(identifier.to_owned()),
+                                request_accepted: decided.is_ok(),
+                            },
+                            client_info,
+                        );
+                        decided?;
+                        let grant = policy_engine.approve(&decision, &recipient)?;
+                        let guard = dfs.read().unwrap();
+                        let artifact = guard.get(&identifier).ok_or_else(|| {
+                            Status::not_found(format!(
+                                "Could not find dataframe: identifier={}",
+                                identifier
+                            ))
+                        })?;
+                        release(
+                            grant,
+                            fetched_dataframe(artifact, restore_dtypes)?,
+                            &identifier,
+                            &recipient,
+                            &watermarker,
+                            &budgets,
+                            waiver.as_deref().map(|waiver| (&*waivers, waiver)),
+                        )
+                    }),
+                }
+            }
+        };
+        if let Some(capped @ CappedOutput::Truncated { .. }) = &artifact.capped_output {
+            delayed.fetch_status = delayed.fetch_status.with_notice(capped.message());
+        }
+        if let Some(noise) = &artifact.noise {
+            delayed.fetch_status = delayed.fetch_status.with_notice(noise.notice());
+        }
+        for warning in artifact.warnings.iter() {
+            delayed.fetch_status = delayed.fetch_status.with_notice(warning.clone());
+        }
+        Ok(delayed)
+    }
+
+    /// Exports a dataframe to `identity` in-process, over the Arrow C stream interface.
+    ///
+    /// This performs the same policy checks as a fetch, and likewise waits for the data owner's
+    /// approval when the policy requires it. See [`arrow_export`] for the lifetime rules of the
+    /// stream.
+    pub async fn export_arrow(
+        &self,
+        identifier: &str,
+        identity: &str,
+    ) -> Result<ArrowArrayStream, Status> {
+        let (identifier, _) = self.resolve(identifier)?;
+        let identifier = identifier.as_str();
+        let version = self.with_df_artifact_ref(identifier, |artifact| artifact.version)?;
+        let pin = self.exports.pin(identifier, version, identity);
+        let delayed = self.get_df(identifier, true, identity, None, None)?;
+        if let FetchStatus::Warning(reason) = &delayed.fetch_status {
+            warn!("Exporting dataframe {identifier} despite the policy: {reason}");
+        }
+        let stream = ArrowArrayStream::new(delayed.future.await?.into_dataframe(), pin)?;
+        info!("Succesfully exported dataframe {identifier} to {identity}");
+        Ok(stream)
+    }
+
+    /// The Arrow exports that have not been released yet.
+    pub fn arrow_exports(&self) -> Vec<ExportInfo> {
+        self.exports.exports()
+    }
+
+    fn check_not_exported(&self, identifier: &str) -> Result<(), Status> {
+        if self.exports.is_exported(identifier) {
+            return Err(Status::failed_precondition(format!(
+                "Dataframe {identifier} is exported: its Arrow streams must be released first"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether `user_id` reviews the dataframes submitted for publication, see [`lifecycle`].
+    fn is_reviewer(&self, user_id: &str) -> Result<bool, Status> {
+        if self.dataset_reviewers.is_empty() {
+            self.sess_manager.verify_if_owner(user_id)
+        } else {
+            Ok(self.dataset_reviewers.iter().any(|r| r == user_id))
+        }
+    }
+
+    /// The faults to inject in the chunk stream of `request`, see [`faults`].
+    fn stream_faults<T>(&self, request: &Request<T>) -> Result<StreamFaults, Status> {
+        StreamFaults::from_metadata(request.metadata(), self.fault_injection)
+    }
+
+    /// Checks that `user_id` may run a query on the dataframes it reads, with `purpose`.
+    fn admit_query(
+        &self,
+        identifiers: &[String],
+        user_id: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<(), Status> {
+        let dfs = self.dataframes.read().unwrap();
+        let subjects: Vec<Subject> = identifiers
+            .iter()
+            .filter_map(|identifier| {
+                let artifact = dfs.get(identifier)?;
+                Some(Subject::artifact(identifier, artifact))
+            })
+            .collect();
+        let decision = self.policy_engine.evaluate(
+            Action::Query,
+            user_id,
+            &subjects,
+            &EvaluationContext { purpose },
+        )?;
+        match decision.verdict() {
+            Verdict::Deny(reason) => Err(Status::permission_denied(reason.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Logs an access of `user_id` and adds it to the access log, see [`purpose`].
+    fn record_access(
+        &self,
+        kind: AccessKind,
+        user_id: &str,
+        identifier: &str,
+        inputs: Vec<String>,
+        purpose: Option<Purpose>,
+        correlation_id: Option<String>,
+    ) {
+        let owners = {
+            let dfs = self.dataframes.read().unwrap();
+            let mut owners: Vec<String> = std::iter::once(identifier)
+                .chain(inputs.iter().map(String::as_str))
+                .filter_map(|identifier| dfs.get(identifier))
+                .map(|artifact| artifact.catalog.owner.clone())
+                .collect();
+            owners.sort();
+            owners.dedup();
+            owners
+        };
+        let action = match kind {
+            AccessKind::Query => "Query",
+            AccessKind::Fetch => "Fetch",
+            AccessKind::Transfer => "Transfer",
+            AccessKind::Upload => "Upload",
+        };
+        let correlation = correlation_id
+            .as_ref()
+            .map_or(String::new(), |id| format!(" (correlation id {id})"));
+        match &purpose {
+            Some(purpose) => {
+                info!("{action} of {identifier} by {user_id} for purpose {purpose}{correlation}")
+            }
+            None => info!("{action} of {identifier} by {user_id} without a purpose{correlation}"),
+        }
+        self.access_log.record(AccessRecord {
+            at: catalog::now_ms(),
+            user_id: user_id.to_string(),
+            kind,
+            identifier: identifier.to_string(),
+            inputs,
+            owners,
+            purpose,
+            correlation_id,
+        });
+    }
+
+    /// Records a fetch or a transfer, along with the dataframes the released one was computed
+    /// from.
+    fn record_fetch(
+        &self,
+        kind: AccessKind,
+        user_id: &str,
+        identifier: &str,
+        purpose: Option<Purpose>,
+        correlation_id: Option<String>,
+    ) -> Result<(), Status> {
+        let inputs = self.provenance_inputs(identifier)?;
+        self.record_access(kind, user_id, identifier, inputs, purpose, correlation_id);
+        Ok(())
+    }
+
+    /// The dataframes `identifier` was computed from.
+    fn provenance_inputs(&self, identifier: &str) -> Result<Vec<String>, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| {
+            artifact
+                .provenance
+                .as_ref()
+                .map_or(Vec::new(), |provenance| {
+                    provenance
+                        .inputs
+                        .iter()
+                        .map(|input| input.identifier.clone())
+                        .collect()
+                })
+        })
+    }
+
+    /// Adds a query to the recent activity of each dataframe it read, see [`activity`].
+    fn record_activity(&self, inputs: &[String], entry: ActivityEntry) {
+        let mut dfs = self.dataframes.write().unwrap();
+        for identifier in inputs {
+            if let Some(artifact) = dfs.get_mut(identifier) {
+                artifact
+                    .activity
+                    .record(entry.clone(), self.activity_limits);
+            }
+        }
+    }
+
+    /// Records how a fetch of result `identifier` by `recipient` went in the audit log, and in the
+    /// recent activity of its inputs.
+    fn record_fetch_outcome(
+        &self,
+        kind: AccessKind,
+        recipient: &str,
+        identifier: &str,
+        fetched: &Result<DelayedDataFrame, Status>,
+    ) {
+        let audited = match fetched {
+            Ok(df) if matches!(df.fetch_status, FetchStatus::Pending(_)) => AuditOutcome::Pending,
+            Ok(_) => AuditOutcome::Allowed,
+            Err(e) => AuditOutcome::of_error(e.code()),
+        };
+        self.audit.record(AuditEntry {
+            at: catalog::now_ms(),
+            identity: recipient.to_string(),
+            action: kind,
+            identifier: identifier.to_string(),
+            inputs: self.provenance_inputs(identifier).unwrap_or_default(),
+            plan_hash: String::new(),
+            segments: Vec::new(),
+            outcome: audited,
+        });
+        let outcome = match fetched {
+            Ok(df) => FetchOutcome::of(&df.fetch_status),
+            Err(e) if e.code() == tonic::Code::PermissionDenied => FetchOutcome::Denied,
+            Err(_) => return,
+        };
+        self.set_fetch_outcome(identifier, outcome);
+    }
+
+    fn set_fetch_outcome(&self, identifier: &str, outcome: FetchOutcome) {
+        let mut dfs = self.dataframes.write().unwrap();
+        let inputs: Vec<String> = match dfs.get(identifier).and_then(|df| df.provenance.as_ref()) {
+            Some(provenance) => provenance
+                .inputs
+                .iter()
+                .map(|input| input.identifier.clone())
+                .collect(),
+            None => return,
+        };
+        for input in inputs {
+            if let Some(artifact) = dfs.get_mut(&input) {
+                artifact.activity.set_fetch(identifier, outcome);
+            }
+        }
+    }
+
+    /// Attaches `identifiers` to workspace `name` of `user_id`, who must be able to read them
+    /// all, see [`workspaces`].
+    pub fn attach_to_workspace(
+        &self,
+        name: &str,
+        user_id: &str,
+        identifiers: &[String],
+    ) -> Result<WorkspaceInfo, Status> {
+        let mut canonical = Vec::with_capacity(identifiers.len());
+        for identifier in identifiers {
+            let (identifier, _) = self.resolve(identifier)?;
+            self.with_df_artifact_ref(&identifier, |_| ())?;
+            canonical.push(identifier);
+        }
+        self.check_resolvable(&canonical, user_id)?;
+        self.workspaces.attach(name, user_id, &canonical)
+    }
+
+    /// Deletes workspace `name` of `user_id`, along with its members if `cascade`.
+    pub fn delete_workspace(
+        &self,
+        name: &str,
+        user_id: &str,
+        cascade: bool,
+    ) -> Result<BulkOutcome, Status> {
+        let outcome = if cascade {
+            self.delete_workspace_members(name, user_id)?
+        } else {
+            BulkOutcome::default()
+        };
+        self.workspaces.remove(name, user_id)?;
+        Ok(outcome)
+    }
+
+    /// Deletes the members of workspace `name` of `user_id`. Data owners can delete any member,
+    /// other users the members they produced.
+    pub fn delete_workspace_members(
+        &self,
+        name: &str,
+        user_id: &str,
+    ) -> Result<BulkOutcome, Status> {
+        let workspace = self.workspaces.owned(name, user_id)?;
+        let data_owner = self.sess_manager.verify_if_owner(user_id)?;
+        let mut outcome = BulkOutcome::default();
+        for identifier in &workspace.members {
+            let result = self
+                .with_df_artifact_ref(identifier, |artifact| artifact.catalog.owner == user_id)
+                .and_then(|produced| {
+                    if !data_owner && !produced {
+                        return Err(Status::permission_denied(
+                            "Only data owners can delete dataframes they did not produce.",
+                        ));
+                    }
+                    self.delete_dfs(identifier)
+                        .map_err(|e| Status::failed_precondition(e.to_string()))
+                });
+            outcome.record(identifier, result);
+        }
+        info!(
+            "Deleted {} members of workspace {name} for {user_id} ({} failed)",
+            outcome.succeeded.len(),
+            outcome.failures.len()
+        );
+        Ok(outcome)
+    }
+
+    /// Keeps the members of workspace `name` of `user_id` for at least `duration` from now, when
+    /// idle results are collected under memory pressure.
+    pub fn extend_workspace_retention(
+        &self,
+        name: &str,
+        user_id: &str,
+        duration: Duration,
+    ) -> Result<BulkOutcome, Status> {
+        let workspace = self.workspaces.owned(name, user_id)?;
+        let until = catalog::now_ms().saturating_add(duration.as_millis() as u64);
+        let mut outcome = BulkOutcome::default();
+        for identifier in &workspace.members {
+            let result = self
+                .check_resolvable(std::slice::from_ref(identifier), user_id)
+                .and_then(|()| {
+                    let mut dfs = self.dataframes.write().unwrap();
+                    let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+                        Status::not_found(format!(
+                            "Could not find dataframe: identifier={identifier}"
+                        ))
+                    })?;
+                    artifact.catalog.retained_until = artifact.catalog.retained_until.max(until);
+                    Ok(())
+                });
+            outcome.record(identifier, result);
+        }
+        Ok(outcome)
+    }
+
+    /// The export manifest of workspace `name`, listing the members `user_id` can read.
+    pub fn workspace_manifest(
+        &self,
+        name: &str,
+        user_id: &str,
+    ) -> Result<(Manifest, BulkOutcome), Status> {
+        let workspace = self.workspaces.get(name, user_id)?;
+        let mut manifest = Manifest {
+            workspace: workspace.name,
+            created_at: catalog::now_ms(),
+            members: Vec::new(),
+        };
+        let mut outcome = BulkOutcome::default();
+        for identifier in &workspace.members {
+            let entry = self
+                .check_resolvable(std::slice::from_ref(identifier), user_id)
+                .and_then(|()| {
+                    self.with_df_artifact_ref(identifier, |artifact| -> Result<_, Status> {
+                        Ok(ManifestEntry {
+                            identifier: identifier.clone(),
+                            name: artifact.catalog.name.clone(),
+                            owner: artifact.catalog.owner.clone(),
+                            kind: artifact.kind().name(),
+                            rows: artifact.dataframe.height() as u64,
+                            header: get_schema_header(&artifact.declared_schema())?,
+                            content_hash: content_hash(&artifact.declared_dataframe()?)?,
+                        })
+                    })?
+                });
+            outcome.record(identifier, entry.map(|entry| manifest.members.push(entry)));
+        }
+        Ok((manifest, outcome))
+    }
+
+    /// The lines of an approval prompt listing the recent queries of `recipient` on the inputs
+    /// of `artifact`.
+    fn approval_history(
+        &self,
+        dfs: &HashMap<String, DataFrameArtifact>,
+        artifact: &DataFrameArtifact,
+        recipient: &str,
+    ) -> String {
+        let provenance = match &artifact.provenance {
+            Some(provenance) => provenance,
+            None => return String::new(),
+        };
+        let inputs: Vec<_> = provenance
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                let activity = &dfs.get(&input.identifier)?.activity;
+                Some((input.identifier.as_str(), activity))
+            })
+            .collect();
+        activity::approval_details(&inputs, recipient, self.activity_limits, catalog::now_ms())
+    }
+
+    /// The recent queries on `identifier`, oldest first, which only its owner can see.
+    pub fn recent_activity(
+        &self,
+        identifier: &str,
+        user_id: &str,
+    ) -> Result<Vec<ActivityEntry>, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| {
+            if artifact.catalog.owner != user_id {
+                return Err(Status::permission_denied(format!(
+                    "Only the owner of {identifier} can see its recent queries"
+                )));
+            }
+            Ok(artifact
+                .activity
+                .recent(self.activity_limits, catalog::now_ms())
+                .into_iter()
+                .cloned()
+                .collect())
+        })?
+    }
+
+    /// Issues an export waiver for `identifier` to `recipient`, see [`waivers`]. Only the owners of
+    /// the data of `identifier` can issue waivers: the owners of the dataframes it was computed
+    /// from, or its own owner if it was uploaded.
+    pub fn create_export_waiver(
+        &self,
+        identifier: &str,
+        recipient: &str,
+        expires_at: u64,
+        justification: &str,
+        user_id: &str,
+    ) -> Result<Waiver, Status> {
+        let (identifier, _) = self.resolve(identifier)?;
+        let owners = {
+            let dfs = self.dataframes.read().unwrap();
+            let artifact = dfs.get(&identifier).ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?;
+            match &artifact.provenance {
+                Some(provenance) => provenance
+                    .inputs
+                    .iter()
+                    .filter_map(|input| dfs.get(&input.identifier))
+                    .map(|input| input.catalog.owner.clone())
+                    .collect(),
+                None => vec![artifact.catalog.owner.clone()],
+            }
+        };
+        if !owners.iter().any(|owner| owner == user_id) {
+            return Err(Status::permission_denied(format!(
+                "Only the owners of the data of {identifier} can issue export waivers for it"
+            )));
+        }
+        let waiver = self.waivers.issue(
+            &identifier,
+            recipient,
+            user_id,
+            justification,
+            expires_at,
+            catalog::now_ms(),
+        )?;
+        info!(
+            "Export waiver {} issued by {user_id} for {identifier} to {recipient}, until \
+             {expires_at}",
+            waiver.id
+        );
+        Ok(waiver)
+    }
+
+    /// Usage of the dataframes of `user_id` by purpose code, see [`purpose`].
+    pub fn usage_report(
+        &self,
+        identifier: Option<&str>,
+        since: u64,
+        user_id: &str,
+    ) -> Result<std::collections::BTreeMap<String, PurposeUsage>, Status> {
+        self.sess_manager.verify_if_owner(user_id)?;
+        Ok(self.access_log.usage(user_id, identifier, since))
+    }
+
+    /// The accesses recorded under `correlation_id`, see [`federation`].
+    pub fn correlated_accesses(&self, correlation_id: &str) -> Vec<AccessRecord> {
+        self.access_log.correlated(correlation_id)
+    }
+
+    /// Runs the sub-plans of `plan` reading federated dataframes on their servers, and returns
+    /// the warnings their results were fetched with, see [`federation`].
+    async fn federate(
+        &self,
+        plan: &mut CompositePlan,
+        correlation_id: &str,
+        purpose: Option<&Purpose>,
+    ) -> Result<Vec<String>, Status> {
+        let remotes = {
+            let dfs = self.dataframes.read().unwrap();
+            plan.split_remote(|identifier| {
+                dfs.get(identifier)
+                    .and_then(|artifact| artifact.remote.clone())
+            })?
+        };
+        let mut warnings = Vec::new();
+        for (index, identifier, source, sub_plan) in remotes {
+            let result = self
+                .federation
+                .forward(&identifier, &source, &sub_plan, correlation_id, purpose)
+                .await?;
+            info!(
+                "Read federated dataframe {identifier} from {} (correlation id {correlation_id})",
+                source.address
+            );
+            warnings.extend(result.warning);
+            plan.set_remote_input(index, result.dataframe);
+        }
+        Ok(warnings)
+    }
+
+    /// Whether `user_id` owns `artifact`, or a workspace shared with them grants access to it.
+    fn is_granted(&self, identifier: &str, artifact: &DataFrameArtifact, user_id: &str) -> bool {
+        artifact.catalog.owner == user_id
+            || self
+                .workspaces
+                .grants(identifier, &artifact.catalog.owner, user_id)
+    }
+
+    /// Fails as if they did not exist when plans of `user_id` cannot read one of `identifiers`.
+    fn check_resolvable(&self, identifiers: &[String], user_id: &str) -> Result<(), Status> {
+        let dfs = self.dataframes.read().unwrap();
+        for identifier in identifiers {
+            if let Some(artifact) = dfs.get(identifier) {
+                if !artifact
+                    .onboarding
+                    .resolvable_by(self.is_granted(identifier, artifact, user_id))
+                {
+                    return Err(Status::not_found(format!(
+                        "Could not find dataframe: identifier={}",
+                        identifier
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The identifier of the dataframe `identifier` stands for, along with the redirect note when
+    /// it is an alias, see [`aliases`].
+    pub fn resolve(&self, identifier: &str) -> Result<(String, Option<String>), Status> {
+        let dfs = self.dataframes.read().unwrap();
+        if dfs.contains_key(identifier) {
+            return Ok((identifier.to_owned(), None));
+        }
+        match self.aliases.resolve(identifier, catalog::now_ms())? {
+            Some(alias) if dfs.contains_key(&alias.canonical) => {
+                Ok((alias.canonical.clone(), Some(alias.redirect(identifier))))
+            }
+            Some(alias) => Err(Status::not_found(format!(
+                "Dataframe {identifier} is an alias of {}, which was deleted",
+                alias.canonical
+            ))),
+            None => {
+                self.expiry.check(identifier)?;
+                Ok((identifier.to_owned(), None))
+            }
+        }
+    }
+
+    /// Makes `alias`, which must not name a dataframe, resolve to `canonical`.
+    pub fn alias_df(&self, alias: &str, canonical: &str, reason: &str) -> Result<Alias, Status> {
+        let (canonical, _) = self.resolve(canonical)?;
+        let entry = {
+            let dfs = self.dataframes.read().unwrap();
+            if dfs.contains_key(alias) {
+                return Err(Status::failed_precondition(format!(
+                    "Dataframe {alias} exists: it must be deleted before its identifier is aliased"
+                )));
+            }
+            if !dfs.contains_key(&canonical) {
+                return Err(Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    canonical
+                )));
+            }
+            self.aliases
+                .insert(alias, &canonical, reason, catalog::now_ms())?
+        };
+        info!(
+            "Identifier {alias} is now an alias of {} ({reason})",
+            entry.canonical
+        );
+        self.persist_aliases()?;
+        Ok(entry)
+    }
+
+    /// Deletes `identifier`, which must hold the same data as `canonical`, and aliases it to
+    /// `canonical`.
+    pub fn deduplicate_df(&self, identifier: &str, canonical: &str) -> Result<Alias, Status> {
+        let (canonical, _) = self.resolve(canonical)?;
+        if canonical == identifier {
+            return Err(Status::invalid_argument(format!(
+                "Dataframe {identifier} cannot be deduplicated into itself"
+            )));
+        }
+        self.load_evicted(identifier)?;
+        self.load_evicted(&canonical)?;
+        let duplicate = {
+            let dfs = self.dataframes.read().unwrap();
+            let get = |identifier: &str| {
+                dfs.get(identifier).ok_or_else(|| {
+                    Status::not_found(format!(
+                        "Could not find dataframe: identifier={}",
+                        identifier
+                    ))
+                })
+            };
+            get(identifier)?
+                .declared_dataframe()?
+                .frame_equal_missing(&get(&canonical)?.declared_dataframe()?)
+        };
+        if !duplicate {
+            return Err(Status::failed_precondition(format!(
+                "Dataframes {identifier} and {canonical} hold different data"
+            )));
+        }
+        self.delete_dfs(identifier)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        self.alias_df(identifier, &canonical, "deduplicated")
+    }
+
+    /// Fails if one of `identifiers` is not published, as `what` may run for other users.
+    fn check_published(&self, identifiers: &[String], what: &str) -> Result<(), Status> {
+        let dfs = self.dataframes.read().unwrap();
+        for identifier in identifiers {
+            if let Some(artifact) = dfs.get(identifier) {
+                if !artifact.onboarding.is_published() {
+                    return Err(Status::failed_precondition(format!(
+                        "Dataframe {identifier} is {}: only published dataframes can be used by {what}",
+                        artifact.onboarding.state.name()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a dataframe to another onboarding state, returning the views dropped on unpublishing.
+    ///
+    /// Owners submit and unpublish their dataframes, reviewers approve or reject the others.
+    /// Unpublishing has the same preconditions as a deletion.
+    pub fn transition_df(
+        &self,
+        identifier: &str,
+        user_id: &str,
+        transition: Transition,
+        reason: Option<String>,
+    ) -> Result<Vec<String>, Status> {
+        if transition == Transition::Unpublish {
+            self.check_not_exported(identifier)?;
+            self.families.check_not_member(identifier)?;
+        }
+        let reviewer = self.is_reviewer(user_id).unwrap_or(false);
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs
+            .get_mut(identifier)
+            .filter(|artifact| {
+                let owner = artifact.catalog.owner == user_id;
+                artifact.onboarding.visible_to(owner, reviewer)
+            })
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?;
+        let owner = artifact.catalog.owner == user_id;
+        match transition {
+            Transition::Submit | Transition::Unpublish if !owner => {
+                return Err(Status::permission_denied(format!(
+                    "Only the owner of dataframe {identifier} can submit or unpublish it."
+                )))
+            }
+            Transition::Approve | Transition::Reject if !reviewer => {
+                return Err(Status::permission_denied(
+                    "Only reviewers can approve or reject dataframes.",
+                ))
+            }
+            Transition::Approve | Transition::Reject if owner => {
+                return Err(Status::permission_denied(
+                    "Reviewers cannot approve or reject their own dataframes.",
+                ))
+            }
+            _ => (),
+        }
+        artifact.onboarding.apply(identifier, transition, reason)?;
+        let state = artifact.onboarding.state;
+        let dropped = if transition == Transition::Unpublish {
+            self.access.bump();
+            self.views.drop_views_of(identifier)
+        } else {
+            Vec::new()
+        };
+        drop(dfs);
+
+        info!(
+            "Dataframe {identifier} is now {} ({:?} by {user_id})",
+            state.name(),
+            transition
+        );
+        for view in dropped.iter() {
+            info!("Dropped view {view} of unpublished dataframe {identifier}");
+        }
+        self.persist_if_stored(identifier)?;
+        Ok(dropped)
+    }
+
+    /// Edits a draft dataframe. Only its owner can do this.
+    pub fn update_draft(
+        &self,
+        identifier: &str,
+        user_id: &str,
+        policy: Option<Policy>,
+        blacklist: Option<Vec<String>>,
+        name: Option<String>,
+        tags: Option<Vec<String>>,
+    ) -> Result<(), Status> {
+        {
+            let mut dfs = self.dataframes.write().unwrap();
+            let artifact = dfs
+                .get_mut(identifier)
+                .filter(|artifact| artifact.catalog.owner == user_id)
+                .ok_or_else(|| {
+                    Status::not_found(format!(
+                        "Could not find dataframe: identifier={}",
+                        identifier
+                    ))
+                })?;
+            artifact.onboarding.check_editable(identifier)?;
+            if let Some(policy) = policy {
+                policy.check_masks(&artifact.declared_schema())?;
+                artifact.policy = policy;
+                self.access.bump();
+            }
+            if let Some(blacklist) = blacklist {
+                artifact.blacklist = blacklist;
+            }
+            if let Some(name) = name {
+                artifact.catalog.name = name;
+            }
+            if let Some(tags) = tags {
+                artifact.catalog.tags = tags;
+            }
+        }
+        info!("Dataframe {identifier} was edited by {user_id}");
+        self.persist_if_stored(identifier)
+    }
+
+    /// The onboarding state of a dataframe, as seen by `user_id`.
+    fn lifecycle_response(
+        &self,
+        identifier: &str,
+        user_id: &str,
+        dropped_views: Vec<String>,
+    ) -> Result<LifecycleResponse, Status> {
+        let reviewer = self.is_reviewer(user_id).unwrap_or(false);
+        self.load_evicted(identifier)?;
+        self.prepare_masks(identifier, Tolerance::Stale)?;
+        let dfs = self.dataframes.read().unwrap();
+        let not_found = || {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        };
+        let artifact = dfs.get(identifier).ok_or_else(not_found)?;
+        let owner = artifact.catalog.owner == user_id;
+        if !artifact.onboarding.visible_to(owner, reviewer) {
+            return Err(not_found());
+        }
+        Ok(LifecycleResponse {
+            identifier: identifier.to_string(),
+            state: artifact.onboarding.state.name().to_string(),
+            owner: artifact.catalog.owner.clone(),
+            header: artifact.header()?,
+            rejection: artifact.onboarding.rejection.clone().unwrap_or_default(),
+            preview: if owner || reviewer {
+                // The state changed already: a preview the masks refuse does not fail the request.
+                artifact
+                    .masking()
+                    .and_then(|masking| {
+                        lifecycle::preview(&artifact.dataframe, &masking, &artifact.blacklist)
+                    })
+                    .unwrap_or_else(|e| format!("No preview: {}", e.message()))
+            } else {
+                String::new()
+            },
+            dropped_views,
+        })
+    }
+
+    /// Registers a dataset family, see [`families`].
+    pub fn register_family(
+        &self,
+        name: &str,
+        key: PartitionKey,
+        members: Vec<(String, PartitionValue)>,
+    ) -> Result<FamilyInfo, Status> {
+        let dfs = self.dataframes.read().unwrap();
+        self.families.register(name, key, members, &dfs)
+    }
+
+    pub fn add_family_members(
+        &self,
+        name: &str,
+        members: Vec<(String, PartitionValue)>,
+    ) -> Result<FamilyInfo, Status> {
+        let dfs = self.dataframes.read().unwrap();
+        self.families.add_members(name, members, &dfs)
+    }
+
+    pub fn remove_family_members(
+        &self,
+        name: &str,
+        identifiers: &[String],
+    ) -> Result<FamilyInfo, Status> {
+        self.families.remove_members(name, identifiers)
+    }
+
+    pub fn family(&self, name: &str) -> Result<FamilyInfo, Status> {
+        self.families.family(name)
+    }
+
+    /// Registers a materialized view, see [`views`].
+    pub fn register_view(
+        &self,
+        name: &str,
+        plan: &CompositePlan,
+        max_staleness: Duration,
+    ) -> Result<ViewInfo, Status> {
+        self.check_published(&plan.entry_points(), "materialized views")?;
+        let dfs = self.dataframes.read().unwrap();
+        self.views.register(name, plan, max_staleness, &dfs)
+    }
+
+    pub fn refresh_view(&self, name: &str) -> Result<ViewInfo, Status> {
+        let dfs = self.dataframes.read().unwrap();
+        self.views.refresh(name, &dfs)
+    }
+
+    pub fn view(&self, name: &str) -> Result<ViewInfo, Status> {
+        self.views.view(name)
+    }
+
+    /// Reads the result of `plan` from a materialized view, if one can answer it.
+    pub fn answer_from_view(&self, plan: &CompositePlan) -> Result<Option<ViewAnswer>, Status> {
+        let dfs = self.dataframes.read().unwrap();
+        self.views.answer(plan, &dfs)
+    }
+
+    pub fn get_df_unchecked(&self, identifier: &str) -> Result<DataFrame, Status> {
+        self.touch(identifier)?;
+        let dfs = self.dataframes.read().unwrap();
+        dfs.get(identifier)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?
+            .declared_dataframe()
+    }
+
+    /// Fails unless `user_id` is `owner`, the owner of a result, or a data owner.
+    fn check_bundle_access(&self, owner: &str, user_id: &str) -> Result<(), Status> {
+        if owner != user_id && !self.sess_manager.verify_if_owner(user_id)? {
+            return Err(Status::permission_denied(
+                "Only the owner of a result and data owners can handle its reproducibility bundles",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The reproducibility bundle of result `identifier`, see [`reproducibility`].
+    pub fn reproducibility_bundle(
+        &self,
+        identifier: &str,
+        user_id: &str,
+    ) -> Result<Bundle, Status> {
+        let owner =
+            self.with_df_artifact_ref(identifier, |artifact| artifact.catalog.owner.clone())?;
+        self.check_bundle_access(&owner, user_id)?;
+        let (provenance, trace, schema, result_hash, semantics_version) = self
+            .with_df_artifact_ref(identifier, |artifact| -> Result<_, Status> {
+                let provenance = artifact.provenance.clone().ok_or_else(|| {
+                    Status::failed_precondition(format!(
+                        "Dataframe {identifier} is not the result of a query"
+                    ))
+                })?;
+                let schema = artifact
+                    .declared_schema()
+                    .iter()
+                    .map(|(name, dtype)| (name.to_string(), dtype.to_string()))
+                    .collect::<Vec<_>>();
+                Ok((
+                    provenance,
+                    artifact
+                        .query_details
+                        .lines()
+                        .map(String::from)
+                        .collect::<Vec<_>>(),
+                    schema,
+                    content_hash(&artifact.declared_dataframe()?)?,
+                    artifact.semantics_version,
+                ))
+            })??;
+
+        let mut inputs = Vec::with_capacity(provenance.inputs.len());
+        for input in provenance.inputs {
+            let hash = self.with_df_artifact_ref(&input.identifier, |artifact| {
+                if artifact.version != input.version {
+                    return Err(Status::failed_precondition(format!(
+                        "Dataframe {} was modified since {identifier} was computed from its version {} (now {}): that content is gone",
+                        input.identifier, input.version, artifact.version
+                    )));
+                }
+                content_hash(&artifact.declared_dataframe()?)
+            })??;
+            inputs.push(BundleInput {
+                input,
+                content_hash: hash,
+            });
+        }
+
+        Ok(Bundle {
+            format: reproducibility::BUNDLE_FORMAT,
+            result: identifier.to_string(),
+            owner,
+            created_at: catalog::now_ms(),
+            plan_hash: reproducibility::plan_hash(&provenance.plan)?,
+            plan: provenance.plan,
+            slot: provenance.slot,
+            inputs,
+            semantics_version,
+            engine: semantics::current_engine().to_string(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            trace,
+            schema,
+            result_hash,
+        })
+    }
+
+    /// What differs between the inputs recorded in `bundle` and the current dataframes.
+    fn bundle_input_mismatches(&self, bundle: &Bundle) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if bundle.engine != semantics::current_engine() {
+            mismatches.push(format!(
+                "The bundle was created on {}, this server runs {}",
+                bundle.engine,
+                semantics::current_engine()
+            ));
+        }
+        for BundleInput {
+            input,
+            content_hash: expected,
+        } in bundle.inputs.iter()
+        {
+            let current = self
+                .with_df_artifact_ref(&input.identifier, |artifact| -> Result<_, Status> {
+                    Ok((
+                        artifact.version,
+                        reproducibility::policy_hash(&artifact.policy)?,
+                        content_hash(&artifact.declared_dataframe()?)?,
+                    ))
+                })
+                .and_then(|current| current);
+            let (version, policy_hash, hash) = match current {
+                Ok(current) => current,
+                Err(e) => {
+                    mismatches.push(format!("{}: {}", input.identifier, e.message()));
+                    continue;
+                }
+            };
+            if version != input.version {
+                mismatches.push(format!(
+                    "Dataframe {} is at version {version}, the bundle has version {}",
+                    input.identifier, input.version
+                ));
+            }
+            if policy_hash != input.policy_hash {
+                mismatches.push(format!(
+                    "The policy of dataframe {} changed",
+                    input.identifier
+                ));
+            }
+            if hash != *expected {
+                mismatches.push(format!(
+                    "The content of dataframe {} changed",
+                    input.identifier
+                ));
+            }
+        }
+        mismatches
+    }
+
+    /// Runs the plan of `bundle` again for `user_id`, returning the content hash of its result.
+    async fn rerun_bundle(&self, bundle: &Bundle, user_id: &str) -> Result<String, Status> {
+        let plan: CompositePlan = serde_json::from_value(bundle.plan.clone()).map_err(|e| {
+            Status::invalid_argument(format!("Could not deserialize composite plan: {e}"))
+        })?;
+        let mut datasets = plan.entry_points();
+        for (family, predicate) in plan.family_entry_points() {
+            let scan = self.families.prune(family, predicate)?;
+            datasets.extend(scan.scanned.into_iter().map(|(identifier, _)| identifier));
+        }
+        self.check_resolvable(&datasets, user_id)?;
+
+        let slot = self
+            .scheduler
+            .acquire(user_id, QueryPriority::Batch)
+            .await?;
+        self.metrics.queue_wait(slot.queue_time);
+        let state = self.clone();
+        let run_user_id = user_id.to_string();
+        let outputs = tokio::task::spawn_blocking(move || plan.run_outputs(&state, &run_user_id))
+            .await
+            .map_err(|e| Status::internal(format!("Query execution task failed: {e}")))??;
+        drop(slot);
+        let (_, res) = outputs
+            .into_iter()
+            .find(|(slot, _)| slot == &bundle.slot)
+            .ok_or_else(|| {
+                Status::failed_precondition(format!(
+                    "The plan of the bundle no longer has output slot {}",
+                    bundle.slot
+                ))
+            })?;
+        content_hash(&res.dataframe)
+    }
+
+    fn with_df_artifact_ref<T>(
+        &self,
+        identifier: &str,
+        mut f: impl FnMut(&DataFrameArtifact) -> T,
+    ) -> Result<T, Status> {
+        self.load_evicted(identifier)?;
+        let dfs = self.dataframes.read().unwrap();
+        Ok(f(dfs.get(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?))
+    }
+
+    /// The statistics of `columns` of `identifier`, all of them if empty, see [`statistics`].
+    /// Policy checks must not tolerate stale ones.
+    pub fn column_statistics(
+        &self,
+        identifier: &str,
+        columns: &[String],
+        tolerance: Tolerance,
+    ) -> Result<Vec<(String, ColumnStatistics, bool)>, Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.check_resident()?;
+        let columns = match columns {
+            [] => artifact
+                .dataframe
+                .get_column_names()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            columns => columns.to_vec(),
+        };
+        let statistics = artifact
+            .statistics
+            .get(&artifact.dataframe, &columns, tolerance)?;
+        Ok(statistics
+            .into_iter()
+            .map(|(column, statistics)| {
+                let stale = artifact.statistics.is_stale(&column);
+                (column, statistics, stale)
+            })
+            .collect())
+    }
+
+    /// The fingerprint of `identifier`, and those of its inputs if it is a result, see
+    /// [`fingerprints`]. Federated dataframes, whose rows are held by another server, have none.
+    pub fn fingerprint(
+        &self,
+        identifier: &str,
+    ) -> Result<(String, HashMap<String, String>), Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        match artifact.remote {
+            Some(_) => Ok(Default::default()),
+            None => artifact.fingerprint(),
+        }
+    }
+
+    /// The fingerprint of `identifier` if it is in memory, or was computed before it was evicted:
+    /// listings do not load dataframes.
+    fn listed_fingerprint(&self, identifier: &str) -> Result<String, Status> {
+        let mut dfs = self.dataframes.write().unwrap();
+        match dfs.get_mut(identifier) {
+            Some(artifact)
+                if artifact.remote.is_none()
+                    && (artifact.storage.resident || artifact.fingerprints.is_cached()) =>
+            {
+                Ok(artifact.fingerprint()?.0)
+            }
+            _ => Ok(String::new()),
+        }
+    }
+
+    /// Records the fingerprints of the inputs of result `res` before it is stored. Inputs modified
+    /// since the query read them are fingerprinted at the version read, if it is still retained.
+    fn record_input_fingerprints(&self, res: &mut DataFrameArtifact) -> Result<(), Status> {
+        let Some(provenance) = res.provenance.as_mut() else {
+            return Ok(());
+        };
+        for input in provenance.inputs.iter() {
+            let fingerprint = self.input_fingerprint(input)?;
+            provenance.fingerprints.insert(input.key(), fingerprint);
+        }
+        Ok(())
+    }
+
+    /// The fingerprint of `input` at the version read, empty if it is gone.
+    fn input_fingerprint(&self, input: &InputVersion) -> Result<String, Status> {
+        let InputVersion {
+            identifier,
+            version,
+            ..
+        } = input;
+        self.load_evicted(identifier)?;
+        let retained = {
+            let mut dfs = self.dataframes.write().unwrap();
+            let artifact = match dfs.get_mut(identifier) {
+                Some(artifact) if artifact.remote.is_none() => artifact,
+                _ => return Ok(String::new()),
+            };
+            if artifact.version == *version {
+                return Ok(artifact.fingerprint()?.0);
+            }
+            let found = artifact.history.find(
+                identifier,
+                artifact.version,
+                Selector::Version(*version),
+                catalog::now_ms(),
+            );
+            match found {
+                Ok(Found::Retained(retained)) => retained.declared_dataframe()?,
+                _ => return Ok(String::new()),
+            }
+        };
+        Fingerprints::default().get(&retained, &retained.schema(), *version)
+    }
+
+    /// Computes the statistics the masks of `identifier` read, see [`masking`]: those never
+    /// computed, or also those whose percentiles are behind if `tolerance` is exact.
+    fn prepare_masks(&self, identifier: &str, tolerance: Tolerance) -> Result<(), Status> {
+        let columns = self.with_df_artifact_ref(identifier, |artifact| {
+            masking::percentile_columns(artifact.policy.column_masks())
+        })?;
+        if !columns.is_empty() {
+            self.column_statistics(identifier, &columns, tolerance)?;
+        }
+        Ok(())
+    }
+
+    /// The statistics of the masked columns of `identifier`, computed from their masked values.
+    fn masked_statistics(
+        &self,
+        identifier: &str,
+    ) -> Result<HashMap<String, ColumnStatistics>, Status> {
+        self.with_df_artifact_ref(identifier, |artifact| {
+            let masking = artifact.masking()?;
+            let names = artifact.dataframe.get_column_names();
+            let columns: Vec<&str> = masking
+                .columns()
+                .map(String::as_str)
+                .filter(|column| names.contains(column))
+                .collect();
+            if columns.is_empty() {
+                return Ok(HashMap::new());
+            }
+            artifact.check_resident()?;
+            let mut df = artifact
+                .dataframe
+                .select(columns)
+                .map_err(|e| Status::internal(format!("Could not mask the statistics: {e}")))?;
+            masking.apply(&mut df)?;
+            df.get_columns()
+                .iter()
+                .map(|series| {
+                    let statistics =
+                        ColumnStatistics::compute(series, artifact.statistics.generation())?;
+                    Ok((series.name().to_string(), statistics))
+                })
+                .collect()
+        })?
+    }
+
+    pub fn get_header(&self, identifier: &str) -> Result<String, Status> {
+        self.dataframes
+            .read()
+            .unwrap()
+            .get(identifier)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find dataframe: identifier={}",
+                    identifier
+                ))
+            })?
+            .header()
+    }
+
+    /// Lists the page of dataframes visible to `user_id` matching `filter` that follows `after`,
+    /// with their headers, see [`catalog`].
+    pub fn list_dfs(
+        &self,
+        user_id: &str,
+        filter: &ListingFilter,
+        after: Option<&Cursor>,
+        page_size: usize,
+    ) -> Result<(Vec<(String, String)>, catalog::Page), Status> {
+        let reviewer = self.is_reviewer(user_id).unwrap_or(false);
+        let dataframes = self.dataframes.read().unwrap();
+        let visible = dataframes.iter().filter(|(identifier, artifact)| {
+            artifact
+                .onboarding
+                .visible_to(self.is_granted(identifier, artifact, user_id), reviewer)
+        });
+        let items = visible.map(|(identifier, artifact)| Listed {
+            identifier,
+            entry: &artifact.catalog,
+            kind: artifact.kind(),
+            size: artifact.size(),
+        });
+        let page = catalog::page(items, filter, after, page_size);
+        let headers = page
+            .identifiers
+            .iter()
+            .map(|identifier| Ok((identifier.clone(), dataframes[identifier].header()?)))
+            .collect::<Result<_, Status>>()?;
+        Ok((headers, page))
+    }
+
+    /// Generates a synthetic dataframe from `identifier`, whose policy must allow it, see
+    /// [`synthetic`].
+    ///
+    /// `rows` defaults to the rows of the source, and `seed` to a random one.
+    pub fn generate_synthetic(
+        &self,
+        identifier: &str,
+        user_id: &str,
+        rows: Option<usize>,
+        seed: Option<u64>,
+    ) -> Result<String, Status> {
+        let (df, settings, identifiers) =
+            self.with_df_artifact_ref(identifier, |artifact| -> Result<_, Status> {
+                let settings = artifact.policy.synthetic().cloned().ok_or_else(|| {
+                    Status::permission_denied(format!(
+                        "The policy of dataframe {identifier} does not allow synthetic data generation"
+                    ))
+                })?;
+                let mut identifiers = settings.identifier_columns.clone();
+                identifiers.extend(artifact.blacklist.iter().cloned());
+                Ok((artifact.declared_dataframe()?, settings, identifiers))
+            })??;
+
+        let rows = rows.unwrap_or(df.height());
+        if let Some(max_rows) = settings.max_rows {
+            if rows > max_rows {
+                return Err(Status::invalid_argument(format!(
+                    "The policy of dataframe {identifier} allows at most {max_rows} synthetic rows"
+                )));
+            }
+        }
+        let seed = seed.unwrap_or_else(|| thread_rng().gen());
+        let df = synthetic::generate(&df, &settings, &identifiers, rows, seed)?;
+
+        let origin = SyntheticOrigin {
+            source: identifier.to_string(),
+            rows,
+            seed,
+        };
+        let mut artifact = DataFrameArtifact::new(df, Policy::allow_by_default(), Vec::new())
+            .with_fetchable(VerificationResult::Safe)
+            .with_owner(user_id);
+        artifact.query_details = origin.lineage();
+        artifact.synthetic = Some(origin);
+        let synthetic = self.insert_df(artifact);
+        info!(
+            "Generated synthetic dataframe {synthetic} from {identifier} for {user_id}: {rows} rows, seed {seed}"
+        );
+        Ok(synthetic)
+    }
+
+    /// Applies the probing response of the result's policy to a query flagged by the detector.
+    async fn respond_to_probing(
+        &self,
+        user_id: &str,
+        datasets: &[String],
+        res: &mut DataFrameArtifact,
+        reason: String,
+    ) -> Result<(), Status> {
+        let response = res.policy.probing_response();
+        warn!(
+            "Possible probing attack by {} on {:?}: {} (responding with {:?})",
+            user_id, datasets, reason, response
+        );
+        match response {
+            ProbingResponse::Alert => (),
+            ProbingResponse::Jitter { max_delay_ms } => {
+                let delay = thread_rng().gen_range(0..=max_delay_ms);
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+            ProbingResponse::ForcePending => res.fetchable.merge(VerificationResult::Unsafe {
+                action: UnsafeAction::Review,
+                reason: format!("This query looks like a probing attempt: {reason}"),
+            }),
+            ProbingResponse::Suspend { duration_secs } => {
+                self.suspend(user_id, datasets, Duration::from_secs(duration_secs));
+                return Err(Status::permission_denied(format!(
+                    "Query rejected because of suspicious activity: {reason}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Suspends `identity` on `datasets`, stopping its ongoing fetches of them.
+    fn suspend(&self, identity: &str, datasets: &[String], duration: Duration) {
+        self.probing.suspend(identity, datasets, duration);
+        self.access.bump();
+    }
+
+    /// Snapshots the fetch of `identifier` by `recipient`, to re-check it while it streams.
+    fn fetch_guard(&self, identifier: &str, recipient: &str) -> Result<FetchGuard, Status> {
+        FetchGuard::new(
+            identifier,
+            recipient,
+            self.fetch_checkpoint_chunks,
+            Arc::clone(&self.sess_manager),
+            Arc::clone(&self.dataframes),
+            Arc::clone(&self.probing),
+            Arc::clone(&self.access),
+        )
+    }
+
+    fn optimize_df_storage(
+        &self,
+        identifier: &str,
+        allow_lossy_floats: bool,
+    ) -> Result<StorageReport, Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.optimize_storage(allow_lossy_floats)
+    }
+
+    /// Appends rows to a dataframe, evaluating its data-quality constraints first.
+    ///
+    /// Breaches are recorded in the dataframe's quality history; the append is rejected if one of
+    /// them has the `Block` severity.
+    fn append_df(&self, identifier: &str, delta: DataFrame) -> Result<String, Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.check_resident()?;
+
+        capabilities::check_rows((artifact.dataframe.height() + delta.height()) as u64)?;
+        let declared = delta.clone();
+        let delta = artifact.align_append(delta)?;
+        self.families.check_rows(identifier, &delta)?;
+        let version = artifact.version + 1;
+        let check = artifact
+            .quality
+            .check(&artifact.dataframe, &delta, version)?;
+        record_quality_check(identifier, artifact, check, "append")?;
+
+        // Appending adds chunks: the previous version shares all of its rows with the new one.
+        let previous = artifact.dataframe.clone();
+        artifact
+            .dataframe
+            .vstack_mut(&delta)
+            .map_err(|e| Status::invalid_argument(format!("Could not append rows: {e}")))?;
+        artifact.statistics.appended(&delta)?;
+        artifact.fingerprints.appended(previous.height());
+        artifact.history.supersede(
+            artifact.version,
+            previous,
+            artifact.dtype_changes.clone(),
+            0,
+            catalog::now_ms(),
+        );
+        artifact.version = version;
+        self.views.appended(identifier, &declared, artifact);
+        get_schema_header(&artifact.declared_schema())
+    }
+
+    /// Merges rows into a dataframe on `keys`, see [`upsert`].
+    ///
+    /// The merge runs on a snapshot of the dataframe, which queries keep seeing until the result is
+    /// swapped in. The upsert fails if the dataframe was modified in the meantime.
+    fn upsert_df(
+        &self,
+        identifier: &str,
+        incoming: DataFrame,
+        keys: &[String],
+    ) -> Result<(UpsertReport, u64, String), Status> {
+        let not_found = || {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        };
+        self.load_evicted(identifier)?;
+        let (current, version, changes) = {
+            let dfs = self.dataframes.read().unwrap();
+            let artifact = dfs.get(identifier).ok_or_else(not_found)?;
+            (
+                artifact.declared_dataframe()?,
+                artifact.version,
+                artifact.dtype_changes.clone(),
+            )
+        };
+
+        let (mut merged, report) = upsert(&current, &incoming, keys)?;
+        capabilities::check_rows(merged.height() as u64)?;
+        let dtype_changes = reapply_dtypes(&mut merged, &changes)?;
+
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(not_found)?;
+        if artifact.version != version {
+            return Err(Status::aborted(
+                "The dataframe was modified during the upsert, please retry",
+            ));
+        }
+        self.families.check_rows(identifier, &merged)?;
+        let check = artifact.quality.check_replacement(&merged, version + 1)?;
+        record_quality_check(identifier, artifact, check, "upsert")?;
+
+        let (previous, previous_changes, unshared) = match artifact.storage.resident {
+            true => {
+                let unshared = versions::share_columns(&artifact.dataframe, &mut merged)?;
+                let previous = std::mem::replace(&mut artifact.dataframe, merged);
+                let changes = std::mem::replace(&mut artifact.dtype_changes, dtype_changes);
+                (previous, changes, unshared)
+            }
+            false => {
+                artifact.dataframe = merged;
+                artifact.dtype_changes = dtype_changes;
+                let unshared = current.estimated_size() as u64;
+                (current.clone(), Vec::new(), unshared)
+            }
+        };
+        artifact.history.supersede(
+            version,
+            previous,
+            previous_changes,
+            unshared,
+            catalog::now_ms(),
+        );
+        artifact.statistics.replaced();
+        artifact.fingerprints.replaced();
+        artifact.storage.resident = true;
+        artifact.version = version + 1;
+        self.views
+            .upserted(identifier, &current, &incoming, keys, artifact);
+        Ok((
+            report,
+            artifact.version,
+            get_schema_header(&artifact.declared_schema())?,
+        ))
+    }
+
+    /// Rewrites the persisted copy of a dataframe, if there is one, after it was modified.
+    fn persist_if_stored(&self, identifier: &str) -> Result<(), Status> {
+        if self.is_persisted(identifier) {
+            self.persist_df(identifier)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the dataframes among `identifiers` (all of them if empty) produced under an older
+    /// semantics version, and re-tags them with the current one if `confirm` is set.
+    fn retag_semantics(
+        &self,
+        identifiers: &[String],
+        confirm: bool,
+    ) -> Result<Vec<SemanticsMigration>, Status> {
+        let mut migrations = Vec::new();
+        {
+            let mut dfs = self.dataframes.write().unwrap();
+            for identifier in identifiers {
+                if !dfs.contains_key(identifier) {
+                    return Err(Status::not_found(format!(
+                        "Could not find dataframe: identifier={}",
+                        identifier
+                    )));
+                }
+            }
+            for (identifier, artifact) in dfs.iter_mut() {
+                if !identifiers.is_empty() && !identifiers.contains(identifier) {
+                    continue;
+                }
+                if artifact.semantics_version == CURRENT_SEMANTICS {
+                    continue;
+                }
+                migrations.push(SemanticsMigration {
+                    identifier: identifier.clone(),
+                    from_version: artifact.semantics_version,
+                    to_version: CURRENT_SEMANTICS,
+                    shims: semantics::shims(artifact.semantics_version)?
+                        .iter()
+                        .map(|shim| shim.name().to_string())
+                        .collect(),
+                });
+                if confirm {
+                    artifact.semantics_version = CURRENT_SEMANTICS;
+                }
+            }
+        }
+        if confirm {
+            for migration in migrations.iter() {
+                self.persist_if_stored(&migration.identifier)?;
+            }
+        }
+        Ok(migrations)
+    }
+
+    fn set_df_quality_constraints(
+        &self,
+        identifier: &str,
+        constraints: Vec<MonitoredConstraint>,
+    ) -> Result<String, Status> {
+        self.load_evicted(identifier)?;
+        let mut dfs = self.dataframes.write().unwrap();
+        let artifact = dfs.get_mut(identifier).ok_or_else(|| {
+            Status::not_found(format!(
+                "Could not find dataframe: identifier={}",
+                identifier
+            ))
+        })?;
+        artifact.check_resident()?;
+        artifact
+            .quality
+            .set_constraints(constraints, &artifact.dataframe)?;
+        quality_status(artifact)
+    }
+
+    pub fn insert_df(&self, mut df: DataFrameArtifact) -> String {
+        df.catalog.created_at = catalog::now_ms();
+        let identifier = format!("{}", Uuid::new_v4());
+        self.expiry.used(&identifier, df.catalog.created_at);
+        // Uploads are decoded before, the lock is only held for the insert.
+        self.dataframes
+            .write()
+            .unwrap()
+            .insert(identifier.clone(), df);
+        identifier
+    }
+
+    pub fn insert_array(&self, array: ArrayStore) -> String {
+        let mut arrays = self.arrays.write().unwrap();
+        let identifier = format!("{}", Uuid::new_v4());
+        arrays.insert(identifier.clone(), array);
+        identifier
+    }
+
+    pub fn get_array(&self, identifier: &str) -> Result<ArrayStore, Status> {
+        let arrays = self.arrays.read().unwrap();
+        let arr = arrays
+            .get(identifier)
+            .cloned()
+            .ok_or(Status::invalid_argument(format!(
+                "Could not find Array: {identifier}"
+            )))?;
+        Ok(arr)
+    }
+
+    fn persist_df(&self, identifier: &str) -> Result<(), Status> {
+        self.load_evicted(identifier)?;
+        // The copy shares its columns, and those of its superseded versions, with the stored
+        // artifact: it is cheap to take, and writing it to disk blocks neither readers nor writers.
+        let df_artifact = self
+            .dataframes
+            .read()
+            .map_err(|_| Status::internal("Unable to read dataframes!"))?
+            .get(identifier)
+            .cloned()
+            .ok_or_else(|| Status::not_found("Unable to find dataframe!"))?;
+        self.store_df(identifier, &df_artifact)
+    }
+
+    /// Writes `df_artifact` to disk, if its policy lets the server persist it.
+    fn store_df(&self, identifier: &str, df_artifact: &DataFrameArtifact) -> Result<(), Status> {
+        df_artifact.check_resident()?;
+        let decision = self.policy_engine.evaluate(
+            Action::Persist,
+            SERVER,
+            &[Subject::artifact(identifier, df_artifact)],
+            &EvaluationContext::default(),
+        )?;
+        if let Verdict::Deny(reason) = decision.verdict() {
+            return Err(Status::unknown(reason.clone()));
+        }
+        #[cfg(test)]
+        if let Some(hook) = &self.store_hook {
+            hook(identifier);
+        }
+
+        if let Some(store) = &self.embedded {
+            store.store_artifact(
+                identifier,
+                df_artifact,
+                &self.persistence,
+                &self.tenant_keys,
+            )?;
+            return self.persist_aliases();
+        }
+
+        let error = create_dir(&self.data_dir);
+        match error {
+            Ok(_) => {}
+            Err(err) => {
+                if err.kind() != ErrorKind::AlreadyExists {
+                    return Err(Status::unknown(err.kind().to_string()));
+                }
+            }
+        }
+
+        store_artifact(
+            &self.data_dir,
+            identifier,
+            df_artifact,
+            &self.persistence,
+            &self.tenant_keys,
+        )?;
+        self.persist_aliases()?;
+
+        Ok(())
+    }
+
+    /// Persists the aliases next to the dataframes, once some dataframe was persisted.
+    fn persist_aliases(&self) -> Result<(), Status> {
+        if let Some(store) = &self.embedded {
+            return store.store_aliases(&self.aliases.aliases());
+        }
+        if !self.data_dir.exists() {
+            return Ok(());
+        }
+        store_aliases(&self.data_dir, &self.aliases.aliases())
+    }
+
+    /// Loads the privacy budgets spent before a restart, and the watermarked fetches when the
+    /// watermarking secret is configured, and persists them from now on, in the embedded store or
+    /// the data directory.
+    ///
+    /// Called on startup, before [`Self::load_dfs`]. Unlike a dataframe, which is skipped when it
+    /// cannot be loaded, an unreadable table is an error: starting without it would reset the
+    /// budgets, or leave leaks untraced.
+    pub fn open_storage(&self) -> Result<(), Error> {
+        let to_io = |e: Status| Error::other(e.message().to_owned());
+        let traced = self.watermarker.is_configured();
+        match &self.embedded {
+            Some(store) => {
+                self.privacy_budgets
+                    .restore(store.load_budgets().map_err(to_io)?);
+                let persisted = Arc::clone(store);
+                self.privacy_budgets
+                    .persist_with(move |budgets: &[SpentBudget]| persisted.store_budgets(budgets));
+                if traced {
+                    self.watermarker
+                        .restore(store.load_watermarks().map_err(to_io)?);
+                    let persisted = Arc::clone(store);
+                    self.watermarker
+                        .persist_with(move |records: &[FetchRecord]| {
+                            persisted.store_watermarks(records)
+                        });
+                }
+            }
+            None => {
+                self.privacy_budgets
+                    .restore(load_budgets(&self.data_dir).map_err(to_io)?);
+                let dir = self.data_dir.clone();
+                self.privacy_budgets
+                    .persist_with(move |budgets: &[SpentBudget]| store_budgets(&dir, budgets));
+                if traced {
+                    self.watermarker
+                        .restore(load_watermarks(&self.data_dir).map_err(to_io)?);
+                    let dir = self.data_dir.clone();
+                    self.watermarker
+                        .persist_with(move |records: &[FetchRecord]| {
+                            store_watermarks(&dir, records)
+                        });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_dfs(&self) -> Result<(), Error> {
+        let to_io = |e: Status| Error::other(e.message().to_owned());
+        let stored: Vec<(String, Option<PathBuf>)> = match &self.embedded {
+            Some(store) => {
+                self.aliases.load(store.load_aliases().map_err(to_io)?);
+                store
+                    .identifiers()
+                    .into_iter()
+                    .map(|id| (id, None))
+                    .collect()
+            }
+            None => {
+                self.aliases
+                    .load(load_aliases(&self.data_dir).map_err(to_io)?);
+                list_artifacts(&self.data_dir)
+                    .map_err(to_io)?
+                    .into_iter()
+                    .map(|(id, path)| (id, Some(path)))
+                    .collect()
+            }
+        };
+        for (identifier, path) in stored {
+            let loaded = match (&self.embedded, path) {
+                (_, Some(path)) => load_artifact(&path, &self.tenant_keys),
+                (Some(store), None) => store.load_artifact(&identifier, &self.tenant_keys),
+                (None, None) => continue,
+            };
+            let df = match loaded {
+                Ok(df) => df,
+                // The other dataframes are still served, whether this one belongs to a revoked
+                // tenant or is corrupted.
+                Err(e) => {
+                    warn!("Skipped persisted dataframe {identifier}: {}", e.message());
+                    continue;
+                }
+            };
+
+            let mut dfs = self.dataframes.write().unwrap();
+            if dfs.insert(identifier, df).is_some() {
+                self.access.bump();
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes `identifier` on behalf of `user_id`: data owners can delete any dataframe, other
+    /// users only the results they computed.
+    pub fn delete_df_for(&self, identifier: &str, user_id: &str) -> Result<(), Status> {
+        let is_owner = self.sess_manager.verify_if_owner(user_id)?;
+        let own_result = {
+            let dfs = self.dataframes.read().unwrap();
+            let artifact = dfs.get(identifier).ok_or_else(|| {
+                Status::not_found(format!("Could not find dataframe: identifier={identifier}"))
+            })?;
+            artifact.kind() != DataFrameKind::Upload && artifact.catalog.owner == user_id
+        };
+        if !is_owner && !own_result {
+            return Err(Status::permission_denied(
+                "Only data owners can delete uploads, and other users the results they computed.",
+            ));
+        }
+        self.check_not_exported(identifier)?;
+        self.families.check_not_member(identifier)?;
+        self.delete_dfs(identifier)
+            .map_err(|e| Status::internal(format!("Could not delete {identifier}: {e}")))
+    }
+
+    pub fn delete_dfs(&self, identifier: &str) -> Result<(), Error> {
+        self.check_not_exported(identifier)
+            .and_then(|_| self.families.check_not_member(identifier))
+            .map_err(|e| Error::other(e.message()))?;
+        let mut dfs = self.dataframes.write().unwrap();
+        if dfs.remove(identifier).is_some() {
+            self.access.bump();
+        }
+        for view in self.views.drop_views_of(identifier) {
+            info!("Dropped view {view} of deleted dataframe {identifier}");
+        }
+        self.workspaces.drop_member(identifier);
+        self.accesses.forget(identifier);
+        self.expiry.forget(identifier);
+
+        if let Some(store) = &self.embedded {
+            if let Err(e) = store.remove(identifier) {
+                warn!(
+                    "Could not delete {identifier} from the embedded store: {}",
+                    e.message()
+                );
+            }
+            return Ok(());
+        }
+        let dir = &self.data_dir;
+        std::fs::remove_file(artifact_path(dir, identifier)).unwrap_or(());
+        std::fs::remove_file(legacy_path(dir, identifier)).unwrap_or(());
+        Ok(())
+    }
+}
+
+fn get_df_header(df: &DataFrame) -> Result<String, Status> {
+    get_schema_header(&df.schema())
+}
+
+fn get_schema_header(schema: &Schema) -> Result<String, Status> {
+    serde_json::to_string(schema)
+        .map_err(|e| Status::internal(format!("Could not serialize data frame header: {}", e)))
+}
+
+#[tonic::async_trait]
+impl PolarsService for BastionLabPolars {
+    type FetchDataFrameStream = ReceiverStream<Result<FetchChunk, Status>>;
+
+    async fn run_query(
+        &self,
+        request: Request<Query>,
+    ) -> Result<Response<ReferenceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+        let client_info = self.sess_manager.get_client_info(token)?;
+        let correlation_id = federation::correlation_id(request.metadata())?
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let response = self
+            .execute_query(
+                request.get_ref(),
+                &user_id,
+                Some(client_info),
+                correlation_id,
+                None,
+                &EntryPoints::default(),
+            )
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn run_query_batch(
+        &self,
+        request: Request<QueryBatch>,
+    ) -> Result<Response<QueryBatchResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+        let client_info = self.sess_manager.get_client_info(token)?;
+        let response = self
+            .run_query_batch(request.into_inner(), &user_id, Some(client_info))
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn send_data_frame(
+        &self,
+        request: Request<Streaming<SendChunk>>,
+    ) -> Result<Response<ReferenceResponse>, Status> {
+        let start_time = Instant::now();
+
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+        let client_info = self.sess_manager.get_client_info(token)?;
+        self.memory.check("uploads", Pressure::Soft)?;
+        let faults = self.stream_faults(&request)?;
+        let correlation_id = federation::correlation_id(request.metadata())?;
+        let defaults = self.default_policies.resolve(&user_id);
+        let (mut df, hash, optimize) = unserialize_dataframe(
+            request.into_inner(),
+            faults,
+            self.max_upload_bytes,
+            self.blank_column_names,
+            defaults.as_ref(),
+        )
+        .await?;
+        let warnings = df
+            .defaults
+            .as_ref()
+            .map_or_else(Vec::new, AppliedDefaults::warnings);
+        for warning in warnings.iter() {
+            warn!("Upload by {user_id}: {warning}");
+        }
+        if let Some(allow_lossy_floats) = optimize {
+            let report = df.optimize_storage(allow_lossy_floats)?;
+            info!(
+                "Storage optimization saved {} bytes ({} columns changed)",
+                report.bytes_before.saturating_sub(report.bytes_after),
+                report.changes.len()
+            );
+        }
+        let header = get_schema_header(&df.declared_schema())?;
+        if !self.publish_uploads {
+            df.onboarding = Onboarding::draft();
+        }
+        let identifier = self.insert_df(df.with_owner(&user_id));
+        logging::record_identifier(&identifier);
+        self.audit.record(AuditEntry {
+            at: catalog::now_ms(),
+            identity: user_id.clone(),
+            action: AccessKind::Upload,
+            identifier: identifier.clone(),
+            inputs: Vec::new(),
+            plan_hash: String::new(),
+            segments: Vec::new(),
+            outcome: AuditOutcome::Allowed,
+        });
+        // Such as transfers from another server, see [`federation`].
+        if correlation_id.is_some() {
+            self.record_access(
+                AccessKind::Upload,
+                &user_id,
+                &identifier,
+                Vec::new(),
+                None,
+                correlation_id,
+            );
+        }
+
+        let elapsed = start_time.elapsed();
+        self.metrics.upload(elapsed);
+        telemetry::add_event(
+            TelemetryEventProps::SendDataFrame {
+                dataset_name: Some(identifier.clone()),
+                dataset_hash: Some(hash),
+                time_taken: elapsed.as_millis() as f64,
+            },
+            Some(client_info),
+        );
+
+        info!(
+            "Succesfully sent dataframe {} to server",
+            identifier.clone()
+        );
+
+        let (fingerprint, _) = self.fingerprint(&identifier)?;
+        Ok(Response::new(ReferenceResponse {
+            identifier,
+            header,
+            warnings,
+            fingerprint,
+            ..Default::default()
+        }))
+    }
+
+    async fn fetch_data_frame(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<Self::FetchDataFrameStream>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let faults = self.stream_faults(&request)?;
+        let correlation_id = federation::correlation_id(request.metadata())?;
+
+        let request = request.into_inner();
+        let mut format = FetchFormat::of(&request, self.fetch_chunk_bytes);
+        let recipient = self.sess_manager.get_user_id(token.clone())?;
+        let (identifier, redirect) = self.resolve(&request.identifier)?;
+        logging::record_identifier(&identifier);
+        self.check_resolvable(std::slice::from_ref(&identifier), &recipient)?;
+        let guard = self
+            .fetch_guard(&identifier, &recipient)?
+            .with_timer(self.metrics.fetch_stream_timer());
+        let purpose = Purpose::from_proto(request.purpose.clone())?;
+        let ranged = request.offset.is_some() || request.limit.is_some();
+        let partial = ranged || !request.columns.is_empty();
+        let waiver = match request.waiver.as_str() {
+            "" => None,
+            waiver if !request.delta_since.is_empty() || partial => {
+                return Err(Status::invalid_argument(format!(
+                    "Could not fetch {identifier}: export waiver {waiver} only covers full \
+                     fetches, not deltas nor some of the rows or columns"
+                )))
+            }
+            waiver => {
+                self.waivers
+                    .check(waiver, &identifier, &recipient, catalog::now_ms())?;
+                Some(waiver)
+            }
+        };
+        if partial && !request.delta_since.is_empty() {
+            return Err(Status::invalid_argument(format!(
+                "Could not fetch {identifier}: deltas are sent with all the rows and columns"
+            )));
+        }
+        if !request.columns.is_empty() {
+            self.with_df_artifact_ref(&identifier, |artifact| {
+                check_fetched_columns(&identifier, artifact, &request.columns)
+            })??;
+        }
+        let df = self.get_df_waived(
+            &identifier,
+            request.restore_dtypes,
+            &recipient,
+            purpose.as_ref(),
+            Some(self.sess_manager.get_client_info(token)?),
+            waiver,
+        );
+        self.record_fetch_outcome(AccessKind::Fetch, &recipient, &identifier, &df);
+        let mut df = df?;
+        format.fingerprint = self.fingerprint(&identifier)?.0;
+        self.record_fetch(
+            AccessKind::Fetch,
+            &recipient,
+            &identifier,
+            purpose.clone(),
+            correlation_id,
+        )?;
+        if let Some(redirect) = redirect {
+            df.fetch_status = df.fetch_status.with_notice(redirect);
+        }
+        if !request.columns.is_empty() {
+            let (future, columns) = (df.future, request.columns);
+            df.future = Box::pin(async move { future.await?.select(&columns) });
+        }
+        // Sliced once released: the policy applies to the whole dataframe, whatever the range.
+        if ranged {
+            let offset = request.offset.unwrap_or(0);
+            return Ok(
+                serialize_delayed_rows(df, format, offset, request.limit, guard, faults).await,
+            );
+        }
+        if request.delta_since.is_empty() {
+            return Ok(serialize_delayed_dataframe(df, format, guard, faults).await);
+        }
+
+        let (since, _) = self.resolve(&request.delta_since)?;
+        let versions = self.delta_versions(
+            &identifier,
+            &since,
+            request.restore_dtypes,
+            &request.delta_keys,
+            &recipient,
+            purpose.as_ref(),
+        )?;
+        if let Err(fallback) = &versions {
+            info!("Sending {identifier} in full instead of a delta since {since}: {fallback}");
+        }
+        let delta = DeltaFetch {
+            identifier,
+            keys: request.delta_keys,
+            versions,
+        };
+        Ok(serialize_delayed_delta(df, format, delta, guard, faults).await)
+    }
+
+    async fn fetch_scalar(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<ScalarValue>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let correlation_id = federation::correlation_id(request.metadata())?;
+
+        let request = request.into_inner();
+        let recipient = self.sess_manager.get_user_id(token.clone())?;
+        let (identifier, redirect) = self.resolve(&request.identifier)?;
+        self.check_resolvable(std::slice::from_ref(&identifier), &recipient)?;
+        // Checked first, so that no approval is asked for a result that is not a scalar.
+        let (rows, cols) =
+            self.with_df_artifact_ref(&identifier, |artifact| artifact.dataframe.shape())?;
+        check_scalar(rows, cols)?;
+        let purpose = Purpose::from_proto(request.purpose.clone())?;
+        let df = self.get_df(
+            &identifier,
+            true,
+            &recipient,
+            purpose.as_ref(),
+            Some(self.sess_manager.get_client_info(token)?),
+        );
+        self.record_fetch_outcome(AccessKind::Fetch, &recipient, &identifier, &df);
+        let df = df?;
+        self.record_fetch(
+            AccessKind::Fetch,
+            &recipient,
+            &identifier,
+            purpose,
+            correlation_id,
+        )?;
+        let status = match redirect {
+            Some(redirect) => df.fetch_status.with_notice(redirect),
+            None => df.fetch_status,
+        };
+        let df = df.future.await?.into_dataframe();
+        let value = Scalar::from_dataframe(&df)?;
+        let warning = match status {
+            FetchStatus::Warning(reason) => reason,
+            _ => String::new(),
+        };
+        Ok(Response::new(
+            value.into_proto(df.get_columns()[0].dtype(), warning),
+        ))
+    }
+
+    async fn create_reproducibility_bundle(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<ReproducibilityBundle>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let (identifier, _) = self.resolve(&request.get_ref().identifier)?;
+        let bundle = self.reproducibility_bundle(&identifier, &user_id)?;
+        info!(
+            "Created the reproducibility bundle of {identifier} ({} inputs) for {user_id}",
+            bundle.inputs.len()
+        );
+        Ok(Response::new(ReproducibilityBundle {
+            bundle: self.bundle_signer.seal(&bundle)?,
+        }))
+    }
+
+    async fn verify_reproducibility_bundle(
+        &self,
+        request: Request<ReproducibilityBundle>,
+    ) -> Result<Response<ReproducibilityReport>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        self.memory.check("queries", Pressure::Hard)?;
+
+        // Only signed bundles are run: their result hash was computed by this server.
+        let bundle = open_bundle(&request.get_ref().bundle, &self.bundle_signer.public_key())?;
+        self.check_bundle_access(&bundle.owner, &user_id)?;
+        let mut mismatches = self.bundle_input_mismatches(&bundle);
+        let inputs_match = mismatches.is_empty();
+        let recomputed_hash = match self.rerun_bundle(&bundle, &user_id).await {
+            Ok(hash) => hash,
+            Err(e) => {
+                mismatches.push(format!("The plan could not run again: {}", e.message()));
+                String::new()
+            }
+        };
+        let result_matches = recomputed_hash == bundle.result_hash;
+        if !result_matches && !recomputed_hash.is_empty() {
+            mismatches.push(format!(
+                "The result has content hash {recomputed_hash}, the bundle has {}",
+                bundle.result_hash
+            ));
+        }
+        info!(
+            "Verified the reproducibility bundle of {} for {user_id}: {}",
+            bundle.result,
+            if result_matches {
+                "the result matches"
+            } else {
+                "the result differs"
+            }
+        );
+        Ok(Response::new(ReproducibilityReport {
+            inputs_match,
+            result_matches,
+            expected_hash: bundle.result_hash,
+            recomputed_hash,
+            mismatches,
+        }))
+    }
+
+    async fn get_usage_report(
+        &self,
+        request: Request<UsageReportRequest>,
+    ) -> Result<Response<UsageReport>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.into_inner();
+        let identifier = match request.identifier.as_str() {
+            "" => None,
+            identifier => Some(self.resolve(identifier)?.0),
+        };
+        let usage = self.usage_report(identifier.as_deref(), request.since, &user_id)?;
+        let waivers = self
+            .waivers
+            .used(&user_id, identifier.as_deref(), request.since);
+        Ok(Response::new(UsageReport {
+            purposes: usage
+                .into_iter()
+                .map(|(code, usage)| polars_proto::PurposeUsage {
+                    code,
+                    queries: usage.queries,
+                    fetches: usage.fetches,
+                    users: usage.users.into_iter().collect(),
+                    transfers: usage.transfers,
+                })
+                .collect(),
+            waivers: waivers.iter().map(Waiver::to_proto).collect(),
+        }))
+    }
+
+    async fn create_export_waiver(
+        &self,
+        request: Request<ExportWaiverRequest>,
+    ) -> Result<Response<ExportWaiver>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.into_inner();
+        let waiver = self.create_export_waiver(
+            &request.identifier,
+            &request.recipient,
+            request.expires_at,
+            &request.justification,
+            &user_id,
+        )?;
+        Ok(Response::new(waiver.to_proto()))
+    }
+
+    async fn get_recent_activity(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<polars_proto::RecentActivity>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let (identifier, _) = self.resolve(&request.get_ref().identifier)?;
+        let entries = self.recent_activity(&identifier, &user_id)?;
+        Ok(Response::new(polars_proto::RecentActivity {
+            entries: entries.iter().map(ActivityEntry::to_proto).collect(),
+        }))
+    }
+
+    async fn create_workspace(
+        &self,
+        request: Request<WorkspaceRequest>,
+    ) -> Result<Response<WorkspaceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let name = &request.get_ref().name;
+        let info = self.workspaces.create(name, &user_id, catalog::now_ms())?;
+        info!("Created workspace {name} for {user_id}");
+        Ok(Response::new(workspace_response(info)))
+    }
+
+    async fn get_workspace(
+        &self,
+        request: Request<WorkspaceRequest>,
+    ) -> Result<Response<WorkspaceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let info = self.workspaces.get(&request.get_ref().name, &user_id)?;
+        Ok(Response::new(workspace_response(info)))
+    }
+
+    async fn list_workspaces(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<WorkspaceList>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        Ok(Response::new(WorkspaceList {
+            workspaces: self
+                .workspaces
+                .list(&user_id)
+                .into_iter()
+                .map(workspace_response)
+                .collect(),
+        }))
+    }
+
+    async fn delete_workspace(
+        &self,
+        request: Request<DeleteWorkspaceRequest>,
+    ) -> Result<Response<BulkResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let DeleteWorkspaceRequest { name, cascade } = request.into_inner();
+        let outcome = self.delete_workspace(&name, &user_id, cascade)?;
+        info!("Deleted workspace {name} for {user_id}");
+        Ok(Response::new(bulk_response(outcome)))
+    }
+
+    async fn attach_to_workspace(
+        &self,
+        request: Request<WorkspaceMembersRequest>,
+    ) -> Result<Response<WorkspaceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.get_ref();
+        let info = self.attach_to_workspace(&request.name, &user_id, &request.identifiers)?;
+        Ok(Response::new(workspace_response(info)))
+    }
+
+    async fn detach_from_workspace(
+        &self,
+        request: Request<WorkspaceMembersRequest>,
+    ) -> Result<Response<WorkspaceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.get_ref();
+        let identifiers = request
+            .identifiers
+            .iter()
+            .map(|identifier| Ok(self.resolve(identifier)?.0))
+            .collect::<Result<Vec<_>, Status>>()?;
+        let info = self
+            .workspaces
+            .detach(&request.name, &user_id, &identifiers)?;
+        Ok(Response::new(workspace_response(info)))
+    }
+
+    async fn share_workspace(
+        &self,
+        request: Request<ShareWorkspaceRequest>,
+    ) -> Result<Response<WorkspaceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.get_ref();
+        let info = self
+            .workspaces
+            .share(&request.name, &user_id, &request.users)?;
+        info!(
+            "Shared workspace {} of {user_id} with {} users",
+            request.name,
+            info.shared_with.len()
+        );
+        Ok(Response::new(workspace_response(info)))
+    }
+
+    async fn delete_workspace_members(
+        &self,
+        request: Request<WorkspaceRequest>,
+    ) -> Result<Response<BulkResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let outcome = self.delete_workspace_members(&request.get_ref().name, &user_id)?;
+        Ok(Response::new(bulk_response(outcome)))
+    }
+
+    async fn extend_workspace_retention(
+        &self,
+        request: Request<RetentionRequest>,
+    ) -> Result<Response<BulkResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.get_ref();
+        let outcome = self.extend_workspace_retention(
+            &request.name,
+            &user_id,
+            Duration::from_secs(request.seconds),
+        )?;
+        Ok(Response::new(bulk_response(outcome)))
+    }
+
+    async fn export_workspace_manifest(
+        &self,
+        request: Request<WorkspaceRequest>,
+    ) -> Result<Response<WorkspaceManifest>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let (manifest, outcome) = self.workspace_manifest(&request.get_ref().name, &user_id)?;
+        Ok(Response::new(WorkspaceManifest {
+            manifest: serde_json::to_string(&manifest)
+                .map_err(|e| Status::internal(format!("Could not serialize the manifest: {e}")))?,
+            failures: bulk_failures(outcome.failures),
+        }))
+    }
+
+    async fn set_storage_class(
+        &self,
+        request: Request<StorageClassRequest>,
+    ) -> Result<Response<StorageClassJob>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let request = request.into_inner();
+        let class = StorageClass::parse(&request.storage_class)?;
+        let (identifier, _) = self.resolve(&request.identifier)?;
+        let job = self.set_storage_class(&identifier, class, &user_id)?;
+        info!(
+            "{user_id} set the storage class of {identifier} to {}",
+            request.storage_class
+        );
+        Ok(Response::new(class_job_response(
+            job,
+            self.storage_class_job(job, &user_id)?,
+        )))
+    }
+
+    async fn roll_out_policy(
+        &self,
+        request: Request<PolicyRolloutRequest>,
+    ) -> Result<Response<PolicyRolloutReport>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let PolicyRolloutRequest {
+            selector,
+            patch,
+            dry_run,
+        } = request.into_inner();
+        let selector = selector.unwrap_or_default();
+        let selector = PolicySelector {
+            tags: selector.tags,
+            owner: Some(selector.owner).filter(|owner| !owner.is_empty()),
+            name_pattern: Some(selector.name_pattern).filter(|pattern| !pattern.is_empty()),
+        };
+        let patch: PolicyPatch = serde_json::from_str(&patch)
+            .map_err(|e| Status::invalid_argument(format!("Invalid policy patch: {e}")))?;
+        let (id, impacts) = self.roll_out_policy(&selector, &patch, dry_run, &user_id)?;
+        Ok(Response::new(rollout_report(
+            id.unwrap_or_default(),
+            impacts,
+            false,
+        )))
+    }
+
+    async fn roll_back_policy_rollout(
+        &self,
+        request: Request<RolloutRequest>,
+    ) -> Result<Response<PolicyRolloutReport>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let rollout = self.roll_back_policy_rollout(&request.get_ref().rollout_id, &user_id)?;
+        Ok(Response::new(rollout_report(
+            rollout.id,
+            rollout.impacts,
+            rollout.rolled_back,
+        )))
+    }
+
+    async fn get_policy_history(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<PolicyHistory>, Status> {
+        self.sess_manager.get_token(&request)?;
+        let (identifier, _) = self.resolve(&request.get_ref().identifier)?;
+        let changes = self
+            .policy_history(&identifier)?
+            .into_iter()
+            .map(|change| {
+                Ok(polars_proto::PolicyChange {
+                    version: change.version,
+                    rollout_id: change.rollout_id,
+                    changed_at: change.changed_at,
+                    author: change.author,
+                    rollback: change.rollback,
+                    policy: serde_json::to_string(&change.policy).map_err(|e| {
+                        Status::internal(format!("Could not serialize policy: {e}"))
+                    })?,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+        Ok(Response::new(PolicyHistory { changes }))
+    }
+
+    async fn get_storage_class_job(
+        &self,
+        request: Request<StorageClassJobRequest>,
+    ) -> Result<Response<StorageClassJob>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let job = request.get_ref().job;
+        Ok(Response::new(class_job_response(
+            job,
+            self.storage_class_job(job, &user_id)?,
+        )))
+    }
+
+    async fn submit_plan_job(
+        &self,
+        request: Request<PlanJobRequest>,
+    ) -> Result<Response<polars_proto::PlanJob>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+        let client_info = self.sess_manager.get_client_info(token)?;
+        let delay = faults::segment_delay(request.metadata(), self.fault_injection)?;
+        let request = request.into_inner();
+        let query = request
+            .query
+            .ok_or_else(|| Status::invalid_argument("The job has no query"))?;
+        let job = self.submit_plan_job(
+            query,
+            request.checkpoint,
+            &user_id,
+            Some(client_info),
+            delay,
+        )?;
+        Ok(Response::new(job.to_proto(0)?))
+    }
+
+    async fn get_plan_job(
+        &self,
+        request: Request<PlanJobQuery>,
+    ) -> Result<Response<polars_proto::PlanJob>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let (job, completed) = self.plan_job(&request.get_ref().job, &user_id)?;
+        Ok(Response::new(job.to_proto(completed)?))
+    }
+
+    async fn list_data_frames(
+        &self,
+        request: Request<ListDataFramesRequest>,
+    ) -> Result<Response<ReferenceList>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+
+        let request = request.into_inner();
+        let after = match request.page_token.as_str() {
+            "" => None,
+            token => Some(Cursor::decode(token)?),
+        };
+        let mut filter = listing_filter(&request);
+        if !request.workspace.is_empty() {
+            let workspace = self.workspaces.get(&request.workspace, &user_id)?;
+            filter.within = Some(workspace.members.into_iter().collect());
+        }
+        let (headers, page) = self.list_dfs(
+            &user_id,
+            &filter,
+            after.as_ref(),
+            request.page_size as usize,
+        )?;
+        let list = headers
+            .into_iter()
+            .map(|(identifier, header)| {
+                Ok(ReferenceResponse {
+                    fingerprint: self.listed_fingerprint(&identifier)?,
+                    identifier,
+                    header,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+        telemetry::add_event(
+            TelemetryEventProps::ListDataFrame {},
+            Some(self.sess_manager.get_client_info(token)?),
+        );
+        Ok(Response::new(ReferenceList {
+            list,
+            next_page_token: page.next.map(|next| next.encode()).unwrap_or_default(),
+            total_count_estimate: page.total,
+        }))
+    }
+
+    async fn get_data_frame_header(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<ReferenceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+
+        let (identifier, redirect) = self.resolve(&request.get_ref().identifier)?;
+        logging::record_identifier(&identifier);
+        self.check_resolvable(std::slice::from_ref(&identifier), &user_id)?;
+        let header = self.get_header(&identifier)?;
+        let (shape, owner, input_versions, exposures, upload) =
+            self.with_df_artifact_ref(&identifier, |artifact| {
+                (
+                    artifact.shape(),
+                    artifact.catalog.owner == user_id,
+                    input_versions(artifact.provenance.as_ref()),
+                    artifact.policy.metadata_exposure().clone(),
+                    artifact.kind() == DataFrameKind::Upload,
+                )
+            })?;
+        // Headers are how data owners refresh the percentiles masks read.
+        let tolerance = match owner {
+            true => Tolerance::Exact,
+            false => Tolerance::Stale,
+        };
+        self.prepare_masks(&identifier, tolerance)?;
+        let mut masked = self.masked_statistics(&identifier)?;
+        let statistics = self
+            .column_statistics(&identifier, &[], Tolerance::Stale)?
+            .into_iter()
+            .map(|(column, statistics, stale)| match masked.remove(&column) {
+                Some(statistics) => (column, statistics, false),
+                None => (column, statistics, stale),
+            })
+            .collect();
+        // Results take the exposures of the policies they inherit, whoever computed them.
+        let statistics = exposure::expose(statistics, &exposures, owner && upload);
+        let (fingerprint, input_fingerprints) = self.fingerprint(&identifier)?;
+        telemetry::add_event(
+            TelemetryEventProps::GetDataFrameHeader {
+                dataset_name: Some(identifier.clone()),
+            },
+            Some(self.sess_manager.get_client_info(token)?),
+        );
+        Ok(Response::new(ReferenceResponse {
+            identifier,
+            header,
+            redirect: redirect.unwrap_or_default(),
+            shape: Some(shape),
+            statistics,
+            input_versions,
+            fingerprint,
+            input_fingerprints,
+            ..Default::default()
+        }))
+    }
+
+    async fn persist_data_frame(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+
+        let identifier = &request.get_ref().identifier;
+        self.persist_df(identifier)?;
+        telemetry::add_event(
+            TelemetryEventProps::SaveDataframe {
+                dataset_name: Some(identifier.clone()),
+            },
+            Some(self.sess_manager.get_client_info(token)?),
+        );
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn delete_data_frame(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+
+        let identifier = &request.get_ref().identifier;
+        let user_id = self.sess_manager.get_user_id(token.clone())?;
+        self.delete_df_for(identifier, &user_id)?;
+        telemetry::add_event(
+            TelemetryEventProps::DeleteDataframe {
+                dataset_name: Some(identifier.clone()),
+            },
+            Some(self.sess_manager.get_client_info(token)?),
+        );
+        Ok(Response::new(Empty {}))
+    }
+    async fn optimize_storage(
+        &self,
+        request: Request<OptimizeStorageRequest>,
+    ) -> Result<Response<OptimizeStorageResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can optimize the storage of dataframes.",
+            ));
+        }
+
+        let report = self.optimize_df_storage(
+            &request.get_ref().identifier,
+            request.get_ref().allow_lossy_floats,
+        )?;
+        info!(
+            "Storage optimization of {} saved {} bytes",
+            request.get_ref().identifier,
+            report.bytes_before.saturating_sub(report.bytes_after)
+        );
+
+        Ok(Response::new(OptimizeStorageResponse {
+            bytes_before: report.bytes_before as u64,
+            bytes_after: report.bytes_after as u64,
+            changed_columns: report.changes.into_iter().map(|c| c.column).collect(),
+        }))
+    }
+
+    async fn append_data_frame(
+        &self,
+        request: Request<Streaming<SendChunk>>,
+    ) -> Result<Response<ReferenceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can append rows to dataframes.",
+            ));
+        }
+
+        let faults = self.stream_faults(&request)?;
+        let mut upload = read_upload(request.into_inner(), faults, self.max_upload_bytes).await?;
+        check_column_names(&mut upload.dataframe, self.blank_column_names)?;
+        let identifier = upload.append_to;
+        logging::record_identifier(&identifier);
+        let rows = upload.dataframe.height();
+        let header = self.append_df(&identifier, upload.dataframe)?;
+        self.persist_if_stored(&identifier)?;
+        info!("Succesfully appended {} rows to {}", rows, identifier);
+
+        let (fingerprint, _) = self.fingerprint(&identifier)?;
+        Ok(Response::new(ReferenceResponse {
+            identifier,
+            header,
+            fingerprint,
+            ..Default::default()
+        }))
+    }
+
+    async fn upsert_rows(
+        &self,
+        request: Request<Streaming<SendChunk>>,
+    ) -> Result<Response<UpsertResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can upsert rows into dataframes.",
+            ));
+        }
+
+        let faults = self.stream_faults(&request)?;
+        let mut upload = read_upload(request.into_inner(), faults, self.max_upload_bytes).await?;
+        check_column_names(&mut upload.dataframe, self.blank_column_names)?;
+        let identifier = upload.append_to;
+        let (report, version, header) =
+            self.upsert_df(&identifier, upload.dataframe, &upload.key_columns)?;
+        self.persist_if_stored(&identifier)?;
+        info!(
+            "Succesfully upserted rows into {} (version {}): {} inserted, {} updated, {} unchanged",
+            identifier, version, report.inserted, report.updated, report.unchanged
+        );
+
+        Ok(Response::new(UpsertResponse {
+            identifier,
+            header,
+            inserted: report.inserted as u64,
+            updated: report.updated as u64,
+            unchanged: report.unchanged as u64,
+            version,
+        }))
+    }
+
+    async fn set_quality_constraints(
+        &self,
+        request: Request<QualityConstraintsRequest>,
+    ) -> Result<Response<QualityStatus>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can set data-quality constraints.",
+            ));
+        }
+
+        let constraints = serde_json::from_str(&request.get_ref().constraints).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse the quality constraints: {e}"))
+        })?;
+        let status = self.set_df_quality_constraints(&request.get_ref().identifier, constraints)?;
+        Ok(Response::new(QualityStatus { status }))
+    }
+
+    async fn get_quality_status(
+        &self,
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<QualityStatus>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can read the data-quality status.",
+            ));
+        }
+
+        let status = self.with_df_artifact_ref(&request.get_ref().identifier, quality_status)??;
+        Ok(Response::new(QualityStatus { status }))
+    }
+
+    async fn trace_watermark(
+        &self,
+        request: Request<Streaming<SendChunk>>,
+    ) -> Result<Response<WatermarkTrace>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can trace watermarks.",
+            ));
+        }
+
+        let faults = self.stream_faults(&request)?;
+        let leaked = read_upload(request.into_inner(), faults, self.max_upload_bytes)
+            .await?
+            .dataframe;
+        let matches = self
+            .watermarker
+            .trace(&leaked)?
+            .into_iter()
+            .map(|s| WatermarkMatch {
+                fetch_id: s.record.fetch_id,
+                recipient: s.record.recipient,
+                identifier: s.record.dataset,
+                fetch_time: s.record.time,
+                score: s.score,
+                values: s.values as u64,
+            })
+            .collect();
+        Ok(Response::new(WatermarkTrace { matches }))
+    }
 
-        let mut res = composite_plan.run(self, &user_id)?;
-        // TODO: this isn't really great.. this does a full serialization under the hood
-        let hash = hash_dataset(&mut res.dataframe)
-            .map_err(|e| Status::internal(format!("Polars error: {e}")))?;
+    async fn recompress(
+        &self,
+        request: Request<RecompressRequest>,
+    ) -> Result<Response<RecompressResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can recompress persisted dataframes.",
+            ));
+        }
 
-        let header = get_df_header(&res.dataframe)?;
-        let identifier = self.insert_df(res);
+        let settings = PersistenceSettings {
+            zstd_level: request
+                .get_ref()
+                .zstd_level
+                .unwrap_or(self.persistence.zstd_level),
+            dictionary_ratio: request
+                .get_ref()
+                .dictionary_ratio
+                .unwrap_or(self.persistence.dictionary_ratio),
+        };
+        let dir = self.data_dir.clone();
+        let keys = self.tenant_keys.clone();
+        let embedded = self.embedded.clone();
+        let report = tokio::task::spawn_blocking(move || match embedded {
+            Some(store) => store.recompress(&settings, &keys),
+            None => recompress_all(&dir, &settings, &keys),
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Recompression failed: {e}")))??;
+        info!(
+            "Recompressed {} persisted dataframes: {} bytes before, {} bytes after",
+            report.rewritten.len(),
+            report.bytes_before,
+            report.bytes_after
+        );
 
-        let elapsed = start_time.elapsed();
+        Ok(Response::new(RecompressResponse {
+            bytes_before: report.bytes_before,
+            bytes_after: report.bytes_after,
+            identifiers: report.rewritten,
+        }))
+    }
 
-        telemetry::add_event(
-            TelemetryEventProps::RunQuery {
-                dataset_name: Some(identifier.clone()),
-                dataset_hash: Some(hash),
-                time_taken: elapsed.as_millis() as f64,
-            },
-            Some(self.sess_manager.get_client_info(token)?),
+    async fn migrate_semantics(
+        &self,
+        request: Request<SemanticsMigrationRequest>,
+    ) -> Result<Response<SemanticsMigrationResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can migrate the semantics of dataframes.",
+            ));
+        }
+
+        let SemanticsMigrationRequest {
+            identifiers,
+            confirm,
+        } = request.into_inner();
+        let migrations = self.retag_semantics(&identifiers, confirm)?;
+        if confirm {
+            info!(
+                "Succesfully migrated {} dataframes to semantics version {}",
+                migrations.len(),
+                CURRENT_SEMANTICS
+            );
+        }
+
+        Ok(Response::new(SemanticsMigrationResponse {
+            migrations,
+            applied: confirm,
+        }))
+    }
+
+    async fn register_family(
+        &self,
+        request: Request<RegisterFamilyRequest>,
+    ) -> Result<Response<FamilyResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can register dataset families.",
+            ));
+        }
+
+        let RegisterFamilyRequest {
+            name,
+            partition_key,
+            members,
+        } = request.into_inner();
+        let key = serde_json::from_str(&partition_key).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse the partition key: {e}"))
+        })?;
+        let info = self.register_family(&name, key, parse_family_members(members)?)?;
+        info!(
+            "Succesfully registered family {} with {} members",
+            name,
+            info.members.len()
         );
+        Ok(Response::new(family_response(info)?))
+    }
+
+    async fn add_family_members(
+        &self,
+        request: Request<FamilyMembersRequest>,
+    ) -> Result<Response<FamilyResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can modify dataset families.",
+            ));
+        }
+
+        let FamilyMembersRequest { name, members } = request.into_inner();
+        let added = members.len();
+        let info = self.add_family_members(&name, parse_family_members(members)?)?;
+        info!("Succesfully added {} members to family {}", added, name);
+        Ok(Response::new(family_response(info)?))
+    }
 
-        info!("Succesfully ran query on {}", identifier.clone());
+    async fn remove_family_members(
+        &self,
+        request: Request<RemoveFamilyMembersRequest>,
+    ) -> Result<Response<FamilyResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can modify dataset families.",
+            ));
+        }
 
-        Ok(Response::new(ReferenceResponse { identifier, header }))
+        let RemoveFamilyMembersRequest { name, identifiers } = request.into_inner();
+        let info = self.remove_family_members(&name, &identifiers)?;
+        info!(
+            "Succesfully removed {} members from family {}",
+            identifiers.len(),
+            name
+        );
+        Ok(Response::new(family_response(info)?))
     }
 
-    async fn send_data_frame(
+    async fn get_family(
         &self,
-        request: Request<Streaming<SendChunk>>,
-    ) -> Result<Response<ReferenceResponse>, Status> {
-        let start_time = Instant::now();
+        request: Request<FamilyRequest>,
+    ) -> Result<Response<FamilyResponse>, Status> {
+        self.sess_manager.get_token(&request)?;
+
+        let info = self.family(&request.get_ref().name)?;
+        Ok(Response::new(family_response(info)?))
+    }
 
+    async fn register_view(
+        &self,
+        request: Request<RegisterViewRequest>,
+    ) -> Result<Response<ViewResponse>, Status> {
         let token = self.sess_manager.get_token(&request)?;
-        let client_info = self.sess_manager.get_client_info(token)?;
-        let (df, hash) = unserialize_dataframe(request.into_inner()).await?;
-        let header = get_df_header(&df.dataframe)?;
-        let identifier = self.insert_df(df);
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can register materialized views.",
+            ));
+        }
 
-        let elapsed = start_time.elapsed();
-        telemetry::add_event(
-            TelemetryEventProps::SendDataFrame {
-                dataset_name: Some(identifier.clone()),
-                dataset_hash: Some(hash),
-                time_taken: elapsed.as_millis() as f64,
-            },
-            Some(client_info),
+        let RegisterViewRequest {
+            name,
+            composite_plan,
+            max_staleness_secs,
+        } = request.into_inner();
+        let deserialize_err = |e: serde_json::Error| {
+            Status::invalid_argument(format!("Could not deserialize composite plan: {e}"))
+        };
+        let plan = serde_json::from_str(&composite_plan).map_err(deserialize_err)?;
+        plan_format::check(&plan, self.plan_compatibility_mode)?;
+        capabilities::check_plan(&plan)?;
+        let mut plan: CompositePlan = serde_json::from_value(plan).map_err(deserialize_err)?;
+        plan.resolve_entry_points(|identifier| Ok(self.resolve(identifier)?.0))?;
+        let info = self.register_view(&name, &plan, Duration::from_secs(max_staleness_secs))?;
+        info!(
+            "Succesfully registered view {} over {} with {} groups",
+            name, info.base, info.groups
         );
+        Ok(Response::new(view_response(info)))
+    }
+
+    async fn refresh_view(
+        &self,
+        request: Request<ViewRequest>,
+    ) -> Result<Response<ViewResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can refresh materialized views.",
+            ));
+        }
 
+        let info = self.refresh_view(&request.get_ref().name)?;
         info!(
-            "Succesfully sent dataframe {} to server",
-            identifier.clone()
+            "Succesfully refreshed view {} at version {} of {}",
+            info.name, info.base_version, info.base
         );
+        Ok(Response::new(view_response(info)))
+    }
+
+    async fn get_view(
+        &self,
+        request: Request<ViewRequest>,
+    ) -> Result<Response<ViewResponse>, Status> {
+        self.sess_manager.get_token(&request)?;
 
-        Ok(Response::new(ReferenceResponse { identifier, header }))
+        let info = self.view(&request.get_ref().name)?;
+        Ok(Response::new(view_response(info)))
     }
 
-    async fn fetch_data_frame(
+    async fn update_draft(
+        &self,
+        request: Request<UpdateDraftRequest>,
+    ) -> Result<Response<LifecycleResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let UpdateDraftRequest {
+            identifier,
+            policy,
+            sanitized_columns,
+            name,
+            tags,
+        } = request.into_inner();
+        let policy = policy
+            .map(|policy| serde_json::from_str(&policy))
+            .transpose()
+            .map_err(|e| Status::invalid_argument(format!("Could not parse the policy: {e}")))?;
+        self.update_draft(
+            &identifier,
+            &user_id,
+            policy,
+            sanitized_columns.map(|list| list.values),
+            name,
+            tags.map(|list| list.values),
+        )?;
+        Ok(Response::new(self.lifecycle_response(
+            &identifier,
+            &user_id,
+            Vec::new(),
+        )?))
+    }
+
+    async fn submit_for_review(
         &self,
         request: Request<ReferenceRequest>,
-    ) -> Result<Response<Self::FetchDataFrameStream>, Status> {
+    ) -> Result<Response<LifecycleResponse>, Status> {
         let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
 
-        let fut = {
-            let df = self.get_df(
-                &request.get_ref().identifier,
-                Some(self.sess_manager.get_client_info(token)?),
-            )?;
-            serialize_delayed_dataframe(df)
+        let identifier = &request.get_ref().identifier;
+        self.transition_df(identifier, &user_id, Transition::Submit, None)?;
+        Ok(Response::new(self.lifecycle_response(
+            identifier,
+            &user_id,
+            Vec::new(),
+        )?))
+    }
+
+    async fn review_data_frame(
+        &self,
+        request: Request<ReviewRequest>,
+    ) -> Result<Response<LifecycleResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let ReviewRequest {
+            identifier,
+            approve,
+            reason,
+        } = request.into_inner();
+        let (transition, reason) = if approve {
+            (Transition::Approve, None)
+        } else {
+            (Transition::Reject, Some(reason).filter(|r| !r.is_empty()))
         };
-        Ok(fut.await)
+        self.transition_df(&identifier, &user_id, transition, reason)?;
+        Ok(Response::new(self.lifecycle_response(
+            &identifier,
+            &user_id,
+            Vec::new(),
+        )?))
     }
 
-    async fn list_data_frames(
+    async fn unpublish_data_frame(
         &self,
-        request: Request<Empty>,
-    ) -> Result<Response<ReferenceList>, Status> {
+        request: Request<ReferenceRequest>,
+    ) -> Result<Response<LifecycleResponse>, Status> {
         let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
 
-        let list = self
-            .get_headers()?
-            .into_iter()
-            .map(|(identifier, header)| ReferenceResponse { identifier, header })
-            .collect();
-        telemetry::add_event(
-            TelemetryEventProps::ListDataFrame {},
-            Some(self.sess_manager.get_client_info(token)?),
-        );
-        Ok(Response::new(ReferenceList { list }))
+        let identifier = &request.get_ref().identifier;
+        let dropped = self.transition_df(identifier, &user_id, Transition::Unpublish, None)?;
+        Ok(Response::new(
+            self.lifecycle_response(identifier, &user_id, dropped)?,
+        ))
     }
 
-    async fn get_data_frame_header(
+    async fn get_lifecycle(
         &self,
         request: Request<ReferenceRequest>,
+    ) -> Result<Response<LifecycleResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        Ok(Response::new(self.lifecycle_response(
+            &request.get_ref().identifier,
+            &user_id,
+            Vec::new(),
+        )?))
+    }
+
+    async fn deduplicate_data_frame(
+        &self,
+        request: Request<DeduplicateRequest>,
+    ) -> Result<Response<AliasResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can deduplicate dataframes.",
+            ));
+        }
+
+        let DeduplicateRequest {
+            identifier,
+            canonical,
+        } = request.into_inner();
+        let entry = self.deduplicate_df(&identifier, &canonical)?;
+        info!(
+            "Succesfully deduplicated dataframe {} into {}",
+            identifier, entry.canonical
+        );
+        Ok(Response::new(alias_response(identifier, entry)))
+    }
+
+    async fn create_alias(
+        &self,
+        request: Request<AliasRequest>,
+    ) -> Result<Response<AliasResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can alias dataframes.",
+            ));
+        }
+
+        let AliasRequest {
+            alias,
+            canonical,
+            reason,
+        } = request.into_inner();
+        let entry = self.alias_df(&alias, &canonical, &reason)?;
+        Ok(Response::new(alias_response(alias, entry)))
+    }
+
+    async fn register_remote_data_frame(
+        &self,
+        request: Request<RemoteDataFrameRequest>,
     ) -> Result<Response<ReferenceResponse>, Status> {
         let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can register federated dataframes.",
+            ));
+        }
 
-        let identifier = String::from(&request.get_ref().identifier);
-        let header = self.get_header(&identifier)?;
-        telemetry::add_event(
-            TelemetryEventProps::GetDataFrameHeader {
-                dataset_name: Some(identifier.clone()),
-            },
+        let mut artifact = federation::remote_artifact(request.into_inner())?;
+        let header = artifact.header()?;
+        if !self.publish_uploads {
+            artifact.onboarding = Onboarding::draft();
+        }
+        let identifier = self.insert_df(artifact.with_owner(&user_id));
+        info!("Registered federated dataframe {identifier}");
+        Ok(Response::new(ReferenceResponse {
+            identifier,
+            header,
+            ..Default::default()
+        }))
+    }
+
+    async fn transfer_data_frame(
+        &self,
+        request: Request<TransferRequest>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let correlation_id = federation::correlation_id(request.metadata())?
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let request = request.into_inner();
+        let recipient = self.sess_manager.get_user_id(token.clone())?;
+        let policy: Policy = serde_json::from_str(&request.policy).map_err(|e| {
+            Status::invalid_argument(format!("Error during the parsing of the policy: {e}"))
+        })?;
+        let (identifier, _) = self.resolve(&request.identifier)?;
+        self.check_resolvable(std::slice::from_ref(&identifier), &recipient)?;
+        let mut guard = self.fetch_guard(&identifier, &recipient)?;
+        let purpose = Purpose::from_proto(request.purpose)?;
+        let df = self.get_df(
+            &identifier,
+            true,
+            &recipient,
+            purpose.as_ref(),
             Some(self.sess_manager.get_client_info(token)?),
         );
-        Ok(Response::new(ReferenceResponse { identifier, header }))
+        self.record_fetch_outcome(AccessKind::Transfer, &recipient, &identifier, &df);
+        // Waits for the approval of the data owner if needed.
+        let df = df?.future.await?.into_dataframe();
+        // Access may have been narrowed while waiting.
+        guard.checkpoint(0)?;
+
+        let reference = self
+            .federation
+            .transfer(
+                &identifier,
+                &request.address,
+                &request.credential,
+                &df,
+                &policy,
+                &correlation_id,
+            )
+            .await?;
+        self.record_fetch(
+            AccessKind::Transfer,
+            &recipient,
+            &identifier,
+            purpose,
+            Some(correlation_id.clone()),
+        )?;
+        info!(
+            "Transferred {identifier} to {} as {} (correlation id {correlation_id})",
+            request.address, reference.identifier
+        );
+        let receipt = TransferReceipt {
+            correlation_id,
+            identifier,
+            address: request.address,
+            destination_identifier: reference.identifier.clone(),
+            rows: df.height() as u64,
+            transferred_at: catalog::now_ms(),
+        };
+        Ok(Response::new(TransferResponse {
+            reference: Some(reference),
+            receipt: Some(receipt),
+        }))
     }
 
-    async fn persist_data_frame(
+    async fn get_server_capabilities(
         &self,
-        request: Request<ReferenceRequest>,
-    ) -> Result<Response<Empty>, Status> {
+        request: Request<Empty>,
+    ) -> Result<Response<ServerCapabilities>, Status> {
+        self.sess_manager.get_token(&request)?;
+        Ok(Response::new(self.server_capabilities()))
+    }
+
+    async fn register_pipeline(
+        &self,
+        request: Request<RegisterPipelineRequest>,
+    ) -> Result<Response<PipelineResponse>, Status> {
         let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
 
-        let identifier = &request.get_ref().identifier;
-        self.persist_df(identifier)?;
-        telemetry::add_event(
-            TelemetryEventProps::SaveDataframe {
-                dataset_name: Some(identifier.clone()),
-            },
-            Some(self.sess_manager.get_client_info(token)?),
+        let RegisterPipelineRequest {
+            name,
+            plan,
+            parameters,
+            visibility,
+        } = request.into_inner();
+        let plan = serde_json::from_str(&plan).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse the pipeline plan: {e}"))
+        })?;
+        self.check_published(&pipelines::fixed_entry_points(&plan), "pipelines")?;
+        let parameters = match parameters.as_str() {
+            "" => Vec::new(),
+            parameters => serde_json::from_str(parameters).map_err(|e| {
+                Status::invalid_argument(format!("Could not parse the pipeline parameters: {e}"))
+            })?,
+        };
+        let visibility = match visibility.as_str() {
+            "" => Default::default(),
+            visibility => serde_json::from_str(visibility).map_err(|e| {
+                Status::invalid_argument(format!("Could not parse the pipeline visibility: {e}"))
+            })?,
+        };
+        let pipeline = self
+            .pipelines
+            .register(&name, &user_id, visibility, parameters, plan)?;
+        info!(
+            "Succesfully registered pipeline {} version {}",
+            pipeline.name, pipeline.version
         );
-        Ok(Response::new(Empty {}))
+        Ok(Response::new(pipeline_response(pipeline)?))
     }
 
-    async fn delete_data_frame(
+    async fn list_pipelines(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<PipelineList>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let list = self
+            .pipelines
+            .list(&user_id)
+            .into_iter()
+            .map(pipeline_response)
+            .collect::<Result<_, _>>()?;
+        Ok(Response::new(PipelineList { list }))
+    }
+
+    async fn get_pipeline(
+        &self,
+        request: Request<PipelineRequest>,
+    ) -> Result<Response<PipelineResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let PipelineRequest { name, version } = request.get_ref();
+        let version = (*version != 0).then_some(*version);
+        let pipeline = self.pipelines.get(name, version, &user_id)?;
+        Ok(Response::new(pipeline_response(pipeline)?))
+    }
+
+    async fn set_default_policy(
+        &self,
+        request: Request<DefaultPolicyRequest>,
+    ) -> Result<Response<DefaultPolicyResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let DefaultPolicyRequest {
+            policy,
+            blacklist_patterns,
+            group,
+            members,
+        } = request.into_inner();
+        let template = match policy.as_str() {
+            "" => serde_json::Value::Object(Default::default()),
+            policy => serde_json::from_str(policy).map_err(|e| {
+                Status::invalid_argument(format!("Could not parse the default policy: {e}"))
+            })?,
+        };
+        let group = match group.as_str() {
+            "" => None,
+            group => {
+                if !self.sess_manager.verify_if_owner(&user_id)? {
+                    return Err(Status::permission_denied(
+                        "Only data owners can set the default policy of a group.",
+                    ));
+                }
+                Some((group, members))
+            }
+        };
+        let defaults = self
+            .default_policies
+            .set(&user_id, group, &template, blacklist_patterns)?;
+        info!(
+            "Succesfully set the default policy of {} version {}",
+            defaults.scope, defaults.version
+        );
+        Ok(Response::new(default_policy_response(defaults)?))
+    }
+
+    async fn get_default_policy(
+        &self,
+        request: Request<DefaultPolicyQuery>,
+    ) -> Result<Response<DefaultPolicyResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let DefaultPolicyQuery { group, version } = request.get_ref();
+        let group = (!group.is_empty()).then_some(group.as_str());
+        let version = (*version != 0).then_some(*version);
+        let owner = group.is_some() && self.sess_manager.verify_if_owner(&user_id).unwrap_or(false);
+        let defaults = self.default_policies.get(&user_id, group, version, owner)?;
+        Ok(Response::new(default_policy_response(defaults)?))
+    }
+
+    async fn list_versions(
         &self,
         request: Request<ReferenceRequest>,
-    ) -> Result<Response<Empty>, Status> {
+    ) -> Result<Response<VersionList>, Status> {
+        self.sess_manager.get_token(&request)?;
+        let (identifier, _) = self.resolve(&request.get_ref().identifier)?;
+        Ok(Response::new(self.df_versions(&identifier)?))
+    }
+
+    async fn set_version_retention(
+        &self,
+        request: Request<VersionRetentionRequest>,
+    ) -> Result<Response<VersionList>, Status> {
         let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
 
-        let identifier = &request.get_ref().identifier;
-        let user_id = self.sess_manager.get_user_id(token.clone())?;
-        let owner_check = self.sess_manager.verify_if_owner(&user_id)?;
-        if owner_check {
-            self.delete_dfs(identifier)?;
+        let VersionRetentionRequest {
+            identifier,
+            max_versions,
+            max_age_secs,
+        } = request.into_inner();
+        let (identifier, _) = self.resolve(&identifier)?;
+        let retention = Retention {
+            max_versions,
+            max_age_secs,
+        };
+        let list = self.retain_versions(&identifier, retention, &user_id)?;
+        info!(
+            "Succesfully set the retention of {} to {} versions for {}s",
+            identifier, max_versions, max_age_secs
+        );
+        Ok(Response::new(list))
+    }
+
+    async fn handshake(
+        &self,
+        request: Request<HandshakeRequest>,
+    ) -> Result<Response<HandshakeResponse>, Status> {
+        self.sess_manager.get_token(&request)?;
+        let request = request.into_inner();
+        let auth_mode = if self.sess_manager.auth_enabled() {
+            "keys"
         } else {
-            return Err(Status::internal("Only data owners can delete dataframes."));
+            "open"
+        };
+        let limits = ClientLimits {
+            max_upload_bytes: self.max_upload_bytes,
+            upload_chunk_bytes: self.upload_chunk_bytes,
+            max_rows: capabilities::max_rows(),
+            max_literal_frame_cells: self.literal_frame_max_cells as u64,
+            max_slice_rows: self.slice_max_rows as u64,
+            max_page_size: catalog::MAX_PAGE_SIZE as u64,
+            max_batch_queries: self.max_queries_per_batch as u64,
+            max_inline_result_bytes: self.inline_result_bytes,
+        };
+        let response = handshake::negotiate(
+            &request,
+            self.server_capabilities(),
+            auth_mode,
+            limits,
+            &self.deprecated_features,
+        );
+        self.client_versions.record(&request);
+        for deprecation in response.deprecations.iter() {
+            warn!(
+                "{} {} uses deprecated feature {}, to be removed after {}",
+                request.client_name,
+                request.client_version,
+                deprecation.feature,
+                deprecation.sunset
+            );
         }
-        telemetry::add_event(
-            TelemetryEventProps::DeleteDataframe {
-                dataset_name: Some(identifier.clone()),
+        Ok(Response::new(response))
+    }
+
+    async fn get_client_versions(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ClientVersionsProto>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can list the versions of clients.",
+            ));
+        }
+        Ok(Response::new(ClientVersionsProto {
+            versions: self.client_versions.distribution(),
+        }))
+    }
+
+    async fn list_running_queries(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<RunningQueriesProto>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let owner =
+            self.sess_manager.auth_enabled() && self.sess_manager.verify_if_owner(&user_id)?;
+        let now = catalog::now_ms();
+        Ok(Response::new(match owner {
+            true => RunningQueriesProto {
+                queries: self.running.list(None, now),
+                kills: self
+                    .running
+                    .kills()
+                    .iter()
+                    .map(|kill| kill.to_proto())
+                    .collect(),
             },
-            Some(self.sess_manager.get_client_info(token)?),
+            false => RunningQueriesProto {
+                queries: self.running.list(Some(&user_id), now),
+                kills: Vec::new(),
+            },
+        }))
+    }
+
+    async fn kill_query(
+        &self,
+        request: Request<KillQueryRequest>,
+    ) -> Result<Response<QueryKill>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        let owner =
+            self.sess_manager.auth_enabled() && self.sess_manager.verify_if_owner(&user_id)?;
+        let KillQueryRequest { id, reason } = request.into_inner();
+        let kill = self
+            .running
+            .kill(&id, &user_id, owner, &reason, catalog::now_ms())?;
+        Ok(Response::new(kill.to_proto()))
+    }
+
+    async fn list_pending_requests(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<PendingRequests>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can list the fetches pending their approval.",
+            ));
+        }
+        let requests = self.approvals.list();
+        Ok(Response::new(PendingRequests {
+            requests: requests.iter().map(|request| request.to_proto()).collect(),
+        }))
+    }
+
+    async fn approve_request(
+        &self,
+        request: Request<ApproveRequestRequest>,
+    ) -> Result<Response<PendingRequestProto>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can approve fetches.",
+            ));
+        }
+        let approved = self.approvals.approve(&request.into_inner().id)?;
+        info!(
+            "Fetch request {} of {} by {} approved by {user_id}",
+            approved.id, approved.identifier, approved.identity
         );
-        Ok(Response::new(Empty {}))
+        Ok(Response::new(approved.to_proto()))
+    }
+
+    async fn reject_request(
+        &self,
+        request: Request<RejectRequestRequest>,
+    ) -> Result<Response<PendingRequestProto>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can reject fetches.",
+            ));
+        }
+        let RejectRequestRequest { id, reason } = request.into_inner();
+        let rejected = self.approvals.reject(&id, &reason)?;
+        info!(
+            "Fetch request {} of {} by {} rejected by {user_id}",
+            rejected.id, rejected.identifier, rejected.identity
+        );
+        Ok(Response::new(rejected.to_proto()))
+    }
+
+    async fn get_audit_log(
+        &self,
+        request: Request<AuditLogRequest>,
+    ) -> Result<Response<AuditLogProto>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+        if !self.sess_manager.verify_if_owner(&user_id)? {
+            return Err(Status::permission_denied(
+                "Only data owners can read the audit log.",
+            ));
+        }
+        let filter = AuditFilter::from_proto(request.into_inner());
+        let entries = self.audit.entries(filter).await?;
+        Ok(Response::new(AuditLogProto {
+            entries: entries.iter().map(AuditEntry::to_proto).collect(),
+        }))
     }
+
+    async fn generate_synthetic(
+        &self,
+        request: Request<SyntheticRequest>,
+    ) -> Result<Response<ReferenceResponse>, Status> {
+        let token = self.sess_manager.get_token(&request)?;
+        let user_id = self.sess_manager.get_user_id(token)?;
+
+        let SyntheticRequest {
+            identifier,
+            rows,
+            seed,
+        } = request.into_inner();
+        self.check_resolvable(std::slice::from_ref(&identifier), &user_id)?;
+        let state = self.clone();
+        let synthetic = tokio::task::spawn_blocking(move || {
+            state.generate_synthetic(&identifier, &user_id, rows.map(|rows| rows as usize), seed)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Synthetic data generation task failed: {e}")))??;
+        let header = self.get_header(&synthetic)?;
+        Ok(Response::new(ReferenceResponse {
+            identifier: synthetic,
+            header,
+            ..Default::default()
+        }))
+    }
+
     async fn split(
         &self,
         request: Request<SplitRequest>,
@@ -632,15 +6481,18 @@ impl PolarsService for BastionLabPolars {
             out_arrays.append(&mut vec![
                 ReferenceResponse {
                     identifier: self.insert_array(upper),
-                    header: String::default(),
+                    ..Default::default()
                 },
                 ReferenceResponse {
                     identifier: self.insert_array(lower),
-                    header: String::default(),
+                    ..Default::default()
                 },
             ])
         }
 
-        Ok(Response::new(ReferenceList { list: out_arrays }))
+        Ok(Response::new(ReferenceList {
+            list: out_arrays,
+            ..Default::default()
+        }))
     }
 }
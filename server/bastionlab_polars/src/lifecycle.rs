@@ -0,0 +1,171 @@
+//! Onboarding states of uploaded dataframes.
+//!
+//! Unless the server publishes uploads right away, a dataframe starts as a draft that only its
+//! owner sees and queries, and whose policy, sanitized columns and catalog entry can still be
+//! edited. Once submitted, it waits for a reviewer to approve it, which publishes it, or to reject
+//! it, which makes it a draft again. Owners can unpublish their dataframes, with the same
+//! preconditions as a deletion.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::masking::Masking;
+use crate::utils::sanitize_df;
+
+/// Number of rows shown to reviewers.
+pub const PREVIEW_ROWS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lifecycle {
+    Draft,
+    InReview,
+    /// Dataframes persisted before onboarding states are published.
+    #[default]
+    Published,
+}
+
+impl Lifecycle {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lifecycle::Draft => "draft",
+            Lifecycle::InReview => "in_review",
+            Lifecycle::Published => "published",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Submit,
+    Approve,
+    Reject,
+    Unpublish,
+}
+
+/// Onboarding state of a dataframe.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Onboarding {
+    pub state: Lifecycle,
+    /// Reason of the last rejection, cleared on approval.
+    #[serde(default)]
+    pub rejection: Option<String>,
+}
+
+impl Onboarding {
+    pub fn draft() -> Self {
+        Onboarding {
+            state: Lifecycle::Draft,
+            rejection: None,
+        }
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.state == Lifecycle::Published
+    }
+
+    /// Whether plans of a user can read the dataframe.
+    pub fn resolvable_by(&self, is_owner: bool) -> bool {
+        is_owner || self.is_published()
+    }
+
+    /// Whether the dataframe is listed to a user, and its metadata shown.
+    pub fn visible_to(&self, is_owner: bool, is_reviewer: bool) -> bool {
+        match self.state {
+            Lifecycle::Published => true,
+            Lifecycle::InReview => is_owner || is_reviewer,
+            Lifecycle::Draft => is_owner,
+        }
+    }
+
+    /// Whether the policy, sanitized columns and catalog entry can be edited.
+    pub fn check_editable(&self, identifier: &str) -> Result<(), Status> {
+        if self.state != Lifecycle::Draft {
+            return Err(Status::failed_precondition(format!(
+                "Dataframe {identifier} is {}: only drafts can be edited",
+                self.state.name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Applies `transition`, failing if it does not start from the current state.
+    pub fn apply(
+        &mut self,
+        identifier: &str,
+        transition: Transition,
+        reason: Option<String>,
+    ) -> Result<(), Status> {
+        let (from, to) = match transition {
+            Transition::Submit => (Lifecycle::Draft, Lifecycle::InReview),
+            Transition::Approve => (Lifecycle::InReview, Lifecycle::Published),
+            Transition::Reject => (Lifecycle::InReview, Lifecycle::Draft),
+            Transition::Unpublish => (Lifecycle::Published, Lifecycle::Draft),
+        };
+        if self.state != from {
+            return Err(Status::failed_precondition(format!(
+                "Dataframe {identifier} is {}, not {}",
+                self.state.name(),
+                from.name()
+            )));
+        }
+        self.state = to;
+        match transition {
+            Transition::Approve => self.rejection = None,
+            Transition::Reject => self.rejection = reason,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// The first rows of `df` shown to reviewers, masked like fetches are and with the sanitized
+/// columns nulled out.
+pub fn preview(df: &DataFrame, masking: &Masking, blacklist: &[String]) -> Result<String, Status> {
+    let mut head = df.head(Some(PREVIEW_ROWS));
+    masking.apply(&mut head)?;
+    sanitize_df(&mut head, &blacklist.to_vec());
+    Ok(format!("{head}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let mut onboarding = Onboarding::draft();
+        assert!(onboarding.apply("df", Transition::Approve, None).is_err());
+        onboarding.apply("df", Transition::Submit, None).unwrap();
+        assert!(onboarding.check_editable("df").is_err());
+        onboarding
+            .apply("df", Transition::Reject, Some("no policy".into()))
+            .unwrap();
+        assert_eq!(onboarding.state, Lifecycle::Draft);
+        assert_eq!(onboarding.rejection.as_deref(), Some("no policy"));
+        onboarding.check_editable("df").unwrap();
+
+        onboarding.apply("df", Transition::Submit, None).unwrap();
+        onboarding.apply("df", Transition::Approve, None).unwrap();
+        assert!(onboarding.is_published());
+        assert_eq!(onboarding.rejection, None);
+        assert!(onboarding.apply("df", Transition::Submit, None).is_err());
+        onboarding.apply("df", Transition::Unpublish, None).unwrap();
+        assert_eq!(onboarding, Onboarding::draft());
+    }
+
+    #[test]
+    fn visibility() {
+        let mut onboarding = Onboarding::draft();
+        assert!(onboarding.visible_to(true, false));
+        assert!(!onboarding.visible_to(false, true));
+        onboarding.state = Lifecycle::InReview;
+        assert!(onboarding.visible_to(false, true));
+        assert!(!onboarding.visible_to(false, false));
+        assert!(!onboarding.resolvable_by(false));
+        assert!(onboarding.resolvable_by(true));
+        // Dataframes persisted before onboarding states are published.
+        assert!(Onboarding::default().visible_to(false, false));
+    }
+}
@@ -0,0 +1,314 @@
+//! Small tables written inline in composite plans, such as lookup tables to join with.
+//!
+//! A `LiteralFramePlanSegment` pushes the dataframe its columns describe. Values are checked
+//! against the declared dtypes before the plan runs, and literal frames hold at most
+//! `literal_frame_max_cells` cells. They only live while the plan runs: nothing is stored unless
+//! an output of the plan is computed from them.
+//!
+//! Values are coerced as follows, and `null` is a null of any dtype:
+//!
+//! | dtype                               | JSON values                                        |
+//! |-------------------------------------|----------------------------------------------------|
+//! | Boolean                             | booleans                                           |
+//! | Int32, Int64, UInt32, UInt64        | integers within the range of the dtype             |
+//! | Float32, Float64                    | numbers, and the strings `NaN`, `inf` and `-inf`   |
+//! | Utf8                                | strings                                            |
+//! | Date                                | `YYYY-MM-DD` strings                               |
+//!
+//! Policies may list key columns in `literal_join_keys`: joining a literal frame on them is
+//! denied, since a literal frame of guessed values would tell which of them are in the data.
+
+use chrono::NaiveDate;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::Status;
+
+use crate::reserved::is_reserved;
+use crate::temporal::days_from_date;
+use crate::visitable::Visitable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LiteralDtype {
+    Boolean,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    Float32,
+    Float64,
+    Utf8,
+    Date,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiteralColumn {
+    pub name: String,
+    pub dtype: LiteralDtype,
+    pub values: Vec<Value>,
+}
+
+/// Builds the dataframe of a literal frame, `segment` naming it in errors.
+pub fn build(
+    segment: &str,
+    columns: &[LiteralColumn],
+    max_cells: usize,
+) -> Result<DataFrame, Status> {
+    let invalid = |message: String| Status::invalid_argument(format!("{segment}: {message}"));
+    let rows = columns.first().map_or(0, |column| column.values.len());
+    let cells = rows.saturating_mul(columns.len());
+    if cells > max_cells {
+        return Err(invalid(format!(
+            "literal frames hold at most {max_cells} cells (`literal_frame_max_cells`), this one \
+             has {cells}: upload larger tables instead"
+        )));
+    }
+    let mut series = Vec::with_capacity(columns.len());
+    for (index, column) in columns.iter().enumerate() {
+        if column.name.trim().is_empty() || is_reserved(&column.name) {
+            return Err(invalid(format!("invalid column name `{}`", column.name)));
+        }
+        if columns[..index].iter().any(|c| c.name == column.name) {
+            return Err(invalid(format!("duplicate column `{}`", column.name)));
+        }
+        if column.values.len() != rows {
+            return Err(invalid(format!(
+                "column `{}` has {} values, but the first column has {rows}",
+                column.name,
+                column.values.len()
+            )));
+        }
+        series.push(column_series(column).map_err(|(row, expected)| {
+            invalid(format!(
+                "column `{}`, row {row}: expected {expected}, got {}",
+                column.name, column.values[row]
+            ))
+        })?);
+    }
+    DataFrame::new(series).map_err(|e| invalid(format!("could not build literal frame: {e}")))
+}
+
+/// Parses the values of `column`, or returns the row of the first invalid one with what was
+/// expected instead.
+fn column_series(column: &LiteralColumn) -> Result<Series, (usize, String)> {
+    fn parse<T>(
+        values: &[Value],
+        expected: &str,
+        f: impl Fn(&Value) -> Option<T>,
+    ) -> Result<Vec<Option<T>>, (usize, String)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(row, value)| match value {
+                Value::Null => Ok(None),
+                value => f(value)
+                    .map(Some)
+                    .ok_or_else(|| (row, expected.to_string())),
+            })
+            .collect()
+    }
+    fn float(value: &Value) -> Option<f64> {
+        match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => match s.as_str() {
+                "NaN" => Some(f64::NAN),
+                "inf" => Some(f64::INFINITY),
+                "-inf" => Some(f64::NEG_INFINITY),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    let (name, values) = (column.name.as_str(), &column.values[..]);
+    Ok(match column.dtype {
+        LiteralDtype::Boolean => Series::new(name, parse(values, "a boolean", Value::as_bool)?),
+        LiteralDtype::Int32 => Series::new(
+            name,
+            parse(values, "an Int32 integer", |v| {
+                v.as_i64().and_then(|v| i32::try_from(v).ok())
+            })?,
+        ),
+        LiteralDtype::Int64 => Series::new(name, parse(values, "an Int64 integer", Value::as_i64)?),
+        LiteralDtype::UInt32 => Series::new(
+            name,
+            parse(values, "a UInt32 integer", |v| {
+                v.as_u64().and_then(|v| u32::try_from(v).ok())
+            })?,
+        ),
+        LiteralDtype::UInt64 => {
+            Series::new(name, parse(values, "a UInt64 integer", Value::as_u64)?)
+        }
+        LiteralDtype::Float32 => Series::new(
+            name,
+            parse(values, "a number", |v| float(v).map(|v| v as f32))?,
+        ),
+        LiteralDtype::Float64 => Series::new(name, parse(values, "a number", float)?),
+        LiteralDtype::Utf8 => Series::new(
+            name,
+            parse(values, "a string", |v| v.as_str().map(String::from))?,
+        ),
+        LiteralDtype::Date => {
+            let days = parse(values, "a YYYY-MM-DD date", |v| {
+                let date = NaiveDate::parse_from_str(v.as_str()?, "%Y-%m-%d").ok()?;
+                i32::try_from(days_from_date(date)).ok()
+            })?;
+            Series::new(name, days)
+                .cast(&DataType::Date)
+                .map_err(|_| (0, String::from("a date")))?
+        }
+    })
+}
+
+/// Checks that `plan`, which reads a literal frame, joins none on the `literal_join_keys` of the
+/// policies of the dataframes it reads, given by identifier.
+pub fn check_joins(plan: &LogicalPlan, forbidden: &[(String, Vec<String>)]) -> Result<(), Status> {
+    if forbidden.iter().all(|(_, keys)| keys.is_empty()) {
+        return Ok(());
+    }
+    let mut keys = Vec::new();
+    plan.visit(&mut keys, |plan, keys| {
+        if let LogicalPlan::Join {
+            left_on, right_on, ..
+        } = plan
+        {
+            for expr in left_on.iter().chain(right_on.iter()) {
+                if let Expr::Column(name) = expr {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        Ok(())
+    })?;
+    for (identifier, columns) in forbidden {
+        if let Some(key) = keys.iter().find(|key| columns.contains(key)) {
+            return Err(Status::permission_denied(format!(
+                "The policy of dataframe {identifier} forbids joining literal frames on column \
+                 {key}."
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn column(dtype: LiteralDtype, values: Value) -> LiteralColumn {
+        LiteralColumn {
+            name: String::from("c"),
+            dtype,
+            values: serde_json::from_value(values).unwrap(),
+        }
+    }
+
+    fn error(columns: &[LiteralColumn], max_cells: usize) -> String {
+        build("segment 0", columns, max_cells)
+            .unwrap_err()
+            .message()
+            .to_string()
+    }
+
+    #[test]
+    fn literals_are_coerced_to_their_dtype() {
+        let df = build(
+            "segment 0",
+            &[
+                LiteralColumn {
+                    name: String::from("i"),
+                    ..column(LiteralDtype::Int32, json!([1, null, -3]))
+                },
+                LiteralColumn {
+                    name: String::from("f"),
+                    ..column(LiteralDtype::Float64, json!([1, 2.5, "NaN"]))
+                },
+                LiteralColumn {
+                    name: String::from("d"),
+                    ..column(
+                        LiteralDtype::Date,
+                        json!(["1970-01-02", null, "2000-01-01"]),
+                    )
+                },
+            ],
+            100,
+        )
+        .unwrap();
+        assert_eq!(
+            df.dtypes(),
+            [DataType::Int32, DataType::Float64, DataType::Date]
+        );
+        assert_eq!(df.column("i").unwrap().null_count(), 1);
+        let floats: Vec<_> = df.column("f").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(floats[..2], [Some(1.0), Some(2.5)]);
+        assert!(floats[2].unwrap().is_nan());
+        let days = df.column("d").unwrap().cast(&DataType::Int32).unwrap();
+        assert_eq!(days.i32().unwrap().get(0), Some(1));
+        assert_eq!(days.i32().unwrap().get(2), Some(10_957));
+    }
+
+    #[test]
+    fn invalid_cells_are_located() {
+        for (dtype, values, expected) in [
+            (
+                LiteralDtype::Int32,
+                json!([1, 2, 3_000_000_000i64]),
+                "row 2: expected an Int32",
+            ),
+            (
+                LiteralDtype::Int64,
+                json!([1, 2.5]),
+                "row 1: expected an Int64",
+            ),
+            (
+                LiteralDtype::UInt32,
+                json!([-1]),
+                "row 0: expected a UInt32",
+            ),
+            (
+                LiteralDtype::Utf8,
+                json!(["a", 1]),
+                "row 1: expected a string, got 1",
+            ),
+            (
+                LiteralDtype::Boolean,
+                json!([true, "yes"]),
+                "row 1: expected a boolean",
+            ),
+            (
+                LiteralDtype::Date,
+                json!(["2023-02-30"]),
+                "row 0: expected a YYYY-MM-DD date",
+            ),
+        ] {
+            let message = error(&[column(dtype, values)], 100);
+            assert!(
+                message.contains(&format!("segment 0: column `c`, {expected}")),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn literal_frames_are_capped() {
+        let columns = [
+            column(LiteralDtype::Int64, json!([1, 2, 3])),
+            LiteralColumn {
+                name: String::from("d"),
+                ..column(LiteralDtype::Int64, json!([1, 2, 3]))
+            },
+        ];
+        assert!(build("segment 0", &columns, 6).is_ok());
+        assert!(error(&columns, 5).contains("at most 5 cells"));
+
+        let ragged = [
+            column(LiteralDtype::Int64, json!([1, 2])),
+            LiteralColumn {
+                name: String::from("d"),
+                ..column(LiteralDtype::Int64, json!([1]))
+            },
+        ];
+        assert!(error(&ragged, 100).contains("has 1 values"));
+    }
+}
@@ -0,0 +1,625 @@
+//! Column masks: owner-defined compositions of maskers applied to the values of columns whenever
+//! they leave the server.
+//!
+//! Policies map columns to an ordered list of [`Masker`]s in `column_masks`, each applied to the
+//! output of the previous one. Compositions are checked against the dtypes of the columns when the
+//! policy is attached, and produce:
+//!
+//! | masker           | input dtypes                 | output dtype |
+//! |------------------|------------------------------|--------------|
+//! | `Bucketize`      | numeric                      | Float64      |
+//! | `TopBottomCode`  | numeric                      | Float64      |
+//! | `Redact`         | Utf8, Categorical            | Utf8         |
+//! | `GeneralizeDate` | Date, Datetime               | Date         |
+//! | `Replace`        | any the value coerces to     | unchanged    |
+//!
+//! Fetches, Arrow exports, review previews and the statistics of headers all go through
+//! [`Masking::apply`], so that masked values are the same wherever they are read. Masks apply
+//! before sanitization and watermarks. They carry over to the results computed from the column,
+//! where a column of the same name whose dtype the composition does not accept is nulled out.
+//!
+//! `TopBottomCode` clamps values to percentiles of the column, read from its cached statistics,
+//! see [`crate::statistics`]. Percentiles are only recomputed when the column is, so releases are
+//! refused when they are more than `max_stale_mutations` mutations behind the data, until the data
+//! owner refreshes them by requesting the header of the dataframe.
+
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate, TimeDelta};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::Status;
+
+use crate::statistics::Statistics;
+use crate::temporal::{date_from_days, days_from_date};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DatePeriod {
+    /// Weeks start on Mondays.
+    Week,
+    Month,
+    Quarter,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Masker {
+    /// Replaces values by the greatest of the increasing `edges` they are not below. Values below
+    /// the first edge, and NaNs, are nulled out.
+    Bucketize { edges: Vec<f64> },
+    /// Clamps values between the `bottom` and `top` percentiles of the column.
+    TopBottomCode {
+        bottom: u8,
+        top: u8,
+        /// How many mutations the percentiles may lag behind the data.
+        #[serde(default)]
+        max_stale_mutations: u64,
+    },
+    /// Replaces every character but the first `keep_first` and last `keep_last` ones with `*`,
+    /// and all of them in strings too short to keep that many.
+    Redact { keep_first: usize, keep_last: usize },
+    /// Replaces dates by the first day of their period. Datetimes are generalized by their UTC
+    /// date.
+    GeneralizeDate { period: DatePeriod },
+    /// Replaces every value with `value`: `null`, a boolean, a number within the range of the
+    /// dtype, a string, or a `YYYY-MM-DD` date.
+    Replace { value: Value },
+}
+
+/// The masker compositions of columns, by column.
+pub type ColumnMasks = BTreeMap<String, Vec<Masker>>;
+
+fn is_percentile_based(masker: &Masker) -> bool {
+    matches!(masker, Masker::TopBottomCode { .. })
+}
+
+/// The columns of `masks` whose compositions read percentiles.
+pub fn percentile_columns(masks: &ColumnMasks) -> Vec<String> {
+    masks
+        .iter()
+        .filter(|(_, composition)| composition.iter().any(is_percentile_based))
+        .map(|(column, _)| column.clone())
+        .collect()
+}
+
+/// Masks columns with the compositions of both `a` and `b`. Columns each masks differently are
+/// nulled out.
+pub fn merge_masks(a: &ColumnMasks, b: &ColumnMasks) -> ColumnMasks {
+    let mut merged = a.clone();
+    for (column, composition) in b.iter() {
+        match merged.get(column) {
+            Some(mine) if mine == composition => (),
+            Some(_) => {
+                merged.insert(column.clone(), vec![Masker::Replace { value: Value::Null }]);
+            }
+            None => {
+                merged.insert(column.clone(), composition.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// Checks that every composition of `masks` applies to the column of `schema` it masks.
+pub fn check(masks: &ColumnMasks, schema: &Schema) -> Result<(), Status> {
+    for (column, composition) in masks.iter() {
+        let invalid = |message: String| {
+            Status::invalid_argument(format!("Invalid mask of column {column}: {message}"))
+        };
+        let mut dtype = schema
+            .get(column)
+            .ok_or_else(|| invalid(String::from("no such column")))?
+            .clone();
+        if composition.is_empty() {
+            return Err(invalid(String::from("no maskers")));
+        }
+        for (index, masker) in composition.iter().enumerate() {
+            check_parameters(masker).map_err(|e| invalid(format!("masker {index}: {e}")))?;
+            dtype = output_dtype(masker, &dtype)
+                .map_err(|e| invalid(format!("masker {index}: {e}")))?;
+        }
+    }
+    Ok(())
+}
+
+fn check_parameters(masker: &Masker) -> Result<(), String> {
+    match masker {
+        Masker::Bucketize { edges } => {
+            if edges.is_empty() || edges.iter().any(|edge| !edge.is_finite()) {
+                return Err(String::from("edges must be finite, and at least one"));
+            }
+            if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(String::from("edges must be increasing"));
+            }
+        }
+        Masker::TopBottomCode { bottom, top, .. } => {
+            if bottom >= top || *top > 100 {
+                return Err(format!(
+                    "percentiles must satisfy bottom < top <= 100, got {bottom} and {top}"
+                ));
+            }
+        }
+        Masker::Redact { .. } | Masker::GeneralizeDate { .. } | Masker::Replace { .. } => (),
+    }
+    Ok(())
+}
+
+/// The dtype `masker` turns `dtype` into, or why it does not apply to it.
+fn output_dtype(masker: &Masker, dtype: &DataType) -> Result<DataType, String> {
+    let unsupported = || Err(format!("{masker:?} does not apply to {dtype}"));
+    match masker {
+        Masker::Bucketize { .. } | Masker::TopBottomCode { .. } if dtype.is_numeric() => {
+            Ok(DataType::Float64)
+        }
+        Masker::Redact { .. } if is_string(dtype) => Ok(DataType::Utf8),
+        Masker::GeneralizeDate { .. } if is_temporal(dtype) => Ok(DataType::Date),
+        Masker::Replace { value } => {
+            replacement("", value, dtype, 1)?;
+            Ok(dtype.clone())
+        }
+        _ => unsupported(),
+    }
+}
+
+fn is_string(dtype: &DataType) -> bool {
+    matches!(dtype, DataType::Utf8 | DataType::Categorical(_))
+}
+
+fn is_temporal(dtype: &DataType) -> bool {
+    matches!(dtype, DataType::Date | DataType::Datetime(_, _))
+}
+
+/// `len` times `value` as a series of `dtype`, or why `value` does not coerce to it.
+fn replacement(name: &str, value: &Value, dtype: &DataType, len: usize) -> Result<Series, String> {
+    let invalid = || Err(format!("{value} does not coerce to {dtype}"));
+    let series = match value {
+        Value::Null => return Ok(Series::full_null(name, len, dtype)),
+        Value::Bool(b) if *dtype == DataType::Boolean => Series::new(name, vec![*b; len]),
+        Value::Number(n) if dtype.is_numeric() => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Series::new(name, vec![i; len]),
+            (_, Some(u), _) => Series::new(name, vec![u; len]),
+            (_, _, Some(f)) if dtype.is_float() => Series::new(name, vec![f; len]),
+            _ => return invalid(),
+        },
+        Value::String(s) if is_string(dtype) => Series::new(name, vec![s.as_str(); len]),
+        Value::String(s) if *dtype == DataType::Date => {
+            let date = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => return invalid(),
+            };
+            match i32::try_from(days_from_date(date)) {
+                Ok(days) => Series::new(name, vec![days; len]),
+                Err(_) => return invalid(),
+            }
+        }
+        _ => return invalid(),
+    };
+    // Casts out of the range of the dtype give nulls.
+    match series.cast(dtype) {
+        Ok(cast) if cast.null_count() == 0 => Ok(cast),
+        _ => invalid(),
+    }
+}
+
+/// The masks of a release, with the percentiles they read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Masking {
+    masks: ColumnMasks,
+    percentiles: BTreeMap<String, Vec<f64>>,
+}
+
+impl Masking {
+    /// Reads the percentiles `masks` need from `statistics`, which must have computed the
+    /// [`percentile_columns`] of `masks`. Fails if they are staler than the maskers tolerate.
+    pub fn resolve(masks: &ColumnMasks, statistics: &Statistics) -> Result<Self, Status> {
+        let mut percentiles = BTreeMap::new();
+        for column in percentile_columns(masks) {
+            let tolerated = masks[&column]
+                .iter()
+                .filter_map(|masker| match masker {
+                    Masker::TopBottomCode {
+                        max_stale_mutations,
+                        ..
+                    } => Some(*max_stale_mutations),
+                    _ => None,
+                })
+                .min()
+                .unwrap_or_default();
+            let (computed, behind) = match (
+                statistics.column(&column),
+                statistics.percentiles_behind(&column),
+            ) {
+                (Some(computed), Some(behind)) => (computed, behind),
+                _ => {
+                    return Err(Status::failed_precondition(format!(
+                        "The percentiles of column {column}, which its mask reads, were never \
+                         computed"
+                    )))
+                }
+            };
+            if behind > tolerated {
+                return Err(Status::failed_precondition(format!(
+                    "The percentiles of column {column} are {behind} mutations behind the data, \
+                     more than the {tolerated} its mask tolerates: the data owner must refresh \
+                     them by requesting the header of the dataframe"
+                )));
+            }
+            percentiles.insert(column, computed.percentiles.clone());
+        }
+        Ok(Masking {
+            masks: masks.clone(),
+            percentiles,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masks.is_empty()
+    }
+
+    /// The masked columns.
+    pub fn columns(&self) -> impl Iterator<Item = &String> {
+        self.masks.keys()
+    }
+
+    /// Masks columns with the compositions of both, see [`merge_masks`].
+    pub fn merge(&mut self, other: &Masking) {
+        self.masks = merge_masks(&self.masks, &other.masks);
+        for (column, percentiles) in other.percentiles.iter() {
+            self.percentiles
+                .entry(column.clone())
+                .or_insert_with(|| percentiles.clone());
+        }
+        let masks = &self.masks;
+        self.percentiles
+            .retain(|column, _| masks[column].iter().any(is_percentile_based));
+    }
+
+    /// Masks the columns of `df`. Those whose dtype the composition does not apply to are nulled
+    /// out.
+    pub fn apply(&self, df: &mut DataFrame) -> Result<(), Status> {
+        for (column, composition) in self.masks.iter() {
+            let series = match df.column(column) {
+                Ok(series) => series,
+                Err(_) => continue,
+            };
+            let percentiles = self.percentiles.get(column).map(|p| &p[..]);
+            let masked = match mask(series, composition, percentiles)? {
+                Some(masked) => masked,
+                None => Series::full_null(column, series.len(), series.dtype()),
+            };
+            df.replace(column, masked)
+                .map_err(|e| Status::internal(format!("Could not mask column {column}: {e}")))?;
+        }
+        Ok(())
+    }
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Could not mask a column: {e}"))
+}
+
+/// `series` masked by `composition`, or `None` if the composition does not apply to its dtype.
+fn mask(
+    series: &Series,
+    composition: &[Masker],
+    percentiles: Option<&[f64]>,
+) -> Result<Option<Series>, Status> {
+    let name = series.name().to_string();
+    let mut series = series.clone();
+    for masker in composition {
+        if !applies(masker, series.dtype()) {
+            return Ok(None);
+        }
+        series = match masker {
+            Masker::Bucketize { edges } => map_floats(&series, |value| {
+                let bucket = edges.partition_point(|edge| *edge <= value);
+                bucket.checked_sub(1).map(|bucket| edges[bucket])
+            })?,
+            Masker::TopBottomCode { bottom, top, .. } => {
+                let bounds = percentiles
+                    .filter(|percentiles| percentiles.len() == 101)
+                    .map(|percentiles| (percentiles[*bottom as usize], percentiles[*top as usize]));
+                map_floats(&series, |value| {
+                    bounds.map(|(bottom, top)| value.clamp(bottom, top))
+                })?
+            }
+            Masker::Redact {
+                keep_first,
+                keep_last,
+            } => {
+                let strings = series.cast(&DataType::Utf8).map_err(polars_err)?;
+                let redacted: Utf8Chunked = strings
+                    .utf8()
+                    .map_err(polars_err)?
+                    .into_iter()
+                    .map(|value| value.map(|value| redact(value, *keep_first, *keep_last)))
+                    .collect();
+                redacted.into_series()
+            }
+            Masker::GeneralizeDate { period } => {
+                let days = series
+                    .cast(&DataType::Date)
+                    .and_then(|dates| dates.cast(&DataType::Int32))
+                    .map_err(polars_err)?;
+                let generalized: Int32Chunked = days
+                    .i32()
+                    .map_err(polars_err)?
+                    .into_iter()
+                    .map(|days| days.and_then(|days| generalize(days, *period)))
+                    .collect();
+                generalized
+                    .into_series()
+                    .cast(&DataType::Date)
+                    .map_err(polars_err)?
+            }
+            Masker::Replace { value } => replace(&series, value).map_err(Status::internal)?,
+        };
+        series.rename(&name);
+    }
+    Ok(Some(series))
+}
+
+/// Whether `masker` applies to a column of `dtype` when data is released.
+fn applies(masker: &Masker, dtype: &DataType) -> bool {
+    match masker {
+        Masker::Replace { value } => replacement("", value, &widened(dtype), 1).is_ok(),
+        masker => output_dtype(masker, dtype).is_ok(),
+    }
+}
+
+/// `series` with every value replaced by `value`, keeping its dtype or, since storage optimization
+/// may have narrowed the dtype the value was checked against, widening it.
+fn replace(series: &Series, value: &Value) -> Result<Series, String> {
+    let (name, dtype, len) = (series.name(), series.dtype(), series.len());
+    replacement(name, value, dtype, len).or_else(|_| replacement(name, value, &widened(dtype), len))
+}
+
+/// The widest dtype of the kind of `dtype`.
+fn widened(dtype: &DataType) -> DataType {
+    match dtype {
+        DataType::Int8 | DataType::Int16 | DataType::Int32 => DataType::Int64,
+        DataType::UInt8 | DataType::UInt16 | DataType::UInt32 => DataType::UInt64,
+        DataType::Float32 => DataType::Float64,
+        dtype => dtype.clone(),
+    }
+}
+
+/// Maps the non-null, non-NaN values of numeric `series` as Float64s, nulling out the others.
+fn map_floats(series: &Series, f: impl Fn(f64) -> Option<f64>) -> Result<Series, Status> {
+    let floats = series.cast(&DataType::Float64).map_err(polars_err)?;
+    let mapped: Float64Chunked = floats
+        .f64()
+        .map_err(polars_err)?
+        .into_iter()
+        .map(|value| value.filter(|value| !value.is_nan()).and_then(&f))
+        .collect();
+    Ok(mapped.into_series())
+}
+
+fn redact(value: &str, keep_first: usize, keep_last: usize) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= keep_first + keep_last {
+        return "*".repeat(chars.len());
+    }
+    let mut redacted: String = chars[..keep_first].iter().collect();
+    redacted.push_str(&"*".repeat(chars.len() - keep_first - keep_last));
+    redacted.extend(&chars[chars.len() - keep_last..]);
+    redacted
+}
+
+/// The first day of the period of the date `days` after the Unix epoch, in the same unit.
+fn generalize(days: i32, period: DatePeriod) -> Option<i32> {
+    let date = date_from_days(days as i64)?;
+    let start = match period {
+        DatePeriod::Week => {
+            date - TimeDelta::try_days(date.weekday().num_days_from_monday() as i64)?
+        }
+        DatePeriod::Month => date.with_day(1)?,
+        DatePeriod::Quarter => {
+            NaiveDate::from_ymd_opt(date.year(), (date.month0() / 3) * 3 + 1, 1)?
+        }
+    };
+    i32::try_from(days_from_date(start)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn masks(value: Value) -> ColumnMasks {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn masking(masks: ColumnMasks) -> Masking {
+        Masking {
+            masks,
+            percentiles: BTreeMap::new(),
+        }
+    }
+
+    fn df() -> DataFrame {
+        let dates = Series::new("d", [Some(19_405i32), None, Some(19_450)])
+            .cast(&DataType::Date)
+            .unwrap();
+        let mut df = df! {
+            "x" => [Some(5i64), Some(-3), Some(42)],
+            "s" => [Some("alice@example.com"), None, Some("bob")],
+        }
+        .unwrap();
+        df.with_column(dates).unwrap();
+        df
+    }
+
+    #[test]
+    fn compositions_are_checked_against_dtypes() {
+        let schema = df().schema();
+        check(
+            &masks(json!({
+                "x": [
+                    {"type": "Bucketize", "edges": [0, 10]},
+                    {"type": "Replace", "value": 2.5},
+                ],
+                "s": [{"type": "Redact", "keep_first": 1, "keep_last": 0}],
+                "d": [
+                    {"type": "GeneralizeDate", "period": "Month"},
+                    {"type": "Replace", "value": "2023-01-01"},
+                ],
+            })),
+            &schema,
+        )
+        .unwrap();
+
+        for (masks, expected) in [
+            (
+                json!({"y": [{"type": "Replace", "value": null}]}),
+                "no such column",
+            ),
+            (json!({"x": []}), "no maskers"),
+            (
+                json!({"s": [{"type": "Bucketize", "edges": [0]}]}),
+                "does not apply",
+            ),
+            (
+                json!({"x": [{"type": "Bucketize", "edges": [1, 1]}]}),
+                "increasing",
+            ),
+            (
+                json!({"x": [{"type": "TopBottomCode", "bottom": 10, "top": 101}]}),
+                "bottom < top",
+            ),
+            // Bucketized values are floats.
+            (
+                json!({"x": [
+                    {"type": "Bucketize", "edges": [0]},
+                    {"type": "Redact", "keep_first": 0, "keep_last": 0},
+                ]}),
+                "masker 1",
+            ),
+            (
+                json!({"x": [{"type": "Replace", "value": 2.5}]}),
+                "does not coerce",
+            ),
+            (
+                json!({"d": [{"type": "Replace", "value": "May"}]}),
+                "does not coerce",
+            ),
+        ] {
+            let message = check(&self::masks(masks), &schema)
+                .unwrap_err()
+                .message()
+                .to_string();
+            assert!(message.contains(expected), "{message}");
+        }
+    }
+
+    #[test]
+    fn compositions_apply_in_order() {
+        let mut df = df();
+        masking(masks(json!({
+            "x": [
+                {"type": "Bucketize", "edges": [0, 10]},
+                {"type": "Replace", "value": null},
+            ],
+            "s": [{"type": "Redact", "keep_first": 2, "keep_last": 4}],
+            "d": [{"type": "GeneralizeDate", "period": "Quarter"}],
+        })))
+        .apply(&mut df)
+        .unwrap();
+        assert_eq!(df.column("x").unwrap().dtype(), &DataType::Float64);
+        assert_eq!(df.column("x").unwrap().null_count(), 3);
+        let s: Vec<_> = df
+            .column("s")
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(s, [Some("al***********.com"), None, Some("***")]);
+        // 2023-02-17 and 2023-04-03.
+        let d = df.column("d").unwrap().cast(&DataType::Int32).unwrap();
+        let d: Vec<_> = d.i32().unwrap().into_iter().collect();
+        assert_eq!(d, [Some(19_358), None, Some(19_448)]);
+
+        let mut df = self::df();
+        masking(masks(json!({
+            "x": [{"type": "Bucketize", "edges": [0, 10]}],
+            "d": [{"type": "GeneralizeDate", "period": "Week"}],
+        })))
+        .apply(&mut df)
+        .unwrap();
+        let x: Vec<_> = df.column("x").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(x, [Some(0.0), None, Some(10.0)]);
+        let d = df.column("d").unwrap().cast(&DataType::Int32).unwrap();
+        // Monday 2023-02-13.
+        assert_eq!(d.i32().unwrap().get(0), Some(19_401));
+    }
+
+    #[test]
+    fn percentiles_must_be_fresh_enough() {
+        let df = df! { "x" => (0..=100i64).collect::<Vec<_>>() }.unwrap();
+        let masks = masks(json!({
+            "x": [{"type": "TopBottomCode", "bottom": 5, "top": 90, "max_stale_mutations": 1}],
+        }));
+        let mut statistics = Statistics::default();
+        statistics
+            .get(
+                &df,
+                &percentile_columns(&masks),
+                crate::statistics::Tolerance::Stale,
+            )
+            .unwrap();
+        let mut masked = df.clone();
+        Masking::resolve(&masks, &statistics)
+            .unwrap()
+            .apply(&mut masked)
+            .unwrap();
+        let x = masked.column("x").unwrap().f64().unwrap();
+        assert_eq!((x.min(), x.max()), (Some(5.0), Some(90.0)));
+
+        statistics.appended(&df).unwrap();
+        assert!(Masking::resolve(&masks, &statistics).is_ok());
+        statistics.appended(&df).unwrap();
+        let err = Masking::resolve(&masks, &statistics).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert!(
+            err.message().contains("2 mutations behind"),
+            "{}",
+            err.message()
+        );
+    }
+
+    #[test]
+    fn incompatible_columns_are_nulled_out() {
+        let mut df = df! { "x" => ["a", "b"] }.unwrap();
+        masking(masks(json!({"x": [{"type": "Bucketize", "edges": [0]}]})))
+            .apply(&mut df)
+            .unwrap();
+        assert_eq!(df.column("x").unwrap().null_count(), 2);
+    }
+
+    #[test]
+    fn replacements_fit_narrowed_dtypes() {
+        let mut df = df! { "x" => [1i8, 2] }.unwrap();
+        masking(masks(json!({"x": [{"type": "Replace", "value": 1000}]})))
+            .apply(&mut df)
+            .unwrap();
+        let x: Vec<_> = df.column("x").unwrap().i64().unwrap().into_iter().collect();
+        assert_eq!(x, [Some(1000), Some(1000)]);
+    }
+
+    #[test]
+    fn differing_compositions_merge_to_nulls() {
+        let bucketize = masks(json!({"x": [{"type": "Bucketize", "edges": [0]}]}));
+        let mut merged = masking(bucketize.clone());
+        merged.merge(&masking(bucketize.clone()));
+        assert_eq!(merged, masking(bucketize));
+        merged.merge(&masking(masks(json!({
+            "x": [{"type": "Bucketize", "edges": [1]}],
+            "y": [{"type": "Replace", "value": 0}],
+        }))));
+        assert_eq!(merged.masks["x"], [Masker::Replace { value: Value::Null }]);
+        assert_eq!(merged.columns().count(), 2);
+    }
+}
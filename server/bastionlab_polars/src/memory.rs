@@ -0,0 +1,270 @@
+//! Responses to memory pressure, before the OOM killer loses every dataframe that was not
+//! persisted.
+//!
+//! The watchdog periodically samples the resident memory of the process, or the estimated size of
+//! the dataframes if it is larger, against two watermarks:
+//! - above the soft one, idle results are deleted, the other dataframes are compacted (see
+//!   [`storage_optimization`](crate::storage_optimization)) and uploads are rejected,
+//! - above the hard one, queries are rejected too, savable dataframes are persisted and the warm
+//!   ones are spilled to disk.
+//!
+//! Hot dataframes are never collected nor spilled, see
+//! [`storage_classes`](crate::storage_classes).
+//!
+//! A level is only left once usage went below its watermark by the recovery margin, so that the
+//! server does not flap around a watermark.
+
+use std::sync::Mutex;
+
+use tonic::Status;
+
+/// Where the resident memory of the process is read from, mocked in tests.
+pub trait MemoryReader: Send + Sync {
+    /// Resident memory of the process, in bytes, if it can be read.
+    fn resident(&self) -> Option<u64>;
+}
+
+/// Reads the resident memory of the process from procfs.
+pub struct ProcessMemory;
+
+impl MemoryReader for ProcessMemory {
+    fn resident(&self) -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb * 1024)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Pressure {
+    #[default]
+    Normal,
+    Soft,
+    Hard,
+}
+
+impl Pressure {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pressure::Normal => "normal",
+            Pressure::Soft => "soft",
+            Pressure::Hard => "hard",
+        }
+    }
+}
+
+/// In bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub soft: u64,
+    pub hard: u64,
+    pub recovery: u64,
+}
+
+impl Watermarks {
+    /// The level of `usage`, coming from `previous`.
+    fn level(&self, previous: Pressure, usage: u64) -> Pressure {
+        let level = |usage: u64| {
+            if usage >= self.hard {
+                Pressure::Hard
+            } else if usage >= self.soft {
+                Pressure::Soft
+            } else {
+                Pressure::Normal
+            }
+        };
+        let reached = level(usage);
+        if reached >= previous {
+            return reached;
+        }
+        level(usage.saturating_add(self.recovery)).min(previous)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemorySample {
+    pub pressure: Pressure,
+    /// In bytes.
+    pub usage: u64,
+}
+
+pub struct MemoryWatchdog {
+    reader: Box<dyn MemoryReader>,
+    /// The watchdog is disabled if unset.
+    watermarks: Option<Watermarks>,
+    last: Mutex<MemorySample>,
+}
+
+impl MemoryWatchdog {
+    pub fn new(reader: Box<dyn MemoryReader>, watermarks: Option<Watermarks>) -> Self {
+        MemoryWatchdog {
+            reader,
+            watermarks,
+            last: Mutex::new(MemorySample::default()),
+        }
+    }
+
+    pub fn watermarks(&self) -> Option<Watermarks> {
+        self.watermarks
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.watermarks.is_some()
+    }
+
+    /// Samples memory usage, given `tracked` bytes of dataframes. Returns the previous level
+    /// along with the new sample.
+    pub fn sample(&self, tracked: u64) -> (Pressure, MemorySample) {
+        let mut last = self.last.lock().unwrap();
+        let previous = last.pressure;
+        if let Some(watermarks) = self.watermarks {
+            let usage = self.reader.resident().unwrap_or(0).max(tracked);
+            *last = MemorySample {
+                pressure: watermarks.level(previous, usage),
+                usage,
+            };
+        }
+        (previous, *last)
+    }
+
+    pub fn last(&self) -> MemorySample {
+        *self.last.lock().unwrap()
+    }
+
+    /// Fails if `what` must be rejected at the current level.
+    pub fn check(&self, what: &str, rejected_from: Pressure) -> Result<(), Status> {
+        let last = self.last();
+        if last.pressure >= rejected_from {
+            return Err(Status::resource_exhausted(format!(
+                "memory pressure: {what} are rejected while memory usage ({} MB) is above the {} watermark, retry later",
+                last.usage / (1 << 20),
+                last.pressure.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::persistence::artifact_path;
+    use crate::{BastionLabPolars, DataFrameArtifact};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tonic::Code;
+
+    const MB: u64 = 1 << 20;
+
+    struct MockMemory(Arc<AtomicU64>);
+
+    impl MemoryReader for MockMemory {
+        fn resident(&self) -> Option<u64> {
+            Some(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    fn polars(resident: &Arc<AtomicU64>) -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600,
+                "memory_soft_watermark_mb": 100,
+                "memory_hard_watermark_mb": 200,
+                "memory_recovery_mb": 20,
+                "memory_idle_result_secs": 0
+            }"#,
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("bastionlab-memory-{}", uuid::Uuid::new_v4()));
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+            .with_data_dir(dir)
+            .with_memory_reader(Box::new(MockMemory(resident.clone())))
+    }
+
+    #[test]
+    fn levels_recover_with_hysteresis() {
+        let watermarks = Watermarks {
+            soft: 100,
+            hard: 200,
+            recovery: 20,
+        };
+        let mut pressure = Pressure::Normal;
+        let mut steps = Vec::new();
+        for usage in [99, 100, 199, 210, 190, 181, 179, 95, 81, 79] {
+            pressure = watermarks.level(pressure, usage);
+            steps.push(pressure);
+        }
+        use Pressure::*;
+        assert_eq!(
+            steps,
+            [Normal, Soft, Soft, Hard, Hard, Hard, Soft, Soft, Soft, Normal]
+        );
+        // A drop below both margins at once recovers fully.
+        assert_eq!(watermarks.level(Hard, 10), Normal);
+    }
+
+    #[test]
+    fn pressure_escalates_and_recovers() {
+        let resident = Arc::new(AtomicU64::new(50 * MB));
+        let polars = polars(&resident);
+        let df = df! { "amount" => [1i64, 2, 3] }.unwrap();
+        let upload = polars.insert_df(DataFrameArtifact::new(
+            df.clone(),
+            Policy::allow_by_default(),
+            vec![],
+        ));
+        let mut result = DataFrameArtifact::new(df, Policy::allow_by_default(), vec![]);
+        result.query_details = String::from("a query");
+        let result = polars.insert_df(result);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let (uploads, queries) = (("uploads", Pressure::Soft), ("queries", Pressure::Hard));
+        let rejected = |(what, from): (&str, Pressure)| {
+            polars
+                .memory
+                .check(what, from)
+                .is_err_and(|e| e.code() == Code::ResourceExhausted)
+        };
+
+        assert_eq!(polars.sample_memory().pressure, Pressure::Normal);
+        assert!(!rejected(uploads));
+
+        // Soft: idle results are collected, dataframes compacted and uploads rejected.
+        resident.store(150 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Soft);
+        assert!(rejected(uploads) && !rejected(queries));
+        assert!(polars.get_header(&result).is_err());
+        polars
+            .with_df_artifact_ref(&upload, |artifact| {
+                assert!(!artifact.dtype_changes.is_empty());
+            })
+            .unwrap();
+
+        // Hard: queries are rejected and savable dataframes persisted.
+        resident.store(250 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Hard);
+        assert!(rejected(queries));
+        assert!(artifact_path(polars.data_dir(), &upload).exists());
+
+        // Recovery waits for the margin below each watermark.
+        resident.store(190 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Hard);
+        resident.store(170 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Soft);
+        assert!(!rejected(queries) && rejected(uploads));
+        resident.store(90 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Soft);
+        resident.store(70 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Normal);
+        assert!(!rejected(uploads));
+        polars.get_header(&upload).unwrap();
+
+        std::fs::remove_dir_all(polars.data_dir()).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! NaN and null semantics of float columns.
+//!
+//! Polars keeps them apart: nulls are skipped by aggregations and sort first, whereas NaN
+//! propagates through means and sums and sorts after every other value, infinity included. Both
+//! are different from every value and fail ordered comparisons. A plan picks one of two semantics
+//! with its `nan_as_null` option:
+//!
+//! - Unset (the default), NaN is a value. [`is_nan`] and [`is_finite`] select or exclude it in
+//!   predicates, and the `skip_nan` flag of a polars segment makes its aggregations skip NaN like
+//!   they skip nulls.
+//! - Set, NaN is converted to null wherever it can appear: in the dataframes entering the plan,
+//!   after every node of a polars segment computing columns, and after udfs. Every later step then
+//!   counts, filters, sorts and joins it as a null.
+//!
+//! In both, joins on float keys are reported in the fetch status: keys only match when exactly
+//! equal, so rounding errors silently drop rows, and NaN or null keys all match each other.
+
+use bastionlab_common::common_conversions::lazy_frame_from_logical_plan;
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::visitable::{Visitable, VisitableMut};
+
+/// Whether the values of `expr` are NaN. Nulls are not.
+///
+/// Unlike polars' own `is_nan`, integer columns are accepted, and never NaN.
+pub fn is_nan(expr: Expr) -> Expr {
+    expr.clone().neq(expr)
+}
+
+/// Whether the values of `expr` are neither NaN, infinite nor null.
+///
+/// Written with arithmetic: the closure behind polars' own `is_finite` cannot be sent in a plan.
+pub fn is_finite(expr: Expr) -> Expr {
+    (expr.clone() - expr).eq(lit(0.0))
+}
+
+fn null() -> Expr {
+    Expr::Literal(LiteralValue::Null)
+}
+
+/// `expr`, with its NaN turned into nulls.
+fn skipping_nan(expr: Expr) -> Expr {
+    when(expr.clone().eq(expr.clone()))
+        .then(expr)
+        .otherwise(null())
+}
+
+fn is_float(dtype: &DataType) -> bool {
+    matches!(dtype, DataType::Float32 | DataType::Float64)
+}
+
+/// The float columns, with their NaN turned into nulls.
+fn float_columns_nan_to_null() -> Expr {
+    dtype_cols([DataType::Float32, DataType::Float64]).fill_nan(null())
+}
+
+/// Converts the NaN of the float columns of `df` to nulls.
+pub fn normalize_dataframe(df: DataFrame) -> Result<DataFrame, Status> {
+    if !df.get_columns().iter().any(|s| is_float(s.dtype())) {
+        return Ok(df);
+    }
+    df.lazy()
+        .with_columns([float_columns_nan_to_null()])
+        .collect()
+        .map_err(|e| Status::internal(format!("Could not convert NaN to null: {e}")))
+}
+
+/// Converts NaN to null after every node of `plan` computing columns.
+pub fn normalize_plan(plan: &mut LogicalPlan) -> Result<(), Status> {
+    plan.visit_mut(&mut (), |plan, _| {
+        if let LogicalPlan::Projection { .. }
+        | LogicalPlan::LocalProjection { .. }
+        | LogicalPlan::HStack { .. }
+        | LogicalPlan::Aggregate { .. } = plan
+        {
+            *plan = lazy_frame_from_logical_plan(plan.clone())
+                .with_columns([float_columns_nan_to_null()])
+                .logical_plan;
+        }
+        Ok(())
+    })
+}
+
+fn skip_nan_in_aggregations(expr: &mut Expr) -> Result<(), Status> {
+    expr.visit_mut(&mut (), |expr, _| {
+        if let Expr::Agg(agg) = expr {
+            let input = match agg {
+                AggExpr::Min {
+                    input,
+                    propagate_nans,
+                }
+                | AggExpr::Max {
+                    input,
+                    propagate_nans,
+                } => {
+                    *propagate_nans = false;
+                    input
+                }
+                AggExpr::Mean(input)
+                | AggExpr::Sum(input)
+                | AggExpr::Median(input)
+                | AggExpr::Std(input, _)
+                | AggExpr::Var(input, _)
+                | AggExpr::Quantile { expr: input, .. } => input,
+                // Counts and selections of values keep NaN, as they keep nulls.
+                _ => return Ok(()),
+            };
+            skip_nan_in_aggregations(input)?;
+            **input = skipping_nan((**input).clone());
+        }
+        Ok(())
+    })
+}
+
+/// Makes the means, sums, extrema, quantiles and deviations of `plan` skip NaN like they skip
+/// nulls.
+pub fn skip_nan(plan: &mut LogicalPlan) -> Result<(), Status> {
+    plan.visit_mut(&mut (), |plan, _| {
+        let exprs = match plan {
+            LogicalPlan::Projection { expr, .. } | LogicalPlan::LocalProjection { expr, .. } => {
+                expr
+            }
+            LogicalPlan::HStack { exprs, .. } => exprs,
+            LogicalPlan::Aggregate { aggs, .. } => aggs,
+            LogicalPlan::Selection { predicate, .. } => return skip_nan_in_aggregations(predicate),
+            _ => return Ok(()),
+        };
+        exprs.iter_mut().try_for_each(skip_nan_in_aggregations)
+    })
+}
+
+/// Describes the joins of `plan` on float key columns.
+pub fn float_join_keys(plan: &LogicalPlan) -> Result<Vec<String>, Status> {
+    let mut warnings = Vec::new();
+    plan.visit(&mut warnings, |plan, warnings| {
+        if let LogicalPlan::Join {
+            input_left,
+            input_right,
+            left_on,
+            right_on,
+            ..
+        } = plan
+        {
+            let mut keys = Vec::new();
+            for (input, on) in [(input_left, left_on), (input_right, right_on)] {
+                let schema = lazy_frame_from_logical_plan((**input).clone())
+                    .select(on.clone())
+                    .schema()
+                    .map_err(|e| {
+                        Status::invalid_argument(format!("Could not resolve the join keys: {e}"))
+                    })?;
+                for (name, dtype) in schema.iter() {
+                    if is_float(dtype) && !keys.contains(name) {
+                        keys.push(name.clone());
+                    }
+                }
+            }
+            if !keys.is_empty() {
+                warnings.push(format!(
+                    "Joined on float key columns `{}`: float keys only match when exactly equal, so rounding errors drop matches, and NaN or null keys all match each other. Round or cast the keys to join on them reliably.",
+                    keys.join("`, `")
+                ));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composite_plan::{CompositePlan, CompositePlanSegment};
+    use crate::{access_control::Policy, BastionLabPolars, DataFrameArtifact, FetchStatus};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use std::sync::Arc;
+
+    fn server() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn upload(state: &BastionLabPolars) -> String {
+        let df = df! {
+            "x" => [Some(1.0), Some(f64::NAN), None, Some(3.0), Some(f64::INFINITY)],
+            "g" => ["a", "a", "b", "b", "b"],
+        }
+        .unwrap();
+        state.insert_df(DataFrameArtifact::new(
+            df,
+            Policy::allow_by_default(),
+            Vec::new(),
+        ))
+    }
+
+    /// Runs `f` on `inputs` copies of the uploaded dataframe.
+    async fn run_on(
+        state: &BastionLabPolars,
+        identifier: &str,
+        inputs: usize,
+        nan_as_null: bool,
+        skip_nan: bool,
+        f: impl FnOnce(LazyFrame) -> LazyFrame,
+    ) -> (FetchStatus, DataFrame) {
+        let input = df! { "x" => [0.0], "g" => ["a"] }.unwrap().lazy();
+        let mut segments: Vec<_> = (0..inputs)
+            .map(|_| CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.to_string(),
+            })
+            .collect();
+        segments.push(CompositePlanSegment::PolarsPlanSegment {
+            plan: f(input).logical_plan,
+            skip_nan,
+            resources: None,
+        });
+        let plan = CompositePlan::new(segments).with_nan_as_null(nan_as_null);
+        let result = state.insert_df(plan.run(state, "analyst").unwrap());
+        let delayed = state.get_df(&result, true, "analyst", None, None).unwrap();
+        (
+            delayed.fetch_status,
+            delayed.future.await.unwrap().into_dataframe(),
+        )
+    }
+
+    async fn run(
+        state: &BastionLabPolars,
+        identifier: &str,
+        nan_as_null: bool,
+        skip_nan: bool,
+        f: impl FnOnce(LazyFrame) -> LazyFrame,
+    ) -> (FetchStatus, DataFrame) {
+        run_on(state, identifier, 1, nan_as_null, skip_nan, f).await
+    }
+
+    fn floats(df: &DataFrame, name: &str) -> Vec<String> {
+        df.column(name)
+            .unwrap()
+            .f64()
+            .unwrap()
+            .into_iter()
+            .map(|v| v.map_or_else(|| String::from("null"), |v| v.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn filters() {
+        let state = server();
+        let identifier = upload(&state);
+        let filter = |predicate: Expr| move |lf: LazyFrame| lf.filter(predicate);
+
+        // NaN and nulls are both different from every value, and fail ordered comparisons.
+        let (_, df) = run(
+            &state,
+            &identifier,
+            false,
+            false,
+            filter(col("x").neq(lit(1.0))),
+        )
+        .await;
+        assert_eq!(floats(&df, "x"), ["NaN", "null", "3", "inf"]);
+        let (_, df) = run(
+            &state,
+            &identifier,
+            true,
+            false,
+            filter(col("x").neq(lit(1.0))),
+        )
+        .await;
+        assert_eq!(floats(&df, "x"), ["null", "null", "3", "inf"]);
+        for nan_as_null in [false, true] {
+            let f = filter(col("x").gt(lit(0.0)));
+            let (_, df) = run(&state, &identifier, nan_as_null, false, f).await;
+            assert_eq!(floats(&df, "x"), ["1", "3", "inf"]);
+        }
+
+        let (_, df) = run(&state, &identifier, false, false, filter(is_nan(col("x")))).await;
+        assert_eq!(floats(&df, "x"), ["NaN"]);
+        let (_, df) = run(&state, &identifier, true, false, filter(is_nan(col("x")))).await;
+        assert_eq!(df.height(), 0);
+
+        let (_, df) = run(
+            &state,
+            &identifier,
+            false,
+            false,
+            filter(is_finite(col("x"))),
+        )
+        .await;
+        assert_eq!(floats(&df, "x"), ["1", "3"]);
+        let f = filter(col("x").is_null());
+        let (_, df) = run(&state, &identifier, true, false, f).await;
+        assert_eq!(floats(&df, "x"), ["null", "null"]);
+    }
+
+    #[tokio::test]
+    async fn group_means() {
+        let state = server();
+        let identifier = upload(&state);
+        let means = |lf: LazyFrame| {
+            lf.groupby_stable([col("g")])
+                .agg([col("x").mean(), col("x").count().alias("count")])
+        };
+
+        // NaN propagates through means unless skipped, nulls are always skipped.
+        let (_, df) = run(&state, &identifier, false, false, means).await;
+        assert_eq!(floats(&df, "x"), ["NaN", "inf"]);
+        let (_, df) = run(&state, &identifier, false, true, means).await;
+        assert_eq!(floats(&df, "x"), ["1", "inf"]);
+        let (_, df) = run(&state, &identifier, true, false, means).await;
+        assert_eq!(floats(&df, "x"), ["1", "inf"]);
+        // Counts include both.
+        let counts: Vec<_> = df
+            .column("count")
+            .unwrap()
+            .idx()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(counts, [Some(2), Some(3)]);
+
+        let total = |lf: LazyFrame| lf.select([col("x").filter(col("x").lt(lit(5.0))).sum()]);
+        let (_, df) = run(&state, &identifier, false, true, total).await;
+        assert_eq!(floats(&df, "x"), ["4"]);
+    }
+
+    #[tokio::test]
+    async fn computed_nan_becomes_null() {
+        let state = server();
+        let identifier = upload(&state);
+        // inf - inf is NaN.
+        let diff = |lf: LazyFrame| lf.select([(col("x") - col("x")).alias("d")]);
+
+        let (_, df) = run(&state, &identifier, false, false, diff).await;
+        assert_eq!(floats(&df, "d"), ["0", "NaN", "null", "0", "NaN"]);
+        let (_, df) = run(&state, &identifier, true, false, diff).await;
+        assert_eq!(floats(&df, "d"), ["0", "null", "null", "0", "null"]);
+    }
+
+    #[tokio::test]
+    async fn sorts() {
+        let state = server();
+        let identifier = upload(&state);
+        let sort = |descending| {
+            move |lf: LazyFrame| {
+                lf.sort(
+                    "x",
+                    SortOptions {
+                        descending,
+                        nulls_last: false,
+                    },
+                )
+            }
+        };
+
+        // NaN sorts after every other value, nulls come first and descending sorts reverse both.
+        let (_, df) = run(&state, &identifier, false, false, sort(false)).await;
+        assert_eq!(floats(&df, "x"), ["null", "1", "3", "inf", "NaN"]);
+        let (_, df) = run(&state, &identifier, false, false, sort(true)).await;
+        assert_eq!(floats(&df, "x"), ["NaN", "inf", "3", "1", "null"]);
+        let (_, df) = run(&state, &identifier, true, false, sort(false)).await;
+        assert_eq!(floats(&df, "x"), ["null", "null", "1", "3", "inf"]);
+    }
+
+    #[tokio::test]
+    async fn joins_on_float_keys_warn() {
+        let state = server();
+        let identifier = upload(&state);
+        let self_join = |on: &'static str| {
+            move |lf: LazyFrame| lf.clone().join(lf, [col(on)], [col(on)], JoinType::Inner)
+        };
+
+        // NaN keys match each other, as null keys do.
+        let (status, df) = run_on(&state, &identifier, 2, false, false, self_join("x")).await;
+        assert_eq!(df.height(), 5);
+        match status {
+            FetchStatus::Warning(reason) => {
+                assert!(reason.contains("float key columns `x`"), "{reason}")
+            }
+            status => panic!("Unexpected status {status:?}"),
+        }
+        // As nulls, the NaN key and the null key all match each other.
+        let (status, df) = run_on(&state, &identifier, 2, true, false, self_join("x")).await;
+        assert_eq!(df.height(), 7);
+        assert!(matches!(status, FetchStatus::Warning(_)));
+
+        let (status, _) = run_on(&state, &identifier, 2, false, false, self_join("g")).await;
+        assert_eq!(status, FetchStatus::Ok);
+    }
+}
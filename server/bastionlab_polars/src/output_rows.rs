@@ -0,0 +1,286 @@
+//! Owner-defined caps on the number of rows of query results.
+//!
+//! A cap set in the policy of a dataset applies to every result computed from it. It is applied
+//! once the whole plan has run, so a final sort is kept: "top N" queries still work under a
+//! truncating cap. Fetches and exports both report what the cap did through the fetch status.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputRowsMode {
+    /// Keep the first `limit` rows and warn about the dropped ones.
+    Truncate,
+    /// Refuse to release results with more than `limit` rows.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaxOutputRows {
+    pub limit: u64,
+    pub mode: OutputRowsMode,
+}
+
+/// What a cap did to a result.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CappedOutput {
+    Truncated { limit: u64, dropped: u64 },
+    Rejected { limit: u64, rows: u64 },
+}
+
+impl MaxOutputRows {
+    /// Combines the caps of several datasets into the most restrictive one.
+    pub fn merge(self, other: Self) -> Self {
+        MaxOutputRows {
+            limit: self.limit.min(other.limit),
+            mode: if self.mode == OutputRowsMode::Reject || other.mode == OutputRowsMode::Reject {
+                OutputRowsMode::Reject
+            } else {
+                OutputRowsMode::Truncate
+            },
+        }
+    }
+
+    /// Applies the cap to a result.
+    ///
+    /// Rejected results are kept whole, they are only withheld from fetches.
+    pub fn apply(&self, df: DataFrame) -> (DataFrame, Option<CappedOutput>) {
+        let rows = df.height() as u64;
+        if rows <= self.limit {
+            return (df, None);
+        }
+        match self.mode {
+            OutputRowsMode::Truncate => (
+                // Below the height of `df`, so it fits in a usize.
+                df.head(Some(self.limit as usize)),
+                Some(CappedOutput::Truncated {
+                    limit: self.limit,
+                    dropped: rows - self.limit,
+                }),
+            ),
+            OutputRowsMode::Reject => (
+                df,
+                Some(CappedOutput::Rejected {
+                    limit: self.limit,
+                    rows,
+                }),
+            ),
+        }
+    }
+}
+
+impl CappedOutput {
+    pub fn message(&self) -> String {
+        match self {
+            CappedOutput::Truncated { limit, dropped } => format!(
+                "The result was truncated to the first {limit} rows by the data owner's policy: {dropped} rows were dropped."
+            ),
+            CappedOutput::Rejected { limit, rows } => format!(
+                "The result has {rows} rows but the data owner's policy releases at most {limit}. Aggregate the data further to fetch it."
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composite_plan::{CompositePlan, CompositePlanSegment};
+    use crate::policy_engine::Released;
+    use crate::{access_control::Policy, BastionLabPolars, DataFrameArtifact, FetchStatus};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::export::arrow::ffi;
+    use std::sync::Arc;
+
+    fn server() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn capped_policy(limit: u64, mode: &str) -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "TrueRule"},
+            "unsafe_handling": {"type": "Log"},
+            "savable": true,
+            "max_output_rows": {"limit": limit, "mode": mode},
+        }))
+        .unwrap()
+    }
+
+    fn upload(state: &BastionLabPolars, rows: i64, policy: Policy) -> String {
+        let df = df! { "x" => (0..rows).collect::<Vec<_>>() }.unwrap();
+        state.insert_df(DataFrameArtifact::new(df, policy, Vec::new()))
+    }
+
+    fn entry_point(identifier: &str) -> CompositePlanSegment {
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.to_string(),
+        }
+    }
+
+    /// A polars segment applied to the top of the stack.
+    fn polars_segment(f: impl FnOnce(LazyFrame) -> LazyFrame) -> CompositePlanSegment {
+        let input = df! { "x" => [0i64] }.unwrap().lazy();
+        CompositePlanSegment::PolarsPlanSegment {
+            plan: f(input).logical_plan,
+            skip_nan: false,
+            resources: None,
+        }
+    }
+
+    fn exported_rows(stream: crate::arrow_export::ArrowArrayStream) -> usize {
+        let mut reader =
+            unsafe { ffi::ArrowArrayStreamReader::try_new(stream.into_arrow2()) }.unwrap();
+        let mut rows = 0;
+        while let Some(batch) = unsafe { reader.next() } {
+            rows += batch.unwrap().len();
+        }
+        rows
+    }
+
+    async fn fetch(
+        state: &BastionLabPolars,
+        identifier: &str,
+    ) -> (FetchStatus, Result<DataFrame, tonic::Status>) {
+        let delayed = state
+            .get_df(identifier, true, "analyst", None, None)
+            .unwrap();
+        (
+            delayed.fetch_status,
+            delayed.future.await.map(Released::into_dataframe),
+        )
+    }
+
+    #[test]
+    fn caps_merge_to_the_most_restrictive() {
+        let truncate = |limit| MaxOutputRows {
+            limit,
+            mode: OutputRowsMode::Truncate,
+        };
+        let reject = |limit| MaxOutputRows {
+            limit,
+            mode: OutputRowsMode::Reject,
+        };
+        assert_eq!(truncate(10).merge(truncate(5)), truncate(5));
+        assert_eq!(truncate(5).merge(reject(10)), reject(5));
+        assert_eq!(reject(10).merge(truncate(50)), reject(10));
+    }
+
+    #[test]
+    fn caps_count_rows_past_u32() {
+        let limit = u32::MAX as u64 + 1;
+        let cap = MaxOutputRows {
+            limit,
+            mode: OutputRowsMode::Reject,
+        };
+        let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+        assert_eq!(cap.apply(df).1, None);
+        let rejected = CappedOutput::Rejected {
+            limit,
+            rows: 3 * limit,
+        };
+        assert!(rejected.message().contains("has 12884901888 rows"));
+    }
+
+    #[tokio::test]
+    async fn truncation_happens_after_the_final_sort() {
+        let state = server();
+        let identifier = upload(&state, 100, capped_policy(10, "truncate"));
+
+        let sort = polars_segment(|lf| {
+            lf.sort(
+                "x",
+                SortOptions {
+                    descending: true,
+                    nulls_last: false,
+                },
+            )
+        });
+        let plan = CompositePlan::new(vec![entry_point(&identifier), sort]);
+        let result = state.insert_df(plan.run(&state, "analyst").unwrap());
+
+        let (status, df) = fetch(&state, &result).await;
+        let df = df.unwrap();
+        assert_eq!(df.height(), 10);
+        // The 10 largest values: a "top N" query works under the cap.
+        assert_eq!(df.column("x").unwrap().i64().unwrap().get(0), Some(99));
+        match status {
+            FetchStatus::Warning(reason) => assert!(reason.contains("90 rows were dropped")),
+            status => panic!("Unexpected status {status:?}"),
+        }
+
+        // Exports see the same truncated result.
+        let stream = state.export_arrow(&result, "analyst").await.unwrap();
+        assert_eq!(exported_rows(stream), 10);
+    }
+
+    #[tokio::test]
+    async fn oversized_results_are_rejected() {
+        let state = server();
+        let identifier = upload(&state, 100, capped_policy(10, "reject"));
+
+        let plan = CompositePlan::new(vec![entry_point(&identifier)]);
+        let result = state.insert_df(plan.run(&state, "analyst").unwrap());
+        let (status, df) = fetch(&state, &result).await;
+        match status {
+            FetchStatus::Pending(reason) => assert!(reason.contains("Aggregate the data further")),
+            status => panic!("Unexpected status {status:?}"),
+        }
+        assert_eq!(df.unwrap_err().code(), tonic::Code::PermissionDenied);
+        assert!(state.export_arrow(&result, "analyst").await.is_err());
+
+        // Small enough results go through untouched.
+        let limit = polars_segment(|lf| lf.limit(5));
+        let plan = CompositePlan::new(vec![entry_point(&identifier), limit]);
+        let result = state.insert_df(plan.run(&state, "analyst").unwrap());
+        let (status, df) = fetch(&state, &result).await;
+        assert_eq!(status, FetchStatus::Ok);
+        assert_eq!(df.unwrap().height(), 5);
+    }
+
+    #[tokio::test]
+    async fn inputs_combine_to_the_most_restrictive_cap() {
+        let state = server();
+        let left = upload(&state, 100, capped_policy(50, "truncate"));
+        let right = upload(&state, 100, capped_policy(20, "truncate"));
+        let uncapped = upload(&state, 100, Policy::allow_by_default());
+
+        let stack = |a: &str, b: &str| {
+            CompositePlan::new(vec![
+                entry_point(a),
+                entry_point(b),
+                CompositePlanSegment::StackPlanSegment,
+            ])
+        };
+        let result = state.insert_df(stack(&left, &right).run(&state, "analyst").unwrap());
+        assert_eq!(fetch(&state, &result).await.1.unwrap().height(), 20);
+
+        let result = state.insert_df(stack(&left, &uncapped).run(&state, "analyst").unwrap());
+        assert_eq!(fetch(&state, &result).await.1.unwrap().height(), 50);
+
+        // A rejecting input makes the combined cap reject.
+        let strict = upload(&state, 100, capped_policy(80, "reject"));
+        let result = state.insert_df(stack(&left, &strict).run(&state, "analyst").unwrap());
+        assert!(matches!(
+            fetch(&state, &result).await.0,
+            FetchStatus::Pending(_)
+        ));
+
+        // Results carry the cap over to the queries made on them.
+        let plan = CompositePlan::new(vec![entry_point(&result)]);
+        let derived = state.insert_df(plan.run(&state, "analyst").unwrap());
+        assert!(matches!(
+            fetch(&state, &derived).await.0,
+            FetchStatus::Pending(_)
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+//! Named outputs of composite plans.
+//!
+//! The dataframe left on the stack at the end of a plan is its [`MAIN_SLOT`] output. Segments may
+//! store other dataframes in named slots: an `OutputSegment` stores its input, a `SplitSegment`
+//! its two parts and a `LabelEncodeSegment` the mapping from the values it encoded to their codes.
+//! A `SlotEntryPointSegment` reads a slot stored by a previous segment back onto the stack.
+//!
+//! Every output is stored as a dataframe of its own, whose policy and lineage only derive from
+//! the inputs it was computed from.
+
+use polars::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use tonic::Status;
+
+use crate::reserved::{is_reserved, ENCODE_ROW};
+
+/// Slot of the dataframe left on the stack.
+pub const MAIN_SLOT: &str = "main";
+
+/// Column of the codes in the mappings of label encodings.
+pub const CODE_COLUMN: &str = "code";
+
+pub fn default_mapping_slot() -> String {
+    String::from("mapping")
+}
+
+pub fn default_split_slots() -> [String; 2] {
+    [String::from("train"), String::from("test")]
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::invalid_argument(format!("Polars error in plan outputs: {e}"))
+}
+
+/// Outputs stored by the segments of a plan, in the order they were stored.
+#[derive(Debug)]
+pub struct Slots<T>(Vec<(String, T)>);
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Slots(Vec::new())
+    }
+}
+
+impl<T> Slots<T> {
+    /// Stores `output` in slot `name`, which must be new.
+    pub fn store(&mut self, name: &str, output: T) -> Result<(), Status> {
+        if name.is_empty() || name == MAIN_SLOT || is_reserved(name) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid output slot name `{name}`: slots must be named, and `{MAIN_SLOT}` is the \
+                 dataframe left on the stack"
+            )));
+        }
+        if self.0.iter().any(|(slot, _)| slot == name) {
+            return Err(Status::invalid_argument(format!(
+                "Output slot `{name}` is written more than once"
+            )));
+        }
+        self.0.push((name.to_string(), output));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&T, Status> {
+        self.0
+            .iter()
+            .find(|(slot, _)| slot == name)
+            .map(|(_, output)| output)
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "No output slot `{name}` was stored before it was read, available slots: {}",
+                    self.names()
+                ))
+            })
+    }
+
+    pub fn names(&self) -> String {
+        if self.0.is_empty() {
+            return String::from("none");
+        }
+        self.0
+            .iter()
+            .map(|(slot, _)| slot.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, T)> {
+        self.0.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<(String, T)> {
+        self.0
+    }
+}
+
+/// Splits `df` into its first `train_size` share of rows and the rest, shuffling its rows first
+/// with `seed` if given.
+pub fn split(
+    df: DataFrame,
+    train_size: f64,
+    seed: Option<u64>,
+) -> Result<(DataFrame, DataFrame), Status> {
+    if !(0.0..=1.0).contains(&train_size) {
+        return Err(Status::invalid_argument(format!(
+            "Could not split dataframe: train_size must be between 0 and 1, got {train_size}"
+        )));
+    }
+    let df = match seed {
+        Some(seed) => {
+            let mut indices: Vec<IdxSize> = (0..df.height() as IdxSize).collect();
+            indices.shuffle(&mut StdRng::seed_from_u64(seed));
+            df.take(&IdxCa::from_vec("", indices)).map_err(polars_err)?
+        }
+        None => df,
+    };
+    let train_rows = (df.height() as f64 * train_size).round() as usize;
+    let test_rows = df.height() - train_rows;
+    Ok((
+        df.slice(0, train_rows),
+        df.slice(train_rows as i64, test_rows),
+    ))
+}
+
+/// Replaces the values of `column` by their index among its sorted distinct values, returning
+/// the encoded dataframe and the mapping from values (in `column`) to codes (in
+/// [`CODE_COLUMN`], of the dtype polars indexes rows with). Nulls stay null and are left out of
+/// the mapping.
+pub fn label_encode(mut df: DataFrame, column: &str) -> Result<(DataFrame, DataFrame), Status> {
+    let values = df.column(column).map_err(|_| {
+        Status::invalid_argument(format!(
+            "Could not label encode: no column `{column}` in data frame"
+        ))
+    })?;
+    let distinct = values
+        .drop_nulls()
+        .unique()
+        .map_err(polars_err)?
+        .sort(false);
+    let codes = Series::new(
+        CODE_COLUMN,
+        (0..distinct.len() as IdxSize).collect::<Vec<_>>(),
+    );
+    let mapping = DataFrame::new(vec![distinct, codes]).map_err(polars_err)?;
+
+    let rows = df
+        .select([column])
+        .and_then(|df| df.with_row_count(ENCODE_ROW, None))
+        .map_err(polars_err)?;
+    let joined = rows
+        .join(&mapping, [column], [column], JoinType::Left, None)
+        .and_then(|df| df.sort([ENCODE_ROW], false))
+        .map_err(polars_err)?;
+    let mut encoded = joined.column(CODE_COLUMN).map_err(polars_err)?.clone();
+    encoded.rename(column);
+    df.replace(column, encoded).map_err(polars_err)?;
+    Ok((df, mapping))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_written_once_and_list_themselves_when_missing() {
+        let mut slots = Slots::default();
+        slots.store("train", 1).unwrap();
+        slots.store("test", 2).unwrap();
+        assert_eq!(*slots.get("test").unwrap(), 2);
+        assert!(slots.store("train", 3).is_err());
+        assert!(slots.store(MAIN_SLOT, 3).is_err());
+
+        let err = slots.get("mapping").unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(err.message().contains("train, test"), "{}", err.message());
+    }
+
+    #[test]
+    fn splits_keep_every_row_once() {
+        let df = df!("a" => (0..10).collect::<Vec<i32>>()).unwrap();
+        let (train, test) = split(df.clone(), 0.7, None).unwrap();
+        assert_eq!((train.height(), test.height()), (7, 3));
+        assert!(train.vstack(&test).unwrap().frame_equal(&df));
+
+        let (train, test) = split(df.clone(), 0.7, Some(42)).unwrap();
+        let (again, _) = split(df, 0.7, Some(42)).unwrap();
+        assert!(train.frame_equal(&again));
+        let mut rows: Vec<_> = train
+            .vstack(&test)
+            .unwrap()
+            .column("a")
+            .unwrap()
+            .i32()
+            .unwrap()
+            .into_no_null_iter()
+            .collect();
+        rows.sort();
+        assert_eq!(rows, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn label_encoding_maps_sorted_values_to_codes() {
+        let df = df!(
+            "city" => [Some("Paris"), Some("Lyon"), None, Some("Paris")],
+            "n" => [1, 2, 3, 4],
+        )
+        .unwrap();
+        let (encoded, mapping) = label_encode(df, "city").unwrap();
+        assert_eq!(encoded.get_column_names(), ["city", "n"]);
+        let codes: Vec<_> = encoded
+            .column("city")
+            .unwrap()
+            .idx()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(codes, [Some(1), Some(0), None, Some(1)]);
+        assert!(mapping.frame_equal(
+            &df!("city" => ["Lyon", "Paris"], CODE_COLUMN => [0 as IdxSize, 1]).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use bastionlab_common::atomic_file;
+use polars::prelude::*;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::aliases::Alias;
+use crate::differential_privacy::SpentBudget;
+use crate::tenant_keys::{encryption_of, tenant_of, TenantKeyring};
+use crate::watermark::FetchRecord;
+use crate::DataFrameArtifact;
+
+/// Magic bytes of persisted artifacts, followed by the format version.
+const MAGIC: &[u8; 4] = b"BLDF";
+const FORMAT_VERSION: u32 = 1;
+
+/// Extension of persisted artifacts. Artifacts persisted as JSON by older versions use `.json`.
+pub const ARTIFACT_EXTENSION: &str = "bldf";
+const LEGACY_EXTENSION: &str = "json";
+/// File holding the identifier aliases, see [`crate::aliases`].
+pub const ALIASES_FILE: &str = "aliases.table";
+/// File holding the privacy budgets spent, see [`crate::differential_privacy`].
+pub const BUDGETS_FILE: &str = "privacy_budgets.table";
+/// File holding the watermarked fetches, see [`crate::watermark`].
+pub const WATERMARKS_FILE: &str = "watermarks.table";
+
+/// Columns whose zstd sample does not shrink below this ratio are stored uncompressed.
+const INCOMPRESSIBLE_RATIO: f64 = 0.95;
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// How persisted artifacts are compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PersistenceSettings {
+    /// zstd level of compressed columns, 0 disables compression.
+    pub zstd_level: i32,
+    /// Utf8 columns whose distinct/total ratio is at most this value are dictionary-encoded.
+    pub dictionary_ratio: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Compression {
+    None,
+    Zstd { level: i32 },
+}
+
+/// How one column is stored, recorded in the manifest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnEntry {
+    pub name: String,
+    /// The column was cast from Utf8 to Categorical before being written.
+    pub dictionary: bool,
+    pub compression: Compression,
+    /// Length of the stored column in bytes.
+    pub length: u64,
+    /// SHA-256 of the stored column, missing in artifacts written before checksums.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// Everything but the column data, stored as JSON at the start of the file.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    artifact: DataFrameArtifact,
+    columns: Vec<ColumnEntry>,
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error while persisting a dataframe: {e}"))
+}
+
+fn io_err(e: std::io::Error) -> Status {
+    Status::internal(format!("Could not access persisted dataframes: {e}"))
+}
+
+fn corrupted(reason: &str) -> Status {
+    Status::data_loss(format!("Corrupted persisted dataframe: {reason}"))
+}
+
+fn checksum(data: &[u8]) -> String {
+    hex::encode(digest(&SHA256, data))
+}
+
+fn column_ipc(series: Series) -> Result<Vec<u8>, Status> {
+    let mut buf = Vec::new();
+    IpcWriter::new(&mut buf)
+        .finish(&mut DataFrame::new(vec![series]).map_err(polars_err)?)
+        .map_err(polars_err)?;
+    Ok(buf)
+}
+
+/// Picks the encoding of a column from its statistics and, for compression, a sample of its data.
+fn encode_column(
+    series: &Series,
+    settings: &PersistenceSettings,
+) -> Result<(ColumnEntry, Vec<u8>), Status> {
+    let name = series.name().to_string();
+    let dictionary = series.dtype() == &DataType::Utf8
+        && !series.is_empty()
+        && series.n_unique().map_err(polars_err)? as f64 / series.len() as f64
+            <= settings.dictionary_ratio;
+    let series = if dictionary {
+        series
+            .cast(&DataType::Categorical(None))
+            .map_err(polars_err)?
+    } else {
+        series.clone()
+    };
+    let raw = column_ipc(series)?;
+
+    let mut compression = Compression::None;
+    if settings.zstd_level > 0 {
+        let sample = &raw[..raw.len().min(SAMPLE_SIZE)];
+        let compressed = zstd::bulk::compress(sample, 1).map_err(io_err)?;
+        if (compressed.len() as f64) < sample.len() as f64 * INCOMPRESSIBLE_RATIO {
+            compression = Compression::Zstd {
+                level: settings.zstd_level,
+            };
+        }
+    }
+    let data = match compression {
+        Compression::None => raw,
+        Compression::Zstd { level } => zstd::bulk::compress(&raw, level).map_err(io_err)?,
+    };
+
+    Ok((
+        ColumnEntry {
+            name,
+            dictionary,
+            compression,
+            length: data.len() as u64,
+            checksum: Some(checksum(&data)),
+        },
+        data,
+    ))
+}
+
+/// Serializes an artifact, compressing each of its columns according to `settings`.
+pub fn encode_artifact(
+    artifact: &DataFrameArtifact,
+    settings: &PersistenceSettings,
+) -> Result<Vec<u8>, Status> {
+    let mut columns = Vec::new();
+    let mut blobs = Vec::new();
+    for series in artifact.dataframe.get_columns() {
+        let (entry, data) = encode_column(series, settings)?;
+        columns.push(entry);
+        blobs.push(data);
+    }
+
+    let manifest = Manifest {
+        artifact: DataFrameArtifact {
+            dataframe: DataFrame::default(),
+            ..artifact.clone()
+        },
+        columns,
+    };
+    let manifest = serde_json::to_vec(&manifest)
+        .map_err(|e| Status::internal(format!("Could not serialize the manifest: {e}")))?;
+
+    let mut buf =
+        Vec::with_capacity(16 + manifest.len() + blobs.iter().map(Vec::len).sum::<usize>());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&manifest);
+    for blob in blobs {
+        buf.extend_from_slice(&blob);
+    }
+    Ok(buf)
+}
+
+/// Reads an artifact written by [`encode_artifact`], with any settings.
+pub fn decode_artifact(buf: &[u8]) -> Result<DataFrameArtifact, Status> {
+    if buf.len() < 16 || &buf[..4] != MAGIC {
+        return Err(corrupted("missing header"));
+    }
+    let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(corrupted(&format!("unknown format version {version}")));
+    }
+    let manifest_len = u64::from_le_bytes(buf[8..16].try_into().unwrap()) as usize;
+    let manifest = buf
+        .get(16..16 + manifest_len)
+        .ok_or_else(|| corrupted("truncated manifest"))?;
+    let manifest: Manifest =
+        serde_json::from_slice(manifest).map_err(|e| corrupted(&e.to_string()))?;
+
+    let mut offset = 16 + manifest_len;
+    let mut columns = Vec::with_capacity(manifest.columns.len());
+    for entry in manifest.columns.iter() {
+        let data = buf
+            .get(offset..offset + entry.length as usize)
+            .ok_or_else(|| corrupted("truncated column"))?;
+        offset += entry.length as usize;
+        if let Some(expected) = &entry.checksum {
+            if &checksum(data) != expected {
+                return Err(corrupted(&format!(
+                    "checksum mismatch in column {}",
+                    entry.name
+                )));
+            }
+        }
+
+        let raw = match entry.compression {
+            Compression::None => data.to_vec(),
+            Compression::Zstd { .. } => zstd::stream::decode_all(data).map_err(io_err)?,
+        };
+        let df = IpcReader::new(Cursor::new(raw))
+            .finish()
+            .map_err(|e| corrupted(&e.to_string()))?;
+        let mut series = df
+            .get_columns()
+            .first()
+            .cloned()
+            .ok_or_else(|| corrupted("empty column"))?;
+        if entry.dictionary {
+            series = series.cast(&DataType::Utf8).map_err(polars_err)?;
+        }
+        series.rename(&entry.name);
+        columns.push(series);
+    }
+    if offset != buf.len() {
+        return Err(corrupted("trailing data after the last column"));
+    }
+
+    let mut artifact = manifest.artifact;
+    artifact.dataframe = DataFrame::new(columns).map_err(|e| corrupted(&e.to_string()))?;
+    Ok(artifact)
+}
+
+/// Loads a persisted artifact from either the current or the legacy JSON format, decrypting it
+/// with `keys` if it is encrypted.
+pub fn load_artifact(path: &Path, keys: &TenantKeyring) -> Result<DataFrameArtifact, Status> {
+    let buf = fs::read(path).map_err(io_err)?;
+    if path.extension().is_some_and(|e| e == LEGACY_EXTENSION) {
+        serde_json::from_slice(&buf).map_err(|e| corrupted(&e.to_string()))
+    } else {
+        decode_artifact(&keys.open(&buf)?)
+    }
+}
+
+/// Writes an artifact to `dir`, encrypted under the key of its tenant if `keys` is enabled,
+/// replacing any previous version of it atomically.
+///
+/// Returns the size of the written file.
+pub fn store_artifact(
+    dir: &Path,
+    identifier: &str,
+    artifact: &DataFrameArtifact,
+    settings: &PersistenceSettings,
+    keys: &TenantKeyring,
+) -> Result<u64, Status> {
+    let buf = keys.seal(tenant_of(artifact), encode_artifact(artifact, settings)?)?;
+    atomic_file::write(&artifact_path(dir, identifier), &buf).map_err(io_err)?;
+
+    // The new file replaces any copy in the legacy format.
+    let legacy = legacy_path(dir, identifier);
+    if legacy.exists() {
+        fs::remove_file(legacy).map_err(io_err)?;
+    }
+    Ok(buf.len() as u64)
+}
+
+pub fn artifact_path(dir: &Path, identifier: &str) -> PathBuf {
+    dir.join(format!("{identifier}.{ARTIFACT_EXTENSION}"))
+}
+
+pub fn legacy_path(dir: &Path, identifier: &str) -> PathBuf {
+    dir.join(format!("{identifier}.{LEGACY_EXTENSION}"))
+}
+
+pub fn store_aliases(dir: &Path, aliases: &HashMap<String, Alias>) -> Result<(), Status> {
+    let buf = serde_json::to_vec(aliases)
+        .map_err(|e| Status::internal(format!("Could not serialize the aliases: {e}")))?;
+    atomic_file::write(&dir.join(ALIASES_FILE), &buf).map_err(io_err)
+}
+
+/// Loads the aliases persisted in `dir`, if any.
+pub fn load_aliases(dir: &Path) -> Result<HashMap<String, Alias>, Status> {
+    let path = dir.join(ALIASES_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let buf = fs::read(path).map_err(io_err)?;
+    serde_json::from_slice(&buf).map_err(|e| corrupted(&e.to_string()))
+}
+
+/// Persists the privacy budgets spent in `dir`, creating it if needed: unlike the aliases, they
+/// matter before any dataframe is persisted.
+pub fn store_budgets(dir: &Path, budgets: &[SpentBudget]) -> Result<(), Status> {
+    let buf = serde_json::to_vec(budgets)
+        .map_err(|e| Status::internal(format!("Could not serialize the privacy budgets: {e}")))?;
+    fs::create_dir_all(dir).map_err(io_err)?;
+    atomic_file::write(&dir.join(BUDGETS_FILE), &buf).map_err(io_err)
+}
+
+/// Loads the privacy budgets persisted in `dir`, if any.
+pub fn load_budgets(dir: &Path) -> Result<Vec<SpentBudget>, Status> {
+    let path = dir.join(BUDGETS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let buf = fs::read(path).map_err(io_err)?;
+    serde_json::from_slice(&buf).map_err(|e| corrupted(&e.to_string()))
+}
+
+/// Persists the records of the watermarked fetches in `dir`, creating it if needed.
+pub fn store_watermarks(dir: &Path, records: &[FetchRecord]) -> Result<(), Status> {
+    let buf = serde_json::to_vec(records)
+        .map_err(|e| Status::internal(format!("Could not serialize the watermarks: {e}")))?;
+    fs::create_dir_all(dir).map_err(io_err)?;
+    atomic_file::write(&dir.join(WATERMARKS_FILE), &buf).map_err(io_err)
+}
+
+/// Loads the records of the watermarked fetches persisted in `dir`, if any.
+pub fn load_watermarks(dir: &Path) -> Result<Vec<FetchRecord>, Status> {
+    let path = dir.join(WATERMARKS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let buf = fs::read(path).map_err(io_err)?;
+    serde_json::from_slice(&buf).map_err(|e| corrupted(&e.to_string()))
+}
+
+/// Lists the persisted artifacts of `dir` as (identifier, path) pairs.
+///
+/// When an artifact exists in both formats, only the current one is returned.
+pub fn list_artifacts(dir: &Path) -> Result<Vec<(String, PathBuf)>, Status> {
+    let mut res: Vec<(String, PathBuf)> = Vec::new();
+    for file in fs::read_dir(dir).map_err(io_err)? {
+        let path = file.map_err(io_err)?.path();
+        let (identifier, extension) = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(ext)) => (stem.to_string_lossy().to_string(), ext.to_owned()),
+            _ => continue,
+        };
+        if extension == ARTIFACT_EXTENSION {
+            res.retain(|(id, _)| id != &identifier);
+            res.push((identifier, path));
+        } else if extension == LEGACY_EXTENSION && !artifact_path(dir, &identifier).exists() {
+            res.push((identifier, path));
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+/// What the admin `inspect` command shows of a persisted artifact.
+#[derive(Debug)]
+pub struct ArtifactSummary {
+    /// Size of the file in bytes.
+    pub size: u64,
+    pub rows: usize,
+    /// Schema declared on upload, before any storage optimization.
+    pub schema: Schema,
+    /// Incremented on every append.
+    pub version: u64,
+    pub semantics_version: u32,
+    /// The query that produced the dataframe, or `uploaded dataframe`.
+    pub origin: String,
+    /// The tenant and key version the file is encrypted under, if it is.
+    pub encryption: Option<(String, u32)>,
+}
+
+/// The tenant and key version the artifact at `path` is encrypted under, if it is.
+pub fn artifact_encryption(path: &Path) -> Result<Option<(String, u32)>, Status> {
+    if path.extension().is_some_and(|e| e == LEGACY_EXTENSION) {
+        return Ok(None);
+    }
+    encryption_of(&fs::read(path).map_err(io_err)?)
+}
+
+/// Loads a persisted artifact as the server does on startup and summarizes it.
+pub fn inspect_artifact(path: &Path, keys: &TenantKeyring) -> Result<ArtifactSummary, Status> {
+    let size = fs::metadata(path).map_err(io_err)?.len();
+    let encryption = artifact_encryption(path)?;
+    let artifact = load_artifact(path, keys)?;
+    Ok(ArtifactSummary {
+        size,
+        encryption,
+        rows: artifact.dataframe.height(),
+        schema: artifact.declared_schema(),
+        version: artifact.version,
+        semantics_version: artifact.semantics_version,
+        origin: artifact.query_details,
+    })
+}
+
+/// File formats persisted dataframes can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Parquet,
+    Ipc,
+}
+
+/// Writes the declared dataframe of a persisted artifact to `out`, bypassing its policy.
+///
+/// Returns the number of rows written.
+pub fn export_artifact(
+    path: &Path,
+    out: &Path,
+    format: ExportFormat,
+    keys: &TenantKeyring,
+) -> Result<usize, Status> {
+    let mut df = load_artifact(path, keys)?.declared_dataframe()?;
+    let mut buf = Vec::new();
+    match format {
+        ExportFormat::Parquet => {
+            ParquetWriter::new(&mut buf)
+                .finish(&mut df)
+                .map_err(polars_err)?;
+        }
+        ExportFormat::Ipc => IpcWriter::new(&mut buf)
+            .finish(&mut df)
+            .map_err(polars_err)?,
+    }
+    atomic_file::write(out, &buf).map_err(io_err)?;
+    Ok(df.height())
+}
+
+#[derive(Debug, Default)]
+pub struct RecompressReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub rewritten: Vec<String>,
+}
+
+/// Rewrites every persisted artifact of `dir` under `settings`.
+pub fn recompress_all(
+    dir: &Path,
+    settings: &PersistenceSettings,
+    keys: &TenantKeyring,
+) -> Result<RecompressReport, Status> {
+    let mut report = RecompressReport::default();
+    for (identifier, path) in list_artifacts(dir)? {
+        let before = fs::metadata(&path).map_err(io_err)?.len();
+        let artifact = load_artifact(&path, keys)?;
+        report.bytes_after += store_artifact(dir, &identifier, &artifact, settings, keys)?;
+        report.bytes_before += before;
+        report.rewritten.push(identifier);
+    }
+    Ok(report)
+}
+
+/// Re-encrypts the artifacts of `tenant` under the active version of its key, after a rotation.
+///
+/// Returns the identifiers of the rewritten artifacts.
+pub fn reencrypt_tenant(
+    dir: &Path,
+    tenant: &str,
+    keys: &TenantKeyring,
+) -> Result<Vec<String>, Status> {
+    let mut rewritten = Vec::new();
+    for (identifier, path) in list_artifacts(dir)? {
+        if !artifact_encryption(&path)?.is_some_and(|(owner, _)| owner == tenant) {
+            continue;
+        }
+        let buf = keys.open(&fs::read(&path).map_err(io_err)?)?;
+        atomic_file::write(&path, &keys.seal(tenant, buf)?).map_err(io_err)?;
+        rewritten.push(identifier);
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::{mpsc, Arc, Mutex};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/persistence");
+
+    /// Settings combinations the fixtures were written with, by file name.
+    const COMBINATIONS: [(&str, i32, f64); 4] = [
+        ("zstd3_dictionary", 3, 0.1),
+        ("zstd19", 19, 0.0),
+        ("raw_dictionary", 0, 0.5),
+        ("raw", 0, 0.0),
+    ];
+
+    fn dataset() -> DataFrame {
+        let mut rng = StdRng::seed_from_u64(42);
+        // Large enough for the random column not to be dominated by the IPC metadata.
+        let n = 1200;
+        let cities = ["paris", "lyon", "nice"];
+        df! {
+            "id" => (0..n as i64).collect::<Vec<_>>(),
+            "city" => (0..n).map(|i| cities[i % 3]).collect::<Vec<_>>(),
+            "noise" => (0..n).map(|_| rng.gen::<u64>()).collect::<Vec<_>>(),
+            "score" => (0..n).map(|i| (i % 7 != 0).then(|| i as f64 / 10.0)).collect::<Vec<_>>(),
+        }
+        .unwrap()
+    }
+
+    fn artifact() -> DataFrameArtifact {
+        DataFrameArtifact::new(
+            dataset(),
+            Policy::allow_by_default(),
+            vec![String::from("city")],
+        )
+    }
+
+    fn optimized_artifact() -> DataFrameArtifact {
+        let mut artifact = artifact();
+        artifact.optimize_storage(false).unwrap();
+        artifact
+    }
+
+    fn settings(zstd_level: i32, dictionary_ratio: f64) -> PersistenceSettings {
+        PersistenceSettings {
+            zstd_level,
+            dictionary_ratio,
+        }
+    }
+
+    fn assert_same(loaded: &DataFrameArtifact, expected: &DataFrameArtifact) {
+        // Categorical columns are compared through the declared dataframe, as their
+        // dictionaries do not survive a round trip.
+        assert_eq!(loaded.dataframe.schema(), expected.dataframe.schema());
+        assert_eq!(loaded.blacklist, expected.blacklist);
+        assert_eq!(loaded.dtype_changes, expected.dtype_changes);
+        assert!(loaded
+            .declared_dataframe()
+            .unwrap()
+            .frame_equal_missing(&dataset()));
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bastionlab-persist-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Regenerates the fixtures: `cargo test -p bastionlab_polars write_fixtures -- --ignored`.
+    ///
+    /// Only do this when adding a combination, the existing files stand for artifacts already on disk.
+    #[test]
+    #[ignore]
+    fn write_fixtures() {
+        let dir = Path::new(FIXTURES);
+        fs::create_dir_all(dir).unwrap();
+        for (name, level, ratio) in COMBINATIONS {
+            let buf = encode_artifact(&artifact(), &settings(level, ratio)).unwrap();
+            fs::write(dir.join(format!("{name}.bldf")), buf).unwrap();
+        }
+        let buf = encode_artifact(&optimized_artifact(), &settings(1, 0.1)).unwrap();
+        fs::write(dir.join("optimized_zstd1.bldf"), buf).unwrap();
+
+        // Artifacts persisted before the binary format, which also predate the newer fields.
+        let mut legacy = serde_json::to_value(artifact()).unwrap();
+        for field in ["dtype_changes", "quality", "version", "capped_output"] {
+            legacy.as_object_mut().unwrap().remove(field);
+        }
+        for field in [
+            "probing_response",
+            "watermark",
+            "exact_columns",
+            "max_output_rows",
+        ] {
+            legacy["policy"].as_object_mut().unwrap().remove(field);
+        }
+        fs::write(dir.join("legacy.json"), legacy.to_string()).unwrap();
+    }
+
+    #[test]
+    fn fixtures_of_every_setting_load() {
+        let dir = Path::new(FIXTURES);
+        for (name, _, _) in COMBINATIONS {
+            let loaded = load_artifact(
+                &dir.join(format!("{name}.bldf")),
+                &TenantKeyring::disabled(),
+            )
+            .unwrap();
+            assert_same(&loaded, &artifact());
+        }
+        let loaded = load_artifact(
+            &dir.join("optimized_zstd1.bldf"),
+            &TenantKeyring::disabled(),
+        )
+        .unwrap();
+        assert_same(&loaded, &optimized_artifact());
+        let loaded = load_artifact(&dir.join("legacy.json"), &TenantKeyring::disabled()).unwrap();
+        assert_same(&loaded, &artifact());
+    }
+
+    #[test]
+    fn encodings_follow_column_statistics() {
+        let buf = encode_artifact(&artifact(), &settings(3, 0.1)).unwrap();
+        let len = u64::from_le_bytes(buf[8..16].try_into().unwrap()) as usize;
+        let manifest: Manifest = serde_json::from_slice(&buf[16..16 + len]).unwrap();
+        let entry = |name: &str| {
+            manifest
+                .columns
+                .iter()
+                .find(|c| c.name == name)
+                .unwrap()
+                .clone()
+        };
+
+        assert!(entry("city").dictionary);
+        assert!(!entry("id").dictionary);
+        assert_eq!(entry("id").compression, Compression::Zstd { level: 3 });
+        // Random bits do not compress, so they are stored as is.
+        assert_eq!(entry("noise").compression, Compression::None);
+
+        let raw = encode_artifact(&artifact(), &settings(0, 0.0)).unwrap();
+        assert!(buf.len() < raw.len());
+        assert_same(&decode_artifact(&buf).unwrap(), &artifact());
+    }
+
+    #[test]
+    fn corrupted_files_are_rejected() {
+        let buf = encode_artifact(&artifact(), &settings(3, 0.1)).unwrap();
+        assert!(decode_artifact(&buf[..buf.len() - 10]).is_err());
+        assert!(decode_artifact(b"not an artifact").is_err());
+        let mut future = buf.clone();
+        future[4] = 2;
+        assert!(decode_artifact(&future).is_err());
+
+        // Flipping a bit of a column is caught by its checksum, even in uncompressed columns.
+        let raw = encode_artifact(&artifact(), &settings(0, 0.0)).unwrap();
+        let mut flipped = raw.clone();
+        let last = flipped.len() - 100;
+        flipped[last] ^= 1;
+        let err = decode_artifact(&flipped).unwrap_err();
+        assert!(
+            err.message().contains("checksum mismatch"),
+            "{}",
+            err.message()
+        );
+        let mut trailing = raw;
+        trailing.extend_from_slice(b"garbage");
+        assert!(decode_artifact(&trailing).is_err());
+    }
+
+    #[test]
+    fn recompression_rewrites_every_artifact() {
+        let dir = temp_dir();
+        fs::copy(
+            Path::new(FIXTURES).join("legacy.json"),
+            legacy_path(&dir, "old"),
+        )
+        .unwrap();
+        store_artifact(
+            &dir,
+            "new",
+            &artifact(),
+            &settings(0, 0.0),
+            &TenantKeyring::disabled(),
+        )
+        .unwrap();
+
+        let report = recompress_all(&dir, &settings(9, 0.1), &TenantKeyring::disabled()).unwrap();
+        assert_eq!(report.rewritten, vec!["new", "old"]);
+        assert!(report.bytes_after < report.bytes_before);
+
+        assert!(!legacy_path(&dir, "old").exists());
+        let listed = list_artifacts(&dir).unwrap();
+        assert_eq!(listed.len(), 2);
+        for (_, path) in listed {
+            assert_same(
+                &load_artifact(&path, &TenantKeyring::disabled()).unwrap(),
+                &artifact(),
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dataframes_are_inserted_while_one_is_written() {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        let dir = temp_dir();
+        let mut polars = BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+            .with_data_dir(dir.clone());
+        let (writing, held) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (writing, released) = (Mutex::new(writing), Mutex::new(released));
+        polars.store_hook = Some(Arc::new(move |_: &str| {
+            writing.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+        }));
+        let identifier = polars.insert_df(artifact());
+        let persisting = {
+            let (polars, identifier) = (polars.clone(), identifier.clone());
+            std::thread::spawn(move || polars.persist_df(&identifier))
+        };
+
+        // The write is held midway, without the dataframes locked.
+        held.recv().unwrap();
+        assert!(polars.dataframes.try_write().is_ok());
+        let other = polars.insert_df(artifact());
+        assert!(polars.dataframes.read().unwrap().contains_key(&other));
+
+        release.send(()).unwrap();
+        persisting.join().unwrap().unwrap();
+        assert!(artifact_path(&dir, &identifier).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
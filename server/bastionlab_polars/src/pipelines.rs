@@ -0,0 +1,585 @@
+//! Named pipelines: composite plans registered once and run again with parameters.
+//!
+//! A pipeline is a serialized composite plan in which some values are replaced by placeholders
+//! `{"$param": "<name>"}`. A placeholder stands for a literal expression, or for the identifier of
+//! an entry point if its parameter is of type `Identifier`. Parameter values are checked against
+//! the declared types and substituted in the parsed plan as typed values, they are never spliced
+//! into the plan text.
+//!
+//! Registering a pipeline again under the same name adds a version. Queries reference pipelines as
+//! `pipeline:<name>` to run the latest version, or `pipeline:<name>@<version>` to pin one.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::Status;
+
+use crate::composite_plan::CompositePlan;
+
+/// Prefix of the `composite_plan` of queries that run a pipeline.
+pub const PIPELINE_PREFIX: &str = "pipeline:";
+
+const PLACEHOLDER: &str = "$param";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParameterType {
+    Int,
+    Float,
+    Str,
+    Bool,
+    /// The identifier of a dataframe, for entry points.
+    Identifier,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ParameterType,
+}
+
+/// Who can see and run a pipeline, besides its author.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(tag = "type")]
+pub enum Visibility {
+    #[default]
+    Private,
+    /// The users with these identifiers.
+    Group {
+        members: Vec<String>,
+    },
+    Public,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipelineVersion {
+    pub name: String,
+    /// Starts at 1.
+    pub version: u32,
+    pub author: String,
+    pub visibility: Visibility,
+    pub parameters: Vec<Parameter>,
+    /// The composite plan, with placeholders.
+    pub plan: Value,
+}
+
+impl PipelineVersion {
+    fn visible_to(&self, user_id: &str) -> bool {
+        self.author == user_id
+            || match &self.visibility {
+                Visibility::Private => false,
+                Visibility::Group { members } => members.iter().any(|m| m == user_id),
+                Visibility::Public => true,
+            }
+    }
+
+    /// Substitutes the parameters in the plan.
+    ///
+    /// Every declared parameter must be given a value of its type, and no other parameter.
+    pub fn instantiate(&self, values: &HashMap<String, String>) -> Result<Value, Status> {
+        let mut typed = HashMap::new();
+        for param in self.parameters.iter() {
+            let raw = values.get(&param.name).ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Missing parameter {} of pipeline {} version {}",
+                    param.name, self.name, self.version
+                ))
+            })?;
+            typed.insert(param.name.as_str(), typed_value(param, raw)?);
+        }
+        if let Some(unknown) = values.keys().find(|k| !typed.contains_key(k.as_str())) {
+            return Err(Status::invalid_argument(format!(
+                "Pipeline {} version {} has no parameter {unknown}",
+                self.name, self.version
+            )));
+        }
+
+        let mut plan = self.plan.clone();
+        substitute(&mut plan, &typed);
+        Ok(plan)
+    }
+}
+
+/// Parses a `pipeline:<name>[@<version>]` reference.
+pub fn parse_reference(reference: &str) -> Option<Result<(&str, Option<u32>), Status>> {
+    let reference = reference.strip_prefix(PIPELINE_PREFIX)?;
+    Some(match reference.rsplit_once('@') {
+        Some((name, version)) => version
+            .parse()
+            .map(|version| (name, Some(version)))
+            .map_err(|_| Status::invalid_argument(format!("Invalid pipeline version: {version}"))),
+        None => Ok((reference, None)),
+    })
+}
+
+fn type_mismatch(param: &Parameter, raw: &str) -> Status {
+    Status::invalid_argument(format!(
+        "Parameter {} must be of type {:?}, got {raw}",
+        param.name, param.ty
+    ))
+}
+
+/// The JSON substituted for a placeholder of `param`, given its JSON-encoded value.
+fn typed_value(param: &Parameter, raw: &str) -> Result<Value, Status> {
+    let value: Value = serde_json::from_str(raw).map_err(|_| type_mismatch(param, raw))?;
+    let literal = match (param.ty, value) {
+        (ParameterType::Identifier, Value::String(identifier)) => {
+            return Ok(Value::String(identifier))
+        }
+        (ParameterType::Int, Value::Number(n)) => {
+            LiteralValue::Int64(n.as_i64().ok_or_else(|| type_mismatch(param, raw))?)
+        }
+        (ParameterType::Float, Value::Number(n)) => {
+            LiteralValue::Float64(n.as_f64().ok_or_else(|| type_mismatch(param, raw))?)
+        }
+        (ParameterType::Str, Value::String(s)) => LiteralValue::Utf8(s),
+        (ParameterType::Bool, Value::Bool(b)) => LiteralValue::Boolean(b),
+        _ => return Err(type_mismatch(param, raw)),
+    };
+    serde_json::to_value(Expr::Literal(literal))
+        .map_err(|e| Status::internal(format!("Could not serialize parameter {}: {e}", param.name)))
+}
+
+/// A value of the type of `param`, used to check that the template is a valid plan.
+fn sample_value(param: &Parameter) -> String {
+    String::from(match param.ty {
+        ParameterType::Int => "0",
+        ParameterType::Float => "0.5",
+        ParameterType::Str | ParameterType::Identifier => "\"\"",
+        ParameterType::Bool => "false",
+    })
+}
+
+fn placeholder(value: &Value) -> Option<&str> {
+    match value {
+        Value::Object(map) if map.len() == 1 => map.get(PLACEHOLDER)?.as_str(),
+        _ => None,
+    }
+}
+
+fn placeholders<'a>(value: &'a Value, found: &mut HashSet<&'a str>) {
+    if let Some(name) = placeholder(value) {
+        found.insert(name);
+        return;
+    }
+    match value {
+        Value::Object(map) => map.values().for_each(|v| placeholders(v, found)),
+        Value::Array(values) => values.iter().for_each(|v| placeholders(v, found)),
+        _ => (),
+    }
+}
+
+/// Identifiers of the entry points of a pipeline plan that are not placeholders.
+pub fn fixed_entry_points(plan: &Value) -> Vec<String> {
+    plan.get("segments")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|segment| segment["type"] == "EntryPointPlanSegment")
+        .filter_map(|segment| segment["identifier"].as_str().map(String::from))
+        .collect()
+}
+
+fn substitute(value: &mut Value, typed: &HashMap<&str, Value>) {
+    if let Some(replacement) = placeholder(value).and_then(|name| typed.get(name)) {
+        *value = replacement.clone();
+        return;
+    }
+    match value {
+        Value::Object(map) => map.values_mut().for_each(|v| substitute(v, typed)),
+        Value::Array(values) => values.iter_mut().for_each(|v| substitute(v, typed)),
+        _ => (),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineRegistry {
+    pipelines: RwLock<HashMap<String, Vec<PipelineVersion>>>,
+}
+
+impl PipelineRegistry {
+    /// Registers a pipeline, or a new version of it.
+    ///
+    /// Only the author of the first version can add versions.
+    pub fn register(
+        &self,
+        name: &str,
+        author: &str,
+        visibility: Visibility,
+        parameters: Vec<Parameter>,
+        plan: Value,
+    ) -> Result<PipelineVersion, Status> {
+        if name.is_empty() || name.contains('@') {
+            return Err(Status::invalid_argument(format!(
+                "Invalid pipeline name: {name:?}"
+            )));
+        }
+        let mut declared = HashSet::new();
+        for param in parameters.iter() {
+            if !declared.insert(param.name.as_str()) {
+                return Err(Status::invalid_argument(format!(
+                    "Parameter {} is declared twice",
+                    param.name
+                )));
+            }
+        }
+        let mut used = HashSet::new();
+        placeholders(&plan, &mut used);
+        if let Some(name) = used.difference(&declared).next() {
+            return Err(Status::invalid_argument(format!(
+                "Parameter {name} is used but not declared"
+            )));
+        }
+        if let Some(name) = declared.difference(&used).next() {
+            return Err(Status::invalid_argument(format!(
+                "Parameter {name} is declared but not used"
+            )));
+        }
+
+        let mut pipeline = PipelineVersion {
+            name: name.to_string(),
+            version: 0,
+            author: author.to_string(),
+            visibility,
+            parameters,
+            plan,
+        };
+
+        // Placeholders must stand where values of their type are valid.
+        let samples = pipeline
+            .parameters
+            .iter()
+            .map(|param| (param.name.clone(), sample_value(param)))
+            .collect();
+        let sample = serde_json::to_string(&pipeline.instantiate(&samples)?)
+            .map_err(|e| Status::internal(format!("Could not serialize pipeline {name}: {e}")))?;
+        serde_json::from_str::<CompositePlan>(&sample).map_err(|e| {
+            Status::invalid_argument(format!(
+                "Pipeline {name} is not a valid composite plan with its declared parameters: {e}"
+            ))
+        })?;
+
+        let mut pipelines = self.pipelines.write().unwrap();
+        let versions = pipelines.entry(name.to_string()).or_default();
+        if let Some(first) = versions.first() {
+            if first.author != author {
+                return Err(Status::permission_denied(format!(
+                    "Only the author of pipeline {name} can add versions"
+                )));
+            }
+        }
+        pipeline.version = versions.len() as u32 + 1;
+        versions.push(pipeline.clone());
+        Ok(pipeline)
+    }
+
+    /// The latest version of a pipeline visible to `user_id` if `version` is unset.
+    ///
+    /// Pipelines that are not visible cannot be told apart from the missing ones.
+    pub fn get(
+        &self,
+        name: &str,
+        version: Option<u32>,
+        user_id: &str,
+    ) -> Result<PipelineVersion, Status> {
+        let pipelines = self.pipelines.read().unwrap();
+        let versions = pipelines.get(name).map(Vec::as_slice).unwrap_or_default();
+        let pipeline = match version {
+            Some(version) => versions.iter().find(|p| p.version == version),
+            None => versions.last(),
+        };
+        match pipeline {
+            Some(pipeline) if pipeline.visible_to(user_id) => Ok(pipeline.clone()),
+            _ => Err(Status::not_found(match version {
+                Some(version) => format!("Could not find pipeline {name} version {version}"),
+                None => format!("Could not find pipeline {name}"),
+            })),
+        }
+    }
+
+    /// The latest version of every pipeline, whoever it is visible to.
+    pub fn latest(&self) -> Vec<PipelineVersion> {
+        let pipelines = self.pipelines.read().unwrap();
+        pipelines
+            .values()
+            .filter_map(|versions| versions.last())
+            .cloned()
+            .collect()
+    }
+
+    /// The latest version of every pipeline visible to `user_id`, by name.
+    pub fn list(&self, user_id: &str) -> Vec<PipelineVersion> {
+        let pipelines = self.pipelines.read().unwrap();
+        let mut list: Vec<_> = pipelines
+            .values()
+            .filter_map(|versions| versions.last())
+            .filter(|pipeline| pipeline.visible_to(user_id))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composite_plan::CompositePlanSegment;
+    use crate::{access_control::Policy, BastionLabPolars, DataFrameArtifact};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn server() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    /// Replaces every occurrence of `from` in `value` by `to`.
+    fn replace(value: &mut Value, from: &Value, to: &Value) {
+        if value == from {
+            *value = to.clone();
+            return;
+        }
+        match value {
+            Value::Object(map) => map.values_mut().for_each(|v| replace(v, from, to)),
+            Value::Array(values) => values.iter_mut().for_each(|v| replace(v, from, to)),
+            _ => (),
+        }
+    }
+
+    /// Reads `input` and keeps the rows whose `x` is above `threshold`, scaled by `factor`.
+    fn template() -> (Value, Vec<Parameter>) {
+        let filter = df! { "x" => [0i64] }
+            .unwrap()
+            .lazy()
+            .filter(col("x").gt(lit(1000i64)))
+            .with_column((col("x") * lit(0.25f64)).alias("scaled"))
+            .logical_plan;
+        let plan = CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: String::from("THE_INPUT"),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: filter,
+                skip_nan: false,
+                resources: None,
+            },
+        ]);
+        let mut plan = serde_json::to_value(&plan).unwrap();
+        replace(&mut plan, &json!("THE_INPUT"), &json!({"$param": "input"}));
+        replace(
+            &mut plan,
+            &serde_json::to_value(lit(1000i64)).unwrap(),
+            &json!({"$param": "threshold"}),
+        );
+        replace(
+            &mut plan,
+            &serde_json::to_value(lit(0.25f64)).unwrap(),
+            &json!({"$param": "factor"}),
+        );
+        let parameters = vec![
+            Parameter {
+                name: String::from("input"),
+                ty: ParameterType::Identifier,
+            },
+            Parameter {
+                name: String::from("threshold"),
+                ty: ParameterType::Int,
+            },
+            Parameter {
+                name: String::from("factor"),
+                ty: ParameterType::Float,
+            },
+        ];
+        (plan, parameters)
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn run(state: &BastionLabPolars, plan: Value) -> DataFrame {
+        let plan: CompositePlan = serde_json::from_value(plan).unwrap();
+        plan.run(state, "analyst").unwrap().dataframe
+    }
+
+    #[test]
+    fn parameters_are_substituted_as_typed_values() {
+        let state = server();
+        let df = df! { "x" => [1i64, 5, 10] }.unwrap();
+        let input = state.insert_df(DataFrameArtifact::new(
+            df,
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+
+        let registry = PipelineRegistry::default();
+        let (plan, parameters) = template();
+        registry
+            .register("clean", "analyst", Visibility::Private, parameters, plan)
+            .unwrap();
+        let pipeline = registry.get("clean", None, "analyst").unwrap();
+        let params = values(&[
+            ("input", &format!("{input:?}")),
+            ("threshold", "2"),
+            ("factor", "0.5"),
+        ]);
+        let df = run(&state, pipeline.instantiate(&params).unwrap());
+        assert_eq!(
+            df.column("scaled")
+                .unwrap()
+                .f64()
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [Some(2.5), Some(5.0)]
+        );
+
+        // Values are not plan text, even when they look like a part of a plan.
+        let injected = values(&[
+            ("input", &format!("{input:?}")),
+            ("threshold", "2"),
+            ("factor", r#""}, {\"Column\": \"x\"""#),
+        ]);
+        assert_eq!(
+            pipeline.instantiate(&injected).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn mismatched_and_missing_parameters_are_rejected() {
+        let registry = PipelineRegistry::default();
+        let (plan, parameters) = template();
+        registry
+            .register("clean", "analyst", Visibility::Public, parameters, plan)
+            .unwrap();
+        let pipeline = registry.get("clean", None, "someone").unwrap();
+
+        let err = pipeline
+            .instantiate(&values(&[
+                ("input", "\"df\""),
+                ("threshold", "2.5"),
+                ("factor", "1"),
+            ]))
+            .unwrap_err();
+        assert!(err.message().contains("threshold must be of type Int"));
+        let err = pipeline
+            .instantiate(&values(&[
+                ("input", "3"),
+                ("threshold", "2"),
+                ("factor", "1"),
+            ]))
+            .unwrap_err();
+        assert!(err.message().contains("input must be of type Identifier"));
+
+        let err = pipeline
+            .instantiate(&values(&[("input", "\"df\""), ("threshold", "2")]))
+            .unwrap_err();
+        assert!(err.message().contains("Missing parameter factor"));
+        let err = pipeline
+            .instantiate(&values(&[
+                ("input", "\"df\""),
+                ("threshold", "2"),
+                ("factor", "1"),
+                ("limit", "3"),
+            ]))
+            .unwrap_err();
+        assert!(err.message().contains("has no parameter limit"));
+
+        // Declarations must match the placeholders, which must stand where their type is valid.
+        let (plan, mut parameters) = template();
+        parameters.pop();
+        let err = registry
+            .register("other", "analyst", Visibility::Private, parameters, plan)
+            .unwrap_err();
+        assert!(err.message().contains("factor is used but not declared"));
+        let (plan, mut parameters) = template();
+        parameters[0].ty = ParameterType::Int;
+        let err = registry
+            .register("other", "analyst", Visibility::Private, parameters, plan)
+            .unwrap_err();
+        assert!(err.message().contains("not a valid composite plan"));
+    }
+
+    #[test]
+    fn versions_are_pinned_across_updates() {
+        let registry = PipelineRegistry::default();
+        let (plan, parameters) = template();
+        let v1 = registry
+            .register(
+                "clean",
+                "analyst",
+                Visibility::Group {
+                    members: vec![String::from("colleague")],
+                },
+                parameters.clone(),
+                plan.clone(),
+            )
+            .unwrap();
+        assert_eq!(v1.version, 1);
+
+        let mut updated = plan.clone();
+        replace(&mut updated, &json!("scaled"), &json!("rescaled"));
+        let v2 = registry
+            .register(
+                "clean",
+                "analyst",
+                Visibility::Group {
+                    members: vec![String::from("colleague")],
+                },
+                parameters.clone(),
+                updated,
+            )
+            .unwrap();
+        assert_eq!(v2.version, 2);
+
+        assert_eq!(registry.get("clean", None, "colleague").unwrap().version, 2);
+        let pinned = registry.get("clean", Some(1), "colleague").unwrap();
+        assert_eq!(pinned, v1);
+        assert_eq!(
+            parse_reference("pipeline:clean@1").unwrap().unwrap(),
+            ("clean", Some(1))
+        );
+        assert_eq!(
+            parse_reference("pipeline:clean").unwrap().unwrap(),
+            ("clean", None)
+        );
+        assert!(parse_reference("{\"segments\": []}").is_none());
+
+        // Only the author adds versions, and others only see what is shared with them.
+        let err = registry
+            .register("clean", "colleague", Visibility::Public, parameters, plan)
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert_eq!(
+            registry.get("clean", None, "stranger").unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        assert!(registry.list("stranger").is_empty());
+        assert_eq!(registry.list("colleague"), [v2]);
+    }
+
+    #[test]
+    fn fixed_entry_points_skip_placeholders() {
+        let (mut plan, _) = template();
+        assert!(fixed_entry_points(&plan).is_empty());
+        replace(&mut plan, &json!({"$param": "input"}), &json!("sales"));
+        assert_eq!(fixed_entry_points(&plan), ["sales"]);
+    }
+}
@@ -0,0 +1,469 @@
+//! Strict checks of serialized composite plans, before they are deserialized.
+//!
+//! Clients running a newer SDK than the server may send segment types or fields this server does
+//! not know. Deserializing would fail with an error naming neither the segment nor the field, or
+//! silently drop the field, so plans are checked first and rejected with a targeted diagnostic:
+//! - plans of a newer `format_version` than [`PLAN_FORMAT_VERSION`] ask for an upgrade,
+//! - unknown segment types are named along with their index and the supported segments,
+//! - unknown fields of segments, and of the objects they hold, are named by their path.
+//!
+//! Fields that do not change the result of a plan, hints and traces, are named `hints`, `trace`
+//! or end in `_hints` or `_trace`: in compatibility mode, unknown ones are skipped instead of
+//! rejected. The logical plans of polars segments are left to the polars deserializer.
+
+use serde_json::{Map, Value};
+use tonic::Status;
+
+use crate::capabilities::SEGMENTS;
+
+/// Version of the composite plan format this server reads, from the `format_version` field of
+/// plans. Plans without one are of version 1.
+pub const PLAN_FORMAT_VERSION: u32 = 1;
+
+pub fn default_format_version() -> u32 {
+    1
+}
+
+const PLAN_FIELDS: &[&str] = &[
+    "segments",
+    "semantics_version",
+    "nan_as_null",
+    "format_version",
+];
+
+/// The fields of each segment type, besides `type`.
+fn segment_fields(kind: &str) -> Option<&'static [&'static str]> {
+    Some(match kind {
+        "PolarsPlanSegment" => &["plan", "skip_nan", "resources"],
+        "UdfPlanSegment" => &["columns", "udf"],
+        "EntryPointPlanSegment" => &["identifier"],
+        "FamilyEntryPointSegment" => &["family", "predicate"],
+        "StackPlanSegment" => &[],
+        "RowCountSegment" => &["row"],
+        "TemporalPlanSegment" => &["columns"],
+        "OutputSegment" | "SlotEntryPointSegment" => &["slot"],
+        "SplitSegment" => &["train_size", "seed", "slots"],
+        "LabelEncodeSegment" => &["column", "mapping"],
+        "LiteralFramePlanSegment" => &["columns"],
+        "JoinPlanSegment" => &["left_on", "right_on", "how"],
+        "GroupByPlanSegment" => &["by", "aggs"],
+        "FilterPlanSegment" => &["predicate"],
+        "SortPlanSegment" => &["by", "stable"],
+        "SelectPlanSegment" => &["columns", "dtypes"],
+        "SamplePlanSegment" => &["fraction", "n", "with_replacement", "seed"],
+        "WithColumnPlanSegment" => &["name", "expr", "replace"],
+        "HeadPlanSegment" => &["n"],
+        "SlicePlanSegment" => &["offset", "length"],
+        "VStackPlanSegment" => &["inputs", "relaxed"],
+        _ => return None,
+    })
+}
+
+fn predicate_fields(kind: &str) -> Option<&'static [&'static str]> {
+    Some(match kind {
+        "Range" => &["from", "to"],
+        "In" => &["values"],
+        _ => return None,
+    })
+}
+
+fn temporal_fields(op: &str) -> Option<&'static [&'static str]> {
+    Some(match op {
+        "Column" => &["name"],
+        "Duration" => &["iso"],
+        "Add" | "Sub" => &["left", "right"],
+        "Truncate" => &["expr", "every"],
+        "BusinessDays" => &["start", "end", "weekend", "holidays"],
+        _ => return None,
+    })
+}
+
+fn filter_fields(op: &str) -> Option<&'static [&'static str]> {
+    Some(match op {
+        "Column" => &["name"],
+        "Literal" => &["value"],
+        "Eq" | "NotEq" | "Lt" | "LtEq" | "Gt" | "GtEq" | "And" | "Or" | "Add" | "Sub" | "Mul"
+        | "Div" | "Concat" => &["left", "right"],
+        "Not" => &["expr"],
+        _ => return None,
+    })
+}
+
+/// Whether `field` only decorates the plan, see the module documentation.
+pub fn is_decoration(field: &str) -> bool {
+    field == "hints" || field == "trace" || field.ends_with("_hints") || field.ends_with("_trace")
+}
+
+/// Checks serialized plan `plan`, skipping unknown decoration fields if `compatibility`.
+pub fn check(plan: &Value, compatibility: bool) -> Result<(), Status> {
+    let checker = Checker { compatibility };
+    let fields = object(plan, "the plan")?;
+    checker.fields(fields, "", PLAN_FIELDS, "the plan")?;
+    check_version(fields.get("format_version"))?;
+    let segments = match fields.get("segments") {
+        Some(Value::Array(segments)) => segments,
+        _ => {
+            return Err(Status::invalid_argument(
+                "Composite plans need a `segments` array",
+            ))
+        }
+    };
+    for (index, segment) in segments.iter().enumerate() {
+        checker.segment(index, segment)?;
+    }
+    Ok(())
+}
+
+fn check_version(version: Option<&Value>) -> Result<(), Status> {
+    let version = match version {
+        None => return Ok(()),
+        Some(version) => version.as_u64().filter(|&v| v > 0).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Invalid plan format version {version}: versions are positive integers"
+            ))
+        })?,
+    };
+    if version > PLAN_FORMAT_VERSION as u64 {
+        return Err(Status::failed_precondition(format!(
+            "The plan is of format version {version}, newer than version {PLAN_FORMAT_VERSION}, \
+             the one this server reads: upgrade the server, or use an SDK writing version \
+             {PLAN_FORMAT_VERSION} plans"
+        )));
+    }
+    Ok(())
+}
+
+fn object<'a>(value: &'a Value, what: &str) -> Result<&'a Map<String, Value>, Status> {
+    value
+        .as_object()
+        .ok_or_else(|| Status::invalid_argument(format!("{what} must be an object")))
+}
+
+/// The path of `field` in the object at `path`.
+fn join(path: &str, field: &str) -> String {
+    match path {
+        "" => field.to_string(),
+        path => format!("{path}.{field}"),
+    }
+}
+
+struct Checker {
+    compatibility: bool,
+}
+
+impl Checker {
+    /// Rejects the fields of the object at `path`, a `what`, that are neither `known` nor
+    /// skippable decorations.
+    fn fields(
+        &self,
+        fields: &Map<String, Value>,
+        path: &str,
+        known: &[&str],
+        what: &str,
+    ) -> Result<(), Status> {
+        for field in fields.keys() {
+            if known.contains(&field.as_str()) || self.compatibility && is_decoration(field) {
+                continue;
+            }
+            let hint = if is_decoration(field) {
+                ": it may be skipped in compatibility mode (`plan_compatibility_mode`)"
+            } else {
+                ""
+            };
+            return Err(Status::invalid_argument(format!(
+                "Unknown field `{}` of {what}, which has fields {}{hint}",
+                join(path, field),
+                list(known)
+            )));
+        }
+        Ok(())
+    }
+
+    /// The object at `path`, tagged with `tag`, once its fields are checked against those of its
+    /// tag given by `fields_of`.
+    fn tagged<'a>(
+        &self,
+        value: &'a Value,
+        path: &str,
+        tag: &str,
+        fields_of: fn(&str) -> Option<&'static [&'static str]>,
+        what: &str,
+    ) -> Result<(&'a str, &'a Map<String, Value>), Status> {
+        let fields = object(value, &format!("`{path}`"))?;
+        let kind = fields
+            .get(tag)
+            .and_then(Value::as_str)
+            .ok_or_else(|| Status::invalid_argument(format!("`{path}` has no `{tag}`")))?;
+        let known = fields_of(kind).ok_or_else(|| {
+            Status::invalid_argument(format!("Unknown {what} `{kind}` at `{path}`"))
+        })?;
+        let mut all = known.to_vec();
+        all.push(tag);
+        self.fields(fields, path, &all, &format!("{what} {kind}"))?;
+        Ok((kind, fields))
+    }
+
+    fn segment(&self, index: usize, segment: &Value) -> Result<(), Status> {
+        let path = format!("segments[{index}]");
+        let kind = segment
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Status::invalid_argument(format!("`{path}` has no `type`")))?;
+        if segment_fields(kind).is_none() {
+            return Err(Status::unimplemented(format!(
+                "Segment {index} of the plan has type `{kind}`, which this server does not \
+                 support. Its segments are {} (see GetServerCapabilities): upgrade the server, \
+                 or use an SDK writing plans for it",
+                SEGMENTS.join(", ")
+            )));
+        }
+        let (_, fields) = self.tagged(segment, &path, "type", segment_fields, "segment")?;
+        match kind {
+            "PolarsPlanSegment" => {
+                if let Some(resources) = fields.get("resources").filter(|r| !r.is_null()) {
+                    let path = join(&path, "resources");
+                    let resources = object(resources, &format!("`{path}`"))?;
+                    self.fields(
+                        resources,
+                        &path,
+                        &["allow_spill", "max_memory_mb"],
+                        "resource hints",
+                    )?;
+                }
+            }
+            "FamilyEntryPointSegment" => {
+                if let Some(predicate) = fields.get("predicate") {
+                    let path = join(&path, "predicate");
+                    self.tagged(
+                        predicate,
+                        &path,
+                        "type",
+                        predicate_fields,
+                        "partition predicate",
+                    )?;
+                }
+            }
+            "TemporalPlanSegment" => {
+                let columns = fields.get("columns").and_then(Value::as_array);
+                for (i, column) in columns.into_iter().flatten().enumerate() {
+                    let path = format!("{path}.columns[{i}]");
+                    let fields = object(column, &format!("`{path}`"))?;
+                    self.fields(fields, &path, &["name", "expr"], "temporal column")?;
+                    if let Some(expr) = fields.get("expr") {
+                        self.temporal(expr, &join(&path, "expr"))?;
+                    }
+                }
+            }
+            "LiteralFramePlanSegment" => {
+                let columns = fields.get("columns").and_then(Value::as_array);
+                for (i, column) in columns.into_iter().flatten().enumerate() {
+                    let path = format!("{path}.columns[{i}]");
+                    let fields = object(column, &format!("`{path}`"))?;
+                    self.fields(
+                        fields,
+                        &path,
+                        &["name", "dtype", "values"],
+                        "literal column",
+                    )?;
+                }
+            }
+            "FilterPlanSegment" => {
+                if let Some(predicate) = fields.get("predicate") {
+                    self.filter(predicate, &join(&path, "predicate"))?;
+                }
+            }
+            "WithColumnPlanSegment" => {
+                if let Some(expr) = fields.get("expr") {
+                    self.filter(expr, &join(&path, "expr"))?;
+                }
+            }
+            "SelectPlanSegment" => {
+                let columns = fields.get("columns").and_then(Value::as_array);
+                for (i, column) in columns.into_iter().flatten().enumerate() {
+                    let path = format!("{path}.columns[{i}]");
+                    let fields = object(column, &format!("`{path}`"))?;
+                    self.fields(fields, &path, &["name", "alias"], "selected column")?;
+                }
+            }
+            "SortPlanSegment" => {
+                let keys = fields.get("by").and_then(Value::as_array);
+                for (i, key) in keys.into_iter().flatten().enumerate() {
+                    let path = format!("{path}.by[{i}]");
+                    let fields = object(key, &format!("`{path}`"))?;
+                    self.fields(
+                        fields,
+                        &path,
+                        &["column", "descending", "nulls_last"],
+                        "sort key",
+                    )?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn filter(&self, expr: &Value, path: &str) -> Result<(), Status> {
+        let (_, fields) = self.tagged(expr, path, "op", filter_fields, "filter expression")?;
+        for (field, value) in fields {
+            if let "left" | "right" | "expr" = field.as_str() {
+                self.filter(value, &join(path, field))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn temporal(&self, expr: &Value, path: &str) -> Result<(), Status> {
+        let (_, fields) = self.tagged(expr, path, "op", temporal_fields, "temporal expression")?;
+        for (field, value) in fields {
+            match field.as_str() {
+                "left" | "right" | "expr" | "start" | "end" => {
+                    self.temporal(value, &join(path, field))?
+                }
+                "holidays" if !value.is_null() => {
+                    let path = join(path, field);
+                    let holidays = object(value, &format!("`{path}`"))?;
+                    self.fields(holidays, &path, &["identifier", "column"], "holidays")?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn list(fields: &[&str]) -> String {
+    match fields {
+        [] => String::from("none"),
+        fields => fields
+            .iter()
+            .map(|field| format!("`{field}`"))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A plan as a future SDK writes it, reading a dataframe into `segment`.
+    fn future_plan(segment: Value) -> Value {
+        json!({
+            "segments": [
+                {"type": "EntryPointPlanSegment", "identifier": "a"},
+                segment,
+            ],
+            "semantics_version": 1,
+            "format_version": 1,
+        })
+    }
+
+    fn message(plan: &Value, compatibility: bool) -> (tonic::Code, String) {
+        let err = check(plan, compatibility).unwrap_err();
+        (err.code(), err.message().to_string())
+    }
+
+    #[test]
+    fn known_plans_pass() {
+        let plan = future_plan(json!({
+            "type": "TemporalPlanSegment",
+            "columns": [{"name": "d", "expr": {
+                "op": "BusinessDays",
+                "start": {"op": "Column", "name": "a"},
+                "end": {"op": "Truncate", "expr": {"op": "Column", "name": "b"}, "every": "P1M"},
+                "holidays": {"identifier": "h", "column": "day"},
+            }}],
+        }));
+        check(&plan, false).unwrap();
+        // Plans of SDKs predating the format version are of version 1.
+        check(&json!({"segments": [{"type": "StackPlanSegment"}]}), false).unwrap();
+        for segment in SEGMENTS {
+            assert!(segment_fields(segment).is_some(), "{segment}");
+        }
+    }
+
+    #[test]
+    fn unknown_segments_name_their_index_and_the_supported_ones() {
+        let plan = future_plan(json!({"type": "WindowPlanSegment", "size": 3}));
+        let (code, message) = message(&plan, true);
+        assert_eq!(code, tonic::Code::Unimplemented);
+        assert!(message.contains("Segment 1 "), "{message}");
+        assert!(message.contains("`WindowPlanSegment`"), "{message}");
+        assert!(message.contains(&SEGMENTS.join(", ")), "{message}");
+    }
+
+    #[test]
+    fn unknown_fields_are_named_by_their_path() {
+        let plan = future_plan(json!({
+            "type": "PolarsPlanSegment",
+            "plan": {},
+            "resources": {"max_memory_mb": 10, "gpu": true},
+        }));
+        let (code, message) = message(&plan, true);
+        assert_eq!(code, tonic::Code::InvalidArgument);
+        assert!(message.contains("`segments[1].resources.gpu`"), "{message}");
+
+        let plan = future_plan(json!({
+            "type": "TemporalPlanSegment",
+            "columns": [{"name": "d", "expr": {
+                "op": "Add",
+                "left": {"op": "Column", "name": "a"},
+                "right": {"op": "Duration", "iso": "P1D", "calendar": "gregorian"},
+            }}],
+        }));
+        let (_, message) = message(&plan, true);
+        assert!(
+            message.contains("`segments[1].columns[0].expr.right.calendar`"),
+            "{message}"
+        );
+
+        let plan = future_plan(json!({
+            "type": "FamilyEntryPointSegment",
+            "family": "sales",
+            "predicate": {"type": "Prefix", "value": "2023"},
+        }));
+        let (_, message) = message(&plan, true);
+        assert!(
+            message.contains("partition predicate `Prefix` at `segments[1].predicate`"),
+            "{message}"
+        );
+
+        let mut plan = future_plan(json!({"type": "StackPlanSegment"}));
+        plan["deterministic"] = json!(true);
+        let (_, message) = message(&plan, true);
+        assert!(message.contains("`deterministic` of the plan"), "{message}");
+    }
+
+    #[test]
+    fn compatibility_mode_only_skips_decorations() {
+        let plan = future_plan(json!({
+            "type": "RowCountSegment",
+            "row": "n",
+            "cache_hints": {"reuse": true},
+            "trace": "sdk 9.1",
+        }));
+        let (code, message) = message(&plan, false);
+        assert_eq!(code, tonic::Code::InvalidArgument);
+        assert!(message.contains("compatibility mode"), "{message}");
+        check(&plan, true).unwrap();
+
+        let plan = future_plan(json!({"type": "RowCountSegment", "row": "n", "offset": 1}));
+        let (_, message) = message(&plan, true);
+        assert!(message.contains("`segments[1].offset`"), "{message}");
+        assert!(!message.contains("compatibility mode"), "{message}");
+    }
+
+    #[test]
+    fn newer_formats_ask_for_an_upgrade() {
+        let mut plan = future_plan(json!({"type": "StackPlanSegment"}));
+        plan["format_version"] = json!(PLAN_FORMAT_VERSION + 1);
+        let (code, message) = message(&plan, true);
+        assert_eq!(code, tonic::Code::FailedPrecondition);
+        assert!(message.contains("upgrade the server"), "{message}");
+
+        plan["format_version"] = json!("2");
+        assert_eq!(message(&plan, true).0, tonic::Code::InvalidArgument);
+    }
+}
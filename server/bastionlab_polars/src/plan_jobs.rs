@@ -0,0 +1,249 @@
+//! Plans run in the background as jobs, which checkpointed jobs resume after a restart.
+//!
+//! Jobs are submitted with `SubmitPlanJob` and followed with `GetPlanJob`. Unfinished jobs are
+//! recorded in the `plan_jobs` directory of the data directory. Checkpointed jobs save the state
+//! of their plan at every segment boundary, see [`crate::checkpoints`]. On startup, the server
+//! resumes them from their last boundary, unless a dataframe they read changed since: they then
+//! start over, with the reason logged and noted on the job. Jobs without checkpoints that a
+//! restart interrupted fail.
+//!
+//! A job fails for good once it started `plan_job_max_attempts` runs, or when its checkpoint is
+//! older than `plan_checkpoint_expiry_secs` on restart. Checkpoints are removed once their job
+//! succeeds or fails.
+//!
+//! A graceful shutdown stops checkpointed jobs at their next segment boundary, once it is saved,
+//! and waits up to `shutdown_grace_secs` for them. Jobs without checkpoints are not waited for.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+use bastionlab_common::atomic_file;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::checkpoints::JobControl;
+use crate::polars_proto;
+use crate::prelude::*;
+
+/// Directory of the unfinished jobs, in the data directory.
+pub const JOBS_DIR: &str = "plan_jobs";
+
+/// Finished jobs kept for their requesters to read their outcome, oldest first to go.
+const MAX_FINISHED: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PlanJobStatus {
+    Running,
+    /// With the encoded `ReferenceResponse` of the result.
+    Succeeded {
+        result: Vec<u8>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl PlanJobStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlanJobStatus::Running => "running",
+            PlanJobStatus::Succeeded { .. } => "succeeded",
+            PlanJobStatus::Failed { .. } => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanJob {
+    pub id: String,
+    pub requester: String,
+    /// The encoded `Query`.
+    pub query: Vec<u8>,
+    pub checkpoint: bool,
+    /// Runs started, the first one included.
+    pub attempts: u32,
+    /// Segments the last run resumed after, if it resumed from a checkpoint.
+    pub resumed_after: Option<usize>,
+    pub notes: Vec<String>,
+    pub status: PlanJobStatus,
+}
+
+impl PlanJob {
+    pub fn to_proto(&self, completed_segments: usize) -> Result<polars_proto::PlanJob, Status> {
+        use prost::Message;
+
+        let (result, error) = match &self.status {
+            PlanJobStatus::Running => (None, String::new()),
+            PlanJobStatus::Succeeded { result } => {
+                let result = polars_proto::ReferenceResponse::decode(&result[..])
+                    .map_err(|e| Status::internal(format!("Invalid job result: {e}")))?;
+                (Some(result), String::new())
+            }
+            PlanJobStatus::Failed { error } => (None, error.clone()),
+        };
+        Ok(polars_proto::PlanJob {
+            job: self.id.clone(),
+            status: self.status.name().to_string(),
+            result,
+            error,
+            checkpoint: self.checkpoint,
+            attempts: self.attempts,
+            completed_segments: completed_segments as u64,
+            resumed_after: self.resumed_after.map(|segments| segments as u64),
+            notes: self.notes.clone(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: HashMap<String, (PlanJob, Arc<JobControl>)>,
+    finished: VecDeque<String>,
+}
+
+#[derive(Default)]
+pub struct PlanJobs {
+    jobs: RwLock<Jobs>,
+}
+
+impl PlanJobs {
+    /// Registers a running job, returning the control of its run.
+    pub fn start(&self, job: PlanJob) -> Arc<JobControl> {
+        let control = Arc::new(JobControl::default());
+        let mut jobs = self.jobs.write().unwrap();
+        jobs.jobs.insert(job.id.clone(), (job, control.clone()));
+        control
+    }
+
+    /// Records the outcome of job `id`.
+    pub fn finish(&self, id: &str, status: PlanJobStatus) {
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some((job, _)) = jobs.jobs.get_mut(id) {
+            job.status = status;
+            jobs.finished.push_back(id.to_string());
+        }
+        while jobs.finished.len() > MAX_FINISHED {
+            if let Some(oldest) = jobs.finished.pop_front() {
+                jobs.jobs.remove(&oldest);
+            }
+        }
+    }
+
+    /// Job `id` and the segments its current run completed, if `user_id` submitted it.
+    pub fn get(&self, id: &str, user_id: &str) -> Result<(PlanJob, usize), Status> {
+        match self.jobs.read().unwrap().jobs.get(id) {
+            Some((job, control)) if job.requester == user_id => {
+                Ok((job.clone(), control.completed()))
+            }
+            _ => Err(Status::not_found(format!("Could not find plan job {id}"))),
+        }
+    }
+
+    /// The controls of the running jobs, checkpointed ones only if `checkpointed`.
+    pub fn running(&self, checkpointed: bool) -> Vec<Arc<JobControl>> {
+        self.jobs
+            .read()
+            .unwrap()
+            .jobs
+            .values()
+            .filter(|(job, _)| job.status == PlanJobStatus::Running)
+            .filter(|(job, _)| job.checkpoint || !checkpointed)
+            .map(|(_, control)| control.clone())
+            .collect()
+    }
+}
+
+/// Records unfinished job `job` in `dir`.
+pub fn save(dir: &Path, job: &PlanJob) -> Result<(), Status> {
+    let err = |e: &dyn std::fmt::Display| {
+        Status::internal(format!("Could not record plan job {}: {e}", job.id))
+    };
+    fs::create_dir_all(dir).map_err(|e| err(&e))?;
+    let buf = serde_json::to_vec(job).map_err(|e| err(&e))?;
+    atomic_file::write(&dir.join(format!("{}.json", job.id)), &buf).map_err(|e| err(&e))
+}
+
+/// Forgets finished job `id` in `dir`.
+pub fn remove(dir: &Path, id: &str) {
+    let path = dir.join(format!("{id}.json"));
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Could not remove {}: {e}", path.display());
+        }
+    }
+}
+
+/// The unfinished jobs recorded in `dir`, unreadable records skipped.
+pub fn load(dir: &Path) -> Vec<PlanJob> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .filter_map(|path| {
+            let job = fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|buf| serde_json::from_slice(&buf).map_err(|e| e.to_string()));
+            match job {
+                Ok(job) => Some(job),
+                Err(e) => {
+                    warn!("Skipped unreadable plan job {}: {e}", path.display());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: &str, checkpoint: bool) -> PlanJob {
+        PlanJob {
+            id: id.to_string(),
+            requester: String::from("alice"),
+            query: Vec::new(),
+            checkpoint,
+            attempts: 1,
+            resumed_after: None,
+            notes: Vec::new(),
+            status: PlanJobStatus::Running,
+        }
+    }
+
+    #[test]
+    fn jobs_are_recorded_until_they_finish() {
+        let dir = std::env::temp_dir().join(format!("plan-jobs-{}", uuid::Uuid::new_v4()));
+        save(&dir, &job("a", true)).unwrap();
+        save(&dir, &job("b", false)).unwrap();
+        fs::write(dir.join("c.json"), b"garbage").unwrap();
+        let mut loaded: Vec<_> = load(&dir).into_iter().map(|job| job.id).collect();
+        loaded.sort();
+        assert_eq!(loaded, ["a", "b"]);
+
+        let jobs = PlanJobs::default();
+        jobs.start(job("a", true));
+        jobs.start(job("b", false));
+        assert_eq!(jobs.running(true).len(), 1);
+        assert_eq!(jobs.running(false).len(), 2);
+        assert!(jobs.get("a", "bob").is_err());
+
+        jobs.finish(
+            "a",
+            PlanJobStatus::Failed {
+                error: String::from("boom"),
+            },
+        );
+        remove(&dir, "a");
+        assert_eq!(jobs.running(true).len(), 0);
+        let (a, _) = jobs.get("a", "alice").unwrap();
+        assert_eq!(a.to_proto(0).unwrap().error, "boom");
+        assert_eq!(load(&dir).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1216 @@
+//! The policy engine: every access decision is made by [`PolicyEngine::evaluate`].
+//!
+//! Admitting a query, deriving a result from its inputs, fetching and persisting a dataframe are
+//! [`Action`]s that an identity takes on one or more artifacts. The engine combines the policies
+//! of all of them into one [`Decision`]: a [`Verdict`], the most restrictive of those of every
+//! artifact, and the [`Transformation`]s any of them requires.
+//!
+//! Policies with a differential privacy rule allow the aggregates their safe zone rejects instead
+//! of reaching its verdict, and the fetches of those results are noised, see
+//! [`crate::differential_privacy`].
+//!
+//! Decisions are recorded when they are made, and cannot be built anywhere else. Data only leaves
+//! the server as [`Released`] dataframes, which only a [`Grant`] produces, and grants are only
+//! issued for allowed or warned decisions, or pending ones the data owner approved: fetches cannot
+//! stream anything the engine did not let through.
+//!
+//! Under strict governance, whatever no rule allows is denied: a mismatch of the safe zone of a
+//! policy is denied whatever its unsafe handling says, and so are actions on no artifact at all.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use polars::prelude::DataFrame;
+use rand::{rngs::StdRng, SeedableRng};
+use tonic::Status;
+
+use crate::access_control::{
+    merge_max_output_rows, Context, Policy, UnsafeAction, VerificationResult,
+};
+use crate::catalog::now_ms;
+use crate::composite_plan::StatsEntry;
+use crate::differential_privacy::NoisyRelease;
+use crate::masking::Masking;
+use crate::output_rows::{CappedOutput, MaxOutputRows};
+use crate::purpose::Purpose;
+use crate::utils::sanitize_df;
+use crate::watermark::{Watermark, Watermarker};
+use crate::DataFrameArtifact;
+
+/// Identity of the decisions the server makes on its own, such as persisting dataframes.
+pub const SERVER: &str = "server";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Running a query on the artifacts, before it runs.
+    Query,
+    /// Computing a result from the artifacts, the inputs of a query. The verdict is the one the
+    /// fetches of the result get, and does not stop the query.
+    Derive,
+    Fetch,
+    Persist,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Query => "query",
+            Action::Derive => "derivation",
+            Action::Fetch => "fetch",
+            Action::Persist => "persistence",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Allowed, but the recipient is warned, and the access logged.
+    Warn(String),
+    /// Allowed once the data owner approves.
+    Pending(String),
+    Deny(String),
+}
+
+impl Verdict {
+    fn severity(&self) -> u8 {
+        match self {
+            Verdict::Allow => 0,
+            Verdict::Warn(_) => 1,
+            Verdict::Pending(_) => 2,
+            Verdict::Deny(_) => 3,
+        }
+    }
+
+    /// The most restrictive of both verdicts, with the reasons of both if they are as restrictive.
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (Verdict::Warn(a), Verdict::Warn(b)) => Verdict::Warn(join_reasons(a, b)),
+            (Verdict::Pending(a), Verdict::Pending(b)) => Verdict::Pending(join_reasons(a, b)),
+            (Verdict::Deny(a), Verdict::Deny(b)) => Verdict::Deny(join_reasons(a, b)),
+            (a, b) if b.severity() > a.severity() => b,
+            (a, _) => a,
+        }
+    }
+
+    /// Whether data may be released right away.
+    pub fn permits(&self) -> bool {
+        matches!(self, Verdict::Allow | Verdict::Warn(_))
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Verdict::Allow => None,
+            Verdict::Warn(reason) | Verdict::Pending(reason) | Verdict::Deny(reason) => {
+                Some(reason)
+            }
+        }
+    }
+
+    /// The verification result results record, see [`crate::DataFrameArtifact`].
+    pub fn to_fetchable(&self) -> VerificationResult {
+        let (action, reason) = match self {
+            Verdict::Allow => return VerificationResult::Safe,
+            Verdict::Warn(reason) => (UnsafeAction::Log, reason),
+            Verdict::Pending(reason) => (UnsafeAction::Review, reason),
+            Verdict::Deny(reason) => (UnsafeAction::Reject, reason),
+        };
+        VerificationResult::Unsafe {
+            action,
+            reason: reason.clone(),
+        }
+    }
+}
+
+fn join_reasons(a: String, b: String) -> String {
+    if a == b || b.is_empty() {
+        a
+    } else if a.is_empty() {
+        b
+    } else {
+        format!("{a}\n{b}")
+    }
+}
+
+/// What must be done to data before it is released.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transformation {
+    /// Adds differential privacy noise to the aggregates of results.
+    Noise(NoisyRelease),
+    /// Masks the values of columns, see [`crate::masking`].
+    Mask(Masking),
+    /// Nulls out the columns.
+    Sanitize(Vec<String>),
+    /// Watermarks the data for its recipient, leaving `exact_columns` untouched.
+    Watermark {
+        watermark: Watermark,
+        exact_columns: Vec<String>,
+    },
+    /// Caps the rows of results.
+    CapRows(MaxOutputRows),
+}
+
+/// An artifact an action is taken on.
+pub struct Subject<'a> {
+    identifier: &'a str,
+    artifact: &'a DataFrameArtifact,
+    /// The policy the artifact is judged by, its own unless previewing another one.
+    policy: &'a Policy,
+    stats: Option<StatsEntry>,
+    /// Whether the result derived from the artifact can be noised, see [`Subject::noisable`].
+    noisable: bool,
+}
+
+impl<'a> Subject<'a> {
+    /// An input of a query, `stats` being what the query reads of it.
+    pub fn input(identifier: &'a str, artifact: &'a DataFrameArtifact, stats: StatsEntry) -> Self {
+        Subject {
+            identifier,
+            artifact,
+            policy: &artifact.policy,
+            stats: Some(stats),
+            noisable: false,
+        }
+    }
+
+    /// A dataframe fetched or persisted as a whole.
+    pub fn artifact(identifier: &'a str, artifact: &'a DataFrameArtifact) -> Self {
+        Subject {
+            identifier,
+            artifact,
+            policy: &artifact.policy,
+            stats: None,
+            noisable: false,
+        }
+    }
+
+    /// The artifact as if its policy were `policy`, see [`PolicyEngine::preview`].
+    pub fn with_policy(mut self, policy: &'a Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// An input whose result is an aggregate differential privacy noise can release.
+    pub fn noisable(mut self) -> Self {
+        self.noisable = true;
+        self
+    }
+}
+
+/// What the request states, beyond who makes it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EvaluationContext<'a> {
+    pub purpose: Option<&'a Purpose>,
+}
+
+/// A decision of the engine, on every artifact of an action at once.
+#[derive(Debug)]
+pub struct Decision {
+    id: u64,
+    action: Action,
+    verdict: Verdict,
+    /// The verdict on each artifact alone.
+    subjects: Vec<(String, Verdict)>,
+    transformations: Vec<Transformation>,
+    withheld: bool,
+    /// The artifacts allowed because the result is noised.
+    noised: Vec<String>,
+}
+
+impl Decision {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn verdict(&self) -> &Verdict {
+        &self.verdict
+    }
+
+    pub fn verdict_of(&self, identifier: &str) -> Option<&Verdict> {
+        self.subjects
+            .iter()
+            .find(|(subject, _)| subject == identifier)
+            .map(|(_, verdict)| verdict)
+    }
+
+    pub fn transformations(&self) -> &[Transformation] {
+        &self.transformations
+    }
+
+    /// Whether a denied fetch is a result held back by a row cap, which clients are told about as
+    /// pending, see [`CappedOutput::Rejected`].
+    pub fn withheld(&self) -> bool {
+        self.withheld
+    }
+
+    /// Whether `identifier` is allowed only because the result is noised.
+    pub fn noised(&self, identifier: &str) -> bool {
+        self.noised.iter().any(|noised| noised == identifier)
+    }
+
+    pub fn noise(&self) -> Option<&NoisyRelease> {
+        noise_of(&self.transformations)
+    }
+
+    pub fn sanitized_columns(&self) -> &[String] {
+        self.transformations
+            .iter()
+            .find_map(|transformation| match transformation {
+                Transformation::Sanitize(columns) => Some(&columns[..]),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    pub fn watermark(&self) -> Option<(&Watermark, &[String])> {
+        self.transformations
+            .iter()
+            .find_map(|transformation| match transformation {
+                Transformation::Watermark {
+                    watermark,
+                    exact_columns,
+                } => Some((watermark, &exact_columns[..])),
+                _ => None,
+            })
+    }
+
+    pub fn row_cap(&self) -> Option<MaxOutputRows> {
+        self.transformations
+            .iter()
+            .find_map(|transformation| match transformation {
+                Transformation::CapRows(cap) => Some(*cap),
+                _ => None,
+            })
+    }
+}
+
+fn noise_of(transformations: &[Transformation]) -> Option<&NoisyRelease> {
+    transformations
+        .iter()
+        .find_map(|transformation| match transformation {
+            Transformation::Noise(release) => Some(release),
+            _ => None,
+        })
+}
+
+/// A decision as the engine recorded it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedDecision {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch.
+    pub at: u64,
+    pub action: Action,
+    pub identity: String,
+    pub subjects: Vec<String>,
+    pub verdict: Verdict,
+}
+
+/// Permission to release data, with what must be done to it first.
+pub struct Grant {
+    decision: u64,
+    transformations: Vec<Transformation>,
+}
+
+impl Grant {
+    /// The noise the release adds, whose privacy must be spent first.
+    pub fn noise(&self) -> Option<&NoisyRelease> {
+        noise_of(&self.transformations)
+    }
+
+    /// Applies the transformations of the decision to `df`, the data of `identifier` fetched by
+    /// `recipient`.
+    pub fn release(
+        &self,
+        mut df: DataFrame,
+        identifier: &str,
+        recipient: &str,
+        watermarker: &Watermarker,
+    ) -> Result<Released, Status> {
+        for transformation in self.transformations.iter() {
+            match transformation {
+                Transformation::Noise(release) => {
+                    release.apply(&mut df, &mut StdRng::from_entropy())?
+                }
+                Transformation::Mask(masking) => masking.apply(&mut df)?,
+                Transformation::Sanitize(columns) => sanitize_df(&mut df, columns),
+                Transformation::Watermark {
+                    watermark,
+                    exact_columns,
+                } => {
+                    watermarker.apply(&mut df, watermark, exact_columns, identifier, recipient)?;
+                }
+                // Applied when the result is computed.
+                Transformation::CapRows(_) => (),
+            }
+        }
+        Ok(Released {
+            dataframe: df,
+            decision: self.decision,
+        })
+    }
+
+    /// The grant without its masks nor its watermark, for fetches under an export waiver, see
+    /// [`crate::waivers`].
+    pub fn waive(mut self) -> Self {
+        self.transformations.retain(|transformation| {
+            !matches!(
+                transformation,
+                Transformation::Mask(_) | Transformation::Watermark { .. }
+            )
+        });
+        self
+    }
+
+    /// Releases `df` masked and sanitized but not watermarked, for the key columns of deltas,
+    /// which are checked not to be watermarked nor noised, see [`crate::delta`].
+    pub fn release_unmarked(&self, mut df: DataFrame) -> Result<Released, Status> {
+        for transformation in self.transformations.iter() {
+            match transformation {
+                Transformation::Mask(masking) => masking.apply(&mut df)?,
+                Transformation::Sanitize(columns) => sanitize_df(&mut df, columns),
+                _ => (),
+            }
+        }
+        Ok(Released {
+            dataframe: df,
+            decision: self.decision,
+        })
+    }
+}
+
+/// Data a grant released, the only data fetches stream.
+pub struct Released {
+    dataframe: DataFrame,
+    decision: u64,
+}
+
+impl Released {
+    /// The decision the data was released under.
+    pub fn decision(&self) -> u64 {
+        self.decision
+    }
+
+    pub fn dataframe(&self) -> &DataFrame {
+        &self.dataframe
+    }
+
+    pub fn into_dataframe(self) -> DataFrame {
+        self.dataframe
+    }
+
+    /// The released data restricted to `columns`, in that order, once masked and watermarked
+    /// like the whole of it.
+    pub fn select(self, columns: &[String]) -> Result<Self, Status> {
+        Ok(Released {
+            dataframe: self.dataframe.select(columns).map_err(|e| {
+                Status::invalid_argument(format!("Could not select the fetched columns: {e}"))
+            })?,
+            decision: self.decision,
+        })
+    }
+}
+
+pub struct PolicyEngine {
+    strict_governance: bool,
+    next_id: AtomicU64,
+    records: RwLock<VecDeque<RecordedDecision>>,
+    capacity: usize,
+}
+
+impl PolicyEngine {
+    /// An engine recording its last `capacity` decisions.
+    pub fn new(strict_governance: bool, capacity: usize) -> Self {
+        PolicyEngine {
+            strict_governance,
+            next_id: AtomicU64::new(0),
+            records: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Decides whether `identity` may take `action` on `subjects`, and records the decision.
+    ///
+    /// Fails only if the policies cannot be evaluated: denials are decisions too.
+    pub fn evaluate(
+        &self,
+        action: Action,
+        identity: &str,
+        subjects: &[Subject],
+        context: &EvaluationContext,
+    ) -> Result<Decision, Status> {
+        let mut verdict = self.default_verdict(action, subjects);
+        let mut verdicts = Vec::with_capacity(subjects.len());
+        let mut withheld = Vec::new();
+        let mut noised = Vec::new();
+        let mut required = Required::default();
+        for subject in subjects {
+            let judgement = self.judge(action, identity, subject, context)?;
+            if let Verdict::Deny(_) = judgement.verdict {
+                withheld.push(judgement.withheld);
+            }
+            if judgement.noised {
+                noised.push(subject.identifier.to_owned());
+            }
+            verdict = verdict.merge(judgement.verdict.clone());
+            verdicts.push((subject.identifier.to_owned(), judgement.verdict));
+            required.add(action, subject, judgement.noised)?;
+        }
+        let decision = Decision {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            action,
+            withheld: matches!(verdict, Verdict::Deny(_))
+                && !withheld.is_empty()
+                && withheld.iter().all(|withheld| *withheld),
+            verdict,
+            subjects: verdicts,
+            transformations: required.into_transformations(),
+            noised,
+        };
+        self.record(&decision, identity);
+        Ok(decision)
+    }
+
+    /// The verdict [`PolicyEngine::evaluate`] would reach, without recording a decision: used to
+    /// report what policy changes would do, see [`crate::rollouts`].
+    pub fn preview(
+        &self,
+        action: Action,
+        identity: &str,
+        subjects: &[Subject],
+        context: &EvaluationContext,
+    ) -> Result<Verdict, Status> {
+        let mut verdict = self.default_verdict(action, subjects);
+        for subject in subjects {
+            verdict = verdict.merge(self.judge(action, identity, subject, context)?.verdict);
+        }
+        Ok(verdict)
+    }
+
+    fn default_verdict(&self, action: Action, subjects: &[Subject]) -> Verdict {
+        match subjects {
+            [] if self.strict_governance => {
+                Verdict::Deny(format!("No policy allows this {action}"))
+            }
+            _ => Verdict::Allow,
+        }
+    }
+
+    /// The verdict on one subject of an action.
+    fn judge(
+        &self,
+        action: Action,
+        identity: &str,
+        subject: &Subject,
+        context: &EvaluationContext,
+    ) -> Result<Judgement, Status> {
+        let policy = subject.policy;
+        if let Action::Query | Action::Fetch = action {
+            if let Err(e) = policy.check_purpose(context.purpose, subject.identifier) {
+                return Ok(Judgement::of(Verdict::Deny(e.message().to_owned())));
+            }
+        }
+        Ok(match action {
+            Action::Query => Judgement::of(Verdict::Allow),
+            Action::Derive => {
+                let stats = subject.stats.ok_or_else(|| {
+                    Status::internal(format!(
+                        "Missing the stats of input {} of a query",
+                        subject.identifier
+                    ))
+                })?;
+                let check = policy.verify(&Context {
+                    stats,
+                    user_id: String::from(identity),
+                    df_identifier: String::from(subject.identifier),
+                })?;
+                match policy.differential_privacy() {
+                    // Results of noisy results are noised again, however safe.
+                    Some(_)
+                        if check == VerificationResult::Safe
+                            && subject.artifact.noise.is_none() =>
+                    {
+                        Judgement::of(Verdict::Allow)
+                    }
+                    Some(_) if subject.noisable => Judgement {
+                        noised: true,
+                        ..Judgement::of(Verdict::Allow)
+                    },
+                    Some(_) => Judgement::of(Verdict::Deny(format!(
+                        "{}Under the differential privacy rule of {}, only the counts, sums and \
+                         means of a group-by ending the query can be released",
+                        match &check {
+                            VerificationResult::Unsafe { reason, .. } => format!("{reason}\n"),
+                            VerificationResult::Safe => String::new(),
+                        },
+                        subject.identifier
+                    ))),
+                    None => Judgement::of(self.verdict_of(&check)),
+                }
+            }
+            Action::Fetch => match &subject.artifact.capped_output {
+                Some(capped @ CappedOutput::Rejected { .. }) => Judgement {
+                    withheld: true,
+                    ..Judgement::of(Verdict::Deny(capped.message()))
+                },
+                _ => match self.verdict_of(&subject.artifact.fetchable) {
+                    Verdict::Deny(reason) => Judgement::of(Verdict::Deny(format!(
+                        "Cannot fetch this DataFrame: operation denied by the data owner's policy
+Reason: {}",
+                        reason
+                    ))),
+                    verdict => Judgement::of(verdict),
+                },
+            },
+            Action::Persist if policy.check_savable() => Judgement::of(Verdict::Allow),
+            Action::Persist => {
+                Judgement::of(Verdict::Deny(String::from("Dataframe is not savable")))
+            }
+        })
+    }
+
+    pub fn verdict_of(&self, result: &VerificationResult) -> Verdict {
+        match result {
+            VerificationResult::Safe => Verdict::Allow,
+            VerificationResult::Unsafe { reason, .. } if self.strict_governance => {
+                Verdict::Deny(reason.clone())
+            }
+            VerificationResult::Unsafe { action, reason } => match action {
+                UnsafeAction::Log => Verdict::Warn(reason.clone()),
+                UnsafeAction::Review => Verdict::Pending(reason.clone()),
+                UnsafeAction::Reject => Verdict::Deny(reason.clone()),
+            },
+        }
+    }
+
+    fn record(&self, decision: &Decision, identity: &str) {
+        log::debug!(
+            "Decision {} on the {} of {:?} by {}: {:?}",
+            decision.id,
+            decision.action,
+            decision.subjects,
+            identity,
+            decision.verdict
+        );
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.write().unwrap();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(RecordedDecision {
+            id: decision.id,
+            at: now_ms(),
+            action: decision.action,
+            identity: identity.to_owned(),
+            subjects: decision
+                .subjects
+                .iter()
+                .map(|(identifier, _)| identifier.clone())
+                .collect(),
+            verdict: decision.verdict.clone(),
+        });
+    }
+
+    /// Permission to release the data of an allowed or warned decision.
+    pub fn grant(&self, decision: &Decision) -> Result<Grant, Status> {
+        match &decision.verdict {
+            Verdict::Allow | Verdict::Warn(_) => Ok(Grant {
+                decision: decision.id,
+                transformations: decision.transformations.clone(),
+            }),
+            verdict => Err(Status::permission_denied(format!(
+                "The {} was not allowed: {}",
+                decision.action,
+                verdict.reason().unwrap_or_default()
+            ))),
+        }
+    }
+
+    /// Records the approval of a pending decision by the data owner, as a new decision allowing
+    /// `identity` the same action, and grants it.
+    pub fn approve(&self, decision: &Decision, identity: &str) -> Result<Grant, Status> {
+        if !matches!(decision.verdict, Verdict::Pending(_)) {
+            return Err(Status::failed_precondition(format!(
+                "Only pending decisions can be approved, not decision {}",
+                decision.id
+            )));
+        }
+        let approved = Decision {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            action: decision.action,
+            verdict: Verdict::Allow,
+            subjects: decision
+                .subjects
+                .iter()
+                .map(|(identifier, _)| (identifier.clone(), Verdict::Allow))
+                .collect(),
+            transformations: decision.transformations.clone(),
+            withheld: false,
+            noised: decision.noised.clone(),
+        };
+        self.record(&approved, identity);
+        self.grant(&approved)
+    }
+
+    /// The decision recorded as `id`, if it was not forgotten since.
+    pub fn recorded(&self, id: u64) -> Option<RecordedDecision> {
+        let records = self.records.read().unwrap();
+        records.iter().find(|record| record.id == id).cloned()
+    }
+}
+
+/// The verdict on one subject of an action.
+struct Judgement {
+    verdict: Verdict,
+    /// Whether a denial withholds a capped result.
+    withheld: bool,
+    /// Whether an allowed derivation is so because its result is noised.
+    noised: bool,
+}
+
+impl Judgement {
+    fn of(verdict: Verdict) -> Self {
+        Judgement {
+            verdict,
+            withheld: false,
+            noised: false,
+        }
+    }
+}
+
+/// The transformations required by the subjects of a decision so far.
+#[derive(Default)]
+struct Required {
+    noise: Option<NoisyRelease>,
+    masking: Masking,
+    sanitized: Vec<String>,
+    watermark: Option<Watermark>,
+    exact_columns: Vec<String>,
+    row_cap: Option<MaxOutputRows>,
+}
+
+impl Required {
+    /// Fails if the percentiles masks of a fetched artifact read are too stale.
+    fn add(&mut self, action: Action, subject: &Subject, noised: bool) -> Result<(), Status> {
+        if let Action::Query | Action::Persist = action {
+            return Ok(());
+        }
+        let artifact = subject.artifact;
+        let noise = match (
+            action,
+            &artifact.noise,
+            artifact.policy.differential_privacy(),
+        ) {
+            (Action::Fetch, noise, _) => noise.clone(),
+            // The budgets spent are those of the data noisy results were computed from.
+            (Action::Derive, noise, Some(privacy)) if noised => Some(NoisyRelease::new(
+                privacy.clone(),
+                match noise {
+                    Some(noise) => noise.sources.clone(),
+                    None => vec![subject.identifier.to_owned()],
+                },
+            )),
+            _ => None,
+        };
+        if let Some(noise) = noise {
+            self.noise = Some(match &self.noise {
+                Some(merged) => merged.merge(&noise),
+                None => noise,
+            });
+        }
+        // Results carry the masks of their inputs over in their policy instead.
+        if action == Action::Fetch {
+            self.masking.merge(&artifact.masking()?);
+        }
+        extend_unique(&mut self.sanitized, &artifact.blacklist);
+        if let Some(watermark) = artifact.policy.watermark() {
+            self.watermark = Some(match &self.watermark {
+                Some(merged) => merged.merge(watermark),
+                None => watermark.clone(),
+            });
+        }
+        // Exact columns apply to the watermarks of the other inputs too.
+        extend_unique(&mut self.exact_columns, artifact.policy.exact_columns());
+        if action == Action::Derive {
+            self.row_cap = merge_max_output_rows(self.row_cap, artifact.policy.max_output_rows());
+        }
+        Ok(())
+    }
+
+    fn into_transformations(self) -> Vec<Transformation> {
+        let mut transformations = Vec::new();
+        if let Some(noise) = self.noise {
+            transformations.push(Transformation::Noise(noise));
+        }
+        if !self.masking.is_empty() {
+            transformations.push(Transformation::Mask(self.masking));
+        }
+        if !self.sanitized.is_empty() {
+            transformations.push(Transformation::Sanitize(self.sanitized));
+        }
+        if let Some(watermark) = self.watermark {
+            transformations.push(Transformation::Watermark {
+                watermark,
+                exact_columns: self.exact_columns,
+            });
+        }
+        if let Some(cap) = self.row_cap {
+            transformations.push(Transformation::CapRows(cap));
+        }
+        transformations
+    }
+}
+
+fn extend_unique(columns: &mut Vec<String>, other: &[String]) {
+    for column in other {
+        if !columns.contains(column) {
+            columns.push(column.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::{Policy, Rule};
+    use crate::composite_plan::{CompositePlan, CompositePlanSegment};
+    use crate::output_rows::OutputRowsMode;
+    use crate::purpose::RequirePurpose;
+    use crate::{BastionLabPolars, FetchStatus};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::sync::Arc;
+
+    const IDENTITIES: [&str; 3] = ["alice", "bob", "carol"];
+    const COLUMNS: [&str; 3] = ["a", "b", "c"];
+    const CODES: [&str; 3] = ["research", "billing", "marketing"];
+
+    fn server(strict_governance: bool) -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_value(serde_json::json!({
+            "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+            "public_keys_directory": "keys/",
+            "session_expiry_in_secs": 3600,
+            "strict_governance": strict_governance,
+        }))
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn identity() -> impl Strategy<Value = String> {
+        prop::sample::select(IDENTITIES.to_vec()).prop_map(String::from)
+    }
+
+    fn columns() -> impl Strategy<Value = Vec<String>> {
+        prop::sample::subsequence(COLUMNS.to_vec(), 0..=COLUMNS.len())
+            .prop_map(|columns| columns.into_iter().map(String::from).collect())
+    }
+
+    fn purpose() -> impl Strategy<Value = Option<Purpose>> {
+        prop::option::of(
+            prop::sample::select(CODES.to_vec()).prop_map(|code| Purpose {
+                code: String::from(code),
+                text: String::new(),
+            }),
+        )
+    }
+
+    fn rule() -> impl Strategy<Value = Rule> {
+        let leaf = prop_oneof![
+            Just(Rule::TrueRule),
+            Just(Rule::FalseRule),
+            identity().prop_map(|id| Rule::UserId { id }),
+            (0u64..4).prop_map(|min_agg_size| Rule::Aggregation { min_agg_size }),
+        ];
+        leaf.prop_recursive(3, 12, 3, |inner| {
+            (0usize..3, vec(inner, 0..3)).prop_map(|(n, of)| Rule::AtLeastNOf { n, of })
+        })
+    }
+
+    fn policy() -> impl Strategy<Value = Policy> {
+        (
+            rule(),
+            prop::sample::select(vec!["Log", "Review", "Reject"]),
+            any::<bool>(),
+            prop::option::of((columns(), 0.01f64..1.0)),
+            columns(),
+            prop::option::of((1u64..8, any::<bool>())),
+            prop::option::of(prop::sample::subsequence(CODES[..2].to_vec(), 0..=2)),
+        )
+            .prop_map(
+                |(safe_zone, unsafe_handling, savable, watermark, exact, cap, codes)| {
+                    let watermark =
+                        watermark.map(|(columns, epsilon)| Watermark { columns, epsilon });
+                    let cap = cap.map(|(limit, reject)| MaxOutputRows {
+                        limit,
+                        mode: match reject {
+                            true => OutputRowsMode::Reject,
+                            false => OutputRowsMode::Truncate,
+                        },
+                    });
+                    let require_purpose = codes.map(|codes| RequirePurpose {
+                        allowed_codes: codes.into_iter().map(String::from).collect(),
+                    });
+                    serde_json::from_value(serde_json::json!({
+                        "safe_zone": safe_zone,
+                        "unsafe_handling": {"type": unsafe_handling},
+                        "savable": savable,
+                        "watermark": watermark,
+                        "exact_columns": exact,
+                        "max_output_rows": cap,
+                        "require_purpose": require_purpose,
+                    }))
+                    .unwrap()
+                },
+            )
+    }
+
+    fn fetchable() -> impl Strategy<Value = VerificationResult> {
+        prop_oneof![
+            Just(VerificationResult::Safe),
+            prop::sample::select(vec![
+                UnsafeAction::Log,
+                UnsafeAction::Review,
+                UnsafeAction::Reject
+            ])
+            .prop_map(|action| VerificationResult::Unsafe {
+                action,
+                reason: format!("{action:?} by the policy"),
+            }),
+        ]
+    }
+
+    fn stats() -> impl Strategy<Value = StatsEntry> {
+        (0u64..6, 1u64..3).prop_map(|(agg_size, join_scaling)| StatsEntry {
+            agg_size,
+            join_scaling,
+        })
+    }
+
+    /// An artifact an action may be taken on, with the stats of a query reading it.
+    #[derive(Debug, Clone)]
+    struct Input {
+        policy: Policy,
+        blacklist: Vec<String>,
+        fetchable: VerificationResult,
+        withheld: bool,
+        stats: StatsEntry,
+    }
+
+    fn input() -> impl Strategy<Value = Input> {
+        (
+            policy(),
+            columns(),
+            fetchable(),
+            prop::bool::weighted(0.1),
+            stats(),
+        )
+            .prop_map(|(policy, blacklist, fetchable, withheld, stats)| Input {
+                policy,
+                blacklist,
+                fetchable,
+                withheld,
+                stats,
+            })
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop::sample::select(vec![
+            Action::Query,
+            Action::Derive,
+            Action::Fetch,
+            Action::Persist,
+        ])
+    }
+
+    fn dataframe() -> DataFrame {
+        df! {
+            "a" => (0..50).map(|i| i as f64 * 1.37).collect::<Vec<_>>(),
+            "b" => (0..50).map(|i| i as f64 * 0.41).collect::<Vec<_>>(),
+            "c" => (0..50).map(|i| (i % 7) as f64).collect::<Vec<_>>(),
+        }
+        .unwrap()
+    }
+
+    fn artifacts(inputs: &[Input]) -> Vec<(String, DataFrameArtifact)> {
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let mut artifact = DataFrameArtifact::new(
+                    dataframe(),
+                    input.policy.clone(),
+                    input.blacklist.clone(),
+                )
+                .with_fetchable(input.fetchable.clone());
+                if input.withheld {
+                    artifact.capped_output = Some(CappedOutput::Rejected { limit: 1, rows: 50 });
+                }
+                (format!("df{i}"), artifact)
+            })
+            .collect()
+    }
+
+    fn subjects<'a>(
+        artifacts: &'a [(String, DataFrameArtifact)],
+        inputs: &[Input],
+    ) -> Vec<Subject<'a>> {
+        artifacts
+            .iter()
+            .zip(inputs)
+            .map(|((identifier, artifact), input)| {
+                Subject::input(identifier, artifact, input.stats)
+            })
+            .collect()
+    }
+
+    fn entry_point(identifier: &str) -> CompositePlanSegment {
+        CompositePlanSegment::EntryPointPlanSegment {
+            identifier: identifier.to_string(),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn strict_governance_denies_what_no_rule_allows(
+            inputs in vec(input(), 0..4),
+            identity in identity(),
+        ) {
+            let engine = PolicyEngine::new(true, 64);
+            let artifacts = artifacts(&inputs);
+            let subjects = subjects(&artifacts, &inputs);
+            let context = EvaluationContext::default();
+
+            let decision = engine.evaluate(Action::Derive, &identity, &subjects, &context).unwrap();
+            let allowed = !inputs.is_empty()
+                && artifacts.iter().zip(&inputs).all(|((identifier, artifact), input)| {
+                    artifact.policy.verify(&Context {
+                        stats: input.stats,
+                        user_id: identity.clone(),
+                        df_identifier: identifier.clone(),
+                    })
+                    .unwrap()
+                        == VerificationResult::Safe
+                });
+            match decision.verdict() {
+                Verdict::Allow => prop_assert!(allowed),
+                Verdict::Deny(_) => prop_assert!(!allowed),
+                verdict => prop_assert!(false, "unexpected verdict {:?}", verdict),
+            }
+
+            // Results recorded as fetchable with a warning or a review are denied too.
+            let decision = engine.evaluate(Action::Fetch, &identity, &subjects, &context).unwrap();
+            let fetchable = !inputs.is_empty()
+                && inputs.iter().all(|input| {
+                    input.fetchable == VerificationResult::Safe
+                        && !input.withheld
+                        && input.policy.require_purpose().is_none()
+                });
+            prop_assert_eq!(decision.verdict() == &Verdict::Allow, fetchable);
+            prop_assert!(matches!(decision.verdict(), Verdict::Allow | Verdict::Deny(_)));
+        }
+
+        #[test]
+        fn the_most_restrictive_verdict_wins(
+            inputs in vec(input(), 0..4),
+            identity in identity(),
+            action in action(),
+            purpose in purpose(),
+            strict_governance in any::<bool>(),
+        ) {
+            let engine = PolicyEngine::new(strict_governance, 64);
+            let artifacts = artifacts(&inputs);
+            let subjects = subjects(&artifacts, &inputs);
+            let context = EvaluationContext { purpose: purpose.as_ref() };
+
+            let decision = engine.evaluate(action, &identity, &subjects, &context).unwrap();
+            // Actions on nothing are denied by default under strict governance.
+            let mut strictest = if subjects.is_empty() && strict_governance { 3 } else { 0 };
+            for subject in subjects.iter() {
+                let alone = engine
+                    .evaluate(action, &identity, std::slice::from_ref(subject), &context)
+                    .unwrap();
+                strictest = strictest.max(alone.verdict().severity());
+                prop_assert_eq!(decision.verdict_of(subject.identifier), Some(alone.verdict()));
+                // Reasons are kept, whichever input they come from.
+                if alone.verdict().severity() == decision.verdict().severity() {
+                    let reason = alone.verdict().reason().unwrap_or_default();
+                    prop_assert!(decision.verdict().reason().unwrap_or_default().contains(reason));
+                }
+            }
+            prop_assert_eq!(decision.verdict().severity(), strictest);
+        }
+
+        #[test]
+        fn transformations_of_every_input_are_required(
+            inputs in vec(input(), 0..4),
+            identity in identity(),
+            action in prop::sample::select(vec![Action::Derive, Action::Fetch]),
+        ) {
+            let engine = PolicyEngine::new(false, 64);
+            let artifacts = artifacts(&inputs);
+            let subjects = subjects(&artifacts, &inputs);
+
+            let decision = engine
+                .evaluate(action, &identity, &subjects, &EvaluationContext::default())
+                .unwrap();
+            for input in inputs.iter() {
+                for column in input.blacklist.iter() {
+                    prop_assert!(decision.sanitized_columns().contains(column));
+                }
+                if let Some(required) = input.policy.watermark() {
+                    let (watermark, exact_columns) = decision.watermark().unwrap();
+                    prop_assert!(required.columns.iter().all(|c| watermark.columns.contains(c)));
+                    prop_assert!(watermark.epsilon <= required.epsilon);
+                    let exact = input.policy.exact_columns();
+                    prop_assert!(exact.iter().all(|c| exact_columns.contains(c)));
+                }
+                if let (Some(required), Action::Derive) = (input.policy.max_output_rows(), action) {
+                    let cap = decision.row_cap().unwrap();
+                    prop_assert!(cap.limit <= required.limit);
+                    if required.mode == OutputRowsMode::Reject {
+                        prop_assert_eq!(cap.mode, OutputRowsMode::Reject);
+                    }
+                }
+            }
+            if inputs.iter().all(|input| input.policy.max_output_rows().is_none()) {
+                prop_assert_eq!(decision.row_cap(), None);
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(48))]
+
+        #[test]
+        fn data_is_only_streamed_under_recorded_permits(
+            inputs in vec((policy(), columns()), 1..4),
+            identity in identity(),
+            purpose in purpose(),
+            strict_governance in any::<bool>(),
+        ) {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let state = server(strict_governance);
+            let mut identifiers: Vec<String> = inputs
+                .into_iter()
+                .map(|(policy, blacklist)| {
+                    state.insert_df(DataFrameArtifact::new(dataframe(), policy, blacklist))
+                })
+                .collect();
+            let mut segments: Vec<_> = identifiers.iter().map(|i| entry_point(i)).collect();
+            segments.extend((1..identifiers.len()).map(|_| CompositePlanSegment::StackPlanSegment));
+            let result = CompositePlan::new(segments).run(&state, &identity).unwrap();
+            identifiers.push(state.insert_df(result));
+
+            for identifier in identifiers.iter() {
+                let fetched = state.get_df(identifier, true, &identity, purpose.as_ref(), None);
+                let delayed = match fetched {
+                    Ok(delayed) => delayed,
+                    Err(e) => {
+                        prop_assert_eq!(e.code(), tonic::Code::PermissionDenied);
+                        continue;
+                    }
+                };
+                // Pending fetches wait for the data owner, on the standard input.
+                if let FetchStatus::Pending(_) = delayed.fetch_status {
+                    continue;
+                }
+                let released = runtime.block_on(delayed.future).unwrap();
+                let record = state.policy_engine.recorded(released.decision()).unwrap();
+                prop_assert!(record.verdict.permits());
+                prop_assert_eq!(record.action, Action::Fetch);
+                prop_assert_eq!(&record.identity, &identity);
+                prop_assert_eq!(&record.subjects, &vec![identifier.clone()]);
+            }
+        }
+    }
+
+    #[test]
+    fn only_permits_and_approvals_are_granted() {
+        let engine = PolicyEngine::new(false, 64);
+        let policy = Policy::allow_by_default();
+        let artifact = |fetchable| {
+            DataFrameArtifact::new(dataframe(), policy.clone(), Vec::new())
+                .with_fetchable(fetchable)
+        };
+        let evaluate = |artifact: &DataFrameArtifact| {
+            engine
+                .evaluate(
+                    Action::Fetch,
+                    "alice",
+                    &[Subject::artifact("df", artifact)],
+                    &EvaluationContext::default(),
+                )
+                .unwrap()
+        };
+        let unsafe_fetch = |action| VerificationResult::Unsafe {
+            action,
+            reason: String::from("not aggregated"),
+        };
+
+        let allowed = evaluate(&artifact(VerificationResult::Safe));
+        assert!(engine.grant(&allowed).is_ok());
+        assert!(engine.approve(&allowed, "alice").is_err());
+
+        let rejected = evaluate(&artifact(unsafe_fetch(UnsafeAction::Reject)));
+        let err = engine.grant(&rejected).err().unwrap();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(engine.approve(&rejected, "alice").is_err());
+
+        let pending = evaluate(&artifact(unsafe_fetch(UnsafeAction::Review)));
+        assert!(engine.grant(&pending).is_err());
+        let released = engine
+            .approve(&pending, "alice")
+            .unwrap()
+            .release_unmarked(dataframe())
+            .unwrap();
+        let record = engine.recorded(released.decision()).unwrap();
+        assert_eq!(record.verdict, Verdict::Allow);
+        assert_ne!(record.id, pending.id());
+        assert_eq!(
+            engine.recorded(pending.id()).unwrap().verdict,
+            Verdict::Pending(String::from("not aggregated"))
+        );
+    }
+
+    /// Watermarks used to be dropped from the results of safe queries.
+    #[tokio::test]
+    async fn safe_results_keep_the_watermarks_of_their_inputs() {
+        let state = server(false);
+        let policy: Policy = serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "TrueRule"},
+            "unsafe_handling": {"type": "Log"},
+            "savable": true,
+            "watermark": {"columns": ["a", "b"], "epsilon": 0.5},
+            "exact_columns": ["b"],
+        }))
+        .unwrap();
+        let input = state.insert_df(DataFrameArtifact::new(dataframe(), policy, Vec::new()));
+        let plan = CompositePlan::new(vec![entry_point(&input)]);
+        let result = state.insert_df(plan.run(&state, "alice").unwrap());
+
+        let delayed = state.get_df(&result, true, "alice", None, None).unwrap();
+        assert_eq!(delayed.fetch_status, FetchStatus::Ok);
+        let df = delayed.future.await.unwrap().into_dataframe();
+        let original = dataframe();
+        assert!(!df
+            .column("a")
+            .unwrap()
+            .series_equal(original.column("a").unwrap()));
+        assert!(df
+            .column("b")
+            .unwrap()
+            .series_equal(original.column("b").unwrap()));
+        let traced = state.watermarker.trace(&df).unwrap();
+        assert_eq!(traced[0].record.recipient, "alice");
+    }
+}
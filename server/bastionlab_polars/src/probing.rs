@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use polars::prelude::*;
+use serde_json::Value;
+use tonic::Status;
+
+use crate::prelude::*;
+
+/// Tunables of the probing detector.
+#[derive(Debug, Clone)]
+pub struct ProbingConfig {
+    /// How long past queries are remembered.
+    pub window: Duration,
+    /// Number of queries differing only in one literal that are considered a sweep.
+    pub literal_variants: usize,
+    /// Number of pairs of scalar counts differing by exactly 1 within the window that are flagged.
+    pub count_pairs: usize,
+    /// Maximum number of queries remembered per identity and dataset.
+    pub history: usize,
+}
+
+/// A composite plan with its literals taken out.
+///
+/// Two plans with the same `structure` only differ by the values of their literals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalPlan {
+    structure: String,
+    literals: Vec<String>,
+}
+
+fn strip_literals(value: &mut Value, literals: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if key == "Literal" {
+                    literals.push(v.to_string());
+                    *v = Value::Null;
+                } else {
+                    strip_literals(v, literals);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| strip_literals(v, literals)),
+        _ => (),
+    }
+}
+
+impl CanonicalPlan {
+    /// Canonicalizes a serialized plan by replacing every literal expression with a placeholder.
+    pub fn new(plan: &Value) -> Self {
+        let mut structure = plan.clone();
+        let mut literals = Vec::new();
+        strip_literals(&mut structure, &mut literals);
+        CanonicalPlan {
+            structure: structure.to_string(),
+            literals,
+        }
+    }
+
+    /// Returns the position of the only literal that differs between the two plans, if any.
+    fn single_difference(&self, other: &Self) -> Option<usize> {
+        if self.structure != other.structure || self.literals.len() != other.literals.len() {
+            return None;
+        }
+        let mut diffs = self
+            .literals
+            .iter()
+            .zip(other.literals.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i);
+        match (diffs.next(), diffs.next()) {
+            (Some(i), None) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the value of a result made of a single integer cell, such as a count.
+fn scalar_count(df: &DataFrame) -> Option<i64> {
+    if df.shape() != (1, 1) {
+        return None;
+    }
+    let series = &df.get_columns()[0];
+    if !series.dtype().is_integer() {
+        return None;
+    }
+    series.cast(&DataType::Int64).ok()?.i64().ok()?.get(0)
+}
+
+struct Observation {
+    at: Instant,
+    plan: CanonicalPlan,
+    count: Option<i64>,
+    /// Number of earlier counts of the window this one differed from by exactly 1.
+    pairs: usize,
+}
+
+#[derive(Default)]
+struct DetectorState {
+    history: HashMap<(String, String), VecDeque<Observation>>,
+    suspended: HashMap<(String, String), Instant>,
+}
+
+/// Flags query patterns that look like differencing attacks.
+///
+/// Queries are tracked per identity and dataset over a sliding window. Two patterns are detected:
+/// many queries that only differ by one literal (typically a filter value being swept), and
+/// scalar counts that differ by exactly 1 from earlier ones (isolating a single row).
+pub struct ProbingDetector {
+    config: ProbingConfig,
+    state: Mutex<DetectorState>,
+}
+
+impl ProbingDetector {
+    pub fn new(config: ProbingConfig) -> Self {
+        ProbingDetector {
+            config,
+            state: Mutex::new(DetectorState::default()),
+        }
+    }
+
+    /// Fails if `identity` is currently suspended on one of `datasets`.
+    pub fn check_suspended(&self, identity: &str, datasets: &[String]) -> Result<(), Status> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.suspended.retain(|_, until| *until > now);
+        for dataset in datasets {
+            if let Some(until) = state.suspended.get(&(identity.to_owned(), dataset.clone())) {
+                return Err(Status::permission_denied(format!(
+                    "Queries on {} are suspended for {} seconds because of suspicious activity",
+                    dataset,
+                    until.duration_since(now).as_secs() + 1
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn suspend(&self, identity: &str, datasets: &[String], duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + duration;
+        for dataset in datasets {
+            state
+                .suspended
+                .insert((identity.to_owned(), dataset.clone()), until);
+        }
+    }
+
+    /// Records a query and its result, returning the reason why it looks like probing, if it does.
+    pub fn observe(
+        &self,
+        identity: &str,
+        datasets: &[String],
+        plan: &CanonicalPlan,
+        result: &DataFrame,
+    ) -> Option<String> {
+        self.observe_at(
+            Instant::now(),
+            identity,
+            datasets,
+            plan,
+            scalar_count(result),
+        )
+    }
+
+    fn observe_at(
+        &self,
+        now: Instant,
+        identity: &str,
+        datasets: &[String],
+        plan: &CanonicalPlan,
+        count: Option<i64>,
+    ) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let window = self.config.window;
+        // Keep the state bounded: forget datasets that have not been queried within the window.
+        state.history.retain(|_, queries| {
+            queries
+                .back()
+                .is_some_and(|q| now.duration_since(q.at) < window)
+        });
+
+        let mut reason = None;
+        for dataset in datasets {
+            let queries = state
+                .history
+                .entry((identity.to_owned(), dataset.clone()))
+                .or_default();
+            while queries
+                .front()
+                .is_some_and(|q| now.duration_since(q.at) >= window)
+            {
+                queries.pop_front();
+            }
+
+            let pairs = count_pairs(queries, plan, count);
+            if reason.is_none() {
+                reason = self.detect(queries, plan, pairs, dataset);
+            }
+
+            queries.push_back(Observation {
+                at: now,
+                plan: plan.clone(),
+                count,
+                pairs,
+            });
+            while queries.len() > self.config.history {
+                queries.pop_front();
+            }
+        }
+        reason
+    }
+
+    fn detect(
+        &self,
+        queries: &VecDeque<Observation>,
+        plan: &CanonicalPlan,
+        pairs: usize,
+        dataset: &str,
+    ) -> Option<String> {
+        let mut variants: HashMap<usize, Vec<&str>> = HashMap::new();
+        for q in queries.iter() {
+            if let Some(pos) = plan.single_difference(&q.plan) {
+                let values = variants.entry(pos).or_default();
+                if !values.contains(&q.plan.literals[pos].as_str()) {
+                    values.push(&q.plan.literals[pos]);
+                }
+            }
+        }
+        if let Some(n) = variants.values().map(|v| v.len() + 1).max() {
+            if n >= self.config.literal_variants {
+                return Some(format!(
+                    "{} queries on {} only differ by the value of one literal",
+                    n, dataset
+                ));
+            }
+        }
+
+        let total = pairs + queries.iter().map(|q| q.pairs).sum::<usize>();
+        if pairs > 0 && self.config.count_pairs > 0 && total >= self.config.count_pairs {
+            return Some(format!(
+                "{} pairs of counts on {} differ by exactly 1",
+                total, dataset
+            ));
+        }
+        None
+    }
+}
+
+/// Number of earlier counts of different plans that differ by exactly 1 from `count`.
+fn count_pairs(queries: &VecDeque<Observation>, plan: &CanonicalPlan, count: Option<i64>) -> usize {
+    let count = match count {
+        Some(count) => count,
+        None => return 0,
+    };
+    queries
+        .iter()
+        .filter(|q| q.plan != *plan)
+        .filter_map(|q| q.count)
+        .filter(|c| (c - count).abs() == 1)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn detector() -> ProbingDetector {
+        ProbingDetector::new(ProbingConfig {
+            window: Duration::from_secs(60),
+            literal_variants: 5,
+            count_pairs: 2,
+            history: 64,
+        })
+    }
+
+    /// A filtered count, shaped like the serialized polars plans.
+    fn count_where(column: &str, value: i64) -> CanonicalPlan {
+        CanonicalPlan::new(&json!([
+            {"type": "EntryPointPlanSegment", "identifier": "patients"},
+            {"type": "PolarsPlanSegment", "plan": {"Aggregate": {
+                "input": {"Selection": {"input": "Stack", "predicate": {"BinaryExpr": {
+                    "left": {"Column": column},
+                    "op": "Eq",
+                    "right": {"Literal": {"Int64": value}},
+                }}}},
+                "aggs": [{"Agg": {"Count": {"Column": "id"}}}],
+            }}},
+        ]))
+    }
+
+    fn run(d: &ProbingDetector, trace: &[(CanonicalPlan, Option<i64>)]) -> Vec<String> {
+        let start = Instant::now();
+        let datasets = vec![String::from("patients")];
+        trace
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (plan, count))| {
+                let at = start + Duration::from_secs(i as u64);
+                d.observe_at(at, "mallory", &datasets, plan, *count)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn canonical_plans_ignore_literal_values() {
+        let a = count_where("age", 30);
+        let b = count_where("age", 31);
+        assert_eq!(a.structure, b.structure);
+        assert_eq!(a.single_difference(&b), Some(0));
+        assert_ne!(a.structure, count_where("zip", 30).structure);
+
+        let expr = |v: i64| serde_json::to_value(col("age").gt(lit(v))).unwrap();
+        let (a, b) = (CanonicalPlan::new(&expr(30)), CanonicalPlan::new(&expr(31)));
+        assert_eq!(a.literals.len(), 1);
+        assert_eq!(a.single_difference(&b), Some(0));
+    }
+
+    #[test]
+    fn literal_sweeps_are_flagged() {
+        let trace: Vec<_> = (0..6).map(|age| (count_where("age", age), None)).collect();
+        let findings = run(&detector(), &trace);
+        assert_eq!(findings.len(), 2);
+        assert!(findings[0].contains("5 queries"));
+    }
+
+    #[test]
+    fn differencing_counts_are_flagged() {
+        // Each pair isolates one patient: a total, then the same total without the target.
+        let trace = [
+            (count_where("ward", 1), Some(120)),
+            (count_where("zip", 75001), Some(119)),
+            (count_where("age", 44), Some(87)),
+            (count_where("height", 182), Some(86)),
+        ];
+        let findings = run(&detector(), &trace);
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].contains("2 pairs of counts"));
+    }
+
+    #[test]
+    fn benign_traces_are_not_flagged() {
+        // An analyst exploring a few columns, repeating identical queries and getting unrelated counts.
+        let trace = [
+            (count_where("age", 30), Some(1200)),
+            (count_where("age", 30), Some(1200)),
+            (count_where("age", 40), Some(950)),
+            (count_where("ward", 3), Some(87)),
+            (count_where("ward", 4), Some(88)),
+            (count_where("zip", 75001), Some(412)),
+            (count_where("zip", 75002), Some(390)),
+            (count_where("age", 50), Some(610)),
+        ];
+        assert!(run(&detector(), &trace).is_empty());
+    }
+
+    #[test]
+    fn old_queries_leave_the_window() {
+        let d = detector();
+        let start = Instant::now();
+        let datasets = vec![String::from("patients")];
+        for age in 0..4 {
+            let at = start + Duration::from_secs(age as u64 * 30);
+            assert!(d
+                .observe_at(at, "mallory", &datasets, &count_where("age", age), None)
+                .is_none());
+        }
+        assert!(d
+            .state
+            .lock()
+            .unwrap()
+            .history
+            .values()
+            .all(|q| q.len() <= 2));
+    }
+
+    #[test]
+    fn suspensions_are_per_dataset() {
+        let d = detector();
+        d.suspend(
+            "mallory",
+            &[String::from("patients")],
+            Duration::from_secs(60),
+        );
+        assert!(d
+            .check_suspended("mallory", &[String::from("patients")])
+            .is_err());
+        assert!(d
+            .check_suspended("mallory", &[String::from("billing")])
+            .is_ok());
+        assert!(d
+            .check_suspended("alice", &[String::from("patients")])
+            .is_ok());
+    }
+}
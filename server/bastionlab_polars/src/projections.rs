@@ -0,0 +1,224 @@
+//! Column selections of a composite plan, without writing a polars plan.
+//!
+//! A `SelectPlanSegment` narrows the dataframe on top of the stack to some of its columns, to run
+//! heavier segments on less data. Columns are selected by name, optionally renamed, then by dtype
+//! group, e.g. every numeric column:
+//!
+//! ```json
+//! {"type": "SelectPlanSegment",
+//!  "columns": [{"name": "site"}, {"name": "age", "alias": "years"}],
+//!  "dtypes": ["Float"]}
+//! ```
+//!
+//! Named columns come first, in the order given, then the columns of the selected dtypes in schema
+//! order. Columns are checked against the schema of the input before anything runs, and can only
+//! be named once.
+//!
+//! Blacklisted columns can be selected: as for polars segments, they stay blacklisted in the
+//! result, under their alias too, so that they are masked when it is fetched. Selecting them
+//! discloses nothing by itself.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::reserved::is_reserved;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectedColumn {
+    pub name: String,
+    /// Name of the column in the result, its own name if unset.
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+impl SelectedColumn {
+    pub fn output(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Groups of dtypes columns can be selected by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DtypeGroup {
+    /// Integers and floats.
+    Numeric,
+    Integer,
+    Float,
+    String,
+    Boolean,
+    /// Dates, datetimes, durations and times.
+    Temporal,
+}
+
+impl DtypeGroup {
+    pub fn name(self) -> &'static str {
+        match self {
+            DtypeGroup::Numeric => "numeric",
+            DtypeGroup::Integer => "integer",
+            DtypeGroup::Float => "float",
+            DtypeGroup::String => "string",
+            DtypeGroup::Boolean => "boolean",
+            DtypeGroup::Temporal => "temporal",
+        }
+    }
+
+    fn contains(self, dtype: &DataType) -> bool {
+        let integer = matches!(
+            dtype,
+            DataType::Int8
+                | DataType::Int16
+                | DataType::Int32
+                | DataType::Int64
+                | DataType::UInt8
+                | DataType::UInt16
+                | DataType::UInt32
+                | DataType::UInt64
+        );
+        let float = matches!(dtype, DataType::Float32 | DataType::Float64);
+        match self {
+            DtypeGroup::Numeric => integer || float,
+            DtypeGroup::Integer => integer,
+            DtypeGroup::Float => float,
+            DtypeGroup::String => matches!(dtype, DataType::Utf8),
+            DtypeGroup::Boolean => matches!(dtype, DataType::Boolean),
+            DtypeGroup::Temporal => matches!(
+                dtype,
+                DataType::Date | DataType::Datetime(_, _) | DataType::Duration(_) | DataType::Time
+            ),
+        }
+    }
+}
+
+/// The columns of `schema` selected by `columns` then `dtypes`, in the order of the result.
+pub fn selection(
+    schema: &Schema,
+    columns: &[SelectedColumn],
+    dtypes: &[DtypeGroup],
+) -> Result<Vec<SelectedColumn>, Status> {
+    let mut selected = Vec::new();
+    for column in columns {
+        if schema.get(&column.name).is_none() {
+            return Err(Status::invalid_argument(format!(
+                "Could not select: no column `{}` in the input",
+                column.name
+            )));
+        }
+        // Renames are recorded once per column, see `record_aliases`.
+        if selected
+            .iter()
+            .any(|other: &SelectedColumn| other.name == column.name)
+        {
+            return Err(Status::invalid_argument(format!(
+                "Could not select: column `{}` is selected twice",
+                column.name
+            )));
+        }
+        selected.push(column.clone());
+    }
+    for (name, dtype) in schema.iter() {
+        let named = columns.iter().any(|column| &column.name == name);
+        if !named && dtypes.iter().any(|group| group.contains(dtype)) {
+            selected.push(SelectedColumn {
+                name: name.to_string(),
+                alias: None,
+            });
+        }
+    }
+    if selected.is_empty() {
+        return Err(Status::invalid_argument(
+            "Could not select: no column of the input is selected",
+        ));
+    }
+    let mut outputs: Vec<&str> = Vec::with_capacity(selected.len());
+    for column in selected.iter() {
+        let output = column.output();
+        if output.trim().is_empty() || is_reserved(output) {
+            return Err(Status::invalid_argument(format!(
+                "Could not select `{}`: invalid name `{output}`",
+                column.name
+            )));
+        }
+        if outputs.contains(&output) {
+            return Err(Status::invalid_argument(format!(
+                "Could not select: the result would have two columns named `{output}`"
+            )));
+        }
+        outputs.push(output);
+    }
+    Ok(selected)
+}
+
+/// The polars plan selecting columns of `df`, whose dataframe scan stands for the input, see
+/// [`crate::composite_plan`].
+pub fn plan(
+    df: &DataFrame,
+    columns: &[SelectedColumn],
+    dtypes: &[DtypeGroup],
+) -> Result<LogicalPlan, Status> {
+    let exprs: Vec<Expr> = selection(&df.schema(), columns, dtypes)?
+        .iter()
+        .map(|column| match &column.alias {
+            // Renames are recorded from the plan, for blacklisted columns to stay so.
+            Some(alias) => col(&column.name).alias(alias),
+            None => col(&column.name),
+        })
+        .collect();
+    Ok(df.head(Some(0)).lazy().select(exprs).logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, alias: Option<&str>) -> SelectedColumn {
+        SelectedColumn {
+            name: name.to_string(),
+            alias: alias.map(String::from),
+        }
+    }
+
+    #[test]
+    fn named_columns_come_before_those_of_the_selected_dtypes() {
+        let df = df! {
+            "id" => [1i64],
+            "site" => ["A"],
+            "score" => [1.5f64],
+            "count" => [3u32],
+        }
+        .unwrap();
+        let schema = df.schema();
+        let outputs = |columns: &[SelectedColumn], dtypes: &[DtypeGroup]| {
+            selection(&schema, columns, dtypes).map(|selected| {
+                selected
+                    .iter()
+                    .map(|column| column.output().to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let selected = outputs(&[column("site", Some("place"))], &[DtypeGroup::Numeric]);
+        assert_eq!(selected.unwrap(), ["place", "id", "score", "count"]);
+        let selected = outputs(&[column("count", None)], &[DtypeGroup::Integer]);
+        assert_eq!(selected.unwrap(), ["count", "id"]);
+        assert_eq!(outputs(&[], &[DtypeGroup::Float]).unwrap(), ["score"]);
+
+        let err = outputs(&[column("age", None)], &[]).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not select: no column `age` in the input"
+        );
+        let err = outputs(&[column("id", Some("score"))], &[DtypeGroup::Float]).unwrap_err();
+        assert!(
+            err.message().contains("two columns named `score`"),
+            "{err:?}"
+        );
+        assert!(outputs(&[], &[DtypeGroup::Temporal]).is_err());
+        let twice = [column("id", None), column("id", Some("key"))];
+        assert!(outputs(&twice, &[]).is_err());
+        assert!(outputs(&[column("id", Some("__bastionlab_id"))], &[]).is_err());
+
+        let plan = plan(&df, &[column("site", Some("place"))], &[]).unwrap();
+        assert!(format!("{plan:?}").contains(r#"col("site").alias("place")"#));
+    }
+}
@@ -0,0 +1,367 @@
+//! Purposes: why requesters run queries and fetch results.
+//!
+//! Queries and fetches may state a purpose: a code, from a list the data owner defines, and free
+//! text. Policies can require one with [`RequirePurpose`], in which case requests without a
+//! purpose or with a code outside of the list are rejected. Results inherit the requirement of the
+//! dataframes they were computed from, so fetching them needs a purpose too.
+//!
+//! Purposes end up in logs, in approval prompts and in the access log, so they are checked on
+//! arrival: codes are short identifiers, and control characters are stripped from texts, which are
+//! truncated to [`MAX_PURPOSE_TEXT`] characters.
+//!
+//! The access log keeps the last accesses in memory, from which data owners get usage reports
+//! grouped by purpose code.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::polars_proto;
+
+/// Longest purpose code, in bytes.
+pub const MAX_PURPOSE_CODE: usize = 64;
+/// Longest purpose text, in characters. Longer texts are truncated.
+pub const MAX_PURPOSE_TEXT: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Purpose {
+    pub code: String,
+    pub text: String,
+}
+
+impl Purpose {
+    /// Checks and sanitizes the purpose of a request, `None` if it states none.
+    pub fn from_proto(purpose: Option<polars_proto::Purpose>) -> Result<Option<Self>, Status> {
+        let purpose = match purpose {
+            Some(purpose) if !purpose.code.is_empty() || !purpose.text.is_empty() => purpose,
+            _ => return Ok(None),
+        };
+        if purpose.code.is_empty() {
+            return Err(Status::invalid_argument("A purpose needs a code"));
+        }
+        if purpose.code.len() > MAX_PURPOSE_CODE
+            || !purpose
+                .code
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+        {
+            return Err(Status::invalid_argument(format!(
+                "Purpose codes are at most {MAX_PURPOSE_CODE} letters, digits, '_', '-', '.' or ':'"
+            )));
+        }
+        Ok(Some(Purpose {
+            code: purpose.code,
+            text: sanitize_text(&purpose.text),
+        }))
+    }
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.text.as_str() {
+            "" => write!(f, "[{}]", self.code),
+            text => write!(f, "[{}] {}", self.code, text),
+        }
+    }
+}
+
+/// Replaces control characters, line breaks included, by spaces and truncates to
+/// [`MAX_PURPOSE_TEXT`] characters.
+fn sanitize_text(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(MAX_PURPOSE_TEXT)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Policy rule: requests must state a purpose with one of `allowed_codes`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequirePurpose {
+    pub allowed_codes: Vec<String>,
+}
+
+impl RequirePurpose {
+    /// Codes allowed by both rules.
+    pub fn merge(&self, other: &Self) -> Self {
+        RequirePurpose {
+            allowed_codes: self
+                .allowed_codes
+                .iter()
+                .filter(|code| other.allowed_codes.contains(code))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn check(&self, purpose: Option<&Purpose>, identifier: &str) -> Result<(), Status> {
+        match purpose {
+            None => Err(Status::permission_denied(format!(
+                "The data owner's policy requires a purpose to access {identifier}, one of: {}",
+                self.allowed_codes.join(", ")
+            ))),
+            Some(purpose) if !self.allowed_codes.contains(&purpose.code) => {
+                Err(Status::permission_denied(format!(
+                    "Purpose {} is not allowed by the data owner's policy on {identifier}, expected one of: {}",
+                    purpose.code,
+                    self.allowed_codes.join(", ")
+                )))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+pub fn merge_require_purpose(
+    a: Option<&RequirePurpose>,
+    b: Option<&RequirePurpose>,
+) -> Option<RequirePurpose> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(b)),
+        (a, b) => a.or(b).cloned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessKind {
+    Query,
+    Fetch,
+    /// A result sent to another server, see [`crate::federation`].
+    Transfer,
+    /// A dataframe received from another server.
+    Upload,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    /// Milliseconds since the Unix epoch.
+    pub at: u64,
+    pub user_id: String,
+    pub kind: AccessKind,
+    /// The dataframe fetched or the query result.
+    pub identifier: String,
+    /// The dataframes read to compute it.
+    pub inputs: Vec<String>,
+    /// Owners of the dataframe and of its inputs, at the time of the access.
+    pub owners: Vec<String>,
+    pub purpose: Option<Purpose>,
+    /// Shared by the records of the servers a federated query went through, see
+    /// [`crate::federation`].
+    pub correlation_id: Option<String>,
+}
+
+impl AccessRecord {
+    fn touches(&self, identifier: &str) -> bool {
+        self.identifier == identifier || self.inputs.iter().any(|input| input == identifier)
+    }
+}
+
+/// Accesses of one purpose code, accesses without a purpose having an empty code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurposeUsage {
+    pub queries: u64,
+    pub fetches: u64,
+    pub transfers: u64,
+    pub users: BTreeSet<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct AccessLog {
+    records: RwLock<VecDeque<AccessRecord>>,
+    capacity: usize,
+}
+
+impl AccessLog {
+    pub fn new(capacity: usize) -> Self {
+        AccessLog {
+            records: Default::default(),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, record: AccessRecord) {
+        let mut records = self.records.write().unwrap();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        if self.capacity > 0 {
+            records.push_back(record);
+        }
+    }
+
+    /// Usage by purpose code of the accesses since `since` to the data of `owner`, restricted to
+    /// those that touched `identifier` if set.
+    pub fn usage(
+        &self,
+        owner: &str,
+        identifier: Option<&str>,
+        since: u64,
+    ) -> BTreeMap<String, PurposeUsage> {
+        let mut usage: BTreeMap<String, PurposeUsage> = BTreeMap::new();
+        let records = self.records.read().unwrap();
+        for record in records.iter().filter(|record| {
+            record.at >= since
+                && record.owners.iter().any(|o| o == owner)
+                && identifier.map_or(true, |identifier| record.touches(identifier))
+        }) {
+            let code = record
+                .purpose
+                .as_ref()
+                .map_or(String::new(), |purpose| purpose.code.clone());
+            let entry = usage.entry(code).or_default();
+            match record.kind {
+                AccessKind::Query => entry.queries += 1,
+                AccessKind::Fetch => entry.fetches += 1,
+                AccessKind::Transfer => entry.transfers += 1,
+                // Uploads are not accesses to the data of others.
+                AccessKind::Upload => continue,
+            }
+            entry.users.insert(record.user_id.clone());
+        }
+        usage
+    }
+
+    /// The accesses recorded under `correlation_id`, oldest first.
+    pub fn correlated(&self, correlation_id: &str) -> Vec<AccessRecord> {
+        let records = self.records.read().unwrap();
+        records
+            .iter()
+            .filter(|record| record.correlation_id.as_deref() == Some(correlation_id))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::{Policy, UnsafeAction, VerificationResult};
+    use crate::{approval_purposes, BastionLabPolars, DataFrameArtifact, FetchStatus};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use std::sync::Arc;
+
+    fn server() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    fn purpose(code: &str, text: &str) -> Result<Option<Purpose>, Status> {
+        Purpose::from_proto(Some(polars_proto::Purpose {
+            code: code.to_string(),
+            text: text.to_string(),
+        }))
+    }
+
+    #[test]
+    fn purposes_are_sanitized() {
+        assert_eq!(purpose("", "").unwrap(), None);
+        assert!(purpose("", "no code").is_err());
+        assert!(purpose("research\nINFO forged", "").is_err());
+        assert!(purpose(&"a".repeat(MAX_PURPOSE_CODE + 1), "").is_err());
+
+        let sanitized = purpose("research", "cohort study\r\nINFO forged line")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sanitized.text, "cohort study  INFO forged line");
+        let long = purpose("research", &"é".repeat(2 * MAX_PURPOSE_TEXT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(long.text.chars().count(), MAX_PURPOSE_TEXT);
+    }
+
+    #[test]
+    fn rules_check_codes() {
+        let rule = RequirePurpose {
+            allowed_codes: vec![String::from("research"), String::from("billing")],
+        };
+        let research = purpose("research", "").unwrap();
+        assert!(rule.check(research.as_ref(), "df").is_ok());
+        let marketing = purpose("marketing", "").unwrap();
+        assert!(rule.check(marketing.as_ref(), "df").is_err());
+        assert!(rule.check(None, "df").is_err());
+
+        let other = RequirePurpose {
+            allowed_codes: vec![String::from("billing")],
+        };
+        let merged = merge_require_purpose(Some(&rule), Some(&other)).unwrap();
+        assert_eq!(merged.allowed_codes, vec![String::from("billing")]);
+        assert!(merged.check(research.as_ref(), "df").is_err());
+    }
+
+    #[test]
+    fn the_log_forgets_the_oldest_accesses() {
+        let log = AccessLog::new(2);
+        for (at, code) in [(0, "a"), (1, "b"), (2, "b")] {
+            log.record(AccessRecord {
+                at,
+                user_id: String::from("user"),
+                kind: AccessKind::Query,
+                identifier: String::from("result"),
+                inputs: vec![String::from("input")],
+                owners: vec![String::from("owner")],
+                purpose: purpose(code, "").unwrap(),
+                correlation_id: None,
+            });
+        }
+        let usage = log.usage("owner", Some("input"), 0);
+        assert_eq!(usage.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(usage["b"].queries, 2);
+        assert!(log.usage("owner", Some("other"), 0).is_empty());
+        assert!(log.usage("other", None, 0).is_empty());
+        assert_eq!(log.usage("owner", None, 2)["b"].queries, 1);
+    }
+
+    #[test]
+    fn pending_approvals_show_the_purposes() {
+        let state = server();
+        let policy: Policy = serde_json::from_value(serde_json::json!({
+            "safe_zone": {"type": "TrueRule"},
+            "unsafe_handling": {"type": "Review"},
+            "savable": true,
+            "require_purpose": {"allowed_codes": ["research"]},
+        }))
+        .unwrap();
+        let df = df! { "x" => [1i64, 2] }.unwrap();
+        let mut artifact = DataFrameArtifact::new(df, policy, Vec::new()).with_fetchable(
+            VerificationResult::Unsafe {
+                action: UnsafeAction::Review,
+                reason: String::from("not aggregated"),
+            },
+        );
+        artifact.purpose = purpose("research", "query\ntext").unwrap();
+        let identifier = state.insert_df(artifact);
+
+        let err = state
+            .get_df(&identifier, true, "analyst", None, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let fetch = purpose("research", "fetch text").unwrap();
+        let delayed = state
+            .get_df(&identifier, true, "analyst", fetch.as_ref(), None)
+            .unwrap();
+        assert!(matches!(delayed.fetch_status, FetchStatus::Pending(_)));
+
+        let query = state
+            .with_df_artifact_ref(&identifier, |artifact| artifact.purpose.clone())
+            .unwrap();
+        let prompt = approval_purposes(fetch.as_ref(), query.as_ref());
+        assert_eq!(
+            prompt,
+            "Purpose of the fetch: [research] fetch text\nPurpose of the query: [research] query text\n"
+        );
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::VecDeque;
+
+use polars::{lazy::dsl::Expr, prelude::*};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::catalog::now_ms;
+
+/// Number of breach events kept per dataframe.
+const MAX_HISTORY: usize = 100;
+
+/// A data-quality constraint monitored on every append.
+///
+/// `NullRate`, `RowCount` and `Range` are evaluated incrementally from running counts, while
+/// `Unique` and `Expr` need a full scan of the dataframe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Constraint {
+    /// The share of nulls in `column` must stay at or below `max`.
+    NullRate {
+        column: String,
+        max: f64,
+    },
+    RowCount {
+        min: Option<usize>,
+        max: Option<usize>,
+    },
+    /// Every non-null value of `column` must be within the bounds.
+    Range {
+        column: String,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Unique {
+        column: String,
+    },
+    /// A polars expression that must evaluate to `true` over the whole dataframe.
+    Expr {
+        expr: Expr,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Severity {
+    /// Record the breach and let the mutation through.
+    Warn,
+    /// Record the breach and reject the mutation.
+    Block,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitoredConstraint {
+    pub name: String,
+    pub constraint: Constraint,
+    pub severity: Severity,
+}
+
+/// Running counts of an incremental constraint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RunningCounts {
+    rows: usize,
+    nulls: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConstraintState {
+    pub constraint: MonitoredConstraint,
+    pub ok: bool,
+    pub observed: String,
+    counts: RunningCounts,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QualityEvent {
+    pub constraint: String,
+    pub observed: String,
+    pub version: u64,
+    pub blocked: bool,
+    /// Seconds since the UNIX epoch.
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QualityMonitor {
+    constraints: Vec<ConstraintState>,
+    history: VecDeque<QualityEvent>,
+}
+
+/// The outcome of evaluating the constraints on a mutation, see [`QualityMonitor::check`].
+pub struct QualityCheck {
+    states: Vec<ConstraintState>,
+    pub events: Vec<QualityEvent>,
+    pub blocked: bool,
+}
+
+#[derive(Serialize)]
+pub struct QualityStatus<'a> {
+    pub version: u64,
+    pub constraints: &'a [ConstraintState],
+    pub history: &'a VecDeque<QualityEvent>,
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::invalid_argument(format!("Polars error while checking data quality: {e}"))
+}
+
+fn column<'a>(df: &'a DataFrame, name: &str) -> Result<&'a Series, Status> {
+    df.column(name).map_err(polars_err)
+}
+
+impl Constraint {
+    fn is_incremental(&self) -> bool {
+        !matches!(self, Constraint::Unique { .. } | Constraint::Expr { .. })
+    }
+
+    /// Adds the rows of `delta` to the running counts.
+    fn accumulate(&self, counts: &mut RunningCounts, delta: &DataFrame) -> Result<(), Status> {
+        counts.rows += delta.height();
+        match self {
+            Constraint::NullRate { column: name, .. } => {
+                counts.nulls += column(delta, name)?.null_count();
+            }
+            Constraint::Range { column: name, .. } => {
+                let values = column(delta, name)?
+                    .cast(&DataType::Float64)
+                    .map_err(polars_err)?;
+                let values = values.f64().map_err(polars_err)?;
+                if let Some(min) = values.min() {
+                    counts.min = Some(counts.min.map_or(min, |m| m.min(min)));
+                }
+                if let Some(max) = values.max() {
+                    counts.max = Some(counts.max.map_or(max, |m| m.max(max)));
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Evaluates the constraint, returning whether it holds and the observed value.
+    ///
+    /// `full` is only used by constraints that are not incremental.
+    fn evaluate(
+        &self,
+        counts: &RunningCounts,
+        full: Option<&DataFrame>,
+    ) -> Result<(bool, String), Status> {
+        Ok(match self {
+            Constraint::NullRate { max, .. } => {
+                let rate = if counts.rows == 0 {
+                    0.0
+                } else {
+                    counts.nulls as f64 / counts.rows as f64
+                };
+                (rate <= *max, format!("null rate {rate:.4}"))
+            }
+            Constraint::RowCount { min, max } => (
+                min.is_none_or(|m| counts.rows >= m) && max.is_none_or(|m| counts.rows <= m),
+                format!("{} rows", counts.rows),
+            ),
+            Constraint::Range { min, max, .. } => (
+                min.is_none_or(|m| counts.min.is_none_or(|v| v >= m))
+                    && max.is_none_or(|m| counts.max.is_none_or(|v| v <= m)),
+                format!("values in [{:?}, {:?}]", counts.min, counts.max),
+            ),
+            Constraint::Unique { column: name } => {
+                let series = column(full.unwrap(), name)?;
+                let duplicates = series.len() - series.n_unique().map_err(polars_err)?;
+                (duplicates == 0, format!("{duplicates} duplicates"))
+            }
+            Constraint::Expr { expr } => {
+                let res = full
+                    .unwrap()
+                    .clone()
+                    .lazy()
+                    .select([expr.clone()])
+                    .collect()
+                    .map_err(polars_err)?;
+                let value = match res.get_columns().first().map(|s| s.bool()) {
+                    Some(Ok(values)) if values.len() == 1 => values.get(0),
+                    _ => {
+                        return Err(Status::invalid_argument(
+                            "Quality expressions must evaluate to a single boolean",
+                        ))
+                    }
+                };
+                (value == Some(true), format!("{value:?}"))
+            }
+        })
+    }
+}
+
+impl QualityMonitor {
+    /// Replaces the monitored constraints, evaluating them with a full scan of `df`.
+    pub fn set_constraints(
+        &mut self,
+        constraints: Vec<MonitoredConstraint>,
+        df: &DataFrame,
+    ) -> Result<(), Status> {
+        let mut states = Vec::with_capacity(constraints.len());
+        for constraint in constraints {
+            let mut counts = RunningCounts::default();
+            constraint.constraint.accumulate(&mut counts, df)?;
+            let (ok, observed) = constraint.constraint.evaluate(&counts, Some(df))?;
+            states.push(ConstraintState {
+                constraint,
+                ok,
+                observed,
+                counts,
+            });
+        }
+        self.constraints = states;
+        Ok(())
+    }
+
+    pub fn status(&self, version: u64) -> QualityStatus<'_> {
+        QualityStatus {
+            version,
+            constraints: &self.constraints,
+            history: &self.history,
+        }
+    }
+
+    /// Evaluates the constraints as if `delta` was appended to `current`, without applying anything.
+    ///
+    /// The concatenated dataframe is only built when a constraint needs a full scan.
+    pub fn check(
+        &self,
+        current: &DataFrame,
+        delta: &DataFrame,
+        version: u64,
+    ) -> Result<QualityCheck, Status> {
+        let full = if self
+            .constraints
+            .iter()
+            .any(|c| !c.constraint.constraint.is_incremental())
+        {
+            Some(current.vstack(delta).map_err(polars_err)?)
+        } else {
+            None
+        };
+
+        self.evaluate_all(version, full.as_ref(), |state| {
+            let mut counts = state.counts.clone();
+            state.constraint.constraint.accumulate(&mut counts, delta)?;
+            Ok(counts)
+        })
+    }
+
+    /// Evaluates the constraints from scratch on `df`, which is about to replace the dataframe.
+    ///
+    /// This is used for updates, after which running counts cannot be adjusted incrementally.
+    pub fn check_replacement(&self, df: &DataFrame, version: u64) -> Result<QualityCheck, Status> {
+        self.evaluate_all(version, Some(df), |state| {
+            let mut counts = RunningCounts::default();
+            state.constraint.constraint.accumulate(&mut counts, df)?;
+            Ok(counts)
+        })
+    }
+
+    fn evaluate_all(
+        &self,
+        version: u64,
+        full: Option<&DataFrame>,
+        counts: impl Fn(&ConstraintState) -> Result<RunningCounts, Status>,
+    ) -> Result<QualityCheck, Status> {
+        let mut states = Vec::with_capacity(self.constraints.len());
+        let mut events = Vec::new();
+        let mut blocked = false;
+        for state in self.constraints.iter() {
+            let counts = counts(state)?;
+            let (ok, observed) = state.constraint.constraint.evaluate(&counts, full)?;
+            if !ok {
+                let block = state.constraint.severity == Severity::Block;
+                blocked |= block;
+                events.push(QualityEvent {
+                    constraint: state.constraint.name.clone(),
+                    observed: observed.clone(),
+                    version,
+                    blocked: block,
+                    time: now_ms() / 1000,
+                });
+            }
+            states.push(ConstraintState {
+                constraint: state.constraint.clone(),
+                ok,
+                observed,
+                counts,
+            });
+        }
+        for event in events.iter_mut() {
+            event.blocked = blocked;
+        }
+        Ok(QualityCheck {
+            states,
+            events,
+            blocked,
+        })
+    }
+
+    /// Records the outcome of a check. The new constraint states are only kept if the mutation
+    /// went through.
+    pub fn commit(&mut self, check: QualityCheck) {
+        if !check.blocked {
+            self.constraints = check.states;
+        }
+        for event in check.events {
+            self.history.push_back(event);
+        }
+        while self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::lazy::dsl::col;
+
+    fn monitored(name: &str, constraint: Constraint, severity: Severity) -> MonitoredConstraint {
+        MonitoredConstraint {
+            name: name.to_string(),
+            constraint,
+            severity,
+        }
+    }
+
+    fn batch(ids: &[i64], scores: &[Option<f64>]) -> DataFrame {
+        df! { "id" => ids, "score" => scores }.unwrap()
+    }
+
+    /// Appends through the monitor like the server does and returns whether the append went through.
+    fn append(
+        monitor: &mut QualityMonitor,
+        df: &mut DataFrame,
+        delta: DataFrame,
+        version: u64,
+    ) -> bool {
+        let check = monitor.check(df, &delta, version).unwrap();
+        let blocked = check.blocked;
+        monitor.commit(check);
+        if !blocked {
+            df.vstack_mut(&delta).unwrap();
+        }
+        !blocked
+    }
+
+    #[test]
+    fn incremental_constraints_follow_appends() {
+        let mut df = batch(&[1, 2], &[Some(0.5), Some(0.7)]);
+        let mut monitor = QualityMonitor::default();
+        monitor
+            .set_constraints(
+                vec![
+                    monitored(
+                        "few nulls",
+                        Constraint::NullRate {
+                            column: "score".into(),
+                            max: 0.25,
+                        },
+                        Severity::Warn,
+                    ),
+                    monitored(
+                        "bounded",
+                        Constraint::RowCount {
+                            min: None,
+                            max: Some(6),
+                        },
+                        Severity::Block,
+                    ),
+                ],
+                &df,
+            )
+            .unwrap();
+
+        // 1 null out of 4 rows is within the limit.
+        assert!(append(
+            &mut monitor,
+            &mut df,
+            batch(&[3, 4], &[None, Some(0.1)]),
+            1
+        ));
+        assert!(monitor.history.is_empty());
+
+        // 2 nulls out of 6 rows breaches the warning constraint but the append goes through.
+        assert!(append(
+            &mut monitor,
+            &mut df,
+            batch(&[5, 6], &[None, Some(0.2)]),
+            2
+        ));
+        assert_eq!(monitor.history.len(), 1);
+        assert_eq!(monitor.history[0].constraint, "few nulls");
+        assert_eq!(monitor.history[0].version, 2);
+        assert!(!monitor.constraints[0].ok);
+        assert_eq!(monitor.constraints[1].counts.rows, 6);
+
+        // Going over 6 rows is blocked: nothing is applied but the breach is recorded.
+        assert!(!append(&mut monitor, &mut df, batch(&[7], &[Some(0.3)]), 3));
+        assert_eq!(df.height(), 6);
+        assert_eq!(monitor.constraints[1].counts.rows, 6);
+        assert!(monitor.history.back().unwrap().blocked);
+    }
+
+    #[test]
+    fn full_scan_constraints_see_the_whole_frame() {
+        let mut df = batch(&[1, 2], &[Some(0.5), Some(0.7)]);
+        let mut monitor = QualityMonitor::default();
+        monitor
+            .set_constraints(
+                vec![
+                    monitored(
+                        "unique ids",
+                        Constraint::Unique {
+                            column: "id".into(),
+                        },
+                        Severity::Block,
+                    ),
+                    monitored(
+                        "mean score",
+                        Constraint::Expr {
+                            expr: col("score").mean().lt(lit(0.6)),
+                        },
+                        Severity::Warn,
+                    ),
+                ],
+                &df,
+            )
+            .unwrap();
+        assert!(!monitor.constraints[1].ok);
+
+        assert!(append(
+            &mut monitor,
+            &mut df,
+            batch(&[3, 4], &[Some(0.1), Some(0.2)]),
+            1
+        ));
+        assert!(monitor.constraints.iter().all(|c| c.ok));
+
+        // A duplicate only shows up when looking at the existing rows too.
+        assert!(!append(&mut monitor, &mut df, batch(&[1], &[Some(0.1)]), 2));
+        assert_eq!(df.height(), 4);
+        assert_eq!(monitor.history.back().unwrap().constraint, "unique ids");
+    }
+
+    #[test]
+    fn non_boolean_expressions_are_rejected() {
+        let df = batch(&[1], &[Some(0.5)]);
+        let mut monitor = QualityMonitor::default();
+        let res = monitor.set_constraints(
+            vec![monitored(
+                "not a predicate",
+                Constraint::Expr {
+                    expr: col("score").sum(),
+                },
+                Severity::Warn,
+            )],
+            &df,
+        );
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Reproducibility bundles: what a third party needs to check that a result came from a plan.
+//!
+//! Queries record their provenance on their result: the plan they ran, once its entry points were
+//! resolved, and the version and policy of every dataframe they read. A bundle adds to it the
+//! content hashes of these inputs, the engine versions, the trace of the query and the schema and
+//! content hash of the result. It holds no data.
+//!
+//! Bundles are signed with the signing key of the server, whose public key is listed in its
+//! capabilities, so that they can be checked offline with [`open_bundle`]. Verifying a bundle
+//! checks its inputs against the current dataframes and runs its plan again.
+//!
+//! Content hashes do not depend on row order, since group-bys and joins do not order their output:
+//! results that only differ by the order of their rows have the same hash.
+
+use std::collections::HashMap;
+
+use polars::prelude::*;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+    ECDSA_P256_SHA256_ASN1_SIGNING,
+};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::access_control::Policy;
+use crate::outputs::MAIN_SLOT;
+use crate::serialization::{checksum, hash_dataset};
+use crate::versions::SEPARATOR;
+
+/// Version of the bundle format.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// A dataframe a query read, as it was when the query ran.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputVersion {
+    pub identifier: String,
+    pub version: u64,
+    /// Hash of the policy of the dataframe, see [`policy_hash`].
+    pub policy_hash: String,
+}
+
+impl InputVersion {
+    /// `identifier@version`.
+    pub fn key(&self) -> String {
+        format!("{}{SEPARATOR}{}", self.identifier, self.version)
+    }
+}
+
+/// Recorded on results, see the module documentation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The composite plan, with resolved entry points.
+    pub plan: serde_json::Value,
+    pub inputs: Vec<InputVersion>,
+    /// Output slot of the plan the result is, see [`crate::outputs`].
+    #[serde(default = "main_slot")]
+    pub slot: String,
+    /// What the query read of each input, for its policy to be evaluated again, see
+    /// [`crate::rollouts`]. Not part of bundles.
+    #[serde(default)]
+    pub input_stats: Vec<InputStats>,
+    /// Fingerprints of the inputs by [`InputVersion::key`], recorded when the result is stored,
+    /// see [`crate::fingerprints`]. Not part of bundles.
+    #[serde(default)]
+    pub fingerprints: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputStats {
+    pub identifier: String,
+    pub agg_size: u64,
+    pub join_scaling: u64,
+}
+
+fn main_slot() -> String {
+    MAIN_SLOT.to_string()
+}
+
+impl Provenance {
+    /// Records that the query read `version` of `identifier`, unless it already did.
+    pub fn read(&mut self, identifier: &str, version: u64, policy: &Policy) -> Result<(), Status> {
+        if self
+            .inputs
+            .iter()
+            .any(|input| input.identifier == identifier && input.version == version)
+        {
+            return Ok(());
+        }
+        self.inputs.push(InputVersion {
+            identifier: identifier.to_string(),
+            version,
+            policy_hash: policy_hash(policy)?,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleInput {
+    #[serde(flatten)]
+    pub input: InputVersion,
+    /// See [`content_hash`].
+    pub content_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: u32,
+    pub result: String,
+    /// The user the result belongs to.
+    pub owner: String,
+    /// In milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub plan: serde_json::Value,
+    /// SHA-256 of the serialized plan.
+    pub plan_hash: String,
+    /// Output slot of the plan the result is.
+    #[serde(default = "main_slot")]
+    pub slot: String,
+    pub inputs: Vec<BundleInput>,
+    pub semantics_version: u32,
+    pub engine: String,
+    pub server_version: String,
+    pub trace: Vec<String>,
+    /// Declared name and dtype of every column of the result.
+    pub schema: Vec<(String, String)>,
+    /// See [`content_hash`].
+    pub result_hash: String,
+}
+
+/// What is exchanged: the bundle as it was signed, and its signature.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedBundle {
+    bundle: String,
+    /// Hex-encoded ASN.1 ECDSA P-256 signature of `bundle`.
+    signature: String,
+    /// Hex-encoded public key of the server.
+    public_key: String,
+}
+
+/// Hash of the policy of an input, so that bundles show which policy was in force.
+pub fn policy_hash(policy: &Policy) -> Result<String, Status> {
+    let policy = serde_json::to_vec(policy)
+        .map_err(|e| Status::internal(format!("Could not serialize policy: {e}")))?;
+    Ok(checksum(&policy))
+}
+
+/// SHA-256 of the plan as serialized in bundles.
+pub fn plan_hash(plan: &serde_json::Value) -> Result<String, Status> {
+    let plan = serde_json::to_vec(plan)
+        .map_err(|e| Status::internal(format!("Could not serialize plan: {e}")))?;
+    Ok(checksum(&plan))
+}
+
+/// Hash of the content of `df`, regardless of the order of its rows.
+///
+/// Rows are sorted by every column before hashing, in their declared order if some column cannot
+/// be sorted.
+pub fn content_hash(df: &DataFrame) -> Result<String, Status> {
+    if df.width() == 0 {
+        return hash_dataset(df);
+    }
+    let columns: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let reverse = vec![false; columns.len()];
+    match df.sort(columns, reverse) {
+        Ok(sorted) => hash_dataset(&sorted),
+        Err(_) => hash_dataset(df),
+    }
+}
+
+/// Signs bundles.
+pub struct BundleSigner {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl BundleSigner {
+    /// A key that only lives as long as the server: bundles cannot be verified after a restart.
+    pub fn ephemeral() -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .expect("Could not generate a bundle signing key");
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+            .expect("Could not load the generated bundle signing key");
+        BundleSigner { key, rng }
+    }
+
+    /// Loads a PEM-encoded PKCS#8 ECDSA P-256 private key.
+    pub fn from_pkcs8_pem(pem: &[u8]) -> Result<Self, Status> {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse the bundle signing key: {e}"))
+        })?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &pem.contents)
+            .map_err(|e| Status::invalid_argument(format!("Invalid bundle signing key: {e}")))?;
+        Ok(BundleSigner {
+            key,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Hex-encoded, as listed in the capabilities of the server.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.public_key().as_ref())
+    }
+
+    /// Serializes and signs `bundle`.
+    pub fn seal(&self, bundle: &Bundle) -> Result<Vec<u8>, Status> {
+        let bundle = serde_json::to_string(bundle)
+            .map_err(|e| Status::internal(format!("Could not serialize bundle: {e}")))?;
+        let signature = self
+            .key
+            .sign(&self.rng, bundle.as_bytes())
+            .map_err(|_| Status::internal("Could not sign bundle"))?;
+        serde_json::to_vec(&SignedBundle {
+            bundle,
+            signature: hex::encode(signature.as_ref()),
+            public_key: self.public_key(),
+        })
+        .map_err(|e| Status::internal(format!("Could not serialize bundle: {e}")))
+    }
+}
+
+/// Checks that `archive` was signed by the server of hex-encoded `public_key`, and returns its
+/// bundle.
+pub fn open_bundle(archive: &[u8], public_key: &str) -> Result<Bundle, Status> {
+    let signed: SignedBundle = serde_json::from_slice(archive)
+        .map_err(|e| Status::invalid_argument(format!("Could not parse bundle: {e}")))?;
+    if signed.public_key != public_key {
+        return Err(Status::invalid_argument(
+            "The bundle was signed by another server",
+        ));
+    }
+    let key = hex::decode(public_key)
+        .map_err(|e| Status::invalid_argument(format!("Invalid public key: {e}")))?;
+    let signature = hex::decode(&signed.signature)
+        .map_err(|e| Status::invalid_argument(format!("Invalid bundle signature: {e}")))?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key)
+        .verify(signed.bundle.as_bytes(), &signature)
+        .map_err(|_| Status::invalid_argument("The signature of the bundle does not match"))?;
+    let bundle: Bundle = serde_json::from_str(&signed.bundle)
+        .map_err(|e| Status::invalid_argument(format!("Could not parse bundle: {e}")))?;
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(Status::invalid_argument(format!(
+            "Unsupported bundle format {}",
+            bundle.format
+        )));
+    }
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> Bundle {
+        Bundle {
+            format: BUNDLE_FORMAT,
+            result: String::from("result"),
+            owner: String::from("owner"),
+            created_at: 0,
+            plan: serde_json::json!({ "segments": [] }),
+            plan_hash: String::new(),
+            slot: main_slot(),
+            inputs: Vec::new(),
+            semantics_version: 1,
+            engine: String::from("polars 0.25.1"),
+            server_version: String::new(),
+            trace: Vec::new(),
+            schema: vec![(String::from("a"), String::from("i64"))],
+            result_hash: String::from("hash"),
+        }
+    }
+
+    #[test]
+    fn bundles_are_checked_against_the_signing_key() {
+        let signer = BundleSigner::ephemeral();
+        let archive = signer.seal(&bundle()).unwrap();
+        let opened = open_bundle(&archive, &signer.public_key()).unwrap();
+        assert_eq!(opened.result_hash, "hash");
+
+        let other = BundleSigner::ephemeral();
+        assert!(open_bundle(&archive, &other.public_key()).is_err());
+
+        // Tampering with the bundle breaks the signature.
+        let tampered = String::from_utf8(archive).unwrap().replace(
+            r#"\"result_hash\":\"hash\""#,
+            r#"\"result_hash\":\"forged\""#,
+        );
+        let err = open_bundle(tampered.as_bytes(), &signer.public_key()).unwrap_err();
+        assert!(err.message().contains("signature"), "{err:?}");
+    }
+
+    #[test]
+    fn content_hashes_ignore_row_order() {
+        let df = df! { "a" => [2i64, 1, 1], "b" => ["x", "z", "y"] }.unwrap();
+        let reversed = df.reverse();
+        assert_eq!(content_hash(&df).unwrap(), content_hash(&reversed).unwrap());
+        let changed = df! { "a" => [2i64, 1, 1], "b" => ["x", "z", "w"] }.unwrap();
+        assert_ne!(content_hash(&df).unwrap(), content_hash(&changed).unwrap());
+    }
+}
@@ -0,0 +1,134 @@
+use bastionlab_common::config::BlankColumnNames;
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::prelude::*;
+
+/// Namespace of the columns the server adds to dataframes while running queries.
+///
+/// Uploaded columns may not use it, and columns in it are dropped from every stored result.
+pub const RESERVED_PREFIX: &str = "__bastionlab_";
+
+pub const JOIN_LEFT_ROW: &str = "__bastionlab_join_left_row";
+pub const JOIN_RIGHT_ROW: &str = "__bastionlab_join_right_row";
+pub const GROUP_ROW: &str = "__bastionlab_group_row";
+/// Position of the rows of the input of a stable sort, see [`crate::sorts`].
+pub const SORT_ROW: &str = "__bastionlab_sort_row";
+pub const UPSERT_TARGET_ROW: &str = "__bastionlab_target_row";
+pub const UPSERT_INCOMING_ROW: &str = "__bastionlab_incoming_row";
+pub const DELTA_PREVIOUS_ROW: &str = "__bastionlab_previous_row";
+/// Columns of the deltas sent to clients, see [`crate::delta`].
+pub const DELTA_OPERATION: &str = "__bastionlab_delta_op";
+pub const DELTA_POSITION: &str = "__bastionlab_delta_position";
+/// Row of the input of a label encoding, see [`crate::outputs`].
+pub const ENCODE_ROW: &str = "__bastionlab_encode_row";
+/// Size of each group of a materialized view, see [`crate::views`].
+pub const VIEW_ROWS: &str = "__bastionlab_view_rows";
+/// Prefix of the columns counting the values of noisy means, see [`crate::differential_privacy`].
+pub const DP_VALUES_PREFIX: &str = "__bastionlab_dp_values_";
+
+pub fn is_reserved(name: &str) -> bool {
+    name.starts_with(RESERVED_PREFIX)
+}
+
+/// Checks the column names of uploaded rows, renaming blank ones if `blank_names` allows it.
+pub fn check_column_names(df: &mut DataFrame, blank_names: BlankColumnNames) -> Result<(), Status> {
+    let reserved: Vec<_> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|name| is_reserved(name))
+        .collect();
+    if !reserved.is_empty() {
+        return Err(Status::invalid_argument(format!(
+            "Column names starting with {RESERVED_PREFIX} are reserved: {}",
+            reserved.join(", ")
+        )));
+    }
+
+    let blank: Vec<_> = df
+        .get_column_names()
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| name.trim().is_empty())
+        .map(|(idx, _)| idx)
+        .collect();
+    if blank.is_empty() {
+        return Ok(());
+    }
+    if blank_names == BlankColumnNames::Reject {
+        return Err(Status::invalid_argument(format!(
+            "Columns {blank:?} have an empty name"
+        )));
+    }
+
+    let mut names: Vec<String> = df
+        .get_column_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    for idx in blank {
+        let mut name = format!("column_{idx}");
+        while names.contains(&name) {
+            name.push('_');
+        }
+        warn!("Renamed blank column {idx} to {name}");
+        names[idx] = name;
+    }
+    df.set_column_names(&names)
+        .map_err(|e| Status::invalid_argument(format!("Could not rename blank columns: {e}")))
+}
+
+/// Drops the columns of the reserved namespace.
+pub fn strip_internal_columns(df: &mut DataFrame) {
+    let internal: Vec<_> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|name| is_reserved(name))
+        .map(String::from)
+        .collect();
+    for name in internal {
+        let _ = df.drop_in_place(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        let mut df = df! { "id" => [1i64], "__bastionlab_row" => [0u32] }.unwrap();
+        let err = check_column_names(&mut df, BlankColumnNames::Rename).unwrap_err();
+        assert!(err.message().contains("__bastionlab_row"));
+
+        // Only the exact prefix is reserved.
+        let mut df = df! { "_bastionlab_row" => [0u32], "__bastion" => [1i64] }.unwrap();
+        assert!(check_column_names(&mut df, BlankColumnNames::Reject).is_ok());
+    }
+
+    #[test]
+    fn blank_names_are_rejected_or_renamed() {
+        let blank = || df! { "" => [1i64], "column_1" => [2i64], "  " => [3i64] }.unwrap();
+        assert!(check_column_names(&mut blank(), BlankColumnNames::Reject).is_err());
+
+        let mut df = blank();
+        check_column_names(&mut df, BlankColumnNames::Rename).unwrap();
+        assert_eq!(df.get_column_names(), ["column_0", "column_1", "column_2"]);
+
+        let mut df = df! { "column_1" => [1i64], " " => [2i64] }.unwrap();
+        check_column_names(&mut df, BlankColumnNames::Rename).unwrap();
+        assert_eq!(df.get_column_names(), ["column_1", "column_1_"]);
+    }
+
+    #[test]
+    fn internal_columns_are_stripped() {
+        let mut df = df! {
+            "id" => [1i64],
+            JOIN_LEFT_ROW => [0u32],
+            GROUP_ROW => [0u32],
+        }
+        .unwrap();
+        strip_internal_columns(&mut df);
+        assert_eq!(df.get_column_names(), ["id"]);
+    }
+}
@@ -0,0 +1,322 @@
+//! Resource hints of plan segments, and spilling of the segments that exceed them.
+//!
+//! Polars segments can carry hints: a memory limit, and whether their inputs may be spilled to
+//! disk when they go above it. Defaults come from the server config, and the policies of the
+//! dataframes a plan reads cap what its requester may ask for. Segments are named after their
+//! heaviest operation (join, groupby or sort) in the trace and in errors.
+//!
+//! Usage is observed rather than predicted: the inputs of a segment are measured before it runs
+//! and its output after, and both are recorded in the trace of the query. Without spilling, a
+//! segment that goes above its limit fails with `ResourceExhausted`. With spilling, inputs above
+//! the limit are written as IPC files to a directory of the query, within a quota, and read back
+//! memory-mapped; the directory is removed once the query completes or fails.
+//!
+//! This polars version has no out-of-core execution: spilled inputs are paged in by the OS as
+//! the segment reads them, and the output of a segment must still fit in memory.
+
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::prelude::*;
+use crate::visitable::Visitable;
+
+const MB: u64 = 1 << 20;
+
+/// What a requester asks for a segment, the server defaults apply to what is unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceHints {
+    #[serde(default)]
+    pub allow_spill: Option<bool>,
+    #[serde(default)]
+    pub max_memory_mb: Option<u64>,
+}
+
+/// What the policy of a dataframe lets requesters ask for the segments reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceCaps {
+    /// Highest memory limit requesters may set.
+    #[serde(default)]
+    pub max_memory_mb: Option<u64>,
+    /// Whether requesters may spill the data to disk.
+    #[serde(default = "default_allow_spill")]
+    pub allow_spill: bool,
+}
+
+fn default_allow_spill() -> bool {
+    true
+}
+
+impl ResourceCaps {
+    /// The strictest of both caps.
+    pub fn merge(self, other: Self) -> Self {
+        ResourceCaps {
+            max_memory_mb: match (self.max_memory_mb, other.max_memory_mb) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            allow_spill: self.allow_spill && other.allow_spill,
+        }
+    }
+}
+
+pub fn merge_resource_caps(
+    a: Option<ResourceCaps>,
+    b: Option<ResourceCaps>,
+) -> Option<ResourceCaps> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Limits of a segment, once hints, defaults and caps are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentLimits {
+    /// In bytes, unlimited if unset.
+    pub max_memory: Option<u64>,
+    pub allow_spill: bool,
+}
+
+/// Resource settings of the server.
+#[derive(Debug, Clone)]
+pub struct ResourceDefaults {
+    /// Memory limit of segments without a hint, in megabytes, 0 for none.
+    pub max_memory_mb: u64,
+    pub allow_spill: bool,
+    /// Where the spill directories of queries are created.
+    pub spill_dir: PathBuf,
+    /// Bytes each query may spill.
+    pub spill_quota: u64,
+}
+
+impl ResourceDefaults {
+    /// The limits of a segment hinted with `hints`, under `caps`, with notes on what was capped.
+    pub fn limits(
+        &self,
+        hints: ResourceHints,
+        caps: Option<ResourceCaps>,
+    ) -> (SegmentLimits, Vec<String>) {
+        let mut notes = Vec::new();
+        let mut max_memory_mb = hints
+            .max_memory_mb
+            .or((self.max_memory_mb > 0).then_some(self.max_memory_mb));
+        let mut allow_spill = hints.allow_spill.unwrap_or(self.allow_spill);
+        if let Some(caps) = caps {
+            if let Some(cap) = caps.max_memory_mb {
+                if max_memory_mb.map_or(true, |mb| mb > cap) {
+                    notes.push(format!(
+                        "Memory limit capped to {cap} MB by the data owner's policy"
+                    ));
+                    max_memory_mb = Some(cap);
+                }
+            }
+            if allow_spill && !caps.allow_spill {
+                notes.push(String::from("Spilling disabled by the data owner's policy"));
+                allow_spill = false;
+            }
+        }
+        (
+            SegmentLimits {
+                max_memory: max_memory_mb.map(|mb| mb * MB),
+                allow_spill,
+            },
+            notes,
+        )
+    }
+}
+
+/// Names a polars segment after its heaviest operation.
+pub fn segment_kind(plan: &LogicalPlan) -> Result<&'static str, Status> {
+    let mut kinds = Vec::new();
+    plan.visit(&mut kinds, |plan, kinds| {
+        match plan {
+            LogicalPlan::Join { .. } => kinds.push("join"),
+            LogicalPlan::Aggregate { .. } => kinds.push("groupby"),
+            LogicalPlan::Sort { .. } => kinds.push("sort"),
+            _ => (),
+        }
+        Ok(())
+    })?;
+    Ok(["join", "groupby", "sort"]
+        .into_iter()
+        .find(|kind| kinds.contains(kind))
+        .unwrap_or("select"))
+}
+
+/// Number of dataframes a polars segment reads from the stack.
+pub fn segment_inputs(plan: &LogicalPlan) -> Result<usize, Status> {
+    let mut inputs = 0;
+    plan.visit(&mut inputs, |plan, inputs| {
+        if let LogicalPlan::DataFrameScan { .. } = plan {
+            *inputs += 1;
+        }
+        Ok(())
+    })?;
+    Ok(inputs)
+}
+
+/// `bytes` in megabytes, for messages.
+pub fn mb(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / MB as f64)
+}
+
+/// The error of a segment that went above its memory limit without being allowed to spill.
+pub fn over_limit(segment: &str, usage: &str, limit: u64) -> Status {
+    Status::resource_exhausted(format!(
+        "{segment} used {usage}, above its limit of {}: allow it to spill or raise its max_memory_mb",
+        mb(limit)
+    ))
+}
+
+/// Temporary directory of a query, created on the first spill and removed when dropped.
+pub struct SpillDir {
+    root: PathBuf,
+    path: Option<PathBuf>,
+    /// In bytes.
+    quota: u64,
+    written: u64,
+}
+
+impl SpillDir {
+    pub fn new(defaults: &ResourceDefaults) -> Self {
+        SpillDir {
+            root: defaults.spill_dir.clone(),
+            path: None,
+            quota: defaults.spill_quota,
+            written: 0,
+        }
+    }
+
+    /// Bytes spilled so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Writes `df` to disk for `segment`, returning a plan reading it back memory-mapped.
+    pub fn spill(&mut self, segment: &str, df: &mut DataFrame) -> Result<LogicalPlan, Status> {
+        let io_err =
+            |e: std::io::Error| Status::internal(format!("Could not spill {segment} to disk: {e}"));
+        let polars_err =
+            |e: PolarsError| Status::internal(format!("Could not spill {segment} to disk: {e}"));
+        let dir = match &self.path {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = self
+                    .root
+                    .join(format!("bastionlab-spill-{}", uuid::Uuid::new_v4()));
+                fs::create_dir_all(&dir).map_err(io_err)?;
+                self.path = Some(dir.clone());
+                dir
+            }
+        };
+        let path = dir.join(format!("{}.arrow", uuid::Uuid::new_v4()));
+        IpcWriter::new(File::create(&path).map_err(io_err)?)
+            .finish(df)
+            .map_err(polars_err)?;
+        self.written += fs::metadata(&path).map_err(io_err)?.len();
+        if self.written > self.quota {
+            return Err(Status::resource_exhausted(format!(
+                "{segment} spilled {}, above the quota of {} per query",
+                mb(self.written),
+                mb(self.quota)
+            )));
+        }
+        let args = ScanArgsIpc {
+            memmap: true,
+            ..Default::default()
+        };
+        Ok(LazyFrame::scan_ipc(&path, args)
+            .map_err(polars_err)?
+            .logical_plan)
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_dir_all(path) {
+                warn!("Could not remove spill directory {path:?}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bastionlab_common::common_conversions::lazy_frame_from_logical_plan;
+
+    fn defaults(max_memory_mb: u64, allow_spill: bool) -> ResourceDefaults {
+        ResourceDefaults {
+            max_memory_mb,
+            allow_spill,
+            spill_dir: std::env::temp_dir()
+                .join(format!("bastionlab-spill-test-{}", uuid::Uuid::new_v4())),
+            spill_quota: MB,
+        }
+    }
+
+    #[test]
+    fn hints_fall_back_to_defaults_under_caps() {
+        let hints = |allow_spill, max_memory_mb| ResourceHints {
+            allow_spill,
+            max_memory_mb,
+        };
+        let limits = |max_memory_mb: Option<u64>, allow_spill| SegmentLimits {
+            max_memory: max_memory_mb.map(|mb| mb * MB),
+            allow_spill,
+        };
+
+        let (resolved, notes) = defaults(0, false).limits(ResourceHints::default(), None);
+        assert_eq!((resolved, notes.len()), (limits(None, false), 0));
+        let (resolved, _) = defaults(100, true).limits(hints(None, Some(10)), None);
+        assert_eq!(resolved, limits(Some(10), true));
+
+        let caps = ResourceCaps {
+            max_memory_mb: Some(50),
+            allow_spill: false,
+        };
+        let (resolved, notes) = defaults(100, true).limits(hints(Some(true), None), Some(caps));
+        assert_eq!(resolved, limits(Some(50), false));
+        assert_eq!(notes.len(), 2);
+        // Hints below the caps are kept.
+        let (resolved, notes) = defaults(0, false).limits(hints(None, Some(10)), Some(caps));
+        assert_eq!((resolved, notes.len()), (limits(Some(10), false), 0));
+
+        let loose = ResourceCaps {
+            max_memory_mb: None,
+            allow_spill: true,
+        };
+        assert_eq!(merge_resource_caps(Some(caps), Some(loose)), Some(caps));
+    }
+
+    #[test]
+    fn spill_directories_are_removed_with_their_query() {
+        let defaults = defaults(0, true);
+        let mut df = df! { "a" => (0..1000i64).collect::<Vec<_>>() }.unwrap();
+        let path = {
+            let mut spill = SpillDir::new(&defaults);
+            let plan = spill.spill("segment 0 (select)", &mut df).unwrap();
+            let read = lazy_frame_from_logical_plan(plan).collect().unwrap();
+            assert!(read.frame_equal(&df));
+            assert!(spill.written() > 0);
+            spill.path.clone().unwrap()
+        };
+        assert!(!path.exists());
+
+        // Going over the quota fails, and still cleans up.
+        let mut big = df! { "a" => (0..200_000i64).collect::<Vec<_>>() }.unwrap();
+        let mut spill = SpillDir::new(&defaults);
+        let err = spill.spill("segment 1 (join)", &mut big).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert!(err.message().contains("segment 1 (join)"), "{err:?}");
+        let path = spill.path.clone().unwrap();
+        drop(spill);
+        assert!(!path.exists());
+        fs::remove_dir_all(defaults.spill_dir).unwrap();
+    }
+}
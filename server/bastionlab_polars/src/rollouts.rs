@@ -0,0 +1,531 @@
+//! Policy rollouts: one policy change applied at once to every dataset a selector matches.
+//!
+//! A [`PolicySelector`] matches uploaded dataframes by tags, owner and name, and a [`PolicyPatch`]
+//! says how their policies change. Rollouts are first run dry: the impact report lists, for every
+//! matched dataset,
+//! - the results computed from it whose fetches would newly be held back or denied, their verdict
+//!   being derived again from what their query read of each input,
+//! - the results pending the data owner's approval whose verdict would change,
+//! - the registered pipelines whose queries would newly be denied.
+//!
+//! Applying a rollout patches every matched policy under one lock: if any patch fails, none is
+//! applied. The fetch verdicts of the results in the report change with them, and every change is
+//! recorded in the policy history of its dataset under the id of the rollout. A rollout can be
+//! rolled back within `policy_rollout_retention_secs`, unless a later one changed the same
+//! policies since.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::Status;
+
+use crate::access_control::{Policy, VerificationResult};
+use crate::catalog::{now_ms, CatalogEntry, DataFrameKind};
+use crate::composite_plan::StatsEntry;
+use crate::pipelines::PipelineVersion;
+use crate::policy_engine::{Action, EvaluationContext, PolicyEngine, Subject, Verdict};
+use crate::DataFrameArtifact;
+
+/// The uploaded dataframes a rollout applies to: those matching every set criterion.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicySelector {
+    pub tags: Vec<String>,
+    pub owner: Option<String>,
+    /// Names, where `*` matches any characters.
+    pub name_pattern: Option<String>,
+}
+
+impl PolicySelector {
+    /// Selectors must set a criterion: rolling out to every dataset is never implied.
+    pub fn check(&self) -> Result<(), Status> {
+        if self.tags.is_empty() && self.owner.is_none() && self.name_pattern.is_none() {
+            return Err(Status::invalid_argument(
+                "Policy rollouts must select datasets by tags, owner or name pattern",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, entry: &CatalogEntry) -> bool {
+        self.tags.iter().all(|tag| entry.tags.contains(tag))
+            && self
+                .owner
+                .as_ref()
+                .map_or(true, |owner| &entry.owner == owner)
+            && self
+                .name_pattern
+                .as_ref()
+                .map_or(true, |pattern| glob_match(pattern, &entry.name))
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// How a rollout changes policies.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum PolicyPatch {
+    /// Merges `policy` in, as results merge the policies of their inputs: the stricter applies.
+    Tighten { policy: Policy },
+    /// Replaces the given fields of the policy, in its serialized form.
+    Set {
+        fields: serde_json::Map<String, Value>,
+    },
+}
+
+impl PolicyPatch {
+    pub fn apply(&self, policy: &Policy) -> Result<Policy, Status> {
+        match self {
+            PolicyPatch::Tighten { policy: other } => Ok(policy.merge(other)),
+            PolicyPatch::Set { fields } => {
+                let invalid = |e: serde_json::Error| {
+                    Status::invalid_argument(format!("Invalid policy patch: {e}"))
+                };
+                let mut value = serde_json::to_value(policy).map_err(invalid)?;
+                let object = value
+                    .as_object_mut()
+                    .ok_or_else(|| Status::internal("Policies serialize to objects"))?;
+                for (field, field_value) in fields {
+                    if !object.contains_key(field) {
+                        return Err(Status::invalid_argument(format!(
+                            "Invalid policy patch: unknown policy field `{field}`"
+                        )));
+                    }
+                    object.insert(field.clone(), field_value.clone());
+                }
+                serde_json::from_value(value).map_err(invalid)
+            }
+        }
+    }
+}
+
+/// What a rollout does to what depends on one dataset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatasetImpact {
+    pub identifier: String,
+    /// Results whose fetches would newly be held back or denied.
+    pub blocked_results: Vec<String>,
+    /// Results pending approval whose verdict would change.
+    pub changed_approvals: Vec<String>,
+    /// Pipelines, as `name@version`, whose queries would newly be denied.
+    pub blocked_pipelines: Vec<String>,
+}
+
+/// A change of the policy of a dataset, in its policy history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicyChange {
+    /// Starts at 1, the policy given on upload being version 0.
+    pub version: u64,
+    pub rollout_id: String,
+    /// Milliseconds since the Unix epoch.
+    pub changed_at: u64,
+    pub author: String,
+    pub rollback: bool,
+    /// The policy from this version on.
+    pub policy: Policy,
+}
+
+/// What applying a rollout changes: the policies of the matched datasets and the fetch verdicts
+/// of the results computed from them.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub impacts: Vec<DatasetImpact>,
+    policies: Vec<(String, Policy)>,
+    fetchables: Vec<(String, VerificationResult)>,
+}
+
+impl Changes {
+    /// Identifiers of the dataframes the changes modify.
+    pub fn modified(&self) -> impl Iterator<Item = &str> {
+        self.policies
+            .iter()
+            .map(|(identifier, _)| identifier.as_str())
+            .chain(
+                self.fetchables
+                    .iter()
+                    .map(|(identifier, _)| identifier.as_str()),
+            )
+    }
+}
+
+/// The changes rolling `patch` out on the datasets of `dfs` that `selector` matches would make.
+///
+/// `pipelines` are the registered pipelines, with the dataframes their entry points read.
+pub fn plan(
+    dfs: &HashMap<String, DataFrameArtifact>,
+    selector: &PolicySelector,
+    patch: &PolicyPatch,
+    engine: &PolicyEngine,
+    pipelines: &[(PipelineVersion, Vec<String>)],
+) -> Result<Changes, Status> {
+    let mut policies = Vec::new();
+    for (identifier, artifact) in dfs.iter() {
+        if artifact.kind() == DataFrameKind::Upload && selector.matches(&artifact.catalog) {
+            let policy = patch
+                .apply(&artifact.policy)
+                .and_then(|policy| {
+                    policy.check_masks(&artifact.declared_schema())?;
+                    Ok(policy)
+                })
+                .map_err(|e| {
+                    Status::new(e.code(), format!("Dataset {identifier}: {}", e.message()))
+                })?;
+            policies.push((identifier.clone(), policy));
+        }
+    }
+    policies.sort_by(|a, b| a.0.cmp(&b.0));
+    let patched: HashMap<&str, &Policy> = policies
+        .iter()
+        .map(|(identifier, policy)| (identifier.as_str(), policy))
+        .collect();
+    let mut impacts: Vec<DatasetImpact> = policies
+        .iter()
+        .map(|(identifier, _)| DatasetImpact {
+            identifier: identifier.clone(),
+            ..Default::default()
+        })
+        .collect();
+    let mut impact_on = |identifier: &str| {
+        let index = impacts
+            .binary_search_by(|impact| impact.identifier.as_str().cmp(identifier))
+            .expect("impacts are listed for every patched dataset");
+        &mut impacts[index]
+    };
+
+    let mut fetchables = Vec::new();
+    let mut results: Vec<_> = dfs.iter().collect();
+    results.sort_by(|a, b| a.0.cmp(b.0));
+    for (identifier, artifact) in results {
+        let Some(provenance) = &artifact.provenance else {
+            continue;
+        };
+        let reads_patched = provenance
+            .input_stats
+            .iter()
+            .any(|input| patched.contains_key(input.identifier.as_str()));
+        // Results whose inputs were deleted cannot be derived again: they keep their verdict.
+        let inputs: Option<Vec<_>> = provenance
+            .input_stats
+            .iter()
+            .map(|input| Some((input, dfs.get(&input.identifier)?)))
+            .collect();
+        let Some(inputs) = inputs.filter(|_| reads_patched) else {
+            continue;
+        };
+        let subjects: Vec<_> = inputs
+            .iter()
+            .map(|(input, input_artifact)| {
+                let stats = StatsEntry {
+                    agg_size: input.agg_size,
+                    join_scaling: input.join_scaling,
+                };
+                Subject::input(&input.identifier, input_artifact, stats).with_policy(policy_of(
+                    &patched,
+                    &input.identifier,
+                    input_artifact,
+                ))
+            })
+            .collect();
+        let before = engine.verdict_of(&artifact.fetchable);
+        let after = engine.preview(
+            Action::Derive,
+            &artifact.catalog.owner,
+            &subjects,
+            &EvaluationContext::default(),
+        )?;
+        let blocked = before.permits() && !after.permits();
+        let changed_approval =
+            matches!(before, Verdict::Pending(_)) && !matches!(after, Verdict::Pending(_));
+        for (input, _) in inputs.iter() {
+            if !patched.contains_key(input.identifier.as_str()) {
+                continue;
+            }
+            let impact = impact_on(&input.identifier);
+            if blocked {
+                impact.blocked_results.push(identifier.clone());
+            }
+            if changed_approval {
+                impact.changed_approvals.push(identifier.clone());
+            }
+        }
+        if severity(&before) != severity(&after) {
+            fetchables.push((identifier.clone(), after.to_fetchable()));
+        }
+    }
+
+    for (pipeline, entry_points) in pipelines {
+        let reads: Vec<_> = entry_points
+            .iter()
+            .filter_map(|identifier| Some((identifier.as_str(), dfs.get(identifier)?)))
+            .collect();
+        if !reads
+            .iter()
+            .any(|(identifier, _)| patched.contains_key(identifier))
+        {
+            continue;
+        }
+        let verdict = |patch: bool| {
+            let subjects: Vec<_> = reads
+                .iter()
+                .map(|(identifier, artifact)| {
+                    let subject = Subject::artifact(identifier, artifact);
+                    match patch {
+                        true => subject.with_policy(policy_of(&patched, identifier, artifact)),
+                        false => subject,
+                    }
+                })
+                .collect();
+            engine.preview(
+                Action::Query,
+                &pipeline.author,
+                &subjects,
+                &EvaluationContext::default(),
+            )
+        };
+        let denied = |verdict: &Verdict| matches!(verdict, Verdict::Deny(_));
+        if !denied(&verdict(false)?) && denied(&verdict(true)?) {
+            for (identifier, _) in reads.iter() {
+                if patched.contains_key(identifier) {
+                    impact_on(identifier)
+                        .blocked_pipelines
+                        .push(format!("{}@{}", pipeline.name, pipeline.version));
+                }
+            }
+        }
+    }
+
+    Ok(Changes {
+        impacts,
+        policies,
+        fetchables,
+    })
+}
+
+fn policy_of<'a>(
+    patched: &HashMap<&str, &'a Policy>,
+    identifier: &str,
+    artifact: &'a DataFrameArtifact,
+) -> &'a Policy {
+    patched.get(identifier).copied().unwrap_or(&artifact.policy)
+}
+
+fn severity(verdict: &Verdict) -> u8 {
+    match verdict {
+        Verdict::Allow => 0,
+        Verdict::Warn(_) => 1,
+        Verdict::Pending(_) => 2,
+        Verdict::Deny(_) => 3,
+    }
+}
+
+/// An applied rollout.
+#[derive(Debug, Clone)]
+pub struct Rollout {
+    pub id: String,
+    pub author: String,
+    /// Milliseconds since the Unix epoch.
+    pub applied_at: u64,
+    pub impacts: Vec<DatasetImpact>,
+    pub rolled_back: bool,
+    /// Policies before and after the rollout, by dataset.
+    policies: Vec<(String, Policy, Policy)>,
+    /// Fetch verdicts before and after the rollout, by result.
+    fetchables: Vec<(String, VerificationResult, VerificationResult)>,
+}
+
+#[derive(Debug)]
+pub struct RolloutRegistry {
+    rollouts: RwLock<HashMap<String, Rollout>>,
+    /// How long rollouts can be rolled back, in milliseconds.
+    retention_ms: u64,
+}
+
+impl RolloutRegistry {
+    pub fn new(retention_ms: u64) -> Self {
+        RolloutRegistry {
+            rollouts: Default::default(),
+            retention_ms,
+        }
+    }
+
+    /// Applies `changes` to `dfs` as rollout `id` of `author`.
+    pub fn apply(
+        &self,
+        dfs: &mut HashMap<String, DataFrameArtifact>,
+        changes: Changes,
+        id: &str,
+        author: &str,
+    ) -> Rollout {
+        let mut rollout = Rollout {
+            id: id.to_string(),
+            author: author.to_string(),
+            applied_at: now_ms(),
+            impacts: changes.impacts,
+            rolled_back: false,
+            policies: Vec::new(),
+            fetchables: Vec::new(),
+        };
+        for (identifier, policy) in changes.policies {
+            if let Some(artifact) = dfs.get_mut(&identifier) {
+                let before = artifact.policy.clone();
+                artifact.change_policy(policy.clone(), id, author, false);
+                rollout.policies.push((identifier, before, policy));
+            }
+        }
+        for (identifier, fetchable) in changes.fetchables {
+            if let Some(artifact) = dfs.get_mut(&identifier) {
+                let before = std::mem::replace(&mut artifact.fetchable, fetchable.clone());
+                rollout.fetchables.push((identifier, before, fetchable));
+            }
+        }
+        let mut rollouts = self.rollouts.write().unwrap();
+        let now = rollout.applied_at;
+        rollouts.retain(|_, rollout| now.saturating_sub(rollout.applied_at) <= self.retention_ms);
+        rollouts.insert(id.to_string(), rollout.clone());
+        rollout
+    }
+
+    /// Restores the policies and fetch verdicts rollout `id` changed in `dfs`, all of them or
+    /// none. Returns the rolled back rollout and the dataframes it modified.
+    pub fn roll_back(
+        &self,
+        dfs: &mut HashMap<String, DataFrameArtifact>,
+        id: &str,
+        author: &str,
+    ) -> Result<(Rollout, Vec<String>), Status> {
+        let mut rollouts = self.rollouts.write().unwrap();
+        let rollout = rollouts
+            .get_mut(id)
+            .filter(|rollout| now_ms().saturating_sub(rollout.applied_at) <= self.retention_ms)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "Could not find policy rollout {id}: it may be past its retention window"
+                ))
+            })?;
+        if rollout.rolled_back {
+            return Err(Status::failed_precondition(format!(
+                "Policy rollout {id} was already rolled back"
+            )));
+        }
+        // Datasets deleted since have nothing to restore.
+        for (identifier, _, after) in rollout.policies.iter() {
+            let Some(artifact) = dfs.get(identifier) else {
+                continue;
+            };
+            if &artifact.policy != after {
+                let by = artifact
+                    .policy_history
+                    .last()
+                    .map(|change| change.rollout_id.as_str())
+                    .unwrap_or_default();
+                return Err(Status::failed_precondition(format!(
+                    "The policy of dataset {identifier} was changed by policy rollout {by} since \
+                     {id}: roll it back first"
+                )));
+            }
+        }
+        let mut modified = Vec::new();
+        for (identifier, before, _) in rollout.policies.iter() {
+            if let Some(artifact) = dfs.get_mut(identifier) {
+                artifact.change_policy(before.clone(), id, author, true);
+                modified.push(identifier.clone());
+            }
+        }
+        for (identifier, before, after) in rollout.fetchables.iter() {
+            match dfs.get_mut(identifier) {
+                Some(artifact) if &artifact.fetchable == after => {
+                    artifact.fetchable = before.clone();
+                    modified.push(identifier.clone());
+                }
+                _ => (),
+            }
+        }
+        rollout.rolled_back = true;
+        Ok((rollout.clone(), modified))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn selectors_match_every_criterion() {
+        let entry = CatalogEntry {
+            owner: String::from("alice"),
+            name: String::from("clinical_visits_2023"),
+            tags: vec![String::from("clinical"), String::from("eu")],
+            ..Default::default()
+        };
+        let selector = |tags: &[&str], owner: Option<&str>, pattern: Option<&str>| PolicySelector {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            owner: owner.map(String::from),
+            name_pattern: pattern.map(String::from),
+        };
+        assert!(selector(&["clinical"], None, None).matches(&entry));
+        assert!(!selector(&["clinical", "us"], None, None).matches(&entry));
+        assert!(selector(&[], Some("alice"), Some("clinical_*_2023")).matches(&entry));
+        assert!(!selector(&[], Some("bob"), Some("*")).matches(&entry));
+        assert!(selector(&[], None, Some("*visits*")).matches(&entry));
+        assert!(!selector(&[], None, Some("visits*")).matches(&entry));
+        assert!(selector(&[], None, None).check().is_err());
+    }
+
+    #[test]
+    fn patches_tighten_or_set_fields() {
+        let policy = Policy::allow_by_default();
+        let k10 = Policy::allow_by_default().merge(
+            &serde_json::from_value(json!({
+                "safe_zone": {"type": "Aggregation", "min_agg_size": 10},
+                "unsafe_handling": {"type": "Reject"},
+                "savable": true,
+            }))
+            .unwrap(),
+        );
+        let tightened = PolicyPatch::Tighten {
+            policy: k10.clone(),
+        }
+        .apply(&policy)
+        .unwrap();
+        assert_eq!(tightened, policy.merge(&k10));
+
+        let set = |fields: Value| PolicyPatch::Set {
+            fields: serde_json::from_value(fields).unwrap(),
+        };
+        let patched = set(json!({"safe_zone": {"type": "Aggregation", "min_agg_size": 10}}))
+            .apply(&policy)
+            .unwrap();
+        let patched = serde_json::to_value(&patched).unwrap();
+        assert_eq!(
+            patched["safe_zone"],
+            json!({"type": "Aggregation", "min_agg_size": 10})
+        );
+        assert_eq!(patched["unsafe_handling"], json!({"type": "Log"}));
+
+        let err = set(json!({"k_anonymity": 10})).apply(&policy).unwrap_err();
+        assert!(err.message().contains("unknown policy field `k_anonymity`"));
+        let err = set(json!({"savable": "yes"})).apply(&policy).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+}
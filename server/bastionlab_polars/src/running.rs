@@ -0,0 +1,404 @@
+//! Queries in flight, which operators list and kill, like a process list.
+//!
+//! Queries are registered from their admission until they return, whichever way they return: the
+//! registration is dropped along with the call, even when its blocking task panics. Running
+//! queries report the segment they are at through atomics, see [`QueryControl::enter`], which
+//! costs nothing noticeable next to a segment.
+//!
+//! Data owners list and kill every query, other identities their own. Killing a queued query takes
+//! it out of the queue, while a running one stops at its next segment boundary: polars segments
+//! cannot be interrupted. Killed queries leave nothing behind, results being stored once their plan
+//! completed, and killed plan jobs fail, which removes their checkpoints. Queries past their last
+//! segment are finishing, storing their results, and are no longer stopped.
+//!
+//! Kills are logged, and the last [`MAX_KILLS`] kept with who killed which query and why, for data
+//! owners to list.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::polars_proto;
+use crate::prelude::*;
+
+/// Kills kept, oldest first to go.
+pub const MAX_KILLS: usize = 1000;
+
+/// Longest kill reason, in characters.
+const MAX_REASON: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryState {
+    /// Waiting for an execution slot.
+    Queued,
+    Running,
+    /// Past its last segment, storing its results.
+    Finishing,
+}
+
+impl QueryState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryState::Queued => "queued",
+            QueryState::Running => "running",
+            QueryState::Finishing => "finishing",
+        }
+    }
+
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => QueryState::Queued,
+            1 => QueryState::Running,
+            _ => QueryState::Finishing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kill {
+    pub id: String,
+    /// The identity that submitted the query.
+    pub identity: String,
+    pub killed_by: String,
+    pub reason: String,
+    /// Milliseconds since the Unix epoch.
+    pub at: u64,
+    /// The state of the query when it was killed: finishing queries complete anyway.
+    pub state: QueryState,
+}
+
+impl Kill {
+    pub fn to_proto(&self) -> polars_proto::QueryKill {
+        polars_proto::QueryKill {
+            id: self.id.clone(),
+            identity: self.identity.clone(),
+            killed_by: self.killed_by.clone(),
+            reason: self.reason.clone(),
+            at: self.at,
+            state: self.state.name().to_string(),
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status::cancelled(format!(
+            "Query {} was killed by {}: {}",
+            self.id, self.killed_by, self.reason
+        ))
+    }
+}
+
+/// How a query reports its progress, and learns that it was killed.
+#[derive(Debug, Default)]
+pub struct QueryControl {
+    state: AtomicU8,
+    segment: AtomicUsize,
+    segments: AtomicUsize,
+    /// Set along with `kill`, so that segments only check an atomic.
+    killed: AtomicBool,
+    kill: Mutex<Option<Kill>>,
+    notify: Notify,
+}
+
+impl QueryControl {
+    pub fn state(&self) -> QueryState {
+        QueryState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Called before segment `index` of the `segments` of the plan, failing if the query was
+    /// killed.
+    pub fn enter(&self, index: usize, segments: usize) -> Result<(), Status> {
+        self.segment.store(index, Ordering::Relaxed);
+        self.segments.store(segments, Ordering::Relaxed);
+        self.check()
+    }
+
+    /// Fails if the query was killed.
+    pub fn check(&self) -> Result<(), Status> {
+        if !self.killed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        match &*self.kill.lock().unwrap() {
+            Some(kill) => Err(kill.status()),
+            None => Ok(()),
+        }
+    }
+
+    /// Called once the plan completed: from then on, the query is no longer stopped. Fails if it
+    /// was killed before.
+    pub fn finish(&self) -> Result<(), Status> {
+        let kill = self.kill.lock().unwrap();
+        if let Some(kill) = &*kill {
+            return Err(kill.status());
+        }
+        self.state
+            .store(QueryState::Finishing as u8, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Resolves once the query is killed, with the error it fails with.
+    pub async fn killed(&self) -> Status {
+        loop {
+            if let Err(e) = self.check() {
+                return e;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Kills the query, unless it is finishing. Returns its state.
+    fn kill(&self, make: impl FnOnce(QueryState) -> Kill) -> Kill {
+        let mut killed = self.kill.lock().unwrap();
+        let kill = make(self.state());
+        if kill.state != QueryState::Finishing && killed.is_none() {
+            *killed = Some(kill.clone());
+            self.killed.store(true, Ordering::Release);
+            // Stored if nobody waits yet.
+            self.notify.notify_one();
+        }
+        kill
+    }
+}
+
+#[derive(Debug)]
+struct RunningQuery {
+    id: String,
+    identity: String,
+    inputs: Vec<String>,
+    submitted_at: u64,
+    /// 0 while queued.
+    started_at: AtomicU64,
+    control: Arc<QueryControl>,
+}
+
+impl RunningQuery {
+    fn to_proto(&self, now: u64) -> polars_proto::RunningQuery {
+        let started_at = self.started_at.load(Ordering::Relaxed);
+        let queue_wait_ms = match started_at {
+            0 => now,
+            started_at => started_at,
+        }
+        .saturating_sub(self.submitted_at);
+        let segment = self.control.segment.load(Ordering::Relaxed);
+        let segments = self.control.segments.load(Ordering::Relaxed);
+        let state = self.control.state();
+        let progress = match state {
+            QueryState::Queued => 0.0,
+            QueryState::Running if segments > 0 => segment as f64 / segments as f64,
+            QueryState::Running => 0.0,
+            QueryState::Finishing => 1.0,
+        };
+        polars_proto::RunningQuery {
+            id: self.id.clone(),
+            identity: self.identity.clone(),
+            submitted_at: self.submitted_at,
+            started_at,
+            queue_wait_ms,
+            segment: segment as u64,
+            segments: segments as u64,
+            inputs: self.inputs.clone(),
+            progress,
+            state: state.name().to_string(),
+        }
+    }
+}
+
+/// The queries in flight, by id, and the last kills.
+#[derive(Debug, Default)]
+pub struct RunningQueries {
+    queries: RwLock<HashMap<String, Arc<RunningQuery>>>,
+    kills: Mutex<VecDeque<Kill>>,
+}
+
+impl RunningQueries {
+    /// Registers a query of `identity` reading `inputs`, queued until [`Registration::started`].
+    pub fn register(self: &Arc<Self>, identity: &str, inputs: &[String], now: u64) -> Registration {
+        let query = Arc::new(RunningQuery {
+            id: Uuid::new_v4().to_string(),
+            identity: identity.to_string(),
+            inputs: inputs.to_vec(),
+            submitted_at: now,
+            started_at: AtomicU64::new(0),
+            control: Default::default(),
+        });
+        self.queries
+            .write()
+            .unwrap()
+            .insert(query.id.clone(), query.clone());
+        Registration {
+            registry: self.clone(),
+            query,
+        }
+    }
+
+    /// The queries in flight, those of `identity` only if set, oldest first.
+    pub fn list(&self, identity: Option<&str>, now: u64) -> Vec<polars_proto::RunningQuery> {
+        let queries = self.queries.read().unwrap();
+        let mut listed: Vec<polars_proto::RunningQuery> = queries
+            .values()
+            .filter(|query| identity.map_or(true, |identity| query.identity == identity))
+            .map(|query| query.to_proto(now))
+            .collect();
+        listed.sort_by(|a, b| (a.submitted_at, &a.id).cmp(&(b.submitted_at, &b.id)));
+        listed
+    }
+
+    /// Kills query `id` on behalf of `killed_by`, who must have submitted it unless `owner`.
+    pub fn kill(
+        &self,
+        id: &str,
+        killed_by: &str,
+        owner: bool,
+        reason: &str,
+        now: u64,
+    ) -> Result<Kill, Status> {
+        let reason: String = reason
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_REASON)
+            .collect();
+        if reason.trim().is_empty() {
+            return Err(Status::invalid_argument(
+                "Could not kill query: it needs a reason",
+            ));
+        }
+        let query = self
+            .queries
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("No running query {id}")))?;
+        if !owner && query.identity != killed_by {
+            return Err(Status::permission_denied(format!(
+                "Could not kill query {id}: only data owners kill the queries of others"
+            )));
+        }
+        let kill = query.control.kill(|state| Kill {
+            id: id.to_string(),
+            identity: query.identity.clone(),
+            killed_by: killed_by.to_string(),
+            reason,
+            at: now,
+            state,
+        });
+        warn!(
+            "Query {id} of {} killed by {} while {}: {}",
+            kill.identity,
+            kill.killed_by,
+            kill.state.name(),
+            kill.reason
+        );
+        let mut kills = self.kills.lock().unwrap();
+        if kills.len() >= MAX_KILLS {
+            kills.pop_front();
+        }
+        kills.push_back(kill.clone());
+        Ok(kill)
+    }
+
+    /// The last kills, oldest first.
+    pub fn kills(&self) -> Vec<Kill> {
+        self.kills.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// A query in flight, unregistered when dropped.
+pub struct Registration {
+    registry: Arc<RunningQueries>,
+    query: Arc<RunningQuery>,
+}
+
+impl Registration {
+    pub fn id(&self) -> &str {
+        &self.query.id
+    }
+
+    pub fn control(&self) -> Arc<QueryControl> {
+        self.query.control.clone()
+    }
+
+    /// The query got an execution slot at `now`.
+    pub fn started(&self, now: u64) {
+        self.query.started_at.store(now.max(1), Ordering::Relaxed);
+        self.query
+            .control
+            .state
+            .store(QueryState::Running as u8, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .queries
+            .write()
+            .unwrap()
+            .remove(&self.query.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn queries_are_listed_until_dropped_and_killed_between_segments() {
+        let registry = Arc::new(RunningQueries::default());
+        let inputs = vec![String::from("visits")];
+        let queued = registry.register("analyst", &inputs, 1_000);
+        let running = registry.register("owner", &inputs, 1_100);
+        running.started(1_300);
+        let control = running.control();
+        control.enter(1, 4).unwrap();
+
+        let listed = registry.list(None, 1_500);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(
+            (listed[0].state.as_str(), listed[0].queue_wait_ms),
+            ("queued", 500)
+        );
+        assert_eq!(
+            (listed[1].state.as_str(), listed[1].queue_wait_ms),
+            ("running", 200)
+        );
+        assert_eq!((listed[1].segment, listed[1].progress), (1, 0.25));
+        assert_eq!(registry.list(Some("analyst"), 1_500).len(), 1);
+
+        let err = registry
+            .kill(running.id(), "analyst", false, "Too slow", 1_600)
+            .unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert!(registry
+            .kill(running.id(), "owner", false, " \u{7}", 1_600)
+            .is_err());
+        let kill = registry
+            .kill(running.id(), "admin", true, "Too slow", 1_600)
+            .unwrap();
+        assert_eq!(kill.state, QueryState::Running);
+        let err = control.enter(2, 4).unwrap_err();
+        assert_eq!(err.code(), Code::Cancelled);
+        assert!(err.message().contains("killed by admin: Too slow"));
+        assert!(control.finish().is_err());
+        assert_eq!(registry.kills(), [kill]);
+
+        drop(running);
+        assert_eq!(registry.list(None, 1_700).len(), 1);
+        let control = queued.control();
+        control.finish().unwrap();
+        let kill = registry
+            .kill(queued.id(), "analyst", false, "Mistake", 1_800)
+            .unwrap();
+        assert_eq!(kill.state, QueryState::Finishing);
+        control.check().unwrap();
+        drop(queued);
+        assert_eq!(registry.list(None, 1_900), []);
+        let err = registry
+            .kill(&kill.id, "analyst", false, "Mistake", 2_000)
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,121 @@
+//! Random row samples of a composite plan.
+//!
+//! A `SamplePlanSegment` replaces the dataframe on top of the stack by a random sample of its rows,
+//! a `fraction` of them or `n` rows, with or without replacement. Sampled rows keep their input
+//! order. Samples drawn with a `seed` are reproducible; without one, the server draws from its own
+//! entropy so that clients cannot bias the sample.
+//!
+//! Sampled rows are rows of the input, not aggregates: the aggregation sizes of the input carry
+//! over unchanged, so that policies requiring aggregations still reject a sample of a protected
+//! dataframe, however small.
+
+use polars::prelude::*;
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+use tonic::Status;
+
+/// Number of rows sampled from `height` rows, a `fraction` of them or `n`.
+pub fn rows(
+    height: usize,
+    fraction: Option<f64>,
+    n: Option<u64>,
+    with_replacement: bool,
+) -> Result<usize, Status> {
+    let rows = match (fraction, n) {
+        (Some(fraction), None) => {
+            if !fraction.is_finite() || fraction < 0.0 {
+                return Err(Status::invalid_argument(format!(
+                    "Could not sample: fraction must be a positive number, got {fraction}"
+                )));
+            }
+            if fraction > 1.0 && !with_replacement {
+                return Err(Status::invalid_argument(format!(
+                    "Could not sample: fraction {fraction} is above 1, which needs sampling with \
+                     replacement"
+                )));
+            }
+            (height as f64 * fraction).round() as usize
+        }
+        (None, Some(n)) => usize::try_from(n).unwrap_or(usize::MAX),
+        _ => {
+            return Err(Status::invalid_argument(
+                "Could not sample: exactly one of fraction and n is needed",
+            ))
+        }
+    };
+    if rows > height && !with_replacement {
+        return Err(Status::invalid_argument(format!(
+            "Could not sample {rows} rows out of {height} without replacement"
+        )));
+    }
+    if rows > 0 && height == 0 {
+        return Err(Status::invalid_argument(
+            "Could not sample: the input has no rows",
+        ));
+    }
+    Ok(rows)
+}
+
+/// The sampled rows of `df`, in their input order. Rows are drawn with `seed` if given, from the
+/// entropy of the server otherwise.
+pub fn sample(
+    df: &DataFrame,
+    fraction: Option<f64>,
+    n: Option<u64>,
+    with_replacement: bool,
+    seed: Option<u64>,
+) -> Result<DataFrame, Status> {
+    let height = df.height();
+    let rows = rows(height, fraction, n, with_replacement)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut indices: Vec<IdxSize> = if with_replacement {
+        (0..rows)
+            .map(|_| rng.gen_range(0..height) as IdxSize)
+            .collect()
+    } else {
+        index::sample(&mut rng, height, rows)
+            .into_iter()
+            .map(|i| i as IdxSize)
+            .collect()
+    };
+    indices.sort_unstable();
+    df.take(&IdxCa::from_vec("", indices))
+        .map_err(|e| Status::internal(format!("Could not sample: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_sized_and_seeded() {
+        let df = df! { "x" => (0..100i64).collect::<Vec<_>>() }.unwrap();
+        let values = |df: DataFrame| -> Vec<i64> {
+            let x = df.column("x").unwrap().i64().unwrap();
+            x.into_no_null_iter().collect()
+        };
+
+        let seeded = values(sample(&df, Some(0.1), None, false, Some(7)).unwrap());
+        assert_eq!(seeded.len(), 10);
+        assert!(seeded.windows(2).all(|pair| pair[0] < pair[1]));
+        let again = values(sample(&df, Some(0.1), None, false, Some(7)).unwrap());
+        assert_eq!(again, seeded);
+        let other = values(sample(&df, Some(0.1), None, false, Some(8)).unwrap());
+        assert_ne!(other, seeded);
+
+        let replaced = sample(&df, None, Some(250), true, None).unwrap();
+        assert_eq!(replaced.height(), 250);
+        assert_eq!(rows(100, Some(1.5), None, true).unwrap(), 150);
+        let err = rows(100, Some(1.5), None, false).unwrap_err();
+        assert!(
+            err.message().contains("needs sampling with replacement"),
+            "{err:?}"
+        );
+        assert!(rows(100, None, Some(101), false).is_err());
+        assert!(rows(100, Some(0.5), Some(1), false).is_err());
+        assert!(rows(100, None, None, false).is_err());
+        assert!(rows(0, None, Some(1), true).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+use tonic::Status;
+
+use crate::polars_proto;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryPriority {
+    Interactive,
+    Batch,
+}
+
+impl From<polars_proto::QueryPriority> for QueryPriority {
+    fn from(priority: polars_proto::QueryPriority) -> Self {
+        match priority {
+            polars_proto::QueryPriority::Interactive => QueryPriority::Interactive,
+            polars_proto::QueryPriority::Batch => QueryPriority::Batch,
+        }
+    }
+}
+
+struct Waiter {
+    identity: String,
+    priority: QueryPriority,
+    tx: oneshot::Sender<QuerySlot>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running: usize,
+    running_batch: usize,
+    queued: usize,
+    /// Identities that have at least one waiting query, in round-robin order.
+    order: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Waiter>>,
+}
+
+/// Execution queue placed in front of the blocking pool.
+///
+/// At most `max_concurrency` queries run at the same time, batch queries may only
+/// use `max_batch` of these slots, and waiting queries are served round-robin across
+/// identities so that one user's backlog cannot starve everyone else.
+pub struct QueryScheduler {
+    max_concurrency: usize,
+    max_batch: usize,
+    max_queue_depth: usize,
+    state: Mutex<SchedulerState>,
+}
+
+/// A running slot in the scheduler. The slot is given back when this is dropped.
+pub struct QuerySlot {
+    scheduler: Arc<QueryScheduler>,
+    priority: QueryPriority,
+    pub queue_time: Duration,
+    /// Queries to be served before this one when it was queued, following the order they are
+    /// served in: round-robin across identities, interactive queries of each first. 0 if it ran
+    /// right away.
+    pub queue_position: usize,
+}
+
+impl Drop for QuerySlot {
+    fn drop(&mut self) {
+        self.scheduler.release(self.priority);
+    }
+}
+
+/// Index of the query served first among the `queued` ones of an identity: its first interactive
+/// query, else its first batch query if `batch` queries may run.
+fn pick(queued: impl Iterator<Item = QueryPriority> + Clone, batch: bool) -> Option<usize> {
+    let first = |wanted: QueryPriority| queued.clone().position(|priority| priority == wanted);
+    first(QueryPriority::Interactive).or_else(|| {
+        if batch {
+            first(QueryPriority::Batch)
+        } else {
+            None
+        }
+    })
+}
+
+impl SchedulerState {
+    fn can_run(&self, priority: QueryPriority, scheduler: &QueryScheduler) -> bool {
+        self.running < scheduler.max_concurrency
+            && (priority == QueryPriority::Interactive || self.running_batch < scheduler.max_batch)
+    }
+
+    fn start(&mut self, priority: QueryPriority) {
+        self.running += 1;
+        if priority == QueryPriority::Batch {
+            self.running_batch += 1;
+        }
+    }
+
+    /// Picks the next waiter following the round-robin order over identities.
+    /// Interactive queries of an identity are served before its batch queries.
+    fn next_waiter(&mut self, scheduler: &QueryScheduler) -> Option<Waiter> {
+        let batch = self.running_batch < scheduler.max_batch;
+        for _ in 0..self.order.len() {
+            let identity = self.order.pop_front()?;
+            let queue = self.waiting.get_mut(&identity)?;
+
+            match pick(queue.iter().map(|w| w.priority), batch) {
+                Some(pos) => {
+                    let waiter = queue.remove(pos).unwrap();
+                    if queue.is_empty() {
+                        self.waiting.remove(&identity);
+                    } else {
+                        self.order.push_back(identity);
+                    }
+                    self.queued -= 1;
+                    return Some(waiter);
+                }
+                None => self.order.push_back(identity),
+            }
+        }
+        None
+    }
+
+    /// Queries to be served before a new one of `identity` with `priority`, replaying the order
+    /// of [`Self::next_waiter`] as if batch queries were not capped.
+    fn position(&self, identity: &str, priority: QueryPriority) -> usize {
+        let mut order = self.order.clone();
+        let mut waiting: HashMap<&str, VecDeque<(QueryPriority, bool)>> = self
+            .waiting
+            .iter()
+            .map(|(other, queue)| {
+                let queue = queue.iter().map(|w| (w.priority, false)).collect();
+                (other.as_str(), queue)
+            })
+            .collect();
+        if !waiting.contains_key(identity) {
+            order.push_back(identity.to_owned());
+        }
+        waiting
+            .entry(identity)
+            .or_default()
+            .push_back((priority, true));
+
+        let mut position = 0;
+        while let Some(other) = order.pop_front() {
+            let queue = waiting.get_mut(other.as_str()).unwrap();
+            let pos = pick(queue.iter().map(|(priority, _)| *priority), true).unwrap();
+            if queue.remove(pos).unwrap().1 {
+                break;
+            }
+            position += 1;
+            if !queue.is_empty() {
+                order.push_back(other);
+            }
+        }
+        position
+    }
+
+    /// Queues a query of `identity`, returning its position and where its slot will be sent.
+    fn enqueue(
+        &mut self,
+        identity: &str,
+        priority: QueryPriority,
+    ) -> (usize, oneshot::Receiver<QuerySlot>) {
+        let (tx, rx) = oneshot::channel();
+        let position = self.position(identity, priority);
+        if !self.waiting.contains_key(identity) {
+            self.order.push_back(identity.to_owned());
+        }
+        self.waiting
+            .entry(identity.to_owned())
+            .or_default()
+            .push_back(Waiter {
+                identity: identity.to_owned(),
+                priority,
+                tx,
+            });
+        self.queued += 1;
+        (position, rx)
+    }
+}
+
+impl QueryScheduler {
+    pub fn new(max_concurrency: usize, batch_share: f64, max_queue_depth: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        let max_batch = ((max_concurrency as f64 * batch_share).floor() as usize).max(1);
+        QueryScheduler {
+            max_concurrency,
+            max_batch,
+            max_queue_depth,
+            state: Mutex::new(SchedulerState::default()),
+        }
+    }
+
+    /// Waits for a free execution slot for `identity`.
+    ///
+    /// Fails with `resource_exhausted` when the queue already holds `max_queue_depth` queries.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        identity: &str,
+        priority: QueryPriority,
+    ) -> Result<QuerySlot, Status> {
+        let start = Instant::now();
+        let (position, rx) = {
+            let mut state = self.state.lock().unwrap();
+            // Free slots are always handed to eligible waiters on release, so if we can run
+            // now, nobody that could use this slot is waiting before us.
+            if state.can_run(priority, self) {
+                state.start(priority);
+                return Ok(QuerySlot {
+                    scheduler: Arc::clone(self),
+                    priority,
+                    queue_time: Duration::ZERO,
+                    queue_position: 0,
+                });
+            }
+
+            if state.queued >= self.max_queue_depth {
+                return Err(Status::resource_exhausted(format!(
+                    "Query queue is full: {} queries are waiting (maximum is {})",
+                    state.queued, self.max_queue_depth
+                )));
+            }
+
+            let (position, rx) = state.enqueue(identity, priority);
+            debug!("Query from {} queued at position {}", identity, position);
+            (position, rx)
+        };
+
+        let mut slot = rx
+            .await
+            .map_err(|_| Status::internal("The query scheduler dropped a queued query"))?;
+        slot.queue_time = start.elapsed();
+        slot.queue_position = position;
+        Ok(slot)
+    }
+
+    /// Number of queries currently waiting for a slot.
+    pub fn queue_depth(&self) -> usize {
+        self.state.lock().unwrap().queued
+    }
+
+    fn release(self: &Arc<Self>, priority: QueryPriority) {
+        let next = {
+            let mut state = self.state.lock().unwrap();
+            state.running -= 1;
+            if priority == QueryPriority::Batch {
+                state.running_batch -= 1;
+            }
+            let waiter = state.next_waiter(self);
+            if let Some(waiter) = &waiter {
+                state.start(waiter.priority);
+            }
+            waiter
+        };
+
+        if let Some(Waiter {
+            identity,
+            priority,
+            tx,
+        }) = next
+        {
+            debug!("Handing a free slot to a query from {}", identity);
+            // If the waiting request has been dropped in the meantime, the slot comes back
+            // to us here and its drop hands it over to the next waiter.
+            let _ = tx.send(QuerySlot {
+                scheduler: Arc::clone(self),
+                priority,
+                queue_time: Duration::ZERO,
+                queue_position: 0,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_are_served_fairly() {
+        let scheduler = QueryScheduler::new(1, 1.0, 100);
+        let mut state = SchedulerState::default();
+        let mut receivers = Vec::new();
+        for _ in 0..4 {
+            receivers.push(state.enqueue("heavy", QueryPriority::Batch));
+        }
+        receivers.push(state.enqueue("heavy", QueryPriority::Interactive));
+        for _ in 0..2 {
+            receivers.push(state.enqueue("light", QueryPriority::Batch));
+        }
+        let positions: Vec<_> = receivers.iter().map(|(position, _)| *position).collect();
+        assert_eq!(positions, [0, 1, 2, 3, 0, 1, 3]);
+
+        // Round-robin interleaving means the light identity never waits for the whole heavy
+        // backlog, and the interactive query of the heavy one goes before its batch queries.
+        let served: Vec<_> = std::iter::from_fn(|| state.next_waiter(&scheduler))
+            .map(|waiter| (waiter.identity, waiter.priority))
+            .collect();
+        let (heavy, light) = (String::from("heavy"), String::from("light"));
+        assert_eq!(
+            served,
+            [
+                (heavy.clone(), QueryPriority::Interactive),
+                (light.clone(), QueryPriority::Batch),
+                (heavy.clone(), QueryPriority::Batch),
+                (light, QueryPriority::Batch),
+                (heavy.clone(), QueryPriority::Batch),
+                (heavy.clone(), QueryPriority::Batch),
+                (heavy, QueryPriority::Batch),
+            ]
+        );
+        assert_eq!(state.queued, 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_rejected() {
+        let scheduler = Arc::new(QueryScheduler::new(1, 1.0, 1));
+        let _running = scheduler
+            .acquire("a", QueryPriority::Interactive)
+            .await
+            .unwrap();
+
+        let waiting = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.acquire("a", QueryPriority::Interactive).await })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(scheduler.queue_depth(), 1);
+
+        let err = scheduler
+            .acquire("b", QueryPriority::Interactive)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+
+        drop(_running);
+        let slot = waiting.await.unwrap().unwrap();
+        assert_eq!(slot.queue_position, 0);
+        assert!(!slot.queue_time.is_zero());
+    }
+
+    #[tokio::test]
+    async fn batch_is_capped_to_its_share() {
+        let scheduler = Arc::new(QueryScheduler::new(2, 0.5, 10));
+        let _batch = scheduler.acquire("a", QueryPriority::Batch).await.unwrap();
+
+        let second_batch = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.acquire("a", QueryPriority::Batch).await })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(scheduler.queue_depth(), 1);
+
+        // The remaining slot is reserved for interactive work.
+        let interactive = tokio::time::timeout(
+            Duration::from_millis(50),
+            scheduler.acquire("b", QueryPriority::Interactive),
+        )
+        .await
+        .expect("interactive query should not wait behind capped batch queries")
+        .unwrap();
+        assert_eq!(interactive.queue_time, Duration::ZERO);
+        second_batch.abort();
+    }
+}
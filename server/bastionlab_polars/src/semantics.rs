@@ -0,0 +1,227 @@
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::visitable::VisitableMut;
+
+/// Semantics version of the plans written for the current engine.
+pub const CURRENT_SEMANTICS: u32 = 1;
+
+/// Version of the plans and artifacts that predate semantics versions.
+pub fn legacy_semantics() -> u32 {
+    1
+}
+
+/// A rewrite of logical plans that keeps a documented behavior of an older semantics version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shim {
+    /// Groups come out in the order of the first occurrence of their keys.
+    MaintainGroupOrder,
+    /// Sorts treat nulls as the smallest values instead of moving them to the end.
+    NullsSmallest,
+}
+
+impl Shim {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shim::MaintainGroupOrder => "maintain_group_order",
+            Shim::NullsSmallest => "nulls_smallest",
+        }
+    }
+}
+
+struct SemanticsVersion {
+    version: u32,
+    engine: &'static str,
+    /// Shims needed to run plans of this version on the current engine.
+    shims: &'static [Shim],
+}
+
+/// The documented behaviors of version 1, pinned by the tests below:
+/// - null join keys match each other,
+/// - sorts are stable but for nulls, which are treated as the smallest values,
+/// - null group keys form their own group, groups are unordered unless `maintain_order` is set.
+///
+/// Upgrading polars adds a version when one of these behaviors changes, and lists for the older
+/// versions the shims that keep theirs.
+const VERSIONS: &[SemanticsVersion] = &[SemanticsVersion {
+    version: 1,
+    engine: "polars 0.25.1",
+    shims: &[],
+}];
+
+/// The engine this server runs, such as `polars 0.25.1`.
+pub fn current_engine() -> &'static str {
+    VERSIONS.last().unwrap().engine
+}
+
+/// Returns the shims to apply to plans of semantics version `version`.
+pub fn shims(version: u32) -> Result<&'static [Shim], Status> {
+    VERSIONS
+        .iter()
+        .find(|v| v.version == version)
+        .map(|v| v.shims)
+        .ok_or_else(|| {
+            let current = VERSIONS.last().unwrap();
+            Status::failed_precondition(format!(
+                "Unknown semantics version {version}: this server runs {} (semantics version {})",
+                current.engine, current.version
+            ))
+        })
+}
+
+/// Rewrites `plan` so that the current engine runs it with the behaviors ensured by `shims`.
+pub fn apply(plan: &mut LogicalPlan, shims: &[Shim]) -> Result<(), Status> {
+    if shims.is_empty() {
+        return Ok(());
+    }
+    plan.visit_mut(&mut (), |plan, _| {
+        match plan {
+            LogicalPlan::Aggregate { maintain_order, .. }
+                if shims.contains(&Shim::MaintainGroupOrder) =>
+            {
+                *maintain_order = true
+            }
+            LogicalPlan::Sort { args, .. } if shims.contains(&Shim::NullsSmallest) => {
+                args.nulls_last = false
+            }
+            _ => (),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(df: &DataFrame, name: &str) -> Vec<Option<i64>> {
+        df.column(name)
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn null_join_keys_match_each_other() {
+        let left = df! { "k" => [Some(1i64), None, Some(2)], "a" => [1i64, 2, 3] }.unwrap();
+        let right = df! { "k" => [Some(1i64), None, Some(3)], "b" => [10i64, 20, 30] }.unwrap();
+
+        let inner = left
+            .clone()
+            .lazy()
+            .join(
+                right.clone().lazy(),
+                [col("k")],
+                [col("k")],
+                JoinType::Inner,
+            )
+            .sort("a", Default::default())
+            .collect()
+            .unwrap();
+        assert_eq!(keys(&inner, "b"), [Some(10), Some(20)]);
+
+        let left = left
+            .lazy()
+            .join(right.lazy(), [col("k")], [col("k")], JoinType::Left)
+            .collect()
+            .unwrap();
+        assert_eq!(keys(&left, "b"), [Some(10), Some(20), None]);
+    }
+
+    #[test]
+    fn sorts_are_stable_with_small_nulls() {
+        let df = df! {
+            "k" => [Some(2i64), None, Some(1), Some(2), None, Some(1)],
+            "row" => [0i64, 1, 2, 3, 4, 5],
+        }
+        .unwrap();
+        let sorted = df
+            .clone()
+            .lazy()
+            .sort("k", Default::default())
+            .collect()
+            .unwrap();
+        assert_eq!(
+            keys(&sorted, "row"),
+            [Some(1), Some(4), Some(2), Some(5), Some(0), Some(3)]
+        );
+
+        let descending = df
+            .lazy()
+            .sort_by_exprs([col("k")], [true], false)
+            .collect()
+            .unwrap();
+        assert_eq!(
+            keys(&descending, "k"),
+            [Some(2), Some(2), Some(1), Some(1), None, None]
+        );
+        // Ties are kept in order, but nulls are not: they are the reversed tail of the sort.
+        assert_eq!(
+            keys(&descending, "row"),
+            [Some(0), Some(3), Some(2), Some(5), Some(4), Some(1)]
+        );
+    }
+
+    #[test]
+    fn null_keys_form_their_own_group() {
+        let df = df! { "k" => [Some(3i64), None, Some(1), None, Some(3)] }.unwrap();
+        let groups = df
+            .lazy()
+            .groupby_stable([col("k")])
+            .agg([col("k").count().alias("n")])
+            .collect()
+            .unwrap();
+        assert_eq!(keys(&groups, "k"), [Some(3), None, Some(1)]);
+        let counts: Vec<_> = groups
+            .column("n")
+            .unwrap()
+            .idx()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(counts, [Some(2), Some(2), Some(1)]);
+    }
+
+    #[test]
+    fn shims_rewrite_plans() {
+        let df = df! { "k" => (0..1000i64).map(|i| (i * 7) % 31).collect::<Vec<_>>() }.unwrap();
+        let mut plan = df
+            .clone()
+            .lazy()
+            .groupby([col("k")])
+            .agg([col("k").count().alias("n")])
+            .sort_by_exprs([col("n")], [false], true)
+            .logical_plan;
+        apply(&mut plan, &[Shim::MaintainGroupOrder, Shim::NullsSmallest]).unwrap();
+        match &plan {
+            LogicalPlan::Sort { input, args, .. } => {
+                assert!(!args.nulls_last);
+                assert!(matches!(
+                    **input,
+                    LogicalPlan::Aggregate {
+                        maintain_order: true,
+                        ..
+                    }
+                ));
+            }
+            _ => panic!("Unexpected plan: {plan:?}"),
+        }
+
+        // Keys first seen in the 8 first rows have 33 rows, the others 32: the stable sort keeps
+        // the first-occurrence order within each count.
+        let result = LazyFrame::from(plan).collect().unwrap();
+        let expected: Vec<_> = (8..31).chain(0..8).map(|i| Some((i * 7) % 31)).collect();
+        assert_eq!(keys(&result, "k"), expected);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert!(shims(CURRENT_SEMANTICS).unwrap().is_empty());
+        assert!(shims(legacy_semantics()).is_ok());
+        let err = shims(CURRENT_SEMANTICS + 1).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert!(err.message().contains("polars 0.25.1"));
+    }
+}
@@ -1,62 +1,679 @@
-use super::polars_proto::{fetch_chunk, FetchChunk, SendChunk};
+use super::polars_proto::{
+    column_order, fetch_chunk, ColumnOrder, ColumnStart, DataFormat, DeltaHeader, FetchChunk,
+    ReferenceRequest, ResultShape, RowRange, SendChunk,
+};
+use crate::canonical::{from_canonical_bytes, to_canonical_bytes};
+use crate::capabilities;
+use crate::default_policies::DefaultPolicy;
+use crate::delta::{DeltaRows, Fallback};
+use crate::faults::{Delivery, StreamFaults};
+use crate::fetch_guard::FetchGuard;
+use crate::policy_engine::Released;
 use crate::prelude::*;
-use crate::{DataFrameArtifact, DelayedDataFrame, FetchStatus};
+use crate::reserved::check_column_names;
+use crate::shape::result_shape;
+use crate::{access_control::Policy, DataFrameArtifact, DelayedDataFrame, FetchStatus};
+use bastionlab_common::config::{BlankColumnNames, MAX_CHUNK_BYTES};
 use polars::prelude::*;
 use ring::digest;
+use std::collections::VecDeque;
 use tokio::sync::mpsc;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Response, Status};
 
-const CHUNK_SIZE: usize = 32 * 1024;
+pub const CHUNK_SIZE: usize = 32 * 1024;
 
 // TODO PERF: Do a PR on polars/pypolars to add the streaming IPC (apache flight) format to the python interface
 // right now, there is only the file format which requires random access
 // which means, we have to do a full copy to a buffer and we cannot parse it as we go
 // also: polar's IpcStreamReader requires the underlying stream to be Seek; which is weird & does not make sense
+// so IPC streams are buffered whole too, see `DataFormat::IpcStream`
 
-pub async fn unserialize_dataframe(
-    mut stream: tonic::Streaming<SendChunk>,
-) -> Result<(DataFrameArtifact, String), Status> {
-    let mut buf: Vec<u8> = Vec::new();
-    let mut first = true;
-    let mut policy = String::new();
-    let mut sanitized_columns = Vec::new();
+/// The format `format` stands for: with [`DataFormat::LegacyFormat`], the canonical format if
+/// `canonical` is set by the older fields, an IPC file otherwise.
+pub fn resolve_format(format: DataFormat, canonical: bool) -> DataFormat {
+    match format {
+        DataFormat::LegacyFormat if canonical => DataFormat::Canonical,
+        DataFormat::LegacyFormat => DataFormat::IpcFile,
+        format => format,
+    }
+}
 
-    let mut hasher = digest::Context::new(&digest::SHA256);
+/// Serializes `df` in `format`, an IPC file for [`DataFormat::LegacyFormat`].
+pub fn encode_dataframe(df: &mut DataFrame, format: DataFormat) -> Result<Vec<u8>, Status> {
+    let polars_err = |err: PolarsError| Status::internal(format!("Polars error: {err}"));
+    match resolve_format(format, false) {
+        DataFormat::Canonical => to_canonical_bytes(df),
+        DataFormat::IpcStream => {
+            let mut buf = Vec::new();
+            polars::io::ipc::IpcStreamWriter::new(&mut buf)
+                .finish(df)
+                .map_err(polars_err)?;
+            Ok(buf)
+        }
+        _ => dataframe_ser_helper(df).map_err(polars_err),
+    }
+}
+
+/// Deserializes a dataframe serialized in `format` by [`encode_dataframe`].
+pub fn decode_dataframe(buf: &[u8], format: DataFormat) -> Result<DataFrame, Status> {
+    match resolve_format(format, false) {
+        DataFormat::Canonical => from_canonical_bytes(buf),
+        DataFormat::IpcStream => ipc_stream_to_dataframe(buf),
+        _ => ipc_to_dataframe(buf),
+    }
+}
+
+/// Splits an IPC-serialized dataframe into upload chunks.
+///
+/// The policy, sanitized columns, storage flags and checksum are only sent on the first chunk.
+pub fn upload_chunks(
+    buf: &[u8],
+    policy: &Policy,
+    sanitized_columns: Vec<String>,
+    optimize_storage: Option<bool>,
+) -> Result<Vec<SendChunk>, Status> {
+    sized_upload_chunks(buf, policy, sanitized_columns, optimize_storage, CHUNK_SIZE)
+}
 
-    while let Some(chunk) = stream.next().await {
-        let mut chunk = chunk?;
-        hasher.update(&chunk.data);
-        buf.append(&mut chunk.data);
-        if first {
-            policy = chunk.policy;
-            sanitized_columns = chunk.sanitized_columns;
-            first = false;
+/// [`upload_chunks`] holding `chunk_size` bytes of data each, as the handshake asks for, see
+/// [`crate::handshake`].
+pub fn sized_upload_chunks(
+    buf: &[u8],
+    policy: &Policy,
+    sanitized_columns: Vec<String>,
+    optimize_storage: Option<bool>,
+    chunk_size: usize,
+) -> Result<Vec<SendChunk>, Status> {
+    let policy = serde_json::to_string(policy)
+        .map_err(|e| Status::invalid_argument(format!("Could not serialize the policy: {e}")))?;
+    let mut chunks: Vec<SendChunk> = buf
+        .chunks(chunk_size.max(1))
+        .map(|data| SendChunk {
+            data: data.to_vec(),
+            ..Default::default()
+        })
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(SendChunk::default());
+    }
+
+    let first = &mut chunks[0];
+    first.policy = policy;
+    first.sanitized_columns = sanitized_columns;
+    first.optimize_storage = optimize_storage.is_some();
+    first.allow_lossy_floats = optimize_storage.unwrap_or(false);
+    first.checksum = checksum(buf);
+    Ok(chunks)
+}
+
+/// Splits a dataframe into upload chunks holding `chunk_size` bytes of data each, serialized as
+/// an Arrow IPC stream.
+pub fn sized_stream_upload_chunks(
+    df: &mut DataFrame,
+    policy: &Policy,
+    sanitized_columns: Vec<String>,
+    optimize_storage: Option<bool>,
+    chunk_size: usize,
+) -> Result<Vec<SendChunk>, Status> {
+    let buf = encode_dataframe(df, DataFormat::IpcStream)?;
+    let mut chunks = sized_upload_chunks(
+        &buf,
+        policy,
+        sanitized_columns,
+        optimize_storage,
+        chunk_size,
+    )?;
+    chunks[0].set_format(DataFormat::IpcStream);
+    Ok(chunks)
+}
+
+/// Splits a dataframe into upload chunks, sending each column as a one-column canonical frame.
+///
+/// The frame lengths go on the first chunk, so that the server can decode every column as soon as
+/// its bytes are in instead of buffering the whole upload. Frames are delimited by these lengths
+/// rather than by a marker in the data, so that columns can hold any bytes.
+///
+/// Compatibility: clients that send no lengths upload a single frame in the format of the first
+/// chunk, which servers still decode as such. No format ever delimited frames with a marker, so no
+/// client depends on one.
+pub fn column_upload_chunks(
+    df: &DataFrame,
+    policy: &Policy,
+    sanitized_columns: Vec<String>,
+    optimize_storage: Option<bool>,
+) -> Result<Vec<SendChunk>, Status> {
+    sized_column_upload_chunks(df, policy, sanitized_columns, optimize_storage, CHUNK_SIZE)
+}
+
+/// [`column_upload_chunks`] holding `chunk_size` bytes of data each.
+pub fn sized_column_upload_chunks(
+    df: &DataFrame,
+    policy: &Policy,
+    sanitized_columns: Vec<String>,
+    optimize_storage: Option<bool>,
+    chunk_size: usize,
+) -> Result<Vec<SendChunk>, Status> {
+    let mut buf = Vec::new();
+    let mut column_lengths = Vec::with_capacity(df.width());
+    for series in df.get_columns() {
+        let frame = to_canonical_bytes(&DataFrame::new_no_checks(vec![series.clone()]))?;
+        column_lengths.push(frame.len() as u64);
+        buf.extend_from_slice(&frame);
+    }
+    let mut chunks = sized_upload_chunks(
+        &buf,
+        policy,
+        sanitized_columns,
+        optimize_storage,
+        chunk_size,
+    )?;
+    chunks[0].column_lengths = column_lengths;
+    Ok(chunks)
+}
+
+/// Reassembles a fetched dataframe from the chunks streamed by [`serialize_delayed_dataframe`].
+///
+/// On fetches with a column order, the columns can be decoded one by one with
+/// [`FetchAssembler::column`] as they arrive, and are put back in schema order by
+/// [`FetchAssembler::finish`].
+#[derive(Debug, Default)]
+pub struct FetchAssembler {
+    buf: Vec<u8>,
+    /// The columns announced so far, and their data.
+    columns: Vec<(ColumnStart, Vec<u8>)>,
+    status: Option<FetchStatus>,
+    delta: Option<DeltaHeader>,
+    row_range: Option<RowRange>,
+    shape: Option<ResultShape>,
+    fingerprint: Option<String>,
+    checksum: Option<String>,
+    format: DataFormat,
+}
+
+impl FetchAssembler {
+    /// `canonical` must match the format requested with `ReferenceRequest::canonical_format`.
+    pub fn new(canonical: bool) -> Self {
+        Self::with_format(resolve_format(DataFormat::LegacyFormat, canonical))
+    }
+
+    /// `format` must match the format requested with `ReferenceRequest::format`.
+    pub fn with_format(format: DataFormat) -> Self {
+        FetchAssembler {
+            format,
+            ..Default::default()
         }
     }
 
-    let hash = hex::encode(hasher.finish().as_ref());
+    pub fn push(&mut self, chunk: FetchChunk) -> Result<(), Status> {
+        if self.checksum.is_some() {
+            return Err(Status::data_loss("Received data after the checksum"));
+        }
+        match chunk.body {
+            Some(fetch_chunk::Body::Data(mut data)) => match self.columns.last_mut() {
+                Some((start, buf)) => {
+                    if (buf.len() + data.len()) as u64 > start.length {
+                        return Err(Status::data_loss(format!(
+                            "Column {} is longer than announced",
+                            start.name
+                        )));
+                    }
+                    buf.append(&mut data)
+                }
+                None => self.buf.append(&mut data),
+            },
+            Some(fetch_chunk::Body::ColumnStart(start)) => {
+                if let Some((previous, buf)) = self.columns.last() {
+                    if (buf.len() as u64) < previous.length {
+                        return Err(Status::data_loss(format!(
+                            "Column {} ended early",
+                            previous.name
+                        )));
+                    }
+                }
+                self.columns.push((start, Vec::new()));
+            }
+            Some(fetch_chunk::Body::Pending(reason)) => {
+                self.status = Some(FetchStatus::Pending(reason))
+            }
+            Some(fetch_chunk::Body::Warning(reason)) => {
+                self.status = Some(FetchStatus::Warning(reason))
+            }
+            Some(fetch_chunk::Body::Checksum(checksum)) => self.checksum = Some(checksum),
+            Some(fetch_chunk::Body::Delta(header)) => self.delta = Some(header),
+            Some(fetch_chunk::Body::RowRange(range)) => self.row_range = Some(range),
+            Some(fetch_chunk::Body::Shape(shape)) => self.shape = Some(shape),
+            Some(fetch_chunk::Body::Fingerprint(fingerprint)) => {
+                self.fingerprint = Some(fingerprint)
+            }
+            None => (),
+        }
+        Ok(())
+    }
 
-    let policy = serde_json::from_str(&policy).map_err(|err| {
-        Status::invalid_argument(format!("Error during the parsing of the policy: {err}"))
-    })?;
+    /// Returns the pending or warning state announced by the server, if any.
+    pub fn status(&self) -> Option<&FetchStatus> {
+        self.status.as_ref()
+    }
+
+    /// Whether the data is a delta against the version requested, on delta fetches.
+    pub fn delta(&self) -> Option<&DeltaHeader> {
+        self.delta.as_ref()
+    }
+
+    /// The rows sent and those of the whole dataframe, on fetches restricted to a range of rows.
+    pub fn row_range(&self) -> Option<&RowRange> {
+        self.row_range.as_ref()
+    }
+
+    /// The shape of the fetched data, announced right before the checksum.
+    pub fn shape(&self) -> Option<&ResultShape> {
+        self.shape.as_ref()
+    }
+
+    /// The fingerprint of the fetched dataframe, announced right before the shape, see
+    /// [`crate::fingerprints`].
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    /// The columns announced so far, in the order they are sent.
+    pub fn columns(&self) -> impl Iterator<Item = &ColumnStart> {
+        self.columns.iter().map(|(start, _)| start)
+    }
+
+    /// Decodes column `name` if all of its data arrived. Its checksum is only verified once the
+    /// whole dataframe is, by [`FetchAssembler::finish`].
+    pub fn column(&self, name: &str) -> Result<Option<Series>, Status> {
+        match self.columns.iter().find(|(start, _)| start.name == name) {
+            Some((start, buf)) if buf.len() as u64 == start.length => {
+                self.decode_column(start, buf).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<DataFrame, Status> {
+        decode_dataframe(buf, self.format)
+    }
+
+    fn decode_column(&self, start: &ColumnStart, buf: &[u8]) -> Result<Series, Status> {
+        match &self.decode(buf)?.get_columns()[..] {
+            [series] if series.name() == start.name => Ok(series.clone()),
+            _ => Err(Status::data_loss(format!(
+                "The data of column {} is not that column",
+                start.name
+            ))),
+        }
+    }
+
+    pub fn finish(self) -> Result<(FetchStatus, DataFrame), Status> {
+        let expected = self
+            .checksum
+            .as_deref()
+            .ok_or_else(|| Status::data_loss("The dataframe stream ended without a checksum"))?;
+        let mut hasher = digest::Context::new(&digest::SHA256);
+        hasher.update(&self.buf);
+        for (_, buf) in self.columns.iter() {
+            hasher.update(buf);
+        }
+        let actual = hex::encode(hasher.finish().as_ref());
+        if actual != expected {
+            return Err(Status::data_loss(format!(
+                "Checksum mismatch on fetched dataframe: expected {expected}, got {actual}"
+            )));
+        }
+        let df = if self.columns.is_empty() {
+            self.decode(&self.buf)?
+        } else {
+            // Back in schema order.
+            let mut columns: Vec<Option<Series>> = vec![None; self.columns.len()];
+            for (start, buf) in self.columns.iter() {
+                let series = self.decode_column(start, buf)?;
+                match columns.get_mut(start.index as usize) {
+                    Some(slot @ None) => *slot = Some(series),
+                    _ => {
+                        return Err(Status::data_loss(format!(
+                            "Invalid position {} of column {}",
+                            start.index, start.name
+                        )))
+                    }
+                }
+            }
+            DataFrame::new(columns.into_iter().flatten().collect())
+                .map_err(|e| Status::data_loss(format!("Polars error: {e}")))?
+        };
+        Ok((self.status.unwrap_or(FetchStatus::Ok), df))
+    }
+}
+
+/// A dataframe uploaded by the client, along with the options sent on the first chunk.
+pub struct Upload {
+    pub dataframe: DataFrame,
+    pub hash: String,
+    pub policy: String,
+    pub sanitized_columns: Vec<String>,
+    /// Whether to optimize storage, and if so whether lossy floats are allowed.
+    pub optimize: Option<bool>,
+    pub append_to: String,
+    pub key_columns: Vec<String>,
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+/// Upper bound on the buffer reserved ahead for a column, whose declared length is not trusted.
+const MAX_COLUMN_RESERVE: usize = 64 * 1024 * 1024;
+
+/// Reassembles an uploaded dataframe from the chunks built by [`upload_chunks`] or
+/// [`column_upload_chunks`].
+///
+/// Whole-dataframe payloads are buffered whole. When column lengths are declared, each column is
+/// decoded as soon as its frame is complete and its bytes are dropped right away: the memory
+/// needed on top of the dataframe is then the largest column frame, not the whole payload.
+pub struct UploadAssembler {
+    hasher: digest::Context,
+    received_first: bool,
+    policy: String,
+    sanitized_columns: Vec<String>,
+    optimize: Option<bool>,
+    expected_checksum: String,
+    append_to: String,
+    key_columns: Vec<String>,
+    name: String,
+    tags: Vec<String>,
+    format: DataFormat,
+    by_column: bool,
+    /// Lengths of the column frames that are not fully received yet.
+    column_lengths: VecDeque<usize>,
+    buf: Vec<u8>,
+    columns: Vec<Series>,
+    /// Bytes of chunk data received so far, and the most accepted, 0 for no limit.
+    received: u64,
+    max_bytes: u64,
+}
+
+impl Default for UploadAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UploadAssembler {
+    pub fn new() -> Self {
+        UploadAssembler {
+            hasher: digest::Context::new(&digest::SHA256),
+            received_first: false,
+            policy: String::new(),
+            sanitized_columns: Vec::new(),
+            optimize: None,
+            expected_checksum: String::new(),
+            append_to: String::new(),
+            key_columns: Vec::new(),
+            name: String::new(),
+            tags: Vec::new(),
+            format: DataFormat::LegacyFormat,
+            by_column: false,
+            column_lengths: VecDeque::new(),
+            buf: Vec::new(),
+            columns: Vec::new(),
+            received: 0,
+            max_bytes: 0,
+        }
+    }
+
+    /// Rejects uploads of more than `max_bytes` bytes of chunk data, unless 0.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn push(&mut self, mut chunk: SendChunk) -> Result<(), Status> {
+        self.received += chunk.data.len() as u64;
+        if self.max_bytes > 0 && self.received > self.max_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "The upload is larger than the {} bytes this server accepts",
+                self.max_bytes
+            )));
+        }
+        self.hasher.update(&chunk.data);
+        if !self.received_first {
+            self.policy = chunk.policy;
+            self.sanitized_columns = chunk.sanitized_columns;
+            if chunk.optimize_storage {
+                self.optimize = Some(chunk.allow_lossy_floats);
+            }
+            self.expected_checksum = chunk.checksum;
+            self.append_to = chunk.append_to;
+            self.key_columns = chunk.key_columns;
+            self.name = chunk.name;
+            self.tags = chunk.tags;
+            self.column_lengths = chunk
+                .column_lengths
+                .into_iter()
+                .map(usize::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(|_| Status::invalid_argument("Column frame too large"))?;
+            self.by_column = !self.column_lengths.is_empty();
+            self.format = chunk.format();
+            if self.by_column
+                && !matches!(
+                    self.format,
+                    DataFormat::LegacyFormat | DataFormat::Canonical
+                )
+            {
+                return Err(Status::invalid_argument(format!(
+                    "Column frames are canonical, not {:?}",
+                    self.format
+                )));
+            }
+            self.received_first = true;
+        }
+
+        if !self.by_column {
+            self.buf.append(&mut chunk.data);
+            return Ok(());
+        }
+
+        let mut data = &chunk.data[..];
+        while !data.is_empty() {
+            let len = *self.column_lengths.front().ok_or_else(|| {
+                Status::invalid_argument("The upload is longer than its declared columns")
+            })?;
+            if self.buf.is_empty() {
+                self.buf.reserve_exact(len.min(MAX_COLUMN_RESERVE));
+            }
+            let n = (len - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == len {
+                self.decode_column()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_column(&mut self) -> Result<(), Status> {
+        self.column_lengths.pop_front();
+        let index = self.columns.len();
+        let frame = std::mem::take(&mut self.buf);
+        let df = from_canonical_bytes(&frame).map_err(|err| {
+            Status::invalid_argument(format!(
+                "Could not decode column {index} of the upload: {}",
+                err.message()
+            ))
+        })?;
+        drop(frame);
+        match &df.get_columns()[..] {
+            [series] => self.columns.push(series.clone()),
+            columns => {
+                return Err(Status::invalid_argument(format!(
+                    "Column frames must contain exactly one column, frame {index} has {}",
+                    columns.len()
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails unless the decoded columns all have the same length, naming those that differ from
+    /// the first one.
+    fn check_column_lengths(&self) -> Result<(), Status> {
+        let first = match self.columns.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let mismatched: Vec<_> = self
+            .columns
+            .iter()
+            .filter(|series| series.len() != first.len())
+            .map(|series| format!("`{}` has {}", series.name(), series.len()))
+            .collect();
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        Err(Status::invalid_argument(format!(
+            "The columns of the upload have different lengths: `{}` has {} rows, {}",
+            first.name(),
+            first.len(),
+            mismatched.join(", ")
+        )))
+    }
+
+    /// Verifies the checksum, if one was sent, and returns the upload.
+    pub fn finish(self) -> Result<Upload, Status> {
+        let hash = hex::encode(self.hasher.finish().as_ref());
+        if !self.expected_checksum.is_empty() && self.expected_checksum != hash {
+            return Err(Status::data_loss(format!(
+                "Checksum mismatch on uploaded dataframe: expected {}, got {hash}",
+                self.expected_checksum
+            )));
+        }
+
+        let dataframe = if self.by_column {
+            if !self.column_lengths.is_empty() {
+                return Err(Status::invalid_argument(
+                    "The upload ended before all of its declared columns",
+                ));
+            }
+            self.check_column_lengths()?;
+            DataFrame::new(self.columns).map_err(|err| {
+                Status::invalid_argument(format!("Could not assemble the uploaded columns: {err}"))
+            })?
+        } else {
+            decode_dataframe(&self.buf, self.format).map_err(|err| {
+                Status::invalid_argument(format!(
+                    "Could not decode the upload as {:?}: {}",
+                    resolve_format(self.format, false),
+                    err.message()
+                ))
+            })?
+        };
+        capabilities::check_rows(dataframe.height() as u64)?;
 
-    let view = std::io::Cursor::new(&buf);
-    let df = polars::io::ipc::IpcReader::new(view)
+        Ok(Upload {
+            dataframe,
+            hash,
+            policy: self.policy,
+            sanitized_columns: self.sanitized_columns,
+            optimize: self.optimize,
+            append_to: self.append_to,
+            key_columns: self.key_columns,
+            name: self.name,
+            tags: self.tags,
+        })
+    }
+}
+
+/// Reads an upload stream of at most `max_bytes` bytes of chunk data (0 for no limit), verifying
+/// its checksum if one was sent.
+///
+/// Chunks go through `faults` as they are received, see [`crate::faults`].
+pub async fn read_upload(
+    mut stream: tonic::Streaming<SendChunk>,
+    mut faults: StreamFaults,
+    max_bytes: u64,
+) -> Result<Upload, Status> {
+    let mut assembler = UploadAssembler::new().with_max_bytes(max_bytes);
+    let mut next = stream.next().await;
+    while let Some(chunk) = next {
+        let chunk = chunk?;
+        next = stream.next().await;
+        match faults.apply(chunk, next.is_none()).await {
+            Delivery::Once(chunk) => assembler.push(chunk)?,
+            Delivery::Twice(chunk) => {
+                assembler.push(chunk.clone())?;
+                assembler.push(chunk)?;
+            }
+            Delivery::Cut => break,
+        }
+    }
+    assembler.finish()
+}
+
+/// Reads an upload, whose policy `defaults` complete if given, see [`crate::default_policies`].
+pub async fn unserialize_dataframe(
+    stream: tonic::Streaming<SendChunk>,
+    faults: StreamFaults,
+    max_bytes: u64,
+    blank_names: BlankColumnNames,
+    defaults: Option<&DefaultPolicy>,
+) -> Result<(DataFrameArtifact, String, Option<bool>), Status> {
+    let mut upload = read_upload(stream, faults, max_bytes).await?;
+    check_column_names(&mut upload.dataframe, blank_names)?;
+
+    let (policy, blacklist, applied) = match defaults {
+        Some(defaults) => {
+            let columns = upload.dataframe.get_column_names();
+            let (policy, blacklist, applied) =
+                defaults.apply(&upload.policy, &columns, upload.sanitized_columns)?;
+            (policy, blacklist, Some(applied))
+        }
+        None if upload.policy.trim().is_empty() => {
+            return Err(Status::invalid_argument(
+                "The upload has no policy and no default policy applies to it",
+            ))
+        }
+        None => {
+            let policy: Policy = serde_json::from_str(&upload.policy).map_err(|err| {
+                Status::invalid_argument(format!("Error during the parsing of the policy: {err}"))
+            })?;
+            (policy, upload.sanitized_columns, None)
+        }
+    };
+    policy.check_masks(&upload.dataframe.schema())?;
+
+    let mut artifact = DataFrameArtifact::new(upload.dataframe, policy, blacklist);
+    artifact.defaults = applied;
+    artifact.catalog.name = upload.name;
+    artifact.catalog.tags = upload.tags;
+    Ok((artifact, upload.hash, upload.optimize))
+}
+
+pub fn ipc_to_dataframe(buf: &[u8]) -> Result<DataFrame, Status> {
+    polars::io::ipc::IpcReader::new(std::io::Cursor::new(buf))
+        .finish()
+        .map_err(|err| Status::invalid_argument(format!("Polars error: {err}")))
+}
+
+pub fn ipc_stream_to_dataframe(buf: &[u8]) -> Result<DataFrame, Status> {
+    polars::io::ipc::IpcStreamReader::new(std::io::Cursor::new(buf))
         .finish()
-        .map_err(|err| Status::invalid_argument(format!("Polars error: {err}")))?;
+        .map_err(|err| Status::invalid_argument(format!("Polars error: {err}")))
+}
 
-    Ok((DataFrameArtifact::new(df, policy, sanitized_columns), hash))
+/// Hex-encoded SHA256 of a serialized payload.
+pub fn checksum(buf: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, buf).as_ref())
 }
 
 // so, to hash a dataset, this does a full serialization; that's kinda bad
-pub fn hash_dataset(df: &mut DataFrame) -> Result<String, PolarsError> {
-    let buf = dataframe_ser_helper(df)?;
-    Ok(hex::encode(digest::digest(&digest::SHA256, &buf).as_ref()))
+// the canonical format is used so that the hash does not depend on chunking or the polars version
+pub fn hash_dataset(df: &DataFrame) -> Result<String, Status> {
+    Ok(checksum(&to_canonical_bytes(df)?))
 }
 
 // This requires &mut because polars is kinda weird about that, but it's not mutated..
-fn dataframe_ser_helper(df: &mut DataFrame) -> Result<Vec<u8>, PolarsError> {
+pub fn dataframe_ser_helper(df: &mut DataFrame) -> Result<Vec<u8>, PolarsError> {
     let mut buf = Vec::new();
 
     // PERF: this can be replaced by manually using arrow IPC methods, to avoid this copy
@@ -66,46 +683,262 @@ fn dataframe_ser_helper(df: &mut DataFrame) -> Result<Vec<u8>, PolarsError> {
     Ok(buf)
 }
 
+/// How a fetched dataframe is streamed.
+#[derive(Debug, Clone, Default)]
+pub struct FetchFormat {
+    /// Never [`DataFormat::LegacyFormat`], see [`resolve_format`].
+    pub data: DataFormat,
+    /// Column by column in this order, see [`column_order`].
+    pub column_order: Option<ColumnOrder>,
+    /// Bytes of data per chunk, the last chunk of each frame holding the rest.
+    pub chunk_size: usize,
+    /// Sent in the trailer, see [`crate::fingerprints`]. Left out if empty.
+    pub fingerprint: String,
+}
+
+impl FetchFormat {
+    /// The format `request` asks for, in chunks of `default_chunk_size` bytes unless it asks for
+    /// another size.
+    pub fn of(request: &ReferenceRequest, default_chunk_size: usize) -> Self {
+        let chunk_size = match request.chunk_kb {
+            0 => default_chunk_size,
+            kb => (kb as usize).saturating_mul(1 << 10),
+        };
+        FetchFormat {
+            data: resolve_format(request.format(), request.canonical_format),
+            column_order: request.column_order.clone(),
+            chunk_size: chunk_size.clamp(1, MAX_CHUNK_BYTES),
+            fingerprint: String::new(),
+        }
+    }
+}
+
+/// Positions of the columns of `df` in the order they are sent: the listed columns first, then
+/// the others by strategy.
+pub fn column_order(df: &DataFrame, order: &ColumnOrder) -> Result<Vec<usize>, Status> {
+    let mut positions = Vec::with_capacity(df.width());
+    for name in order.columns.iter() {
+        let position = df.find_idx_by_name(name).ok_or_else(|| {
+            Status::invalid_argument(format!("Unknown column {name} in the column order"))
+        })?;
+        if positions.contains(&position) {
+            return Err(Status::invalid_argument(format!(
+                "Column {name} is listed twice in the column order"
+            )));
+        }
+        positions.push(position);
+    }
+    let mut rest: Vec<usize> = (0..df.width())
+        .filter(|position| !positions.contains(position))
+        .collect();
+    if order.strategy() == column_order::Strategy::SmallestFirst {
+        // Stable: columns of the same size stay in schema order.
+        let columns = df.get_columns();
+        rest.sort_by_key(|&position| columns[position].estimated_size());
+    }
+    positions.extend(rest);
+    Ok(positions)
+}
+
+/// Streams a dataframe to the client in `format`.
 pub async fn serialize_delayed_dataframe(
     df: DelayedDataFrame,
+    format: FetchFormat,
+    guard: FetchGuard,
+    faults: StreamFaults,
 ) -> Response<ReceiverStream<Result<FetchChunk, Status>>> {
-    let (tx, rx) = mpsc::channel(4);
-
-    match df.fetch_status {
-        FetchStatus::Pending(reason) => tx
-            .send(Ok(FetchChunk {
-                body: Some(fetch_chunk::Body::Pending(reason)),
-            }))
-            .await
-            .unwrap(),
-        FetchStatus::Warning(reason) => tx
-            .send(Ok(FetchChunk {
-                body: Some(fetch_chunk::Body::Warning(reason)),
-            }))
-            .await
-            .unwrap(),
-        _ => (),
-    }
-
-    tokio::spawn(async move {
-        // important things to note about tokio channels:
-        // - send() on them will block until there is space in the queue
-        // - send() returns an error when the receiver has been dropped / .close() has been called on it
-        //   this means that send() will return Err only when the client has "lost interest", has dropped the connection / call
-
-        let mut df: DataFrame = match df.future.await {
-            Ok(df) => df,
-            Err(e) => {
-                // ignore send() error: error means the channel has been closed, ie, client dropped the request.
-                let _ignored = tx.send(Err(e)).await;
-                return;
+    serialize_delayed(df, format, guard, faults, |df| Ok((df, None))).await
+}
+
+/// Streams the `limit` rows of a dataframe from `offset`, the rest of them if `None`, after a
+/// [`RowRange`] chunk with the number of rows of the whole dataframe. Offsets past the end send
+/// no rows.
+pub async fn serialize_delayed_rows(
+    df: DelayedDataFrame,
+    format: FetchFormat,
+    offset: u64,
+    limit: Option<u64>,
+    guard: FetchGuard,
+    faults: StreamFaults,
+) -> Response<ReceiverStream<Result<FetchChunk, Status>>> {
+    serialize_delayed(df, format, guard, faults, move |df| {
+        let total_rows = df.height() as u64;
+        let start = offset.min(total_rows);
+        let length = limit.unwrap_or(u64::MAX).min(total_rows - start);
+        let range = RowRange { offset, total_rows };
+        Ok((
+            df.slice(start as i64, length as usize),
+            Some(fetch_chunk::Body::RowRange(range)),
+        ))
+    })
+    .await
+}
+
+/// The versions a delta fetch is computed on, see [`crate::delta`].
+pub struct DeltaFetch {
+    pub identifier: String,
+    pub keys: Vec<String>,
+    /// The previous version, whose keys the delta discloses, and the new one, or why the full
+    /// result is sent instead.
+    pub versions: Result<(Released, DataFrame), Fallback>,
+}
+
+impl DeltaFetch {
+    /// Replaces the fetched result with the delta, if one can be computed.
+    fn delta(self, df: DataFrame) -> Result<(DataFrame, DeltaHeader), Status> {
+        let (df, fallback) = match self.versions {
+            Ok((previous, current)) if current.height() == df.height() => {
+                let previous = previous.dataframe();
+                match DeltaRows::diff(previous, &current, &self.keys)? {
+                    Ok(rows) if rows.len() >= current.height().max(1) => {
+                        (df, Some("most rows changed".to_string()))
+                    }
+                    Ok(rows) => (rows.to_dataframe(previous, &df, &self.keys)?, None),
+                    Err(fallback) => (df, Some(fallback)),
+                }
             }
+            Ok(_) => (df, Some("the result changed during the fetch".to_string())),
+            Err(fallback) => (df, Some(fallback)),
         };
+        Ok((
+            df,
+            DeltaHeader {
+                identifier: self.identifier,
+                full: fallback.is_some(),
+                reason: fallback.unwrap_or_default(),
+            },
+        ))
+    }
+}
+
+/// Streams the rows of a result changed since a previous version, or the full result with the
+/// reason why when no delta can be computed.
+pub async fn serialize_delayed_delta(
+    df: DelayedDataFrame,
+    format: FetchFormat,
+    delta: DeltaFetch,
+    guard: FetchGuard,
+    faults: StreamFaults,
+) -> Response<ReceiverStream<Result<FetchChunk, Status>>> {
+    serialize_delayed(df, format, guard, faults, move |df| {
+        let (df, header) = delta.delta(df)?;
+        Ok((df, Some(fetch_chunk::Body::Delta(header))))
+    })
+    .await
+}
+
+/// Streams the dataframe returned by `prepare` once `df` is ready, after the chunk it may return.
+/// Data chunks go through `faults` as they are sent, see [`crate::faults`].
+///
+/// Dataframes that are ready right away and have no rows are sent as a single schema-only chunk
+/// without spawning a task.
+async fn serialize_delayed(
+    df: DelayedDataFrame,
+    format: FetchFormat,
+    mut guard: FetchGuard,
+    mut faults: StreamFaults,
+    prepare: impl FnOnce(DataFrame) -> Result<(DataFrame, Option<fetch_chunk::Body>), Status>
+        + Send
+        + 'static,
+) -> Response<ReceiverStream<Result<FetchChunk, Status>>> {
+    // Room for the status, prepared, fingerprint and shape chunks, a schema-only data chunk and the
+    // checksum.
+    let (tx, rx) = mpsc::channel(8);
 
-        let res = dataframe_ser_helper(&mut df)
-            .map_err(|err| Status::internal(format!("Polars error: {err}"))); // this is an internal error
+    // ignore send() errors: the receiver is only returned below.
+    let ready = match df.fetch_status {
+        FetchStatus::Pending(reason) => {
+            let _ignored = tx
+                .send(Ok(FetchChunk {
+                    body: Some(fetch_chunk::Body::Pending(reason)),
+                }))
+                .await;
+            false
+        }
+        FetchStatus::Warning(reason) => {
+            let _ignored = tx
+                .send(Ok(FetchChunk {
+                    body: Some(fetch_chunk::Body::Warning(reason)),
+                }))
+                .await;
+            true
+        }
+        FetchStatus::Ok => true,
+    };
 
-        let buf = match res {
+    if ready {
+        // Not pending on an approval: the dataframe, or the refusal, is already there.
+        let prepared = df.future.await.and_then(|df| prepare(df.into_dataframe()));
+        if matches!(&prepared, Ok((df, _)) if df.height() == 0) {
+            send_prepared(&tx, prepared, &format, &mut guard, &mut faults).await;
+        } else {
+            tokio::spawn(async move {
+                send_prepared(&tx, prepared, &format, &mut guard, &mut faults).await;
+            });
+        }
+    } else {
+        tokio::spawn(async move {
+            let prepared = df.future.await.and_then(|df| prepare(df.into_dataframe()));
+            send_prepared(&tx, prepared, &format, &mut guard, &mut faults).await;
+        });
+    }
+
+    Response::new(ReceiverStream::new(rx))
+}
+
+/// Sends the chunk returned by `prepare`, then the dataframe, its fingerprint, its shape and its
+/// checksum.
+///
+/// With a column order, each column is serialized when its turn comes, and sent after a
+/// [`ColumnStart`].
+async fn send_prepared(
+    tx: &mpsc::Sender<Result<FetchChunk, Status>>,
+    prepared: Result<(DataFrame, Option<fetch_chunk::Body>), Status>,
+    format: &FetchFormat,
+    guard: &mut FetchGuard,
+    faults: &mut StreamFaults,
+) {
+    // important things to note about tokio channels:
+    // - send() on them will block until there is space in the queue
+    // - send() returns an error when the receiver has been dropped / .close() has been called on it
+    //   this means that send() will return Err only when the client has "lost interest", has dropped the connection / call
+
+    let df: DataFrame = match prepared {
+        Ok((df, None)) => df,
+        Ok((df, Some(body))) => {
+            if let Err(_ignored) = tx.send(Ok(FetchChunk { body: Some(body) })).await {
+                return;
+            }
+            df
+        }
+        Err(e) => {
+            // ignore send() error: error means the channel has been closed, ie, client dropped the request.
+            let _ignored = tx.send(Err(e)).await;
+            return;
+        }
+    };
+
+    // The whole dataframe, or each column in order.
+    let frames: Vec<Option<usize>> = match &format.column_order {
+        None => vec![None],
+        Some(order) => match column_order(&df, order) {
+            Ok(positions) => positions.into_iter().map(Some).collect(),
+            Err(err) => {
+                let _ignored = tx.send(Err(err)).await;
+                return;
+            }
+        },
+    };
+
+    let mut hasher = digest::Context::new(&digest::SHA256);
+    let mut index = 0;
+    let mut sent = 0;
+    for (frame, position) in frames.iter().enumerate() {
+        let mut frame_df = match position {
+            Some(position) => DataFrame::new_no_checks(vec![df.get_columns()[*position].clone()]),
+            None => df.clone(),
+        };
+        let buf = match encode_dataframe(&mut frame_df, format.data) {
             Ok(buf) => buf,
             Err(err) => {
                 // ignore send() error
@@ -114,18 +947,153 @@ pub async fn serialize_delayed_dataframe(
             }
         };
 
-        for chunk in buf.chunks(CHUNK_SIZE) {
+        if let Some(position) = position {
+            let series = &df.get_columns()[*position];
+            let start = FetchChunk {
+                body: Some(fetch_chunk::Body::ColumnStart(ColumnStart {
+                    name: series.name().to_string(),
+                    dtype: series.dtype().to_string(),
+                    index: *position as u32,
+                    length: buf.len() as u64,
+                })),
+            };
+            if let Err(_ignored) = tx.send(Ok(start)).await {
+                return;
+            }
+        }
+
+        let chunk_size = format.chunk_size.max(1);
+        let chunks = buf.len().div_ceil(chunk_size);
+        for (chunk_index, chunk) in buf.chunks(chunk_size).enumerate() {
+            if let Err(err) = guard.checkpoint(index) {
+                warn!(
+                    "Terminated the fetch of {} by {} after {} bytes: {}",
+                    guard.identifier(),
+                    guard.recipient(),
+                    sent,
+                    err.message()
+                );
+                let _ignored = tx.send(Err(err)).await;
+                return;
+            }
+            hasher.update(chunk);
             let data = FetchChunk {
                 body: Some(fetch_chunk::Body::Data(chunk.into())),
             };
+            let last = frame + 1 == frames.len() && chunk_index + 1 == chunks;
+            let data = match faults.apply(data, last).await {
+                Delivery::Once(data) => data,
+                Delivery::Twice(data) => {
+                    if let Err(_ignored) = tx.send(Ok(data.clone())).await {
+                        return;
+                    }
+                    data
+                }
+                Delivery::Cut => return,
+            };
 
             if let Err(_ignored) = tx.send(Ok(data)).await {
                 // we have a send() error, meaning client isnt listening anymore
                 // stop the task when this is the case
+                debug!(
+                    "The fetch of {} by {} was dropped by the client after {} bytes",
+                    guard.identifier(),
+                    guard.recipient(),
+                    sent
+                );
                 return;
             }
+            index += 1;
+            sent += chunk.len();
         }
-    });
+    }
 
-    Response::new(ReceiverStream::new(rx))
+    if !format.fingerprint.is_empty() {
+        let fingerprint = FetchChunk {
+            body: Some(fetch_chunk::Body::Fingerprint(format.fingerprint.clone())),
+        };
+        if let Err(_ignored) = tx.send(Ok(fingerprint)).await {
+            return;
+        }
+    }
+    let shape = FetchChunk {
+        body: Some(fetch_chunk::Body::Shape(result_shape(
+            df.height(),
+            &df.schema(),
+        ))),
+    };
+    if let Err(_ignored) = tx.send(Ok(shape)).await {
+        return;
+    }
+    let _ignored = tx
+        .send(Ok(FetchChunk {
+            body: Some(fetch_chunk::Body::Checksum(hex::encode(
+                hasher.finish().as_ref(),
+            ))),
+        }))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The error the upload of `chunks` fails with.
+    fn rejection(chunks: Vec<SendChunk>) -> Status {
+        let mut assembler = UploadAssembler::new();
+        for chunk in chunks {
+            if let Err(err) = assembler.push(chunk) {
+                return err;
+            }
+        }
+        assembler.finish().err().expect("The upload was accepted")
+    }
+
+    /// Uploads `frames`, one-column canonical frames, in chunks of 16 bytes.
+    fn column_chunks(frames: &[Vec<u8>]) -> Vec<SendChunk> {
+        let buf = frames.concat();
+        let policy = Policy::allow_by_default();
+        let mut chunks = sized_upload_chunks(&buf, &policy, Vec::new(), None, 16).unwrap();
+        chunks[0].column_lengths = frames.iter().map(|frame| frame.len() as u64).collect();
+        chunks
+    }
+
+    #[test]
+    fn corrupted_uploads_are_rejected_with_the_failing_column() {
+        let ids = to_canonical_bytes(&df! { "id" => [1i64, 2, 3] }.unwrap()).unwrap();
+        let names = to_canonical_bytes(&df! { "name" => ["a", "b", "c"] }.unwrap()).unwrap();
+        let short = to_canonical_bytes(&df! { "age" => [30i64, 40] }.unwrap()).unwrap();
+
+        let truncated = names[..names.len() - 1].to_vec();
+        let err = rejection(column_chunks(&[ids.clone(), truncated]));
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(
+            err.message().starts_with(
+                "Could not decode column 1 of the upload: Invalid canonical dataframe"
+            ),
+            "{err:?}"
+        );
+
+        let err = rejection(column_chunks(&[ids, names, short]));
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            err.message(),
+            "The columns of the upload have different lengths: `id` has 3 rows, `age` has 2"
+        );
+
+        let buf = encode_dataframe(
+            &mut df! { "id" => [1i64, 2, 3] }.unwrap(),
+            DataFormat::IpcFile,
+        )
+        .unwrap();
+        let policy = Policy::allow_by_default();
+        let chunks = upload_chunks(&buf[..buf.len() / 2], &policy, Vec::new(), None).unwrap();
+        let err = rejection(chunks);
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(
+            err.message()
+                .starts_with("Could not decode the upload as IpcFile: "),
+            "{err:?}"
+        );
+    }
 }
@@ -0,0 +1,220 @@
+//! Shapes of results, so that clients need not decode a frame to handle empty and scalar results.
+//!
+//! A result without rows is `empty`, one with a single row and a single column is a `scalar`,
+//! which can be fetched as a typed value, and anything else is a `table`.
+
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::polars_proto::{
+    result_shape, scalar_value, Empty, ResultShape, ScalarShape, ScalarValue, TableShape,
+};
+
+/// The shape of a result of `rows` rows with the declared `schema`.
+pub fn result_shape(rows: usize, schema: &Schema) -> ResultShape {
+    let shape = match (rows, schema.len()) {
+        (0, _) => result_shape::Shape::Empty(Empty {}),
+        (1, 1) => result_shape::Shape::Scalar(ScalarShape {
+            dtype: schema
+                .iter()
+                .map(|(_, dtype)| dtype.to_string())
+                .next()
+                .unwrap_or_default(),
+        }),
+        (rows, cols) => result_shape::Shape::Table(TableShape {
+            rows: rows as u64,
+            cols: cols as u64,
+        }),
+    };
+    ResultShape { shape: Some(shape) }
+}
+
+/// Fails unless a result of `rows` rows and `cols` columns is a scalar.
+pub fn check_scalar(rows: usize, cols: usize) -> Result<(), Status> {
+    if (rows, cols) != (1, 1) {
+        return Err(Status::failed_precondition(format!(
+            "Only results with one row and one column can be fetched as scalars, this one has {rows} rows and {cols} columns"
+        )));
+    }
+    Ok(())
+}
+
+/// The value of a scalar result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Scalar {
+    /// The value of `df`, which must have one row and one column.
+    ///
+    /// Integers that do not fit in an `i64` and dtypes other than booleans, numbers and strings
+    /// cannot be fetched as scalars.
+    pub fn from_dataframe(df: &DataFrame) -> Result<Self, Status> {
+        check_scalar(df.height(), df.width())?;
+        let series = &df.get_columns()[0];
+        if series.null_count() == 1 {
+            return Ok(Scalar::Null);
+        }
+        let polars_err = |e: PolarsError| Status::internal(format!("Polars error: {e}"));
+        let cast = |dtype: &DataType| series.cast(dtype).map_err(polars_err);
+        let missing = || Status::internal("Missing scalar value");
+        Ok(match series.dtype() {
+            DataType::Boolean => Scalar::Bool(
+                series
+                    .bool()
+                    .map_err(polars_err)?
+                    .get(0)
+                    .ok_or_else(missing)?,
+            ),
+            DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32 => Scalar::Int(
+                cast(&DataType::Int64)?
+                    .i64()
+                    .map_err(polars_err)?
+                    .get(0)
+                    .ok_or_else(missing)?,
+            ),
+            DataType::UInt64 => {
+                let value = series
+                    .u64()
+                    .map_err(polars_err)?
+                    .get(0)
+                    .ok_or_else(missing)?;
+                Scalar::Int(i64::try_from(value).map_err(|_| {
+                    Status::failed_precondition(format!(
+                        "The scalar {value} does not fit in an i64, fetch the dataframe instead"
+                    ))
+                })?)
+            }
+            DataType::Float32 | DataType::Float64 => Scalar::Float(
+                cast(&DataType::Float64)?
+                    .f64()
+                    .map_err(polars_err)?
+                    .get(0)
+                    .ok_or_else(missing)?,
+            ),
+            DataType::Utf8 | DataType::Categorical(_) => Scalar::String(
+                cast(&DataType::Utf8)?
+                    .utf8()
+                    .map_err(polars_err)?
+                    .get(0)
+                    .ok_or_else(missing)?
+                    .to_string(),
+            ),
+            dtype => {
+                return Err(Status::failed_precondition(format!(
+                "Scalars of dtype {dtype} cannot be fetched as values, fetch the dataframe instead"
+            )))
+            }
+        })
+    }
+
+    pub fn into_proto(self, dtype: &DataType, warning: String) -> ScalarValue {
+        let value = match self {
+            Scalar::Null => scalar_value::Value::Null(Empty {}),
+            Scalar::Bool(value) => scalar_value::Value::Bool(value),
+            Scalar::Int(value) => scalar_value::Value::Int(value),
+            Scalar::Float(value) => scalar_value::Value::Float(value),
+            Scalar::String(value) => scalar_value::Value::String(value),
+        };
+        ScalarValue {
+            value: Some(value),
+            dtype: dtype.to_string(),
+            warning,
+        }
+    }
+}
+
+impl From<Option<scalar_value::Value>> for Scalar {
+    fn from(value: Option<scalar_value::Value>) -> Self {
+        match value {
+            None | Some(scalar_value::Value::Null(_)) => Scalar::Null,
+            Some(scalar_value::Value::Bool(value)) => Scalar::Bool(value),
+            Some(scalar_value::Value::Int(value)) => Scalar::Int(value),
+            Some(scalar_value::Value::Float(value)) => Scalar::Float(value),
+            Some(scalar_value::Value::String(value)) => Scalar::String(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalar(series: Series) -> Result<Scalar, Status> {
+        Scalar::from_dataframe(&DataFrame::new(vec![series]).unwrap())
+    }
+
+    #[test]
+    fn every_dtype_maps_to_a_scalar() {
+        let cases = [
+            (Series::new("b", [true]), Scalar::Bool(true)),
+            (Series::new("i", [-3i8]), Scalar::Int(-3)),
+            (Series::new("i", [-3i16]), Scalar::Int(-3)),
+            (Series::new("i", [-3i32]), Scalar::Int(-3)),
+            (Series::new("i", [-3i64]), Scalar::Int(-3)),
+            (Series::new("u", [3u8]), Scalar::Int(3)),
+            (Series::new("u", [3u16]), Scalar::Int(3)),
+            (Series::new("u", [3u32]), Scalar::Int(3)),
+            (Series::new("u", [3u64]), Scalar::Int(3)),
+            (Series::new("f", [0.5f32]), Scalar::Float(0.5)),
+            (Series::new("f", [0.5f64]), Scalar::Float(0.5)),
+            (Series::new("s", ["a"]), Scalar::String("a".into())),
+            (
+                Series::new("c", ["a"])
+                    .cast(&DataType::Categorical(None))
+                    .unwrap(),
+                Scalar::String("a".into()),
+            ),
+            (Series::new("n", [None::<i64>]), Scalar::Null),
+            (Series::new("n", [None::<&str>]), Scalar::Null),
+        ];
+        for (series, expected) in cases {
+            let dtype = series.dtype().clone();
+            let value = scalar(series).unwrap();
+            assert_eq!(value, expected, "{dtype}");
+            let proto = value.clone().into_proto(&dtype, String::new());
+            assert_eq!(Scalar::from(proto.value), value);
+        }
+
+        let err = scalar(Series::new("u", [u64::MAX])).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let date = Series::new("d", [1i32]).cast(&DataType::Date).unwrap();
+        assert_eq!(
+            scalar(date).unwrap_err().code(),
+            tonic::Code::FailedPrecondition
+        );
+    }
+
+    #[test]
+    fn shapes() {
+        let df = df! { "a" => [1i64, 2], "b" => ["x", "y"] }.unwrap();
+        let shape = |df: &DataFrame| result_shape(df.height(), &df.schema()).shape.unwrap();
+        assert_eq!(
+            shape(&df),
+            result_shape::Shape::Table(TableShape { rows: 2, cols: 2 })
+        );
+        assert_eq!(
+            shape(&df.head(Some(0))),
+            result_shape::Shape::Empty(Empty {})
+        );
+        assert_eq!(
+            shape(&df.select(["b"]).unwrap().head(Some(1))),
+            result_shape::Shape::Scalar(ScalarShape {
+                dtype: DataType::Utf8.to_string()
+            })
+        );
+        let err = Scalar::from_dataframe(&df).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,64 @@
+//! Heads and slices of a composite plan, without writing a polars plan.
+//!
+//! A `HeadPlanSegment` keeps the first `n` rows of the dataframe on top of the stack, a
+//! `SlicePlanSegment` the `length` rows from `offset`. As in polars, negative offsets count from
+//! the end, and slices past the end of the input are cut short, or empty.
+//!
+//! Both run as the polars segment they stand for, so that the policy and blacklist of the input
+//! apply to the result unchanged. Previews are for a few rows: the rows kept are capped to
+//! `slice_max_rows`, so that a head of a protected table cannot copy all of it.
+
+use polars::prelude::*;
+use tonic::Status;
+
+/// Checks that slices of `length` rows are within `max_rows`.
+pub fn check(segment: &str, length: u64, max_rows: usize) -> Result<(), Status> {
+    if length > max_rows as u64 {
+        return Err(Status::permission_denied(format!(
+            "Could not slice in {segment}: slices keep at most {max_rows} rows \
+             (`slice_max_rows`), this one {length}"
+        )));
+    }
+    Ok(())
+}
+
+/// The polars plan keeping the `length` rows of `df` from `offset`, whose dataframe scan stands
+/// for the input, see [`crate::composite_plan`].
+pub fn plan(df: &DataFrame, offset: i64, length: u64) -> Result<LogicalPlan, Status> {
+    let length = IdxSize::try_from(length).map_err(|_| {
+        Status::invalid_argument(format!("Could not slice: {length} rows is too many"))
+    })?;
+    Ok(df.head(Some(0)).lazy().slice(offset, length).logical_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn slices_are_capped() {
+        check("segment 1", 10, 10).unwrap();
+        let err = check("segment 1", 11, 10).unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert_eq!(
+            err.message(),
+            "Could not slice in segment 1: slices keep at most 10 rows (`slice_max_rows`), \
+             this one 11"
+        );
+
+        let df = df! { "x" => [0i64, 1, 2] }.unwrap();
+        let plan = plan(&df, -2, 5).unwrap();
+        assert!(
+            matches!(
+                plan,
+                LogicalPlan::Slice {
+                    offset: -2,
+                    len: 5,
+                    ..
+                }
+            ),
+            "{plan:?}"
+        );
+    }
+}
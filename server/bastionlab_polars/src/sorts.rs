@@ -0,0 +1,138 @@
+//! Sorts of a composite plan, without writing a polars plan.
+//!
+//! A `SortPlanSegment` sorts the dataframe on top of the stack by a list of keys, each a column
+//! sorted ascending or descending with its nulls first or last:
+//!
+//! ```json
+//! {"type": "SortPlanSegment",
+//!  "by": [{"column": "site"}, {"column": "age", "descending": true, "nulls_last": true}],
+//!  "stable": true}
+//! ```
+//!
+//! Stable sorts keep rows of equal keys in their input order. Sorting only reorders rows: the
+//! segment runs as a polars segment would, so that the policy and blacklist of the input apply to
+//! the result unchanged.
+//!
+//! Keys are checked against the schema of the input before anything runs. Polars sorts the nulls
+//! of every key on the same side, so each key sorts on whether its values are null first.
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::reserved::SORT_ROW;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+    /// Nulls come first unless set, whichever the direction.
+    #[serde(default)]
+    pub nulls_last: bool,
+}
+
+/// Checks that `schema` can be sorted by `by`.
+pub fn check(schema: &Schema, by: &[SortKey]) -> Result<(), Status> {
+    if by.is_empty() {
+        return Err(Status::invalid_argument(
+            "Could not sort: at least one sort key is needed",
+        ));
+    }
+    for key in by {
+        let dtype = schema.get(&key.column).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Could not sort: no column `{}` in the input",
+                key.column
+            ))
+        })?;
+        if matches!(dtype, DataType::List(_) | DataType::Struct(_)) {
+            return Err(Status::invalid_argument(format!(
+                "Could not sort by `{}` ({dtype}): its values have no order",
+                key.column
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The polars plan sorting `df` by `by`, whose dataframe scan stands for the input, see
+/// [`crate::composite_plan`].
+pub fn plan(df: &DataFrame, by: &[SortKey], stable: bool) -> Result<LogicalPlan, Status> {
+    check(&df.schema(), by)?;
+    let ldf = sort(df.head(Some(0)).lazy(), by, stable);
+    // Fails here rather than on the rows, e.g. on dtypes polars cannot sort.
+    ldf.clone()
+        .collect()
+        .map_err(|e| Status::invalid_argument(format!("Could not sort: {e}")))?;
+    Ok(ldf.logical_plan)
+}
+
+fn sort(mut ldf: LazyFrame, by: &[SortKey], stable: bool) -> LazyFrame {
+    let mut exprs = Vec::with_capacity(2 * by.len() + 1);
+    let mut reverse = Vec::with_capacity(exprs.capacity());
+    for key in by {
+        exprs.push(col(&key.column).is_null().cast(DataType::UInt8));
+        reverse.push(!key.nulls_last);
+        exprs.push(col(&key.column));
+        reverse.push(key.descending);
+    }
+    if stable {
+        // Ties are broken by the position of the rows.
+        ldf = ldf.with_row_count(SORT_ROW, None);
+        exprs.push(col(SORT_ROW));
+        reverse.push(false);
+    }
+    let ldf = ldf.sort_by_exprs(exprs, reverse, false);
+    if stable {
+        ldf.drop_columns([SORT_ROW])
+    } else {
+        ldf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(column: &str, descending: bool, nulls_last: bool) -> SortKey {
+        SortKey {
+            column: column.to_string(),
+            descending,
+            nulls_last,
+        }
+    }
+
+    fn rows(by: &[SortKey]) -> Vec<i64> {
+        let df = df! {
+            "site" => [Some("B"), None, Some("A"), Some("B"), Some("A")],
+            "age" => [Some(30i64), Some(40), None, Some(30), Some(50)],
+            "row" => [0i64, 1, 2, 3, 4],
+        }
+        .unwrap();
+        check(&df.schema(), by).unwrap();
+        let sorted = sort(df.lazy(), by, true).collect().unwrap();
+        assert_eq!(sorted.get_column_names(), ["site", "age", "row"]);
+        let rows = sorted.column("row").unwrap().i64().unwrap();
+        rows.into_no_null_iter().collect()
+    }
+
+    #[test]
+    fn keys_sort_their_nulls_on_their_own_side() {
+        assert_eq!(rows(&[key("age", false, false)]), [2, 0, 3, 1, 4]);
+        assert_eq!(rows(&[key("age", true, true)]), [4, 1, 0, 3, 2]);
+        assert_eq!(
+            rows(&[key("site", false, true), key("age", true, false)]),
+            [2, 4, 0, 3, 1]
+        );
+
+        let df = df! { "age" => [1i64] }.unwrap();
+        let err = plan(&df, &[key("missing", false, false)], false).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            err.message(),
+            "Could not sort: no column `missing` in the input"
+        );
+        assert!(plan(&df, &[], false).is_err());
+    }
+}
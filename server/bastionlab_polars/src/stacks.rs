@@ -0,0 +1,174 @@
+//! Vertical stacks of stored dataframes, such as the monthly parts of a dataset.
+//!
+//! A `VStackPlanSegment` reads its `inputs`, identifiers of dataframes, and pushes their rows one
+//! input after the other, in order:
+//!
+//! ```json
+//! {"type": "VStackPlanSegment", "inputs": ["visits-2023-01", "visits-2023-02"]}
+//! ```
+//!
+//! The inputs must have the same columns, in any order: the result has those of the first input,
+//! in its order. Their dtypes must match too, unless `relaxed` is set: columns are then cast to a
+//! dtype all the inputs fit in, integers of different types to `i64` and integers and floats to
+//! `f64`. Like the members of a dataset family, every input is read by the query: the policy of
+//! the result combines all of theirs, and its blacklist is the union of their blacklists.
+
+use polars::prelude::*;
+use tonic::Status;
+
+/// Stacks `inputs`, dataframes by identifier, in order.
+pub fn stack(inputs: Vec<(String, DataFrame)>, relaxed: bool) -> Result<DataFrame, Status> {
+    let (first, first_df) = match &inputs[..] {
+        [] => {
+            return Err(Status::invalid_argument(
+                "Could not stack: at least one input is needed",
+            ))
+        }
+        [(_, df)] => return Ok(df.clone()),
+        [(first, df), ..] => (first, df),
+    };
+    let schema = first_df.schema();
+    let mut dtypes: Vec<(String, DataType)> = schema
+        .iter()
+        .map(|(name, dtype)| (name.to_string(), dtype.clone()))
+        .collect();
+    let mut differences = Vec::new();
+    for (identifier, df) in inputs.iter().skip(1) {
+        let other = df.schema();
+        let mut diff = Vec::new();
+        let missing: Vec<_> = dtypes
+            .iter()
+            .filter(|(name, _)| other.get(name).is_none())
+            .map(|(name, _)| format!("`{name}`"))
+            .collect();
+        if !missing.is_empty() {
+            diff.push(format!("no {}", missing.join(", ")));
+        }
+        let extra: Vec<_> = other
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| schema.get(name).is_none())
+            .map(|name| format!("`{name}`"))
+            .collect();
+        if !extra.is_empty() {
+            diff.push(format!("extra {}", extra.join(", ")));
+        }
+        for (name, dtype) in dtypes.iter_mut() {
+            let other_dtype = match other.get(name) {
+                Some(other_dtype) if other_dtype != dtype => other_dtype,
+                _ => continue,
+            };
+            match supertype(dtype, other_dtype) {
+                Some(common) if relaxed => *dtype = common,
+                _ => diff.push(format!(
+                    "`{name}` is {other_dtype}, not {}",
+                    schema.get(name).unwrap()
+                )),
+            }
+        }
+        if !diff.is_empty() {
+            differences.push(format!("`{identifier}`: {}", diff.join("; ")));
+        }
+    }
+    if !differences.is_empty() {
+        return Err(Status::invalid_argument(format!(
+            "Could not stack: the columns of the inputs differ from those of `{first}`{}\n{}",
+            if relaxed {
+                ""
+            } else {
+                ", set `relaxed` to cast compatible dtypes"
+            },
+            differences.join("\n")
+        )));
+    }
+
+    let mut stacked: Option<DataFrame> = None;
+    for (identifier, df) in inputs.iter() {
+        let columns = dtypes
+            .iter()
+            .map(|(name, dtype)| df.column(name)?.cast(dtype))
+            .collect::<PolarsResult<Vec<_>>>()
+            .and_then(DataFrame::new)
+            .map_err(|e| {
+                Status::invalid_argument(format!("Could not stack `{identifier}`: {e}"))
+            })?;
+        match stacked.as_mut() {
+            Some(stacked) => {
+                stacked.vstack_mut(&columns).map_err(|e| {
+                    Status::invalid_argument(format!("Could not stack `{identifier}`: {e}"))
+                })?;
+            }
+            None => stacked = Some(columns),
+        }
+    }
+    let mut stacked = stacked.unwrap();
+    stacked.rechunk();
+    Ok(stacked)
+}
+
+/// The dtype values of both `a` and `b` can be cast to without loss of meaning, if any.
+fn supertype(a: &DataType, b: &DataType) -> Option<DataType> {
+    match (a, b) {
+        _ if a == b => Some(a.clone()),
+        (DataType::Null, other) | (other, DataType::Null) => Some(other.clone()),
+        _ if is_float(a) || is_float(b) => {
+            (a.is_numeric() && b.is_numeric()).then_some(DataType::Float64)
+        }
+        _ if a.is_numeric() && b.is_numeric() => Some(DataType::Int64),
+        _ => None,
+    }
+}
+
+fn is_float(dtype: &DataType) -> bool {
+    matches!(dtype, DataType::Float32 | DataType::Float64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(dfs: Vec<DataFrame>) -> Vec<(String, DataFrame)> {
+        dfs.into_iter()
+            .enumerate()
+            .map(|(i, df)| (format!("month{}", i + 1), df))
+            .collect()
+    }
+
+    #[test]
+    fn inputs_are_stacked_in_order_and_cast_if_relaxed() {
+        let january = df! { "site" => ["a", "b"], "visits" => [1i64, 2] }.unwrap();
+        let february = df! { "visits" => [3i32], "site" => ["c"] }.unwrap();
+        let march = df! { "site" => ["d"], "visits" => [4.5f64] }.unwrap();
+
+        let err = stack(Vec::new(), false).unwrap_err();
+        assert!(err.message().contains("at least one input"), "{err:?}");
+        let single = stack(inputs(vec![january.clone()]), false).unwrap();
+        assert!(single.frame_equal(&january));
+
+        let all = inputs(vec![january.clone(), february.clone(), march.clone()]);
+        let err = stack(all.clone(), false).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Could not stack: the columns of the inputs differ from those of `month1`, set \
+             `relaxed` to cast compatible dtypes\n`month2`: `visits` is i32, not i64\n`month3`: \
+             `visits` is f64, not i64"
+        );
+        let stacked = stack(all, true).unwrap();
+        assert_eq!(stacked.get_column_names(), ["site", "visits"]);
+        let visits = stacked.column("visits").unwrap().f64().unwrap();
+        assert_eq!(
+            Vec::from(visits),
+            [Some(1.0), Some(2.0), Some(3.0), Some(4.5)]
+        );
+        let stacked = stack(inputs(vec![january.clone(), february]), true).unwrap();
+        assert_eq!(stacked.column("visits").unwrap().dtype(), &DataType::Int64);
+
+        let renamed = df! { "place" => ["e"], "visits" => ["5"] }.unwrap();
+        let err = stack(inputs(vec![january, renamed]), true).unwrap_err();
+        assert!(
+            err.message()
+                .ends_with("`month2`: no `site`; extra `place`; `visits` is str, not i64"),
+            "{err:?}"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Cached per-column statistics of stored dataframes: null counts, numeric bounds and distinct
+//! counts.
+//!
+//! Every mutation of a dataframe bumps the generation of its statistics, and each column records
+//! the generation it was computed at. Appends update the columns that were fresh from the
+//! appended rows alone: null counts add up, bounds widen and distinct-count sketches take the new
+//! values in. Other mutations, such as upserts, leave them stale until they are read again.
+//!
+//! Readers state whether they tolerate stale values: headers and planner heuristics do and only
+//! compute the columns never computed before, policy checks do not and recompute the stale
+//! columns they read, and only those.
+//!
+//! Percentiles of numeric columns cannot be updated from appended rows: they are as of their own
+//! generation, which only computing the column in full moves. Headers requested by data owners
+//! recompute the columns masks read the percentiles of when they are behind, which is how owners
+//! refresh them, see [`crate::masking`].
+
+use std::collections::BTreeMap;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::catalog::now_ms;
+
+/// Whether a reader of statistics accepts values computed before the last mutation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tolerance {
+    Stale,
+    Fresh,
+    /// Fresh, with percentiles as of the last mutation too.
+    Exact,
+}
+
+/// Bits of the hash selecting a register of the distinct-count sketches.
+const SKETCH_BITS: u32 = 12;
+
+/// A HyperLogLog sketch of the distinct non-null values of a column, within about 2%.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistinctSketch {
+    registers: Vec<u8>,
+}
+
+impl Default for DistinctSketch {
+    fn default() -> Self {
+        DistinctSketch {
+            registers: vec![0; 1 << SKETCH_BITS],
+        }
+    }
+}
+
+impl DistinctSketch {
+    fn insert(&mut self, value: &str) {
+        let hash = hash(value.as_bytes());
+        let register = (hash >> (64 - SKETCH_BITS)) as usize;
+        let rank = ((hash << SKETCH_BITS) | (1 << (SKETCH_BITS - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    fn extend(&mut self, series: &Series) -> Result<(), Status> {
+        let values = series.cast(&DataType::Utf8).map_err(polars_err)?;
+        for value in values.utf8().map_err(polars_err)?.into_iter().flatten() {
+            self.insert(value);
+        }
+        Ok(())
+    }
+
+    /// Merges the values of `other` in.
+    pub fn merge(&mut self, other: &DistinctSketch) {
+        for (register, rank) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*rank);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&rank| 2f64.powi(-(rank as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|&&rank| rank == 0).count();
+        // Linear counting is more accurate on small cardinalities.
+        if estimate <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+/// FNV-1a, finalized with the mixer of splitmix64: stable across builds, unlike the hashers of
+/// the standard library, as sketches are persisted.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Could not compute column statistics: {e}"))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStatistics {
+    pub nulls: u64,
+    /// Bounds of the non-null values of numeric columns.
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub distinct: DistinctSketch,
+    /// The generation of the statistics the column was computed or updated at.
+    pub generation: u64,
+    /// When the column was last computed or updated, in milliseconds since the Unix epoch.
+    pub refreshed_at: u64,
+    /// Percentiles 0 to 100 of the non-null values of numeric columns, empty if there are none.
+    #[serde(default)]
+    pub percentiles: Vec<f64>,
+    /// The generation of the statistics the percentiles were computed at.
+    #[serde(default)]
+    pub percentiles_generation: u64,
+}
+
+impl ColumnStatistics {
+    pub fn compute(series: &Series, generation: u64) -> Result<Self, Status> {
+        let mut statistics = ColumnStatistics {
+            nulls: 0,
+            min: None,
+            max: None,
+            distinct: DistinctSketch::default(),
+            generation,
+            refreshed_at: now_ms(),
+            percentiles: percentiles(series)?,
+            percentiles_generation: generation,
+        };
+        statistics.update(series, generation)?;
+        Ok(statistics)
+    }
+
+    /// Adds the values of `series` to the statistics.
+    fn update(&mut self, series: &Series, generation: u64) -> Result<(), Status> {
+        self.nulls += series.null_count() as u64;
+        if series.dtype().is_numeric() {
+            let values = series.cast(&DataType::Float64).map_err(polars_err)?;
+            let values = values.f64().map_err(polars_err)?;
+            if let Some(min) = values.min() {
+                self.min = Some(self.min.map_or(min, |m| m.min(min)));
+            }
+            if let Some(max) = values.max() {
+                self.max = Some(self.max.map_or(max, |m| m.max(max)));
+            }
+        }
+        self.distinct.extend(series)?;
+        self.generation = generation;
+        self.refreshed_at = now_ms();
+        Ok(())
+    }
+}
+
+/// The nearest-rank percentiles 0 to 100 of the non-null, non-NaN values of numeric `series`.
+fn percentiles(series: &Series) -> Result<Vec<f64>, Status> {
+    if !series.dtype().is_numeric() {
+        return Ok(Vec::new());
+    }
+    let values = series.cast(&DataType::Float64).map_err(polars_err)?;
+    let mut values: Vec<f64> = values
+        .f64()
+        .map_err(polars_err)?
+        .into_iter()
+        .flatten()
+        .filter(|value| !value.is_nan())
+        .collect();
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+    values.sort_by(f64::total_cmp);
+    let last = values.len() - 1;
+    Ok((0..=100).map(|p| values[(p * last + 50) / 100]).collect())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Statistics {
+    generation: u64,
+    columns: BTreeMap<String, ColumnStatistics>,
+    /// Number of columns computed from the whole dataframe, rather than updated from mutations.
+    #[serde(skip)]
+    recomputed: u64,
+}
+
+impl Statistics {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn recomputed(&self) -> u64 {
+        self.recomputed
+    }
+
+    pub fn is_stale(&self, column: &str) -> bool {
+        self.columns
+            .get(column)
+            .map_or(true, |statistics| statistics.generation != self.generation)
+    }
+
+    /// How many mutations behind the percentiles of `column` are, if they were ever computed.
+    pub fn percentiles_behind(&self, column: &str) -> Option<u64> {
+        let statistics = self.columns.get(column)?;
+        Some(self.generation - statistics.percentiles_generation)
+    }
+
+    pub fn column(&self, column: &str) -> Option<&ColumnStatistics> {
+        self.columns.get(column)
+    }
+
+    /// The columns computed so far, stale or not.
+    pub fn cached(&self) -> impl Iterator<Item = (&String, &ColumnStatistics)> {
+        self.columns.iter()
+    }
+
+    /// Records that `delta` was appended, updating the columns that were fresh from it.
+    pub fn appended(&mut self, delta: &DataFrame) -> Result<(), Status> {
+        let previous = self.generation;
+        self.generation += 1;
+        for (column, statistics) in self.columns.iter_mut() {
+            if statistics.generation != previous {
+                continue;
+            }
+            if let Ok(series) = delta.column(column) {
+                statistics.update(series, self.generation)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a mutation the statistics cannot be updated from.
+    pub fn replaced(&mut self) {
+        self.generation += 1;
+    }
+
+    /// The statistics of `columns` of `df`, the dataframe they describe. Columns never computed
+    /// are computed, and so are stale ones unless `tolerance` accepts them.
+    pub fn get(
+        &mut self,
+        df: &DataFrame,
+        columns: &[String],
+        tolerance: Tolerance,
+    ) -> Result<Vec<(String, ColumnStatistics)>, Status> {
+        let mut statistics = Vec::with_capacity(columns.len());
+        for column in columns {
+            let recompute = match self.columns.get(column) {
+                None => true,
+                Some(_) => match tolerance {
+                    Tolerance::Stale => false,
+                    Tolerance::Fresh => self.is_stale(column),
+                    Tolerance::Exact => self.percentiles_behind(column) != Some(0),
+                },
+            };
+            if recompute {
+                let series = df.column(column).map_err(|_| {
+                    Status::invalid_argument(format!("No column {column} in the dataframe"))
+                })?;
+                let computed = ColumnStatistics::compute(series, self.generation)?;
+                self.columns.insert(column.clone(), computed);
+                self.recomputed += 1;
+            }
+            statistics.push((column.clone(), self.columns[column].clone()));
+        }
+        Ok(statistics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(columns: &[&str]) -> Vec<String> {
+        columns.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn appends_update_fresh_columns_incrementally() {
+        let mut df = df! {
+            "x" => [Some(1i64), None, Some(3)],
+            "s" => ["a", "b", "a"],
+        }
+        .unwrap();
+        let mut statistics = Statistics::default();
+        let x = statistics
+            .get(&df, &names(&["x"]), Tolerance::Fresh)
+            .unwrap();
+        assert_eq!(
+            (x[0].1.nulls, x[0].1.min, x[0].1.max),
+            (1, Some(1.0), Some(3.0))
+        );
+        assert_eq!(statistics.recomputed(), 1);
+
+        let delta = df! { "x" => [None, Some(-2i64)], "s" => ["c", "c"] }.unwrap();
+        df.vstack_mut(&delta).unwrap();
+        statistics.appended(&delta).unwrap();
+        assert!(!statistics.is_stale("x"));
+        let x = statistics
+            .get(&df, &names(&["x"]), Tolerance::Fresh)
+            .unwrap();
+        assert_eq!((x[0].1.nulls, x[0].1.min), (2, Some(-2.0)));
+        assert_eq!(x[0].1.distinct.estimate(), 3);
+        // Updated from the appended rows alone.
+        assert_eq!(statistics.recomputed(), 1);
+
+        let s = statistics
+            .get(&df, &names(&["s"]), Tolerance::Stale)
+            .unwrap();
+        assert_eq!((s[0].1.min, s[0].1.distinct.estimate()), (None, 3));
+    }
+
+    #[test]
+    fn stale_columns_are_only_recomputed_for_fresh_readers() {
+        let df = df! { "x" => [1i64, 2, 3] }.unwrap();
+        let mut statistics = Statistics::default();
+        statistics
+            .get(&df, &names(&["x"]), Tolerance::Stale)
+            .unwrap();
+        let upserted = df! { "x" => [1i64, 2, 30] }.unwrap();
+        statistics.replaced();
+        assert!(statistics.is_stale("x"));
+
+        let x = statistics
+            .get(&upserted, &names(&["x"]), Tolerance::Stale)
+            .unwrap();
+        assert_eq!(x[0].1.max, Some(3.0));
+        assert_eq!(statistics.recomputed(), 1);
+        let x = statistics
+            .get(&upserted, &names(&["x"]), Tolerance::Fresh)
+            .unwrap();
+        assert_eq!(x[0].1.max, Some(30.0));
+        assert_eq!(x[0].1.generation, statistics.generation());
+        assert_eq!(statistics.recomputed(), 2);
+
+        // Percentiles are only moved by recomputing the column in full.
+        assert_eq!(x[0].1.percentiles[100], 30.0);
+        statistics.appended(&df).unwrap();
+        assert!(!statistics.is_stale("x"));
+        assert_eq!(statistics.percentiles_behind("x"), Some(1));
+        statistics
+            .get(&df, &names(&["x"]), Tolerance::Fresh)
+            .unwrap();
+        assert_eq!(statistics.recomputed(), 2);
+        statistics
+            .get(&df, &names(&["x"]), Tolerance::Exact)
+            .unwrap();
+        assert_eq!(statistics.percentiles_behind("x"), Some(0));
+        assert_eq!(statistics.recomputed(), 3);
+
+        // Stale columns are not updated by appends: they are recomputed in full.
+        statistics.replaced();
+        statistics.appended(&df).unwrap();
+        assert!(statistics.is_stale("x"));
+    }
+
+    #[test]
+    fn sketches_estimate_and_merge_distinct_counts() {
+        let mut a = DistinctSketch::default();
+        let mut b = DistinctSketch::default();
+        for i in 0..20_000 {
+            a.insert(&i.to_string());
+            b.insert(&(i + 10_000).to_string());
+        }
+        a.merge(&b);
+        let estimate = a.estimate() as f64;
+        assert!((estimate - 30_000.0).abs() / 30_000.0 < 0.05, "{estimate}");
+    }
+}
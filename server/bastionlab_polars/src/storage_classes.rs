@@ -0,0 +1,491 @@
+//! Storage classes: which dataframes stay in memory and which are read from disk.
+//!
+//! - `hot` dataframes are always in memory: they are neither collected nor spilled under memory
+//!   pressure, and their total size is capped by the hot budget,
+//! - `warm` dataframes are in memory, and spilled to disk under hard memory pressure,
+//! - `cold` dataframes are on disk: they are loaded when read and evicted again at the next
+//!   memory sample once they were not read for the cold residency.
+//!
+//! Evicted dataframes keep their metadata in memory, with an empty dataframe of their schema, and
+//! are persisted first: only dataframes the policy lets the server persist can be evicted.
+//!
+//! Owners and data owners set the class of a dataframe, which pins it. Unpinned dataframes take
+//! the class of their size and of how often they are read, reevaluated at every memory sample.
+//! Class changes are applied in the background by jobs, which clients poll.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+/// Jobs kept for polling, the oldest are dropped first.
+const MAX_JOBS: usize = 1024;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageClass {
+    Hot,
+    #[default]
+    Warm,
+    Cold,
+}
+
+impl StorageClass {
+    pub const ALL: [StorageClass; 3] = [StorageClass::Hot, StorageClass::Warm, StorageClass::Cold];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageClass::Hot => "hot",
+            StorageClass::Warm => "warm",
+            StorageClass::Cold => "cold",
+        }
+    }
+
+    /// Parses a class set by a client, `None` for `auto`, which unpins the dataframe.
+    pub fn parse(name: &str) -> Result<Option<Self>, Status> {
+        match name {
+            "hot" => Ok(Some(StorageClass::Hot)),
+            "warm" => Ok(Some(StorageClass::Warm)),
+            "cold" => Ok(Some(StorageClass::Cold)),
+            "auto" => Ok(None),
+            _ => Err(Status::invalid_argument(format!(
+                "Unknown storage class {name}: expected hot, warm, cold or auto"
+            ))),
+        }
+    }
+}
+
+/// The storage class of a dataframe, persisted with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageState {
+    pub class: StorageClass,
+    /// Set by owners and data owners: the class is not changed automatically.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Whether the rows are in memory, always the case when the dataframe is loaded.
+    #[serde(skip, default = "resident")]
+    pub resident: bool,
+    /// Estimated size of the rows when they were evicted, in bytes.
+    #[serde(skip)]
+    pub evicted_bytes: u64,
+}
+
+fn resident() -> bool {
+    true
+}
+
+impl Default for StorageState {
+    fn default() -> Self {
+        StorageState {
+            class: StorageClass::default(),
+            pinned: false,
+            resident: true,
+            evicted_bytes: 0,
+        }
+    }
+}
+
+/// How unpinned dataframes are classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassPolicy {
+    /// Accesses per window from which dataframes are hot, 0 disables promotion.
+    pub hot_accesses: u32,
+    /// Size, in bytes, from which dataframes accessed at most `cold_accesses` times per window
+    /// are cold, 0 disables demotion.
+    pub cold_min_bytes: u64,
+    pub cold_accesses: u32,
+    pub window: Duration,
+    /// Total size of the hot dataframes, in bytes, 0 for no limit.
+    pub hot_budget: u64,
+    /// How long cold dataframes stay in memory after their last access.
+    pub cold_residency: Duration,
+}
+
+impl ClassPolicy {
+    /// The class of an unpinned dataframe of `size` bytes, accessed `accesses` times in the
+    /// current window.
+    pub fn classify(&self, size: u64, accesses: u32) -> StorageClass {
+        if self.hot_accesses > 0 && accesses >= self.hot_accesses {
+            StorageClass::Hot
+        } else if self.cold_min_bytes > 0
+            && size >= self.cold_min_bytes
+            && accesses <= self.cold_accesses
+        {
+            StorageClass::Cold
+        } else {
+            StorageClass::Warm
+        }
+    }
+
+    /// Fails if making `size` more bytes hot would exceed the budget, given `hot` bytes already.
+    pub fn check_budget(&self, hot: u64, size: u64) -> Result<(), Status> {
+        if self.hot_budget > 0 && hot.saturating_add(size) > self.hot_budget {
+            return Err(Status::resource_exhausted(format!(
+                "The hot budget of {} MB would be exceeded: {} MB are hot already",
+                self.hot_budget >> 20,
+                hot >> 20
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Accesses {
+    /// Milliseconds since the Unix epoch.
+    window_start: u64,
+    count: u32,
+    last: u64,
+}
+
+/// Counts the accesses to each dataframe, kept in memory only.
+#[derive(Default)]
+pub struct AccessCounter {
+    accesses: Mutex<HashMap<String, Accesses>>,
+}
+
+impl AccessCounter {
+    pub fn record(&self, identifier: &str, window: Duration, now: u64) {
+        let mut accesses = self.accesses.lock().unwrap();
+        let entry = accesses.entry(identifier.to_string()).or_default();
+        if now >= entry.window_start.saturating_add(window.as_millis() as u64) {
+            entry.window_start = now;
+            entry.count = 0;
+        }
+        entry.count = entry.count.saturating_add(1);
+        entry.last = now;
+    }
+
+    /// Accesses to `identifier` in the current window.
+    pub fn count(&self, identifier: &str, window: Duration, now: u64) -> u32 {
+        match self.accesses.lock().unwrap().get(identifier) {
+            Some(entry) if now < entry.window_start.saturating_add(window.as_millis() as u64) => {
+                entry.count
+            }
+            _ => 0,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch of the last access to `identifier`, if any.
+    pub fn last(&self, identifier: &str) -> Option<u64> {
+        self.accesses
+            .lock()
+            .unwrap()
+            .get(identifier)
+            .map(|e| e.last)
+    }
+
+    pub fn forget(&self, identifier: &str) {
+        self.accesses.lock().unwrap().remove(identifier);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Done,
+    Failed(String),
+}
+
+impl JobStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Done => "done",
+            JobStatus::Failed(_) => "failed",
+        }
+    }
+}
+
+/// A change of the class of a dataframe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassJob {
+    pub identifier: String,
+    /// `None` unpins the dataframe.
+    pub class: Option<StorageClass>,
+    pub requester: String,
+    pub status: JobStatus,
+}
+
+#[derive(Default)]
+pub struct ClassJobs {
+    next: AtomicU64,
+    jobs: RwLock<BTreeMap<u64, ClassJob>>,
+}
+
+impl ClassJobs {
+    pub fn create(&self, identifier: &str, class: Option<StorageClass>, requester: &str) -> u64 {
+        let id = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut jobs = self.jobs.write().unwrap();
+        jobs.insert(
+            id,
+            ClassJob {
+                identifier: identifier.to_string(),
+                class,
+                requester: requester.to_string(),
+                status: JobStatus::Pending,
+            },
+        );
+        while jobs.len() > MAX_JOBS {
+            jobs.pop_first();
+        }
+        id
+    }
+
+    pub fn finish(&self, id: u64, result: Result<(), Status>) {
+        if let Some(job) = self.jobs.write().unwrap().get_mut(&id) {
+            job.status = match result {
+                Ok(()) => JobStatus::Done,
+                Err(e) => JobStatus::Failed(e.message().to_string()),
+            };
+        }
+    }
+
+    /// Job `id`, which only its requester sees.
+    pub fn get(&self, id: u64, user_id: &str) -> Result<ClassJob, Status> {
+        match self.jobs.read().unwrap().get(&id) {
+            Some(job) if job.requester == user_id => Ok(job.clone()),
+            _ => Err(Status::not_found(format!(
+                "Could not find storage class job: {id}"
+            ))),
+        }
+    }
+}
+
+/// Memory and disk usage of the dataframes of a class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassUsage {
+    pub dataframes: u64,
+    /// Estimated size of the dataframes in memory, in bytes.
+    pub memory_bytes: u64,
+    /// Size of the persisted dataframes, in bytes.
+    pub disk_bytes: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::memory::{MemoryReader, Pressure};
+    use crate::{BastionLabPolars, DataFrameArtifact};
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn policy() -> ClassPolicy {
+        ClassPolicy {
+            hot_accesses: 10,
+            cold_min_bytes: 1 << 20,
+            cold_accesses: 1,
+            window: WINDOW,
+            hot_budget: 4 << 20,
+            cold_residency: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn classes_follow_size_and_accesses() {
+        let policy = policy();
+        assert_eq!(policy.classify(10 << 20, 0), StorageClass::Cold);
+        assert_eq!(policy.classify(10 << 20, 1), StorageClass::Cold);
+        assert_eq!(policy.classify(10 << 20, 2), StorageClass::Warm);
+        assert_eq!(policy.classify(10 << 20, 10), StorageClass::Hot);
+        // Small dataframes are not worth evicting.
+        assert_eq!(policy.classify(1 << 10, 0), StorageClass::Warm);
+
+        let disabled = ClassPolicy {
+            hot_accesses: 0,
+            cold_min_bytes: 0,
+            ..policy
+        };
+        assert_eq!(disabled.classify(10 << 20, 0), StorageClass::Warm);
+        assert_eq!(disabled.classify(10 << 20, 100), StorageClass::Warm);
+
+        policy.check_budget(3 << 20, 1 << 20).unwrap();
+        assert_eq!(
+            policy.check_budget(3 << 20, 2 << 20).unwrap_err().code(),
+            tonic::Code::ResourceExhausted
+        );
+    }
+
+    #[test]
+    fn accesses_are_counted_per_window() {
+        let counter = AccessCounter::default();
+        for now in [0, 10, 20] {
+            counter.record("a", WINDOW, now);
+        }
+        assert_eq!(counter.count("a", WINDOW, 30), 3);
+        assert_eq!(counter.count("a", WINDOW, 60_000), 0);
+        counter.record("a", WINDOW, 60_000);
+        assert_eq!(counter.count("a", WINDOW, 60_001), 1);
+        assert_eq!(counter.last("a"), Some(60_000));
+        counter.forget("a");
+        assert_eq!(counter.last("a"), None);
+    }
+
+    #[test]
+    fn jobs_are_seen_by_their_requester() {
+        let jobs = ClassJobs::default();
+        let id = jobs.create("a", Some(StorageClass::Hot), "alice");
+        assert_eq!(jobs.get(id, "alice").unwrap().status, JobStatus::Pending);
+        assert!(jobs.get(id, "bob").is_err());
+        jobs.finish(id, Err(Status::resource_exhausted("over budget")));
+        assert_eq!(
+            jobs.get(id, "alice").unwrap().status,
+            JobStatus::Failed(String::from("over budget"))
+        );
+        assert_eq!(StorageClass::parse("auto").unwrap(), None);
+        assert!(StorageClass::parse("frozen").is_err());
+    }
+
+    const MB: u64 = 1 << 20;
+
+    struct MockMemory(Arc<AtomicU64>);
+
+    impl MemoryReader for MockMemory {
+        fn resident(&self) -> Option<u64> {
+            Some(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    fn polars(settings: &str, resident: &Arc<AtomicU64>) -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(&format!(
+            r#"{{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600,
+                "memory_idle_result_secs": 0,
+                "storage_cold_residency_secs": 0,
+                {settings}
+            }}"#
+        ))
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("bastionlab-classes-{}", uuid::Uuid::new_v4()));
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+            .with_data_dir(dir)
+            .with_memory_reader(Box::new(MockMemory(resident.clone())))
+    }
+
+    /// About 1.6 MB.
+    fn large() -> DataFrame {
+        df! { "amount" => (0..200_000i64).collect::<Vec<_>>() }.unwrap()
+    }
+
+    fn class(polars: &BastionLabPolars, identifier: &str) -> (StorageClass, bool) {
+        let dfs = polars.dataframes.read().unwrap();
+        let storage = &dfs[identifier].storage;
+        (storage.class, storage.resident)
+    }
+
+    #[test]
+    fn hot_pins_survive_memory_pressure() {
+        let resident = Arc::new(AtomicU64::new(0));
+        let polars = polars(
+            r#""memory_soft_watermark_mb": 100, "memory_hard_watermark_mb": 200,
+               "memory_recovery_mb": 20, "storage_hot_budget_mb": 1"#,
+            &resident,
+        );
+        let result = |df: DataFrame| {
+            let mut result = DataFrameArtifact::new(df, Policy::allow_by_default(), vec![]);
+            result.query_details = String::from("a query");
+            polars.insert_df(result)
+        };
+        let small = df! { "amount" => [1i64, 2, 3] }.unwrap();
+        let (hot, warm) = (result(small.clone()), result(small.clone()));
+        let upload = polars.insert_df(DataFrameArtifact::new(
+            small,
+            Policy::allow_by_default(),
+            vec![],
+        ));
+        polars
+            .apply_storage_class(&hot, Some(StorageClass::Hot))
+            .unwrap();
+        let over_budget = result(large());
+        let err = polars
+            .apply_storage_class(&over_budget, Some(StorageClass::Hot))
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        std::thread::sleep(Duration::from_millis(2));
+
+        // Soft pressure collects idle results, but not hot ones.
+        resident.store(150 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Soft);
+        assert!(polars.get_header(&warm).is_err());
+        assert_eq!(class(&polars, &hot), (StorageClass::Hot, true));
+
+        // Hard pressure spills warm dataframes, but not hot ones.
+        resident.store(250 * MB, Ordering::Relaxed);
+        assert_eq!(polars.sample_memory().pressure, Pressure::Hard);
+        assert_eq!(class(&polars, &hot), (StorageClass::Hot, true));
+        assert_eq!(class(&polars, &upload), (StorageClass::Warm, false));
+        // Spilled dataframes are loaded when read.
+        assert_eq!(polars.get_df_unchecked(&upload).unwrap().height(), 3);
+        assert_eq!(class(&polars, &upload), (StorageClass::Warm, true));
+        let usage = polars.storage_usage();
+        assert_eq!(usage[0].dataframes, 1);
+        assert!(usage[0].memory_bytes > 0 && usage[1].disk_bytes > 0);
+
+        std::fs::remove_dir_all(polars.data_dir()).unwrap();
+    }
+
+    #[test]
+    fn classes_follow_access_thresholds() {
+        let resident = Arc::new(AtomicU64::new(0));
+        let polars = polars(
+            r#""storage_hot_accesses": 3, "storage_cold_min_mb": 1, "storage_cold_accesses": 0"#,
+            &resident,
+        );
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            large(),
+            Policy::allow_by_default(),
+            vec![],
+        ));
+        let small = polars.insert_df(DataFrameArtifact::new(
+            df! { "amount" => [1i64] }.unwrap(),
+            Policy::allow_by_default(),
+            vec![],
+        ));
+
+        // Large dataframes that are not read are demoted and evicted.
+        polars.sample_memory();
+        assert_eq!(class(&polars, &identifier), (StorageClass::Cold, false));
+        assert_eq!(class(&polars, &small), (StorageClass::Warm, true));
+        let usage = polars.storage_usage();
+        assert_eq!(usage[2].memory_bytes, 0);
+        assert!(usage[2].disk_bytes > 0);
+
+        // Reads load them back, and enough reads promote them further.
+        for _ in 0..2 {
+            assert_eq!(
+                polars.get_df_unchecked(&identifier).unwrap().height(),
+                200_000
+            );
+        }
+        polars.sample_memory();
+        assert_eq!(class(&polars, &identifier), (StorageClass::Warm, true));
+        polars.get_df_unchecked(&identifier).unwrap();
+        polars.sample_memory();
+        assert_eq!(class(&polars, &identifier), (StorageClass::Hot, true));
+
+        // Pinned classes are left alone.
+        polars
+            .apply_storage_class(&small, Some(StorageClass::Cold))
+            .unwrap();
+        polars.sample_memory();
+        assert_eq!(class(&polars, &small), (StorageClass::Cold, false));
+        polars.apply_storage_class(&small, None).unwrap();
+        polars.sample_memory();
+        assert_eq!(class(&polars, &small).0, StorageClass::Warm);
+
+        std::fs::remove_dir_all(polars.data_dir()).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+/// Utf8 columns whose distinct/total ratio is at most this value are stored as Categorical.
+const CATEGORICAL_RATIO_THRESHOLD: f64 = 0.5;
+
+/// A dtype change applied to a stored column, used to restore the declared schema.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DtypeChange {
+    pub column: String,
+    #[serde(with = "serde_dtype")]
+    pub declared: DataType,
+    #[serde(with = "serde_dtype")]
+    pub stored: DataType,
+}
+
+/// Polars cannot serialize the Categorical dtype, so it is written as the `"Categorical"` string.
+mod serde_dtype {
+    use polars::prelude::DataType;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Plain(DataType),
+        Name(String),
+    }
+
+    pub fn serialize<S: Serializer>(dtype: &DataType, s: S) -> Result<S::Ok, S::Error> {
+        match dtype {
+            DataType::Categorical(_) => "Categorical".serialize(s),
+            _ => dtype.serialize(s),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DataType, D::Error> {
+        match Repr::deserialize(d)? {
+            Repr::Plain(dtype) => Ok(dtype),
+            Repr::Name(name) if name == "Categorical" => Ok(DataType::Categorical(None)),
+            Repr::Name(name) => Err(serde::de::Error::custom(format!("unknown dtype {name}"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StorageReport {
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub changes: Vec<DtypeChange>,
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error while optimizing storage: {e}"))
+}
+
+fn smallest_signed(min: i64, max: i64) -> DataType {
+    if min >= i8::MIN as i64 && max <= i8::MAX as i64 {
+        DataType::Int8
+    } else if min >= i16::MIN as i64 && max <= i16::MAX as i64 {
+        DataType::Int16
+    } else if min >= i32::MIN as i64 && max <= i32::MAX as i64 {
+        DataType::Int32
+    } else {
+        DataType::Int64
+    }
+}
+
+fn smallest_unsigned(max: u64) -> DataType {
+    if max <= u8::MAX as u64 {
+        DataType::UInt8
+    } else if max <= u16::MAX as u64 {
+        DataType::UInt16
+    } else if max <= u32::MAX as u64 {
+        DataType::UInt32
+    } else {
+        DataType::UInt64
+    }
+}
+
+fn is_exact_f32(series: &Series) -> Result<bool, Status> {
+    Ok(series
+        .f64()
+        .map_err(polars_err)?
+        .into_iter()
+        .all(|v| match v {
+            Some(x) => x.is_nan() || (x as f32) as f64 == x,
+            None => true,
+        }))
+}
+
+/// Picks the dtype a column can be stored as without changing its values.
+fn target_dtype(series: &Series, allow_lossy_floats: bool) -> Result<Option<DataType>, Status> {
+    // Columns without any values keep their dtype: there is nothing to shrink.
+    if series.len() == series.null_count() {
+        return Ok(None);
+    }
+
+    let target = match series.dtype() {
+        DataType::Int16 | DataType::Int32 | DataType::Int64 => {
+            let values = series.cast(&DataType::Int64).map_err(polars_err)?;
+            let values = values.i64().map_err(polars_err)?;
+            match (values.min(), values.max()) {
+                (Some(min), Some(max)) => smallest_signed(min, max),
+                _ => return Ok(None),
+            }
+        }
+        DataType::UInt16 | DataType::UInt32 | DataType::UInt64 => {
+            let values = series.cast(&DataType::UInt64).map_err(polars_err)?;
+            match values.u64().map_err(polars_err)?.max() {
+                Some(max) => smallest_unsigned(max),
+                None => return Ok(None),
+            }
+        }
+        DataType::Float64 if allow_lossy_floats || is_exact_f32(series)? => DataType::Float32,
+        DataType::Utf8 => {
+            let n_unique = series.n_unique().map_err(polars_err)?;
+            if n_unique as f64 / series.len() as f64 <= CATEGORICAL_RATIO_THRESHOLD {
+                DataType::Categorical(None)
+            } else {
+                return Ok(None);
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(if &target != series.dtype() {
+        Some(target)
+    } else {
+        None
+    })
+}
+
+/// Shrinks the dtypes of the columns of `df` where this can be done without changing any value.
+///
+/// Integers are only narrowed to a type that holds their whole range, Float64 columns are
+/// narrowed to Float32 only if every value is exactly representable (or `allow_lossy_floats`
+/// is set) and low-cardinality Utf8 columns become Categorical.
+pub fn optimize_storage(
+    df: &mut DataFrame,
+    allow_lossy_floats: bool,
+) -> Result<StorageReport, Status> {
+    let bytes_before = df.estimated_size();
+    let mut changes = Vec::new();
+
+    for series in df.get_columns_mut().iter_mut() {
+        if let Some(target) = target_dtype(series, allow_lossy_floats)? {
+            let stored = series.strict_cast(&target).map_err(polars_err)?;
+            changes.push(DtypeChange {
+                column: series.name().to_string(),
+                declared: series.dtype().clone(),
+                stored: target,
+            });
+            *series = stored;
+        }
+    }
+
+    Ok(StorageReport {
+        bytes_before,
+        bytes_after: df.estimated_size(),
+        changes,
+    })
+}
+
+/// Casts every optimized column of `df` back to its declared dtype.
+pub fn restore_dtypes(df: &mut DataFrame, changes: &[DtypeChange]) -> Result<(), Status> {
+    for change in changes {
+        let idx = match df.find_idx_by_name(&change.column) {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let series = df.get_columns_mut().get_mut(idx).unwrap();
+        if series.dtype() == &change.stored {
+            *series = series.cast(&change.declared).map_err(polars_err)?;
+        }
+    }
+    Ok(())
+}
+
+/// Casts columns of a dataframe in its declared dtypes back to their stored dtypes.
+///
+/// Returns the changes that still apply: columns whose values no longer fit are kept as declared.
+pub fn reapply_dtypes(
+    df: &mut DataFrame,
+    changes: &[DtypeChange],
+) -> Result<Vec<DtypeChange>, Status> {
+    let mut applied = Vec::with_capacity(changes.len());
+    for change in changes {
+        let idx = match df.find_idx_by_name(&change.column) {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let series = df.get_columns_mut().get_mut(idx).unwrap();
+        if let Ok(stored) = series.strict_cast(&change.stored) {
+            *series = stored;
+            applied.push(change.clone());
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(df: &DataFrame, allow_lossy_floats: bool) -> (DataFrame, StorageReport) {
+        let mut optimized = df.clone();
+        let report = optimize_storage(&mut optimized, allow_lossy_floats).unwrap();
+        let mut restored = optimized;
+        restore_dtypes(&mut restored, &report.changes).unwrap();
+        (restored, report)
+    }
+
+    #[test]
+    fn integers_keep_their_values() {
+        let df = df! {
+            "small" => [Some(1i64), None, Some(-128)],
+            "edge" => [Some(i32::MAX as i64), Some(i32::MIN as i64), None],
+            "wide" => [Some(i64::MAX), Some(0), None],
+        }
+        .unwrap();
+
+        let (restored, report) = round_trip(&df, false);
+        assert!(restored.frame_equal_missing(&df));
+
+        let stored: Vec<_> = report.changes.iter().map(|c| c.stored.clone()).collect();
+        assert_eq!(stored, vec![DataType::Int8, DataType::Int32]);
+        assert!(report.bytes_after < report.bytes_before);
+    }
+
+    #[test]
+    fn floats_need_an_opt_in_unless_exact() {
+        let df = df! {
+            "exact" => [Some(-0.0f64), Some(1.5), None, Some(f64::NAN)],
+            "inexact" => [Some(0.1f64), Some(2.0), None, Some(3.0)],
+        }
+        .unwrap();
+
+        let (restored, report) = round_trip(&df, false);
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.changes[0].column, "exact");
+
+        let exact = restored.column("exact").unwrap().f64().unwrap();
+        assert!(exact.get(0).unwrap().is_sign_negative());
+        assert_eq!(exact.get(1), Some(1.5));
+        assert_eq!(exact.get(2), None);
+        assert!(exact.get(3).unwrap().is_nan());
+        assert!(restored
+            .column("inexact")
+            .unwrap()
+            .series_equal_missing(df.column("inexact").unwrap()));
+
+        let (_, report) = round_trip(&df, true);
+        assert_eq!(report.changes.len(), 2);
+    }
+
+    #[test]
+    fn low_cardinality_strings_become_categorical() {
+        let df = df! {
+            "site" => [Some("a"), Some("b"), Some("a"), Some("a"), None, Some("b")],
+            "id" => ["1", "2", "3", "4", "5", "6"],
+        }
+        .unwrap();
+
+        let (restored, report) = round_trip(&df, false);
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.changes[0].stored, DataType::Categorical(None));
+        assert!(restored.frame_equal_missing(&df));
+    }
+}
@@ -0,0 +1,389 @@
+//! Seeded synthetic dataframes, for analysts to develop against before their access is approved.
+//!
+//! A synthetic dataframe has the schema of its source and follows its per-column marginals, each
+//! column being generated independently of the others:
+//! - the rate of nulls is kept,
+//! - numeric and temporal values follow a histogram of the source with noisy counts,
+//! - categories follow their frequencies, except those seen fewer than `min_category_count`
+//!   times, which are never generated,
+//! - identifier columns, high-cardinality strings and categories all below the floor get
+//!   format-preserving fakes: letters and digits are replaced, and no fake equals a source value.
+//!
+//! Joint distributions are not preserved. Generation only depends on the source and the seed.
+
+use std::collections::{BTreeMap, HashSet};
+
+use polars::prelude::*;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+/// Bins of the histograms of numeric columns.
+const HISTOGRAM_BINS: usize = 10;
+/// Scale of the Laplace noise added to histogram and category counts.
+const COUNT_NOISE: f64 = 1.0;
+/// String columns with more distinct values than this share of their values are identifiers.
+const HIGH_CARDINALITY_RATIO: f64 = 0.5;
+/// Fakes equal to a source value are redrawn this many times before being lengthened.
+const FAKE_ATTEMPTS: usize = 16;
+
+fn default_min_category_count() -> usize {
+    5
+}
+
+/// Enables synthetic data generation in the policy of a dataset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntheticPolicy {
+    /// Columns that only get format-preserving fakes, in addition to the sanitized ones.
+    #[serde(default)]
+    pub identifier_columns: Vec<String>,
+    /// Categories seen fewer times are never generated.
+    #[serde(default = "default_min_category_count")]
+    pub min_category_count: usize,
+    /// Cap on the rows of the generated dataframes.
+    #[serde(default)]
+    pub max_rows: Option<usize>,
+}
+
+impl SyntheticPolicy {
+    /// Combines the settings of two datasets into the most restrictive ones.
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut identifier_columns = self.identifier_columns.clone();
+        identifier_columns.extend(
+            other
+                .identifier_columns
+                .iter()
+                .filter(|c| !self.identifier_columns.contains(c))
+                .cloned(),
+        );
+        SyntheticPolicy {
+            identifier_columns,
+            min_category_count: self.min_category_count.max(other.min_category_count),
+            max_rows: match (self.max_rows, other.max_rows) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+/// Where a synthetic dataframe comes from, recorded in its header and lineage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntheticOrigin {
+    pub source: String,
+    pub rows: usize,
+    pub seed: u64,
+}
+
+impl SyntheticOrigin {
+    pub fn lineage(&self) -> String {
+        format!(
+            "Synthetic data generated from {} with {} rows and seed {}: per-column marginals only, joint distributions are not preserved",
+            self.source, self.rows, self.seed
+        )
+    }
+}
+
+/// Generates `rows` rows following the marginals of `df`.
+///
+/// `identifiers` are the columns to fake whatever their cardinality.
+pub fn generate(
+    df: &DataFrame,
+    policy: &SyntheticPolicy,
+    identifiers: &[String],
+    rows: usize,
+    seed: u64,
+) -> Result<DataFrame, Status> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let columns = df
+        .get_columns()
+        .iter()
+        .map(|series| {
+            let identifier = identifiers.iter().any(|c| c == series.name());
+            generate_column(series, policy, identifier, rows, &mut rng)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    DataFrame::new(columns)
+        .map_err(|e| Status::internal(format!("Could not assemble the synthetic dataframe: {e}")))
+}
+
+fn polars_err(name: &str) -> impl Fn(PolarsError) -> Status + '_ {
+    move |e| Status::internal(format!("Could not synthesize column {name}: {e}"))
+}
+
+fn generate_column(
+    series: &Series,
+    policy: &SyntheticPolicy,
+    identifier: bool,
+    rows: usize,
+    rng: &mut StdRng,
+) -> Result<Series, Status> {
+    let name = series.name();
+    let err = polars_err(name);
+    let null_rate = if series.is_empty() {
+        1.0
+    } else {
+        series.null_count() as f64 / series.len() as f64
+    };
+    let nulls: Vec<bool> = (0..rows)
+        .map(|_| rng.gen_bool(null_rate.clamp(0.0, 1.0)))
+        .collect();
+
+    let dtype = series.dtype();
+    if identifier {
+        let strings = series.cast(&DataType::Utf8).map_err(&err)?;
+        let fakes = fakes(strings.utf8().map_err(&err)?, &nulls, rng);
+        return fakes.cast(dtype).map_err(&err);
+    }
+    match dtype {
+        DataType::Boolean => {
+            let values = series.bool().map_err(&err)?;
+            let trues = values.into_iter().filter(|v| *v == Some(true)).count() as f64;
+            let weights = [
+                noisy(
+                    values.len() as f64 - values.null_count() as f64 - trues,
+                    rng,
+                ),
+                noisy(trues, rng),
+            ];
+            let generated: BooleanChunked = match WeightedIndex::new(weights) {
+                Ok(index) => nulls
+                    .iter()
+                    .map(|null| (!null).then(|| index.sample(rng) == 1))
+                    .collect(),
+                Err(_) => nulls.iter().map(|_| None).collect(),
+            };
+            Ok(named(generated.into_series(), name))
+        }
+        DataType::Utf8 | DataType::Categorical(_) => {
+            let strings = series.cast(&DataType::Utf8).map_err(&err)?;
+            let strings = strings.utf8().map_err(&err)?;
+            let mut counts = BTreeMap::new();
+            for value in strings.into_iter().flatten() {
+                *counts.entry(value).or_insert(0usize) += 1;
+            }
+            let non_null = strings.len() - strings.null_count();
+            let frequent: Vec<_> = counts
+                .iter()
+                .filter(|(_, count)| **count >= policy.min_category_count)
+                .collect();
+            if frequent.is_empty() || counts.len() as f64 > HIGH_CARDINALITY_RATIO * non_null as f64
+            {
+                return fakes(strings, &nulls, rng).cast(dtype).map_err(&err);
+            }
+            let weights: Vec<_> = frequent
+                .iter()
+                .map(|(_, count)| noisy(**count as f64, rng))
+                .collect();
+            let generated: Utf8Chunked = match WeightedIndex::new(&weights) {
+                Ok(index) => nulls
+                    .iter()
+                    .map(|null| (!null).then(|| *frequent[index.sample(rng)].0))
+                    .collect(),
+                Err(_) => nulls.iter().map(|_| None::<&str>).collect(),
+            };
+            named(generated.into_series(), name)
+                .cast(dtype)
+                .map_err(&err)
+        }
+        dtype if dtype.to_physical().is_numeric() => {
+            let physical = series.to_physical_repr();
+            let values = physical.cast(&DataType::Float64).map_err(&err)?;
+            let values: Vec<f64> = values
+                .f64()
+                .map_err(&err)?
+                .into_iter()
+                .flatten()
+                .filter(|v| v.is_finite())
+                .collect();
+            let generated: Float64Chunked = match Histogram::new(&values, rng) {
+                Some(histogram) => nulls
+                    .iter()
+                    .map(|null| (!null).then(|| histogram.sample(rng)))
+                    .collect(),
+                None => nulls.iter().map(|_| None).collect(),
+            };
+            let mut generated = named(generated.into_series(), name);
+            if !matches!(physical.dtype(), DataType::Float32 | DataType::Float64) {
+                generated = generated.round(0).map_err(&err)?;
+            }
+            generated
+                .cast(physical.dtype())
+                .and_then(|s| s.cast(dtype))
+                .map_err(&err)
+        }
+        // Nested and binary columns are not synthesized.
+        dtype => Ok(Series::full_null(name, rows, dtype)),
+    }
+}
+
+/// `count` with Laplace noise, never negative.
+fn noisy(count: f64, rng: &mut StdRng) -> f64 {
+    let u: f64 = rng.gen_range(-0.5..0.5);
+    let noise = -COUNT_NOISE * u.signum() * (1.0 - 2.0 * u.abs()).ln();
+    (count + noise).max(0.0)
+}
+
+struct Histogram {
+    min: f64,
+    width: f64,
+    index: WeightedIndex<f64>,
+}
+
+impl Histogram {
+    fn new(values: &[f64], rng: &mut StdRng) -> Option<Self> {
+        let min = values.iter().copied().reduce(f64::min)?;
+        let max = values.iter().copied().reduce(f64::max)?;
+        let width = (max - min) / HISTOGRAM_BINS as f64;
+        let mut counts = [0.0; HISTOGRAM_BINS];
+        for v in values {
+            let bin = if width > 0.0 {
+                (((v - min) / width) as usize).min(HISTOGRAM_BINS - 1)
+            } else {
+                0
+            };
+            counts[bin] += 1.0;
+        }
+        let weights: Vec<_> = counts.iter().map(|count| noisy(*count, rng)).collect();
+        // All the noisy counts can round down to zero on tiny columns.
+        let index = WeightedIndex::new(&weights)
+            .or_else(|_| WeightedIndex::new(counts))
+            .ok()?;
+        Some(Histogram { min, width, index })
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        let bin = self.index.sample(rng) as f64;
+        self.min + self.width * (bin + rng.gen::<f64>())
+    }
+}
+
+/// Format-preserving fakes of `values`, none of which is a value of `values`.
+fn fakes(values: &Utf8Chunked, nulls: &[bool], rng: &mut StdRng) -> Series {
+    let real: HashSet<&str> = values.into_iter().flatten().collect();
+    let mut formats: Vec<&str> = real.iter().copied().collect();
+    formats.sort_unstable();
+    let generated: Utf8Chunked = nulls
+        .iter()
+        .map(|null| {
+            if *null || formats.is_empty() {
+                return None;
+            }
+            let format = formats[rng.gen_range(0..formats.len())];
+            let mut fake = String::new();
+            for _ in 0..FAKE_ATTEMPTS {
+                fake = format.chars().map(|c| fake_char(c, rng)).collect();
+                if !real.contains(fake.as_str()) {
+                    return Some(fake);
+                }
+            }
+            while real.contains(fake.as_str()) {
+                fake.push(rng.gen_range('a'..='z'));
+            }
+            Some(fake)
+        })
+        .collect();
+    named(generated.into_series(), values.name())
+}
+
+fn named(mut series: Series, name: &str) -> Series {
+    series.rename(name);
+    series
+}
+
+fn fake_char(c: char, rng: &mut StdRng) -> char {
+    match c {
+        '0'..='9' => rng.gen_range('0'..='9'),
+        'A'..='Z' => rng.gen_range('A'..='Z'),
+        c if c.is_alphabetic() => rng.gen_range('a'..='z'),
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> SyntheticPolicy {
+        SyntheticPolicy {
+            identifier_columns: Vec::new(),
+            min_category_count: 5,
+            max_rows: None,
+        }
+    }
+
+    fn source() -> DataFrame {
+        let n = 1000;
+        df! {
+            "age" => (0..n).map(|i| (i % 10 != 0).then(|| 20 + (i % 50) as i64)).collect::<Vec<_>>(),
+            "score" => (0..n).map(|i| i as f64 / n as f64).collect::<Vec<_>>(),
+            "city" => (0..n).map(|i| if i % 4 == 0 { "Paris" } else { "Lyon" }).collect::<Vec<_>>(),
+            "rare" => (0..n).map(|i| if i == 0 { "Unique" } else { "Common" }).collect::<Vec<_>>(),
+            "email" => (0..n).map(|i| format!("user{i}@example.com")).collect::<Vec<_>>(),
+            "code" => (0..n).map(|i| format!("A{}", i % 20)).collect::<Vec<_>>(),
+            "flag" => (0..n).map(|i| i % 3 == 0).collect::<Vec<_>>(),
+        }
+        .unwrap()
+    }
+
+    #[test]
+    fn generation_is_deterministic_under_a_seed() {
+        let df = source();
+        let first = generate(&df, &policy(), &[], 500, 42).unwrap();
+        assert_eq!(first.schema(), df.schema());
+        assert_eq!(first.height(), 500);
+        assert!(first.frame_equal_missing(&generate(&df, &policy(), &[], 500, 42).unwrap()));
+        assert!(!first.frame_equal_missing(&generate(&df, &policy(), &[], 500, 43).unwrap()));
+    }
+
+    #[test]
+    fn marginals_are_followed() {
+        let df = source();
+        let synthetic = generate(&df, &policy(), &[], 2000, 7).unwrap();
+
+        let age = synthetic.column("age").unwrap();
+        let null_rate = age.null_count() as f64 / 2000.0;
+        assert!((null_rate - 0.1).abs() < 0.05, "{null_rate}");
+        let ages = age.i64().unwrap();
+        assert!(ages.min().unwrap() >= 20 && ages.max().unwrap() <= 69);
+
+        let paris = synthetic
+            .column("city")
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .filter(|v| *v == Some("Paris"))
+            .count() as f64;
+        assert!((paris / 2000.0 - 0.25).abs() < 0.05, "{paris}");
+
+        // Categories under the floor are never generated.
+        let rare = synthetic.column("rare").unwrap().utf8().unwrap();
+        assert!(rare.into_iter().all(|v| v == Some("Common")));
+    }
+
+    #[test]
+    fn no_real_value_of_an_identifier_column_appears() {
+        let df = source();
+        let identifiers = [String::from("code")];
+        let synthetic = generate(&df, &policy(), &identifiers, 5000, 1).unwrap();
+
+        for column in ["code", "email"] {
+            let real: HashSet<_> = df
+                .column(column)
+                .unwrap()
+                .utf8()
+                .unwrap()
+                .into_iter()
+                .collect();
+            let fakes = synthetic.column(column).unwrap().utf8().unwrap();
+            assert!(fakes.into_iter().all(|v| !real.contains(&v)), "{column}");
+        }
+        // Fakes keep the format of the source values.
+        let email = synthetic.column("email").unwrap().utf8().unwrap();
+        let email = email.get(0).unwrap();
+        assert!(email.starts_with(|c: char| c.is_ascii_lowercase()));
+        assert!(email.contains('@') && email.contains('.'), "{email}");
+    }
+}
@@ -0,0 +1,1240 @@
+//! Temporal arithmetic polars plans cannot express: ISO-8601 durations and calendar offsets,
+//! datetime arithmetic, truncation to period boundaries and business-day differences.
+//!
+//! A [`TemporalPlanSegment`](crate::composite_plan::CompositePlanSegment::TemporalPlanSegment)
+//! adds columns computed by [`TemporalExpr`]s to the dataframe on top of the stack. Expressions
+//! are typed: datetimes and dates can be shifted by durations, subtracted from one another into
+//! durations, truncated and counted in business days, and any other combination is rejected
+//! before a row is computed.
+//!
+//! Timezones are explicit:
+//! - operations on timezone-aware datetimes keep their timezone, and mixing naive and aware
+//!   datetimes is an error,
+//! - days, weeks, months and years shift the wall clock of aware datetimes, while hours, minutes
+//!   and seconds are exact: `P1D` after 10:00 the day before a DST change is 10:00, `PT24H` is
+//!   11:00 or 9:00,
+//! - wall-clock times that a DST change skips are moved forward by the length of the gap, and
+//!   those it repeats resolve to their first occurrence.
+//!
+//! Months and years clamp to the end of shorter months: `P1M` after January 31st is the last day
+//! of February. Results beyond the range of their dtype fail with the column and row.
+
+use std::collections::HashMap;
+
+use chrono::{
+    DateTime, Datelike, LocalResult, Months, NaiveDate, NaiveDateTime, Offset, TimeDelta, TimeZone,
+};
+use chrono_tz::Tz;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// A column added by a temporal plan segment, or replaced if `name` exists.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemporalColumn {
+    pub name: String,
+    pub expr: TemporalExpr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum TemporalExpr {
+    Column {
+        name: String,
+    },
+    /// An ISO-8601 duration such as `P1Y2M10DT2H30M` or `-PT15M`.
+    Duration {
+        iso: String,
+    },
+    Add {
+        left: Box<TemporalExpr>,
+        right: Box<TemporalExpr>,
+    },
+    Sub {
+        left: Box<TemporalExpr>,
+        right: Box<TemporalExpr>,
+    },
+    /// Truncates datetimes or dates to the start of their period, an ISO-8601 duration of a
+    /// single unit: months and years start on the first day of the month, weeks on Mondays.
+    Truncate {
+        expr: Box<TemporalExpr>,
+        every: String,
+    },
+    /// Business days from `start` to `end`, excluding `end`, negative if `end` is before `start`.
+    BusinessDays {
+        start: Box<TemporalExpr>,
+        end: Box<TemporalExpr>,
+        /// Which days of the week, from Monday, are not business days.
+        #[serde(default = "default_weekend")]
+        weekend: [bool; 7],
+        #[serde(default)]
+        holidays: Option<Holidays>,
+    },
+}
+
+fn default_weekend() -> [bool; 7] {
+    [false, false, false, false, false, true, true]
+}
+
+/// A dataframe listing holidays in a date or datetime column.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Holidays {
+    pub identifier: String,
+    pub column: String,
+}
+
+impl TemporalExpr {
+    /// Identifiers of the holiday dataframes the expression reads.
+    fn holiday_frames(&self, frames: &mut Vec<String>) {
+        match self {
+            TemporalExpr::Column { .. } | TemporalExpr::Duration { .. } => (),
+            TemporalExpr::Add { left, right } | TemporalExpr::Sub { left, right } => {
+                left.holiday_frames(frames);
+                right.holiday_frames(frames);
+            }
+            TemporalExpr::Truncate { expr, .. } => expr.holiday_frames(frames),
+            TemporalExpr::BusinessDays {
+                start,
+                end,
+                holidays,
+                ..
+            } => {
+                start.holiday_frames(frames);
+                end.holiday_frames(frames);
+                if let Some(holidays) = holidays {
+                    if !frames.contains(&holidays.identifier) {
+                        frames.push(holidays.identifier.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl TemporalExpr {
+    fn holidays_mut(
+        &mut self,
+        f: &mut impl FnMut(&mut Holidays) -> Result<(), Status>,
+    ) -> Result<(), Status> {
+        match self {
+            TemporalExpr::Column { .. } | TemporalExpr::Duration { .. } => Ok(()),
+            TemporalExpr::Add { left, right } | TemporalExpr::Sub { left, right } => {
+                left.holidays_mut(f)?;
+                right.holidays_mut(f)
+            }
+            TemporalExpr::Truncate { expr, .. } => expr.holidays_mut(f),
+            TemporalExpr::BusinessDays {
+                start,
+                end,
+                holidays,
+                ..
+            } => {
+                start.holidays_mut(f)?;
+                end.holidays_mut(f)?;
+                holidays.as_mut().map_or(Ok(()), |holidays| f(holidays))
+            }
+        }
+    }
+}
+
+/// Replaces the identifier of every holiday dataframe with the one `resolve` returns for it.
+pub fn resolve_holidays(
+    columns: &mut [TemporalColumn],
+    mut resolve: impl FnMut(&str) -> Result<String, Status>,
+) -> Result<(), Status> {
+    for column in columns {
+        column.expr.holidays_mut(&mut |holidays| {
+            holidays.identifier = resolve(&holidays.identifier)?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Identifiers of the holiday dataframes `columns` read.
+pub fn holiday_frames(columns: &[TemporalColumn]) -> Vec<String> {
+    let mut frames = Vec::new();
+    for column in columns {
+        column.expr.holiday_frames(&mut frames);
+    }
+    frames
+}
+
+/// A calendar period: months and days shift the wall clock, nanoseconds are exact.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Period {
+    pub months: i64,
+    pub days: i64,
+    pub nanos: i64,
+}
+
+impl Period {
+    pub fn parse(iso: &str) -> Result<Self, Status> {
+        let err = || {
+            Status::invalid_argument(format!(
+                "Invalid ISO-8601 duration {iso:?}, expected something like P1Y2M10DT2H30M or -PT15M"
+            ))
+        };
+        let (negative, rest) = match iso.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, iso),
+        };
+        let rest = rest.strip_prefix('P').ok_or_else(err)?;
+        let (date, time) = match rest.split_once('T') {
+            Some((date, time)) if !time.is_empty() => (date, Some(time)),
+            Some(_) => return Err(err()),
+            None => (rest, None),
+        };
+        let mut period = Period::default();
+        let mut empty = true;
+        for (number, unit) in components(date).ok_or_else(err)? {
+            let n = parse_int(number).ok_or_else(err)?;
+            let (months, days) = match unit {
+                'Y' => (n.checked_mul(12), Some(0)),
+                'M' => (Some(n), Some(0)),
+                'W' => (Some(0), n.checked_mul(7)),
+                'D' => (Some(0), Some(n)),
+                _ => return Err(err()),
+            };
+            period.months = months
+                .and_then(|months| period.months.checked_add(months))
+                .ok_or_else(err)?;
+            period.days = days
+                .and_then(|days| period.days.checked_add(days))
+                .ok_or_else(err)?;
+            empty = false;
+        }
+        for (number, unit) in components(time.unwrap_or("")).ok_or_else(err)? {
+            let nanos = match unit {
+                'H' => parse_int(number).and_then(|n| n.checked_mul(3600 * NANOS_PER_SECOND)),
+                'M' => parse_int(number).and_then(|n| n.checked_mul(60 * NANOS_PER_SECOND)),
+                'S' => parse_seconds(number),
+                _ => return Err(err()),
+            };
+            period.nanos = nanos
+                .and_then(|nanos| period.nanos.checked_add(nanos))
+                .ok_or_else(err)?;
+            empty = false;
+        }
+        if empty {
+            return Err(err());
+        }
+        Ok(if negative { period.negate() } else { period })
+    }
+
+    fn negate(self) -> Self {
+        Period {
+            months: -self.months,
+            days: -self.days,
+            nanos: -self.nanos,
+        }
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Some(Period {
+            months: self.months.checked_add(other.months)?,
+            days: self.days.checked_add(other.days)?,
+            nanos: self.nanos.checked_add(other.nanos)?,
+        })
+    }
+
+    /// As an exact number of nanoseconds, if it has no months: days are then 24 hours long.
+    fn fixed_nanos(&self) -> Option<i64> {
+        if self.months != 0 {
+            return None;
+        }
+        self.days
+            .checked_mul(86_400 * NANOS_PER_SECOND)?
+            .checked_add(self.nanos)
+    }
+}
+
+/// Splits `P1Y2M` into `[("1", 'Y'), ("2", 'M')]`.
+fn components(s: &str) -> Option<Vec<(&str, char)>> {
+    let mut components = Vec::new();
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c.is_ascii_alphabetic() {
+            if i == start {
+                return None;
+            }
+            components.push((&s[start..i], c));
+            start = i + 1;
+        }
+    }
+    (start == s.len()).then_some(components)
+}
+
+/// A number without sign.
+fn parse_int(number: &str) -> Option<i64> {
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Seconds with an optional fraction of up to 9 digits, in nanoseconds.
+fn parse_seconds(number: &str) -> Option<i64> {
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction: i64 = format!("{fraction:0<9}").parse().ok()?;
+    parse_int(whole)?
+        .checked_mul(NANOS_PER_SECOND)?
+        .checked_add(fraction)
+}
+
+fn per_second(unit: TimeUnit) -> i64 {
+    match unit {
+        TimeUnit::Nanoseconds => NANOS_PER_SECOND,
+        TimeUnit::Microseconds => 1_000_000,
+        TimeUnit::Milliseconds => 1_000,
+    }
+}
+
+/// Converts `value` from `from` to `to`, rounding down.
+fn convert(value: i64, from: TimeUnit, to: TimeUnit) -> Option<i64> {
+    let (from, to) = (per_second(from), per_second(to));
+    if to >= from {
+        value.checked_mul(to / from)
+    } else {
+        Some(value.div_euclid(from / to))
+    }
+}
+
+fn to_naive(value: i64, unit: TimeUnit) -> Option<NaiveDateTime> {
+    let per_second = per_second(unit);
+    let nanos = value.rem_euclid(per_second) * (NANOS_PER_SECOND / per_second);
+    DateTime::from_timestamp(value.div_euclid(per_second), nanos as u32).map(|dt| dt.naive_utc())
+}
+
+fn from_naive(dt: NaiveDateTime, unit: TimeUnit) -> Option<i64> {
+    let dt = dt.and_utc();
+    let per_second = per_second(unit);
+    dt.timestamp()
+        .checked_mul(per_second)?
+        .checked_add(dt.timestamp_subsec_nanos() as i64 / (NANOS_PER_SECOND / per_second))
+}
+
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+}
+
+pub(crate) fn date_from_days(days: i64) -> Option<NaiveDate> {
+    epoch().checked_add_signed(TimeDelta::try_days(days)?)
+}
+
+pub(crate) fn days_from_date(date: NaiveDate) -> i64 {
+    (date - epoch()).num_days()
+}
+
+/// The wall clock of UTC datetime `utc`.
+fn to_local(utc: NaiveDateTime, zone: Option<Tz>) -> NaiveDateTime {
+    match zone {
+        Some(tz) => tz.from_utc_datetime(&utc).naive_local(),
+        None => utc,
+    }
+}
+
+/// The UTC datetime of wall clock `local`, see the module documentation for DST changes.
+fn from_local(local: NaiveDateTime, zone: Option<Tz>) -> Option<NaiveDateTime> {
+    let Some(tz) = zone else {
+        return Some(local);
+    };
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(dt) => Some(dt.naive_utc()),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.naive_utc()),
+        LocalResult::None => {
+            // With the offset in force before the gap, the time lands as far after it.
+            let before = local.checked_sub_signed(TimeDelta::try_days(1)?)?;
+            let offset = tz.offset_from_utc_datetime(&before).fix().local_minus_utc();
+            local.checked_sub_signed(TimeDelta::try_seconds(offset as i64)?)
+        }
+    }
+}
+
+fn shift_calendar(dt: NaiveDateTime, months: i64, days: i64) -> Option<NaiveDateTime> {
+    let shift = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+    let dt = if months >= 0 {
+        dt.checked_add_months(shift)?
+    } else {
+        dt.checked_sub_months(shift)?
+    };
+    dt.checked_add_signed(TimeDelta::try_days(days)?)
+}
+
+fn add_period(value: i64, unit: TimeUnit, zone: Option<Tz>, period: Period) -> Option<i64> {
+    let mut utc = to_naive(value, unit)?;
+    if period.months != 0 || period.days != 0 {
+        let local = shift_calendar(to_local(utc, zone), period.months, period.days)?;
+        utc = from_local(local, zone)?;
+    }
+    from_naive(
+        utc.checked_add_signed(TimeDelta::nanoseconds(period.nanos))?,
+        unit,
+    )
+}
+
+/// The start of the period of `dt`, for periods checked by [`check_truncation`].
+fn truncate_naive(dt: NaiveDateTime, every: Period) -> Option<NaiveDateTime> {
+    match (every.months, every.days, every.nanos) {
+        (months, 0, 0) => {
+            let index = dt.year() as i64 * 12 + dt.month0() as i64;
+            let start = index.div_euclid(months) * months;
+            NaiveDate::from_ymd_opt(
+                i32::try_from(start.div_euclid(12)).ok()?,
+                start.rem_euclid(12) as u32 + 1,
+                1,
+            )?
+            .and_hms_opt(0, 0, 0)
+        }
+        (0, days, 0) => {
+            // Weeks start on Mondays, and 1969-12-29 was one.
+            let anchor = if days % 7 == 0 { -3 } else { 0 };
+            let index = days_from_date(dt.date());
+            let start = (index - anchor).div_euclid(days) * days + anchor;
+            date_from_days(start)?.and_hms_opt(0, 0, 0)
+        }
+        (0, 0, nanos) => {
+            let utc = dt.and_utc();
+            let total = utc.timestamp() as i128 * NANOS_PER_SECOND as i128
+                + utc.timestamp_subsec_nanos() as i128;
+            let start = total.div_euclid(nanos as i128) * nanos as i128;
+            let secs = i64::try_from(start.div_euclid(NANOS_PER_SECOND as i128)).ok()?;
+            let nanos = start.rem_euclid(NANOS_PER_SECOND as i128) as u32;
+            DateTime::from_timestamp(secs, nanos).map(|dt| dt.naive_utc())
+        }
+        _ => None,
+    }
+}
+
+fn check_truncation(every: &str, column: &str) -> Result<Period, Status> {
+    let period = Period::parse(every)?;
+    let units = [period.months, period.days, period.nanos];
+    if units.iter().any(|unit| *unit < 0) || units.iter().filter(|unit| **unit > 0).count() != 1 {
+        return Err(Status::invalid_argument(format!(
+            "Column {column}: cannot truncate to {every}, use a positive period of a single unit such as P1M, P1W, P1D or PT1H"
+        )));
+    }
+    Ok(period)
+}
+
+/// Business days in `[start, end)`, or minus those in `[end, start)`. `holidays` are sorted
+/// business days.
+fn business_days(
+    start: NaiveDate,
+    end: NaiveDate,
+    weekend: &[bool; 7],
+    holidays: &[NaiveDate],
+) -> i64 {
+    if end < start {
+        return -business_days(end, start, weekend, holidays);
+    }
+    let days = (end - start).num_days();
+    let per_week = weekend.iter().filter(|weekend| !**weekend).count() as i64;
+    let first = start.weekday().num_days_from_monday() as i64;
+    let rest = (0..days % 7)
+        .filter(|i| !weekend[((first + i) % 7) as usize])
+        .count() as i64;
+    let off =
+        holidays.partition_point(|day| *day < end) - holidays.partition_point(|day| *day < start);
+    days / 7 * per_week + rest - off as i64
+}
+
+/// An evaluated expression.
+enum Value {
+    Datetime {
+        values: Vec<Option<i64>>,
+        unit: TimeUnit,
+        tz: Option<String>,
+    },
+    Date(Vec<Option<i32>>),
+    Duration {
+        values: Vec<Option<i64>>,
+        unit: TimeUnit,
+    },
+    /// A duration literal.
+    Period(Period),
+    Int(Vec<Option<i64>>),
+}
+
+impl Value {
+    fn dtype(&self) -> String {
+        match self {
+            Value::Datetime { unit, tz, .. } => DataType::Datetime(*unit, tz.clone()).to_string(),
+            Value::Date(_) => DataType::Date.to_string(),
+            Value::Duration { unit, .. } => DataType::Duration(*unit).to_string(),
+            Value::Period(_) => String::from("duration literal"),
+            Value::Int(_) => DataType::Int64.to_string(),
+        }
+    }
+
+    fn into_series(self, name: &str) -> Result<Series, Status> {
+        let mut series = match self {
+            Value::Datetime { values, unit, tz } => Int64Chunked::from_iter(values)
+                .into_datetime(unit, tz)
+                .into_series(),
+            Value::Date(values) => Int32Chunked::from_iter(values).into_date().into_series(),
+            Value::Duration { values, unit } => Int64Chunked::from_iter(values)
+                .into_duration(unit)
+                .into_series(),
+            Value::Int(values) => Int64Chunked::from_iter(values).into_series(),
+            Value::Period(_) => {
+                return Err(Status::invalid_argument(format!(
+                "Column {name}: a duration literal is not a column, add it to a datetime or date"
+            )))
+            }
+        };
+        series.rename(name);
+        Ok(series)
+    }
+}
+
+fn zone(tz: &Option<String>) -> Result<Option<Tz>, Status> {
+    tz.as_ref()
+        .map(|tz| {
+            tz.parse::<Tz>()
+                .map_err(|_| Status::invalid_argument(format!("Unknown timezone {tz}")))
+        })
+        .transpose()
+}
+
+struct Context<'a> {
+    df: &'a DataFrame,
+    /// The column being computed, for errors.
+    column: &'a str,
+    holidays: &'a HashMap<String, DataFrame>,
+}
+
+impl Context<'_> {
+    fn overflow(&self, row: usize, dtype: &str) -> Status {
+        Status::out_of_range(format!(
+            "Column {}: row {row} is out of the range of {dtype}",
+            self.column
+        ))
+    }
+
+    fn mismatch(&self, op: &str, left: &Value, right: &Value) -> Status {
+        Status::invalid_argument(format!(
+            "Column {}: cannot {op} {} and {}",
+            self.column,
+            left.dtype(),
+            right.dtype()
+        ))
+    }
+
+    fn mixed_timezones(&self) -> Status {
+        Status::invalid_argument(format!(
+            "Column {}: cannot mix timezone-naive and timezone-aware datetimes, give both a timezone first",
+            self.column
+        ))
+    }
+
+    /// Applies `f` to every row of `values`, failing on the first row it overflows.
+    fn map<T: Copy, U>(
+        &self,
+        values: &[Option<T>],
+        dtype: &str,
+        mut f: impl FnMut(T) -> Option<U>,
+    ) -> Result<Vec<Option<U>>, Status> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(row, value)| match value {
+                Some(value) => f(*value).map(Some).ok_or_else(|| self.overflow(row, dtype)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Applies `f` to every pair of rows, null if either is.
+    fn zip<T: Copy, U: Copy, V>(
+        &self,
+        left: &[Option<T>],
+        right: &[Option<U>],
+        dtype: &str,
+        mut f: impl FnMut(T, U) -> Option<V>,
+    ) -> Result<Vec<Option<V>>, Status> {
+        left.iter()
+            .zip(right)
+            .enumerate()
+            .map(|(row, pair)| match pair {
+                (Some(l), Some(r)) => f(*l, *r).map(Some).ok_or_else(|| self.overflow(row, dtype)),
+                _ => Ok(None),
+            })
+            .collect()
+    }
+
+    fn column(&self, name: &str) -> Result<Value, Status> {
+        let series = self
+            .df
+            .column(name)
+            .map_err(|e| Status::invalid_argument(format!("Column {}: {e}", self.column)))?;
+        let physical = series.to_physical_repr();
+        let polars_err = |e: PolarsError| Status::internal(format!("Polars error: {e}"));
+        Ok(match series.dtype() {
+            DataType::Datetime(unit, tz) => Value::Datetime {
+                values: physical.i64().map_err(polars_err)?.into_iter().collect(),
+                unit: *unit,
+                tz: tz.clone(),
+            },
+            DataType::Date => {
+                Value::Date(physical.i32().map_err(polars_err)?.into_iter().collect())
+            }
+            DataType::Duration(unit) => Value::Duration {
+                values: physical.i64().map_err(polars_err)?.into_iter().collect(),
+                unit: *unit,
+            },
+            dtype => {
+                return Err(Status::invalid_argument(format!(
+                    "Column {}: {name} is of dtype {dtype}, not a datetime, date or duration",
+                    self.column
+                )))
+            }
+        })
+    }
+
+    fn eval(&self, expr: &TemporalExpr) -> Result<Value, Status> {
+        match expr {
+            TemporalExpr::Column { name } => self.column(name),
+            TemporalExpr::Duration { iso } => Ok(Value::Period(Period::parse(iso)?)),
+            TemporalExpr::Add { left, right } => {
+                let (left, right) = (self.eval(left)?, self.eval(right)?);
+                self.add(left, right, false)
+            }
+            TemporalExpr::Sub { left, right } => {
+                let (left, right) = (self.eval(left)?, self.eval(right)?);
+                self.add(left, right, true)
+            }
+            TemporalExpr::Truncate { expr, every } => {
+                let every = check_truncation(every, self.column)?;
+                self.truncate(self.eval(expr)?, every)
+            }
+            TemporalExpr::BusinessDays {
+                start,
+                end,
+                weekend,
+                holidays,
+            } => {
+                let (start, end) = (self.eval(start)?, self.eval(end)?);
+                self.business_days(start, end, weekend, holidays.as_ref())
+            }
+        }
+    }
+
+    /// `left + right`, or `left - right` if `subtract`.
+    fn add(&self, left: Value, right: Value, subtract: bool) -> Result<Value, Status> {
+        let op = if subtract { "subtract" } else { "add" };
+        let sign = |period: Period| if subtract { period.negate() } else { period };
+        Ok(match (left, right) {
+            (Value::Period(l), Value::Period(r)) => Value::Period(
+                l.checked_add(sign(r))
+                    .ok_or_else(|| self.overflow(0, "duration literal"))?,
+            ),
+            (Value::Period(period), datetime @ (Value::Datetime { .. } | Value::Date(_)))
+                if !subtract =>
+            {
+                self.add(datetime, Value::Period(period), false)?
+            }
+            (Value::Datetime { values, unit, tz }, Value::Period(period)) => {
+                let (period, zone, dtype) = (
+                    sign(period),
+                    zone(&tz)?,
+                    DataType::Datetime(unit, tz.clone()).to_string(),
+                );
+                Value::Datetime {
+                    values: self.map(&values, &dtype, |value| {
+                        add_period(value, unit, zone, period)
+                    })?,
+                    unit,
+                    tz,
+                }
+            }
+            (Value::Date(values), Value::Period(period)) => {
+                if period.nanos != 0 {
+                    return Err(Status::invalid_argument(format!(
+                        "Column {}: dates can only be shifted by days, weeks, months and years",
+                        self.column
+                    )));
+                }
+                let period = sign(period);
+                Value::Date(self.map(&values, "date", |days| {
+                    let date = date_from_days(days as i64)?.and_hms_opt(0, 0, 0)?;
+                    let date = shift_calendar(date, period.months, period.days)?;
+                    i32::try_from(days_from_date(date.date())).ok()
+                })?)
+            }
+            (
+                Value::Datetime { values, unit, tz },
+                Value::Duration {
+                    values: durations,
+                    unit: duration_unit,
+                },
+            ) => {
+                let dtype = DataType::Datetime(unit, tz.clone()).to_string();
+                Value::Datetime {
+                    values: self.zip(&values, &durations, &dtype, |value, duration| {
+                        let duration = convert(duration, duration_unit, unit)?;
+                        if subtract {
+                            value.checked_sub(duration)
+                        } else {
+                            value.checked_add(duration)
+                        }
+                    })?,
+                    unit,
+                    tz,
+                }
+            }
+            (
+                Value::Datetime { values, unit, tz },
+                Value::Datetime {
+                    values: others,
+                    unit: other_unit,
+                    tz: other_tz,
+                },
+            ) if subtract => {
+                if tz.is_some() != other_tz.is_some() {
+                    return Err(self.mixed_timezones());
+                }
+                let dtype = DataType::Duration(unit).to_string();
+                Value::Duration {
+                    values: self.zip(&values, &others, &dtype, |value, other| {
+                        value.checked_sub(convert(other, other_unit, unit)?)
+                    })?,
+                    unit,
+                }
+            }
+            (Value::Date(values), Value::Date(others)) if subtract => {
+                let dtype = DataType::Duration(TimeUnit::Milliseconds).to_string();
+                Value::Duration {
+                    values: self.zip(&values, &others, &dtype, |value, other| {
+                        (value as i64 - other as i64).checked_mul(MILLIS_PER_DAY)
+                    })?,
+                    unit: TimeUnit::Milliseconds,
+                }
+            }
+            (
+                Value::Duration { values, unit },
+                Value::Duration {
+                    values: others,
+                    unit: other_unit,
+                },
+            ) => {
+                let dtype = DataType::Duration(unit).to_string();
+                Value::Duration {
+                    values: self.zip(&values, &others, &dtype, |value, other| {
+                        let other = convert(other, other_unit, unit)?;
+                        if subtract {
+                            value.checked_sub(other)
+                        } else {
+                            value.checked_add(other)
+                        }
+                    })?,
+                    unit,
+                }
+            }
+            (Value::Duration { values, unit }, Value::Period(period)) => {
+                let nanos = sign(period).fixed_nanos().ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "Column {}: months and years have no fixed length, they cannot be added to durations",
+                        self.column
+                    ))
+                })?;
+                let dtype = DataType::Duration(unit).to_string();
+                let shift = convert(nanos, TimeUnit::Nanoseconds, unit)
+                    .ok_or_else(|| self.overflow(0, &dtype))?;
+                Value::Duration {
+                    values: self.map(&values, &dtype, |value| value.checked_add(shift))?,
+                    unit,
+                }
+            }
+            (left, right) => return Err(self.mismatch(op, &left, &right)),
+        })
+    }
+
+    fn truncate(&self, value: Value, every: Period) -> Result<Value, Status> {
+        Ok(match value {
+            Value::Datetime { values, unit, tz } => {
+                let (zone, dtype) = (zone(&tz)?, DataType::Datetime(unit, tz.clone()).to_string());
+                Value::Datetime {
+                    values: self.map(&values, &dtype, |value| {
+                        let local = to_local(to_naive(value, unit)?, zone);
+                        from_naive(from_local(truncate_naive(local, every)?, zone)?, unit)
+                    })?,
+                    unit,
+                    tz,
+                }
+            }
+            Value::Date(values) => {
+                if every.nanos != 0 {
+                    return Err(Status::invalid_argument(format!(
+                        "Column {}: dates can only be truncated to days, weeks, months and years",
+                        self.column
+                    )));
+                }
+                Value::Date(self.map(&values, "date", |days| {
+                    let date = date_from_days(days as i64)?.and_hms_opt(0, 0, 0)?;
+                    i32::try_from(days_from_date(truncate_naive(date, every)?.date())).ok()
+                })?)
+            }
+            value => {
+                return Err(Status::invalid_argument(format!(
+                    "Column {}: cannot truncate {}, only datetimes and dates",
+                    self.column,
+                    value.dtype()
+                )))
+            }
+        })
+    }
+
+    /// The local dates of `value`, with whether they come from aware datetimes.
+    fn local_dates(&self, value: &Value) -> Result<(Vec<Option<NaiveDate>>, Option<bool>), Status> {
+        match value {
+            Value::Datetime { values, unit, tz } => {
+                let zone = zone(tz)?;
+                let dtype = DataType::Datetime(*unit, tz.clone()).to_string();
+                let dates = self.map(values, &dtype, |value| {
+                    Some(to_local(to_naive(value, *unit)?, zone).date())
+                })?;
+                Ok((dates, Some(tz.is_some())))
+            }
+            Value::Date(values) => Ok((
+                self.map(values, "date", |days| date_from_days(days as i64))?,
+                None,
+            )),
+            value => Err(Status::invalid_argument(format!(
+                "Column {}: cannot count business days of {}, only datetimes and dates",
+                self.column,
+                value.dtype()
+            ))),
+        }
+    }
+
+    fn business_days(
+        &self,
+        start: Value,
+        end: Value,
+        weekend: &[bool; 7],
+        holidays: Option<&Holidays>,
+    ) -> Result<Value, Status> {
+        if weekend.iter().all(|weekend| *weekend) {
+            return Err(Status::invalid_argument(format!(
+                "Column {}: the weekend mask leaves no business day",
+                self.column
+            )));
+        }
+        let (starts, start_aware) = self.local_dates(&start)?;
+        let (ends, end_aware) = self.local_dates(&end)?;
+        if let (Some(start_aware), Some(end_aware)) = (start_aware, end_aware) {
+            if start_aware != end_aware {
+                return Err(self.mixed_timezones());
+            }
+        }
+        let holidays = match holidays {
+            Some(holidays) => self.holidays(holidays, weekend)?,
+            None => Vec::new(),
+        };
+        Ok(Value::Int(self.zip(
+            &starts,
+            &ends,
+            "i64",
+            |start, end| Some(business_days(start, end, weekend, &holidays)),
+        )?))
+    }
+
+    /// The sorted business days listed in `holidays`.
+    fn holidays(&self, holidays: &Holidays, weekend: &[bool; 7]) -> Result<Vec<NaiveDate>, Status> {
+        let df = self.holidays.get(&holidays.identifier).ok_or_else(|| {
+            Status::internal(format!(
+                "Holiday dataframe {} was not loaded",
+                holidays.identifier
+            ))
+        })?;
+        let context = Context {
+            df,
+            column: self.column,
+            holidays: self.holidays,
+        };
+        let (dates, _) = context.local_dates(&context.column(&holidays.column)?)?;
+        let mut dates: Vec<_> = dates
+            .into_iter()
+            .flatten()
+            .filter(|date| !weekend[date.weekday().num_days_from_monday() as usize])
+            .collect();
+        dates.sort();
+        dates.dedup();
+        Ok(dates)
+    }
+}
+
+/// Adds `columns` to `df`, in order: later columns can use the earlier ones. `holidays` holds the
+/// dataframes listed by [`holiday_frames`].
+pub fn apply(
+    mut df: DataFrame,
+    columns: &[TemporalColumn],
+    holidays: &HashMap<String, DataFrame>,
+) -> Result<DataFrame, Status> {
+    for column in columns {
+        let value = Context {
+            df: &df,
+            column: &column.name,
+            holidays,
+        }
+        .eval(&column.expr)?;
+        df.with_column(value.into_series(&column.name)?)
+            .map_err(|e| Status::invalid_argument(format!("Column {}: {e}", column.name)))?;
+    }
+    Ok(df)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    const PARIS: &str = "Europe/Paris";
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// Milliseconds of wall-clock times `local` in `tz`.
+    fn datetimes(name: &str, local: &[&str], tz: Option<&str>) -> Series {
+        let zone = tz.map(|tz| tz.parse::<Tz>().unwrap());
+        let values = local.iter().map(|local| {
+            from_naive(
+                from_local(naive(local), zone).unwrap(),
+                TimeUnit::Milliseconds,
+            )
+        });
+        let mut series = Int64Chunked::from_iter(values)
+            .into_datetime(TimeUnit::Milliseconds, tz.map(String::from))
+            .into_series();
+        series.rename(name);
+        series
+    }
+
+    fn dates(name: &str, dates: &[&str]) -> Series {
+        let days = dates.iter().map(|date| {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+            Some(days_from_date(date) as i32)
+        });
+        let mut series = Int32Chunked::from_iter(days).into_date().into_series();
+        series.rename(name);
+        series
+    }
+
+    fn col(name: &str) -> Box<TemporalExpr> {
+        Box::new(TemporalExpr::Column {
+            name: name.to_string(),
+        })
+    }
+
+    fn duration(iso: &str) -> Box<TemporalExpr> {
+        Box::new(TemporalExpr::Duration {
+            iso: iso.to_string(),
+        })
+    }
+
+    fn add(left: Box<TemporalExpr>, right: Box<TemporalExpr>) -> TemporalExpr {
+        TemporalExpr::Add { left, right }
+    }
+
+    fn run(df: &DataFrame, expr: TemporalExpr) -> Result<Series, Status> {
+        run_with(df, expr, &HashMap::new())
+    }
+
+    fn run_with(
+        df: &DataFrame,
+        expr: TemporalExpr,
+        holidays: &HashMap<String, DataFrame>,
+    ) -> Result<Series, Status> {
+        let columns = [TemporalColumn {
+            name: String::from("out"),
+            expr,
+        }];
+        Ok(apply(df.clone(), &columns, holidays)?
+            .column("out")
+            .unwrap()
+            .clone())
+    }
+
+    /// Wall-clock times of a datetime series in its timezone.
+    fn local(series: &Series) -> Vec<String> {
+        let DataType::Datetime(unit, tz) = series.dtype() else {
+            panic!("{series:?}");
+        };
+        let zone = zone(tz).unwrap();
+        series
+            .to_physical_repr()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .map(|value| {
+                to_local(to_naive(value.unwrap(), *unit).unwrap(), zone)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn ints(series: &Series) -> Vec<i64> {
+        series
+            .to_physical_repr()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .map(Option::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn durations_parse_as_iso_8601() {
+        let hour = 3600 * NANOS_PER_SECOND;
+        let cases = [
+            ("P1Y2M10D", (14, 10, 0)),
+            ("P2W", (0, 14, 0)),
+            ("PT2H30M", (0, 0, 2 * hour + hour / 2)),
+            ("PT1.5S", (0, 0, 1_500_000_000)),
+            ("-P1DT1H", (0, -1, -hour)),
+        ];
+        for (iso, (months, days, nanos)) in cases {
+            assert_eq!(
+                Period::parse(iso).unwrap(),
+                Period {
+                    months,
+                    days,
+                    nanos
+                },
+                "{iso}"
+            );
+        }
+        for iso in [
+            "",
+            "P",
+            "PT",
+            "1D",
+            "P1H",
+            "PT1D",
+            "P1.5D",
+            "P-1D",
+            "PT1.0000000001S",
+        ] {
+            assert!(Period::parse(iso).is_err(), "{iso}");
+        }
+    }
+
+    #[test]
+    fn days_follow_the_wall_clock_across_dst() {
+        // Paris moved from +01:00 to +02:00 on 2024-03-31 at 02:00, and back on 2024-10-27.
+        let df = DataFrame::new(vec![datetimes(
+            "t",
+            &["2024-03-30 10:00", "2024-10-26 10:00", "2024-03-30 02:30"],
+            Some(PARIS),
+        )])
+        .unwrap();
+        let out = run(&df, add(col("t"), duration("P1D"))).unwrap();
+        assert_eq!(
+            out.dtype(),
+            &DataType::Datetime(TimeUnit::Milliseconds, Some(PARIS.into()))
+        );
+        // The skipped 02:30 moves forward by the hour of the gap.
+        assert_eq!(
+            local(&out),
+            ["2024-03-31 10:00", "2024-10-27 10:00", "2024-03-31 03:30"]
+        );
+        let out = run(&df, add(col("t"), duration("PT24H"))).unwrap();
+        assert_eq!(
+            local(&out),
+            ["2024-03-31 11:00", "2024-10-27 09:00", "2024-03-31 03:30"]
+        );
+
+        // Differences are exact: the spring day is 23 hours long, the autumn one 25.
+        let df = DataFrame::new(vec![
+            datetimes("a", &["2024-03-30 12:00", "2024-10-26 12:00"], Some(PARIS)),
+            datetimes("b", &["2024-03-31 12:00", "2024-10-27 12:00"], Some(PARIS)),
+        ])
+        .unwrap();
+        let out = run(
+            &df,
+            TemporalExpr::Sub {
+                left: col("b"),
+                right: col("a"),
+            },
+        )
+        .unwrap();
+        assert_eq!(out.dtype(), &DataType::Duration(TimeUnit::Milliseconds));
+        assert_eq!(ints(&out), [23 * 3_600_000, 25 * 3_600_000]);
+
+        // Days start at local midnight, whatever the offset.
+        let df = DataFrame::new(vec![datetimes(
+            "t",
+            &["2024-03-31 23:30", "2024-10-27 01:30"],
+            Some(PARIS),
+        )])
+        .unwrap();
+        let truncate = |every: &str| TemporalExpr::Truncate {
+            expr: col("t"),
+            every: every.to_string(),
+        };
+        let out = run(&df, truncate("P1D")).unwrap();
+        assert_eq!(local(&out), ["2024-03-31 00:00", "2024-10-27 00:00"]);
+        let out = run(&df, truncate("P1W")).unwrap();
+        assert_eq!(local(&out), ["2024-03-25 00:00", "2024-10-21 00:00"]);
+        let out = run(&df, truncate("P3M")).unwrap();
+        assert_eq!(local(&out), ["2024-01-01 00:00", "2024-10-01 00:00"]);
+        let out = run(&df, truncate("PT1H")).unwrap();
+        assert_eq!(local(&out), ["2024-03-31 23:00", "2024-10-27 01:00"]);
+        let err = run(&df, truncate("P1M1D")).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn months_and_years_clamp_on_leap_days() {
+        let df = DataFrame::new(vec![dates(
+            "d",
+            &["2024-01-31", "2024-02-29", "2023-02-28", "2024-02-28"],
+        )])
+        .unwrap();
+        let shifted = |iso: &str| {
+            let out = run(&df, add(col("d"), duration(iso))).unwrap();
+            assert_eq!(out.dtype(), &DataType::Date);
+            out.cast(&DataType::Utf8)
+                .unwrap()
+                .utf8()
+                .unwrap()
+                .into_iter()
+                .map(|date| date.unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            shifted("P1M"),
+            ["2024-02-29", "2024-03-29", "2023-03-28", "2024-03-28"]
+        );
+        assert_eq!(
+            shifted("P1Y"),
+            ["2025-01-31", "2025-02-28", "2024-02-28", "2025-02-28"]
+        );
+        assert_eq!(
+            shifted("P1D"),
+            ["2024-02-01", "2024-03-01", "2023-03-01", "2024-02-29"]
+        );
+
+        // Dates subtract into whole days, across the leap day.
+        let df = DataFrame::new(vec![
+            dates("a", &["2024-02-28", "2023-02-28"]),
+            dates("b", &["2024-03-01", "2023-03-01"]),
+        ])
+        .unwrap();
+        let out = run(
+            &df,
+            TemporalExpr::Sub {
+                left: col("b"),
+                right: col("a"),
+            },
+        )
+        .unwrap();
+        assert_eq!(ints(&out), [2 * MILLIS_PER_DAY, MILLIS_PER_DAY]);
+
+        let err = run(&df, add(col("a"), duration("PT1H"))).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn business_days_skip_weekends_and_holidays() {
+        // 2024-12-23 is a Monday.
+        let df = DataFrame::new(vec![
+            dates(
+                "ordered",
+                &["2024-12-23", "2024-12-27", "2025-01-06", "2024-12-23"],
+            ),
+            dates(
+                "delivered",
+                &["2024-12-30", "2025-01-03", "2025-01-06", "2024-12-20"],
+            ),
+        ])
+        .unwrap();
+        let count = |weekend: [bool; 7], holidays: Option<Holidays>| TemporalExpr::BusinessDays {
+            start: col("ordered"),
+            end: col("delivered"),
+            weekend,
+            holidays,
+        };
+        let out = run(&df, count(default_weekend(), None)).unwrap();
+        assert_eq!(ints(&out), [5, 5, 0, -1]);
+
+        // Christmas and New Year's Day, with a Saturday that is skipped anyway.
+        let calendar = DataFrame::new(vec![dates(
+            "day",
+            &["2024-12-25", "2025-01-01", "2024-12-28", "2024-12-25"],
+        )])
+        .unwrap();
+        let holidays = HashMap::from([(String::from("calendar"), calendar)]);
+        let listed = Holidays {
+            identifier: String::from("calendar"),
+            column: String::from("day"),
+        };
+        let out = run_with(
+            &df,
+            count(default_weekend(), Some(listed.clone())),
+            &holidays,
+        )
+        .unwrap();
+        assert_eq!(ints(&out), [4, 4, 0, -1]);
+
+        // A Friday and Saturday weekend.
+        let weekend = [false, false, false, false, true, true, false];
+        let out = run_with(&df, count(weekend, Some(listed)), &holidays).unwrap();
+        assert_eq!(ints(&out), [4, 4, 0, -1]);
+        let err = run(&df, count([true; 7], None)).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn timezones_are_explicit_and_overflows_fail() {
+        let df = DataFrame::new(vec![
+            datetimes("aware", &["2024-01-01 00:00"], Some(PARIS)),
+            datetimes("naive", &["2024-01-01 00:00"], None),
+            Series::new("n", [1i64]),
+        ])
+        .unwrap();
+        let err = run(
+            &df,
+            TemporalExpr::Sub {
+                left: col("aware"),
+                right: col("naive"),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert!(err.message().contains("timezone"), "{err:?}");
+        let err = run(&df, add(col("n"), duration("P1D"))).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let err = run(&df, *duration("P1D")).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        // Years beyond what chrono represents fail with the column and row.
+        let df = DataFrame::new(vec![datetimes(
+            "t",
+            &["2024-01-01 00:00", "2024-01-01 00:00"],
+            None,
+        )])
+        .unwrap();
+        let null = Series::new("t", [Some(0i64), None])
+            .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))
+            .unwrap();
+        let out = run(
+            &DataFrame::new(vec![null]).unwrap(),
+            add(col("t"), duration("P1D")),
+        )
+        .unwrap();
+        assert_eq!(out.null_count(), 1);
+        let err = run(&df, add(col("t"), duration("P300000Y"))).unwrap_err();
+        assert_eq!(err.code(), Code::OutOfRange);
+        assert!(err.message().contains("Column out: row 0"), "{err:?}");
+    }
+}
@@ -0,0 +1,597 @@
+//! Per-tenant encryption of persisted dataframes.
+//!
+//! The tenant of a dataframe is its owner in the catalog, or [`DEFAULT_TENANT`] if it has none.
+//! Each tenant has its own master key, so that the files of one tenant leak nothing about the
+//! others. The key is either generated by the server and stored wrapped by a key derived from the
+//! private key of the server, or provided by the KMS as a file of 32 hex-encoded bytes.
+//!
+//! Keys are unwrapped on the first read or write of their tenant. Wrapped keys can be rotated:
+//! new files are encrypted under the new version, and older versions are kept to read the files
+//! that were not re-encrypted yet. Revoking a tenant erases its wrapped key material, after which
+//! its persisted dataframes can neither be loaded nor written, with a `PermissionDenied` error
+//! naming the tenant, while other tenants are unaffected.
+//!
+//! Persisted dataframes are the only data the server keeps at rest: there are no audit segments
+//! or spill files, and the aliases, privacy budgets and watermarks tables hold no data values, so
+//! they stay in plaintext.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use bastionlab_common::atomic_file;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+
+use crate::catalog::now_ms;
+use crate::DataFrameArtifact;
+
+/// Directory of the tenant key files, under the data directory.
+pub const KEYS_DIR: &str = "tenant_keys";
+/// Tenant of the dataframes without an owner.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Magic bytes of encrypted artifacts, followed by the envelope version.
+const MAGIC: &[u8; 4] = b"BLEN";
+const ENVELOPE_VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+/// Domain separation of the wrapping key from other uses of the server key.
+const WRAPPING_LABEL: &[u8] = b"bastionlab tenant keys\0";
+
+pub fn tenant_of(artifact: &DataFrameArtifact) -> &str {
+    match artifact.catalog.owner.as_str() {
+        "" => DEFAULT_TENANT,
+        owner => owner,
+    }
+}
+
+/// Whether `e` was caused by a revoked tenant key.
+pub fn is_revoked(e: &Status) -> bool {
+    e.code() == Code::PermissionDenied
+}
+
+fn revoked(tenant: &str, revoked_at: u64) -> Status {
+    Status::permission_denied(format!(
+        "The key of tenant {tenant} was revoked at {revoked_at}: its dataframes can no longer be loaded or persisted"
+    ))
+}
+
+fn io_err(e: std::io::Error) -> Status {
+    Status::internal(format!("Could not access the tenant keys: {e}"))
+}
+
+fn undecryptable(reason: &str) -> Status {
+    Status::data_loss(format!("Could not decrypt persisted dataframe: {reason}"))
+}
+
+fn check_tenant(tenant: &str) -> Result<(), Status> {
+    if tenant.is_empty()
+        || !tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Status::invalid_argument(format!(
+            "Invalid tenant {tenant:?}: only letters, digits, - and _ are allowed"
+        )));
+    }
+    Ok(())
+}
+
+fn aead_key(bytes: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, bytes).expect("keys are 32 bytes long"))
+}
+
+/// Encrypts `data` under `key`, returning the nonce followed by the ciphertext.
+fn seal(key: &LessSafeKey, aad: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, Status> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Status::internal("Could not generate a nonce"))?;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut data,
+    )
+    .map_err(|_| Status::internal("Could not encrypt data"))?;
+    let mut res = nonce.to_vec();
+    res.append(&mut data);
+    Ok(res)
+}
+
+/// Reverses [`seal`], returning `None` if the data was not sealed under `key` and `aad`.
+fn open(key: &LessSafeKey, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    let nonce = Nonce::try_assume_unique_for_key(sealed.get(..NONCE_LEN)?).ok()?;
+    let mut data = sealed[NONCE_LEN..].to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), &mut data)
+        .ok()?
+        .len();
+    data.truncate(len);
+    Some(data)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyVersion {
+    version: u32,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Nonce followed by the key sealed under the wrapping key, hex-encoded.
+    wrapped: String,
+}
+
+/// What is stored of a tenant key, in `<tenant>.json` under [`KEYS_DIR`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct KeyFile {
+    #[serde(default)]
+    versions: Vec<KeyVersion>,
+    #[serde(default)]
+    revoked_at: Option<u64>,
+}
+
+/// The unwrapped versions of a tenant key.
+struct TenantKey {
+    versions: HashMap<u32, [u8; KEY_LEN]>,
+    active: u32,
+}
+
+/// What the admin `tenant-keys list` command shows of a tenant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantKeyStatus {
+    pub tenant: String,
+    /// `wrapped` or `kms`.
+    pub source: &'static str,
+    /// Version new files are encrypted under, 0 once revoked.
+    pub active_version: u32,
+    pub revoked_at: Option<u64>,
+    /// Whether the key was unwrapped since the keyring was created.
+    pub loaded: bool,
+}
+
+/// Callers that also lock the dataframes must lock them first.
+#[derive(Default)]
+pub struct TenantKeyring {
+    dir: PathBuf,
+    /// Persisted dataframes are written in plaintext if unset.
+    wrapping: Option<[u8; KEY_LEN]>,
+    /// Key files of the tenants whose keys are provided by the KMS.
+    provided: HashMap<String, PathBuf>,
+    loaded: RwLock<HashMap<String, Arc<TenantKey>>>,
+}
+
+impl TenantKeyring {
+    /// A keyring that writes plaintext and cannot read encrypted dataframes.
+    pub fn disabled() -> Self {
+        TenantKeyring::default()
+    }
+
+    /// A keyring storing its key files in `dir`, wrapping keys with a key derived from
+    /// `server_key`, and reading the keys of the tenants of `provided` from the given files.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        server_key: &[u8],
+        provided: HashMap<String, PathBuf>,
+    ) -> Self {
+        let wrapping = digest(&SHA256, &[WRAPPING_LABEL, server_key].concat());
+        TenantKeyring {
+            dir: dir.into(),
+            wrapping: Some(wrapping.as_ref().try_into().unwrap()),
+            provided,
+            loaded: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.wrapping.is_some()
+    }
+
+    fn key_path(&self, tenant: &str) -> PathBuf {
+        self.dir.join(format!("{tenant}.json"))
+    }
+
+    fn read_key_file(&self, tenant: &str) -> Result<KeyFile, Status> {
+        let path = self.key_path(tenant);
+        if !path.exists() {
+            return Ok(KeyFile::default());
+        }
+        let buf = fs::read(path).map_err(io_err)?;
+        serde_json::from_slice(&buf)
+            .map_err(|e| Status::data_loss(format!("Corrupted key file of tenant {tenant}: {e}")))
+    }
+
+    fn write_key_file(&self, tenant: &str, file: &KeyFile) -> Result<(), Status> {
+        fs::create_dir_all(&self.dir).map_err(io_err)?;
+        let buf = serde_json::to_vec_pretty(file)
+            .map_err(|e| Status::internal(format!("Could not serialize a key file: {e}")))?;
+        atomic_file::write(&self.key_path(tenant), &buf).map_err(io_err)
+    }
+
+    fn wrapping_key(&self) -> Result<LessSafeKey, Status> {
+        self.wrapping
+            .as_ref()
+            .map(|key| aead_key(key))
+            .ok_or_else(|| Status::failed_precondition("Tenant encryption is disabled"))
+    }
+
+    fn new_version(&self, tenant: &str, version: u32) -> Result<KeyVersion, Status> {
+        let mut key = [0u8; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| Status::internal("Could not generate a tenant key"))?;
+        let wrapped = seal(&self.wrapping_key()?, tenant.as_bytes(), key.to_vec())?;
+        Ok(KeyVersion {
+            version,
+            created_at: now_ms(),
+            wrapped: hex::encode(wrapped),
+        })
+    }
+
+    /// Reads and unwraps the key of `tenant`, generating it if `create` is set and it has none.
+    fn unwrap_key(&self, tenant: &str, create: bool) -> Result<TenantKey, Status> {
+        let mut file = self.read_key_file(tenant)?;
+        if let Some(revoked_at) = file.revoked_at {
+            return Err(revoked(tenant, revoked_at));
+        }
+        if let Some(path) = self.provided.get(tenant) {
+            let key = fs::read_to_string(path)
+                .map_err(|e| {
+                    Status::unavailable(format!(
+                        "Could not read the KMS key of tenant {tenant}: {e}"
+                    ))
+                })
+                .and_then(|hex_key| {
+                    hex::decode(hex_key.trim())
+                        .ok()
+                        .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+                        .ok_or_else(|| {
+                            Status::invalid_argument(format!(
+                                "The KMS key of tenant {tenant} is not 32 hex-encoded bytes"
+                            ))
+                        })
+                })?;
+            return Ok(TenantKey {
+                versions: HashMap::from([(1, key)]),
+                active: 1,
+            });
+        }
+
+        if file.versions.is_empty() {
+            if !create {
+                return Err(undecryptable(&format!("tenant {tenant} has no key")));
+            }
+            file.versions.push(self.new_version(tenant, 1)?);
+            self.write_key_file(tenant, &file)?;
+        }
+        let wrapping = self.wrapping_key()?;
+        let mut versions = HashMap::new();
+        for version in file.versions.iter() {
+            let key = hex::decode(&version.wrapped)
+                .ok()
+                .and_then(|wrapped| open(&wrapping, tenant.as_bytes(), &wrapped))
+                .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+                .ok_or_else(|| {
+                    undecryptable(&format!(
+                        "version {} of the key of tenant {tenant} is not wrapped by this server key",
+                        version.version
+                    ))
+                })?;
+            versions.insert(version.version, key);
+        }
+        Ok(TenantKey {
+            active: file.versions.iter().map(|v| v.version).max().unwrap_or(0),
+            versions,
+        })
+    }
+
+    /// The key of `tenant`, unwrapped on first use.
+    fn key(&self, tenant: &str, create: bool) -> Result<Arc<TenantKey>, Status> {
+        check_tenant(tenant)?;
+        if let Some(key) = self.loaded.read().unwrap().get(tenant) {
+            return Ok(key.clone());
+        }
+        let mut loaded = self.loaded.write().unwrap();
+        if let Some(key) = loaded.get(tenant) {
+            return Ok(key.clone());
+        }
+        let key = Arc::new(self.unwrap_key(tenant, create)?);
+        loaded.insert(tenant.to_string(), key.clone());
+        Ok(key)
+    }
+
+    /// Encrypts an encoded artifact of `tenant`, or returns it as is if encryption is disabled.
+    pub fn seal(&self, tenant: &str, data: Vec<u8>) -> Result<Vec<u8>, Status> {
+        if !self.is_enabled() {
+            return Ok(data);
+        }
+        let key = self.key(tenant, true)?;
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&ENVELOPE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(tenant.len() as u16).to_le_bytes());
+        header.extend_from_slice(tenant.as_bytes());
+        header.extend_from_slice(&key.active.to_le_bytes());
+        let sealed = seal(&aead_key(&key.versions[&key.active]), &header, data)?;
+        header.extend_from_slice(&sealed);
+        Ok(header)
+    }
+
+    /// Decrypts what [`Self::seal`] returned, passing plaintext artifacts through.
+    pub fn open(&self, buf: &[u8]) -> Result<Vec<u8>, Status> {
+        let Some((tenant, version, header_len)) = parse_header(buf)? else {
+            return Ok(buf.to_vec());
+        };
+        if !self.is_enabled() {
+            return Err(Status::failed_precondition(format!(
+                "Persisted dataframe is encrypted under the key of tenant {tenant}, but tenant encryption is disabled"
+            )));
+        }
+        let key = self.key(&tenant, false)?;
+        let version_key = key.versions.get(&version).ok_or_else(|| {
+            undecryptable(&format!(
+                "unknown version {version} of the key of tenant {tenant}"
+            ))
+        })?;
+        open(
+            &aead_key(version_key),
+            &buf[..header_len],
+            &buf[header_len..],
+        )
+        .ok_or_else(|| {
+            undecryptable(&format!(
+                "authentication failed under version {version} of the key of tenant {tenant}"
+            ))
+        })
+    }
+
+    /// Adds a new version of the key of `tenant`, under which new files are encrypted.
+    ///
+    /// Keys provided by the KMS are rotated there.
+    pub fn rotate(&self, tenant: &str) -> Result<u32, Status> {
+        check_tenant(tenant)?;
+        if self.provided.contains_key(tenant) {
+            return Err(Status::failed_precondition(format!(
+                "The key of tenant {tenant} is provided by the KMS, rotate it there"
+            )));
+        }
+        let mut loaded = self.loaded.write().unwrap();
+        let mut file = self.read_key_file(tenant)?;
+        if let Some(revoked_at) = file.revoked_at {
+            return Err(revoked(tenant, revoked_at));
+        }
+        let version = file.versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+        file.versions.push(self.new_version(tenant, version)?);
+        self.write_key_file(tenant, &file)?;
+        loaded.remove(tenant);
+        Ok(version)
+    }
+
+    /// Erases the wrapped key material of `tenant` for good.
+    pub fn revoke(&self, tenant: &str) -> Result<(), Status> {
+        check_tenant(tenant)?;
+        let mut loaded = self.loaded.write().unwrap();
+        let mut file = self.read_key_file(tenant)?;
+        if file.revoked_at.is_some() {
+            return Err(Status::failed_precondition(format!(
+                "The key of tenant {tenant} is already revoked"
+            )));
+        }
+        file.versions.clear();
+        file.revoked_at = Some(now_ms());
+        self.write_key_file(tenant, &file)?;
+        loaded.remove(tenant);
+        Ok(())
+    }
+
+    /// The status of every tenant with a key file or a key provided by the KMS.
+    pub fn statuses(&self) -> Result<Vec<TenantKeyStatus>, Status> {
+        let mut tenants: Vec<String> = self.provided.keys().cloned().collect();
+        if self.dir.exists() {
+            for entry in fs::read_dir(&self.dir).map_err(io_err)? {
+                let path = entry.map_err(io_err)?.path();
+                if path.extension().is_some_and(|e| e == "json") {
+                    if let Some(stem) = path.file_stem() {
+                        tenants.push(stem.to_string_lossy().to_string());
+                    }
+                }
+            }
+        }
+        tenants.sort();
+        tenants.dedup();
+
+        let loaded = self.loaded.read().unwrap();
+        tenants
+            .into_iter()
+            .map(|tenant| {
+                let file = self.read_key_file(&tenant)?;
+                let provided = self.provided.contains_key(&tenant);
+                Ok(TenantKeyStatus {
+                    source: if provided { "kms" } else { "wrapped" },
+                    active_version: match (file.revoked_at, provided) {
+                        (Some(_), _) => 0,
+                        (None, true) => 1,
+                        (None, false) => file.versions.iter().map(|v| v.version).max().unwrap_or(0),
+                    },
+                    revoked_at: file.revoked_at,
+                    loaded: loaded.contains_key(&tenant),
+                    tenant,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The tenant, key version and header length of an encrypted artifact, `None` if it is not
+/// encrypted.
+fn parse_header(buf: &[u8]) -> Result<Option<(String, u32, usize)>, Status> {
+    if !buf.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let truncated = || undecryptable("truncated header");
+    let version = u32::from_le_bytes(buf.get(4..8).ok_or_else(truncated)?.try_into().unwrap());
+    if version != ENVELOPE_VERSION {
+        return Err(undecryptable(&format!(
+            "unknown envelope version {version}"
+        )));
+    }
+    let tenant_len =
+        u16::from_le_bytes(buf.get(8..10).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    let tenant = buf.get(10..10 + tenant_len).ok_or_else(truncated)?;
+    let tenant = String::from_utf8(tenant.to_vec()).map_err(|_| undecryptable("invalid tenant"))?;
+    let end = 10 + tenant_len + 4;
+    let key_version = u32::from_le_bytes(
+        buf.get(end - 4..end)
+            .ok_or_else(truncated)?
+            .try_into()
+            .unwrap(),
+    );
+    Ok(Some((tenant, key_version, end)))
+}
+
+/// The tenant and key version an artifact is encrypted under, if it is.
+pub fn encryption_of(buf: &[u8]) -> Result<Option<(String, u32)>, Status> {
+    Ok(parse_header(buf)?.map(|(tenant, version, _)| (tenant, version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::persistence::{artifact_path, load_artifact};
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use polars::prelude::*;
+    use std::path::Path;
+
+    const SERVER_KEY: &[u8] = b"server private key";
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("bastionlab-tenants-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn keyring(dir: &Path) -> TenantKeyring {
+        TenantKeyring::new(dir.join(KEYS_DIR), SERVER_KEY, HashMap::new())
+    }
+
+    fn polars(dir: &Path) -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+            .with_data_dir(dir)
+            .with_tenant_keys(keyring(dir))
+    }
+
+    fn persist(polars: &BastionLabPolars, owner: &str) -> String {
+        let df = df! { "amount" => [1i64, 2, 3] }.unwrap();
+        let artifact = DataFrameArtifact::new(df, Policy::allow_by_default(), vec![]);
+        let identifier = polars.insert_df(artifact.with_owner(owner));
+        polars.persist_df(&identifier).unwrap();
+        identifier
+    }
+
+    #[test]
+    fn keys_are_rotated_and_wrapped_by_the_server_key() {
+        let dir = temp_dir();
+        let keys = keyring(&dir);
+        let before = keys.seal("alice", b"first".to_vec()).unwrap();
+        assert_eq!(encryption_of(&before).unwrap(), Some(("alice".into(), 1)));
+        assert!(!before.windows(5).any(|w| w == b"first"));
+
+        assert_eq!(keys.rotate("alice").unwrap(), 2);
+        let after = keys.seal("alice", b"second".to_vec()).unwrap();
+        assert_eq!(encryption_of(&after).unwrap(), Some(("alice".into(), 2)));
+        // Older versions still read the files written before the rotation, once reloaded.
+        let reloaded = keyring(&dir);
+        assert_eq!(reloaded.open(&before).unwrap(), b"first");
+        assert_eq!(reloaded.open(&after).unwrap(), b"second");
+        assert_eq!(reloaded.open(b"plaintext").unwrap(), b"plaintext");
+
+        // Another server key cannot unwrap them.
+        let other = TenantKeyring::new(dir.join(KEYS_DIR), b"other key", HashMap::new());
+        assert_eq!(other.open(&before).unwrap_err().code(), Code::DataLoss);
+        let mut tampered = after.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(reloaded.open(&tampered).unwrap_err().code(), Code::DataLoss);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn kms_keys_are_read_lazily() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("bob.key");
+        fs::write(&key_file, hex::encode([7u8; KEY_LEN])).unwrap();
+        let provided = HashMap::from([(String::from("bob"), key_file.clone())]);
+        let keys = TenantKeyring::new(dir.join(KEYS_DIR), SERVER_KEY, provided.clone());
+        assert!(!keys.statuses().unwrap()[0].loaded);
+        let sealed = keys.seal("bob", b"data".to_vec()).unwrap();
+        assert_eq!(
+            keys.statuses().unwrap(),
+            vec![TenantKeyStatus {
+                tenant: "bob".into(),
+                source: "kms",
+                active_version: 1,
+                revoked_at: None,
+                loaded: true,
+            }]
+        );
+        assert_eq!(
+            keys.rotate("bob").unwrap_err().code(),
+            Code::FailedPrecondition
+        );
+
+        // The KMS key is all it takes, whatever the server key.
+        let other = TenantKeyring::new(dir.join(KEYS_DIR), b"other key", provided);
+        assert_eq!(other.open(&sealed).unwrap(), b"data");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn revoking_a_tenant_leaves_the_others_untouched() {
+        let dir = temp_dir();
+        let server = polars(&dir);
+        let alice = persist(&server, "alice");
+        let bob = persist(&server, "bob");
+        let unowned = persist(&server, "");
+        server.tenant_keys.revoke("alice").unwrap();
+
+        // The running server can no longer persist alice's dataframes, but still bob's.
+        let err = server.persist_df(&alice).unwrap_err();
+        assert!(is_revoked(&err), "{err:?}");
+        assert!(err.message().contains("tenant alice"), "{err:?}");
+        persist(&server, "bob");
+
+        // On restart, alice's dataframes fail precisely and the others load.
+        let restarted = polars(&dir);
+        restarted.load_dfs().unwrap();
+        assert!(restarted.get_header(&alice).is_err());
+        restarted.get_header(&bob).unwrap();
+        restarted.get_header(&unowned).unwrap();
+        let err = load_artifact(&artifact_path(&dir, &alice), &keyring(&dir)).unwrap_err();
+        assert!(is_revoked(&err), "{err:?}");
+        assert!(err.message().contains("tenant alice"), "{err:?}");
+
+        let statuses = restarted.tenant_keys.statuses().unwrap();
+        let tenants: Vec<_> = statuses
+            .iter()
+            .map(|s| (s.tenant.as_str(), s.revoked_at.is_some(), s.loaded))
+            .collect();
+        assert_eq!(
+            tenants,
+            [
+                ("alice", true, false),
+                ("bob", false, true),
+                (DEFAULT_TENANT, false, true)
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
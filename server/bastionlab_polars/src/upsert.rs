@@ -0,0 +1,263 @@
+use polars::prelude::*;
+use tonic::Status;
+
+use crate::reserved::{UPSERT_INCOMING_ROW as INCOMING_ROW, UPSERT_TARGET_ROW as TARGET_ROW};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UpsertReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::invalid_argument(format!("Polars error during upsert: {e}"))
+}
+
+/// Checks that `incoming` can be merged into `target` on `keys`.
+///
+/// `incoming` must contain the keys and a subset of the columns of `target`, with the same dtypes.
+fn validate(target: &DataFrame, incoming: &DataFrame, keys: &[String]) -> Result<(), Status> {
+    if keys.is_empty() {
+        return Err(Status::invalid_argument(
+            "At least one key column is required",
+        ));
+    }
+    let schema = target.schema();
+    for key in keys {
+        if incoming.find_idx_by_name(key).is_none() || schema.get(key).is_none() {
+            return Err(Status::invalid_argument(format!(
+                "Key column {key} must be present in both the dataframe and the incoming rows"
+            )));
+        }
+    }
+    for series in incoming.get_columns() {
+        match schema.get(series.name()) {
+            Some(dtype) if dtype == series.dtype() => (),
+            Some(dtype) => {
+                return Err(Status::invalid_argument(format!(
+                    "Column {} has dtype {} but {} was expected",
+                    series.name(),
+                    series.dtype(),
+                    dtype
+                )))
+            }
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "Column {} is not in the dataframe",
+                    series.name()
+                )))
+            }
+        }
+    }
+
+    let keys = incoming.select(keys).map_err(polars_err)?;
+    if keys.get_columns().iter().any(|s| s.null_count() > 0) {
+        return Err(Status::invalid_argument(
+            "Key columns of the incoming rows must not contain nulls",
+        ));
+    }
+    // Only the number of duplicates is reported: the key values may be sensitive.
+    let duplicated = keys.is_duplicated().map_err(polars_err)?.sum().unwrap_or(0);
+    if duplicated > 0 {
+        return Err(Status::invalid_argument(format!(
+            "{duplicated} incoming rows have duplicated keys"
+        )));
+    }
+    Ok(())
+}
+
+/// Returns whether the values of two series are equal, nulls being equal to each other.
+pub(crate) fn same_values(a: &Series, b: &Series) -> Result<Vec<bool>, Status> {
+    let eq = a.equal(b).map_err(polars_err)?;
+    Ok(eq
+        .into_iter()
+        .zip(a.is_null().into_iter().zip(&b.is_null()))
+        .map(|(eq, (a_null, b_null))| {
+            eq.unwrap_or(false) || (a_null == Some(true) && b_null == Some(true))
+        })
+        .collect())
+}
+
+/// Merges `incoming` into `target`: rows whose keys match are updated, the others are inserted.
+///
+/// Updated rows only change in the columns present in `incoming`, and inserted rows get nulls in
+/// the other ones. Existing rows keep their position and new rows are added at the end.
+pub fn upsert(
+    target: &DataFrame,
+    incoming: &DataFrame,
+    keys: &[String],
+) -> Result<(DataFrame, UpsertReport), Status> {
+    validate(target, incoming, keys)?;
+    if incoming.height() == 0 {
+        return Ok((target.clone(), UpsertReport::default()));
+    }
+
+    let target_keys = target
+        .select(keys)
+        .and_then(|df| df.with_row_count(TARGET_ROW, None))
+        .map_err(polars_err)?;
+    let incoming_keys = incoming
+        .select(keys)
+        .and_then(|df| df.with_row_count(INCOMING_ROW, None))
+        .map_err(polars_err)?;
+    let joined = target_keys
+        .join(&incoming_keys, keys, keys, JoinType::Left, None)
+        .and_then(|df| df.sort([TARGET_ROW], false))
+        .map_err(polars_err)?;
+    let matches = joined
+        .column(INCOMING_ROW)
+        .map_err(polars_err)?
+        .idx()
+        .map_err(polars_err)?;
+    let mask = matches.is_not_null();
+    // Unmatched rows take any incoming row, `mask` then keeps their current values.
+    let take = matches.fill_null_with_values(0).map_err(polars_err)?;
+
+    let mut unchanged = mask.clone();
+    let mut columns = Vec::with_capacity(target.width());
+    for series in target.get_columns() {
+        let new = match incoming.column(series.name()) {
+            Ok(values) if !keys.iter().any(|k| k == series.name()) => {
+                let values = values.take(&take).map_err(polars_err)?;
+                let same: BooleanChunked = same_values(&values, series)?.into_iter().collect();
+                unchanged = &unchanged & &same;
+                values.zip_with(&mask, series).map_err(polars_err)?
+            }
+            _ => series.clone(),
+        };
+        columns.push(new);
+    }
+    let mut merged = DataFrame::new(columns).map_err(polars_err)?;
+
+    let mut matched = vec![false; incoming.height()];
+    for idx in matches.into_iter().flatten() {
+        matched[idx as usize] = true;
+    }
+    let new_rows: BooleanChunked = matched.iter().map(|m| !m).collect();
+    let inserted = incoming.filter(&new_rows).map_err(polars_err)?;
+    let inserted = DataFrame::new(
+        target
+            .get_columns()
+            .iter()
+            .map(|s| match inserted.column(s.name()) {
+                Ok(values) => values.clone(),
+                Err(_) => Series::full_null(s.name(), inserted.height(), s.dtype()),
+            })
+            .collect(),
+    )
+    .map_err(polars_err)?;
+    merged.vstack_mut(&inserted).map_err(polars_err)?;
+
+    let updated = mask.sum().unwrap_or(0) as usize;
+    let unchanged = unchanged.sum().unwrap_or(0) as usize;
+    Ok((
+        merged,
+        UpsertReport {
+            inserted: inserted.height(),
+            updated: updated - unchanged,
+            unchanged,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn patients() -> DataFrame {
+        df! {
+            "id" => [1i64, 2, 3],
+            "ward" => ["a", "b", "c"],
+            "weight" => [Some(70.0), None, Some(80.0)],
+        }
+        .unwrap()
+    }
+
+    #[test]
+    fn updates_matching_keys_and_inserts_the_rest() {
+        let incoming = df! {
+            "id" => [3i64, 4, 1],
+            "ward" => ["c", "d", "z"],
+            "weight" => [Some(80.0), Some(60.0), Some(71.0)],
+        }
+        .unwrap();
+        let (merged, report) = upsert(&patients(), &incoming, &keys(&["id"])).unwrap();
+        assert_eq!(
+            report,
+            UpsertReport {
+                inserted: 1,
+                updated: 1,
+                unchanged: 1
+            }
+        );
+        let expected = df! {
+            "id" => [1i64, 2, 3, 4],
+            "ward" => ["z", "b", "c", "d"],
+            "weight" => [Some(71.0), None, Some(80.0), Some(60.0)],
+        }
+        .unwrap();
+        assert!(merged.frame_equal_missing(&expected));
+    }
+
+    #[test]
+    fn column_subsets_only_touch_their_columns() {
+        let incoming = df! {
+            "ward" => ["b", "e"],
+            "id" => [2i64, 5],
+        }
+        .unwrap();
+        let (merged, report) = upsert(&patients(), &incoming, &keys(&["id"])).unwrap();
+        // Row 2 already is in ward b: its null weight is left as is.
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.inserted, 1);
+        let expected = df! {
+            "id" => [1i64, 2, 3, 5],
+            "ward" => ["a", "b", "c", "e"],
+            "weight" => [Some(70.0), None, Some(80.0), None],
+        }
+        .unwrap();
+        assert!(merged.frame_equal_missing(&expected));
+    }
+
+    #[test]
+    fn composite_keys_are_supported() {
+        let incoming = df! {
+            "id" => [1i64, 1],
+            "ward" => ["a", "b"],
+            "weight" => [Some(90.0), Some(91.0)],
+        }
+        .unwrap();
+        let (merged, report) = upsert(&patients(), &incoming, &keys(&["id", "ward"])).unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(report.inserted, 1);
+        assert_eq!(merged.height(), 4);
+    }
+
+    #[test]
+    fn duplicated_keys_are_rejected_without_their_values() {
+        let incoming = df! {
+            "id" => [7i64, 7, 8],
+            "weight" => [Some(1.0), Some(2.0), Some(3.0)],
+        }
+        .unwrap();
+        let err = upsert(&patients(), &incoming, &keys(&["id"])).unwrap_err();
+        assert!(err.message().contains("2 incoming rows"));
+        assert!(!err.message().contains('7'));
+    }
+
+    #[test]
+    fn incompatible_rows_are_rejected() {
+        let wrong_dtype = df! { "id" => [1i32] }.unwrap();
+        assert!(upsert(&patients(), &wrong_dtype, &keys(&["id"])).is_err());
+        let unknown = df! { "id" => [1i64], "age" => [3i64] }.unwrap();
+        assert!(upsert(&patients(), &unknown, &keys(&["id"])).is_err());
+        let null_key = df! { "id" => [Some(1i64), None] }.unwrap();
+        assert!(upsert(&patients(), &null_key, &keys(&["id"])).is_err());
+        assert!(upsert(&patients(), &patients(), &[]).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Retained versions of dataframes, for queries against a dataset as it was.
+//!
+//! Appends and upserts supersede the version of a dataframe with a new one. Owners set how many
+//! superseded versions a dataset retains, and for how long after they were superseded, see
+//! [`Retention`]: none by default. Entry points select a version with `identifier@version`, or
+//! with `identifier@timestamp`, an RFC 3339 timestamp resolving to the latest version created at
+//! or before it. Queries pin the version they resolved in their plan, and results record it in
+//! their lineage. Selecting a version that is no longer retained fails with the available range.
+//!
+//! Retained versions share column data with the version that superseded them where possible: an
+//! append keeps the rows of the previous version as they are, and an upsert keeps the columns it
+//! left unchanged. Only the data a version does not share is reported as its storage usage.
+//! Retained versions are kept in memory, they do not survive a restart.
+
+use std::collections::VecDeque;
+
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::storage_optimization::{restore_dtypes, DtypeChange};
+
+/// Separates the identifier of an entry point from its version selector.
+pub const SEPARATOR: char = '@';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selector {
+    Version(u64),
+    /// The latest version created at or before this time, in milliseconds since the Unix epoch.
+    At(u64),
+}
+
+impl Selector {
+    fn parse(selector: &str) -> Option<Selector> {
+        if let Ok(version) = selector.parse() {
+            return Some(Selector::Version(version));
+        }
+        let at = chrono::DateTime::parse_from_rfc3339(selector).ok()?;
+        Some(Selector::At(at.timestamp_millis().max(0) as u64))
+    }
+}
+
+/// Splits the version selector off entry point `identifier`.
+pub fn split(identifier: &str) -> Result<(&str, Option<Selector>), Status> {
+    match identifier.rsplit_once(SEPARATOR) {
+        Some((base, selector)) => match Selector::parse(selector) {
+            Some(selector) => Ok((base, Some(selector))),
+            None => Err(Status::invalid_argument(format!(
+                "Invalid version selector `{selector}` of {base}: expected a version number or \
+                 an RFC 3339 timestamp"
+            ))),
+        },
+        None => Ok((identifier, None)),
+    }
+}
+
+/// The identifier of entry point `identifier`, without its version selector if it has a valid one.
+pub fn base(identifier: &str) -> &str {
+    match split(identifier) {
+        Ok((base, _)) => base,
+        Err(_) => identifier,
+    }
+}
+
+/// The RFC 3339 timestamp of `ms` milliseconds since the Unix epoch, as selectors take them.
+pub fn timestamp(ms: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms as i64).map_or(ms.to_string(), |at| {
+        at.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+    })
+}
+
+/// How many superseded versions of a dataframe are retained, and for how long.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    /// The most recent ones are kept, none if 0.
+    pub max_versions: u32,
+    /// Seconds after they were superseded, without limit if 0.
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetainedVersion {
+    pub version: u64,
+    /// Milliseconds since the Unix epoch, 0 if unknown.
+    pub created_at: u64,
+    pub superseded_at: u64,
+    /// As stored, whose declared dtypes `dtype_changes` restore.
+    dataframe: DataFrame,
+    dtype_changes: Vec<DtypeChange>,
+    /// Bytes of the columns not shared with the version that superseded it.
+    pub unshared_bytes: u64,
+}
+
+impl RetainedVersion {
+    pub fn rows(&self) -> usize {
+        self.dataframe.height()
+    }
+
+    pub fn declared_dataframe(&self) -> Result<DataFrame, Status> {
+        let mut df = self.dataframe.clone();
+        restore_dtypes(&mut df, &self.dtype_changes)?;
+        Ok(df)
+    }
+}
+
+/// A version selected by an entry point.
+pub enum Found<'a> {
+    Current,
+    Retained(&'a RetainedVersion),
+}
+
+/// The superseded versions of a dataframe.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VersionHistory {
+    #[serde(default)]
+    pub retention: Retention,
+    /// When the current version was created, in milliseconds since the Unix epoch, 0 if unknown.
+    #[serde(default)]
+    pub created_at: u64,
+    /// Oldest first.
+    #[serde(skip)]
+    retained: VecDeque<RetainedVersion>,
+}
+
+impl VersionHistory {
+    pub fn new(created_at: u64) -> Self {
+        VersionHistory {
+            created_at,
+            ..Default::default()
+        }
+    }
+
+    /// Records that `version`, stored as `dataframe`, was superseded at `now`. `unshared_bytes`
+    /// are the bytes of its columns the new version does not share, see [`share_columns`].
+    pub fn supersede(
+        &mut self,
+        version: u64,
+        dataframe: DataFrame,
+        dtype_changes: Vec<DtypeChange>,
+        unshared_bytes: u64,
+        now: u64,
+    ) {
+        self.retained.push_back(RetainedVersion {
+            version,
+            created_at: self.created_at,
+            superseded_at: now,
+            dataframe,
+            dtype_changes,
+            unshared_bytes,
+        });
+        self.created_at = now;
+        self.prune(now);
+    }
+
+    /// Sets the retention, pruning the versions it no longer retains.
+    pub fn set_retention(&mut self, retention: Retention, now: u64) {
+        self.retention = retention;
+        self.prune(now);
+    }
+
+    /// When retained version `version` expires, if it does at a given time.
+    pub fn expires_at(&self, version: &RetainedVersion) -> Option<u64> {
+        (self.retention.max_age_secs > 0).then(|| {
+            version
+                .superseded_at
+                .saturating_add(self.retention.max_age_secs.saturating_mul(1000))
+        })
+    }
+
+    /// Forgets the versions the retention no longer covers at `now`.
+    pub fn prune(&mut self, now: u64) {
+        while self.retained.len() > self.retention.max_versions as usize {
+            self.retained.pop_front();
+        }
+        while let Some(oldest) = self.retained.front() {
+            match self.expires_at(oldest) {
+                Some(expires_at) if expires_at <= now => self.retained.pop_front(),
+                _ => break,
+            };
+        }
+    }
+
+    /// The retained versions, oldest first.
+    pub fn retained(&self) -> impl Iterator<Item = &RetainedVersion> {
+        self.retained.iter()
+    }
+
+    /// Bytes held by the retained versions only.
+    pub fn retained_bytes(&self) -> u64 {
+        self.retained.iter().map(|v| v.unshared_bytes).sum()
+    }
+
+    /// The retained versions that have not expired at `now`, oldest first.
+    fn live(&self, now: u64) -> impl DoubleEndedIterator<Item = &RetainedVersion> {
+        self.retained
+            .iter()
+            .filter(move |retained| self.expires_at(retained).map_or(true, |at| at > now))
+    }
+
+    /// The version of `identifier`, currently at version `current`, that `selector` selects at
+    /// `now`.
+    pub fn find(
+        &self,
+        identifier: &str,
+        current: u64,
+        selector: Selector,
+        now: u64,
+    ) -> Result<Found<'_>, Status> {
+        let found = match selector {
+            Selector::Version(version) if version == current => Some(Found::Current),
+            Selector::Version(version) => self
+                .live(now)
+                .find(|retained| retained.version == version)
+                .map(Found::Retained),
+            Selector::At(at) if at >= self.created_at => Some(Found::Current),
+            Selector::At(at) => self
+                .live(now)
+                .rev()
+                .find(|retained| retained.created_at <= at)
+                .map(Found::Retained),
+        };
+        found.ok_or_else(|| {
+            let selected = match selector {
+                Selector::Version(version) => format!("Version {version}"),
+                Selector::At(at) => format!("No version created at or before {}", timestamp(at)),
+            };
+            let (oldest, since) = self
+                .live(now)
+                .next()
+                .map_or((current, self.created_at), |v| (v.version, v.created_at));
+            Status::out_of_range(format!(
+                "{selected} of {identifier} is not retained: versions {oldest} to {current} are \
+                 available, created since {}",
+                timestamp(since)
+            ))
+        })
+    }
+}
+
+/// Replaces the columns of `next` equal to those of `previous` by them, so that both versions
+/// share their data. Returns the bytes of the columns of `previous` that are not shared.
+pub fn share_columns(previous: &DataFrame, next: &mut DataFrame) -> Result<u64, Status> {
+    let mut unshared = 0;
+    for column in previous.get_columns() {
+        let unchanged = next.column(column.name()).map_or(false, |next| {
+            next.dtype() == column.dtype() && next.series_equal_missing(column)
+        });
+        if unchanged {
+            next.replace(column.name(), column.clone())
+                .map_err(|e| Status::internal(format!("Could not share column data: {e}")))?;
+        } else {
+            unshared += column.estimated_size() as u64;
+        }
+    }
+    Ok(unshared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn history(retention: Retention) -> VersionHistory {
+        let mut history = VersionHistory::new(1_000);
+        history.set_retention(retention, 1_000);
+        for version in 0..3u64 {
+            let df = df! { "x" => vec![version as i64; version as usize + 1] }.unwrap();
+            let now = 2_000 + version * 1_000;
+            history.supersede(version, df, Vec::new(), 0, now);
+        }
+        history
+    }
+
+    #[test]
+    fn selectors_resolve_to_retained_versions() {
+        assert_eq!(split("abc").unwrap(), ("abc", None));
+        assert_eq!(split("abc@2").unwrap(), ("abc", Some(Selector::Version(2))));
+        assert_eq!(
+            split("abc@1970-01-01T00:00:02Z").unwrap(),
+            ("abc", Some(Selector::At(2_000)))
+        );
+        assert!(split("abc@yesterday").is_err());
+        assert_eq!(base("abc@yesterday"), "abc@yesterday");
+
+        let history = history(Retention {
+            max_versions: 2,
+            max_age_secs: 0,
+        });
+        // Version 0 was pruned, 1 and 2 are retained and 3 is current.
+        let version = |selector| match history.find("abc", 3, selector, 4_000) {
+            Ok(Found::Current) => Ok(3),
+            Ok(Found::Retained(retained)) => Ok(retained.version),
+            Err(e) => Err(e),
+        };
+        assert_eq!(version(Selector::Version(1)).unwrap(), 1);
+        assert_eq!(version(Selector::Version(3)).unwrap(), 3);
+        assert_eq!(version(Selector::At(3_500)).unwrap(), 2);
+        assert_eq!(version(Selector::At(3_000)).unwrap(), 2);
+        assert_eq!(version(Selector::At(9_000)).unwrap(), 3);
+        let err = version(Selector::Version(0)).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+        assert_eq!(
+            err.message(),
+            "Version 0 of abc is not retained: versions 1 to 3 are available, created since \
+             1970-01-01T00:00:02.000Z"
+        );
+        assert!(version(Selector::At(1_500)).is_err());
+    }
+
+    #[test]
+    fn versions_expire_after_they_are_superseded() {
+        let mut history = history(Retention {
+            max_versions: 10,
+            max_age_secs: 2,
+        });
+        // Superseded at 2s, 3s and 4s: the first one expired at 4s.
+        let versions = |history: &VersionHistory| -> Vec<u64> {
+            history
+                .retained()
+                .map(|retained| retained.version)
+                .collect()
+        };
+        assert_eq!(versions(&history), [1, 2]);
+        assert_eq!(
+            history.expires_at(history.retained().next().unwrap()),
+            Some(5_000)
+        );
+        history.prune(5_000);
+        assert_eq!(versions(&history), [2]);
+        history.set_retention(Retention::default(), 5_000);
+        assert_eq!(versions(&history), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn unchanged_columns_are_shared() {
+        let previous = df! { "id" => [1i64, 2], "v" => [1.0f64, 2.0] }.unwrap();
+        let mut next = df! { "id" => [1i64, 2], "v" => [1.0f64, 3.0] }.unwrap();
+        let unshared = share_columns(&previous, &mut next).unwrap();
+        assert_eq!(
+            unshared,
+            previous.column("v").unwrap().estimated_size() as u64
+        );
+        assert!(Arc::ptr_eq(
+            &previous.column("id").unwrap().0,
+            &next.column("id").unwrap().0
+        ));
+    }
+}
@@ -0,0 +1,1019 @@
+//! Materialized views: group-bys over a dataframe, the base, whose result is kept up to date as
+//! rows are appended or upserted, and answers the queries running the same group-by.
+//!
+//! On appends, sums, counts, minimums and maximums are merged with those of the new rows. Views
+//! with other aggregations recompute the groups the new rows fall in, and upserts recompute the
+//! groups of the rows they update or insert, before and after the change.
+//!
+//! A view that misses a change of its base, e.g. because its maintenance failed, is stale: it
+//! keeps answering queries for its staleness bound, then queries run on the base again until the
+//! view is refreshed. A view whose base changes schema is invalid until refreshed.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use polars::prelude::*;
+use serde_json::Value;
+use tonic::Status;
+
+use crate::{
+    composite_plan::{exprs_agg_check, CompositePlan},
+    nan,
+    prelude::*,
+    reserved::VIEW_ROWS,
+    semantics, DataFrameArtifact,
+};
+
+/// How the values of an aggregation over two sets of rows combine into the value over both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Merge {
+    Sum,
+    Min { propagate_nans: bool },
+    Max { propagate_nans: bool },
+}
+
+impl Merge {
+    fn of(agg: &Expr) -> Option<Merge> {
+        match agg {
+            Expr::Alias(inner, _) => Merge::of(inner),
+            Expr::Agg(AggExpr::Sum(_)) | Expr::Agg(AggExpr::Count(_)) | Expr::Count => {
+                Some(Merge::Sum)
+            }
+            Expr::Agg(AggExpr::Min { propagate_nans, .. }) => Some(Merge::Min {
+                propagate_nans: *propagate_nans,
+            }),
+            Expr::Agg(AggExpr::Max { propagate_nans, .. }) => Some(Merge::Max {
+                propagate_nans: *propagate_nans,
+            }),
+            _ => None,
+        }
+    }
+
+    fn expr(self, column: &str) -> Expr {
+        let input = Box::new(col(column));
+        match self {
+            Merge::Sum => col(column).sum(),
+            Merge::Min { propagate_nans } => Expr::Agg(AggExpr::Min {
+                input,
+                propagate_nans,
+            }),
+            Merge::Max { propagate_nans } => Expr::Agg(AggExpr::Max {
+                input,
+                propagate_nans,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ViewState {
+    /// Up to date with its base.
+    Fresh,
+    /// Behind its base since `since`.
+    Stale { since: Instant, reason: String },
+    /// Not used until refreshed.
+    Invalid { reason: String },
+}
+
+impl ViewState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ViewState::Fresh => "fresh",
+            ViewState::Stale { .. } => "stale",
+            ViewState::Invalid { .. } => "invalid",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            ViewState::Fresh => "",
+            ViewState::Stale { reason, .. } | ViewState::Invalid { reason } => reason,
+        }
+    }
+}
+
+/// A view, as returned to clients.
+#[derive(Debug, Clone)]
+pub struct ViewInfo {
+    pub name: String,
+    pub base: String,
+    pub base_version: u64,
+    pub groups: usize,
+    /// Whether appends are merged rather than recomputed.
+    pub incremental: bool,
+    pub state: ViewState,
+}
+
+/// The result of a query, read from a view.
+#[derive(Debug)]
+pub struct ViewAnswer {
+    pub view: String,
+    pub base: String,
+    pub base_version: u64,
+    /// With the size of each group in [`VIEW_ROWS`].
+    pub dataframe: DataFrame,
+    /// Size of the smallest group, if the aggregations hide the rows of the base.
+    pub agg_size: Option<u64>,
+    /// The group-by, as it runs on the base.
+    pub plan: LogicalPlan,
+    pub stale_for: Option<Duration>,
+}
+
+impl fmt::Display for ViewAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Answered from materialized view {} (version {} of {})",
+            self.view, self.base_version, self.base
+        )?;
+        match self.stale_for {
+            Some(stale_for) => write!(f, ", stale for {}s", stale_for.as_secs()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn polars_err(name: &str) -> impl Fn(PolarsError) -> Status + '_ {
+    move |e| Status::internal(format!("Could not maintain view {name}: {e}"))
+}
+
+/// The aggregation node of `plan` without its input, which queries replace by the dataframe they
+/// read, and without its output schema, which is derived from the input.
+fn canonical(plan: &LogicalPlan) -> Result<Value, Status> {
+    let mut value = serde_json::to_value(plan)
+        .map_err(|e| Status::internal(format!("Could not serialize the group-by: {e}")))?;
+    if let Some(aggregate) = value.get_mut("Aggregate").and_then(Value::as_object_mut) {
+        aggregate.remove("input");
+        aggregate.remove("schema");
+    }
+    Ok(value)
+}
+
+/// What a query must run, as written by the client, to be answered from a view.
+fn signature(plan: &LogicalPlan, skip_nan: bool, semantics_version: u32) -> Result<Value, Status> {
+    Ok(serde_json::json!({
+        "plan": canonical(plan)?,
+        "skip_nan": skip_nan,
+        "semantics_version": semantics_version,
+    }))
+}
+
+/// Identifies the group of each row of `keys`, made of the key columns only.
+fn group_ids(keys: &DataFrame) -> Result<Vec<String>, PolarsError> {
+    // Categories are compared by value: each dataframe has its own mapping.
+    let columns = keys
+        .get_columns()
+        .iter()
+        .map(|s| match s.dtype() {
+            DataType::Categorical(_) => s.cast(&DataType::Utf8),
+            _ => Ok(s.clone()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys = DataFrame::new_no_checks(columns);
+    Ok((0..keys.height())
+        .map(|idx| format!("{:?}", keys.get(idx)))
+        .collect())
+}
+
+fn membership(ids: &[String], groups: &HashSet<String>) -> BooleanChunked {
+    ids.iter().map(|id| groups.contains(id)).collect()
+}
+
+#[derive(Debug)]
+struct View {
+    base: String,
+    /// The composite plan the view was registered with, to rebuild it on refresh.
+    definition: String,
+    signature: Value,
+    keys: Vec<Expr>,
+    aggs: Vec<Expr>,
+    /// Merge of each aggregation, unset if one of them does not merge.
+    merges: Option<Vec<Merge>>,
+    /// Whether the aggregations hide the rows of the base, see [`exprs_agg_check`].
+    hides_rows: bool,
+    base_schema: Schema,
+    base_version: u64,
+    max_staleness: Duration,
+    /// The keys and aggregations of each group, followed by its size in [`VIEW_ROWS`].
+    data: DataFrame,
+    state: ViewState,
+}
+
+impl View {
+    /// Builds the view `plan` defines on its base in `dfs`, and computes it.
+    fn build(
+        name: &str,
+        plan: &CompositePlan,
+        max_staleness: Duration,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<View, Status> {
+        let (base, raw, skip_nan) = match plan.single_plan() {
+            Some(single) if !plan.nan_as_null() => single,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "A view must run a single polars plan on a dataframe, without nan_as_null",
+                ))
+            }
+        };
+        let artifact = dfs.get(base).ok_or_else(|| {
+            Status::not_found(format!("Could not find dataframe: identifier={base}"))
+        })?;
+        let base_schema = artifact.declared_schema();
+
+        let mut prepared = raw.clone();
+        semantics::apply(&mut prepared, semantics::shims(plan.semantics_version())?)?;
+        if skip_nan {
+            nan::skip_nan(&mut prepared)?;
+        }
+        let (keys, aggs) = match &prepared {
+            LogicalPlan::Aggregate {
+                input, keys, aggs, ..
+            } if matches!(**input, LogicalPlan::DataFrameScan { .. }) => {
+                ((**keys).clone(), aggs.clone())
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "A view must be a group-by over its dataframe",
+                ))
+            }
+        };
+        // Ordered, dynamic and rolling group-bys differ from the group-by of their keys and
+        // aggregations.
+        let placeholder = DataFrame::new_no_checks(
+            base_schema
+                .iter()
+                .map(|(name, dtype)| Series::new_empty(name, dtype))
+                .collect(),
+        );
+        let plain = placeholder
+            .lazy()
+            .groupby(keys.clone())
+            .agg(aggs.clone())
+            .logical_plan;
+        if canonical(&plain)? != canonical(&prepared)? {
+            return Err(Status::invalid_argument(
+                "Only plain group-bys can be views: ordered, dynamic and rolling ones cannot",
+            ));
+        }
+
+        let mut view = View {
+            base: base.to_string(),
+            definition: serde_json::to_string(plan).map_err(|e| {
+                Status::internal(format!("Could not serialize composite plan: {e}"))
+            })?,
+            signature: signature(raw, skip_nan, plan.semantics_version())?,
+            merges: aggs.iter().map(Merge::of).collect(),
+            hides_rows: exprs_agg_check(&aggs)?,
+            keys,
+            aggs,
+            base_schema,
+            base_version: artifact.version,
+            max_staleness,
+            data: DataFrame::default(),
+            state: ViewState::Fresh,
+        };
+        view.data = view.aggregate(name, artifact.declared_dataframe()?)?;
+        Ok(view)
+    }
+
+    fn info(&self, name: &str) -> ViewInfo {
+        ViewInfo {
+            name: name.to_string(),
+            base: self.base.clone(),
+            base_version: self.base_version,
+            groups: self.data.height(),
+            incremental: self.merges.is_some(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// Runs the group-by of the view on `df`, with the size of each group.
+    fn aggregate(&self, name: &str, df: DataFrame) -> Result<DataFrame, Status> {
+        let mut aggs = self.aggs.clone();
+        aggs.push(Expr::Count.alias(VIEW_ROWS));
+        df.lazy()
+            .groupby(self.keys.clone())
+            .agg(aggs)
+            .collect()
+            .map_err(polars_err(name))
+    }
+
+    /// Casts the columns of `df`, a result of [`View::aggregate`] or a merge of them, to those of
+    /// the view.
+    fn conform(&self, name: &str, df: DataFrame) -> Result<DataFrame, Status> {
+        let columns: Vec<_> = self
+            .data
+            .get_columns()
+            .iter()
+            .map(|s| col(s.name()).cast(s.dtype().clone()))
+            .collect();
+        df.lazy()
+            .select(columns)
+            .collect()
+            .map_err(polars_err(name))
+    }
+
+    fn n_keys(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Group ids of the rows of the view.
+    fn group_ids(&self, name: &str) -> Result<Vec<String>, Status> {
+        let keys = DataFrame::new_no_checks(self.data.get_columns()[..self.n_keys()].to_vec());
+        group_ids(&keys).map_err(polars_err(name))
+    }
+
+    /// Group ids of the rows of `df`, a dataframe with the schema of the base.
+    fn row_group_ids(&self, name: &str, df: &DataFrame) -> Result<Vec<String>, Status> {
+        let keys = df
+            .clone()
+            .lazy()
+            .select(self.keys.clone())
+            .collect()
+            .map_err(polars_err(name))?;
+        group_ids(&keys).map_err(polars_err(name))
+    }
+
+    /// Replaces `groups` by `updated`, which holds their new values.
+    fn replace_groups(
+        &self,
+        name: &str,
+        groups: &HashSet<String>,
+        updated: DataFrame,
+    ) -> Result<DataFrame, Status> {
+        let kept = self
+            .data
+            .filter(&!membership(&self.group_ids(name)?, groups))
+            .map_err(polars_err(name))?;
+        kept.vstack(&self.conform(name, updated)?)
+            .map_err(polars_err(name))
+    }
+
+    /// Recomputes `groups` from the rows of `base` in them.
+    fn recompute_groups(
+        &self,
+        name: &str,
+        base: &DataFrame,
+        groups: &HashSet<String>,
+    ) -> Result<DataFrame, Status> {
+        let rows = base
+            .filter(&membership(&self.row_group_ids(name, base)?, groups))
+            .map_err(polars_err(name))?;
+        self.replace_groups(name, groups, self.aggregate(name, rows)?)
+    }
+
+    /// The view after `delta` was appended to its base, now `base`.
+    fn appended(
+        &self,
+        name: &str,
+        delta: &DataFrame,
+        base: &DataFrameArtifact,
+    ) -> Result<DataFrame, Status> {
+        let delta = self.aggregate(name, delta.clone())?;
+        let keys = DataFrame::new_no_checks(delta.get_columns()[..self.n_keys()].to_vec());
+        let touched: HashSet<_> = group_ids(&keys)
+            .map_err(polars_err(name))?
+            .into_iter()
+            .collect();
+
+        let merges = match &self.merges {
+            Some(merges) => merges,
+            None => return self.recompute_groups(name, &base.declared_dataframe()?, &touched),
+        };
+        let previous = self
+            .data
+            .filter(&membership(&self.group_ids(name)?, &touched))
+            .map_err(polars_err(name))?;
+        let stacked = previous
+            .vstack(&self.conform(name, delta)?)
+            .map_err(polars_err(name))?;
+        let names = stacked.get_column_names();
+        let (keys, values) = names.split_at(self.n_keys());
+        let merged: Vec<_> = values
+            .iter()
+            .zip(merges.iter().chain([&Merge::Sum]))
+            .map(|(column, merge)| merge.expr(column))
+            .collect();
+        let merged = stacked
+            .clone()
+            .lazy()
+            .groupby(keys.iter().map(|key| col(key)).collect::<Vec<_>>())
+            .agg(merged)
+            .collect()
+            .map_err(polars_err(name))?;
+        self.replace_groups(name, &touched, merged)
+    }
+
+    /// The view after `incoming` was upserted on `keys` into `previous`, giving `base`.
+    fn upserted(
+        &self,
+        name: &str,
+        previous: &DataFrame,
+        incoming: &DataFrame,
+        keys: &[String],
+        base: &DataFrameArtifact,
+    ) -> Result<DataFrame, Status> {
+        let row_ids = |df: &DataFrame| -> Result<Vec<String>, Status> {
+            group_ids(&df.select(keys).map_err(polars_err(name))?).map_err(polars_err(name))
+        };
+        let upserted: HashSet<_> = row_ids(incoming)?.into_iter().collect();
+        let base = base.declared_dataframe()?;
+
+        // The groups the updated rows leave, and those they and the inserted rows join.
+        let mut touched = HashSet::new();
+        for df in [previous, &base] {
+            let rows = df
+                .filter(&membership(&row_ids(df)?, &upserted))
+                .map_err(polars_err(name))?;
+            touched.extend(self.row_group_ids(name, &rows)?);
+        }
+        self.recompute_groups(name, &base, &touched)
+    }
+
+    /// Moves the view to the version of `base` its maintenance computed, or records why it could
+    /// not follow.
+    fn maintain(
+        &mut self,
+        name: &str,
+        base: &DataFrameArtifact,
+        update: impl FnOnce(&View) -> Result<DataFrame, Status>,
+    ) {
+        if base.declared_schema() != self.base_schema {
+            warn!(
+                "View {name} is invalid: the schema of dataframe {} changed",
+                self.base
+            );
+            self.state = ViewState::Invalid {
+                reason: format!("The schema of dataframe {} changed", self.base),
+            };
+            return;
+        }
+        let reason = match &self.state {
+            ViewState::Invalid { .. } => return,
+            ViewState::Stale { .. } => None,
+            ViewState::Fresh if self.base_version + 1 != base.version => Some(format!(
+                "Version {} of dataframe {} was missed",
+                self.base_version + 1,
+                self.base
+            )),
+            ViewState::Fresh => match update(self) {
+                Ok(data) => {
+                    self.data = data;
+                    self.base_version = base.version;
+                    return;
+                }
+                Err(e) => Some(e.message().to_string()),
+            },
+        };
+        if let Some(reason) = reason {
+            warn!("View {name} is stale: {reason}");
+            self.state = ViewState::Stale {
+                since: Instant::now(),
+                reason,
+            };
+        }
+    }
+
+    /// How long the view has been behind `base`, if it can answer queries on it.
+    fn usable_on(&self, base: &DataFrameArtifact) -> Result<Option<Duration>, String> {
+        if base.declared_schema() != self.base_schema {
+            return Err(format!("the schema of dataframe {} changed", self.base));
+        }
+        match &self.state {
+            ViewState::Fresh if self.base_version == base.version => Ok(None),
+            ViewState::Fresh => Err(format!("it missed a version of dataframe {}", self.base)),
+            ViewState::Stale { since, .. } if since.elapsed() <= self.max_staleness => {
+                Ok(Some(since.elapsed()))
+            }
+            ViewState::Stale { .. } => Err(String::from("it is stale beyond its bound")),
+            ViewState::Invalid { reason } => Err(reason.clone()),
+        }
+    }
+}
+
+/// The registered materialized views.
+///
+/// Callers that also lock the dataframes must lock them first.
+#[derive(Debug, Default)]
+pub struct ViewRegistry {
+    views: RwLock<HashMap<String, View>>,
+}
+
+impl ViewRegistry {
+    pub fn register(
+        &self,
+        name: &str,
+        plan: &CompositePlan,
+        max_staleness: Duration,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<ViewInfo, Status> {
+        if self.views.read().unwrap().contains_key(name) {
+            return Err(Status::already_exists(format!(
+                "View {name} is already registered"
+            )));
+        }
+        // Computed without holding the views, which queries read.
+        let view = View::build(name, plan, max_staleness, dfs)?;
+        let info = view.info(name);
+        let mut views = self.views.write().unwrap();
+        if views.contains_key(name) {
+            return Err(Status::already_exists(format!(
+                "View {name} is already registered"
+            )));
+        }
+        views.insert(name.to_string(), view);
+        Ok(info)
+    }
+
+    /// Recomputes a view from its base, which brings it back to the fresh state.
+    pub fn refresh(
+        &self,
+        name: &str,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<ViewInfo, Status> {
+        let (definition, max_staleness) = {
+            let views = self.views.read().unwrap();
+            let view = views.get(name).ok_or_else(|| not_found(name))?;
+            (view.definition.clone(), view.max_staleness)
+        };
+        let plan: CompositePlan = serde_json::from_str(&definition)
+            .map_err(|e| Status::internal(format!("Could not parse view {name}: {e}")))?;
+        let view = View::build(name, &plan, max_staleness, dfs)?;
+        let info = view.info(name);
+        let mut views = self.views.write().unwrap();
+        *views.get_mut(name).ok_or_else(|| not_found(name))? = view;
+        Ok(info)
+    }
+
+    pub fn view(&self, name: &str) -> Result<ViewInfo, Status> {
+        let views = self.views.read().unwrap();
+        Ok(views.get(name).ok_or_else(|| not_found(name))?.info(name))
+    }
+
+    /// Updates the views of `identifier` after `delta`, with its declared dtypes, was appended
+    /// to it.
+    pub fn appended(&self, identifier: &str, delta: &DataFrame, base: &DataFrameArtifact) {
+        let mut views = self.views.write().unwrap();
+        for (name, view) in views.iter_mut().filter(|(_, v)| v.base == identifier) {
+            view.maintain(name, base, |view| view.appended(name, delta, base));
+        }
+    }
+
+    /// Updates the views of `identifier` after `incoming` was upserted into `previous` on `keys`.
+    pub fn upserted(
+        &self,
+        identifier: &str,
+        previous: &DataFrame,
+        incoming: &DataFrame,
+        keys: &[String],
+        base: &DataFrameArtifact,
+    ) {
+        let mut views = self.views.write().unwrap();
+        for (name, view) in views.iter_mut().filter(|(_, v)| v.base == identifier) {
+            view.maintain(name, base, |view| {
+                view.upserted(name, previous, incoming, keys, base)
+            });
+        }
+    }
+
+    pub fn has_views(&self, identifier: &str) -> bool {
+        let views = self.views.read().unwrap();
+        views.values().any(|view| view.base == identifier)
+    }
+
+    /// Drops the views of `identifier`, returning their names.
+    pub fn drop_views_of(&self, identifier: &str) -> Vec<String> {
+        let mut views = self.views.write().unwrap();
+        let dropped: Vec<_> = views
+            .iter()
+            .filter(|(_, view)| view.base == identifier)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in dropped.iter() {
+            views.remove(name);
+        }
+        dropped
+    }
+
+    /// Reads the result of `plan` from a view, if one matches it and can answer it.
+    pub fn answer(
+        &self,
+        plan: &CompositePlan,
+        dfs: &HashMap<String, DataFrameArtifact>,
+    ) -> Result<Option<ViewAnswer>, Status> {
+        let views = self.views.read().unwrap();
+        let (base, raw, skip_nan) = match plan.single_plan() {
+            Some(single) if !plan.nan_as_null() && !views.is_empty() => single,
+            _ => return Ok(None),
+        };
+        let signature = signature(raw, skip_nan, plan.semantics_version())?;
+        let (name, view) = match views
+            .iter()
+            .find(|(_, view)| view.base == base && view.signature == signature)
+        {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let artifact = match dfs.get(base) {
+            Some(artifact) => artifact,
+            None => return Ok(None),
+        };
+        let stale_for = match view.usable_on(artifact) {
+            Ok(stale_for) => stale_for,
+            Err(reason) => {
+                info!("View {name} matches the query but is not used: {reason}");
+                return Ok(None);
+            }
+        };
+        // The policy checks need the size of the smallest group.
+        if view.data.height() == 0 {
+            return Ok(None);
+        }
+
+        let agg_size = if view.hides_rows {
+            view.data
+                .column(VIEW_ROWS)
+                .and_then(|s| s.cast(&DataType::UInt64))
+                .map_err(polars_err(name))?
+                .u64()
+                .map_err(polars_err(name))?
+                .min()
+        } else {
+            None
+        };
+        let placeholder = DataFrame::new_no_checks(
+            view.base_schema
+                .iter()
+                .map(|(name, dtype)| Series::new_empty(name, dtype))
+                .collect(),
+        );
+        Ok(Some(ViewAnswer {
+            view: name.clone(),
+            base: view.base.clone(),
+            base_version: view.base_version,
+            dataframe: view.data.clone(),
+            agg_size,
+            plan: placeholder
+                .lazy()
+                .groupby(view.keys.clone())
+                .agg(view.aggs.clone())
+                .logical_plan,
+            stale_for,
+        }))
+    }
+}
+
+fn not_found(name: &str) -> Status {
+    Status::not_found(format!("Could not find view: name={name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::Policy;
+    use crate::composite_plan::CompositePlanSegment;
+    use crate::BastionLabPolars;
+    use bastionlab_common::config::BastionLabConfig;
+    use bastionlab_common::session::SessionManager;
+    use rand::prelude::*;
+    use std::sync::Arc;
+
+    fn polars() -> BastionLabPolars {
+        let config: BastionLabConfig = serde_json::from_str(
+            r#"{
+                "client_to_enclave_untrusted_url": "https://0.0.0.0:50056",
+                "public_keys_directory": "keys/",
+                "session_expiry_in_secs": 3600
+            }"#,
+        )
+        .unwrap();
+        BastionLabPolars::new(Arc::new(SessionManager::new(None, 3600)), &config)
+    }
+
+    /// Events of a few users, with nulls.
+    fn events(rng: &mut StdRng, rows: usize) -> DataFrame {
+        let users: Vec<_> = (0..rows)
+            .map(|_| format!("user{}", rng.gen_range(0..8)))
+            .collect();
+        let kinds: Vec<_> = (0..rows).map(|_| rng.gen_range(0..3i64)).collect();
+        let amounts: Vec<_> = (0..rows)
+            .map(|_| rng.gen_bool(0.9).then(|| rng.gen_range(-50..100i64)))
+            .collect();
+        let ids: Vec<_> = (0..rows).map(|_| rng.gen::<u32>() as i64).collect();
+        df! { "id" => ids, "user" => users, "kind" => kinds, "amount" => amounts }.unwrap()
+    }
+
+    fn group_by(identifier: &str, aggs: Vec<Expr>) -> CompositePlan {
+        let placeholder = events(&mut StdRng::seed_from_u64(0), 0);
+        CompositePlan::new(vec![
+            CompositePlanSegment::EntryPointPlanSegment {
+                identifier: identifier.to_string(),
+            },
+            CompositePlanSegment::PolarsPlanSegment {
+                plan: placeholder
+                    .lazy()
+                    .groupby([col("user"), col("kind")])
+                    .agg(aggs)
+                    .logical_plan,
+                skip_nan: false,
+                resources: None,
+            },
+        ])
+    }
+
+    fn decomposable() -> Vec<Expr> {
+        vec![
+            col("amount").sum().alias("total"),
+            col("amount").count().alias("events"),
+            col("amount").min().alias("smallest"),
+            col("amount").max().alias("largest"),
+        ]
+    }
+
+    fn non_decomposable() -> Vec<Expr> {
+        vec![
+            col("amount").mean().alias("average"),
+            col("amount").n_unique().alias("distinct"),
+            col("amount").sum().alias("total"),
+        ]
+    }
+
+    fn sorted(df: &DataFrame) -> DataFrame {
+        df.sort(["user", "kind"], false).unwrap()
+    }
+
+    /// Checks that the maintained view is what computing it from scratch gives.
+    fn assert_matches_recompute(polars: &BastionLabPolars, name: &str) {
+        let dfs = polars.dataframes.read().unwrap();
+        let views = polars.views.views.read().unwrap();
+        let view = &views[name];
+        let base = dfs[&view.base].declared_dataframe().unwrap();
+        let expected = view.aggregate(name, base).unwrap();
+        assert!(matches!(view.state, ViewState::Fresh));
+        assert_eq!(view.base_version, dfs[&view.base].version);
+        assert_eq!(view.data.schema(), expected.schema());
+        assert!(
+            sorted(&view.data).frame_equal_missing(&sorted(&expected)),
+            "{name} diverged:\n{}\n{}",
+            sorted(&view.data),
+            sorted(&expected)
+        );
+    }
+
+    #[test]
+    fn views_match_full_recomputation_after_random_appends() {
+        for seed in 0..5 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let polars = polars();
+            let identifier = polars.insert_df(DataFrameArtifact::new(
+                events(&mut rng, 200),
+                Policy::allow_by_default(),
+                Vec::new(),
+            ));
+            for (name, aggs) in [
+                ("merged", decomposable()),
+                ("recomputed", non_decomposable()),
+            ] {
+                let info = polars
+                    .register_view(name, &group_by(&identifier, aggs), Duration::ZERO)
+                    .unwrap();
+                assert_eq!(info.incremental, name == "merged");
+            }
+
+            for _ in 0..20 {
+                let rows = rng.gen_range(0..30);
+                polars
+                    .append_df(&identifier, events(&mut rng, rows))
+                    .unwrap();
+                assert_matches_recompute(&polars, "merged");
+                assert_matches_recompute(&polars, "recomputed");
+            }
+        }
+    }
+
+    #[test]
+    fn views_match_full_recomputation_after_random_upserts() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let polars = polars();
+        let initial = events(&mut rng, 200);
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            initial.clone(),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        polars
+            .register_view(
+                "merged",
+                &group_by(&identifier, decomposable()),
+                Duration::ZERO,
+            )
+            .unwrap();
+        let keys = [String::from("id")];
+
+        for _ in 0..20 {
+            // Updates of existing rows, which may move them to another group, and new rows.
+            let current = polars.get_df_unchecked(&identifier).unwrap();
+            let offset = rng.gen_range(0..current.height() - 10) as i64;
+            let updated = current.slice(offset, 10);
+            let moved = events(&mut rng, 10);
+            let updated = DataFrame::new(vec![
+                updated.column("id").unwrap().clone(),
+                moved.column("user").unwrap().clone(),
+                moved.column("kind").unwrap().clone(),
+                moved.column("amount").unwrap().clone(),
+            ])
+            .unwrap();
+            let incoming = updated.vstack(&events(&mut rng, 5)).unwrap();
+            polars.upsert_df(&identifier, incoming, &keys).unwrap();
+            assert_matches_recompute(&polars, "merged");
+        }
+    }
+
+    #[test]
+    fn matching_queries_are_answered_from_views() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let polars = polars();
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            events(&mut rng, 300),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        polars
+            .register_view(
+                "totals",
+                &group_by(&identifier, decomposable()),
+                Duration::ZERO,
+            )
+            .unwrap();
+        polars.append_df(&identifier, events(&mut rng, 50)).unwrap();
+
+        let from_view = group_by(&identifier, decomposable())
+            .run(&polars, "analyst")
+            .unwrap();
+        assert!(from_view.query_details.contains(&format!(
+            "Answered from materialized view totals (version 1 of {identifier})"
+        )));
+        assert!(from_view
+            .dataframe
+            .get_column_names()
+            .iter()
+            .all(|name| !name.starts_with("__bastionlab_")));
+
+        // Another aggregation runs on the base, and computes the same thing.
+        let from_base = group_by(
+            &identifier,
+            vec![
+                col("amount").sum().alias("total"),
+                col("amount").count().alias("events"),
+                col("amount").min().alias("smallest"),
+                col("amount").max().alias("largest"),
+                col("id").count().alias("rows"),
+            ],
+        )
+        .run(&polars, "analyst")
+        .unwrap();
+        assert!(!from_base.query_details.contains("materialized view"));
+        let from_base = from_base.dataframe.drop("rows").unwrap();
+        assert!(sorted(&from_view.dataframe).frame_equal_missing(&sorted(&from_base)));
+    }
+
+    #[test]
+    fn stale_views_answer_within_their_bound_only() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let polars = polars();
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            events(&mut rng, 100),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        let plan = || group_by(&identifier, decomposable());
+        polars
+            .register_view("bounded", &plan(), Duration::from_secs(3600))
+            .unwrap();
+
+        // A version the view did not see, as if its maintenance had failed.
+        polars
+            .views
+            .views
+            .write()
+            .unwrap()
+            .get_mut("bounded")
+            .unwrap()
+            .base_version = 7;
+        polars.append_df(&identifier, events(&mut rng, 10)).unwrap();
+        let info = polars.view("bounded").unwrap();
+        assert_eq!(info.state.name(), "stale");
+        assert!(info.state.reason().contains("was missed"));
+        let answer = plan().run(&polars, "analyst").unwrap();
+        assert!(answer.query_details.contains(", stale for 0s"));
+
+        polars
+            .views
+            .views
+            .write()
+            .unwrap()
+            .get_mut("bounded")
+            .unwrap()
+            .max_staleness = Duration::ZERO;
+        std::thread::sleep(Duration::from_millis(10));
+        let answer = plan().run(&polars, "analyst").unwrap();
+        assert!(!answer.query_details.contains("materialized view"));
+
+        let info = polars.refresh_view("bounded").unwrap();
+        assert_eq!(info.state.name(), "fresh");
+        assert_eq!(info.base_version, 1);
+        assert_matches_recompute(&polars, "bounded");
+    }
+
+    #[test]
+    fn schema_changes_invalidate_views() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let polars = polars();
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            events(&mut rng, 100),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        polars
+            .register_view(
+                "totals",
+                &group_by(&identifier, decomposable()),
+                Duration::MAX,
+            )
+            .unwrap();
+
+        let widened = events(&mut rng, 100)
+            .lazy()
+            .with_column(col("amount").cast(DataType::Float64))
+            .collect()
+            .unwrap();
+        let base = DataFrameArtifact::new(widened, Policy::allow_by_default(), Vec::new());
+        polars
+            .views
+            .appended(&identifier, &events(&mut rng, 1), &base);
+        let info = polars.view("totals").unwrap();
+        assert_eq!(info.state.name(), "invalid");
+        assert!(info.state.reason().contains("schema"));
+        let answer = group_by(&identifier, decomposable())
+            .run(&polars, "analyst")
+            .unwrap();
+        assert!(!answer.query_details.contains("materialized view"));
+    }
+
+    #[test]
+    fn only_plain_group_bys_can_be_views() {
+        let polars = polars();
+        let mut rng = StdRng::seed_from_u64(4);
+        let df = events(&mut rng, 10);
+        let identifier = polars.insert_df(DataFrameArtifact::new(
+            df.clone(),
+            Policy::allow_by_default(),
+            Vec::new(),
+        ));
+        let plan = |plan: LogicalPlan| {
+            CompositePlan::new(vec![
+                CompositePlanSegment::EntryPointPlanSegment {
+                    identifier: identifier.clone(),
+                },
+                CompositePlanSegment::PolarsPlanSegment {
+                    plan,
+                    skip_nan: false,
+                    resources: None,
+                },
+            ])
+        };
+        let placeholder = df.head(Some(0)).lazy();
+
+        let filtered = placeholder.clone().filter(col("kind").eq(lit(1i64)));
+        let ordered = placeholder
+            .clone()
+            .groupby_stable([col("user")])
+            .agg([col("amount").sum()]);
+        for rejected in [filtered.logical_plan, ordered.logical_plan] {
+            let err = polars
+                .register_view("view", &plan(rejected), Duration::ZERO)
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        }
+        let plain = placeholder
+            .groupby([col("user")])
+            .agg([col("amount").sum()]);
+        polars
+            .register_view("view", &plan(plain.logical_plan), Duration::ZERO)
+            .unwrap();
+        let err = polars
+            .register_view(
+                "view",
+                &group_by(&identifier, decomposable()),
+                Duration::ZERO,
+            )
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::AlreadyExists);
+
+        // Views go away with their base.
+        polars.delete_dfs(&identifier).unwrap();
+        assert!(polars.view("view").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+//! Export waivers: one-time exceptions to the masking and watermarking of a fetch.
+//!
+//! Some results must leave the server pristine, such as the tables of a regulatory submission.
+//! Rather than editing the policy, an owner of the data a result was computed from issues a waiver
+//! for it: bound to the result, to the identity that will fetch it and to an expiry, with a
+//! justification. Fetches presenting the waiver are released without masks nor watermarks, and
+//! otherwise go through the policy as usual: the waiver neither approves nor unblocks anything,
+//! and blacklisted columns stay sanitized.
+//!
+//! Waivers are single-use. Their use is logged along with its justification and the content hash
+//! of what was released, and listed in the usage report of their issuer. Waivers are kept in
+//! memory: unused ones are lost on restart, which only means issuing them again.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::polars_proto;
+
+/// Longest justification, in characters.
+pub const MAX_JUSTIFICATION: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waiver {
+    pub id: String,
+    /// The result the waiver covers.
+    pub identifier: String,
+    /// The identity allowed to fetch it.
+    pub recipient: String,
+    pub issuer: String,
+    pub justification: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// When it was used, with the content hash of what it released.
+    pub used: Option<(u64, String)>,
+}
+
+impl Waiver {
+    pub fn to_proto(&self) -> polars_proto::ExportWaiver {
+        let (used_at, content_hash) = self.used.clone().unwrap_or_default();
+        polars_proto::ExportWaiver {
+            id: self.id.clone(),
+            identifier: self.identifier.clone(),
+            recipient: self.recipient.clone(),
+            issuer: self.issuer.clone(),
+            justification: self.justification.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            used_at,
+            content_hash,
+        }
+    }
+
+    /// Checks that `recipient` may use the waiver to fetch `identifier` at `now`.
+    fn check(&self, identifier: &str, recipient: &str, now: u64) -> Result<(), Status> {
+        let id = &self.id;
+        if self.identifier != identifier {
+            return Err(Status::permission_denied(format!(
+                "Export waiver {id} covers {}, not {identifier}",
+                self.identifier
+            )));
+        }
+        if self.recipient != recipient {
+            return Err(Status::permission_denied(format!(
+                "Export waiver {id} was issued to {}, not {recipient}",
+                self.recipient
+            )));
+        }
+        if let Some((used_at, _)) = &self.used {
+            return Err(Status::failed_precondition(format!(
+                "Export waiver {id} was already used at {used_at}"
+            )));
+        }
+        if self.expires_at <= now {
+            return Err(Status::failed_precondition(format!(
+                "Export waiver {id} expired at {}",
+                self.expires_at
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Waivers {
+    waivers: RwLock<HashMap<String, Waiver>>,
+}
+
+impl Waivers {
+    /// Issues a waiver for `identifier` to `recipient`, once the issuer was checked to own its
+    /// data.
+    pub fn issue(
+        &self,
+        identifier: &str,
+        recipient: &str,
+        issuer: &str,
+        justification: &str,
+        expires_at: u64,
+        now: u64,
+    ) -> Result<Waiver, Status> {
+        let justification: String = justification
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\n')
+            .take(MAX_JUSTIFICATION)
+            .collect();
+        if justification.trim().is_empty() {
+            return Err(Status::invalid_argument(
+                "Could not issue export waiver: it needs a justification",
+            ));
+        }
+        if recipient.is_empty() {
+            return Err(Status::invalid_argument(
+                "Could not issue export waiver: it needs a recipient",
+            ));
+        }
+        if expires_at <= now {
+            return Err(Status::invalid_argument(format!(
+                "Could not issue export waiver: its expiry {expires_at} has passed"
+            )));
+        }
+        let waiver = Waiver {
+            id: Uuid::new_v4().to_string(),
+            identifier: identifier.to_string(),
+            recipient: recipient.to_string(),
+            issuer: issuer.to_string(),
+            justification,
+            created_at: now,
+            expires_at,
+            used: None,
+        };
+        self.waivers
+            .write()
+            .unwrap()
+            .insert(waiver.id.clone(), waiver.clone());
+        Ok(waiver)
+    }
+
+    /// Checks that `recipient` may use waiver `id` to fetch `identifier` at `now`, without using
+    /// it.
+    pub fn check(
+        &self,
+        id: &str,
+        identifier: &str,
+        recipient: &str,
+        now: u64,
+    ) -> Result<(), Status> {
+        let waivers = self.waivers.read().unwrap();
+        let waiver = waivers
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("No export waiver {id}")))?;
+        waiver.check(identifier, recipient, now)
+    }
+
+    /// Uses waiver `id`, which released data of hash `content_hash`.
+    pub fn consume(
+        &self,
+        id: &str,
+        identifier: &str,
+        recipient: &str,
+        content_hash: String,
+        now: u64,
+    ) -> Result<Waiver, Status> {
+        let mut waivers = self.waivers.write().unwrap();
+        let waiver = waivers
+            .get_mut(id)
+            .ok_or_else(|| Status::not_found(format!("No export waiver {id}")))?;
+        waiver.check(identifier, recipient, now)?;
+        waiver.used = Some((now, content_hash));
+        Ok(waiver.clone())
+    }
+
+    /// The waivers `issuer` issued that were used since `since`, restricted to those covering
+    /// `identifier` if set, oldest use first.
+    pub fn used(&self, issuer: &str, identifier: Option<&str>, since: u64) -> Vec<Waiver> {
+        let waivers = self.waivers.read().unwrap();
+        let mut used: Vec<Waiver> = waivers
+            .values()
+            .filter(|waiver| {
+                waiver.issuer == issuer
+                    && identifier.map_or(true, |identifier| waiver.identifier == identifier)
+                    && waiver.used.as_ref().is_some_and(|(at, _)| *at >= since)
+            })
+            .cloned()
+            .collect();
+        used.sort_by_key(|waiver| waiver.used.as_ref().map(|(at, _)| *at));
+        used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn waivers_are_bound_and_single_use() {
+        let waivers = Waivers::default();
+        let err = waivers
+            .issue("result", "analyst", "owner", " \u{7}", 2_000, 1_000)
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert!(waivers
+            .issue("result", "analyst", "owner", "Submission", 1_000, 1_000)
+            .is_err());
+
+        let waiver = waivers
+            .issue("result", "analyst", "owner", "Submission", 2_000, 1_000)
+            .unwrap();
+        let id = &waiver.id;
+        let err = waivers.check(id, "other", "analyst", 1_500).unwrap_err();
+        assert_eq!(
+            err.message(),
+            format!("Export waiver {id} covers result, not other")
+        );
+        let err = waivers.check(id, "result", "intruder", 1_500).unwrap_err();
+        assert_eq!(
+            err.message(),
+            format!("Export waiver {id} was issued to analyst, not intruder")
+        );
+        let err = waivers.check(id, "result", "analyst", 2_000).unwrap_err();
+        assert_eq!(err.message(), format!("Export waiver {id} expired at 2000"));
+        assert_eq!(
+            waivers
+                .check("unknown", "result", "analyst", 1_500)
+                .unwrap_err()
+                .code(),
+            Code::NotFound
+        );
+
+        let used = waivers
+            .consume(id, "result", "analyst", String::from("abc"), 1_500)
+            .unwrap();
+        assert_eq!(used.used, Some((1_500, String::from("abc"))));
+        let err = waivers
+            .consume(id, "result", "analyst", String::from("abc"), 1_600)
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            format!("Export waiver {id} was already used at 1500")
+        );
+        assert_eq!(waivers.used("owner", None, 0), [used.clone()]);
+        assert_eq!(waivers.used("owner", Some("result"), 1_501), []);
+        assert_eq!(waivers.used("someone", None, 0), []);
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+use polars::prelude::*;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::catalog::now_ms;
+use crate::prelude::*;
+
+/// Lattice distance, relative to the step, under which a value is considered to sit on the lattice.
+const LATTICE_TOLERANCE: f64 = 0.01;
+
+/// Lattice indices are only exact below 2^52, larger values are left unmarked.
+const MAX_LATTICE_INDEX: f64 = 4_503_599_627_370_496.0;
+
+/// Watermarking of the numeric columns of a dataset, set in its policy.
+///
+/// Marked values are moved by less than `epsilon`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Watermark {
+    /// Float64 columns that may be perturbed.
+    pub columns: Vec<String>,
+    pub epsilon: f64,
+}
+
+impl Watermark {
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut columns = self.columns.clone();
+        for column in other.columns.iter() {
+            if !columns.contains(column) {
+                columns.push(column.clone());
+            }
+        }
+        Watermark {
+            columns,
+            epsilon: self.epsilon.min(other.epsilon),
+        }
+    }
+}
+
+/// The parameters of a watermarked fetch, kept so that leaked data can be traced back to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchRecord {
+    pub fetch_id: String,
+    pub recipient: String,
+    pub dataset: String,
+    pub columns: Vec<String>,
+    pub epsilon: f64,
+    /// Seconds since the UNIX epoch.
+    pub time: u64,
+}
+
+/// Persists all the watermarked fetches kept, see [`Watermarker::persist_with`].
+type Persist = Box<dyn Fn(&[FetchRecord]) -> Result<(), Status> + Send + Sync>;
+
+#[derive(Default)]
+struct Records {
+    kept: VecDeque<FetchRecord>,
+    /// Records added since startup, the last one included.
+    added: u64,
+}
+
+#[derive(Default)]
+struct Persistence {
+    persist: Option<Persist>,
+    /// Records added since startup that were persisted.
+    persisted: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceScore {
+    pub record: FetchRecord,
+    /// Share of the marked values that carry this fetch's watermark: about 0.5 for unrelated data.
+    pub score: f64,
+    pub values: usize,
+}
+
+fn polars_err(e: PolarsError) -> Status {
+    Status::internal(format!("Polars error while watermarking: {e}"))
+}
+
+/// Embeds recipient-specific watermarks in fetched dataframes and detects them in leaked copies.
+///
+/// Each value `x` of a marked column is snapped to one of the two points of its cell of width
+/// `epsilon` (`[2ks, 2ks + 2s)` with `s = epsilon / 2`): `2ks` or `(2k + 1)s`. The choice is a bit
+/// of `HMAC(fetch key, column || k)`, where the fetch key is derived from the recipient identity and
+/// a fresh fetch id. Since the bit only depends on the value itself, the watermark survives row
+/// subsets, reordering and dropped columns.
+///
+/// The last `capacity` fetches are kept for tracing. With a configured secret, they can be
+/// persisted so that leaks are still traced after a restart: the secret is never persisted, since
+/// whoever reads the persisted state could otherwise strip or forge watermarks.
+pub struct Watermarker {
+    secret: hmac::Key,
+    configured: bool,
+    records: Mutex<Records>,
+    capacity: usize,
+    /// Held while the records are written, outside of the lock of the records.
+    persistence: Mutex<Persistence>,
+}
+
+impl Watermarker {
+    /// Creates a watermarker with a random secret, whose fetches cannot be traced after a restart.
+    pub fn new(capacity: usize) -> Self {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .expect("Could not generate the watermarking secret");
+        Self::from_secret(&secret, false, capacity)
+    }
+
+    /// Creates a watermarker with a configured secret.
+    pub fn with_secret(secret: &[u8], capacity: usize) -> Self {
+        Self::from_secret(secret, true, capacity)
+    }
+
+    /// Creates a watermarker with the secret of `path`, 32 hex-encoded bytes.
+    pub fn from_secret_file(path: &Path, capacity: usize) -> Result<Self, Status> {
+        let secret = fs::read_to_string(path).map_err(|e| {
+            Status::internal(format!(
+                "Could not read the watermarking secret {}: {e}",
+                path.display()
+            ))
+        })?;
+        let secret = hex::decode(secret.trim())
+            .ok()
+            .filter(|secret| secret.len() == 32)
+            .ok_or_else(|| {
+                Status::invalid_argument("The watermarking secret is not 32 hex-encoded bytes")
+            })?;
+        Ok(Self::with_secret(&secret, capacity))
+    }
+
+    fn from_secret(secret: &[u8], configured: bool, capacity: usize) -> Self {
+        Watermarker {
+            secret: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            configured,
+            records: Default::default(),
+            capacity,
+            persistence: Default::default(),
+        }
+    }
+
+    /// Whether the secret was configured, so that fetches can be traced after a restart.
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Loads `records`, those persisted before a restart. Fetches watermarked under another secret
+    /// are no longer traced.
+    pub fn restore(&self, records: Vec<FetchRecord>) {
+        let mut kept = self.records.lock().unwrap();
+        kept.kept.extend(records);
+        while kept.kept.len() > self.capacity {
+            kept.kept.pop_front();
+        }
+    }
+
+    /// Persists the records with `persist` from now on, on every watermarked fetch.
+    pub fn persist_with(
+        &self,
+        persist: impl Fn(&[FetchRecord]) -> Result<(), Status> + Send + Sync + 'static,
+    ) {
+        self.persistence.lock().unwrap().persist = Some(Box::new(persist));
+    }
+
+    fn fetch_key(&self, recipient: &str, fetch_id: &str) -> hmac::Key {
+        let mut ctx = hmac::Context::with_key(&self.secret);
+        ctx.update(recipient.as_bytes());
+        ctx.update(&[0]);
+        ctx.update(fetch_id.as_bytes());
+        hmac::Key::new(hmac::HMAC_SHA256, ctx.sign().as_ref())
+    }
+
+    /// Watermarks `df` for `recipient`, leaving the columns in `exact_columns` untouched.
+    ///
+    /// Returns the record of the fetch, which is also kept for tracing. Nothing is released if the
+    /// record could not be persisted.
+    pub fn apply(
+        &self,
+        df: &mut DataFrame,
+        watermark: &Watermark,
+        exact_columns: &[String],
+        dataset: &str,
+        recipient: &str,
+    ) -> Result<FetchRecord, Status> {
+        if !(watermark.epsilon > 0.0 && watermark.epsilon.is_finite()) {
+            return Err(Status::invalid_argument(
+                "The watermark epsilon must be a positive number",
+            ));
+        }
+
+        let fetch_id = Uuid::new_v4().to_string();
+        let key = self.fetch_key(recipient, &fetch_id);
+        let step = watermark.epsilon / 2.0;
+
+        let mut columns = Vec::new();
+        for name in watermark.columns.iter() {
+            if exact_columns.contains(name) {
+                continue;
+            }
+            let idx = match df.find_idx_by_name(name) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let series = df.get_columns_mut().get_mut(idx).unwrap();
+            if series.dtype() != &DataType::Float64 {
+                continue;
+            }
+            let mut marked: Float64Chunked = series
+                .f64()
+                .map_err(polars_err)?
+                .into_iter()
+                .map(|v| v.map(|x| embed(&key, name, x, step)))
+                .collect();
+            marked.rename(name);
+            *series = marked.into_series();
+            columns.push(name.clone());
+        }
+
+        let record = FetchRecord {
+            fetch_id,
+            recipient: recipient.to_owned(),
+            dataset: dataset.to_owned(),
+            columns,
+            epsilon: watermark.epsilon,
+            time: now_ms() / 1000,
+        };
+        info!(
+            "Watermarked fetch {} of {} for {} (columns {:?}, epsilon {})",
+            record.fetch_id, record.dataset, record.recipient, record.columns, record.epsilon
+        );
+        let added = {
+            let mut records = self.records.lock().unwrap();
+            records.kept.push_back(record.clone());
+            if records.kept.len() > self.capacity {
+                records.kept.pop_front();
+            }
+            records.added += 1;
+            records.added
+        };
+        self.persist(added, &record.fetch_id).map_err(|e| {
+            Status::unavailable(format!(
+                "Could not release the dataframe: its watermark could not be persisted: {}",
+                e.message()
+            ))
+        })?;
+        Ok(record)
+    }
+
+    /// Persists the records kept, unless a write started after the `added`th record was added.
+    ///
+    /// Fetches wait for the write in progress, then the next write covers all of them, so that the
+    /// records are written once per batch of concurrent fetches. If it fails, the record of fetch
+    /// `fetch_id` is dropped.
+    fn persist(&self, added: u64, fetch_id: &str) -> Result<(), Status> {
+        let mut persistence = self.persistence.lock().unwrap();
+        let Some(persist) = persistence.persist.as_ref() else {
+            return Ok(());
+        };
+        if persistence.persisted >= added {
+            return Ok(());
+        }
+        let (records, added): (Vec<_>, _) = {
+            let records = self.records.lock().unwrap();
+            (records.kept.iter().cloned().collect(), records.added)
+        };
+        if let Err(e) = persist(&records) {
+            let mut records = self.records.lock().unwrap();
+            records.kept.retain(|record| record.fetch_id != fetch_id);
+            return Err(e);
+        }
+        persistence.persisted = added;
+        Ok(())
+    }
+
+    /// Scores every watermarked fetch against a suspected leak, best matches first.
+    pub fn trace(&self, leaked: &DataFrame) -> Result<Vec<TraceScore>, Status> {
+        let records = self.records.lock().unwrap().kept.clone();
+        let mut scores = Vec::new();
+        for record in records {
+            let key = self.fetch_key(&record.recipient, &record.fetch_id);
+            let step = record.epsilon / 2.0;
+            let (mut matches, mut values) = (0, 0);
+            for name in record.columns.iter() {
+                let series = match leaked.column(name) {
+                    Ok(series) => series.cast(&DataType::Float64).map_err(polars_err)?,
+                    Err(_) => continue,
+                };
+                for x in series.f64().map_err(polars_err)?.into_iter().flatten() {
+                    if let Some(matched) = detect(&key, name, x, step) {
+                        values += 1;
+                        matches += matched as usize;
+                    }
+                }
+            }
+            if values > 0 {
+                scores.push(TraceScore {
+                    record,
+                    score: matches as f64 / values as f64,
+                    values,
+                });
+            }
+        }
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(scores)
+    }
+}
+
+fn bit(key: &hmac::Key, column: &str, cell: i64) -> i64 {
+    let mut ctx = hmac::Context::with_key(key);
+    ctx.update(column.as_bytes());
+    ctx.update(&[0]);
+    ctx.update(&cell.to_le_bytes());
+    (ctx.sign().as_ref()[0] & 1) as i64
+}
+
+fn embed(key: &hmac::Key, column: &str, x: f64, step: f64) -> f64 {
+    if !x.is_finite() {
+        return x;
+    }
+    let cell = (x / (2.0 * step)).floor();
+    if cell.abs() >= MAX_LATTICE_INDEX / 2.0 {
+        return x;
+    }
+    let cell = cell as i64;
+    (2 * cell + bit(key, column, cell)) as f64 * step
+}
+
+/// Returns whether `x` carries the watermark bit, or `None` if it is not on the lattice.
+fn detect(key: &hmac::Key, column: &str, x: f64, step: f64) -> Option<bool> {
+    let t = x / step;
+    let n = t.round();
+    if !n.is_finite() || (t - n).abs() > LATTICE_TOLERANCE || n.abs() >= MAX_LATTICE_INDEX {
+        return None;
+    }
+    let n = n as i64;
+    Some(n.rem_euclid(2) == bit(key, column, n.div_euclid(2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::mpsc;
+
+    const RECIPIENTS: [&str; 5] = ["alice", "bob", "carol", "dave", "erin"];
+
+    fn dataset() -> DataFrame {
+        let mut rng = StdRng::seed_from_u64(7);
+        let n = 500;
+        df! {
+            "id" => (0..n as i64).collect::<Vec<_>>(),
+            "income" => (0..n).map(|_| rng.gen_range(20_000.0..90_000.0)).collect::<Vec<f64>>(),
+            "bmi" => (0..n).map(|_| rng.gen_range(15.0..40.0)).collect::<Vec<f64>>(),
+            "dose" => (0..n).map(|_| rng.gen_range(0.0..5.0)).collect::<Vec<f64>>(),
+        }
+        .unwrap()
+    }
+
+    fn watermark() -> Watermark {
+        Watermark {
+            columns: vec!["income".into(), "bmi".into(), "dose".into()],
+            epsilon: 0.01,
+        }
+    }
+
+    /// Fetches the dataset once for each recipient and returns the copies.
+    fn fetch_all(marker: &Watermarker) -> Vec<DataFrame> {
+        RECIPIENTS
+            .iter()
+            .map(|recipient| {
+                let mut df = dataset();
+                marker
+                    .apply(
+                        &mut df,
+                        &watermark(),
+                        &["dose".into()],
+                        "patients",
+                        recipient,
+                    )
+                    .unwrap();
+                df
+            })
+            .collect()
+    }
+
+    #[test]
+    fn perturbation_is_bounded_and_spares_exact_columns() {
+        let marker = Watermarker::with_secret(b"secret", 100);
+        let original = dataset();
+        let marked = &fetch_all(&marker)[0];
+        for name in ["income", "bmi"] {
+            let diff = (original.column(name).unwrap() - marked.column(name).unwrap()).abs();
+            assert!(diff.unwrap().max::<f64>().unwrap() < 0.01);
+        }
+        assert!(original
+            .column("dose")
+            .unwrap()
+            .series_equal(marked.column("dose").unwrap()));
+        assert!(original
+            .column("id")
+            .unwrap()
+            .series_equal(marked.column("id").unwrap()));
+    }
+
+    #[test]
+    fn leaks_are_traced_to_their_recipient() {
+        let marker = Watermarker::with_secret(b"secret", 100);
+        let copies = fetch_all(&marker);
+        let leaked = &copies[2];
+
+        let scores = marker.trace(leaked).unwrap();
+        assert_eq!(scores.len(), RECIPIENTS.len());
+        assert_eq!(scores[0].record.recipient, "carol");
+        assert_eq!(scores[0].score, 1.0);
+        assert!(scores[1].score < 0.7);
+    }
+
+    #[test]
+    fn subsets_and_dropped_columns_are_traced() {
+        let marker = Watermarker::with_secret(b"secret", 100);
+        let copies = fetch_all(&marker);
+
+        // A shuffled sample of 40 rows, without the income column.
+        let leaked = copies[3]
+            .sample_n(40, false, true, Some(1))
+            .unwrap()
+            .drop("income")
+            .unwrap();
+        let scores = marker.trace(&leaked).unwrap();
+        assert_eq!(scores[0].record.recipient, "dave");
+        assert_eq!(scores[0].values, 40);
+        assert!(scores[1].score < 0.8);
+    }
+
+    /// Persists the records of `marker` to `stored`.
+    fn persist_to(marker: &Watermarker, stored: &Arc<Mutex<Vec<FetchRecord>>>) {
+        let stored = Arc::clone(stored);
+        marker.persist_with(move |records: &[FetchRecord]| {
+            *stored.lock().unwrap() = records.to_vec();
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn leaks_are_traced_after_a_restart() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let marker = Watermarker::with_secret(b"secret", 100);
+        persist_to(&marker, &stored);
+        let copies = fetch_all(&marker);
+        assert_eq!(stored.lock().unwrap().len(), RECIPIENTS.len());
+
+        let restarted = Watermarker::with_secret(b"secret", 100);
+        restarted.restore(stored.lock().unwrap().clone());
+        let scores = restarted.trace(&copies[1]).unwrap();
+        assert_eq!(scores[0].record.recipient, "bob");
+        assert_eq!(scores[0].score, 1.0);
+        assert!(scores[1].score < 0.7);
+    }
+
+    #[test]
+    fn only_the_last_fetches_are_kept() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let marker = Watermarker::with_secret(b"secret", 3);
+        persist_to(&marker, &stored);
+        let copies = fetch_all(&marker);
+
+        let recipients = |records: &[FetchRecord]| -> Vec<String> {
+            records.iter().map(|r| r.recipient.clone()).collect()
+        };
+        assert_eq!(
+            recipients(&stored.lock().unwrap()),
+            ["carol", "dave", "erin"]
+        );
+        assert!(marker.trace(&copies[0]).unwrap()[0].score < 0.7);
+        assert_eq!(
+            marker.trace(&copies[4]).unwrap()[0].record.recipient,
+            "erin"
+        );
+
+        let smaller = Watermarker::with_secret(b"secret", 2);
+        smaller.restore(stored.lock().unwrap().clone());
+        let traced = smaller.trace(&copies[4]).unwrap();
+        assert_eq!(
+            recipients(&traced.into_iter().map(|s| s.record).collect::<Vec<_>>()),
+            ["erin", "dave"]
+        );
+    }
+
+    #[test]
+    fn fetches_whose_record_is_not_persisted_are_refused() {
+        let marker = Watermarker::with_secret(b"secret", 100);
+        marker.persist_with(|_: &[FetchRecord]| Err(Status::internal("disk full")));
+        let err = marker
+            .apply(&mut dataset(), &watermark(), &[], "patients", "alice")
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert!(marker.trace(&dataset()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_fetches_are_persisted_in_one_write() {
+        let marker = Arc::new(Watermarker::with_secret(b"secret", 100));
+        let (entered, writing) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (entered, released) = (Mutex::new(entered), Mutex::new(released));
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let written = Arc::clone(&writes);
+        marker.persist_with(move |records: &[FetchRecord]| {
+            entered.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            written.lock().unwrap().push(records.len());
+            Ok(())
+        });
+        let fetch = |recipient: &'static str| {
+            let marker = Arc::clone(&marker);
+            std::thread::spawn(move || {
+                marker
+                    .apply(&mut dataset(), &watermark(), &[], "patients", recipient)
+                    .unwrap()
+            })
+        };
+
+        // While the first record is written, tracing goes on and the other fetches add theirs.
+        let mut fetches = vec![fetch("alice")];
+        writing.recv().unwrap();
+        assert_eq!(marker.trace(&dataset()).unwrap().len(), 1);
+        fetches.extend(["bob", "carol"].map(fetch));
+        while marker.records.lock().unwrap().added < 3 {
+            std::thread::yield_now();
+        }
+        release.send(()).unwrap();
+
+        // The next write covers both of them.
+        writing.recv().unwrap();
+        release.send(()).unwrap();
+        for fetch in fetches {
+            fetch.join().unwrap();
+        }
+        assert_eq!(*writes.lock().unwrap(), [1, 3]);
+    }
+
+    #[test]
+    fn unmarked_data_is_not_traced() {
+        let marker = Watermarker::with_secret(b"secret", 100);
+        fetch_all(&marker);
+        let scores = marker.trace(&dataset()).unwrap();
+        // Unmarked values almost never sit on the lattice, and match half of the time when they do.
+        assert!(scores.iter().all(|s| s.values < 60 && s.score < 0.8));
+    }
+}
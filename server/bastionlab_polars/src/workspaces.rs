@@ -0,0 +1,321 @@
+//! Analysis workspaces: named sets of dataframes a user handles together.
+//!
+//! Users attach the dataframes they can read, typically uploads they own and results they
+//! produced, to their workspaces and operate on all the members at once: deletion, retention
+//! extension and export manifests. A member an operation fails on is reported along with the
+//! reason, and does not stop the operation on the others.
+//!
+//! Sharing a workspace with a group of users lets them list, read and query the members the owner
+//! of the workspace owns, whatever their onboarding state. The grant is evaluated on every access:
+//! detaching a member, unsharing or deleting the workspace revokes it at once. Deleting a
+//! workspace only detaches its members, unless the deletion cascades to them.
+//!
+//! Workspaces are kept in memory, as families and views are.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::RwLock;
+
+use serde::Serialize;
+use tonic::Status;
+
+/// Longest workspace name, in bytes.
+const MAX_NAME: usize = 128;
+
+#[derive(Debug, Clone)]
+struct Workspace {
+    owner: String,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    members: BTreeSet<String>,
+    shared_with: BTreeSet<String>,
+}
+
+/// A workspace, as returned to its owner and the users it is shared with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkspaceInfo {
+    pub name: String,
+    pub owner: String,
+    pub created_at: u64,
+    pub members: Vec<String>,
+    pub shared_with: Vec<String>,
+}
+
+impl Workspace {
+    fn info(&self, name: &str) -> WorkspaceInfo {
+        WorkspaceInfo {
+            name: name.to_string(),
+            owner: self.owner.clone(),
+            created_at: self.created_at,
+            members: self.members.iter().cloned().collect(),
+            shared_with: self.shared_with.iter().cloned().collect(),
+        }
+    }
+
+    fn visible_to(&self, user_id: &str) -> bool {
+        self.owner == user_id || self.shared_with.contains(user_id)
+    }
+}
+
+/// How a bulk operation went on each member of a workspace.
+#[derive(Debug, Default)]
+pub struct BulkOutcome {
+    pub succeeded: Vec<String>,
+    pub failures: Vec<(String, Status)>,
+}
+
+impl BulkOutcome {
+    pub fn record(&mut self, identifier: &str, result: Result<(), Status>) {
+        match result {
+            Ok(()) => self.succeeded.push(identifier.to_string()),
+            Err(e) => self.failures.push((identifier.to_string(), e)),
+        }
+    }
+}
+
+/// A member in the export manifest of a workspace.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    pub identifier: String,
+    pub name: String,
+    pub owner: String,
+    pub kind: &'static str,
+    pub rows: u64,
+    /// JSON-encoded declared schema.
+    pub header: String,
+    /// See [`crate::reproducibility::content_hash`].
+    pub content_hash: String,
+}
+
+/// The export manifest of a workspace: what an export of its members holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub workspace: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub members: Vec<ManifestEntry>,
+}
+
+fn not_found(name: &str) -> Status {
+    Status::not_found(format!("Could not find workspace: {name}"))
+}
+
+#[derive(Default)]
+pub struct WorkspaceRegistry {
+    workspaces: RwLock<BTreeMap<String, Workspace>>,
+}
+
+impl WorkspaceRegistry {
+    pub fn create(&self, name: &str, owner: &str, now: u64) -> Result<WorkspaceInfo, Status> {
+        if name.is_empty() || name.len() > MAX_NAME {
+            return Err(Status::invalid_argument(format!(
+                "Workspace names are 1 to {MAX_NAME} bytes long"
+            )));
+        }
+        let mut workspaces = self.workspaces.write().unwrap();
+        if workspaces.contains_key(name) {
+            return Err(Status::already_exists(format!(
+                "Workspace {name} already exists"
+            )));
+        }
+        let workspace = Workspace {
+            owner: owner.to_string(),
+            created_at: now,
+            members: BTreeSet::new(),
+            shared_with: BTreeSet::new(),
+        };
+        let info = workspace.info(name);
+        workspaces.insert(name.to_string(), workspace);
+        Ok(info)
+    }
+
+    /// The workspace `name`, which only its owner and the users it is shared with can see.
+    pub fn get(&self, name: &str, user_id: &str) -> Result<WorkspaceInfo, Status> {
+        let workspaces = self.workspaces.read().unwrap();
+        match workspaces.get(name) {
+            Some(workspace) if workspace.visible_to(user_id) => Ok(workspace.info(name)),
+            _ => Err(not_found(name)),
+        }
+    }
+
+    /// The workspace `name`, failing unless `user_id` owns it.
+    pub fn owned(&self, name: &str, user_id: &str) -> Result<WorkspaceInfo, Status> {
+        self.modify(name, user_id, |_| Ok(()))
+    }
+
+    /// The workspaces `user_id` owns or that are shared with them, by name.
+    pub fn list(&self, user_id: &str) -> Vec<WorkspaceInfo> {
+        let workspaces = self.workspaces.read().unwrap();
+        workspaces
+            .iter()
+            .filter(|(_, workspace)| workspace.visible_to(user_id))
+            .map(|(name, workspace)| workspace.info(name))
+            .collect()
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        user_id: &str,
+        f: impl FnOnce(&mut Workspace) -> Result<(), Status>,
+    ) -> Result<WorkspaceInfo, Status> {
+        let mut workspaces = self.workspaces.write().unwrap();
+        let workspace = match workspaces.get_mut(name) {
+            Some(workspace) if workspace.visible_to(user_id) => workspace,
+            _ => return Err(not_found(name)),
+        };
+        if workspace.owner != user_id {
+            return Err(Status::permission_denied(format!(
+                "Only the owner of workspace {name} can modify it"
+            )));
+        }
+        f(workspace)?;
+        Ok(workspace.info(name))
+    }
+
+    /// Attaches `identifiers`, which the caller checked `user_id` can read.
+    pub fn attach(
+        &self,
+        name: &str,
+        user_id: &str,
+        identifiers: &[String],
+    ) -> Result<WorkspaceInfo, Status> {
+        self.modify(name, user_id, |workspace| {
+            workspace.members.extend(identifiers.iter().cloned());
+            Ok(())
+        })
+    }
+
+    pub fn detach(
+        &self,
+        name: &str,
+        user_id: &str,
+        identifiers: &[String],
+    ) -> Result<WorkspaceInfo, Status> {
+        self.modify(name, user_id, |workspace| {
+            for identifier in identifiers {
+                workspace.members.remove(identifier);
+            }
+            Ok(())
+        })
+    }
+
+    /// Shares the workspace with `users` only, or with no one if empty.
+    pub fn share(
+        &self,
+        name: &str,
+        user_id: &str,
+        users: &[String],
+    ) -> Result<WorkspaceInfo, Status> {
+        self.modify(name, user_id, |workspace| {
+            workspace.shared_with = users
+                .iter()
+                .filter(|user| user.as_str() != user_id)
+                .cloned()
+                .collect();
+            Ok(())
+        })
+    }
+
+    /// Deletes the workspace, returning it with the members it had.
+    pub fn remove(&self, name: &str, user_id: &str) -> Result<WorkspaceInfo, Status> {
+        let info = self.owned(name, user_id)?;
+        self.workspaces.write().unwrap().remove(name);
+        Ok(info)
+    }
+
+    /// Whether a workspace shared with `user_id` grants them access to `identifier`, owned by
+    /// `owner`: the owner of the workspace must own the member too.
+    pub fn grants(&self, identifier: &str, owner: &str, user_id: &str) -> bool {
+        let workspaces = self.workspaces.read().unwrap();
+        workspaces.values().any(|workspace| {
+            workspace.owner == owner
+                && workspace.shared_with.contains(user_id)
+                && workspace.members.contains(identifier)
+        })
+    }
+
+    /// Detaches deleted dataframe `identifier` from every workspace.
+    pub fn drop_member(&self, identifier: &str) {
+        let mut workspaces = self.workspaces.write().unwrap();
+        for workspace in workspaces.values_mut() {
+            workspace.members.remove(identifier);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(identifiers: &[&str]) -> Vec<String> {
+        identifiers.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn grants_follow_membership_and_sharing() {
+        let registry = WorkspaceRegistry::default();
+        registry.create("churn", "alice", 0).unwrap();
+        registry
+            .attach("churn", "alice", &ids(&["mine", "theirs"]))
+            .unwrap();
+        assert!(!registry.grants("mine", "alice", "bob"));
+
+        registry.share("churn", "alice", &ids(&["bob"])).unwrap();
+        assert!(registry.grants("mine", "alice", "bob"));
+        // Members owned by others are not the owner's to share.
+        assert!(!registry.grants("theirs", "carol", "bob"));
+        assert!(!registry.grants("mine", "alice", "carol"));
+        assert_eq!(registry.list("bob").len(), 1);
+
+        registry.detach("churn", "alice", &ids(&["mine"])).unwrap();
+        assert!(!registry.grants("mine", "alice", "bob"));
+
+        registry.attach("churn", "alice", &ids(&["mine"])).unwrap();
+        registry.share("churn", "alice", &[]).unwrap();
+        assert!(!registry.grants("mine", "alice", "bob"));
+        assert_eq!(
+            registry.get("churn", "bob").unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+
+        registry.share("churn", "alice", &ids(&["bob"])).unwrap();
+        registry.drop_member("mine");
+        assert!(!registry.grants("mine", "alice", "bob"));
+
+        registry.attach("churn", "alice", &ids(&["mine"])).unwrap();
+        let removed = registry.remove("churn", "alice").unwrap();
+        assert_eq!(removed.members, ["mine", "theirs"]);
+        assert!(!registry.grants("mine", "alice", "bob"));
+    }
+
+    #[test]
+    fn only_owners_modify_workspaces() {
+        let registry = WorkspaceRegistry::default();
+        registry.create("churn", "alice", 0).unwrap();
+        assert_eq!(
+            registry.create("churn", "bob", 0).unwrap_err().code(),
+            tonic::Code::AlreadyExists
+        );
+        assert_eq!(
+            registry
+                .attach("churn", "bob", &ids(&["x"]))
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+        registry.share("churn", "alice", &ids(&["bob"])).unwrap();
+        assert_eq!(
+            registry
+                .attach("churn", "bob", &ids(&["x"]))
+                .unwrap_err()
+                .code(),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            registry.remove("churn", "bob").unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+        assert!(registry.get("churn", "bob").is_ok());
+        assert!(registry.create("", "alice", 0).is_err());
+    }
+}
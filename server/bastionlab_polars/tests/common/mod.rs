@@ -0,0 +1 @@
+pub mod peak_alloc;
@@ -0,0 +1,65 @@
+//! A global allocator keeping track of the live and peak allocated bytes.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(size: usize) {
+    let now = CURRENT.fetch_add(size, Ordering::SeqCst) + size;
+    PEAK.fetch_max(now, Ordering::SeqCst);
+}
+
+fn shrink(size: usize) {
+    CURRENT.fetch_sub(size, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            // Both blocks may be live while the data is moved.
+            grow(new_size);
+            shrink(layout.size());
+        }
+        new
+    }
+}
+
+/// Bytes currently allocated.
+pub fn current() -> usize {
+    CURRENT.load(Ordering::SeqCst)
+}
+
+/// Highest value of [`current`] since the last [`reset_peak`].
+pub fn peak() -> usize {
+    PEAK.load(Ordering::SeqCst)
+}
+
+pub fn reset_peak() {
+    PEAK.store(current(), Ordering::SeqCst);
+}
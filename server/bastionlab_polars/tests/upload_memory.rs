@@ -0,0 +1,106 @@
+mod common;
+
+use std::sync::Mutex;
+
+use bastionlab_polars::access_control::Policy;
+use bastionlab_polars::polars_proto::SendChunk;
+use bastionlab_polars::serialization::{
+    column_upload_chunks, dataframe_ser_helper, upload_chunks, UploadAssembler,
+};
+use bastionlab_polars::DataFrameArtifact;
+use common::peak_alloc::{self, PeakAlloc};
+use polars::prelude::*;
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+/// Allocations not proportional to the data: chunk copies, decoders, etc.
+const SLACK: usize = 1024 * 1024;
+
+/// The allocation counters are global, so measurements must not overlap.
+static MEASURING: Mutex<()> = Mutex::new(());
+
+/// A frame of about `size` bytes over columns of different types and sizes.
+fn frame(size: usize) -> DataFrame {
+    let rows = size / 32;
+    df! {
+        "id" => (0..rows as i64).collect::<Vec<_>>(),
+        "value" => (0..rows).map(|i| i as f64 * 0.5).collect::<Vec<_>>(),
+        "label" => (0..rows).map(|i| format!("{:03}", i % 1000)).collect::<Vec<_>>(),
+        "flag" => (0..rows).map(|i| i % 3 == 0).collect::<Vec<_>>(),
+    }
+    .unwrap()
+}
+
+/// Reassembles the upload as the server does, returning the memory used on top of the decoded
+/// dataframe at the peak.
+fn peak_overhead(chunks: &[SendChunk], expected: &DataFrame) -> usize {
+    peak_alloc::reset_peak();
+    let base = peak_alloc::current();
+
+    let mut assembler = UploadAssembler::new();
+    for chunk in chunks {
+        // The server receives each chunk in a freshly allocated message.
+        assembler.push(chunk.clone()).unwrap();
+    }
+    let upload = assembler.finish().unwrap();
+
+    let decoded = peak_alloc::current() - base;
+    let overhead = peak_alloc::peak() - base - decoded;
+    assert!(upload.dataframe.frame_equal(expected));
+    overhead
+}
+
+fn check_upload_overhead(size: usize) {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+    let df = frame(size);
+    let policy = Policy::allow_by_default();
+
+    let chunks = column_upload_chunks(&df, &policy, Vec::new(), None).unwrap();
+    let payload: usize = chunks.iter().map(|c| c.data.len()).sum();
+    let largest = *chunks[0].column_lengths.iter().max().unwrap() as usize;
+    let overhead = peak_overhead(&chunks, &df);
+    assert!(
+        overhead <= largest + SLACK,
+        "Column upload of {payload} bytes used {overhead} extra bytes, the largest column is {largest} bytes"
+    );
+    drop(chunks);
+
+    // The buffered IPC path keeps the whole payload alive while decoding.
+    let buf = dataframe_ser_helper(&mut df.clone()).unwrap();
+    let chunks = upload_chunks(&buf, &policy, Vec::new(), None).unwrap();
+    drop(buf);
+    assert!(peak_overhead(&chunks, &df) > largest + SLACK);
+}
+
+/// Query segments and fetches read stored dataframes through `declared_dataframe`: the copy must
+/// share the columns of the stored one rather than duplicate them.
+#[test]
+fn reading_a_stored_dataframe_does_not_copy_it() {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+    let df = frame(64 * 1024 * 1024);
+    let artifact = DataFrameArtifact::new(df.clone(), Policy::allow_by_default(), Vec::new());
+
+    let base = peak_alloc::current();
+    peak_alloc::reset_peak();
+    let read = artifact.declared_dataframe().unwrap();
+    let used = peak_alloc::peak() - base;
+    assert!(
+        used <= SLACK,
+        "Reading a stored dataframe of {} bytes used {used} bytes",
+        df.estimated_size()
+    );
+    assert!(read.frame_equal(&df));
+}
+
+#[test]
+fn column_uploads_only_buffer_one_column() {
+    check_upload_overhead(64 * 1024 * 1024);
+}
+
+/// Needs about 4 GB of memory: run with `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn column_uploads_only_buffer_one_column_at_1gb() {
+    check_upload_overhead(1024 * 1024 * 1024);
+}
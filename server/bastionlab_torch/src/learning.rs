@@ -10,7 +10,7 @@ use bastionlab_learning::optim::{Adam, Optimizer, OptimizerStateType, SGD};
 use bastionlab_learning::procedures::{self, Tester, Trainer};
 use bastionlab_learning::serialization::BinaryModule;
 
-use log::info;
+use tracing::info;
 use std::io::Cursor;
 use std::sync::{Arc, RwLock};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
@@ -1,3 +1,4 @@
+use bastionlab_common::config::MAX_CHUNK_BYTES;
 use bastionlab_common::prelude::*;
 use bastionlab_common::session::SessionManager;
 use bastionlab_common::telemetry::{self, TelemetryEventProps};
@@ -276,7 +277,7 @@ impl TorchService for BastionLabTorch {
             tcherror_to_status(artifact.serialize())?
         };
 
-        Ok(stream_data(serialized, 4_194_285, "Dataset".to_string()).await)
+        Ok(stream_data(serialized, MAX_CHUNK_BYTES, "Dataset".to_string()).await)
     }
 
     async fn fetch_module(
@@ -329,7 +330,7 @@ impl TorchService for BastionLabTorch {
             }
         };
 
-        Ok(stream_data(serialized, 4_194_285, "Model".to_string()).await)
+        Ok(stream_data(serialized, MAX_CHUNK_BYTES, "Model".to_string()).await)
     }
 
     async fn delete_dataset(&self, request: Request<Reference>) -> Result<Response<Empty>, Status> {
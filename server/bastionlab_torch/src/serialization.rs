@@ -1,7 +1,7 @@
 use super::Chunk;
 use crate::storage::Artifact;
 use bastionlab_learning::serialization::SizedObjectsBytes;
-use log::info;
+use tracing::{debug, info};
 use ring::hmac;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
@@ -47,6 +47,9 @@ pub async fn unstream_data(
 }
 
 /// Converts a raw artifact (a header and a binary object) into a stream of chunks to be sent over gRPC.
+///
+/// Chunks hold `chunk_size` bytes of data, the last one the rest. Empty objects are sent as a
+/// single chunk, which carries the header.
 pub async fn stream_data(
     artifact: Artifact<SizedObjectsBytes>,
     chunk_size: usize,
@@ -60,9 +63,14 @@ pub async fn stream_data(
         .unwrap()
         .into();
     let start_time = Instant::now();
+    let name = stream_type.clone();
     tokio::spawn(async move {
-        for (i, bytes) in raw_bytes.chunks(chunk_size).enumerate() {
-            tx.send(Ok(Chunk {
+        let chunks: Vec<&[u8]> = match raw_bytes.is_empty() {
+            true => vec![&[]],
+            false => raw_bytes.chunks(chunk_size.max(1)).collect(),
+        };
+        for (i, bytes) in chunks.into_iter().enumerate() {
+            let chunk = Chunk {
                 // Chunks always contain one object -> fix this
                 data: bytes.to_vec(),
                 name: if i == 0 {
@@ -81,9 +89,12 @@ pub async fn stream_data(
                 } else {
                     Vec::new()
                 },
-            }))
-            .await
-            .unwrap(); // Fix this
+            };
+            if tx.send(Ok(chunk)).await.is_err() {
+                // The client dropped the stream.
+                debug!("{name} fetch dropped by the client after {i} chunks");
+                return;
+            }
         }
     });
 
@@ -0,0 +1,345 @@
+//! Offline subcommands, working directly on the data and keys directories of a stopped server.
+//!
+//! Files are read and written with the same code as the server, so what these commands report is
+//! what the server sees on startup.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bastionlab_common::auth::{KeyManagement, KeyRole};
+use bastionlab_common::config::BastionLabConfig;
+use bastionlab_common::config_check;
+use bastionlab_common::prelude::*;
+use bastionlab_polars::persistence::{
+    self, export_artifact, inspect_artifact, list_artifacts, reencrypt_tenant,
+};
+use bastionlab_polars::tenant_keys::{TenantKeyring, KEYS_DIR};
+use bastionlab_polars::DEFAULT_DATA_DIR;
+use clap::{Args, Subcommand, ValueEnum};
+use ring::digest::{digest, SHA256};
+
+/// Private key of the TLS certificate of the server.
+pub const SERVER_KEY: &str = "tls/host_server.key";
+
+#[derive(Args)]
+pub struct DataDir {
+    /// Directory of the persisted dataframes.
+    #[arg(long, default_value = DEFAULT_DATA_DIR)]
+    data_dir: PathBuf,
+    /// Master key of a tenant provided by the KMS, as TENANT=FILE, to read its dataframes.
+    #[arg(long = "tenant-key", value_parser = parse_tenant_key)]
+    tenant_keys: Vec<(String, PathBuf)>,
+}
+
+fn parse_tenant_key(arg: &str) -> Result<(String, PathBuf)> {
+    let (tenant, path) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected TENANT=FILE, got {arg:?}"))?;
+    Ok((tenant.to_string(), PathBuf::from(path)))
+}
+
+impl DataDir {
+    /// The keys of the tenants, wrapped by `server_key`. Encrypted dataframes are unreadable if
+    /// it does not exist.
+    fn keyring(&self, server_key: &Path) -> Result<TenantKeyring> {
+        if !server_key.exists() {
+            return Ok(TenantKeyring::disabled());
+        }
+        let server_key =
+            fs::read(server_key).with_context(|| anyhow!("Reading file: {server_key:?}"))?;
+        let provided: HashMap<_, _> = self.tenant_keys.iter().cloned().collect();
+        Ok(TenantKeyring::new(
+            self.data_dir.join(KEYS_DIR),
+            &server_key,
+            provided,
+        ))
+    }
+}
+
+#[derive(Args)]
+pub struct KeysArgs {
+    /// Public keys directory, `public_keys_directory` of config.toml by default.
+    #[arg(long)]
+    keys_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    command: KeysCommand,
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Lists the registered keys with their hashes.
+    List,
+    /// Registers a PEM public key.
+    Add {
+        #[arg(value_enum)]
+        role: Role,
+        /// PEM file of the public key.
+        pem: PathBuf,
+    },
+    /// Removes the key with the given hash.
+    Revoke { hash: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Role {
+    Owner,
+    User,
+}
+
+impl From<Role> for KeyRole {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Owner => KeyRole::Owner,
+            Role::User => KeyRole::User,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// Parquet file.
+    Parquet,
+    /// Arrow IPC file.
+    Ipc,
+}
+
+impl From<ExportFormat> for persistence::ExportFormat {
+    fn from(format: ExportFormat) -> Self {
+        match format {
+            ExportFormat::Parquet => persistence::ExportFormat::Parquet,
+            ExportFormat::Ipc => persistence::ExportFormat::Ipc,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    identifier: String,
+    #[arg(long, value_enum, default_value = "parquet")]
+    format: ExportFormat,
+    /// File the dataframe is written to.
+    #[arg(long)]
+    output: PathBuf,
+    /// Break-glass flag: a copy of the private key of the server, required as exports bypass
+    /// the policy of the dataframe.
+    #[arg(long)]
+    master_key: PathBuf,
+    /// Private key of the server the master key is checked against, which also wraps the tenant
+    /// keys.
+    #[arg(long, default_value = SERVER_KEY)]
+    server_key: PathBuf,
+    #[command(flatten)]
+    data: DataDir,
+}
+
+/// Lists the persisted dataframes, including the ones the server cannot load.
+pub fn inspect(args: &DataDir) -> Result<()> {
+    let keys = args.keyring(Path::new(SERVER_KEY))?;
+    for (identifier, path) in list_artifacts(&args.data_dir)? {
+        match inspect_artifact(&path, &keys) {
+            Ok(summary) => {
+                let encryption = match summary.encryption {
+                    Some((tenant, version)) => format!("  tenant {tenant} key v{version}"),
+                    None => String::new(),
+                };
+                println!(
+                    "{identifier}  {} rows  {} bytes  version {}  semantics {}{encryption}  ({})",
+                    summary.rows,
+                    summary.size,
+                    summary.version,
+                    summary.semantics_version,
+                    summary.origin
+                );
+                let schema: Vec<_> = summary
+                    .schema
+                    .iter()
+                    .map(|(name, dtype)| format!("{name}: {dtype}"))
+                    .collect();
+                println!("    {}", schema.join(", "));
+            }
+            Err(e) => println!("{identifier}  unreadable: {}", e.message()),
+        }
+    }
+    Ok(())
+}
+
+/// Checks the manifest and column checksums of every persisted dataframe.
+///
+/// Returns whether they are all valid.
+pub fn verify(args: &DataDir) -> Result<bool> {
+    let keys = args.keyring(Path::new(SERVER_KEY))?;
+    let artifacts = list_artifacts(&args.data_dir)?;
+    let mut corrupted = 0;
+    for (identifier, path) in artifacts.iter() {
+        match inspect_artifact(path, &keys) {
+            Ok(_) => println!("ok         {identifier}"),
+            Err(e) => {
+                corrupted += 1;
+                println!("corrupted  {identifier}: {}", e.message());
+            }
+        }
+    }
+    println!("{} dataframes, {corrupted} corrupted", artifacts.len());
+    Ok(corrupted == 0)
+}
+
+fn keys_dir(args: &KeysArgs) -> Result<PathBuf> {
+    if let Some(dir) = &args.keys_dir {
+        return Ok(dir.clone());
+    }
+    let path = config_check::config_path(None, |var| std::env::var(var).ok());
+    let config: BastionLabConfig =
+        toml::from_str(&fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?)
+            .with_context(|| format!("Parsing {path:?}"))?;
+    Ok(PathBuf::from(config.public_keys_directory()?))
+}
+
+pub fn keys(args: &KeysArgs) -> Result<()> {
+    let dir = keys_dir(args)?;
+    match &args.command {
+        KeysCommand::List => {
+            for entry in KeyManagement::list_entries(&dir)? {
+                println!(
+                    "{:<6}  {}  {}",
+                    entry.role.dir_name(),
+                    entry.hash,
+                    entry.path.display()
+                );
+            }
+        }
+        KeysCommand::Add { role, pem } => {
+            let pem = fs::read(pem).with_context(|| anyhow!("Reading file: {pem:?}"))?;
+            let entry = KeyManagement::add_key(&dir, (*role).into(), &pem)?;
+            println!("Added {} at {}", entry.hash, entry.path.display());
+        }
+        KeysCommand::Revoke { hash } => {
+            let entry = KeyManagement::revoke_key(&dir, hash)?;
+            println!("Revoked {} ({})", entry.hash, entry.path.display());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Loads and checks the configuration, then prints its effective values along with where
+    /// each came from: the file, the environment or the defaults. Secrets are redacted.
+    Check {
+        /// Configuration file, `BASTIONLAB_CONFIG` or config.toml by default.
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+/// Returns whether the configuration is valid, its problems printed otherwise.
+pub fn config(args: &ConfigArgs) -> bool {
+    match &args.command {
+        ConfigCommand::Check { config } => {
+            let path = config_check::config_path(config.as_deref(), |var| std::env::var(var).ok());
+            match config_check::load_file(&path, Default::default()) {
+                Ok(loaded) => {
+                    print!("{}", loaded.render());
+                    println!("Configuration is valid.");
+                    true
+                }
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct TenantKeysArgs {
+    /// Private key of the server the tenant keys are wrapped by.
+    #[arg(long, default_value = SERVER_KEY)]
+    server_key: PathBuf,
+    #[command(flatten)]
+    data: DataDir,
+    #[command(subcommand)]
+    command: TenantKeysCommand,
+}
+
+#[derive(Subcommand)]
+enum TenantKeysCommand {
+    /// Lists the tenants with the status of their keys.
+    List,
+    /// Adds a new version of the key of a tenant and re-encrypts its dataframes under it.
+    Rotate { tenant: String },
+    /// Erases the key of a tenant, after which its dataframes can no longer be loaded.
+    Revoke { tenant: String },
+}
+
+pub fn tenant_keys(args: &TenantKeysArgs) -> Result<()> {
+    ensure!(
+        args.server_key.exists(),
+        "Tenant keys are wrapped by the private key of the server, missing at {:?}",
+        args.server_key
+    );
+    let keys = args.data.keyring(&args.server_key)?;
+    match &args.command {
+        TenantKeysCommand::List => {
+            for status in keys.statuses()? {
+                let state = match status.revoked_at {
+                    Some(at) => format!("revoked at {at}"),
+                    None => format!("active v{}", status.active_version),
+                };
+                println!("{:<8}  {}  {state}", status.source, status.tenant);
+            }
+        }
+        TenantKeysCommand::Rotate { tenant } => {
+            let version = keys.rotate(tenant)?;
+            let rewritten = reencrypt_tenant(&args.data.data_dir, tenant, &keys)?;
+            println!(
+                "Rotated the key of {tenant} to v{version}, re-encrypted {} dataframes",
+                rewritten.len()
+            );
+        }
+        TenantKeysCommand::Revoke { tenant } => {
+            keys.revoke(tenant)?;
+            warn!("Revoked the key of tenant {tenant}");
+            println!("Revoked the key of {tenant}");
+        }
+    }
+    Ok(())
+}
+
+fn file_digest(path: &Path) -> Result<Vec<u8>> {
+    let contents = fs::read(path).with_context(|| anyhow!("Reading file: {path:?}"))?;
+    Ok(digest(&SHA256, &contents).as_ref().to_vec())
+}
+
+/// Writes a persisted dataframe to a file, whatever its policy, for break-glass recovery.
+pub fn export(args: &ExportArgs) -> Result<()> {
+    ensure!(
+        file_digest(&args.master_key)? == file_digest(&args.server_key)?,
+        "The master key does not match the private key of the server at {:?}",
+        args.server_key
+    );
+    let (_, path) = list_artifacts(&args.data.data_dir)?
+        .into_iter()
+        .find(|(identifier, _)| identifier == &args.identifier)
+        .ok_or_else(|| anyhow!("No persisted dataframe {}", args.identifier))?;
+
+    let keys = args.data.keyring(&args.server_key)?;
+    let rows = export_artifact(&path, &args.output, args.format.into(), &keys)?;
+    warn!(
+        "Exported dataframe {} to {:?} bypassing its policy",
+        args.identifier, args.output
+    );
+    println!(
+        "Exported {rows} rows of {} to {}",
+        args.identifier,
+        args.output.display()
+    );
+    Ok(())
+}
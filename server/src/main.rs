@@ -1,72 +1,212 @@
+use bastionlab_client::federation::ClientConnector;
+use bastionlab_client::self_test::{SelfTest, SelfTestOptions};
 use bastionlab_common::config::BastionLabConfig;
 use bastionlab_common::prelude::*;
 use bastionlab_common::{
     auth::KeyManagement,
-    session::SessionManager,
+    config_check,
+    connections::{load_tls, ConnectionManager},
+    health,
+    logging::{self, LogFormat},
+    metrics::{self, Metrics},
+    reload,
+    replay::ReplayGuard,
+    session::{SessionManager, TokenValidator},
     telemetry::{self, TelemetryEventProps},
 };
+use bastionlab_polars::audit::JsonLinesSink;
+use bastionlab_polars::embedded::EmbeddedStore;
+use bastionlab_polars::reproducibility::BundleSigner;
+use bastionlab_polars::tenant_keys::{TenantKeyring, KEYS_DIR};
+use bastionlab_polars::watermark::Watermarker;
 use bastionlab_polars::BastionLabPolars;
 use bastionlab_torch::BastionLabTorch;
+use clap::{Args, Parser, Subcommand};
 use std::collections::hash_map::DefaultHasher;
 use std::fs;
-use std::path::Path;
-use std::time::SystemTime;
-use tonic::transport::{Identity, Server, ServerTlsConfig};
-use tonic::Status;
-
-#[derive(Clone)]
-struct TokenValidator {
-    sess_manager: Arc<SessionManager>,
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use toml::value::{Table, Value};
+use tonic::transport::Server;
+
+mod admin;
+
+/// The BastionLab server, and offline tools for its persisted state.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+    /// Filter of the logs, such as `debug` or `bastionlab_polars=trace,info`, instead of
+    /// `RUST_LOG`.
+    #[arg(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
 }
 
-impl tonic::service::Interceptor for TokenValidator {
-    fn call(&mut self, req: tonic::Request<()>) -> std::result::Result<tonic::Request<()>, Status> {
-        if !self.sess_manager.auth_enabled() {
-            return Ok(req);
-        }
-        let meta = req
-            .metadata()
-            .get_bin("accesstoken-bin")
-            .ok_or_else(|| Status::invalid_argument("No access token in request metadata"))?;
+#[derive(Args)]
+struct ServeArgs {
+    /// Runs the self-test against an in-process server, then exits.
+    #[arg(long)]
+    self_test: bool,
+    /// Runs a development server keeping all its state in a single file, listening on localhost
+    /// only and without authentication.
+    #[arg(long, value_name = "PATH")]
+    embedded: Option<PathBuf>,
+    /// Keeps authentication enabled in embedded mode.
+    #[arg(long, requires = "embedded")]
+    embedded_auth: bool,
+    /// Configuration file, `BASTIONLAB_CONFIG` or config.toml by default.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    overrides: ConfigFlags,
+}
 
-        let access_token = meta
-            .to_bytes()
-            .map_err(|_| Status::invalid_argument("Could not decode accesstoken"))?;
+/// Settings overriding the configuration file and the environment.
+#[derive(Args)]
+struct ConfigFlags {
+    /// URL the server listens on, e.g. `https://0.0.0.0:50056`, instead of
+    /// `client_to_enclave_untrusted_url`.
+    #[arg(long, value_name = "URL")]
+    listen: Option<String>,
+    /// Certificate served over TLS, instead of `tls_cert_file`.
+    #[arg(long, value_name = "PATH")]
+    tls_cert: Option<String>,
+    /// Private key of the TLS certificate, instead of `tls_key_file`.
+    #[arg(long, value_name = "PATH")]
+    tls_key: Option<String>,
+    /// Public keys directory, instead of `public_keys_directory`.
+    #[arg(long, value_name = "PATH")]
+    keys_dir: Option<String>,
+    /// Kilobytes of data per chunk of fetched dataframes, instead of `fetch_chunk_kb`.
+    #[arg(long, value_name = "KB")]
+    fetch_chunk_kb: Option<u32>,
+    /// Largest upload, in megabytes, instead of `max_upload_mb`.
+    #[arg(long, value_name = "MB")]
+    max_upload_mb: Option<u32>,
+    /// Seconds results are kept unused, instead of `dataframe_ttl_secs`.
+    #[arg(long, value_name = "SECS")]
+    dataframe_ttl_secs: Option<u32>,
+}
 
-        let mut tokens = self.sess_manager.sessions.write().expect("Poisoned lock");
+impl ConfigFlags {
+    /// The keys of the configuration set by the flags.
+    fn table(&self) -> Table {
+        let strings = [
+            ("client_to_enclave_untrusted_url", &self.listen),
+            ("tls_cert_file", &self.tls_cert),
+            ("tls_key_file", &self.tls_key),
+            ("public_keys_directory", &self.keys_dir),
+        ];
+        let integers = [
+            ("fetch_chunk_kb", self.fetch_chunk_kb),
+            ("max_upload_mb", self.max_upload_mb),
+            ("dataframe_ttl_secs", self.dataframe_ttl_secs),
+        ];
+        let strings = strings
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, Value::String(value.clone()?))));
+        let integers = integers
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, Value::Integer(value?.into()))));
+        strings
+            .chain(integers)
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+}
 
-        let session = tokens
-            .get(access_token.as_ref())
-            .ok_or_else(|| Status::aborted("Session not found!"))?;
+/// Used in embedded mode when there is no configuration file.
+const EMBEDDED_CONFIG: &str = r#"
+client_to_enclave_untrusted_url = "https://127.0.0.1:50056"
+public_keys_directory = "keys/"
+session_expiry_in_secs = 86400
+"#;
 
-        let recv_ip = &req
-            .remote_addr()
-            .ok_or_else(|| Status::aborted("User IP unavailable"))?;
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the server, the default.
+    Serve(ServeArgs),
+    /// Lists the persisted dataframes.
+    Inspect(admin::DataDir),
+    /// Checks the persisted dataframes, exiting with an error if any is corrupted.
+    Verify(admin::DataDir),
+    /// Manages the public keys directory.
+    Keys(admin::KeysArgs),
+    /// Exports a persisted dataframe for break-glass recovery.
+    Export(admin::ExportArgs),
+    /// Manages the encryption keys of the tenants.
+    TenantKeys(admin::TenantKeysArgs),
+    /// Checks config.toml without starting the server.
+    Config(admin::ConfigArgs),
+}
 
-        // ip verification
-        if session.user_ip.ip() != recv_ip.ip() {
-            return Err(Status::aborted("Unknown IP Address!"));
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(
+        LogFormat::from_env()?,
+        cli.log_level.as_deref(),
+        std::io::stderr,
+    )?;
+    match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => serve(args).await,
+        Command::Inspect(args) => admin::inspect(&args),
+        Command::Verify(args) => {
+            if !admin::verify(&args)? {
+                std::process::exit(1);
+            }
+            Ok(())
         }
-
-        // expiry verification
-        let curr_time = SystemTime::now();
-        if curr_time > session.expiry {
-            tokens.remove(access_token.as_ref());
-            return Err(Status::aborted("Session Expired"));
+        Command::Keys(args) => admin::keys(&args),
+        Command::Export(args) => admin::export(&args),
+        Command::TenantKeys(args) => admin::tenant_keys(&args),
+        Command::Config(args) => {
+            if !admin::config(&args) {
+                std::process::exit(1);
+            }
+            Ok(())
         }
-
-        Ok(req)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let config: BastionLabConfig =
-        toml::from_str(&fs::read_to_string("config.toml").context("Reading the config.toml file")?)
-            .context("Parsing the config.toml file")?;
+async fn serve(args: ServeArgs) -> Result<()> {
+    let embedded = args.embedded.as_deref();
+    let path = config_check::config_path(args.config.as_deref(), |var| std::env::var(var).ok());
+    let overrides = args.overrides.table();
+    // Fails listing every problem of the configuration.
+    let loaded = if embedded.is_some() && args.config.is_none() && !path.exists() {
+        config_check::load_overridden(EMBEDDED_CONFIG, |var| std::env::var(var).ok(), overrides)?
+    } else {
+        config_check::load_file(&path, overrides)?
+    };
+    info!("Effective configuration:\n{}", loaded.render().trim_end());
+    let config: BastionLabConfig = loaded.config;
+
+    if args.self_test {
+        let report = SelfTest::default()
+            .run(&config, SelfTestOptions::default())
+            .await;
+        if report.passed() {
+            info!("{report}");
+        } else {
+            error!("{report}");
+        }
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
 
-    let disable_authentication = !std::env::var("DISABLE_AUTHENTICATION").is_err();
+    let disable_authentication = !std::env::var("DISABLE_AUTHENTICATION").is_err()
+        || (embedded.is_some() && !args.embedded_auth);
+    if let Some(path) = embedded {
+        embedded_banner(path, disable_authentication);
+    }
+
+    // Not serving until the keys are loaded and the persisted dataframes reloaded.
+    let (mut health_reporter, health_service) = health::health_service().await;
 
     let keys = if !disable_authentication {
         match KeyManagement::load_from_dir(Path::new(
@@ -79,7 +219,7 @@ async fn main() -> Result<()> {
                 Some(keys)
             }
             Err(e) => {
-                println!("Exiting due to an error reading keys. {}", e.message());
+                error!("Exiting due to an error reading keys. {}", e.message());
                 //Temp fix to exit early, returning an error seems to break the "?" handlers above.
                 return Ok(());
             }
@@ -89,17 +229,81 @@ async fn main() -> Result<()> {
         None
     };
 
-    let sess_manager: Arc<SessionManager> = Arc::new(SessionManager::new(
-        keys,
-        config
-            .session_expiry()
-            .context("Parsing the public session_expiry config")?,
-    ));
-    let server_cert =
-        fs::read("tls/host_server.pem").context("Reading the tls/host_server.pem file")?;
-    let server_key =
-        fs::read("tls/host_server.key").context("Reading the tls/host_server.key file")?;
-    let server_identity = Identity::from_pem(&server_cert, &server_key);
+    let replay = if keys.is_some() {
+        ReplayGuard::open(
+            Path::new(&config.replay_state_file),
+            config.signature_max_skew_secs,
+            config.replay_persist_interval_secs,
+        )
+    } else {
+        ReplayGuard::new(config.signature_max_skew_secs)
+    };
+    let sess_manager: Arc<SessionManager> = Arc::new(
+        SessionManager::new(
+            keys,
+            config
+                .session_expiry()
+                .context("Parsing the public session_expiry config")?,
+        )
+        .with_replay_guard(replay)
+        .with_challenges(
+            Duration::from_secs(config.challenge_ttl_secs),
+            config.max_outstanding_challenges,
+            config.max_challenges_per_holder,
+        )
+        .with_keys_dir(PathBuf::from(config.public_keys_directory.clone())),
+    );
+    let tls_files = config.tls_files();
+    let (cert_resolver, tls) = match load_tls(&tls_files)? {
+        Some((cert_resolver, tls)) => {
+            if let Some(client_ca) = &tls_files.client_ca {
+                info!(
+                    "Clients must present a certificate issued by {}.",
+                    client_ca.display()
+                );
+            }
+            (Some(cert_resolver), Some(tls))
+        }
+        None => {
+            // Embedded servers run without TLS unless a certificate is set up.
+            if embedded.is_none() {
+                warn!("{}", "!".repeat(80));
+                warn!(
+                    "No TLS certificate at {} or no key at {}: serving in PLAINTEXT, requests \
+                     and dataframes travel unencrypted.",
+                    tls_files.cert.display(),
+                    tls_files.key.display()
+                );
+                warn!("{}", "!".repeat(80));
+            }
+            (None, None)
+        }
+    };
+
+    // Embedded servers only keep the background tasks they cannot do without.
+    if config.credentials_reload_secs > 0 && embedded.is_none() {
+        let interval = Duration::from_secs(config.credentials_reload_secs);
+        if let Some(cert_resolver) = cert_resolver {
+            reload::watch_tls(
+                cert_resolver,
+                tls_files.cert.clone(),
+                tls_files.key.clone(),
+                interval,
+            );
+        }
+        if sess_manager.auth_enabled() {
+            reload::watch_public_keys(
+                sess_manager.clone(),
+                PathBuf::from(config.public_keys_directory.clone()),
+                interval,
+            );
+        }
+    }
+
+    // Challenges are also evicted lazily, this bounds how long expired ones stay in memory.
+    if sess_manager.auth_enabled() {
+        sess_manager.evict_challenges_every(Duration::from_secs(config.challenge_ttl_secs));
+    }
 
     //TODO: Change it when specifying the TEE will be available
     let tee_mode = String::from("None");
@@ -112,7 +316,7 @@ async fn main() -> Result<()> {
         String::from(format!("{:X}", hasher.finish()))
     };
 
-    if std::env::var("BASTIONLAB_DISABLE_TELEMETRY").is_err() {
+    if embedded.is_none() && std::env::var("BASTIONLAB_DISABLE_TELEMETRY").is_err() {
         telemetry::setup(platform, uid, tee_mode).context("Setting up telemetry")?;
         info!("Telemetry is enabled.")
     } else {
@@ -120,12 +324,15 @@ async fn main() -> Result<()> {
     }
     telemetry::add_event(TelemetryEventProps::Started {}, None);
 
-    let token_validator = TokenValidator {
-        sess_manager: sess_manager.clone(),
-    };
-    let mut builder = Server::builder()
-        .tls_config(ServerTlsConfig::new().identity(server_identity))
-        .context("Setting up TLS")?;
+    let token_validator = TokenValidator::new(sess_manager.clone());
+    let metrics = Arc::new(Metrics::new());
+    let connection_manager =
+        Arc::new(ConnectionManager::new(&config).with_metrics(metrics.clone()));
+    {
+        let sess_manager = sess_manager.clone();
+        connection_manager.on_close(move |connection| sess_manager.close_connection(connection));
+    }
+    let mut builder = Server::builder();
 
     // Session
     let builder = {
@@ -137,6 +344,32 @@ async fn main() -> Result<()> {
         builder.add_service(SessionServiceServer::new(svc))
     };
 
+    // Health, unauthenticated for probes
+    let builder = builder.add_service(health_service);
+
+    // Connections
+    let builder = {
+        use bastionlab_common::{
+            connections::ConnectionGrpcService,
+            session_proto::connection_service_server::ConnectionServiceServer,
+        };
+        builder.add_service(ConnectionServiceServer::with_interceptor(
+            ConnectionGrpcService::new(sess_manager.clone(), connection_manager.clone()),
+            token_validator.clone(),
+        ))
+    };
+
+    // Keys
+    let builder = {
+        use bastionlab_common::{
+            auth::KeyGrpcService, session_proto::key_service_server::KeyServiceServer,
+        };
+        builder.add_service(KeyServiceServer::with_interceptor(
+            KeyGrpcService::new(sess_manager.clone()),
+            token_validator.clone(),
+        ))
+    };
+
     // Torch
     let torch_svc = BastionLabTorch::new(sess_manager.clone());
     let builder = {
@@ -148,16 +381,88 @@ async fn main() -> Result<()> {
     };
 
     // Polars
-    let polars_svc = BastionLabPolars::new(sess_manager.clone());
+    let mut polars_svc = BastionLabPolars::new(sess_manager.clone(), &config)
+        .with_remote_connector(Arc::new(ClientConnector))
+        .with_metrics(metrics.clone());
+    if let Some(path) = embedded {
+        let store = EmbeddedStore::open(path, config.embedded_compaction_ratio)
+            .context("Opening the embedded store")?;
+        polars_svc = polars_svc.with_embedded_store(store);
+        if config.tenant_encryption {
+            warn!("Tenant encryption is not available in embedded mode, ignoring it.");
+        }
+    } else if config.tenant_encryption {
+        let dir = polars_svc.data_dir().join(KEYS_DIR);
+        let provided = config
+            .tenant_key_files
+            .iter()
+            .map(|(tenant, path)| (tenant.clone(), PathBuf::from(path)))
+            .collect();
+        let server_key = fs::read(&tls_files.key).with_context(|| {
+            format!(
+                "Tenant encryption requires the server key, reading {}",
+                tls_files.key.display()
+            )
+        })?;
+        polars_svc = polars_svc.with_tenant_keys(TenantKeyring::new(dir, &server_key, provided));
+        info!("Persisted dataframes are encrypted under tenant keys.");
+    }
+    if !config.audit_log_file.is_empty() {
+        let sink = JsonLinesSink::open(&config.audit_log_file)
+            .with_context(|| format!("Opening the audit log {}", config.audit_log_file))?;
+        polars_svc = polars_svc.with_audit_sinks(vec![Box::new(sink)]);
+        info!(
+            "Queries and fetches are audited to {}.",
+            config.audit_log_file
+        );
+    }
+    if !config.bundle_signing_key_file.is_empty() {
+        let pem =
+            fs::read(&config.bundle_signing_key_file).context("Reading the bundle signing key")?;
+        polars_svc = polars_svc.with_bundle_signer(
+            BundleSigner::from_pkcs8_pem(&pem).context("Loading the bundle signing key")?,
+        );
+    } else {
+        warn!("No bundle signing key is configured: reproducibility bundles cannot be verified after a restart.");
+    }
+    if !config.watermark_secret_file.is_empty() {
+        polars_svc = polars_svc.with_watermarker(
+            Watermarker::from_secret_file(
+                Path::new(&config.watermark_secret_file),
+                config.watermark_record_capacity,
+            )
+            .context("Loading the watermarking secret")?,
+        );
+    } else {
+        warn!("No watermarking secret is configured: watermarked fetches cannot be traced after a restart.");
+    }
+    if config.fault_injection {
+        if cfg!(debug_assertions) {
+            warn!("Fault injection is enabled: requests can ask for faults in their streams.");
+        } else {
+            warn!("Fault injection is only available in dev builds, ignoring it.");
+        }
+    }
     let builder = {
         use bastionlab_polars::{
             polars_proto::polars_service_server::PolarsServiceServer, BastionLabPolars,
         };
-        let svc = BastionLabPolars::new(sess_manager.clone());
-        match BastionLabPolars::load_dfs(&svc) {
+        if let Err(e) = polars_svc.open_storage() {
+            error!(
+                "Exiting due to an error loading the persisted privacy budgets or watermarks. {e}"
+            );
+            std::process::exit(1);
+        }
+        match BastionLabPolars::load_dfs(&polars_svc) {
             Ok(_) => info!("Successfully loaded saved dataframes"),
-            Err(_) => info!("There was an error loading saved dataframes"),
+            Err(e) => warn!("There was an error loading saved dataframes: {e}"),
         };
+        health::ready(&mut health_reporter).await;
+        polars_svc.resume_plan_jobs();
+        if embedded.is_none() {
+            polars_svc.watch_memory(Duration::from_secs(config.memory_sample_secs));
+        }
+        polars_svc.watch_expiry(Duration::from_secs(config.dataframe_ttl_sweep_secs));
         builder.add_service(PolarsServiceServer::with_interceptor(
             polars_svc.clone(),
             token_validator.clone(),
@@ -176,15 +481,92 @@ async fn main() -> Result<()> {
         ))
     };
 
-    let addr = config
+    // Self-test
+    let builder = {
+        use bastionlab_client::{
+            self_test::SelfTestGrpcService,
+            self_test_proto::self_test_service_server::SelfTestServiceServer,
+        };
+        builder.add_service(SelfTestServiceServer::with_interceptor(
+            SelfTestGrpcService::new(sess_manager.clone(), config.clone(), SelfTest::default()),
+            token_validator.clone(),
+        ))
+    };
+
+    let mut addr = config
         .client_to_enclave_untrusted_socket()
         .context("Parsing the client_to_enclave_untrusted_socket config")?;
+    if embedded.is_some() {
+        addr = SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port()));
+    }
+
+    if config.metrics_port != 0 {
+        let metrics_addr = SocketAddr::new(addr.ip(), config.metrics_port);
+        let listener = TcpListener::bind(metrics_addr)
+            .await
+            .context("Binding the metrics_port")?;
+        info!("Metrics served on http://{metrics_addr}/metrics.");
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, metrics).await {
+                error!("Serving the metrics failed: {e}");
+            }
+        });
+    }
 
     info!("BastionLab server listening on {addr:?}.");
     info!("Server ready to take requests");
 
     // serve!
-    builder.serve(addr).await?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("Binding the client_to_enclave_untrusted_socket")?;
+    let grace = Duration::from_secs(config.shutdown_grace_secs);
+    let shutdown = async {
+        shutdown_signal().await;
+        info!(
+            "Shutting down: requests in flight get {}s to finish, then checkpointed plan jobs as \
+             long to stop at their next segment boundary",
+            grace.as_secs()
+        );
+        let rejected = polars_svc.reject_pending_approvals();
+        if rejected > 0 {
+            info!("Rejected {rejected} fetches pending approval, to be fetched again");
+        }
+    };
+    connection_manager
+        .serve_with_shutdown(listener, tls, builder.into_service(), shutdown, grace)
+        .await?;
+    polars_svc.stop_plan_jobs(grace).await;
+    polars_svc.flush_audit_log().await;
+    info!("Shut down");
 
     Ok(())
 }
+
+/// Completes on Ctrl-C, or on SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => (),
+                _ = terminate.recv() => (),
+            },
+            Err(_) => tokio::signal::ctrl_c().await.unwrap_or(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or(());
+}
+
+fn embedded_banner(path: &Path, disable_authentication: bool) {
+    let rule = "*".repeat(78);
+    warn!("{rule}");
+    warn!("EMBEDDED MODE: for development only, do not load sensitive data.");
+    warn!("All state is kept in {}.", path.display());
+    if disable_authentication {
+        warn!("AUTHENTICATION IS DISABLED: anyone on this machine can access every dataframe.");
+    }
+    warn!("{rule}");
+}
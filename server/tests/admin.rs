@@ -0,0 +1,343 @@
+//! The offline admin subcommands, run on copies of the fixture directories.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use bastionlab_common::auth::KeyManagement;
+use bastionlab_polars::access_control::Policy;
+use bastionlab_polars::persistence::{encode_artifact, store_artifact, PersistenceSettings};
+use bastionlab_polars::tenant_keys::{TenantKeyring, KEYS_DIR};
+use bastionlab_polars::DataFrameArtifact;
+use polars::prelude::*;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/admin");
+
+fn dataset() -> DataFrame {
+    df! {
+        "id" => (0..50i64).collect::<Vec<_>>(),
+        "city" => (0..50).map(|i| ["paris", "lyon"][i % 2]).collect::<Vec<_>>(),
+    }
+    .unwrap()
+}
+
+/// Regenerates the dataframe fixtures: `cargo test -p bastionlab write_fixtures -- --ignored`.
+#[test]
+#[ignore]
+fn write_fixtures() {
+    let dir = Path::new(FIXTURES).join("data_frames");
+    let artifact = DataFrameArtifact::new(dataset(), Policy::allow_by_default(), Vec::new());
+    let settings = PersistenceSettings {
+        zstd_level: 0,
+        dictionary_ratio: 0.0,
+    };
+    let buf = encode_artifact(&artifact, &settings).unwrap();
+    fs::write(dir.join("good.bldf"), &buf).unwrap();
+
+    let mut flipped = buf.clone();
+    let last = flipped.len() - 50;
+    flipped[last] ^= 1;
+    fs::write(dir.join("flipped.bldf"), flipped).unwrap();
+    fs::write(dir.join("truncated.bldf"), &buf[..buf.len() - 10]).unwrap();
+    fs::copy(
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/bastionlab_polars/tests/fixtures/persistence/legacy.json"
+        ),
+        dir.join("legacy.json"),
+    )
+    .unwrap();
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let dest = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &dest);
+        } else {
+            fs::copy(&path, &dest).unwrap();
+        }
+    }
+}
+
+/// A copy of the fixtures the commands can modify.
+fn fixtures() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bastionlab-admin-{}", uuid::Uuid::new_v4()));
+    copy_dir(Path::new(FIXTURES), &dir);
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bastionlab"))
+        .args(args)
+        .current_dir(dir)
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn line<'a>(out: &'a str, prefix: &str) -> &'a str {
+    out.lines()
+        .find(|line| line.starts_with(prefix))
+        .unwrap_or_else(|| panic!("No line starting with {prefix:?} in:\n{out}"))
+}
+
+#[test]
+fn inspect_lists_every_artifact() {
+    let dir = fixtures();
+    let output = run(&dir, &["inspect"]);
+    assert!(output.status.success());
+    let out = stdout(&output);
+
+    let good = line(&out, "good ");
+    assert!(good.contains("50 rows"), "{good}");
+    assert!(good.contains("version 0"), "{good}");
+    assert!(good.contains("(uploaded dataframe)"), "{good}");
+    assert!(out.contains("id: i64, city: str"), "{out}");
+    assert!(line(&out, "legacy ").contains("1200 rows"));
+    assert!(line(&out, "flipped ").contains("unreadable"));
+    assert!(line(&out, "truncated ").contains("unreadable"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn verify_reports_corrupted_entries() {
+    let dir = fixtures();
+    let output = run(&dir, &["verify"]);
+    assert_eq!(output.status.code(), Some(1));
+    let out = stdout(&output);
+    assert!(line(&out, "corrupted  flipped").contains("checksum mismatch"));
+    assert!(line(&out, "corrupted  truncated").contains("truncated"));
+    line(&out, "ok         good");
+    line(&out, "ok         legacy");
+    line(&out, "4 dataframes, 2 corrupted");
+
+    let data = dir.join("data_frames");
+    fs::remove_file(data.join("flipped.bldf")).unwrap();
+    fs::remove_file(data.join("truncated.bldf")).unwrap();
+    assert!(run(&dir, &["verify", "--data-dir", "data_frames"])
+        .status
+        .success());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn keys_are_added_and_revoked_atomically() {
+    let dir = fixtures();
+    let keys = ["keys", "--keys-dir", "keys"];
+    let list = |dir: &Path| stdout(&run(dir, &[&keys[..], &["list"]].concat()));
+    assert_eq!(list(&dir).lines().count(), 2);
+
+    let output = run(&dir, &[&keys[..], &["add", "user", "extra.pem"]].concat());
+    assert!(output.status.success());
+    let hash = stdout(&output)
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .to_string();
+    assert!(dir.join("keys/users").join(format!("{hash}.pem")).exists());
+    assert!(list(&dir)
+        .lines()
+        .any(|line| line.starts_with("users") && line.contains(&hash)));
+    assert_eq!(list(&dir).lines().count(), 3);
+    // Nothing temporary is left where the server would load it.
+    assert_eq!(fs::read_dir(dir.join("keys/users")).unwrap().count(), 2);
+
+    // The server loads the result, and knows the new key as a user.
+    let loaded = KeyManagement::load_from_dir(&dir.join("keys")).unwrap();
+    assert!(loaded.contains(&hash));
+    assert!(!loaded.verify_owner(&hash));
+
+    let twice = run(&dir, &[&keys[..], &["add", "owner", "extra.pem"]].concat());
+    assert!(!twice.status.success());
+    assert!(String::from_utf8_lossy(&twice.stderr).contains("already registered"));
+
+    assert!(run(&dir, &[&keys[..], &["revoke", &hash]].concat())
+        .status
+        .success());
+    assert!(!list(&dir).contains(&hash));
+    assert!(!run(&dir, &[&keys[..], &["revoke", &hash]].concat())
+        .status
+        .success());
+
+    // Files that are not PEM keys are refused.
+    let garbage = run(&dir, &[&keys[..], &["add", "owner", "wrong.key"]].concat());
+    assert!(!garbage.status.success());
+    assert_eq!(list(&dir).lines().count(), 2);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn export_requires_the_master_key() {
+    let dir = fixtures();
+    let export = |master_key: &str, identifier: &str, format: &str, output: &str| {
+        run(
+            &dir,
+            &[
+                "export",
+                identifier,
+                "--format",
+                format,
+                "--output",
+                output,
+                "--master-key",
+                master_key,
+            ],
+        )
+    };
+    let master_key = "tls/host_server.key";
+
+    let output = export("wrong.key", "good", "parquet", "good.parquet");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not match"));
+    assert!(!dir.join("good.parquet").exists());
+    assert!(!export(master_key, "flipped", "parquet", "flipped.parquet")
+        .status
+        .success());
+    assert!(!export(master_key, "missing", "parquet", "missing.parquet")
+        .status
+        .success());
+
+    let output = export(master_key, "good", "parquet", "good.parquet");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Exported 50 rows"));
+    let exported = ParquetReader::new(fs::File::open(dir.join("good.parquet")).unwrap())
+        .finish()
+        .unwrap();
+    assert!(exported.frame_equal(&dataset()));
+
+    assert!(export(master_key, "good", "ipc", "good.arrow")
+        .status
+        .success());
+    let exported = IpcReader::new(fs::File::open(dir.join("good.arrow")).unwrap())
+        .finish()
+        .unwrap();
+    assert!(exported.frame_equal(&dataset()));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn tenant_keys_are_rotated_and_revoked_in_isolation() {
+    let dir = fixtures();
+    let data = dir.join("data_frames");
+    let server_key = fs::read(dir.join("tls/host_server.key")).unwrap();
+    let keys = TenantKeyring::new(data.join(KEYS_DIR), &server_key, Default::default());
+    let settings = PersistenceSettings {
+        zstd_level: 0,
+        dictionary_ratio: 0.0,
+    };
+    for tenant in ["alice", "bob"] {
+        let artifact = DataFrameArtifact::new(dataset(), Policy::allow_by_default(), Vec::new())
+            .with_owner(tenant);
+        store_artifact(&data, &format!("{tenant}_df"), &artifact, &settings, &keys).unwrap();
+    }
+    let tenant_keys = |args: &[&str]| run(&dir, &[&["tenant-keys"][..], args].concat());
+
+    let out = stdout(&run(&dir, &["inspect"]));
+    assert!(line(&out, "alice_df ").contains("tenant alice key v1"));
+    assert!(line(&out, "bob_df ").contains("50 rows"));
+    assert!(!line(&out, "good ").contains("tenant"));
+    let out = stdout(&tenant_keys(&["list"]));
+    assert!(line(&out, "wrapped   alice").contains("active v1"));
+
+    let output = tenant_keys(&["rotate", "bob"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("re-encrypted 1 dataframes"));
+    assert!(tenant_keys(&["revoke", "alice"]).status.success());
+    assert!(!tenant_keys(&["revoke", "alice"]).status.success());
+
+    let out = stdout(&run(&dir, &["inspect"]));
+    let alice = line(&out, "alice_df ");
+    assert!(alice.contains("unreadable") && alice.contains("tenant alice was revoked"));
+    assert!(line(&out, "bob_df ").contains("tenant bob key v2"));
+    assert!(line(&out, "good ").contains("50 rows"));
+    let out = stdout(&tenant_keys(&["list"]));
+    assert!(line(&out, "wrapped   alice").contains("revoked at"));
+    assert!(line(&out, "wrapped   bob").contains("active v2"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn serve_stays_the_default() {
+    let dir = fixtures();
+    // Without a config file, serving fails on reading it, with or without the subcommand.
+    for args in [&[][..], &["serve"], &["--self-test"]] {
+        let output = run(&dir, args);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("config.toml"));
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn config_check_lists_every_problem() {
+    let dir = fixtures();
+    let config = "client_to_enclave_untrusted_url = \"https://0.0.0.0:50056\"\n\
+                  public_keys_directory = \"keys/\"\n\
+                  session_expiry_in_secs = 3600\n";
+    fs::write(
+        dir.join("config.toml"),
+        format!("{config}persistence_zstd_level = 23\nquery_concurency = 2\n"),
+    )
+    .unwrap();
+    let output = run(&dir, &["config", "check"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 problem(s)"), "{stderr}");
+    assert!(stderr.contains("Unknown key `query_concurency`"));
+    assert!(stderr.contains("`persistence_zstd_level` must be between 0 and 22"));
+
+    fs::write(dir.join("config.toml"), config).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bastionlab"))
+        .args(["config", "check"])
+        .current_dir(&dir)
+        .env("BASTIONLAB_QUERY_CONCURRENCY", "2")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let out = stdout(&output);
+    assert!(line(&out, "session_expiry_in_secs =").ends_with("3600  # file"));
+    assert!(line(&out, "query_concurrency =").ends_with("2  # env BASTIONLAB_QUERY_CONCURRENCY"));
+    assert!(line(&out, "max_upload_mb =").ends_with("# default"));
+    assert!(out.ends_with("Configuration is valid.\n"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn serve_reads_the_named_config_with_flags_overriding_it() {
+    let dir = fixtures();
+    fs::write(
+        dir.join("custom.toml"),
+        "client_to_enclave_untrusted_url = \"https://127.0.0.1:0\"\n\
+         public_keys_directory = \"keys/\"\n\
+         session_expiry_in_secs = 3600\n\
+         upload_chunk_kb = 2048\n",
+    )
+    .unwrap();
+    let output = run(&dir, &["--config", "custom.toml", "--max-upload-mb", "1"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`upload_chunk_kb` (2048 KB) is larger than `max_upload_mb` (1 MB)"),
+        "{stderr}"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bastionlab"))
+        .args(["serve", "--log-level", "error", "--max-upload-mb", "1"])
+        .args(["--fetch-chunk-kb", "0"])
+        .current_dir(&dir)
+        .env("BASTIONLAB_CONFIG", "custom.toml")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 problem(s)"), "{stderr}");
+    assert!(stderr.contains("`fetch_chunk_kb` must be between 1 and 4095, not 0"));
+    fs::remove_dir_all(dir).unwrap();
+}